@@ -0,0 +1,660 @@
+//! SecureIoTOS Authentication & Identity Attestation Module
+//! --------------------------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Attestation reports: a signed statement of which firmware the device is
+//! running, answering a verifier's challenge.
+//!
+//! The report binds the verifier's nonce (freshness), the firmware build ID
+//! (`kernel::image::firmware_info().build_id`) and a timestamp, and is
+//! signed with the device key (see `token::sign_attestation`). A backend
+//! accepts it only if the nonce is the one it sent and the build ID is one
+//! it released.
+//!
+//! Remote attestation runs the mutual challenge-response protocol below,
+//! with CBOR messages (`codec::cbor`):
+//!
+//! ```text
+//! verifier                                    device
+//!    | -- Challenge { nonce, verifier id } ------> |  signed by the verifier
+//!    | <-- Evidence { nonce, device id,         -- |  signed by the device
+//!    |       measurement, timestamp, dev nonce }   |
+//!    | -- Verdict { dev nonce, accepted } -------> |  signed by the verifier
+//! ```
+//!
+//! Each message is `[body: bstr, signature: bstr]`, the signature being
+//! ECDSA P-256 / SHA-256 (`r || s`) over the body, and each body a map with
+//! small integer keys whose key 0 names the message type, so one message
+//! can never be replayed as another. The device answers only challenges
+//! signed by the verifier it was provisioned with, and learns the outcome
+//! from a verdict bound to its own nonce; the verifier accepts only
+//! evidence for its latest challenge.
+//!
+//! The evidence carries the `BootMeasurement` the bootloader left in a
+//! retained RAM slot: SHA-256 of the image it started, the image's build
+//! ID and how it was booted (`BOOT_*` flags).
+//!
+//! `Attester` is the device side, `Verifier` the backend (or a peer
+//! device) side.
+
+use codec::cbor::{CborError, Decoder, Encoder};
+use crypto::ecc::{self, EccError, KeyPair, SignatureFormat};
+use p256::ecdsa::{Signature, VerifyingKey};
+
+use crate::timestamp::{self, ArtifactKind, Timestamp, TimestampError, TIMESTAMP_LEN};
+
+/// Length of the verifier's challenge nonce.
+pub const NONCE_LEN: usize = 16;
+/// Length of a firmware build ID (`kernel::image::BUILD_ID_LEN`).
+pub const BUILD_ID_LEN: usize = 20;
+/// Layout version of the signed body.
+pub const REPORT_VERSION: u8 = 1;
+/// Size of `AttestationReport::to_bytes()`.
+pub const REPORT_LEN: usize = 1 + NONCE_LEN + BUILD_ID_LEN + 1 + TIMESTAMP_LEN;
+
+/// Report flag: the firmware was built from a tree with uncommitted changes.
+pub const FLAG_DIRTY_BUILD: u8 = 1 << 0;
+
+/// The signed body of an attestation report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AttestationReport {
+    /// Challenge from the verifier
+    pub nonce: [u8; NONCE_LEN],
+    /// Build ID of the running firmware
+    pub build_id: [u8; BUILD_ID_LEN],
+    /// `FLAG_*` bits
+    pub flags: u8,
+    /// Stamp of kind `ArtifactKind::Attestation`
+    pub timestamp: Timestamp,
+}
+
+impl AttestationReport {
+    /// Fixed encoding, the exact bytes that are signed:
+    /// `version(1) | nonce(16) | build_id(20) | flags(1) | timestamp(25)`.
+    pub fn to_bytes(&self) -> [u8; REPORT_LEN] {
+        let mut out = [0u8; REPORT_LEN];
+        let mut at = 0;
+        for part in [
+            &[REPORT_VERSION][..],
+            &self.nonce[..],
+            &self.build_id[..],
+            &[self.flags][..],
+            &self.timestamp.to_bytes()[..],
+        ] {
+            out[at..at + part.len()].copy_from_slice(part);
+            at += part.len();
+        }
+        out
+    }
+}
+
+/// Magic at the start of the bootloader's measurement record.
+pub const MEASUREMENT_MAGIC: [u8; 8] = *b"SIOSMEAS";
+/// Layout version of the measurement record.
+pub const MEASUREMENT_VERSION: u8 = 1;
+/// Size of `BootMeasurement::to_bytes()`.
+pub const MEASUREMENT_LEN: usize = 8 + 1 + 1 + 2 + 4 + 32 + BUILD_ID_LEN;
+
+/// Boot flag: the bootloader verified the image signature before starting it.
+pub const BOOT_SIGNATURE_VERIFIED: u8 = 1 << 0;
+/// Boot flag: the debug port was locked (e.g. nRF APPROTECT) at boot.
+pub const BOOT_DEBUG_LOCKED: u8 = 1 << 1;
+
+/// What the bootloader measured before starting the application.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootMeasurement {
+    /// `BOOT_*` bits
+    pub boot_flags: u8,
+    /// Length of the measured image in bytes
+    pub image_len: u32,
+    /// SHA-256 of the image
+    pub firmware_hash: [u8; 32],
+    /// Build ID from the image header; all zero if the image has none
+    pub build_id: [u8; BUILD_ID_LEN],
+}
+
+impl BootMeasurement {
+    /// Record layout, as written by `bootloader::firmware::measure()`:
+    /// `magic(8) | version(1) | boot_flags(1) | reserved(2) | image_len(4, LE) |
+    /// firmware_hash(32) | build_id(20)`.
+    pub fn to_bytes(&self) -> [u8; MEASUREMENT_LEN] {
+        let mut out = [0u8; MEASUREMENT_LEN];
+        out[..8].copy_from_slice(&MEASUREMENT_MAGIC);
+        out[8] = MEASUREMENT_VERSION;
+        out[9] = self.boot_flags;
+        out[12..16].copy_from_slice(&self.image_len.to_le_bytes());
+        out[16..48].copy_from_slice(&self.firmware_hash);
+        out[48..].copy_from_slice(&self.build_id);
+        out
+    }
+
+    /// Parse the record; `None` if there is none (cold RAM, an older
+    /// bootloader).
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < MEASUREMENT_LEN || bytes[..8] != MEASUREMENT_MAGIC || bytes[8] != MEASUREMENT_VERSION {
+            return None;
+        }
+        let mut firmware_hash = [0u8; 32];
+        firmware_hash.copy_from_slice(&bytes[16..48]);
+        let mut build_id = [0u8; BUILD_ID_LEN];
+        build_id.copy_from_slice(&bytes[48..MEASUREMENT_LEN]);
+        Some(Self {
+            boot_flags: bytes[9],
+            image_len: u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]),
+            firmware_hash,
+            build_id,
+        })
+    }
+}
+
+/// Largest encoded protocol message.
+pub const MAX_MESSAGE_LEN: usize = 320;
+/// Longest device or verifier ID in bytes.
+pub const MAX_ID_LEN: usize = 64;
+/// Fixed `r || s` signature length.
+const SIGNATURE_LEN: usize = 64;
+
+// Message types (body key 0)
+const MSG_CHALLENGE: u64 = 1;
+const MSG_EVIDENCE: u64 = 2;
+const MSG_VERDICT: u64 = 3;
+
+// Body keys
+const K_TYPE: u64 = 0;
+const K_NONCE: u64 = 1;
+const K_VERIFIER: u64 = 2;
+const K_DEVICE: u64 = 3;
+const K_FIRMWARE_HASH: u64 = 4;
+const K_BUILD_ID: u64 = 5;
+const K_BOOT_FLAGS: u64 = 6;
+const K_IMAGE_LEN: u64 = 7;
+const K_TIMESTAMP: u64 = 8;
+const K_DEVICE_NONCE: u64 = 9;
+const K_ACCEPTED: u64 = 10;
+
+/// Errors of the attestation protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttestError {
+    /// Not a well-formed message, or a field is missing or has the wrong size
+    Malformed,
+    /// A message of another type than expected
+    UnexpectedMessage,
+    /// The signature does not verify against the peer's key
+    BadSignature,
+    /// The message answers another challenge (stale or replayed)
+    NonceMismatch,
+    /// No challenge is outstanding
+    NoChallenge,
+    /// Firmware hash or build ID is not among the reference values
+    UnknownFirmware,
+    /// A required `BOOT_*` flag is missing
+    BootState,
+    /// Output buffer too small
+    BufferFull,
+    /// Signing or nonce generation failed
+    Crypto(EccError),
+    /// The time-stamping service could not stamp the evidence
+    Timestamp(TimestampError),
+}
+
+impl From<CborError> for AttestError {
+    fn from(e: CborError) -> Self {
+        match e {
+            CborError::BufferFull => AttestError::BufferFull,
+            _ => AttestError::Malformed,
+        }
+    }
+}
+
+impl From<EccError> for AttestError {
+    fn from(e: EccError) -> Self {
+        AttestError::Crypto(e)
+    }
+}
+
+impl From<TimestampError> for AttestError {
+    fn from(e: TimestampError) -> Self {
+        AttestError::Timestamp(e)
+    }
+}
+
+/// Verifier's challenge.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Challenge<'a> {
+    pub nonce: [u8; NONCE_LEN],
+    pub verifier: &'a str,
+}
+
+/// Device's signed evidence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Evidence<'a> {
+    /// Nonce of the challenge answered
+    pub nonce: [u8; NONCE_LEN],
+    pub device_id: &'a str,
+    pub measurement: BootMeasurement,
+    /// `Timestamp::to_bytes()` of a stamp of kind `ArtifactKind::Attestation`
+    pub timestamp: [u8; TIMESTAMP_LEN],
+    /// Nonce the verdict must echo
+    pub device_nonce: [u8; NONCE_LEN],
+}
+
+/// Verifier's decision on a piece of evidence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Verdict {
+    pub device_nonce: [u8; NONCE_LEN],
+    pub accepted: bool,
+}
+
+/// What a verifier accepts.
+#[derive(Debug, Clone, Copy)]
+pub struct ReferenceValues<'a> {
+    /// SHA-256 of every released image
+    pub firmware_hashes: &'a [[u8; 32]],
+    /// Build IDs of the same releases
+    pub build_ids: &'a [[u8; BUILD_ID_LEN]],
+    /// `BOOT_*` bits that must all be set
+    pub required_boot_flags: u8,
+}
+
+/// Device side of the protocol.
+pub struct Attester {
+    verifier_key: VerifyingKey,
+    measurement: BootMeasurement,
+    /// Device nonce of the evidence awaiting a verdict
+    pending: Option<[u8; NONCE_LEN]>,
+}
+
+impl Attester {
+    /// Answer challenges of the verifier holding `verifier_key` with
+    /// `measurement` (read from the bootloader's record at startup).
+    pub fn new(verifier_key: VerifyingKey, measurement: BootMeasurement) -> Self {
+        Self { verifier_key, measurement, pending: None }
+    }
+
+    /// Check `challenge` and write the signed evidence to `out`; returns
+    /// its length. `sign` signs with the device key, e.g.
+    /// `crypto::ecc::sign_message`.
+    pub fn respond(
+        &mut self,
+        challenge: &[u8],
+        device_id: &str,
+        timestamp: &Timestamp,
+        sign: impl FnOnce(&[u8]) -> Result<Signature, EccError>,
+        out: &mut [u8],
+    ) -> Result<usize, AttestError> {
+        let body = open_signed(challenge, &self.verifier_key)?;
+        let challenge = decode_challenge(body)?;
+
+        let mut device_nonce = [0u8; NONCE_LEN];
+        crypto::rng::fill_random(&mut device_nonce).map_err(EccError::from)?;
+        let evidence = Evidence {
+            nonce: challenge.nonce,
+            device_id,
+            measurement: self.measurement,
+            timestamp: timestamp.to_bytes(),
+            device_nonce,
+        };
+        let mut buf = [0u8; MAX_MESSAGE_LEN];
+        let len = encode_evidence(&evidence, &mut buf)?;
+        let signature = sign(&buf[..len])?;
+        let written = seal(&buf[..len], &signature, out)?;
+        self.pending = Some(device_nonce);
+        Ok(written)
+    }
+
+    /// `respond` with the next stamp of the time-stamping service
+    /// (`timestamp::init_timestamping()` must have run).
+    pub fn respond_now(
+        &mut self,
+        challenge: &[u8],
+        device_id: &str,
+        sign: impl FnOnce(&[u8]) -> Result<Signature, EccError>,
+        out: &mut [u8],
+    ) -> Result<usize, AttestError> {
+        let timestamp = timestamp::stamp(ArtifactKind::Attestation)?;
+        self.respond(challenge, device_id, &timestamp, sign, out)
+    }
+
+    /// Check the verifier's verdict on the last evidence sent; returns
+    /// whether it was accepted. Each verdict is taken once.
+    pub fn accept_verdict(&mut self, verdict: &[u8]) -> Result<bool, AttestError> {
+        let expected = self.pending.ok_or(AttestError::NoChallenge)?;
+        let verdict = decode_verdict(open_signed(verdict, &self.verifier_key)?)?;
+        if !crypto::ct::eq(&verdict.device_nonce, &expected) {
+            return Err(AttestError::NonceMismatch);
+        }
+        self.pending = None;
+        Ok(verdict.accepted)
+    }
+}
+
+/// Verifier side of the protocol.
+pub struct Verifier {
+    id: String,
+    key: KeyPair,
+    /// Nonce of the outstanding challenge
+    pending: Option<[u8; NONCE_LEN]>,
+}
+
+impl Verifier {
+    /// Verifier `id` signing with `key`; devices are provisioned with its
+    /// public half.
+    pub fn new(id: &str, key: KeyPair) -> Self {
+        Self { id: id.into(), key, pending: None }
+    }
+
+    /// Write a fresh signed challenge to `out`; returns its length. A new
+    /// challenge replaces any outstanding one.
+    pub fn challenge(&mut self, out: &mut [u8]) -> Result<usize, AttestError> {
+        if self.id.len() > MAX_ID_LEN {
+            return Err(AttestError::Malformed);
+        }
+        let mut nonce = [0u8; NONCE_LEN];
+        crypto::rng::fill_random(&mut nonce).map_err(EccError::from)?;
+        let mut buf = [0u8; MAX_MESSAGE_LEN];
+        let mut enc = Encoder::new(&mut buf);
+        enc.map(3)?;
+        enc.u64(K_TYPE)?.u64(MSG_CHALLENGE)?;
+        enc.u64(K_NONCE)?.bytes(&nonce)?;
+        enc.u64(K_VERIFIER)?.str(&self.id)?;
+        let len = enc.len();
+        let written = seal(&buf[..len], &self.key.sign(&buf[..len]), out)?;
+        self.pending = Some(nonce);
+        Ok(written)
+    }
+
+    /// Check the signature of `evidence` against the device's key and that
+    /// it answers the outstanding challenge, which is then used up.
+    /// Appraise the result with `appraise`.
+    pub fn verify<'m>(&mut self, evidence: &'m [u8], device_key: &VerifyingKey) -> Result<Evidence<'m>, AttestError> {
+        let expected = self.pending.ok_or(AttestError::NoChallenge)?;
+        let evidence = decode_evidence(open_signed(evidence, device_key)?)?;
+        if !crypto::ct::eq(&evidence.nonce, &expected) {
+            return Err(AttestError::NonceMismatch);
+        }
+        self.pending = None;
+        Ok(evidence)
+    }
+
+    /// Compare verified evidence against the reference values.
+    pub fn appraise(evidence: &Evidence, refs: &ReferenceValues) -> Result<(), AttestError> {
+        let m = &evidence.measurement;
+        if !refs.firmware_hashes.contains(&m.firmware_hash) || !refs.build_ids.contains(&m.build_id) {
+            return Err(AttestError::UnknownFirmware);
+        }
+        if m.boot_flags & refs.required_boot_flags != refs.required_boot_flags {
+            return Err(AttestError::BootState);
+        }
+        Ok(())
+    }
+
+    /// Write the signed verdict on `evidence` to `out`; returns its length.
+    pub fn verdict(&self, evidence: &Evidence, accepted: bool, out: &mut [u8]) -> Result<usize, AttestError> {
+        let mut buf = [0u8; MAX_MESSAGE_LEN];
+        let mut enc = Encoder::new(&mut buf);
+        enc.map(3)?;
+        enc.u64(K_TYPE)?.u64(MSG_VERDICT)?;
+        enc.u64(K_DEVICE_NONCE)?.bytes(&evidence.device_nonce)?;
+        enc.u64(K_ACCEPTED)?.bool(accepted)?;
+        let len = enc.len();
+        seal(&buf[..len], &self.key.sign(&buf[..len]), out)
+    }
+}
+
+/// `[body, signature]`.
+fn seal(body: &[u8], signature: &Signature, out: &mut [u8]) -> Result<usize, AttestError> {
+    let mut enc = Encoder::new(out);
+    enc.array(2)?.bytes(body)?.bytes(signature.as_ref())?;
+    Ok(enc.len())
+}
+
+/// The body of a `[body, signature]` message, once the signature checks
+/// out against `key`.
+fn open_signed<'m>(message: &'m [u8], key: &VerifyingKey) -> Result<&'m [u8], AttestError> {
+    let mut dec = Decoder::new(message);
+    if dec.array()? != 2 {
+        return Err(AttestError::Malformed);
+    }
+    let body = dec.bytes()?;
+    let signature = dec.bytes()?;
+    if !dec.is_at_end() || signature.len() != SIGNATURE_LEN {
+        return Err(AttestError::Malformed);
+    }
+    let signature = ecc::parse_signature(signature, SignatureFormat::Fixed).map_err(|_| AttestError::Malformed)?;
+    ecc::verify(key, body, &signature).map_err(|_| AttestError::BadSignature)?;
+    Ok(body)
+}
+
+fn encode_evidence(e: &Evidence, out: &mut [u8]) -> Result<usize, AttestError> {
+    if e.device_id.is_empty() || e.device_id.len() > MAX_ID_LEN {
+        return Err(AttestError::Malformed);
+    }
+    let m = &e.measurement;
+    let mut enc = Encoder::new(out);
+    enc.map(9)?;
+    enc.u64(K_TYPE)?.u64(MSG_EVIDENCE)?;
+    enc.u64(K_NONCE)?.bytes(&e.nonce)?;
+    enc.u64(K_DEVICE)?.str(e.device_id)?;
+    enc.u64(K_FIRMWARE_HASH)?.bytes(&m.firmware_hash)?;
+    enc.u64(K_BUILD_ID)?.bytes(&m.build_id)?;
+    enc.u64(K_BOOT_FLAGS)?.u64(u64::from(m.boot_flags))?;
+    enc.u64(K_IMAGE_LEN)?.u64(u64::from(m.image_len))?;
+    enc.u64(K_TIMESTAMP)?.bytes(&e.timestamp)?;
+    enc.u64(K_DEVICE_NONCE)?.bytes(&e.device_nonce)?;
+    Ok(enc.len())
+}
+
+/// Walk the map of a body, checking its type and handing every other
+/// key to `field`; unknown keys are skipped.
+fn decode_body<'a>(
+    body: &'a [u8],
+    msg_type: u64,
+    mut field: impl FnMut(u64, &mut Decoder<'a>) -> Result<bool, AttestError>,
+) -> Result<(), AttestError> {
+    let mut dec = Decoder::new(body);
+    let mut typed = false;
+    for _ in 0..dec.map()? {
+        let key = dec.u64()?;
+        if key == K_TYPE {
+            if dec.u64()? != msg_type {
+                return Err(AttestError::UnexpectedMessage);
+            }
+            typed = true;
+        } else if !field(key, &mut dec)? {
+            dec.skip()?;
+        }
+    }
+    if !typed || !dec.is_at_end() {
+        return Err(AttestError::Malformed);
+    }
+    Ok(())
+}
+
+fn fixed<const N: usize>(dec: &mut Decoder) -> Result<[u8; N], AttestError> {
+    dec.bytes()?.try_into().map_err(|_| AttestError::Malformed)
+}
+
+fn decode_challenge(body: &[u8]) -> Result<Challenge<'_>, AttestError> {
+    let (mut nonce, mut verifier) = (None, None);
+    decode_body(body, MSG_CHALLENGE, |key, dec| {
+        match key {
+            K_NONCE => nonce = Some(fixed(dec)?),
+            K_VERIFIER => verifier = Some(dec.str()?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    })?;
+    Ok(Challenge { nonce: nonce.ok_or(AttestError::Malformed)?, verifier: verifier.ok_or(AttestError::Malformed)? })
+}
+
+fn decode_evidence(body: &[u8]) -> Result<Evidence<'_>, AttestError> {
+    let (mut nonce, mut device_id, mut firmware_hash, mut build_id) = (None, None, None, None);
+    let (mut boot_flags, mut image_len, mut timestamp, mut device_nonce) = (None, None, None, None);
+    decode_body(body, MSG_EVIDENCE, |key, dec| {
+        match key {
+            K_NONCE => nonce = Some(fixed(dec)?),
+            K_DEVICE => device_id = Some(dec.str()?),
+            K_FIRMWARE_HASH => firmware_hash = Some(fixed(dec)?),
+            K_BUILD_ID => build_id = Some(fixed(dec)?),
+            K_BOOT_FLAGS => boot_flags = Some(u8::try_from(dec.u64()?).map_err(|_| AttestError::Malformed)?),
+            K_IMAGE_LEN => image_len = Some(u32::try_from(dec.u64()?).map_err(|_| AttestError::Malformed)?),
+            K_TIMESTAMP => timestamp = Some(fixed(dec)?),
+            K_DEVICE_NONCE => device_nonce = Some(fixed(dec)?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    })?;
+    let missing = AttestError::Malformed;
+    Ok(Evidence {
+        nonce: nonce.ok_or(missing)?,
+        device_id: device_id.ok_or(missing)?,
+        measurement: BootMeasurement {
+            boot_flags: boot_flags.ok_or(missing)?,
+            image_len: image_len.ok_or(missing)?,
+            firmware_hash: firmware_hash.ok_or(missing)?,
+            build_id: build_id.ok_or(missing)?,
+        },
+        timestamp: timestamp.ok_or(missing)?,
+        device_nonce: device_nonce.ok_or(missing)?,
+    })
+}
+
+fn decode_verdict(body: &[u8]) -> Result<Verdict, AttestError> {
+    let (mut device_nonce, mut accepted) = (None, None);
+    decode_body(body, MSG_VERDICT, |key, dec| {
+        match key {
+            K_DEVICE_NONCE => device_nonce = Some(fixed(dec)?),
+            K_ACCEPTED => accepted = Some(dec.bool()?),
+            _ => return Ok(false),
+        }
+        Ok(true)
+    })?;
+    Ok(Verdict {
+        device_nonce: device_nonce.ok_or(AttestError::Malformed)?,
+        accepted: accepted.ok_or(AttestError::Malformed)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::timestamp::BootSession;
+
+    #[test]
+    fn encoding_layout() {
+        let timestamp = Timestamp {
+            kind: ArtifactKind::Attestation,
+            session: BootSession { boot_count: 3, nonce: 9 },
+            counter: 1,
+            wall_clock_ms: None,
+        };
+        let report = AttestationReport { nonce: [0x11; NONCE_LEN], build_id: [0x22; BUILD_ID_LEN], flags: 0, timestamp };
+        let bytes = report.to_bytes();
+        assert_eq!(bytes[0], REPORT_VERSION);
+        assert_eq!(&bytes[1..17], &[0x11; NONCE_LEN]);
+        assert_eq!(&bytes[17..37], &[0x22; BUILD_ID_LEN]);
+        assert_eq!(bytes[37], 0);
+        assert_eq!(&bytes[38..], &timestamp.to_bytes());
+    }
+
+    const FIRMWARE: [u8; 32] = [0xF1; 32];
+    const BUILD: [u8; BUILD_ID_LEN] = [0x42; BUILD_ID_LEN];
+
+    fn measurement() -> BootMeasurement {
+        BootMeasurement {
+            boot_flags: BOOT_SIGNATURE_VERIFIED,
+            image_len: 0x2_0000,
+            firmware_hash: FIRMWARE,
+            build_id: BUILD,
+        }
+    }
+
+    fn stamp() -> Timestamp {
+        Timestamp {
+            kind: ArtifactKind::Attestation,
+            session: BootSession { boot_count: 5, nonce: 1 },
+            counter: 2,
+            wall_clock_ms: None,
+        }
+    }
+
+    fn parties() -> (Verifier, Attester, KeyPair) {
+        let verifier_key = KeyPair::from_private_bytes(&[0x11; 32]).unwrap();
+        let attester = Attester::new(verifier_key.public_key(), measurement());
+        let device_key = KeyPair::from_private_bytes(&[0x22; 32]).unwrap();
+        (Verifier::new("backend-1", verifier_key), attester, device_key)
+    }
+
+    #[test]
+    fn measurement_record_round_trips() {
+        let bytes = measurement().to_bytes();
+        assert_eq!(&bytes[..8], &MEASUREMENT_MAGIC);
+        assert_eq!(BootMeasurement::from_bytes(&bytes), Some(measurement()));
+        assert_eq!(BootMeasurement::from_bytes(&[0xA5; MEASUREMENT_LEN]), None);
+    }
+
+    #[test]
+    fn mutual_attestation_round_trip() {
+        let (mut verifier, mut attester, device_key) = parties();
+        let refs = ReferenceValues {
+            firmware_hashes: &[FIRMWARE],
+            build_ids: &[BUILD],
+            required_boot_flags: BOOT_SIGNATURE_VERIFIED,
+        };
+
+        let mut challenge = [0u8; MAX_MESSAGE_LEN];
+        let len = verifier.challenge(&mut challenge).unwrap();
+        let mut evidence = [0u8; MAX_MESSAGE_LEN];
+        let sign = |m: &[u8]| Ok(device_key.sign(m));
+        let elen = attester.respond(&challenge[..len], "dev-0001", &stamp(), sign, &mut evidence).unwrap();
+
+        let parsed = verifier.verify(&evidence[..elen], &device_key.public_key()).unwrap();
+        assert_eq!(parsed.device_id, "dev-0001");
+        assert_eq!(parsed.measurement, measurement());
+        assert_eq!(parsed.timestamp, stamp().to_bytes());
+        assert_eq!(Verifier::appraise(&parsed, &refs), Ok(()));
+        let strict = ReferenceValues { required_boot_flags: BOOT_SIGNATURE_VERIFIED | BOOT_DEBUG_LOCKED, ..refs };
+        assert_eq!(Verifier::appraise(&parsed, &strict), Err(AttestError::BootState));
+
+        let mut verdict = [0u8; MAX_MESSAGE_LEN];
+        let vlen = verifier.verdict(&parsed, true, &mut verdict).unwrap();
+        assert_eq!(attester.accept_verdict(&verdict[..vlen]), Ok(true));
+        assert_eq!(attester.accept_verdict(&verdict[..vlen]), Err(AttestError::NoChallenge));
+        // The evidence answered a challenge that is now used up.
+        assert_eq!(verifier.verify(&evidence[..elen], &device_key.public_key()).err(), Some(AttestError::NoChallenge));
+    }
+
+    #[test]
+    fn forged_stale_and_mistyped_messages_are_rejected() {
+        let (mut verifier, mut attester, device_key) = parties();
+        let sign = |m: &[u8]| Ok(device_key.sign(m));
+        let mut first = [0u8; MAX_MESSAGE_LEN];
+        let flen = verifier.challenge(&mut first).unwrap();
+
+        // A challenge from anyone but the provisioned verifier.
+        let mut rogue = Verifier::new("rogue", KeyPair::from_private_bytes(&[0x33; 32]).unwrap());
+        let mut forged = [0u8; MAX_MESSAGE_LEN];
+        let len = rogue.challenge(&mut forged).unwrap();
+        let mut out = [0u8; MAX_MESSAGE_LEN];
+        assert_eq!(attester.respond(&forged[..len], "d", &stamp(), sign, &mut out), Err(AttestError::BadSignature));
+
+        // Evidence for a superseded challenge.
+        let elen = attester.respond(&first[..flen], "d", &stamp(), sign, &mut out).unwrap();
+        let mut second = [0u8; MAX_MESSAGE_LEN];
+        verifier.challenge(&mut second).unwrap();
+        assert_eq!(verifier.verify(&out[..elen], &device_key.public_key()).err(), Some(AttestError::NonceMismatch));
+
+        // A signed challenge is not a verdict.
+        assert_eq!(attester.accept_verdict(&first[..flen]), Err(AttestError::UnexpectedMessage));
+
+        // A flipped bit anywhere breaks the signature or the framing.
+        let mut tampered = out;
+        tampered[10] ^= 1;
+        assert!(verifier.verify(&tampered[..elen], &device_key.public_key()).is_err());
+    }
+}
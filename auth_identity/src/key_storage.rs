@@ -9,19 +9,23 @@
 //!
 //! Provides secure, interrupt-safe storage for device encryption keys.
 //! Keys are stored in RAM (protected by a Mutex) and should ideally be
-//! persisted in secure flash or a hardware security module (HSM).
+//! persisted in secure flash or a hardware security module (HSM); see
+//! `init_keys_from_store` for the flash-backed key store.
 
 // RefCell is a type from Rust’s core library (a minimal, no_std version of std), 
 // used for interior mutability.
 use core::cell::RefCell;
 
-// Mutex here comes from the cortex-m crate.
-// Purpose --> Provides exclusive access to data across interrupt contexts
-use cortex_m::interrupt::Mutex;
+// From the critical-section crate: a mutex whose critical sections disable
+// interrupts on the target (cortex-m's implementation) and take a std
+// mutex in host tests, ensuring exclusive access across interrupt contexts.
+use critical_section::Mutex;
 use rand::RngCore; // optional for random key generation
+use secure_storage::key_store::{Caller, KeyBlobStore, KeyKind, KeyPolicy, KeyStore, KeyStoreError, Operation, Usage};
+use sios_log::Secret;
 
 /// Static in-RAM key store, protected against race conditions
-static DEVICE_KEY: Mutex<RefCell<[u8; 16]>> = Mutex::new(RefCell::new([0u8; 16]));
+static DEVICE_KEY: Mutex<RefCell<Secret<[u8; 16]>>> = Mutex::new(RefCell::new(Secret::new([0u8; 16])));
 
 /// Initialize key storage
 ///
@@ -29,28 +33,51 @@ static DEVICE_KEY: Mutex<RefCell<[u8; 16]>> = Mutex::new(RefCell::new([0u8; 16])
 /// - Load keys from secure flash
 /// - Or generate a random key if none exists
 pub fn init_keys() {
-    cortex_m::interrupt::free(|cs| {
+    critical_section::with(|cs| {
         let mut key_ref = DEVICE_KEY.borrow(cs).borrow_mut();
-        if key_ref.iter().all(|&b| b == 0) {
+        if crypto::ct::is_zero(key_ref.expose_secret()) {
             // Example: generate a random AES-128 key if empty
-            let mut tmp_key = [0u8; 16];
-            rand::thread_rng().fill_bytes(&mut tmp_key);
-            *key_ref = tmp_key;
+            rand::thread_rng().fill_bytes(key_ref.expose_secret_mut());
         }
     });
 }
 
+/// Initialize key storage from the persistent key store
+///
+/// Loads the AES-128 key in slot `name` into RAM, creating it on first
+/// boot. The slot is restricted to the `caller` task and may only be
+/// exported (to here) or used for encryption.
+pub fn init_keys_from_store<S: KeyBlobStore>(
+    store: &mut KeyStore<S>,
+    name: &str,
+    caller: Caller,
+) -> Result<(), KeyStoreError> {
+    if store.kind(name) == Err(KeyStoreError::Unknown) {
+        let usage = Usage::ENCRYPT | Usage::DECRYPT | Usage::EXPORT;
+        let tasks = 1u32.checked_shl(caller.into()).unwrap_or(0);
+        store.generate(name, KeyKind::Aes128, KeyPolicy::new(usage).for_tasks(tasks))?;
+    }
+    if store.kind(name)? != KeyKind::Aes128 {
+        return Err(KeyStoreError::WrongKind);
+    }
+    store.with_key(name, caller, Operation::Export, |bytes| {
+        let mut key = Secret::new([0u8; 16]);
+        key.expose_secret_mut().copy_from_slice(bytes);
+        store_device_key(key);
+    })
+}
+
 /// Store device key securely (overwrites old key)
-pub fn store_device_key(key: [u8; 16]) {
-    cortex_m::interrupt::free(|cs| {
+pub fn store_device_key(key: Secret<[u8; 16]>) {
+    critical_section::with(|cs| {
         *DEVICE_KEY.borrow(cs).borrow_mut() = key;
     });
 }
 
 /// Retrieve a copy of the device key
-pub fn get_device_key() -> [u8; 16] {
-    cortex_m::interrupt::free(|cs| {
-        *DEVICE_KEY.borrow(cs).borrow()
+pub fn get_device_key() -> Secret<[u8; 16]> {
+    critical_section::with(|cs| {
+        DEVICE_KEY.borrow(cs).borrow().clone()
     })
 }
 
@@ -58,7 +85,7 @@ pub fn get_device_key() -> [u8; 16] {
 ///
 /// Useful if you want to wipe secrets before shutdown or re-provisioning
 pub fn clear_device_key() {
-    cortex_m::interrupt::free(|cs| {
-        *DEVICE_KEY.borrow(cs).borrow_mut() = [0u8; 16];
+    critical_section::with(|cs| {
+        *DEVICE_KEY.borrow(cs).borrow_mut() = Secret::new([0u8; 16]);
     });
 }
@@ -9,6 +9,9 @@
 
 pub mod key_storage;
 pub mod token;
+pub mod timestamp;
+pub mod attestation;
+pub mod provisioning;
 
 /// Initialize authentication modules for production.
 ///
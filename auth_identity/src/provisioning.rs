@@ -0,0 +1,659 @@
+//! SecureIoTOS Authentication & Identity Provisioning Module
+//! ---------------------------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Device side of identity provisioning: the device key is generated on
+//! the device and never leaves it; the outside world only sees a CSR and
+//! hands back the signed certificate.
+//!
+//! 1. `challenge()`: a nonce for the provisioning authority;
+//! 2. `authorize()`: the authority's signed grant over that nonce and the
+//!    device ID, checked against the authority key of its mode, yields a
+//!    `ProvisioningCapability`. Every step below takes it;
+//! 3. `generate_key()`: a P-256 key in the `key_store` slot `IDENTITY_KEY`,
+//!    sign-only and not exportable;
+//! 4. `csr()`: PKCS#10 request for that key, subject CN = device ID;
+//! 5. `store_certificate()`: the CA-signed certificate, which must carry
+//!    the identity key;
+//! 6. `lock()`: back to (or into) `Lifecycle::Production`; the capability
+//!    is consumed and provisioning is closed until the next grant.
+//!
+//! Two modes, each with its own authority key:
+//! - `Mode::Factory`: the production line, for devices in `Blank` or
+//!   `Provisioning`. The first grant moves a blank device to
+//!   `Provisioning`.
+//! - `Mode::Field`: an installer's tool, for devices shipped blank
+//!   (identity bound at installation) and for renewing the key and
+//!   certificate of a device in `Production`. It never reopens
+//!   `Provisioning`.
+//!
+//! The lifecycle state and the certificate are persisted, sealed with an
+//! AES-256 key of the key store (AES-GCM, so a modified record does not
+//! load), through an `IdentityStore`; the identity key itself lives in the
+//! key store. Restoring an older sealed record cannot be detected here:
+//! parts with one-time-programmable lock bits should also burn them on
+//! `lock()`.
+//!
+//! The host side of the factory flow (`Provisioner`, `host` feature) is in
+//! `station`.
+
+#[cfg(feature = "host")]
+mod station;
+#[cfg(feature = "host")]
+pub use station::*;
+
+use crypto::aes::NONCE_LEN as GCM_NONCE_LEN;
+use crypto::ecc::{self, PublicKeyFormat, SignatureFormat};
+use p256::ecdsa::VerifyingKey;
+use secure_storage::key_store::{Caller, KeyBlobStore, KeyKind, KeyPolicy, KeyStore, KeyStoreError, Usage};
+use sios_log::{info, warn};
+
+use crate::attestation::NONCE_LEN;
+
+/// Key store slot of the device identity key.
+pub const IDENTITY_KEY: &str = "identity";
+/// Key store slot of the key sealing the provisioning state.
+pub const STATE_KEY: &str = "prov-state";
+/// Longest device ID in bytes.
+pub const MAX_DEVICE_ID_LEN: usize = 64;
+/// Largest certificate accepted (DER).
+pub const MAX_CERT_LEN: usize = 2048;
+/// Raw `r || s` ECDSA P-256 signature length.
+pub const SIGNATURE_LEN: usize = 64;
+
+/// Magic prefix of the sealed state record ("PRVS").
+const MAGIC: [u8; 4] = *b"PRVS";
+/// Encoding version of the state record.
+const FORMAT_VERSION: u8 = 1;
+/// Domain separation of grant signatures.
+const GRANT_CONTEXT: &[u8] = b"sios/provisioning-grant";
+
+/// Device lifecycle states. Transitions only ever go forward.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Lifecycle {
+    /// Fresh from manufacturing, no identity
+    Blank = 0,
+    /// Accepting keys and certificates
+    Provisioning = 1,
+    /// Provisioning locked; normal operation
+    Production = 2,
+    /// Secrets erased, out of service
+    Decommissioned = 3,
+}
+
+impl Lifecycle {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Self::Blank),
+            1 => Some(Self::Provisioning),
+            2 => Some(Self::Production),
+            3 => Some(Self::Decommissioned),
+            _ => None,
+        }
+    }
+}
+
+/// Who is provisioning the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Mode {
+    /// Production line
+    Factory = 1,
+    /// Installer in the field
+    Field = 2,
+}
+
+impl Mode {
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(Self::Factory),
+            2 => Some(Self::Field),
+            _ => None,
+        }
+    }
+
+    /// Whether this mode may provision a device in `state`.
+    fn allows(&self, state: Lifecycle) -> bool {
+        match self {
+            Mode::Factory => matches!(state, Lifecycle::Blank | Lifecycle::Provisioning),
+            Mode::Field => matches!(state, Lifecycle::Blank | Lifecycle::Production),
+        }
+    }
+}
+
+/// Errors of device provisioning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdentityError {
+    /// The device is in a lifecycle state this step or mode does not allow
+    WrongLifecycle(Lifecycle),
+    /// Grant signature, device ID or nonce does not check out, or no
+    /// challenge is outstanding
+    NotAuthorized,
+    /// The capability is from an earlier session
+    StaleCapability,
+    /// No identity key has been generated
+    NoKey,
+    /// The certificate does not carry the identity key
+    CertificateMismatch,
+    /// `lock()` before both key and certificate are in place
+    Incomplete,
+    /// Malformed grant or certificate, or oversize device ID
+    Malformed,
+    /// Persisted state failed to unseal or has the wrong format
+    Corrupt,
+    /// No randomness for a nonce
+    Rng,
+    /// The key store refused an operation
+    KeyStore(KeyStoreError),
+    /// Backing storage failed
+    Storage(&'static str),
+}
+
+impl From<KeyStoreError> for IdentityError {
+    fn from(e: KeyStoreError) -> Self {
+        IdentityError::KeyStore(e)
+    }
+}
+
+/// Backing store for the sealed state record, typically a flash sector.
+pub trait IdentityStore {
+    /// Load the last saved record; `Ok(None)` if nothing was ever saved.
+    fn load(&mut self) -> Result<Option<Vec<u8>>, &'static str>;
+    /// Atomically replace the saved record.
+    fn save(&mut self, data: &[u8]) -> Result<(), &'static str>;
+}
+
+/// Public keys of the provisioning authorities the firmware trusts.
+#[derive(Debug, Clone, Copy)]
+pub struct Authorities {
+    pub factory: VerifyingKey,
+    pub field: VerifyingKey,
+}
+
+/// Proof that a provisioning authority allowed this session. Only
+/// `authorize()` creates one; it is neither `Clone` nor `Copy`, and
+/// `lock()` consumes it.
+#[derive(Debug)]
+pub struct ProvisioningCapability {
+    mode: Mode,
+    session: u64,
+}
+
+impl ProvisioningCapability {
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+}
+
+/// Persisted provisioning state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct State {
+    lifecycle: Lifecycle,
+    certificate: Option<Vec<u8>>,
+}
+
+/// The device's identity and its provisioning state.
+pub struct DeviceIdentity<S: IdentityStore> {
+    store: S,
+    device_id: String,
+    /// Task that owns the state key in the key store
+    caller: Caller,
+    authorities: Authorities,
+    state: State,
+    /// Nonce of the outstanding challenge
+    challenge: Option<[u8; NONCE_LEN]>,
+    /// Current session; capabilities of other sessions are stale
+    session: u64,
+}
+
+impl<S: IdentityStore> DeviceIdentity<S> {
+    /// Restore the provisioning state from `store`, creating the state key
+    /// in `keys` on first use. `caller` is the task running provisioning.
+    pub fn open<K: KeyBlobStore>(
+        mut store: S,
+        keys: &mut KeyStore<K>,
+        device_id: &str,
+        caller: Caller,
+        authorities: Authorities,
+    ) -> Result<Self, IdentityError> {
+        if device_id.is_empty() || device_id.len() > MAX_DEVICE_ID_LEN {
+            return Err(IdentityError::Malformed);
+        }
+        let state = match store.load().map_err(IdentityError::Storage)? {
+            Some(sealed) => unseal(keys, caller, device_id, &sealed)?,
+            None => State { lifecycle: Lifecycle::Blank, certificate: None },
+        };
+        if keys.kind(STATE_KEY) == Err(KeyStoreError::Unknown) {
+            let tasks = 1u32.checked_shl(caller.into()).unwrap_or(0);
+            keys.generate(
+                STATE_KEY,
+                KeyKind::Aes256,
+                KeyPolicy::new(Usage::ENCRYPT | Usage::DECRYPT).for_tasks(tasks),
+            )?;
+        }
+        Ok(Self { store, device_id: device_id.into(), caller, authorities, state, challenge: None, session: 1 })
+    }
+
+    pub fn lifecycle(&self) -> Lifecycle {
+        self.state.lifecycle
+    }
+
+    /// The stored device certificate (DER).
+    pub fn certificate(&self) -> Option<&[u8]> {
+        self.state.certificate.as_deref()
+    }
+
+    /// Fresh nonce for the authority to sign into a grant. Replaces any
+    /// outstanding one.
+    pub fn challenge(&mut self) -> Result<[u8; NONCE_LEN], IdentityError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        crypto::rng::fill_random(&mut nonce).map_err(|_| IdentityError::Rng)?;
+        self.challenge = Some(nonce);
+        Ok(nonce)
+    }
+
+    /// Check an authority's grant,
+    /// `mode(1) | nonce(16) | id_len(1) | device_id | r || s(64)`, signed
+    /// over `"sios/provisioning-grant" | everything before the signature`.
+    pub fn authorize<K: KeyBlobStore>(
+        &mut self,
+        keys: &mut KeyStore<K>,
+        grant: &[u8],
+    ) -> Result<ProvisioningCapability, IdentityError> {
+        let expected = self.challenge.take().ok_or(IdentityError::NotAuthorized)?;
+        let split = grant.len().checked_sub(SIGNATURE_LEN).ok_or(IdentityError::Malformed)?;
+        let (body, signature) = grant.split_at(split);
+        if body.len() < 2 + NONCE_LEN || body.len() != 2 + NONCE_LEN + usize::from(body[1 + NONCE_LEN]) {
+            return Err(IdentityError::Malformed);
+        }
+        let mode = Mode::from_u8(body[0]).ok_or(IdentityError::Malformed)?;
+        let authority = match mode {
+            Mode::Factory => &self.authorities.factory,
+            Mode::Field => &self.authorities.field,
+        };
+        let signature =
+            ecc::parse_signature(signature, SignatureFormat::Fixed).map_err(|_| IdentityError::Malformed)?;
+        let signed = [GRANT_CONTEXT, body].concat();
+        if ecc::verify(authority, &signed, &signature).is_err()
+            || !crypto::ct::eq(&body[1..1 + NONCE_LEN], &expected)
+            || &body[2 + NONCE_LEN..] != self.device_id.as_bytes()
+        {
+            warn!("provisioning grant refused");
+            return Err(IdentityError::NotAuthorized);
+        }
+        if !mode.allows(self.state.lifecycle) {
+            return Err(IdentityError::WrongLifecycle(self.state.lifecycle));
+        }
+
+        if self.state.lifecycle == Lifecycle::Blank && mode == Mode::Factory {
+            let next = State { lifecycle: Lifecycle::Provisioning, ..self.state.clone() };
+            self.persist(keys, next)?;
+        }
+        self.session += 1;
+        info!("provisioning authorized (mode {})", mode as u8);
+        Ok(ProvisioningCapability { mode, session: self.session })
+    }
+
+    /// Generate the identity key (replacing an existing one on renewal,
+    /// which also drops the certificate issued for it).
+    pub fn generate_key<K: KeyBlobStore>(
+        &mut self,
+        cap: &ProvisioningCapability,
+        keys: &mut KeyStore<K>,
+    ) -> Result<VerifyingKey, IdentityError> {
+        self.check(cap)?;
+        match keys.kind(IDENTITY_KEY) {
+            Ok(_) => keys.rotate(IDENTITY_KEY)?,
+            Err(KeyStoreError::Unknown) => keys.generate(IDENTITY_KEY, KeyKind::P256, KeyPolicy::new(Usage::SIGN))?,
+            Err(e) => return Err(e.into()),
+        }
+        if self.state.certificate.is_some() {
+            let next = State { certificate: None, ..self.state.clone() };
+            self.persist(keys, next)?;
+        }
+        Ok(keys.public_key(IDENTITY_KEY)?)
+    }
+
+    /// PKCS#10 certificate signing request (DER) for the identity key,
+    /// subject `CN=<device ID>`, signed with ECDSA P-256 / SHA-256.
+    pub fn csr<K: KeyBlobStore>(
+        &self,
+        cap: &ProvisioningCapability,
+        keys: &mut KeyStore<K>,
+    ) -> Result<Vec<u8>, IdentityError> {
+        self.check(cap)?;
+        let public_key = identity_key(keys)?;
+
+        // CertificationRequestInfo: version 0, subject, SPKI, no attributes
+        let cn = der(0x30, &[der(0x06, &OID_COMMON_NAME), der(0x0C, self.device_id.as_bytes())].concat());
+        let subject = der(0x30, &der(0x31, &cn));
+        let spki = ecc::export_public_key(&public_key, PublicKeyFormat::Der);
+        let info = der(0x30, &[der(0x02, &[0]), subject, spki, der(0xA0, &[])].concat());
+
+        let signature = keys.sign(IDENTITY_KEY, self.caller, &info)?;
+        let bits = [&[0u8][..], signature.to_der().as_bytes()].concat();
+        let algorithm = der(0x30, &der(0x06, &OID_ECDSA_SHA256));
+        Ok(der(0x30, &[info, algorithm, der(0x03, &bits)].concat()))
+    }
+
+    /// Store the certificate the CA issued for the CSR. It must carry the
+    /// identity public key; its chain is the CA's and the backend's
+    /// business.
+    pub fn store_certificate<K: KeyBlobStore>(
+        &mut self,
+        cap: &ProvisioningCapability,
+        keys: &mut KeyStore<K>,
+        der_cert: &[u8],
+    ) -> Result<(), IdentityError> {
+        self.check(cap)?;
+        if der_cert.is_empty() || der_cert.len() > MAX_CERT_LEN || der_cert[0] != 0x30 {
+            return Err(IdentityError::Malformed);
+        }
+        let point = identity_key(keys)?.to_encoded_point(false);
+        if !der_cert.windows(point.as_bytes().len()).any(|w| w == point.as_bytes()) {
+            return Err(IdentityError::CertificateMismatch);
+        }
+        let next = State { certificate: Some(der_cert.to_vec()), ..self.state.clone() };
+        self.persist(keys, next)
+    }
+
+    /// Close provisioning: the device goes to (or stays in) `Production`
+    /// and the capability is used up, along with any other of this
+    /// session.
+    pub fn lock<K: KeyBlobStore>(
+        &mut self,
+        cap: ProvisioningCapability,
+        keys: &mut KeyStore<K>,
+    ) -> Result<(), IdentityError> {
+        self.check(&cap)?;
+        if keys.kind(IDENTITY_KEY).is_err() || self.state.certificate.is_none() {
+            return Err(IdentityError::Incomplete);
+        }
+        let next = State { lifecycle: Lifecycle::Production, ..self.state.clone() };
+        self.persist(keys, next)?;
+        self.session += 1;
+        info!("provisioning locked (mode {})", cap.mode as u8);
+        Ok(())
+    }
+
+    fn check(&self, cap: &ProvisioningCapability) -> Result<(), IdentityError> {
+        if cap.session != self.session {
+            return Err(IdentityError::StaleCapability);
+        }
+        if !cap.mode.allows(self.state.lifecycle) {
+            return Err(IdentityError::WrongLifecycle(self.state.lifecycle));
+        }
+        Ok(())
+    }
+
+    /// Seal and save `next`; only then does it become the current state.
+    fn persist<K: KeyBlobStore>(&mut self, keys: &mut KeyStore<K>, next: State) -> Result<(), IdentityError> {
+        let sealed = seal(keys, self.caller, &self.device_id, &next)?;
+        self.store.save(&sealed).map_err(IdentityError::Storage)?;
+        self.state = next;
+        Ok(())
+    }
+}
+
+/// id-at-commonName (2.5.4.3)
+const OID_COMMON_NAME: [u8; 3] = [0x55, 0x04, 0x03];
+/// ecdsa-with-SHA256 (1.2.840.10045.4.3.2)
+const OID_ECDSA_SHA256: [u8; 8] = [0x2A, 0x86, 0x48, 0xCE, 0x3D, 0x04, 0x03, 0x02];
+
+/// DER TLV with a definite length.
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let len = content.len();
+    let mut out = Vec::with_capacity(len + 4);
+    out.push(tag);
+    match len {
+        0..=0x7F => out.push(len as u8),
+        0x80..=0xFF => out.extend_from_slice(&[0x81, len as u8]),
+        _ => out.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]),
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+fn identity_key<K: KeyBlobStore>(keys: &mut KeyStore<K>) -> Result<VerifyingKey, IdentityError> {
+    match keys.public_key(IDENTITY_KEY) {
+        Err(KeyStoreError::Unknown) => Err(IdentityError::NoKey),
+        other => Ok(other?),
+    }
+}
+
+/// `magic(4) | nonce(12) | AES-GCM(version(1) | lifecycle(1) | cert_len(2) | cert)`,
+/// with `magic | device_id` as associated data.
+fn seal<K: KeyBlobStore>(
+    keys: &mut KeyStore<K>,
+    caller: Caller,
+    device_id: &str,
+    state: &State,
+) -> Result<Vec<u8>, IdentityError> {
+    let cert = state.certificate.as_deref().unwrap_or(&[]);
+    let mut plain = vec![FORMAT_VERSION, state.lifecycle as u8];
+    plain.extend_from_slice(&(cert.len() as u16).to_le_bytes());
+    plain.extend_from_slice(cert);
+
+    let mut nonce = [0u8; GCM_NONCE_LEN];
+    crypto::rng::fill_random(&mut nonce).map_err(|_| IdentityError::Rng)?;
+    let aad = [&MAGIC[..], device_id.as_bytes()].concat();
+    let sealed = keys.seal(STATE_KEY, caller, &nonce, &aad, &plain)?;
+    Ok([&MAGIC[..], &nonce, &sealed].concat())
+}
+
+fn unseal<K: KeyBlobStore>(
+    keys: &mut KeyStore<K>,
+    caller: Caller,
+    device_id: &str,
+    data: &[u8],
+) -> Result<State, IdentityError> {
+    if data.len() < MAGIC.len() + GCM_NONCE_LEN || data[..4] != MAGIC {
+        return Err(IdentityError::Corrupt);
+    }
+    let mut nonce = [0u8; GCM_NONCE_LEN];
+    nonce.copy_from_slice(&data[4..4 + GCM_NONCE_LEN]);
+    let aad = [&MAGIC[..], device_id.as_bytes()].concat();
+    let plain = match keys.open_sealed(STATE_KEY, caller, &nonce, &aad, &data[4 + GCM_NONCE_LEN..]) {
+        Ok(plain) => plain,
+        Err(KeyStoreError::Crypto(_) | KeyStoreError::Unknown) => return Err(IdentityError::Corrupt),
+        Err(e) => return Err(e.into()),
+    };
+    if plain.len() < 4 || plain[0] != FORMAT_VERSION {
+        return Err(IdentityError::Corrupt);
+    }
+    let lifecycle = Lifecycle::from_u8(plain[1]).ok_or(IdentityError::Corrupt)?;
+    let cert_len = usize::from(u16::from_le_bytes([plain[2], plain[3]]));
+    if plain.len() != 4 + cert_len {
+        return Err(IdentityError::Corrupt);
+    }
+    let certificate = (cert_len > 0).then(|| plain[4..].to_vec());
+    Ok(State { lifecycle, certificate })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::ecc::KeyPair;
+    use secure_storage::key_store::Kek;
+
+    #[derive(Default)]
+    struct RamStore {
+        data: Option<Vec<u8>>,
+    }
+
+    impl IdentityStore for &mut RamStore {
+        fn load(&mut self) -> Result<Option<Vec<u8>>, &'static str> {
+            Ok(self.data.clone())
+        }
+        fn save(&mut self, data: &[u8]) -> Result<(), &'static str> {
+            self.data = Some(data.to_vec());
+            Ok(())
+        }
+    }
+
+    impl KeyBlobStore for &mut RamStore {
+        fn load(&mut self) -> Result<Option<Vec<u8>>, &'static str> {
+            Ok(self.data.clone())
+        }
+        fn save(&mut self, data: &[u8]) -> Result<(), &'static str> {
+            self.data = Some(data.to_vec());
+            Ok(())
+        }
+    }
+
+    const DEVICE: &str = "dev-0001";
+    const TASK: Caller = 4;
+
+    fn authority(seed: u8) -> KeyPair {
+        KeyPair::from_private_bytes(&[seed; 32]).unwrap()
+    }
+
+    fn authorities() -> Authorities {
+        Authorities { factory: authority(1).public_key(), field: authority(2).public_key() }
+    }
+
+    fn grant(signer: &KeyPair, mode: Mode, nonce: &[u8; NONCE_LEN], device: &str) -> Vec<u8> {
+        let mut body = vec![mode as u8];
+        body.extend_from_slice(nonce);
+        body.push(device.len() as u8);
+        body.extend_from_slice(device.as_bytes());
+        let sig = signer.sign(&[GRANT_CONTEXT, &body].concat());
+        [body, sig.as_ref().to_vec()].concat()
+    }
+
+    /// A stand-in for the CA: a "certificate" that embeds the public key.
+    fn certificate_for(key: &VerifyingKey) -> Vec<u8> {
+        der(0x30, &der(0x03, &[&[0u8][..], key.to_encoded_point(false).as_bytes()].concat()))
+    }
+
+    /// Split one DER TLV off the front of `bytes`.
+    fn tlv(bytes: &[u8]) -> (&[u8], &[u8], &[u8]) {
+        let (len, hdr) = match bytes[1] {
+            0x81 => (usize::from(bytes[2]), 3),
+            0x82 => (usize::from(u16::from_be_bytes([bytes[2], bytes[3]])), 4),
+            l => (usize::from(l), 2),
+        };
+        (&bytes[..hdr + len], &bytes[hdr..hdr + len], &bytes[hdr + len..])
+    }
+
+    fn open<'a>(state: &'a mut RamStore, keys: &mut KeyStore<&mut RamStore>) -> DeviceIdentity<&'a mut RamStore> {
+        DeviceIdentity::open(state, keys, DEVICE, TASK, authorities()).unwrap()
+    }
+
+    #[test]
+    fn factory_flow_generates_key_issues_csr_and_locks() {
+        let (mut state, mut key_image) = (RamStore::default(), RamStore::default());
+        let mut keys = KeyStore::open(&mut key_image, Kek::new(&[9; 32]), 8).unwrap();
+        let public = {
+            let mut id = open(&mut state, &mut keys);
+            assert_eq!(id.lifecycle(), Lifecycle::Blank);
+            let nonce = id.challenge().unwrap();
+            let cap = id.authorize(&mut keys, &grant(&authority(1), Mode::Factory, &nonce, DEVICE)).unwrap();
+            assert_eq!(id.lifecycle(), Lifecycle::Provisioning);
+
+            let public = id.generate_key(&cap, &mut keys).unwrap();
+            let csr = id.csr(&cap, &mut keys).unwrap();
+            let (_, body, _) = tlv(&csr);
+            let (info, info_body, rest) = tlv(body);
+            assert!(info_body.windows(DEVICE.len()).any(|w| w == DEVICE.as_bytes()));
+            let (_, _, rest) = tlv(rest);
+            let (_, bits, _) = tlv(rest);
+            let sig = ecc::parse_signature(&bits[1..], SignatureFormat::Der).unwrap();
+            ecc::verify(&public, info, &sig).unwrap();
+
+            let stale = cap;
+            let cap = authority_cap(&mut id, &mut keys);
+            assert_eq!(id.lock(stale, &mut keys), Err(IdentityError::StaleCapability));
+            assert_eq!(id.lock(cap, &mut keys), Err(IdentityError::Incomplete));
+            let cap = authority_cap(&mut id, &mut keys);
+            id.store_certificate(&cap, &mut keys, &certificate_for(&public)).unwrap();
+            id.lock(cap, &mut keys).unwrap();
+            public
+        };
+
+        // Reboot: locked, certificate kept, factory grants refused.
+        let mut id = open(&mut state, &mut keys);
+        assert_eq!(id.lifecycle(), Lifecycle::Production);
+        assert_eq!(id.certificate(), Some(&certificate_for(&public)[..]));
+        let nonce = id.challenge().unwrap();
+        let again = grant(&authority(1), Mode::Factory, &nonce, DEVICE);
+        assert_eq!(id.authorize(&mut keys, &again).err(), Some(IdentityError::WrongLifecycle(Lifecycle::Production)));
+        assert_eq!(
+            keys.with_key(IDENTITY_KEY, TASK, secure_storage::key_store::Operation::Export, |_| ()).err(),
+            Some(KeyStoreError::Denied)
+        );
+    }
+
+    /// A fresh factory capability (the previous one goes stale).
+    fn authority_cap(
+        id: &mut DeviceIdentity<&mut RamStore>,
+        keys: &mut KeyStore<&mut RamStore>,
+    ) -> ProvisioningCapability {
+        let nonce = id.challenge().unwrap();
+        id.authorize(keys, &grant(&authority(1), Mode::Factory, &nonce, DEVICE)).unwrap()
+    }
+
+    #[test]
+    fn grants_are_bound_to_authority_nonce_and_device() {
+        let (mut state, mut key_image) = (RamStore::default(), RamStore::default());
+        let mut keys = KeyStore::open(&mut key_image, Kek::new(&[9; 32]), 8).unwrap();
+        let mut id = open(&mut state, &mut keys);
+
+        let nonce = id.challenge().unwrap();
+        // Field key signing a factory grant, another device, a stale nonce.
+        for g in [
+            grant(&authority(2), Mode::Factory, &nonce, DEVICE),
+            grant(&authority(1), Mode::Factory, &nonce, "dev-0002"),
+            grant(&authority(1), Mode::Factory, &[0; NONCE_LEN], DEVICE),
+        ] {
+            id.challenge = Some(nonce);
+            assert_eq!(id.authorize(&mut keys, &g).err(), Some(IdentityError::NotAuthorized));
+        }
+        // Each challenge is good for one attempt.
+        let good = grant(&authority(1), Mode::Factory, &nonce, DEVICE);
+        assert_eq!(id.authorize(&mut keys, &good).err(), Some(IdentityError::NotAuthorized));
+        assert_eq!(id.lifecycle(), Lifecycle::Blank);
+    }
+
+    #[test]
+    fn field_mode_renews_a_production_device() {
+        let (mut state, mut key_image) = (RamStore::default(), RamStore::default());
+        let mut keys = KeyStore::open(&mut key_image, Kek::new(&[9; 32]), 8).unwrap();
+        let mut id = open(&mut state, &mut keys);
+
+        // Shipped blank, identity bound at installation.
+        let nonce = id.challenge().unwrap();
+        let cap = id.authorize(&mut keys, &grant(&authority(2), Mode::Field, &nonce, DEVICE)).unwrap();
+        assert_eq!(id.lifecycle(), Lifecycle::Blank);
+        let first = id.generate_key(&cap, &mut keys).unwrap();
+        id.store_certificate(&cap, &mut keys, &certificate_for(&first)).unwrap();
+        id.lock(cap, &mut keys).unwrap();
+        assert_eq!(id.lifecycle(), Lifecycle::Production);
+
+        // Renewal: a new key drops the old certificate until the new one is stored.
+        let nonce = id.challenge().unwrap();
+        let cap = id.authorize(&mut keys, &grant(&authority(2), Mode::Field, &nonce, DEVICE)).unwrap();
+        let second = id.generate_key(&cap, &mut keys).unwrap();
+        assert_ne!(first, second);
+        assert_eq!(id.certificate(), None);
+        assert_eq!(
+            id.store_certificate(&cap, &mut keys, &certificate_for(&first)),
+            Err(IdentityError::CertificateMismatch)
+        );
+        id.store_certificate(&cap, &mut keys, &certificate_for(&second)).unwrap();
+        id.lock(cap, &mut keys).unwrap();
+
+        // A tampered state record does not load.
+        state.data.as_mut().unwrap()[20] ^= 1;
+        assert_eq!(
+            DeviceIdentity::open(&mut state, &mut keys, DEVICE, TASK, authorities()).err(),
+            Some(IdentityError::Corrupt)
+        );
+    }
+}
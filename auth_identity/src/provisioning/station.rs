@@ -0,0 +1,494 @@
+//! SecureIoTOS Authentication & Identity Provisioning Station Module
+//! -----------------------------------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Host side of the factory provisioning flow (std only, `host` feature).
+//!
+//! A device in provisioning mode listens on its serial / USB CDC port for
+//! framed commands; `Provisioner` drives it over anything that is
+//! `Read + Write` (usually the opened tty, e.g. `/dev/ttyACM0`):
+//!
+//! 1. `hello()`: protocol version, device ID and lifecycle state; the
+//!    device must be in `Lifecycle::Provisioning`;
+//! 2. `inject_key()`: keys wrapped by the factory HSM for this device. The
+//!    host only ever sees the wrapped blob; the device unwraps it;
+//! 3. `write_cert()`: certificates, sent in `MAX_CHUNK` pieces;
+//! 4. `attest()`: a fresh challenge; the signed report must verify
+//!    against the device's public key, echo the nonce and name a released
+//!    build (see `attestation`);
+//! 5. `set_lifecycle(Production)`: only after all of the above, since it
+//!    locks provisioning for good.
+//!
+//! `Provisioner::run()` performs exactly this sequence and returns the
+//! `ProvisioningRecord` to store in the factory database.
+//!
+//! Frames, both directions (little-endian):
+//!
+//! ```text
+//!   "PV" | code(1) | seq(1) | len(2) | payload(len) | crc32(4)
+//! ```
+//!
+//! `code` is the `Command` in requests and the status (`STATUS_OK` or a
+//! device error code) in responses; `seq` is echoed back; the CRC-32 covers
+//! everything before it.
+
+use std::io::{self, Read, Write};
+
+use rand::RngCore;
+
+use super::{Lifecycle, SIGNATURE_LEN};
+use crate::attestation::{BUILD_ID_LEN, NONCE_LEN, REPORT_LEN, REPORT_VERSION};
+
+/// Frame magic.
+const SYNC: [u8; 2] = *b"PV";
+/// `SYNC | code | seq | len`
+const HEADER_LEN: usize = 6;
+/// Version of the provisioning protocol spoken here.
+pub const PROTOCOL_VERSION: u8 = 1;
+/// Largest frame payload.
+pub const MAX_PAYLOAD: usize = 1024;
+/// Certificate bytes per `WriteCert` frame (`slot(1) | offset(2) | last(1)` header).
+pub const MAX_CHUNK: usize = MAX_PAYLOAD - 4;
+/// Response status of a successful command.
+pub const STATUS_OK: u8 = 0;
+
+/// Provisioning commands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Command {
+    Hello = 0x01,
+    InjectKey = 0x10,
+    WriteCert = 0x11,
+    SetLifecycle = 0x12,
+    PublicKey = 0x13,
+    Attest = 0x14,
+}
+
+/// Why a provisioning step failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProvisionError {
+    /// Transport read/write failed
+    Io(io::ErrorKind),
+    /// Response is not a well-formed frame
+    BadFrame,
+    /// Response CRC does not match
+    Checksum,
+    /// Response answers another request
+    Sequence,
+    /// Device rejected the command with this status
+    Device(u8),
+    /// Device speaks another protocol version
+    Version(u8),
+    /// Device is not in the lifecycle state the step needs
+    WrongLifecycle(Lifecycle),
+    /// Lifecycle states only move forward
+    BadTransition,
+    /// Payload exceeds `MAX_PAYLOAD` (or the certificate 64 KiB)
+    TooLarge,
+    /// Attestation signature does not verify against the device key
+    BadSignature,
+    /// Attestation report does not echo our challenge
+    NonceMismatch,
+    /// Attestation report names a build that was not released
+    UnknownBuild,
+}
+
+impl From<io::Error> for ProvisionError {
+    fn from(e: io::Error) -> Self {
+        ProvisionError::Io(e.kind())
+    }
+}
+
+/// A key wrapped by the factory HSM for one device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WrappedKey {
+    /// Device key slot to unwrap it into
+    pub slot: u8,
+    /// Which device key-encryption key the blob is wrapped with
+    pub kek_id: u8,
+    /// Wrapped key material, opaque to the host
+    pub blob: Vec<u8>,
+}
+
+/// Certificate for a device certificate slot (DER).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Certificate {
+    pub slot: u8,
+    pub der: Vec<u8>,
+}
+
+/// Everything one device gets at the station.
+#[derive(Debug, Clone, Default)]
+pub struct ProvisioningPlan {
+    pub keys: Vec<WrappedKey>,
+    pub certs: Vec<Certificate>,
+    /// Build IDs the device may be running
+    pub released_builds: Vec<[u8; BUILD_ID_LEN]>,
+}
+
+/// Device answer to `hello()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub protocol: u8,
+    pub lifecycle: Lifecycle,
+    pub device_id: Vec<u8>,
+}
+
+/// Verified attestation, the fields the station cares about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attested {
+    pub build_id: [u8; BUILD_ID_LEN],
+    /// `attestation::FLAG_*` bits
+    pub flags: u8,
+    /// Signed report, as received
+    pub report: [u8; REPORT_LEN],
+}
+
+/// What the factory database keeps about a provisioned device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProvisioningRecord {
+    pub device_id: Vec<u8>,
+    /// SEC1 public key of the device attestation key
+    pub public_key: Vec<u8>,
+    pub attested: Attested,
+}
+
+/// Drives one device in provisioning mode over `port`.
+pub struct Provisioner<T: Read + Write> {
+    port: T,
+    seq: u8,
+}
+
+impl<T: Read + Write> Provisioner<T> {
+    pub fn new(port: T) -> Self {
+        Self { port, seq: 0 }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.port
+    }
+
+    /// Version, lifecycle state and device ID.
+    pub fn hello(&mut self) -> Result<DeviceInfo, ProvisionError> {
+        let resp = self.request(Command::Hello, &[])?;
+        let (&protocol, rest) = resp.split_first().ok_or(ProvisionError::BadFrame)?;
+        if protocol != PROTOCOL_VERSION {
+            return Err(ProvisionError::Version(protocol));
+        }
+        let (&state, device_id) = rest.split_first().ok_or(ProvisionError::BadFrame)?;
+        let lifecycle = Lifecycle::from_u8(state).ok_or(ProvisionError::BadFrame)?;
+        Ok(DeviceInfo { protocol, lifecycle, device_id: device_id.to_vec() })
+    }
+
+    /// Hand a wrapped key to the device: `slot(1) | kek_id(1) | blob`.
+    pub fn inject_key(&mut self, key: &WrappedKey) -> Result<(), ProvisionError> {
+        let mut payload = vec![key.slot, key.kek_id];
+        payload.extend_from_slice(&key.blob);
+        self.request(Command::InjectKey, &payload).map(drop)
+    }
+
+    /// Write a certificate in `MAX_CHUNK` pieces:
+    /// `slot(1) | offset(2) | last(1) | bytes`.
+    pub fn write_cert(&mut self, cert: &Certificate) -> Result<(), ProvisionError> {
+        if cert.der.is_empty() || cert.der.len() > usize::from(u16::MAX) {
+            return Err(ProvisionError::TooLarge);
+        }
+        let chunks = cert.der.chunks(MAX_CHUNK).count();
+        for (i, chunk) in cert.der.chunks(MAX_CHUNK).enumerate() {
+            let offset = (i * MAX_CHUNK) as u16;
+            let mut payload = vec![cert.slot];
+            payload.extend_from_slice(&offset.to_le_bytes());
+            payload.push((i + 1 == chunks) as u8);
+            payload.extend_from_slice(chunk);
+            self.request(Command::WriteCert, &payload)?;
+        }
+        Ok(())
+    }
+
+    /// Move the device to `state`; refused locally if it would go back.
+    pub fn set_lifecycle(&mut self, current: Lifecycle, state: Lifecycle) -> Result<(), ProvisionError> {
+        if state <= current {
+            return Err(ProvisionError::BadTransition);
+        }
+        self.request(Command::SetLifecycle, &[state as u8]).map(drop)
+    }
+
+    /// SEC1 public key of the device attestation key.
+    pub fn public_key(&mut self) -> Result<Vec<u8>, ProvisionError> {
+        self.request(Command::PublicKey, &[])
+    }
+
+    /// Challenge the device with `nonce` and verify the signed report
+    /// (`report | r || s`) against `public_key` and `released_builds`.
+    pub fn attest(
+        &mut self,
+        nonce: &[u8; NONCE_LEN],
+        public_key: &[u8],
+        released_builds: &[[u8; BUILD_ID_LEN]],
+    ) -> Result<Attested, ProvisionError> {
+        let resp = self.request(Command::Attest, nonce)?;
+        if resp.len() != REPORT_LEN + SIGNATURE_LEN {
+            return Err(ProvisionError::BadFrame);
+        }
+        let (report, sig) = resp.split_at(REPORT_LEN);
+        if !crypto::ecc::verify_message(public_key, report, sig) {
+            return Err(ProvisionError::BadSignature);
+        }
+
+        // version(1) | nonce(16) | build_id(20) | flags(1) | timestamp
+        if report[0] != REPORT_VERSION {
+            return Err(ProvisionError::BadFrame);
+        }
+        if !crypto::ct::eq(&report[1..1 + NONCE_LEN], &nonce[..]) {
+            return Err(ProvisionError::NonceMismatch);
+        }
+        let mut build_id = [0u8; BUILD_ID_LEN];
+        build_id.copy_from_slice(&report[1 + NONCE_LEN..1 + NONCE_LEN + BUILD_ID_LEN]);
+        if !released_builds.contains(&build_id) {
+            return Err(ProvisionError::UnknownBuild);
+        }
+        let mut signed = [0u8; REPORT_LEN];
+        signed.copy_from_slice(report);
+        Ok(Attested { build_id, flags: report[1 + NONCE_LEN + BUILD_ID_LEN], report: signed })
+    }
+
+    /// The whole factory flow for one device; see the module docs. The
+    /// device is locked (`Production`) only after attestation passed.
+    pub fn run(&mut self, plan: &ProvisioningPlan) -> Result<ProvisioningRecord, ProvisionError> {
+        let info = self.hello()?;
+        if info.lifecycle != Lifecycle::Provisioning {
+            return Err(ProvisionError::WrongLifecycle(info.lifecycle));
+        }
+        for key in &plan.keys {
+            self.inject_key(key)?;
+        }
+        for cert in &plan.certs {
+            self.write_cert(cert)?;
+        }
+        let public_key = self.public_key()?;
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let attested = self.attest(&nonce, &public_key, &plan.released_builds)?;
+        self.set_lifecycle(info.lifecycle, Lifecycle::Production)?;
+        Ok(ProvisioningRecord { device_id: info.device_id, public_key, attested })
+    }
+
+    /// Send one command and return the payload of its `STATUS_OK` answer.
+    fn request(&mut self, cmd: Command, payload: &[u8]) -> Result<Vec<u8>, ProvisionError> {
+        self.seq = self.seq.wrapping_add(1);
+        let frame = encode_frame(cmd as u8, self.seq, payload)?;
+        self.port.write_all(&frame)?;
+        self.port.flush()?;
+
+        let (status, seq, payload) = read_frame(&mut self.port)?;
+        if seq != self.seq {
+            return Err(ProvisionError::Sequence);
+        }
+        if status != STATUS_OK {
+            return Err(ProvisionError::Device(status));
+        }
+        Ok(payload)
+    }
+}
+
+/// `SYNC | code | seq | len | payload | crc32`.
+pub fn encode_frame(code: u8, seq: u8, payload: &[u8]) -> Result<Vec<u8>, ProvisionError> {
+    if payload.len() > MAX_PAYLOAD {
+        return Err(ProvisionError::TooLarge);
+    }
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len() + 4);
+    frame.extend_from_slice(&SYNC);
+    frame.extend_from_slice(&[code, seq]);
+    frame.extend_from_slice(&(payload.len() as u16).to_le_bytes());
+    frame.extend_from_slice(payload);
+    let crc = crc32(&frame);
+    frame.extend_from_slice(&crc.to_le_bytes());
+    Ok(frame)
+}
+
+/// Read one frame; returns `(code, seq, payload)`.
+pub fn read_frame(port: &mut impl Read) -> Result<(u8, u8, Vec<u8>), ProvisionError> {
+    let mut header = [0u8; HEADER_LEN];
+    port.read_exact(&mut header)?;
+    if header[..2] != SYNC {
+        return Err(ProvisionError::BadFrame);
+    }
+    let len = usize::from(u16::from_le_bytes([header[4], header[5]]));
+    if len > MAX_PAYLOAD {
+        return Err(ProvisionError::BadFrame);
+    }
+    let mut rest = vec![0u8; len + 4];
+    port.read_exact(&mut rest)?;
+    let (payload, crc) = rest.split_at(len);
+    let mut covered = header.to_vec();
+    covered.extend_from_slice(payload);
+    if crc32(&covered).to_le_bytes() != crc {
+        return Err(ProvisionError::Checksum);
+    }
+    Ok((header[2], header[3], payload.to_vec()))
+}
+
+/// CRC-32 (IEEE 802.3, reflected), bitwise: frames are small.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attestation::AttestationReport;
+    use crate::timestamp::{ArtifactKind, BootSession, Timestamp};
+    use p256::ecdsa::signature::Signer;
+    use p256::ecdsa::{Signature, SigningKey};
+    use std::collections::VecDeque;
+
+    const BUILD: [u8; BUILD_ID_LEN] = [0x42; BUILD_ID_LEN];
+
+    /// Device in provisioning mode: answers each request frame written to
+    /// it with a response frame to read back.
+    struct Device {
+        key: SigningKey,
+        lifecycle: Lifecycle,
+        keys: Vec<(u8, Vec<u8>)>,
+        cert: Vec<u8>,
+        /// Answer attestation with this nonce instead of the challenge
+        stale_nonce: Option<[u8; NONCE_LEN]>,
+        out: VecDeque<u8>,
+    }
+
+    impl Device {
+        fn new() -> Self {
+            Self {
+                key: SigningKey::from_bytes(&[7u8; 32]).unwrap(),
+                lifecycle: Lifecycle::Provisioning,
+                keys: Vec::new(),
+                cert: Vec::new(),
+                stale_nonce: None,
+                out: VecDeque::new(),
+            }
+        }
+
+        fn handle(&mut self, cmd: u8, p: &[u8]) -> (u8, Vec<u8>) {
+            match cmd {
+                0x01 => (STATUS_OK, [&[PROTOCOL_VERSION, self.lifecycle as u8][..], b"dev-0001"].concat()),
+                0x10 => {
+                    self.keys.push((p[0], p[2..].to_vec()));
+                    (STATUS_OK, vec![])
+                }
+                0x11 => {
+                    let offset = usize::from(u16::from_le_bytes([p[1], p[2]]));
+                    assert_eq!(offset, self.cert.len(), "chunks in order");
+                    self.cert.extend_from_slice(&p[4..]);
+                    (STATUS_OK, vec![])
+                }
+                0x12 => {
+                    self.lifecycle = Lifecycle::from_u8(p[0]).unwrap();
+                    (STATUS_OK, vec![])
+                }
+                0x13 => (STATUS_OK, self.key.verifying_key().to_encoded_point(true).as_bytes().to_vec()),
+                0x14 => {
+                    let report = AttestationReport {
+                        nonce: self.stale_nonce.unwrap_or_else(|| p.try_into().unwrap()),
+                        build_id: BUILD,
+                        flags: 0,
+                        timestamp: Timestamp {
+                            kind: ArtifactKind::Attestation,
+                            session: BootSession { boot_count: 1, nonce: 2 },
+                            counter: 0,
+                            wall_clock_ms: None,
+                        },
+                    };
+                    let body = report.to_bytes();
+                    let sig: Signature = self.key.sign(&body);
+                    (STATUS_OK, [&body[..], sig.as_ref()].concat())
+                }
+                _ => (0xFF, vec![]),
+            }
+        }
+    }
+
+    impl Write for Device {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let (cmd, seq, payload) = read_frame(&mut &buf[..]).unwrap();
+            let (status, resp) = self.handle(cmd, &payload);
+            self.out.extend(encode_frame(status, seq, &resp).unwrap());
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Read for Device {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.out.read(buf)
+        }
+    }
+
+    fn plan() -> ProvisioningPlan {
+        ProvisioningPlan {
+            keys: vec![WrappedKey { slot: 1, kek_id: 0, blob: vec![0xAA; 40] }],
+            certs: vec![Certificate { slot: 0, der: (0..2500u32).map(|i| i as u8).collect() }],
+            released_builds: vec![BUILD],
+        }
+    }
+
+    #[test]
+    fn full_flow_provisions_and_locks() {
+        let mut p = Provisioner::new(Device::new());
+        let record = p.run(&plan()).unwrap();
+        assert_eq!(record.device_id, b"dev-0001");
+        assert_eq!(record.attested.build_id, BUILD);
+
+        let dev = p.into_inner();
+        assert_eq!(dev.lifecycle, Lifecycle::Production);
+        assert_eq!(dev.keys, [(1, vec![0xAA; 40])]);
+        assert_eq!(dev.cert, plan().certs[0].der, "reassembled from three chunks");
+
+        // Provisioning is locked from now on
+        let mut p = Provisioner::new(dev);
+        assert_eq!(p.run(&plan()).unwrap_err(), ProvisionError::WrongLifecycle(Lifecycle::Production));
+        assert_eq!(
+            p.set_lifecycle(Lifecycle::Production, Lifecycle::Provisioning),
+            Err(ProvisionError::BadTransition)
+        );
+    }
+
+    #[test]
+    fn failed_attestation_leaves_device_unlocked() {
+        let mut dev = Device::new();
+        dev.stale_nonce = Some([0; NONCE_LEN]);
+        let mut p = Provisioner::new(dev);
+        assert_eq!(p.run(&plan()).unwrap_err(), ProvisionError::NonceMismatch);
+        assert_eq!(p.into_inner().lifecycle, Lifecycle::Provisioning);
+
+        let mut p = Provisioner::new(Device::new());
+        let other = SigningKey::from_bytes(&[9u8; 32]).unwrap();
+        let wrong_key = other.verifying_key().to_encoded_point(true).as_bytes().to_vec();
+        assert_eq!(p.attest(&[1; NONCE_LEN], &wrong_key, &[BUILD]), Err(ProvisionError::BadSignature));
+        let key = p.public_key().unwrap();
+        assert_eq!(p.attest(&[1; NONCE_LEN], &key, &[]), Err(ProvisionError::UnknownBuild));
+    }
+
+    #[test]
+    fn corrupt_frames_are_rejected() {
+        let mut frame = encode_frame(STATUS_OK, 3, b"abc").unwrap();
+        assert_eq!(read_frame(&mut &frame[..]).unwrap(), (STATUS_OK, 3, b"abc".to_vec()));
+        frame[7] ^= 1;
+        assert_eq!(read_frame(&mut &frame[..]), Err(ProvisionError::Checksum));
+        assert_eq!(read_frame(&mut &frame[..3]), Err(ProvisionError::Io(io::ErrorKind::UnexpectedEof)));
+        assert_eq!(encode_frame(1, 0, &[0; MAX_PAYLOAD + 1]), Err(ProvisionError::TooLarge));
+    }
+}
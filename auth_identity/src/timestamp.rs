@@ -0,0 +1,272 @@
+//! SecureIoTOS Authentication & Identity Timestamp Module
+//! ------------------------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Unified time-stamping for outbound security artifacts (telemetry, audit
+//! entries, attestation reports).
+//!
+//! Every stamp carries:
+//! - the boot session (persistent boot counter + random per-boot nonce),
+//! - a monotonic counter that never repeats within the session,
+//! - synced wall-clock time, when it is available.
+//!
+//! `(boot_count, counter)` gives backends a total order across reboots even
+//! when the RTC is unset or jumps. Wall-clock time is informational only.
+//!
+//! Boot calls `init_timestamping()` once the boot counter is known; the
+//! artifact paths then stamp through `stamp()`:
+//! - attestation: `attestation::Attester::respond_now()`,
+//! - telemetry: `secure_communication::store_forward::StoreAndForward`
+//!   built with `stamped()`, which prefixes each message when it is queued,
+//! - audit: the kernel's audit hook, which cannot depend on this crate:
+//!
+//! ```ignore
+//! fn persist_audit(record: &kernel::runtime_monitor::AuditRecord) {
+//!     if let Ok(ts) = timestamp::stamp(ArtifactKind::Audit) {
+//!         audit_store.append(&ts.to_bytes(), record);
+//!     }
+//! }
+//! kernel::runtime_monitor::set_audit_hook(persist_audit)?;
+//! ```
+
+use core::cell::RefCell;
+use core::cmp::Ordering;
+use critical_section::Mutex;
+
+/// Size of the fixed binary encoding produced by `Timestamp::to_bytes()`.
+pub const TIMESTAMP_LEN: usize = 25;
+
+/// Kind of artifact being stamped (bound into the encoding so a stamp cannot
+/// be lifted from one artifact type onto another).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArtifactKind {
+    Telemetry = 1,
+    Audit = 2,
+    Attestation = 3,
+}
+
+/// Identifies one boot of the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootSession {
+    /// Persistent boot counter (monotonic across reboots, from secure storage)
+    pub boot_count: u32,
+    /// Random per-boot nonce; disambiguates sessions if the counter is rolled back
+    pub nonce: u32,
+}
+
+/// A stamp attached to an outbound artifact.
+#[derive(Debug, Clone, Copy)]
+pub struct Timestamp {
+    pub kind: ArtifactKind,
+    pub session: BootSession,
+    /// Monotonic per-session counter
+    pub counter: u64,
+    /// Unix time in milliseconds, `None` until the clock has been synced
+    pub wall_clock_ms: Option<u64>,
+}
+
+impl Timestamp {
+    /// Fixed big-endian encoding, suitable for inclusion in signed data:
+    /// `kind(1) | boot_count(4) | nonce(4) | counter(8) | wall_clock_ms(8)`.
+    /// An unsynced clock is encoded as 0.
+    pub fn to_bytes(&self) -> [u8; TIMESTAMP_LEN] {
+        let mut out = [0u8; TIMESTAMP_LEN];
+        out[0] = self.kind as u8;
+        out[1..5].copy_from_slice(&self.session.boot_count.to_be_bytes());
+        out[5..9].copy_from_slice(&self.session.nonce.to_be_bytes());
+        out[9..17].copy_from_slice(&self.counter.to_be_bytes());
+        out[17..25].copy_from_slice(&self.wall_clock_ms.unwrap_or(0).to_be_bytes());
+        out
+    }
+
+    /// What `Eq` and `Ord` compare. Within a session the counter alone
+    /// identifies a stamp; the nonce only separates sessions that share a
+    /// rolled-back boot count.
+    fn key(&self) -> (u32, u64, u32) {
+        (self.session.boot_count, self.counter, self.session.nonce)
+    }
+}
+
+/// Same stamp: same session and counter.
+impl PartialEq for Timestamp {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Timestamp {}
+
+/// Total order by (boot_count, counter); wall-clock time is ignored since it
+/// may be unset or reset.
+impl Ord for Timestamp {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+impl PartialOrd for Timestamp {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Errors from the time-stamping service.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampError {
+    /// `init_timestamping()` has not been called yet
+    Uninitialized,
+    /// Counter exhausted for this session; reboot required
+    CounterExhausted,
+}
+
+struct StampState {
+    session: BootSession,
+    counter: u64,
+    /// Monotonic uptime source (milliseconds since boot)
+    uptime_ms: fn() -> u64,
+    /// Wall-clock sync point: (unix_ms, uptime_ms at sync)
+    sync: Option<(u64, u64)>,
+}
+
+static STATE: Mutex<RefCell<Option<StampState>>> = Mutex::new(RefCell::new(None));
+
+/// Start the service for this boot.
+///
+/// `boot_count` should be the incremented persistent boot counter and
+/// `nonce` a fresh random value. `uptime_ms` is the kernel's monotonic tick
+/// source; it must not go backwards.
+pub fn init_timestamping(boot_count: u32, nonce: u32, uptime_ms: fn() -> u64) {
+    critical_section::with(|cs| {
+        *STATE.borrow(cs).borrow_mut() = Some(StampState {
+            session: BootSession { boot_count, nonce },
+            counter: 0,
+            uptime_ms,
+            sync: None,
+        });
+    });
+}
+
+/// Record a wall-clock sync (from SNTP, a backend response, an RTC, ...).
+///
+/// Later stamps derive wall time from this point and the uptime source, so
+/// a resync only changes `wall_clock_ms`, never the ordering fields.
+pub fn sync_wall_clock(unix_ms: u64) -> Result<(), TimestampError> {
+    critical_section::with(|cs| {
+        let mut guard = STATE.borrow(cs).borrow_mut();
+        let state = guard.as_mut().ok_or(TimestampError::Uninitialized)?;
+        state.sync = Some((unix_ms, (state.uptime_ms)()));
+        Ok(())
+    })
+}
+
+/// Forget the wall-clock sync (e.g. RTC lost power or sync is distrusted).
+pub fn invalidate_wall_clock() {
+    critical_section::with(|cs| {
+        if let Some(state) = STATE.borrow(cs).borrow_mut().as_mut() {
+            state.sync = None;
+        }
+    });
+}
+
+/// Current boot session, if the service is running.
+pub fn boot_session() -> Option<BootSession> {
+    critical_section::with(|cs| STATE.borrow(cs).borrow().as_ref().map(|s| s.session))
+}
+
+/// Produce the next stamp for an artifact of `kind`.
+pub fn stamp(kind: ArtifactKind) -> Result<Timestamp, TimestampError> {
+    critical_section::with(|cs| {
+        let mut guard = STATE.borrow(cs).borrow_mut();
+        let state = guard.as_mut().ok_or(TimestampError::Uninitialized)?;
+
+        let counter = state.counter.checked_add(1).ok_or(TimestampError::CounterExhausted)?;
+        state.counter = counter;
+
+        let now = (state.uptime_ms)();
+        Ok(Timestamp {
+            kind,
+            session: state.session,
+            counter,
+            wall_clock_ms: state.sync.map(|sync| wall_clock_at(sync, now)),
+        })
+    })
+}
+
+/// Wall time at uptime `now`, given a sync point `(unix_ms, uptime_at_sync)`.
+fn wall_clock_at((unix_ms, synced_at): (u64, u64), now: u64) -> u64 {
+    unix_ms.saturating_add(now.saturating_sub(synced_at))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ts(boot_count: u32, counter: u64, wall: Option<u64>) -> Timestamp {
+        Timestamp {
+            kind: ArtifactKind::Audit,
+            session: BootSession { boot_count, nonce: 0xdead_beef },
+            counter,
+            wall_clock_ms: wall,
+        }
+    }
+
+    #[test]
+    fn orders_across_reboots_regardless_of_wall_clock() {
+        // Second boot has an earlier (reset) wall clock but must sort later.
+        let a = ts(1, 900, Some(1_700_000_000_000));
+        let b = ts(2, 1, Some(0));
+        let c = ts(2, 2, None);
+        assert!(a < b && b < c);
+    }
+
+    #[test]
+    fn equality_and_order_agree() {
+        // Kind and wall clock are not part of the identity
+        let a = ts(3, 7, Some(1));
+        let b = Timestamp { kind: ArtifactKind::Telemetry, ..ts(3, 7, None) };
+        assert_eq!(a, b);
+        assert_eq!(a.cmp(&b), Ordering::Equal);
+        // A rolled-back boot count with a new nonce is another stamp
+        let c = Timestamp { session: BootSession { boot_count: 3, nonce: 1 }, ..a };
+        assert_ne!(a, c);
+        assert_ne!(a.cmp(&c), Ordering::Equal);
+    }
+
+    #[test]
+    fn stamps_count_up_within_the_session() {
+        fn uptime() -> u64 {
+            40
+        }
+        init_timestamping(4, 0x5eed, uptime);
+        sync_wall_clock(1_000).unwrap();
+        let first = stamp(ArtifactKind::Telemetry).unwrap();
+        let second = stamp(ArtifactKind::Audit).unwrap();
+        assert!(first < second);
+        assert_eq!(second.session, BootSession { boot_count: 4, nonce: 0x5eed });
+        assert_eq!(second.wall_clock_ms, Some(1_000));
+        invalidate_wall_clock();
+        assert_eq!(stamp(ArtifactKind::Attestation).unwrap().wall_clock_ms, None);
+    }
+
+    #[test]
+    fn encoding_layout() {
+        let bytes = ts(7, 0x0102, Some(5)).to_bytes();
+        assert_eq!(bytes[0], ArtifactKind::Audit as u8);
+        assert_eq!(&bytes[1..5], &7u32.to_be_bytes());
+        assert_eq!(&bytes[5..9], &0xdead_beefu32.to_be_bytes());
+        assert_eq!(&bytes[9..17], &0x0102u64.to_be_bytes());
+        assert_eq!(&bytes[17..25], &5u64.to_be_bytes());
+    }
+
+    #[test]
+    fn wall_clock_advances_with_uptime() {
+        assert_eq!(wall_clock_at((1_000, 200), 450), 1_250);
+        // Uptime must not go backwards, but never underflow if it does.
+        assert_eq!(wall_clock_at((1_000, 200), 100), 1_000);
+    }
+}
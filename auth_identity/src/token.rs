@@ -16,10 +16,10 @@
 // the RefCell itself is immutable, but only at runtime.
 use core::cell::RefCell;
 
-// From the cortex-m crate, specifically for ARM Cortex-M microcontrollers.
-// A mutex designed for bare-metal embedded systems that disables interrupts 
-// while accessing the data, ensuring critical sections are safe.
-use cortex_m::interrupt::Mutex;
+// From the critical-section crate: a mutex whose critical sections disable
+// interrupts on the target (cortex-m's implementation) and take a std
+// mutex in host tests, ensuring exclusive access across interrupt contexts.
+use critical_section::Mutex;
 
 // These are from the p256 crate, which implements the NIST P-256 (a.k.a. secp256r1) elliptic curve:
 // SigningKey --> Holds the private key used to produce ECDSA signatures.
@@ -27,19 +27,22 @@ use cortex_m::interrupt::Mutex;
 // signature::Signer --> A trait (from the signature crate) that defines a sign() method.
 use p256::ecdsa::{SigningKey, Signature, signature::Signer};
 
-// A cryptographically secure random number generator (RNG) from the rand_core crate 
-use rand_core::OsRng;
+// A cryptographically secure random number generator (RNG), the OS one via the rand crate
+use rand::rngs::OsRng;
 
-static DEVICE_SIGNING_KEY: Mutex<RefCell<Option<SigningKey>>> = Mutex::new(RefCell::new(None));
+// Keeps the private key out of any `{:?}` / log output.
+use sios_log::Secret;
+
+static DEVICE_SIGNING_KEY: Mutex<RefCell<Option<Secret<SigningKey>>>> = Mutex::new(RefCell::new(None));
 
 /// Initialize the token module and optionally pre-generate persistent keys
 pub fn init_tokens() {
-    cortex_m::interrupt::free(|cs| {
+    critical_section::with(|cs| {
         let mut guard = DEVICE_SIGNING_KEY.borrow(cs).borrow_mut();
         if guard.is_none() {
             // In production, load key from secure element instead of generating
             let key = SigningKey::random(&mut OsRng);
-            *guard = Some(key);
+            *guard = Some(Secret::new(key));
         }
     });
 }
@@ -57,24 +60,36 @@ pub fn init_tokens() {
 /// * In production, this key must reside in hardware-backed storage.
 /// * Token is deterministic for the same key but unique per device ID.
 pub fn generate_device_token(device_id: u32) -> Signature {
-    cortex_m::interrupt::free(|cs| {
+    critical_section::with(|cs| {
         let guard = DEVICE_SIGNING_KEY.borrow(cs).borrow();
         let key = guard.as_ref().expect("Token module not initialized");
         let message = device_id.to_be_bytes();
-        key.sign(&message)
+        key.expose_secret().sign(&message)
+    })
+}
+
+/// Sign an attestation report with the device key.
+///
+/// The signature covers `report.to_bytes()`; the verifier checks it with
+/// the device's public key before looking at the build ID.
+pub fn sign_attestation(report: &crate::attestation::AttestationReport) -> Signature {
+    critical_section::with(|cs| {
+        let guard = DEVICE_SIGNING_KEY.borrow(cs).borrow();
+        let key = guard.as_ref().expect("Token module not initialized");
+        key.expose_secret().sign(&report.to_bytes())
     })
 }
 
 /// Optional: Rotate device key (requires re-issuing tokens)
 /// In production, securely rotate keys in the secure element
 pub fn rotate_device_key() {
-    cortex_m::interrupt::free(|cs| {
+    critical_section::with(|cs| {
         let mut guard = DEVICE_SIGNING_KEY.borrow(cs).borrow_mut();
         // Zeroize old key before replacing
         if let Some(old_key) = guard.take() {
             drop(old_key); // `SigningKey` drops private material safely
         }
         let new_key = SigningKey::random(&mut OsRng); // Replace with hardware-backed key in production
-        *guard = Some(new_key);
+        *guard = Some(Secret::new(new_key));
     });
 }
@@ -0,0 +1,66 @@
+//! SecureIoTOS Bootloader Build Script
+//! -----------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author : Md Mahbubur Rahman
+//! URL    : https://m-a-h-b-u-b.github.io
+//! GitHub : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Bakes the vendor key ring into the bootloader. `SIOS_VENDOR_PUBKEYS`
+//! names a file of uncompressed SEC1 P-256 points (65 bytes each,
+//! concatenated), in key index order: the signing key first, then the
+//! spares kept offline for revocation. For one key:
+//!
+//! ```bash
+//! openssl ec -in vendor.pem -pubout -outform DER | tail -c 65 > vendor_pubkeys.bin
+//! ```
+//!
+//! There is no placeholder: the build fails without the file, so a
+//! bootloader that trusts nothing (or everything) is never flashed.
+//!
+//! Also links the STM32G474 image (`main.rs`) against `memory.x`. Board
+//! examples using the library bring their own memory maps.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// `secure_storage::vendor_keys::VENDOR_KEY_LEN`
+const KEY_LEN: usize = 65;
+/// `secure_storage::vendor_keys::MAX_VENDOR_KEYS`
+const MAX_KEYS: usize = 32;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=SIOS_VENDOR_PUBKEYS");
+    println!("cargo:rerun-if-changed=build.rs");
+
+    let Some(path) = env::var_os("SIOS_VENDOR_PUBKEYS") else {
+        panic!("SIOS_VENDOR_PUBKEYS is not set: point it at the vendor public keys (see build.rs)");
+    };
+    println!("cargo:rerun-if-changed={}", PathBuf::from(&path).display());
+    let raw = fs::read(&path).expect("read SIOS_VENDOR_PUBKEYS");
+
+    if raw.is_empty() || raw.len() % KEY_LEN != 0 || raw.len() / KEY_LEN > MAX_KEYS {
+        panic!("SIOS_VENDOR_PUBKEYS: expected 1 to {MAX_KEYS} keys of {KEY_LEN} bytes, got {} bytes", raw.len());
+    }
+    let mut out = format!("pub const VENDOR_PUBKEYS: [[u8; VENDOR_KEY_LEN]; {}] = [\n", raw.len() / KEY_LEN);
+    for (i, key) in raw.chunks_exact(KEY_LEN).enumerate() {
+        if key[0] != 0x04 || key[1..].iter().all(|&b| b == 0) {
+            panic!("SIOS_VENDOR_PUBKEYS: key {i} is not an uncompressed SEC1 point");
+        }
+        if raw.chunks_exact(KEY_LEN).take(i).any(|k| k == key) {
+            panic!("SIOS_VENDOR_PUBKEYS: key {i} repeats an earlier key");
+        }
+        out.push_str(&format!("    {key:?},\n"));
+    }
+    out.push_str("];\n");
+
+    let dir = PathBuf::from(env::var_os("OUT_DIR").expect("cargo sets OUT_DIR"));
+    fs::write(dir.join("vendor_pubkeys.rs"), out).expect("write vendor keys");
+
+    fs::copy("memory.x", dir.join("memory.x")).expect("copy memory map");
+    println!("cargo:rustc-link-arg-bins=-L{}", dir.display());
+    println!("cargo:rustc-link-arg-bins=-Tlink.x");
+    println!("cargo:rerun-if-changed=memory.x");
+}
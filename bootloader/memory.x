@@ -0,0 +1,15 @@
+/* STM32G474 (512 KB flash, 96 KB SRAM1+SRAM2 contiguous), bootloader.
+ *
+ * Flash: this image in the first 64 KB; the boot control pages, the
+ * revocation area and the A/B slots follow (`Stm32g474::LAYOUT` in
+ * src/main.rs).
+ *
+ * RAM: the top 512 B are left out of both stages' RAM so neither runtime
+ * initializes them: the measured-boot event log at 0x20017E00 and the
+ * boot measurement record at 0x20017F00.
+ */
+MEMORY
+{
+  FLASH : ORIGIN = 0x08000000, LENGTH = 64K
+  RAM   : ORIGIN = 0x20000000, LENGTH = 96K - 512
+}
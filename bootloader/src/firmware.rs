@@ -6,14 +6,12 @@
 //! URL: https://m-a-h-b-u-b.github.io
 //! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
 
-// Import the SHA-256 hasher and its Digest trait (provides `update` and `finalize`)
-use sha2::{Sha256, Digest};
+// Streaming hashes (SHA-256 here) of the shared crypto crate
+use crypto::hash::{self, Algorithm};
 
 // Import ECDSA (Elliptic Curve Digital Signature Algorithm) primitives
 // from the P-256 curve implementation
-use p256::ecdsa::{Signature, VerifyingKey, signature::Verifier};
-
-use subtle::ConstantTimeEq;
+use p256::ecdsa::{Signature, VerifyingKey};
 
 /// Verify the integrity of the firmware by comparing its SHA-256 hash
 /// with the expected hash provided by a trusted source (e.g., secure server).
@@ -26,22 +24,26 @@ use subtle::ConstantTimeEq;
 /// * `true` if firmware hash matches `expected_hash`
 /// * `false` if mismatch (corrupted or tampered firmware)
 pub fn verify_firmware(firmware: &[u8], expected_hash: &[u8]) -> bool {
-	// Create a new SHA-256 context
-    let mut hasher = Sha256::new();     
-	// Feed firmware bytes into the hasher
-    hasher.update(firmware);            
-	// Compute final 32-byte hash (digest)
-    let result = hasher.finalize();     
-
-    
-	// vulnerable to timing attacks 
-    // result.as_slice() == expected_hash
-	
-	// Safe against timing attacks
-	// ct_eq compares every byte, regardless of mismatch position.
-	// Always takes the same time.
-	// Returns a Choice (boolean-like), which you convert with .into().
-    result.as_slice().ct_eq(expected_hash).into()
+    // Memory-mapped flash is hashed in place; nothing is copied to RAM.
+    // `matches` compares every byte, regardless of mismatch position,
+    // so it is safe against timing attacks.
+    hash::digest(Algorithm::Sha256, firmware).matches(expected_hash)
+}
+
+/// As `verify_firmware`, for an image that is not memory mapped (an OTA
+/// staging slot in external flash): `read(offset, buf)` fills `buf` from
+/// the slot, one `chunk` at a time.
+///
+/// # Returns
+/// * `Ok(true)` / `Ok(false)` as `verify_firmware`
+/// * `Err(e)` if a read failed
+pub fn verify_firmware_with<E>(
+    len: usize,
+    expected_hash: &[u8],
+    chunk: &mut [u8],
+    read: impl FnMut(usize, &mut [u8]) -> Result<(), E>,
+) -> Result<bool, E> {
+    Ok(hash::digest_reader(Algorithm::Sha256, len, chunk, read)?.matches(expected_hash))
 }
 
 /// Verify that the firmware was signed by a trusted source using ECDSA (P-256).
@@ -55,7 +57,166 @@ pub fn verify_firmware(firmware: &[u8], expected_hash: &[u8]) -> bool {
 /// * `true` if signature is valid (firmware is authentic)
 /// * `false` if invalid (forged or untrusted firmware)
 pub fn verify_signature(firmware: &[u8], sig: &Signature, pub_key: &VerifyingKey) -> bool {
-    // Uses the `Verifier` trait implementation on `VerifyingKey`
+    // Shared ECC surface: the same check the rest of the system uses
     // Returns `Ok(())` if signature is valid, error otherwise
-    pub_key.verify(firmware, sig).is_ok()
+    crypto::ecc::verify(pub_key, firmware, sig).is_ok()
+}
+
+/// Magic and layout of the image header the kernel embeds after its
+/// vector table (`kernel::image::ImageHeader`).
+const IMAGE_HEADER_MAGIC: [u8; 8] = *b"SIOSIMG\0";
+const IMAGE_HEADER_BUILD_ID_OFFSET: usize = 16;
+/// Length of a firmware build ID.
+pub const BUILD_ID_LEN: usize = 20;
+/// The header is searched for within this many bytes of the image start
+/// (the vector table of any supported part is smaller).
+const IMAGE_HEADER_SEARCH: usize = 4 * 1024;
+
+/// Find the build ID in a firmware image's header.
+///
+/// # Returns
+/// * `Some(build_id)` if an image header is found, word-aligned, within the
+///   first `IMAGE_HEADER_SEARCH` bytes
+/// * `None` if the image has no header (not a SecureIoTOS image, or built
+///   before build IDs were embedded)
+pub fn image_build_id(firmware: &[u8]) -> Option<[u8; BUILD_ID_LEN]> {
+    let search = &firmware[..firmware.len().min(IMAGE_HEADER_SEARCH)];
+    let start = (0..search.len()).step_by(4).find(|&i| search[i..].starts_with(&IMAGE_HEADER_MAGIC))?;
+    let at = start + IMAGE_HEADER_BUILD_ID_OFFSET;
+    firmware.get(at..at + BUILD_ID_LEN)?.try_into().ok()
+}
+
+/// Check, before installing an OTA image, that the binary is the build the
+/// update manifest announces. Run after `verify_signature()` has
+/// authenticated the manifest, so a mix-up of manifest and binary (or a
+/// stale binary re-served under a new manifest) is refused.
+///
+/// # Returns
+/// * `true` if the image header carries `manifest_build_id`
+/// * `false` if it carries another ID or has no header
+pub fn verify_build_id(firmware: &[u8], manifest_build_id: &[u8; BUILD_ID_LEN]) -> bool {
+    image_build_id(firmware).is_some_and(|id| id == *manifest_build_id)
+}
+
+/// Magic and size of the boot measurement record handed to the
+/// application (`auth_identity::attestation::BootMeasurement`).
+const MEASUREMENT_MAGIC: [u8; 8] = *b"SIOSMEAS";
+const MEASUREMENT_VERSION: u8 = 1;
+pub const MEASUREMENT_LEN: usize = 68;
+/// Boot flag: the image signature was verified before starting it.
+pub const BOOT_SIGNATURE_VERIFIED: u8 = 1 << 0;
+/// Boot flag: the debug port is locked.
+pub const BOOT_DEBUG_LOCKED: u8 = 1 << 1;
+
+/// Measure the image about to be started, for remote attestation: its
+/// SHA-256, length and build ID, with the `BOOT_*` flags describing how it
+/// was booted. The caller stores the record where the application finds
+/// it (a retained RAM slot both stages agree on).
+///
+/// # Returns
+/// * The record, laid out as
+///   `magic(8) | version(1) | boot_flags(1) | reserved(2) | image_len(4, LE) |
+///   firmware_hash(32) | build_id(20, zero if the image has no header)`
+pub fn measure(firmware: &[u8], boot_flags: u8) -> [u8; MEASUREMENT_LEN] {
+    let mut out = [0u8; MEASUREMENT_LEN];
+    out[..8].copy_from_slice(&MEASUREMENT_MAGIC);
+    out[8] = MEASUREMENT_VERSION;
+    out[9] = boot_flags;
+    out[12..16].copy_from_slice(&(firmware.len() as u32).to_le_bytes());
+    out[16..48].copy_from_slice(hash::digest(Algorithm::Sha256, firmware).as_bytes());
+    if let Some(id) = image_build_id(firmware) {
+        out[48..].copy_from_slice(&id);
+    }
+    out
+}
+
+/// Magic, version and limits of the measured-boot event log handed to the
+/// kernel (`kernel::measured_boot::BootLog`).
+const LOG_MAGIC: [u8; 8] = *b"SIOSMLOG";
+const LOG_VERSION: u8 = 1;
+const LOG_HEADER_LEN: usize = 12 + 32;
+const LOG_EVENT_LEN: usize = 4 + 32;
+/// Most events one boot records.
+pub const LOG_MAX_EVENTS: usize = 4;
+/// Size of the encoded log with every event slot used.
+pub const LOG_MAX_LEN: usize = LOG_HEADER_LEN + LOG_MAX_EVENTS * LOG_EVENT_LEN;
+/// Event kinds: the bootloader's own code, the firmware image, the device
+/// configuration (fuses, option bytes, lock bits).
+pub const EVENT_BOOTLOADER: u8 = 1;
+pub const EVENT_FIRMWARE: u8 = 2;
+pub const EVENT_CONFIG: u8 = 3;
+
+/// One step of the measurement chain: `SHA-256(pcr || digest)`.
+pub fn extend(pcr: &[u8; 32], digest: &[u8; 32]) -> [u8; 32] {
+    let mut h = hash::Hasher::new(Algorithm::Sha256);
+    h.update(pcr);
+    h.update(digest);
+    let mut out = [0u8; 32];
+    out.copy_from_slice(h.finalize().as_bytes());
+    out
+}
+
+/// Measured-boot event log. Each stage is measured before it runs and its
+/// digest extended into the chain register, which starts at zero; the
+/// kernel replays the chain when it takes over the log.
+pub struct MeasurementLog {
+    pcr: [u8; 32],
+    events: [(u8, [u8; 32]); LOG_MAX_EVENTS],
+    count: usize,
+}
+
+impl MeasurementLog {
+    pub fn new() -> Self {
+        Self { pcr: [0; 32], events: [(0, [0; 32]); LOG_MAX_EVENTS], count: 0 }
+    }
+
+    /// Hash `data` and record it as an event of `kind`.
+    ///
+    /// # Returns
+    /// * `false` if the log is full (the event is not recorded)
+    pub fn measure(&mut self, kind: u8, data: &[u8]) -> bool {
+        let mut digest = [0u8; 32];
+        digest.copy_from_slice(hash::digest(Algorithm::Sha256, data).as_bytes());
+        self.record(kind, digest)
+    }
+
+    /// Record an event whose digest is already known.
+    pub fn record(&mut self, kind: u8, digest: [u8; 32]) -> bool {
+        let Some(slot) = self.events.get_mut(self.count) else {
+            return false;
+        };
+        *slot = (kind, digest);
+        self.pcr = extend(&self.pcr, &digest);
+        self.count += 1;
+        true
+    }
+
+    /// Current value of the chain register.
+    pub fn pcr(&self) -> [u8; 32] {
+        self.pcr
+    }
+
+    /// The log, laid out as
+    /// `magic(8) | version(1) | count(1) | reserved(2) | pcr(32) |
+    /// count x (kind(1) | reserved(3) | digest(32))`, zero padded to
+    /// `LOG_MAX_LEN`.
+    pub fn to_bytes(&self) -> [u8; LOG_MAX_LEN] {
+        let mut out = [0u8; LOG_MAX_LEN];
+        out[..8].copy_from_slice(&LOG_MAGIC);
+        out[8] = LOG_VERSION;
+        out[9] = self.count as u8;
+        out[12..44].copy_from_slice(&self.pcr);
+        for (i, (kind, digest)) in self.events[..self.count].iter().enumerate() {
+            let at = LOG_HEADER_LEN + i * LOG_EVENT_LEN;
+            out[at] = *kind;
+            out[at + 4..at + LOG_EVENT_LEN].copy_from_slice(digest);
+        }
+        out
+    }
+}
+
+impl Default for MeasurementLog {
+    fn default() -> Self {
+        Self::new()
+    }
 }
@@ -0,0 +1,149 @@
+//! SecureIoTOS Bootloader Library Module
+//! -----------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/SecureIoTOS>
+//!
+//! The board-independent part of the bootloader, shared by the STM32G474
+//! image (`main.rs`) and the board examples (`examples/nrf52840`):
+//! - `boot()`: the boot flow, over a board's `Board` implementation;
+//! - `firmware`: image hashes and signatures, the boot measurement record
+//!   and the measured-boot event log;
+//! - `recovery`: XMODEM download of a signed image when nothing boots;
+//! - `VENDOR_PUBKEYS`: the vendor key ring, baked in by build.rs.
+//!
+//! A board image initializes its heap (`secure_storage` allocates for
+//! manifests) and calls `boot()`, which does not return:
+//!
+//! 1. Load the vendor keys, minus the revocations burnt into flash.
+//! 2. Pick the A/B slot to boot (`secure_storage::update`): a new image on
+//!    trial while it has boot attempts left, otherwise the confirmed one,
+//!    rolling back an update that was never confirmed.
+//! 3. Verify the slot's signature, version and hash; recovery mode
+//!    (`Board::recover()`) when no slot verifies.
+//! 4. Measure the bootloader, the device configuration and the image into
+//!    the measured-boot event log, and leave the image's measurement
+//!    record, both in retained RAM for the firmware.
+//! 5. Point VTOR at the image's vector table and jump through its reset
+//!    vector, still privileged: the kernel sets up the MPU and drops
+//!    privilege for its tasks.
+
+// If we are not running tests, compile this crate without the standard library (no_std).
+#![cfg_attr(not(test), no_std)]
+
+pub mod firmware;
+pub mod recovery;
+
+use cortex_m::asm;
+use cortex_m::peripheral::SCB;
+use hal::bsp::BspFlash;
+use secure_storage::update::{self, Layout};
+use secure_storage::vendor_keys::{VendorKeys, VENDOR_KEY_LEN};
+use sios_log::{error, info, warn};
+
+use crate::firmware::MeasurementLog;
+
+// VENDOR_PUBKEYS: The vendor's P-256 public keys (SEC1, uncompressed) that sign
+// slot manifests; a manifest names its key by index. Spare keys stay offline
+// until an update signed with one revokes a compromised key. Baked in by
+// build.rs from SIOS_VENDOR_PUBKEYS; the build fails without them.
+include!(concat!(env!("OUT_DIR"), "/vendor_pubkeys.rs"));
+
+/// What `boot()` needs from a board.
+pub trait Board {
+    type Flash: BspFlash;
+
+    /// Slots, boot control pages and revocation area. The slots must be
+    /// memory mapped: images are measured and run in place.
+    const LAYOUT: Layout;
+    /// Retained RAM, outside both stages' RAM, where the measured-boot
+    /// event log is left (`firmware::LOG_MAX_LEN` bytes, word aligned).
+    const BOOT_LOG_ADDR: u32;
+    /// Retained RAM for the boot measurement record
+    /// (`firmware::MEASUREMENT_LEN` bytes, word aligned).
+    const MEASUREMENT_ADDR: u32;
+
+    fn flash(&mut self) -> &mut Self::Flash;
+
+    /// This bootloader's flash, measured first.
+    fn bootloader_code(&self) -> &'static [u8];
+
+    /// The device configuration (option bytes, UICR), measured next.
+    fn config(&self) -> &'static [u8];
+
+    /// Whether the debug port is locked (`firmware::BOOT_DEBUG_LOCKED`).
+    fn debug_locked(&self) -> bool;
+
+    /// Recovery mode: take a signed image over a local link
+    /// (`recovery::receive()`) and reset into it.
+    fn recover(&mut self, keys: &VendorKeys) -> !;
+}
+
+/// Boot the firmware as described in the module documentation.
+pub fn boot<B: Board>(board: &mut B) -> ! {
+    let Ok(keys) = VendorKeys::load(board.flash(), B::LAYOUT.revocations, &VENDOR_PUBKEYS) else {
+        error!("boot: revocation area unreadable");
+        fail_safe();
+    };
+    let (base, image_len) = match update::select_boot_slot(board.flash(), &B::LAYOUT, &keys) {
+        Ok((slot, manifest)) => {
+            info!("boot: slot {:?}, version {}", slot, manifest.version);
+            (B::LAYOUT.slot(slot).image_base(), manifest.image_len)
+        }
+        Err(e) => {
+            warn!("boot: no bootable slot ({:?}), entering recovery", e);
+            board.recover(&keys)
+        }
+    };
+    // SAFETY: the slot is memory-mapped flash (`Board::LAYOUT`) holding an
+    // image of this length, verified just now; nothing writes it before
+    // the jump.
+    let image = unsafe { core::slice::from_raw_parts(base as *const u8, image_len) };
+    if firmware::image_build_id(image).is_none() {
+        warn!("boot: signed image has no image header");
+    }
+    record_measurements(board, image);
+
+    // SAFETY: the image verified; it starts with its vector table, whose
+    // first two words are the initial stack pointer and the reset vector.
+    // Nothing of the bootloader is used after the jump.
+    unsafe {
+        (*SCB::PTR).vtor.write(base);
+        asm::bootload(base as *const u32)
+    }
+}
+
+/// Measure the boot chain for attestation before the firmware runs
+// Event log: this bootloader, the device configuration, then the image;
+// each digest is extended into the chain register the kernel replays.
+// Measurement record: the image alone, with how it was booted.
+fn record_measurements<B: Board>(board: &B, image: &[u8]) {
+    let mut log = MeasurementLog::new();
+    log.measure(firmware::EVENT_BOOTLOADER, board.bootloader_code());
+    log.measure(firmware::EVENT_CONFIG, board.config());
+    log.measure(firmware::EVENT_FIRMWARE, image);
+
+    let mut flags = firmware::BOOT_SIGNATURE_VERIFIED;
+    if board.debug_locked() {
+        flags |= firmware::BOOT_DEBUG_LOCKED;
+    }
+    // SAFETY: retained RAM the board reserved for the hand-over, outside
+    // this stage's memory map; word aligned and large enough for each.
+    unsafe {
+        core::ptr::write_volatile(B::BOOT_LOG_ADDR as *mut [u8; firmware::LOG_MAX_LEN], log.to_bytes());
+        core::ptr::write_volatile(B::MEASUREMENT_ADDR as *mut [u8; firmware::MEASUREMENT_LEN], firmware::measure(image, flags));
+    }
+}
+
+/// Park the core; a debugger or power cycle is the way out.
+// Uses wfi instruction → puts CPU in low-power wait mode.
+#[inline(never)]
+pub fn fail_safe() -> ! {
+    // TODO: Blink error LED or trigger watchdog reset
+    loop {
+        asm::wfi(); // Wait for interrupt (low-power)
+    }
+}
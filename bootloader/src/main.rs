@@ -7,12 +7,17 @@
 //! URL     : <https://m-a-h-b-u-b.github.io>
 //! GitHub  : <https://github.com/m-a-h-b-u-b/SecureIoTOS>
 //!
-//! Provides the main bootloader entry point for SecureIoTOS.
-//! Responsibilities:
+//! Provides the main bootloader entry point for SecureIoTOS on the
+//! STM32G474. Responsibilities:
 //! 1. Initialize NVIC and SysTick timers.
-//! 2. Verify firmware integrity.
-//! 3. Switch CPU mode and jump to firmware if valid.
-//! 4. Fail-safe loop on verification failure.
+//! 2. Describe the board to the shared boot flow (`bootloader::boot()`):
+//!    flash layout and controller, retained RAM for the measurements,
+//!    option bytes, and the debug UART for recovery mode.
+//! 3. Hand over to `bootloader::boot()`, which picks and verifies an A/B
+//!    slot, measures the boot chain and jumps to the firmware, or enters
+//!    recovery mode when no slot verifies: a signed image is taken over
+//!    the debug UART (XMODEM, see `bootloader::recovery`) and the device
+//!    resets into it.
 
 // #![no_std]: Tells Rust not to use the standard library (important 
 // for embedded systems where std is unavailable)
@@ -21,17 +26,37 @@
 // Instead, we use a custom entry defined by the cortex-m-rt crate.
 #![no_main]
 
+// core::ptr::addr_of_mut: the heap array's address, without a reference to it.
+use core::ptr::addr_of_mut;
+
 // ortex_m_rt::entry: Defines the entry point of the program for ARM Cortex-M microcontrollers.
 use cortex_m_rt::entry;
-// cortex_m::asm: Gives access to inline assembly functions like wfi (Wait For Interrupt).
+// cortex_m::asm: Gives access to inline assembly functions like delay (busy-wait cycles).
 use cortex_m::asm;
+// cortex_m::peripheral::SCB: system reset once recovery staged an image.
+use cortex_m::peripheral::SCB;
+// panic_halt: a panic parks the core; nothing unverified runs.
+use panic_halt as _;
+
+// bootloader: the shared boot flow and recovery transfer.
+use bootloader::{recovery, Board};
+// hal::bsp: flash interface the update code is written against.
+use hal::bsp::{BspError, BspFlash};
+// hal::serial / hal::timer: UART and delay used by the recovery transfer.
+use hal::serial::Serial;
+use hal::timer::Delay;
+// secure_storage::update: slot layout.
+use secure_storage::update::{Layout, SlotRegion};
+// secure_storage::vendor_keys: vendor key ring with revocations burnt by updates.
+use secure_storage::vendor_keys::VendorKeys;
+
+// CORE_HZ: core clock the bootloader runs at (internal RC oscillator after
+// reset), for busy-wait delays.
+const CORE_HZ: u32 = 16_000_000;
+// HEAP_SIZE: heap for manifest parsing and the recovery writer.
+const HEAP_SIZE: usize = 16 * 1024;
 
-// FIRMWARE_START: Memory address where the actual firmware begins (after bootloader).
-// FIRMWARE_SIZE: Size of the firmware (64 KB).
-// EXPECTED_HASH: Placeholder for a SHA-256 hash of the firmware (used for verification).
-const FIRMWARE_START: u32 = 0x0800_4000;
-const FIRMWARE_SIZE: usize = 64 * 1024;
-const EXPECTED_HASH: [u8; 32] = [0; 32]; // Replace with real firmware hash
+static mut HEAP: [u8; HEAP_SIZE] = [0; HEAP_SIZE];
 
 /// Program entry point executed at reset
 #[entry]
@@ -40,57 +65,328 @@ fn main() -> ! {
 	// init_systick() → Setup system timer (for delays or RTOS scheduling).
     init_nvic();
     init_systick();
+    // The heap array is handed to the allocator once, here.
+    memory::heap::init_heap(addr_of_mut!(HEAP) as usize, HEAP_SIZE);
+
+    // Select, verify, measure and start the firmware
+	// If no slot verifies → recovery mode (Stm32g474::recover).
+    bootloader::boot(&mut Stm32g474 { flash: InternalFlash })
+}
+
+/// Initialize NVIC (Nested Vectored Interrupt Controller)
+fn init_nvic() {
+    // TODO: Add NVIC setup (priority, enable interrupts, etc.)
+}
+
+/// Initialize SysTick timer
+fn init_systick() {
+    // TODO: Configure system tick for timing / RTOS tick
+}
+
+/// The STM32G474 as seen by the boot flow
+struct Stm32g474 {
+    flash: InternalFlash,
+}
 
-    // Load firmware slice from flash
-	// Uses from_raw_parts to create a slice (array view) of the firmware region
-    let firmware = unsafe { core::slice::from_raw_parts(FIRMWARE_START as *const u8, FIRMWARE_SIZE) };
+impl Board for Stm32g474 {
+    type Flash = InternalFlash;
 
-    // Verify firmware integrity
-	// Calls verify_firmware().
-	// If check fails → enters fail_safe() loop.
-    if !verify_firmware(firmware, &EXPECTED_HASH) {
-        fail_safe();
+    // Flash layout (STM32G474, 512 KB in two banks of 2 KB pages):
+    // 0x0800_0000  bootloader     64 KB (memory.x)
+    // 0x0801_0000  boot control   2 x 2 KB (A/B copies of the record)
+    // 0x0801_1000  revocations    2 KB (vendor key revocation bits, never erased)
+    // 0x0801_2000  slot A         220 KB (manifest, then image)
+    // 0x0804_9000  slot B         220 KB
+    const LAYOUT: Layout = Layout {
+        slots: [
+            SlotRegion { base: 0x0801_2000, len: 220 * 1024 },
+            SlotRegion { base: 0x0804_9000, len: 220 * 1024 },
+        ],
+        control: [0x0801_0000, 0x0801_0800],
+        revocations: 0x0801_1000,
+    };
+    // Retained RAM handed to the firmware: the top 512 B of SRAM1+SRAM2,
+    // left out of both stages' RAM so neither runtime initializes them.
+    // The measured-boot event log (taken over by
+    // `kernel::measured_boot::capture()`) and, above it, the boot
+    // measurement record (`auth_identity::attestation::BootMeasurement`).
+    const BOOT_LOG_ADDR: u32 = 0x2001_7E00;
+    const MEASUREMENT_ADDR: u32 = 0x2001_7F00;
+
+    fn flash(&mut self) -> &mut InternalFlash {
+        &mut self.flash
     }
 
-    // Switch to unprivileged mode
-    unsafe { cortex_m::register::CONTROL.write(1); }
+    fn bootloader_code(&self) -> &'static [u8] {
+        flash(FLASH_BASE, (FLASH_WRITABLE_START - FLASH_BASE) as usize)
+    }
 
-    // Jump to firmware entry point
-    let firmware_entry: extern "C" fn() -> ! =
-        unsafe { core::mem::transmute(FIRMWARE_START as *const u32) };
+    // Option bytes: read protection, write protection, boot options
+    fn config(&self) -> &'static [u8] {
+        flash(OPTION_BYTES, OPTION_BYTES_LEN)
+    }
+
+    fn debug_locked(&self) -> bool {
+        read_reg(FLASH_OPTR) & FLASH_OPTR_RDP != RDP_LEVEL_0
+    }
 
-    firmware_entry(); // Never returns
+    // Transfers are retried until one succeeds; the reset then boots the
+    // new image through the normal verification.
+    fn recover(&mut self, keys: &VendorKeys) -> ! {
+        let mut uart = BootUart::init();
+        loop {
+            if recovery::receive(&mut uart, &mut BusyDelay, &mut self.flash, &Self::LAYOUT, keys).is_ok() {
+                SCB::sys_reset();
+            }
+        }
+    }
+}
+
+/// Internal flash (or the option bytes) as a slice.
+fn flash(addr: u32, len: usize) -> &'static [u8] {
+    // SAFETY: only called with ranges inside the internal flash or the
+    // option bytes, which are always mapped and not written while they
+    // are measured.
+    unsafe { core::slice::from_raw_parts(addr as *const u8, len) }
 }
 
-/// Fail-safe loop in case of firmware verification failure
-// Infinite loop in case of verification failure.
-// Uses wfi instruction → puts CPU in low-power wait mode.
-// TODO: Could blink an LED or reset watchdog.
-#[inline(never)]
-fn fail_safe() -> ! {
-    // TODO: Blink error LED or trigger watchdog reset
-    loop {
-        asm::wfi(); // Wait for interrupt (low-power)
+fn read_reg(addr: u32) -> u32 {
+    // SAFETY: only called with the register addresses below, which are
+    // valid, aligned MMIO words on the STM32G474.
+    unsafe { core::ptr::read_volatile(addr as *const u32) }
+}
+
+fn write_reg(addr: u32, value: u32) {
+    // SAFETY: as in `read_reg`, or a flash word `InternalFlash` checked
+    // and is programming; the writes follow the reference manual (RM0440)
+    // and none of them alias Rust memory.
+    unsafe { core::ptr::write_volatile(addr as *mut u32, value) }
+}
+
+fn modify_reg(addr: u32, f: impl FnOnce(u32) -> u32) {
+    write_reg(addr, f(read_reg(addr)));
+}
+
+// FLASH: internal flash controller (dual bank, the factory default)
+const FLASH_BASE: u32 = 0x0800_0000;
+const FLASH_SIZE: u32 = 512 * 1024;
+const FLASH_BANK_SIZE: u32 = FLASH_SIZE / 2;
+/// First byte the update code may change; below it is this bootloader.
+const FLASH_WRITABLE_START: u32 = 0x0801_0000;
+const FLASH_ACR: u32 = 0x4002_2000;
+const FLASH_KEYR: u32 = 0x4002_2008;
+const FLASH_SR: u32 = 0x4002_2010;
+const FLASH_CR: u32 = 0x4002_2014;
+const FLASH_OPTR: u32 = 0x4002_2020;
+const FLASH_KEY1: u32 = 0x4567_0123;
+const FLASH_KEY2: u32 = 0xCDEF_89AB;
+const FLASH_ACR_DCEN: u32 = 1 << 10;
+const FLASH_ACR_DCRST: u32 = 1 << 12;
+const FLASH_SR_EOP: u32 = 1 << 0;
+/// OPERR, PROGERR, WRPERR, PGAERR, SIZERR, PGSERR, MISSERR, FASTERR
+const FLASH_SR_ERRORS: u32 = 0x3FA;
+const FLASH_SR_BSY: u32 = 1 << 16;
+const FLASH_CR_PG: u32 = 1 << 0;
+const FLASH_CR_PER: u32 = 1 << 1;
+const FLASH_CR_PNB_SHIFT: u32 = 3;
+const FLASH_CR_BKER: u32 = 1 << 11;
+const FLASH_CR_STRT: u32 = 1 << 16;
+const FLASH_CR_LOCK: u32 = 1 << 31;
+/// Read protection level in OPTR; any value but 0xAA locks the debug port
+/// out of flash (level 1) or entirely (level 2, 0xCC).
+const FLASH_OPTR_RDP: u32 = 0xFF;
+const RDP_LEVEL_0: u32 = 0xAA;
+/// Option bytes of bank 1: user options and RDP, PCROP, WRP and the
+/// securable area
+const OPTION_BYTES: u32 = 0x1FFF_7800;
+const OPTION_BYTES_LEN: usize = 0x30;
+
+/// Internal flash, as seen by the update code
+// Reads are memory mapped; erase and program go through the flash
+// controller, which is unlocked for one operation at a time.
+struct InternalFlash;
+
+impl InternalFlash {
+    fn check(addr: u32, len: usize) -> Result<(), BspError> {
+        let end = addr.checked_add(len as u32).ok_or(BspError::OutOfRange)?;
+        if addr < FLASH_BASE || end > FLASH_BASE + FLASH_SIZE {
+            return Err(BspError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    fn check_writable(addr: u32, len: usize) -> Result<(), BspError> {
+        Self::check(addr, len)?;
+        if addr < FLASH_WRITABLE_START || !addr.is_multiple_of(Self::WRITE_SIZE as u32) || !len.is_multiple_of(Self::WRITE_SIZE) {
+            return Err(BspError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    /// Unlock the controller and clear what the last operation left in SR.
+    fn unlock() {
+        while read_reg(FLASH_SR) & FLASH_SR_BSY != 0 {}
+        if read_reg(FLASH_CR) & FLASH_CR_LOCK != 0 {
+            write_reg(FLASH_KEYR, FLASH_KEY1);
+            write_reg(FLASH_KEYR, FLASH_KEY2);
+        }
+        write_reg(FLASH_SR, FLASH_SR_EOP | FLASH_SR_ERRORS);
+    }
+
+    fn lock() {
+        write_reg(FLASH_CR, FLASH_CR_LOCK);
+    }
+
+    /// Wait for the operation started last and collect its outcome.
+    fn wait_done() -> Result<(), BspError> {
+        while read_reg(FLASH_SR) & FLASH_SR_BSY != 0 {}
+        let sr = read_reg(FLASH_SR);
+        write_reg(FLASH_SR, FLASH_SR_EOP | FLASH_SR_ERRORS);
+        if sr & FLASH_SR_ERRORS != 0 {
+            return Err(BspError::Flash);
+        }
+        Ok(())
+    }
+
+    /// The data cache keeps serving the old contents of changed flash
+    /// until it is reset, which only works while it is disabled.
+    fn reset_data_cache() {
+        let acr = read_reg(FLASH_ACR);
+        if acr & FLASH_ACR_DCEN != 0 {
+            write_reg(FLASH_ACR, acr & !FLASH_ACR_DCEN);
+            write_reg(FLASH_ACR, (acr & !FLASH_ACR_DCEN) | FLASH_ACR_DCRST);
+            write_reg(FLASH_ACR, acr);
+        }
     }
 }
 
-/// Initialize NVIC (Nested Vectored Interrupt Controller)
-fn init_nvic() {
-    // TODO: Add NVIC setup (priority, enable interrupts, etc.)
+impl BspFlash for InternalFlash {
+    const PAGE_SIZE: usize = 2048;
+    const WRITE_SIZE: usize = 8;
+
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), BspError> {
+        Self::check(addr, buf.len())?;
+        // SAFETY: inside the internal flash, which is always mapped and
+        // readable; nothing writes it concurrently.
+        unsafe { core::ptr::copy_nonoverlapping(addr as *const u8, buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    fn erase_page(&mut self, addr: u32) -> Result<(), BspError> {
+        Self::check_writable(addr, Self::PAGE_SIZE)?;
+        if !addr.is_multiple_of(Self::PAGE_SIZE as u32) {
+            return Err(BspError::OutOfRange);
+        }
+        // Pages are numbered per bank
+        let offset = addr - FLASH_BASE;
+        let bank = if offset >= FLASH_BANK_SIZE { FLASH_CR_BKER } else { 0 };
+        let page = (offset % FLASH_BANK_SIZE) / Self::PAGE_SIZE as u32;
+        Self::unlock();
+        write_reg(FLASH_CR, FLASH_CR_PER | bank | page << FLASH_CR_PNB_SHIFT);
+        write_reg(FLASH_CR, FLASH_CR_PER | bank | page << FLASH_CR_PNB_SHIFT | FLASH_CR_STRT);
+        let res = Self::wait_done();
+        Self::lock();
+        Self::reset_data_cache();
+        res
+    }
+
+    fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), BspError> {
+        Self::check_writable(addr, data.len())?;
+        Self::unlock();
+        write_reg(FLASH_CR, FLASH_CR_PG);
+        let mut res = Ok(());
+        // Double words: the second word write starts the programming
+        for (i, dword) in data.chunks_exact(Self::WRITE_SIZE).enumerate() {
+            let at = addr + (i * Self::WRITE_SIZE) as u32;
+            write_reg(at, u32::from_le_bytes([dword[0], dword[1], dword[2], dword[3]]));
+            write_reg(at + 4, u32::from_le_bytes([dword[4], dword[5], dword[6], dword[7]]));
+            res = Self::wait_done();
+            if res.is_err() {
+                break;
+            }
+        }
+        Self::lock();
+        Self::reset_data_cache();
+        res
+    }
 }
 
-/// Initialize SysTick timer
-fn init_systick() {
-    // TODO: Configure system tick for timing / RTOS tick
+// RCC / GPIOA / USART2: the debug UART (ST-LINK virtual COM port)
+const RCC_AHB2ENR: u32 = 0x4002_104C;
+const RCC_AHB2ENR_GPIOAEN: u32 = 1 << 0;
+const RCC_APB1ENR1: u32 = 0x4002_1058;
+const RCC_APB1ENR1_USART2EN: u32 = 1 << 17;
+const GPIOA_MODER: u32 = 0x4800_0000;
+const GPIOA_AFRL: u32 = 0x4800_0020;
+const USART2: u32 = 0x4000_4400;
+const USART_CR1: u32 = USART2;
+const USART_BRR: u32 = USART2 + 0x0C;
+const USART_ISR: u32 = USART2 + 0x1C;
+const USART_ICR: u32 = USART2 + 0x20;
+const USART_RDR: u32 = USART2 + 0x24;
+const USART_TDR: u32 = USART2 + 0x28;
+const USART_CR1_UE: u32 = 1 << 0;
+const USART_CR1_RE: u32 = 1 << 2;
+const USART_CR1_TE: u32 = 1 << 3;
+const USART_ISR_ORE: u32 = 1 << 3;
+const USART_ISR_RXNE: u32 = 1 << 5;
+const USART_ISR_TC: u32 = 1 << 6;
+const USART_ISR_TXE: u32 = 1 << 7;
+const USART_ICR_ORECF: u32 = 1 << 3;
+/// PA2 (TX) / PA3 (RX), alternate function 7
+const UART_TX_PIN: u32 = 2;
+const UART_RX_PIN: u32 = 3;
+const UART_AF: u32 = 7;
+const UART_BAUD: u32 = 115_200;
+
+/// Debug UART, as used by recovery mode
+// 115200 8N1 on USART2 (PA2/PA3), clocked from PCLK1 = CORE_HZ after reset.
+struct BootUart;
+
+impl BootUart {
+    /// Route PA2/PA3 to USART2 and enable it. Only recovery mode needs
+    /// the UART, so nothing is touched on a normal boot.
+    fn init() -> Self {
+        modify_reg(RCC_AHB2ENR, |r| r | RCC_AHB2ENR_GPIOAEN);
+        modify_reg(RCC_APB1ENR1, |r| r | RCC_APB1ENR1_USART2EN);
+        // Read back so the clocks are on before the peripherals are written
+        let _ = read_reg(RCC_APB1ENR1);
+        for pin in [UART_TX_PIN, UART_RX_PIN] {
+            modify_reg(GPIOA_MODER, |r| (r & !(0b11 << (2 * pin))) | 0b10 << (2 * pin));
+            modify_reg(GPIOA_AFRL, |r| (r & !(0xF << (4 * pin))) | UART_AF << (4 * pin));
+        }
+        write_reg(USART_CR1, 0);
+        write_reg(USART_BRR, (CORE_HZ + UART_BAUD / 2) / UART_BAUD);
+        write_reg(USART_CR1, USART_CR1_UE | USART_CR1_RE | USART_CR1_TE);
+        BootUart
+    }
+}
+
+impl Serial for BootUart {
+    fn write_byte(&mut self, byte: u8) {
+        while read_reg(USART_ISR) & USART_ISR_TXE == 0 {}
+        write_reg(USART_TDR, byte as u32);
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        let isr = read_reg(USART_ISR);
+        if isr & USART_ISR_ORE != 0 {
+            // A byte was lost; the block's CRC fails and XMODEM resends it
+            write_reg(USART_ICR, USART_ICR_ORECF);
+        }
+        (isr & USART_ISR_RXNE != 0).then(|| read_reg(USART_RDR) as u8)
+    }
+
+    fn flush(&mut self) {
+        while read_reg(USART_ISR) & USART_ISR_TC == 0 {}
+    }
 }
 
-/// Verify firmware integrity using a hash
-///
-/// # Arguments
-/// * `firmware` - firmware byte slice
-/// * `expected_hash` - expected hash for verification
-fn verify_firmware(firmware: &[u8], expected_hash: &[u8]) -> bool {
-    // TODO: Implement actual hash check (e.g., SHA-256)
-    // Placeholder returns true for now
-    true
+/// Busy-wait delay at CORE_HZ
+struct BusyDelay;
+
+impl Delay for BusyDelay {
+    fn delay_us(&mut self, us: u32) {
+        asm::delay(us * (CORE_HZ / 1_000_000));
+    }
 }
@@ -0,0 +1,235 @@
+//! SecureIoTOS Bootloader recovery Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+
+//! Recovery mode: re-flash a device on which no slot verifies.
+//!
+//! The image comes over the debug UART with XMODEM-CRC (128-byte `SOH`
+//! blocks) or XMODEM-1K (1024-byte `STX` blocks), as sent by `sx`/`lrzsz`,
+//! minicom or Tera Term. The file is a slot as it sits in flash: the
+//! `MANIFEST_LEN`-byte slot manifest (`secure_storage::update`), then the
+//! image.
+//!
+//! Nothing about the link is trusted. The manifest's vendor signature
+//! (by an unrevoked key),
+//! size and minimum version are checked by `Updater::recover()` before any
+//! flash is erased, the image hash once the last byte is in, and the slot
+//! is verified again like any other when the device boots from it. A
+//! transfer that fails any check is cancelled and leaves nothing bootable
+//! behind.
+
+// hal: byte-oriented UART and a blocking delay for the timeouts
+use hal::bsp::BspFlash;
+use hal::serial::Serial;
+use hal::timer::Delay;
+
+// secure_storage: slot layout, the checked slot writer and the vendor keys
+use secure_storage::update::{Layout, Slot, UpdateError, Updater, MANIFEST_LEN};
+use secure_storage::vendor_keys::VendorKeys;
+
+/// XMODEM control bytes.
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+/// Sent by the receiver to ask for CRC-16 instead of checksum blocks.
+const CRC_MODE: u8 = b'C';
+
+/// Largest block (XMODEM-1K).
+const MAX_BLOCK: usize = 1024;
+/// Wait for the sender between invitations, and for the next block.
+const BLOCK_TIMEOUT_MS: u32 = 3000;
+/// Wait for each byte inside a block.
+const BYTE_TIMEOUT_MS: u32 = 1000;
+/// Consecutive bad or missing blocks before the transfer is given up.
+const MAX_ERRORS: u8 = 10;
+/// UART poll interval: short enough not to overrun a one-byte receive
+/// register at 115200 baud.
+const POLL_US: u32 = 10;
+
+/// Why a recovery transfer ended without a new image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RecoveryError {
+    /// The sender cancelled
+    Cancelled,
+    /// Too many corrupt or missing blocks in a row
+    TooManyErrors,
+    /// A block arrived out of sequence
+    Sequence,
+    /// The image was refused or could not be written
+    Update(UpdateError),
+}
+
+impl From<UpdateError> for RecoveryError {
+    fn from(e: UpdateError) -> Self {
+        RecoveryError::Update(e)
+    }
+}
+
+/// Receive a signed slot over XMODEM and stage it for the next boot.
+/// Waits for a sender for as long as it takes.
+///
+/// # Returns
+/// * `Ok(slot)`: the slot now pending; reset to boot it
+/// * `Err(e)` if the transfer failed; the caller may simply try again
+pub fn receive<S: Serial, D: Delay, F: BspFlash>(
+    serial: &mut S,
+    delay: &mut D,
+    flash: &mut F,
+    layout: &Layout,
+    keys: &VendorKeys,
+) -> Result<Slot, RecoveryError> {
+    let mut sink =
+        SlotSink { flash: Some(flash), layout, keys, manifest: [0xFF; MANIFEST_LEN], filled: 0, updater: None };
+    let mut block = [0u8; MAX_BLOCK];
+    let mut expected: u8 = 1;
+    let mut errors: u8 = 0;
+    let mut started = false;
+
+    let result = loop {
+        if !started {
+            serial.write_byte(CRC_MODE);
+        }
+        let Some(first) = read_byte(serial, delay, BLOCK_TIMEOUT_MS) else {
+            if started {
+                errors += 1;
+                if errors >= MAX_ERRORS {
+                    break Err(RecoveryError::TooManyErrors);
+                }
+                serial.write_byte(NAK);
+            }
+            continue;
+        };
+        let len = match first {
+            SOH => 128,
+            STX => MAX_BLOCK,
+            EOT => {
+                serial.write_byte(ACK);
+                break sink.finish();
+            }
+            CAN => break Err(RecoveryError::Cancelled),
+            // Line noise before or between blocks
+            _ => continue,
+        };
+        started = true;
+
+        match read_block(serial, delay, &mut block[..len]) {
+            Some(number) if number == expected => {
+                if let Err(e) = sink.push(&block[..len]) {
+                    break Err(e.into());
+                }
+                expected = expected.wrapping_add(1);
+                errors = 0;
+                serial.write_byte(ACK);
+            }
+            // Our ACK was lost and the sender repeated the last block
+            Some(number) if number == expected.wrapping_sub(1) => serial.write_byte(ACK),
+            Some(_) => break Err(RecoveryError::Sequence),
+            None => {
+                errors += 1;
+                if errors >= MAX_ERRORS {
+                    break Err(RecoveryError::TooManyErrors);
+                }
+                purge(serial, delay);
+                serial.write_byte(NAK);
+            }
+        }
+    };
+
+    if result.is_err() {
+        serial.write_all(&[CAN, CAN, CAN]);
+    }
+    serial.flush();
+    result
+}
+
+/// Where received bytes go: the manifest is collected first, then the
+/// image is streamed into the slot it names.
+struct SlotSink<'a, F: BspFlash> {
+    /// Until the manifest is complete
+    flash: Option<&'a mut F>,
+    layout: &'a Layout,
+    keys: &'a VendorKeys<'a>,
+    manifest: [u8; MANIFEST_LEN],
+    filled: usize,
+    updater: Option<Updater<'a, F>>,
+}
+
+impl<'a, F: BspFlash> SlotSink<'a, F> {
+    fn push(&mut self, mut data: &[u8]) -> Result<(), UpdateError> {
+        if let Some(flash) = self.flash.take() {
+            let take = data.len().min(MANIFEST_LEN - self.filled);
+            self.manifest[self.filled..self.filled + take].copy_from_slice(&data[..take]);
+            self.filled += take;
+            data = &data[take..];
+            if self.filled < MANIFEST_LEN {
+                self.flash = Some(flash);
+                return Ok(());
+            }
+            self.updater = Some(Updater::recover(flash, self.layout, &self.manifest, self.keys)?);
+        }
+        let Some(updater) = self.updater.as_mut() else {
+            return Err(UpdateError::BadManifest);
+        };
+        // The last block is padded with SUB (0x1A); the manifest says
+        // where the image ends.
+        let take = data.len().min(updater.remaining());
+        updater.write(&data[..take])
+    }
+
+    fn finish(self) -> Result<Slot, RecoveryError> {
+        let updater = self.updater.ok_or(UpdateError::Incomplete)?;
+        Ok(updater.finish()?)
+    }
+}
+
+/// Read the rest of a block after its start byte into `data`.
+///
+/// # Returns
+/// * `Some(number)`: block number, framing and CRC are good
+/// * `None` on a timeout, a bad complement or a CRC mismatch
+fn read_block<S: Serial, D: Delay>(serial: &mut S, delay: &mut D, data: &mut [u8]) -> Option<u8> {
+    let number = read_byte(serial, delay, BYTE_TIMEOUT_MS)?;
+    let complement = read_byte(serial, delay, BYTE_TIMEOUT_MS)?;
+    for b in data.iter_mut() {
+        *b = read_byte(serial, delay, BYTE_TIMEOUT_MS)?;
+    }
+    let hi = read_byte(serial, delay, BYTE_TIMEOUT_MS)?;
+    let lo = read_byte(serial, delay, BYTE_TIMEOUT_MS)?;
+    (number ^ complement == 0xFF && crc16_xmodem(data) == u16::from_be_bytes([hi, lo])).then_some(number)
+}
+
+/// Drop whatever is left of a bad block, so the retransmission starts
+/// clean.
+fn purge<S: Serial, D: Delay>(serial: &mut S, delay: &mut D) {
+    while read_byte(serial, delay, BYTE_TIMEOUT_MS).is_some() {}
+}
+
+fn read_byte<S: Serial, D: Delay>(serial: &mut S, delay: &mut D, timeout_ms: u32) -> Option<u8> {
+    for _ in 0..timeout_ms * (1000 / POLL_US) {
+        if let Some(b) = serial.read_byte() {
+            return Some(b);
+        }
+        delay.delay_us(POLL_US);
+    }
+    None
+}
+
+/// CRC-16/XMODEM (poly 0x1021, init 0).
+fn crc16_xmodem(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
@@ -0,0 +1,35 @@
+/*
+ * SecureIoTOS FreeRTOS compatibility header
+ * License : Dual License
+ *           - Apache 2.0 for open-source / personal use
+ *           - Commercial license required for closed-source use
+ * Author  : Md Mahbubur Rahman
+ * GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+ *
+ * Drop-in replacement for FreeRTOS.h when porting FreeRTOS applications.
+ * Add capi/include/freertos to the include path (ahead of any FreeRTOS
+ * kernel headers) and build the C API library with `--features freertos`.
+ *
+ * The supported API subset and its differences from FreeRTOS are listed
+ * in capi/src/freertos.rs.
+ */
+
+#ifndef INC_FREERTOS_H
+#define INC_FREERTOS_H
+
+#define SIOS_FREERTOS_COMPAT 1
+#include "../secureiotos.h"
+
+#define pdMS_TO_TICKS(xTimeInMs) \
+  ((TickType_t)(((uint64_t)(xTimeInMs) * (uint64_t)configTICK_RATE_HZ) / 1000U))
+#define portTICK_PERIOD_MS ((TickType_t)1000U / configTICK_RATE_HZ)
+
+#define configMINIMAL_STACK_SIZE ((uint16_t)128)
+#define tskIDLE_PRIORITY ((UBaseType_t)0U)
+
+/* The scheduler preempts on every tick; there is no explicit yield. */
+#define taskYIELD() ((void)0)
+#define portYIELD_FROM_ISR(x) ((void)(x))
+#define portEND_SWITCHING_ISR(x) ((void)(x))
+
+#endif /* INC_FREERTOS_H */
@@ -0,0 +1,17 @@
+/*
+ * SecureIoTOS FreeRTOS compatibility header: Queues.
+ * License : Dual License
+ *           - Apache 2.0 for open-source / personal use
+ *           - Commercial license required for closed-source use
+ * Author  : Md Mahbubur Rahman
+ * GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+ *
+ * The declarations live in secureiotos.h; see FreeRTOS.h.
+ */
+
+#ifndef INC_QUEUE_H
+#define INC_QUEUE_H
+
+#include "FreeRTOS.h"
+
+#endif /* INC_QUEUE_H */
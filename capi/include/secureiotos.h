@@ -0,0 +1,511 @@
+/*
+ * SecureIoTOS C API
+ * License : Dual License
+ *           - Apache 2.0 for open-source / personal use
+ *           - Commercial license required for closed-source use
+ * Author  : Md Mahbubur Rahman
+ * GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+ */
+
+#ifndef SECUREIOTOS_H
+#define SECUREIOTOS_H
+
+/* Generated by cbindgen from capi/src. Do not edit by hand. */
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+// Number of key slots.
+#define SIOS_KEY_SLOTS 8
+
+// AES-256 key size in bytes.
+#define SIOS_AES256_KEY_LEN 32
+
+// GCM nonce size in bytes.
+#define SIOS_GCM_NONCE_LEN 12
+
+// GCM tag size in bytes.
+#define SIOS_GCM_TAG_LEN 16
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// Number of queue/semaphore objects available to FreeRTOS code.
+#define configSIOS_MAX_QUEUES 16
+#endif
+
+// Number of queues available to C code.
+#define SIOS_QUEUE_MAX 8
+
+// Messages per queue.
+#define SIOS_QUEUE_DEPTH 16
+
+// Maximum message size in bytes.
+#define SIOS_QUEUE_MSG_SIZE 64
+
+// Length of `SiosFirmwareInfo::build_id`.
+#define SIOS_BUILD_ID_LEN 20
+
+// Largest message a kernel IPC queue carries.
+#define SIOS_IPC_MSG_SIZE 128
+
+// `sios_ipc_recv()` timeout that waits until a message arrives.
+#define SIOS_WAIT_FOREVER UINT32_MAX
+
+// Result code returned by every C API function.
+enum SiosStatus
+#if defined(__cplusplus) || __STDC_VERSION__ >= 202311L
+  : int32_t
+#endif // defined(__cplusplus) || __STDC_VERSION__ >= 202311L
+ {
+  SIOS_STATUS_OK = 0,
+  // Bad argument (null pointer, bad length, invalid handle, ...)
+  SIOS_STATUS_INVALID = -1,
+  // The kernel rejected a user pointer
+  SIOS_STATUS_BAD_ADDRESS = -2,
+  // The calling task lacks the required capability
+  SIOS_STATUS_PERMISSION_DENIED = -3,
+  // Buffer or message larger than allowed
+  SIOS_STATUS_TOO_LARGE = -4,
+  SIOS_STATUS_NOT_FOUND = -5,
+  SIOS_STATUS_UNSUPPORTED = -6,
+  // Queue full
+  SIOS_STATUS_FULL = -7,
+  // Queue empty
+  SIOS_STATUS_EMPTY = -8,
+  // No free queue/key slot
+  SIOS_STATUS_NO_SLOT = -9,
+  // Object is in use by another context; retry
+  SIOS_STATUS_BUSY = -10,
+  // Nothing arrived before the timeout
+  SIOS_STATUS_TIMED_OUT = -11,
+  // Authenticated decryption failed: data, AAD, key or nonce are wrong
+  SIOS_STATUS_AUTH_FAILED = -12,
+  SIOS_STATUS_UNKNOWN = -100,
+};
+#ifndef __cplusplus
+#if __STDC_VERSION__ >= 202311L
+typedef enum SiosStatus SiosStatus;
+#else
+typedef int32_t SiosStatus;
+#endif // __STDC_VERSION__ >= 202311L
+#endif // __cplusplus
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// Queue or semaphore object. FreeRTOS uses one handle type for both.
+typedef struct QueueDefinition QueueDefinition;
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// Opaque task control block; handles are `task id + 1` cast to a pointer.
+typedef struct tskTaskControlBlock tskTaskControlBlock;
+#endif
+
+// Key handle (0 is never a valid handle).
+typedef uint32_t SiosKey;
+
+#if defined(SIOS_FREERTOS_COMPAT)
+typedef int32_t BaseType_t;
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+typedef void (*TaskFunction_t)(void*);
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+typedef uint32_t UBaseType_t;
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+typedef struct tskTaskControlBlock *TaskHandle_t;
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+typedef uint32_t TickType_t;
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+typedef struct QueueDefinition *QueueHandle_t;
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+typedef QueueHandle_t SemaphoreHandle_t;
+#endif
+
+// Queue handle (0 is never a valid handle).
+typedef uint32_t SiosQueue;
+
+// Memory usage snapshot for the calling task, written to user space by
+// `GetMemInfo`. Layout is part of the ABI: append fields, never reorder.
+typedef struct SiosMemInfo {
+  // Size of the task stack in bytes
+  uint32_t stack_size;
+  // Deepest stack usage observed so far (watermark), in bytes
+  uint32_t stack_high_watermark;
+  // Heap bytes the task may allocate (0 = no quota)
+  uint32_t heap_quota;
+  // Heap bytes currently allocated by the task
+  uint32_t heap_used;
+  // Number of shared-memory regions granted to the task
+  uint32_t shm_grants;
+  // Total size of those regions in bytes
+  uint32_t shm_bytes;
+} SiosMemInfo;
+
+// CPU usage of one task, written to user space by `GetTaskStats`
+// (see `scheduler::TaskStats`). Layout is part of the ABI: append
+// fields, never reorder.
+typedef struct SiosTaskCpuStats {
+  // Task id
+  uint32_t id;
+  // Times the task was switched in
+  uint32_t switches;
+  // Ticks the task ran, low and high words
+  uint32_t run_ticks_lo;
+  uint32_t run_ticks_hi;
+  // Share of CPU time since the last stats reset, in 0.1 %
+  uint32_t utilization_permille;
+} SiosTaskCpuStats;
+
+// Identity of the running firmware, written to user space by
+// `GetFirmwareInfo` (see `image::FirmwareInfo`). Layout is part of the
+// ABI: append fields, never reorder.
+typedef struct SiosFirmwareInfo {
+  // Build ID: 12 bytes of git commit, 8 bytes of configuration hash
+  uint8_t build_id[SIOS_BUILD_ID_LEN];
+  // Version: major, minor, patch
+  uint16_t version[3];
+  // `image::FLAG_DIRTY` if built with uncommitted changes
+  uint16_t flags;
+} SiosFirmwareInfo;
+
+#if defined(SIOS_FREERTOS_COMPAT)
+#define pdFALSE 0
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+#define pdTRUE 1
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+#define pdPASS pdTRUE
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+#define pdFAIL pdFALSE
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+#define errQUEUE_EMPTY 0
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+#define errQUEUE_FULL 0
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+#define errCOULD_NOT_ALLOCATE_REQUIRED_MEMORY -1
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+#define portMAX_DELAY 4294967295
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// Tick rate of the kernel clock (`kernel::time::TICK_HZ`).
+#define configTICK_RATE_HZ 1000
+#endif
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Import an AES-256 key. The caller should wipe its copy afterwards.
+//
+// # Safety
+// `key` must be valid for reads of `len` bytes, `out` for one write.
+SiosStatus sios_key_import_aes256(const uint8_t *key, size_t len, SiosKey *out);
+
+// Wipe a key and free its slot.
+SiosStatus sios_key_destroy(SiosKey k);
+
+// AES-256-GCM encrypt `len` bytes in place and write the 16-byte tag
+// over `aad` and the ciphertext to `tag`.
+//
+// # Safety
+// `nonce` must be valid for 12 byte reads, `aad` for `aad_len` (may be
+// null if 0), `buf` for `len` byte reads/writes, `tag` for 16 byte writes.
+SiosStatus sios_aes256_gcm_seal(SiosKey k,
+                                const uint8_t *nonce,
+                                const uint8_t *aad,
+                                size_t aad_len,
+                                uint8_t *buf,
+                                size_t len,
+                                uint8_t *tag);
+
+// Check `tag` and AES-256-GCM decrypt `len` bytes in place. On
+// `SIOS_STATUS_AUTH_FAILED` the buffer is wiped.
+//
+// # Safety
+// As for `sios_aes256_gcm_seal`, with `tag` valid for 16 byte reads.
+SiosStatus sios_aes256_gcm_open(SiosKey k,
+                                const uint8_t *nonce,
+                                const uint8_t *aad,
+                                size_t aad_len,
+                                uint8_t *buf,
+                                size_t len,
+                                const uint8_t *tag);
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// Create a task. `pcName` is accepted for source compatibility only.
+//
+// # Safety
+// `pxCreatedTask` must be null or valid for one write.
+BaseType_t xTaskCreate(TaskFunction_t pvTaskCode,
+                       const char *_pcName,
+                       uint16_t usStackDepth,
+                       void *pvParameters,
+                       UBaseType_t uxPriority,
+                       TaskHandle_t *pxCreatedTask);
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// Delete a task (`NULL` = the calling task, which then never returns).
+void vTaskDelete(TaskHandle_t xTaskToDelete);
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// Block the calling task for `xTicksToDelay` ticks (0 returns at once).
+void vTaskDelay(TickType_t xTicksToDelay);
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// Block until `*pxPreviousWakeTime + xTimeIncrement`, then advance
+// `*pxPreviousWakeTime` by the increment (fixed-rate loops).
+//
+// # Safety
+// `pxPreviousWakeTime` must be valid for reads and writes.
+void vTaskDelayUntil(TickType_t *pxPreviousWakeTime, TickType_t xTimeIncrement);
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// Ticks since start-up.
+TickType_t xTaskGetTickCount(void);
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// ISR variant of `xTaskGetTickCount()`.
+TickType_t xTaskGetTickCountFromISR(void);
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// Create a queue of `uxQueueLength` items of `uxItemSize` bytes. Every
+// queue holds `SIOS_QUEUE_DEPTH` items; longer queues and items larger
+// than `SIOS_QUEUE_MSG_SIZE` cannot be created.
+QueueHandle_t xQueueCreate(UBaseType_t uxQueueLength, UBaseType_t uxItemSize);
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// Delete a queue or semaphore. No task may be blocked on it.
+void vQueueDelete(QueueHandle_t xQueue);
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// Copy one item into the queue, waiting up to `xTicksToWait` for space.
+//
+// # Safety
+// `pvItemToQueue` must be valid for reads of the queue's item size.
+BaseType_t xQueueSend(QueueHandle_t xQueue, const void *pvItemToQueue, TickType_t xTicksToWait);
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// Alias of `xQueueSend()` (FIFO order).
+//
+// # Safety
+// Same as `xQueueSend()`.
+BaseType_t xQueueSendToBack(QueueHandle_t xQueue,
+                            const void *pvItemToQueue,
+                            TickType_t xTicksToWait);
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// Receive one item, waiting up to `xTicksToWait` for data.
+//
+// # Safety
+// `pvBuffer` must be valid for writes of the queue's item size.
+BaseType_t xQueueReceive(QueueHandle_t xQueue, void *pvBuffer, TickType_t xTicksToWait);
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// ISR variant of `xQueueSend()`; never blocks.
+//
+// # Safety
+// Same as `xQueueSend()`; `pxHigherPriorityTaskWoken` may be null.
+BaseType_t xQueueSendFromISR(QueueHandle_t xQueue,
+                             const void *pvItemToQueue,
+                             BaseType_t *pxHigherPriorityTaskWoken);
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// ISR variant of `xQueueReceive()`; never blocks.
+//
+// # Safety
+// Same as `xQueueReceive()`; `pxHigherPriorityTaskWoken` may be null.
+BaseType_t xQueueReceiveFromISR(QueueHandle_t xQueue,
+                                void *pvBuffer,
+                                BaseType_t *pxHigherPriorityTaskWoken);
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// Number of items in a queue, or the count of a semaphore.
+UBaseType_t uxQueueMessagesWaiting(QueueHandle_t xQueue);
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// Binary semaphore, created empty (as in FreeRTOS).
+SemaphoreHandle_t xSemaphoreCreateBinary(void);
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// Counting semaphore.
+SemaphoreHandle_t xSemaphoreCreateCounting(UBaseType_t uxMaxCount, UBaseType_t uxInitialCount);
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// Mutex, created available. NOTE: no priority inheritance and no owner
+// tracking; it behaves like a binary semaphore.
+SemaphoreHandle_t xSemaphoreCreateMutex(void);
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// Take a semaphore, waiting up to `xTicksToWait`.
+BaseType_t xSemaphoreTake(SemaphoreHandle_t xSemaphore, TickType_t xTicksToWait);
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// Give a semaphore. Fails if it is already at its maximum count.
+BaseType_t xSemaphoreGive(SemaphoreHandle_t xSemaphore);
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// ISR variant of `xSemaphoreGive()`.
+//
+// # Safety
+// `pxHigherPriorityTaskWoken` must be null or valid for one write.
+BaseType_t xSemaphoreGiveFromISR(SemaphoreHandle_t xSemaphore,
+                                 BaseType_t *pxHigherPriorityTaskWoken);
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// ISR variant of `xSemaphoreTake()`; never blocks.
+//
+// # Safety
+// `pxHigherPriorityTaskWoken` must be null or valid for one write.
+BaseType_t xSemaphoreTakeFromISR(SemaphoreHandle_t xSemaphore,
+                                 BaseType_t *pxHigherPriorityTaskWoken);
+#endif
+
+#if defined(SIOS_FREERTOS_COMPAT)
+// Delete a semaphore (same as `vQueueDelete()`).
+void vSemaphoreDelete(SemaphoreHandle_t xSemaphore);
+#endif
+
+// Allocate a queue from the pool.
+//
+// # Safety
+// `out` must be null or valid for writes.
+SiosStatus sios_queue_create(SiosQueue *out);
+
+// Return a queue to the pool. Pending messages are discarded.
+// No other context may use the handle afterwards.
+SiosStatus sios_queue_delete(SiosQueue q);
+
+// Copy `len` bytes into the queue. Never blocks.
+//
+// # Safety
+// `data` must be valid for reads of `len` bytes.
+SiosStatus sios_queue_send(SiosQueue q, const uint8_t *data, size_t len);
+
+// Copy the oldest message into `buf` and store its length in `out_len`.
+// Never blocks. `cap` must be at least `SIOS_QUEUE_MSG_SIZE`, so every
+// message fits and none is ever dropped on receive.
+//
+// # Safety
+// `buf` must be valid for writes of `cap` bytes, `out_len` for one write.
+SiosStatus sios_queue_recv(SiosQueue q, uint8_t *buf, size_t cap, size_t *out_len);
+
+// ISR variant of `sios_queue_send`.
+//
+// # Safety
+// Same as `sios_queue_send`.
+SiosStatus sios_queue_send_from_isr(SiosQueue q, const uint8_t *data, size_t len);
+
+// ISR variant of `sios_queue_recv`.
+//
+// # Safety
+// Same as `sios_queue_recv`.
+SiosStatus sios_queue_recv_from_isr(SiosQueue q, uint8_t *buf, size_t cap, size_t *out_len);
+
+// Number of queued messages (0 for an invalid handle).
+size_t sios_queue_count(SiosQueue q);
+
+// Raw syscall: returns the kernel's encoded result (bit 31 set = error).
+// Prefer the typed wrappers below.
+uint32_t sios_syscall(uint32_t id, size_t a0, size_t a1, size_t a2);
+
+// Current system time in seconds.
+//
+// # Safety
+// `out_secs` must be null or valid for writes.
+SiosStatus sios_get_time(uint32_t *out_secs);
+
+// Open a kernel message queue owned by the calling task. Other tasks
+// send to it with `sios_ipc_send()`; only the owner can receive.
+//
+// # Safety
+// `out` must be null or valid for writes.
+SiosStatus sios_ipc_create_queue(uint32_t *out);
+
+// Send `len` bytes (at most `SIOS_IPC_MSG_SIZE`) to kernel queue `dest`.
+// Never blocks; `SIOS_STATUS_FULL` if the queue is full.
+//
+// # Safety
+// `buf` must be valid for reads of `len` bytes.
+SiosStatus sios_ipc_send(uint32_t dest, const uint8_t *buf, size_t len);
+
+// Receive the oldest message of a queue the calling task owns, waiting
+// up to `timeout_ms` for one (0 = poll, `SIOS_WAIT_FOREVER` = no limit).
+// `cap` must be at least `SIOS_IPC_MSG_SIZE`.
+//
+// # Safety
+// `buf` must be valid for writes of `cap` bytes, `out_len` for one write.
+SiosStatus sios_ipc_recv(uint32_t queue,
+                         uint8_t *buf,
+                         size_t cap,
+                         size_t *out_len,
+                         uint32_t timeout_ms);
+
+// Memory usage of the calling task.
+//
+// # Safety
+// `out` must be null or valid for writes of one `SiosMemInfo`.
+SiosStatus sios_get_mem_info(struct SiosMemInfo *out);
+
+// CPU usage per task: writes up to `max` entries to `out` and the
+// number written to `out_count`.
+//
+// # Safety
+// `out` must be null or valid for writes of `max` `SiosTaskCpuStats`;
+// `out_count` must be null or valid for writes.
+SiosStatus sios_get_task_stats(struct SiosTaskCpuStats *out, size_t max, size_t *out_count);
+
+// Build ID and version of the running firmware, e.g. to tag telemetry.
+//
+// # Safety
+// `out` must be null or valid for writes of one `SiosFirmwareInfo`.
+SiosStatus sios_get_firmware_info(struct SiosFirmwareInfo *out);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* SECUREIOTOS_H */
@@ -0,0 +1,266 @@
+//! SecureIoTOS C API Crypto Module
+//! -------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Crypto-by-handle for C code.
+//!
+//! C callers import a key once and from then on refer to it by an opaque
+//! `SiosKey` handle; there is no call that reads key material back out.
+//! Vendor middleware therefore never keeps raw keys in its own buffers,
+//! and a key is wiped from the slot table by `sios_key_destroy()`.
+//!
+//! Each slot is guarded by an atomic state so an operation racing with
+//! `sios_key_destroy()` fails with `SIOS_STATUS_BUSY` instead of using a
+//! half-wiped key.
+//!
+//! Only authenticated encryption is offered: AES-256-GCM through
+//! `crypto::aes`, so C code gets the same implementation (and hardware
+//! backend) as the Rust side. A GCM nonce must never repeat under one
+//! key; derive it from a persisted counter.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{compiler_fence, AtomicU8, Ordering};
+
+use crypto::aes::{AesError, CryptoBackend, SoftwareAes, KEY_LEN, NONCE_LEN, TAG_LEN};
+
+use crate::{status, SiosStatus};
+
+/// Number of key slots.
+pub const SIOS_KEY_SLOTS: usize = 8;
+/// AES-256 key size in bytes.
+pub const SIOS_AES256_KEY_LEN: usize = 32;
+/// GCM nonce size in bytes.
+pub const SIOS_GCM_NONCE_LEN: usize = 12;
+/// GCM tag size in bytes.
+pub const SIOS_GCM_TAG_LEN: usize = 16;
+
+// Spelled out above so cbindgen can put the values in the header
+const _: () = assert!(SIOS_AES256_KEY_LEN == KEY_LEN && SIOS_GCM_NONCE_LEN == NONCE_LEN && SIOS_GCM_TAG_LEN == TAG_LEN);
+
+/// Key handle (0 is never a valid handle).
+pub type SiosKey = u32;
+
+const FREE: u8 = 0;
+const BUSY: u8 = 1;
+const READY: u8 = 2;
+
+struct KeySlot {
+    state: AtomicU8,
+    key: UnsafeCell<[u8; KEY_LEN]>,
+}
+
+// SAFETY: `key` is only accessed by the context that moved `state` to BUSY.
+unsafe impl Sync for KeySlot {}
+
+static KEYS: [KeySlot; SIOS_KEY_SLOTS] =
+    [const { KeySlot { state: AtomicU8::new(FREE), key: UnsafeCell::new([0; KEY_LEN]) } }; SIOS_KEY_SLOTS];
+
+fn slot(k: SiosKey) -> Result<&'static KeySlot, SiosStatus> {
+    let idx = (k as usize).checked_sub(1).ok_or(SiosStatus::Invalid)?;
+    KEYS.get(idx).ok_or(SiosStatus::Invalid)
+}
+
+/// Run `f` with the key of handle `k`, holding the slot BUSY meanwhile.
+fn with_key<R>(k: SiosKey, f: impl FnOnce(&[u8; KEY_LEN]) -> R) -> Result<R, SiosStatus> {
+    let s = slot(k)?;
+    match s.state.compare_exchange(READY, BUSY, Ordering::Acquire, Ordering::Relaxed) {
+        Ok(_) => {}
+        Err(BUSY) => return Err(SiosStatus::Busy),
+        Err(_) => return Err(SiosStatus::Invalid),
+    }
+    // SAFETY: we own the slot while it is BUSY.
+    let r = f(unsafe { &*s.key.get() });
+    s.state.store(READY, Ordering::Release);
+    Ok(r)
+}
+
+fn wipe(key: &mut [u8; KEY_LEN]) {
+    for b in key.iter_mut() {
+        // SAFETY: `b` is a valid, exclusive reference.
+        unsafe { core::ptr::write_volatile(b, 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+}
+
+impl From<AesError> for SiosStatus {
+    fn from(e: AesError) -> Self {
+        match e {
+            AesError::AuthFailed => SiosStatus::AuthFailed,
+            AesError::InvalidLength | AesError::Padding => SiosStatus::Invalid,
+            AesError::Unsupported => SiosStatus::Unsupported,
+            AesError::Hardware(_) => SiosStatus::Unknown,
+        }
+    }
+}
+
+/// Import an AES-256 key. The caller should wipe its copy afterwards.
+///
+/// # Safety
+/// `key` must be valid for reads of `len` bytes, `out` for one write.
+#[no_mangle]
+pub unsafe extern "C" fn sios_key_import_aes256(key: *const u8, len: usize, out: *mut SiosKey) -> SiosStatus {
+    if key.is_null() || len != KEY_LEN {
+        return SiosStatus::Invalid;
+    }
+    let Some(out) = out.as_mut() else {
+        return SiosStatus::Invalid;
+    };
+    for (idx, s) in KEYS.iter().enumerate() {
+        if s.state.compare_exchange(FREE, BUSY, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+            (*s.key.get()).copy_from_slice(core::slice::from_raw_parts(key, len));
+            s.state.store(READY, Ordering::Release);
+            *out = idx as SiosKey + 1;
+            return SiosStatus::Ok;
+        }
+    }
+    SiosStatus::NoSlot
+}
+
+/// Wipe a key and free its slot.
+#[no_mangle]
+pub extern "C" fn sios_key_destroy(k: SiosKey) -> SiosStatus {
+    status((|| {
+        let s = slot(k)?;
+        match s.state.compare_exchange(READY, BUSY, Ordering::Acquire, Ordering::Relaxed) {
+            Ok(_) => {}
+            Err(BUSY) => return Err(SiosStatus::Busy),
+            Err(_) => return Err(SiosStatus::Invalid),
+        }
+        // SAFETY: we own the slot while it is BUSY.
+        wipe(unsafe { &mut *s.key.get() });
+        s.state.store(FREE, Ordering::Release);
+        Ok(())
+    })())
+}
+
+/// AES-256-GCM encrypt `len` bytes in place and write the 16-byte tag
+/// over `aad` and the ciphertext to `tag`.
+///
+/// # Safety
+/// `nonce` must be valid for 12 byte reads, `aad` for `aad_len` (may be
+/// null if 0), `buf` for `len` byte reads/writes, `tag` for 16 byte writes.
+#[no_mangle]
+pub unsafe extern "C" fn sios_aes256_gcm_seal(
+    k: SiosKey,
+    nonce: *const u8,
+    aad: *const u8,
+    aad_len: usize,
+    buf: *mut u8,
+    len: usize,
+    tag: *mut u8,
+) -> SiosStatus {
+    let Some((nonce, aad, data)) = gcm_args(nonce, aad, aad_len, buf, len) else {
+        return SiosStatus::Invalid;
+    };
+    if tag.is_null() {
+        return SiosStatus::Invalid;
+    }
+    status(with_key(k, |key| SoftwareAes.gcm_encrypt(key, nonce, aad, data)).and_then(|r| {
+        core::slice::from_raw_parts_mut(tag, TAG_LEN).copy_from_slice(&r?);
+        Ok(())
+    }))
+}
+
+/// Check `tag` and AES-256-GCM decrypt `len` bytes in place. On
+/// `SIOS_STATUS_AUTH_FAILED` the buffer is wiped.
+///
+/// # Safety
+/// As for `sios_aes256_gcm_seal`, with `tag` valid for 16 byte reads.
+#[no_mangle]
+pub unsafe extern "C" fn sios_aes256_gcm_open(
+    k: SiosKey,
+    nonce: *const u8,
+    aad: *const u8,
+    aad_len: usize,
+    buf: *mut u8,
+    len: usize,
+    tag: *const u8,
+) -> SiosStatus {
+    let Some((nonce, aad, data)) = gcm_args(nonce, aad, aad_len, buf, len) else {
+        return SiosStatus::Invalid;
+    };
+    let Some(tag) = tag.cast::<[u8; TAG_LEN]>().as_ref() else {
+        return SiosStatus::Invalid;
+    };
+    let r = with_key(k, |key| SoftwareAes.gcm_decrypt(key, nonce, aad, data, tag)).and_then(|r| Ok(r?));
+    if r == Err(SiosStatus::AuthFailed) {
+        // Never hand out unauthenticated plaintext
+        data.fill(0);
+    }
+    status(r)
+}
+
+/// Borrow the buffers of a GCM call; `None` if a required pointer is null.
+unsafe fn gcm_args<'a>(
+    nonce: *const u8,
+    aad: *const u8,
+    aad_len: usize,
+    buf: *mut u8,
+    len: usize,
+) -> Option<(&'a [u8; NONCE_LEN], &'a [u8], &'a mut [u8])> {
+    let nonce = nonce.cast::<[u8; NONCE_LEN]>().as_ref()?;
+    let aad = match aad_len {
+        0 => &[][..],
+        _ if aad.is_null() => return None,
+        _ => core::slice::from_raw_parts(aad, aad_len),
+    };
+    let data = match len {
+        0 => &mut [][..],
+        _ if buf.is_null() => return None,
+        _ => core::slice::from_raw_parts_mut(buf, len),
+    };
+    Some((nonce, aad, data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // NIST GCM test vectors, AES-256 test case 14 (all-zero key, nonce and
+    // 16-byte plaintext)
+    const CT: [u8; 16] = [
+        0xce, 0xa7, 0x40, 0x3d, 0x4d, 0x60, 0x6b, 0x6e, 0x07, 0x4e, 0xc5, 0xd3, 0xba, 0xf3, 0x9d, 0x18,
+    ];
+    const TAG: [u8; 16] = [
+        0xd0, 0xd1, 0xc8, 0xa7, 0x99, 0x99, 0x6b, 0xf0, 0x26, 0x5b, 0x98, 0xb5, 0xd4, 0x8a, 0xb9, 0x19,
+    ];
+
+    #[test]
+    fn gcm_by_handle() {
+        let mut k = 0;
+        let key = [0u8; 32];
+        assert_eq!(unsafe { sios_key_import_aes256(key.as_ptr(), 16, &mut k) }, SiosStatus::Invalid);
+        assert_eq!(unsafe { sios_key_import_aes256(key.as_ptr(), key.len(), &mut k) }, SiosStatus::Ok);
+
+        let nonce = [0u8; 12];
+        let mut data = [0u8; 16];
+        let mut tag = [0u8; 16];
+        let seal = |data: &mut [u8; 16], tag: &mut [u8; 16]| unsafe {
+            sios_aes256_gcm_seal(k, nonce.as_ptr(), core::ptr::null(), 0, data.as_mut_ptr(), 16, tag.as_mut_ptr())
+        };
+        assert_eq!(seal(&mut data, &mut tag), SiosStatus::Ok);
+        assert_eq!((data, tag), (CT, TAG));
+
+        let open = |data: &mut [u8; 16], aad: &[u8], tag: &[u8; 16]| unsafe {
+            sios_aes256_gcm_open(k, nonce.as_ptr(), aad.as_ptr(), aad.len(), data.as_mut_ptr(), 16, tag.as_ptr())
+        };
+        let mut forged = data;
+        assert_eq!(open(&mut forged, b"hdr", &tag), SiosStatus::AuthFailed, "AAD is authenticated");
+        assert_eq!(forged, [0; 16], "no unauthenticated plaintext");
+        assert_eq!(open(&mut data, b"", &tag), SiosStatus::Ok);
+        assert_eq!(data, [0; 16]);
+
+        let null_nonce = unsafe {
+            sios_aes256_gcm_seal(k, core::ptr::null(), core::ptr::null(), 0, data.as_mut_ptr(), 16, tag.as_mut_ptr())
+        };
+        assert_eq!(null_nonce, SiosStatus::Invalid);
+
+        assert_eq!(sios_key_destroy(k), SiosStatus::Ok);
+        assert_eq!(seal(&mut data, &mut tag), SiosStatus::Invalid);
+    }
+}
@@ -0,0 +1,588 @@
+//! SecureIoTOS C API FreeRTOS Compatibility Module
+//! -----------------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! A subset of the FreeRTOS API on top of SecureIoTOS primitives, so
+//! existing FreeRTOS application code can be ported with few changes.
+//! C code includes the headers in `include/freertos/` (`FreeRTOS.h`,
+//! `task.h`, `queue.h`, `semphr.h`) instead of the FreeRTOS ones.
+//!
+//! Mapping:
+//!
+//! - queues → C API queue pool (`sios_queue_*`), plus IPC wait lists for
+//!   blocking send/receive with timeouts
+//! - semaphores → atomic counters with wait lists (binary, counting and
+//!   mutex; mutexes have no priority inheritance)
+//! - delays and timeouts → `kernel::time` (tick count, timed wakeups)
+//! - tasks → whatever `TaskSpawner` the platform installs
+//!
+//! Blocking goes through the scheduler hooks in `ipc::wait`; before they
+//! are installed, blocking calls spin.
+//!
+//! Not provided: task notifications, software timers, event groups, queue
+//! sets, stream/message buffers, `...FromISR` wake-up-higher-priority
+//! semantics (the `pxHigherPriorityTaskWoken` out-parameter is always set
+//! to `pdFALSE`; the woken task runs at the next scheduling point).
+
+#![allow(non_snake_case, non_camel_case_types, non_upper_case_globals)]
+
+use core::ffi::{c_char, c_void};
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU32, AtomicU8, AtomicUsize, Ordering};
+
+use ipc::wait::{parker, TaskId, WaitList};
+use kernel::time::{self, reached};
+
+use crate::queue::{self, SiosQueue, SIOS_QUEUE_DEPTH, SIOS_QUEUE_MSG_SIZE};
+use crate::SiosStatus;
+
+pub type BaseType_t = i32;
+pub type UBaseType_t = u32;
+pub type TickType_t = u32;
+pub type TaskFunction_t = extern "C" fn(*mut c_void);
+
+pub const pdFALSE: BaseType_t = 0;
+pub const pdTRUE: BaseType_t = 1;
+pub const pdPASS: BaseType_t = pdTRUE;
+pub const pdFAIL: BaseType_t = pdFALSE;
+pub const errQUEUE_EMPTY: BaseType_t = 0;
+pub const errQUEUE_FULL: BaseType_t = 0;
+pub const errCOULD_NOT_ALLOCATE_REQUIRED_MEMORY: BaseType_t = -1;
+pub const portMAX_DELAY: TickType_t = 0xFFFF_FFFF;
+/// Tick rate of the kernel clock (`kernel::time::TICK_HZ`).
+pub const configTICK_RATE_HZ: TickType_t = 1000;
+const _: () = assert!(configTICK_RATE_HZ == time::TICK_HZ);
+
+/// Number of queue/semaphore objects available to FreeRTOS code.
+pub const configSIOS_MAX_QUEUES: usize = 16;
+
+// ---------------------------
+// Timed waits
+// ---------------------------
+
+/// Retry `attempt` until it succeeds or `ticks` elapse, parking the task on
+/// `list` (if any) with a kernel timer wakeup in between.
+///
+/// A zero timeout never parks, which is what the `...FromISR` calls use;
+/// any other timeout is task-only.
+fn block_for<T>(list: Option<&WaitList>, ticks: TickType_t, mut attempt: impl FnMut() -> Option<T>) -> Option<T> {
+    if ticks != 0 {
+        ipc::isr::assert_task_context("blocking FreeRTOS call");
+    }
+    let deadline = (ticks != portMAX_DELAY).then(|| time::ticks().wrapping_add(ticks));
+    loop {
+        if let Some(v) = attempt() {
+            return Some(v);
+        }
+        if deadline.is_some_and(|d| reached(time::ticks(), d)) {
+            return None;
+        }
+        let Some(h) = parker() else {
+            core::hint::spin_loop();
+            continue;
+        };
+        let me = (h.current)();
+        let listed = list.is_none_or(|l| l.register(me));
+        let timed = deadline.is_none_or(|d| time::arm_wakeup(me, d));
+        if listed && timed {
+            // Re-check after registering so a concurrent wakeup is not lost.
+            if let Some(v) = attempt() {
+                unregister(list, me);
+                return Some(v);
+            }
+            (h.park)();
+        } else {
+            // Out of wait slots: fall back to polling.
+            core::hint::spin_loop();
+        }
+        unregister(list, me);
+    }
+}
+
+fn unregister(list: Option<&WaitList>, task: TaskId) {
+    if let Some(l) = list {
+        l.remove(task);
+    }
+    time::cancel_wakeup(task);
+}
+
+// ---------------------------
+// Tasks
+// ---------------------------
+
+/// Opaque task control block; handles are `task id + 1` cast to a pointer.
+pub struct tskTaskControlBlock {
+    _private: [u8; 0],
+}
+pub type TaskHandle_t = *mut tskTaskControlBlock;
+
+/// Platform hooks used by `xTaskCreate()` / `vTaskDelete()`.
+pub struct TaskSpawner {
+    /// Create a task running `entry(param)` with a stack of `stack_words`
+    /// 32-bit words. Returns the new task's ID.
+    pub spawn: fn(entry: TaskFunction_t, param: *mut c_void, stack_words: usize, priority: u32) -> Option<TaskId>,
+    /// Remove a task and reclaim its stack.
+    pub delete: fn(TaskId),
+}
+
+static SPAWNER: AtomicPtr<TaskSpawner> = AtomicPtr::new(ptr::null_mut());
+
+/// Install the task backend. Until this is called `xTaskCreate()` fails.
+pub fn set_task_spawner(spawner: &'static TaskSpawner) {
+    SPAWNER.store(spawner as *const TaskSpawner as *mut TaskSpawner, Ordering::Release);
+}
+
+fn spawner() -> Option<&'static TaskSpawner> {
+    // SAFETY: only ever set from a `&'static TaskSpawner`.
+    unsafe { SPAWNER.load(Ordering::Acquire).as_ref() }
+}
+
+fn handle_to_id(h: TaskHandle_t) -> Option<TaskId> {
+    (h as usize as TaskId).checked_sub(1)
+}
+
+/// Create a task. `pcName` is accepted for source compatibility only.
+///
+/// # Safety
+/// `pxCreatedTask` must be null or valid for one write.
+#[no_mangle]
+pub unsafe extern "C" fn xTaskCreate(
+    pvTaskCode: TaskFunction_t,
+    _pcName: *const c_char,
+    usStackDepth: u16,
+    pvParameters: *mut c_void,
+    uxPriority: UBaseType_t,
+    pxCreatedTask: *mut TaskHandle_t,
+) -> BaseType_t {
+    let Some(id) = spawner().and_then(|s| (s.spawn)(pvTaskCode, pvParameters, usStackDepth as usize, uxPriority))
+    else {
+        return errCOULD_NOT_ALLOCATE_REQUIRED_MEMORY;
+    };
+    if let Some(out) = pxCreatedTask.as_mut() {
+        *out = id.wrapping_add(1) as usize as TaskHandle_t;
+    }
+    pdPASS
+}
+
+/// Delete a task (`NULL` = the calling task, which then never returns).
+#[no_mangle]
+pub extern "C" fn vTaskDelete(xTaskToDelete: TaskHandle_t) {
+    let me = parker().map(|h| (h.current)());
+    let Some(id) = handle_to_id(xTaskToDelete).or(me) else {
+        return;
+    };
+    if let Some(s) = spawner() {
+        (s.delete)(id);
+    }
+    if Some(id) == me {
+        loop {
+            match parker() {
+                Some(h) => (h.park)(),
+                None => core::hint::spin_loop(),
+            }
+        }
+    }
+}
+
+/// Block the calling task for `xTicksToDelay` ticks (0 returns at once).
+#[no_mangle]
+pub extern "C" fn vTaskDelay(xTicksToDelay: TickType_t) {
+    time::sleep_ticks(xTicksToDelay);
+}
+
+/// Block until `*pxPreviousWakeTime + xTimeIncrement`, then advance
+/// `*pxPreviousWakeTime` by the increment (fixed-rate loops).
+///
+/// # Safety
+/// `pxPreviousWakeTime` must be valid for reads and writes.
+#[no_mangle]
+pub unsafe extern "C" fn vTaskDelayUntil(pxPreviousWakeTime: *mut TickType_t, xTimeIncrement: TickType_t) {
+    if let Some(prev) = pxPreviousWakeTime.as_mut() {
+        time::delay_until(prev, xTimeIncrement);
+    }
+}
+
+/// Ticks since start-up.
+#[no_mangle]
+pub extern "C" fn xTaskGetTickCount() -> TickType_t {
+    time::ticks()
+}
+
+/// ISR variant of `xTaskGetTickCount()`.
+#[no_mangle]
+pub extern "C" fn xTaskGetTickCountFromISR() -> TickType_t {
+    xTaskGetTickCount()
+}
+
+// ---------------------------
+// Queues and semaphores
+// ---------------------------
+
+const KIND_FREE: u8 = 0;
+const KIND_QUEUE: u8 = 1;
+const KIND_SEMAPHORE: u8 = 2;
+
+/// Queue or semaphore object. FreeRTOS uses one handle type for both.
+pub struct QueueDefinition {
+    kind: AtomicU8,
+    /// Backing C API queue (queues only)
+    queue: AtomicU32,
+    item_size: AtomicUsize,
+    /// Current and maximum count (semaphores only)
+    count: AtomicU32,
+    max: AtomicU32,
+    not_empty: WaitList,
+    not_full: WaitList,
+}
+
+pub type QueueHandle_t = *mut QueueDefinition;
+pub type SemaphoreHandle_t = QueueHandle_t;
+
+static OBJECTS: [QueueDefinition; configSIOS_MAX_QUEUES] = [const {
+    QueueDefinition {
+        kind: AtomicU8::new(KIND_FREE),
+        queue: AtomicU32::new(0),
+        item_size: AtomicUsize::new(0),
+        count: AtomicU32::new(0),
+        max: AtomicU32::new(0),
+        not_empty: WaitList::new(),
+        not_full: WaitList::new(),
+    }
+}; configSIOS_MAX_QUEUES];
+
+/// Claim a free object and hand it to `init`; `init` returns `false` to
+/// release it again.
+fn alloc_object(kind: u8, init: impl FnOnce(&QueueDefinition) -> bool) -> QueueHandle_t {
+    for obj in OBJECTS.iter() {
+        if obj.kind.compare_exchange(KIND_FREE, kind, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+            if init(obj) {
+                return obj as *const QueueDefinition as QueueHandle_t;
+            }
+            obj.kind.store(KIND_FREE, Ordering::Release);
+            return ptr::null_mut();
+        }
+    }
+    ptr::null_mut()
+}
+
+/// Resolve a handle, rejecting anything that does not point into the pool.
+fn object(h: QueueHandle_t, kind: u8) -> Option<&'static QueueDefinition> {
+    let obj = OBJECTS.iter().find(|o| ptr::eq(*o, h))?;
+    (obj.kind.load(Ordering::Acquire) == kind).then_some(obj)
+}
+
+/// Create a queue of `uxQueueLength` items of `uxItemSize` bytes. Every
+/// queue holds `SIOS_QUEUE_DEPTH` items; longer queues and items larger
+/// than `SIOS_QUEUE_MSG_SIZE` cannot be created.
+#[no_mangle]
+pub extern "C" fn xQueueCreate(uxQueueLength: UBaseType_t, uxItemSize: UBaseType_t) -> QueueHandle_t {
+    let (len, size) = (uxQueueLength as usize, uxItemSize as usize);
+    if len == 0 || len > SIOS_QUEUE_DEPTH || size == 0 || size > SIOS_QUEUE_MSG_SIZE {
+        return ptr::null_mut();
+    }
+    alloc_object(KIND_QUEUE, |obj| {
+        let mut q: SiosQueue = 0;
+        // SAFETY: `q` is a valid out-pointer.
+        if unsafe { queue::sios_queue_create(&mut q) } != SiosStatus::Ok {
+            return false;
+        }
+        obj.queue.store(q, Ordering::Relaxed);
+        obj.item_size.store(size, Ordering::Release);
+        true
+    })
+}
+
+/// Delete a queue or semaphore. No task may be blocked on it.
+#[no_mangle]
+pub extern "C" fn vQueueDelete(xQueue: QueueHandle_t) {
+    if let Some(obj) = object(xQueue, KIND_QUEUE) {
+        let _ = queue::sios_queue_delete(obj.queue.load(Ordering::Relaxed));
+    } else if object(xQueue, KIND_SEMAPHORE).is_none() {
+        return;
+    }
+    if let Some(obj) = OBJECTS.iter().find(|o| ptr::eq(*o, xQueue)) {
+        obj.kind.store(KIND_FREE, Ordering::Release);
+    }
+}
+
+/// Copy one item into the queue, waiting up to `xTicksToWait` for space.
+///
+/// # Safety
+/// `pvItemToQueue` must be valid for reads of the queue's item size.
+#[no_mangle]
+pub unsafe extern "C" fn xQueueSend(
+    xQueue: QueueHandle_t,
+    pvItemToQueue: *const c_void,
+    xTicksToWait: TickType_t,
+) -> BaseType_t {
+    let Some(obj) = object(xQueue, KIND_QUEUE) else {
+        return pdFAIL;
+    };
+    let (q, size) = (obj.queue.load(Ordering::Relaxed), obj.item_size.load(Ordering::Acquire));
+    let sent = block_for(Some(&obj.not_full), xTicksToWait, || {
+        match queue::sios_queue_send(q, pvItemToQueue as *const u8, size) {
+            SiosStatus::Full => None,
+            st => Some(st == SiosStatus::Ok),
+        }
+    });
+    if sent == Some(true) {
+        obj.not_empty.wake_one();
+        pdPASS
+    } else {
+        errQUEUE_FULL
+    }
+}
+
+/// Alias of `xQueueSend()` (FIFO order).
+///
+/// # Safety
+/// Same as `xQueueSend()`.
+#[no_mangle]
+pub unsafe extern "C" fn xQueueSendToBack(
+    xQueue: QueueHandle_t,
+    pvItemToQueue: *const c_void,
+    xTicksToWait: TickType_t,
+) -> BaseType_t {
+    xQueueSend(xQueue, pvItemToQueue, xTicksToWait)
+}
+
+/// Receive one item, waiting up to `xTicksToWait` for data.
+///
+/// # Safety
+/// `pvBuffer` must be valid for writes of the queue's item size.
+#[no_mangle]
+pub unsafe extern "C" fn xQueueReceive(
+    xQueue: QueueHandle_t,
+    pvBuffer: *mut c_void,
+    xTicksToWait: TickType_t,
+) -> BaseType_t {
+    let Some(obj) = object(xQueue, KIND_QUEUE) else {
+        return pdFAIL;
+    };
+    if pvBuffer.is_null() {
+        return pdFAIL;
+    }
+    let (q, size) = (obj.queue.load(Ordering::Relaxed), obj.item_size.load(Ordering::Acquire));
+    let mut item = [0u8; SIOS_QUEUE_MSG_SIZE];
+    let got = block_for(Some(&obj.not_empty), xTicksToWait, || {
+        let mut len = 0;
+        match queue::sios_queue_recv(q, item.as_mut_ptr(), item.len(), &mut len) {
+            SiosStatus::Empty => None,
+            st => Some(st == SiosStatus::Ok),
+        }
+    });
+    if got != Some(true) {
+        return errQUEUE_EMPTY;
+    }
+    let Some(src) = item.get(..size) else {
+        return pdFAIL;
+    };
+    core::slice::from_raw_parts_mut(pvBuffer as *mut u8, size).copy_from_slice(src);
+    obj.not_full.wake_one();
+    pdPASS
+}
+
+/// ISR variant of `xQueueSend()`; never blocks.
+///
+/// # Safety
+/// Same as `xQueueSend()`; `pxHigherPriorityTaskWoken` may be null.
+#[no_mangle]
+pub unsafe extern "C" fn xQueueSendFromISR(
+    xQueue: QueueHandle_t,
+    pvItemToQueue: *const c_void,
+    pxHigherPriorityTaskWoken: *mut BaseType_t,
+) -> BaseType_t {
+    if let Some(w) = pxHigherPriorityTaskWoken.as_mut() {
+        *w = pdFALSE;
+    }
+    xQueueSend(xQueue, pvItemToQueue, 0)
+}
+
+/// ISR variant of `xQueueReceive()`; never blocks.
+///
+/// # Safety
+/// Same as `xQueueReceive()`; `pxHigherPriorityTaskWoken` may be null.
+#[no_mangle]
+pub unsafe extern "C" fn xQueueReceiveFromISR(
+    xQueue: QueueHandle_t,
+    pvBuffer: *mut c_void,
+    pxHigherPriorityTaskWoken: *mut BaseType_t,
+) -> BaseType_t {
+    if let Some(w) = pxHigherPriorityTaskWoken.as_mut() {
+        *w = pdFALSE;
+    }
+    xQueueReceive(xQueue, pvBuffer, 0)
+}
+
+/// Number of items in a queue, or the count of a semaphore.
+#[no_mangle]
+pub extern "C" fn uxQueueMessagesWaiting(xQueue: QueueHandle_t) -> UBaseType_t {
+    if let Some(obj) = object(xQueue, KIND_QUEUE) {
+        return queue::sios_queue_count(obj.queue.load(Ordering::Relaxed)) as UBaseType_t;
+    }
+    object(xQueue, KIND_SEMAPHORE).map_or(0, |obj| obj.count.load(Ordering::Acquire))
+}
+
+fn semaphore_create(max: u32, initial: u32) -> SemaphoreHandle_t {
+    if max == 0 || initial > max {
+        return ptr::null_mut();
+    }
+    alloc_object(KIND_SEMAPHORE, |obj| {
+        obj.max.store(max, Ordering::Relaxed);
+        obj.count.store(initial, Ordering::Release);
+        true
+    })
+}
+
+/// Binary semaphore, created empty (as in FreeRTOS).
+#[no_mangle]
+pub extern "C" fn xSemaphoreCreateBinary() -> SemaphoreHandle_t {
+    semaphore_create(1, 0)
+}
+
+/// Counting semaphore.
+#[no_mangle]
+pub extern "C" fn xSemaphoreCreateCounting(uxMaxCount: UBaseType_t, uxInitialCount: UBaseType_t) -> SemaphoreHandle_t {
+    semaphore_create(uxMaxCount, uxInitialCount)
+}
+
+/// Mutex, created available. NOTE: no priority inheritance and no owner
+/// tracking; it behaves like a binary semaphore.
+#[no_mangle]
+pub extern "C" fn xSemaphoreCreateMutex() -> SemaphoreHandle_t {
+    semaphore_create(1, 1)
+}
+
+/// Take a semaphore, waiting up to `xTicksToWait`.
+#[no_mangle]
+pub extern "C" fn xSemaphoreTake(xSemaphore: SemaphoreHandle_t, xTicksToWait: TickType_t) -> BaseType_t {
+    let Some(obj) = object(xSemaphore, KIND_SEMAPHORE) else {
+        return pdFAIL;
+    };
+    let taken = block_for(Some(&obj.not_empty), xTicksToWait, || {
+        obj.count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |c| c.checked_sub(1))
+            .ok()
+    });
+    if taken.is_some() {
+        pdPASS
+    } else {
+        pdFAIL
+    }
+}
+
+/// Give a semaphore. Fails if it is already at its maximum count.
+#[no_mangle]
+pub extern "C" fn xSemaphoreGive(xSemaphore: SemaphoreHandle_t) -> BaseType_t {
+    let Some(obj) = object(xSemaphore, KIND_SEMAPHORE) else {
+        return pdFAIL;
+    };
+    let max = obj.max.load(Ordering::Relaxed);
+    let given = obj.count.fetch_update(Ordering::AcqRel, Ordering::Acquire, |c| (c < max).then_some(c + 1));
+    if given.is_err() {
+        return pdFAIL;
+    }
+    obj.not_empty.wake_one();
+    pdPASS
+}
+
+/// ISR variant of `xSemaphoreGive()`.
+///
+/// # Safety
+/// `pxHigherPriorityTaskWoken` must be null or valid for one write.
+#[no_mangle]
+pub unsafe extern "C" fn xSemaphoreGiveFromISR(
+    xSemaphore: SemaphoreHandle_t,
+    pxHigherPriorityTaskWoken: *mut BaseType_t,
+) -> BaseType_t {
+    if let Some(w) = pxHigherPriorityTaskWoken.as_mut() {
+        *w = pdFALSE;
+    }
+    xSemaphoreGive(xSemaphore)
+}
+
+/// ISR variant of `xSemaphoreTake()`; never blocks.
+///
+/// # Safety
+/// `pxHigherPriorityTaskWoken` must be null or valid for one write.
+#[no_mangle]
+pub unsafe extern "C" fn xSemaphoreTakeFromISR(
+    xSemaphore: SemaphoreHandle_t,
+    pxHigherPriorityTaskWoken: *mut BaseType_t,
+) -> BaseType_t {
+    if let Some(w) = pxHigherPriorityTaskWoken.as_mut() {
+        *w = pdFALSE;
+    }
+    xSemaphoreTake(xSemaphore, 0)
+}
+
+/// Delete a semaphore (same as `vQueueDelete()`).
+#[no_mangle]
+pub extern "C" fn vSemaphoreDelete(xSemaphore: SemaphoreHandle_t) {
+    vQueueDelete(xSemaphore)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn queue_roundtrip_and_timeouts() {
+        let q = xQueueCreate(4, 4);
+        assert!(!q.is_null());
+        assert!(xQueueCreate(SIOS_QUEUE_DEPTH as u32 + 1, 4).is_null());
+
+        let item: u32 = 0xDEAD_BEEF;
+        let mut out: u32 = 0;
+        unsafe {
+            assert_eq!(xQueueSend(q, &item as *const u32 as *const c_void, 0), pdPASS);
+            assert_eq!(uxQueueMessagesWaiting(q), 1);
+            assert_eq!(xQueueReceive(q, &mut out as *mut u32 as *mut c_void, 0), pdPASS);
+            assert_eq!(out, item);
+            assert_eq!(xQueueReceiveFromISR(q, &mut out as *mut u32 as *mut c_void, ptr::null_mut()), errQUEUE_EMPTY);
+        }
+        vQueueDelete(q);
+        assert_eq!(uxQueueMessagesWaiting(q), 0);
+        assert_eq!(unsafe { xQueueSend(q, &item as *const u32 as *const c_void, 0) }, pdFAIL);
+    }
+
+    #[test]
+    fn semaphores_count_and_saturate() {
+        let bin = xSemaphoreCreateBinary();
+        assert_eq!(xSemaphoreTake(bin, 0), pdFAIL);
+        assert_eq!(xSemaphoreGive(bin), pdPASS);
+        assert_eq!(xSemaphoreGive(bin), pdFAIL, "binary semaphore saturates at 1");
+        assert_eq!(xSemaphoreTake(bin, 0), pdPASS);
+
+        let counting = xSemaphoreCreateCounting(3, 2);
+        assert_eq!(uxQueueMessagesWaiting(counting), 2);
+        assert_eq!(unsafe { xSemaphoreGiveFromISR(counting, ptr::null_mut()) }, pdPASS);
+        assert_eq!(xSemaphoreGive(counting), pdFAIL);
+
+        let mutex = xSemaphoreCreateMutex();
+        assert_eq!(xSemaphoreTake(mutex, portMAX_DELAY), pdPASS);
+        assert_eq!(xSemaphoreGive(mutex), pdPASS);
+
+        for s in [bin, counting, mutex] {
+            vSemaphoreDelete(s);
+        }
+        assert_eq!(xSemaphoreTake(bin, 0), pdFAIL);
+    }
+
+    #[test]
+    fn ticks_and_task_creation() {
+        let before = xTaskGetTickCount();
+        time::on_tick();
+        assert!(xTaskGetTickCount().wrapping_sub(before) >= 1);
+        // Without a spawner task creation fails cleanly.
+        extern "C" fn entry(_: *mut c_void) {}
+        let mut h: TaskHandle_t = ptr::null_mut();
+        let r = unsafe { xTaskCreate(entry, ptr::null(), 128, ptr::null_mut(), 1, &mut h) };
+        assert_eq!(r, errCOULD_NOT_ALLOCATE_REQUIRED_MEMORY);
+    }
+}
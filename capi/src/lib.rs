@@ -0,0 +1,147 @@
+//! SecureIoTOS C API Module
+//! ------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Stable C interface to kernel services, so existing C application code
+//! and vendor middleware can run as SecureIoTOS tasks next to Rust ones.
+//!
+//! - `syscall`: user-side syscall stubs (`svc` trampolines)
+//! - `queue`:   message queues addressed by handle
+//! - `crypto`:  crypto-by-handle; key material never leaves the API
+//! - `freertos`: FreeRTOS API subset (feature `freertos`)
+//!
+//! The C header `include/secureiotos.h` is generated from this crate with
+//! cbindgen (see `cbindgen.toml`). Every function returns a `SiosStatus`
+//! (0 = success, negative = error) unless documented otherwise; output
+//! values are written through pointer arguments.
+
+#![no_std]
+// Panics must not unwind into C callers: every error is a `SiosStatus`.
+#![cfg_attr(not(test), deny(
+    clippy::panic,
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::indexing_slicing,
+    clippy::unreachable,
+    clippy::todo,
+    clippy::unimplemented
+))]
+
+// Hosted builds (tests, the staticlib for a simulator) take the panic
+// handler and allocator from std; firmware images bring their own.
+#[cfg(any(test, not(target_os = "none")))]
+extern crate std;
+
+pub mod crypto;
+#[cfg(feature = "freertos")]
+pub mod freertos;
+pub mod queue;
+pub mod syscall;
+
+use kernel::syscall::SyscallError;
+
+/// Result code returned by every C API function.
+#[repr(i32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SiosStatus {
+    Ok = 0,
+    /// Bad argument (null pointer, bad length, invalid handle, ...)
+    Invalid = -1,
+    /// The kernel rejected a user pointer
+    BadAddress = -2,
+    /// The calling task lacks the required capability
+    PermissionDenied = -3,
+    /// Buffer or message larger than allowed
+    TooLarge = -4,
+    NotFound = -5,
+    Unsupported = -6,
+    /// Queue full
+    Full = -7,
+    /// Queue empty
+    Empty = -8,
+    /// No free queue/key slot
+    NoSlot = -9,
+    /// Object is in use by another context; retry
+    Busy = -10,
+    /// Nothing arrived before the timeout
+    TimedOut = -11,
+    /// Authenticated decryption failed: data, AAD, key or nonce are wrong
+    AuthFailed = -12,
+    Unknown = -100,
+}
+
+impl From<SyscallError> for SiosStatus {
+    fn from(e: SyscallError) -> Self {
+        match e {
+            SyscallError::Invalid => SiosStatus::Invalid,
+            SyscallError::BadAddress => SiosStatus::BadAddress,
+            SyscallError::PermissionDenied => SiosStatus::PermissionDenied,
+            SyscallError::TooLarge => SiosStatus::TooLarge,
+            SyscallError::NotFound => SiosStatus::NotFound,
+            SyscallError::Unsupported => SiosStatus::Unsupported,
+            SyscallError::Full => SiosStatus::Full,
+            SyscallError::TimedOut => SiosStatus::TimedOut,
+            SyscallError::NoSlot => SiosStatus::NoSlot,
+            SyscallError::Unknown => SiosStatus::Unknown,
+        }
+    }
+}
+
+impl SiosStatus {
+    /// Decode a raw syscall return value (high bit set = error code).
+    pub fn from_raw(raw: u32) -> Result<u32, SiosStatus> {
+        if raw & 0x8000_0000 == 0 {
+            return Ok(raw);
+        }
+        Err(match raw & 0x7FFF_FFFF {
+            1 => SiosStatus::Invalid,
+            2 => SiosStatus::BadAddress,
+            3 => SiosStatus::PermissionDenied,
+            4 => SiosStatus::TooLarge,
+            5 => SiosStatus::NotFound,
+            6 => SiosStatus::Unsupported,
+            7 => SiosStatus::Full,
+            8 => SiosStatus::TimedOut,
+            9 => SiosStatus::NoSlot,
+            _ => SiosStatus::Unknown,
+        })
+    }
+}
+
+/// A C firmware image has no Rust binary crate to define the panic
+/// handler, so the staticlib brings one. The deny gates above keep panics
+/// out of this crate; this only catches ones from dependencies.
+#[cfg(all(feature = "panic-handler", not(test)))]
+#[panic_handler]
+fn panic(_info: &core::panic::PanicInfo) -> ! {
+    kernel::kfail!("panic in C API layer");
+    loop {
+        core::hint::spin_loop();
+    }
+}
+
+/// Collapse an internal result into the C return code.
+#[inline]
+pub(crate) fn status(r: Result<(), SiosStatus>) -> SiosStatus {
+    match r {
+        Ok(()) => SiosStatus::Ok,
+        Err(e) => e,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_results_decode_like_the_kernel_encodes_them() {
+        assert_eq!(SiosStatus::from_raw(42), Ok(42));
+        assert_eq!(SiosStatus::from_raw(0x8000_0003), Err(SiosStatus::PermissionDenied));
+        assert_eq!(SiosStatus::from_raw(0x8000_FFFF), Err(SiosStatus::Unknown));
+    }
+}
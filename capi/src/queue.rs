@@ -0,0 +1,187 @@
+//! SecureIoTOS C API Queue Module
+//! ------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Message queues for C code, addressed by handle.
+//!
+//! Rust queues are generic over depth and message size, which C cannot
+//! express, so the C API serves queues from a fixed pool of
+//! `SIOS_QUEUE_MAX` MPMC queues, each holding `SIOS_QUEUE_DEPTH` messages
+//! of up to `SIOS_QUEUE_MSG_SIZE` bytes. All operations are lock-free and
+//! may be called from tasks and interrupt handlers alike.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use ipc::{IpcMessage, MpmcQueue};
+
+use crate::{status, SiosStatus};
+
+/// Number of queues available to C code.
+pub const SIOS_QUEUE_MAX: usize = 8;
+/// Messages per queue.
+pub const SIOS_QUEUE_DEPTH: usize = 16;
+/// Maximum message size in bytes.
+pub const SIOS_QUEUE_MSG_SIZE: usize = 64;
+
+/// Queue handle (0 is never a valid handle).
+pub type SiosQueue = u32;
+
+type Queue = MpmcQueue<SIOS_QUEUE_DEPTH, SIOS_QUEUE_MSG_SIZE>;
+
+struct Entry {
+    in_use: AtomicBool,
+    queue: Queue,
+}
+
+static POOL: [Entry; SIOS_QUEUE_MAX] =
+    [const { Entry { in_use: AtomicBool::new(false), queue: Queue::new() } }; SIOS_QUEUE_MAX];
+
+fn lookup(q: SiosQueue) -> Result<&'static Queue, SiosStatus> {
+    let idx = (q as usize).checked_sub(1).ok_or(SiosStatus::Invalid)?;
+    let entry = POOL.get(idx).ok_or(SiosStatus::Invalid)?;
+    if !entry.in_use.load(Ordering::Acquire) {
+        return Err(SiosStatus::Invalid);
+    }
+    Ok(&entry.queue)
+}
+
+/// Allocate a queue from the pool.
+///
+/// # Safety
+/// `out` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn sios_queue_create(out: *mut SiosQueue) -> SiosStatus {
+    let Some(out) = out.as_mut() else {
+        return SiosStatus::Invalid;
+    };
+    for (idx, entry) in POOL.iter().enumerate() {
+        if entry.in_use.compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+            // Drop anything a previous owner left behind.
+            while entry.queue.dequeue().is_some() {}
+            *out = idx as SiosQueue + 1;
+            return SiosStatus::Ok;
+        }
+    }
+    SiosStatus::NoSlot
+}
+
+/// Return a queue to the pool. Pending messages are discarded.
+/// No other context may use the handle afterwards.
+#[no_mangle]
+pub extern "C" fn sios_queue_delete(q: SiosQueue) -> SiosStatus {
+    let Some(entry) = (q as usize).checked_sub(1).and_then(|i| POOL.get(i)) else {
+        return SiosStatus::Invalid;
+    };
+    match entry.in_use.compare_exchange(true, false, Ordering::AcqRel, Ordering::Relaxed) {
+        Ok(_) => SiosStatus::Ok,
+        Err(_) => SiosStatus::Invalid,
+    }
+}
+
+/// Copy `len` bytes into the queue. Never blocks.
+///
+/// # Safety
+/// `data` must be valid for reads of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn sios_queue_send(q: SiosQueue, data: *const u8, len: usize) -> SiosStatus {
+    status((|| {
+        let queue = lookup(q)?;
+        if data.is_null() {
+            return Err(SiosStatus::Invalid);
+        }
+        let src = core::slice::from_raw_parts(data, len);
+        let mut msg = IpcMessage::<SIOS_QUEUE_MSG_SIZE>::new();
+        msg.data.get_mut(..len).ok_or(SiosStatus::TooLarge)?.copy_from_slice(src);
+        msg.length = len;
+        queue.enqueue(msg).map_err(|_| SiosStatus::Full)
+    })())
+}
+
+/// Copy the oldest message into `buf` and store its length in `out_len`.
+/// Never blocks. `cap` must be at least `SIOS_QUEUE_MSG_SIZE`, so every
+/// message fits and none is ever dropped on receive.
+///
+/// # Safety
+/// `buf` must be valid for writes of `cap` bytes, `out_len` for one write.
+#[no_mangle]
+pub unsafe extern "C" fn sios_queue_recv(q: SiosQueue, buf: *mut u8, cap: usize, out_len: *mut usize) -> SiosStatus {
+    status((|| {
+        let queue = lookup(q)?;
+        if buf.is_null() || cap < SIOS_QUEUE_MSG_SIZE {
+            return Err(SiosStatus::Invalid);
+        }
+        let out_len = out_len.as_mut().ok_or(SiosStatus::Invalid)?;
+        let msg = queue.dequeue().ok_or(SiosStatus::Empty)?;
+        let payload = msg.data.get(..msg.length).ok_or(SiosStatus::Unknown)?;
+        core::slice::from_raw_parts_mut(buf, cap)
+            .get_mut(..payload.len())
+            .ok_or(SiosStatus::TooLarge)?
+            .copy_from_slice(payload);
+        *out_len = payload.len();
+        Ok(())
+    })())
+}
+
+/// ISR variant of `sios_queue_send`.
+///
+/// # Safety
+/// Same as `sios_queue_send`.
+#[no_mangle]
+pub unsafe extern "C" fn sios_queue_send_from_isr(q: SiosQueue, data: *const u8, len: usize) -> SiosStatus {
+    sios_queue_send(q, data, len)
+}
+
+/// ISR variant of `sios_queue_recv`.
+///
+/// # Safety
+/// Same as `sios_queue_recv`.
+#[no_mangle]
+pub unsafe extern "C" fn sios_queue_recv_from_isr(
+    q: SiosQueue,
+    buf: *mut u8,
+    cap: usize,
+    out_len: *mut usize,
+) -> SiosStatus {
+    sios_queue_recv(q, buf, cap, out_len)
+}
+
+/// Number of queued messages (0 for an invalid handle).
+#[no_mangle]
+pub extern "C" fn sios_queue_count(q: SiosQueue) -> usize {
+    lookup(q).map_or(0, |queue| queue.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn create_send_recv_delete() {
+        let mut q = 0;
+        assert_eq!(unsafe { sios_queue_create(&mut q) }, SiosStatus::Ok);
+        assert_ne!(q, 0);
+
+        let msg = b"hello";
+        assert_eq!(unsafe { sios_queue_send(q, msg.as_ptr(), msg.len()) }, SiosStatus::Ok);
+        assert_eq!(sios_queue_count(q), 1);
+
+        let mut buf = [0u8; SIOS_QUEUE_MSG_SIZE];
+        let mut len = 0;
+        assert_eq!(unsafe { sios_queue_recv(q, buf.as_mut_ptr(), buf.len(), &mut len) }, SiosStatus::Ok);
+        assert_eq!(&buf[..len], msg);
+        assert_eq!(unsafe { sios_queue_recv(q, buf.as_mut_ptr(), buf.len(), &mut len) }, SiosStatus::Empty);
+
+        let big = [0u8; SIOS_QUEUE_MSG_SIZE + 1];
+        assert_eq!(unsafe { sios_queue_send(q, big.as_ptr(), big.len()) }, SiosStatus::TooLarge);
+
+        assert_eq!(sios_queue_delete(q), SiosStatus::Ok);
+        assert_eq!(sios_queue_delete(q), SiosStatus::Invalid);
+        assert_eq!(unsafe { sios_queue_send(q, msg.as_ptr(), msg.len()) }, SiosStatus::Invalid);
+        assert_eq!(sios_queue_count(0), 0);
+    }
+}
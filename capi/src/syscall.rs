@@ -0,0 +1,208 @@
+//! SecureIoTOS C API Syscall Module
+//! --------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! User-side syscall stubs.
+//!
+//! SVC calling convention: `r0` = syscall ID, `r1`-`r3` = arguments,
+//! result in `r0` (encoded as by `kernel::syscall::syscall_entry`). The
+//! kernel's SVCall handler forwards the stacked registers to
+//! `syscall_entry`. On host builds the stubs call `syscall_entry` directly.
+
+use kernel::syscall::{FirmwareInfoAbi, MemInfo, SyscallId, TaskCpuStats};
+
+use crate::{status, SiosStatus};
+
+/// Length of `SiosFirmwareInfo::build_id`.
+pub const SIOS_BUILD_ID_LEN: usize = 20;
+const _: () = assert!(SIOS_BUILD_ID_LEN == kernel::image::BUILD_ID_LEN);
+
+/// Largest message a kernel IPC queue carries.
+pub const SIOS_IPC_MSG_SIZE: usize = 128;
+const _: () = assert!(SIOS_IPC_MSG_SIZE == kernel::syscall::MAX_MESSAGE_LEN);
+
+/// `sios_ipc_recv()` timeout that waits until a message arrives.
+pub const SIOS_WAIT_FOREVER: u32 = u32::MAX;
+const _: () = assert!(SIOS_WAIT_FOREVER == kernel::ipc_queue::WAIT_FOREVER);
+
+#[cfg(target_arch = "arm")]
+#[inline(always)]
+fn raw_syscall(id: u32, a0: usize, a1: usize, a2: usize) -> u32 {
+    let ret: u32;
+    // SAFETY: `svc` traps into the kernel, which validates every argument.
+    unsafe {
+        core::arch::asm!(
+            "svc 0",
+            inout("r0") id => ret,
+            in("r1") a0,
+            in("r2") a1,
+            in("r3") a2,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+#[cfg(not(target_arch = "arm"))]
+fn raw_syscall(id: u32, a0: usize, a1: usize, a2: usize) -> u32 {
+    kernel::syscall::syscall_entry(id, a0 as u64, a1 as u64, a2 as u64, 0, 0, 0)
+}
+
+/// Raw syscall: returns the kernel's encoded result (bit 31 set = error).
+/// Prefer the typed wrappers below.
+#[no_mangle]
+pub extern "C" fn sios_syscall(id: u32, a0: usize, a1: usize, a2: usize) -> u32 {
+    raw_syscall(id, a0, a1, a2)
+}
+
+/// Current system time in seconds.
+///
+/// # Safety
+/// `out_secs` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn sios_get_time(out_secs: *mut u32) -> SiosStatus {
+    status((|| {
+        let out = out_secs.as_mut().ok_or(SiosStatus::Invalid)?;
+        *out = SiosStatus::from_raw(raw_syscall(SyscallId::GetTime as u32, 0, 0, 0))?;
+        Ok(())
+    })())
+}
+
+/// Open a kernel message queue owned by the calling task. Other tasks
+/// send to it with `sios_ipc_send()`; only the owner can receive.
+///
+/// # Safety
+/// `out` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn sios_ipc_create_queue(out: *mut u32) -> SiosStatus {
+    status((|| {
+        let out = out.as_mut().ok_or(SiosStatus::Invalid)?;
+        *out = SiosStatus::from_raw(raw_syscall(SyscallId::CreateQueue as u32, 0, 0, 0))?;
+        Ok(())
+    })())
+}
+
+/// Send `len` bytes (at most `SIOS_IPC_MSG_SIZE`) to kernel queue `dest`.
+/// Never blocks; `SIOS_STATUS_FULL` if the queue is full.
+///
+/// # Safety
+/// `buf` must be valid for reads of `len` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn sios_ipc_send(dest: u32, buf: *const u8, len: usize) -> SiosStatus {
+    if buf.is_null() {
+        return SiosStatus::Invalid;
+    }
+    let raw = raw_syscall(SyscallId::SendMessage as u32, buf as usize, len, dest as usize);
+    status(SiosStatus::from_raw(raw).map(|_| ()))
+}
+
+/// Receive the oldest message of a queue the calling task owns, waiting
+/// up to `timeout_ms` for one (0 = poll, `SIOS_WAIT_FOREVER` = no limit).
+/// `cap` must be at least `SIOS_IPC_MSG_SIZE`.
+///
+/// # Safety
+/// `buf` must be valid for writes of `cap` bytes, `out_len` for one write.
+#[no_mangle]
+pub unsafe extern "C" fn sios_ipc_recv(
+    queue: u32,
+    buf: *mut u8,
+    cap: usize,
+    out_len: *mut usize,
+    timeout_ms: u32,
+) -> SiosStatus {
+    status((|| {
+        let out_len = out_len.as_mut().ok_or(SiosStatus::Invalid)?;
+        if buf.is_null() || cap < SIOS_IPC_MSG_SIZE {
+            return Err(SiosStatus::Invalid);
+        }
+        let raw = raw_syscall(SyscallId::ReceiveMessage as u32, buf as usize, queue as usize, timeout_ms as usize);
+        *out_len = SiosStatus::from_raw(raw)? as usize;
+        Ok(())
+    })())
+}
+
+/// Memory usage of the calling task.
+///
+/// # Safety
+/// `out` must be null or valid for writes of one `SiosMemInfo`.
+#[no_mangle]
+pub unsafe extern "C" fn sios_get_mem_info(out: *mut MemInfo) -> SiosStatus {
+    if out.is_null() {
+        return SiosStatus::Invalid;
+    }
+    let raw = raw_syscall(SyscallId::GetMemInfo as u32, out as usize, MemInfo::SIZE, 0);
+    status(SiosStatus::from_raw(raw).map(|_| ()))
+}
+
+/// CPU usage per task: writes up to `max` entries to `out` and the
+/// number written to `out_count`.
+///
+/// # Safety
+/// `out` must be null or valid for writes of `max` `SiosTaskCpuStats`;
+/// `out_count` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn sios_get_task_stats(out: *mut TaskCpuStats, max: usize, out_count: *mut usize) -> SiosStatus {
+    status((|| {
+        let count = out_count.as_mut().ok_or(SiosStatus::Invalid)?;
+        if out.is_null() || max == 0 {
+            return Err(SiosStatus::Invalid);
+        }
+        let len = max.saturating_mul(TaskCpuStats::SIZE);
+        let raw = raw_syscall(SyscallId::GetTaskStats as u32, out as usize, len, 0);
+        *count = SiosStatus::from_raw(raw)? as usize;
+        Ok(())
+    })())
+}
+
+/// Build ID and version of the running firmware, e.g. to tag telemetry.
+///
+/// # Safety
+/// `out` must be null or valid for writes of one `SiosFirmwareInfo`.
+#[no_mangle]
+pub unsafe extern "C" fn sios_get_firmware_info(out: *mut FirmwareInfoAbi) -> SiosStatus {
+    if out.is_null() {
+        return SiosStatus::Invalid;
+    }
+    let raw = raw_syscall(SyscallId::GetFirmwareInfo as u32, out as usize, FirmwareInfoAbi::SIZE, 0);
+    status(SiosStatus::from_raw(raw).map(|_| ()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typed_wrappers_reach_the_kernel() {
+        let mut secs = 0;
+        assert_eq!(unsafe { sios_get_time(&mut secs) }, SiosStatus::Ok);
+        assert_ne!(secs, 0);
+
+        // Host builds have no scheduler, so there is no calling task
+        let mut info = MemInfo::default();
+        assert_eq!(unsafe { sios_get_mem_info(&mut info) }, SiosStatus::NotFound);
+
+        let mut fw = FirmwareInfoAbi::default();
+        assert_eq!(unsafe { sios_get_firmware_info(&mut fw) }, SiosStatus::Ok);
+        assert_eq!(fw.build_id, kernel::image::BUILD_ID);
+
+        let mut q = 0;
+        let mut buf = [0u8; SIOS_IPC_MSG_SIZE];
+        let mut len = 0;
+        assert_eq!(unsafe { sios_ipc_create_queue(&mut q) }, SiosStatus::Ok);
+        assert_eq!(unsafe { sios_ipc_send(q, b"hi".as_ptr(), 2) }, SiosStatus::Ok);
+        assert_eq!(unsafe { sios_ipc_recv(q, buf.as_mut_ptr(), buf.len(), &mut len, 0) }, SiosStatus::Ok);
+        assert_eq!(&buf[..len], b"hi");
+        assert_eq!(unsafe { sios_ipc_recv(q, buf.as_mut_ptr(), buf.len(), &mut len, 0) }, SiosStatus::TimedOut);
+        assert_eq!(unsafe { sios_ipc_recv(q, buf.as_mut_ptr(), 8, &mut len, 0) }, SiosStatus::Invalid);
+
+        assert_eq!(unsafe { sios_get_time(core::ptr::null_mut()) }, SiosStatus::Invalid);
+        let mut n = 0;
+        assert_eq!(unsafe { sios_get_task_stats(core::ptr::null_mut(), 4, &mut n) }, SiosStatus::Invalid);
+        assert_eq!(sios_syscall(0xFF, 0, 0, 0) & 0x8000_0000, 0x8000_0000);
+    }
+}
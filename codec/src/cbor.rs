@@ -0,0 +1,453 @@
+//! SecureIoTOS Codec CBOR Module
+//! -----------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Minimal CBOR (RFC 8949) encoder/decoder for `no_std`.
+//!
+//! One shared serialization layer for telemetry, SUIT manifests, COSE
+//! structures, config blobs, and attestation evidence, so each module stops
+//! inventing its own byte format.
+//!
+//! - Works on caller-provided buffers; no allocation.
+//! - Definite-length items only (what COSE/SUIT require in practice).
+//! - Decoded byte/text strings borrow from the input buffer (zero-copy).
+//! - A read that fails consumes nothing, so the caller can retry the item
+//!   as another type or `skip()` it.
+
+/// CBOR major types (high 3 bits of the initial byte).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Major {
+    Unsigned = 0,
+    Negative = 1,
+    Bytes = 2,
+    Text = 3,
+    Array = 4,
+    Map = 5,
+    Tag = 6,
+    Simple = 7,
+}
+
+impl Major {
+    fn from_bits(b: u8) -> Self {
+        match b >> 5 {
+            0 => Major::Unsigned,
+            1 => Major::Negative,
+            2 => Major::Bytes,
+            3 => Major::Text,
+            4 => Major::Array,
+            5 => Major::Map,
+            6 => Major::Tag,
+            _ => Major::Simple,
+        }
+    }
+}
+
+/// Errors returned by the encoder and decoder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CborError {
+    /// Output buffer too small
+    BufferFull,
+    /// Input ended in the middle of an item
+    UnexpectedEnd,
+    /// Item has a different type than requested
+    TypeMismatch,
+    /// Indefinite lengths, reserved additional info, or out-of-range values
+    Unsupported,
+    /// Text string is not valid UTF-8
+    InvalidUtf8,
+}
+
+// Simple values (major type 7)
+const FALSE: u8 = 0xF4;
+const TRUE: u8 = 0xF5;
+const NULL: u8 = 0xF6;
+const FLOAT32: u8 = 0xFA;
+
+/// Streaming CBOR encoder writing into a fixed buffer.
+pub struct Encoder<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Encoder<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    /// Number of bytes written so far.
+    pub fn len(&self) -> usize {
+        self.pos
+    }
+
+    /// True if nothing has been written yet.
+    pub fn is_empty(&self) -> bool {
+        self.pos == 0
+    }
+
+    /// The encoded bytes.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[..self.pos]
+    }
+
+    fn put(&mut self, bytes: &[u8]) -> Result<(), CborError> {
+        let end = self.pos.checked_add(bytes.len()).ok_or(CborError::BufferFull)?;
+        if end > self.buf.len() {
+            return Err(CborError::BufferFull);
+        }
+        self.buf[self.pos..end].copy_from_slice(bytes);
+        self.pos = end;
+        Ok(())
+    }
+
+    /// Write an initial byte + argument using the shortest encoding.
+    fn head(&mut self, major: Major, value: u64) -> Result<&mut Self, CborError> {
+        let m = (major as u8) << 5;
+        if value < 24 {
+            self.put(&[m | value as u8])?;
+        } else if value <= u8::MAX as u64 {
+            self.put(&[m | 24, value as u8])?;
+        } else if value <= u16::MAX as u64 {
+            self.put(&[m | 25])?;
+            self.put(&(value as u16).to_be_bytes())?;
+        } else if value <= u32::MAX as u64 {
+            self.put(&[m | 26])?;
+            self.put(&(value as u32).to_be_bytes())?;
+        } else {
+            self.put(&[m | 27])?;
+            self.put(&value.to_be_bytes())?;
+        }
+        Ok(self)
+    }
+
+    pub fn u64(&mut self, v: u64) -> Result<&mut Self, CborError> {
+        self.head(Major::Unsigned, v)
+    }
+
+    pub fn i64(&mut self, v: i64) -> Result<&mut Self, CborError> {
+        if v >= 0 {
+            self.head(Major::Unsigned, v as u64)
+        } else {
+            // -1 - n encoding: -1 → 0, -100 → 99
+            self.head(Major::Negative, !(v as u64))
+        }
+    }
+
+    pub fn bytes(&mut self, b: &[u8]) -> Result<&mut Self, CborError> {
+        self.head(Major::Bytes, b.len() as u64)?;
+        self.put(b)?;
+        Ok(self)
+    }
+
+    pub fn str(&mut self, s: &str) -> Result<&mut Self, CborError> {
+        self.head(Major::Text, s.len() as u64)?;
+        self.put(s.as_bytes())?;
+        Ok(self)
+    }
+
+    /// Start an array of `len` items; the caller then encodes the items.
+    pub fn array(&mut self, len: usize) -> Result<&mut Self, CborError> {
+        self.head(Major::Array, len as u64)
+    }
+
+    /// Start a map of `len` key/value pairs.
+    pub fn map(&mut self, len: usize) -> Result<&mut Self, CborError> {
+        self.head(Major::Map, len as u64)
+    }
+
+    /// Semantic tag (e.g. 18 = COSE_Sign1, 16 = COSE_Encrypt0).
+    pub fn tag(&mut self, tag: u64) -> Result<&mut Self, CborError> {
+        self.head(Major::Tag, tag)
+    }
+
+    pub fn bool(&mut self, b: bool) -> Result<&mut Self, CborError> {
+        self.put(&[if b { TRUE } else { FALSE }])?;
+        Ok(self)
+    }
+
+    pub fn null(&mut self) -> Result<&mut Self, CborError> {
+        self.put(&[NULL])?;
+        Ok(self)
+    }
+
+    pub fn f32(&mut self, v: f32) -> Result<&mut Self, CborError> {
+        self.put(&[FLOAT32])?;
+        self.put(&v.to_be_bytes())?;
+        Ok(self)
+    }
+}
+
+/// Zero-copy CBOR decoder over a byte slice.
+pub struct Decoder<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    /// Current read offset.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// True once every byte has been consumed.
+    pub fn is_at_end(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], CborError> {
+        let end = self.pos.checked_add(n).ok_or(CborError::UnexpectedEnd)?;
+        if end > self.buf.len() {
+            return Err(CborError::UnexpectedEnd);
+        }
+        let s = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(s)
+    }
+
+    /// Run `read`, rewinding to where it started if it fails.
+    fn rewind_on_error<T>(&mut self, read: impl FnOnce(&mut Self) -> Result<T, CborError>) -> Result<T, CborError> {
+        let start = self.pos;
+        let result = read(self);
+        if result.is_err() {
+            self.pos = start;
+        }
+        result
+    }
+
+    /// Major type of the next item without consuming it.
+    pub fn peek(&self) -> Result<Major, CborError> {
+        self.buf.get(self.pos).map(|&b| Major::from_bits(b)).ok_or(CborError::UnexpectedEnd)
+    }
+
+    /// Read an initial byte + argument, returning (major, argument).
+    fn head(&mut self) -> Result<(Major, u64), CborError> {
+        let ib = self.take(1)?[0];
+        let major = Major::from_bits(ib);
+        let info = ib & 0x1F;
+        let value = match info {
+            0..=23 => info as u64,
+            24 => self.take(1)?[0] as u64,
+            25 => u16::from_be_bytes([self.take(1)?[0], self.take(1)?[0]]) as u64,
+            26 => {
+                let b = self.take(4)?;
+                u32::from_be_bytes([b[0], b[1], b[2], b[3]]) as u64
+            }
+            27 => {
+                let b = self.take(8)?;
+                u64::from_be_bytes([b[0], b[1], b[2], b[3], b[4], b[5], b[6], b[7]])
+            }
+            // 28..30 reserved, 31 = indefinite length
+            _ => return Err(CborError::Unsupported),
+        };
+        Ok((major, value))
+    }
+
+    fn expect(&mut self, major: Major) -> Result<u64, CborError> {
+        self.rewind_on_error(|d| match d.head()? {
+            (m, v) if m == major => Ok(v),
+            _ => Err(CborError::TypeMismatch),
+        })
+    }
+
+    pub fn u64(&mut self) -> Result<u64, CborError> {
+        self.expect(Major::Unsigned)
+    }
+
+    pub fn i64(&mut self) -> Result<i64, CborError> {
+        self.rewind_on_error(|d| match d.head()? {
+            (Major::Unsigned, v) if v <= i64::MAX as u64 => Ok(v as i64),
+            (Major::Negative, v) if v <= i64::MAX as u64 => Ok(-1 - v as i64),
+            (Major::Unsigned, _) | (Major::Negative, _) => Err(CborError::Unsupported),
+            _ => Err(CborError::TypeMismatch),
+        })
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], CborError> {
+        self.rewind_on_error(|d| {
+            let len = d.expect(Major::Bytes)?;
+            d.take(usize::try_from(len).map_err(|_| CborError::Unsupported)?)
+        })
+    }
+
+    pub fn str(&mut self) -> Result<&'a str, CborError> {
+        self.rewind_on_error(|d| {
+            let len = d.expect(Major::Text)?;
+            let raw = d.take(usize::try_from(len).map_err(|_| CborError::Unsupported)?)?;
+            core::str::from_utf8(raw).map_err(|_| CborError::InvalidUtf8)
+        })
+    }
+
+    /// Read an array header, returning the item count.
+    pub fn array(&mut self) -> Result<usize, CborError> {
+        self.rewind_on_error(|d| usize::try_from(d.expect(Major::Array)?).map_err(|_| CborError::Unsupported))
+    }
+
+    /// Read a map header, returning the number of key/value pairs.
+    pub fn map(&mut self) -> Result<usize, CborError> {
+        self.rewind_on_error(|d| usize::try_from(d.expect(Major::Map)?).map_err(|_| CborError::Unsupported))
+    }
+
+    pub fn tag(&mut self) -> Result<u64, CborError> {
+        self.expect(Major::Tag)
+    }
+
+    fn simple(&mut self, byte: u8) -> Result<(), CborError> {
+        match self.buf.get(self.pos) {
+            Some(&b) if b == byte => {
+                self.pos += 1;
+                Ok(())
+            }
+            Some(_) => Err(CborError::TypeMismatch),
+            None => Err(CborError::UnexpectedEnd),
+        }
+    }
+
+    pub fn bool(&mut self) -> Result<bool, CborError> {
+        match self.simple(TRUE) {
+            Ok(()) => Ok(true),
+            Err(CborError::TypeMismatch) => self.simple(FALSE).map(|_| false),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn null(&mut self) -> Result<(), CborError> {
+        self.simple(NULL)
+    }
+
+    pub fn f32(&mut self) -> Result<f32, CborError> {
+        self.rewind_on_error(|d| {
+            d.simple(FLOAT32)?;
+            let b = d.take(4)?;
+            Ok(f32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+        })
+    }
+
+    /// Skip the next item, including nested arrays/maps/tags.
+    /// Useful for ignoring unknown map keys from newer peers.
+    pub fn skip(&mut self) -> Result<(), CborError> {
+        self.rewind_on_error(|d| {
+            // Explicit work counter instead of recursion: bounded stack on MCUs.
+            let mut pending: u64 = 1;
+            while pending > 0 {
+                pending -= 1;
+                let (major, v) = d.head()?;
+                match major {
+                    Major::Unsigned | Major::Negative => {}
+                    Major::Bytes | Major::Text => {
+                        d.take(usize::try_from(v).map_err(|_| CborError::Unsupported)?)?;
+                    }
+                    Major::Array => pending = pending.checked_add(v).ok_or(CborError::Unsupported)?,
+                    Major::Map => {
+                        let items = v.checked_mul(2).ok_or(CborError::Unsupported)?;
+                        pending = pending.checked_add(items).ok_or(CborError::Unsupported)?;
+                    }
+                    Major::Tag => pending += 1,
+                    Major::Simple => match v {
+                        // head() already consumed the 1/2/4/8 argument bytes
+                        0..=27 => {}
+                        _ => return Err(CborError::Unsupported),
+                    },
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enc(f: impl FnOnce(&mut Encoder) -> Result<(), CborError>) -> ([u8; 32], usize) {
+        let mut buf = [0u8; 32];
+        let mut e = Encoder::new(&mut buf);
+        f(&mut e).unwrap();
+        let n = e.len();
+        (buf, n)
+    }
+
+    #[test]
+    fn encodes_rfc8949_appendix_a_vectors() {
+        let cases: &[(i64, &[u8])] = &[
+            (0, &[0x00]),
+            (23, &[0x17]),
+            (24, &[0x18, 0x18]),
+            (100, &[0x18, 0x64]),
+            (1000, &[0x19, 0x03, 0xe8]),
+            (1_000_000, &[0x1a, 0x00, 0x0f, 0x42, 0x40]),
+            (-1, &[0x20]),
+            (-100, &[0x38, 0x63]),
+        ];
+        for (v, expected) in cases {
+            let (buf, n) = enc(|e| e.i64(*v).map(|_| ()));
+            assert_eq!(&buf[..n], *expected, "value {}", v);
+            assert_eq!(Decoder::new(expected).i64().unwrap(), *v);
+        }
+
+        let (buf, n) = enc(|e| e.f32(100000.0).map(|_| ()));
+        assert_eq!(&buf[..n], &[0xfa, 0x47, 0xc3, 0x50, 0x00]);
+    }
+
+    #[test]
+    fn roundtrips_nested_structure() {
+        let (buf, n) = enc(|e| {
+            e.map(3)?
+                .str("id")?.u64(42)?
+                .str("fw")?.bytes(&[1, 2, 3, 4])?
+                .str("ok")?.bool(true)?;
+            Ok(())
+        });
+
+        let mut d = Decoder::new(&buf[..n]);
+        assert_eq!(d.map().unwrap(), 3);
+        assert_eq!(d.str().unwrap(), "id");
+        assert_eq!(d.u64().unwrap(), 42);
+        assert_eq!(d.str().unwrap(), "fw");
+        assert_eq!(d.bytes().unwrap(), &[1, 2, 3, 4]);
+        assert_eq!(d.str().unwrap(), "ok");
+        assert!(d.bool().unwrap());
+        assert!(d.is_at_end());
+    }
+
+    #[test]
+    fn skip_walks_nested_items_and_type_errors_do_not_consume() {
+        // [1, {"a": [2, 3]}, h'ff'], 7
+        let data = [0x83, 0x01, 0xa1, 0x61, 0x61, 0x82, 0x02, 0x03, 0x41, 0xff, 0x07];
+        let mut d = Decoder::new(&data);
+        assert_eq!(d.str(), Err(CborError::TypeMismatch));
+        d.skip().unwrap();
+        assert_eq!(d.u64().unwrap(), 7);
+    }
+
+    #[test]
+    fn failed_reads_consume_nothing() {
+        // Truncated head, out-of-range integer, truncated string, bad UTF-8
+        let inputs: [&[u8]; 4] = [&[0x19, 0x03], &[0x3b, 0xff, 0, 0, 0, 0, 0, 0, 0], &[0x43, 1], &[0x61, 0xff]];
+        for input in inputs {
+            let mut d = Decoder::new(input);
+            assert!(d.u64().is_err() && d.i64().is_err() && d.bytes().is_err() && d.str().is_err());
+            assert!(d.array().is_err() && d.f32().is_err());
+            assert_eq!(d.position(), 0, "{:02x?}", input);
+        }
+        // A nested item cut short rewinds to its start
+        let mut d = Decoder::new(&[0x01, 0x82, 0x01]);
+        d.u64().unwrap();
+        assert_eq!(d.skip(), Err(CborError::UnexpectedEnd));
+        assert_eq!(d.position(), 1);
+    }
+
+    #[test]
+    fn reports_truncation_and_full_buffers() {
+        assert_eq!(Decoder::new(&[0x19, 0x03]).u64(), Err(CborError::UnexpectedEnd));
+        let mut small = [0u8; 2];
+        assert_eq!(Encoder::new(&mut small).str("abc").err(), Some(CborError::BufferFull));
+    }
+}
@@ -0,0 +1,405 @@
+//! SecureIoTOS Codec Compression Module
+//! ------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Heatshrink-style LZSS compression with streaming APIs and bounded RAM.
+//!
+//! - `WINDOW` (history) and `LOOKAHEAD` sizes are const generics and must
+//!   be powers of two, `WINDOW * LOOKAHEAD` at least 128. RAM use is `WINDOW + LOOKAHEAD` bytes for the
+//!   compressor and `WINDOW` bytes for the decompressor — nothing else.
+//! - Output is emitted byte-by-byte through a sink closure, so callers can
+//!   stream straight into an encryption buffer, a flash page, or a socket.
+//!
+//! Bitstream format (MSB first):
+//! - `1` + 8 bits              → literal byte
+//! - `0` + W bits + L bits     → back-reference: (distance - 1, length - 1)
+//!
+//! The final byte is padded with up to 7 zero bits. Every token is at least
+//! 8 bits long (`new()` enforces it through the `WINDOW * LOOKAHEAD`
+//! minimum), so the decoder simply ignores an incomplete final token.
+//!
+//! Telemetry reports are compressed *before* encryption (ciphertext does
+//! not compress), see `examples/nrf52840`; OTA images sent compressed are
+//! decompressed on the fly while written to the inactive slot
+//! (`secure_storage::update::Updater::write_compressed`).
+
+/// Errors reported by the streaming codecs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressError {
+    /// Back-reference points before the start of the stream
+    InvalidBackReference,
+    /// Output buffer too small (slice helpers only)
+    OutputFull,
+}
+
+/// Window/lookahead sizes used by telemetry and OTA (1 KiB + 16 B).
+pub type DefaultCompressor = Compressor<1024, 16>;
+pub type DefaultDecompressor = Decompressor<1024, 16>;
+
+/// Number of bits needed to address `n` (a power of two) entries.
+const fn bits_for(n: usize) -> u32 {
+    n.trailing_zeros()
+}
+
+/// A back-reference (`1 + W + L` bits) must be longer than the trailing
+/// padding, or the padding would decode as one.
+const fn padding_is_unambiguous(window: usize, lookahead: usize) -> bool {
+    bits_for(window) + bits_for(lookahead) >= 7
+}
+
+/// MSB-first bit packer feeding a byte sink.
+struct BitWriter {
+    acc: u32,
+    nbits: u32,
+}
+
+impl BitWriter {
+    const fn new() -> Self {
+        Self { acc: 0, nbits: 0 }
+    }
+
+    fn put(&mut self, value: u32, bits: u32, sink: &mut impl FnMut(u8)) {
+        self.acc = (self.acc << bits) | (value & ((1 << bits) - 1));
+        self.nbits += bits;
+        while self.nbits >= 8 {
+            self.nbits -= 8;
+            sink((self.acc >> self.nbits) as u8);
+        }
+        self.acc &= (1 << self.nbits) - 1;
+    }
+
+    fn flush(&mut self, sink: &mut impl FnMut(u8)) {
+        if self.nbits > 0 {
+            sink((self.acc << (8 - self.nbits)) as u8);
+            self.acc = 0;
+            self.nbits = 0;
+        }
+    }
+}
+
+/// Streaming LZSS compressor.
+pub struct Compressor<const WINDOW: usize, const LOOKAHEAD: usize> {
+    history: [u8; WINDOW],
+    hist_pos: usize,
+    hist_len: usize,
+    look: [u8; LOOKAHEAD],
+    look_start: usize,
+    look_len: usize,
+    bits: BitWriter,
+}
+
+impl<const WINDOW: usize, const LOOKAHEAD: usize> Compressor<WINDOW, LOOKAHEAD> {
+    /// Create a compressor. Panics if the sizes are not powers of two, the
+    /// lookahead is not smaller than the window, or their product is below 128.
+    pub const fn new() -> Self {
+        assert!(WINDOW.is_power_of_two() && LOOKAHEAD.is_power_of_two());
+        assert!(LOOKAHEAD < WINDOW && WINDOW <= 1 << 15);
+        assert!(padding_is_unambiguous(WINDOW, LOOKAHEAD));
+        Self {
+            history: [0u8; WINDOW],
+            hist_pos: 0,
+            hist_len: 0,
+            look: [0u8; LOOKAHEAD],
+            look_start: 0,
+            look_len: 0,
+            bits: BitWriter::new(),
+        }
+    }
+
+    /// Feed more input; compressed bytes are passed to `sink` as they
+    /// become available.
+    pub fn feed(&mut self, input: &[u8], mut sink: impl FnMut(u8)) {
+        for &b in input {
+            let idx = (self.look_start + self.look_len) % LOOKAHEAD;
+            self.look[idx] = b;
+            self.look_len += 1;
+            if self.look_len == LOOKAHEAD {
+                self.encode_one(&mut sink);
+            }
+        }
+    }
+
+    /// Encode any pending input and flush the final partial byte.
+    /// The compressor is reset and can be reused afterwards.
+    pub fn finish(&mut self, mut sink: impl FnMut(u8)) {
+        while self.look_len > 0 {
+            self.encode_one(&mut sink);
+        }
+        self.bits.flush(&mut sink);
+        *self = Self::new();
+    }
+
+    #[inline]
+    fn look_at(&self, i: usize) -> u8 {
+        self.look[(self.look_start + i) % LOOKAHEAD]
+    }
+
+    /// Byte `k` positions into a candidate match that starts `dist` bytes
+    /// back. Matches may run into the lookahead itself (run-length case).
+    #[inline]
+    fn source_at(&self, dist: usize, k: usize) -> u8 {
+        if k < dist {
+            self.history[(self.hist_pos + WINDOW - dist + k) % WINDOW]
+        } else {
+            self.look_at(k - dist)
+        }
+    }
+
+    fn encode_one(&mut self, sink: &mut impl FnMut(u8)) {
+        let wb = bits_for(WINDOW);
+        let lb = bits_for(LOOKAHEAD);
+
+        // Linear search: O(WINDOW * LOOKAHEAD), fine for the small windows
+        // used on MCUs and keeps RAM strictly bounded (no hash chains).
+        let mut best_len = 0;
+        let mut best_dist = 0;
+        for dist in 1..=self.hist_len {
+            let mut k = 0;
+            while k < self.look_len && self.source_at(dist, k) == self.look_at(k) {
+                k += 1;
+            }
+            if k > best_len {
+                best_len = k;
+                best_dist = dist;
+                if k == self.look_len {
+                    break;
+                }
+            }
+        }
+
+        // Only use a back-reference when it is shorter than the literals.
+        let min_len = (1 + wb + lb) as usize / 9 + 1;
+        let consumed = if best_len >= min_len {
+            self.bits.put(0, 1, sink);
+            self.bits.put((best_dist - 1) as u32, wb, sink);
+            self.bits.put((best_len - 1) as u32, lb, sink);
+            best_len
+        } else {
+            self.bits.put(1, 1, sink);
+            self.bits.put(self.look_at(0) as u32, 8, sink);
+            1
+        };
+
+        for _ in 0..consumed {
+            let b = self.look_at(0);
+            self.history[self.hist_pos] = b;
+            self.hist_pos = (self.hist_pos + 1) % WINDOW;
+            self.hist_len = (self.hist_len + 1).min(WINDOW);
+            self.look_start = (self.look_start + 1) % LOOKAHEAD;
+            self.look_len -= 1;
+        }
+    }
+}
+
+impl<const WINDOW: usize, const LOOKAHEAD: usize> Default for Compressor<WINDOW, LOOKAHEAD> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy)]
+enum DecodeState {
+    Tag,
+    Literal,
+    Distance,
+    Length { dist: usize },
+}
+
+/// Streaming LZSS decompressor.
+pub struct Decompressor<const WINDOW: usize, const LOOKAHEAD: usize> {
+    history: [u8; WINDOW],
+    hist_pos: usize,
+    produced: usize,
+    acc: u32,
+    nbits: u32,
+    state: DecodeState,
+}
+
+impl<const WINDOW: usize, const LOOKAHEAD: usize> Decompressor<WINDOW, LOOKAHEAD> {
+    /// Create a decompressor; panics on the sizes `Compressor::new()` does.
+    pub const fn new() -> Self {
+        assert!(WINDOW.is_power_of_two() && LOOKAHEAD.is_power_of_two());
+        assert!(padding_is_unambiguous(WINDOW, LOOKAHEAD));
+        Self {
+            history: [0u8; WINDOW],
+            hist_pos: 0,
+            produced: 0,
+            acc: 0,
+            nbits: 0,
+            state: DecodeState::Tag,
+        }
+    }
+
+    /// Feed compressed bytes; decompressed bytes are passed to `sink`.
+    pub fn feed(&mut self, input: &[u8], mut sink: impl FnMut(u8)) -> Result<(), CompressError> {
+        let wb = bits_for(WINDOW);
+        let lb = bits_for(LOOKAHEAD);
+
+        for &b in input {
+            self.acc = (self.acc << 8) | b as u32;
+            self.nbits += 8;
+
+            loop {
+                let need = match self.state {
+                    DecodeState::Tag => 1,
+                    DecodeState::Literal => 8,
+                    DecodeState::Distance => wb,
+                    DecodeState::Length { .. } => lb,
+                };
+                if self.nbits < need {
+                    break;
+                }
+                self.nbits -= need;
+                let value = ((self.acc >> self.nbits) & ((1 << need) - 1)) as usize;
+                self.acc &= (1 << self.nbits) - 1;
+
+                self.state = match self.state {
+                    DecodeState::Tag if value == 1 => DecodeState::Literal,
+                    DecodeState::Tag => DecodeState::Distance,
+                    DecodeState::Literal => {
+                        self.emit(value as u8, &mut sink);
+                        DecodeState::Tag
+                    }
+                    DecodeState::Distance => DecodeState::Length { dist: value + 1 },
+                    DecodeState::Length { dist } => {
+                        if dist > self.produced.min(WINDOW) {
+                            return Err(CompressError::InvalidBackReference);
+                        }
+                        for _ in 0..value + 1 {
+                            let byte = self.history[(self.hist_pos + WINDOW - dist) % WINDOW];
+                            self.emit(byte, &mut sink);
+                        }
+                        DecodeState::Tag
+                    }
+                };
+            }
+        }
+        Ok(())
+    }
+
+    /// Reset for the next stream. Any incomplete trailing token is padding.
+    pub fn finish(&mut self) {
+        *self = Self::new();
+    }
+
+    #[inline]
+    fn emit(&mut self, byte: u8, sink: &mut impl FnMut(u8)) {
+        self.history[self.hist_pos] = byte;
+        self.hist_pos = (self.hist_pos + 1) % WINDOW;
+        self.produced = self.produced.saturating_add(1);
+        sink(byte);
+    }
+}
+
+impl<const WINDOW: usize, const LOOKAHEAD: usize> Default for Decompressor<WINDOW, LOOKAHEAD> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One-shot helper: compress `input` into `out`, returning bytes written.
+pub fn compress_into(input: &[u8], out: &mut [u8]) -> Result<usize, CompressError> {
+    let mut c = DefaultCompressor::new();
+    let mut n = 0;
+    let mut overflow = false;
+    {
+        let mut sink = |b: u8| {
+            if n < out.len() {
+                out[n] = b;
+                n += 1;
+            } else {
+                overflow = true;
+            }
+        };
+        c.feed(input, &mut sink);
+        c.finish(&mut sink);
+    }
+    if overflow { Err(CompressError::OutputFull) } else { Ok(n) }
+}
+
+/// One-shot helper: decompress `input` into `out`, returning bytes written.
+pub fn decompress_into(input: &[u8], out: &mut [u8]) -> Result<usize, CompressError> {
+    let mut d = DefaultDecompressor::new();
+    let mut n = 0;
+    let mut overflow = false;
+    d.feed(input, |b| {
+        if n < out.len() {
+            out[n] = b;
+            n += 1;
+        } else {
+            overflow = true;
+        }
+    })?;
+    if overflow { Err(CompressError::OutputFull) } else { Ok(n) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(data: &[u8]) -> usize {
+        let mut packed = [0u8; 4096];
+        let n = compress_into(data, &mut packed).unwrap();
+        let mut unpacked = [0u8; 4096];
+        let m = decompress_into(&packed[..n], &mut unpacked).unwrap();
+        assert_eq!(&unpacked[..m], data);
+        n
+    }
+
+    #[test]
+    fn roundtrips_text_and_runs() {
+        let text = b"temp=21.5;temp=21.6;temp=21.5;temp=21.7;humidity=50;humidity=51;";
+        assert!(roundtrip(text) < text.len());
+        assert!(roundtrip(&[0xAA; 1000]) < 200);
+        assert_eq!(roundtrip(b""), 0);
+    }
+
+    #[test]
+    fn streaming_in_small_chunks_matches_one_shot() {
+        let data: [u8; 300] = core::array::from_fn(|i| (i % 7) as u8);
+        let mut one_shot = [0u8; 512];
+        let n = compress_into(&data, &mut one_shot).unwrap();
+
+        let mut c = DefaultCompressor::new();
+        let mut streamed = [0u8; 512];
+        let mut m = 0;
+        for chunk in data.chunks(5) {
+            c.feed(chunk, |b| { streamed[m] = b; m += 1; });
+        }
+        c.finish(|b| { streamed[m] = b; m += 1; });
+        assert_eq!(&streamed[..m], &one_shot[..n]);
+    }
+
+    #[test]
+    fn smallest_window_pads_unambiguously() {
+        // 6 + 1 bits of back-reference fields: the shortest token is 8
+        // bits, longer than any padding
+        let data = [3u8, 3, 3, 3, 3, 3, 3, 9];
+        let mut c = Compressor::<64, 2>::new();
+        let mut packed = Vec::new();
+        c.feed(&data, |b| packed.push(b));
+        c.finish(|b| packed.push(b));
+        let mut d = Decompressor::<64, 2>::new();
+        let mut unpacked = Vec::new();
+        d.feed(&packed, |b| unpacked.push(b)).unwrap();
+        assert_eq!(unpacked, data);
+    }
+
+    #[test]
+    #[should_panic]
+    fn refuses_windows_whose_padding_could_decode() {
+        let _ = Compressor::<16, 4>::new();
+    }
+
+    #[test]
+    fn rejects_reference_before_start() {
+        // Tag 0 (back-ref) as the very first token.
+        let mut out = [0u8; 16];
+        assert_eq!(decompress_into(&[0x00, 0x00, 0x00], &mut out), Err(CompressError::InvalidBackReference));
+    }
+}
@@ -0,0 +1,20 @@
+//! SecureIoTOS Codec Lib Module
+//! ----------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Shared, dependency-free encoding utilities for SecureIoTOS:
+//! - `compress`: streaming LZSS compression for telemetry and OTA payloads
+//! - `cbor`: minimal CBOR encoder/decoder shared by telemetry, SUIT, COSE and attestation
+//! - `segment`: MTU-aware segmentation/reassembly for secure_bus, UDP and LoRa links
+
+// If we are not running tests, compile this crate without the standard library (no_std).
+#![cfg_attr(not(test), no_std)]
+
+pub mod compress;
+pub mod cbor;
+pub mod segment;
@@ -0,0 +1,365 @@
+//! SecureIoTOS Codec Segmentation Module
+//! -------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! MTU-aware segmentation and reassembly of application messages.
+//!
+//! One layer for every link whose frames are smaller than the messages
+//! sent over it (secure_bus transfers, UDP datagrams, LoRa payloads of a
+//! few dozen bytes), instead of each protocol splitting messages its own
+//! way.
+//!
+//! Each segment is a frame of at most `mtu` bytes:
+//!
+//! ```text
+//! msg_id u16 | index u8 | count u8 | total u16 | offset u16 | data | crc16
+//! ```
+//!
+//! (big-endian; CRC-16/CCITT-FALSE over everything before it). `msg_id`
+//! is a per-sender sequence number, so a message has at most 255 segments
+//! and 65535 bytes.
+//!
+//! `Reassembler` accepts segments in any order, drops duplicates and
+//! frames with a bad CRC, and hands out a message once all its segments
+//! arrived. Messages still incomplete `timeout_ms` after their first
+//! segment are purged, so lost segments do not pin buffers forever. No
+//! allocation: buffers are const generics.
+
+/// Segment header length.
+pub const HEADER_LEN: usize = 8;
+/// Trailing CRC length.
+pub const CRC_LEN: usize = 2;
+/// Bytes of each frame not available to message data.
+pub const OVERHEAD: usize = HEADER_LEN + CRC_LEN;
+/// Most segments one message can use.
+pub const MAX_SEGMENTS: usize = 255;
+
+/// Errors reported by segmentation and reassembly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SegmentError {
+    /// MTU leaves no room for data, or exceeds the frame buffer
+    BadMtu,
+    /// Message needs more than 255 segments or 65535 bytes, or does not
+    /// fit the reassembly buffer
+    TooLarge,
+    /// Frame too short for a segment
+    Truncated,
+    /// Frame CRC mismatch
+    BadCrc,
+    /// Header fields out of range, or disagreeing with earlier segments
+    /// of the same message
+    Inconsistent,
+    /// All reassembly slots are busy with other messages
+    NoSlot,
+}
+
+/// CRC-16/CCITT-FALSE (poly 0x1021, init 0xFFFF).
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for &b in data {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Splits messages into frames of at most `mtu` bytes, built in a
+/// `MAX_FRAME`-byte buffer.
+pub struct Segmenter<const MAX_FRAME: usize> {
+    mtu: usize,
+    next_id: u16,
+}
+
+impl<const MAX_FRAME: usize> Segmenter<MAX_FRAME> {
+    /// Segmenter for a link with the given `mtu` (frame payload size).
+    pub fn new(mtu: usize) -> Result<Self, SegmentError> {
+        let mut s = Self { mtu: 0, next_id: 0 };
+        s.set_mtu(mtu)?;
+        Ok(s)
+    }
+
+    /// Change the MTU, e.g. when a LoRa link switches spreading factor.
+    pub fn set_mtu(&mut self, mtu: usize) -> Result<(), SegmentError> {
+        if mtu <= OVERHEAD || mtu > MAX_FRAME {
+            return Err(SegmentError::BadMtu);
+        }
+        self.mtu = mtu;
+        Ok(())
+    }
+
+    /// Message data carried per frame.
+    pub fn chunk_len(&self) -> usize {
+        self.mtu - OVERHEAD
+    }
+
+    /// Largest message this MTU can carry.
+    pub fn max_message(&self) -> usize {
+        (self.chunk_len() * MAX_SEGMENTS).min(u16::MAX as usize)
+    }
+
+    /// Split `message` and pass each frame, in order, to `send`. Returns
+    /// the message id used. Stops at the first error of `send`.
+    pub fn split<E>(
+        &mut self,
+        message: &[u8],
+        mut send: impl FnMut(&[u8]) -> Result<(), E>,
+    ) -> Result<u16, SplitError<E>> {
+        if message.len() > self.max_message() {
+            return Err(SplitError::Segment(SegmentError::TooLarge));
+        }
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let chunk = self.chunk_len();
+        let count = message.len().div_ceil(chunk).max(1);
+        let mut frame = [0u8; MAX_FRAME];
+        for index in 0..count {
+            let offset = index * chunk;
+            let data = &message[offset..(offset + chunk).min(message.len())];
+            frame[0..2].copy_from_slice(&id.to_be_bytes());
+            frame[2] = index as u8;
+            frame[3] = count as u8;
+            frame[4..6].copy_from_slice(&(message.len() as u16).to_be_bytes());
+            frame[6..8].copy_from_slice(&(offset as u16).to_be_bytes());
+            let end = HEADER_LEN + data.len();
+            frame[HEADER_LEN..end].copy_from_slice(data);
+            let crc = crc16(&frame[..end]);
+            frame[end..end + CRC_LEN].copy_from_slice(&crc.to_be_bytes());
+            send(&frame[..end + CRC_LEN]).map_err(SplitError::Send)?;
+        }
+        Ok(id)
+    }
+}
+
+/// Error of `Segmenter::split`: bad input, or the link's own error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SplitError<E> {
+    Segment(SegmentError),
+    Send(E),
+}
+
+/// Decoded segment header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    msg_id: u16,
+    index: u8,
+    count: u8,
+    total: u16,
+    offset: u16,
+}
+
+/// Check a frame and split it into header and data.
+fn parse(frame: &[u8]) -> Result<(Header, &[u8]), SegmentError> {
+    if frame.len() < OVERHEAD {
+        return Err(SegmentError::Truncated);
+    }
+    let (body, crc) = frame.split_at(frame.len() - CRC_LEN);
+    if crc16(body) != u16::from_be_bytes([crc[0], crc[1]]) {
+        return Err(SegmentError::BadCrc);
+    }
+    let h = Header {
+        msg_id: u16::from_be_bytes([body[0], body[1]]),
+        index: body[2],
+        count: body[3],
+        total: u16::from_be_bytes([body[4], body[5]]),
+        offset: u16::from_be_bytes([body[6], body[7]]),
+    };
+    let data = &body[HEADER_LEN..];
+    let end = h.offset as usize + data.len();
+    let last = h.index as usize + 1 == h.count as usize;
+    if h.count == 0 || h.index >= h.count || end > h.total as usize || (last && end != h.total as usize) {
+        return Err(SegmentError::Inconsistent);
+    }
+    Ok((h, data))
+}
+
+/// A message being reassembled, or the last one delivered from here.
+struct Slot<const MAX_MSG: usize> {
+    active: bool,
+    /// Delivered; kept to recognise late duplicates until reused
+    done: bool,
+    source: u32,
+    msg_id: u16,
+    count: u8,
+    total: u16,
+    received: [u32; 8],
+    missing: u8,
+    started_ms: u32,
+    buf: [u8; MAX_MSG],
+}
+
+impl<const MAX_MSG: usize> Slot<MAX_MSG> {
+    const EMPTY: Self = Self {
+        active: false,
+        done: false,
+        source: 0,
+        msg_id: 0,
+        count: 0,
+        total: 0,
+        received: [0; 8],
+        missing: 0,
+        started_ms: 0,
+        buf: [0; MAX_MSG],
+    };
+}
+
+/// Reassembles up to `SLOTS` concurrent messages of up to `MAX_MSG`
+/// bytes each.
+pub struct Reassembler<const SLOTS: usize, const MAX_MSG: usize> {
+    slots: [Slot<MAX_MSG>; SLOTS],
+    timeout_ms: u32,
+}
+
+impl<const SLOTS: usize, const MAX_MSG: usize> Reassembler<SLOTS, MAX_MSG> {
+    /// Reassembler purging messages incomplete after `timeout_ms`.
+    pub const fn new(timeout_ms: u32) -> Self {
+        Self { slots: [Slot::EMPTY; SLOTS], timeout_ms }
+    }
+
+    /// Feed one received frame. `source` tells senders apart (link
+    /// address, UDP peer, LoRa DevAddr) since each numbers its own
+    /// messages; `now_ms` is a wrapping millisecond clock.
+    ///
+    /// Returns the complete message once its last missing segment
+    /// arrives, `None` while segments are outstanding or for a duplicate
+    /// (also of a message just delivered).
+    pub fn push(&mut self, source: u32, frame: &[u8], now_ms: u32) -> Result<Option<&[u8]>, SegmentError> {
+        self.purge(now_ms);
+        let (h, data) = parse(frame)?;
+        if h.total as usize > MAX_MSG {
+            return Err(SegmentError::TooLarge);
+        }
+
+        let i = match self.slots.iter().position(|s| (s.active || s.done) && s.source == source && s.msg_id == h.msg_id) {
+            Some(i) if self.slots[i].done => return Ok(None),
+            Some(i) => i,
+            None => {
+                let i = self
+                    .slots
+                    .iter()
+                    .position(|s| !s.active && !s.done)
+                    .or_else(|| self.slots.iter().position(|s| !s.active))
+                    .ok_or(SegmentError::NoSlot)?;
+                let s = &mut self.slots[i];
+                s.active = true;
+                s.done = false;
+                s.source = source;
+                s.msg_id = h.msg_id;
+                s.count = h.count;
+                s.total = h.total;
+                s.received = [0; 8];
+                s.missing = h.count;
+                s.started_ms = now_ms;
+                i
+            }
+        };
+        let s = &mut self.slots[i];
+        if s.count != h.count || s.total != h.total {
+            return Err(SegmentError::Inconsistent);
+        }
+        let (word, bit) = ((h.index / 32) as usize, 1u32 << (h.index % 32));
+        if s.received[word] & bit != 0 {
+            return Ok(None);
+        }
+        s.received[word] |= bit;
+        s.missing -= 1;
+        let offset = h.offset as usize;
+        s.buf[offset..offset + data.len()].copy_from_slice(data);
+        if s.missing > 0 {
+            return Ok(None);
+        }
+        s.active = false;
+        s.done = true;
+        Ok(Some(&s.buf[..s.total as usize]))
+    }
+
+    /// Drop messages incomplete for longer than the timeout. Returns how
+    /// many were dropped.
+    pub fn purge(&mut self, now_ms: u32) -> usize {
+        let mut dropped = 0;
+        for s in self.slots.iter_mut().filter(|s| s.active) {
+            if now_ms.wrapping_sub(s.started_ms) > self.timeout_ms {
+                s.active = false;
+                dropped += 1;
+            }
+        }
+        dropped
+    }
+
+    /// Messages currently being reassembled.
+    pub fn pending(&self) -> usize {
+        self.slots.iter().filter(|s| s.active).count()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split_all(seg: &mut Segmenter<64>, msg: &[u8]) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        seg.split(msg, |f| {
+            frames.push(f.to_vec());
+            Ok::<(), ()>(())
+        })
+        .unwrap();
+        frames
+    }
+
+    #[test]
+    fn out_of_order_and_duplicate_segments_reassemble() {
+        let msg: Vec<u8> = (0..200u8).collect();
+        let mut seg = Segmenter::<64>::new(51).unwrap(); // LoRa SF10 payload
+        let frames = split_all(&mut seg, &msg);
+        assert_eq!(frames.len(), 200usize.div_ceil(51 - OVERHEAD));
+        assert!(frames.iter().all(|f| f.len() <= 51));
+
+        let mut rx = Reassembler::<2, 256>::new(5_000);
+        let mut out = None;
+        for f in frames.iter().rev().chain(frames.first()) {
+            if let Some(m) = rx.push(7, f, 100).unwrap() {
+                out = Some(m.to_vec());
+            }
+        }
+        assert_eq!(out.as_deref(), Some(&msg[..]));
+        assert_eq!(rx.pending(), 0);
+
+        // Empty and single-frame messages still round-trip
+        for m in [&[][..], b"hi"] {
+            let f = split_all(&mut seg, m);
+            assert_eq!(rx.push(7, &f[0], 200).unwrap(), Some(m));
+        }
+    }
+
+    #[test]
+    fn corrupt_foreign_and_stale_segments_are_handled() {
+        let mut seg = Segmenter::<64>::new(20).unwrap();
+        let a = split_all(&mut seg, &[0xAA; 25]);
+        let b = split_all(&mut seg, &[0xBB; 25]);
+        let mut rx = Reassembler::<1, 64>::new(1_000);
+
+        let mut bad = a[0].clone();
+        bad[HEADER_LEN] ^= 1;
+        assert_eq!(rx.push(1, &bad, 0), Err(SegmentError::BadCrc));
+        assert_eq!(rx.push(1, &a[0][..5], 0), Err(SegmentError::Truncated));
+
+        assert_eq!(rx.push(1, &a[0], 0), Ok(None));
+        // A second message finds no slot until the first one times out
+        assert_eq!(rx.push(2, &b[0], 500), Err(SegmentError::NoSlot));
+        assert_eq!(rx.push(2, &b[0], 1_001), Ok(None));
+        assert_eq!(rx.pending(), 1);
+        assert_eq!(rx.push(1, &a[1], 1_002), Err(SegmentError::NoSlot));
+
+        assert_eq!(Segmenter::<64>::new(OVERHEAD).err(), Some(SegmentError::BadMtu));
+        assert_eq!(Segmenter::<64>::new(65).err(), Some(SegmentError::BadMtu));
+        let too_big = vec![0u8; seg.max_message() + 1];
+        assert_eq!(seg.split(&too_big, |_| Ok::<(), ()>(())), Err(SplitError::Segment(SegmentError::TooLarge)));
+    }
+}
@@ -7,30 +7,48 @@
 //! URL     : <https://m-a-h-b-u-b.github.io>
 //! GitHub  : <https://github.com/m-a-h-b-u-b/SecureIoTOS>
 //!
-//! Provides AES-128 CBC encryption/decryption with PKCS7 padding.
-//! Recommended for secure IoT flash storage or RAM encryption.
+//! Provides AES-128 CBC encryption/decryption with PKCS7 padding, used by
+//! the flash encryption layer, plus AES-256-GCM and AES-256-CTR behind a
+//! `CryptoBackend` so a hardware AES engine can take over.
+//!
+//! `SoftwareAes` is the portable implementation. A driver for an engine
+//! such as the STM32 CRYP or the nRF CC310 implements `CryptoBackend` and
+//! returns `AesError::Unsupported` for modes the part lacks (the CRYP of
+//! the STM32F415 has no GCM, the CC310 no GCM either); wrapped in
+//! `Fallback`, those calls are served by `SoftwareAes` instead:
+//!
+//! ```ignore
+//! let mut aes = Fallback(Stm32Cryp::new(dp.CRYP));
+//! let sealed = gcm_seal(&mut aes, &key, &nonce, b"hdr", payload)?;
+//! ```
+//!
+//! A GCM nonce must never repeat under one key; derive it from a counter
+//! persisted with the data, not from a sector index that gets rewritten.
+
+use alloc::vec::Vec;
 
 // Aes128 is a struct that implements the AES 
 // (Advanced Encryption Standard) algorithm with a 128-bit key size.
 use aes::Aes128;
 
-// BlockMode: a trait that defines a common interface for 
-// different block cipher modes (encrypt/decrypt).
-// Cbc: a specific block cipher mode called Cipher Block Chaining (CBC).
-use block_modes::{BlockMode, Cbc};
+// BlockEncryptMut/BlockDecryptMut: the interface of the `cbc` mode
+// (Cipher Block Chaining) encryptor and decryptor.
+use cbc::cipher::{BlockDecryptMut, BlockEncryptMut};
 
 // AES works on fixed-size blocks (16 bytes for AES).
 // If the plaintext length isn’t a multiple of 16, PKCS#7 adds 
 // extra bytes with values equal to the number of padding bytes.
-use block_modes::block_padding::Pkcs7;
+use cbc::cipher::block_padding::Pkcs7;
 
-// Result is a convenient type alias for Result<T, anyhow::Error>.
-// Context is a trait that lets you attach extra context to errors (.context("message")).
-use anyhow::{Context, Result};
+// AES-256 in counter mode (128-bit big-endian counter) and GCM.
+use aes::Aes256;
+use aes_gcm::aead::{AeadInPlace, KeyInit};
+use aes_gcm::Aes256Gcm;
+use ctr::cipher::{KeyIvInit, StreamCipher};
 
-// Creates a type alias—a shorter name for an existing, possibly long or complex type.
-// Purpose: Instead of repeatedly writing Cbc<Aes128, Pkcs7> everywhere, you can just write Aes128Cbc.
-type Aes128Cbc = Cbc<Aes128, Pkcs7>;
+// Type aliases—shorter names for the CBC encryptor and decryptor over AES-128.
+type Aes128CbcEnc = cbc::Encryptor<Aes128>;
+type Aes128CbcDec = cbc::Decryptor<Aes128>;
 
 /// Encrypt data using AES-128 CBC with PKCS7 padding.
 ///
@@ -40,11 +58,9 @@ type Aes128Cbc = Cbc<Aes128, Pkcs7>;
 /// * `iv`   - 16-byte initialization vector
 ///
 /// # Returns
-/// `Result<Vec<u8>>` containing ciphertext or error
-pub fn encrypt_aes(data: &[u8], key: &[u8; 16], iv: &[u8; 16]) -> Result<Vec<u8>> {
-    let cipher = Aes128Cbc::new_from_slices(key, iv)
-        .context("Failed to create AES-128 CBC cipher")?;
-    Ok(cipher.encrypt_vec(data))
+/// The ciphertext, a multiple of 16 bytes
+pub fn encrypt_aes(data: &[u8], key: &[u8; 16], iv: &[u8; 16]) -> Vec<u8> {
+    Aes128CbcEnc::new(key.into(), iv.into()).encrypt_padded_vec_mut::<Pkcs7>(data)
 }
 
 /// Decrypt data using AES-128 CBC with PKCS7 padding.
@@ -55,11 +71,176 @@ pub fn encrypt_aes(data: &[u8], key: &[u8; 16], iv: &[u8; 16]) -> Result<Vec<u8>
 /// * `iv`   - 16-byte initialization vector
 ///
 /// # Returns
-/// `Result<Vec<u8>>` containing plaintext or error
-pub fn decrypt_aes(data: &[u8], key: &[u8; 16], iv: &[u8; 16]) -> Result<Vec<u8>> {
-    let cipher = Aes128Cbc::new_from_slices(key, iv)
-        .context("Failed to create AES-128 CBC cipher")?;
-    cipher.decrypt_vec(data).context("AES-128 CBC decryption failed")
+/// The plaintext, `AesError::InvalidLength` if `data` is not a multiple of
+/// 16 bytes or `AesError::Padding` if the padding does not check out
+pub fn decrypt_aes(data: &[u8], key: &[u8; 16], iv: &[u8; 16]) -> Result<Vec<u8>, AesError> {
+    if !data.len().is_multiple_of(16) {
+        return Err(AesError::InvalidLength);
+    }
+    Aes128CbcDec::new(key.into(), iv.into()).decrypt_padded_vec_mut::<Pkcs7>(data).map_err(|_| AesError::Padding)
+}
+
+/// AES-256 key length in bytes.
+pub const KEY_LEN: usize = 32;
+/// GCM nonce length in bytes (96 bits, the only size engines support).
+pub const NONCE_LEN: usize = 12;
+/// GCM tag length in bytes.
+pub const TAG_LEN: usize = 16;
+
+/// Errors of the AES modes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum AesError {
+    /// GCM tag did not match: data, AAD, key or nonce are wrong
+    AuthFailed,
+    /// Input too short (e.g. sealed data without a tag)
+    InvalidLength,
+    /// CBC padding invalid after decryption: key, IV or data are wrong.
+    /// CBC is unauthenticated, so a valid padding proves nothing either.
+    Padding,
+    /// The backend does not implement this mode
+    Unsupported,
+    /// The engine reported an error
+    Hardware(&'static str),
+}
+
+/// AES-256 operations a backend provides. Buffers are processed in place.
+pub trait CryptoBackend {
+    /// XOR the AES-256-CTR keystream into `data`. `iv` is the initial
+    /// counter block, incremented as a 128-bit big-endian integer.
+    fn ctr_apply(&mut self, key: &[u8; KEY_LEN], iv: &[u8; 16], data: &mut [u8]) -> Result<(), AesError>;
+
+    /// Encrypt `buf` with AES-256-GCM and return the tag over `aad` and
+    /// the ciphertext.
+    fn gcm_encrypt(
+        &mut self,
+        key: &[u8; KEY_LEN],
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        buf: &mut [u8],
+    ) -> Result<[u8; TAG_LEN], AesError>;
+
+    /// Check `tag` and decrypt `buf`. On `AuthFailed` the contents of
+    /// `buf` are unspecified and must be discarded.
+    fn gcm_decrypt(
+        &mut self,
+        key: &[u8; KEY_LEN],
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        buf: &mut [u8],
+        tag: &[u8; TAG_LEN],
+    ) -> Result<(), AesError>;
+}
+
+/// Portable constant-time software implementation (RustCrypto `aes`).
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SoftwareAes;
+
+impl CryptoBackend for SoftwareAes {
+    fn ctr_apply(&mut self, key: &[u8; KEY_LEN], iv: &[u8; 16], data: &mut [u8]) -> Result<(), AesError> {
+        let mut cipher = ctr::Ctr128BE::<Aes256>::new(key.into(), iv.into());
+        cipher.try_apply_keystream(data).map_err(|_| AesError::InvalidLength)
+    }
+
+    fn gcm_encrypt(
+        &mut self,
+        key: &[u8; KEY_LEN],
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        buf: &mut [u8],
+    ) -> Result<[u8; TAG_LEN], AesError> {
+        let tag = Aes256Gcm::new(key.into())
+            .encrypt_in_place_detached(nonce.into(), aad, buf)
+            .map_err(|_| AesError::InvalidLength)?;
+        Ok(tag.into())
+    }
+
+    fn gcm_decrypt(
+        &mut self,
+        key: &[u8; KEY_LEN],
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        buf: &mut [u8],
+        tag: &[u8; TAG_LEN],
+    ) -> Result<(), AesError> {
+        Aes256Gcm::new(key.into())
+            .decrypt_in_place_detached(nonce.into(), aad, buf, tag.into())
+            .map_err(|_| AesError::AuthFailed)
+    }
+}
+
+/// Hardware backend `H`, with `SoftwareAes` for the modes it reports as
+/// `Unsupported`.
+#[derive(Debug, Default)]
+pub struct Fallback<H>(pub H);
+
+impl<H: CryptoBackend> CryptoBackend for Fallback<H> {
+    fn ctr_apply(&mut self, key: &[u8; KEY_LEN], iv: &[u8; 16], data: &mut [u8]) -> Result<(), AesError> {
+        match self.0.ctr_apply(key, iv, data) {
+            Err(AesError::Unsupported) => SoftwareAes.ctr_apply(key, iv, data),
+            other => other,
+        }
+    }
+
+    fn gcm_encrypt(
+        &mut self,
+        key: &[u8; KEY_LEN],
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        buf: &mut [u8],
+    ) -> Result<[u8; TAG_LEN], AesError> {
+        match self.0.gcm_encrypt(key, nonce, aad, buf) {
+            Err(AesError::Unsupported) => SoftwareAes.gcm_encrypt(key, nonce, aad, buf),
+            other => other,
+        }
+    }
+
+    fn gcm_decrypt(
+        &mut self,
+        key: &[u8; KEY_LEN],
+        nonce: &[u8; NONCE_LEN],
+        aad: &[u8],
+        buf: &mut [u8],
+        tag: &[u8; TAG_LEN],
+    ) -> Result<(), AesError> {
+        match self.0.gcm_decrypt(key, nonce, aad, buf, tag) {
+            Err(AesError::Unsupported) => SoftwareAes.gcm_decrypt(key, nonce, aad, buf, tag),
+            other => other,
+        }
+    }
+}
+
+/// Encrypt `plaintext` with AES-256-GCM; returns ciphertext followed by
+/// the tag.
+pub fn gcm_seal<B: CryptoBackend>(
+    backend: &mut B,
+    key: &[u8; KEY_LEN],
+    nonce: &[u8; NONCE_LEN],
+    aad: &[u8],
+    plaintext: &[u8],
+) -> Result<Vec<u8>, AesError> {
+    let mut out = Vec::with_capacity(plaintext.len() + TAG_LEN);
+    out.extend_from_slice(plaintext);
+    let tag = backend.gcm_encrypt(key, nonce, aad, &mut out)?;
+    out.extend_from_slice(&tag);
+    Ok(out)
+}
+
+/// Check and decrypt the output of `gcm_seal`.
+pub fn gcm_open<B: CryptoBackend>(
+    backend: &mut B,
+    key: &[u8; KEY_LEN],
+    nonce: &[u8; NONCE_LEN],
+    aad: &[u8],
+    sealed: &[u8],
+) -> Result<Vec<u8>, AesError> {
+    let split = sealed.len().checked_sub(TAG_LEN).ok_or(AesError::InvalidLength)?;
+    let (ciphertext, tag) = sealed.split_at(split);
+    let mut tag_bytes = [0u8; TAG_LEN];
+    tag_bytes.copy_from_slice(tag);
+    let mut out = ciphertext.to_vec();
+    backend.gcm_decrypt(key, nonce, aad, &mut out, &tag_bytes)?;
+    Ok(out)
 }
 
 #[cfg(test)]
@@ -72,9 +253,84 @@ mod tests {
         let iv = [1u8; 16];
         let plaintext = b"SecureIoTOS Test Data";
 
-        let ciphertext = encrypt_aes(plaintext, &key, &iv).unwrap();
+        let ciphertext = encrypt_aes(plaintext, &key, &iv);
         let decrypted = decrypt_aes(&ciphertext, &key, &iv).unwrap();
 
         assert_eq!(plaintext.to_vec(), decrypted);
+        assert_eq!(decrypt_aes(&ciphertext[..20], &key, &iv), Err(AesError::InvalidLength));
+    }
+
+    #[test]
+    fn cbc_matches_sp800_38a() {
+        // SP 800-38A F.2.1, first block; a full block of padding follows
+        let key: [u8; 16] = hex("2b7e151628aed2a6abf7158809cf4f3c").try_into().unwrap();
+        let iv: [u8; 16] = hex("000102030405060708090a0b0c0d0e0f").try_into().unwrap();
+        let ciphertext = encrypt_aes(&hex("6bc1bee22e409f96e93d7e117393172a"), &key, &iv);
+        assert_eq!(ciphertext[..16], hex("7649abac8119b246cee98e9b12e9197d"));
+        assert_eq!(decrypt_aes(&ciphertext, &key, &iv).unwrap(), hex("6bc1bee22e409f96e93d7e117393172a"));
+        // Without the padding block the last byte (0x2a) is not valid PKCS7
+        assert_eq!(decrypt_aes(&ciphertext[..16], &key, &iv), Err(AesError::Padding));
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn ctr_matches_sp800_38a() {
+        // SP 800-38A F.5.5, first block
+        let key: [u8; 32] = hex("603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4").try_into().unwrap();
+        let iv: [u8; 16] = hex("f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff").try_into().unwrap();
+        let mut data = hex("6bc1bee22e409f96e93d7e117393172a");
+        SoftwareAes.ctr_apply(&key, &iv, &mut data).unwrap();
+        assert_eq!(data, hex("601ec313775789a5b7a7f504bbf3d228"));
+        SoftwareAes.ctr_apply(&key, &iv, &mut data).unwrap();
+        assert_eq!(data, hex("6bc1bee22e409f96e93d7e117393172a"));
+    }
+
+    #[test]
+    fn gcm_seals_and_rejects_tampering() {
+        // GCM spec test case 14: zero key, zero nonce, one zero block
+        let (key, nonce) = ([0u8; KEY_LEN], [0u8; NONCE_LEN]);
+        let sealed = gcm_seal(&mut SoftwareAes, &key, &nonce, &[], &[0u8; 16]).unwrap();
+        assert_eq!(sealed, hex("cea7403d4d606b6e074ec5d3baf39d18d0d1c8a799996bf0265b98b5d48ab919"));
+        assert_eq!(gcm_open(&mut SoftwareAes, &key, &nonce, &[], &sealed), Ok(vec![0u8; 16]));
+
+        let mut flipped = sealed.clone();
+        flipped[3] ^= 0x80;
+        assert_eq!(gcm_open(&mut SoftwareAes, &key, &nonce, &[], &flipped), Err(AesError::AuthFailed));
+        assert_eq!(gcm_open(&mut SoftwareAes, &key, &nonce, b"aad", &sealed), Err(AesError::AuthFailed));
+        assert_eq!(gcm_open(&mut SoftwareAes, &key, &nonce, &[], &sealed[..8]), Err(AesError::InvalidLength));
+    }
+
+    /// Engine with CTR only, like the CRYP of an STM32F415.
+    #[derive(Default)]
+    struct CtrOnlyEngine {
+        ctr_calls: usize,
+    }
+
+    impl CryptoBackend for CtrOnlyEngine {
+        fn ctr_apply(&mut self, key: &[u8; KEY_LEN], iv: &[u8; 16], data: &mut [u8]) -> Result<(), AesError> {
+            self.ctr_calls += 1;
+            SoftwareAes.ctr_apply(key, iv, data)
+        }
+        fn gcm_encrypt(&mut self, _: &[u8; KEY_LEN], _: &[u8; NONCE_LEN], _: &[u8], _: &mut [u8]) -> Result<[u8; TAG_LEN], AesError> {
+            Err(AesError::Unsupported)
+        }
+        fn gcm_decrypt(&mut self, _: &[u8; KEY_LEN], _: &[u8; NONCE_LEN], _: &[u8], _: &mut [u8], _: &[u8; TAG_LEN]) -> Result<(), AesError> {
+            Err(AesError::Unsupported)
+        }
+    }
+
+    #[test]
+    fn fallback_covers_modes_the_engine_lacks() {
+        let (key, nonce) = ([7u8; KEY_LEN], [1u8; NONCE_LEN]);
+        let mut aes = Fallback(CtrOnlyEngine::default());
+        let sealed = gcm_seal(&mut aes, &key, &nonce, b"hdr", b"payload").unwrap();
+        assert_eq!(gcm_open(&mut SoftwareAes, &key, &nonce, b"hdr", &sealed).unwrap(), b"payload");
+
+        let mut data = *b"stream";
+        aes.ctr_apply(&key, &[0; 16], &mut data).unwrap();
+        assert_eq!(aes.0.ctr_calls, 1);
     }
 }
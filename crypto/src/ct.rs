@@ -0,0 +1,105 @@
+//! SecureIoTOS Cryptography Constant-Time Module
+//! --------------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Constant-time comparisons and selection for secret data.
+//!
+//! `==` on slices returns at the first differing byte, so the time it
+//! takes tells an attacker how many leading bytes of a guessed tag,
+//! digest or key were right; with enough tries a MAC can be forged byte
+//! by byte. Anything compared against attacker-supplied data, or any
+//! branch on a secret, goes through this module instead.
+//!
+//! Built on `subtle`, whose `Choice` carries the result through an
+//! optimisation barrier so the compiler cannot turn the loops back into
+//! early exits. Lengths are treated as public: comparing slices of
+//! different lengths returns `false` immediately.
+//!
+//! `net` and `memory` keep their own small comparison helpers because
+//! they do not depend on this crate.
+
+use subtle::{Choice, ConditionallySelectable, ConstantTimeEq};
+
+/// `a == b`, in time independent of the contents.
+pub fn eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && bool::from(a.ct_eq(b))
+}
+
+/// Whether every byte of `a` is zero (an unset key, a wiped buffer),
+/// without branching on the key bytes.
+pub fn is_zero(a: &[u8]) -> bool {
+    let acc = a.iter().fold(0u8, |acc, &b| acc | b);
+    bool::from(acc.ct_eq(&0))
+}
+
+/// Check a received MAC or authentication tag against the computed one.
+///
+/// Stricter than `eq`: an empty or truncated tag is rejected, so a
+/// caller passing a short slice from a malformed frame cannot end up
+/// comparing nothing.
+pub fn verify_tag(computed: &[u8], received: &[u8]) -> bool {
+    !computed.is_empty() && eq(computed, received)
+}
+
+/// `a` if `choice` is false, `b` if it is true, without a branch.
+pub fn select_u8(choice: bool, a: u8, b: u8) -> u8 {
+    u8::conditional_select(&a, &b, Choice::from(choice as u8))
+}
+
+/// `a` if `choice` is false, `b` if it is true, without a branch.
+pub fn select_u32(choice: bool, a: u32, b: u32) -> u32 {
+    u32::conditional_select(&a, &b, Choice::from(choice as u8))
+}
+
+/// Copy `src` into `dst` if `choice` is true, touching every byte of
+/// `dst` either way.
+///
+/// # Panics
+/// If the slices differ in length.
+pub fn conditional_copy(dst: &mut [u8], src: &[u8], choice: bool) {
+    assert_eq!(dst.len(), src.len(), "conditional_copy needs equal lengths");
+    let choice = Choice::from(choice as u8);
+    for (d, s) in dst.iter_mut().zip(src) {
+        d.conditional_assign(s, choice);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comparisons_match_plain_equality() {
+        assert!(eq(b"secret tag", b"secret tag"));
+        assert!(!eq(b"secret tag", b"secret taG"));
+        assert!(!eq(b"secret tag", b"secret ta"));
+        assert!(eq(b"", b""));
+
+        assert!(is_zero(&[0; 16]));
+        assert!(is_zero(&[]));
+        assert!(!is_zero(&[0, 0, 0x80, 0]));
+
+        let tag = [0xA5u8; 32];
+        assert!(verify_tag(&tag, &tag));
+        assert!(!verify_tag(&tag, &tag[..16]));
+        assert!(!verify_tag(&[], &[]));
+    }
+
+    #[test]
+    fn selection_picks_by_choice() {
+        assert_eq!(select_u8(false, 1, 2), 1);
+        assert_eq!(select_u8(true, 1, 2), 2);
+        assert_eq!(select_u32(true, 0xDEAD_BEEF, 7), 7);
+
+        let mut dst = [1u8, 2, 3];
+        conditional_copy(&mut dst, &[9, 9, 9], false);
+        assert_eq!(dst, [1, 2, 3]);
+        conditional_copy(&mut dst, &[9, 8, 7], true);
+        assert_eq!(dst, [9, 8, 7]);
+    }
+}
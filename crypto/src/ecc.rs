@@ -8,8 +8,18 @@
 //! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
 //! 
 //! Provides cryptographic operations for SecureIoTOS.
-//! Currently supports ECC (P-256) signing.
+//! The one ECC (P-256) surface of the system: device key signing, signature
+//! verification, key pair generation and public key import/export.
 //! In production, private keys should be stored in secure hardware (TPM, secure element) and never exposed in RAM.
+//!
+//! Public keys travel as SEC1 points (33 bytes compressed, 65 uncompressed)
+//! or as DER `SubjectPublicKeyInfo` (what `openssl ec -pubout -outform DER`
+//! writes); `import_public_key` accepts all three. Signatures are either
+//! fixed-size `r || s` (64 bytes, on the wire between devices) or DER (what
+//! `openssl dgst -sign` writes), see `SignatureFormat`.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
 
 // RefCell is a smart pointer type from Rust’s core library (the minimal, no-std version of std).
 // Provides interior mutability—you can mutate the data it wraps even when 
@@ -21,54 +31,230 @@ use core::cell::RefCell;
 // while accessing the data, ensuring critical sections are safe.
 use cortex_m::interrupt::Mutex;
 
+use zeroize::{Zeroize, Zeroizing};
+
 // These are from the p256 crate, which implements the NIST P-256 (a.k.a. secp256r1) elliptic curve:
 // SigningKey --> Holds the private key used to produce ECDSA signatures.
 // Signature --> Represents an actual ECDSA signature (the pair of integers (r, s)).
 // signature::Signer --> A trait (from the signature crate) that defines a sign() method.
-use p256::ecdsa::{SigningKey, Signature, signature::Signer};
+use p256::ecdsa::{SigningKey, Signature, VerifyingKey, signature::{Signer, Verifier}};
+
+// SubjectPublicKeyInfo (DER) decoding of public keys.
+use p256::pkcs8::DecodePublicKey;
+
+// The device key may live in a secure element instead of RAM.
+use crate::secure_element::{KeySlot, SeError, SecureElement};
+
+// Key pairs are generated from the system DRBG.
+use crate::rng::{self, RngError};
+
+/// Errors of the ECC operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EccError {
+    /// Not a valid SEC1 or DER P-256 public key
+    InvalidPublicKey,
+    /// Not a valid private key (zero or not below the group order)
+    InvalidPrivateKey,
+    /// The signature is malformed
+    MalformedSignature,
+    /// The signature does not match the message and key
+    BadSignature,
+    /// `init_crypto` has not loaded a signing key
+    NotInitialized,
+    /// The device key is in use by another caller (secure element
+    /// operation in progress)
+    Busy,
+    /// No entropy for key generation
+    Rng(RngError),
+    /// The secure element holding the device key failed
+    SecureElement(SeError),
+}
+
+impl From<RngError> for EccError {
+    fn from(e: RngError) -> Self {
+        EccError::Rng(e)
+    }
+}
+
+impl From<SeError> for EccError {
+    fn from(e: SeError) -> Self {
+        EccError::SecureElement(e)
+    }
+}
+
+/// Encoding of an exported public key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PublicKeyFormat {
+    /// SEC1 point, 33 bytes (`02`/`03` | x)
+    Sec1Compressed,
+    /// SEC1 point, 65 bytes (`04` | x | y)
+    Sec1Uncompressed,
+    /// DER `SubjectPublicKeyInfo` around the uncompressed point (91 bytes)
+    Der,
+}
+
+/// Encoding of a signature.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignatureFormat {
+    /// `r || s`, 32 bytes each
+    Fixed,
+    /// ASN.1 DER `SEQUENCE { r INTEGER, s INTEGER }`, at most 72 bytes
+    Der,
+}
+
+/// DER `SubjectPublicKeyInfo` header in front of an uncompressed P-256
+/// point: `SEQUENCE { SEQUENCE { id-ecPublicKey, prime256v1 }, BIT STRING }`.
+const SPKI_P256_PREFIX: [u8; 26] = [
+    0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a, 0x86, 0x48, 0xce,
+    0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
+];
+
+/// A P-256 key pair. The private half is wiped when dropped.
+pub struct KeyPair {
+    signing: SigningKey,
+}
+
+impl KeyPair {
+    /// Fresh key pair from the system DRBG.
+    pub fn generate() -> Result<Self, EccError> {
+        let mut scalar = [0u8; 32];
+        // A random 256-bit value is a valid scalar unless it is zero or at
+        // least the group order (probability about 2^-32); draw again then.
+        loop {
+            rng::fill_random(&mut scalar)?;
+            let key = SigningKey::from_bytes(&scalar);
+            scalar.zeroize();
+            if let Ok(signing) = key {
+                return Ok(Self { signing });
+            }
+        }
+    }
+
+    /// Key pair from a 32-byte big-endian private scalar.
+    pub fn from_private_bytes(bytes: &[u8]) -> Result<Self, EccError> {
+        SigningKey::from_bytes(bytes).map(|signing| Self { signing }).map_err(|_| EccError::InvalidPrivateKey)
+    }
 
-use crate::crypto_hw::secure_element_load_key; // hypothetical module
+    /// The private scalar, for sealing into secure storage.
+    pub fn private_bytes(&self) -> Zeroizing<[u8; 32]> {
+        Zeroizing::new(self.signing.to_bytes().into())
+    }
 
-// A cryptographically secure random number generator (RNG) from the rand_core crate 
-use rand_core::OsRng;
+    pub fn public_key(&self) -> VerifyingKey {
+        self.signing.verifying_key()
+    }
+
+    /// Sign `message` (SHA-256, deterministic nonce per RFC 6979).
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        self.signing.sign(message)
+    }
+}
+
+impl core::fmt::Debug for KeyPair {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("KeyPair").field("public", &self.public_key()).finish_non_exhaustive()
+    }
+}
+
+/// Where the device signing key lives.
+enum DeviceKey {
+    /// In RAM: development boards without a secure element
+    Software(SigningKey),
+    /// Inside a secure element, in `slot`; it never leaves the chip
+    SecureElement { se: Box<dyn SecureElement + Send>, slot: KeySlot },
+}
+
+impl DeviceKey {
+    fn public_key(&mut self) -> Result<VerifyingKey, EccError> {
+        match self {
+            DeviceKey::Software(key) => Ok(key.verifying_key()),
+            DeviceKey::SecureElement { se, slot } => Ok(se.public_key(*slot)?),
+        }
+    }
+}
+
+enum KeyState {
+    Absent,
+    Ready(DeviceKey),
+    /// Taken out by `with_device_key` while a caller uses it
+    InUse,
+}
 
 /// Atomic, interrupt-protected storage for the signing key
-// SIGNING_KEY is a global, thread-safe and interrupt-safe container 
-// that starts empty and will later hold an ECDSA signing key.
+// DEVICE_KEY is a global, thread-safe and interrupt-safe container
+// that starts empty and will later hold the device's ECDSA signing key
+// (or the secure element holding it).
 // Mutex stops interrupts while we touch it (atomic access).
 // RefCell lets us mutate it even though it’s a static.
-// Option represents “maybe we’ve generated the key, maybe not.”
-//This pattern is common in bare-metal embedded Rust to share a single hardware or cryptographic 
+//This pattern is common in bare-metal embedded Rust to share a single hardware or cryptographic
 // resource safely across main code and interrupt handlers.
-static SIGNING_KEY: Mutex<RefCell<Option<SigningKey>>> = Mutex::new(RefCell::new(None));
+static DEVICE_KEY: Mutex<RefCell<KeyState>> = Mutex::new(RefCell::new(KeyState::Absent));
 
-/// Initialize the cryptography module
-/// Generates or loads a persistent signing key
-pub fn init_crypto() {
-    interrupt::free(|cs| {
-        let mut guard = SIGNING_KEY.borrow(cs).borrow_mut();
-
-        if guard.is_none() {
-            // ---- Production key loading ----
-            // Replace this call with your platform's actual SE/HSM API.
-            // Example assumes it returns a 32-byte P-256 private key.
-            match secure_element_load_key() {
-                Ok(private_key_bytes) => {
-                    // Construct a SigningKey from the raw private key bytes.
-                    let key = SigningKey::from_bytes(&private_key_bytes)
-                        .expect("secure element returned invalid key");
-                    *guard = Some(key);
-                }
-                Err(e) => {
-                    // Decide how to handle hardware failures:
-                    // - retry,
-                    // - log and halt,
-                    // - or fall back to a safe degraded mode.
-                    panic!("Failed to load signing key from secure element: {e}");
-                }
+/// Run `f` on the device key. Secure element commands take milliseconds,
+/// so the key is taken out of `DEVICE_KEY` and `f` runs with interrupts
+/// enabled; a concurrent caller gets `EccError::Busy`.
+fn with_device_key<R>(f: impl FnOnce(&mut DeviceKey) -> Result<R, EccError>) -> Result<R, EccError> {
+    let mut key = cortex_m::interrupt::free(|cs| {
+        let mut state = DEVICE_KEY.borrow(cs).borrow_mut();
+        match core::mem::replace(&mut *state, KeyState::InUse) {
+            KeyState::Ready(key) => Ok(key),
+            KeyState::Absent => {
+                *state = KeyState::Absent;
+                Err(EccError::NotInitialized)
             }
+            KeyState::InUse => Err(EccError::Busy),
+        }
+    })?;
+    let res = f(&mut key);
+    cortex_m::interrupt::free(|cs| {
+        let mut state = DEVICE_KEY.borrow(cs).borrow_mut();
+        // A key installed meanwhile wins over the one we return.
+        if let KeyState::InUse = *state {
+            *state = KeyState::Ready(key);
         }
     });
+    res
+}
+
+/// Keep the device signing key in `slot` of a secure element. Call
+/// before `init_crypto`.
+pub fn install_secure_element(se: Box<dyn SecureElement + Send>, slot: KeySlot) {
+    cortex_m::interrupt::free(|cs| {
+        *DEVICE_KEY.borrow(cs).borrow_mut() = KeyState::Ready(DeviceKey::SecureElement { se, slot });
+    });
+}
+
+/// Use `pair` as the device signing key (held in RAM).
+pub fn install_signing_key(pair: KeyPair) {
+    cortex_m::interrupt::free(|cs| {
+        *DEVICE_KEY.borrow(cs).borrow_mut() = KeyState::Ready(DeviceKey::Software(pair.signing));
+    });
+}
+
+/// Initialize the cryptography module
+/// Makes sure a device signing key exists and returns its public key.
+///
+/// * With a secure element installed, the key in its slot is used; an
+///   empty slot (the chip rejects the public key readout) gets a new key
+///   generated inside the chip on first boot.
+/// * Without one, a key pair is generated into RAM. It changes on every
+///   boot: development boards only.
+pub fn init_crypto() -> Result<VerifyingKey, EccError> {
+    match with_device_key(|key| match key {
+        DeviceKey::Software(key) => Ok(key.verifying_key()),
+        DeviceKey::SecureElement { se, slot } => match se.public_key(*slot) {
+            Err(SeError::Device(_)) => Ok(se.generate_key(*slot)?),
+            other => Ok(other?),
+        },
+    }) {
+        Err(EccError::NotInitialized) => {
+            let pair = KeyPair::generate()?;
+            let public = pair.public_key();
+            install_signing_key(pair);
+            Ok(public)
+        }
+        other => other,
+    }
 }
 
 /// Sign a message using ECC (P-256)
@@ -80,26 +266,168 @@ pub fn init_crypto() {
 /// * `Signature` - ECC signature of the message
 ///
 /// # Security Notes
-/// * Uses a persistent signing key stored in `SIGNING_KEY`.
-/// * In production, the key must reside in secure hardware.
-/// * The signing key never leaves protected storage.
-pub fn sign_message(message: &[u8]) -> Signature {
-    cortex_m::interrupt::free(|cs| {
-        let guard = SIGNING_KEY.borrow(cs).borrow();
-        let key = guard.as_ref().expect("Cryptography module not initialized");
-        key.sign(message)
+/// * Uses the device signing key installed in `DEVICE_KEY`.
+/// * With a secure element, only the SHA-256 digest of `message` goes to
+///   the chip; the signing key never leaves it.
+pub fn sign_message(message: &[u8]) -> Result<Signature, EccError> {
+    with_device_key(|key| match key {
+        DeviceKey::Software(key) => Ok(key.sign(message)),
+        DeviceKey::SecureElement { se, slot } => Ok(se.sign(*slot, message)?),
     })
 }
 
+/// Public half of the device signing key, to export for enrollment.
+pub fn device_public_key() -> Result<VerifyingKey, EccError> {
+    with_device_key(DeviceKey::public_key)
+}
+
 /// Optional: Rotate the signing key (requires re-signing stored messages)
-/// In production, securely rotate keys in the secure element
-pub fn rotate_signing_key() {
-    cortex_m::interrupt::free(|cs| {
-        let mut guard = SIGNING_KEY.borrow(cs).borrow_mut();
-        if let Some(old_key) = guard.take() {
-            drop(old_key); // Drops and zeroizes the old key
+/// A secure element generates the new key in the same slot.
+///
+/// # Returns
+/// * The new public key, to re-enroll the device with
+pub fn rotate_signing_key() -> Result<VerifyingKey, EccError> {
+    with_device_key(|key| match key {
+        DeviceKey::Software(old) => {
+            // Generate first: if the RNG fails, the old key stays in service.
+            // The old key is dropped (and zeroized) when replaced.
+            *old = KeyPair::generate()?.signing;
+            Ok(old.verifying_key())
         }
-        let new_key = SigningKey::random(&mut OsRng); // Replace with hardware-backed key in production
-        *guard = Some(new_key);
-    });
+        DeviceKey::SecureElement { se, slot } => Ok(se.generate_key(*slot)?),
+    })
+}
+
+/// Export `key` in `format`.
+pub fn export_public_key(key: &VerifyingKey, format: PublicKeyFormat) -> Vec<u8> {
+    match format {
+        PublicKeyFormat::Sec1Compressed => key.to_encoded_point(true).as_bytes().to_vec(),
+        PublicKeyFormat::Sec1Uncompressed => key.to_encoded_point(false).as_bytes().to_vec(),
+        PublicKeyFormat::Der => {
+            let mut der = SPKI_P256_PREFIX.to_vec();
+            der.extend_from_slice(key.to_encoded_point(false).as_bytes());
+            der
+        }
+    }
+}
+
+/// Import a public key in any `PublicKeyFormat`; the encoding is told
+/// apart by the first byte (`30` starts a DER `SEQUENCE`, SEC1 points
+/// start with `02`, `03` or `04`).
+pub fn import_public_key(bytes: &[u8]) -> Result<VerifyingKey, EccError> {
+    match bytes.first() {
+        Some(0x30) => p256::PublicKey::from_public_key_der(bytes)
+            .map(|key| VerifyingKey::from(&key))
+            .map_err(|_| EccError::InvalidPublicKey),
+        Some(0x02..=0x04) => VerifyingKey::from_sec1_bytes(bytes).map_err(|_| EccError::InvalidPublicKey),
+        _ => Err(EccError::InvalidPublicKey),
+    }
+}
+
+/// Parse a signature in `format`.
+pub fn parse_signature(bytes: &[u8], format: SignatureFormat) -> Result<Signature, EccError> {
+    match format {
+        SignatureFormat::Fixed => Signature::try_from(bytes),
+        SignatureFormat::Der => Signature::from_der(bytes),
+    }
+    .map_err(|_| EccError::MalformedSignature)
+}
+
+/// Check `signature` over `message` against `key`.
+pub fn verify(key: &VerifyingKey, message: &[u8], signature: &Signature) -> Result<(), EccError> {
+    key.verify(message, signature).map_err(|_| EccError::BadSignature)
+}
+
+/// Verify an ECDSA (P-256) signature made by another device.
+///
+/// # Arguments
+/// * `public_key` - Signer's public key, SEC1 (compressed or not) or DER
+/// * `message` - The signed bytes
+/// * `signature` - Fixed-size `r || s` signature (64 bytes)
+///
+/// # Returns
+/// * `true` only if the key parses and the signature is valid for `message`
+pub fn verify_message(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    verify_encoded(public_key, message, signature, SignatureFormat::Fixed).is_ok()
+}
+
+/// As `verify_message`, for a signature in `format`, reporting why it
+/// was rejected.
+pub fn verify_encoded(
+    public_key: &[u8],
+    message: &[u8],
+    signature: &[u8],
+    format: SignatureFormat,
+) -> Result<(), EccError> {
+    let key = import_public_key(public_key)?;
+    verify(&key, message, &parse_signature(signature, format)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixed_pair() -> KeyPair {
+        KeyPair::from_private_bytes(&[0x11; 32]).unwrap()
+    }
+
+    #[test]
+    fn generated_pairs_sign_and_verify() {
+        let a = KeyPair::generate().unwrap();
+        let b = KeyPair::generate().unwrap();
+        assert_ne!(a.public_key(), b.public_key());
+
+        let sig = a.sign(b"telemetry");
+        assert_eq!(verify(&a.public_key(), b"telemetry", &sig), Ok(()));
+        assert_eq!(verify(&a.public_key(), b"telemetrY", &sig), Err(EccError::BadSignature));
+        assert_eq!(verify(&b.public_key(), b"telemetry", &sig), Err(EccError::BadSignature));
+
+        let restored = KeyPair::from_private_bytes(&a.private_bytes()[..]).unwrap();
+        assert_eq!(restored.public_key(), a.public_key());
+        assert_eq!(KeyPair::from_private_bytes(&[0; 32]).unwrap_err(), EccError::InvalidPrivateKey);
+    }
+
+    #[test]
+    fn public_keys_round_trip_in_every_format() {
+        let key = fixed_pair().public_key();
+        for (format, len) in [
+            (PublicKeyFormat::Sec1Compressed, 33),
+            (PublicKeyFormat::Sec1Uncompressed, 65),
+            (PublicKeyFormat::Der, 91),
+        ] {
+            let bytes = export_public_key(&key, format);
+            assert_eq!(bytes.len(), len, "{:?}", format);
+            assert_eq!(import_public_key(&bytes), Ok(key), "{:?}", format);
+        }
+
+        // SPKI header of an uncompressed P-256 key
+        let der = export_public_key(&key, PublicKeyFormat::Der);
+        assert_eq!(der[..4], [0x30, 0x59, 0x30, 0x13]);
+        assert_eq!(der[26..], export_public_key(&key, PublicKeyFormat::Sec1Uncompressed)[..]);
+
+        assert_eq!(import_public_key(&der[..90]), Err(EccError::InvalidPublicKey));
+        assert_eq!(import_public_key(&[0x05; 33]), Err(EccError::InvalidPublicKey));
+        assert_eq!(import_public_key(&[]), Err(EccError::InvalidPublicKey));
+    }
+
+    #[test]
+    fn encoded_signatures_verify_against_encoded_keys() {
+        let pair = fixed_pair();
+        let sig = pair.sign(b"firmware");
+        let der_key = export_public_key(&pair.public_key(), PublicKeyFormat::Der);
+        let sec1_key = export_public_key(&pair.public_key(), PublicKeyFormat::Sec1Compressed);
+
+        assert!(verify_message(&sec1_key, b"firmware", sig.as_ref()));
+        assert!(verify_message(&der_key, b"firmware", sig.as_ref()));
+        assert!(!verify_message(&der_key, b"firmwarE", sig.as_ref()));
+        assert_eq!(verify_encoded(&der_key, b"firmware", sig.to_der().as_bytes(), SignatureFormat::Der), Ok(()));
+        assert_eq!(
+            verify_encoded(&der_key, b"firmware", sig.as_ref(), SignatureFormat::Der),
+            Err(EccError::MalformedSignature)
+        );
+        assert_eq!(
+            verify_encoded(&[0x04; 65], b"firmware", sig.as_ref(), SignatureFormat::Fixed),
+            Err(EccError::InvalidPublicKey)
+        );
+    }
 }
@@ -0,0 +1,358 @@
+//! SecureIoTOS Cryptography Hash Module
+//! ------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Incremental hashing: SHA-256, SHA-512 and BLAKE2s-256.
+//!
+//! `Hasher` takes input in pieces of any size (`update`) and produces a
+//! `Digest` at the end (`finalize`), so a firmware image or flash region
+//! is hashed chunk by chunk without ever being held in RAM. For storage
+//! that is not memory mapped (external SPI flash, a staging slot behind a
+//! driver), `digest_reader` drives the reads itself through a caller
+//! provided chunk buffer.
+//!
+//! BLAKE2s (RFC 7693) works on 32-bit words and is faster than SHA-256 in
+//! software on Cortex-M0/M3 parts without a hash accelerator; use it for
+//! integrity checks that stay on the device. Anything a verifier off the
+//! device recomputes (signatures, manifests) stays on SHA-256.
+
+use sha2::{Digest as _, Sha256, Sha512};
+
+use crate::ct;
+
+/// Longest digest of any algorithm (SHA-512).
+pub const MAX_DIGEST_LEN: usize = 64;
+
+/// Supported hash functions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha256,
+    Sha512,
+    /// BLAKE2s, unkeyed, 32-byte output
+    Blake2s,
+}
+
+impl Algorithm {
+    /// Digest length in bytes.
+    pub const fn output_len(&self) -> usize {
+        match self {
+            Algorithm::Sha256 | Algorithm::Blake2s => 32,
+            Algorithm::Sha512 => 64,
+        }
+    }
+
+    /// Input block length in bytes; chunk sizes that are a multiple of it
+    /// avoid internal buffering.
+    pub const fn block_len(&self) -> usize {
+        match self {
+            Algorithm::Sha256 | Algorithm::Blake2s => 64,
+            Algorithm::Sha512 => 128,
+        }
+    }
+}
+
+/// A finished hash value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Digest {
+    algorithm: Algorithm,
+    bytes: [u8; MAX_DIGEST_LEN],
+}
+
+impl Digest {
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.algorithm.output_len()]
+    }
+
+    /// Compare against an expected value in constant time (for equal
+    /// lengths); `false` if the lengths differ.
+    pub fn matches(&self, expected: &[u8]) -> bool {
+        ct::eq(self.as_bytes(), expected)
+    }
+}
+
+impl AsRef<[u8]> for Digest {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+/// Streaming hash state.
+#[derive(Clone)]
+pub enum Hasher {
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Blake2s(Blake2s),
+}
+
+impl Hasher {
+    pub fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            Algorithm::Sha512 => Hasher::Sha512(Sha512::new()),
+            Algorithm::Blake2s => Hasher::Blake2s(Blake2s::new()),
+        }
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        match self {
+            Hasher::Sha256(_) => Algorithm::Sha256,
+            Hasher::Sha512(_) => Algorithm::Sha512,
+            Hasher::Blake2s(_) => Algorithm::Blake2s,
+        }
+    }
+
+    /// Absorb the next piece of input.
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Sha512(h) => h.update(data),
+            Hasher::Blake2s(h) => h.update(data),
+        }
+    }
+
+    /// Finish and return the digest.
+    pub fn finalize(self) -> Digest {
+        let mut bytes = [0u8; MAX_DIGEST_LEN];
+        let algorithm = self.algorithm();
+        match self {
+            Hasher::Sha256(h) => bytes[..32].copy_from_slice(&h.finalize()),
+            Hasher::Sha512(h) => bytes.copy_from_slice(&h.finalize()),
+            Hasher::Blake2s(h) => bytes[..32].copy_from_slice(&h.finalize()),
+        }
+        Digest { algorithm, bytes }
+    }
+
+    /// Start over with the same algorithm.
+    pub fn reset(&mut self) {
+        *self = Hasher::new(self.algorithm());
+    }
+}
+
+/// One-shot digest of `data`.
+pub fn digest(algorithm: Algorithm, data: &[u8]) -> Digest {
+    let mut h = Hasher::new(algorithm);
+    h.update(data);
+    h.finalize()
+}
+
+/// Digest of `len` bytes read through `read(offset, buf)`, one `chunk`
+/// at a time. `chunk` is the only buffer used; a multiple of the block
+/// length (and of the flash read granularity) is the best size.
+///
+/// # Panics
+/// If `chunk` is empty.
+pub fn digest_reader<E>(
+    algorithm: Algorithm,
+    len: usize,
+    chunk: &mut [u8],
+    mut read: impl FnMut(usize, &mut [u8]) -> Result<(), E>,
+) -> Result<Digest, E> {
+    assert!(!chunk.is_empty(), "digest_reader needs a chunk buffer");
+    let mut h = Hasher::new(algorithm);
+    let mut offset = 0;
+    while offset < len {
+        let n = chunk.len().min(len - offset);
+        read(offset, &mut chunk[..n])?;
+        h.update(&chunk[..n]);
+        offset += n;
+    }
+    Ok(h.finalize())
+}
+
+// ---------------------------------------------------------------------------
+// BLAKE2s-256 (RFC 7693)
+// ---------------------------------------------------------------------------
+
+const BLAKE2S_IV: [u32; 8] = [
+    0x6A09_E667, 0xBB67_AE85, 0x3C6E_F372, 0xA54F_F53A, 0x510E_527F, 0x9B05_688C, 0x1F83_D9AB, 0x5BE0_CD19,
+];
+
+const BLAKE2S_SIGMA: [[usize; 16]; 10] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15],
+    [14, 10, 4, 8, 9, 15, 13, 6, 1, 12, 0, 2, 11, 7, 5, 3],
+    [11, 8, 12, 0, 5, 2, 15, 13, 10, 14, 3, 6, 7, 1, 9, 4],
+    [7, 9, 3, 1, 13, 12, 11, 14, 2, 6, 5, 10, 4, 0, 15, 8],
+    [9, 0, 5, 7, 2, 4, 10, 15, 14, 1, 11, 12, 6, 8, 3, 13],
+    [2, 12, 6, 10, 0, 11, 8, 3, 4, 13, 7, 5, 15, 14, 1, 9],
+    [12, 5, 1, 15, 14, 13, 4, 10, 0, 7, 6, 3, 9, 2, 8, 11],
+    [13, 11, 7, 14, 12, 1, 3, 9, 5, 0, 15, 4, 8, 6, 2, 10],
+    [6, 15, 14, 9, 11, 3, 0, 8, 12, 2, 13, 7, 1, 4, 10, 5],
+    [10, 2, 8, 4, 7, 6, 1, 5, 15, 11, 9, 14, 3, 12, 13, 0],
+];
+
+/// Unkeyed BLAKE2s with a 32-byte digest.
+#[derive(Clone)]
+pub struct Blake2s {
+    h: [u32; 8],
+    buf: [u8; 64],
+    buf_len: usize,
+    /// Bytes compressed so far
+    counter: u64,
+}
+
+impl Default for Blake2s {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Blake2s {
+    pub fn new() -> Self {
+        let mut h = BLAKE2S_IV;
+        // Parameter block: digest length 32, no key, fanout 1, depth 1
+        h[0] ^= 0x0101_0000 | 32;
+        Self { h, buf: [0; 64], buf_len: 0, counter: 0 }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            // The last block is compressed with the final flag, so a full
+            // buffer is only compressed once more input follows it.
+            if self.buf_len == 64 {
+                self.counter += 64;
+                let block = self.buf;
+                self.compress(&block, false);
+                self.buf_len = 0;
+            }
+            let n = (64 - self.buf_len).min(data.len());
+            self.buf[self.buf_len..self.buf_len + n].copy_from_slice(&data[..n]);
+            self.buf_len += n;
+            data = &data[n..];
+        }
+    }
+
+    pub fn finalize(mut self) -> [u8; 32] {
+        self.counter += self.buf_len as u64;
+        self.buf[self.buf_len..].fill(0);
+        let block = self.buf;
+        self.compress(&block, true);
+        let mut out = [0u8; 32];
+        for (chunk, word) in out.chunks_exact_mut(4).zip(self.h) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        out
+    }
+
+    fn compress(&mut self, block: &[u8; 64], last: bool) {
+        let mut m = [0u32; 16];
+        for (word, bytes) in m.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        let mut v = [0u32; 16];
+        v[..8].copy_from_slice(&self.h);
+        v[8..].copy_from_slice(&BLAKE2S_IV);
+        v[12] ^= self.counter as u32;
+        v[13] ^= (self.counter >> 32) as u32;
+        if last {
+            v[14] = !v[14];
+        }
+        for s in &BLAKE2S_SIGMA {
+            g(&mut v, 0, 4, 8, 12, m[s[0]], m[s[1]]);
+            g(&mut v, 1, 5, 9, 13, m[s[2]], m[s[3]]);
+            g(&mut v, 2, 6, 10, 14, m[s[4]], m[s[5]]);
+            g(&mut v, 3, 7, 11, 15, m[s[6]], m[s[7]]);
+            g(&mut v, 0, 5, 10, 15, m[s[8]], m[s[9]]);
+            g(&mut v, 1, 6, 11, 12, m[s[10]], m[s[11]]);
+            g(&mut v, 2, 7, 8, 13, m[s[12]], m[s[13]]);
+            g(&mut v, 3, 4, 9, 14, m[s[14]], m[s[15]]);
+        }
+        for i in 0..8 {
+            self.h[i] ^= v[i] ^ v[i + 8];
+        }
+    }
+}
+
+/// BLAKE2s mixing function.
+fn g(v: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize, x: u32, y: u32) {
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(x);
+    v[d] = (v[d] ^ v[a]).rotate_right(16);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(12);
+    v[a] = v[a].wrapping_add(v[b]).wrapping_add(y);
+    v[d] = (v[d] ^ v[a]).rotate_right(8);
+    v[c] = v[c].wrapping_add(v[d]);
+    v[b] = (v[b] ^ v[c]).rotate_right(7);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn digests_match_reference_vectors() {
+        assert_eq!(
+            digest(Algorithm::Sha256, b"abc").as_bytes(),
+            hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+        assert_eq!(
+            digest(Algorithm::Sha512, b"abc").as_bytes(),
+            hex("ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a\
+                 2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f")
+        );
+        // RFC 7693 appendix B
+        assert_eq!(
+            digest(Algorithm::Blake2s, b"abc").as_bytes(),
+            hex("508c5e8c327c14e2e1a72ba34eeb452f37458b209ed63a294d999b4c86675982")
+        );
+        assert_eq!(
+            digest(Algorithm::Blake2s, b"").as_bytes(),
+            hex("69217a3079908094e11121d042354a7c1f55b6482ca1a51e1b250dfd1ed0eef9")
+        );
+    }
+
+    #[test]
+    fn streaming_matches_one_shot_at_any_split() {
+        let data: Vec<u8> = (0..300u32).map(|i| (i * 7) as u8).collect();
+        for alg in [Algorithm::Sha256, Algorithm::Sha512, Algorithm::Blake2s] {
+            let whole = digest(alg, &data);
+            for split in [0, 1, 63, 64, 65, 128, 299, 300] {
+                let mut h = Hasher::new(alg);
+                h.update(&data[..split]);
+                h.update(&[]);
+                h.update(&data[split..]);
+                assert_eq!(h.finalize(), whole, "{:?} split at {}", alg, split);
+            }
+            assert!(whole.matches(digest(alg, &data).as_bytes()));
+            assert!(!whole.matches(&whole.as_bytes()[1..]));
+
+            let mut h = Hasher::new(alg);
+            h.update(b"discarded");
+            h.reset();
+            h.update(&data);
+            assert_eq!(h.finalize(), whole);
+        }
+    }
+
+    #[test]
+    fn reader_hashes_a_region_chunk_by_chunk() {
+        let flash: Vec<u8> = (0..1000u32).map(|i| (i ^ (i >> 3)) as u8).collect();
+        let mut chunk = [0u8; 96];
+        let mut reads = 0;
+        let d = digest_reader(Algorithm::Sha256, flash.len(), &mut chunk, |off, buf: &mut [u8]| {
+            reads += 1;
+            buf.copy_from_slice(&flash[off..off + buf.len()]);
+            Ok::<(), ()>(())
+        })
+        .unwrap();
+        assert_eq!(d, digest(Algorithm::Sha256, &flash));
+        assert_eq!(reads, 11);
+
+        let err = digest_reader(Algorithm::Blake2s, flash.len(), &mut chunk, |off, _: &mut [u8]| {
+            if off >= 500 { Err(off) } else { Ok(()) }
+        });
+        assert_eq!(err, Err(576));
+    }
+}
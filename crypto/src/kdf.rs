@@ -0,0 +1,203 @@
+//! SecureIoTOS Cryptography KDF Module
+//! -----------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! HMAC-SHA256, HKDF (RFC 5869) and per-purpose key derivation from the
+//! device root key.
+//!
+//! Every subsystem key (storage encryption, secure bus authentication,
+//! token signing, ...) is derived from one root key provisioned into the
+//! device, instead of each module generating or hard-coding its own:
+//!
+//! ```text
+//! PRK = HKDF-Extract(salt = "SecureIoTOS root v1", root key)
+//! key = HKDF-Expand(PRK, purpose label || 0x00 || context, 32)
+//! ```
+//!
+//! Keys for different purposes, or different contexts (a sector, a peer
+//! address, a key epoch), are independent, and the same inputs always
+//! give the same key, so nothing derived has to be stored. Rotating a
+//! subsystem key means changing its context (e.g. bumping an epoch).
+//!
+//! Boot code calls `install_root_key` once; subsystems then call
+//! `derive_key`. `DeviceKdf` is the same derivation without the global,
+//! for host tools and tests.
+
+use core::cell::RefCell;
+use cortex_m::interrupt::Mutex;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::ct;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// HMAC-SHA256 tag and derived key length in bytes.
+pub const KEY_LEN: usize = 32;
+
+/// HKDF salt of the root key extraction.
+const ROOT_SALT: &[u8] = b"SecureIoTOS root v1";
+
+/// Errors of the key derivation functions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KdfError {
+    /// Requested output longer than HKDF allows (255 * 32 bytes)
+    OutputTooLong,
+    /// `install_root_key` has not been called
+    NoRootKey,
+}
+
+/// What a derived key is used for. Each purpose has a fixed label that is
+/// part of the derivation, so keys never overlap across subsystems.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Purpose {
+    /// Flash / secure storage encryption
+    StorageEncryption,
+    /// Secure bus message authentication
+    BusAuthentication,
+    /// Session and access token signing
+    TokenSigning,
+    /// Telemetry payload encryption
+    TelemetryEncryption,
+    /// Key encryption key of the persistent key store
+    KeyWrapping,
+    /// Application defined; the label must not start with "sios/" or
+    /// contain NUL
+    Custom(&'static str),
+}
+
+impl Purpose {
+    /// Label mixed into the HKDF info.
+    pub const fn label(&self) -> &'static str {
+        match self {
+            Purpose::StorageEncryption => "sios/storage-enc",
+            Purpose::BusAuthentication => "sios/bus-auth",
+            Purpose::TokenSigning => "sios/token-sign",
+            Purpose::TelemetryEncryption => "sios/telemetry-enc",
+            Purpose::KeyWrapping => "sios/key-wrap",
+            Purpose::Custom(label) => label,
+        }
+    }
+}
+
+/// HMAC-SHA256 of `data` under `key`.
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; KEY_LEN] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// Check an HMAC-SHA256 `tag` in constant time.
+pub fn hmac_sha256_verify(key: &[u8], data: &[u8], tag: &[u8]) -> bool {
+    ct::verify_tag(&hmac_sha256(key, data), tag)
+}
+
+/// HKDF-Extract: pseudorandom key from input keying material.
+pub fn hkdf_extract(salt: &[u8], ikm: &[u8]) -> [u8; KEY_LEN] {
+    let (prk, _) = Hkdf::<Sha256>::extract(Some(salt), ikm);
+    prk.into()
+}
+
+/// HKDF-Expand: fill `okm` with keying material for `info`.
+pub fn hkdf_expand(prk: &[u8; KEY_LEN], info: &[u8], okm: &mut [u8]) -> Result<(), KdfError> {
+    let hk = Hkdf::<Sha256>::from_prk(prk).map_err(|_| KdfError::OutputTooLong)?;
+    hk.expand(info, okm).map_err(|_| KdfError::OutputTooLong)
+}
+
+/// Per-purpose key derivation from a root key.
+pub struct DeviceKdf {
+    prk: Zeroizing<[u8; KEY_LEN]>,
+}
+
+impl DeviceKdf {
+    /// Derivation rooted at `root_key` (any length, at least 32 bytes of
+    /// entropy recommended).
+    pub fn new(root_key: &[u8]) -> Self {
+        Self { prk: Zeroizing::new(hkdf_extract(ROOT_SALT, root_key)) }
+    }
+
+    /// Fill `out` with key material for `purpose` and `context`.
+    pub fn derive_into(&self, purpose: Purpose, context: &[u8], out: &mut [u8]) -> Result<(), KdfError> {
+        let hk = Hkdf::<Sha256>::from_prk(&self.prk[..]).map_err(|_| KdfError::OutputTooLong)?;
+        hk.expand_multi_info(&[purpose.label().as_bytes(), &[0], context], out)
+            .map_err(|_| KdfError::OutputTooLong)
+    }
+
+    /// 32-byte key for `purpose` and `context`.
+    pub fn derive_key(&self, purpose: Purpose, context: &[u8]) -> Zeroizing<[u8; KEY_LEN]> {
+        let mut key = Zeroizing::new([0u8; KEY_LEN]);
+        // 32 bytes is always within the HKDF output limit.
+        let _ = self.derive_into(purpose, context, &mut key[..]);
+        key
+    }
+}
+
+/// Device-wide derivation, set up once at boot.
+static DEVICE_KDF: Mutex<RefCell<Option<DeviceKdf>>> = Mutex::new(RefCell::new(None));
+
+/// Install the device root key (from OTP, secure element or provisioning
+/// storage). The caller's copy is wiped; a second call replaces the root.
+pub fn install_root_key(root_key: &mut [u8]) {
+    let kdf = DeviceKdf::new(root_key);
+    root_key.zeroize();
+    cortex_m::interrupt::free(|cs| *DEVICE_KDF.borrow(cs).borrow_mut() = Some(kdf));
+}
+
+/// 32-byte key for `purpose` and `context`, from the installed root key.
+pub fn derive_key(purpose: Purpose, context: &[u8]) -> Result<Zeroizing<[u8; KEY_LEN]>, KdfError> {
+    cortex_m::interrupt::free(|cs| {
+        DEVICE_KDF.borrow(cs).borrow().as_ref().map(|kdf| kdf.derive_key(purpose, context)).ok_or(KdfError::NoRootKey)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn hmac_and_hkdf_match_rfc_vectors() {
+        // RFC 4231 test case 2
+        let tag = hmac_sha256(b"Jefe", b"what do ya want for nothing?");
+        assert_eq!(tag.to_vec(), hex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"));
+        assert!(hmac_sha256_verify(b"Jefe", b"what do ya want for nothing?", &tag));
+        assert!(!hmac_sha256_verify(b"Jefe", b"what do ya want for nothing!", &tag));
+
+        // RFC 5869 test case 1
+        let prk = hkdf_extract(&hex("000102030405060708090a0b0c"), &[0x0b; 22]);
+        assert_eq!(prk.to_vec(), hex("077709362c2e32df0ddc3f0dc47bba6390b6c73bb50f9c3122ec844ad7c2b3e5"));
+        let mut okm = [0u8; 42];
+        hkdf_expand(&prk, &hex("f0f1f2f3f4f5f6f7f8f9"), &mut okm).unwrap();
+        assert_eq!(
+            okm.to_vec(),
+            hex("3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf34007208d5b887185865")
+        );
+        assert_eq!(hkdf_expand(&prk, &[], &mut [0u8; 255 * 32 + 1]), Err(KdfError::OutputTooLong));
+    }
+
+    #[test]
+    fn derived_keys_are_deterministic_and_separated() {
+        let kdf = DeviceKdf::new(&[0x42; 32]);
+        let storage = kdf.derive_key(Purpose::StorageEncryption, b"sector-0");
+        assert_eq!(*storage, *DeviceKdf::new(&[0x42; 32]).derive_key(Purpose::StorageEncryption, b"sector-0"));
+        assert_ne!(*storage, *kdf.derive_key(Purpose::StorageEncryption, b"sector-1"));
+        assert_ne!(*storage, *kdf.derive_key(Purpose::BusAuthentication, b"sector-0"));
+        assert_ne!(*storage, *DeviceKdf::new(&[0x43; 32]).derive_key(Purpose::StorageEncryption, b"sector-0"));
+        assert_ne!(*kdf.derive_key(Purpose::Custom("app"), b"x"), *kdf.derive_key(Purpose::Custom("appx"), b""));
+
+        // Shorter output is a prefix of the full key
+        let mut short = [0u8; 16];
+        kdf.derive_into(Purpose::StorageEncryption, b"sector-0", &mut short).unwrap();
+        assert_eq!(short, storage[..16]);
+    }
+}
@@ -7,12 +7,22 @@
 //! URL     : https://m-a-h-b-u-b.github.io
 //! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
 
+// If we are not running tests, compile this crate without the standard library (no_std).
+#![cfg_attr(not(test), no_std)]
+
+// Variable-length outputs (`aes`, `hash`, ...) and boxed entropy sources
+extern crate alloc;
+
 // Re-export or declare submodules. Replace the `mod` bodies
 // with your actual AES/ECC implementations or keep these
 // placeholders if you’re scaffolding the library.
 pub mod aes;
+pub mod ct;
 pub mod ecc;
+pub mod hash;
+pub mod kdf;
 pub mod rng;
+pub mod secure_element;
 
 /// Initialize all cryptography modules.
 ///
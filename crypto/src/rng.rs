@@ -6,33 +6,582 @@
 //! Author  : Md Mahbubur Rahman
 //! URL     : https://m-a-h-b-u-b.github.io
 //! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Random numbers from a health-tested entropy source through a DRBG.
+//!
+//! ```text
+//! EntropySource --> HealthChecked --> SHA-256 conditioning --> CTR_DRBG --> fill_random()
+//!  (TRNG / ADC)     (SP 800-90B 4.4)                          (SP 800-90A, AES-256)
+//! ```
+//!
+//! - `EntropySource` is a raw noise source with an assessed min-entropy
+//!   per byte. Backends: `Stm32Rng` and `Nrf52Rng` (on-chip TRNGs) and
+//!   `AdcNoise` (LSBs of a floating ADC input) for parts without one.
+//! - `HealthChecked` runs the SP 800-90B continuous tests on every raw
+//!   byte: the repetition count test (a stuck source) and the adaptive
+//!   proportion test (a source losing entropy), with cutoffs for a false
+//!   alarm rate of 2^-20 derived from the claimed entropy. 1024 bytes are
+//!   tested and discarded at startup. A failure is latched: the source
+//!   stays unusable until the device is reset.
+//! - `CtrDrbg` is CTR_DRBG with AES-256 and no derivation function. Its
+//!   seed is conditioned with SHA-256 from enough raw bytes to carry 448
+//!   bits of min-entropy, so weak sources get longer reads, not weaker
+//!   seeds. It reseeds every `RESEED_INTERVAL` requests.
+//!
+//! Boot code installs the board's source with `install_entropy_source`;
+//! `fill_random` and `generate_random_key` then draw from the DRBG. Host
+//! builds use the operating system RNG instead.
 
+use alloc::boxed::Box;
+use aes::cipher::{BlockEncrypt, KeyInit};
+use aes::Aes256;
+use sha2::{Digest, Sha256};
+use zeroize::Zeroize;
+
+#[cfg(not(target_arch = "arm"))]
 use rand_core::{OsRng, RngCore};
 
+/// Errors of the random number generator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum RngError {
+    /// The same value repeated too often: the source is stuck
+    RepetitionCount,
+    /// One value is too frequent in a window: the source lost entropy
+    AdaptiveProportion,
+    /// The hardware reported an error or did not deliver in time
+    SourceFailure,
+    /// No entropy source has been installed
+    NotInitialized,
+    /// More than `MAX_REQUEST` bytes requested at once
+    RequestTooLarge,
+    /// The DRBG must be reseeded before the next request
+    ReseedRequired,
+}
+
+/// A raw noise source.
+pub trait EntropySource {
+    /// Assessed min-entropy per output byte, in eighths of a bit
+    /// (64 = full entropy). Health test cutoffs and seed lengths are
+    /// derived from it, so claim what the source's assessment supports.
+    fn min_entropy_eighths(&self) -> u8;
+
+    /// Fill `out` with raw (unconditioned) noise.
+    fn fill_raw(&mut self, out: &mut [u8]) -> Result<(), RngError>;
+}
+
+impl<S: EntropySource + ?Sized> EntropySource for Box<S> {
+    fn min_entropy_eighths(&self) -> u8 {
+        (**self).min_entropy_eighths()
+    }
+
+    fn fill_raw(&mut self, out: &mut [u8]) -> Result<(), RngError> {
+        (**self).fill_raw(out)
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Health tests (SP 800-90B section 4.4)
+// ---------------------------------------------------------------------------
+
+/// Adaptive proportion test window for non-binary samples.
+pub const APT_WINDOW: u16 = 512;
+/// Bytes tested and discarded before a source is first used.
+pub const STARTUP_SAMPLES: usize = 1024;
+
+/// Adaptive proportion cutoffs for W = 512 and alpha = 2^-20 (SP 800-90B
+/// table 2), by min-entropy in eighths of a bit.
+const APT_CUTOFFS: [(u8, u16); 5] = [(64, 13), (32, 62), (16, 177), (8, 311), (4, 410)];
+
+/// Continuous health tests over 8-bit samples.
+#[derive(Debug, Clone)]
+pub struct HealthTests {
+    rct_cutoff: u16,
+    apt_cutoff: u16,
+    last: Option<u8>,
+    run: u16,
+    apt_ref: u8,
+    apt_count: u16,
+    apt_seen: u16,
+}
+
+impl HealthTests {
+    /// Tests for a source claiming `min_entropy_eighths` per sample
+    /// (clamped to 4..=64, i.e. 0.5 to 8 bits).
+    pub fn new(min_entropy_eighths: u8) -> Self {
+        let h = min_entropy_eighths.clamp(4, 64);
+        // C = 1 + ceil(20 / H), with H = h / 8
+        let rct_cutoff = 1 + 160u16.div_ceil(u16::from(h));
+        // Table entry for the largest H not above the claim
+        let apt_cutoff = APT_CUTOFFS.iter().find(|&&(th, _)| th <= h).map_or(410, |&(_, c)| c);
+        Self { rct_cutoff, apt_cutoff, last: None, run: 0, apt_ref: 0, apt_count: 0, apt_seen: 0 }
+    }
+
+    /// Repetition count and adaptive proportion cutoffs.
+    pub fn cutoffs(&self) -> (u16, u16) {
+        (self.rct_cutoff, self.apt_cutoff)
+    }
+
+    /// Feed one sample.
+    pub fn check(&mut self, sample: u8) -> Result<(), RngError> {
+        // Repetition count test
+        if self.last == Some(sample) {
+            self.run += 1;
+            if self.run >= self.rct_cutoff {
+                return Err(RngError::RepetitionCount);
+            }
+        } else {
+            self.last = Some(sample);
+            self.run = 1;
+        }
+
+        // Adaptive proportion test: count the window's first value
+        if self.apt_seen == 0 {
+            self.apt_ref = sample;
+            self.apt_count = 1;
+        } else if sample == self.apt_ref {
+            self.apt_count += 1;
+            if self.apt_count >= self.apt_cutoff {
+                return Err(RngError::AdaptiveProportion);
+            }
+        }
+        self.apt_seen += 1;
+        if self.apt_seen == APT_WINDOW {
+            self.apt_seen = 0;
+        }
+        Ok(())
+    }
+}
+
+/// An entropy source behind the continuous health tests.
+pub struct HealthChecked<S> {
+    source: S,
+    tests: HealthTests,
+    started: bool,
+    failed: Option<RngError>,
+}
+
+impl<S: EntropySource> HealthChecked<S> {
+    pub fn new(source: S) -> Self {
+        let tests = HealthTests::new(source.min_entropy_eighths());
+        Self { source, tests, started: false, failed: None }
+    }
+
+    /// The failure that took the source out of service, if any.
+    pub fn failure(&self) -> Option<RngError> {
+        self.failed
+    }
+
+    pub fn min_entropy_eighths(&self) -> u8 {
+        self.source.min_entropy_eighths().clamp(4, 64)
+    }
+
+    /// Fill `out` with raw noise that passed the health tests.
+    pub fn fill(&mut self, out: &mut [u8]) -> Result<(), RngError> {
+        if let Some(e) = self.failed {
+            return Err(e);
+        }
+        if !self.started {
+            let mut discard = [0u8; 64];
+            for _ in 0..STARTUP_SAMPLES / discard.len() {
+                self.fill_tested(&mut discard)?;
+            }
+            discard.zeroize();
+            self.started = true;
+        }
+        self.fill_tested(out)
+    }
+
+    fn fill_tested(&mut self, out: &mut [u8]) -> Result<(), RngError> {
+        let res = self.source.fill_raw(out).and_then(|()| out.iter().try_for_each(|&b| self.tests.check(b)));
+        if let Err(e) = res {
+            out.zeroize();
+            if e != RngError::SourceFailure {
+                self.failed = Some(e);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+// ---------------------------------------------------------------------------
+// CTR_DRBG (SP 800-90A section 10.2, AES-256, no derivation function)
+// ---------------------------------------------------------------------------
+
+/// Seed length: key + block.
+pub const SEED_LEN: usize = 48;
+/// Largest single request (2^19 bits).
+pub const MAX_REQUEST: usize = 1 << 16;
+/// Requests between reseeds.
+pub const RESEED_INTERVAL: u64 = 1 << 20;
+/// Min-entropy gathered for a seed, in bits (seed length + 64).
+const SEED_ENTROPY_BITS: usize = SEED_LEN * 8 + 64;
+
+/// Deterministic random bit generator.
+pub struct CtrDrbg {
+    key: [u8; 32],
+    v: [u8; 16],
+    reseed_counter: u64,
+}
+
+impl CtrDrbg {
+    /// Instantiate from `SEED_LEN` bytes of full-entropy input and an
+    /// optional personalization string (at most `SEED_LEN` bytes used).
+    pub fn new(entropy: &[u8; SEED_LEN], personalization: &[u8]) -> Self {
+        let mut drbg = Self { key: [0; 32], v: [0; 16], reseed_counter: 1 };
+        drbg.update(&xor_pad(entropy, personalization));
+        drbg
+    }
+
+    /// Reseed with fresh full-entropy input.
+    pub fn reseed(&mut self, entropy: &[u8; SEED_LEN], additional: &[u8]) {
+        self.update(&xor_pad(entropy, additional));
+        self.reseed_counter = 1;
+    }
+
+    /// True once `RESEED_INTERVAL` requests have been served.
+    pub fn needs_reseed(&self) -> bool {
+        self.reseed_counter > RESEED_INTERVAL
+    }
+
+    /// Fill `out` (at most `MAX_REQUEST` bytes).
+    pub fn generate(&mut self, out: &mut [u8], additional_input: &[u8]) -> Result<(), RngError> {
+        if out.len() > MAX_REQUEST {
+            return Err(RngError::RequestTooLarge);
+        }
+        if self.needs_reseed() {
+            return Err(RngError::ReseedRequired);
+        }
+        let additional = xor_pad(&[0; SEED_LEN], additional_input);
+        if !additional_input.is_empty() {
+            self.update(&additional);
+        }
+        let cipher = Aes256::new(&self.key.into());
+        for chunk in out.chunks_mut(16) {
+            increment(&mut self.v);
+            let mut block = self.v.into();
+            cipher.encrypt_block(&mut block);
+            chunk.copy_from_slice(&block[..chunk.len()]);
+            block.zeroize();
+        }
+        self.update(&additional);
+        self.reseed_counter += 1;
+        Ok(())
+    }
+
+    /// CTR_DRBG_Update.
+    fn update(&mut self, provided: &[u8; SEED_LEN]) {
+        let cipher = Aes256::new(&self.key.into());
+        let mut temp = [0u8; SEED_LEN];
+        for chunk in temp.chunks_mut(16) {
+            increment(&mut self.v);
+            let mut block = self.v.into();
+            cipher.encrypt_block(&mut block);
+            chunk.copy_from_slice(&block);
+        }
+        for (t, p) in temp.iter_mut().zip(provided) {
+            *t ^= p;
+        }
+        self.key.copy_from_slice(&temp[..32]);
+        self.v.copy_from_slice(&temp[32..]);
+        temp.zeroize();
+    }
+}
+
+impl Drop for CtrDrbg {
+    fn drop(&mut self) {
+        self.key.zeroize();
+        self.v.zeroize();
+    }
+}
+
+fn increment(v: &mut [u8; 16]) {
+    *v = u128::from_be_bytes(*v).wrapping_add(1).to_be_bytes();
+}
+
+/// `a` XOR `b`, with `b` zero-padded or truncated to `SEED_LEN`.
+fn xor_pad(a: &[u8; SEED_LEN], b: &[u8]) -> [u8; SEED_LEN] {
+    let mut out = *a;
+    for (o, x) in out.iter_mut().zip(b) {
+        *o ^= x;
+    }
+    out
+}
+
+/// Full-entropy seed from a health-checked source: raw bytes carrying
+/// `SEED_ENTROPY_BITS` of min-entropy, compressed with SHA-256.
+fn conditioned_seed<S: EntropySource>(source: &mut HealthChecked<S>) -> Result<[u8; SEED_LEN], RngError> {
+    let raw_len = (SEED_ENTROPY_BITS * 8).div_ceil(usize::from(source.min_entropy_eighths()));
+    let (mut h0, mut h1) = (Sha256::new_with_prefix([0u8]), Sha256::new_with_prefix([1u8]));
+    let mut chunk = [0u8; 64];
+    let mut left = raw_len;
+    while left > 0 {
+        let n = left.min(chunk.len());
+        source.fill(&mut chunk[..n])?;
+        h0.update(&chunk[..n]);
+        h1.update(&chunk[..n]);
+        left -= n;
+    }
+    chunk.zeroize();
+    let mut seed = [0u8; SEED_LEN];
+    seed[..32].copy_from_slice(&h0.finalize());
+    seed[32..].copy_from_slice(&h1.finalize()[..16]);
+    Ok(seed)
+}
+
+/// Health-tested source feeding a CTR_DRBG.
+pub struct SystemRng<S> {
+    source: HealthChecked<S>,
+    drbg: Option<CtrDrbg>,
+}
+
+impl<S: EntropySource> SystemRng<S> {
+    /// Personalization string of the DRBG.
+    const PERSONALIZATION: &'static [u8] = b"SecureIoTOS rng v1";
+
+    pub fn new(source: S) -> Self {
+        Self { source: HealthChecked::new(source), drbg: None }
+    }
+
+    /// Fill `out`; seeds on first use and reseeds when due. Requests
+    /// larger than `MAX_REQUEST` are split.
+    pub fn fill(&mut self, out: &mut [u8]) -> Result<(), RngError> {
+        for chunk in out.chunks_mut(MAX_REQUEST) {
+            let drbg = match self.drbg.take() {
+                Some(mut d) if d.needs_reseed() => {
+                    let mut seed = conditioned_seed(&mut self.source)?;
+                    d.reseed(&seed, &[]);
+                    seed.zeroize();
+                    d
+                }
+                Some(d) => d,
+                None => {
+                    let mut seed = conditioned_seed(&mut self.source)?;
+                    let d = CtrDrbg::new(&seed, Self::PERSONALIZATION);
+                    seed.zeroize();
+                    d
+                }
+            };
+            self.drbg.insert(drbg).generate(chunk, &[])?;
+        }
+        Ok(())
+    }
+
+    /// Health test failure of the source, if it was taken out of service.
+    pub fn source_failure(&self) -> Option<RngError> {
+        self.source.failure()
+    }
+}
+
+// ---------------------------------------------------------------------------
+// Entropy sources
+// ---------------------------------------------------------------------------
+
+fn read_reg(addr: usize) -> u32 {
+    // SAFETY: only called with register addresses of the TRNG a backend
+    // was constructed for.
+    unsafe { core::ptr::read_volatile(addr as *const u32) }
+}
+
+fn write_reg(addr: usize, value: u32) {
+    // SAFETY: as in `read_reg`.
+    unsafe { core::ptr::write_volatile(addr as *mut u32, value) }
+}
+
+/// Polls of a data-ready flag before the source counts as failed.
+const READY_POLLS: u32 = 100_000;
+
+/// STM32 RNG peripheral (F2/F4/F7/L4/H7/G4), 32 bits per read.
+pub struct Stm32Rng {
+    base: usize,
+}
+
+impl Stm32Rng {
+    /// RNG base on STM32F4/F7 (AHB2).
+    pub const STM32F4_BASE: usize = 0x5006_0800;
+    /// RNG base on STM32L4.
+    pub const STM32L4_BASE: usize = 0x5006_0800;
+    /// RNG base on STM32H7.
+    pub const STM32H7_BASE: usize = 0x4802_1800;
+
+    const CR: usize = 0x0;
+    const SR: usize = 0x4;
+    const DR: usize = 0x8;
+    const CR_RNGEN: u32 = 1 << 2;
+    const SR_DRDY: u32 = 1 << 0;
+    const SR_CECS: u32 = 1 << 1;
+    const SR_SECS: u32 = 1 << 2;
+
+    /// Enable the RNG at `base`. Its clock (RCC AHB2ENR.RNGEN, and the
+    /// 48 MHz domain) must already be on.
+    pub fn new(base: usize) -> Self {
+        write_reg(base + Self::CR, read_reg(base + Self::CR) | Self::CR_RNGEN);
+        Self { base }
+    }
+
+    fn word(&mut self) -> Result<u32, RngError> {
+        for _ in 0..READY_POLLS {
+            let sr = read_reg(self.base + Self::SR);
+            if sr & (Self::SR_CECS | Self::SR_SECS) != 0 {
+                // Clock or seed error: the block needs a restart
+                write_reg(self.base + Self::CR, read_reg(self.base + Self::CR) & !Self::CR_RNGEN);
+                write_reg(self.base + Self::CR, read_reg(self.base + Self::CR) | Self::CR_RNGEN);
+                return Err(RngError::SourceFailure);
+            }
+            if sr & Self::SR_DRDY != 0 {
+                return Ok(read_reg(self.base + Self::DR));
+            }
+        }
+        Err(RngError::SourceFailure)
+    }
+}
+
+impl EntropySource for Stm32Rng {
+    fn min_entropy_eighths(&self) -> u8 {
+        // Conservative: half of the output bits
+        32
+    }
+
+    fn fill_raw(&mut self, out: &mut [u8]) -> Result<(), RngError> {
+        for chunk in out.chunks_mut(4) {
+            let w = self.word()?.to_le_bytes();
+            chunk.copy_from_slice(&w[..chunk.len()]);
+        }
+        Ok(())
+    }
+}
+
+/// nRF52 RNG peripheral, one byte per read, bias correction on.
+pub struct Nrf52Rng;
+
+impl Nrf52Rng {
+    const BASE: usize = 0x4000_D000;
+    const TASKS_START: usize = Self::BASE;
+    const EVENTS_VALRDY: usize = Self::BASE + 0x100;
+    const CONFIG: usize = Self::BASE + 0x504;
+    const VALUE: usize = Self::BASE + 0x508;
+
+    pub fn new() -> Self {
+        // DERCEN: digital error correction removes the bias
+        write_reg(Self::CONFIG, 1);
+        write_reg(Self::EVENTS_VALRDY, 0);
+        write_reg(Self::TASKS_START, 1);
+        Self
+    }
+}
+
+impl Default for Nrf52Rng {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EntropySource for Nrf52Rng {
+    fn min_entropy_eighths(&self) -> u8 {
+        // Bias-corrected, but assessed conservatively at 6 bits/byte
+        48
+    }
+
+    fn fill_raw(&mut self, out: &mut [u8]) -> Result<(), RngError> {
+        for b in out.iter_mut() {
+            if !(0..READY_POLLS).any(|_| read_reg(Self::EVENTS_VALRDY) != 0) {
+                return Err(RngError::SourceFailure);
+            }
+            *b = read_reg(Self::VALUE) as u8;
+            write_reg(Self::EVENTS_VALRDY, 0);
+        }
+        Ok(())
+    }
+}
+
+/// Fallback for parts without a TRNG: the least significant bit of
+/// repeated conversions of a floating (or thermal-noise) ADC input, eight
+/// conversions per byte.
+pub struct AdcNoise<F> {
+    read: F,
+    min_entropy_eighths: u8,
+}
+
+impl<F: FnMut() -> Result<u16, RngError>> AdcNoise<F> {
+    /// `read` performs one conversion. `min_entropy_eighths` is the
+    /// assessed entropy per collected byte; without an assessment of the
+    /// board, use the default of 1 bit (8).
+    pub fn new(read: F, min_entropy_eighths: u8) -> Self {
+        Self { read, min_entropy_eighths }
+    }
+}
+
+impl<F: FnMut() -> Result<u16, RngError>> EntropySource for AdcNoise<F> {
+    fn min_entropy_eighths(&self) -> u8 {
+        self.min_entropy_eighths
+    }
+
+    fn fill_raw(&mut self, out: &mut [u8]) -> Result<(), RngError> {
+        for b in out.iter_mut() {
+            let mut v = 0u8;
+            for _ in 0..8 {
+                v = (v << 1) | ((self.read)()? & 1) as u8;
+            }
+            *b = v;
+        }
+        Ok(())
+    }
+}
+
+// ---------------------------------------------------------------------------
+// System RNG
+// ---------------------------------------------------------------------------
+
+/// The board's entropy source, as installed
+#[cfg(target_arch = "arm")]
+type BoxedSource = Box<dyn EntropySource + Send>;
+
+#[cfg(target_arch = "arm")]
+static SYSTEM_RNG: cortex_m::interrupt::Mutex<core::cell::RefCell<Option<SystemRng<BoxedSource>>>> =
+    cortex_m::interrupt::Mutex::new(core::cell::RefCell::new(None));
+
+/// Install the board's entropy source (once, during boot). The startup
+/// health tests run on the first request.
+#[cfg(target_arch = "arm")]
+pub fn install_entropy_source(source: BoxedSource) {
+    cortex_m::interrupt::free(|cs| *SYSTEM_RNG.borrow(cs).borrow_mut() = Some(SystemRng::new(source)));
+}
+
+/// Fill `out` with random bytes from the system DRBG.
+#[cfg(target_arch = "arm")]
+pub fn fill_random(out: &mut [u8]) -> Result<(), RngError> {
+    cortex_m::interrupt::free(|cs| {
+        SYSTEM_RNG.borrow(cs).borrow_mut().as_mut().ok_or(RngError::NotInitialized)?.fill(out)
+    })
+}
+
+/// Fill `out` with random bytes from the operating system.
+#[cfg(not(target_arch = "arm"))]
+pub fn fill_random(out: &mut [u8]) -> Result<(), RngError> {
+    OsRng.try_fill_bytes(out).map_err(|_| RngError::SourceFailure)
+}
+
 /// Initialize hardware RNG if available.
 ///
-/// * On embedded boards, call the MCU-specific HAL here (e.g. `stm32_hal::rng_init()`).
+/// * On embedded boards, boot code installs the MCU's source with
+///   `install_entropy_source` (e.g. `Box::new(Stm32Rng::new(Stm32Rng::STM32F4_BASE))`).
 /// * On desktop/host builds, no explicit initialization is required.
 pub fn init_rng() {
-    #[cfg(target_arch = "arm")]
-    {
-        // Example stub: replace with your MCU HAL init
-        // stm32_hal::rng::init().expect("Failed to init hardware RNG");
-    }
-
     // On non-embedded platforms, nothing to do: `OsRng` is lazy-initialized.
 }
 
 /// Generate a random 128-bit key (16 bytes) using a cryptographically
-/// secure RNG. Falls back to the operating-system RNG if no hardware RNG
-/// is configured.
+/// secure RNG.
+///
+/// # Panics
+/// If no entropy source is installed or it failed its health tests: no
+/// key is better than a predictable one.
 pub fn generate_random_key() -> [u8; 16] {
     let mut key = [0u8; 16];
-
-    // Prefer hardware RNG if you've set one up; otherwise OsRng.
-    // OsRng pulls from the host OS or hardware entropy source.
-    OsRng.fill_bytes(&mut key);
-
+    if let Err(e) = fill_random(&mut key) {
+        panic!("no usable entropy for key generation: {:?}", e);
+    }
     key
 }
 
@@ -47,4 +596,102 @@ mod tests {
         assert_eq!(k1.len(), 16);
         assert_ne!(k1, k2, "Two generated keys should almost never match");
     }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    /// Scripted source: xorshift noise, or a fixed pattern.
+    struct TestSource {
+        state: u32,
+        pattern: Option<fn(u32, u8) -> u8>,
+        claim: u8,
+        reads: usize,
+    }
+
+    impl TestSource {
+        fn noise(claim: u8) -> Self {
+            Self { state: 0x1234_5678, pattern: None, claim, reads: 0 }
+        }
+    }
+
+    impl EntropySource for TestSource {
+        fn min_entropy_eighths(&self) -> u8 {
+            self.claim
+        }
+
+        fn fill_raw(&mut self, out: &mut [u8]) -> Result<(), RngError> {
+            for b in out.iter_mut() {
+                self.state ^= self.state << 13;
+                self.state ^= self.state >> 17;
+                self.state ^= self.state << 5;
+                *b = self.pattern.map_or(self.state as u8, |p| p(self.reads as u32, self.state as u8));
+                self.reads += 1;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn health_tests_catch_stuck_and_biased_sources() {
+        assert_eq!(HealthTests::new(64).cutoffs(), (4, 13));
+        assert_eq!(HealthTests::new(8).cutoffs(), (21, 311));
+
+        let mut t = HealthTests::new(8);
+        assert!((0..20).all(|_| t.check(0xAA).is_ok()));
+        assert_eq!(t.check(0xAA), Err(RngError::RepetitionCount));
+
+        // 0x00 in every other sample: no long runs, but far too frequent
+        let mut t = HealthTests::new(32);
+        let res = (0..APT_WINDOW as u32).try_for_each(|i| t.check(if i % 2 == 0 { 0 } else { i as u8 | 1 }));
+        assert_eq!(res, Err(RngError::AdaptiveProportion));
+
+        // Failures are latched; the startup samples are consumed first
+        let mut src = HealthChecked::new(TestSource { pattern: Some(|i, n| if i >= 2000 { 7 } else { n }), ..TestSource::noise(64) });
+        let mut buf = [0u8; 512];
+        src.fill(&mut buf).unwrap();
+        assert_eq!(src.source.reads, STARTUP_SAMPLES + 512);
+        assert_eq!(src.fill(&mut buf), Err(RngError::RepetitionCount));
+        assert_eq!(buf, [0u8; 512]);
+        src.source.pattern = None;
+        assert_eq!(src.fill(&mut buf), Err(RngError::RepetitionCount));
+        assert_eq!(src.failure(), Some(RngError::RepetitionCount));
+    }
+
+    #[test]
+    fn ctr_drbg_matches_reference_and_reseeds() {
+        let entropy: [u8; SEED_LEN] = core::array::from_fn(|i| i as u8);
+        let mut drbg = CtrDrbg::new(&entropy, b"SecureIoTOS");
+        let mut out = [0u8; 32];
+        drbg.generate(&mut out, &[]).unwrap();
+        drbg.generate(&mut out, &[]).unwrap();
+        assert_eq!(out.to_vec(), hex("dd5f1e950e30bdb24759e3b452b77c53adbd226ebc57be1ff1f2f68064143270"));
+
+        let mut short = [0u8; 20];
+        CtrDrbg::new(&entropy, &[]).generate(&mut short, b"extra").unwrap();
+        assert_eq!(short.to_vec(), hex("ae189b9fce3ab5e002000f2b7338ffeb14879bbe"));
+
+        let before = out;
+        drbg.reseed(&[0x5A; SEED_LEN], &[]);
+        drbg.generate(&mut out, &[]).unwrap();
+        assert_ne!(out, before);
+        assert_eq!(drbg.generate(&mut vec![0; MAX_REQUEST + 1], &[]), Err(RngError::RequestTooLarge));
+        drbg.reseed_counter = RESEED_INTERVAL + 1;
+        assert_eq!(drbg.generate(&mut out, &[]), Err(RngError::ReseedRequired));
+    }
+
+    #[test]
+    fn system_rng_seeds_from_enough_raw_entropy() {
+        // Half a bit per byte: 448 bits of min-entropy take 896 raw bytes
+        let mut rng = SystemRng::new(TestSource::noise(4));
+        let mut a = vec![0u8; MAX_REQUEST + 16];
+        rng.fill(&mut a).unwrap();
+        assert_eq!(rng.source.source.reads, STARTUP_SAMPLES + 896);
+        assert_ne!(a[..16], a[MAX_REQUEST..MAX_REQUEST + 16]);
+
+        let mut b = [0u8; 16];
+        rng.drbg.as_mut().unwrap().reseed_counter = RESEED_INTERVAL + 1;
+        rng.fill(&mut b).unwrap();
+        assert_eq!(rng.source.source.reads, STARTUP_SAMPLES + 2 * 896);
+    }
 }
@@ -0,0 +1,188 @@
+//! SecureIoTOS Cryptography Secure Element Module
+//! ----------------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Secure elements: P-256 keys that are generated, stored and used inside
+//! a separate chip, so the private key never appears on the MCU's bus or
+//! in its RAM.
+//!
+//! - `SecureElement` is what the rest of the system uses: random bytes,
+//!   key generation in a slot, public key readout and signing of a
+//!   SHA-256 digest.
+//! - `atecc608::Atecc608` drives a Microchip ATECC608A/B over I2C (the
+//!   Microchip command protocol, CRC-16 framed).
+//! - `se050::Se050` drives an NXP SE050 over I2C (ISO 7816 APDUs of the
+//!   SE050 IoT applet over T=1).
+//!
+//! Both drivers are generic over the embedded-hal 1.0 `I2c` and `DelayNs`
+//! traits; wrap a SecureIoTOS bus and timer in `hal::ehal::Compat` to use
+//! them. `ecc::install_secure_element` makes a secure element the holder
+//! of the device signing key, and `SeEntropy` feeds its RNG to
+//! `rng::SystemRng`.
+//!
+//! Parts come from the factory unconfigured: slot configuration and
+//! locking (ATECC608) or curve creation (SE050) belong to provisioning
+//! and are not done here.
+
+pub mod atecc608;
+pub mod se050;
+
+use alloc::boxed::Box;
+use p256::ecdsa::{Signature, VerifyingKey};
+
+use crate::hash::{self, Algorithm};
+use crate::rng::{EntropySource, RngError};
+
+/// Errors of secure element operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeError {
+    /// An I2C transfer failed (other than the busy NACK while polling)
+    Bus,
+    /// The chip did not answer within the command's execution time
+    Timeout,
+    /// A response failed its CRC
+    Checksum,
+    /// A response was malformed or did not answer the request
+    Protocol,
+    /// The slot or object id does not exist on this part
+    InvalidSlot,
+    /// The chip returned an invalid public key or signature
+    BadKey,
+    /// The chip rejected the command with this status (ATECC608 status
+    /// byte or SE050 status word)
+    Device(u16),
+}
+
+/// Key slot (ATECC608, 0..=15) or object id (SE050).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct KeySlot(pub u32);
+
+/// A chip that keeps P-256 private keys and uses them internally.
+pub trait SecureElement {
+    /// Part name, for logs.
+    fn name(&self) -> &'static str;
+
+    /// Fill `out` from the chip's random number generator.
+    fn random(&mut self, out: &mut [u8]) -> Result<(), SeError>;
+
+    /// Create a new private key in `slot` (replacing any key there) and
+    /// return its public key.
+    fn generate_key(&mut self, slot: KeySlot) -> Result<VerifyingKey, SeError>;
+
+    /// Public key of the private key in `slot`.
+    fn public_key(&mut self, slot: KeySlot) -> Result<VerifyingKey, SeError>;
+
+    /// ECDSA signature over a SHA-256 `digest` with the key in `slot`.
+    fn sign_digest(&mut self, slot: KeySlot, digest: &[u8; 32]) -> Result<Signature, SeError>;
+
+    /// ECDSA P-256 / SHA-256 signature over `message`; the message is
+    /// hashed on the MCU, only the digest goes to the chip.
+    fn sign(&mut self, slot: KeySlot, message: &[u8]) -> Result<Signature, SeError> {
+        let mut digest = [0u8; 32];
+        digest.copy_from_slice(hash::digest(Algorithm::Sha256, message).as_bytes());
+        self.sign_digest(slot, &digest)
+    }
+}
+
+impl<S: SecureElement + ?Sized> SecureElement for Box<S> {
+    fn name(&self) -> &'static str {
+        (**self).name()
+    }
+
+    fn random(&mut self, out: &mut [u8]) -> Result<(), SeError> {
+        (**self).random(out)
+    }
+
+    fn generate_key(&mut self, slot: KeySlot) -> Result<VerifyingKey, SeError> {
+        (**self).generate_key(slot)
+    }
+
+    fn public_key(&mut self, slot: KeySlot) -> Result<VerifyingKey, SeError> {
+        (**self).public_key(slot)
+    }
+
+    fn sign_digest(&mut self, slot: KeySlot, digest: &[u8; 32]) -> Result<Signature, SeError> {
+        (**self).sign_digest(slot, digest)
+    }
+}
+
+/// A secure element's RNG as an `rng::EntropySource`. Both parts output
+/// DRBG-conditioned bytes from an internal TRNG, so full entropy is
+/// claimed; the continuous health tests still run on everything read.
+pub struct SeEntropy<S>(pub S);
+
+impl<S: SecureElement> EntropySource for SeEntropy<S> {
+    fn min_entropy_eighths(&self) -> u8 {
+        64
+    }
+
+    fn fill_raw(&mut self, out: &mut [u8]) -> Result<(), RngError> {
+        self.0.random(out).map_err(|_| RngError::SourceFailure)
+    }
+}
+
+/// Public key from the raw `X || Y` coordinates secure elements return.
+pub(crate) fn public_key_from_xy(xy: &[u8]) -> Result<VerifyingKey, SeError> {
+    if xy.len() != 64 {
+        return Err(SeError::BadKey);
+    }
+    let mut sec1 = [0u8; 65];
+    sec1[0] = 0x04;
+    sec1[1..].copy_from_slice(xy);
+    VerifyingKey::from_sec1_bytes(&sec1).map_err(|_| SeError::BadKey)
+}
+
+/// Whether a polling read failed because the chip is busy (it NACKs its
+/// address while executing a command).
+pub(crate) fn is_busy<E: embedded_hal::i2c::Error>(e: &E) -> bool {
+    matches!(e.kind(), embedded_hal::i2c::ErrorKind::NoAcknowledge(_))
+}
+
+#[cfg(test)]
+pub(crate) mod test_support {
+    //! Pieces shared by the driver tests' chip models.
+
+    use embedded_hal::delay::DelayNs;
+    use embedded_hal::i2c::{self, ErrorKind, NoAcknowledgeSource};
+    use p256::ecdsa::Signature;
+    use p256::elliptic_curve::ops::Reduce;
+    use p256::elliptic_curve::sec1::ToEncodedPoint;
+    use p256::elliptic_curve::AffineXCoordinate;
+    use p256::{FieldBytes, ProjectivePoint, Scalar, SecretKey};
+
+    /// The only I2C error a chip model raises: address not acknowledged.
+    #[derive(Debug)]
+    pub struct Nack;
+
+    impl i2c::Error for Nack {
+        fn kind(&self) -> ErrorKind {
+            ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address)
+        }
+    }
+
+    pub struct NoDelay;
+
+    impl DelayNs for NoDelay {
+        fn delay_ns(&mut self, _ns: u32) {}
+    }
+
+    /// Uncompressed public point of a private scalar, without the `04`.
+    pub fn public_xy(key: &[u8; 32]) -> Vec<u8> {
+        SecretKey::from_be_bytes(key).unwrap().public_key().to_encoded_point(false).as_bytes()[1..].to_vec()
+    }
+
+    /// ECDSA over a digest, as the chips compute it (fixed nonce: models only).
+    pub fn sign_prehash(key: &[u8; 32], digest: &[u8; 32]) -> Signature {
+        let d = Scalar::from_be_bytes_reduced(FieldBytes::from(*key));
+        let z = Scalar::from_be_bytes_reduced(FieldBytes::from(*digest));
+        let k = Scalar::from(0x5EC0_DE42u64);
+        let r = Scalar::from_be_bytes_reduced((ProjectivePoint::GENERATOR * k).to_affine().x());
+        let s = k.invert().unwrap() * (z + r * d);
+        Signature::from_scalars(r, s).unwrap()
+    }
+}
@@ -0,0 +1,427 @@
+//! SecureIoTOS Cryptography Secure Element ATECC608 Module
+//! -------------------------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Microchip ATECC608A/B over I2C.
+//!
+//! Every operation is one wake / command(s) / sleep cycle: the chip's
+//! watchdog puts it to sleep 1.3 s after wake anyway, and sleeping clears
+//! TempKey, so no digest is left behind between operations.
+//!
+//! ```text
+//! command:  word address 0x03 | count | opcode | param1 | param2 (LE) | data | CRC-16 (LE)
+//! response: count | data | CRC-16 (LE)       (a 1-byte data field is a status)
+//! ```
+//!
+//! `count` covers itself through the CRC. The chip NACKs its address
+//! while a command executes; the driver polls until the command's maximum
+//! execution time has passed.
+//!
+//! Signing loads the digest into TempKey with Nonce (pass-through) and
+//! runs Sign in external-message mode, so slots must be configured for
+//! external signatures.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+use p256::ecdsa::{Signature, VerifyingKey};
+use zeroize::Zeroize;
+
+use super::{is_busy, public_key_from_xy, KeySlot, SeError, SecureElement};
+
+/// Factory default 7-bit I2C address (0xC0 in Microchip's 8-bit notation).
+pub const DEFAULT_ADDRESS: u8 = 0x60;
+/// Number of data slots.
+pub const SLOT_COUNT: u32 = 16;
+
+/// Word address of a command packet.
+const WORD_ADDRESS_COMMAND: u8 = 0x03;
+/// Word address that puts the chip to sleep.
+const WORD_ADDRESS_SLEEP: u8 = 0x01;
+
+const OP_NONCE: u8 = 0x16;
+const OP_RANDOM: u8 = 0x1B;
+const OP_GENKEY: u8 = 0x40;
+const OP_SIGN: u8 = 0x41;
+
+/// GenKey: create a private key in the slot.
+const GENKEY_MODE_PRIVATE: u8 = 0x04;
+/// GenKey: compute the public key of the slot's private key.
+const GENKEY_MODE_PUBLIC: u8 = 0x00;
+/// Nonce: pass 32 bytes through to TempKey.
+const NONCE_MODE_PASSTHROUGH: u8 = 0x03;
+/// Sign: sign the message in TempKey.
+const SIGN_MODE_EXTERNAL: u8 = 0x80;
+
+/// Response to a wake pulse: count, status 0x11, CRC.
+const WAKE_RESPONSE: [u8; 4] = [0x04, 0x11, 0x33, 0x43];
+/// Wake pulse to first command (tWHI), in microseconds.
+const WAKE_DELAY_US: u32 = 1500;
+
+/// Maximum execution times (datasheet), in milliseconds.
+const EXEC_RANDOM_MS: u32 = 23;
+const EXEC_NONCE_MS: u32 = 7;
+const EXEC_GENKEY_MS: u32 = 115;
+const EXEC_SIGN_MS: u32 = 50;
+
+/// Random bytes per Random command.
+const RANDOM_LEN: usize = 32;
+/// Random commands per wake, well inside the watchdog period.
+const RANDOM_PER_WAKE: usize = 16;
+/// Longest data field of a command or response.
+const MAX_DATA: usize = 64;
+
+/// ATECC608 on an I2C bus.
+pub struct Atecc608<I2C, D> {
+    i2c: I2C,
+    delay: D,
+    address: u8,
+}
+
+impl<I2C: I2c, D: DelayNs> Atecc608<I2C, D> {
+    pub fn new(i2c: I2C, delay: D) -> Self {
+        Self::with_address(i2c, delay, DEFAULT_ADDRESS)
+    }
+
+    /// Driver for a chip whose address was changed in its config zone.
+    pub fn with_address(i2c: I2C, delay: D, address: u8) -> Self {
+        Self { i2c, delay, address }
+    }
+
+    /// Give the bus and delay back.
+    pub fn release(self) -> (I2C, D) {
+        (self.i2c, self.delay)
+    }
+
+    fn wake(&mut self) -> Result<(), SeError> {
+        // Address 0 is never acknowledged; at 100 kHz the transfer holds
+        // SDA low long enough (tWLO) to wake the chip.
+        let _ = self.i2c.write(0x00, &[0x00]);
+        self.delay.delay_us(WAKE_DELAY_US);
+        let mut resp = [0u8; 4];
+        self.i2c.read(self.address, &mut resp).map_err(|_| SeError::Bus)?;
+        if resp != WAKE_RESPONSE {
+            return Err(SeError::Protocol);
+        }
+        Ok(())
+    }
+
+    fn sleep(&mut self) {
+        // A chip that misses this sleeps on its own when the watchdog expires.
+        let _ = self.i2c.write(self.address, &[WORD_ADDRESS_SLEEP]);
+    }
+
+    /// Run `f` between wake and sleep.
+    fn session<R>(&mut self, f: impl FnOnce(&mut Self) -> Result<R, SeError>) -> Result<R, SeError> {
+        self.wake()?;
+        let res = f(self);
+        self.sleep();
+        res
+    }
+
+    /// Send one command and read its response into `resp`, which must
+    /// have the exact length of the expected data (1 for a status).
+    fn execute(
+        &mut self,
+        opcode: u8,
+        param1: u8,
+        param2: u16,
+        data: &[u8],
+        exec_ms: u32,
+        resp: &mut [u8],
+    ) -> Result<(), SeError> {
+        if data.len() > MAX_DATA || resp.len() > MAX_DATA {
+            return Err(SeError::Protocol);
+        }
+        let count = 7 + data.len();
+        let mut packet = [0u8; 1 + 7 + MAX_DATA];
+        packet[0] = WORD_ADDRESS_COMMAND;
+        packet[1] = count as u8;
+        packet[2] = opcode;
+        packet[3] = param1;
+        packet[4..6].copy_from_slice(&param2.to_le_bytes());
+        packet[6..6 + data.len()].copy_from_slice(data);
+        let crc = crc16(&packet[1..count - 1]);
+        packet[count - 1..=count].copy_from_slice(&crc.to_le_bytes());
+        let res = self.transfer(&packet[..=count], exec_ms, resp);
+        packet.zeroize();
+        res
+    }
+
+    fn transfer(&mut self, packet: &[u8], exec_ms: u32, resp: &mut [u8]) -> Result<(), SeError> {
+        self.i2c.write(self.address, packet).map_err(|_| SeError::Bus)?;
+
+        let mut frame = [0u8; 1 + MAX_DATA + 2];
+        let mut waited = 0;
+        loop {
+            match self.i2c.read(self.address, &mut frame[..1]) {
+                Ok(()) => break,
+                Err(e) if !is_busy(&e) => return Err(SeError::Bus),
+                Err(_) if waited >= exec_ms => return Err(SeError::Timeout),
+                Err(_) => {
+                    self.delay.delay_ms(1);
+                    waited += 1;
+                }
+            }
+        }
+        let n = usize::from(frame[0]);
+        if !(4..=frame.len()).contains(&n) {
+            return Err(SeError::Protocol);
+        }
+        self.i2c.read(self.address, &mut frame[1..n]).map_err(|_| SeError::Bus)?;
+        if crc16(&frame[..n - 2]).to_le_bytes() != frame[n - 2..n] {
+            return Err(SeError::Checksum);
+        }
+
+        let body = &frame[1..n - 2];
+        let res = if body.len() == 1 && body[0] != 0x00 {
+            Err(SeError::Device(u16::from(body[0])))
+        } else if body.len() != resp.len() {
+            Err(SeError::Protocol)
+        } else {
+            resp.copy_from_slice(body);
+            Ok(())
+        };
+        frame.zeroize();
+        res
+    }
+
+    fn check_slot(slot: KeySlot) -> Result<u16, SeError> {
+        if slot.0 < SLOT_COUNT { Ok(slot.0 as u16) } else { Err(SeError::InvalidSlot) }
+    }
+}
+
+impl<I2C: I2c, D: DelayNs> SecureElement for Atecc608<I2C, D> {
+    fn name(&self) -> &'static str {
+        "ATECC608"
+    }
+
+    fn random(&mut self, out: &mut [u8]) -> Result<(), SeError> {
+        for batch in out.chunks_mut(RANDOM_LEN * RANDOM_PER_WAKE) {
+            self.session(|se| {
+                for chunk in batch.chunks_mut(RANDOM_LEN) {
+                    let mut block = [0u8; RANDOM_LEN];
+                    se.execute(OP_RANDOM, 0, 0, &[], EXEC_RANDOM_MS, &mut block)?;
+                    chunk.copy_from_slice(&block[..chunk.len()]);
+                    block.zeroize();
+                }
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    fn generate_key(&mut self, slot: KeySlot) -> Result<VerifyingKey, SeError> {
+        let slot = Self::check_slot(slot)?;
+        let mut xy = [0u8; 64];
+        self.session(|se| se.execute(OP_GENKEY, GENKEY_MODE_PRIVATE, slot, &[], EXEC_GENKEY_MS, &mut xy))?;
+        public_key_from_xy(&xy)
+    }
+
+    fn public_key(&mut self, slot: KeySlot) -> Result<VerifyingKey, SeError> {
+        let slot = Self::check_slot(slot)?;
+        let mut xy = [0u8; 64];
+        self.session(|se| se.execute(OP_GENKEY, GENKEY_MODE_PUBLIC, slot, &[], EXEC_GENKEY_MS, &mut xy))?;
+        public_key_from_xy(&xy)
+    }
+
+    fn sign_digest(&mut self, slot: KeySlot, digest: &[u8; 32]) -> Result<Signature, SeError> {
+        let slot = Self::check_slot(slot)?;
+        let mut rs = [0u8; 64];
+        self.session(|se| {
+            // A Random first updates the RNG seed the signing nonce comes
+            // from, as Microchip's library does before every Sign.
+            let mut seed = [0u8; RANDOM_LEN];
+            se.execute(OP_RANDOM, 0, 0, &[], EXEC_RANDOM_MS, &mut seed)?;
+            se.execute(OP_NONCE, NONCE_MODE_PASSTHROUGH, 0, digest, EXEC_NONCE_MS, &mut [0u8; 1])?;
+            se.execute(OP_SIGN, SIGN_MODE_EXTERNAL, slot, &[], EXEC_SIGN_MS, &mut rs)
+        })?;
+        Signature::try_from(&rs[..]).map_err(|_| SeError::BadKey)
+    }
+}
+
+/// CRC-16 of the command protocol: polynomial 0x8005, initial value 0,
+/// data bits fed least significant first.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in data {
+        for bit in 0..8 {
+            let data_bit = (byte >> bit) & 1;
+            let crc_bit = (crc >> 15) as u8;
+            crc <<= 1;
+            if data_bit != crc_bit {
+                crc ^= 0x8005;
+            }
+        }
+    }
+    crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secure_element::test_support::{public_xy, sign_prehash, Nack, NoDelay};
+    use embedded_hal::i2c::{ErrorType, Operation};
+
+    /// Command-level model of the chip.
+    #[derive(Default)]
+    struct MockChip {
+        awake: bool,
+        keys: [Option<[u8; 32]>; 16],
+        tempkey: Option<[u8; 32]>,
+        out: Vec<u8>,
+        /// Reads NACKed after each command (executing)
+        busy_polls: u32,
+        busy: u32,
+        rng: u8,
+        corrupt_next: bool,
+        wakes: u32,
+    }
+
+    impl MockChip {
+        fn respond(&mut self, body: &[u8]) {
+            let mut frame = vec![(body.len() + 3) as u8];
+            frame.extend_from_slice(body);
+            frame.extend_from_slice(&crc16(&frame).to_le_bytes());
+            if std::mem::take(&mut self.corrupt_next) {
+                frame[1] ^= 1;
+            }
+            self.out = frame;
+            self.busy = self.busy_polls;
+        }
+
+        fn command(&mut self, packet: &[u8]) {
+            let count = usize::from(packet[0]);
+            assert_eq!(count, packet.len(), "count covers the packet");
+            assert_eq!(crc16(&packet[..count - 2]).to_le_bytes(), packet[count - 2..], "command CRC");
+            let (opcode, p1, p2) = (packet[1], packet[2], usize::from(u16::from_le_bytes([packet[3], packet[4]])));
+            let data = &packet[5..count - 2];
+            match (opcode, p1) {
+                (OP_RANDOM, 0) => {
+                    let block: Vec<u8> = (0..32)
+                        .map(|_| {
+                            self.rng = self.rng.wrapping_mul(29).wrapping_add(11);
+                            self.rng
+                        })
+                        .collect();
+                    self.respond(&block);
+                }
+                (OP_NONCE, NONCE_MODE_PASSTHROUGH) => {
+                    self.tempkey = Some(data.try_into().unwrap());
+                    self.respond(&[0x00]);
+                }
+                (OP_GENKEY, GENKEY_MODE_PRIVATE) => {
+                    let key = [p2 as u8 + 1; 32];
+                    self.keys[p2] = Some(key);
+                    self.respond(&public_xy(&key));
+                }
+                (OP_GENKEY, GENKEY_MODE_PUBLIC) => match self.keys[p2] {
+                    Some(key) => self.respond(&public_xy(&key)),
+                    None => self.respond(&[0x0F]),
+                },
+                (OP_SIGN, SIGN_MODE_EXTERNAL) => match (self.keys[p2], self.tempkey.take()) {
+                    (Some(key), Some(digest)) => self.respond(sign_prehash(&key, &digest).as_ref()),
+                    _ => self.respond(&[0x0F]),
+                },
+                _ => self.respond(&[0x03]),
+            }
+        }
+    }
+
+    impl ErrorType for MockChip {
+        type Error = Nack;
+    }
+
+    impl I2c for MockChip {
+        fn transaction(&mut self, address: u8, operations: &mut [Operation<'_>]) -> Result<(), Nack> {
+            for op in operations {
+                match op {
+                    Operation::Write(_) if address == 0x00 => {
+                        if !self.awake {
+                            self.awake = true;
+                            self.wakes += 1;
+                            self.out = WAKE_RESPONSE.to_vec();
+                        }
+                        return Err(Nack);
+                    }
+                    _ if address != DEFAULT_ADDRESS || !self.awake => return Err(Nack),
+                    Operation::Write(bytes) => match bytes[0] {
+                        WORD_ADDRESS_SLEEP => {
+                            self.awake = false;
+                            self.tempkey = None;
+                        }
+                        WORD_ADDRESS_COMMAND => self.command(&bytes[1..]),
+                        _ => return Err(Nack),
+                    },
+                    Operation::Read(buf) => {
+                        if self.busy > 0 {
+                            self.busy -= 1;
+                            return Err(Nack);
+                        }
+                        let n = buf.len().min(self.out.len());
+                        buf[..n].copy_from_slice(&self.out[..n]);
+                        self.out.drain(..n);
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn chip() -> Atecc608<MockChip, NoDelay> {
+        Atecc608::new(MockChip { busy_polls: 3, ..MockChip::default() }, NoDelay)
+    }
+
+    #[test]
+    fn crc_matches_the_wake_response() {
+        assert_eq!(crc16(&WAKE_RESPONSE[..2]).to_le_bytes(), WAKE_RESPONSE[2..]);
+    }
+
+    #[test]
+    fn keys_are_generated_and_used_inside_the_chip() {
+        let mut se = chip();
+        let public = se.generate_key(KeySlot(2)).unwrap();
+        assert_eq!(se.public_key(KeySlot(2)), Ok(public));
+
+        let sig = se.sign(KeySlot(2), b"attestation report").unwrap();
+        assert_eq!(crate::ecc::verify(&public, b"attestation report", &sig), Ok(()));
+        assert!(crate::ecc::verify(&public, b"attestation rePort", &sig).is_err());
+
+        // Each operation is its own wake / sleep cycle; TempKey is gone after it
+        let (mock, _) = se.release();
+        assert_eq!(mock.wakes, 3);
+        assert!(!mock.awake && mock.tempkey.is_none());
+    }
+
+    #[test]
+    fn random_spans_several_commands_and_wakes() {
+        let mut se = chip();
+        let mut out = [0u8; RANDOM_LEN * RANDOM_PER_WAKE + 5];
+        se.random(&mut out).unwrap();
+        assert_ne!(out[..32], out[32..64]);
+        assert_ne!(out[out.len() - 5..], [0; 5]);
+        assert_eq!(se.release().0.wakes, 2);
+    }
+
+    #[test]
+    fn chip_errors_are_reported() {
+        let mut se = chip();
+        assert_eq!(se.public_key(KeySlot(SLOT_COUNT)), Err(SeError::InvalidSlot));
+        assert_eq!(se.public_key(KeySlot(5)), Err(SeError::Device(0x0F)));
+        assert_eq!(se.sign_digest(KeySlot(5), &[0; 32]), Err(SeError::Device(0x0F)));
+
+        let (mut mock, delay) = se.release();
+        mock.corrupt_next = true;
+        let mut se = Atecc608::new(mock, delay);
+        assert_eq!(se.random(&mut [0u8; 8]), Err(SeError::Checksum));
+
+        let (mut mock, delay) = se.release();
+        mock.busy_polls = EXEC_GENKEY_MS + 10;
+        let mut se = Atecc608::new(mock, delay);
+        assert_eq!(se.generate_key(KeySlot(0)), Err(SeError::Timeout));
+    }
+}
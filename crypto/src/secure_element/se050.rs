@@ -0,0 +1,605 @@
+//! SecureIoTOS Cryptography Secure Element SE050 Module
+//! ----------------------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! NXP SE050 over I2C: APDUs of the SE050 IoT applet, carried in ISO 7816-3
+//! T=1 blocks (NXP's T=1 over I2C).
+//!
+//! ```text
+//! block: NAD | PCB | LEN | INF (LEN bytes) | CRC-16 (MSB first)
+//!        NAD 0x5A host -> SE, 0xA5 SE -> host; CRC-16/X-25 over NAD..INF
+//! PCB:   I-block 0 N(S) M 00000     R-block 1 0 0 N(R) 00 err     S-block 1 1 response type
+//! ```
+//!
+//! APDUs longer than `IFSC` are chained (M bit, acknowledged with
+//! R-blocks); chained responses are acknowledged the same way. A waiting
+//! time extension request (S(WTX)) is answered and the wait restarted.
+//! The first operation after power-up (or after an error) resynchronises
+//! the block sequence numbers and selects the applet.
+//!
+//! The applet commands used are TLV encoded (tags `0x41`, `0x42`, ...):
+//! GetRandom, WriteECKey (key pair generation), ReadObject and ECDSASign.
+//! The P-256 curve must exist on the chip (created at provisioning).
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::i2c::I2c;
+use p256::ecdsa::{Signature, VerifyingKey};
+use zeroize::Zeroize;
+
+use super::{is_busy, KeySlot, SeError, SecureElement};
+
+/// Default 7-bit I2C address.
+pub const DEFAULT_ADDRESS: u8 = 0x48;
+
+const NAD_TO_SE: u8 = 0x5A;
+const NAD_FROM_SE: u8 = 0xA5;
+/// Largest information field in either direction.
+const IFSC: usize = 254;
+
+const PCB_S_RESYNCH_REQ: u8 = 0xC0;
+const PCB_S_RESYNCH_RESP: u8 = 0xE0;
+const PCB_S_WTX_REQ: u8 = 0xC3;
+const PCB_S_WTX_RESP: u8 = 0xE3;
+
+/// How long to poll for a block before giving up (restarted by S(WTX)).
+const RESPONSE_TIMEOUT_MS: u32 = 1000;
+
+/// AID of the SE050 IoT applet.
+const APPLET_AID: [u8; 16] = [
+    0xA0, 0x00, 0x00, 0x03, 0x96, 0x54, 0x53, 0x00, 0x00, 0x00, 0x01, 0x03, 0x00, 0x00, 0x00, 0x00,
+];
+
+/// Applet instruction headers (CLA, INS, P1, P2).
+const APDU_SELECT: [u8; 4] = [0x00, 0xA4, 0x04, 0x00];
+const APDU_GET_RANDOM: [u8; 4] = [0x80, 0x04, 0x00, 0x49];
+/// WriteECKey: INS_WRITE, P1_EC | P1_KEY_PAIR
+const APDU_WRITE_EC_KEY_PAIR: [u8; 4] = [0x80, 0x01, 0x61, 0x00];
+const APDU_READ_OBJECT: [u8; 4] = [0x80, 0x02, 0x00, 0x00];
+/// ECDSASign: INS_CRYPTO, P1_SIGNATURE, P2_SIGN
+const APDU_ECDSA_SIGN: [u8; 4] = [0x80, 0x03, 0x0C, 0x09];
+
+const TAG_1: u8 = 0x41;
+const TAG_2: u8 = 0x42;
+const TAG_3: u8 = 0x43;
+/// Curve id of NIST P-256.
+const CURVE_NIST_P256: u8 = 0x03;
+/// ECSignatureAlgo: ECDSA over a SHA-256 digest.
+const SIG_ECDSA_SHA_256: u8 = 0x21;
+/// Status word of success.
+const SW_OK: u16 = 0x9000;
+
+/// Random bytes per GetRandom.
+const RANDOM_CHUNK: usize = 128;
+/// Longest APDU (command or response) the driver handles.
+const MAX_APDU: usize = 300;
+/// Object ids from here up belong to the applet.
+const RESERVED_OBJECTS: u32 = 0x7FFF_0000;
+
+/// SE050 on an I2C bus.
+pub struct Se050<I2C, D> {
+    i2c: I2C,
+    delay: D,
+    address: u8,
+    /// N(S) of our next I-block
+    seq: bool,
+    /// Sequence numbers synchronised and applet selected
+    ready: bool,
+}
+
+impl<I2C: I2c, D: DelayNs> Se050<I2C, D> {
+    pub fn new(i2c: I2C, delay: D) -> Self {
+        Self::with_address(i2c, delay, DEFAULT_ADDRESS)
+    }
+
+    pub fn with_address(i2c: I2C, delay: D, address: u8) -> Self {
+        Self { i2c, delay, address, seq: false, ready: false }
+    }
+
+    /// Give the bus and delay back.
+    pub fn release(self) -> (I2C, D) {
+        (self.i2c, self.delay)
+    }
+
+    // ---------------------------------------------------------------
+    // T=1 blocks
+    // ---------------------------------------------------------------
+
+    fn send_block(&mut self, pcb: u8, inf: &[u8]) -> Result<(), SeError> {
+        let mut frame = [0u8; 3 + IFSC + 2];
+        frame[..3].copy_from_slice(&[NAD_TO_SE, pcb, inf.len() as u8]);
+        frame[3..3 + inf.len()].copy_from_slice(inf);
+        let end = 3 + inf.len();
+        let crc = crc16(&frame[..end]);
+        frame[end..end + 2].copy_from_slice(&crc.to_be_bytes());
+        let res = self.i2c.write(self.address, &frame[..end + 2]).map_err(|_| SeError::Bus);
+        frame.zeroize();
+        res
+    }
+
+    /// Next block from the chip; S(WTX) requests are answered here.
+    /// Returns the PCB and the INF length in `inf`.
+    fn recv_block(&mut self, inf: &mut [u8; IFSC]) -> Result<(u8, usize), SeError> {
+        loop {
+            let mut header = [0u8; 3];
+            let mut waited = 0;
+            loop {
+                match self.i2c.read(self.address, &mut header) {
+                    Ok(()) => break,
+                    Err(e) if !is_busy(&e) => return Err(SeError::Bus),
+                    Err(_) if waited >= RESPONSE_TIMEOUT_MS => return Err(SeError::Timeout),
+                    Err(_) => {
+                        self.delay.delay_ms(1);
+                        waited += 1;
+                    }
+                }
+            }
+            let [nad, pcb, len] = header;
+            let len = usize::from(len);
+            if nad != NAD_FROM_SE || len > IFSC {
+                return Err(SeError::Protocol);
+            }
+            let mut rest = [0u8; IFSC + 2];
+            self.i2c.read(self.address, &mut rest[..len + 2]).map_err(|_| SeError::Bus)?;
+            let mut crc_input = [0u8; 3 + IFSC];
+            crc_input[..3].copy_from_slice(&header);
+            crc_input[3..3 + len].copy_from_slice(&rest[..len]);
+            let crc_ok = crc16(&crc_input[..3 + len]).to_be_bytes() == rest[len..len + 2];
+            crc_input.zeroize();
+            if !crc_ok {
+                rest.zeroize();
+                return Err(SeError::Checksum);
+            }
+            if pcb == PCB_S_WTX_REQ {
+                // Echo the multiplier; the chip needs more time
+                self.send_block(PCB_S_WTX_RESP, &rest[..len])?;
+                continue;
+            }
+            inf[..len].copy_from_slice(&rest[..len]);
+            rest.zeroize();
+            return Ok((pcb, len));
+        }
+    }
+
+    /// Reset both sides' sequence numbers.
+    fn resynch(&mut self) -> Result<(), SeError> {
+        self.send_block(PCB_S_RESYNCH_REQ, &[])?;
+        let mut inf = [0u8; IFSC];
+        match self.recv_block(&mut inf)? {
+            (PCB_S_RESYNCH_RESP, 0) => {
+                self.seq = false;
+                Ok(())
+            }
+            _ => Err(SeError::Protocol),
+        }
+    }
+
+    /// Send `apdu` and collect the response APDU (data and status word)
+    /// into `resp`.
+    fn transceive(&mut self, apdu: &[u8], resp: &mut [u8]) -> Result<usize, SeError> {
+        let mut inf = [0u8; IFSC];
+        let mut chunks = apdu.chunks(IFSC).peekable();
+        while let Some(chunk) = chunks.next() {
+            let more = chunks.peek().is_some();
+            self.send_block((u8::from(self.seq) << 6) | (u8::from(more) << 5), chunk)?;
+            self.seq = !self.seq;
+            if more {
+                // The chip acknowledges each chained block with R(N(R))
+                let (pcb, _) = self.recv_block(&mut inf)?;
+                if pcb & 0xC0 != 0x80 || pcb & 0x03 != 0 {
+                    return Err(SeError::Protocol);
+                }
+            }
+        }
+
+        let mut len = 0;
+        loop {
+            let (pcb, n) = self.recv_block(&mut inf)?;
+            if pcb & 0x80 != 0 {
+                return Err(SeError::Protocol);
+            }
+            let dst = resp.get_mut(len..len + n).ok_or(SeError::Protocol)?;
+            dst.copy_from_slice(&inf[..n]);
+            len += n;
+            if pcb & 0x20 == 0 {
+                break;
+            }
+            // More to come: acknowledge with N(R) = the next expected N(S)
+            let next = ((pcb >> 6) & 1) ^ 1;
+            self.send_block(0x80 | (next << 4), &[])?;
+        }
+        inf.zeroize();
+        Ok(len)
+    }
+
+    // ---------------------------------------------------------------
+    // APDUs
+    // ---------------------------------------------------------------
+
+    /// Run one case 4 APDU (`header | Lc | data | Le=00`); returns the
+    /// response data length in `resp` after checking the status word.
+    fn command(&mut self, header: [u8; 4], data: &[u8], resp: &mut [u8]) -> Result<usize, SeError> {
+        if data.len() > 255 {
+            return Err(SeError::Protocol);
+        }
+        let mut apdu = [0u8; 4 + 1 + 255 + 1];
+        apdu[..4].copy_from_slice(&header);
+        apdu[4] = data.len() as u8;
+        apdu[5..5 + data.len()].copy_from_slice(data);
+        let apdu_len = 6 + data.len();
+
+        let mut raw = [0u8; MAX_APDU];
+        let res = self.transceive(&apdu[..apdu_len], &mut raw).and_then(|n| {
+            if n < 2 {
+                return Err(SeError::Protocol);
+            }
+            let sw = u16::from_be_bytes([raw[n - 2], raw[n - 1]]);
+            if sw != SW_OK {
+                return Err(SeError::Device(sw));
+            }
+            let out = resp.get_mut(..n - 2).ok_or(SeError::Protocol)?;
+            out.copy_from_slice(&raw[..n - 2]);
+            Ok(n - 2)
+        });
+        apdu.zeroize();
+        raw.zeroize();
+        // After a broken exchange the sequence numbers are unknown
+        if matches!(res, Err(e) if !matches!(e, SeError::Device(_))) {
+            self.ready = false;
+        }
+        res
+    }
+
+    /// Resynchronise and select the applet, once per power-up.
+    fn open(&mut self) -> Result<(), SeError> {
+        if !self.ready {
+            self.resynch()?;
+            let mut version = [0u8; 32];
+            self.command(APDU_SELECT, &APPLET_AID, &mut version)?;
+            self.ready = true;
+        }
+        Ok(())
+    }
+
+    fn check_slot(slot: KeySlot) -> Result<[u8; 4], SeError> {
+        if slot.0 == 0 || slot.0 >= RESERVED_OBJECTS {
+            return Err(SeError::InvalidSlot);
+        }
+        Ok(slot.0.to_be_bytes())
+    }
+}
+
+impl<I2C: I2c, D: DelayNs> SecureElement for Se050<I2C, D> {
+    fn name(&self) -> &'static str {
+        "SE050"
+    }
+
+    fn random(&mut self, out: &mut [u8]) -> Result<(), SeError> {
+        self.open()?;
+        let mut resp = [0u8; RANDOM_CHUNK + 4];
+        for chunk in out.chunks_mut(RANDOM_CHUNK) {
+            let mut req = [0u8; 4];
+            let n = tlv(&mut req, 0, TAG_1, &(chunk.len() as u16).to_be_bytes());
+            let len = self.command(APDU_GET_RANDOM, &req[..n], &mut resp)?;
+            let random = find_tlv(&resp[..len], TAG_1)?;
+            if random.len() != chunk.len() {
+                return Err(SeError::Protocol);
+            }
+            chunk.copy_from_slice(random);
+        }
+        resp.zeroize();
+        Ok(())
+    }
+
+    fn generate_key(&mut self, slot: KeySlot) -> Result<VerifyingKey, SeError> {
+        let id = Self::check_slot(slot)?;
+        self.open()?;
+        let mut req = [0u8; 9];
+        let n = tlv(&mut req, 0, TAG_1, &id);
+        let n = tlv(&mut req, n, TAG_2, &[CURVE_NIST_P256]);
+        self.command(APDU_WRITE_EC_KEY_PAIR, &req[..n], &mut [])?;
+        self.public_key(slot)
+    }
+
+    fn public_key(&mut self, slot: KeySlot) -> Result<VerifyingKey, SeError> {
+        let id = Self::check_slot(slot)?;
+        self.open()?;
+        let mut req = [0u8; 6];
+        let n = tlv(&mut req, 0, TAG_1, &id);
+        let mut resp = [0u8; 128];
+        let len = self.command(APDU_READ_OBJECT, &req[..n], &mut resp)?;
+        // Raw SEC1 point or SubjectPublicKeyInfo, depending on the applet
+        crate::ecc::import_public_key(find_tlv(&resp[..len], TAG_1)?).map_err(|_| SeError::BadKey)
+    }
+
+    fn sign_digest(&mut self, slot: KeySlot, digest: &[u8; 32]) -> Result<Signature, SeError> {
+        let id = Self::check_slot(slot)?;
+        self.open()?;
+        let mut req = [0u8; 6 + 3 + 34];
+        let n = tlv(&mut req, 0, TAG_1, &id);
+        let n = tlv(&mut req, n, TAG_2, &[SIG_ECDSA_SHA_256]);
+        let n = tlv(&mut req, n, TAG_3, digest);
+        let mut resp = [0u8; 80];
+        let res = self.command(APDU_ECDSA_SIGN, &req[..n], &mut resp);
+        req.zeroize();
+        let len = res?;
+        Signature::from_der(find_tlv(&resp[..len], TAG_1)?).map_err(|_| SeError::BadKey)
+    }
+}
+
+/// Write `tag | len | value` at `at` (values shorter than 128 bytes);
+/// returns the end offset.
+fn tlv(buf: &mut [u8], at: usize, tag: u8, value: &[u8]) -> usize {
+    buf[at] = tag;
+    buf[at + 1] = value.len() as u8;
+    buf[at + 2..at + 2 + value.len()].copy_from_slice(value);
+    at + 2 + value.len()
+}
+
+/// Value of the first `tag` in `data` (BER lengths: short, 0x81, 0x82).
+fn find_tlv(mut data: &[u8], tag: u8) -> Result<&[u8], SeError> {
+    while let [t, first, rest @ ..] = data {
+        let (len, rest) = match *first {
+            n @ 0..=0x7F => (usize::from(n), rest),
+            0x81 => (usize::from(*rest.first().ok_or(SeError::Protocol)?), &rest[1..]),
+            0x82 if rest.len() >= 2 => (usize::from(u16::from_be_bytes([rest[0], rest[1]])), &rest[2..]),
+            _ => return Err(SeError::Protocol),
+        };
+        let value = rest.get(..len).ok_or(SeError::Protocol)?;
+        if *t == tag {
+            return Ok(value);
+        }
+        data = &rest[len..];
+    }
+    Err(SeError::Protocol)
+}
+
+/// CRC-16/X-25 (reflected 0x1021, initial and final XOR 0xFFFF).
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for &byte in data {
+        crc ^= u16::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0x8408 } else { crc >> 1 };
+        }
+    }
+    crc ^ 0xFFFF
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secure_element::test_support::{public_xy, sign_prehash, Nack, NoDelay};
+    use embedded_hal::i2c::{ErrorType, Operation};
+    use std::collections::{HashMap, VecDeque};
+
+    /// Block-level model of the chip and its applet.
+    #[derive(Default)]
+    struct MockSe {
+        /// Blocks queued for the host, each read as header then rest
+        out: VecDeque<Vec<u8>>,
+        partial: Vec<u8>,
+        /// Command APDU being received (chained)
+        apdu: Vec<u8>,
+        /// Response APDU still to send, in chained blocks
+        pending: Vec<u8>,
+        seq: u8,
+        selected: bool,
+        keys: HashMap<u32, [u8; 32]>,
+        rng: u8,
+        /// Largest INF the model sends (forces response chaining)
+        ifsd: usize,
+        wtx_before_sign: bool,
+        busy: u32,
+        corrupt_next: bool,
+    }
+
+    impl MockSe {
+        fn queue(&mut self, pcb: u8, inf: &[u8]) {
+            let mut block = vec![NAD_FROM_SE, pcb, inf.len() as u8];
+            block.extend_from_slice(inf);
+            let crc = crc16(&block);
+            block.extend_from_slice(&crc.to_be_bytes());
+            if std::mem::take(&mut self.corrupt_next) {
+                block[3] ^= 1;
+            }
+            self.out.push_back(block);
+            self.busy = 2;
+        }
+
+        fn send_pending(&mut self) {
+            let n = self.pending.len().min(self.ifsd);
+            let chunk: Vec<u8> = self.pending.drain(..n).collect();
+            let more = !self.pending.is_empty();
+            let pcb = (self.seq << 6) | (u8::from(more) << 5);
+            self.seq ^= 1;
+            self.queue(pcb, &chunk);
+        }
+
+        fn block(&mut self, frame: &[u8]) {
+            assert_eq!(frame[0], NAD_TO_SE);
+            let len = usize::from(frame[2]);
+            assert_eq!(frame.len(), len + 5);
+            assert_eq!(crc16(&frame[..3 + len]).to_be_bytes(), frame[3 + len..], "block CRC");
+            let (pcb, inf) = (frame[1], &frame[3..3 + len]);
+            match pcb {
+                PCB_S_RESYNCH_REQ => {
+                    self.seq = 0;
+                    self.queue(PCB_S_RESYNCH_RESP, &[]);
+                }
+                PCB_S_WTX_RESP => self.send_pending(),
+                // R-block acknowledging a chained response block
+                p if p & 0xC0 == 0x80 => self.send_pending(),
+                p if p & 0x80 == 0 => {
+                    self.apdu.extend_from_slice(inf);
+                    if p & 0x20 != 0 {
+                        self.queue(0x80 | (((p >> 6) ^ 1) << 4), &[]);
+                        return;
+                    }
+                    let apdu = std::mem::take(&mut self.apdu);
+                    self.pending = self.process(&apdu);
+                    if self.wtx_before_sign && apdu[1] == 0x03 {
+                        self.queue(PCB_S_WTX_REQ, &[0x02]);
+                    } else {
+                        self.send_pending();
+                    }
+                }
+                _ => panic!("unexpected PCB {:#04x}", pcb),
+            }
+        }
+
+        fn process(&mut self, apdu: &[u8]) -> Vec<u8> {
+            let (header, lc) = (&apdu[..4], usize::from(apdu[4]));
+            let data = &apdu[5..5 + lc];
+            assert_eq!(apdu.len(), 6 + lc, "case 4 APDU");
+            let mut resp = Vec::new();
+            let sw: u16 = match header {
+                h if h == APDU_SELECT && data == APPLET_AID => {
+                    self.selected = true;
+                    resp.extend_from_slice(&[0x07, 0x02, 0x00]);
+                    0x9000
+                }
+                _ if !self.selected => 0x6985,
+                h if h == APDU_GET_RANDOM => {
+                    let n = usize::from(u16::from_be_bytes(find_tlv(data, TAG_1).unwrap().try_into().unwrap()));
+                    resp.extend_from_slice(&[TAG_1, 0x81, n as u8]);
+                    for _ in 0..n {
+                        self.rng = self.rng.wrapping_mul(29).wrapping_add(11);
+                        resp.push(self.rng);
+                    }
+                    0x9000
+                }
+                h if h == APDU_WRITE_EC_KEY_PAIR => {
+                    assert_eq!(find_tlv(data, TAG_2).unwrap(), [CURVE_NIST_P256]);
+                    let id = u32::from_be_bytes(find_tlv(data, TAG_1).unwrap().try_into().unwrap());
+                    self.keys.insert(id, [id as u8; 32]);
+                    0x9000
+                }
+                h if h == APDU_READ_OBJECT => {
+                    let id = u32::from_be_bytes(find_tlv(data, TAG_1).unwrap().try_into().unwrap());
+                    match self.keys.get(&id) {
+                        Some(key) => {
+                            resp.extend_from_slice(&[TAG_1, 65, 0x04]);
+                            resp.extend_from_slice(&public_xy(key));
+                            0x9000
+                        }
+                        None => 0x6A82,
+                    }
+                }
+                h if h == APDU_ECDSA_SIGN => {
+                    assert_eq!(find_tlv(data, TAG_2).unwrap(), [SIG_ECDSA_SHA_256]);
+                    let id = u32::from_be_bytes(find_tlv(data, TAG_1).unwrap().try_into().unwrap());
+                    let digest: [u8; 32] = find_tlv(data, TAG_3).unwrap().try_into().unwrap();
+                    match self.keys.get(&id) {
+                        Some(key) => {
+                            let der = sign_prehash(key, &digest).to_der();
+                            resp.extend_from_slice(&[TAG_1, der.as_bytes().len() as u8]);
+                            resp.extend_from_slice(der.as_bytes());
+                            0x9000
+                        }
+                        None => 0x6A82,
+                    }
+                }
+                _ => 0x6D00,
+            };
+            resp.extend_from_slice(&sw.to_be_bytes());
+            resp
+        }
+    }
+
+    impl ErrorType for MockSe {
+        type Error = Nack;
+    }
+
+    impl I2c for MockSe {
+        fn transaction(&mut self, address: u8, operations: &mut [Operation<'_>]) -> Result<(), Nack> {
+            if address != DEFAULT_ADDRESS {
+                return Err(Nack);
+            }
+            for op in operations {
+                match op {
+                    Operation::Write(frame) => self.block(frame),
+                    Operation::Read(buf) => {
+                        if self.partial.is_empty() {
+                            if self.busy > 0 || self.out.is_empty() {
+                                self.busy = self.busy.saturating_sub(1);
+                                return Err(Nack);
+                            }
+                            self.partial = self.out.pop_front().unwrap();
+                        }
+                        let n = buf.len();
+                        buf.copy_from_slice(&self.partial[..n]);
+                        self.partial.drain(..n);
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn chip(ifsd: usize) -> Se050<MockSe, NoDelay> {
+        Se050::new(MockSe { ifsd, ..MockSe::default() }, NoDelay)
+    }
+
+    #[test]
+    fn crc_and_tlv_encoding() {
+        // CRC-16/X-25 check value
+        assert_eq!(crc16(b"123456789"), 0x906E);
+
+        let mut buf = [0u8; 16];
+        let n = tlv(&mut buf, 0, TAG_1, &[1, 2, 3]);
+        let n = tlv(&mut buf, n, TAG_2, &[]);
+        assert_eq!(buf[..n], [TAG_1, 3, 1, 2, 3, TAG_2, 0]);
+        assert_eq!(find_tlv(&buf[..n], TAG_1), Ok(&[1, 2, 3][..]));
+        assert_eq!(find_tlv(&buf[..n], TAG_2), Ok(&[][..]));
+        assert_eq!(find_tlv(&buf[..n], TAG_3), Err(SeError::Protocol));
+        assert_eq!(find_tlv(&[TAG_1, 0x81, 2, 9, 9], TAG_1), Ok(&[9, 9][..]));
+        assert_eq!(find_tlv(&[TAG_1, 5, 1], TAG_1), Err(SeError::Protocol));
+    }
+
+    #[test]
+    fn keys_are_generated_and_used_inside_the_chip() {
+        let mut se = chip(IFSC);
+        let slot = KeySlot(0x2000_0001);
+        let public = se.generate_key(slot).unwrap();
+        assert_eq!(se.public_key(slot), Ok(public));
+
+        let sig = se.sign(slot, b"attestation report").unwrap();
+        assert_eq!(crate::ecc::verify(&public, b"attestation report", &sig), Ok(()));
+        assert!(crate::ecc::verify(&public, b"attestation rePort", &sig).is_err());
+    }
+
+    #[test]
+    fn chained_responses_and_wait_extensions() {
+        let mut se = chip(16);
+        se.i2c.wtx_before_sign = true;
+        let mut out = [0u8; RANDOM_CHUNK + 40];
+        se.random(&mut out).unwrap();
+        assert_ne!(out[..16], out[16..32]);
+        assert_ne!(out[RANDOM_CHUNK..], [0; 40]);
+
+        let slot = KeySlot(0x10);
+        let public = se.generate_key(slot).unwrap();
+        let sig = se.sign(slot, b"m").unwrap();
+        assert_eq!(crate::ecc::verify(&public, b"m", &sig), Ok(()));
+    }
+
+    #[test]
+    fn errors_are_reported_and_the_link_recovers() {
+        let mut se = chip(IFSC);
+        assert_eq!(se.public_key(KeySlot(0)), Err(SeError::InvalidSlot));
+        assert_eq!(se.public_key(KeySlot(0x7FFF_0201)), Err(SeError::InvalidSlot));
+        assert_eq!(se.public_key(KeySlot(5)), Err(SeError::Device(0x6A82)));
+
+        se.i2c.corrupt_next = true;
+        assert_eq!(se.random(&mut [0u8; 8]), Err(SeError::Checksum));
+        // The next operation resynchronises and selects again
+        se.i2c.out.clear();
+        se.i2c.selected = false;
+        se.random(&mut [0u8; 8]).unwrap();
+    }
+}
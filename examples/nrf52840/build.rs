@@ -0,0 +1,49 @@
+//! SecureIoTOS nRF52840 Example Build Script
+//! -----------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author : Md Mahbubur Rahman
+//! URL    : https://m-a-h-b-u-b.github.io
+//! GitHub : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Links each stage against its own memory map: `memory-boot.x` and
+//! `memory-app.x` are copied as `memory.x` into a directory per binary,
+//! which is put on that binary's linker search path for `link.x`.
+//! Application images run in place, so `SIOS_APP_SLOT=b` links the
+//! application for slot B (`memory-app-b.x`) instead; the default is
+//! slot A.
+//!
+//! cortex-m-rt is built for a device (`__INTERRUPTS` in src/lib.rs); the
+//! `device.x` its linker script includes is empty, as no interrupt
+//! handler is bound by name.
+//!
+//! The vendor keys come with the `bootloader` crate, whose build script
+//! requires `SIOS_VENDOR_PUBKEYS` (see bootloader/build.rs).
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+fn main() {
+    let out = PathBuf::from(env::var_os("OUT_DIR").expect("cargo sets OUT_DIR"));
+    let app_script = match env::var("SIOS_APP_SLOT").as_deref() {
+        Ok("a") | Err(env::VarError::NotPresent) => "memory-app.x",
+        Ok("b") => "memory-app-b.x",
+        _ => panic!("SIOS_APP_SLOT: expected a or b"),
+    };
+
+    for (bin, script) in [("boot", "memory-boot.x"), ("app", app_script)] {
+        let dir = out.join(bin);
+        fs::create_dir_all(&dir).expect("create linker script directory");
+        fs::copy(script, dir.join("memory.x")).expect("copy memory map");
+        println!("cargo:rustc-link-arg-bin={bin}=-L{}", dir.display());
+        println!("cargo:rerun-if-changed={script}");
+    }
+    fs::write(out.join("device.x"), "/* no named interrupt handlers */\n").expect("write device.x");
+    println!("cargo:rustc-link-arg-bins=-L{}", out.display());
+    println!("cargo:rustc-link-arg-bins=-Tlink.x");
+    println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
+    println!("cargo:rerun-if-env-changed=SIOS_APP_SLOT");
+    println!("cargo:rerun-if-changed=build.rs");
+}
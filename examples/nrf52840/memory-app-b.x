@@ -0,0 +1,19 @@
+/* Application in slot B; the layout is described in memory-boot.x.
+ * Images run in place: this is the build for slot B (SIOS_APP_SLOT=b,
+ * see build.rs); slot A images are linked with memory-app.x.
+ */
+MEMORY
+{
+  FLASH : ORIGIN = 0x00082100, LENGTH = 0x0006DF00
+  RAM   : ORIGIN = 0x20000000, LENGTH = 256K - 512
+}
+
+/* Image header (kernel::image) right after the vector table, where the
+ * bootloader and host tools look for it; code starts after it.
+ */
+SECTIONS
+{
+  .image_header : { KEEP(*(.image_header)) } > FLASH
+}
+INSERT AFTER .vector_table;
+_stext = ALIGN(ADDR(.image_header) + SIZEOF(.image_header), 4);
@@ -0,0 +1,19 @@
+/* Application in slot A; the layout is described in memory-boot.x.
+ * Images run in place, so a build for slot B is linked with
+ * memory-app-b.x instead (SIOS_APP_SLOT=b, see build.rs).
+ */
+MEMORY
+{
+  FLASH : ORIGIN = 0x00014100, LENGTH = 0x0006DF00
+  RAM   : ORIGIN = 0x20000000, LENGTH = 256K - 512
+}
+
+/* Image header (kernel::image) right after the vector table, where the
+ * bootloader and host tools look for it; code starts after it.
+ */
+SECTIONS
+{
+  .image_header : { KEEP(*(.image_header)) } > FLASH
+}
+INSERT AFTER .vector_table;
+_stext = ALIGN(ADDR(.image_header) + SIZEOF(.image_header), 4);
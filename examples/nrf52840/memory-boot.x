@@ -0,0 +1,22 @@
+/* nRF52840 (1 MB flash, 256 KB RAM), no SoftDevice.
+ *
+ * 0x00000000  boot         64 KB   this image
+ * 0x00010000  boot control 2 x 4 KB (A/B copies of the record)
+ * 0x00012000  revocations  4 KB    vendor key revocation bits, never erased
+ * 0x00013000  reserved     4 KB
+ * 0x00014000  slot A       440 KB  manifest page (256 B), then the image
+ * 0x00082000  slot B       440 KB  see memory-app.x / memory-app-b.x
+ * 0x000F0000  ledger       2 x 4 KB boot ledger (A/B pages)
+ * 0x000F2000  reserved
+ *
+ * The slots, control pages and revocation area are `LAYOUT` in src/lib.rs.
+ *
+ * RAM: the top 512 B are left out of both stages' RAM so neither runtime
+ * initializes them: the measured-boot event log at 0x2003FE00 and the
+ * boot measurement slot at 0x2003FF00.
+ */
+MEMORY
+{
+  FLASH : ORIGIN = 0x00000000, LENGTH = 64K
+  RAM   : ORIGIN = 0x20000000, LENGTH = 256K - 512
+}
@@ -0,0 +1,46 @@
+#!/usr/bin/env bash
+#
+# SecureIoTOS – nRF52840 image signing
+#
+# Builds the A/B slot manifest in front of the application (see
+# secure_storage::update and tools/sign-manifest) and writes app.signed.bin:
+# the slot as it sits in flash, to be flashed at the slot base (slot A:
+# 0x00014000, for a build linked for it) or sent to recovery mode. The
+# image version (anti-rollback) comes from SIOS_IMAGE_VERSION, default 1;
+# the index of the vendor key in the bootloader's key ring that signs from
+# SIOS_KEY_ID, default 0; keys to revoke once the image is confirmed from
+# SIOS_REVOKE (bit mask), default none.
+#
+#   SIOS_IMAGE_VERSION=3 ./sign.sh vendor.pem target/thumbv7em-none-eabihf/release/app
+#
+# License : Dual License
+#           - Apache 2.0 for open-source / personal use
+#           - Commercial license required for closed-source use
+# Author  : Md Mahbubur Rahman
+# Project : https://m-a-h-b-u-b.github.io
+# GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+#
+
+set -euo pipefail
+
+KEY="${1:?usage: sign.sh <vendor.pem> <app ELF>}"
+ELF="${2:?usage: sign.sh <vendor.pem> <app ELF>}"
+OUT="${3:-app.signed.bin}"
+ROOT="$(cd "$(dirname "$0")/../.." && pwd)"
+WORK="$(mktemp -d)"
+trap 'rm -rf "$WORK"' EXIT
+
+rust-objcopy -O binary "$ELF" "$WORK/app.bin"
+
+# Manifest layout comes from secure_storage::update, which the bootloader
+# parses it with (tools/sign-manifest); only the signature is made here.
+# Built from its own directory: this one's .cargo/config.toml targets the MCU.
+(cd "$ROOT/tools/sign-manifest" && cargo build --quiet --release)
+TOOL="$ROOT/tools/sign-manifest/target/release/sign-manifest"
+"$TOOL" slot-tbs "${SIOS_IMAGE_VERSION:-1}" "${SIOS_KEY_ID:-0}" "${SIOS_REVOKE:-0}" "$WORK/app.bin" "$WORK/manifest.tbs"
+
+# ECDSA with OpenSSL >= 3.2 can be made deterministic (RFC 6979) with
+# -sigopt nonce-type:1; then the same image always signs the same.
+openssl dgst -sha256 -sign "$KEY" -out "$WORK/manifest.sig" "$WORK/manifest.tbs"
+
+"$TOOL" slot-assemble "$WORK/manifest.tbs" "$WORK/manifest.sig" "$WORK/app.bin" "$OUT"
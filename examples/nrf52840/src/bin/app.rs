@@ -0,0 +1,423 @@
+//! SecureIoTOS nRF52840 Example App Module
+//! ---------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Second stage, started by `boot` once the signature checked out:
+//!
+//! 1. Clocks, heap, reset cause (`Bsp`); the kernel takes over the
+//!    bootloader's measured-boot log (`kernel::measured_boot`).
+//! 2. Device root key from UICR into `crypto::kdf`; the storage key is
+//!    derived from it (`key_mgmt::init_keys_from_root`).
+//! 3. The boot is recorded in the hash-chained boot ledger in flash; its
+//!    boot count makes the telemetry nonces unique across reboots.
+//! 4. MPU: flash read-only/executable, RAM privileged only, plus the
+//!    windows the unprivileged sensor task needs; W^X is enforced.
+//! 5. Kernel: SysTick, fault handlers, tasks, syscall table sealed.
+//!    With all of that up, an image on trial after an update is confirmed
+//!    (`secure_storage::update::confirm_boot`); until then the bootloader
+//!    rolls back to the previous slot after `BOOT_ATTEMPTS` resets.
+//! 6. Tasks:
+//!    - `sensor` (unprivileged): samples the die temperature once a
+//!      second and sends it to `telemetry` through a kernel queue;
+//!    - `telemetry` (privileged, owns the board): every `PUBLISH_EVERY`
+//!      samples encodes a CBOR report, compresses it (`codec::compress`),
+//!      seals it with AES-256-GCM under the telemetry key and publishes it
+//!      over MQTT-over-TLS.
+//!
+//!    - `idle` (unprivileged): `wfi` while nothing else is ready.
+//!
+//! The reset flow then hands over to the first task through `PendSV`
+//! (`kernel::context::start_first_task`) and does not resume.
+//!
+//! Published frame: `nonce (12) || ciphertext || tag (16)`, the plaintext
+//! being the LZSS-compressed CBOR report, with the nonce
+//! `boot count (4, BE) || report sequence (8, BE)` and the board name as
+//! associated data.
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use core::cell::RefCell;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use cortex_m::interrupt::{self, Mutex};
+use cortex_m_rt::entry;
+use defmt_rtt as _;
+use panic_halt as _;
+
+use codec::cbor::Encoder;
+use codec::compress::compress_into;
+use crypto::aes::{gcm_seal, SoftwareAes, NONCE_LEN};
+use crypto::kdf::{self, Purpose};
+use hal::bsp::{Bsp, BspFlash, ResetCause, Uplink};
+use kernel::context::{init_checked_stack, Task};
+use kernel::syscall::caps;
+use memory::mpu::{self, Backend, MpuAccess, MpuBackend, RegionConfig, WxPolicy};
+use secure_storage::boot_ledger::{BootEvent, BootLedger, LedgerStore};
+use secure_storage::key_mgmt;
+use secure_storage::update;
+use secureiotos::syscall::{sios_ipc_recv, sios_ipc_send, SIOS_IPC_MSG_SIZE, SIOS_WAIT_FOREVER};
+
+use sios_nrf52840::bsp::{read_die_temperature, BrokerConfig, Nrf52840, TEMP_BASE};
+use sios_nrf52840::{BOOT_LOG_ADDR, LAYOUT, LEDGER_PAGES};
+
+/// Telemetry broker (TLS on 8883).
+const BROKER: BrokerConfig = BrokerConfig { host: "mqtt.example.com", port: 8883 };
+const TELEMETRY_TOPIC: &str = "sios/nrf52840/telemetry";
+/// Storage key epoch; bump to rotate the storage key.
+const KEY_EPOCH: u32 = 0;
+/// Boot ledger entries kept (fits one flash page).
+const LEDGER_CAPACITY: usize = 32;
+const SAMPLE_PERIOD_MS: u32 = 1000;
+/// Samples per published report.
+const PUBLISH_EVERY: u32 = 10;
+const HEAP_SIZE: usize = 32 * 1024;
+
+/// The board, until the telemetry task takes it over.
+static BOARD: Mutex<RefCell<Option<Nrf52840>>> = Mutex::new(RefCell::new(None));
+/// Boot count from the ledger, 0 if the ledger is unusable.
+static BOOT_COUNT: AtomicU32 = AtomicU32::new(0);
+
+static mut HEAP: [u8; HEAP_SIZE] = [0; HEAP_SIZE];
+static mut TELEMETRY_STACK: [u32; 2048] = [0; 2048];
+
+/// Queues the sensor task uses, set before the scheduler starts.
+struct SensorQueues {
+    /// Owned by `sensor`; never sent to, received on to sleep
+    own: AtomicU32,
+    /// Owned by `telemetry`
+    telemetry: AtomicU32,
+}
+
+/// RAM that unprivileged code may touch: one 8 KB MPU region.
+#[repr(C, align(8192))]
+struct UserRam {
+    sensor_stack: [u32; 1536],
+    idle_stack: [u32; 256],
+    queues: SensorQueues,
+}
+
+const USER_RAM_SIZE_FIELD: u32 = 12; // 2^13 = 8 KB
+const _: () = assert!(core::mem::size_of::<UserRam>() == 8192);
+
+static mut USER_RAM: UserRam = UserRam {
+    sensor_stack: [0; 1536],
+    idle_stack: [0; 256],
+    queues: SensorQueues { own: AtomicU32::new(0), telemetry: AtomicU32::new(0) },
+};
+
+fn queues() -> &'static SensorQueues {
+    // SAFETY: only the atomics are shared; the stacks are handed out once
+    // in `main` before any task runs.
+    unsafe { &(*addr_of_mut!(USER_RAM)).queues }
+}
+
+/// Boot ledger on two alternating flash pages:
+///
+/// ```text
+/// seq u32 | len u32 | ledger bytes
+/// ```
+///
+/// A save goes to the page not holding the current copy and writes `seq`
+/// last, so a save cut short by a reset leaves the old copy in charge.
+struct FlashLedger<'a, F: BspFlash> {
+    flash: &'a mut F,
+    /// Page holding the current copy and its sequence number
+    current: Option<(usize, u32)>,
+}
+
+impl<'a, F: BspFlash> FlashLedger<'a, F> {
+    const HEADER: usize = 8;
+
+    fn new(flash: &'a mut F) -> Self {
+        let mut current = None;
+        for (i, &page) in LEDGER_PAGES.iter().enumerate() {
+            let mut hdr = [0u8; 8];
+            if flash.read(page, &mut hdr).is_err() {
+                continue;
+            }
+            let seq = u32::from_le_bytes([hdr[0], hdr[1], hdr[2], hdr[3]]);
+            let len = u32::from_le_bytes([hdr[4], hdr[5], hdr[6], hdr[7]]) as usize;
+            if seq == u32::MAX || len > F::PAGE_SIZE - Self::HEADER {
+                continue;
+            }
+            if current.is_none_or(|(_, s)| seq > s) {
+                current = Some((i, seq));
+            }
+        }
+        Self { flash, current }
+    }
+}
+
+impl<F: BspFlash> LedgerStore for FlashLedger<'_, F> {
+    fn load(&mut self) -> Result<Option<alloc::vec::Vec<u8>>, &'static str> {
+        let Some((i, _)) = self.current else {
+            return Ok(None);
+        };
+        let page = LEDGER_PAGES[i];
+        let mut len = [0u8; 4];
+        self.flash.read(page + 4, &mut len).map_err(|_| "ledger read failed")?;
+        let mut data = alloc::vec![0u8; u32::from_le_bytes(len) as usize];
+        self.flash.read(page + Self::HEADER as u32, &mut data).map_err(|_| "ledger read failed")?;
+        Ok(Some(data))
+    }
+
+    fn save(&mut self, data: &[u8]) -> Result<(), &'static str> {
+        if data.len() > F::PAGE_SIZE - Self::HEADER {
+            return Err("ledger larger than a page");
+        }
+        let (target, seq) = match self.current {
+            Some((i, seq)) => (1 - i, seq.wrapping_add(1) % u32::MAX),
+            None => (0, 0),
+        };
+        let page = LEDGER_PAGES[target];
+        let mut body = alloc::vec::Vec::with_capacity(4 + data.len() + 3);
+        body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        body.extend_from_slice(data);
+        body.resize(body.len().next_multiple_of(F::WRITE_SIZE), 0xFF);
+
+        self.flash.erase_page(page).map_err(|_| "ledger erase failed")?;
+        self.flash.write(page + 4, &body).map_err(|_| "ledger write failed")?;
+        self.flash.write(page, &seq.to_le_bytes()).map_err(|_| "ledger commit failed")?;
+        self.current = Some((target, seq));
+        Ok(())
+    }
+}
+
+/// Record this boot; returns the boot count.
+fn record_boot(board: &mut Nrf52840, cause: ResetCause) -> Option<u32> {
+    let store = FlashLedger::new(board.flash());
+    let mut ledger = match BootLedger::open(store, LEDGER_CAPACITY) {
+        Ok(ledger) => ledger,
+        Err(e) => {
+            // Reported, never reset: a broken or rolled-back ledger is
+            // evidence for the backend.
+            sios_log::error!("boot ledger unusable: {:?}", e);
+            return None;
+        }
+    };
+    // No RTC is set this early; entries are ordered by their sequence.
+    if let Err(e) = ledger.record(BootEvent::Boot { reset_cause: cause as u8 }, 0) {
+        sios_log::error!("boot not recorded: {:?}", e);
+        return None;
+    }
+    Some(ledger.boot_count())
+}
+
+/// nRF52840 memory map on top of the kernel's fault handling.
+fn setup_mpu() -> Result<(), mpu::WxViolation> {
+    // Taking the address only; the region is programmed below.
+    let user_ram = addr_of_mut!(USER_RAM) as u32;
+    let regions = [
+        // Flash: read-only and executable for everyone
+        RegionConfig { number: 0, base: 0x0000_0000, size_field: 19, access: MpuAccess::ReadOnly, executable: true },
+        // SRAM: kernel and privileged tasks only
+        RegionConfig { number: 1, base: 0x2000_0000, size_field: 17, access: MpuAccess::PrivRW, executable: false },
+        // Stacks and queue handles of the unprivileged tasks
+        RegionConfig { number: 2, base: user_ram, size_field: USER_RAM_SIZE_FIELD, access: MpuAccess::UnprivRW, executable: false },
+        // TEMP peripheral, for the sensor task
+        RegionConfig { number: 3, base: TEMP_BASE, size_field: 11, access: MpuAccess::UnprivRW, executable: false },
+    ];
+    Backend::disable();
+    for r in &regions {
+        // SAFETY: the MPU is off; every base is aligned to its size (the
+        // user RAM by its type's alignment).
+        unsafe { Backend::set_region(r.number, r.base, r.size_field, r.access, mpu::region_xn(r)) };
+    }
+    Backend::enable();
+    mpu::enforce_wx(WxPolicy::FailBoot).map(|_| ())
+}
+
+/// Where a task's entry function would return to; tasks never return.
+extern "C" fn task_returned() -> ! {
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
+
+/// Register a task running `entry` on `stack`; returns its id.
+fn spawn(entry: extern "C" fn(u32) -> !, stack: &'static mut [u32], privilege: u8, capabilities: u32) -> Option<u32> {
+    let (sp, bounds) = init_checked_stack(stack, entry as usize, task_returned as extern "C" fn() -> ! as usize, 0)?;
+    kernel::scheduler::add_task(Task { id: 0, privilege, stack_pointer: sp, capabilities, stack: Some(bounds) }).ok()
+}
+
+#[entry]
+fn main() -> ! {
+    let Some(mut board) = Nrf52840::take(BROKER) else {
+        fail_safe();
+    };
+    let core_hz = board.init_clocks();
+    // The heap array is handed to the allocator once, here.
+    memory::heap::init_heap(addr_of_mut!(HEAP) as usize, HEAP_SIZE);
+    let cause = board.reset_cause();
+    sios_log::info!("{} up, reset cause {}", Nrf52840::NAME, cause as u8);
+    // SAFETY: the log slot is reserved RAM outside this stage's memory map,
+    // written by the bootloader before the jump.
+    let raw = unsafe { core::ptr::read_volatile(BOOT_LOG_ADDR as *const [u8; kernel::measured_boot::MAX_LOG_LEN]) };
+    if let Err(e) = kernel::measured_boot::capture(&raw) {
+        sios_log::warn!("no measured-boot log: {}", e as u8);
+    }
+
+    // Keys: everything below derives from the provisioned root key
+    let mut root = [0u8; 32];
+    if board.read_root_key(&mut root).is_err() {
+        sios_log::error!("device not provisioned");
+        fail_safe();
+    }
+    kdf::install_root_key(&mut root);
+    if key_mgmt::init_keys_from_root(KEY_EPOCH).is_err() {
+        fail_safe();
+    }
+
+    match record_boot(&mut board, cause) {
+        Some(count) => BOOT_COUNT.store(count, Ordering::Relaxed),
+        None => sios_log::warn!("telemetry disabled: no boot count"),
+    }
+
+    if let Err(e) = setup_mpu() {
+        sios_log::error!("MPU region {} is writable and executable", e.region);
+        fail_safe();
+    }
+    kernel::fault::init();
+    if kernel::init::init_systick(core_hz / kernel::time::TICK_HZ).is_err() {
+        fail_safe();
+    }
+
+    // SAFETY: each static buffer is borrowed exactly once, here, before
+    // the scheduler runs.
+    let (user, telemetry_stack) = unsafe { (&mut *addr_of_mut!(USER_RAM), &mut *addr_of_mut!(TELEMETRY_STACK)) };
+    let spawned = spawn(idle_task, &mut user.idle_stack, 1, 0)
+        .and_then(|_| spawn(telemetry_task, telemetry_stack, 0, caps::RECV_MESSAGE | caps::FIRMWARE_INFO))
+        .and_then(|telemetry| {
+            let sensor = spawn(sensor_task, &mut user.sensor_stack, 1, caps::SEND_MESSAGE | caps::RECV_MESSAGE)?;
+            let q = &user.queues;
+            q.telemetry.store(kernel::ipc_queue::create(telemetry).ok()?, Ordering::Release);
+            q.own.store(kernel::ipc_queue::create(sensor).ok()?, Ordering::Release);
+            Some(())
+        });
+    if spawned.is_none() {
+        sios_log::error!("task setup failed");
+        fail_safe();
+    }
+    if let Err(e) = update::confirm_boot(board.flash(), &LAYOUT) {
+        sios_log::warn!("boot not confirmed: {:?}", e);
+    }
+    kernel::syscall::seal_syscalls();
+    interrupt::free(|cs| *BOARD.borrow(cs).borrow_mut() = Some(board));
+
+    // PendSV enters the first task; the reset flow does not resume.
+    kernel::context::start_first_task();
+    fail_safe();
+}
+
+/// Unprivileged: runs when no other task is ready.
+extern "C" fn idle_task(_: u32) -> ! {
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
+
+/// Unprivileged: sample the die temperature, hand it to `telemetry`.
+extern "C" fn sensor_task(_: u32) -> ! {
+    let own = queues().own.load(Ordering::Acquire);
+    let telemetry = queues().telemetry.load(Ordering::Acquire);
+    let mut seq: u32 = 0;
+    let mut buf = [0u8; SIOS_IPC_MSG_SIZE];
+    loop {
+        if let Some(t) = read_die_temperature() {
+            let mut msg = [0u8; 8];
+            msg[..4].copy_from_slice(&seq.to_le_bytes());
+            msg[4..].copy_from_slice(&t.to_le_bytes());
+            // SAFETY: `msg` is valid for 8 bytes.
+            let _ = unsafe { sios_ipc_send(telemetry, msg.as_ptr(), msg.len()) };
+            seq = seq.wrapping_add(1);
+        }
+        // Nothing is ever sent to `own`: this is a sleep
+        let mut len = 0;
+        // SAFETY: `buf` and `len` are valid for writes.
+        let _ = unsafe { sios_ipc_recv(own, buf.as_mut_ptr(), buf.len(), &mut len, SAMPLE_PERIOD_MS) };
+    }
+}
+
+/// Privileged: collect samples, publish sealed reports.
+extern "C" fn telemetry_task(_: u32) -> ! {
+    let queue = queues().telemetry.load(Ordering::Acquire);
+    let boots = BOOT_COUNT.load(Ordering::Relaxed);
+    let board = interrupt::free(|cs| BOARD.borrow(cs).borrow_mut().take());
+    let (Some(mut board), Ok(key), true) = (board, kdf::derive_key(Purpose::TelemetryEncryption, Nrf52840::NAME.as_bytes()), boots != 0)
+    else {
+        task_returned();
+    };
+    let fw = kernel::image::firmware_info();
+    let mut aes = SoftwareAes;
+    let mut report_seq: u64 = 0;
+    let (mut samples, mut min, mut max, mut sum) = (0u32, i32::MAX, i32::MIN, 0i64);
+    let mut buf = [0u8; SIOS_IPC_MSG_SIZE];
+
+    loop {
+        let mut len = 0;
+        // SAFETY: `buf` and `len` are valid for writes.
+        let status = unsafe { sios_ipc_recv(queue, buf.as_mut_ptr(), buf.len(), &mut len, SIOS_WAIT_FOREVER) };
+        if status != secureiotos::SiosStatus::Ok || len != 8 {
+            continue;
+        }
+        let t = i32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        samples += 1;
+        (min, max, sum) = (min.min(t), max.max(t), sum + i64::from(t));
+        if samples < PUBLISH_EVERY {
+            continue;
+        }
+
+        let mut report = [0u8; 128];
+        let mut enc = Encoder::new(&mut report);
+        let encoded = (|| {
+            enc.map(6)?;
+            enc.str("boot")?.u64(u64::from(boots))?;
+            enc.str("seq")?.u64(report_seq)?;
+            enc.str("fw")?.bytes(&fw.build_id)?;
+            enc.str("t_min")?.i64(i64::from(min))?;
+            enc.str("t_max")?.i64(i64::from(max))?;
+            enc.str("t_avg")?.i64(sum / i64::from(samples))?;
+            Ok::<_, codec::cbor::CborError>(())
+        })();
+        (samples, min, max, sum) = (0, i32::MAX, i32::MIN, 0);
+        if encoded.is_err() {
+            continue;
+        }
+        // Compressed before sealing: ciphertext does not compress
+        let mut packed = [0u8; 160];
+        let Ok(packed_len) = compress_into(enc.as_slice(), &mut packed) else {
+            continue;
+        };
+
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[..4].copy_from_slice(&boots.to_be_bytes());
+        nonce[4..].copy_from_slice(&report_seq.to_be_bytes());
+        report_seq += 1;
+        let Ok(sealed) = gcm_seal(&mut aes, &key, &nonce, Nrf52840::NAME.as_bytes(), &packed[..packed_len]) else {
+            continue;
+        };
+        let mut frame = alloc::vec::Vec::with_capacity(NONCE_LEN + sealed.len());
+        frame.extend_from_slice(&nonce);
+        frame.extend_from_slice(&sealed);
+        if board.uplink().publish(TELEMETRY_TOPIC, &frame).is_err() {
+            sios_log::warn!("publish of report {} failed", report_seq - 1);
+        }
+    }
+}
+
+/// Park the core; a debugger or power cycle is the way out.
+#[inline(never)]
+fn fail_safe() -> ! {
+    loop {
+        cortex_m::asm::wfi();
+    }
+}
@@ -0,0 +1,129 @@
+//! SecureIoTOS nRF52840 Example Boot Module
+//! ----------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! First stage: the SecureIoTOS bootloader (`bootloader::boot()`) on the
+//! nRF52840-DK.
+//!
+//! 1. Pick the A/B application slot to boot (`LAYOUT`): a new image on
+//!    trial while it has boot attempts left, otherwise the confirmed one,
+//!    rolling back an update that was never confirmed.
+//! 2. Verify the slot's manifest against the vendor keys baked into the
+//!    `bootloader` crate, minus any revoked by an earlier update, then its
+//!    version and hash.
+//! 3. Measure this bootloader, the UICR configuration and the image into
+//!    the measured-boot event log at `BOOT_LOG_ADDR`, and leave the
+//!    image's measurement record at `MEASUREMENT_ADDR` for attestation.
+//! 4. Point VTOR at the image and jump through its reset vector, still
+//!    privileged: the kernel sets up the MPU and drops privilege for its
+//!    tasks.
+//!
+//! When no slot verifies, recovery mode takes a signed slot image
+//! (`sign.sh` output) over the J-Link virtual COM port with XMODEM and
+//! resets into it. Nothing unsigned runs.
+
+#![no_std]
+#![no_main]
+
+use core::ptr::addr_of_mut;
+
+use bootloader::{recovery, Board};
+use cortex_m::peripheral::SCB;
+use cortex_m_rt::entry;
+use defmt_rtt as _;
+use hal::timer::Delay;
+use panic_halt as _;
+use secure_storage::update::Layout;
+use secure_storage::vendor_keys::VendorKeys;
+
+use sios_nrf52840::bsp::{enable_cycle_counter, DebugUart, Nvmc, CORE_HZ};
+use sios_nrf52840::{BOOT_LOG_ADDR, LAYOUT, MEASUREMENT_ADDR};
+
+/// UICR.APPROTECT: low byte 0x00 locks the debug port.
+const UICR_APPROTECT: u32 = 0x1000_1208;
+/// Customer-writable UICR registers measured as the device configuration
+/// (NRFFW, NRFHW, CUSTOMER, PSELRESET, APPROTECT, NFCPINS, ...).
+const UICR_BASE: u32 = 0x1000_1000;
+const UICR_LEN: usize = 0x210;
+/// This image's flash (see memory-boot.x).
+const BOOT_LEN: usize = 64 * 1024;
+/// Heap for manifest parsing and the recovery writer.
+const HEAP_SIZE: usize = 16 * 1024;
+
+static mut HEAP: [u8; HEAP_SIZE] = [0; HEAP_SIZE];
+
+/// The nRF52840-DK as seen by the boot flow.
+struct NrfBoot {
+    flash: Nvmc,
+}
+
+impl Board for NrfBoot {
+    type Flash = Nvmc;
+
+    const LAYOUT: Layout = LAYOUT;
+    const BOOT_LOG_ADDR: u32 = BOOT_LOG_ADDR;
+    const MEASUREMENT_ADDR: u32 = MEASUREMENT_ADDR;
+
+    fn flash(&mut self) -> &mut Nvmc {
+        &mut self.flash
+    }
+
+    fn bootloader_code(&self) -> &'static [u8] {
+        mapped(0, BOOT_LEN)
+    }
+
+    fn config(&self) -> &'static [u8] {
+        mapped(UICR_BASE, UICR_LEN)
+    }
+
+    fn debug_locked(&self) -> bool {
+        // SAFETY: UICR is always readable.
+        let approtect = unsafe { core::ptr::read_volatile(UICR_APPROTECT as *const u32) };
+        approtect & 0xFF == 0
+    }
+
+    // Transfers are retried until one succeeds; the reset then boots the
+    // new image through the normal verification.
+    fn recover(&mut self, keys: &VendorKeys) -> ! {
+        let mut uart = DebugUart::init();
+        loop {
+            match recovery::receive(&mut uart, &mut BusyDelay, &mut self.flash, &LAYOUT, keys) {
+                Ok(slot) => {
+                    sios_log::info!("recovery: slot {:?} written, resetting", slot);
+                    SCB::sys_reset();
+                }
+                Err(e) => sios_log::warn!("recovery: transfer failed: {:?}", e),
+            }
+        }
+    }
+}
+
+/// Busy-wait delay at CORE_HZ (the HFINT oscillator runs the core at
+/// full speed from reset).
+struct BusyDelay;
+
+impl Delay for BusyDelay {
+    fn delay_us(&mut self, us: u32) {
+        cortex_m::asm::delay(us * (CORE_HZ / 1_000_000));
+    }
+}
+
+/// Internal flash or UICR as a slice.
+fn mapped(addr: u32, len: usize) -> &'static [u8] {
+    // SAFETY: only called with ranges inside the internal flash or the
+    // UICR, which are always mapped and not written while they are read.
+    unsafe { core::slice::from_raw_parts(addr as *const u8, len) }
+}
+
+#[entry]
+fn main() -> ! {
+    enable_cycle_counter();
+    // The heap array is handed to the allocator once, here.
+    memory::heap::init_heap(addr_of_mut!(HEAP) as usize, HEAP_SIZE);
+    bootloader::boot(&mut NrfBoot { flash: Nvmc::for_bootloader() })
+}
@@ -0,0 +1,527 @@
+//! SecureIoTOS nRF52840 Example BSP Module
+//! ---------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! `hal::bsp::Bsp` for the nRF52840-DK.
+//!
+//! - clocks: HFXO started, core at 64 MHz, DWT cycle counter on;
+//! - reset cause: POWER.RESETREAS (read once, then cleared);
+//! - root key: UICR.CUSTOMER[0..8], written at provisioning; protect the
+//!   device with UICR.APPROTECT so it cannot be read back over SWD;
+//! - flash: NVMC, 4 KB pages, word writes;
+//! - debug UART: the DK's J-Link virtual COM port (UART0, P0.06 TX, P0.08
+//!   RX, 115200 8N1), polled, for the bootloader's recovery mode;
+//! - uplink: Quectel BG95 on UARTE0 (P1.02 TX, P1.01 RX, 115200 8N1). The
+//!   modem holds the TLS session: CA, client certificate and key are
+//!   uploaded into its file system at provisioning and referenced from SSL
+//!   context 2. The MQTT client ID is the FICR device ID.
+//!
+//! Registers are accessed directly through the addresses below (product
+//! specification v1.7); there is no PAC dependency.
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use cortex_m::peripheral::{DCB, DWT};
+use hal::bsp::{Bsp, BspError, BspFlash, ResetCause, Uplink};
+use hal::serial::Serial;
+
+use crate::APP_WRITABLE_START;
+
+/// Core clock with the HFXO running.
+pub const CORE_HZ: u32 = 64_000_000;
+/// Internal flash size.
+pub const FLASH_SIZE: u32 = 0x0010_0000;
+
+// CLOCK
+const CLOCK_TASKS_HFCLKSTART: u32 = 0x4000_0000;
+const CLOCK_EVENTS_HFCLKSTARTED: u32 = 0x4000_0100;
+// POWER
+const POWER_RESETREAS: u32 = 0x4000_0400;
+const RESETREAS_RESETPIN: u32 = 1 << 0;
+const RESETREAS_DOG: u32 = 1 << 1;
+const RESETREAS_SREQ: u32 = 1 << 2;
+const RESETREAS_LOCKUP: u32 = 1 << 3;
+/// OFF, LPCOMP, DIF, NFC, VBUS: wakeups from System OFF
+const RESETREAS_WAKEUP: u32 = 0x1F << 16;
+// TEMP
+/// Base of the TEMP peripheral (the sensor task's MPU window).
+pub const TEMP_BASE: u32 = 0x4000_C000;
+const TEMP_TASKS_START: u32 = TEMP_BASE;
+const TEMP_TASKS_STOP: u32 = TEMP_BASE + 0x004;
+const TEMP_EVENTS_DATARDY: u32 = TEMP_BASE + 0x100;
+const TEMP_TEMP: u32 = TEMP_BASE + 0x508;
+// NVMC
+const NVMC_READY: u32 = 0x4001_E400;
+const NVMC_CONFIG: u32 = 0x4001_E504;
+const NVMC_ERASEPAGE: u32 = 0x4001_E508;
+const NVMC_CONFIG_REN: u32 = 0;
+const NVMC_CONFIG_WEN: u32 = 1;
+const NVMC_CONFIG_EEN: u32 = 2;
+// UARTE0
+const UARTE0: u32 = 0x4000_2000;
+const UARTE_TASKS_STARTRX: u32 = UARTE0;
+const UARTE_TASKS_STARTTX: u32 = UARTE0 + 0x008;
+const UARTE_EVENTS_ENDRX: u32 = UARTE0 + 0x110;
+const UARTE_EVENTS_ENDTX: u32 = UARTE0 + 0x120;
+const UARTE_ENABLE: u32 = UARTE0 + 0x500;
+const UARTE_PSEL_TXD: u32 = UARTE0 + 0x50C;
+const UARTE_PSEL_RXD: u32 = UARTE0 + 0x514;
+const UARTE_BAUDRATE: u32 = UARTE0 + 0x524;
+const UARTE_RXD_PTR: u32 = UARTE0 + 0x534;
+const UARTE_RXD_MAXCNT: u32 = UARTE0 + 0x538;
+const UARTE_RXD_AMOUNT: u32 = UARTE0 + 0x53C;
+const UARTE_TXD_PTR: u32 = UARTE0 + 0x544;
+const UARTE_TXD_MAXCNT: u32 = UARTE0 + 0x548;
+const UARTE_ENABLE_ON: u32 = 8;
+const UARTE_BAUD_115200: u32 = 0x01D7_E000;
+// UART0: the same instance as UARTE0 in its legacy, byte-register mode
+const UART_TASKS_STARTRX: u32 = UARTE0;
+const UART_TASKS_STARTTX: u32 = UARTE0 + 0x008;
+const UART_EVENTS_RXDRDY: u32 = UARTE0 + 0x108;
+const UART_EVENTS_TXDRDY: u32 = UARTE0 + 0x11C;
+const UART_ERRORSRC: u32 = UARTE0 + 0x480;
+const UART_RXD: u32 = UARTE0 + 0x518;
+const UART_TXD: u32 = UARTE0 + 0x51C;
+const UART_ENABLE_ON: u32 = 4;
+/// P0.06 / P0.08 (J-Link VCOM)
+const DEBUG_TXD_PIN: u32 = 6;
+const DEBUG_RXD_PIN: u32 = 8;
+/// P1.02 / P1.01 (Arduino D1 / D0)
+const MODEM_TXD_PIN: u32 = 32 + 2;
+const MODEM_RXD_PIN: u32 = 32 + 1;
+// UICR / FICR
+const UICR_CUSTOMER: u32 = 0x1000_1080;
+const FICR_DEVICEID: u32 = 0x1000_0060;
+
+fn read_reg(addr: u32) -> u32 {
+    // SAFETY: only called with the register addresses above, which are
+    // valid, aligned MMIO (or UICR/FICR) words on the nRF52840.
+    unsafe { core::ptr::read_volatile(addr as *const u32) }
+}
+
+fn write_reg(addr: u32, value: u32) {
+    // SAFETY: as in `read_reg`; the writes are the documented register
+    // protocols of the peripherals, none of which alias Rust memory.
+    unsafe { core::ptr::write_volatile(addr as *mut u32, value) }
+}
+
+/// Turn on the DWT cycle counter, which the timeouts here run on.
+pub fn enable_cycle_counter() {
+    // SAFETY: DEMCR.TRCENA and DWT.CTRL.CYCCNTENA only enable the
+    // cycle counter; nothing else uses the DWT at this point.
+    unsafe {
+        (*DCB::PTR).demcr.modify(|r| r | 1 << 24);
+        (*DWT::PTR).ctrl.modify(|r| r | 1);
+    }
+}
+
+/// Spin until `done()` or `timeout_ms` have passed (DWT must be on).
+fn wait_for(timeout_ms: u32, mut done: impl FnMut() -> bool) -> Result<(), BspError> {
+    let start = DWT::cycle_count();
+    let limit = timeout_ms.saturating_mul(CORE_HZ / 1000);
+    while !done() {
+        if DWT::cycle_count().wrapping_sub(start) > limit {
+            return Err(BspError::Timeout);
+        }
+    }
+    Ok(())
+}
+
+/// Die temperature in 0.25 °C. Needs nothing but the TEMP registers, so
+/// an unprivileged task with an MPU window over `TEMP_BASE` may call it.
+pub fn read_die_temperature() -> Option<i32> {
+    write_reg(TEMP_EVENTS_DATARDY, 0);
+    write_reg(TEMP_TASKS_START, 1);
+    // A conversion takes 36 µs; give up after ~1000 polls.
+    let ready = (0..1000).any(|_| read_reg(TEMP_EVENTS_DATARDY) != 0);
+    write_reg(TEMP_TASKS_STOP, 1);
+    write_reg(TEMP_EVENTS_DATARDY, 0);
+    ready.then(|| read_reg(TEMP_TEMP) as i32)
+}
+
+/// Internal flash through the NVMC.
+pub struct Nvmc(());
+
+impl Nvmc {
+    /// The flash alone, for the bootloader, which has no other use for the
+    /// board; the application takes it with `Nrf52840`. Needs the cycle
+    /// counter (`enable_cycle_counter()`).
+    pub fn for_bootloader() -> Self {
+        Nvmc(())
+    }
+
+    fn check(addr: u32, len: usize) -> Result<(), BspError> {
+        let end = addr.checked_add(len as u32).ok_or(BspError::OutOfRange)?;
+        if end > FLASH_SIZE {
+            return Err(BspError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    fn check_writable(addr: u32, len: usize) -> Result<(), BspError> {
+        Self::check(addr, len)?;
+        if addr < APP_WRITABLE_START || !addr.is_multiple_of(4) || !len.is_multiple_of(4) {
+            return Err(BspError::OutOfRange);
+        }
+        Ok(())
+    }
+
+    fn wait_ready() -> Result<(), BspError> {
+        // Page erase takes up to 85 ms
+        wait_for(100, || read_reg(NVMC_READY) & 1 != 0).map_err(|_| BspError::Flash)
+    }
+}
+
+impl BspFlash for Nvmc {
+    const PAGE_SIZE: usize = 4096;
+    const WRITE_SIZE: usize = 4;
+
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), BspError> {
+        Self::check(addr, buf.len())?;
+        for (i, b) in buf.iter_mut().enumerate() {
+            // SAFETY: inside the internal flash, which is always mapped
+            // and readable.
+            *b = unsafe { core::ptr::read_volatile((addr as usize + i) as *const u8) };
+        }
+        Ok(())
+    }
+
+    fn erase_page(&mut self, addr: u32) -> Result<(), BspError> {
+        Self::check_writable(addr, Self::PAGE_SIZE)?;
+        if !(addr as usize).is_multiple_of(Self::PAGE_SIZE) {
+            return Err(BspError::OutOfRange);
+        }
+        write_reg(NVMC_CONFIG, NVMC_CONFIG_EEN);
+        write_reg(NVMC_ERASEPAGE, addr);
+        let res = Self::wait_ready();
+        write_reg(NVMC_CONFIG, NVMC_CONFIG_REN);
+        res
+    }
+
+    fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), BspError> {
+        Self::check_writable(addr, data.len())?;
+        write_reg(NVMC_CONFIG, NVMC_CONFIG_WEN);
+        let mut res = Ok(());
+        for (i, word) in data.chunks_exact(4).enumerate() {
+            let value = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+            write_reg(addr + 4 * i as u32, value);
+            res = Self::wait_ready();
+            if res.is_err() {
+                break;
+            }
+        }
+        write_reg(NVMC_CONFIG, NVMC_CONFIG_REN);
+        res
+    }
+}
+
+/// Debug UART, as used by the bootloader's recovery mode
+// UART0 shares its registers with the modem's UARTE0; only the bootloader,
+// which never starts the modem, uses it.
+pub struct DebugUart(());
+
+impl DebugUart {
+    /// Route P0.06/P0.08 to UART0 and start both directions.
+    pub fn init() -> Self {
+        write_reg(UARTE_ENABLE, 0);
+        write_reg(UARTE_PSEL_TXD, DEBUG_TXD_PIN);
+        write_reg(UARTE_PSEL_RXD, DEBUG_RXD_PIN);
+        write_reg(UARTE_BAUDRATE, UARTE_BAUD_115200);
+        write_reg(UARTE_ENABLE, UART_ENABLE_ON);
+        write_reg(UART_TASKS_STARTTX, 1);
+        write_reg(UART_TASKS_STARTRX, 1);
+        DebugUart(())
+    }
+}
+
+impl Serial for DebugUart {
+    fn write_byte(&mut self, byte: u8) {
+        write_reg(UART_EVENTS_TXDRDY, 0);
+        write_reg(UART_TXD, byte as u32);
+        while read_reg(UART_EVENTS_TXDRDY) == 0 {}
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        let errors = read_reg(UART_ERRORSRC);
+        if errors != 0 {
+            // A byte was lost or garbled; the block's CRC fails and
+            // XMODEM resends it
+            write_reg(UART_ERRORSRC, errors);
+        }
+        if read_reg(UART_EVENTS_RXDRDY) == 0 {
+            return None;
+        }
+        write_reg(UART_EVENTS_RXDRDY, 0);
+        Some(read_reg(UART_RXD) as u8)
+    }
+}
+
+/// Broker the modem connects to.
+#[derive(Debug, Clone, Copy)]
+pub struct BrokerConfig {
+    pub host: &'static str,
+    pub port: u16,
+}
+
+/// Line buffer for AT commands and responses.
+struct Line {
+    buf: [u8; 160],
+    len: usize,
+}
+
+impl Line {
+    const fn new() -> Self {
+        Self { buf: [0; 160], len: 0 }
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    fn contains(&self, needle: &[u8]) -> bool {
+        self.as_bytes().windows(needle.len()).any(|w| w == needle)
+    }
+}
+
+impl Write for Line {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let end = self.len + s.len();
+        self.buf.get_mut(self.len..end).ok_or(core::fmt::Error)?.copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// MQTT-over-TLS through a Quectel BG95 modem (client 0, SSL context 2).
+pub struct ModemUplink {
+    broker: BrokerConfig,
+    connected: bool,
+    msg_id: u16,
+    /// EasyDMA only reads RAM, so outgoing bytes are staged here
+    tx: [u8; 64],
+    rx: [u8; 1],
+}
+
+impl ModemUplink {
+    fn new(broker: BrokerConfig) -> Self {
+        write_reg(UARTE_PSEL_TXD, MODEM_TXD_PIN);
+        write_reg(UARTE_PSEL_RXD, MODEM_RXD_PIN);
+        write_reg(UARTE_BAUDRATE, UARTE_BAUD_115200);
+        write_reg(UARTE_ENABLE, UARTE_ENABLE_ON);
+        Self { broker, connected: false, msg_id: 0, tx: [0; 64], rx: [0; 1] }
+    }
+
+    fn send(&mut self, mut data: &[u8]) -> Result<(), BspError> {
+        while !data.is_empty() {
+            let n = data.len().min(self.tx.len());
+            self.tx[..n].copy_from_slice(&data[..n]);
+            write_reg(UARTE_EVENTS_ENDTX, 0);
+            write_reg(UARTE_TXD_PTR, self.tx.as_ptr() as u32);
+            write_reg(UARTE_TXD_MAXCNT, n as u32);
+            write_reg(UARTE_TASKS_STARTTX, 1);
+            wait_for(100, || read_reg(UARTE_EVENTS_ENDTX) != 0)?;
+            data = &data[n..];
+        }
+        Ok(())
+    }
+
+    fn recv_byte(&mut self, timeout_ms: u32) -> Result<u8, BspError> {
+        write_reg(UARTE_EVENTS_ENDRX, 0);
+        write_reg(UARTE_RXD_PTR, self.rx.as_mut_ptr() as u32);
+        write_reg(UARTE_RXD_MAXCNT, 1);
+        write_reg(UARTE_TASKS_STARTRX, 1);
+        wait_for(timeout_ms, || read_reg(UARTE_EVENTS_ENDRX) != 0)?;
+        if read_reg(UARTE_RXD_AMOUNT) != 1 {
+            return Err(BspError::Uplink);
+        }
+        Ok(self.rx[0])
+    }
+
+    /// Read response lines until one contains `token` (or, for `b">"`,
+    /// until the data prompt). "ERROR" lines fail the command.
+    fn expect(&mut self, token: &[u8], timeout_ms: u32) -> Result<(), BspError> {
+        let mut line = Line::new();
+        loop {
+            let b = self.recv_byte(timeout_ms)?;
+            if b == b'>' && token == b">" {
+                return Ok(());
+            }
+            if b != b'\n' {
+                if b != b'\r' && line.len < line.buf.len() {
+                    line.buf[line.len] = b;
+                    line.len += 1;
+                }
+                continue;
+            }
+            if line.contains(token) {
+                return Ok(());
+            }
+            if line.contains(b"ERROR") {
+                sios_log::warn!("modem: command failed");
+                return Err(BspError::Uplink);
+            }
+            line.len = 0;
+        }
+    }
+
+    /// Send an AT command and wait for `token` in the answer.
+    fn command(&mut self, args: core::fmt::Arguments, token: &[u8], timeout_ms: u32) -> Result<(), BspError> {
+        let mut line = Line::new();
+        line.write_fmt(args).map_err(|_| BspError::Uplink)?;
+        line.write_str("\r\n").map_err(|_| BspError::Uplink)?;
+        let Line { buf, len } = line;
+        self.send(&buf[..len])?;
+        self.expect(token, timeout_ms)
+    }
+}
+
+impl Uplink for ModemUplink {
+    fn connect(&mut self) -> Result<(), BspError> {
+        if self.connected {
+            return Ok(());
+        }
+        let id = (u64::from(read_reg(FICR_DEVICEID + 4)) << 32) | u64::from(read_reg(FICR_DEVICEID));
+        let BrokerConfig { host, port } = self.broker;
+
+        self.command(format_args!("AT"), b"OK", 1000)?;
+        self.command(format_args!("AT+QSSLCFG=\"sslversion\",2,4"), b"OK", 1000)?;
+        self.command(format_args!("AT+QSSLCFG=\"seclevel\",2,2"), b"OK", 1000)?;
+        self.command(format_args!("AT+QSSLCFG=\"cacert\",2,\"UFS:ca.pem\""), b"OK", 1000)?;
+        self.command(format_args!("AT+QSSLCFG=\"clientcert\",2,\"UFS:client.pem\""), b"OK", 1000)?;
+        self.command(format_args!("AT+QSSLCFG=\"clientkey\",2,\"UFS:client.key\""), b"OK", 1000)?;
+        self.command(format_args!("AT+QMTCFG=\"ssl\",0,1,2"), b"OK", 1000)?;
+        // Network attach and the TLS handshake can take a while on LTE-M
+        self.command(format_args!("AT+QMTOPEN=0,\"{}\",{}", host, port), b"+QMTOPEN: 0,0", 75_000)?;
+        self.command(format_args!("AT+QMTCONN=0,\"{:016x}\"", id), b"+QMTCONN: 0,0,0", 10_000)?;
+        self.connected = true;
+        sios_log::info!("uplink connected");
+        Ok(())
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn publish(&mut self, topic: &str, payload: &[u8]) -> Result<(), BspError> {
+        self.connect()?;
+        // QoS 1 needs a message ID in 1..=65535
+        self.msg_id = self.msg_id.wrapping_add(1).max(1);
+        let id = self.msg_id;
+        let res = self
+            .command(format_args!("AT+QMTPUBEX=0,{},1,0,\"{}\",{}", id, topic, payload.len()), b">", 5000)
+            .and_then(|()| self.send(payload))
+            .and_then(|()| {
+                let mut done = Line::new();
+                let _ = write!(done, "+QMTPUBEX: 0,{},0", id);
+                let Line { buf, len } = done;
+                self.expect(&buf[..len], 15_000)
+            });
+        if res.is_err() {
+            // Reconnect on the next publish
+            self.connected = false;
+        }
+        res
+    }
+}
+
+/// The nRF52840-DK.
+pub struct Nrf52840 {
+    flash: Nvmc,
+    uplink: ModemUplink,
+    core_hz: u32,
+    reset: Option<ResetCause>,
+}
+
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+impl Nrf52840 {
+    /// Take the board; `None` if it was taken before.
+    pub fn take(broker: BrokerConfig) -> Option<Self> {
+        if TAKEN.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        Some(Self { flash: Nvmc(()), uplink: ModemUplink::new(broker), core_hz: 0, reset: None })
+    }
+}
+
+impl Bsp for Nrf52840 {
+    type Flash = Nvmc;
+    type Uplink = ModemUplink;
+
+    const NAME: &'static str = "nrf52840-dk";
+
+    fn init_clocks(&mut self) -> u32 {
+        enable_cycle_counter();
+        write_reg(CLOCK_EVENTS_HFCLKSTARTED, 0);
+        write_reg(CLOCK_TASKS_HFCLKSTART, 1);
+        if wait_for(10, || read_reg(CLOCK_EVENTS_HFCLKSTARTED) != 0).is_err() {
+            // The CPU still runs at 64 MHz from the HFINT oscillator,
+            // only less accurately.
+            sios_log::warn!("HFXO did not start");
+        }
+        self.core_hz = CORE_HZ;
+        self.core_hz
+    }
+
+    fn core_clock_hz(&self) -> u32 {
+        self.core_hz
+    }
+
+    fn reset_cause(&mut self) -> ResetCause {
+        if let Some(cause) = self.reset {
+            return cause;
+        }
+        let reas = read_reg(POWER_RESETREAS);
+        // Write-1-to-clear; otherwise causes accumulate across resets
+        write_reg(POWER_RESETREAS, reas);
+        let cause = if reas == 0 {
+            ResetCause::PowerOn
+        } else if reas & RESETREAS_DOG != 0 {
+            ResetCause::Watchdog
+        } else if reas & RESETREAS_LOCKUP != 0 {
+            ResetCause::Lockup
+        } else if reas & RESETREAS_SREQ != 0 {
+            ResetCause::Software
+        } else if reas & RESETREAS_RESETPIN != 0 {
+            ResetCause::Pin
+        } else if reas & RESETREAS_WAKEUP != 0 {
+            ResetCause::Wakeup
+        } else {
+            ResetCause::Other
+        };
+        self.reset = Some(cause);
+        cause
+    }
+
+    fn read_root_key(&mut self, out: &mut [u8; 32]) -> Result<(), BspError> {
+        for (i, chunk) in out.chunks_exact_mut(4).enumerate() {
+            chunk.copy_from_slice(&read_reg(UICR_CUSTOMER + 4 * i as u32).to_le_bytes());
+        }
+        if out.iter().all(|&b| b == 0xFF) {
+            return Err(BspError::NotProvisioned);
+        }
+        Ok(())
+    }
+
+    fn cycles(&self) -> u32 {
+        DWT::cycle_count()
+    }
+
+    fn temperature_quarter_c(&mut self) -> Option<i32> {
+        read_die_temperature()
+    }
+
+    fn flash(&mut self) -> &mut Nvmc {
+        &mut self.flash
+    }
+
+    fn uplink(&mut self) -> &mut ModemUplink {
+        &mut self.uplink
+    }
+}
@@ -0,0 +1,70 @@
+//! SecureIoTOS nRF52840 Example Lib Module
+//! ---------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Reference target: secure boot -> kernel -> telemetry on an nRF52840-DK
+//! with an LTE-M modem (Quectel BG95) on the Arduino header UART.
+//!
+//! - `bin/boot.rs`: the SecureIoTOS bootloader (`bootloader::boot()`):
+//!   picks and verifies one of the A/B application slots, measures the
+//!   boot chain and jumps to it, or takes a signed image over the debug
+//!   UART when no slot verifies;
+//! - `bin/app.rs`: installs the device root key, records the boot in the
+//!   boot ledger, confirms a new image, sets up the MPU and starts an unprivileged sensor task
+//!   and the telemetry task, which publishes encrypted readings over
+//!   MQTT-over-TLS;
+//! - `bsp`: everything nRF52840 specific, behind `hal::bsp::Bsp`.
+//!
+//! This module holds what both stages agree on: the flash layout and
+//! where the bootloader leaves its measurements for attestation.
+
+#![no_std]
+
+pub mod bsp;
+
+use secure_storage::update::{Layout, SlotRegion};
+
+/// Application slots, boot control pages and the vendor key revocation
+/// area (`secure_storage::update`); the map is in memory-boot.x. Each
+/// slot is a 256-byte manifest page (written by `sign.sh`) followed by
+/// the image, whose vector table is thus 256-byte aligned for VTOR.
+pub const LAYOUT: Layout = Layout {
+    slots: [
+        SlotRegion { base: 0x0001_4000, len: 0x0006_E000 },
+        SlotRegion { base: 0x0008_2000, len: 0x0006_E000 },
+    ],
+    control: [0x0001_0000, 0x0001_1000],
+    revocations: 0x0001_2000,
+};
+/// Interrupt vectors of the nRF52840 (48 lines), taking the place of a
+/// device crate's for cortex-m-rt's `device` feature: its generic table
+/// has 240 entries and would have to be 1 KB aligned, but the
+/// application's vector table only follows a 256-byte manifest page. No
+/// interrupt is used; every line goes to `DefaultHandler`.
+#[link_section = ".vector_table.interrupts"]
+#[no_mangle]
+#[used]
+static __INTERRUPTS: [unsafe extern "C" fn(); 48] = [DefaultHandler; 48];
+
+extern "C" {
+    fn DefaultHandler();
+}
+
+/// A/B pages of the boot ledger.
+pub const LEDGER_PAGES: [u32; 2] = [0x000F_0000, 0x000F_1000];
+/// Flash below this address (the bootloader) is never written; the
+/// bootloader itself writes the boot control pages, revocations and slots.
+pub const APP_WRITABLE_START: u32 = LAYOUT.control[0];
+/// Retained RAM slot (outside both stages' `RAM`) holding the boot
+/// measurement record (`bootloader::firmware::measure()`), read by the
+/// application with `auth_identity::attestation::BootMeasurement`.
+pub const MEASUREMENT_ADDR: u32 = 0x2003_FF00;
+/// Retained RAM slot below it holding the measured-boot event log
+/// (`bootloader::firmware::MeasurementLog`), taken over by the kernel with
+/// `kernel::measured_boot::capture()`.
+pub const BOOT_LOG_ADDR: u32 = 0x2003_FE00;
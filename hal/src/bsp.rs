@@ -0,0 +1,100 @@
+//! SecureIoTOS HAL Board Support Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Board support package (BSP) interface.
+//!
+//! Everything a SecureIoTOS image needs from a concrete board, beyond the
+//! Cortex-M core itself, goes through `Bsp`: clock setup, the reset
+//! cause, the provisioned device root key, the internal flash and the
+//! uplink that carries telemetry. The boot flow and the tasks are written
+//! against this trait, so bringing up a new board means implementing it
+//! once; `examples/nrf52840` is the reference implementation.
+//!
+//! `Uplink` is a publish-only MQTT session. On boards without an IP stack
+//! of their own it is typically a cellular or Wi-Fi modem that holds the
+//! TLS session and the broker connection, driven over a UART.
+
+/// Why the board last came out of reset, as recorded in the boot ledger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetCause {
+    PowerOn = 0,
+    /// Reset pin
+    Pin = 1,
+    Watchdog = 2,
+    /// `SYSRESETREQ` (software reset, e.g. after an update)
+    Software = 3,
+    Lockup = 4,
+    /// Wakeup from a low-power (system off) state
+    Wakeup = 5,
+    Other = 0xFF,
+}
+
+/// Errors of the board support functions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BspError {
+    /// Address or length outside the flash, or not aligned
+    OutOfRange,
+    /// Flash controller reported a failure
+    Flash,
+    /// No root key has been provisioned into the device
+    NotProvisioned,
+    /// The uplink did not answer in time
+    Timeout,
+    /// The uplink refused the command
+    Uplink,
+}
+
+/// Internal flash of the board.
+pub trait BspFlash {
+    /// Erase granularity in bytes.
+    const PAGE_SIZE: usize;
+    /// Smallest programmable unit in bytes.
+    const WRITE_SIZE: usize;
+
+    fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), BspError>;
+    /// Erase the page starting at `addr` (page aligned).
+    fn erase_page(&mut self, addr: u32) -> Result<(), BspError>;
+    /// Program erased flash; `addr` and `data.len()` are multiples of
+    /// `WRITE_SIZE`.
+    fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), BspError>;
+}
+
+/// Publish-only MQTT-over-TLS session to the telemetry broker.
+pub trait Uplink {
+    /// Bring up the link and the broker session (idempotent).
+    fn connect(&mut self) -> Result<(), BspError>;
+    fn is_connected(&self) -> bool;
+    /// Publish `payload` (binary) on `topic` with QoS 1.
+    fn publish(&mut self, topic: &str, payload: &[u8]) -> Result<(), BspError>;
+}
+
+/// A concrete board.
+pub trait Bsp {
+    type Flash: BspFlash;
+    type Uplink: Uplink;
+
+    /// Board name, reported in telemetry.
+    const NAME: &'static str;
+
+    /// Switch to the final clock tree; returns the core clock in Hz.
+    fn init_clocks(&mut self) -> u32;
+    /// Core clock in Hz after `init_clocks()`.
+    fn core_clock_hz(&self) -> u32;
+    /// Cause of the last reset. Reading it clears the hardware latch, so
+    /// the first read per boot is the one to keep.
+    fn reset_cause(&mut self) -> ResetCause;
+    /// Copy the provisioned device root key into `out`.
+    fn read_root_key(&mut self, out: &mut [u8; 32]) -> Result<(), BspError>;
+    /// Free-running cycle counter (for timestamps and trace clocks).
+    fn cycles(&self) -> u32;
+    /// Die temperature in units of 0.25 °C, if the board can measure it.
+    fn temperature_quarter_c(&mut self) -> Option<i32>;
+
+    fn flash(&mut self) -> &mut Self::Flash;
+    fn uplink(&mut self) -> &mut Self::Uplink;
+}
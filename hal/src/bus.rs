@@ -12,8 +12,8 @@
 //! generic traits, SecureIoTOS can interact with various 
 //! hardware platforms in a platform-independent manner.
 
-use embedded_hal::blocking::i2c::{Read, Write};
-use embedded_hal::blocking::spi::Transfer;
+use embedded_hal_02::blocking::i2c::{Read, Write};
+use embedded_hal_02::blocking::spi::Transfer;
 
 /// Trait representing SPI communication functionality
 pub trait Spi {
@@ -43,9 +43,14 @@ impl<SPI, E> Spi for HalSpi<SPI>
 where SPI: Transfer<u8, Error = E> 
 {
     /// Write data via SPI by internally performing a transfer
+    // Through a stack buffer, a chunk at a time: `transfer` overwrites it
     fn write(&mut self, data: &[u8]) { 
-        let mut buf = data.to_vec(); 
-        let _ = self.spi.transfer(&mut buf); 
+        let mut buf = [0u8; 64];
+        for chunk in data.chunks(buf.len()) {
+            let buf = &mut buf[..chunk.len()];
+            buf.copy_from_slice(chunk);
+            let _ = self.spi.transfer(buf);
+        }
     }
 
     /// Perform a SPI transfer, modifying the input buffer with the response
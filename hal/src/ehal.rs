@@ -0,0 +1,327 @@
+//! SecureIoTOS HAL embedded-hal Compatibility Module
+//! -------------------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! embedded-hal 1.0 traits for SecureIoTOS's own bus, serial and delay
+//! abstractions, so existing device driver crates run unmodified on top
+//! of them. Wrap the SecureIoTOS object in `Compat` and hand it to the
+//! driver:
+//!
+//! | SecureIoTOS trait | `Compat<T>` implements                                   |
+//! |-------------------|----------------------------------------------------------|
+//! | `bus::Spi`        | `embedded_hal::spi::SpiBus`                              |
+//! | `bus::I2c`        | `embedded_hal::i2c::I2c`                                 |
+//! | `serial::Serial`  | `embedded_hal_nb::serial::{Read, Write}`, `embedded_io::{Read, Write}` |
+//! | `timer::Delay`    | `embedded_hal::delay::DelayNs`                           |
+//!
+//! Drivers that want an `SpiDevice` (bus + chip select) take the
+//! `SpiBus` through `embedded_hal_bus::spi::ExclusiveDevice`.
+//!
+//! Our traits report no errors, so every `Error` type here is
+//! `Infallible`. `bus::I2c` has no repeated-start primitive: the
+//! operations of an `I2c::transaction` are issued as separate
+//! transfers, which the common sensor `write_read` register access
+//! tolerates.
+
+use core::convert::Infallible;
+
+use embedded_hal::{delay, i2c, spi};
+use embedded_hal_nb::{nb, serial};
+
+use crate::bus::{I2c, Spi};
+use crate::serial::Serial;
+use crate::timer::Delay;
+
+/// Stack scratch used by `SpiBus::transfer` for unequal buffer lengths.
+const SPI_CHUNK: usize = 32;
+
+/// Adapter exposing a SecureIoTOS HAL object through embedded-hal 1.0.
+pub struct Compat<T>(pub T);
+
+impl<T> Compat<T> {
+    pub fn new(inner: T) -> Self {
+        Self(inner)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+// ---------------------------
+// SPI
+// ---------------------------
+
+impl<T: Spi> spi::ErrorType for Compat<T> {
+    type Error = Infallible;
+}
+
+impl<T: Spi> spi::SpiBus<u8> for Compat<T> {
+    fn read(&mut self, words: &mut [u8]) -> Result<(), Infallible> {
+        words.fill(0);
+        self.0.transfer(words);
+        Ok(())
+    }
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Infallible> {
+        self.0.write(words);
+        Ok(())
+    }
+
+    /// Clocks `max(read.len(), write.len())` bytes: missing write bytes
+    /// are sent as 0x00 and surplus read bytes are discarded.
+    fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Infallible> {
+        let total = read.len().max(write.len());
+        let mut buf = [0u8; SPI_CHUNK];
+        let mut off = 0;
+        while off < total {
+            let n = (total - off).min(SPI_CHUNK);
+            let chunk = &mut buf[..n];
+            chunk.fill(0);
+            if let Some(src) = write.get(off..) {
+                let k = src.len().min(n);
+                chunk[..k].copy_from_slice(&src[..k]);
+            }
+            self.0.transfer(chunk);
+            if let Some(dst) = read.get_mut(off..) {
+                let k = dst.len().min(n);
+                dst[..k].copy_from_slice(&chunk[..k]);
+            }
+            off += n;
+        }
+        Ok(())
+    }
+
+    fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Infallible> {
+        self.0.transfer(words);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), Infallible> {
+        Ok(())
+    }
+}
+
+// ---------------------------
+// I2C
+// ---------------------------
+
+impl<T: I2c> i2c::ErrorType for Compat<T> {
+    type Error = Infallible;
+}
+
+impl<T: I2c> i2c::I2c for Compat<T> {
+    fn transaction(&mut self, address: u8, operations: &mut [i2c::Operation<'_>]) -> Result<(), Infallible> {
+        for op in operations {
+            match op {
+                i2c::Operation::Write(data) => self.0.write(address, data),
+                i2c::Operation::Read(buf) => self.0.read(address, buf),
+            }
+        }
+        Ok(())
+    }
+}
+
+// ---------------------------
+// Serial
+// ---------------------------
+
+impl<T: Serial> serial::ErrorType for Compat<T> {
+    type Error = Infallible;
+}
+
+impl<T: Serial> serial::Read<u8> for Compat<T> {
+    fn read(&mut self) -> nb::Result<u8, Infallible> {
+        self.0.read_byte().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+impl<T: Serial> serial::Write<u8> for Compat<T> {
+    fn write(&mut self, word: u8) -> nb::Result<(), Infallible> {
+        self.0.write_byte(word);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), Infallible> {
+        self.0.flush();
+        Ok(())
+    }
+}
+
+impl<T: Serial> embedded_io::ErrorType for Compat<T> {
+    type Error = Infallible;
+}
+
+impl<T: Serial> embedded_io::Read for Compat<T> {
+    /// Waits for the first byte, then returns whatever else is pending.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+        let Some((first, rest)) = buf.split_first_mut() else {
+            return Ok(0);
+        };
+        *first = loop {
+            match self.0.read_byte() {
+                Some(b) => break b,
+                None => core::hint::spin_loop(),
+            }
+        };
+        let mut n = 1;
+        for slot in rest {
+            match self.0.read_byte() {
+                Some(b) => *slot = b,
+                None => break,
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+impl<T: Serial> embedded_io::Write for Compat<T> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+        self.0.write_all(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Infallible> {
+        self.0.flush();
+        Ok(())
+    }
+}
+
+// ---------------------------
+// Delay
+// ---------------------------
+
+impl<T: Delay> delay::DelayNs for Compat<T> {
+    fn delay_ns(&mut self, ns: u32) {
+        // Round up: a delay may be longer than asked, never shorter.
+        self.0.delay_us(ns.div_ceil(1000));
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        self.0.delay_us(us);
+    }
+
+    fn delay_ms(&mut self, ms: u32) {
+        self.0.delay_ms(ms);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use embedded_hal::delay::DelayNs;
+    use embedded_hal::i2c::I2c as _;
+    use embedded_hal::spi::SpiBus;
+
+    /// Loopback SPI that returns every byte incremented by one.
+    struct Loopback {
+        clocked: usize,
+    }
+
+    impl Spi for Loopback {
+        fn write(&mut self, data: &[u8]) {
+            self.clocked += data.len();
+        }
+
+        fn transfer(&mut self, data: &mut [u8]) {
+            self.clocked += data.len();
+            data.iter_mut().for_each(|b| *b = b.wrapping_add(1));
+        }
+    }
+
+    #[test]
+    fn spi_transfer_with_unequal_lengths() {
+        let mut bus = Compat::new(Loopback { clocked: 0 });
+        let write = [7u8; 40];
+        let mut read = [0u8; 3];
+        bus.transfer(&mut read, &write).unwrap();
+        assert_eq!(read, [8; 3]);
+        assert_eq!(bus.0.clocked, 40, "longer buffer decides the length");
+
+        let mut read = [0u8; 40];
+        bus.transfer(&mut read, &[1, 2]).unwrap();
+        assert_eq!(&read[..3], &[2, 3, 1], "missing write bytes are sent as 0");
+        assert_eq!(read[39], 1);
+    }
+
+    struct Regs {
+        pointer: u8,
+        regs: [u8; 4],
+    }
+
+    impl I2c for Regs {
+        fn write(&mut self, _addr: u8, data: &[u8]) {
+            self.pointer = data[0];
+        }
+
+        fn read(&mut self, _addr: u8, buffer: &mut [u8]) {
+            for (i, b) in buffer.iter_mut().enumerate() {
+                *b = self.regs[self.pointer as usize + i];
+            }
+        }
+    }
+
+    #[test]
+    fn i2c_write_read_register_access() {
+        let mut i2c = Compat::new(Regs { pointer: 0, regs: [10, 11, 12, 13] });
+        let mut out = [0u8; 2];
+        i2c.write_read(0x48, &[2], &mut out).unwrap();
+        assert_eq!(out, [12, 13]);
+    }
+
+    struct Fifo {
+        rx: &'static [u8],
+        tx: [u8; 8],
+        tx_len: usize,
+    }
+
+    impl Serial for Fifo {
+        fn write_byte(&mut self, byte: u8) {
+            self.tx[self.tx_len] = byte;
+            self.tx_len += 1;
+        }
+
+        fn read_byte(&mut self) -> Option<u8> {
+            let (&b, rest) = self.rx.split_first()?;
+            self.rx = rest;
+            Some(b)
+        }
+    }
+
+    #[test]
+    fn serial_nb_and_io() {
+        let mut uart = Compat::new(Fifo { rx: b"hi", tx: [0; 8], tx_len: 0 });
+        assert_eq!(serial::Read::read(&mut uart), Ok(b'h'));
+
+        let mut buf = [0u8; 4];
+        assert_eq!(embedded_io::Read::read(&mut uart, &mut buf), Ok(1));
+        assert_eq!(buf[0], b'i');
+        assert_eq!(serial::Read::read(&mut uart), Err(nb::Error::WouldBlock));
+
+        embedded_io::Write::write_all(&mut uart, b"ok").unwrap();
+        assert_eq!(&uart.0.tx[..uart.0.tx_len], b"ok");
+    }
+
+    struct Recorder(u32);
+
+    impl Delay for Recorder {
+        fn delay_us(&mut self, us: u32) {
+            self.0 += us;
+        }
+    }
+
+    #[test]
+    fn delay_rounds_up() {
+        let mut d = Compat::new(Recorder(0));
+        d.delay_ns(1);
+        d.delay_ns(1500);
+        d.delay_ms(1);
+        assert_eq!(d.0 .0, 1 + 2 + 1000);
+    }
+}
@@ -7,9 +7,13 @@
 //! URL: https://m-a-h-b-u-b.github.io
 //! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
 
+use crate::trace;
+
 /// Representation of a GPIO pin
+///
+/// `port` is the address of the port's output data register.
 pub struct GPIO {
-    pub port: u8,
+    pub port: usize,
     pub pin: u8,
 }
 
@@ -22,29 +26,24 @@ pub trait GpioExt {
 
 impl GpioExt for GPIO {
     fn set_high(&mut self) {
-        unsafe { core::ptr::write_volatile(self.port as *mut u32, 1 << self.pin) };
+        unsafe { trace::modify32(self.port, |v| v | (1 << self.pin)) };
     }
 
     fn set_low(&mut self) {
-        unsafe { core::ptr::write_volatile(self.port as *mut u32, 0 << self.pin) };
+        unsafe { trace::modify32(self.port, |v| v & !(1 << self.pin)) };
     }
 
     fn toggle(&mut self) {
-        unsafe {
-            core::ptr::write_volatile(
-                self.port as *mut u32,
-                core::ptr::read_volatile(self.port as *mut u32) ^ (1 << self.pin),
-            );
-        }
+        unsafe { trace::modify32(self.port, |v| v ^ (1 << self.pin)) };
     }
 }
 
 /// Initialize GPIOs (default configuration)
 pub fn init_gpio() {
     // Example: Configure some default GPIO pins
-    let mut led1 = GPIO { port: 0x4800_0000 as u8, pin: 5 }; // Example: Port A, Pin 5
-    let mut led2 = GPIO { port: 0x4800_0000 as u8, pin: 6 }; // Example: Port A, Pin 6
-    let mut button = GPIO { port: 0x4800_0000 as u8, pin: 13 }; // Example: Port C, Pin 13
+    let mut led1 = GPIO { port: 0x4800_0014, pin: 5 }; // Example: Port A, Pin 5
+    let mut led2 = GPIO { port: 0x4800_0014, pin: 6 }; // Example: Port A, Pin 6
+    let mut button = GPIO { port: 0x4800_0814, pin: 13 }; // Example: Port C, Pin 13
 
     // Initialize default states
     led1.set_low();   // Turn off LED1
@@ -6,13 +6,23 @@
 //! URL: https://m-a-h-b-u-b.github.io
 //! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
 
+// If we are not running tests, compile this crate without the standard library (no_std).
+#![cfg_attr(not(test), no_std)]
+
 pub mod gpio;
 pub mod timer;
 pub mod bus;
+pub mod serial;
+pub mod ehal;
+pub mod trace;
+pub mod watch;
+pub mod bsp;
 
 /// Initialize HAL modules
+///
+/// Buses wrap board-specific drivers, so the BSP builds them itself with
+/// `bus::init_bus(spi, i2c)`.
 pub fn init_hal() {
     gpio::init_gpio();
     timer::init_timer();
-    bus::init_bus();
 }
\ No newline at end of file
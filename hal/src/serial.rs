@@ -0,0 +1,29 @@
+//! SecureIoTOS HAL Serial Module
+//! -----------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Byte-oriented serial port (UART) abstraction.
+
+/// Trait representing a serial port
+pub trait Serial {
+    /// Queue one byte for transmission, waiting for TX space if needed
+    fn write_byte(&mut self, byte: u8);
+
+    /// Take one received byte, or `None` if nothing is pending
+    fn read_byte(&mut self) -> Option<u8>;
+
+    /// Wait until every queued byte has left the transmitter
+    fn flush(&mut self) {}
+
+    /// Write all of `data`
+    fn write_all(&mut self, data: &[u8]) {
+        for &b in data {
+            self.write_byte(b);
+        }
+    }
+}
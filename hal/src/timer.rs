@@ -9,6 +9,11 @@
 //!
 //! Provides a simple and safe abstraction for hardware timers in SecureIoTOS.
 //! Supports starting, stopping, reading, and resetting timers.
+//!
+//! Also home of the hardware watchdogs (`HardwareWatchdog`, implemented for
+//! the STM32 `Iwdg` and `Wwdg`) that `kernel::watchdog` drives.
+
+use crate::trace;
 
 /// Basic Timer struct
 pub struct Timer {
@@ -65,6 +70,19 @@ impl Timer {
     }
 }
 
+/// Trait representing a blocking delay source
+pub trait Delay {
+    /// Wait for at least `us` microseconds
+    fn delay_us(&mut self, us: u32);
+
+    /// Wait for at least `ms` milliseconds
+    fn delay_ms(&mut self, ms: u32) {
+        for _ in 0..ms {
+            self.delay_us(1000);
+        }
+    }
+}
+
 /// Initialize system timers (placeholder)
 ///
 /// In production, this would set up system tick, hardware timers,
@@ -72,3 +90,145 @@ impl Timer {
 pub fn init_timer() {
     // TODO: Implement hardware-specific timer initialization
 }
+
+/// A hardware watchdog: resets the device unless fed in time. Once
+/// started it cannot be stopped.
+pub trait HardwareWatchdog {
+    /// Start with a timeout close to `timeout_ms` (clamped to what the
+    /// hardware supports). Returns the timeout actually programmed.
+    fn start(&mut self, timeout_ms: u32) -> u32;
+
+    /// Restart the countdown.
+    fn feed(&mut self);
+}
+
+/// Largest IWDG reload value (12 bits).
+pub const IWDG_MAX_RELOAD: u16 = 0x0FFF;
+
+/// IWDG prescaler setting and reload value for `timeout_ms` with an LSI
+/// of `lsi_hz`, and the timeout they give in ms. Picks the smallest
+/// prescaler (finest resolution) that reaches the timeout.
+pub fn iwdg_config(timeout_ms: u32, lsi_hz: u32) -> (u8, u16, u32) {
+    let cycles = u64::from(timeout_ms) * u64::from(lsi_hz) / 1000;
+    // PR = 0..=6 divides LSI by 4 << PR
+    let mut pr = 0u8;
+    while pr < 6 && cycles.div_ceil(4u64 << pr) > u64::from(IWDG_MAX_RELOAD) + 1 {
+        pr += 1;
+    }
+    let div = 4u64 << pr;
+    let counts = cycles.div_ceil(div).clamp(1, u64::from(IWDG_MAX_RELOAD) + 1);
+    let actual = (counts * div * 1000 / u64::from(lsi_hz.max(1))) as u32;
+    (pr, (counts - 1) as u16, actual)
+}
+
+/// STM32 independent watchdog (IWDG), clocked from the LSI oscillator so
+/// it keeps running if the main clock fails.
+pub struct Iwdg {
+    base: usize,
+    lsi_hz: u32,
+}
+
+impl Iwdg {
+    /// STM32F4 IWDG base address.
+    pub const STM32F4_BASE: usize = 0x4000_3000;
+
+    const KR: usize = 0x00;
+    const PR: usize = 0x04;
+    const RLR: usize = 0x08;
+    const SR: usize = 0x0C;
+    const KEY_RELOAD: u32 = 0xAAAA;
+    const KEY_UNLOCK: u32 = 0x5555;
+    const KEY_START: u32 = 0xCCCC;
+    /// SR: prescaler / reload update in progress
+    const SR_BUSY: u32 = 0b11;
+
+    /// `lsi_hz` is the nominal LSI frequency (about 32 kHz).
+    pub const fn new(base: usize, lsi_hz: u32) -> Self {
+        Self { base, lsi_hz }
+    }
+}
+
+impl HardwareWatchdog for Iwdg {
+    fn start(&mut self, timeout_ms: u32) -> u32 {
+        let (pr, reload, actual) = iwdg_config(timeout_ms, self.lsi_hz);
+        // SAFETY: `base` is the IWDG block; the key sequence is the
+        // documented way to start and configure it.
+        unsafe {
+            trace::write32(self.base + Self::KR, Self::KEY_START);
+            trace::write32(self.base + Self::KR, Self::KEY_UNLOCK);
+            trace::write32(self.base + Self::PR, u32::from(pr));
+            trace::write32(self.base + Self::RLR, u32::from(reload));
+            while trace::read32(self.base + Self::SR) & Self::SR_BUSY != 0 {}
+            trace::write32(self.base + Self::KR, Self::KEY_RELOAD);
+        }
+        actual
+    }
+
+    fn feed(&mut self) {
+        // SAFETY: writing the reload key only restarts the countdown.
+        unsafe { trace::write32(self.base + Self::KR, Self::KEY_RELOAD) };
+    }
+}
+
+/// STM32 window watchdog (WWDG), clocked from PCLK1. Run here with the
+/// window fully open (feeding is allowed at any time), so it acts as a
+/// short-timeout watchdog: at most 4096 * 8 * 64 PCLK1 cycles, about
+/// 50 ms at 42 MHz. Use `Iwdg` for longer timeouts.
+pub struct Wwdg {
+    base: usize,
+    pclk_hz: u32,
+    counter: u8,
+}
+
+impl Wwdg {
+    /// STM32F4 WWDG base address.
+    pub const STM32F4_BASE: usize = 0x4000_2C00;
+
+    const CR: usize = 0x00;
+    const CFR: usize = 0x04;
+    const CR_WDGA: u32 = 1 << 7;
+    /// Reset happens when the counter drops below this
+    const T_MIN: u32 = 0x40;
+    const T_MAX: u32 = 0x7F;
+
+    pub const fn new(base: usize, pclk_hz: u32) -> Self {
+        Self { base, pclk_hz, counter: Self::T_MAX as u8 }
+    }
+}
+
+impl HardwareWatchdog for Wwdg {
+    fn start(&mut self, timeout_ms: u32) -> u32 {
+        let per_count = |tb: u32| 4096u64 << tb;
+        let wanted = u64::from(timeout_ms) * u64::from(self.pclk_hz) / 1000;
+        let counts_max = u64::from(Self::T_MAX - Self::T_MIN + 1);
+        let tb = (0..3).find(|&tb| wanted.div_ceil(per_count(tb)) <= counts_max).unwrap_or(3);
+        let counts = wanted.div_ceil(per_count(tb)).clamp(1, counts_max);
+        self.counter = (u64::from(Self::T_MIN) + counts - 1) as u8;
+        // SAFETY: `base` is the WWDG block; window = T_MAX keeps it open.
+        unsafe {
+            trace::write32(self.base + Self::CFR, (tb << 7) | Self::T_MAX);
+            trace::write32(self.base + Self::CR, Self::CR_WDGA | u32::from(self.counter));
+        }
+        (counts * per_count(tb) * 1000 / u64::from(self.pclk_hz.max(1))) as u32
+    }
+
+    fn feed(&mut self) {
+        // SAFETY: reloading the counter with WDGA kept set.
+        unsafe { trace::write32(self.base + Self::CR, Self::CR_WDGA | u32::from(self.counter)) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn iwdg_config_picks_finest_prescaler() {
+        // 32 kHz LSI: /4 covers up to 512 ms
+        assert_eq!(iwdg_config(500, 32_000), (0, 3999, 500));
+        assert_eq!(iwdg_config(2_000, 32_000), (2, 3999, 2_000));
+        // Longest possible: /256 * 4096 = 32.768 s
+        assert_eq!(iwdg_config(60_000, 32_000), (6, IWDG_MAX_RELOAD, 32_768));
+        assert_eq!(iwdg_config(0, 32_000).1, 0, "at least one count");
+    }
+}
@@ -0,0 +1,224 @@
+//! SecureIoTOS HAL Register Trace Module
+//! -------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Volatile register access with optional tracing.
+//!
+//! Drivers should access peripheral registers through `read32()` /
+//! `write32()` / `modify32()` (and the 8/16-bit variants) instead of raw
+//! `core::ptr::read_volatile` / `write_volatile`. Without the `reg-trace`
+//! feature these compile down to the plain volatile access.
+//!
+//! With `reg-trace` enabled, every access that falls into one of the
+//! address ranges registered with `trace_range()` is appended to a small
+//! in-RAM trace buffer (address, value, width, direction, timestamp).
+//! Dumping the buffer on two boards and diffing the output is often enough
+//! to find the "works on board A, not board B" register sequence without a
+//! logic analyzer.
+
+use core::ptr::{read_volatile, write_volatile};
+
+/// Number of access records kept (oldest are overwritten).
+pub const TRACE_DEPTH: usize = 128;
+
+/// Maximum number of traced address ranges.
+pub const MAX_TRACE_RANGES: usize = 4;
+
+/// Direction of a traced access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// One traced register access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceRecord {
+    pub timestamp: u32,
+    pub addr: usize,
+    pub value: u32,
+    /// Access width in bytes (1, 2 or 4)
+    pub width: u8,
+    pub access: Access,
+}
+
+/// Read a 32-bit peripheral register.
+///
+/// # Safety
+/// `addr` must be a valid, aligned peripheral register address.
+#[inline(always)]
+pub unsafe fn read32(addr: usize) -> u32 {
+    let v = read_volatile(addr as *const u32);
+    record(Access::Read, addr, v, 4);
+    v
+}
+
+/// Write a 32-bit peripheral register.
+///
+/// # Safety
+/// `addr` must be a valid, aligned peripheral register address.
+#[inline(always)]
+pub unsafe fn write32(addr: usize, value: u32) {
+    record(Access::Write, addr, value, 4);
+    write_volatile(addr as *mut u32, value);
+}
+
+/// Read-modify-write a 32-bit peripheral register.
+///
+/// # Safety
+/// Same as `read32()` / `write32()`; not atomic with respect to interrupts.
+#[inline(always)]
+pub unsafe fn modify32(addr: usize, f: impl FnOnce(u32) -> u32) {
+    let v = read32(addr);
+    write32(addr, f(v));
+}
+
+/// Read a 16-bit peripheral register.
+///
+/// # Safety
+/// `addr` must be a valid, aligned peripheral register address.
+#[inline(always)]
+pub unsafe fn read16(addr: usize) -> u16 {
+    let v = read_volatile(addr as *const u16);
+    record(Access::Read, addr, v as u32, 2);
+    v
+}
+
+/// Write a 16-bit peripheral register.
+///
+/// # Safety
+/// `addr` must be a valid, aligned peripheral register address.
+#[inline(always)]
+pub unsafe fn write16(addr: usize, value: u16) {
+    record(Access::Write, addr, value as u32, 2);
+    write_volatile(addr as *mut u16, value);
+}
+
+/// Read an 8-bit peripheral register.
+///
+/// # Safety
+/// `addr` must be a valid peripheral register address.
+#[inline(always)]
+pub unsafe fn read8(addr: usize) -> u8 {
+    let v = read_volatile(addr as *const u8);
+    record(Access::Read, addr, v as u32, 1);
+    v
+}
+
+/// Write an 8-bit peripheral register.
+///
+/// # Safety
+/// `addr` must be a valid peripheral register address.
+#[inline(always)]
+pub unsafe fn write8(addr: usize, value: u8) {
+    record(Access::Write, addr, value as u32, 1);
+    write_volatile(addr as *mut u8, value);
+}
+
+#[cfg(not(feature = "reg-trace"))]
+#[inline(always)]
+fn record(_access: Access, _addr: usize, _value: u32, _width: u8) {}
+
+#[cfg(feature = "reg-trace")]
+pub use traced::*;
+
+#[cfg(feature = "reg-trace")]
+use traced::record;
+
+#[cfg(feature = "reg-trace")]
+mod traced {
+    use super::{Access, TraceRecord, MAX_TRACE_RANGES, TRACE_DEPTH};
+    use core::cell::RefCell;
+    use cortex_m::interrupt::{self, Mutex};
+
+    struct TraceState {
+        ranges: [Option<(usize, usize)>; MAX_TRACE_RANGES],
+        records: [Option<TraceRecord>; TRACE_DEPTH],
+        /// Next slot to write
+        head: usize,
+        /// Records overwritten before being drained
+        dropped: u32,
+        timestamp: Option<fn() -> u32>,
+    }
+
+    static TRACE: Mutex<RefCell<TraceState>> = Mutex::new(RefCell::new(TraceState {
+        ranges: [None; MAX_TRACE_RANGES],
+        records: [None; TRACE_DEPTH],
+        head: 0,
+        dropped: 0,
+        timestamp: None,
+    }));
+
+    /// Trace accesses to `[start, start + len)`.
+    ///
+    /// Returns `false` if all `MAX_TRACE_RANGES` slots are in use.
+    pub fn trace_range(start: usize, len: usize) -> bool {
+        interrupt::free(|cs| {
+            let mut t = TRACE.borrow(cs).borrow_mut();
+            match t.ranges.iter_mut().find(|r| r.is_none()) {
+                Some(slot) => {
+                    *slot = Some((start, start.saturating_add(len)));
+                    true
+                }
+                None => false,
+            }
+        })
+    }
+
+    /// Stop tracing all ranges (the buffer is kept).
+    pub fn clear_trace_ranges() {
+        interrupt::free(|cs| TRACE.borrow(cs).borrow_mut().ranges = [None; MAX_TRACE_RANGES]);
+    }
+
+    /// Install a timestamp source (e.g. the DWT cycle counter or a tick
+    /// counter). Records are stamped 0 until one is set.
+    pub fn set_trace_timestamp(source: fn() -> u32) {
+        interrupt::free(|cs| TRACE.borrow(cs).borrow_mut().timestamp = Some(source));
+    }
+
+    /// Hand every buffered record to `f`, oldest first, and empty the buffer.
+    /// Returns the number of records lost to overwrites since the last drain.
+    ///
+    /// `f` runs inside a critical section; copy records out rather than
+    /// doing slow I/O from it.
+    pub fn drain_trace(mut f: impl FnMut(&TraceRecord)) -> u32 {
+        interrupt::free(|cs| {
+            let mut t = TRACE.borrow(cs).borrow_mut();
+            let head = t.head;
+            for i in 0..TRACE_DEPTH {
+                let idx = (head + i) % TRACE_DEPTH;
+                if let Some(rec) = t.records[idx].take() {
+                    f(&rec);
+                }
+            }
+            t.head = 0;
+            core::mem::take(&mut t.dropped)
+        })
+    }
+
+    pub(super) fn record(access: Access, addr: usize, value: u32, width: u8) {
+        interrupt::free(|cs| {
+            let mut t = TRACE.borrow(cs).borrow_mut();
+            let traced = t
+                .ranges
+                .iter()
+                .flatten()
+                .any(|&(start, end)| addr >= start && addr < end);
+            if !traced {
+                return;
+            }
+            let timestamp = t.timestamp.map(|f| f()).unwrap_or(0);
+            let head = t.head;
+            if t.records[head].is_some() {
+                t.dropped = t.dropped.saturating_add(1);
+            }
+            t.records[head] = Some(TraceRecord { timestamp, addr, value, width, access });
+            t.head = (head + 1) % TRACE_DEPTH;
+        });
+    }
+}
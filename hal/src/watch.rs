@@ -0,0 +1,292 @@
+//! SecureIoTOS HAL Watchpoint Module
+//! ---------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Data corruption detector built on the DWT comparators (ARMv7-M).
+//!
+//! `watch()` points a free DWT comparator at a critical variable (active
+//! sector index, key status, scheduler state, ...) and arms it for writes.
+//! The owner of the variable makes its own writes with `expected_write()`,
+//! which disarms the comparator for the duration; any other write raises
+//! the DebugMonitor exception, which records a `WatchHit` with the name of
+//! the variable and the PC of the offending code. `drain_hits()` hands
+//! the records to telemetry or the log.
+//!
+//! Data watchpoints are imprecise: the recorded PC is that of the
+//! instruction after the write, or a few instructions later. Look just
+//! before it in the disassembly.
+//!
+//! Requires the `watchpoints` feature; without it `watch()` returns `None`
+//! and `expected_write()` just runs its closure, so call sites need no
+//! `cfg`. The DebugMonitor exception only fires while no debugger has
+//! halting debug enabled; with one attached, the core halts at the write.
+
+/// Number of comparators the module will use (the DWT may have fewer).
+pub const MAX_WATCHPOINTS: usize = 4;
+
+/// Number of hit records kept (oldest are overwritten).
+pub const MAX_HITS: usize = 8;
+
+/// An unexpected write to a watched variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    /// Name given to `watch()`
+    pub name: &'static str,
+    /// Start of the watched range
+    pub addr: usize,
+    /// Stacked PC at the DebugMonitor exception (shortly after the write)
+    pub pc: u32,
+}
+
+/// DWT MASK value watching `[addr, addr + len)`: the range must be a
+/// power of two in size and aligned to it (at most 32 KiB).
+fn mask_bits(addr: usize, len: usize) -> Option<u32> {
+    if !len.is_power_of_two() || len > 0x8000 || addr & (len - 1) != 0 {
+        return None;
+    }
+    Some(len.trailing_zeros())
+}
+
+#[cfg(not(feature = "watchpoints"))]
+mod imp {
+    use super::WatchHit;
+
+    pub fn watch(_name: &'static str, addr: usize, len: usize) -> Option<u8> {
+        super::mask_bits(addr, len)?;
+        None
+    }
+
+    pub fn unwatch(_addr: usize) {}
+
+    pub fn expected_write<R>(_addr: usize, f: impl FnOnce() -> R) -> R {
+        f()
+    }
+
+    pub fn drain_hits(_f: impl FnMut(&WatchHit)) -> u32 {
+        0
+    }
+}
+
+#[cfg(feature = "watchpoints")]
+// The DebugMonitor entry, the only user of the hit path, is target-only.
+#[cfg_attr(not(target_arch = "arm"), allow(dead_code))]
+mod imp {
+    use super::{mask_bits, WatchHit, MAX_HITS, MAX_WATCHPOINTS};
+    use crate::trace::{read32, write32};
+    use core::cell::RefCell;
+    use cortex_m::interrupt::{self, Mutex};
+
+    const DEMCR: usize = 0xE000_EDFC;
+    const DEMCR_TRCENA: u32 = 1 << 24;
+    const DEMCR_MON_EN: u32 = 1 << 16;
+
+    const DWT_CTRL: usize = 0xE000_1000;
+    const DWT_COMP0: usize = 0xE000_1020;
+    const DWT_COMP_STRIDE: usize = 0x10;
+    const MASK_OFFSET: usize = 0x4;
+    const FUNCTION_OFFSET: usize = 0x8;
+
+    /// FUNCTION: data address watchpoint on write
+    const FUNCTION_WRITE: u32 = 0b0110;
+    const FUNCTION_MATCHED: u32 = 1 << 24;
+
+    #[derive(Clone, Copy)]
+    struct Watch {
+        name: &'static str,
+        addr: usize,
+    }
+
+    struct WatchState {
+        watches: [Option<Watch>; MAX_WATCHPOINTS],
+        hits: [Option<WatchHit>; MAX_HITS],
+        /// Next hit slot to write
+        head: usize,
+        /// Hits overwritten before being drained
+        dropped: u32,
+    }
+
+    static WATCH: Mutex<RefCell<WatchState>> = Mutex::new(RefCell::new(WatchState {
+        watches: [None; MAX_WATCHPOINTS],
+        hits: [None; MAX_HITS],
+        head: 0,
+        dropped: 0,
+    }));
+
+    fn comp(n: usize) -> usize {
+        DWT_COMP0 + n * DWT_COMP_STRIDE
+    }
+
+    /// Comparators implemented by this DWT, capped at `MAX_WATCHPOINTS`.
+    fn comparators() -> usize {
+        // SAFETY: DWT_CTRL is always readable once TRCENA is set.
+        let numcomp = unsafe { read32(DWT_CTRL) } >> 28;
+        (numcomp as usize).min(MAX_WATCHPOINTS)
+    }
+
+    pub fn watch(name: &'static str, addr: usize, len: usize) -> Option<u8> {
+        let mask = mask_bits(addr, len)?;
+        interrupt::free(|cs| {
+            // SAFETY: DEMCR is a core debug register; enabling the DWT and
+            // the debug monitor has no other effect.
+            unsafe { crate::trace::modify32(DEMCR, |v| v | DEMCR_TRCENA | DEMCR_MON_EN) };
+            let mut st = WATCH.borrow(cs).borrow_mut();
+            let n = (0..comparators()).find(|&n| st.watches[n].is_none())?;
+            st.watches[n] = Some(Watch { name, addr });
+            // SAFETY: comparator `n` exists and is ours.
+            unsafe {
+                write32(comp(n), addr as u32);
+                write32(comp(n) + MASK_OFFSET, mask);
+                write32(comp(n) + FUNCTION_OFFSET, FUNCTION_WRITE);
+            }
+            Some(n as u8)
+        })
+    }
+
+    pub fn unwatch(addr: usize) {
+        interrupt::free(|cs| {
+            let mut st = WATCH.borrow(cs).borrow_mut();
+            for n in 0..MAX_WATCHPOINTS {
+                if st.watches[n].is_some_and(|w| w.addr == addr) {
+                    st.watches[n] = None;
+                    // SAFETY: comparator `n` was programmed by `watch()`.
+                    unsafe { write32(comp(n) + FUNCTION_OFFSET, 0) };
+                }
+            }
+        });
+    }
+
+    pub fn expected_write<R>(addr: usize, f: impl FnOnce() -> R) -> R {
+        interrupt::free(|cs| {
+            let n = WATCH.borrow(cs).borrow().watches.iter().position(|w| w.is_some_and(|w| w.addr == addr));
+            let Some(n) = n else {
+                return f();
+            };
+            // SAFETY: comparator `n` was programmed by `watch()`; it is
+            // re-armed before interrupts can run again.
+            unsafe { write32(comp(n) + FUNCTION_OFFSET, 0) };
+            let r = f();
+            // SAFETY: as above.
+            unsafe {
+                // Reading FUNCTION clears a stale MATCHED flag.
+                let _ = read32(comp(n) + FUNCTION_OFFSET);
+                write32(comp(n) + FUNCTION_OFFSET, FUNCTION_WRITE);
+            }
+            r
+        })
+    }
+
+    pub fn drain_hits(mut f: impl FnMut(&WatchHit)) -> u32 {
+        interrupt::free(|cs| {
+            let mut st = WATCH.borrow(cs).borrow_mut();
+            let head = st.head;
+            for i in 0..MAX_HITS {
+                if let Some(hit) = st.hits[(head + i) % MAX_HITS].take() {
+                    f(&hit);
+                }
+            }
+            st.head = 0;
+            core::mem::take(&mut st.dropped)
+        })
+    }
+
+    /// Record a hit for every comparator that matched. `frame` is the
+    /// exception frame of the interrupted code.
+    extern "C" fn on_debug_monitor(frame: *const u32) {
+        // SAFETY: `frame` is the hardware-stacked frame; word 6 is PC.
+        let pc = unsafe { frame.add(6).read_volatile() };
+        interrupt::free(|cs| {
+            let mut st = WATCH.borrow(cs).borrow_mut();
+            for n in 0..MAX_WATCHPOINTS {
+                let Some(w) = st.watches[n] else { continue };
+                // SAFETY: comparator `n` is ours; the read clears MATCHED.
+                if unsafe { read32(comp(n) + FUNCTION_OFFSET) } & FUNCTION_MATCHED == 0 {
+                    continue;
+                }
+                let head = st.head;
+                if st.hits[head].is_some() {
+                    st.dropped = st.dropped.saturating_add(1);
+                }
+                st.hits[head] = Some(WatchHit { name: w.name, addr: w.addr, pc });
+                st.head = (head + 1) % MAX_HITS;
+            }
+        });
+    }
+
+    /// DebugMonitor exception: find the stacked frame (MSP or PSP, per
+    /// EXC_RETURN bit 2) and record the hit.
+    ///
+    /// # Safety
+    /// Only the exception mechanism may call this.
+    #[cfg(target_arch = "arm")]
+    #[unsafe(naked)]
+    #[no_mangle]
+    pub unsafe extern "C" fn DebugMonitor() {
+        core::arch::naked_asm!(
+            "tst lr, #4",
+            "ite eq",
+            "mrseq r0, msp",
+            "mrsne r0, psp",
+            "b {handler}",
+            handler = sym on_debug_monitor,
+        );
+    }
+}
+
+/// Watch `[addr, addr + len)` for unexpected writes; `name` identifies it
+/// in hit records. The range must be a power of two in size and aligned
+/// to it. Returns the comparator used, or `None` if the range is invalid,
+/// all comparators are taken, or the feature is off.
+pub fn watch(name: &'static str, addr: usize, len: usize) -> Option<u8> {
+    imp::watch(name, addr, len)
+}
+
+/// Watch a variable for unexpected writes (see `watch()`).
+pub fn watch_var<T>(name: &'static str, var: *const T) -> Option<u8> {
+    watch(name, var as usize, core::mem::size_of::<T>())
+}
+
+/// Stop watching the range starting at `addr`.
+pub fn unwatch(addr: usize) {
+    imp::unwatch(addr)
+}
+
+/// Run `f`, a legitimate write to the watched range starting at `addr`,
+/// with its comparator disarmed. Runs with interrupts disabled.
+pub fn expected_write<R>(addr: usize, f: impl FnOnce() -> R) -> R {
+    imp::expected_write(addr, f)
+}
+
+/// Hand every recorded hit to `f`, oldest first, and clear them. Returns
+/// the number of hits lost to overwrites since the last drain.
+pub fn drain_hits(f: impl FnMut(&WatchHit)) -> u32 {
+    imp::drain_hits(f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ranges_must_be_aligned_powers_of_two() {
+        assert_eq!(mask_bits(0x2000_0010, 4), Some(2));
+        assert_eq!(mask_bits(0x2000_0000, 0x8000), Some(15));
+        assert_eq!(mask_bits(0x2000_0002, 4), None, "misaligned");
+        assert_eq!(mask_bits(0x2000_0000, 12), None, "not a power of two");
+        assert_eq!(mask_bits(0x2000_0000, 0), None);
+        assert_eq!(mask_bits(0x2000_0000, 0x1_0000), None, "too large");
+    }
+
+    #[test]
+    #[cfg(not(feature = "watchpoints"))]
+    fn unwatched_writes_just_run() {
+        let mut x = 0;
+        expected_write(&x as *const i32 as usize, || x = 5);
+        assert_eq!(x, 5);
+        assert_eq!(drain_hits(|_| {}), 0);
+    }
+}
@@ -11,26 +11,26 @@
 //! and a configurable greeting function for SecureIoTOS.
 
 
-use log::{info, warn};
+use sios_log::{info, warn};
 
 /// Logs a simple Hello World message for SecureIoTOS.
 pub fn hello_world() {
     info!("Hello, SecureIoTOS!");
 }
 
+// name: Option<&str> – The parameter is an Option that may contain a 
+// string slice (&str) or be None.
+// Some(&str) → caller passed a name.
+// None → caller passed no name.
+
 /// Logs a personalized greeting if a name is provided, otherwise defaults to [`hello_world`].
 ///
 /// # Examples
 /// ```
-/// use secure_iotos::hello::greet;
+/// use iot_app_examples::hello::greet;
 /// greet(Some("Alice"));
 /// greet(None);
 /// ```
-
-// name: Option<&str> – The parameter is an Option that may contain a 
-// string slice (&str) or be None.
-// Some(&str) → caller passed a name.
-// None → caller passed no name.
 pub fn greet(name: Option<&str>) {
 	// match is Rust’s powerful pattern-matching construct.
     match name {
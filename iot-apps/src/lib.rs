@@ -15,8 +15,12 @@
 pub mod hello;
 pub mod sensor;
 pub mod telemetry;
+pub mod schema;
+pub mod qos;
+pub mod sim;
+pub mod pipeline;
 
-use log::{info, error};
+use sios_log::{error, info};
 
 /// Run a demonstration of IoT sensor + telemetry pipeline
 pub async fn run_demo() -> Result<(), &'static str> {
@@ -25,9 +29,9 @@ pub async fn run_demo() -> Result<(), &'static str> {
 
     // Step 2: Sensor read + secure send
     info!("Starting sensor demo...");
-    match sensor::read_sensor() {
-        Ok(temp) => {
-            sensor::send_sensor_data(&temp)?;
+    match sensor::collect_sensor_data(&sensor::TemperatureSensor) {
+        Ok(reading) => {
+            sensor::send_sensor_data(&reading)?;
         }
         Err(e) => {
             error!("Sensor read failed: {}", e);
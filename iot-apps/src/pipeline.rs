@@ -0,0 +1,401 @@
+//! SecureIoTOS IoTApps Telemetry Pipeline Module
+//! ---------------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Pull-based collect → encode → encrypt → transmit pipeline with bounded
+//! queues and explicit backpressure.
+//!
+//! Each stage only takes work from the stage before it when its own output
+//! queue has room, starting from the uplink: frames are transmitted only
+//! while the [`Uplink`] reports it is ready, sealed frames are only
+//! produced while the sealed queue has room, and so on back to the sample
+//! queue. A slow uplink therefore fills the queues from the end, and the
+//! sample queue's fill level becomes the [`Backpressure`] signal the
+//! sampler acts on according to its [`ThrottlePolicy`]. Nothing is ever
+//! queued beyond the configured depths, so a stalled link costs a fixed
+//! amount of RAM instead of growing until the OOM handler fires.
+
+use crate::telemetry::{self, TelemetryData};
+use sios_log::{debug, warn, Dbg};
+use std::collections::VecDeque;
+
+/// What the sampler does when the pipeline backs up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThrottlePolicy {
+    /// Skip samples while the sample queue is full
+    Pause,
+    /// Double the sampling interval (up to `max_interval_ms`) while
+    /// pressure is high, and skip samples while the queue is full; back to
+    /// the base interval once pressure clears
+    Stretch,
+    /// Keep sampling and drop the oldest queued sample to make room
+    DropOldest,
+}
+
+/// Fill level of the sample queue, as seen by the sampler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Backpressure {
+    /// Below three quarters full
+    Clear,
+    /// At least three quarters full
+    High,
+    /// No room for another sample
+    Full,
+}
+
+/// Pipeline tuning.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipelineConfig {
+    /// Depth of the collect → encode queue
+    pub samples: usize,
+    /// Depth of the encode → encrypt queue
+    pub encoded: usize,
+    /// Depth of the encrypt → transmit queue
+    pub sealed: usize,
+    /// Sampling interval with no backpressure
+    pub interval_ms: u64,
+    /// Longest interval `ThrottlePolicy::Stretch` backs off to
+    pub max_interval_ms: u64,
+    pub policy: ThrottlePolicy,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            samples: 8,
+            encoded: 2,
+            sealed: 4,
+            interval_ms: 1_000,
+            max_interval_ms: 60_000,
+            policy: ThrottlePolicy::Stretch,
+        }
+    }
+}
+
+/// The transmit end of the pipeline (MQTT/CoAP/HTTPS client).
+pub trait Uplink {
+    /// Whether the link can take another frame now.
+    fn ready(&self) -> bool;
+    /// Send one sealed frame. On error the frame is kept and retried.
+    fn send(&mut self, frame: &str) -> Result<(), &'static str>;
+}
+
+/// Pipeline counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PipelineStats {
+    /// Samples taken
+    pub sampled: u64,
+    /// Samples not taken because of backpressure
+    pub skipped: u64,
+    /// Queued samples dropped under `ThrottlePolicy::DropOldest`
+    pub dropped: u64,
+    /// Samples lost to encode/encrypt errors
+    pub failed: u64,
+    /// Frames handed to the uplink
+    pub sent: u64,
+    /// Uplink send errors (frame retried)
+    pub send_errors: u64,
+}
+
+/// FIFO that never holds more than `cap` items.
+struct Bounded<T> {
+    items: VecDeque<T>,
+    cap: usize,
+}
+
+impl<T> Bounded<T> {
+    fn new(cap: usize) -> Self {
+        let cap = cap.max(1);
+        Self { items: VecDeque::with_capacity(cap), cap }
+    }
+
+    fn has_room(&self) -> bool {
+        self.items.len() < self.cap
+    }
+}
+
+/// Telemetry pipeline driven by [`TelemetryPipeline::poll`].
+pub struct TelemetryPipeline<U: Uplink> {
+    config: PipelineConfig,
+    key: [u8; 32],
+    uplink: U,
+    samples: Bounded<TelemetryData>,
+    encoded: Bounded<Vec<u8>>,
+    sealed: Bounded<String>,
+    interval_ms: u64,
+    next_sample_ms: u64,
+    stats: PipelineStats,
+}
+
+impl<U: Uplink> TelemetryPipeline<U> {
+    /// `key` is the AES-256-GCM telemetry key. The first sample is due
+    /// immediately.
+    pub fn new(config: PipelineConfig, key: [u8; 32], uplink: U) -> Self {
+        Self {
+            samples: Bounded::new(config.samples),
+            encoded: Bounded::new(config.encoded),
+            sealed: Bounded::new(config.sealed),
+            interval_ms: config.interval_ms,
+            next_sample_ms: 0,
+            stats: PipelineStats::default(),
+            config,
+            key,
+            uplink,
+        }
+    }
+
+    pub fn stats(&self) -> PipelineStats {
+        self.stats
+    }
+
+    pub fn uplink(&self) -> &U {
+        &self.uplink
+    }
+
+    pub fn uplink_mut(&mut self) -> &mut U {
+        &mut self.uplink
+    }
+
+    /// Current sampling interval (grows under `ThrottlePolicy::Stretch`).
+    pub fn interval_ms(&self) -> u64 {
+        self.interval_ms
+    }
+
+    /// Items waiting in the sample, encoded and sealed queues.
+    pub fn queued(&self) -> (usize, usize, usize) {
+        (self.samples.items.len(), self.encoded.items.len(), self.sealed.items.len())
+    }
+
+    /// Backpressure seen by the sampler.
+    pub fn backpressure(&self) -> Backpressure {
+        let len = self.samples.items.len();
+        if !self.samples.has_room() {
+            Backpressure::Full
+        } else if len * 4 >= self.samples.cap * 3 {
+            Backpressure::High
+        } else {
+            Backpressure::Clear
+        }
+    }
+
+    /// Run one pipeline step at `now_ms`: move work downstream, take a
+    /// sample through `collect` if one is due and the policy allows it,
+    /// and move it on as far as there is room. Returns when the next
+    /// sample is due. A failed `collect` is reported after the next sample
+    /// has been scheduled.
+    pub fn poll<F>(&mut self, now_ms: u64, collect: F) -> Result<u64, &'static str>
+    where
+        F: FnOnce() -> Result<TelemetryData, &'static str>,
+    {
+        self.pump();
+        let mut result = Ok(());
+        if now_ms >= self.next_sample_ms {
+            if self.admit_sample() {
+                result = collect().map(|data| {
+                    self.samples.items.push_back(data);
+                    self.stats.sampled += 1;
+                });
+            }
+            self.next_sample_ms = now_ms.saturating_add(self.interval_ms);
+            self.pump();
+        }
+        result.map(|()| self.next_sample_ms)
+    }
+
+    /// Apply the throttle policy; `true` if a sample may be taken now.
+    fn admit_sample(&mut self) -> bool {
+        let pressure = self.backpressure();
+        if self.config.policy == ThrottlePolicy::Stretch {
+            self.interval_ms = match pressure {
+                Backpressure::Clear => self.config.interval_ms,
+                _ => self.interval_ms.saturating_mul(2).min(self.config.max_interval_ms.max(self.config.interval_ms)),
+            };
+        }
+        if pressure != Backpressure::Full {
+            return true;
+        }
+        match self.config.policy {
+            ThrottlePolicy::DropOldest => {
+                self.samples.items.pop_front();
+                self.stats.dropped += 1;
+                true
+            }
+            ThrottlePolicy::Pause | ThrottlePolicy::Stretch => {
+                debug!("Telemetry backpressure: sample skipped ({:?})", Dbg(&self.config.policy));
+                self.stats.skipped += 1;
+                false
+            }
+        }
+    }
+
+    /// Pull work through the stages, starting at the uplink. Returns the
+    /// number of frames sent.
+    pub fn pump(&mut self) -> usize {
+        let sent_before = self.stats.sent;
+        loop {
+            let moved = self.transmit() | self.encrypt() | self.encode();
+            if !moved {
+                break;
+            }
+        }
+        (self.stats.sent - sent_before) as usize
+    }
+
+    fn transmit(&mut self) -> bool {
+        let mut moved = false;
+        while self.uplink.ready() {
+            let Some(frame) = self.sealed.items.front() else { break };
+            if let Err(e) = self.uplink.send(frame) {
+                warn!("Telemetry uplink send failed: {}", e);
+                self.stats.send_errors += 1;
+                break;
+            }
+            self.sealed.items.pop_front();
+            self.stats.sent += 1;
+            moved = true;
+        }
+        moved
+    }
+
+    fn encrypt(&mut self) -> bool {
+        let mut moved = false;
+        while self.sealed.has_room() {
+            let Some(payload) = self.encoded.items.pop_front() else { break };
+            match telemetry::seal(&payload, &self.key) {
+                Ok(frame) => self.sealed.items.push_back(frame),
+                Err(_) => self.stats.failed += 1,
+            }
+            moved = true;
+        }
+        moved
+    }
+
+    fn encode(&mut self) -> bool {
+        let mut moved = false;
+        while self.encoded.has_room() {
+            let Some(data) = self.samples.items.pop_front() else { break };
+            match serde_json::to_vec(&data) {
+                Ok(payload) => self.encoded.items.push_back(payload),
+                Err(_) => {
+                    warn!("Telemetry serialization failed");
+                    self.stats.failed += 1;
+                }
+            }
+            moved = true;
+        }
+        moved
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Uplink that accepts `budget` frames, then reports not ready.
+    #[derive(Default)]
+    struct Link {
+        budget: usize,
+        fail: bool,
+        frames: Vec<String>,
+    }
+
+    impl Uplink for Link {
+        fn ready(&self) -> bool {
+            self.budget > 0
+        }
+        fn send(&mut self, frame: &str) -> Result<(), &'static str> {
+            if self.fail {
+                return Err("link down");
+            }
+            self.budget -= 1;
+            self.frames.push(frame.to_string());
+            Ok(())
+        }
+    }
+
+    fn reading() -> Result<TelemetryData, &'static str> {
+        Ok(TelemetryData { temperature: 21.5, humidity: 40.0, build_id: String::new() })
+    }
+
+    fn pipeline(policy: ThrottlePolicy) -> TelemetryPipeline<Link> {
+        let config = PipelineConfig {
+            samples: 4,
+            encoded: 1,
+            sealed: 2,
+            interval_ms: 100,
+            max_interval_ms: 800,
+            policy,
+        };
+        TelemetryPipeline::new(config, [7; 32], Link::default())
+    }
+
+    fn run(p: &mut TelemetryPipeline<Link>, from_ms: u64, steps: u64) {
+        for i in 0..steps {
+            let _ = p.poll(from_ms + i * 100, reading);
+        }
+    }
+
+    #[test]
+    fn stalled_uplink_bounds_queues_and_pauses_sampling() {
+        let mut p = pipeline(ThrottlePolicy::Pause);
+        run(&mut p, 0, 20);
+        // sealed 2 + encoded 1 + samples 4 and nothing more
+        assert_eq!(p.queued(), (4, 1, 2));
+        assert_eq!(p.backpressure(), Backpressure::Full);
+        assert_eq!(p.stats().sampled, 7);
+        assert_eq!(p.stats().skipped, 13);
+
+        // Link comes back: everything drains in order and sampling resumes
+        p.uplink_mut().budget = 100;
+        assert_eq!(p.pump(), 7);
+        assert_eq!(p.queued(), (0, 0, 0));
+        assert_eq!(p.backpressure(), Backpressure::Clear);
+        run(&mut p, 2_000, 1);
+        assert_eq!(p.stats().sampled, 8);
+        assert_eq!(p.uplink().frames.len(), 8);
+    }
+
+    #[test]
+    fn stretch_backs_off_and_recovers() {
+        let mut p = pipeline(ThrottlePolicy::Stretch);
+        let mut now = 0;
+        for _ in 0..10 {
+            now = p.poll(now, reading).unwrap();
+        }
+        assert_eq!(p.interval_ms(), 800, "capped at max_interval_ms");
+        assert_eq!(p.queued().0, 4);
+
+        p.uplink_mut().budget = 100;
+        p.poll(now, reading).unwrap();
+        assert_eq!(p.interval_ms(), 100);
+    }
+
+    #[test]
+    fn drop_oldest_keeps_newest_samples() {
+        let mut p = pipeline(ThrottlePolicy::DropOldest);
+        run(&mut p, 0, 10);
+        assert_eq!(p.stats().sampled, 10);
+        assert_eq!(p.stats().dropped, 3);
+        assert_eq!(p.queued(), (4, 1, 2));
+    }
+
+    #[test]
+    fn failed_send_keeps_frame() {
+        let mut p = pipeline(ThrottlePolicy::Pause);
+        p.uplink_mut().budget = 1;
+        p.uplink_mut().fail = true;
+        run(&mut p, 0, 1);
+        assert_eq!(p.stats().send_errors, 1);
+        assert_eq!(p.queued().2, 1);
+
+        p.uplink_mut().fail = false;
+        assert_eq!(p.pump(), 1);
+        let frame = &p.uplink().frames[0];
+        assert!(frame.len() > 16, "nonce + ciphertext, base64");
+    }
+}
@@ -0,0 +1,129 @@
+//! SecureIoTOS IoTApps QoS Mapping Module
+//! --------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Maps telemetry priority classes to transport QoS.
+//!
+//! Applications tag each telemetry event with a [`Priority`]; the
+//! [`QosMap`] turns that into an MQTT QoS level and a CoAP message type, so
+//! critical alerts are delivered reliably while bulk sensor data stays cheap
+//! on bandwidth and battery. The map is `serde`-configurable, so a
+//! deployment can tighten or relax it without touching application code.
+
+use serde::{Serialize, Deserialize};
+
+/// Priority class of a telemetry event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    /// Safety/security alerts: must arrive, exactly once if possible
+    Critical,
+    /// State changes the backend must see
+    High,
+    /// Regular periodic readings
+    Normal,
+    /// Bulk/batched data where loss is acceptable
+    Bulk,
+}
+
+/// MQTT delivery guarantee (numeric values match the MQTT spec).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MqttQos {
+    AtMostOnce = 0,
+    AtLeastOnce = 1,
+    ExactlyOnce = 2,
+}
+
+/// CoAP message type used for the request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CoapMessageType {
+    /// CON: acknowledged and retransmitted until ACKed
+    Confirmable,
+    /// NON: fire-and-forget
+    NonConfirmable,
+}
+
+/// Transport settings resolved for one priority class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransportQos {
+    pub mqtt: MqttQos,
+    pub coap: CoapMessageType,
+}
+
+/// Priority → transport QoS table.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QosMap {
+    pub critical: TransportQos,
+    pub high: TransportQos,
+    pub normal: TransportQos,
+    pub bulk: TransportQos,
+}
+
+impl Default for QosMap {
+    fn default() -> Self {
+        let reliable = TransportQos { mqtt: MqttQos::AtLeastOnce, coap: CoapMessageType::Confirmable };
+        let cheap = TransportQos { mqtt: MqttQos::AtMostOnce, coap: CoapMessageType::NonConfirmable };
+        Self {
+            critical: TransportQos { mqtt: MqttQos::ExactlyOnce, coap: CoapMessageType::Confirmable },
+            high: reliable,
+            normal: cheap,
+            bulk: cheap,
+        }
+    }
+}
+
+impl QosMap {
+    /// Transport settings for `priority`.
+    pub fn resolve(&self, priority: Priority) -> TransportQos {
+        match priority {
+            Priority::Critical => self.critical,
+            Priority::High => self.high,
+            Priority::Normal => self.normal,
+            Priority::Bulk => self.bulk,
+        }
+    }
+}
+
+/// A telemetry payload tagged with its priority class.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TelemetryEvent<T> {
+    pub priority: Priority,
+    pub data: T,
+}
+
+impl<T> TelemetryEvent<T> {
+    pub fn new(priority: Priority, data: T) -> Self {
+        Self { priority, data }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_map_favours_reliability_for_alerts() {
+        let map = QosMap::default();
+        assert_eq!(map.resolve(Priority::Critical).mqtt, MqttQos::ExactlyOnce);
+        assert_eq!(map.resolve(Priority::High).coap, CoapMessageType::Confirmable);
+        assert_eq!(map.resolve(Priority::Bulk).mqtt as u8, 0);
+        assert_eq!(map.resolve(Priority::Normal).coap, CoapMessageType::NonConfirmable);
+    }
+
+    #[test]
+    fn map_is_configurable() {
+        let map: QosMap = serde_json::from_str(
+            r#"{ "normal": { "mqtt": "AtLeastOnce", "coap": "Confirmable" } }"#,
+        )
+        .unwrap();
+        assert_eq!(map.resolve(Priority::Normal).mqtt, MqttQos::AtLeastOnce);
+        // Unspecified classes keep their defaults
+        assert_eq!(map.resolve(Priority::Critical), QosMap::default().critical);
+    }
+}
@@ -0,0 +1,181 @@
+//! SecureIoTOS IoTApps Schema Registry Module
+//! ------------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Message schema registry and versioned payload migration.
+//!
+//! Every telemetry/command payload is wrapped in a [`VersionedPayload`]
+//! carrying a schema ID and version. Applications register migration
+//! functions between adjacent versions (both upgrades and downgrades), and
+//! the registry walks the chain so an old device can talk to a new backend
+//! and vice versa while the fleet is mid-rollout.
+
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use sios_log::debug;
+
+/// Numeric schema identifier (stable across versions).
+pub type SchemaId = u16;
+
+/// Well-known schema IDs used by the bundled applications.
+pub mod ids {
+    use super::SchemaId;
+    pub const SENSOR_DATA: SchemaId = 1;
+    pub const TELEMETRY: SchemaId = 2;
+    pub const COMMAND: SchemaId = 3;
+}
+
+/// Payload envelope carrying schema metadata alongside the body.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VersionedPayload {
+    pub schema_id: SchemaId,
+    pub version: u16,
+    pub body: Value,
+}
+
+impl VersionedPayload {
+    /// Wrap any serializable value as version `version` of `schema_id`.
+    pub fn wrap<T: Serialize>(schema_id: SchemaId, version: u16, value: &T) -> Result<Self, &'static str> {
+        let body = serde_json::to_value(value).map_err(|_| "Serialization error")?;
+        Ok(Self { schema_id, version, body })
+    }
+
+    /// Decode the body into a concrete type.
+    pub fn unwrap_into<T: for<'de> Deserialize<'de>>(&self) -> Result<T, &'static str> {
+        serde_json::from_value(self.body.clone()).map_err(|_| "Deserialization error")
+    }
+}
+
+/// A single migration step between two adjacent versions.
+pub type MigrationFn = fn(Value) -> Result<Value, &'static str>;
+
+/// Registry of known schemas and their migration steps.
+#[derive(Default)]
+pub struct SchemaRegistry {
+    /// schema_id → latest version this build understands
+    current: HashMap<SchemaId, u16>,
+    /// (schema_id, from_version, to_version) → migration
+    steps: HashMap<(SchemaId, u16, u16), MigrationFn>,
+}
+
+impl SchemaRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declare the version of `schema_id` that this firmware produces natively.
+    pub fn register_schema(&mut self, schema_id: SchemaId, current_version: u16) {
+        self.current.insert(schema_id, current_version);
+    }
+
+    /// Register an upgrade step `from → from + 1`.
+    ///
+    /// Fails if `from` is the last representable version.
+    pub fn register_upgrade(&mut self, schema_id: SchemaId, from: u16, f: MigrationFn) -> Result<(), &'static str> {
+        let to = from.checked_add(1).ok_or("No version above the last one")?;
+        self.steps.insert((schema_id, from, to), f);
+        Ok(())
+    }
+
+    /// Register a downgrade step `from → from - 1` (for talking to older peers).
+    ///
+    /// Fails if `from` is version 0.
+    pub fn register_downgrade(&mut self, schema_id: SchemaId, from: u16, f: MigrationFn) -> Result<(), &'static str> {
+        let to = from.checked_sub(1).ok_or("No version below version 0")?;
+        self.steps.insert((schema_id, from, to), f);
+        Ok(())
+    }
+
+    /// Latest native version for a schema, if registered.
+    pub fn current_version(&self, schema_id: SchemaId) -> Option<u16> {
+        self.current.get(&schema_id).copied()
+    }
+
+    /// Migrate `payload` to `target` version, one step at a time.
+    ///
+    /// Fails if any step in the chain is missing.
+    pub fn migrate(&self, mut payload: VersionedPayload, target: u16) -> Result<VersionedPayload, &'static str> {
+        if !self.current.contains_key(&payload.schema_id) {
+            return Err("Unknown schema");
+        }
+
+        while payload.version != target {
+            let next = if payload.version < target { payload.version + 1 } else { payload.version - 1 };
+            let step = self
+                .steps
+                .get(&(payload.schema_id, payload.version, next))
+                .ok_or("Missing migration step")?;
+
+            debug!("Migrating schema {} v{} -> v{}", payload.schema_id, payload.version, next);
+            payload.body = step(payload.body)?;
+            payload.version = next;
+        }
+        Ok(payload)
+    }
+
+    /// Convenience: bring an incoming payload up (or down) to our native version.
+    pub fn migrate_to_current(&self, payload: VersionedPayload) -> Result<VersionedPayload, &'static str> {
+        let target = self.current_version(payload.schema_id).ok_or("Unknown schema")?;
+        self.migrate(payload, target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // v1: {"temp": f}  →  v2: {"temperature": f, "unit": "C"}
+    fn up_1_to_2(mut v: Value) -> Result<Value, &'static str> {
+        let t = v.get("temp").cloned().ok_or("missing temp")?;
+        v = json!({ "temperature": t, "unit": "C" });
+        Ok(v)
+    }
+
+    fn down_2_to_1(v: Value) -> Result<Value, &'static str> {
+        let t = v.get("temperature").cloned().ok_or("missing temperature")?;
+        Ok(json!({ "temp": t }))
+    }
+
+    fn registry() -> SchemaRegistry {
+        let mut r = SchemaRegistry::new();
+        r.register_schema(ids::SENSOR_DATA, 2);
+        r.register_upgrade(ids::SENSOR_DATA, 1, up_1_to_2).unwrap();
+        r.register_downgrade(ids::SENSOR_DATA, 2, down_2_to_1).unwrap();
+        r
+    }
+
+    #[test]
+    fn upgrades_old_payload() {
+        let old = VersionedPayload { schema_id: ids::SENSOR_DATA, version: 1, body: json!({ "temp": 21.5 }) };
+        let new = registry().migrate_to_current(old).unwrap();
+        assert_eq!(new.version, 2);
+        assert_eq!(new.body["temperature"], json!(21.5));
+    }
+
+    #[test]
+    fn downgrades_for_old_backend() {
+        let new = VersionedPayload { schema_id: ids::SENSOR_DATA, version: 2, body: json!({ "temperature": 3.0, "unit": "C" }) };
+        let old = registry().migrate(new, 1).unwrap();
+        assert_eq!(old.body, json!({ "temp": 3.0 }));
+    }
+
+    #[test]
+    fn missing_step_is_an_error() {
+        let p = VersionedPayload { schema_id: ids::SENSOR_DATA, version: 0, body: json!({}) };
+        assert!(registry().migrate_to_current(p).is_err());
+    }
+
+    #[test]
+    fn refuses_steps_past_the_version_range() {
+        let mut r = registry();
+        assert!(r.register_upgrade(ids::SENSOR_DATA, u16::MAX, up_1_to_2).is_err());
+        assert!(r.register_downgrade(ids::SENSOR_DATA, 0, down_2_to_1).is_err());
+    }
+}
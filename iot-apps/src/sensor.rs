@@ -8,7 +8,7 @@
 //! Provides a unified interface for IoT sensor readings and secure data transmission.
 
 use serde::{Serialize, Deserialize};
-use log::{info, warn, error};
+use sios_log::{error, info, warn};
 
 /// Trait for generic IoT sensors
 pub trait Sensor {
@@ -16,13 +16,22 @@ pub trait Sensor {
     fn name(&self) -> &'static str;
 }
 
+/// Trait for generic IoT actuators (relays, valves, PWM outputs, ...)
+pub trait Actuator {
+    /// Command a new output value
+    fn set(&self, value: f32) -> Result<(), &'static str>;
+    /// Current output value
+    fn state(&self) -> f32;
+    fn name(&self) -> &'static str;
+}
+
 /// Example temperature sensor (simulated)
 pub struct TemperatureSensor;
 
 impl Sensor for TemperatureSensor {
     fn read(&self) -> Result<f32, &'static str> {
         let temp = 25.0; // Simulated
-        info!("{} reading: {} C", self.name(), temp);
+        info!("{} reading: {} °C", self.name(), temp);
         Ok(temp)
     }
 
@@ -45,7 +54,7 @@ pub fn collect_sensor_data<S: Sensor>(sensor: &S) -> Result<SensorData, &'static
         Ok(value) => Ok(SensorData {
             sensor: sensor.name().to_string(),
             value,
-            unit: "C".to_string(),
+            unit: "°C".to_string(),
         }),
         Err(e) => {
             warn!("{} failed to read: {}", sensor.name(), e);
@@ -59,7 +68,7 @@ pub fn send_sensor_data(data: &SensorData) -> Result<(), &'static str> {
     match serde_json::to_string(data) {
         Ok(payload) => {
             // Placeholder for encryption/secure transport (TLS, DTLS, MQTT, etc.)
-            info!("Transmitting securely -> {}", payload);
+            info!("Transmitting securely -> {}", payload.as_str());
             Ok(())
         }
         Err(_) => {
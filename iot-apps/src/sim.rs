@@ -0,0 +1,338 @@
+//! SecureIoTOS IoTApps Simulation Module
+//! -------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Virtual sensors and actuators for the host/QEMU simulator.
+//!
+//! Lets application logic, rules and telemetry paths be developed without
+//! hardware:
+//! - [`VirtualSensor`] implements [`Sensor`] and produces a configurable
+//!   waveform plus noise, with optional fault injection (stuck value,
+//!   dropouts, spikes, drift).
+//! - [`VirtualActuator`] implements [`Actuator`], records every command and
+//!   can be made to fail or stick.
+//! - Everything runs on a shared [`SimClock`] and seeded RNGs, so a
+//!   simulation run is reproducible.
+
+use crate::sensor::{Actuator, Sensor};
+use sios_log::{debug, warn, Dbg};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Serialize, Deserialize};
+use std::f64::consts::PI;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Virtual time shared by all simulated devices (milliseconds).
+#[derive(Debug, Clone, Default)]
+pub struct SimClock {
+    now_ms: Arc<AtomicU64>,
+}
+
+impl SimClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn now_ms(&self) -> u64 {
+        self.now_ms.load(Ordering::Relaxed)
+    }
+
+    pub fn advance(&self, ms: u64) {
+        self.now_ms.fetch_add(ms, Ordering::Relaxed);
+    }
+
+    pub fn set(&self, ms: u64) {
+        self.now_ms.store(ms, Ordering::Relaxed);
+    }
+}
+
+/// Noise-free signal shape.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Waveform {
+    Constant { value: f64 },
+    Sine { offset: f64, amplitude: f64, period_ms: u64 },
+    Square { low: f64, high: f64, period_ms: u64 },
+    Sawtooth { min: f64, max: f64, period_ms: u64 },
+    /// Bounded random walk; `step` is the max change per read
+    RandomWalk { start: f64, step: f64, min: f64, max: f64 },
+}
+
+/// Injected fault.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Fault {
+    /// Always report this value
+    StuckAt { value: f64 },
+    /// Fail a read with this probability (0..1)
+    Dropout { probability: f64 },
+    /// Add `magnitude` to a read with this probability
+    Spike { probability: f64, magnitude: f64 },
+    /// Add a slowly growing offset (units per second)
+    Drift { per_second: f64 },
+}
+
+/// Configuration of one virtual sensor.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SensorConfig {
+    pub waveform: Waveform,
+    /// Standard deviation of Gaussian noise added to every read
+    #[serde(default)]
+    pub noise_std_dev: f64,
+    #[serde(default)]
+    pub faults: Vec<Fault>,
+    /// RNG seed; identical seeds give identical runs
+    #[serde(default)]
+    pub seed: u64,
+}
+
+struct SensorState {
+    rng: StdRng,
+    walk: Option<f64>,
+    faults: Vec<Fault>,
+}
+
+/// Simulated sensor driven by a [`SimClock`].
+pub struct VirtualSensor {
+    name: &'static str,
+    config: SensorConfig,
+    clock: SimClock,
+    state: Mutex<SensorState>,
+}
+
+impl VirtualSensor {
+    pub fn new(name: &'static str, config: SensorConfig, clock: SimClock) -> Self {
+        let state = SensorState {
+            rng: StdRng::seed_from_u64(config.seed),
+            walk: None,
+            faults: config.faults.clone(),
+        };
+        Self { name, config, clock, state: Mutex::new(state) }
+    }
+
+    /// Inject a fault at runtime (e.g. from a test scenario script).
+    pub fn inject(&self, fault: Fault) {
+        debug!("{}: injecting fault {:?}", self.name, Dbg(&fault));
+        self.state.lock().unwrap().faults.push(fault);
+    }
+
+    /// Remove all injected faults.
+    pub fn clear_faults(&self) {
+        self.state.lock().unwrap().faults.clear();
+    }
+
+    fn sample(&self, state: &mut SensorState) -> Result<f64, &'static str> {
+        let t = self.clock.now_ms();
+        let mut value = match &self.config.waveform {
+            Waveform::Constant { value } => *value,
+            Waveform::Sine { offset, amplitude, period_ms } => {
+                offset + amplitude * (2.0 * PI * phase(t, *period_ms)).sin()
+            }
+            Waveform::Square { low, high, period_ms } => {
+                if phase(t, *period_ms) < 0.5 { *high } else { *low }
+            }
+            Waveform::Sawtooth { min, max, period_ms } => min + (max - min) * phase(t, *period_ms),
+            Waveform::RandomWalk { start, step, min, max } => {
+                let delta = state.rng.gen_range(-1.0..=1.0) * step;
+                let next = (state.walk.unwrap_or(*start) + delta).clamp(*min, *max);
+                state.walk = Some(next);
+                next
+            }
+        };
+
+        if self.config.noise_std_dev > 0.0 {
+            value += gaussian(&mut state.rng) * self.config.noise_std_dev;
+        }
+
+        for fault in state.faults.clone() {
+            match fault {
+                Fault::StuckAt { value: v } => value = v,
+                Fault::Dropout { probability } => {
+                    if state.rng.gen_bool(probability.clamp(0.0, 1.0)) {
+                        return Err("Simulated sensor dropout");
+                    }
+                }
+                Fault::Spike { probability, magnitude } => {
+                    if state.rng.gen_bool(probability.clamp(0.0, 1.0)) {
+                        value += magnitude;
+                    }
+                }
+                Fault::Drift { per_second } => value += per_second * t as f64 / 1000.0,
+            }
+        }
+        Ok(value)
+    }
+}
+
+impl Sensor for VirtualSensor {
+    fn read(&self) -> Result<f32, &'static str> {
+        let mut state = self.state.lock().map_err(|_| "Sensor state poisoned")?;
+        self.sample(&mut state).map(|v| v as f32)
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// Injected actuator fault.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ActuatorFault {
+    /// Commands are rejected
+    Fail,
+    /// Commands are accepted but the output does not move
+    Stuck,
+}
+
+struct ActuatorState {
+    value: f32,
+    history: Vec<(u64, f32)>,
+    fault: Option<ActuatorFault>,
+}
+
+/// Simulated actuator (relay, valve, PWM output, ...).
+pub struct VirtualActuator {
+    name: &'static str,
+    clock: SimClock,
+    /// Allowed output range
+    range: (f32, f32),
+    state: Mutex<ActuatorState>,
+}
+
+impl VirtualActuator {
+    pub fn new(name: &'static str, range: (f32, f32), clock: SimClock) -> Self {
+        let state = ActuatorState { value: range.0, history: Vec::new(), fault: None };
+        Self { name, clock, range, state: Mutex::new(state) }
+    }
+
+    pub fn inject(&self, fault: Option<ActuatorFault>) {
+        self.state.lock().unwrap().fault = fault;
+    }
+
+    /// Every accepted command as (sim time ms, requested value).
+    pub fn history(&self) -> Vec<(u64, f32)> {
+        self.state.lock().unwrap().history.clone()
+    }
+}
+
+impl Actuator for VirtualActuator {
+    fn set(&self, value: f32) -> Result<(), &'static str> {
+        if value < self.range.0 || value > self.range.1 {
+            return Err("Actuator value out of range");
+        }
+        let mut state = self.state.lock().map_err(|_| "Actuator state poisoned")?;
+        match state.fault {
+            Some(ActuatorFault::Fail) => {
+                warn!("{}: simulated failure", self.name);
+                return Err("Simulated actuator failure");
+            }
+            Some(ActuatorFault::Stuck) => {}
+            None => state.value = value,
+        }
+        let now = self.clock.now_ms();
+        state.history.push((now, value));
+        Ok(())
+    }
+
+    fn state(&self) -> f32 {
+        self.state.lock().map(|s| s.value).unwrap_or(self.range.0)
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+/// Fraction of the current period elapsed, in [0, 1).
+fn phase(t_ms: u64, period_ms: u64) -> f64 {
+    if period_ms == 0 {
+        return 0.0;
+    }
+    (t_ms % period_ms) as f64 / period_ms as f64
+}
+
+/// Standard normal sample (Box-Muller).
+fn gaussian(rng: &mut StdRng) -> f64 {
+    let u1: f64 = rng.gen_range(f64::EPSILON..1.0);
+    let u2: f64 = rng.gen();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(clock: &SimClock, noise: f64, seed: u64) -> VirtualSensor {
+        let config = SensorConfig {
+            waveform: Waveform::Sine { offset: 20.0, amplitude: 5.0, period_ms: 1000 },
+            noise_std_dev: noise,
+            faults: Vec::new(),
+            seed,
+        };
+        VirtualSensor::new("sim-temp", config, clock.clone())
+    }
+
+    #[test]
+    fn waveform_follows_sim_clock() {
+        let clock = SimClock::new();
+        let s = sine(&clock, 0.0, 0);
+        assert!((s.read().unwrap() - 20.0).abs() < 1e-4);
+        clock.advance(250);
+        assert!((s.read().unwrap() - 25.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn same_seed_same_noise() {
+        let clock = SimClock::new();
+        let a = sine(&clock, 1.0, 42);
+        let b = sine(&clock, 1.0, 42);
+        for _ in 0..5 {
+            assert_eq!(a.read().unwrap(), b.read().unwrap());
+        }
+    }
+
+    #[test]
+    fn faults_can_be_injected_and_cleared() {
+        let clock = SimClock::new();
+        let s = sine(&clock, 0.0, 0);
+        s.inject(Fault::StuckAt { value: -1.0 });
+        assert_eq!(s.read().unwrap(), -1.0);
+        s.inject(Fault::Dropout { probability: 1.0 });
+        assert!(s.read().is_err());
+        s.clear_faults();
+        assert!(s.read().is_ok());
+    }
+
+    #[test]
+    fn actuator_records_and_faults() {
+        let clock = SimClock::new();
+        let valve = VirtualActuator::new("valve", (0.0, 1.0), clock.clone());
+        valve.set(0.5).unwrap();
+        clock.advance(10);
+        valve.inject(Some(ActuatorFault::Stuck));
+        valve.set(1.0).unwrap();
+        assert_eq!(valve.state(), 0.5);
+        assert_eq!(valve.history(), vec![(0, 0.5), (10, 1.0)]);
+        valve.inject(Some(ActuatorFault::Fail));
+        assert!(valve.set(0.2).is_err());
+        assert!(valve.set(2.0).is_err());
+    }
+
+    #[test]
+    fn config_from_json() {
+        let cfg: SensorConfig = serde_json::from_str(
+            r#"{ "waveform": { "kind": "square", "low": 0, "high": 1, "period_ms": 100 },
+                 "faults": [ { "kind": "spike", "probability": 0.1, "magnitude": 50 } ] }"#,
+        )
+        .unwrap();
+        assert_eq!(cfg.faults.len(), 1);
+        assert_eq!(cfg.noise_std_dev, 0.0);
+    }
+}
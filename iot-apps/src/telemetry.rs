@@ -11,8 +11,9 @@
 //! transmitting sensor data in IoT devices.
 
 use crate::sensor;
+use crate::qos::{QosMap, TelemetryEvent, TransportQos};
 use serde::{Serialize, Deserialize};
-use log::{info, error};
+use sios_log::{error, info, Dbg};
 
 use aes_gcm::{Aes256Gcm, Key, Nonce};
 use aes_gcm::aead::{Aead, KeyInit};
@@ -20,10 +21,14 @@ use rand::RngCore;
 use base64::{engine::general_purpose, Engine as _};
 
 /// Telemetry data structure
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TelemetryData {
     pub temperature: f32,
     pub humidity: f32,
+    /// Build ID of the firmware that produced the reading (hex), so the
+    /// backend can tell which build a fleet anomaly comes from
+    #[serde(default)]
+    pub build_id: String,
 }
 
 /// Trait for all telemetry sources (extensible for more sensors)
@@ -36,7 +41,7 @@ pub struct TemperatureSensor;
 
 impl TelemetrySource for TemperatureSensor {
     fn read(&self) -> Result<f32, &'static str> {
-        sensor::read_sensor().ok_or("Temperature sensor read failed")
+        sensor::Sensor::read(&sensor::TemperatureSensor).map_err(|_| "Temperature sensor read failed")
     }
 }
 
@@ -57,9 +62,52 @@ pub fn collect_telemetry() -> Result<TelemetryData, &'static str> {
     Ok(TelemetryData {
         temperature: temp_sensor.read()?,
         humidity: humidity_sensor.read()?,
+        build_id: firmware_build_id(),
     })
 }
 
+/// Build ID of the running firmware as lowercase hex.
+pub fn firmware_build_id() -> String {
+    kernel::image::firmware_info().build_id.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Egress usage of one task towards one destination, as reported.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EgressRecord {
+    pub task: u16,
+    pub dest: String,
+    pub bytes: u64,
+    pub packets: u32,
+    pub denied: u32,
+}
+
+/// Per-period egress usage report (`net::egress`), so the backend can
+/// track metered data per task and destination.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EgressReport {
+    pub build_id: String,
+    pub entries: Vec<EgressRecord>,
+    /// Bytes the device could not attribute (accounting table full)
+    pub unattributed: u64,
+}
+
+impl EgressReport {
+    /// Snapshot of the stack's current accounting period.
+    pub fn from_accounting<const S: usize, const Q: usize>(acct: &net::egress::EgressAccounting<S, Q>) -> Self {
+        let entries = acct
+            .usage()
+            .map(|u| EgressRecord {
+                task: u.task,
+                dest: format!("{:?}", u.dest),
+                bytes: u.bytes,
+                packets: u.packets,
+                denied: u.denied,
+            })
+            .collect();
+        Self { build_id: firmware_build_id(), entries, unattributed: acct.overflow() }
+    }
+}
+
 /// Securely transmit telemetry data:
 /// 1. Serialize to JSON
 /// 2. Encrypt with AES-256-GCM
@@ -77,6 +125,43 @@ pub fn transmit_telemetry(
             "Serialization error"
         })?;
 
+    // --- 2. Encrypt, 3. encode & send ---
+    seal_and_send(json_payload.as_bytes(), key_bytes)
+}
+
+/// Send an egress usage report on the encrypted telemetry path. Call it
+/// before `EgressAccounting::reset_period()` so no period goes unreported.
+pub fn transmit_egress_report(report: &EgressReport, key_bytes: &[u8; 32]) -> Result<(), &'static str> {
+    let json_payload = serde_json::to_string(report).map_err(|_| {
+        error!("Egress report serialization failed");
+        "Serialization error"
+    })?;
+    info!("Reporting egress usage for {} destinations", report.entries.len());
+    seal_and_send(json_payload.as_bytes(), key_bytes)
+}
+
+/// Upload an encoded crash dump (the bytes a `kernel::crash::CrashSink`
+/// receives) on the same encrypted path as regular telemetry. The backend
+/// stores it for `tools/crash-symbolicate`.
+pub fn transmit_crash_dump(dump: &[u8], key_bytes: &[u8; 32]) -> Result<(), &'static str> {
+    info!("Uploading crash dump ({} bytes)", dump.len());
+    seal_and_send(dump, key_bytes)
+}
+
+/// Encrypt `payload` with AES-256-GCM under a fresh nonce, prepend the
+/// nonce, Base64-encode and send.
+fn seal_and_send(payload: &[u8], key_bytes: &[u8; 32]) -> Result<(), &'static str> {
+    let encoded = seal(payload, key_bytes)?;
+
+    // In production: send `encoded` via HTTPS/MQTT/etc.
+    info!("Securely transmitting telemetry payload: {}", encoded.as_str());
+
+    Ok(())
+}
+
+/// Encrypt `payload` with AES-256-GCM under a fresh nonce, prepend the
+/// nonce and Base64-encode the result.
+pub fn seal(payload: &[u8], key_bytes: &[u8; 32]) -> Result<String, &'static str> {
     // --- 2. Encrypt ---
     let key = Key::<Aes256Gcm>::from_slice(key_bytes);
     let cipher = Aes256Gcm::new(key);
@@ -87,7 +172,7 @@ pub fn transmit_telemetry(
     let nonce = Nonce::from_slice(&nonce_bytes);
 
     let ciphertext = cipher
-        .encrypt(nonce, json_payload.as_bytes())
+        .encrypt(nonce, payload)
         .map_err(|_| {
             error!("Telemetry encryption failed");
             "Encryption error"
@@ -97,11 +182,22 @@ pub fn transmit_telemetry(
     let mut message = nonce_bytes.to_vec();
     message.extend_from_slice(&ciphertext);
 
-    // --- 3. Encode & "send" ---
-    let encoded = general_purpose::STANDARD.encode(message);
-
-    // In production: send `encoded` via HTTPS/MQTT/etc.
-    info!("Securely transmitting telemetry payload: {}", encoded);
+    // --- 3. Encode ---
+    Ok(general_purpose::STANDARD.encode(message))
+}
 
-    Ok(())
+/// Transmit a prioritized telemetry event.
+///
+/// Resolves the transport QoS for the event's priority class via `qos_map`
+/// and returns it, so the uplink (MQTT publish / CoAP request) can be issued
+/// with the matching reliability.
+pub fn transmit_telemetry_event(
+    event: &TelemetryEvent<TelemetryData>,
+    key_bytes: &[u8; 32],
+    qos_map: &QosMap,
+) -> Result<TransportQos, &'static str> {
+    let qos = qos_map.resolve(event.priority);
+    info!("Telemetry priority {:?} -> MQTT {:?}, CoAP {:?}", Dbg(&event.priority), Dbg(&qos.mqtt), Dbg(&qos.coap));
+    transmit_telemetry(&event.data, key_bytes)?;
+    Ok(qos)
 }
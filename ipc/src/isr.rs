@@ -0,0 +1,94 @@
+//! SecureIoTOS IPC Execution Context Module
+//! ----------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! ISR-safety classification of kernel and IPC APIs.
+//!
+//! Every public kernel, scheduler and IPC call is documented as one of:
+//!
+//! - **ISR-safe**: never blocks or spins on another context; may be called
+//!   from a task or an interrupt handler.
+//! - **Task-only**: may park the caller (or acts on "the calling task");
+//!   must not be called from an interrupt handler.
+//!
+//! Task-only calls start with `assert_task_context()`, which in debug
+//! builds panics if the CPU is in Handler mode (IPSR != 0). Blocking in an
+//! ISR would park whatever task the interrupt preempted, or hang the
+//! system if the condition can only be met by code that the ISR is
+//! keeping from running. Release builds skip the check.
+//!
+//! ISR-side counterparts of blocking calls carry a `_from_isr` suffix
+//! (`MpmcQueue::enqueue_from_isr`, `MessageQueue::try_enqueue_from_isr`,
+//! `Semaphore::give_from_isr`).
+//!
+//! A task woken from an ISR that outranks the running task is switched to
+//! on exception return: the scheduler's `unpark` hook pends PendSV itself
+//! (see `scheduler_ipc::scheduler::Scheduler::wake_from_isr`).
+
+/// True while executing an exception or interrupt handler.
+#[cfg(target_arch = "arm")]
+#[inline]
+pub fn in_isr() -> bool {
+    let ipsr: u32;
+    // SAFETY: reading IPSR has no side effects and is allowed at any
+    // privilege level.
+    unsafe {
+        core::arch::asm!("mrs {}, IPSR", out(reg) ipsr, options(nomem, nostack, preserves_flags));
+    }
+    ipsr & 0x1FF != 0
+}
+
+/// True while executing an exception or interrupt handler (never on the
+/// host, except inside `simulate_isr()` in tests).
+#[cfg(not(target_arch = "arm"))]
+#[inline]
+pub fn in_isr() -> bool {
+    #[cfg(test)]
+    return tests::SIMULATED.with(|s| s.get());
+    #[cfg(not(test))]
+    false
+}
+
+/// Debug-build check that a task-only API is not called from an ISR.
+#[inline]
+#[track_caller]
+pub fn assert_task_context(api: &'static str) {
+    debug_assert!(!in_isr(), "{} is task-only and must not be called from an ISR", api);
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    extern crate std;
+
+    use core::cell::Cell;
+
+    std::thread_local! {
+        pub(crate) static SIMULATED: Cell<bool> = const { Cell::new(false) };
+    }
+
+    /// Run `f` as if from an interrupt handler.
+    pub(crate) fn simulate_isr<R>(f: impl FnOnce() -> R) -> R {
+        SIMULATED.with(|s| s.set(true));
+        let r = f();
+        SIMULATED.with(|s| s.set(false));
+        r
+    }
+
+    #[test]
+    fn task_context_is_detected() {
+        assert!(!super::in_isr());
+        assert!(simulate_isr(super::in_isr));
+        super::assert_task_context("test");
+    }
+
+    #[test]
+    #[should_panic(expected = "task-only")]
+    fn task_only_call_from_isr_panics() {
+        simulate_isr(|| super::assert_task_context("test"));
+    }
+}
@@ -1,39 +1,56 @@
 //! SecureIoTOS IPC Library Module
 //! ---------------------------------
 //! License : Dual License
-//!   - Apache 2.0 for open-source / personal use
-//!   - Commercial license required for closed-source use
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
 //! Author  : Md Mahbubur Rahman
 //! URL     : https://m-a-h-b-u-b.github.io
 //! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
 
 //! This module provides IPC primitives — mechanisms for 
 //! tasks (threads, processes, or lightweight tasks in SecureIoTOS) to communicate and synchronize safely:
-//! Message Queues (for passing data between tasks)
+//! Message Queues (for passing data between tasks; `MpmcQueue` for ISRs
+//! and multiple producers/consumers)
 //! Semaphores (for signaling between tasks)
 //! Event Flags (for task synchronization via event triggers)
+//!
+//! Every call is ISR-safe unless its documentation says "Task-only";
+//! see [`isr`] for the classification and its debug-build enforcement.
 
 // #![no_std]: disables Rust’s standard library, ensuring compatibility with embedded systems.
 #![no_std]
 
-#[cfg(feature = "alloc")]
-extern crate alloc;
-
-#[cfg(feature = "alloc")]
-use alloc::vec::Vec;
-
 // UnsafeCell: allows mutable memory inside immutable structs, 
-// needed for concurrency (e.g., message queue head/tail).
+// needed for concurrency (e.g., message queue buffer).
 use core::cell::UnsafeCell;
 
 // AtomicBool: provides lock-free synchronization for semaphores/events
+// AtomicUsize: message queue head/tail indices
 // Ordering: defines memory ordering guarantees (Acquire, Release, etc.).
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+// Lock-free multi-producer/multi-consumer queue (ISR safe).
+pub mod mpmc;
+pub use mpmc::MpmcQueue;
+
+// ISR-safe / task-only classification and the IPSR check.
+pub mod isr;
+
+// Wait lists shared with the scheduler (blocking IPC).
+pub mod wait;
+use wait::WaitList;
+
+// Typed (postcard-encoded) message payloads.
+#[cfg(feature = "postcard")]
+pub mod payload;
+#[cfg(feature = "postcard")]
+pub use payload::{FromIpcPayload, PayloadError, ToIpcPayload};
 
 // A generic fixed-size message container (N = max message size).
 // Example: IpcMessage<16> → holds up to 16 bytes.
 // length specifies how many bytes are actually used.
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct IpcMessage<const N: usize> {
     pub data: [u8; N],
     pub length: usize,
@@ -48,83 +65,153 @@ impl<const N: usize> IpcMessage<N> {
     }
 }
 
+impl<const N: usize> Default for IpcMessage<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 /// Simple single-producer, single-consumer message queue.
 /// Can be used for task-to-task communication.
 /// SIZE = number of messages it can store.
 /// MSG_SIZE = max size of each message.
 /// Uses a circular buffer with head (enqueue index) and tail (dequeue index).
+///
+/// NOTE: exactly one context may enqueue and exactly one may dequeue.
+/// For multiple producers/consumers, use [`MpmcQueue`].
+///
+/// `enqueue_blocking()` / `dequeue_blocking()` park the calling task on the
+/// queue's wait lists instead of spinning; the opposite side wakes it when
+/// space/data becomes available. The blocking variants are task-only; an
+/// ISR that is the queue's producer uses `try_enqueue_from_isr()`.
 pub struct MessageQueue<const SIZE: usize, const MSG_SIZE: usize> {
-    buffer: [IpcMessage<MSG_SIZE>; SIZE],
-    head: UnsafeCell<usize>,
-    tail: UnsafeCell<usize>,
+    buffer: UnsafeCell<[IpcMessage<MSG_SIZE>; SIZE]>,
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    /// Consumers waiting for data
+    not_empty: WaitList,
+    /// Producers waiting for space
+    not_full: WaitList,
 }
 
+// SAFETY: with one producer and one consumer, a buffer slot is only written
+// by the producer before it publishes `head` (Release) and only read by the
+// consumer after it observes `head` (Acquire), and vice versa for `tail`.
+unsafe impl<const SIZE: usize, const MSG_SIZE: usize> Sync for MessageQueue<SIZE, MSG_SIZE> {}
+
 impl<const SIZE: usize, const MSG_SIZE: usize> MessageQueue<SIZE, MSG_SIZE> {
     /// Creates a new empty queue
     pub const fn new() -> Self {
-        const EMPTY: IpcMessage<0> = IpcMessage { data: [], length: 0 };
-        // SAFETY: casting array of zero-sized to MSG_SIZE
-        let buffer: [IpcMessage<MSG_SIZE>; SIZE] = unsafe { core::mem::transmute([EMPTY; SIZE]) };
         Self {
-            buffer,
-            head: UnsafeCell::new(0),
-            tail: UnsafeCell::new(0),
+            buffer: UnsafeCell::new([IpcMessage::new(); SIZE]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            not_empty: WaitList::new(),
+            not_full: WaitList::new(),
         }
     }
 
-    /// Enqueue a message
+    /// Enqueue a message. ISR-safe (from the single producer context).
+    #[allow(clippy::result_unit_err)] // a full queue is the only failure
     pub fn enqueue(&self, msg: IpcMessage<MSG_SIZE>) -> Result<(), ()> {
-        let head = unsafe { *self.head.get() };
+        let head = self.head.load(Ordering::Relaxed);
         let next_head = (head + 1) % SIZE;
-        let tail = unsafe { *self.tail.get() };
+        let tail = self.tail.load(Ordering::Acquire);
 
         if next_head == tail {
             return Err(()); // Queue full
         }
 
-        self.buffer[head] = msg;
-        unsafe { *self.head.get() = next_head };
+        unsafe { (*self.buffer.get())[head] = msg };
+        self.head.store(next_head, Ordering::Release);
+        self.not_empty.wake_one();
         Ok(())
     }
 
-    /// Dequeue a message
+    /// Dequeue a message. ISR-safe (from the single consumer context).
     pub fn dequeue(&self) -> Option<IpcMessage<MSG_SIZE>> {
-        let tail = unsafe { *self.tail.get() };
-        let head = unsafe { *self.head.get() };
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
 
         if tail == head {
             return None; // Queue empty
         }
 
-        let msg = self.buffer[tail];
-        unsafe { *self.tail.get() = (tail + 1) % SIZE };
+        let msg = unsafe { (*self.buffer.get())[tail] };
+        self.tail.store((tail + 1) % SIZE, Ordering::Release);
+        self.not_full.wake_one();
         Some(msg)
     }
+
+    /// Enqueue from interrupt context, where the ISR is the queue's only
+    /// producer. ISR-safe: never waits; returns the message if the queue
+    /// is full. A consumer parked in `dequeue_blocking()` is woken.
+    pub fn try_enqueue_from_isr(&self, msg: IpcMessage<MSG_SIZE>) -> Result<(), IpcMessage<MSG_SIZE>> {
+        self.enqueue(msg).map_err(|()| msg)
+    }
+
+    /// Enqueue a message, blocking the calling task while the queue is full.
+    /// Task-only.
+    pub fn enqueue_blocking(&self, msg: IpcMessage<MSG_SIZE>) {
+        wait::block_on(&self.not_full, || self.enqueue(msg).ok())
+    }
+
+    /// Dequeue a message, blocking the calling task while the queue is empty.
+    /// Task-only.
+    pub fn dequeue_blocking(&self) -> IpcMessage<MSG_SIZE> {
+        wait::block_on(&self.not_empty, || self.dequeue())
+    }
+}
+
+impl<const SIZE: usize, const MSG_SIZE: usize> Default for MessageQueue<SIZE, MSG_SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-/// Simple binary semaphore for signaling between tasks.
+/// Simple binary semaphore for signaling between tasks (and from ISRs to
+/// tasks).
 pub struct Semaphore {
     flag: AtomicBool,
+    /// Tasks parked in `wait_blocking()`
+    waiters: WaitList,
 }
 
 impl Semaphore {
     pub const fn new(initial: bool) -> Self {
         Self {
             flag: AtomicBool::new(initial),
+            waiters: WaitList::new(),
         }
     }
 
-    /// Signal / release the semaphore
+    /// Signal / release the semaphore, waking one waiting task. ISR-safe.
     pub fn signal(&self) {
         self.flag.store(true, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    /// Give the semaphore from interrupt context. ISR-safe. Returns `true`
+    /// if a waiting task was woken, so the ISR can request a context
+    /// switch on exit.
+    pub fn give_from_isr(&self) -> bool {
+        self.flag.store(true, Ordering::Release);
+        self.waiters.wake_one().is_some()
     }
 
     /// Wait for the semaphore. Returns true if acquired, false if not set.
+    /// ISR-safe: never blocks.
     pub fn wait(&self) -> bool {
         self.flag.swap(false, Ordering::AcqRel)
     }
 
-    /// Check if the semaphore is currently set
+    /// Take the semaphore, blocking the calling task until it is given.
+    /// Task-only.
+    pub fn wait_blocking(&self) {
+        wait::block_on(&self.waiters, || self.wait().then_some(()))
+    }
+
+    /// Check if the semaphore is currently set. ISR-safe.
     pub fn is_set(&self) -> bool {
         self.flag.load(Ordering::Acquire)
     }
@@ -142,22 +229,28 @@ impl EventFlags {
         }
     }
 
-    /// Set event flag
+    /// Set event flag. ISR-safe.
     pub fn set(&self) {
         self.flags.store(true, Ordering::Release);
     }
 
-    /// Clear event flag
+    /// Clear event flag. ISR-safe.
     pub fn clear(&self) {
         self.flags.store(false, Ordering::Release);
     }
 
-    /// Wait for event flag
+    /// Wait for event flag (consumes it; never blocks). ISR-safe.
     pub fn wait(&self) -> bool {
         self.flags.swap(false, Ordering::AcqRel)
     }
 }
 
+impl Default for EventFlags {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -178,6 +271,15 @@ mod tests {
         assert_eq!(received.data[0], 1);
     }
 
+    #[test]
+    fn test_message_queue_blocking_roundtrip() {
+        let queue: MessageQueue<2, 1> = MessageQueue::new();
+        queue.enqueue_blocking(IpcMessage { data: [7], length: 1 });
+        assert_eq!(queue.enqueue(IpcMessage::new()), Err(()), "one slot is kept free");
+        assert_eq!(queue.dequeue_blocking().data[0], 7);
+        assert!(queue.dequeue().is_none());
+    }
+
     #[test]
     fn test_semaphore() {
         let sem = Semaphore::new(false);
@@ -187,6 +289,29 @@ mod tests {
         assert!(!sem.wait());
     }
 
+    #[test]
+    fn test_isr_variants() {
+        let queue: MessageQueue<2, 1> = MessageQueue::new();
+        let msg = IpcMessage { data: [3], length: 1 };
+        isr::tests::simulate_isr(|| {
+            assert!(queue.try_enqueue_from_isr(msg).is_ok());
+            assert_eq!(queue.try_enqueue_from_isr(msg).map_err(|m| m.data), Err([3]), "full");
+        });
+        assert_eq!(queue.dequeue_blocking().data[0], 3);
+
+        let sem = Semaphore::new(false);
+        assert!(!isr::tests::simulate_isr(|| sem.give_from_isr()), "nobody waiting");
+        sem.wait_blocking();
+        assert!(!sem.is_set());
+    }
+
+    #[test]
+    #[should_panic(expected = "task-only")]
+    fn test_blocking_call_from_isr_is_caught() {
+        let sem = Semaphore::new(true);
+        isr::tests::simulate_isr(|| sem.wait_blocking());
+    }
+
     #[test]
     fn test_event_flags() {
         let evt = EventFlags::new();
@@ -0,0 +1,273 @@
+//! SecureIoTOS IPC MPMC Queue Module
+//! ---------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Bounded multi-producer / multi-consumer message queue.
+//!
+//! Unlike `MessageQueue` (single producer, single consumer, no
+//! synchronization), `MpmcQueue` may be shared between any number of tasks
+//! and interrupt handlers. It is a lock-free ring (D. Vyukov's bounded
+//! MPMC design): every slot carries a sequence number that tells producers
+//! and consumers whether it is free, being written, or ready to read, and
+//! the head/tail positions are claimed with compare-and-swap.
+//!
+//! Neither side ever blocks or spins waiting for another context, so the
+//! queue is safe to use from an ISR that preempts a task in the middle of
+//! an enqueue/dequeue: at worst the ISR sees the slot as not yet ready
+//! and gets `Err`/`None` back.
+//!
+//! NOTE: requires atomic compare-and-swap (ARMv7-M and later). ARMv6-M
+//! (Cortex-M0/M0+) has no CAS instructions; use `MessageQueue` inside a
+//! critical section there instead.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::IpcMessage;
+
+struct Slot<const MSG_SIZE: usize> {
+    /// Position this slot is waiting for: `pos` = free for the producer
+    /// claiming `pos`, `pos + 1` = filled for the consumer claiming `pos`.
+    sequence: AtomicUsize,
+    msg: UnsafeCell<IpcMessage<MSG_SIZE>>,
+}
+
+/// Lock-free bounded MPMC queue of `SIZE` messages of up to `MSG_SIZE`
+/// bytes. `SIZE` must be a power of two.
+pub struct MpmcQueue<const SIZE: usize, const MSG_SIZE: usize> {
+    slots: [Slot<MSG_SIZE>; SIZE],
+    /// Next position to enqueue at
+    head: AtomicUsize,
+    /// Next position to dequeue from
+    tail: AtomicUsize,
+}
+
+// SAFETY: a slot's message is only written by the producer that won the
+// CAS on `head` for that position, and only read by the consumer that won
+// the CAS on `tail`; the slot sequence number (Release/Acquire) hands the
+// message over between them. `IpcMessage` is plain bytes, so moving it
+// across contexts is fine.
+unsafe impl<const SIZE: usize, const MSG_SIZE: usize> Sync for MpmcQueue<SIZE, MSG_SIZE> {}
+unsafe impl<const SIZE: usize, const MSG_SIZE: usize> Send for MpmcQueue<SIZE, MSG_SIZE> {}
+
+impl<const SIZE: usize, const MSG_SIZE: usize> MpmcQueue<SIZE, MSG_SIZE> {
+    /// Positions wrap at `usize::MAX`; a power-of-two size keeps the slot
+    /// index continuous across that wrap.
+    const SIZE_OK: () = assert!(SIZE > 0 && SIZE.is_power_of_two(), "MpmcQueue SIZE must be a power of two");
+
+    /// Creates a new empty queue (usable in `static` context).
+    pub const fn new() -> Self {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::SIZE_OK;
+
+        let mut slots = [const { Slot { sequence: AtomicUsize::new(0), msg: UnsafeCell::new(IpcMessage::new()) } }; SIZE];
+        let mut i = 0;
+        while i < SIZE {
+            slots[i].sequence = AtomicUsize::new(i);
+            i += 1;
+        }
+        Self {
+            slots,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Enqueue a message. Returns the message back if the queue is full.
+    /// ISR-safe.
+    pub fn enqueue(&self, msg: IpcMessage<MSG_SIZE>) -> Result<(), IpcMessage<MSG_SIZE>> {
+        let mut pos = self.head.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & (SIZE - 1)];
+            let seq = slot.sequence.load(Ordering::Acquire);
+            let diff = seq.wrapping_sub(pos) as isize;
+
+            if diff == 0 {
+                // Slot is free for this position: try to claim it.
+                match self.head.compare_exchange_weak(pos, pos.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        // SAFETY: we own this slot until we publish it below.
+                        unsafe { *slot.msg.get() = msg };
+                        slot.sequence.store(pos.wrapping_add(1), Ordering::Release);
+                        return Ok(());
+                    }
+                    Err(current) => pos = current,
+                }
+            } else if diff < 0 {
+                // Slot still holds a message from the previous lap: full.
+                return Err(msg);
+            } else {
+                // Another producer got here first.
+                pos = self.head.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Dequeue a message, or `None` if the queue is empty. ISR-safe.
+    pub fn dequeue(&self) -> Option<IpcMessage<MSG_SIZE>> {
+        let mut pos = self.tail.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & (SIZE - 1)];
+            let seq = slot.sequence.load(Ordering::Acquire);
+            let diff = seq.wrapping_sub(pos.wrapping_add(1)) as isize;
+
+            if diff == 0 {
+                match self.tail.compare_exchange_weak(pos, pos.wrapping_add(1), Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        // SAFETY: the producer published this slot and we
+                        // won the claim, so nobody else touches it now.
+                        let msg = unsafe { *slot.msg.get() };
+                        // Free the slot for the producer one lap ahead.
+                        slot.sequence.store(pos.wrapping_add(SIZE), Ordering::Release);
+                        return Some(msg);
+                    }
+                    Err(current) => pos = current,
+                }
+            } else if diff < 0 {
+                // Not yet written (empty, or a preempted producer).
+                return None;
+            } else {
+                pos = self.tail.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Enqueue from interrupt context.
+    ///
+    /// Never waits for the preempted context; returns the message if the
+    /// queue is full. Keep messages small: the copy happens inside the ISR.
+    #[inline]
+    pub fn enqueue_from_isr(&self, msg: IpcMessage<MSG_SIZE>) -> Result<(), IpcMessage<MSG_SIZE>> {
+        self.enqueue(msg)
+    }
+
+    /// Dequeue from interrupt context.
+    ///
+    /// Returns `None` if the queue is empty or the next message is still
+    /// being written by the context this ISR preempted.
+    #[inline]
+    pub fn dequeue_from_isr(&self) -> Option<IpcMessage<MSG_SIZE>> {
+        self.dequeue()
+    }
+
+    /// Approximate number of queued messages (exact when quiescent).
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        let tail = self.tail.load(Ordering::Acquire);
+        head.wrapping_sub(tail).min(SIZE)
+    }
+
+    /// True if no messages are queued (approximate under contention).
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Maximum number of messages the queue holds.
+    pub const fn capacity(&self) -> usize {
+        SIZE
+    }
+}
+
+impl<const SIZE: usize, const MSG_SIZE: usize> Default for MpmcQueue<SIZE, MSG_SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use std::vec::Vec;
+
+    fn msg(v: u8) -> IpcMessage<4> {
+        IpcMessage { data: [v; 4], length: 1 }
+    }
+
+    #[test]
+    fn fifo_and_full_empty() {
+        let q: MpmcQueue<4, 4> = MpmcQueue::new();
+        assert!(q.dequeue().is_none());
+        for i in 0..4 {
+            q.enqueue(msg(i)).unwrap();
+        }
+        assert_eq!(q.enqueue(msg(9)).unwrap_err().data[0], 9);
+        assert_eq!(q.len(), 4);
+        for i in 0..4 {
+            assert_eq!(q.dequeue_from_isr().unwrap().data[0], i);
+        }
+        assert!(q.is_empty());
+        // Wrap around a few laps
+        for i in 0..10 {
+            q.enqueue_from_isr(msg(i)).unwrap();
+            assert_eq!(q.dequeue().unwrap().data[0], i);
+        }
+    }
+
+    #[test]
+    fn concurrent_producers_and_consumers() {
+        const PER_PRODUCER: usize = 2_000;
+        let q: Arc<MpmcQueue<8, 4>> = Arc::new(MpmcQueue::new());
+
+        let producers: Vec<_> = (0..3u8)
+            .map(|p| {
+                let q = q.clone();
+                thread::spawn(move || {
+                    for _ in 0..PER_PRODUCER {
+                        let mut m = msg(p);
+                        while let Err(back) = q.enqueue(m) {
+                            m = back;
+                            thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        let consumers: Vec<_> = (0..2)
+            .map(|_| {
+                let q = q.clone();
+                thread::spawn(move || {
+                    let mut counts = [0usize; 3];
+                    let mut idle = 0;
+                    while idle < 1_000 {
+                        match q.dequeue() {
+                            Some(m) => {
+                                counts[m.data[0] as usize] += 1;
+                                idle = 0;
+                            }
+                            None => {
+                                idle += 1;
+                                thread::yield_now();
+                            }
+                        }
+                    }
+                    counts
+                })
+            })
+            .collect();
+
+        for p in producers {
+            p.join().unwrap();
+        }
+        let mut total = [0usize; 3];
+        for c in consumers {
+            for (t, n) in total.iter_mut().zip(c.join().unwrap()) {
+                *t += n;
+            }
+        }
+        // Drain anything the consumers left behind after going idle
+        while let Some(m) = q.dequeue() {
+            total[m.data[0] as usize] += 1;
+        }
+        assert_eq!(total, [PER_PRODUCER; 3]);
+    }
+}
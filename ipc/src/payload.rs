@@ -0,0 +1,135 @@
+//! SecureIoTOS IPC Payload Module
+//! ---------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Typed payloads for `IpcMessage`, so application structs can go through
+//! `MessageQueue`/`MpmcQueue` without packing bytes by hand.
+//!
+//! Any type that derives `serde::Serialize` / `serde::Deserialize`
+//! implements [`ToIpcPayload`] / [`FromIpcPayload`]; the wire format is
+//! postcard (compact, varint-encoded, no_std). Both directions check
+//! lengths: encoding fails with `TooLarge` instead of truncating, and
+//! decoding only looks at the first `length` bytes and rejects messages
+//! with bytes left over, so a message of another type is not silently
+//! half-read.
+//!
+//! ```ignore
+//! #[derive(Serialize, Deserialize)]
+//! struct Reading { sensor: u8, milli_celsius: i32 }
+//!
+//! queue.enqueue(Reading { sensor: 1, milli_celsius: 21_500 }.to_ipc()?)?;
+//! let r: Reading = queue.dequeue().unwrap().decode()?;
+//! ```
+//!
+//! Requires the `postcard` feature.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::IpcMessage;
+
+/// Why a payload could not be encoded or decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PayloadError {
+    /// Encoded value does not fit in the message
+    TooLarge,
+    /// `length` is larger than the message buffer
+    BadLength,
+    /// Bytes are not a valid encoding of the requested type
+    Malformed,
+    /// Decoding stopped before the end of the message
+    TrailingBytes,
+}
+
+/// A value that can be sent as an `IpcMessage`.
+pub trait ToIpcPayload {
+    /// Encode into a message of up to `N` bytes.
+    fn to_ipc<const N: usize>(&self) -> Result<IpcMessage<N>, PayloadError>;
+}
+
+/// A value that can be read back from an `IpcMessage`.
+pub trait FromIpcPayload: Sized {
+    /// Decode the first `msg.length` bytes of `msg`.
+    fn from_ipc<const N: usize>(msg: &IpcMessage<N>) -> Result<Self, PayloadError>;
+}
+
+impl<T: Serialize> ToIpcPayload for T {
+    fn to_ipc<const N: usize>(&self) -> Result<IpcMessage<N>, PayloadError> {
+        let mut msg = IpcMessage::new();
+        let used = postcard::to_slice(self, &mut msg.data).map_err(|e| match e {
+            postcard::Error::SerializeBufferFull => PayloadError::TooLarge,
+            _ => PayloadError::Malformed,
+        })?;
+        msg.length = used.len();
+        Ok(msg)
+    }
+}
+
+impl<T: DeserializeOwned> FromIpcPayload for T {
+    fn from_ipc<const N: usize>(msg: &IpcMessage<N>) -> Result<Self, PayloadError> {
+        let bytes = msg.data.get(..msg.length).ok_or(PayloadError::BadLength)?;
+        let (value, rest) = postcard::take_from_bytes(bytes).map_err(|_| PayloadError::Malformed)?;
+        if !rest.is_empty() {
+            return Err(PayloadError::TrailingBytes);
+        }
+        Ok(value)
+    }
+}
+
+impl<const N: usize> IpcMessage<N> {
+    /// Message carrying `value` (see [`ToIpcPayload`]).
+    pub fn encode<T: ToIpcPayload>(value: &T) -> Result<Self, PayloadError> {
+        value.to_ipc()
+    }
+
+    /// The value carried by this message (see [`FromIpcPayload`]).
+    pub fn decode<T: FromIpcPayload>(&self) -> Result<T, PayloadError> {
+        T::from_ipc(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MessageQueue;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Reading {
+        sensor: u8,
+        milli_celsius: i32,
+        label: [u8; 4],
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Ack(u8);
+
+    #[test]
+    fn structs_roundtrip_through_a_queue() {
+        let queue: MessageQueue<2, 16> = MessageQueue::new();
+        let sent = Reading { sensor: 3, milli_celsius: -12_500, label: *b"room" };
+        queue.enqueue(sent.to_ipc().unwrap()).unwrap();
+        let msg = queue.dequeue().unwrap();
+        assert!(msg.length < 16);
+        assert_eq!(msg.decode::<Reading>(), Ok(sent));
+    }
+
+    #[test]
+    fn lengths_are_checked() {
+        let reading = Reading { sensor: 1, milli_celsius: i32::MAX, label: [0; 4] };
+        assert_eq!(IpcMessage::<4>::encode(&reading).map(|m| m.length), Err(PayloadError::TooLarge));
+
+        let mut msg = IpcMessage::<16>::encode(&reading).unwrap();
+        assert_eq!(msg.decode::<Ack>(), Err(PayloadError::TrailingBytes), "wrong type");
+        msg.length -= 1;
+        assert_eq!(msg.decode::<Reading>(), Err(PayloadError::Malformed), "truncated");
+        msg.length = 17;
+        assert_eq!(msg.decode::<Reading>(), Err(PayloadError::BadLength));
+    }
+}
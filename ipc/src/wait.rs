@@ -0,0 +1,188 @@
+//! SecureIoTOS IPC Wait List Module
+//! --------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Wait lists shared between IPC objects and the scheduler.
+//!
+//! The ipc crate does not know about tasks or context switching; the
+//! scheduler registers a small set of hooks (`ParkerHooks`) at startup and
+//! IPC objects use them to put the calling task to sleep and wake it up
+//! again:
+//!
+//! - `park()` blocks the current task until someone calls `unpark(id)`.
+//!   An `unpark()` that arrives *before* `park()` must make the next
+//!   `park()` return immediately (wakeup token), otherwise a wakeup sent
+//!   between "queue is full" and "go to sleep" would be lost.
+//! - `WaitList` records which tasks are waiting on one condition
+//!   (e.g. "queue not empty"); `wake_one()` unparks the first of them.
+//!
+//! Until hooks are installed (early boot, host tests) blocking calls fall
+//! back to spinning.
+//!
+//! `WaitList` operations are ISR-safe, so an interrupt handler can wake
+//! waiters; `block_on()` is task-only (see `isr`).
+
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, AtomicU32, Ordering};
+
+/// Task identifier as used by the scheduler.
+pub type TaskId = u32;
+
+/// Scheduler entry points used to block and wake tasks.
+pub struct ParkerHooks {
+    /// ID of the task currently running
+    pub current: fn() -> TaskId,
+    /// Block the current task until unparked (or return at once if an
+    /// unpark is already pending)
+    pub park: fn(),
+    /// Make `task` runnable again (also called from ISRs)
+    pub unpark: fn(TaskId),
+}
+
+static HOOKS: AtomicPtr<ParkerHooks> = AtomicPtr::new(ptr::null_mut());
+
+/// Install the scheduler hooks. Called once by the scheduler at startup.
+pub fn set_parker(hooks: &'static ParkerHooks) {
+    HOOKS.store(hooks as *const ParkerHooks as *mut ParkerHooks, Ordering::Release);
+}
+
+/// Currently installed hooks, if any.
+pub fn parker() -> Option<&'static ParkerHooks> {
+    // SAFETY: only ever set from a `&'static ParkerHooks`.
+    unsafe { HOOKS.load(Ordering::Acquire).as_ref() }
+}
+
+/// Maximum number of tasks that can wait on one condition.
+pub const MAX_WAITERS: usize = 4;
+
+/// Slot value for "no waiter"; slots store `task id + 1`.
+const EMPTY: u32 = 0;
+
+/// Set of tasks waiting for one condition.
+pub struct WaitList {
+    slots: [AtomicU32; MAX_WAITERS],
+}
+
+impl WaitList {
+    pub const fn new() -> Self {
+        Self { slots: [const { AtomicU32::new(EMPTY) }; MAX_WAITERS] }
+    }
+
+    /// Add `task` to the list. Returns `false` if the list is full.
+    pub fn register(&self, task: TaskId) -> bool {
+        let tag = task.wrapping_add(1);
+        self.slots.iter().any(|s| {
+            s.compare_exchange(EMPTY, tag, Ordering::AcqRel, Ordering::Relaxed).is_ok()
+        })
+    }
+
+    /// Remove `task` from the list (after it was woken or gave up).
+    pub fn remove(&self, task: TaskId) {
+        let tag = task.wrapping_add(1);
+        for s in self.slots.iter() {
+            let _ = s.compare_exchange(tag, EMPTY, Ordering::AcqRel, Ordering::Relaxed);
+        }
+    }
+
+    /// Wake the first waiting task. Returns the task woken, if any.
+    pub fn wake_one(&self) -> Option<TaskId> {
+        for s in self.slots.iter() {
+            let tag = s.swap(EMPTY, Ordering::AcqRel);
+            if tag != EMPTY {
+                let task = tag.wrapping_sub(1);
+                if let Some(h) = parker() {
+                    (h.unpark)(task);
+                }
+                return Some(task);
+            }
+        }
+        None
+    }
+
+    /// Wake every waiting task.
+    pub fn wake_all(&self) {
+        while self.wake_one().is_some() {}
+    }
+
+    /// True if no task is waiting.
+    pub fn is_empty(&self) -> bool {
+        self.slots.iter().all(|s| s.load(Ordering::Acquire) == EMPTY)
+    }
+}
+
+impl Default for WaitList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Retry `attempt` until it succeeds, parking the calling task on `list`
+/// between attempts.
+///
+/// The task registers itself *before* re-checking the condition, so a
+/// wakeup that races with going to sleep is never lost.
+pub fn block_on<T>(list: &WaitList, mut attempt: impl FnMut() -> Option<T>) -> T {
+    crate::isr::assert_task_context("blocking IPC call");
+    loop {
+        if let Some(v) = attempt() {
+            return v;
+        }
+        let Some(h) = parker() else {
+            // No scheduler yet: nothing else can run, just spin.
+            core::hint::spin_loop();
+            continue;
+        };
+        let me = (h.current)();
+        if !list.register(me) {
+            // Too many waiters: let others run and try again.
+            sios_log::debug!("wait list full, task {} retries", me);
+            (h.park)();
+            continue;
+        }
+        if let Some(v) = attempt() {
+            list.remove(me);
+            return v;
+        }
+        (h.park)();
+        list.remove(me);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_wake_and_remove() {
+        let list = WaitList::new();
+        assert!(list.is_empty());
+        for id in 0..MAX_WAITERS as u32 {
+            assert!(list.register(id));
+        }
+        assert!(!list.register(99), "list is full");
+
+        assert_eq!(list.wake_one(), Some(0));
+        list.remove(2);
+        assert_eq!(list.wake_one(), Some(1));
+        assert_eq!(list.wake_one(), Some(3));
+        assert_eq!(list.wake_one(), None);
+        assert!(list.is_empty());
+    }
+
+    #[test]
+    fn block_on_without_scheduler_spins_until_ready() {
+        let list = WaitList::new();
+        let mut tries = 0;
+        let v = block_on(&list, || {
+            tries += 1;
+            (tries == 3).then_some(tries)
+        });
+        assert_eq!(v, 3);
+        assert!(list.is_empty());
+    }
+}
@@ -0,0 +1,96 @@
+//! SecureIoTOS Kernel Build Script
+//! -------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author : Md Mahbubur Rahman
+//! URL    : https://m-a-h-b-u-b.github.io
+//! GitHub : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Computes the inputs of the firmware build ID (see `src/image.rs`) and
+//! writes them to `$OUT_DIR/build_id.rs`:
+//!
+//! - the git commit (`git rev-parse HEAD`, or `SIOS_GIT_HASH` for builds
+//!   outside a checkout) and whether tracked files have local changes;
+//! - the SHA-256 of the build configuration: target, profile, opt-level,
+//!   enabled features and the free-form `SIOS_BUILD_CONFIG` (board, ...).
+//!
+//! Nothing else (no time, host or path) goes in, so the same commit and
+//! configuration always give the same ID.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+use sha2::{Digest, Sha256};
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=SIOS_GIT_HASH");
+    println!("cargo:rerun-if-env-changed=SIOS_BUILD_CONFIG");
+
+    let (commit, dirty) = git_commit();
+    let config = Sha256::digest(config_string().as_bytes());
+
+    let out = PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR is set by cargo")).join("build_id.rs");
+    let code = format!(
+        "/// First 12 bytes of the git commit hash.\n\
+         pub const GIT_HASH: [u8; 12] = {:?};\n\
+         /// First 8 bytes of the SHA-256 of the build configuration.\n\
+         pub const CONFIG_HASH: [u8; 8] = {:?};\n\
+         /// Tracked files had uncommitted changes.\n\
+         pub const DIRTY: bool = {};\n",
+        &commit[..12],
+        &config[..8],
+        dirty,
+    );
+    fs::write(out, code).expect("write build_id.rs");
+}
+
+/// Commit hash (all zero if unknown) and the dirty flag.
+fn git_commit() -> ([u8; 20], bool) {
+    if let Ok(hex) = env::var("SIOS_GIT_HASH") {
+        return (parse_hash(&hex).expect("SIOS_GIT_HASH must be a 40-digit hex commit hash"), false);
+    }
+    if let Some(dir) = git(&["rev-parse", "--git-dir"]) {
+        println!("cargo:rerun-if-changed={dir}/HEAD");
+        println!("cargo:rerun-if-changed={dir}/index");
+    }
+    let commit = git(&["rev-parse", "HEAD"]).and_then(|h| parse_hash(&h)).unwrap_or([0; 20]);
+    let dirty = git(&["status", "--porcelain", "--untracked-files=no"]).is_some_and(|s| !s.is_empty());
+    (commit, dirty)
+}
+
+fn git(args: &[&str]) -> Option<String> {
+    let out = Command::new("git").args(args).current_dir(env::var("CARGO_MANIFEST_DIR").ok()?).output().ok()?;
+    out.status.success().then(|| String::from_utf8_lossy(&out.stdout).trim().to_string())
+}
+
+fn parse_hash(hex: &str) -> Option<[u8; 20]> {
+    let hex = hex.trim();
+    if hex.len() != 40 {
+        return None;
+    }
+    let mut hash = [0u8; 20];
+    for (i, b) in hash.iter_mut().enumerate() {
+        *b = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(hash)
+}
+
+/// Everything about the configuration that changes the image.
+fn config_string() -> String {
+    let var = |k: &str| env::var(k).unwrap_or_default();
+    let mut features: Vec<String> =
+        env::vars().filter_map(|(k, _)| k.strip_prefix("CARGO_FEATURE_").map(str::to_string)).collect();
+    features.sort();
+    format!(
+        "target={};profile={};opt={};features={};config={}",
+        var("TARGET"),
+        var("PROFILE"),
+        var("OPT_LEVEL"),
+        features.join(","),
+        var("SIOS_BUILD_CONFIG"),
+    )
+}
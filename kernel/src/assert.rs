@@ -0,0 +1,241 @@
+//! SecureIoTOS Kernel Assert Module
+//! --------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author : Md Mahbubur Rahman
+//! URL    : https://m-a-h-b-u-b.github.io
+//! GitHub : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Runtime assertion and invariant checking for kernel code.
+//!
+//! `panic!()` in a `no_std` kernel is a poor failure mode: the message is
+//! formatted into nowhere, and whatever the panic handler does is the same
+//! for a cosmetic invariant and a corrupted task table. Kernel paths use
+//! `kassert!()` / `kfail!()` instead, whose behaviour depends on the build
+//! profile:
+//!
+//! - **debug** (`debug_assertions`): report through the log hook, then halt
+//!   at a breakpoint so the debugger stops on the failing line.
+//! - **release**: record the failure in a small in-RAM log (readable later
+//!   via `assert_log()` or a crash dump) and then either continue or reset,
+//!   per the active `FailurePolicy`.
+//!
+//! `kassert!()` evaluates to `true` if the condition held, so release code
+//! that continues after a failure can still bail out of the current
+//! operation:
+//!
+//! ```ignore
+//! if !kassert!(idx < tasks.len(), "task index out of range") {
+//!     return Err(SchedError::Corrupt);
+//! }
+//! ```
+
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+
+/// What release builds do after recording a failed assertion.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailurePolicy {
+    /// Record and keep running
+    Continue,
+    /// Record and reset the system
+    Reset,
+}
+
+/// Default release policy; the `kassert-reset` feature selects `Reset`.
+#[cfg(not(feature = "kassert-reset"))]
+pub const DEFAULT_POLICY: FailurePolicy = FailurePolicy::Continue;
+#[cfg(feature = "kassert-reset")]
+pub const DEFAULT_POLICY: FailurePolicy = FailurePolicy::Reset;
+
+/// Action taken for one failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Halt,
+    Continue,
+    Reset,
+}
+
+/// One recorded assertion failure.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AssertRecord {
+    pub file: &'static str,
+    pub line: u32,
+    pub msg: &'static str,
+    /// Optional error/diagnostic code supplied by the call site
+    pub code: u32,
+}
+
+/// Number of failures kept in the log (oldest are overwritten).
+pub const ASSERT_LOG_DEPTH: usize = 8;
+
+/// Ring of the most recent failures plus a total counter.
+#[derive(Debug, Clone, Copy)]
+pub struct AssertLog {
+    records: [Option<AssertRecord>; ASSERT_LOG_DEPTH],
+    next: usize,
+    total: u32,
+}
+
+impl AssertLog {
+    pub const fn new() -> Self {
+        Self { records: [None; ASSERT_LOG_DEPTH], next: 0, total: 0 }
+    }
+
+    pub fn push(&mut self, rec: AssertRecord) {
+        self.records[self.next] = Some(rec);
+        self.next = (self.next + 1) % ASSERT_LOG_DEPTH;
+        self.total = self.total.saturating_add(1);
+    }
+
+    /// Total failures since boot (including overwritten ones).
+    pub fn total(&self) -> u32 {
+        self.total
+    }
+
+    /// Most recent failure.
+    pub fn last(&self) -> Option<AssertRecord> {
+        self.records[(self.next + ASSERT_LOG_DEPTH - 1) % ASSERT_LOG_DEPTH]
+    }
+
+    /// Recorded failures, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &AssertRecord> {
+        let (newer, older) = self.records.split_at(self.next);
+        older.iter().chain(newer.iter()).flatten()
+    }
+}
+
+impl Default for AssertLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct AssertState {
+    log: AssertLog,
+    policy: FailurePolicy,
+    hook: Option<fn(&AssertRecord)>,
+}
+
+static STATE: Mutex<RefCell<AssertState>> = Mutex::new(RefCell::new(AssertState {
+    log: AssertLog::new(),
+    policy: DEFAULT_POLICY,
+    hook: None,
+}));
+
+/// Override the release-build failure policy at runtime.
+pub fn set_failure_policy(policy: FailurePolicy) {
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().policy = policy);
+}
+
+/// Install a reporting hook (UART/RTT logger, telemetry counter, ...).
+/// Runs inside a critical section: keep it short and never assert in it.
+pub fn set_assert_hook(hook: fn(&AssertRecord)) {
+    interrupt::free(|cs| STATE.borrow(cs).borrow_mut().hook = Some(hook));
+}
+
+/// Copy of the failure log.
+pub fn assert_log() -> AssertLog {
+    interrupt::free(|cs| STATE.borrow(cs).borrow().log)
+}
+
+/// Decide what to do for a failure given the build profile and policy.
+pub const fn action_for(debug_build: bool, policy: FailurePolicy) -> Action {
+    if debug_build {
+        return Action::Halt;
+    }
+    match policy {
+        FailurePolicy::Continue => Action::Continue,
+        FailurePolicy::Reset => Action::Reset,
+    }
+}
+
+/// Slow path of `kassert!()` / `kfail!()`. Not meant to be called directly.
+#[cold]
+#[inline(never)]
+pub fn assert_failed(file: &'static str, line: u32, msg: &'static str, code: u32) {
+    let rec = AssertRecord { file, line, msg, code };
+    let action = interrupt::free(|cs| {
+        let mut state = STATE.borrow(cs).borrow_mut();
+        state.log.push(rec);
+        if let Some(hook) = state.hook {
+            hook(&rec);
+        }
+        action_for(cfg!(debug_assertions), state.policy)
+    });
+
+    match action {
+        Action::Continue => {}
+        Action::Reset => cortex_m::peripheral::SCB::sys_reset(),
+        Action::Halt => {
+            interrupt::disable();
+            loop {
+                // Stop in the debugger; without one attached, stay halted.
+                cortex_m::asm::bkpt();
+            }
+        }
+    }
+}
+
+/// Check a kernel invariant. Evaluates to `true` if it held.
+///
+/// `kassert!(cond)`, `kassert!(cond, "msg")` or `kassert!(cond, "msg", code)`.
+#[macro_export]
+macro_rules! kassert {
+    ($cond:expr $(,)?) => {
+        $crate::kassert!($cond, concat!("assertion failed: ", stringify!($cond)), 0)
+    };
+    ($cond:expr, $msg:expr $(,)?) => {
+        $crate::kassert!($cond, $msg, 0)
+    };
+    ($cond:expr, $msg:expr, $code:expr $(,)?) => {{
+        let ok: bool = $cond;
+        if !ok {
+            $crate::assert::assert_failed(file!(), line!(), $msg, $code as u32);
+        }
+        ok
+    }};
+}
+
+/// Report an unconditional failure (replacement for `panic!()` in kernel
+/// paths). Continues afterwards only under `FailurePolicy::Continue`.
+#[macro_export]
+macro_rules! kfail {
+    ($msg:expr $(,)?) => {
+        $crate::assert::assert_failed(file!(), line!(), $msg, 0)
+    };
+    ($msg:expr, $code:expr $(,)?) => {
+        $crate::assert::assert_failed(file!(), line!(), $msg, $code as u32)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rec(line: u32) -> AssertRecord {
+        AssertRecord { file: "k.rs", line, msg: "x", code: 0 }
+    }
+
+    #[test]
+    fn log_keeps_most_recent_failures_in_order() {
+        let mut log = AssertLog::new();
+        assert!(log.last().is_none());
+        for line in 0..(ASSERT_LOG_DEPTH as u32 + 3) {
+            log.push(rec(line));
+        }
+        assert_eq!(log.total(), ASSERT_LOG_DEPTH as u32 + 3);
+        assert_eq!(log.last().unwrap().line, ASSERT_LOG_DEPTH as u32 + 2);
+        let first = log.iter().next().unwrap().line;
+        assert_eq!(first, 3);
+        assert_eq!(log.iter().count(), ASSERT_LOG_DEPTH);
+    }
+
+    #[test]
+    fn profile_and_policy_select_action() {
+        assert_eq!(action_for(true, FailurePolicy::Continue), Action::Halt);
+        assert_eq!(action_for(false, FailurePolicy::Continue), Action::Continue);
+        assert_eq!(action_for(false, FailurePolicy::Reset), Action::Reset);
+    }
+}
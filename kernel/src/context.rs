@@ -8,66 +8,320 @@
 //! GitHub : https://github.com/m-a-h-b-u-b/SecureIoTOS
 //!
 //! This module manages task contexts (register state, privilege level,
-//! and stack pointers). It provides `context_switch`, which saves the
-//! current CPU state and restores the next task’s CPU state.
+//! and stack pointers) and provides the `PendSV` handler that performs
+//! the actual context switch requested by `scheduler::schedule()`.
+//!
+//! Saved context, lowest address first (`Task::stack_pointer` points at R4):
+//!
+//! | words   | pushed by | contents                                   |
+//! |---------|-----------|--------------------------------------------|
+//! | 0..8    | `PendSV`  | R4-R11                                     |
+//! | 8       | `PendSV`  | EXC_RETURN of the task                     |
+//! | (16)    | `PendSV`  | S16-S31, only if the task has an FP frame  |
+//! | 8 words | hardware  | R0-R3, R12, LR, PC, xPSR (+ S0-S15, FPSCR) |
+//!
+//! EXC_RETURN is saved per task so a task that used the FPU returns with
+//! its extended frame and one that did not returns with the basic frame.
+//! Privilege is not part of EXC_RETURN: `PendSV` sets CONTROL.nPRIV from
+//! the incoming task's `privilege` before returning to Thread mode.
 //!
 //! NOTE: This is Cortex-M specific and requires `unsafe` assembly
 //! for manipulating registers like PSP/MSP and general-purpose registers.
 
+// Core kernel path: must not panic (see `tools/no-panic-check`).
+#![cfg_attr(not(test), deny(
+    clippy::panic,
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::indexing_slicing,
+    clippy::unreachable,
+    clippy::todo,
+    clippy::unimplemented
+))]
+
+use memory::stack::GuardedStack;
+
+use crate::stack_guard::StackBounds;
+
 /// Representation of a task in the system.
 ///
 /// Each task has:
 /// - `id`: unique identifier.
 /// - `privilege`: 0 = kernel, 1 = user.
 /// - `stack_pointer`: saved stack pointer for context switching.
+/// - `capabilities`: `syscall::caps` bits the task holds. Granted when the
+///   task is created; changed afterwards only through the `DelegateCaps`
+///   syscall (see `scheduler::delegate_capabilities`).
+/// - `stack`: stack memory, checked for overflow at every switch away
+///   (`stack_guard`); `None` if unknown.
 #[derive(Clone, Debug)]
 pub struct Task {
     pub id: u32,
     pub privilege: u8,
     pub stack_pointer: *mut u32,
+    pub capabilities: u32,
+    pub stack: Option<StackBounds>,
 }
 
-/// Performs a context switch between two tasks.
+/// EXC_RETURN for a task that has not run yet: Thread mode, PSP, basic
+/// (non-FP) frame.
+pub const EXC_RETURN_THREAD_PSP: u32 = 0xFFFF_FFFD;
+
+/// EXC_RETURN bit 4: clear when the hardware stacked an extended FP frame.
+pub const EXC_RETURN_FTYPE: u32 = 1 << 4;
+
+/// Words saved by `PendSV` below the hardware frame (R4-R11, EXC_RETURN).
+pub const SW_FRAME_WORDS: usize = 9;
+
+/// Words of the basic frame stacked by hardware on exception entry.
+pub const HW_FRAME_WORDS: usize = 8;
+
+/// Initial xPSR: only the Thumb bit set.
+const INITIAL_XPSR: u32 = 0x0100_0000;
+
+/// Lay out the initial context of a task at the top of `stack`, so the
+/// first `PendSV` switch to it "returns" into `entry` with `arg` in R0.
+/// `exit` is placed in LR and runs if `entry` returns.
 ///
-/// # Safety
-/// This involves `unsafe` assembly for low-level register operations.
-/// Interrupts should be disabled during the switch.
-pub fn context_switch(current: &mut Task, next: &Task) {
-    unsafe {
-        save_cpu_state(current);
-        restore_cpu_state(next);
+/// Returns the value to store in `Task::stack_pointer`, or `None` if the
+/// stack cannot hold the frame.
+pub fn init_stack(stack: &mut [u32], entry: usize, exit: usize, arg: u32) -> Option<*mut u32> {
+    // Keep the hardware frame 8-byte aligned so xPSR needs no padding bit.
+    let base = stack.as_mut_ptr() as usize;
+    let top = (base + stack.len() * 4) & !0x7;
+    let sp = top.checked_sub((SW_FRAME_WORDS + HW_FRAME_WORDS) * 4)?;
+    let start = sp.checked_sub(base)? / 4;
+    let frame = stack.get_mut(start..start + SW_FRAME_WORDS + HW_FRAME_WORDS)?;
+
+    frame.fill(0);
+    let (sw, hw) = frame.split_at_mut(SW_FRAME_WORDS);
+    *sw.last_mut()? = EXC_RETURN_THREAD_PSP;
+    // [0] = R0, [1..4] = R1-R3, [4] = R12
+    *hw.first_mut()? = arg;
+    *hw.get_mut(5)? = exit as u32; // LR
+    *hw.get_mut(6)? = entry as u32 & !1; // PC (Thumb bit lives in xPSR)
+    *hw.get_mut(7)? = INITIAL_XPSR;
+    Some(frame.as_mut_ptr())
+}
+
+/// Like `init_stack()`, and also paint `stack` for watermarking and put
+/// the canary at its bottom (`memory::stack::init_task_stack`), so the
+/// scheduler can check it on every switch.
+///
+/// Returns `Task::stack_pointer` and `Task::stack`, or `None` if the stack
+/// cannot hold the canary and the frame.
+pub fn init_checked_stack(stack: &mut [u32], entry: usize, exit: usize, arg: u32) -> Option<(*mut u32, StackBounds)> {
+    let bottom = stack.as_mut_ptr() as usize;
+    let top = bottom + core::mem::size_of_val(stack);
+    // SAFETY: the same memory viewed as bytes, for as long as `stack` is
+    // borrowed here; `u8` has no alignment or validity requirements.
+    let bytes = unsafe { core::slice::from_raw_parts_mut(stack.as_mut_ptr().cast::<u8>(), top - bottom) };
+    if bytes.len() < memory::stack::STACK_CANARY.len() {
+        return None;
     }
+    memory::stack::init_task_stack(bytes);
+    let sp = init_stack(stack, entry, exit, arg)?;
+    let bounds = StackBounds { bottom, top, guard: None };
+    // The frame must not reach into the canary
+    (sp as usize >= bounds.limit()).then_some((sp, bounds))
 }
 
-/// Save CPU registers and update the task's stack pointer.
+/// `init_checked_stack()` for a stack from a `GuardedStackPool`. The
+/// bounds also carry its guard, which the scheduler moves below the stack
+/// whenever the task runs (`stack_guard::enter`), so an overflow faults on
+/// the first push past the bottom.
 ///
-/// On ARM Cortex-M, the hardware automatically saves some registers
-/// (R0-R3, R12, LR, PC, xPSR) on exception entry.  
-/// This function saves callee-saved registers (R4-R11).
-unsafe fn save_cpu_state(task: &mut Task) {
-    let sp: *mut u32;
-    core::arch::asm!(
-        "
-        mrs {0}, psp          // Read Process Stack Pointer
-        stmdb {0}!, {{r4-r11}} // Push R4-R11 onto stack
-        ",
-        out(reg) sp,
-        options(nostack)
+/// `guarded` stays with the caller, to return its slot to the pool once
+/// the task is gone.
+pub fn init_guarded_stack(guarded: &mut GuardedStack, entry: usize, exit: usize, arg: u32) -> Option<(*mut u32, StackBounds)> {
+    // SAFETY: any bytes are valid `u32`s; pool stacks are aligned to and
+    // sized in multiples of `STACK_GUARD_SIZE`, which is checked below.
+    let (head, words, tail) = unsafe { guarded.stack.align_to_mut::<u32>() };
+    if !head.is_empty() || !tail.is_empty() {
+        return None;
+    }
+    let (sp, bounds) = init_checked_stack(words, entry, exit, arg)?;
+    Some((sp, StackBounds { guard: Some(guarded.guard), ..bounds }))
+}
+
+/// PendSV exception: save the running task's context, let the scheduler
+/// pick the next task, restore its context.
+///
+/// Exported under its vector table name, like `time::SysTick`; it must
+/// run at the lowest exception priority so it never preempts another
+/// handler's frame on the main stack. R0-R3/R12 are caller-saved and
+/// already on the task stack, and LR holds EXC_RETURN on entry, so the
+/// call into `pendsv_switch` only needs LR saved with the task context.
+///
+/// # Safety
+/// Only the exception mechanism may call this.
+#[cfg(target_arch = "arm")]
+#[unsafe(naked)]
+#[no_mangle]
+pub unsafe extern "C" fn PendSV() {
+    #[cfg(target_abi = "eabihf")]
+    core::arch::naked_asm!(
+        // Naked functions are assembled without the target's FPU
+        // features; name the FPU for the S16-S31 transfers.
+        ".fpu fpv4-sp-d16",
+        "mrs r0, psp",
+        "isb",
+        // Lazy FP stacking: save S16-S31 only if the task has an FP frame
+        "tst lr, #{ftype}",
+        "it eq",
+        "vstmdbeq r0!, {{s16-s31}}",
+        "stmdb r0!, {{r4-r11, lr}}",
+        "cpsid i",
+        "bl {switch}",
+        "cpsie i",
+        "ldmia r0!, {{r4-r11, lr}}",
+        "tst lr, #{ftype}",
+        "it eq",
+        "vldmiaeq r0!, {{s16-s31}}",
+        "msr psp, r0",
+        "isb",
+        "bx lr",
+        ftype = const EXC_RETURN_FTYPE,
+        switch = sym pendsv_switch,
     );
-    task.stack_pointer = sp;
+    #[cfg(not(target_abi = "eabihf"))]
+    core::arch::naked_asm!(
+        "mrs r0, psp",
+        "isb",
+        "stmdb r0!, {{r4-r11, lr}}",
+        "cpsid i",
+        "bl {switch}",
+        "cpsie i",
+        "ldmia r0!, {{r4-r11, lr}}",
+        "msr psp, r0",
+        "isb",
+        "bx lr",
+        switch = sym pendsv_switch,
+    );
+}
+
+/// Words `PendSV` may save for the boot code: R4-R11, EXC_RETURN and,
+/// if it used the FPU, S16-S31.
+#[cfg(target_arch = "arm")]
+const BOOT_SCRATCH_WORDS: usize = SW_FRAME_WORDS + 16;
+
+/// Start multitasking: pend the switch to the first task.
+///
+/// Boot code runs in Thread mode on MSP, but `PendSV` always saves the
+/// outgoing context below PSP, so PSP is first pointed at a scratch area.
+/// The scheduler does not record that context (no task ran before), and
+/// `PendSV` returns into the first task on its own stack. The boot code
+/// only resumes if there is no task to run yet.
+///
+/// Must be called in privileged Thread mode on MSP, once, after
+/// `init::kernel_init()`.
+#[cfg(target_arch = "arm")]
+pub fn start_first_task() {
+    static mut BOOT_SCRATCH: [u64; BOOT_SCRATCH_WORDS.div_ceil(2)] = [0; BOOT_SCRATCH_WORDS.div_ceil(2)];
+    // SAFETY: only the address is taken; the area is written by `PendSV`
+    // alone.
+    let top = unsafe { core::ptr::addr_of_mut!(BOOT_SCRATCH).add(1) } as u32;
+    // SAFETY: Thread mode runs on MSP here, so PSP is free to move.
+    unsafe { crate::init::set_psp(top) };
+    crate::scheduler::schedule();
+    // SAFETY: `kernel_init` has set up the scheduler and its handlers;
+    // the pended PendSV is taken as soon as interrupts are unmasked.
+    unsafe { cortex_m::interrupt::enable() };
+    cortex_m::asm::isb();
 }
 
-/// Restore CPU registers from the task's stack pointer.
+/// Called by `PendSV` with the outgoing task's saved stack pointer;
+/// returns the stack pointer to restore. If the scheduler cannot switch,
+/// the outgoing task is resumed. Registered secure RAM regions are
+/// resealed on every switch (`memory::secure_ram::reseal_registered`).
+#[cfg(target_arch = "arm")]
+extern "C" fn pendsv_switch(saved_sp: *mut u32) -> *mut u32 {
+    // SAFETY: called from PendSV with interrupts disabled.
+    match unsafe { crate::scheduler::do_context_switch(saved_sp) } {
+        Ok((next_sp, privilege)) => {
+            memory::secure_ram::reseal_registered();
+            // SAFETY: Handler mode; takes effect on exception return.
+            unsafe { set_thread_privilege(privilege) };
+            next_sp
+        }
+        Err(_) => saved_sp,
+    }
+}
+
+/// Set CONTROL.nPRIV for Thread mode: `privilege` 0 = privileged,
+/// anything else = unprivileged.
 ///
-/// Restores R4-R11 and updates the PSP (Process Stack Pointer).
-unsafe fn restore_cpu_state(task: &Task) {
-    let sp = task.stack_pointer;
+/// # Safety
+/// Must run in Handler mode (or privileged Thread mode).
+#[cfg(target_arch = "arm")]
+unsafe fn set_thread_privilege(privilege: u8) {
+    let unprivileged = u32::from(privilege != 0);
     core::arch::asm!(
-        "
-        ldmia {0}!, {{r4-r11}} // Pop R4-R11 from stack
-        msr psp, {0}           // Restore PSP
-        ",
-        inout(reg) (sp) => _,
-        options(nostack)
+        "mrs {tmp}, CONTROL",
+        "bic {tmp}, {tmp}, #1",
+        "orr {tmp}, {tmp}, {npriv}",
+        "msr CONTROL, {tmp}",
+        "isb",
+        tmp = out(reg) _,
+        npriv = in(reg) unprivileged,
+        options(nostack, preserves_flags)
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn initial_frame_layout() {
+        let mut stack = [0xAAAA_AAAAu32; 64];
+        let sp = init_stack(&mut stack, 0x0800_1235, 0x0800_2001, 7).unwrap();
+
+        let base = stack.as_ptr() as usize;
+        let off = (sp as usize - base) / 4;
+        assert_eq!(sp as usize % 4, 0);
+        assert_eq!((sp as usize + SW_FRAME_WORDS * 4) % 8, 0, "hw frame is 8-byte aligned");
+
+        let frame = &stack[off..off + SW_FRAME_WORDS + HW_FRAME_WORDS];
+        assert!(frame[..8].iter().all(|&w| w == 0), "R4-R11 cleared");
+        assert_eq!(frame[8], EXC_RETURN_THREAD_PSP);
+        assert_eq!(frame[9], 7, "R0 = arg");
+        assert_eq!(frame[14], 0x0800_2001, "LR = exit");
+        assert_eq!(frame[15], 0x0800_1234, "PC without Thumb bit");
+        assert_eq!(frame[16], INITIAL_XPSR);
+    }
+
+    #[test]
+    fn too_small_stack_is_rejected() {
+        let mut stack = [0u32; SW_FRAME_WORDS + HW_FRAME_WORDS - 1];
+        assert!(init_stack(&mut stack, 0, 0, 0).is_none());
+        let mut stack = [0u32; SW_FRAME_WORDS + HW_FRAME_WORDS + 1];
+        assert!(init_checked_stack(&mut stack, 0, 0, 0).is_none(), "frame would overlap the canary");
+    }
+
+    #[test]
+    fn guarded_stack_carries_its_guard() {
+        static POOL: memory::stack::GuardedStackPool<1, 256> = memory::stack::GuardedStackPool::new();
+        let mut guarded = POOL.alloc(200).unwrap();
+        let (sp, bounds) = init_guarded_stack(&mut guarded, 0x0800_0001, 0x0800_0001, 0).unwrap();
+        assert_eq!(bounds.guard, Some(guarded.guard));
+        assert_eq!(bounds.bottom, guarded.guard + memory::stack::STACK_GUARD_SIZE);
+        let task = Task { id: 1, privilege: 1, stack_pointer: sp, capabilities: 0, stack: Some(bounds) };
+        assert_eq!(crate::stack_guard::check(&task), Ok(()));
+    }
+
+    #[test]
+    fn checked_stack_passes_the_guard_until_overflow() {
+        let mut stack = [0u32; 64];
+        let (sp, bounds) = init_checked_stack(&mut stack, 0x0800_1235, 0x0800_2001, 0).unwrap();
+        let mut task = Task { id: 1, privilege: 1, stack_pointer: sp, capabilities: 0, stack: Some(bounds) };
+        assert_eq!(crate::stack_guard::check(&task), Ok(()));
+        assert_eq!(stack[2], u32::from_ne_bytes([memory::stack::STACK_PATTERN; 4]), "painted");
+
+        task.stack_pointer = stack.as_mut_ptr();
+        assert_eq!(crate::stack_guard::check(&task), Err(crate::stack_guard::StackFault::SpOutOfBounds));
+        // SAFETY: the bottom byte of `stack`, which outlives the task.
+        unsafe { (bounds.bottom as *mut u8).write(0) };
+        assert_eq!(crate::stack_guard::check(&task), Err(crate::stack_guard::StackFault::CanaryCorrupted));
+    }
+}
@@ -0,0 +1,374 @@
+//! SecureIoTOS Kernel Crash Dump Module
+//! ------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author : Md Mahbubur Rahman
+//! URL    : https://m-a-h-b-u-b.github.io
+//! GitHub : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Compact crash dumps for field debugging.
+//!
+//! The fault handlers (`fault`) build a `CrashDump` (stacked registers,
+//! fault status registers, a snippet of the faulting stack, the build ID)
+//! and pass it to `record()`, which encodes it into a RAM slot that
+//! survives a warm reset. After the reboot, `upload_pending()` hands the
+//! encoded dump to a `CrashSink` (normally the telemetry uplink) and
+//! clears the slot once the sink accepted it. On the host, `tools/crash-symbolicate` decodes
+//! the dump and resolves PC, LR and stack words against the ELF of the
+//! matching build.
+//!
+//! Encoding (version 1, little-endian, at most `MAX_DUMP_LEN` bytes):
+//!
+//! ```text
+//!   "SCD" | version(1) | build_id(20) | reason(1) | stack_words(1) | 0(2)
+//!   | task(4) | r0 r1 r2 r3 r12 lr pc xpsr sp (9 x 4)
+//!   | cfsr hfsr mmfar bfar (4 x 4) | stack (stack_words x 4) | crc32(4)
+//! ```
+//!
+//! The CRC (IEEE) covers everything before it, so garbage left in the slot
+//! by a cold boot is never mistaken for a dump.
+
+use core::mem::MaybeUninit;
+
+pub use crate::image::BUILD_ID_LEN;
+/// Maximum number of stack words kept.
+pub const STACK_WORDS: usize = 16;
+/// Format version written by `encode()`.
+pub const FORMAT_VERSION: u8 = 1;
+/// Encoded length without stack words.
+pub const FIXED_LEN: usize = HEADER_LEN + 4;
+/// Largest encoded dump.
+pub const MAX_DUMP_LEN: usize = FIXED_LEN + STACK_WORDS * 4;
+
+const MAGIC: &[u8; 3] = b"SCD";
+/// Everything before the stack words.
+const HEADER_LEN: usize = 84;
+/// Encoding of "no task" (fault in handler or boot code).
+const NO_TASK: u32 = u32::MAX;
+
+/// What brought the system down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CrashReason {
+    HardFault = 1,
+    MemManage = 2,
+    BusFault = 3,
+    UsageFault = 4,
+    /// Stack check failed on a context switch
+    StackOverflow = 5,
+    Panic = 6,
+}
+
+impl CrashReason {
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(CrashReason::HardFault),
+            2 => Some(CrashReason::MemManage),
+            3 => Some(CrashReason::BusFault),
+            4 => Some(CrashReason::UsageFault),
+            5 => Some(CrashReason::StackOverflow),
+            6 => Some(CrashReason::Panic),
+            _ => None,
+        }
+    }
+}
+
+/// Registers stacked by the hardware on exception entry.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[repr(C)]
+pub struct ExceptionFrame {
+    pub r0: u32,
+    pub r1: u32,
+    pub r2: u32,
+    pub r3: u32,
+    pub r12: u32,
+    pub lr: u32,
+    pub pc: u32,
+    pub xpsr: u32,
+}
+
+impl ExceptionFrame {
+    /// Copy the frame stacked at `sp`.
+    ///
+    /// # Safety
+    /// `sp` must point to a readable exception frame (8 words).
+    pub unsafe fn read(sp: *const u32) -> Self {
+        // SAFETY: per the caller contract; `ExceptionFrame` is `repr(C)`
+        // with the hardware stacking order.
+        unsafe { sp.cast::<ExceptionFrame>().read_volatile() }
+    }
+}
+
+/// Fault status registers at the time of the crash (zero if not a fault).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FaultStatus {
+    pub cfsr: u32,
+    pub hfsr: u32,
+    pub mmfar: u32,
+    pub bfar: u32,
+}
+
+/// Why an encoded dump was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrashError {
+    /// Output buffer shorter than the encoding
+    BufferTooSmall,
+    /// Not a crash dump
+    BadMagic,
+    /// Written by a newer format version
+    UnsupportedVersion,
+    /// Shorter than its header says
+    Truncated,
+    /// CRC does not match
+    BadChecksum,
+    /// Unknown `CrashReason`
+    BadReason,
+}
+
+/// One crash, as recorded and uploaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CrashDump {
+    pub build_id: [u8; BUILD_ID_LEN],
+    pub reason: CrashReason,
+    /// Running task, `None` for handler or boot code
+    pub task: Option<u32>,
+    pub frame: ExceptionFrame,
+    /// Stack pointer before the exception frame was pushed
+    pub sp: u32,
+    pub status: FaultStatus,
+    stack: [u32; STACK_WORDS],
+    stack_len: u8,
+}
+
+impl CrashDump {
+    pub fn new(reason: CrashReason, task: Option<u32>, frame: ExceptionFrame, sp: u32, status: FaultStatus) -> Self {
+        Self {
+            build_id: crate::image::BUILD_ID,
+            reason,
+            task,
+            frame,
+            sp,
+            status,
+            stack: [0; STACK_WORDS],
+            stack_len: 0,
+        }
+    }
+
+    /// Keep up to `STACK_WORDS` of `words` (the top of the faulting stack).
+    pub fn set_stack(&mut self, words: &[u32]) {
+        let n = words.len().min(STACK_WORDS);
+        self.stack[..n].copy_from_slice(&words[..n]);
+        self.stack_len = n as u8;
+    }
+
+    /// Stack words kept, from the stack pointer upwards.
+    pub fn stack(&self) -> &[u32] {
+        &self.stack[..self.stack_len as usize]
+    }
+
+    /// Length of the encoding.
+    pub fn encoded_len(&self) -> usize {
+        FIXED_LEN + self.stack().len() * 4
+    }
+
+    /// Encode into `out`; returns the number of bytes written.
+    pub fn encode(&self, out: &mut [u8]) -> Result<usize, CrashError> {
+        let len = self.encoded_len();
+        let out = out.get_mut(..len).ok_or(CrashError::BufferTooSmall)?;
+        out[..3].copy_from_slice(MAGIC);
+        out[3] = FORMAT_VERSION;
+        out[4..24].copy_from_slice(&self.build_id);
+        out[24] = self.reason as u8;
+        out[25] = self.stack_len;
+        out[26..28].fill(0);
+        let f = &self.frame;
+        let s = &self.status;
+        let words = [
+            self.task.unwrap_or(NO_TASK),
+            f.r0,
+            f.r1,
+            f.r2,
+            f.r3,
+            f.r12,
+            f.lr,
+            f.pc,
+            f.xpsr,
+            self.sp,
+            s.cfsr,
+            s.hfsr,
+            s.mmfar,
+            s.bfar,
+        ];
+        for (chunk, w) in out[28..].chunks_exact_mut(4).zip(words.iter().chain(self.stack())) {
+            chunk.copy_from_slice(&w.to_le_bytes());
+        }
+        let crc = crc32(&out[..len - 4]);
+        out[len - 4..].copy_from_slice(&crc.to_le_bytes());
+        Ok(len)
+    }
+
+    /// Decode and verify a dump produced by `encode()`.
+    pub fn decode(bytes: &[u8]) -> Result<Self, CrashError> {
+        if bytes.len() < 4 || &bytes[..3] != MAGIC {
+            return Err(CrashError::BadMagic);
+        }
+        if bytes[3] != FORMAT_VERSION {
+            return Err(CrashError::UnsupportedVersion);
+        }
+        if bytes.len() < FIXED_LEN {
+            return Err(CrashError::Truncated);
+        }
+        let stack_len = (bytes[25] as usize).min(STACK_WORDS);
+        let len = FIXED_LEN + stack_len * 4;
+        let bytes = bytes.get(..len).ok_or(CrashError::Truncated)?;
+        let crc = u32::from_le_bytes([bytes[len - 4], bytes[len - 3], bytes[len - 2], bytes[len - 1]]);
+        if crc32(&bytes[..len - 4]) != crc {
+            return Err(CrashError::BadChecksum);
+        }
+        let word = |i: usize| {
+            let o = 28 + i * 4;
+            u32::from_le_bytes([bytes[o], bytes[o + 1], bytes[o + 2], bytes[o + 3]])
+        };
+
+        let mut build_id = [0u8; BUILD_ID_LEN];
+        build_id.copy_from_slice(&bytes[4..24]);
+        let mut stack = [0u32; STACK_WORDS];
+        for (i, w) in stack[..stack_len].iter_mut().enumerate() {
+            *w = word(14 + i);
+        }
+        Ok(Self {
+            build_id,
+            reason: CrashReason::from_u8(bytes[24]).ok_or(CrashError::BadReason)?,
+            task: Some(word(0)).filter(|&t| t != NO_TASK),
+            frame: ExceptionFrame {
+                r0: word(1),
+                r1: word(2),
+                r2: word(3),
+                r3: word(4),
+                r12: word(5),
+                lr: word(6),
+                pc: word(7),
+                xpsr: word(8),
+            },
+            sp: word(9),
+            status: FaultStatus { cfsr: word(10), hfsr: word(11), mmfar: word(12), bfar: word(13) },
+            stack,
+            stack_len: stack_len as u8,
+        })
+    }
+}
+
+/// CRC-32 (IEEE 802.3, reflected), bitwise: dumps are rare and small.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Retained slot: not zeroed by the runtime, so a dump written before a
+/// reset is still there after it.
+#[cfg_attr(target_os = "none", link_section = ".uninit.crash")]
+static mut CRASH_SLOT: MaybeUninit<[u8; MAX_DUMP_LEN]> = MaybeUninit::uninit();
+
+/// Store `dump` in the retained slot, replacing any earlier one. Takes no
+/// locks, so fault handlers can call it.
+pub fn record(dump: &CrashDump) {
+    let mut buf = [0u8; MAX_DUMP_LEN];
+    if dump.encode(&mut buf).is_ok() {
+        // SAFETY: the slot is only accessed through `record()`,
+        // `pending()` and `clear()`, which copy it whole; a fault handler
+        // preempting one of the others leaves at worst a torn dump that
+        // fails its CRC.
+        unsafe { core::ptr::addr_of_mut!(CRASH_SLOT).cast::<[u8; MAX_DUMP_LEN]>().write_volatile(buf) };
+    }
+}
+
+/// The dump in the retained slot, if there is a valid one.
+pub fn pending() -> Option<CrashDump> {
+    // SAFETY: see `record()`. After a cold boot the slot holds whatever
+    // the RAM powered up with, which `decode()` rejects.
+    let buf = unsafe { core::ptr::addr_of!(CRASH_SLOT).cast::<[u8; MAX_DUMP_LEN]>().read_volatile() };
+    CrashDump::decode(&buf).ok()
+}
+
+/// Forget the dump in the retained slot.
+pub fn clear() {
+    // SAFETY: see `record()`; zero bytes never decode.
+    unsafe { core::ptr::addr_of_mut!(CRASH_SLOT).cast::<[u8; MAX_DUMP_LEN]>().write_volatile([0; MAX_DUMP_LEN]) };
+}
+
+/// Where encoded dumps are sent (the telemetry uplink, a debug UART, ...).
+pub trait CrashSink {
+    type Error;
+
+    fn send(&mut self, dump: &[u8]) -> Result<(), Self::Error>;
+}
+
+/// Upload the pending dump, if any, and clear it once `sink` accepted it.
+/// Returns whether a dump was sent; on error the dump stays for a retry.
+pub fn upload_pending<S: CrashSink>(sink: &mut S) -> Result<bool, S::Error> {
+    let Some(dump) = pending() else {
+        return Ok(false);
+    };
+    let mut buf = [0u8; MAX_DUMP_LEN];
+    let Ok(len) = dump.encode(&mut buf) else {
+        return Ok(false);
+    };
+    sink.send(&buf[..len])?;
+    clear();
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> CrashDump {
+        let frame = ExceptionFrame { r0: 1, r1: 2, r2: 3, r3: 4, r12: 12, lr: 0x0800_0101, pc: 0x0800_0200, xpsr: 0x0100_0000 };
+        let status = FaultStatus { cfsr: 0x82, hfsr: 0, mmfar: 0x2000_0000, bfar: 0 };
+        let mut dump = CrashDump {
+            build_id: [0xAB; BUILD_ID_LEN],
+            reason: CrashReason::MemManage,
+            task: Some(3),
+            frame,
+            sp: 0x2000_1000,
+            status,
+            stack: [0; STACK_WORDS],
+            stack_len: 0,
+        };
+        dump.set_stack(&[0xDEAD_BEEF, 0x0800_0301]);
+        dump
+    }
+
+    #[test]
+    fn dump_round_trips() {
+        let dump = sample();
+        let mut buf = [0u8; MAX_DUMP_LEN];
+        let n = dump.encode(&mut buf).unwrap();
+        assert_eq!(n, FIXED_LEN + 8);
+        assert_eq!(CrashDump::decode(&buf[..n]), Ok(dump));
+
+        let mut no_task = dump;
+        no_task.task = None;
+        no_task.encode(&mut buf).unwrap();
+        assert_eq!(CrashDump::decode(&buf).unwrap().task, None, "trailing bytes are ignored");
+        assert_eq!(dump.encode(&mut buf[..n - 1]), Err(CrashError::BufferTooSmall));
+    }
+
+    #[test]
+    fn damaged_dumps_are_rejected() {
+        let mut buf = [0u8; MAX_DUMP_LEN];
+        let n = sample().encode(&mut buf).unwrap();
+        assert_eq!(CrashDump::decode(&[0; MAX_DUMP_LEN]), Err(CrashError::BadMagic));
+        assert_eq!(CrashDump::decode(&buf[..n - 2]), Err(CrashError::Truncated));
+        buf[40] ^= 1;
+        assert_eq!(CrashDump::decode(&buf[..n]), Err(CrashError::BadChecksum));
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+}
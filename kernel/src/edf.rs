@@ -0,0 +1,244 @@
+//! SecureIoTOS Kernel EDF Module
+//! -----------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author : Md Mahbubur Rahman
+//! URL    : https://m-a-h-b-u-b.github.io
+//! GitHub : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Bookkeeping for the earliest-deadline-first scheduler policy
+//! (`scheduler::Policy::Edf`).
+//!
+//! A periodic task declares `RtParams`: every `period` ticks it releases a
+//! job that needs at most `wcet` ticks of CPU and must finish within
+//! `deadline` ticks of its release (`wcet <= deadline <= period`). The
+//! task ends each job with `scheduler::wait_next_period()`.
+//!
+//! Admission control uses the density test: the set is accepted while
+//! `sum(wcet / deadline) <= 1`. For implicit deadlines (`deadline ==
+//! period`) this is exact for EDF; for shorter deadlines it is sufficient
+//! but pessimistic. Densities are summed in parts per million, rounded up,
+//! so rounding never admits an overloaded set.
+//!
+//! A job that has not completed when its deadline passes is reported once
+//! as a `DeadlineMiss`; it keeps running (late) until it completes or its
+//! next period starts. All times are `time::ticks()` values and are
+//! compared wrap-safely.
+
+// Core kernel path: must not panic (see `tools/no-panic-check`).
+#![cfg_attr(not(test), deny(
+    clippy::panic,
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::indexing_slicing,
+    clippy::unreachable,
+    clippy::todo,
+    clippy::unimplemented
+))]
+
+use crate::time::reached;
+
+/// Total density of an admissible task set, in parts per million.
+pub const FULL_LOAD_PPM: u32 = 1_000_000;
+
+/// Timing contract of a periodic task, in ticks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtParams {
+    /// Time between job releases
+    pub period: u32,
+    /// Relative deadline of each job
+    pub deadline: u32,
+    /// Worst-case execution time of each job
+    pub wcet: u32,
+}
+
+impl RtParams {
+    /// Parameters with the deadline at the end of the period.
+    pub const fn implicit(period: u32, wcet: u32) -> Self {
+        Self { period, deadline: period, wcet }
+    }
+
+    /// `0 < wcet <= deadline <= period`.
+    pub const fn is_valid(&self) -> bool {
+        self.wcet > 0 && self.wcet <= self.deadline && self.deadline <= self.period
+    }
+
+    /// `wcet / deadline` in parts per million, rounded up.
+    pub const fn density_ppm(&self) -> u32 {
+        if self.deadline == 0 {
+            return u32::MAX;
+        }
+        let ppm = (self.wcet as u64 * FULL_LOAD_PPM as u64).div_ceil(self.deadline as u64);
+        if ppm > u32::MAX as u64 {
+            u32::MAX
+        } else {
+            ppm as u32
+        }
+    }
+}
+
+/// Why a task set was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AdmitError {
+    /// Parameters violate `0 < wcet <= deadline <= period`
+    InvalidParams,
+    /// Total density would exceed `FULL_LOAD_PPM`
+    Infeasible,
+}
+
+/// Density test over `admitted` plus `candidate`. Returns the resulting
+/// total load in parts per million.
+pub fn admit<'a>(admitted: impl Iterator<Item = &'a RtParams>, candidate: &'a RtParams) -> Result<u32, AdmitError> {
+    if !candidate.is_valid() {
+        return Err(AdmitError::InvalidParams);
+    }
+    let load = admitted
+        .chain(core::iter::once(candidate))
+        .try_fold(0u32, |sum, p| sum.checked_add(p.density_ppm()))
+        .ok_or(AdmitError::Infeasible)?;
+    if load > FULL_LOAD_PPM {
+        return Err(AdmitError::Infeasible);
+    }
+    Ok(load)
+}
+
+/// A deadline that passed before its job completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadlineMiss {
+    /// Task id
+    pub task: u32,
+    /// Absolute deadline that was missed (ticks)
+    pub deadline: u32,
+    /// CPU ticks the job had used; above `wcet` means it overran its budget
+    pub used: u32,
+    /// Declared `wcet`
+    pub wcet: u32,
+}
+
+/// Current job of a periodic task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtJob {
+    pub params: RtParams,
+    /// Release tick of the current job
+    pub release: u32,
+    /// Absolute deadline of the current job
+    pub deadline: u32,
+    /// CPU ticks used by the current job
+    pub used: u32,
+    /// Job completed; waiting for the next release
+    pub done: bool,
+    /// Miss of the current job already reported
+    pub missed: bool,
+}
+
+impl RtJob {
+    /// First job released at `now`.
+    pub const fn new(params: RtParams, now: u32) -> Self {
+        Self {
+            params,
+            release: now,
+            deadline: now.wrapping_add(params.deadline),
+            used: 0,
+            done: false,
+            missed: false,
+        }
+    }
+
+    /// Released and not yet completed.
+    pub const fn runnable(&self) -> bool {
+        !self.done
+    }
+
+    /// Release tick of the next job.
+    pub const fn next_release(&self) -> u32 {
+        self.release.wrapping_add(self.params.period)
+    }
+
+    /// `true` if this job's deadline comes before `other`'s.
+    pub const fn earlier_than(&self, other: &RtJob) -> bool {
+        (self.deadline.wrapping_sub(other.deadline) as i32) < 0
+    }
+
+    /// Mark the current job complete. Returns the next release tick.
+    pub fn complete(&mut self) -> u32 {
+        self.done = true;
+        self.next_release()
+    }
+
+    /// Advance task `task`'s job to tick `now`; `running` charges the tick
+    /// to it. Returns the miss if its deadline passed just now.
+    pub fn tick(&mut self, task: u32, now: u32, running: bool) -> Option<DeadlineMiss> {
+        if running && !self.done {
+            self.used = self.used.saturating_add(1);
+        }
+        let mut missed = None;
+        if !self.done && !self.missed && reached(now, self.deadline) {
+            self.missed = true;
+            missed = Some(DeadlineMiss { task, deadline: self.deadline, used: self.used, wcet: self.params.wcet });
+        }
+        if reached(now, self.next_release()) {
+            // Skip whole periods the task overran, so releases stay on
+            // the original grid.
+            let behind = now.wrapping_sub(self.release) / self.params.period.max(1);
+            self.release = self.release.wrapping_add(behind.wrapping_mul(self.params.period));
+            self.deadline = self.release.wrapping_add(self.params.deadline);
+            self.used = 0;
+            self.done = false;
+            self.missed = false;
+        }
+        missed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn admission_uses_density() {
+        let a = RtParams::implicit(10, 5);
+        let b = RtParams::implicit(20, 10);
+        assert_eq!(admit([a].iter(), &b), Ok(FULL_LOAD_PPM));
+        assert_eq!(admit([a, b].iter(), &RtParams::implicit(100, 1)), Err(AdmitError::Infeasible));
+        // Deadline shorter than the period counts against the deadline
+        let c = RtParams { period: 100, deadline: 10, wcet: 6 };
+        assert_eq!(admit([a].iter(), &c), Err(AdmitError::Infeasible));
+        assert_eq!(admit([].iter(), &RtParams { period: 10, deadline: 20, wcet: 1 }), Err(AdmitError::InvalidParams));
+        assert_eq!(admit([].iter(), &RtParams::implicit(10, 0)), Err(AdmitError::InvalidParams));
+        // 1/3 rounds up, so three of them do not fit
+        let third = RtParams::implicit(3, 1);
+        assert_eq!(admit([third, third].iter(), &third), Err(AdmitError::Infeasible));
+    }
+
+    #[test]
+    fn jobs_release_and_report_misses_once() {
+        let mut job = RtJob::new(RtParams { period: 10, deadline: 5, wcet: 2 }, u32::MAX - 2);
+        let mut now = u32::MAX - 2;
+        let mut misses = 0;
+        for _ in 0..7 {
+            now = now.wrapping_add(1);
+            misses += job.tick(1, now, true).is_some() as u32;
+        }
+        assert_eq!(misses, 1, "deadline passes across the tick wrap");
+        assert_eq!(job.used, 7);
+
+        assert_eq!(job.complete(), job.release.wrapping_add(10));
+        assert!(!job.runnable());
+        for _ in 0..3 {
+            now = now.wrapping_add(1);
+            assert_eq!(job.tick(1, now, false), None);
+        }
+        assert!(job.runnable(), "next job released");
+        assert_eq!((job.used, job.missed), (0, false));
+
+        // Overrunning several periods keeps releases on the grid
+        let start = job.release;
+        for _ in 0..25 {
+            now = now.wrapping_add(1);
+            job.tick(1, now, true);
+        }
+        assert_eq!(job.release.wrapping_sub(start) % 10, 0);
+        assert!(job.earlier_than(&RtJob::new(RtParams::implicit(100, 1), now)));
+    }
+}
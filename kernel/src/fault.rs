@@ -0,0 +1,246 @@
+//! SecureIoTOS Kernel Fault Handler Module
+//! ---------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author : Md Mahbubur Rahman
+//! URL    : https://m-a-h-b-u-b.github.io
+//! GitHub : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! HardFault, MemManage, BusFault and UsageFault handlers.
+//!
+//! Each handler captures the stacked frame, CFSR/HFSR/MMFAR/BFAR, the
+//! offending task and a snippet of its stack into a `crash::CrashDump`,
+//! stores it in the retained crash slot (uploaded after the next boot,
+//! see `crash`), and then:
+//!
+//! - **kills the task** if the fault came from a task, the policy is
+//!   `FaultPolicy::KillTask` and another task can run. The rest of the
+//!   system keeps going; an MPU violation costs one task, not the device.
+//! - **resets** otherwise: faults in handler or boot code, a fault while
+//!   the scheduler itself was busy, or `FaultPolicy::Reset`.
+//!
+//! `init()` enables the configurable fault handlers; without it every
+//! fault escalates to HardFault (still recorded, with CFSR showing the
+//! original cause).
+
+// Core kernel path: must not panic (see `tools/no-panic-check`).
+#![cfg_attr(not(test), deny(
+    clippy::panic,
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::indexing_slicing,
+    clippy::unreachable,
+    clippy::todo,
+    clippy::unimplemented
+))]
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+/// EXC_RETURN bit 2: the exception frame is on the process stack (the
+/// fault interrupted a task).
+pub const EXC_RETURN_SPSEL: u32 = 1 << 2;
+
+/// CFSR bits set when pushing the exception frame itself failed, so the
+/// "frame" is not valid (MSTKERR, STKERR).
+const CFSR_STACKING_ERRORS: u32 = (1 << 4) | (1 << 12);
+
+/// Reaction to a fault in a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum FaultPolicy {
+    /// Remove the faulting task and keep running
+    KillTask = 0,
+    /// Reset the device
+    Reset = 1,
+}
+
+/// What a handler does after recording the fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultAction {
+    KillTask,
+    Reset,
+}
+
+static POLICY: AtomicU8 = AtomicU8::new(FaultPolicy::KillTask as u8);
+
+/// Select the reaction to task faults (default: `KillTask`).
+pub fn set_fault_policy(policy: FaultPolicy) {
+    POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+/// Currently selected reaction to task faults.
+pub fn fault_policy() -> FaultPolicy {
+    match POLICY.load(Ordering::Relaxed) {
+        0 => FaultPolicy::KillTask,
+        _ => FaultPolicy::Reset,
+    }
+}
+
+/// Enable the MemManage, BusFault and UsageFault handlers. Call once
+/// during kernel init.
+pub fn init() {
+    crate::regs::scb::enable_fault_handlers();
+}
+
+/// Decide how to recover: only a fault taken from a known task may be
+/// survived, and only if the policy allows it.
+pub const fn action_for(exc_return: u32, task: Option<u32>, policy: FaultPolicy) -> FaultAction {
+    let from_task = exc_return & EXC_RETURN_SPSEL != 0;
+    match (from_task, task, policy) {
+        (true, Some(_), FaultPolicy::KillTask) => FaultAction::KillTask,
+        _ => FaultAction::Reset,
+    }
+}
+
+/// Whether the core managed to push the exception frame (stacking can
+/// itself fault, e.g. on a task stack overflow into a guard region).
+pub const fn frame_stacked(cfsr: u32) -> bool {
+    cfsr & CFSR_STACKING_ERRORS == 0
+}
+
+/// Stack pointer of the faulting code: the frame address plus the frame
+/// (basic or extended FP) plus the alignment word the core may have
+/// inserted (xPSR bit 9).
+pub const fn sp_before_frame(frame: u32, exc_return: u32, xpsr: u32) -> u32 {
+    let words = if exc_return & crate::context::EXC_RETURN_FTYPE == 0 { 26 } else { 8 };
+    let pad = if xpsr & (1 << 9) != 0 { 4 } else { 0 };
+    frame.wrapping_add(words * 4 + pad)
+}
+
+/// Words of stack at `sp` that can be read without leaving the 1 KiB
+/// block `sp` lies in (RAM ends on at least a 1 KiB boundary, so this
+/// never runs off the end of SRAM), capped at `crash::STACK_WORDS`.
+pub const fn readable_stack_words(sp: u32) -> usize {
+    let to_boundary = (0x400 - (sp & 0x3FF)) / 4;
+    let n = to_boundary as usize;
+    if n < crate::crash::STACK_WORDS {
+        n
+    } else {
+        crate::crash::STACK_WORDS
+    }
+}
+
+/// Scratch area the process stack is pointed at after a task is killed:
+/// the tail-chained PendSV saves the dead task's context here and the
+/// scheduler discards it. Holds R4-R11, EXC_RETURN and S16-S31.
+#[cfg(target_arch = "arm")]
+static mut GRAVEYARD: [u32; 32] = [0; 32];
+
+/// Common body of the fault handlers. `frame` is the stacked exception
+/// frame, `exc_return` the handler's LR on entry.
+#[cfg(target_arch = "arm")]
+extern "C" fn on_fault(frame: *const u32, exc_return: u32, reason: u8) {
+    use crate::crash::{self, CrashDump, CrashReason, ExceptionFrame, STACK_WORDS};
+
+    let status = crate::regs::scb::fault_status();
+    let from_task = exc_return & EXC_RETURN_SPSEL != 0;
+    let task = if from_task { crate::scheduler::current_task_id() } else { None };
+    let reason = match reason {
+        2 => CrashReason::MemManage,
+        3 => CrashReason::BusFault,
+        4 => CrashReason::UsageFault,
+        _ => CrashReason::HardFault,
+    };
+
+    let stacked = frame_stacked(status.cfsr);
+    let ef = if stacked {
+        // SAFETY: the core pushed a complete frame at `frame`.
+        unsafe { ExceptionFrame::read(frame) }
+    } else {
+        ExceptionFrame::default()
+    };
+    let sp = sp_before_frame(frame as u32, exc_return, ef.xpsr);
+    let mut dump = CrashDump::new(reason, task, ef, sp, status);
+    if stacked {
+        let mut words = [0u32; STACK_WORDS];
+        let n = readable_stack_words(sp);
+        for (i, w) in words.iter_mut().take(n).enumerate() {
+            // SAFETY: `sp` is the faulting stack, which the frame above it
+            // was pushed to, and the read stays within its 1 KiB block.
+            *w = unsafe { (sp as *const u32).add(i).read_volatile() };
+        }
+        dump.set_stack(words.get(..n).unwrap_or(&[]));
+    }
+    crash::record(&dump);
+    crate::regs::scb::clear_fault_status(&status);
+
+    // A killed task's kernel queues are closed, or they would stay
+    // allocated forever.
+    if action_for(exc_return, task, fault_policy()) == FaultAction::KillTask
+        && crate::scheduler::kill_current().map(|dead| crate::ipc_queue::release_task(dead.id)).is_some()
+    {
+        // SAFETY: Handler mode; PendSV tail-chains before the dead task
+        // could resume and only ever stores into `GRAVEYARD`, whose end
+        // it starts below.
+        unsafe {
+            let top = core::ptr::addr_of_mut!(GRAVEYARD).cast::<u32>().add(32);
+            core::arch::asm!("msr psp, {}", "isb", in(reg) top, options(nostack, preserves_flags));
+        }
+        return;
+    }
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+/// Fault handler entry: find the stacked frame (MSP or PSP, per
+/// EXC_RETURN bit 2) and pass it, EXC_RETURN and the fault kind to
+/// `on_fault`. Exported under the vector table names, like `PendSV`.
+macro_rules! fault_entry {
+    ($name:ident, $reason:expr) => {
+        /// # Safety
+        /// Only the exception mechanism may call this.
+        #[cfg(target_arch = "arm")]
+        #[unsafe(naked)]
+        #[no_mangle]
+        pub unsafe extern "C" fn $name() {
+            core::arch::naked_asm!(
+                "tst lr, #4",
+                "ite eq",
+                "mrseq r0, msp",
+                "mrsne r0, psp",
+                "mov r1, lr",
+                "movs r2, #{reason}",
+                "push {{r4, lr}}",
+                "bl {handler}",
+                "pop {{r4, pc}}",
+                reason = const $reason as u8,
+                handler = sym on_fault,
+            );
+        }
+    };
+}
+
+fault_entry!(HardFault, crate::crash::CrashReason::HardFault);
+fault_entry!(MemoryManagement, crate::crash::CrashReason::MemManage);
+fault_entry!(BusFault, crate::crash::CrashReason::BusFault);
+fault_entry!(UsageFault, crate::crash::CrashReason::UsageFault);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::context::EXC_RETURN_THREAD_PSP;
+
+    /// Handler mode, MSP, basic frame.
+    const EXC_RETURN_HANDLER: u32 = 0xFFFF_FFF1;
+
+    #[test]
+    fn only_task_faults_are_survivable() {
+        assert_eq!(action_for(EXC_RETURN_THREAD_PSP, Some(0x101), FaultPolicy::KillTask), FaultAction::KillTask);
+        assert_eq!(action_for(EXC_RETURN_THREAD_PSP, Some(0x101), FaultPolicy::Reset), FaultAction::Reset);
+        assert_eq!(action_for(EXC_RETURN_THREAD_PSP, None, FaultPolicy::KillTask), FaultAction::Reset, "scheduler busy");
+        assert_eq!(action_for(EXC_RETURN_HANDLER, Some(0x101), FaultPolicy::KillTask), FaultAction::Reset);
+    }
+
+    #[test]
+    fn faulting_sp_and_stack_snippet_bounds() {
+        assert_eq!(sp_before_frame(0x2000_1000, EXC_RETURN_THREAD_PSP, 0), 0x2000_1020);
+        assert_eq!(sp_before_frame(0x2000_1000, EXC_RETURN_THREAD_PSP, 1 << 9), 0x2000_1024, "aligner word");
+        assert_eq!(sp_before_frame(0x2000_1000, 0xFFFF_FFED, 0), 0x2000_1068, "extended FP frame");
+
+        assert_eq!(readable_stack_words(0x2000_1000), crate::crash::STACK_WORDS);
+        assert_eq!(readable_stack_words(0x2000_13F8), 2);
+
+        assert!(frame_stacked(0x82), "DACCVIOL, MMARVALID");
+        assert!(!frame_stacked(1 << 4), "MSTKERR");
+    }
+}
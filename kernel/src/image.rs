@@ -0,0 +1,143 @@
+//! SecureIoTOS Kernel Image Module
+//! -------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author : Md Mahbubur Rahman
+//! URL    : https://m-a-h-b-u-b.github.io
+//! GitHub : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Firmware identity: the build ID and the image header that carries it.
+//!
+//! The build ID is 20 bytes: the first 12 bytes of the git commit hash,
+//! then the first 8 bytes of the SHA-256 of the build configuration
+//! (target, profile, features, `SIOS_BUILD_CONFIG`); see `build.rs`. It
+//! depends on nothing else, so rebuilding a commit with the same
+//! configuration reproduces it, and two images with the same ID were
+//! built from the same source the same way. Builds with uncommitted
+//! changes are flagged `dirty` and should never ship.
+//!
+//! `IMAGE_HEADER` is placed in `.image_header`, which the application's
+//! linker script keeps right after the vector table, moving the start of
+//! code (cortex-m-rt's `_stext`) behind it:
+//!
+//! ```text
+//! SECTIONS { .image_header : { KEEP(*(.image_header)) } > FLASH }
+//! INSERT AFTER .vector_table;
+//! _stext = ALIGN(ADDR(.image_header) + SIZEOF(.image_header), 4);
+//! ```
+//!
+//! The bootloader/OTA installer reads it from the binary to check the
+//! manifest, `tools/crash-symbolicate` from the ELF to match crash dumps.
+//! On the device, `firmware_info()` reports it; tasks get it through the
+//! `GetFirmwareInfo` syscall for telemetry, and it is stamped into crash
+//! dumps and attestation reports.
+
+mod generated {
+    include!(concat!(env!("OUT_DIR"), "/build_id.rs"));
+}
+
+/// Length of the build ID.
+pub const BUILD_ID_LEN: usize = 20;
+
+/// Magic at the start of the image header.
+pub const HEADER_MAGIC: [u8; 8] = *b"SIOSIMG\0";
+/// Layout version of `ImageHeader`.
+pub const HEADER_VERSION: u32 = 1;
+/// `ImageHeader::flags`: built from a tree with uncommitted changes.
+pub const FLAG_DIRTY: u32 = 1 << 0;
+
+/// This build's ID.
+pub const BUILD_ID: [u8; BUILD_ID_LEN] = {
+    let mut id = [0u8; BUILD_ID_LEN];
+    let mut i = 0;
+    while i < 12 {
+        id[i] = generated::GIT_HASH[i];
+        i += 1;
+    }
+    while i < BUILD_ID_LEN {
+        id[i] = generated::CONFIG_HASH[i - 12];
+        i += 1;
+    }
+    id
+};
+
+/// Header embedded in the image. Layout is read by the bootloader and host
+/// tools: append fields, never reorder.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageHeader {
+    pub magic: [u8; 8],
+    pub header_version: u32,
+    pub flags: u32,
+    pub build_id: [u8; BUILD_ID_LEN],
+    /// Crate version: major, minor, patch
+    pub version: [u16; 3],
+    pub reserved: u16,
+}
+
+impl ImageHeader {
+    /// Size of the header in bytes.
+    pub const SIZE: usize = core::mem::size_of::<ImageHeader>();
+}
+
+#[used]
+#[cfg_attr(target_os = "none", link_section = ".image_header")]
+pub static IMAGE_HEADER: ImageHeader = ImageHeader {
+    magic: HEADER_MAGIC,
+    header_version: HEADER_VERSION,
+    flags: if generated::DIRTY { FLAG_DIRTY } else { 0 },
+    build_id: BUILD_ID,
+    version: [
+        parse_u16(env!("CARGO_PKG_VERSION_MAJOR")),
+        parse_u16(env!("CARGO_PKG_VERSION_MINOR")),
+        parse_u16(env!("CARGO_PKG_VERSION_PATCH")),
+    ],
+    reserved: 0,
+};
+
+/// What the running firmware is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FirmwareInfo {
+    pub build_id: [u8; BUILD_ID_LEN],
+    /// Major, minor, patch
+    pub version: [u16; 3],
+    /// Built from a tree with uncommitted changes
+    pub dirty: bool,
+}
+
+/// Identity of the running firmware, as recorded in its image header.
+pub fn firmware_info() -> FirmwareInfo {
+    // SAFETY: a valid static; the volatile read keeps the header (and so
+    // its section) referenced even where the linker script forgets KEEP.
+    let h = unsafe { core::ptr::read_volatile(&IMAGE_HEADER) };
+    FirmwareInfo { build_id: h.build_id, version: h.version, dirty: h.flags & FLAG_DIRTY != 0 }
+}
+
+const fn parse_u16(s: &str) -> u16 {
+    let b = s.as_bytes();
+    let mut v: u16 = 0;
+    let mut i = 0;
+    while i < b.len() {
+        v = v * 10 + (b[i] - b'0') as u16;
+        i += 1;
+    }
+    v
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_layout_and_info() {
+        assert_eq!(ImageHeader::SIZE, 44);
+        assert_eq!(core::mem::offset_of!(ImageHeader, build_id), 16);
+
+        let info = firmware_info();
+        assert_eq!(info.build_id, BUILD_ID);
+        assert_eq!(&info.build_id[..12], &generated::GIT_HASH);
+        assert_eq!(info.version[0], parse_u16(env!("CARGO_PKG_VERSION_MAJOR")));
+        assert_eq!(parse_u16("42"), 42);
+    }
+}
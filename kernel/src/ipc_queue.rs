@@ -0,0 +1,286 @@
+//! SecureIoTOS Kernel IPC Queue Module
+//! -----------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author : Md Mahbubur Rahman
+//! URL    : https://m-a-h-b-u-b.github.io
+//! GitHub : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Kernel-owned message queues behind the `CreateQueue`, `SendMessage`
+//! and `ReceiveMessage` syscalls, so unprivileged tasks can exchange
+//! messages without sharing memory.
+//!
+//! Queues come from a fixed pool of `MAX_QUEUES` `ipc::MpmcQueue`s (any
+//! number of tasks may send to one queue, so the single-producer
+//! `MessageQueue` does not fit). A queue belongs to the task that created
+//! it: only that task may receive from or close it; any task holding
+//! `caps::SEND_MESSAGE` and the handle may send to it. Handles encode the
+//! pool slot and a generation, as task IDs do:
+//!
+//! ```text
+//!   handle = generation << 8 | (slot + 1)
+//! ```
+//!
+//! The generation is bumped when a queue is closed, so a handle kept
+//! after close never reaches the queue that reuses the slot. Handles stay
+//! below bit 31, which the syscall ABI reserves for errors.
+//!
+//! Sending never blocks (`Full` if the queue is full). Receiving can wait
+//! for a message with a timeout; the receiver parks on the queue's wait
+//! list and is woken by the next send or by the tick timer. A blocking
+//! receive is task-only: the syscall must run in the caller's Thread-mode
+//! context, not inside the SVCall exception.
+
+// Core kernel path: must not panic (see `tools/no-panic-check`).
+#![cfg_attr(not(test), deny(
+    clippy::panic,
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::indexing_slicing,
+    clippy::unreachable,
+    clippy::todo,
+    clippy::unimplemented
+))]
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use ipc::wait::{parker, TaskId, WaitList};
+use ipc::{IpcMessage, MpmcQueue};
+
+use crate::time::{self, reached};
+
+/// Number of kernel queues.
+pub const MAX_QUEUES: usize = 8;
+/// Messages per queue (power of two, as `MpmcQueue` requires).
+pub const QUEUE_DEPTH: usize = 8;
+/// Largest message a kernel queue carries, in bytes.
+pub const QUEUE_MSG_SIZE: usize = 128;
+/// Receive timeout meaning "wait until a message arrives".
+pub const WAIT_FOREVER: u32 = u32::MAX;
+
+/// Queue handle as seen by user space (0 is never valid).
+pub type QueueHandle = u32;
+
+/// A message as received from a kernel queue.
+pub type QueueMessage = IpcMessage<QUEUE_MSG_SIZE>;
+
+const SLOT_BITS: u32 = 8;
+const SLOT_MASK: u32 = (1 << SLOT_BITS) - 1;
+/// Generation bits that fit below the syscall error bit.
+const GEN_MASK: u32 = (1 << (31 - SLOT_BITS)) - 1;
+
+const _: () = assert!(MAX_QUEUES < SLOT_MASK as usize);
+
+/// Why a queue operation failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueError {
+    /// Handle does not name an open queue
+    Invalid,
+    /// Caller does not own the queue
+    NotOwner,
+    /// Message longer than `QUEUE_MSG_SIZE`
+    TooLarge,
+    /// Queue is full
+    Full,
+    /// Every queue in the pool is in use
+    NoSlot,
+    /// No message arrived before the timeout
+    TimedOut,
+}
+
+struct Slot {
+    /// Owning task id + 1; 0 = free
+    owner: AtomicU32,
+    generation: AtomicU32,
+    queue: MpmcQueue<QUEUE_DEPTH, QUEUE_MSG_SIZE>,
+    /// Owner parked in `receive()`
+    not_empty: WaitList,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Slot {
+            owner: AtomicU32::new(0),
+            generation: AtomicU32::new(0),
+            queue: MpmcQueue::new(),
+            not_empty: WaitList::new(),
+        }
+    }
+
+    fn owned_by(&self, task: TaskId) -> bool {
+        self.owner.load(Ordering::Acquire) == task.wrapping_add(1)
+    }
+}
+
+static QUEUES: [Slot; MAX_QUEUES] = [const { Slot::new() }; MAX_QUEUES];
+
+fn lookup(handle: QueueHandle) -> Result<&'static Slot, QueueError> {
+    let idx = ((handle & SLOT_MASK) as usize).checked_sub(1).ok_or(QueueError::Invalid)?;
+    let slot = QUEUES.get(idx).ok_or(QueueError::Invalid)?;
+    let generation = slot.generation.load(Ordering::Acquire) & GEN_MASK;
+    if slot.owner.load(Ordering::Acquire) == 0 || handle >> SLOT_BITS != generation {
+        return Err(QueueError::Invalid);
+    }
+    Ok(slot)
+}
+
+/// Open a queue owned by `owner`. ISR-safe.
+pub fn create(owner: TaskId) -> Result<QueueHandle, QueueError> {
+    let tag = owner.wrapping_add(1);
+    for (idx, slot) in QUEUES.iter().enumerate() {
+        if slot.owner.compare_exchange(0, tag, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+            // Drop anything the previous owner left behind; no handle of
+            // this generation exists yet, so nobody can be sending.
+            while slot.queue.dequeue().is_some() {}
+            let generation = slot.generation.load(Ordering::Acquire) & GEN_MASK;
+            return Ok(generation << SLOT_BITS | (idx as u32 + 1));
+        }
+    }
+    Err(QueueError::NoSlot)
+}
+
+/// Close a queue. Only its owner may close it; pending messages are
+/// dropped and a receiver still waiting is woken. ISR-safe.
+pub fn close(handle: QueueHandle, caller: TaskId) -> Result<(), QueueError> {
+    let slot = lookup(handle)?;
+    if !slot.owned_by(caller) {
+        return Err(QueueError::NotOwner);
+    }
+    release(slot);
+    Ok(())
+}
+
+/// Close every queue owned by `task`, e.g. after it was killed. ISR-safe.
+pub fn release_task(task: TaskId) {
+    for slot in QUEUES.iter().filter(|s| s.owned_by(task)) {
+        release(slot);
+    }
+}
+
+fn release(slot: &Slot) {
+    // Stale handles fail from here on, before the slot can be reused.
+    slot.generation.fetch_add(1, Ordering::AcqRel);
+    slot.owner.store(0, Ordering::Release);
+    slot.not_empty.wake_all();
+}
+
+/// Copy `data` into the queue. Never blocks. ISR-safe.
+pub fn send(handle: QueueHandle, data: &[u8]) -> Result<(), QueueError> {
+    let slot = lookup(handle)?;
+    let mut msg = QueueMessage::new();
+    msg.data.get_mut(..data.len()).ok_or(QueueError::TooLarge)?.copy_from_slice(data);
+    msg.length = data.len();
+    slot.queue.enqueue(msg).map_err(|_| QueueError::Full)?;
+    slot.not_empty.wake_one();
+    Ok(())
+}
+
+/// Take the oldest message, waiting up to `timeout` ticks for one
+/// (0 = poll, `WAIT_FOREVER` = no limit). Only the owner may receive.
+/// Task-only unless `timeout` is 0.
+pub fn receive(handle: QueueHandle, caller: TaskId, timeout: u32) -> Result<QueueMessage, QueueError> {
+    let slot = lookup(handle)?;
+    if !slot.owned_by(caller) {
+        return Err(QueueError::NotOwner);
+    }
+    wait_for(&slot.not_empty, timeout, || {
+        // A queue closed under us ends the wait.
+        match lookup(handle) {
+            Ok(_) => slot.queue.dequeue().map(Ok),
+            Err(e) => Some(Err(e)),
+        }
+    })
+    .unwrap_or(Err(QueueError::TimedOut))
+}
+
+/// Retry `attempt` until it succeeds or `timeout` ticks pass, parking the
+/// caller on `list` with a timer wakeup in between.
+fn wait_for<T>(list: &WaitList, timeout: u32, mut attempt: impl FnMut() -> Option<T>) -> Option<T> {
+    if timeout == 0 {
+        return attempt();
+    }
+    ipc::isr::assert_task_context("blocking receive");
+    let deadline = (timeout != WAIT_FOREVER).then(|| time::ticks().wrapping_add(timeout));
+    loop {
+        if let Some(v) = attempt() {
+            return Some(v);
+        }
+        if deadline.is_some_and(|d| reached(time::ticks(), d)) {
+            return None;
+        }
+        let Some(h) = parker() else {
+            core::hint::spin_loop();
+            continue;
+        };
+        let me = (h.current)();
+        let listed = list.register(me);
+        let timed = deadline.is_none_or(|d| time::arm_wakeup(me, d));
+        if listed && timed {
+            // Re-check after registering so a send in between is not missed.
+            if let Some(v) = attempt() {
+                list.remove(me);
+                time::cancel_wakeup(me);
+                return Some(v);
+            }
+            (h.park)();
+        } else {
+            // Out of wait slots: fall back to polling.
+            core::hint::spin_loop();
+        }
+        list.remove(me);
+        time::cancel_wakeup(me);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    #[test]
+    fn owner_receives_others_send() {
+        let q = create(1).unwrap();
+        assert_eq!(send(q, b"ping"), Ok(()));
+        assert_eq!(receive(q, 2, 0).map(|m| m.length), Err(QueueError::NotOwner));
+        let msg = receive(q, 1, 0).unwrap();
+        assert_eq!(&msg.data[..msg.length], b"ping");
+        assert_eq!(receive(q, 1, 0).map(|m| m.length), Err(QueueError::TimedOut), "empty poll");
+
+        assert_eq!(send(q, &[0; QUEUE_MSG_SIZE + 1]), Err(QueueError::TooLarge));
+        for _ in 0..QUEUE_DEPTH {
+            send(q, &[1]).unwrap();
+        }
+        assert_eq!(send(q, &[1]), Err(QueueError::Full));
+
+        assert_eq!(close(q, 2), Err(QueueError::NotOwner));
+        close(q, 1).unwrap();
+        assert_eq!(send(q, &[1]), Err(QueueError::Invalid), "stale handle");
+        assert_eq!(send(0, &[1]), Err(QueueError::Invalid));
+        assert!(q & 0x8000_0000 == 0);
+    }
+
+    #[test]
+    fn blocking_receive_times_out_or_gets_message() {
+        let q = create(3).unwrap();
+        let ticker = std::thread::spawn(|| {
+            for _ in 0..50 {
+                time::on_tick();
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+        });
+        assert_eq!(receive(q, 3, 5).map(|m| m.length), Err(QueueError::TimedOut));
+        ticker.join().unwrap();
+
+        let sender = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(5));
+            send(q, b"late").unwrap();
+        });
+        assert_eq!(receive(q, 3, WAIT_FOREVER).map(|m| m.length), Ok(4));
+        sender.join().unwrap();
+
+        release_task(3);
+        assert_eq!(receive(q, 3, 0).map(|m| m.length), Err(QueueError::Invalid));
+    }
+}
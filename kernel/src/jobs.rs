@@ -0,0 +1,382 @@
+//! SecureIoTOS Kernel Scheduled Jobs Module
+//! ----------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author : Md Mahbubur Rahman
+//! URL    : https://m-a-h-b-u-b.github.io
+//! GitHub : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Cron-like jobs for housekeeping routines: key rotation, log upload, OTA
+//! check-in, sensor calibration.
+//!
+//! A job runs every N seconds, daily at a time of day (UTC), or once at a
+//! given Unix time. Jobs are identified by an application-chosen `id`; the
+//! scheduler only decides *when* they are due and calls back with the job,
+//! so the work itself runs in the task that owns the `JobScheduler`:
+//!
+//! ```ignore
+//! let mut jobs = JobScheduler::new();
+//! jobs.add(Job { id: KEY_ROTATION, schedule: Schedule::Daily { at: 3 * 3600 }, catch_up: CatchUp::RunOnce }, now())?;
+//! jobs.add(Job { id: OTA_CHECK_IN, schedule: Schedule::Every { secs: 6 * 3600 }, catch_up: CatchUp::Skip }, now())?;
+//! jobs.restore(&flash_copy)?;
+//! loop {
+//!     if jobs.poll(now(), |job| run_job(job.id)) > 0 {
+//!         jobs.persist(&mut flash)?;
+//!     }
+//!     time::sleep_ms(jobs.idle_ms(now()));
+//! }
+//! ```
+//!
+//! Times are wall-clock Unix seconds from the synced clock; a job task
+//! should not start polling before the clock is set. Only the last run of
+//! each job is persisted; the next run is derived from it, so after a
+//! reboot or a long sleep a job finds its run overdue and its `CatchUp`
+//! policy decides: `Skip` drops the missed run(s) and waits for the next
+//! regular one, `RunOnce` runs once straight away. Missed runs are never
+//! replayed one by one.
+//!
+//! State encoding (version 1, little-endian):
+//!
+//! ```text
+//!   "SJB" | version(1) | count(1) | { id(2) | last_run(8) } * count | crc32(4)
+//! ```
+
+// Core kernel path: must not panic (see `tools/no-panic-check`).
+#![cfg_attr(not(test), deny(
+    clippy::panic,
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::indexing_slicing,
+    clippy::unreachable,
+    clippy::todo,
+    clippy::unimplemented
+))]
+
+/// Maximum number of registered jobs.
+pub const MAX_JOBS: usize = 8;
+/// A run this many seconds past its due time counts as missed.
+pub const LATE_GRACE_SECS: u64 = 60;
+/// Longest `idle_ms()` result, so a clock step is noticed within a minute.
+pub const MAX_IDLE_MS: u32 = 60_000;
+/// Format version written by `persist()`.
+pub const FORMAT_VERSION: u8 = 1;
+/// Largest encoded state.
+pub const MAX_STATE_LEN: usize = HEADER_LEN + MAX_JOBS * RECORD_LEN + 4;
+
+const SECS_PER_DAY: u64 = 86_400;
+const MAGIC: &[u8; 3] = b"SJB";
+const HEADER_LEN: usize = 5;
+const RECORD_LEN: usize = 10;
+
+/// When a job runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Schedule {
+    /// Every `secs` seconds (first run `secs` after registration)
+    Every { secs: u32 },
+    /// Once a day, `at` seconds after midnight UTC
+    Daily { at: u32 },
+    /// Once, at Unix time `at`
+    At { at: u64 },
+}
+
+/// What to do about runs missed while the device was off or asleep.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CatchUp {
+    /// Drop them; wait for the next regular occurrence
+    Skip,
+    /// Run once as soon as possible, then resume the schedule
+    RunOnce,
+}
+
+/// A registered job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Job {
+    /// Application-chosen identifier, also the key in the persisted state
+    pub id: u16,
+    pub schedule: Schedule,
+    pub catch_up: CatchUp,
+}
+
+/// Why a job operation failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobError {
+    /// `MAX_JOBS` reached
+    Full,
+    /// A job with this id is already registered
+    Duplicate,
+    /// `Every { secs: 0 }` or `Daily { at }` past the end of the day
+    BadSchedule,
+    /// Persisted state is not a valid encoding
+    Corrupt,
+}
+
+/// Where `persist()` writes the state (flash, secure storage, ...).
+pub trait JobStore {
+    type Error;
+
+    fn save(&mut self, state: &[u8]) -> Result<(), Self::Error>;
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    job: Job,
+    last_run: Option<u64>,
+    /// `None` once a one-shot job is done
+    next: Option<u64>,
+}
+
+/// Fixed table of jobs and their run times.
+pub struct JobScheduler {
+    entries: [Option<Entry>; MAX_JOBS],
+}
+
+/// First occurrence of `schedule` strictly after `t`.
+fn next_after(schedule: Schedule, t: u64) -> Option<u64> {
+    match schedule {
+        Schedule::Every { secs } => Some(t + u64::from(secs)),
+        Schedule::Daily { at } => {
+            let today = t - t % SECS_PER_DAY + u64::from(at);
+            Some(if today > t { today } else { today + SECS_PER_DAY })
+        }
+        Schedule::At { at } => (at > t).then_some(at),
+    }
+}
+
+impl JobScheduler {
+    pub const fn new() -> Self {
+        JobScheduler { entries: [None; MAX_JOBS] }
+    }
+
+    /// Register `job` at wall-clock time `now`.
+    pub fn add(&mut self, job: Job, now: u64) -> Result<(), JobError> {
+        match job.schedule {
+            Schedule::Every { secs: 0 } => return Err(JobError::BadSchedule),
+            Schedule::Daily { at } if u64::from(at) >= SECS_PER_DAY => return Err(JobError::BadSchedule),
+            _ => {}
+        }
+        if self.get(job.id).is_some() {
+            return Err(JobError::Duplicate);
+        }
+        let slot = self.entries.iter_mut().find(|e| e.is_none()).ok_or(JobError::Full)?;
+        let next = match job.schedule {
+            // An overdue one-shot job is handled by its catch-up policy.
+            Schedule::At { at } => Some(at),
+            s => next_after(s, now),
+        };
+        *slot = Some(Entry { job, last_run: None, next });
+        Ok(())
+    }
+
+    /// Unregister job `id`. Returns whether it existed.
+    pub fn remove(&mut self, id: u16) -> bool {
+        match self.entries.iter_mut().find(|e| e.is_some_and(|e| e.job.id == id)) {
+            Some(e) => {
+                *e = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn get(&self, id: u16) -> Option<&Entry> {
+        self.entries.iter().flatten().find(|e| e.job.id == id)
+    }
+
+    /// Unix time job `id` last ran, if ever.
+    pub fn last_run(&self, id: u16) -> Option<u64> {
+        self.get(id)?.last_run
+    }
+
+    /// Unix time job `id` is next due; `None` for a finished one-shot job.
+    pub fn next_due(&self, id: u16) -> Option<u64> {
+        self.get(id)?.next
+    }
+
+    /// Run every job due at `now` (subject to its catch-up policy).
+    /// Returns the number of jobs run; persist the state if it is not 0.
+    pub fn poll(&mut self, now: u64, mut run: impl FnMut(&Job)) -> usize {
+        let mut ran = 0;
+        for e in self.entries.iter_mut().flatten() {
+            let Some(due) = e.next.filter(|&due| due <= now) else {
+                continue;
+            };
+            e.next = next_after(e.job.schedule, now);
+            if now - due > LATE_GRACE_SECS && e.job.catch_up == CatchUp::Skip {
+                continue;
+            }
+            run(&e.job);
+            e.last_run = Some(now);
+            ran += 1;
+        }
+        ran
+    }
+
+    /// Milliseconds until the next job is due (at most `MAX_IDLE_MS`).
+    pub fn idle_ms(&self, now: u64) -> u32 {
+        self.entries
+            .iter()
+            .flatten()
+            .filter_map(|e| e.next)
+            .map(|due| due.saturating_sub(now).saturating_mul(1000))
+            .min()
+            .map_or(MAX_IDLE_MS, |ms| ms.min(u64::from(MAX_IDLE_MS)) as u32)
+    }
+
+    /// Encode the last run of every job into `out`; returns the length.
+    pub fn encode(&self, out: &mut [u8; MAX_STATE_LEN]) -> usize {
+        let (header, records) = out.split_at_mut(HEADER_LEN);
+        let runs = self.entries.iter().flatten().filter_map(|e| Some((e.job.id, e.last_run?)));
+        let mut count = 0;
+        for ((id, last), rec) in runs.zip(records.chunks_exact_mut(RECORD_LEN)) {
+            let (id_bytes, last_bytes) = rec.split_at_mut(2);
+            id_bytes.copy_from_slice(&id.to_le_bytes());
+            last_bytes.copy_from_slice(&last.to_le_bytes());
+            count += 1;
+        }
+        let (magic, rest) = header.split_at_mut(MAGIC.len());
+        magic.copy_from_slice(MAGIC);
+        rest.copy_from_slice(&[FORMAT_VERSION, count as u8]);
+
+        let len = HEADER_LEN + count * RECORD_LEN;
+        let crc = crate::crash::crc32(out.get(..len).unwrap_or(&[]));
+        if let Some(tail) = out.get_mut(len..len + 4) {
+            tail.copy_from_slice(&crc.to_le_bytes());
+        }
+        len + 4
+    }
+
+    /// Encode the state and hand it to `store`.
+    pub fn persist<S: JobStore>(&self, store: &mut S) -> Result<(), S::Error> {
+        let mut buf = [0u8; MAX_STATE_LEN];
+        let len = self.encode(&mut buf);
+        store.save(buf.get(..len).unwrap_or(&buf))
+    }
+
+    /// Apply persisted last-run times to the registered jobs (call after
+    /// `add()`ing them) and recompute when each is next due. Records of
+    /// jobs no longer registered are ignored.
+    pub fn restore(&mut self, state: &[u8]) -> Result<(), JobError> {
+        if state.get(..3) != Some(&MAGIC[..]) || state.get(3) != Some(&FORMAT_VERSION) {
+            return Err(JobError::Corrupt);
+        }
+        let count = state.get(4).copied().ok_or(JobError::Corrupt)?;
+        let len = HEADER_LEN + usize::from(count) * RECORD_LEN;
+        let body = state.get(..len).ok_or(JobError::Corrupt)?;
+        let crc = state.get(len..len + 4).ok_or(JobError::Corrupt)?;
+        if crate::crash::crc32(body).to_le_bytes() != crc {
+            return Err(JobError::Corrupt);
+        }
+        for rec in body.get(HEADER_LEN..).unwrap_or(&[]).chunks_exact(RECORD_LEN) {
+            let (id, last) = rec.split_at(2);
+            let id = u16::from_le_bytes(id.try_into().map_err(|_| JobError::Corrupt)?);
+            let last = u64::from_le_bytes(last.try_into().map_err(|_| JobError::Corrupt)?);
+            if let Some(e) = self.entries.iter_mut().flatten().find(|e| e.job.id == id) {
+                e.last_run = Some(last);
+                e.next = next_after(e.job.schedule, last);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for JobScheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    const DAY: u64 = SECS_PER_DAY;
+    const T0: u64 = 1_700_000_000 - 1_700_000_000 % DAY; // a midnight
+
+    fn job(id: u16, schedule: Schedule, catch_up: CatchUp) -> Job {
+        Job { id, schedule, catch_up }
+    }
+
+    fn ran(s: &mut JobScheduler, now: u64) -> Vec<u16> {
+        let mut ids = Vec::new();
+        s.poll(now, |j| ids.push(j.id));
+        ids
+    }
+
+    #[test]
+    fn periodic_daily_and_one_shot() {
+        let mut s = JobScheduler::new();
+        s.add(job(1, Schedule::Every { secs: 600 }, CatchUp::Skip), T0).unwrap();
+        s.add(job(2, Schedule::Daily { at: 3 * 3600 }, CatchUp::Skip), T0).unwrap();
+        s.add(job(3, Schedule::At { at: T0 + 900 }, CatchUp::Skip), T0).unwrap();
+        assert_eq!(s.add(job(1, Schedule::Every { secs: 5 }, CatchUp::Skip), T0), Err(JobError::Duplicate));
+        assert_eq!(s.add(job(9, Schedule::Every { secs: 0 }, CatchUp::Skip), T0), Err(JobError::BadSchedule));
+
+        assert!(ran(&mut s, T0 + 599).is_empty());
+        assert_eq!(s.idle_ms(T0 + 599), 1000);
+        assert_eq!(ran(&mut s, T0 + 600), [1]);
+        assert_eq!(ran(&mut s, T0 + 900), [3]);
+        assert_eq!(s.next_due(3), None, "one-shot is done");
+        assert_eq!(ran(&mut s, T0 + 1200), [1]);
+        assert_eq!(s.next_due(2), Some(T0 + 3 * 3600));
+        assert_eq!(ran(&mut s, T0 + 3 * 3600 + 10), [2]);
+        assert_eq!(s.next_due(2), Some(T0 + DAY + 3 * 3600));
+    }
+
+    #[test]
+    fn catch_up_after_sleep() {
+        let mut s = JobScheduler::new();
+        s.add(job(1, Schedule::Every { secs: 600 }, CatchUp::Skip), T0).unwrap();
+        s.add(job(2, Schedule::Every { secs: 600 }, CatchUp::RunOnce), T0).unwrap();
+        // Asleep for several periods: one run for RunOnce, none for Skip.
+        assert_eq!(ran(&mut s, T0 + 5000), [2]);
+        assert_eq!(s.next_due(1), Some(T0 + 5600));
+        assert_eq!(s.next_due(2), Some(T0 + 5600));
+        assert_eq!(ran(&mut s, T0 + 5600), [1, 2]);
+    }
+
+    struct Flash(Vec<u8>);
+
+    impl JobStore for Flash {
+        type Error = ();
+
+        fn save(&mut self, state: &[u8]) -> Result<(), ()> {
+            self.0 = state.to_vec();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn state_survives_reboot() {
+        let jobs = [
+            job(7, Schedule::Daily { at: 0 }, CatchUp::RunOnce),
+            job(8, Schedule::Every { secs: 3600 }, CatchUp::Skip),
+            job(9, Schedule::At { at: T0 + 10 }, CatchUp::Skip),
+        ];
+        let mut s = JobScheduler::new();
+        for j in jobs {
+            s.add(j, T0 - 1).unwrap();
+        }
+        assert_eq!(ran(&mut s, T0 + 10), [7, 9]);
+        let mut flash = Flash(Vec::new());
+        s.persist(&mut flash).unwrap();
+
+        // Reboot two days later
+        let mut s = JobScheduler::new();
+        let now = T0 + 2 * DAY + 5;
+        for j in jobs {
+            s.add(j, now).unwrap();
+        }
+        s.restore(&flash.0).unwrap();
+        assert_eq!(s.last_run(7), Some(T0 + 10));
+        assert_eq!(s.next_due(9), None, "one-shot already ran");
+        assert_eq!(ran(&mut s, now), [7], "daily job catches up once");
+
+        flash.0[6] ^= 1;
+        assert_eq!(s.restore(&flash.0), Err(JobError::Corrupt));
+        assert_eq!(s.restore(b"SJB"), Err(JobError::Corrupt));
+    }
+}
@@ -1,8 +1,8 @@
 //! SecureIoTOS Kernel Library Module
 //! ---------------------------------
 //! License : Dual License
-//!   - Apache 2.0 for open-source / personal use
-//!   - Commercial license required for closed-source use
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
 //! Author  : Md Mahbubur Rahman
 //! URL     : https://m-a-h-b-u-b.github.io
 //! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
@@ -12,35 +12,56 @@
 //! - Core subsystem initialization (MPU, NVIC, SysTick, etc.)
 //! - Scheduler startup and context switching
 //! - Safe halting in case of unexpected return
+//!
+//! # Notes
+//! - Assumes ARM Cortex-M architecture
+//! - Context switching relies on `SysTick` + `PendSV` exceptions
+//! - `kernel_start()` **never returns** under normal operation
 
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+// Unsafe-code audit gate: every `unsafe` block states why it is sound.
+// Memory-mapped registers are only touched through `regs`.
+#![deny(clippy::undocumented_unsafe_blocks)]
 
+// Declared first so `kassert!` / `kfail!` are usable by every module below.
+#[macro_use]
+pub mod assert;
+pub mod regs;
 pub mod scheduler;
 pub mod context;
+pub mod stack_guard;
+pub mod task_table;
 pub mod syscall;
+pub mod time;
+pub mod tickless;
+pub mod ipc_queue;
+pub mod jobs;
+pub mod edf;
+pub mod image;
+pub mod measured_boot;
+pub mod crash;
+pub mod fault;
+pub mod watchdog;
+pub mod runtime_monitor;
 pub mod init;
 
-//! # Notes
-//! - Assumes ARM Cortex-M architecture
-//! - Context switching relies on `SysTick` + `PendSV` exceptions
-//! - `kernel_start()` **never returns** under normal operation
-
-
 /// Starts the SecureIoTOS kernel.
 ///
 /// This function:
 /// 1. Initializes kernel core subsystems (MPU, NVIC, SysTick, etc.)
-/// 2. Starts the scheduler and enters the first task
-/// 3. Halts safely if execution ever returns (should never happen)
+/// 2. Pends the first context switch: `PendSV` enters the first task on
+///    its own process stack, and the boot code does not resume
+/// 3. Idles if there was no task to run, until one is added
 ///
 /// # Safety
-/// - Must be called **only once** from the reset handler
+/// - Must be called **only once** from the reset handler, in privileged
+///   Thread mode on the main stack, after the tasks have been added
 /// - Assumes:
 ///   * Stack pointers are properly set
 ///   * Memory protection (MPU) configured if used
 ///   * SysTick and NVIC are configured
 /// - Improper usage may cause undefined behavior
+#[cfg(target_arch = "arm")]
 pub fn kernel_start() -> ! {
     // ---------------------------
     // 1. Initialize core subsystems
@@ -50,19 +71,23 @@ pub fn kernel_start() -> ! {
     // ---------------------------
     // 2. Start the scheduler
     // ---------------------------
-    // This will trigger the first context switch via PendSV
-    scheduler::schedule();
+    // PendSV switches straight to the first task
+    context::start_first_task();
 
     // ---------------------------
-    // 3. Execution should never reach here
+    // 3. Only reached with no task to run
     // ---------------------------
-    // If it does, enter a safe low-power halt
+    // Wait in a low-power halt; the next schedule() after a task is added
+    // switches away from here
     loop {
-		// WFI stands for Wait For Interrupt.
-		// It halts the CPU core in a low-power state.
-		// The CPU stays halted until an interrupt occurs.
-		// During this time, the processor does not execute normal instructions, saving power.
-		// Once an interrupt is triggered, the CPU wakes up, handles the interrupt, and then continues execution.
+        // WFI stands for Wait For Interrupt.
+        // It halts the CPU core in a low-power state.
+        // The CPU stays halted until an interrupt occurs.
+        // During this time, the processor does not execute normal instructions, saving power.
+        // Once an interrupt is triggered, the CPU wakes up, handles the interrupt, and then continues execution.
+        #[cfg(feature = "tickless")]
+        tickless::idle();
+        #[cfg(not(feature = "tickless"))]
         cortex_m::asm::wfi(); // Wait For Interrupt
     }
 }
@@ -0,0 +1,257 @@
+//! SecureIoTOS Kernel Measured Boot Module
+//! ---------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author : Md Mahbubur Rahman
+//! URL    : https://m-a-h-b-u-b.github.io
+//! GitHub : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! The bootloader's measurement log, for attestation.
+//!
+//! Before running each stage the bootloader hashes it (its own code, the
+//! firmware image, the device configuration) and extends the digest into
+//! a chain register, `pcr = SHA-256(pcr || digest)` starting from zero
+//! (`bootloader::firmware::MeasurementLog`). It leaves the events and the
+//! final register in retained RAM or backup registers:
+//!
+//! ```text
+//! magic "SIOSMLOG" | version u8 | count u8 | reserved [2] | pcr [32] |
+//! count x (kind u8 | reserved [3] | digest [32])
+//! ```
+//!
+//! Early in init the platform hands those bytes to `capture()`, which
+//! replays the chain and keeps a copy, so the retained area can be reused.
+//! A log whose events do not reproduce its register was changed after the
+//! bootloader wrote it and is refused. `boot_log()` returns the copy; tasks
+//! read it with the `GetBootMeasurements` syscall and put it into signed
+//! attestation evidence, where a verifier compares the digests with
+//! reference values.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use sha2::{Digest, Sha256};
+
+/// Magic at the start of the log.
+pub const LOG_MAGIC: [u8; 8] = *b"SIOSMLOG";
+/// Layout version of the log.
+pub const LOG_VERSION: u8 = 1;
+/// Most events one boot records.
+pub const MAX_EVENTS: usize = 4;
+/// Header: magic, version, count, reserved, pcr.
+const HEADER_LEN: usize = 12 + 32;
+/// Encoded size of one event.
+const EVENT_LEN: usize = 4 + 32;
+/// Size of the log with every event slot used.
+pub const MAX_LOG_LEN: usize = HEADER_LEN + MAX_EVENTS * EVENT_LEN;
+
+/// What an event measured.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// The bootloader's own code
+    Bootloader = 1,
+    /// The firmware image it started
+    Firmware = 2,
+    /// Device configuration: fuses, option bytes, lock bits
+    Config = 3,
+}
+
+impl EventKind {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(EventKind::Bootloader),
+            2 => Some(EventKind::Firmware),
+            3 => Some(EventKind::Config),
+            _ => None,
+        }
+    }
+}
+
+/// One measurement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootEvent {
+    pub kind: EventKind,
+    /// SHA-256 of what was measured
+    pub digest: [u8; 32],
+}
+
+/// Why a log was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogError {
+    /// No log (erased or never written)
+    BadMagic,
+    /// Written by a newer layout version
+    UnsupportedVersion,
+    /// Shorter than its event count says
+    Truncated,
+    /// More than `MAX_EVENTS` events
+    TooManyEvents,
+    /// Unknown `EventKind`
+    BadKind,
+    /// The events do not reproduce the chain register
+    ChainMismatch,
+    /// `capture()` was already called this boot
+    AlreadyCaptured,
+}
+
+/// A parsed, replayed measurement log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootLog {
+    events: [Option<BootEvent>; MAX_EVENTS],
+    pcr: [u8; 32],
+}
+
+impl BootLog {
+    /// Parse `raw` and check that its events reproduce its register.
+    pub fn parse(raw: &[u8]) -> Result<Self, LogError> {
+        let header = raw.get(..HEADER_LEN).ok_or(LogError::Truncated)?;
+        if header.get(..8) != Some(&LOG_MAGIC[..]) {
+            return Err(LogError::BadMagic);
+        }
+        if header.get(8).copied() != Some(LOG_VERSION) {
+            return Err(LogError::UnsupportedVersion);
+        }
+        let count = header.get(9).copied().map_or(0, usize::from);
+        if count > MAX_EVENTS {
+            return Err(LogError::TooManyEvents);
+        }
+        let mut pcr = [0u8; 32];
+        pcr.copy_from_slice(header.get(12..).ok_or(LogError::Truncated)?);
+
+        let mut events = [None; MAX_EVENTS];
+        let mut chain = [0u8; 32];
+        let body = raw.get(HEADER_LEN..HEADER_LEN + count * EVENT_LEN).ok_or(LogError::Truncated)?;
+        for (slot, bytes) in events.iter_mut().zip(body.chunks_exact(EVENT_LEN)) {
+            let kind = bytes.first().copied().and_then(EventKind::from_u8).ok_or(LogError::BadKind)?;
+            let mut digest = [0u8; 32];
+            digest.copy_from_slice(bytes.get(4..).ok_or(LogError::Truncated)?);
+            chain = extend(&chain, &digest);
+            *slot = Some(BootEvent { kind, digest });
+        }
+        if chain != pcr {
+            return Err(LogError::ChainMismatch);
+        }
+        Ok(BootLog { events, pcr })
+    }
+
+    /// The events, in the order they were measured.
+    pub fn events(&self) -> impl Iterator<Item = &BootEvent> {
+        self.events.iter().flatten()
+    }
+
+    /// Digest of the first event of `kind`.
+    pub fn digest(&self, kind: EventKind) -> Option<[u8; 32]> {
+        self.events().find(|e| e.kind == kind).map(|e| e.digest)
+    }
+
+    /// Final value of the chain register.
+    pub fn pcr(&self) -> [u8; 32] {
+        self.pcr
+    }
+}
+
+/// One step of the chain: `SHA-256(pcr || digest)`.
+pub fn extend(pcr: &[u8; 32], digest: &[u8; 32]) -> [u8; 32] {
+    let mut h = Sha256::new();
+    h.update(pcr);
+    h.update(digest);
+    h.finalize().into()
+}
+
+/// The captured log: written once by `capture()`, read-only afterwards.
+struct Captured {
+    log: UnsafeCell<Option<BootLog>>,
+    /// `EMPTY` -> `WRITING` -> `READY`, never back
+    state: AtomicU8,
+}
+
+const EMPTY: u8 = 0;
+const WRITING: u8 = 1;
+const READY: u8 = 2;
+
+// SAFETY: `log` is written only by the one caller that moved `state` from
+// EMPTY to WRITING, and read only once `state` is READY (Release/Acquire).
+unsafe impl Sync for Captured {}
+
+static CAPTURED: Captured = Captured { log: UnsafeCell::new(None), state: AtomicU8::new(EMPTY) };
+
+/// Take over the bootloader's log from wherever it left it. Call once,
+/// during init, before tasks run.
+pub fn capture(raw: &[u8]) -> Result<&'static BootLog, LogError> {
+    if CAPTURED.state.compare_exchange(EMPTY, WRITING, Ordering::Acquire, Ordering::Relaxed).is_err() {
+        return Err(LogError::AlreadyCaptured);
+    }
+    let parsed = BootLog::parse(raw);
+    // SAFETY: this caller won the EMPTY -> WRITING transition, so it is
+    // the only writer, and no reader looks before READY.
+    unsafe { *CAPTURED.log.get() = parsed.ok() };
+    CAPTURED.state.store(READY, Ordering::Release);
+    parsed?;
+    boot_log().ok_or(LogError::BadMagic)
+}
+
+/// The log captured this boot, if it was valid.
+pub fn boot_log() -> Option<&'static BootLog> {
+    if CAPTURED.state.load(Ordering::Acquire) != READY {
+        return None;
+    }
+    // SAFETY: READY, so the log is never written again.
+    unsafe { (*CAPTURED.log.get()).as_ref() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode a log the way the bootloader does.
+    fn encode(events: &[(u8, [u8; 32])]) -> [u8; MAX_LOG_LEN] {
+        let mut out = [0u8; MAX_LOG_LEN];
+        out[..8].copy_from_slice(&LOG_MAGIC);
+        out[8] = LOG_VERSION;
+        out[9] = events.len() as u8;
+        let mut pcr = [0u8; 32];
+        for (i, (kind, digest)) in events.iter().enumerate() {
+            let at = HEADER_LEN + i * EVENT_LEN;
+            out[at] = *kind;
+            out[at + 4..at + EVENT_LEN].copy_from_slice(digest);
+            pcr = extend(&pcr, digest);
+        }
+        out[12..44].copy_from_slice(&pcr);
+        out
+    }
+
+    #[test]
+    fn log_replays_and_tampering_is_refused() {
+        let raw = encode(&[(1, [0x11; 32]), (2, [0x22; 32]), (3, [0x33; 32])]);
+        let log = BootLog::parse(&raw).unwrap();
+        assert_eq!(log.events().count(), 3);
+        assert_eq!(log.digest(EventKind::Firmware), Some([0x22; 32]));
+        assert_eq!(log.pcr(), extend(&extend(&extend(&[0; 32], &[0x11; 32]), &[0x22; 32]), &[0x33; 32]));
+
+        // A swapped firmware digest no longer reproduces the register.
+        let mut forged = raw;
+        forged[HEADER_LEN + EVENT_LEN + 4] ^= 1;
+        assert_eq!(BootLog::parse(&forged), Err(LogError::ChainMismatch));
+        // Dropping the last event does not either.
+        let mut cut = raw;
+        cut[9] = 2;
+        assert_eq!(BootLog::parse(&cut), Err(LogError::ChainMismatch));
+
+        assert_eq!(BootLog::parse(&[0u8; MAX_LOG_LEN]), Err(LogError::BadMagic));
+        assert_eq!(BootLog::parse(&raw[..HEADER_LEN + EVENT_LEN]), Err(LogError::Truncated));
+        let mut unknown = encode(&[(9, [0; 32])]);
+        assert_eq!(BootLog::parse(&unknown), Err(LogError::BadKind));
+        unknown[9] = 5;
+        assert_eq!(BootLog::parse(&unknown), Err(LogError::TooManyEvents));
+    }
+
+    #[test]
+    fn capture_happens_once() {
+        let raw = encode(&[(2, [0x44; 32])]);
+        assert_eq!(capture(&raw).map(|l| l.pcr()), Ok(extend(&[0; 32], &[0x44; 32])));
+        assert_eq!(boot_log().and_then(|l| l.digest(EventKind::Firmware)), Some([0x44; 32]));
+        assert_eq!(capture(&raw).err(), Some(LogError::AlreadyCaptured));
+    }
+}
@@ -0,0 +1,286 @@
+//! SecureIoTOS Kernel Register Access Module
+//! -----------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author : Md Mahbubur Rahman
+//! URL    : https://m-a-h-b-u-b.github.io
+//! GitHub : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Safe access to the System Control Space registers the kernel programs:
+//! SCB (ICSR, SHCSR, fault status), SysTick, NVIC and MPU.
+//!
+//! This is the only kernel module that turns an address into a volatile
+//! read or write. Registers are private `Reg` constants whose addresses
+//! are checked at compile time to be aligned and inside the SCS; callers
+//! get one function per operation, which `debug_assert!`s its arguments
+//! (reload range, IRQ number, region alignment) before touching hardware.
+//!
+//! Adding a register means adding a constant here, checked against the
+//! ARMv7-M Architecture Reference Manual (B3.2 System Control Space).
+
+// Core kernel path: must not panic (see `tools/no-panic-check`).
+#![cfg_attr(not(test), deny(
+    clippy::panic,
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::indexing_slicing,
+    clippy::unreachable,
+    clippy::todo,
+    clippy::unimplemented
+))]
+
+use core::ptr::{read_volatile, write_volatile};
+
+/// System Control Space: every register of this module lies in it.
+const SCS_START: usize = 0xE000_E000;
+const SCS_END: usize = 0xE000_F000;
+
+/// A 32-bit memory-mapped register in the System Control Space.
+#[derive(Clone, Copy)]
+struct Reg(usize);
+
+impl Reg {
+    /// Only usable in `const` items, so a bad address fails the build.
+    const fn at(addr: usize) -> Self {
+        assert!(addr >= SCS_START && addr < SCS_END && addr.is_multiple_of(4), "not an SCS register");
+        Reg(addr)
+    }
+
+    /// The `n`-th register of a bank starting at `self` (e.g. ISERn).
+    fn nth(self, n: usize) -> Self {
+        let addr = self.0 + n * 4;
+        debug_assert!(addr < SCS_END, "register bank index out of range");
+        Reg(addr)
+    }
+
+    fn read(self) -> u32 {
+        // SAFETY: `Reg` values are the checked constants below (or `nth`
+        // of one within the SCS); SCS registers are always mapped and
+        // 32-bit accessible from privileged code, the kernel's context.
+        unsafe { read_volatile(self.0 as *const u32) }
+    }
+
+    fn write(self, value: u32) {
+        // SAFETY: as for `read`; what each write does is the concern of
+        // the typed wrapper calling it.
+        unsafe { write_volatile(self.0 as *mut u32, value) }
+    }
+}
+
+fn barrier() {
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
+
+/// System Control Block.
+pub mod scb {
+    use super::Reg;
+    use crate::crash::FaultStatus;
+
+    const ICSR: Reg = Reg::at(0xE000_ED04);
+    const ICSR_PENDSVSET: u32 = 1 << 28;
+
+    const SHCSR: Reg = Reg::at(0xE000_ED24);
+    const CFSR: Reg = Reg::at(0xE000_ED28);
+    const HFSR: Reg = Reg::at(0xE000_ED2C);
+    const MMFAR: Reg = Reg::at(0xE000_ED34);
+    const BFAR: Reg = Reg::at(0xE000_ED38);
+
+    const SHCSR_MEMFAULTENA: u32 = 1 << 16;
+    const SHCSR_BUSFAULTENA: u32 = 1 << 17;
+    const SHCSR_USGFAULTENA: u32 = 1 << 18;
+
+    /// Pend the PendSV exception (context switch at exception return).
+    /// Writing zeros to the other ICSR bits has no effect.
+    pub fn set_pendsv() {
+        ICSR.write(ICSR_PENDSVSET);
+    }
+
+    /// Route MemManage, BusFault and UsageFault to their own handlers
+    /// instead of escalating them to HardFault.
+    pub fn enable_fault_handlers() {
+        SHCSR.write(SHCSR.read() | SHCSR_MEMFAULTENA | SHCSR_BUSFAULTENA | SHCSR_USGFAULTENA);
+        super::barrier();
+    }
+
+    /// CFSR, HFSR and the fault address registers. MMFAR / BFAR are only
+    /// meaningful while CFSR.MMARVALID / BFARVALID are set.
+    pub fn fault_status() -> FaultStatus {
+        FaultStatus { cfsr: CFSR.read(), hfsr: HFSR.read(), mmfar: MMFAR.read(), bfar: BFAR.read() }
+    }
+
+    /// Clear the bits of `status` in CFSR and HFSR (write-one-to-clear),
+    /// so the next fault reports only its own causes.
+    pub fn clear_fault_status(status: &FaultStatus) {
+        CFSR.write(status.cfsr);
+        HFSR.write(status.hfsr);
+    }
+}
+
+/// SysTick timer.
+pub mod syst {
+    use super::Reg;
+
+    const CSR: Reg = Reg::at(0xE000_E010);
+    const RVR: Reg = Reg::at(0xE000_E014);
+    const CVR: Reg = Reg::at(0xE000_E018);
+
+    const CSR_ENABLE: u32 = 1 << 0;
+    const CSR_TICKINT: u32 = 1 << 1;
+    const CSR_CLKSOURCE_CORE: u32 = 1 << 2;
+    const CSR_COUNTFLAG: u32 = 1 << 16;
+
+    /// Largest reload value (24-bit counter).
+    pub const MAX_RELOAD: u32 = 0x00FF_FFFF;
+
+    /// Stop the counter and its interrupt.
+    pub fn disable() {
+        CSR.write(0);
+    }
+
+    /// Set the reload value; the counter period is `reload + 1` cycles.
+    pub fn set_reload(reload: u32) {
+        debug_assert!(reload != 0 && reload <= MAX_RELOAD, "SysTick reload out of range");
+        RVR.write(reload & MAX_RELOAD);
+    }
+
+    /// Current reload value.
+    pub fn reload() -> u32 {
+        RVR.read() & MAX_RELOAD
+    }
+
+    /// Cycles left until the counter next reaches zero.
+    pub fn current() -> u32 {
+        CVR.read() & MAX_RELOAD
+    }
+
+    /// Whether the counter reached zero since the last call (reading
+    /// clears COUNTFLAG).
+    pub fn has_wrapped() -> bool {
+        CSR.read() & CSR_COUNTFLAG != 0
+    }
+
+    /// Clear the current value (and COUNTFLAG).
+    pub fn clear_current() {
+        CVR.write(0);
+    }
+
+    /// Start counting on the processor clock with the SysTick exception on.
+    pub fn enable_with_interrupt() {
+        CSR.write(CSR_CLKSOURCE_CORE | CSR_TICKINT | CSR_ENABLE);
+    }
+}
+
+/// Nested Vectored Interrupt Controller.
+pub mod nvic {
+    use super::Reg;
+
+    const ISER0: Reg = Reg::at(0xE000_E100);
+    const ICER0: Reg = Reg::at(0xE000_E180);
+
+    /// External interrupts an ARMv7-M NVIC can implement up to (Cortex-M3/M4).
+    pub const MAX_IRQS: u16 = 240;
+
+    /// Enable external interrupt `irq`.
+    pub fn enable(irq: u8) {
+        debug_assert!(u16::from(irq) < MAX_IRQS, "IRQ number out of range");
+        ISER0.nth(usize::from(irq / 32)).write(1 << (irq % 32));
+    }
+
+    /// Disable external interrupt `irq`.
+    pub fn disable(irq: u8) {
+        debug_assert!(u16::from(irq) < MAX_IRQS, "IRQ number out of range");
+        ICER0.nth(usize::from(irq / 32)).write(1 << (irq % 32));
+        super::barrier();
+    }
+}
+
+/// Memory Protection Unit (ARMv7-M PMSA).
+pub mod mpu {
+    use super::Reg;
+
+    const TYPE: Reg = Reg::at(0xE000_ED90);
+    const CTRL: Reg = Reg::at(0xE000_ED94);
+    const RNR: Reg = Reg::at(0xE000_ED98);
+    const RBAR: Reg = Reg::at(0xE000_ED9C);
+    const RASR: Reg = Reg::at(0xE000_EDA0);
+
+    const CTRL_ENABLE: u32 = 1 << 0;
+    const CTRL_PRIVDEFENA: u32 = 1 << 2;
+
+    const RASR_ENABLE: u32 = 1 << 0;
+    const RASR_SIZE_SHIFT: u32 = 1;
+    const RASR_SIZE_MASK: u32 = 0x1F;
+    const RASR_SRD_MASK: u32 = 0xFF << 8;
+    const RASR_AP_SHIFT: u32 = 24;
+    const RASR_AP_MASK: u32 = 0b111;
+    /// AP: read/write for privileged and unprivileged code
+    const AP_FULL_ACCESS: u32 = 0b011;
+
+    /// Number of regions the MPU implements; 0 if there is no MPU.
+    pub fn regions() -> u8 {
+        ((TYPE.read() >> 8) & 0xFF) as u8
+    }
+
+    /// Turn the MPU off (before reprogramming regions).
+    pub fn disable() {
+        super::barrier();
+        CTRL.write(0);
+    }
+
+    /// Turn the MPU on. With `privileged_default_map`, privileged code
+    /// keeps the default memory map where no region matches.
+    pub fn enable(privileged_default_map: bool) {
+        let privdef = if privileged_default_map { CTRL_PRIVDEFENA } else { 0 };
+        CTRL.write(CTRL_ENABLE | privdef);
+        super::barrier();
+    }
+
+    /// Program region `number` with base address `base` and attribute/size
+    /// word `rasr`. An enabled region must be at least 32 bytes and `base`
+    /// aligned to its size.
+    pub fn set_region(number: u8, base: u32, rasr: u32) {
+        debug_assert!(number < regions(), "MPU region number out of range");
+        if rasr & RASR_ENABLE != 0 {
+            let size_field = (rasr >> RASR_SIZE_SHIFT) & RASR_SIZE_MASK;
+            debug_assert!(size_field >= 4, "MPU region smaller than 32 bytes");
+            let size = 1u64 << (size_field + 1);
+            debug_assert!(u64::from(base).is_multiple_of(size), "MPU region base not aligned to its size");
+        }
+        RNR.write(u32::from(number));
+        RBAR.write(base & !0x1F);
+        RASR.write(rasr);
+    }
+
+    /// Whether unprivileged code may read and write all of
+    /// `[base, base + len)`: it must lie inside one region with full access
+    /// and no disabled subregions, and no higher-numbered region (which
+    /// takes priority, e.g. a stack guard) may overlap it. Everything is
+    /// accessible while the MPU is off. Selects regions through RNR, so
+    /// must not race with `set_region`.
+    pub fn user_accessible(base: u32, len: u32) -> bool {
+        if CTRL.read() & CTRL_ENABLE == 0 {
+            return true;
+        }
+        let (start, end) = (u64::from(base), u64::from(base) + u64::from(len));
+        let mut allowed = false;
+        for number in 0..regions() {
+            RNR.write(u32::from(number));
+            let rasr = RASR.read();
+            if rasr & RASR_ENABLE == 0 {
+                continue;
+            }
+            let region = u64::from(RBAR.read() & !0x1F);
+            let size = 1u64 << (((rasr >> RASR_SIZE_SHIFT) & RASR_SIZE_MASK) + 1);
+            if end <= region || start >= region + size {
+                continue;
+            }
+            allowed = start >= region
+                && end <= region + size
+                && rasr & RASR_SRD_MASK == 0
+                && (rasr >> RASR_AP_SHIFT) & RASR_AP_MASK == AP_FULL_ACCESS;
+        }
+        allowed
+    }
+}
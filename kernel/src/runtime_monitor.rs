@@ -0,0 +1,592 @@
+//! SecureIoTOS Kernel Runtime Monitor Module
+//! -----------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author : Md Mahbubur Rahman
+//! URL    : https://m-a-h-b-u-b.github.io
+//! GitHub : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Runtime anomaly monitoring with enforcement ("watch mode").
+//!
+//! Detectors report anomalies of a task with `report()`:
+//! - the built-in CPU check flags tasks running above the budget set with
+//!   `set_cpu_budget()` (checked by `service()`);
+//! - `on_deadline_miss` and `on_heartbeat_miss` can be installed as the
+//!   scheduler's deadline-miss hook and the watchdog's miss hook;
+//! - `syscall_entry` reports every syscall refused for lack of
+//!   capabilities;
+//! - fault handlers and application-level detectors may report too.
+//!
+//! Each `Anomaly` kind may have a `Policy` (`set_policy()`; by default
+//! none, so reports are only counted). Its `Action` runs once a task has
+//! collected `trigger` reports of that kind:
+//! - `Log`: audit record only;
+//! - `Suspend`: the task leaves the rotation (`scheduler::suspend_task`);
+//! - `Restart`: the task is killed and handed to the hook from
+//!   `set_restart_hook()`, as `watchdog` does;
+//! - `Sandbox(bits)`: the `syscall::caps` bits are dropped for good.
+//!
+//! Hysteresis: every `CHECK_INTERVAL` without a report of a kind lowers
+//! the task's score for it by one. An action fires when the score reaches
+//! `trigger` and is lifted only once it is back down to `release`: a
+//! suspended task is resumed, the other actions are re-armed. A task
+//! hovering around its threshold is not suspended and resumed every
+//! period, and a suspended task sits out at least `trigger - release`
+//! periods.
+//!
+//! Every action taken, lifted or refused by the scheduler is recorded in
+//! an audit log (the last `AUDIT_LEN` records, `audit_log()`) and passed
+//! to the hook from `set_audit_hook()`, e.g. to persist it.
+
+// Core kernel path: must not panic (see `tools/no-panic-check`).
+#![cfg_attr(not(test), deny(
+    clippy::panic,
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::indexing_slicing,
+    clippy::unreachable,
+    clippy::todo,
+    clippy::unimplemented
+))]
+
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+
+use crate::edf::DeadlineMiss;
+use crate::scheduler::{self, TaskStats, MAX_TASKS};
+use crate::time::{self, reached};
+use crate::watchdog::Missed;
+
+/// Ticks between two `service()` runs (one hysteresis period).
+pub const CHECK_INTERVAL: u32 = 100;
+
+/// Audit records kept by `audit_log()`.
+pub const AUDIT_LEN: usize = 16;
+
+const KINDS: usize = 5;
+
+/// Decisions one `Enforcer::decay()` can produce.
+const MAX_DECISIONS: usize = MAX_TASKS * KINDS;
+
+/// Kind of misbehaviour reported for a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Anomaly {
+    /// CPU share above the task's budget over a check period
+    CpuOverrun = 0,
+    /// Periodic job missed its deadline
+    DeadlineMiss = 1,
+    /// Watchdog heartbeat missed
+    HeartbeatMiss = 2,
+    /// Syscall refused for lack of capabilities
+    SyscallDenied = 3,
+    /// Reported by an application-level detector
+    Detector = 4,
+}
+
+impl Anomaly {
+    const ALL: [Anomaly; KINDS] =
+        [Anomaly::CpuOverrun, Anomaly::DeadlineMiss, Anomaly::HeartbeatMiss, Anomaly::SyscallDenied, Anomaly::Detector];
+}
+
+/// Enforcement applied to an offending task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Audit record only
+    Log,
+    /// Take the task out of the rotation until its score has decayed
+    Suspend,
+    /// Kill the task and let the restart hook start it again
+    Restart,
+    /// Drop these `syscall::caps` bits (not restored on release)
+    Sandbox(u32),
+}
+
+/// When and how to act on one kind of anomaly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Policy {
+    pub action: Action,
+    /// Score at which the action fires (at least 1)
+    pub trigger: u8,
+    /// Score at which it is lifted again (below `trigger`)
+    pub release: u8,
+}
+
+/// What happened, as recorded in the audit log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditEvent {
+    /// Action applied
+    Enforced,
+    /// Action lifted after the score decayed
+    Released,
+    /// The scheduler refused the action (e.g. last runnable task)
+    Refused,
+}
+
+/// One audit log entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// Tick of the event
+    pub tick: u32,
+    /// Task id
+    pub task: u32,
+    pub anomaly: Anomaly,
+    pub action: Action,
+    pub event: AuditEvent,
+}
+
+/// Why a monitor call failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MonitorError {
+    /// `trigger` is 0 or `release` is not below it
+    BadPolicy,
+    /// All `MAX_TASKS` tracking entries are in use
+    Full,
+    /// Monitor state is busy (re-entrant call)
+    Busy,
+}
+
+/// An action to apply (or with `release`, to lift), as decided by
+/// `Enforcer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Decision {
+    pub task: u32,
+    pub anomaly: Anomaly,
+    pub action: Action,
+    pub release: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Track {
+    task: u32,
+    scores: [u8; KINDS],
+    /// Kinds reported since the last `decay()` (bit per kind)
+    seen: u8,
+    /// Kinds whose action is in force (bit per kind)
+    enforced: u8,
+    /// CPU budget in 0.1 %
+    cpu_budget: Option<u16>,
+    /// `run_ticks` at the previous CPU check
+    cpu_prev: u64,
+}
+
+impl Track {
+    const fn new(task: u32) -> Self {
+        Self { task, scores: [0; KINDS], seen: 0, enforced: 0, cpu_budget: None, cpu_prev: 0 }
+    }
+}
+
+/// Anomaly scores and enforcement state for up to `MAX_TASKS` tasks.
+pub struct Enforcer {
+    tracks: [Option<Track>; MAX_TASKS],
+    policies: [Option<Policy>; KINDS],
+}
+
+impl Enforcer {
+    pub const fn new() -> Self {
+        Self { tracks: [None; MAX_TASKS], policies: [None; KINDS] }
+    }
+
+    /// Set (or with `None`, clear) the policy for `anomaly`.
+    pub fn set_policy(&mut self, anomaly: Anomaly, policy: Option<Policy>) -> Result<(), MonitorError> {
+        if policy.is_some_and(|p| p.trigger == 0 || p.release >= p.trigger) {
+            return Err(MonitorError::BadPolicy);
+        }
+        if let Some(slot) = self.policies.get_mut(anomaly as usize) {
+            *slot = policy;
+        }
+        Ok(())
+    }
+
+    fn policy(&self, anomaly: Anomaly) -> Option<Policy> {
+        self.policies.get(anomaly as usize).copied().flatten()
+    }
+
+    /// Tracking entry of `task`, created on first use.
+    fn track(&mut self, task: u32) -> Result<&mut Track, MonitorError> {
+        let index = match self.tracks.iter().position(|t| t.is_some_and(|t| t.task == task)) {
+            Some(i) => i,
+            None => self.tracks.iter().position(Option::is_none).ok_or(MonitorError::Full)?,
+        };
+        let slot = self.tracks.get_mut(index).ok_or(MonitorError::Full)?;
+        Ok(slot.get_or_insert(Track::new(task)))
+    }
+
+    /// Flag `task` as `CpuOverrun` when its CPU share over a check period
+    /// exceeds `budget` (0.1 %), or stop checking it with `None`.
+    pub fn set_cpu_budget(&mut self, task: u32, budget: Option<u16>) -> Result<(), MonitorError> {
+        self.track(task)?.cpu_budget = budget;
+        Ok(())
+    }
+
+    /// Stop tracking `task` (it ended or was restarted).
+    pub fn forget(&mut self, task: u32) {
+        if let Some(slot) = self.tracks.iter_mut().find(|t| t.is_some_and(|t| t.task == task)) {
+            *slot = None;
+        }
+    }
+
+    /// Let `anomaly`'s action fire again for `task`, e.g. after the
+    /// scheduler refused it.
+    pub fn rearm(&mut self, task: u32, anomaly: Anomaly) {
+        if let Some(t) = self.tracks.iter_mut().flatten().find(|t| t.task == task) {
+            t.enforced &= !(1 << anomaly as u8);
+        }
+    }
+
+    /// Count one `anomaly` of `task`. Returns the action to apply if the
+    /// score just reached the policy's trigger.
+    pub fn report(&mut self, task: u32, anomaly: Anomaly) -> Result<Option<Decision>, MonitorError> {
+        let policy = self.policy(anomaly);
+        let t = self.track(task)?;
+        let bit = 1 << anomaly as u8;
+        t.seen |= bit;
+        let Some(score) = t.scores.get_mut(anomaly as usize) else { return Ok(None) };
+        *score = score.saturating_add(1);
+        let Some(policy) = policy.filter(|p| *score >= p.trigger && t.enforced & bit == 0) else {
+            return Ok(None);
+        };
+        t.enforced |= bit;
+        Ok(Some(Decision { task, anomaly, action: policy.action, release: false }))
+    }
+
+    /// End a check period: lower the score of every kind not reported
+    /// during it, and return the actions to lift. A suspension stays in
+    /// force while another kind still holds the task suspended.
+    pub fn decay(&mut self) -> [Option<Decision>; MAX_DECISIONS] {
+        let mut out = [None; MAX_DECISIONS];
+        let mut next = out.iter_mut();
+        for t in self.tracks.iter_mut().flatten() {
+            for (kind, score) in Anomaly::ALL.iter().zip(t.scores.iter_mut()) {
+                let bit = 1 << *kind as u8;
+                if t.seen & bit == 0 {
+                    *score = score.saturating_sub(1);
+                }
+                let Some(policy) = self.policies.get(*kind as usize).copied().flatten() else { continue };
+                if t.enforced & bit == 0 || *score > policy.release {
+                    continue;
+                }
+                t.enforced &= !bit;
+                let still_suspended = Anomaly::ALL.iter().any(|k| {
+                    t.enforced & (1 << *k as u8) != 0
+                        && self.policies.get(*k as usize).copied().flatten().is_some_and(|p| p.action == Action::Suspend)
+                });
+                if policy.action == Action::Suspend && still_suspended {
+                    continue;
+                }
+                if let Some(slot) = next.next() {
+                    *slot = Some(Decision { task: t.task, anomaly: *kind, action: policy.action, release: true });
+                }
+            }
+            t.seen = 0;
+        }
+        out
+    }
+
+    /// Compare every budgeted task's CPU use since the previous call with
+    /// its budget, over a period of `window` ticks, and report overruns.
+    /// Tracks of tasks missing from `stats` are dropped.
+    pub fn check_cpu(&mut self, stats: &[Option<TaskStats>], window: u32) -> [Option<Decision>; MAX_TASKS] {
+        let mut over = [None; MAX_TASKS];
+        for slot in self.tracks.iter_mut() {
+            let Some(t) = slot.as_mut() else { continue };
+            let Some(s) = stats.iter().flatten().find(|s| s.id == t.task) else {
+                *slot = None;
+                continue;
+            };
+            let used = s.run_ticks.saturating_sub(t.cpu_prev);
+            t.cpu_prev = s.run_ticks;
+            let permille = used.saturating_mul(1000) / u64::from(window.max(1));
+            if t.cpu_budget.is_some_and(|b| permille > u64::from(b)) {
+                if let Some(o) = over.iter_mut().find(|o| o.is_none()) {
+                    *o = Some(t.task);
+                }
+            }
+        }
+        let mut decisions = [None; MAX_TASKS];
+        for (task, d) in over.iter().flatten().zip(decisions.iter_mut()) {
+            *d = self.report(*task, Anomaly::CpuOverrun).ok().flatten();
+        }
+        decisions
+    }
+
+    /// Stop tracking restarted task `old`, moving its CPU budget to its
+    /// successor `new` (if it was started again).
+    fn replace(&mut self, old: u32, new: Option<u32>) -> Result<(), MonitorError> {
+        let budget = self.tracks.iter().flatten().find(|t| t.task == old).and_then(|t| t.cpu_budget);
+        self.forget(old);
+        match new {
+            Some(new) if budget.is_some() => self.set_cpu_budget(new, budget),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Default for Enforcer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Ring of the last `AUDIT_LEN` audit records.
+pub struct AuditLog {
+    records: [Option<AuditRecord>; AUDIT_LEN],
+    next: usize,
+}
+
+impl AuditLog {
+    pub const fn new() -> Self {
+        Self { records: [None; AUDIT_LEN], next: 0 }
+    }
+
+    /// Append `record`, overwriting the oldest once full.
+    pub fn push(&mut self, record: AuditRecord) {
+        if let Some(slot) = self.records.get_mut(self.next % AUDIT_LEN) {
+            *slot = Some(record);
+        }
+        self.next = self.next.wrapping_add(1);
+    }
+
+    /// Records, oldest first.
+    pub fn records(&self) -> [Option<AuditRecord>; AUDIT_LEN] {
+        let start = if self.next > AUDIT_LEN { self.next % AUDIT_LEN } else { 0 };
+        core::array::from_fn(|i| self.records.get((start + i) % AUDIT_LEN).copied().flatten())
+    }
+}
+
+impl Default for AuditLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct MonitorState {
+    enforcer: Enforcer,
+    audit: AuditLog,
+    next_check: u32,
+    last_check: u32,
+    audit_hook: Option<fn(&AuditRecord)>,
+    restart_hook: Option<fn(u32) -> Option<u32>>,
+}
+
+static STATE: Mutex<RefCell<MonitorState>> = Mutex::new(RefCell::new(MonitorState {
+    enforcer: Enforcer::new(),
+    audit: AuditLog::new(),
+    next_check: 0,
+    last_check: 0,
+    audit_hook: None,
+    restart_hook: None,
+}));
+
+fn with_state<R>(f: impl FnOnce(&mut MonitorState) -> R) -> Result<R, MonitorError> {
+    interrupt::free(|cs| {
+        let mut st = STATE.borrow(cs).try_borrow_mut().map_err(|_| MonitorError::Busy)?;
+        Ok(f(&mut st))
+    })
+}
+
+/// Set (or with `None`, clear) the policy for `anomaly`. ISR-safe.
+pub fn set_policy(anomaly: Anomaly, policy: Option<Policy>) -> Result<(), MonitorError> {
+    with_state(|st| st.enforcer.set_policy(anomaly, policy))?
+}
+
+/// Report task `task` as `CpuOverrun` whenever its CPU share over a
+/// check period exceeds `budget_permille`; `None` stops the check.
+/// ISR-safe.
+pub fn set_cpu_budget(task: u32, budget_permille: Option<u16>) -> Result<(), MonitorError> {
+    with_state(|st| st.enforcer.set_cpu_budget(task, budget_permille))?
+}
+
+/// Install the hook told about every audit record (persistent log,
+/// telemetry, ...). Called from task, ISR and SysTick context: keep it
+/// short.
+pub fn set_audit_hook(hook: fn(&AuditRecord)) -> Result<(), MonitorError> {
+    with_state(|st| st.audit_hook = Some(hook))
+}
+
+/// Install the hook that starts a task killed by `Action::Restart` again.
+/// It gets the old task id and returns the new one, or `None` if the task
+/// stays down.
+pub fn set_restart_hook(hook: fn(u32) -> Option<u32>) -> Result<(), MonitorError> {
+    with_state(|st| st.restart_hook = Some(hook))
+}
+
+/// The last `AUDIT_LEN` audit records, oldest first. ISR-safe.
+pub fn audit_log() -> Result<[Option<AuditRecord>; AUDIT_LEN], MonitorError> {
+    with_state(|st| st.audit.records())
+}
+
+/// Report one `anomaly` of task `task`, enforcing its policy if the task
+/// just crossed the trigger. ISR-safe.
+pub fn report(task: u32, anomaly: Anomaly) -> Result<(), MonitorError> {
+    if let Some(d) = with_state(|st| st.enforcer.report(task, anomaly))?? {
+        apply(&d);
+    }
+    Ok(())
+}
+
+/// Deadline-miss hook for `scheduler::set_deadline_miss_hook`.
+pub fn on_deadline_miss(miss: &DeadlineMiss) {
+    let _ = report(miss.task, Anomaly::DeadlineMiss);
+}
+
+/// Miss hook for `watchdog::set_miss_hook`.
+pub fn on_heartbeat_miss(missed: &Missed) {
+    let _ = report(missed.task, Anomaly::HeartbeatMiss);
+}
+
+/// Check CPU budgets, end the hysteresis period and apply the resulting
+/// actions, at most once per `CHECK_INTERVAL`. Called from the SysTick
+/// handler only.
+pub fn service() {
+    let now = time::ticks();
+    let Ok(Some(window)) = with_state(|st| {
+        if !reached(now, st.next_check) {
+            return None;
+        }
+        st.next_check = now.wrapping_add(CHECK_INTERVAL);
+        Some(now.wrapping_sub(core::mem::replace(&mut st.last_check, now)))
+    }) else {
+        return;
+    };
+    // Read outside our state borrow: the scheduler has its own.
+    let Ok(stats) = scheduler::stats() else { return };
+    let Ok((overruns, releases)) = with_state(|st| {
+        let overruns = st.enforcer.check_cpu(&stats, window);
+        (overruns, st.enforcer.decay())
+    }) else {
+        return;
+    };
+    overruns.iter().chain(releases.iter()).flatten().for_each(apply);
+}
+
+/// Carry out `d` on the scheduler and audit the outcome.
+fn apply(d: &Decision) {
+    let Ok((audit_hook, restart_hook)) = with_state(|st| (st.audit_hook, st.restart_hook)) else { return };
+    let done = match (d.action, d.release) {
+        (Action::Suspend, true) => scheduler::resume_task(d.task).is_ok(),
+        (_, true) | (Action::Log, false) => true,
+        (Action::Suspend, false) => scheduler::suspend_task(d.task).is_ok(),
+        (Action::Sandbox(bits), false) => scheduler::drop_capabilities(d.task, bits).is_ok(),
+        (Action::Restart, false) => restart(d.task, restart_hook),
+    };
+    let event = match (done, d.release) {
+        (false, _) => AuditEvent::Refused,
+        (true, false) => AuditEvent::Enforced,
+        (true, true) => AuditEvent::Released,
+    };
+    let record = AuditRecord { tick: time::ticks(), task: d.task, anomaly: d.anomaly, action: d.action, event };
+    let _ = with_state(|st| {
+        st.audit.push(record);
+        if !done {
+            st.enforcer.rearm(d.task, d.anomaly);
+        }
+    });
+    if let Some(hook) = audit_hook {
+        hook(&record);
+    }
+}
+
+/// Kill `task` and start it again through `hook`, moving its CPU budget
+/// to the new task. `false` if the scheduler refused the kill.
+fn restart(task: u32, hook: Option<fn(u32) -> Option<u32>>) -> bool {
+    if scheduler::kill_task(task).is_none() {
+        return false;
+    }
+    crate::ipc_queue::release_task(task);
+    let new_id = hook.and_then(|h| h(task));
+    let _ = with_state(|st| st.enforcer.replace(task, new_id));
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    fn stats(task: u32, run_ticks: u64) -> [Option<TaskStats>; MAX_TASKS] {
+        let mut s = [None; MAX_TASKS];
+        s[0] = Some(TaskStats { id: task, run_ticks, switches: 0, utilization_permille: 0 });
+        s
+    }
+
+    fn lifted(e: &mut Enforcer) -> Vec<(u32, Action)> {
+        e.decay().iter().flatten().map(|d| (d.task, d.action)).collect()
+    }
+
+    #[test]
+    fn actions_fire_once_and_lift_with_hysteresis() {
+        let mut e = Enforcer::new();
+        assert_eq!(e.set_policy(Anomaly::CpuOverrun, Some(Policy { action: Action::Suspend, trigger: 2, release: 2 })), Err(MonitorError::BadPolicy));
+        e.set_policy(Anomaly::CpuOverrun, Some(Policy { action: Action::Suspend, trigger: 3, release: 1 })).unwrap();
+        e.set_cpu_budget(7, Some(500)).unwrap();
+
+        // 60 % of a 100-tick window, three periods in a row
+        let mut run = 0;
+        let mut fired = Vec::new();
+        for _ in 0..3 {
+            run += 60;
+            fired.extend(e.check_cpu(&stats(7, run), 100).into_iter().flatten());
+            assert!(lifted(&mut e).is_empty());
+        }
+        assert_eq!(fired, [Decision { task: 7, anomaly: Anomaly::CpuOverrun, action: Action::Suspend, release: false }]);
+
+        // Still over budget: no second suspension
+        run += 60;
+        assert!(e.check_cpu(&stats(7, run), 100).iter().all(Option::is_none));
+        e.decay();
+
+        // Suspended, so idle: 4 -> 3 -> 2 -> 1 = release
+        assert!(e.check_cpu(&stats(7, run), 100).iter().all(Option::is_none));
+        assert!(lifted(&mut e).is_empty());
+        assert!(lifted(&mut e).is_empty());
+        assert_eq!(lifted(&mut e), [(7, Action::Suspend)]);
+
+        // Gone from the scheduler: track dropped
+        e.check_cpu(&stats(8, 0), 100);
+        assert!(e.tracks.iter().all(Option::is_none));
+    }
+
+    #[test]
+    fn refused_actions_can_fire_again_and_suspensions_overlap() {
+        let mut e = Enforcer::new();
+        let sandbox = Policy { action: Action::Sandbox(0b110), trigger: 1, release: 0 };
+        let suspend = Policy { action: Action::Suspend, trigger: 1, release: 0 };
+        e.set_policy(Anomaly::SyscallDenied, Some(sandbox)).unwrap();
+        e.set_policy(Anomaly::DeadlineMiss, Some(suspend)).unwrap();
+        e.set_policy(Anomaly::HeartbeatMiss, Some(Policy { trigger: 2, ..suspend })).unwrap();
+
+        assert!(e.report(1, Anomaly::SyscallDenied).unwrap().is_some());
+        assert_eq!(e.report(1, Anomaly::SyscallDenied), Ok(None));
+        e.rearm(1, Anomaly::SyscallDenied);
+        assert!(e.report(1, Anomaly::SyscallDenied).unwrap().is_some(), "re-armed");
+
+        assert!(e.report(2, Anomaly::DeadlineMiss).unwrap().is_some());
+        e.report(2, Anomaly::HeartbeatMiss).unwrap();
+        assert!(e.report(2, Anomaly::HeartbeatMiss).unwrap().is_some());
+        e.report(2, Anomaly::HeartbeatMiss).unwrap();
+        // The deadline miss lifts after one quiet period, but the
+        // heartbeat misses still hold task 2 suspended
+        assert!(lifted(&mut e).is_empty());
+        assert!(lifted(&mut e).is_empty());
+        assert!(lifted(&mut e).is_empty());
+        assert_eq!(lifted(&mut e), [(1, Action::Sandbox(0b110)), (2, Action::Suspend)]);
+    }
+
+    #[test]
+    fn audit_log_keeps_the_latest_records_in_order() {
+        let mut log = AuditLog::new();
+        let rec = |tick| AuditRecord { tick, task: 1, anomaly: Anomaly::Detector, action: Action::Log, event: AuditEvent::Enforced };
+        log.push(rec(0));
+        assert_eq!(log.records()[0].map(|r| r.tick), Some(0));
+        assert!(log.records()[1].is_none());
+        for tick in 1..=AUDIT_LEN as u32 + 2 {
+            log.push(rec(tick));
+        }
+        let ticks: Vec<u32> = log.records().iter().flatten().map(|r| r.tick).collect();
+        assert_eq!(ticks, (3..=AUDIT_LEN as u32 + 2).collect::<Vec<_>>());
+    }
+}
@@ -7,65 +7,919 @@
 //! URL    : https://m-a-h-b-u-b.github.io
 //! GitHub : https://github.com/m-a-h-b-u-b/SecureIoTOS
 //!
-//! This module implements the SecureIoTOS task scheduler.
-//! Currently, a round-robin scheduling policy is used. The scheduler
-//! selects the next runnable task and performs a context switch.
+//! This module implements the SecureIoTOS task scheduler. The scheduler
+//! selects the next runnable task; `context::PendSV` performs the switch.
+//!
+//! Policies (`set_policy()`):
+//! - `Policy::RoundRobin` (default): every task in turn, one tick each.
+//! - `Policy::Edf`: periodic tasks with `edf::RtParams` (see
+//!   `set_rt_params()`) run earliest-deadline-first whenever one has a
+//!   released job; other tasks share the remaining time round-robin.
+//!   Admission control rejects parameters that would make the periodic
+//!   set infeasible, and every missed deadline is passed to the hook
+//!   installed with `set_deadline_miss_hook()`.
+//!
+//! CPU accounting: every SysTick charges one tick to the task it
+//! interrupted (`account_tick()`), and every switch counts towards the
+//! incoming task. `stats()` turns this into per-task utilization. The
+//! sampling is statistical: a task that always yields just before the
+//! tick is under-counted, so read short windows with care.
+//!
+//! Capabilities: each task carries the `syscall::caps` bits it was created
+//! with. A task holding `caps::DELEGATE` can pass on bits it holds itself
+//! or take bits away from other tasks (`delegate_capabilities()`,
+//! `revoke_capabilities()`); nothing else changes them. `DELEGATE` is only
+//! ever given to privileged tasks, so user tasks cannot widen their own or
+//! each other's rights. The kernel itself may take bits away from a
+//! misbehaving task (`drop_capabilities()`, used by `runtime_monitor`).
+//!
+//! Stack overflow: the stack of every task switched away from is checked
+//! (`stack_guard`) and the incoming task's hardware stack limit armed. A
+//! task whose stack overflowed is removed under every `OverflowPolicy`
+//! short of `Reset`; its saved context is never restored.
+//!
+//! Memory accounting: each task has a `TaskMemory` record (heap quota and
+//! usage, shared-memory grants), kept up to date by whoever hands it
+//! memory (`set_heap_quota()`, `charge_heap()`, `grant_shm()`, ...).
+//! Together with the stack watermark it is what `GetMemInfo` reports
+//! (`task_memory()`).
+//!
+//! Suspension: `suspend_task()` keeps a task in the table but out of the
+//! rotation until `resume_task()`. The last runnable task cannot be
+//! suspended, so there is always something to switch to.
 //!
 //! NOTE: This implementation assumes an ARM Cortex-M architecture.
 //! Context switches should normally be triggered from the PendSV
 //! exception, not directly from application code.
 
-use crate::context::{context_switch, Task};
-use crate::init::get_tasks;
+// Core kernel path: must not panic (see `tools/no-panic-check`).
+#![cfg_attr(not(test), deny(
+    clippy::panic,
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::indexing_slicing,
+    clippy::unreachable,
+    clippy::todo,
+    clippy::unimplemented
+))]
+
+use crate::context::Task;
+use crate::edf::{self, AdmitError, DeadlineMiss, RtJob, RtParams};
+use crate::stack_guard::{self, OverflowPolicy, StackBounds, StackFault};
+use crate::syscall::caps;
+use crate::task_table::{TaskTable, TaskTableError};
 use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
 
-/// Global scheduler state (static task table + current index).
-///
-/// In a real kernel this might be replaced with a proper task table
-/// in kernel memory with ready/wait queues.
-thread_local! {
-    static TASKS: RefCell<Vec<Task>> = RefCell::new(get_tasks());
-    static CURRENT_INDEX: RefCell<usize> = RefCell::new(0);
+/// Number of tasks the kernel can hold.
+pub const MAX_TASKS: usize = 8;
+
+/// How the next task is chosen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// All tasks in turn
+    RoundRobin,
+    /// Earliest deadline first for periodic tasks, round-robin for the rest
+    Edf,
+}
+
+/// Global scheduler state: fixed task table + slot of the running task,
+/// CPU accounting and the periodic job of each slot.
+struct SchedState {
+    tasks: TaskTable<MAX_TASKS>,
+    current: usize,
+    run_ticks: [u64; MAX_TASKS],
+    switches: [u32; MAX_TASKS],
+    /// Ticks accounted since the last `reset_stats()`, idle ones included
+    total_ticks: u64,
+    policy: Policy,
+    rt: [Option<RtJob>; MAX_TASKS],
+    miss_hook: Option<fn(&DeadlineMiss)>,
+    suspended: [bool; MAX_TASKS],
+    memory: [TaskMemory; MAX_TASKS],
+    /// A task has been switched in; before that the code `PendSV`
+    /// interrupts is the boot code, whose context is not kept
+    started: bool,
+}
+
+// SAFETY: the raw stack pointers inside `Task` are only dereferenced by the
+// context-switch code, which runs with interrupts disabled on a single core.
+unsafe impl Send for SchedState {}
+
+static SCHED: Mutex<RefCell<SchedState>> = Mutex::new(RefCell::new(SchedState::new()));
+
+/// CPU usage of one task, as reported by `stats()`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TaskStats {
+    /// Task id
+    pub id: u32,
+    /// Ticks during which the task was running
+    pub run_ticks: u64,
+    /// Times the task was switched in
+    pub switches: u32,
+    /// Share of all ticks since the last `reset_stats()`, in 0.1 %
+    pub utilization_permille: u16,
+}
+
+/// Memory accounted to one task (see the module notes).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TaskMemory {
+    /// Heap bytes the task may allocate (0 = no quota)
+    pub heap_quota: u32,
+    /// Heap bytes currently charged to the task
+    pub heap_used: u32,
+    /// Number of shared-memory regions granted to the task
+    pub shm_grants: u32,
+    /// Total size of those regions in bytes
+    pub shm_bytes: u32,
+}
+
+const NO_MEMORY: TaskMemory = TaskMemory { heap_quota: 0, heap_used: 0, shm_grants: 0, shm_bytes: 0 };
+
+impl SchedState {
+    const fn new() -> Self {
+        Self {
+            tasks: TaskTable::new(),
+            current: 0,
+            run_ticks: [0; MAX_TASKS],
+            switches: [0; MAX_TASKS],
+            total_ticks: 0,
+            policy: Policy::RoundRobin,
+            rt: [None; MAX_TASKS],
+            miss_hook: None,
+            suspended: [false; MAX_TASKS],
+            memory: [NO_MEMORY; MAX_TASKS],
+            started: false,
+        }
+    }
+
+    fn insert(&mut self, task: Task) -> Result<u32, SchedError> {
+        let id = self.tasks.insert(task)?;
+        if let Some(slot) = self.tasks.slot_of(id) {
+            self.reset_slot(slot);
+        }
+        Ok(id)
+    }
+
+    fn reset_slot(&mut self, slot: usize) {
+        if let Some(t) = self.run_ticks.get_mut(slot) {
+            *t = 0;
+        }
+        if let Some(n) = self.switches.get_mut(slot) {
+            *n = 0;
+        }
+        if let Some(rt) = self.rt.get_mut(slot) {
+            *rt = None;
+        }
+        if let Some(s) = self.suspended.get_mut(slot) {
+            *s = false;
+        }
+        if let Some(m) = self.memory.get_mut(slot) {
+            *m = TaskMemory::default();
+        }
+    }
+
+    /// Whether slot `slot` holds a task that may get the CPU.
+    fn runnable(&self, slot: usize) -> bool {
+        self.tasks.at(slot).is_some() && !self.suspended.get(slot).copied().unwrap_or(false)
+    }
+
+    /// First runnable slot after `slot` in round-robin order, wrapping
+    /// around; `slot` itself is returned last.
+    fn next_runnable(&self, slot: usize) -> Option<usize> {
+        (1..=MAX_TASKS).map(|step| (slot + step) % MAX_TASKS).find(|&s| self.runnable(s))
+    }
+
+    /// Record `saved_sp` for the running task, pick the next one by policy
+    /// and return the incoming task's stack pointer and privilege.
+    fn switch(&mut self, saved_sp: *mut u32) -> Result<(*mut u32, u8), SchedError> {
+        if self.tasks.is_empty() {
+            return Err(SchedError::NoTasks);
+        }
+
+        // Nothing to save on the very first switch (boot code, see
+        // `context::start_first_task`) or if the running task was killed
+        let started = core::mem::replace(&mut self.started, true);
+        let was_running = match self.tasks.at_mut(self.current).filter(|_| started) {
+            Some(cur) => {
+                cur.stack_pointer = saved_sp;
+                true
+            }
+            None => false,
+        };
+        let was_running = was_running && self.check_stack(stack_guard::overflow_policy()).is_none();
+
+        let next = self.pick_next().ok_or(SchedError::NoTasks)?;
+        let next_task = self.tasks.at(next).ok_or(SchedError::BadIndex)?;
+        stack_guard::enter(next_task);
+        let switch_to = (next_task.stack_pointer, next_task.privilege);
+        if next != self.current || !was_running {
+            if let Some(n) = self.switches.get_mut(next) {
+                *n = n.saturating_add(1);
+            }
+        }
+        let current = core::ptr::addr_of!(self.current) as usize;
+        hal::watch::expected_write(current, || self.current = next);
+        Ok(switch_to)
+    }
+
+    /// Check the stack of the task being switched away from. On a fault
+    /// the task is reported (`Report`) and removed, or the device reset
+    /// (`Reset`, or if no other task could run); the fault is returned.
+    fn check_stack(&mut self, policy: OverflowPolicy) -> Option<(StackFault, u32)> {
+        let task = self.tasks.at(self.current)?;
+        let fault = stack_guard::check(task).err()?;
+        let id = task.id;
+        if policy == OverflowPolicy::Reset || self.tasks.len() < 2 {
+            stack_guard::reset();
+        }
+        if policy == OverflowPolicy::Report {
+            crate::kfail!("task stack overflow", id);
+        }
+        self.tasks.remove(id);
+        Some((fault, id))
+    }
+
+    /// Slot to run next under the current policy.
+    fn pick_next(&self) -> Option<usize> {
+        if self.policy == Policy::Edf {
+            if let Some(slot) = self.earliest_deadline() {
+                return Some(slot);
+            }
+            // Round-robin over the non-periodic tasks; if there are none,
+            // stay put until a job is released.
+            let mut slot = self.current;
+            for _ in 0..MAX_TASKS {
+                slot = self.next_runnable(slot)?;
+                if self.job(slot).is_none() {
+                    return Some(slot);
+                }
+            }
+            return Some(self.current).filter(|&c| self.runnable(c)).or_else(|| self.next_runnable(self.current));
+        }
+        // Round-robin: move to next task (itself if it is the only one)
+        self.next_runnable(self.current)
+    }
+
+    /// Periodic job of the task in `slot`, if it has one.
+    fn job(&self, slot: usize) -> Option<&RtJob> {
+        self.tasks.at(slot)?;
+        self.rt.get(slot)?.as_ref()
+    }
+
+    /// Slot of the released job with the earliest deadline. Ties keep the
+    /// running task, so equal deadlines do not cause extra switches.
+    fn earliest_deadline(&self) -> Option<usize> {
+        let mut best: Option<(usize, &RtJob)> = None;
+        for slot in 0..MAX_TASKS {
+            let Some(job) = self.job(slot).filter(|j| j.runnable() && self.runnable(slot)) else { continue };
+            let better = match best {
+                None => true,
+                Some((_, bj)) => job.earlier_than(bj) || (slot == self.current && !bj.earlier_than(job)),
+            };
+            if better {
+                best = Some((slot, job));
+            }
+        }
+        best.map(|(slot, _)| slot)
+    }
+
+    /// Declare (or with `None`, drop) the timing contract of task `id`,
+    /// subject to admission control. Returns the new periodic load.
+    fn set_rt_params(&mut self, id: u32, params: Option<RtParams>, now: u32) -> Result<u32, SchedError> {
+        let slot = self.tasks.slot_of(id).ok_or(SchedError::BadIndex)?;
+        let Some(params) = params else {
+            if let Some(rt) = self.rt.get_mut(slot) {
+                *rt = None;
+            }
+            return Ok(self.rt_load());
+        };
+        let others = (0..MAX_TASKS).filter(|&s| s != slot).filter_map(|s| self.job(s)).map(|j| &j.params);
+        let load = edf::admit(others, &params)?;
+        let rt = self.rt.get_mut(slot).ok_or(SchedError::BadIndex)?;
+        *rt = Some(RtJob::new(params, now));
+        Ok(load)
+    }
+
+    /// Total density of the admitted periodic tasks, in ppm.
+    fn rt_load(&self) -> u32 {
+        (0..MAX_TASKS).filter_map(|s| self.job(s)).map(|j| j.params.density_ppm()).fold(0, u32::saturating_add)
+    }
+
+    /// Complete the running task's job. Returns its next release tick.
+    fn complete_current(&mut self) -> Result<u32, SchedError> {
+        self.tasks.at(self.current).ok_or(SchedError::BadIndex)?;
+        let job = self.rt.get_mut(self.current).and_then(Option::as_mut).ok_or(SchedError::NotPeriodic)?;
+        Ok(job.complete())
+    }
+
+    /// Tick by which the scheduler needs to run again: the earliest
+    /// periodic release, or `now` if a released job is waiting.
+    fn next_release(&self, now: u32) -> Option<u32> {
+        let relative = |job: &RtJob| if job.runnable() { 0 } else { job.next_release().wrapping_sub(now) as i32 };
+        (0..MAX_TASKS)
+            .filter_map(|s| self.job(s))
+            .map(|job| relative(job).max(0))
+            .min()
+            .map(|ahead| now.wrapping_add(ahead as u32))
+    }
+
+    /// Advance every periodic job to tick `now`, charging the running one.
+    /// Returns the deadlines that passed just now.
+    fn rt_tick(&mut self, now: u32) -> [Option<DeadlineMiss>; MAX_TASKS] {
+        let mut misses = [None; MAX_TASKS];
+        for (slot, (rt, miss)) in self.rt.iter_mut().zip(misses.iter_mut()).enumerate() {
+            let (Some(task), Some(job)) = (self.tasks.at(slot), rt.as_mut()) else { continue };
+            *miss = job.tick(task.id, now, slot == self.current);
+        }
+        misses
+    }
+
+    fn account_tick(&mut self) {
+        self.total_ticks += 1;
+        if self.tasks.at(self.current).is_some() {
+            if let Some(t) = self.run_ticks.get_mut(self.current) {
+                *t += 1;
+            }
+        }
+    }
+
+    /// Remove the running task without switching away: the next switch
+    /// finds its slot empty and does not save its context. Refused for
+    /// the last task, which would leave nothing to switch to.
+    fn kill_current(&mut self) -> Option<Task> {
+        if self.tasks.len() < 2 {
+            return None;
+        }
+        let id = self.tasks.at(self.current)?.id;
+        self.tasks.remove(id)
+    }
+
+    /// Remove task `id`, running or not (see `kill_current()`). Also
+    /// returns whether it was the running task.
+    fn kill(&mut self, id: u32) -> Option<(Task, bool)> {
+        if self.tasks.slot_of(id)? == self.current {
+            return self.kill_current().map(|t| (t, true));
+        }
+        self.tasks.remove(id).map(|t| (t, false))
+    }
+
+    /// Take task `id` out of the rotation. Refused with `LastTask` if no
+    /// other task could run. Also returns whether it was the running task.
+    fn suspend(&mut self, id: u32) -> Result<bool, SchedError> {
+        let slot = self.tasks.slot_of(id).ok_or(SchedError::BadIndex)?;
+        if (0..MAX_TASKS).all(|s| s == slot || !self.runnable(s)) {
+            return Err(SchedError::LastTask);
+        }
+        if let Some(s) = self.suspended.get_mut(slot) {
+            *s = true;
+        }
+        Ok(slot == self.current)
+    }
+
+    /// Put task `id` back into the rotation.
+    fn resume(&mut self, id: u32) -> Result<(), SchedError> {
+        let slot = self.tasks.slot_of(id).ok_or(SchedError::BadIndex)?;
+        if let Some(s) = self.suspended.get_mut(slot) {
+            *s = false;
+        }
+        Ok(())
+    }
+
+    /// Give task `to` the bits `caps` on behalf of task `from`, which must
+    /// hold `DELEGATE` and every bit it passes on. Returns `to`'s new set.
+    fn delegate(&mut self, from: u32, to: u32, bits: u32) -> Result<u32, SchedError> {
+        let held = self.tasks.get(from).ok_or(SchedError::BadIndex)?.capabilities;
+        if held & caps::DELEGATE == 0 || held & bits != bits {
+            return Err(SchedError::NotPermitted);
+        }
+        let target = self.tasks.get_mut(to).ok_or(SchedError::BadIndex)?;
+        if bits & caps::DELEGATE != 0 && target.privilege != 0 {
+            return Err(SchedError::NotPermitted);
+        }
+        target.capabilities |= bits;
+        Ok(target.capabilities)
+    }
+
+    /// Take the bits `caps` away from task `to` on behalf of task `from`,
+    /// which must hold `DELEGATE`. Returns `to`'s new set.
+    fn revoke(&mut self, from: u32, to: u32, bits: u32) -> Result<u32, SchedError> {
+        let held = self.tasks.get(from).ok_or(SchedError::BadIndex)?.capabilities;
+        if held & caps::DELEGATE == 0 {
+            return Err(SchedError::NotPermitted);
+        }
+        let target = self.tasks.get_mut(to).ok_or(SchedError::BadIndex)?;
+        target.capabilities &= !bits;
+        Ok(target.capabilities)
+    }
+
+    fn memory_mut(&mut self, id: u32) -> Result<&mut TaskMemory, SchedError> {
+        let slot = self.tasks.slot_of(id).ok_or(SchedError::BadIndex)?;
+        self.memory.get_mut(slot).ok_or(SchedError::BadIndex)
+    }
+
+    /// Charge `bytes` of heap to task `id`, within its quota.
+    fn charge_heap(&mut self, id: u32, bytes: u32) -> Result<u32, SchedError> {
+        let mem = self.memory_mut(id)?;
+        let used = mem.heap_used.checked_add(bytes).ok_or(SchedError::QuotaExceeded)?;
+        if mem.heap_quota != 0 && used > mem.heap_quota {
+            return Err(SchedError::QuotaExceeded);
+        }
+        mem.heap_used = used;
+        Ok(used)
+    }
+
+    fn task_memory(&self, id: u32) -> Option<(Option<StackBounds>, TaskMemory)> {
+        let slot = self.tasks.slot_of(id)?;
+        let task = self.tasks.at(slot)?;
+        Some((task.stack, self.memory.get(slot).copied().unwrap_or_default()))
+    }
+
+    fn stats(&self) -> [Option<TaskStats>; MAX_TASKS] {
+        let total = self.total_ticks.max(1);
+        core::array::from_fn(|slot| {
+            let task = self.tasks.at(slot)?;
+            let run_ticks = self.run_ticks.get(slot).copied().unwrap_or(0);
+            Some(TaskStats {
+                id: task.id,
+                run_ticks,
+                switches: self.switches.get(slot).copied().unwrap_or(0),
+                utilization_permille: (run_ticks.min(total) * 1000 / total) as u16,
+            })
+        })
+    }
+}
+
+/// Register a task; its `id` is assigned by the table and returned.
+/// ISR-safe.
+pub fn add_task(task: Task) -> Result<u32, SchedError> {
+    with_state(|st| st.insert(task))?
+}
+
+/// Remove task `id`. The running task cannot be removed. ISR-safe.
+pub fn remove_task(id: u32) -> Result<Task, SchedError> {
+    with_state(|st| {
+        if st.tasks.slot_of(id) == Some(st.current) {
+            return Err(SchedError::Busy);
+        }
+        st.tasks.remove(id).ok_or(SchedError::BadIndex)
+    })?
+}
+
+/// Id of the running task. ISR-safe.
+pub fn current_task_id() -> Option<u32> {
+    with_state(|st| st.tasks.at(st.current).map(|t| t.id)).ok().flatten()
+}
+
+/// Id and capabilities of the running task. ISR-safe.
+pub fn current_capabilities() -> Option<(u32, u32)> {
+    with_state(|st| st.tasks.at(st.current).map(|t| (t.id, t.capabilities))).ok().flatten()
+}
+
+/// Grant `bits` to task `to` on behalf of task `from` (see the module
+/// notes). Returns `to`'s new capability set. ISR-safe.
+pub fn delegate_capabilities(from: u32, to: u32, bits: u32) -> Result<u32, SchedError> {
+    with_state(|st| st.delegate(from, to, bits))?
+}
+
+/// Revoke `bits` from task `to` on behalf of task `from`. Returns `to`'s
+/// new capability set. ISR-safe.
+pub fn revoke_capabilities(from: u32, to: u32, bits: u32) -> Result<u32, SchedError> {
+    with_state(|st| st.revoke(from, to, bits))?
+}
+
+/// Take `bits` away from task `id` on the kernel's own authority (no
+/// `DELEGATE` holder involved). Returns `id`'s new capability set.
+/// ISR-safe.
+pub fn drop_capabilities(id: u32, bits: u32) -> Result<u32, SchedError> {
+    with_state(|st| {
+        let task = st.tasks.get_mut(id).ok_or(SchedError::BadIndex)?;
+        task.capabilities &= !bits;
+        Ok(task.capabilities)
+    })?
+}
+
+/// Stop giving task `id` the CPU until `resume_task()`, switching away
+/// from it if it is running. ISR-safe.
+pub fn suspend_task(id: u32) -> Result<(), SchedError> {
+    if with_state(|st| st.suspend(id))?? {
+        trigger_pendsv();
+    }
+    Ok(())
+}
+
+/// Let suspended task `id` run again. ISR-safe.
+pub fn resume_task(id: u32) -> Result<(), SchedError> {
+    with_state(|st| st.resume(id))?
+}
+
+/// Remove the running task (from a fault handler) and request a switch to
+/// the next one. Returns the removed task, or `None` if there is no other
+/// task to run. ISR-safe.
+pub fn kill_current() -> Option<Task> {
+    let task = with_state(SchedState::kill_current).ok().flatten()?;
+    trigger_pendsv();
+    Some(task)
+}
+
+/// Remove task `id` even if it is the one running (e.g. a hung task found
+/// by `watchdog`), switching away from it if so. Returns the removed
+/// task, or `None` if there is no such task or nothing else to run.
+/// ISR-safe.
+pub fn kill_task(id: u32) -> Option<Task> {
+    let (task, was_running) = with_state(|st| st.kill(id)).ok().flatten()?;
+    if was_running {
+        trigger_pendsv();
+    }
+    Some(task)
+}
+
+/// Run `f` on the scheduler state, `Busy` if it is already borrowed.
+fn with_state<R>(f: impl FnOnce(&mut SchedState) -> R) -> Result<R, SchedError> {
+    interrupt::free(|cs| {
+        let mut st = SCHED.borrow(cs).try_borrow_mut().map_err(|_| SchedError::Busy)?;
+        Ok(f(&mut st))
+    })
+}
+
+/// Report writes to the running-task index from anywhere but the context
+/// switch (see `hal::watch`). Returns `false` if no watchpoint could be
+/// armed.
+pub fn watch_state() -> bool {
+    with_state(|st| hal::watch::watch_var("sched_current", core::ptr::addr_of!(st.current))).ok().flatten().is_some()
+}
+
+/// Charge the current tick to the running task and advance the periodic
+/// jobs, reporting missed deadlines. Called from the SysTick handler only.
+pub fn account_tick() {
+    let now = crate::time::ticks();
+    let Ok((misses, hook)) = with_state(|st| {
+        st.account_tick();
+        (st.rt_tick(now), st.miss_hook)
+    }) else {
+        return;
+    };
+    // Outside the state borrow, so the hook may query the scheduler.
+    if let Some(hook) = hook {
+        misses.iter().flatten().for_each(hook);
+    }
+}
+
+/// Count `n` ticks slept through by tickless idle. They are charged to
+/// no task; periodic jobs catch up at the next `account_tick()`.
+pub fn account_idle(n: u32) {
+    let _ = with_state(|st| st.total_ticks += u64::from(n));
+}
+
+/// Earliest tick at which a periodic job is due (see `tickless`).
+/// ISR-safe.
+pub fn next_release() -> Option<u32> {
+    let now = crate::time::ticks();
+    with_state(|st| st.next_release(now)).ok().flatten()
+}
+
+/// Select the scheduling policy. ISR-safe.
+pub fn set_policy(policy: Policy) -> Result<(), SchedError> {
+    with_state(|st| st.policy = policy)
+}
+
+/// Declare task `id` periodic with `params` (ticks), or make it a plain
+/// task again with `None`. Refused with `Infeasible` if the periodic set
+/// would no longer be schedulable. The first job is released at once.
+/// Returns the total periodic load in parts per million. ISR-safe.
+pub fn set_rt_params(id: u32, params: Option<RtParams>) -> Result<u32, SchedError> {
+    let now = crate::time::ticks();
+    with_state(|st| st.set_rt_params(id, params, now))?
+}
+
+/// Install the deadline-miss hook (monitoring, telemetry counter, ...).
+/// Called from the SysTick handler: keep it short.
+pub fn set_deadline_miss_hook(hook: fn(&DeadlineMiss)) -> Result<(), SchedError> {
+    with_state(|st| st.miss_hook = Some(hook))
+}
+
+/// End the calling periodic task's current job and block until the next
+/// one is released. Task-only.
+pub fn wait_next_period() -> Result<(), SchedError> {
+    let release = with_state(SchedState::complete_current)??;
+    schedule();
+    crate::time::sleep_until(release);
+    Ok(())
+}
+
+/// CPU usage of every task, in task-table slot order. ISR-safe.
+pub fn stats() -> Result<[Option<TaskStats>; MAX_TASKS], SchedError> {
+    with_state(|st| st.stats())
+}
+
+/// Limit task `id`'s heap to `bytes` (0 = no quota). ISR-safe.
+pub fn set_heap_quota(id: u32, bytes: u32) -> Result<(), SchedError> {
+    with_state(|st| st.memory_mut(id).map(|m| m.heap_quota = bytes))?
+}
+
+/// Account `bytes` of heap allocated on behalf of task `id`; fails with
+/// `QuotaExceeded` (and charges nothing) past its quota. Returns the
+/// task's new heap usage. ISR-safe.
+pub fn charge_heap(id: u32, bytes: u32) -> Result<u32, SchedError> {
+    with_state(|st| st.charge_heap(id, bytes))?
+}
+
+/// Account `bytes` of task `id`'s heap as freed. ISR-safe.
+pub fn release_heap(id: u32, bytes: u32) -> Result<(), SchedError> {
+    with_state(|st| st.memory_mut(id).map(|m| m.heap_used = m.heap_used.saturating_sub(bytes)))?
+}
+
+/// Record a shared-memory region of `bytes` granted to task `id`. ISR-safe.
+pub fn grant_shm(id: u32, bytes: u32) -> Result<(), SchedError> {
+    with_state(|st| {
+        st.memory_mut(id).map(|m| {
+            m.shm_grants = m.shm_grants.saturating_add(1);
+            m.shm_bytes = m.shm_bytes.saturating_add(bytes);
+        })
+    })?
+}
+
+/// Record that a shared-memory region of `bytes` was taken back from task
+/// `id`. ISR-safe.
+pub fn revoke_shm(id: u32, bytes: u32) -> Result<(), SchedError> {
+    with_state(|st| {
+        st.memory_mut(id).map(|m| {
+            m.shm_grants = m.shm_grants.saturating_sub(1);
+            m.shm_bytes = m.shm_bytes.saturating_sub(bytes);
+        })
+    })?
+}
+
+/// Stack bounds (if known) and memory record of task `id`. ISR-safe.
+pub fn task_memory(id: u32) -> Option<(Option<StackBounds>, TaskMemory)> {
+    with_state(|st| st.task_memory(id)).ok().flatten()
+}
+
+/// Start a new measurement window: clear all counters. ISR-safe.
+pub fn reset_stats() -> Result<(), SchedError> {
+    with_state(|st| {
+        st.run_ticks = [0; MAX_TASKS];
+        st.switches = [0; MAX_TASKS];
+        st.total_ticks = 0;
+    })
 }
 
 /// Trigger the scheduler to pick the next task.
 ///
-/// Normally this would set the PendSV interrupt pending bit so the
-/// context switch happens at exception return.
+/// Sets the PendSV pending bit so the context switch happens at exception
+/// return. ISR-safe.
 pub fn schedule() {
     trigger_pendsv();
 }
 
-/// Selects the next task and performs a context switch.
-///
-/// # Safety
-/// Must only be called in kernel/interrupt context with interrupts disabled.
-pub unsafe fn do_context_switch() {
-    TASKS.with(|tasks_ref| {
-        CURRENT_INDEX.with(|idx_ref| {
-            let mut tasks = tasks_ref.borrow_mut();
-            let mut current_index = idx_ref.borrow_mut();
-
-            let current = &mut tasks[*current_index];
+/// Why a context switch could not be performed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedError {
+    /// The task table is empty
+    NoTasks,
+    /// The scheduler state is already borrowed (re-entrant call), or the
+    /// task is running
+    Busy,
+    /// The current index does not refer to a task
+    BadIndex,
+    /// All `MAX_TASKS` slots are in use
+    TableFull,
+    /// The caller lacks the capability for the change
+    NotPermitted,
+    /// The task has no periodic timing contract
+    NotPeriodic,
+    /// Timing parameters are not `0 < wcet <= deadline <= period`
+    InvalidParams,
+    /// Admitting the task would overload the periodic set
+    Infeasible,
+    /// The change would leave no task to run
+    LastTask,
+    /// The task's heap quota would be exceeded
+    QuotaExceeded,
+}
 
-            // Round-robin: move to next task
-            *current_index = (*current_index + 1) % tasks.len();
-            let next = &tasks[*current_index];
+impl From<AdmitError> for SchedError {
+    fn from(e: AdmitError) -> Self {
+        match e {
+            AdmitError::InvalidParams => SchedError::InvalidParams,
+            AdmitError::Infeasible => SchedError::Infeasible,
+        }
+    }
+}
 
-            context_switch(current, next);
-        });
-    });
+impl From<TaskTableError> for SchedError {
+    fn from(e: TaskTableError) -> Self {
+        match e {
+            TaskTableError::Full => SchedError::TableFull,
+        }
+    }
 }
 
-/// Triggers PendSV exception to request a context switch.
+/// Selects the next task for the `PendSV` handler.
+///
+/// Records `saved_sp` (the outgoing task's context, see `context`) as
+/// the current task's stack pointer, picks the next task by policy and
+/// returns the incoming task's stack pointer and privilege.
 ///
 /// # Safety
-/// This writes to SCB->ICSR register directly.
+/// Must only be called in kernel/interrupt context with interrupts disabled.
+pub unsafe fn do_context_switch(saved_sp: *mut u32) -> Result<(*mut u32, u8), SchedError> {
+    with_state(|st| st.switch(saved_sp))?
+}
+
+/// Triggers PendSV exception to request a context switch.
 fn trigger_pendsv() {
-    const ICSR: *mut u32 = 0xE000_ED04 as *mut u32;
-    const PENDSVSET: u32 = 1 << 28;
+    crate::regs::scb::set_pendsv();
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+
+    fn task() -> Task {
+        Task { id: 0, privilege: 1, stack_pointer: core::ptr::null_mut(), capabilities: 0, stack: None }
+    }
+
+    #[test]
+    fn memory_is_accounted_per_task_and_cleared_with_it() {
+        let mut st = SchedState::new();
+        let app = st.insert(task()).unwrap();
+        st.memory_mut(app).unwrap().heap_quota = 100;
+        assert_eq!(st.charge_heap(app, 60), Ok(60));
+        assert_eq!(st.charge_heap(app, 41), Err(SchedError::QuotaExceeded));
+        assert_eq!(st.charge_heap(app, 40), Ok(100));
+        assert_eq!(st.charge_heap(0xDEAD, 1), Err(SchedError::BadIndex));
+        assert_eq!(st.task_memory(app).unwrap().1.heap_used, 100);
+
+        st.tasks.remove(app);
+        let next = st.insert(task()).unwrap();
+        assert_eq!(st.task_memory(next), Some((None, TaskMemory::default())), "slot reused clean");
+    }
+
+    #[test]
+    fn capabilities_are_delegated_without_escalation() {
+        let mut st = SchedState::new();
+        let sup = st.insert(Task { privilege: 0, capabilities: caps::DELEGATE | caps::SYS_TIME, ..task() }).unwrap();
+        let app = st.insert(Task { capabilities: caps::SEND_MESSAGE, ..task() }).unwrap();
+
+        assert_eq!(st.delegate(sup, app, caps::SYS_TIME), Ok(caps::SYS_TIME | caps::SEND_MESSAGE));
+        assert_eq!(st.delegate(sup, app, caps::MEM_INFO), Err(SchedError::NotPermitted), "not held by granter");
+        assert_eq!(st.delegate(sup, app, caps::DELEGATE), Err(SchedError::NotPermitted), "user task");
+        assert_eq!(st.delegate(app, app, caps::SYS_TIME), Err(SchedError::NotPermitted), "no DELEGATE");
+        assert_eq!(st.revoke(app, sup, caps::SYS_TIME), Err(SchedError::NotPermitted));
+
+        assert_eq!(st.revoke(sup, app, caps::SEND_MESSAGE), Ok(caps::SYS_TIME));
+        assert_eq!(st.delegate(sup, 0xDEAD, caps::SYS_TIME), Err(SchedError::BadIndex));
+    }
+
+    #[test]
+    fn edf_runs_earliest_deadline_and_reports_misses() {
+        let mut st = SchedState::new();
+        st.policy = Policy::Edf;
+        let bg = st.insert(task()).unwrap();
+        let slow = st.insert(task()).unwrap();
+        let fast = st.insert(task()).unwrap();
+        assert_eq!(st.set_rt_params(slow, Some(RtParams::implicit(20, 10)), 0), Ok(500_000));
+        assert_eq!(st.set_rt_params(fast, Some(RtParams { period: 10, deadline: 5, wcet: 3 }), 0), Err(SchedError::Infeasible));
+        assert_eq!(st.set_rt_params(fast, Some(RtParams { period: 10, deadline: 20, wcet: 3 }), 0), Err(SchedError::InvalidParams));
+        assert_eq!(st.set_rt_params(fast, Some(RtParams::implicit(10, 3)), 0), Ok(800_000));
+
+        let running = |st: &SchedState| st.tasks.at(st.current).unwrap().id;
+        st.switch(core::ptr::null_mut()).unwrap();
+        assert_eq!(running(&st), fast, "deadline 10 before 20");
+        assert_eq!(st.complete_current(), Ok(10));
+        st.switch(core::ptr::null_mut()).unwrap();
+        assert_eq!(running(&st), slow);
+        st.complete_current().unwrap();
+        st.switch(core::ptr::null_mut()).unwrap();
+        assert_eq!(running(&st), bg, "background task gets the idle time");
+        assert_eq!(st.complete_current(), Err(SchedError::NotPeriodic));
+
+        // `fast` is released at 10 and never completes: one miss at 20
+        let mut misses = std::vec::Vec::new();
+        for now in 1..=25 {
+            misses.extend(st.rt_tick(now).into_iter().flatten());
+            st.switch(core::ptr::null_mut()).unwrap();
+        }
+        assert_eq!(misses.len(), 1);
+        assert_eq!((misses[0].task, misses[0].deadline), (fast, 20));
+        assert!(misses[0].used > misses[0].wcet);
+
+        // `slow` completed its job released at 20: next one at 40
+        st.current = st.tasks.slot_of(slow).unwrap();
+        st.complete_current().unwrap();
+        assert_eq!(st.next_release(25), Some(25), "fast is still runnable");
+        assert_eq!(st.set_rt_params(fast, None, 25), Ok(500_000));
+        assert_eq!(st.next_release(25), Some(40));
+    }
+
+    #[test]
+    fn ticks_and_switches_are_charged_to_the_running_task() {
+        let mut st = SchedState::new();
+        let a = st.insert(task()).unwrap();
+        let b = st.insert(task()).unwrap();
+
+        st.switch(core::ptr::null_mut()).unwrap(); // -> b (slot after 0)
+        for _ in 0..3 {
+            st.account_tick();
+        }
+        st.switch(core::ptr::null_mut()).unwrap(); // -> a
+        st.account_tick();
+
+        let stats = st.stats();
+        let find = |id| stats.iter().flatten().find(|s| s.id == id).copied().unwrap();
+        assert_eq!((find(a).run_ticks, find(a).switches), (1, 1));
+        assert_eq!((find(b).run_ticks, find(b).switches), (3, 1));
+        assert_eq!(find(a).utilization_permille, 250);
+        assert_eq!(find(b).utilization_permille, 750);
+    }
+
+    #[test]
+    fn boot_context_is_not_saved_on_the_first_switch() {
+        let mut st = SchedState::new();
+        let mut stack = [0u32; 1];
+        let a = st.insert(Task { stack_pointer: stack.as_mut_ptr(), ..task() }).unwrap();
+        let mut scratch = [0u32; 1];
+        st.switch(scratch.as_mut_ptr()).unwrap();
+        assert_eq!(st.tasks.get(a).unwrap().stack_pointer, stack.as_mut_ptr(), "boot SP discarded");
+        st.switch(scratch.as_mut_ptr()).unwrap();
+        assert_eq!(st.tasks.get(a).unwrap().stack_pointer, scratch.as_mut_ptr(), "task SP saved");
+    }
+
+    #[test]
+    fn overflowed_task_is_removed_and_never_resumed() {
+        let mut st = SchedState::new();
+        let mut words = [0u32; 64];
+        let (sp, bounds) = crate::context::init_checked_stack(&mut words, 0x0800_0001, 0x0800_0001, 0).unwrap();
+        let a = st.insert(Task { stack_pointer: sp, stack: Some(bounds), ..task() }).unwrap();
+        let b = st.insert(task()).unwrap();
+        st.switch(core::ptr::null_mut()).unwrap(); // -> b
+        st.switch(core::ptr::null_mut()).unwrap(); // -> a
+        assert_eq!(st.check_stack(OverflowPolicy::KillTask), None, "healthy stack");
+
+        st.tasks.get_mut(a).unwrap().stack_pointer = sp;
+        // SAFETY: the bottom byte of `words`, which outlives the task.
+        unsafe { (bounds.bottom as *mut u8).write(0) };
+        assert_eq!(st.check_stack(OverflowPolicy::KillTask), Some((StackFault::CanaryCorrupted, a)));
+        assert!(st.tasks.get(a).is_none());
+        st.switch(core::ptr::null_mut()).unwrap();
+        assert_eq!(st.tasks.at(st.current).map(|t| t.id), Some(b));
+    }
+
+    #[test]
+    fn reused_slot_starts_from_zero() {
+        let mut st = SchedState::new();
+        let a = st.insert(task()).unwrap();
+        st.switch(core::ptr::null_mut()).unwrap();
+        st.account_tick();
+        st.tasks.remove(a);
+        let b = st.insert(task()).unwrap();
+        let s = st.stats()[0].unwrap();
+        assert_eq!((s.id, s.run_ticks, s.switches), (b, 0, 0));
+    }
+
+    #[test]
+    fn killed_task_is_not_saved_or_resumed() {
+        let mut st = SchedState::new();
+        let a = st.insert(task()).unwrap();
+        assert!(st.kill_current().is_none(), "last task stays");
+        let b = st.insert(task()).unwrap();
+        st.switch(core::ptr::null_mut()).unwrap(); // -> b
+
+        assert_eq!(st.kill_current().map(|t| t.id), Some(b));
+        let mut sp = [0u32; 1];
+        st.switch(sp.as_mut_ptr()).unwrap(); // -> a, nothing saved for b
+        assert_eq!(st.tasks.at(st.current).map(|t| t.id), Some(a));
+        assert!(st.tasks.get(b).is_none());
+
+        let c = st.insert(task()).unwrap();
+        assert_eq!(st.kill(c).map(|(t, running)| (t.id, running)), Some((c, false)));
+        assert!(st.kill(a).is_none(), "last task stays");
+    }
+
+    #[test]
+    fn suspended_tasks_are_skipped_until_resumed() {
+        let mut st = SchedState::new();
+        let a = st.insert(task()).unwrap();
+        let b = st.insert(task()).unwrap();
+        let c = st.insert(task()).unwrap();
+        let running = |st: &mut SchedState| {
+            st.switch(core::ptr::null_mut()).unwrap();
+            st.tasks.at(st.current).unwrap().id
+        };
+        assert_eq!(running(&mut st), b);
+
+        assert_eq!(st.suspend(b), Ok(true), "running task");
+        assert_eq!(st.suspend(c), Ok(false));
+        assert_eq!(st.suspend(a), Err(SchedError::LastTask));
+        assert_eq!((running(&mut st), running(&mut st)), (a, a));
 
-    unsafe {
-        core::ptr::write_volatile(ICSR, PENDSVSET);
+        st.resume(c).unwrap();
+        assert_eq!((running(&mut st), running(&mut st)), (c, a));
+        st.tasks.remove(b);
+        let d = st.insert(task()).unwrap();
+        assert_eq!(running(&mut st), d, "reused slot is not suspended");
     }
 }
@@ -0,0 +1,220 @@
+//! SecureIoTOS Kernel Stack Guard Module
+//! -------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author : Md Mahbubur Rahman
+//! URL    : https://m-a-h-b-u-b.github.io
+//! GitHub : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Stack overflow detection on the context switch path.
+//!
+//! At every switch (`scheduler`, from `PendSV`) the outgoing task is
+//! checked before its context is trusted again:
+//!
+//! - its canary (`memory::stack::STACK_CANARY`, at the lowest address of
+//!   the stack) must be intact, and
+//! - its saved stack pointer must lie inside the stack, above the canary.
+//!
+//! Either failure is a `StackFault`, handled by the `OverflowPolicy` set
+//! with `set_overflow_policy()`. The overflowing task is never resumed:
+//! its saved context is below its stack and may be garbage.
+//!
+//! These checks only see an overflow after the fact. `enter()` also arms
+//! a hardware limit for the incoming task, so the offending push faults at
+//! once:
+//!
+//! - on ARMv8-M (feature `armv8m`) PSPLIM is set to just above its canary
+//!   and the core raises a UsageFault (STKOF); `set_msp_limit()` does the
+//!   same for the main stack;
+//! - otherwise the no-access MPU guard region below a guarded stack
+//!   (`context::init_guarded_stack()`) is moved there
+//!   (`memory::mpu::set_stack_guard`) and raises a MemManage fault.
+//!
+//! Only tasks with known bounds (`Task::stack`, set up by
+//! `context::init_checked_stack()` or `init_guarded_stack()`) are checked.
+
+// Core kernel path: must not panic (see `tools/no-panic-check`).
+#![cfg_attr(not(test), deny(
+    clippy::panic,
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::indexing_slicing,
+    clippy::unreachable,
+    clippy::todo,
+    clippy::unimplemented
+))]
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use memory::stack::{canary_intact, used_stack_bytes, STACK_CANARY};
+
+use crate::context::Task;
+
+/// Memory of a task stack: `[bottom, top)`, canary at `bottom`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackBounds {
+    pub bottom: usize,
+    pub top: usize,
+    /// Base of the MPU guard region right below `bottom`, if any
+    pub guard: Option<usize>,
+}
+
+impl StackBounds {
+    /// Lowest address the stack pointer may reach (just above the canary).
+    pub fn limit(&self) -> usize {
+        self.bottom + STACK_CANARY.len()
+    }
+}
+
+/// What went wrong with a task stack.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackFault {
+    /// The canary at the bottom of the stack was overwritten
+    CanaryCorrupted,
+    /// The saved stack pointer is outside the stack (or in the canary)
+    SpOutOfBounds,
+}
+
+/// Reaction to a `StackFault`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum OverflowPolicy {
+    /// Report through `kfail!` (debug builds halt in the debugger, release
+    /// builds follow the `assert` failure policy), then remove the task
+    Report = 0,
+    /// Remove the task and switch to the next one
+    KillTask = 1,
+    /// Reset the device
+    Reset = 2,
+}
+
+static POLICY: AtomicU8 = AtomicU8::new(OverflowPolicy::Report as u8);
+
+/// Select the reaction to stack overflows (default: `Report`).
+pub fn set_overflow_policy(policy: OverflowPolicy) {
+    POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+/// Currently selected reaction to stack overflows.
+pub fn overflow_policy() -> OverflowPolicy {
+    match POLICY.load(Ordering::Relaxed) {
+        1 => OverflowPolicy::KillTask,
+        2 => OverflowPolicy::Reset,
+        _ => OverflowPolicy::Report,
+    }
+}
+
+/// Check the stack of `task`, which is not running (its `stack_pointer`
+/// is the saved one).
+pub fn check(task: &Task) -> Result<(), StackFault> {
+    let Some(bounds) = task.stack else {
+        return Ok(());
+    };
+    // SAFETY: `bounds` describes the stack memory owned by `task`, which
+    // stays allocated while the task is in the table, and the canary lies
+    // inside it.
+    let canary = unsafe { core::slice::from_raw_parts(bounds.bottom as *const u8, STACK_CANARY.len()) };
+    if !canary_intact(canary) {
+        return Err(StackFault::CanaryCorrupted);
+    }
+    let sp = task.stack_pointer as usize;
+    if sp < bounds.limit() || sp > bounds.top {
+        return Err(StackFault::SpOutOfBounds);
+    }
+    Ok(())
+}
+
+/// Deepest use of the stack since it was painted, in bytes (the
+/// `memory::stack` watermark).
+pub fn watermark(bounds: &StackBounds) -> usize {
+    // SAFETY: as in `check()`, `bounds` describes live stack memory of a
+    // task in the table. Bytes the task writes concurrently only make the
+    // estimate stale.
+    let stack = unsafe { core::slice::from_raw_parts(bounds.bottom as *const u8, bounds.top - bounds.bottom) };
+    used_stack_bytes(stack)
+}
+
+/// Reset the device (the `Reset` policy, or nothing left to run).
+pub fn reset() -> ! {
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// Program PSPLIM for the incoming task, so its stack faults on overflow
+/// (STKOF) before it reaches the canary. Tasks without known bounds run
+/// without a limit.
+#[cfg(all(target_arch = "arm", feature = "armv8m"))]
+pub fn enter(task: &Task) {
+    let limit = task.stack.map_or(0, |b| b.limit());
+    // SAFETY: called from `PendSV` in Handler mode, which runs on MSP, so
+    // the limit of the (inactive) process stack can change.
+    unsafe { core::arch::asm!("msr PSPLIM, {}", in(reg) limit, options(nomem, nostack, preserves_flags)) };
+}
+
+/// Move the MPU stack guard below the incoming task's stack, so it
+/// faults (MemManage) on overflow before it reaches the memory below.
+/// Tasks without a guarded stack run without one.
+#[cfg(all(target_arch = "arm", not(feature = "armv8m")))]
+pub fn enter(task: &Task) {
+    memory::mpu::set_stack_guard(task.stack.and_then(|b| b.guard));
+}
+
+#[cfg(not(target_arch = "arm"))]
+pub fn enter(_task: &Task) {}
+
+/// Set MSPLIM to `limit` (the lowest address of the main stack), so
+/// handler and boot code overflowing it fault at once.
+///
+/// # Safety
+/// The current main stack pointer must be above `limit`.
+#[cfg(all(target_arch = "arm", feature = "armv8m"))]
+pub unsafe fn set_msp_limit(limit: usize) {
+    core::arch::asm!("msr MSPLIM, {}", in(reg) limit, options(nomem, nostack, preserves_flags));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use memory::stack::init_task_stack;
+
+    fn task_on(stack: &mut [u8], sp_offset: usize) -> Task {
+        let bottom = stack.as_ptr() as usize;
+        Task {
+            id: 1,
+            privilege: 1,
+            stack_pointer: (bottom + sp_offset) as *mut u32,
+            capabilities: 0,
+            stack: Some(StackBounds { bottom, top: bottom + stack.len(), guard: None }),
+        }
+    }
+
+    #[test]
+    fn detects_corrupted_canary_and_stray_sp() {
+        let mut stack = [0u8; 256];
+        init_task_stack(&mut stack);
+        assert_eq!(check(&task_on(&mut stack, 128)), Ok(()));
+        assert_eq!(check(&task_on(&mut stack, 4)), Err(StackFault::SpOutOfBounds), "SP inside the canary");
+        assert_eq!(check(&task_on(&mut stack, 300)), Err(StackFault::SpOutOfBounds));
+
+        stack[3] ^= 0xFF;
+        assert_eq!(check(&task_on(&mut stack, 128)), Err(StackFault::CanaryCorrupted));
+    }
+
+    #[test]
+    fn watermark_reads_the_painted_stack() {
+        let mut stack = [0u8; 256];
+        init_task_stack(&mut stack);
+        let bounds = task_on(&mut stack, 128).stack.unwrap();
+        assert_eq!(watermark(&bounds), 0);
+        stack[200..].fill(0);
+        assert_eq!(watermark(&bounds), 56);
+    }
+
+    #[test]
+    fn policy_round_trips() {
+        assert_eq!(overflow_policy(), OverflowPolicy::Report);
+        set_overflow_policy(OverflowPolicy::Reset);
+        assert_eq!(overflow_policy(), OverflowPolicy::Reset);
+        set_overflow_policy(OverflowPolicy::Report);
+    }
+}
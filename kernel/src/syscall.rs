@@ -11,14 +11,33 @@
 //! - Multi-argument support (up to 6 args) typical for modern ABIs.
 //! - Handlers return `Result<u32, SyscallError>`; the C entrypoint encodes this
 //!   into a `u32` return value (success = value, error = high-bit set + code).
-//! - Thin capability/privilege check example.
-//! - Secure user-memory copy helpers (stubs; MUST be implemented per-arch).
+//! - Capability checks against the calling task's capability set, kept in
+//!   its task control block (`DelegateCaps` changes it at run time).
+//! - User-memory copy helpers that only touch ranges the MPU lets the
+//!   caller write (`validate_user_ptr`).
 //! - Trait-based handlers for composability and unit testing.
+//! - Registration table so subsystems add syscalls at init time without
+//!   editing the kernel (`register_syscall`).
 
-#![no_std] // comment out if you want std during testing
 #![allow(dead_code)]
+// Core kernel path: must not panic (see `tools/no-panic-check`).
+#![cfg_attr(not(test), deny(
+    clippy::panic,
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::indexing_slicing,
+    clippy::unreachable,
+    clippy::todo,
+    clippy::unimplemented
+))]
 
+use core::cell::UnsafeCell;
 use core::convert::TryFrom;
+use core::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+use crate::ipc_queue::{self, QueueError, QUEUE_MSG_SIZE};
+use crate::scheduler::TaskMemory;
+use crate::stack_guard::StackBounds;
 
 /// Maximum syscall arguments we'll support here (adjust for target ABI).
 pub const MAX_SYSCALL_ARGS: usize = 6;
@@ -33,6 +52,12 @@ pub enum SyscallError {
     TooLarge = 4,
     NotFound = 5,
     Unsupported = 6,
+    /// Destination queue is full
+    Full = 7,
+    /// No message arrived before the timeout
+    TimedOut = 8,
+    /// No free kernel object (e.g. queue) left
+    NoSlot = 9,
     Unknown = 0xFFFF,
 }
 
@@ -42,6 +67,19 @@ impl From<SyscallError> for u32 {
     }
 }
 
+impl From<QueueError> for SyscallError {
+    fn from(e: QueueError) -> Self {
+        match e {
+            QueueError::Invalid => SyscallError::Invalid,
+            QueueError::NotOwner => SyscallError::PermissionDenied,
+            QueueError::TooLarge => SyscallError::TooLarge,
+            QueueError::Full => SyscallError::Full,
+            QueueError::NoSlot => SyscallError::NoSlot,
+            QueueError::TimedOut => SyscallError::TimedOut,
+        }
+    }
+}
+
 /// A raw-user-visible encoding scheme for errors:
 /// - If top bit (0x8000_0000) is 0 => success, value is the return value.
 /// - If top bit is 1 => failure, lower 31 bits contain error code (SyscallError as u32).
@@ -62,13 +100,12 @@ pub struct SyscallArgs {
 
 impl SyscallArgs {
     pub fn arg_u32(&self, idx: usize) -> Result<u32, SyscallError> {
-        if idx >= self.nargs { return Err(SyscallError::Invalid); }
-        Ok(self.args[idx] as u32)
+        self.arg_u64(idx).map(|v| v as u32)
     }
 
     pub fn arg_u64(&self, idx: usize) -> Result<u64, SyscallError> {
         if idx >= self.nargs { return Err(SyscallError::Invalid); }
-        Ok(self.args[idx])
+        self.args.get(idx).copied().ok_or(SyscallError::Invalid)
     }
 }
 
@@ -78,6 +115,14 @@ impl SyscallArgs {
 pub enum SyscallId {
     GetTime = 1,
     SendMessage = 2,
+    GetMemInfo = 3,
+    GetTaskStats = 4,
+    GetFirmwareInfo = 5,
+    ReceiveMessage = 6,
+    CreateQueue = 7,
+    DelegateCaps = 8,
+    GetHeapStats = 9,
+    GetBootMeasurements = 10,
     // add more here...
 }
 
@@ -87,32 +132,61 @@ impl TryFrom<u32> for SyscallId {
         match v {
             1 => Ok(SyscallId::GetTime),
             2 => Ok(SyscallId::SendMessage),
+            3 => Ok(SyscallId::GetMemInfo),
+            4 => Ok(SyscallId::GetTaskStats),
+            5 => Ok(SyscallId::GetFirmwareInfo),
+            6 => Ok(SyscallId::ReceiveMessage),
+            7 => Ok(SyscallId::CreateQueue),
+            8 => Ok(SyscallId::DelegateCaps),
+            9 => Ok(SyscallId::GetHeapStats),
+            10 => Ok(SyscallId::GetBootMeasurements),
             _ => Err(()),
         }
     }
 }
 
-/// Represents the current execution context (thread/process) — minimal stub.
-/// Fill with real fields in your kernel (UID/GID, capabilities, address space, etc).
+/// The calling task as seen by a syscall handler.
 #[derive(Debug)]
 pub struct CurrentContext {
     pub uid: u32,
+    /// Scheduler ID of the calling task
+    pub task: u32,
+    /// The task's capability set (`caps` bits)
     pub capabilities: u32,
 }
 
-/// Example capability flags (you can use bitflags crate in std builds).
+/// Capability bits. Each syscall requires one; a task holds the set it
+/// was created with (`context::Task::capabilities`) plus whatever was
+/// delegated to it since.
 pub mod caps {
     pub const SYS_TIME: u32 = 1 << 0;
     pub const SEND_MESSAGE: u32 = 1 << 1;
+    pub const MEM_INFO: u32 = 1 << 2;
+    pub const TASK_STATS: u32 = 1 << 3;
+    pub const FIRMWARE_INFO: u32 = 1 << 4;
+    pub const CREATE_QUEUE: u32 = 1 << 5;
+    pub const RECV_MESSAGE: u32 = 1 << 6;
+    /// Grant/revoke other tasks' capabilities; privileged tasks only
+    pub const DELEGATE: u32 = 1 << 7;
+    pub const HEAP_STATS: u32 = 1 << 8;
+    pub const BOOT_MEASUREMENTS: u32 = 1 << 9;
+    /// Bits 16-31 are left to subsystems for their registered syscalls.
+    pub const SUBSYSTEM_MASK: u32 = 0xFFFF_0000;
 }
 
-/// Return the current execution context (stub — implement per-kernel).
+/// Context of the calling task, read from its task control block. Without
+/// a running task the caller holds no capabilities.
+#[cfg(target_arch = "arm")]
 fn current_context() -> CurrentContext {
-    // TODO: get context from scheduler / current thread struct
-    CurrentContext {
-        uid: 0,
-        capabilities: caps::SYS_TIME | caps::SEND_MESSAGE,
-    }
+    let (task, capabilities) = crate::scheduler::current_capabilities().unwrap_or((0, 0));
+    CurrentContext { uid: 0, task, capabilities }
+}
+
+/// Host builds (C API tests) have no scheduler; syscalls run as task 0
+/// holding every capability.
+#[cfg(not(target_arch = "arm"))]
+fn current_context() -> CurrentContext {
+    CurrentContext { uid: 0, task: 0, capabilities: u32::MAX }
 }
 
 /// Trait for syscall handlers. Implementations return `Result<u32, SyscallError>`.
@@ -125,12 +199,154 @@ pub fn dispatch_syscall(id: SyscallId, ctx: &CurrentContext, args: &SyscallArgs)
     match id {
         SyscallId::GetTime => GetTimeSyscall.handle(ctx, args),
         SyscallId::SendMessage => SendMessageSyscall.handle(ctx, args),
+        SyscallId::GetMemInfo => GetMemInfoSyscall.handle(ctx, args),
+        SyscallId::GetTaskStats => GetTaskStatsSyscall.handle(ctx, args),
+        SyscallId::GetFirmwareInfo => GetFirmwareInfoSyscall.handle(ctx, args),
+        SyscallId::ReceiveMessage => ReceiveMessageSyscall.handle(ctx, args),
+        SyscallId::CreateQueue => CreateQueueSyscall.handle(ctx, args),
+        SyscallId::DelegateCaps => DelegateCapsSyscall.handle(ctx, args),
+        SyscallId::GetHeapStats => GetHeapStatsSyscall.handle(ctx, args),
+        SyscallId::GetBootMeasurements => GetBootMeasurementsSyscall.handle(ctx, args),
     }
 }
 
-/// -----------------
-/// Implementations
-/// -----------------
+// -----------------
+// Registered syscalls
+// -----------------
+
+/// Syscalls subsystems can register.
+pub const MAX_REGISTERED_SYSCALLS: usize = 16;
+/// IDs below this are reserved for the built-in `SyscallId`s.
+pub const FIRST_REGISTERED_ID: u32 = 0x40;
+
+/// Why a registration was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    /// ID is in the built-in range (below `FIRST_REGISTERED_ID`)
+    Reserved,
+    /// Another handler already has this ID
+    Duplicate,
+    /// `MAX_REGISTERED_SYSCALLS` reached
+    Full,
+    /// Registration is closed (`seal_syscalls` was called)
+    Sealed,
+}
+
+/// A registered syscall: its ID, the capabilities a caller must hold, and
+/// the handler.
+#[derive(Clone, Copy)]
+pub struct SyscallEntry {
+    pub id: u32,
+    pub required_caps: u32,
+    handler: &'static (dyn SyscallHandler + Sync),
+}
+
+/// Fixed-size table of registered syscalls.
+pub struct SyscallTable {
+    entries: [Option<SyscallEntry>; MAX_REGISTERED_SYSCALLS],
+}
+
+impl SyscallTable {
+    pub const fn new() -> Self {
+        SyscallTable { entries: [None; MAX_REGISTERED_SYSCALLS] }
+    }
+
+    /// Add `handler` under `id`, callable by tasks holding all of
+    /// `required_caps`.
+    pub fn register(
+        &mut self,
+        id: u32,
+        required_caps: u32,
+        handler: &'static (dyn SyscallHandler + Sync),
+    ) -> Result<(), RegisterError> {
+        if id < FIRST_REGISTERED_ID {
+            return Err(RegisterError::Reserved);
+        }
+        if self.lookup(id).is_some() {
+            return Err(RegisterError::Duplicate);
+        }
+        let slot = self.entries.iter_mut().find(|e| e.is_none()).ok_or(RegisterError::Full)?;
+        *slot = Some(SyscallEntry { id, required_caps, handler });
+        Ok(())
+    }
+
+    pub fn lookup(&self, id: u32) -> Option<&SyscallEntry> {
+        self.entries.iter().flatten().find(|e| e.id == id)
+    }
+
+    /// Registered syscalls, in registration order.
+    pub fn entries(&self) -> impl Iterator<Item = &SyscallEntry> {
+        self.entries.iter().flatten()
+    }
+
+    /// Check the caller's capabilities, then run the handler.
+    pub fn dispatch(&self, id: u32, ctx: &CurrentContext, args: &SyscallArgs) -> Result<u32, SyscallError> {
+        let entry = self.lookup(id).ok_or(SyscallError::Invalid)?;
+        if ctx.capabilities & entry.required_caps != entry.required_caps {
+            return Err(SyscallError::PermissionDenied);
+        }
+        entry.handler.handle(ctx, args)
+    }
+}
+
+impl Default for SyscallTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The system table: written only before `seal_syscalls`, read only after.
+struct Registry {
+    table: UnsafeCell<SyscallTable>,
+    sealed: AtomicBool,
+}
+
+// SAFETY: `table` is mutated only while unsealed, inside a critical
+// section, and read only once sealed; sealing is one-way (Release/Acquire).
+unsafe impl Sync for Registry {}
+
+static REGISTRY: Registry = Registry { table: UnsafeCell::new(SyscallTable::new()), sealed: AtomicBool::new(false) };
+
+/// Register a subsystem syscall. Call during init, before `seal_syscalls`
+/// (which `kernel_init` does before the first task runs), e.g.
+///
+/// ```ignore
+/// static STORE_GET: StoreGetSyscall = StoreGetSyscall;
+/// register_syscall(0x40, CAP_STORAGE, &STORE_GET)?;
+/// ```
+pub fn register_syscall(
+    id: u32,
+    required_caps: u32,
+    handler: &'static (dyn SyscallHandler + Sync),
+) -> Result<(), RegisterError> {
+    cortex_m::interrupt::free(|_| {
+        if REGISTRY.sealed.load(Ordering::Acquire) {
+            return Err(RegisterError::Sealed);
+        }
+        // SAFETY: unsealed, so no reader exists yet, and the critical
+        // section excludes other registrations.
+        unsafe { (*REGISTRY.table.get()).register(id, required_caps, handler) }
+    })
+}
+
+/// Close registration; registered syscalls become callable.
+pub fn seal_syscalls() {
+    REGISTRY.sealed.store(true, Ordering::Release);
+}
+
+/// The registered syscalls, once registration is sealed.
+pub fn registered_syscalls() -> Option<&'static SyscallTable> {
+    if REGISTRY.sealed.load(Ordering::Acquire) {
+        // SAFETY: sealed, so the table is never written again.
+        Some(unsafe { &*REGISTRY.table.get() })
+    } else {
+        None
+    }
+}
+
+// -----------------
+// Implementations
+// -----------------
 
 /// GetTime Syscall: returns a 32-bit time value (seconds since epoch or ticks).
 pub struct GetTimeSyscall;
@@ -148,11 +364,29 @@ impl SyscallHandler for GetTimeSyscall {
     }
 }
 
-/// SendMessage Syscall:
+/// Largest message accepted by `SendMessage`.
+pub const MAX_MESSAGE_LEN: usize = QUEUE_MSG_SIZE;
+
+/// CreateQueue Syscall: open a kernel message queue owned by the caller
+/// (see `ipc_queue`). No arguments.
+///
+/// Returns the queue handle.
+pub struct CreateQueueSyscall;
+
+impl SyscallHandler for CreateQueueSyscall {
+    fn handle(&self, ctx: &CurrentContext, _args: &SyscallArgs) -> Result<u32, SyscallError> {
+        if (ctx.capabilities & caps::CREATE_QUEUE) == 0 {
+            return Err(SyscallError::PermissionDenied);
+        }
+        Ok(ipc_queue::create(ctx.task)?)
+    }
+}
+
+/// SendMessage Syscall: copy a message into a kernel queue. Never blocks.
 /// Args:
 /// - arg0: user-space pointer (u32/usize) to buffer
-/// - arg1: length (u32)
-/// - arg2: destination id (u32)
+/// - arg1: length (u32), at most `MAX_MESSAGE_LEN`
+/// - arg2: destination queue handle (u32)
 pub struct SendMessageSyscall;
 
 impl SyscallHandler for SendMessageSyscall {
@@ -165,24 +399,535 @@ impl SyscallHandler for SendMessageSyscall {
         let len = args.arg_u64(1).map_err(|_| SyscallError::Invalid)? as usize;
         let dest = args.arg_u64(2).map_err(|_| SyscallError::Invalid)? as u32;
 
-        if len == 0 || len > 4096 {
+        if len == 0 || len > MAX_MESSAGE_LEN {
             return Err(SyscallError::TooLarge);
         }
+        if !validate_user_ptr(ptr, len) {
+            return Err(SyscallError::BadAddress);
+        }
 
-        // Copy data from user space into kernel-owned buffer (safe copy).
-        let mut buf = vec![0u8; len]; // NOTE: replace with kernel allocator if no std
-        copy_from_user(ptr, &mut buf).map_err(|_| SyscallError::BadAddress)?;
+        // Copy data from user space into a kernel-owned bounce buffer
+        // (no heap on this path, so it cannot fail on allocation).
+        let mut bounce = [0u8; MAX_MESSAGE_LEN];
+        let buf = bounce.get_mut(..len).ok_or(SyscallError::TooLarge)?;
+        copy_from_user(ptr, buf).map_err(|_| SyscallError::BadAddress)?;
 
-        // TODO: enqueue message into IPC subsystem
-        kernel_ipc_send(dest, &buf).map_err(|_| SyscallError::Unknown)?;
+        ipc_queue::send(dest, buf)?;
 
         Ok(0) // success, return 0
     }
 }
 
-/// ---------------
-/// Kernel primitives (stubs - platform-specific)
-/// ---------------
+/// ReceiveMessage Syscall: take the oldest message from a queue the
+/// caller owns, waiting for one if it is empty.
+/// Args:
+/// - arg0: user-space pointer to a buffer of at least `MAX_MESSAGE_LEN`
+///   bytes (so no message is ever truncated)
+/// - arg1: queue handle
+/// - arg2: timeout in ms (0 = poll, `ipc_queue::WAIT_FOREVER` = no limit)
+///
+/// Returns the message length.
+pub struct ReceiveMessageSyscall;
+
+impl SyscallHandler for ReceiveMessageSyscall {
+    fn handle(&self, ctx: &CurrentContext, args: &SyscallArgs) -> Result<u32, SyscallError> {
+        if (ctx.capabilities & caps::RECV_MESSAGE) == 0 {
+            return Err(SyscallError::PermissionDenied);
+        }
+
+        let ptr = args.arg_u64(0)? as usize;
+        let queue = args.arg_u32(1)?;
+        let timeout_ms = args.arg_u32(2)?;
+        if !validate_user_ptr(ptr, MAX_MESSAGE_LEN) {
+            return Err(SyscallError::BadAddress);
+        }
+
+        let timeout = match timeout_ms {
+            ipc_queue::WAIT_FOREVER => ipc_queue::WAIT_FOREVER,
+            ms => crate::time::ms_to_ticks(ms),
+        };
+        let msg = ipc_queue::receive(queue, ctx.task, timeout)?;
+        let payload = msg.data.get(..msg.length).ok_or(SyscallError::Unknown)?;
+        copy_to_user(ptr, payload).map_err(|_| SyscallError::BadAddress)?;
+
+        Ok(msg.length as u32)
+    }
+}
+
+/// `DelegateCaps` operations (arg2).
+pub const CAPS_GRANT: u32 = 0;
+pub const CAPS_REVOKE: u32 = 1;
+
+/// DelegateCaps Syscall: grant capabilities the caller holds to another
+/// task, or revoke capabilities from one. Privileged: the caller needs
+/// `caps::DELEGATE`, which only privileged tasks can hold.
+/// Args:
+/// - arg0: target task id
+/// - arg1: capability bits
+/// - arg2: `CAPS_GRANT` or `CAPS_REVOKE`
+///
+/// Returns 0.
+pub struct DelegateCapsSyscall;
+
+impl SyscallHandler for DelegateCapsSyscall {
+    fn handle(&self, ctx: &CurrentContext, args: &SyscallArgs) -> Result<u32, SyscallError> {
+        if (ctx.capabilities & caps::DELEGATE) == 0 {
+            return Err(SyscallError::PermissionDenied);
+        }
+
+        let target = args.arg_u32(0)?;
+        let bits = args.arg_u32(1)?;
+        let result = match args.arg_u32(2)? {
+            CAPS_GRANT => crate::scheduler::delegate_capabilities(ctx.task, target, bits),
+            CAPS_REVOKE => crate::scheduler::revoke_capabilities(ctx.task, target, bits),
+            _ => return Err(SyscallError::Invalid),
+        };
+        result.map(|_| 0).map_err(|e| match e {
+            crate::scheduler::SchedError::NotPermitted => SyscallError::PermissionDenied,
+            crate::scheduler::SchedError::BadIndex => SyscallError::NotFound,
+            _ => SyscallError::Unknown,
+        })
+    }
+}
+
+/// Memory usage snapshot for the calling task, written to user space by
+/// `GetMemInfo`. Layout is part of the ABI: append fields, never reorder.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemInfo {
+    /// Size of the task stack in bytes
+    pub stack_size: u32,
+    /// Deepest stack usage observed so far (watermark), in bytes
+    pub stack_high_watermark: u32,
+    /// Heap bytes the task may allocate (0 = no quota)
+    pub heap_quota: u32,
+    /// Heap bytes currently allocated by the task
+    pub heap_used: u32,
+    /// Number of shared-memory regions granted to the task
+    pub shm_grants: u32,
+    /// Total size of those regions in bytes
+    pub shm_bytes: u32,
+}
+
+impl MemInfo {
+    /// Size of the user-visible structure in bytes.
+    pub const SIZE: usize = core::mem::size_of::<MemInfo>();
+
+    /// Snapshot of a task with stack `stack` (if known) and memory record
+    /// `memory`.
+    pub fn new(stack: Option<&StackBounds>, memory: &TaskMemory) -> Self {
+        let (stack_size, stack_high_watermark) =
+            stack.map_or((0, 0), |b| (b.top - b.bottom, crate::stack_guard::watermark(b)));
+        MemInfo {
+            stack_size: stack_size as u32,
+            stack_high_watermark: stack_high_watermark as u32,
+            heap_quota: memory.heap_quota,
+            heap_used: memory.heap_used,
+            shm_grants: memory.shm_grants,
+            shm_bytes: memory.shm_bytes,
+        }
+    }
+
+    /// Serialize in native field order (matches the `#[repr(C)]` layout).
+    fn to_ne_bytes(self) -> [u8; Self::SIZE] {
+        let fields = [
+            self.stack_size,
+            self.stack_high_watermark,
+            self.heap_quota,
+            self.heap_used,
+            self.shm_grants,
+            self.shm_bytes,
+        ];
+        let mut out = [0u8; Self::SIZE];
+        for (chunk, v) in out.chunks_exact_mut(4).zip(fields.iter()) {
+            chunk.copy_from_slice(&v.to_ne_bytes());
+        }
+        out
+    }
+}
+
+/// GetMemInfo Syscall: report the calling task's memory usage so
+/// applications can feed memory pressure into telemetry.
+/// Args:
+/// - arg0: user-space pointer to a `MemInfo` buffer
+/// - arg1: buffer length (must be >= `MemInfo::SIZE`)
+///
+/// Returns the number of bytes written.
+pub struct GetMemInfoSyscall;
+
+impl SyscallHandler for GetMemInfoSyscall {
+    fn handle(&self, ctx: &CurrentContext, args: &SyscallArgs) -> Result<u32, SyscallError> {
+        if (ctx.capabilities & caps::MEM_INFO) == 0 {
+            return Err(SyscallError::PermissionDenied);
+        }
+
+        let ptr = args.arg_u64(0)? as usize;
+        let len = args.arg_u64(1)? as usize;
+        if len < MemInfo::SIZE {
+            return Err(SyscallError::Invalid);
+        }
+        if !validate_user_ptr(ptr, MemInfo::SIZE) {
+            return Err(SyscallError::BadAddress);
+        }
+
+        let info = kernel_task_mem_info(ctx).ok_or(SyscallError::NotFound)?;
+        copy_to_user(ptr, &info.to_ne_bytes()).map_err(|_| SyscallError::BadAddress)?;
+
+        Ok(MemInfo::SIZE as u32)
+    }
+}
+
+/// CPU usage of one task, written to user space by `GetTaskStats`
+/// (see `scheduler::TaskStats`). Layout is part of the ABI: append
+/// fields, never reorder.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TaskCpuStats {
+    /// Task id
+    pub id: u32,
+    /// Times the task was switched in
+    pub switches: u32,
+    /// Ticks the task ran, low and high words
+    pub run_ticks_lo: u32,
+    pub run_ticks_hi: u32,
+    /// Share of CPU time since the last stats reset, in 0.1 %
+    pub utilization_permille: u32,
+}
+
+impl TaskCpuStats {
+    /// Size of the user-visible structure in bytes.
+    pub const SIZE: usize = core::mem::size_of::<TaskCpuStats>();
+
+    /// Serialize in native field order (matches the `#[repr(C)]` layout).
+    fn to_ne_bytes(self) -> [u8; Self::SIZE] {
+        let fields = [self.id, self.switches, self.run_ticks_lo, self.run_ticks_hi, self.utilization_permille];
+        let mut out = [0u8; Self::SIZE];
+        for (chunk, v) in out.chunks_exact_mut(4).zip(fields.iter()) {
+            chunk.copy_from_slice(&v.to_ne_bytes());
+        }
+        out
+    }
+}
+
+impl From<crate::scheduler::TaskStats> for TaskCpuStats {
+    fn from(s: crate::scheduler::TaskStats) -> Self {
+        TaskCpuStats {
+            id: s.id,
+            switches: s.switches,
+            run_ticks_lo: s.run_ticks as u32,
+            run_ticks_hi: (s.run_ticks >> 32) as u32,
+            utilization_permille: u32::from(s.utilization_permille),
+        }
+    }
+}
+
+/// GetTaskStats Syscall: per-task CPU usage, to find the task hogging
+/// the CPU.
+/// Args:
+/// - arg0: user-space pointer to an array of `TaskCpuStats`
+/// - arg1: buffer length in bytes (room for at least one entry)
+///
+/// Returns the number of entries written; tasks beyond the buffer are
+/// left out.
+pub struct GetTaskStatsSyscall;
+
+impl SyscallHandler for GetTaskStatsSyscall {
+    fn handle(&self, ctx: &CurrentContext, args: &SyscallArgs) -> Result<u32, SyscallError> {
+        if (ctx.capabilities & caps::TASK_STATS) == 0 {
+            return Err(SyscallError::PermissionDenied);
+        }
+
+        let ptr = args.arg_u64(0)? as usize;
+        let len = args.arg_u64(1)? as usize;
+        let room = len / TaskCpuStats::SIZE;
+        if room == 0 {
+            return Err(SyscallError::Invalid);
+        }
+
+        let stats = crate::scheduler::stats().map_err(|_| SyscallError::Unknown)?;
+        let mut written = 0;
+        for s in stats.iter().flatten().take(room) {
+            let at = ptr + written * TaskCpuStats::SIZE;
+            if !validate_user_ptr(at, TaskCpuStats::SIZE) {
+                return Err(SyscallError::BadAddress);
+            }
+            copy_to_user(at, &TaskCpuStats::from(*s).to_ne_bytes()).map_err(|_| SyscallError::BadAddress)?;
+            written += 1;
+        }
+
+        Ok(written as u32)
+    }
+}
+
+/// Identity of the running firmware, written to user space by
+/// `GetFirmwareInfo` (see `image::FirmwareInfo`). Layout is part of the
+/// ABI: append fields, never reorder.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FirmwareInfoAbi {
+    /// Build ID: 12 bytes of git commit, 8 bytes of configuration hash
+    pub build_id: [u8; crate::image::BUILD_ID_LEN],
+    /// Version: major, minor, patch
+    pub version: [u16; 3],
+    /// `image::FLAG_DIRTY` if built with uncommitted changes
+    pub flags: u16,
+}
+
+impl FirmwareInfoAbi {
+    /// Size of the user-visible structure in bytes.
+    pub const SIZE: usize = core::mem::size_of::<FirmwareInfoAbi>();
+
+    /// Serialize in native field order (matches the `#[repr(C)]` layout).
+    fn to_ne_bytes(self) -> [u8; Self::SIZE] {
+        let mut out = [0u8; Self::SIZE];
+        let (id, rest) = out.split_at_mut(crate::image::BUILD_ID_LEN);
+        id.copy_from_slice(&self.build_id);
+        let fields = [self.version[0], self.version[1], self.version[2], self.flags];
+        for (chunk, v) in rest.chunks_exact_mut(2).zip(fields.iter()) {
+            chunk.copy_from_slice(&v.to_ne_bytes());
+        }
+        out
+    }
+}
+
+impl From<crate::image::FirmwareInfo> for FirmwareInfoAbi {
+    fn from(info: crate::image::FirmwareInfo) -> Self {
+        FirmwareInfoAbi {
+            build_id: info.build_id,
+            version: info.version,
+            flags: if info.dirty { crate::image::FLAG_DIRTY as u16 } else { 0 },
+        }
+    }
+}
+
+/// GetFirmwareInfo Syscall: build ID and version of the running firmware,
+/// so applications can tag their telemetry with it.
+/// Args:
+/// - arg0: user-space pointer to a `FirmwareInfoAbi` buffer
+/// - arg1: buffer length (must be >= `FirmwareInfoAbi::SIZE`)
+///
+/// Returns the number of bytes written.
+pub struct GetFirmwareInfoSyscall;
+
+impl SyscallHandler for GetFirmwareInfoSyscall {
+    fn handle(&self, ctx: &CurrentContext, args: &SyscallArgs) -> Result<u32, SyscallError> {
+        if (ctx.capabilities & caps::FIRMWARE_INFO) == 0 {
+            return Err(SyscallError::PermissionDenied);
+        }
+
+        let ptr = args.arg_u64(0)? as usize;
+        let len = args.arg_u64(1)? as usize;
+        if len < FirmwareInfoAbi::SIZE {
+            return Err(SyscallError::Invalid);
+        }
+        if !validate_user_ptr(ptr, FirmwareInfoAbi::SIZE) {
+            return Err(SyscallError::BadAddress);
+        }
+
+        let info = FirmwareInfoAbi::from(crate::image::firmware_info());
+        copy_to_user(ptr, &info.to_ne_bytes()).map_err(|_| SyscallError::BadAddress)?;
+
+        Ok(FirmwareInfoAbi::SIZE as u32)
+    }
+}
+
+/// Kernel heap statistics, written to user space by `GetHeapStats` (see
+/// `memory::size_class::ExtendedStats`). Layout is part of the ABI:
+/// append fields, never reorder.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapInfo {
+    /// Heap size in bytes, all size classes
+    pub total: u32,
+    /// Bytes in use
+    pub used: u32,
+    /// Highest `used` since boot
+    pub peak: u32,
+    /// Successful allocations
+    pub allocs: u32,
+    /// Deallocations
+    pub frees: u32,
+    /// Allocations that failed
+    pub failures: u32,
+    /// Largest single allocation that would succeed now
+    pub largest_free: u32,
+    /// Free bytes outside the largest free block, in percent
+    pub fragmentation_pct: u32,
+}
+
+impl HeapInfo {
+    /// Size of the user-visible structure in bytes.
+    pub const SIZE: usize = core::mem::size_of::<HeapInfo>();
+
+    /// Serialize in native field order (matches the `#[repr(C)]` layout).
+    fn to_ne_bytes(self) -> [u8; Self::SIZE] {
+        let fields = [
+            self.total,
+            self.used,
+            self.peak,
+            self.allocs,
+            self.frees,
+            self.failures,
+            self.largest_free,
+            self.fragmentation_pct,
+        ];
+        let mut out = [0u8; Self::SIZE];
+        for (chunk, v) in out.chunks_exact_mut(4).zip(fields.iter()) {
+            chunk.copy_from_slice(&v.to_ne_bytes());
+        }
+        out
+    }
+}
+
+/// Source of `HeapInfo`; null until `set_heap_info_source` is called.
+static HEAP_INFO_SOURCE: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Install the function `GetHeapStats` reads heap statistics from. The
+/// kernel does not own the heap, so the platform wires it up at init:
+///
+/// ```ignore
+/// set_heap_info_source(|| {
+///     let s = memory::heap::stats_extended();
+///     HeapInfo { total: s.total as u32, used: s.used as u32, /* ... */ }
+/// });
+/// ```
+pub fn set_heap_info_source(source: fn() -> HeapInfo) {
+    HEAP_INFO_SOURCE.store(source as *mut (), Ordering::Release);
+}
+
+fn heap_info() -> Option<HeapInfo> {
+    let raw = HEAP_INFO_SOURCE.load(Ordering::Acquire);
+    if raw.is_null() {
+        return None;
+    }
+    // SAFETY: the only non-null value ever stored is a `fn() -> HeapInfo`
+    // (`set_heap_info_source`).
+    let source: fn() -> HeapInfo = unsafe { core::mem::transmute(raw) };
+    Some(source())
+}
+
+/// GetHeapStats Syscall: kernel heap usage, peak and fragmentation, to
+/// spot leaks and OOM risk from telemetry.
+/// Args:
+/// - arg0: user-space pointer to a `HeapInfo` buffer
+/// - arg1: buffer length (must be >= `HeapInfo::SIZE`)
+///
+/// Returns the number of bytes written; `NotFound` if the platform did
+/// not install a heap statistics source.
+pub struct GetHeapStatsSyscall;
+
+impl SyscallHandler for GetHeapStatsSyscall {
+    fn handle(&self, ctx: &CurrentContext, args: &SyscallArgs) -> Result<u32, SyscallError> {
+        if (ctx.capabilities & caps::HEAP_STATS) == 0 {
+            return Err(SyscallError::PermissionDenied);
+        }
+
+        let ptr = args.arg_u64(0)? as usize;
+        let len = args.arg_u64(1)? as usize;
+        if len < HeapInfo::SIZE {
+            return Err(SyscallError::Invalid);
+        }
+        if !validate_user_ptr(ptr, HeapInfo::SIZE) {
+            return Err(SyscallError::BadAddress);
+        }
+
+        let info = heap_info().ok_or(SyscallError::NotFound)?;
+        copy_to_user(ptr, &info.to_ne_bytes()).map_err(|_| SyscallError::BadAddress)?;
+
+        Ok(HeapInfo::SIZE as u32)
+    }
+}
+
+/// One measured-boot event, as written to user space by
+/// `GetBootMeasurements`. Layout is part of the ABI.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BootEventAbi {
+    /// `measured_boot::EventKind`, 0 for an unused slot
+    pub kind: u32,
+    /// SHA-256 of what was measured
+    pub digest: [u8; 32],
+}
+
+/// The bootloader's measurement log (see `measured_boot::BootLog`),
+/// written to user space by `GetBootMeasurements`. Layout is part of the
+/// ABI: append fields, never reorder.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BootMeasurementsAbi {
+    /// Final value of the measurement chain
+    pub pcr: [u8; 32],
+    /// Events used in `events`
+    pub count: u32,
+    pub events: [BootEventAbi; crate::measured_boot::MAX_EVENTS],
+}
+
+impl BootMeasurementsAbi {
+    /// Size of the user-visible structure in bytes.
+    pub const SIZE: usize = core::mem::size_of::<BootMeasurementsAbi>();
+
+    /// Serialize in native field order (matches the `#[repr(C)]` layout).
+    fn to_ne_bytes(self) -> [u8; Self::SIZE] {
+        let mut out = [0u8; Self::SIZE];
+        let (pcr, rest) = out.split_at_mut(32);
+        pcr.copy_from_slice(&self.pcr);
+        let (count, rest) = rest.split_at_mut(4);
+        count.copy_from_slice(&self.count.to_ne_bytes());
+        for (chunk, e) in rest.chunks_exact_mut(core::mem::size_of::<BootEventAbi>()).zip(self.events.iter()) {
+            let (kind, digest) = chunk.split_at_mut(4);
+            kind.copy_from_slice(&e.kind.to_ne_bytes());
+            digest.copy_from_slice(&e.digest);
+        }
+        out
+    }
+}
+
+impl From<&crate::measured_boot::BootLog> for BootMeasurementsAbi {
+    fn from(log: &crate::measured_boot::BootLog) -> Self {
+        let mut abi = BootMeasurementsAbi { pcr: log.pcr(), ..Default::default() };
+        for (slot, e) in abi.events.iter_mut().zip(log.events()) {
+            *slot = BootEventAbi { kind: e.kind as u32, digest: e.digest };
+            abi.count += 1;
+        }
+        abi
+    }
+}
+
+/// GetBootMeasurements Syscall: the measured-boot log, for attestation
+/// evidence.
+/// Args:
+/// - arg0: user-space pointer to a `BootMeasurementsAbi` buffer
+/// - arg1: buffer length (must be >= `BootMeasurementsAbi::SIZE`)
+///
+/// Returns the number of bytes written; `NotFound` if no valid log was
+/// captured this boot.
+pub struct GetBootMeasurementsSyscall;
+
+impl SyscallHandler for GetBootMeasurementsSyscall {
+    fn handle(&self, ctx: &CurrentContext, args: &SyscallArgs) -> Result<u32, SyscallError> {
+        if (ctx.capabilities & caps::BOOT_MEASUREMENTS) == 0 {
+            return Err(SyscallError::PermissionDenied);
+        }
+
+        let ptr = args.arg_u64(0)? as usize;
+        let len = args.arg_u64(1)? as usize;
+        if len < BootMeasurementsAbi::SIZE {
+            return Err(SyscallError::Invalid);
+        }
+        if !validate_user_ptr(ptr, BootMeasurementsAbi::SIZE) {
+            return Err(SyscallError::BadAddress);
+        }
+
+        let log = crate::measured_boot::boot_log().ok_or(SyscallError::NotFound)?;
+        copy_to_user(ptr, &BootMeasurementsAbi::from(log).to_ne_bytes()).map_err(|_| SyscallError::BadAddress)?;
+
+        Ok(BootMeasurementsAbi::SIZE as u32)
+    }
+}
+
+// ---------------
+// Kernel primitives (stubs - platform-specific)
+// ---------------
 
 /// Example kernel time source (stub). Replace with RTC or clocksource.
 fn kernel_get_time_seconds() -> u32 {
@@ -190,32 +935,69 @@ fn kernel_get_time_seconds() -> u32 {
     1_700_000_000u32 // placeholder epoch-like value
 }
 
-/// IPC sending primitive (stub). Return Ok(()) on success.
-fn kernel_ipc_send(_dest: u32, _buf: &[u8]) -> Result<(), ()> {
-    // TODO: implement message queue / sockets / ports
-    Ok(())
+/// Memory accounting for the task owning `ctx`: its stack size and
+/// watermark (`stack_guard::watermark`) and its `scheduler::TaskMemory`.
+/// `None` if the task is not in the table.
+#[cfg(target_arch = "arm")]
+fn kernel_task_mem_info(ctx: &CurrentContext) -> Option<MemInfo> {
+    let (stack, memory) = crate::scheduler::task_memory(ctx.task)?;
+    Some(MemInfo::new(stack.as_ref(), &memory))
 }
 
-/// Securely copy memory from user address space into a kernel buffer.
-/// IMPORTANT: This is architecture- and MMU-specific. This stub MUST be replaced
-/// by a function that:
-///  - validates the user pointer is mapped and accessible,
-///  - disables preemption if necessary,
-///  - uses safe primitives for copy (e.g., copy_from_user on Linux),
-///  - returns Err on any memory fault or invalid mapping.
+/// Host builds have no scheduler, so no task to report on.
+#[cfg(not(target_arch = "arm"))]
+fn kernel_task_mem_info(_ctx: &CurrentContext) -> Option<MemInfo> {
+    None
+}
+
+/// Copy `dst.len()` bytes from user address `user_ptr` into a kernel
+/// buffer. Fails unless the whole range passes `validate_user_ptr`.
 fn copy_from_user(user_ptr: usize, dst: &mut [u8]) -> Result<(), ()> {
-    // STUB: in a real kernel this must not just do pointer casts.
-    // Replace with: verify user mapping, then memcopy with fault handling.
+    if !validate_user_ptr(user_ptr, dst.len()) {
+        return Err(());
+    }
+    // SAFETY: the range is mapped and accessible to the caller (checked
+    // above) and cannot overlap the kernel buffer `dst`, which the MPU
+    // does not give user code access to.
+    unsafe {
+        core::ptr::copy_nonoverlapping(user_ptr as *const u8, dst.as_mut_ptr(), dst.len());
+    }
+    Ok(())
+}
+
+/// Copy a kernel buffer out to user address `user_ptr`. Fails unless the
+/// whole range passes `validate_user_ptr`.
+fn copy_to_user(user_ptr: usize, src: &[u8]) -> Result<(), ()> {
+    if !validate_user_ptr(user_ptr, src.len()) {
+        return Err(());
+    }
+    // SAFETY: as for `copy_from_user`, with the roles swapped.
     unsafe {
-        let user_slice = core::slice::from_raw_parts(user_ptr as *const u8, dst.len());
-        dst.copy_from_slice(user_slice);
+        core::ptr::copy_nonoverlapping(src.as_ptr(), user_ptr as *mut u8, src.len());
     }
     Ok(())
 }
 
-/// Validate a user pointer / length (example stub).
-fn validate_user_ptr(_ptr: usize, _len: usize) -> bool {
-    // TODO: check that [ptr, ptr+len) is in user-space portion and mapped
+/// Check that `[ptr, ptr + len)` is memory unprivileged code may read and
+/// write: not null, not wrapping around, and granted by the MPU.
+fn validate_user_ptr(ptr: usize, len: usize) -> bool {
+    if len == 0 {
+        return true;
+    }
+    ptr != 0 && ptr.checked_add(len).is_some() && user_accessible(ptr, len)
+}
+
+#[cfg(target_arch = "arm")]
+fn user_accessible(ptr: usize, len: usize) -> bool {
+    match (u32::try_from(ptr), u32::try_from(len)) {
+        (Ok(ptr), Ok(len)) => cortex_m::interrupt::free(|_| crate::regs::mpu::user_accessible(ptr, len)),
+        _ => false,
+    }
+}
+
+/// Host builds (C API tests) have no MPU: any non-null range is accepted.
+#[cfg(not(target_arch = "arm"))]
+fn user_accessible(_ptr: usize, _len: usize) -> bool {
     true
 }
 
@@ -228,14 +1010,12 @@ fn validate_user_ptr(_ptr: usize, _len: usize) -> bool {
 ///
 /// The entrypoint:
 /// - constructs `SyscallArgs`,
-/// - resolves `SyscallId`,
 /// - obtains `CurrentContext`,
-/// - calls dispatcher,
+/// - dispatches to a built-in `SyscallId` or a registered syscall,
 /// - returns encoded `u32` result (success or error-encoded).
 ///
 /// NOTE: On 64-bit platforms the architecture will usually pass arguments in
 /// registers (e.g., rdi/rsi/...); ensure your syscall trampoline forwards them.
-
 #[no_mangle]
 pub extern "C" fn syscall_entry(
     raw_id: u32,
@@ -251,17 +1031,18 @@ pub extern "C" fn syscall_entry(
     // In many ABIs the number of args isn't passed; we assume maximum and handlers check
     let args = SyscallArgs { args: all_args, nargs: MAX_SYSCALL_ARGS };
 
-    // Resolve syscall id
-    let id = match SyscallId::try_from(raw_id) {
-        Ok(id) => id,
-        Err(_) => return encode_syscall_result(Err(SyscallError::Invalid)),
-    };
-
     // Fetch current context (implement per-kernel)
     let ctx = current_context();
 
-    // Dispatch
-    let res = dispatch_syscall(id, &ctx, &args);
+    // Dispatch: built-in syscalls first, then the registered ones
+    let res = match SyscallId::try_from(raw_id) {
+        Ok(id) => dispatch_syscall(id, &ctx, &args),
+        Err(_) => registered_syscalls()
+            .map_or(Err(SyscallError::Invalid), |table| table.dispatch(raw_id, &ctx, &args)),
+    };
+    if res == Err(SyscallError::PermissionDenied) {
+        let _ = crate::runtime_monitor::report(ctx.task, crate::runtime_monitor::Anomaly::SyscallDenied);
+    }
 
     // Encode result for userland
     encode_syscall_result(res)
@@ -286,9 +1067,185 @@ mod tests {
 
     #[test]
     fn get_time_via_dispatch() {
-        let ctx = CurrentContext { uid: 0, capabilities: caps::SYS_TIME };
+        let ctx = CurrentContext { uid: 0, task: 0, capabilities: caps::SYS_TIME };
         let args = SyscallArgs { args: [0; MAX_SYSCALL_ARGS], nargs: 0 };
         let r = dispatch_syscall(SyscallId::GetTime, &ctx, &args);
         assert!(r.is_ok());
     }
+
+    #[test]
+    fn mem_info_reports_the_task_stack_and_accounting() {
+        let mut stack = [0u32; 64];
+        let (sp, bounds) = crate::context::init_checked_stack(&mut stack, 0x0800_0001, 0x0800_0001, 0).unwrap();
+        let task = crate::context::Task { id: 1, privilege: 1, stack_pointer: sp, capabilities: 0, stack: Some(bounds) };
+        let memory = TaskMemory { heap_quota: 4096, heap_used: 512, shm_grants: 1, shm_bytes: 256 };
+
+        let info = MemInfo::new(task.stack.as_ref(), &memory);
+        let frame = bounds.top - sp as usize;
+        assert_eq!((info.stack_size, info.stack_high_watermark), (256, frame as u32), "initial frame only");
+        assert_eq!((info.heap_quota, info.heap_used, info.shm_grants, info.shm_bytes), (4096, 512, 1, 256));
+
+        // The task digs deeper into its stack
+        // SAFETY: a word inside `stack`, which is still alive.
+        unsafe { (bounds.top as *mut u32).sub(40).write(0) };
+        assert_eq!(MemInfo::new(Some(&bounds), &memory).stack_high_watermark, 160);
+        let bytes = MemInfo::new(Some(&bounds), &memory).to_ne_bytes();
+        assert_eq!(u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]), 256);
+    }
+
+    #[test]
+    fn get_mem_info_rejects_bad_requests() {
+        let ctx = CurrentContext { uid: 0, task: 0, capabilities: caps::MEM_INFO };
+        let mut out = [0u8; MemInfo::SIZE];
+        let mut args = SyscallArgs { args: [0; MAX_SYSCALL_ARGS], nargs: 2 };
+        args.args[0] = out.as_mut_ptr() as u64;
+        args.args[1] = out.len() as u64;
+
+        // No scheduler on the host, hence no calling task
+        assert_eq!(dispatch_syscall(SyscallId::GetMemInfo, &ctx, &args), Err(SyscallError::NotFound));
+        args.args[0] = 0;
+        assert_eq!(dispatch_syscall(SyscallId::GetMemInfo, &ctx, &args), Err(SyscallError::BadAddress));
+
+        // Short buffer and missing capability are rejected
+        args.args[0] = out.as_mut_ptr() as u64;
+        args.args[1] = 4;
+        assert_eq!(dispatch_syscall(SyscallId::GetMemInfo, &ctx, &args), Err(SyscallError::Invalid));
+        let no_cap = CurrentContext { uid: 0, task: 0, capabilities: caps::SYS_TIME };
+        assert_eq!(dispatch_syscall(SyscallId::GetMemInfo, &no_cap, &args), Err(SyscallError::PermissionDenied));
+    }
+
+    #[test]
+    fn task_cpu_stats_layout() {
+        let s = crate::scheduler::TaskStats { id: 0x101, run_ticks: (2 << 32) | 5, switches: 3, utilization_permille: 125 };
+        let bytes = TaskCpuStats::from(s).to_ne_bytes();
+        let word = |i: usize| u32::from_ne_bytes([bytes[i * 4], bytes[i * 4 + 1], bytes[i * 4 + 2], bytes[i * 4 + 3]]);
+        assert_eq!(TaskCpuStats::SIZE, 20);
+        assert_eq!([word(0), word(1), word(2), word(3), word(4)], [0x101, 3, 5, 2, 125]);
+
+        // Rejected before the scheduler is consulted
+        let ctx = CurrentContext { uid: 0, task: 0, capabilities: caps::TASK_STATS };
+        let args = SyscallArgs { args: [0x2000_0000, 4, 0, 0, 0, 0], nargs: 2 };
+        assert_eq!(dispatch_syscall(SyscallId::GetTaskStats, &ctx, &args), Err(SyscallError::Invalid));
+        let no_cap = CurrentContext { uid: 0, task: 0, capabilities: caps::MEM_INFO };
+        assert_eq!(dispatch_syscall(SyscallId::GetTaskStats, &no_cap, &args), Err(SyscallError::PermissionDenied));
+    }
+
+    #[test]
+    fn firmware_info_layout_and_checks() {
+        let info = FirmwareInfoAbi { build_id: [0xAB; crate::image::BUILD_ID_LEN], version: [1, 2, 3], flags: 1 };
+        let bytes = info.to_ne_bytes();
+        assert_eq!(FirmwareInfoAbi::SIZE, 28);
+        assert_eq!(&bytes[..20], &[0xAB; 20]);
+        assert_eq!(&bytes[20..], &[1u16.to_ne_bytes(), 2u16.to_ne_bytes(), 3u16.to_ne_bytes(), 1u16.to_ne_bytes()].concat()[..]);
+        assert_eq!(FirmwareInfoAbi::from(crate::image::firmware_info()).build_id, crate::image::BUILD_ID);
+
+        let ctx = CurrentContext { uid: 0, task: 0, capabilities: caps::FIRMWARE_INFO };
+        let short = SyscallArgs { args: [0x2000_0000, 8, 0, 0, 0, 0], nargs: 2 };
+        assert_eq!(dispatch_syscall(SyscallId::GetFirmwareInfo, &ctx, &short), Err(SyscallError::Invalid));
+        let no_cap = CurrentContext { uid: 0, task: 0, capabilities: caps::MEM_INFO };
+        assert_eq!(dispatch_syscall(SyscallId::GetFirmwareInfo, &no_cap, &short), Err(SyscallError::PermissionDenied));
+    }
+
+    #[test]
+    fn heap_stats_come_from_the_installed_source() {
+        let ctx = CurrentContext { uid: 0, task: 0, capabilities: caps::HEAP_STATS };
+        let mut out = [0u8; HeapInfo::SIZE];
+        let args = SyscallArgs { args: [out.as_mut_ptr() as u64, out.len() as u64, 0, 0, 0, 0], nargs: 2 };
+        let no_cap = CurrentContext { uid: 0, task: 0, capabilities: caps::MEM_INFO };
+        assert_eq!(dispatch_syscall(SyscallId::GetHeapStats, &no_cap, &args), Err(SyscallError::PermissionDenied));
+
+        set_heap_info_source(|| HeapInfo { total: 16384, used: 512, peak: 4096, fragmentation_pct: 7, ..HeapInfo::default() });
+        assert_eq!(dispatch_syscall(SyscallId::GetHeapStats, &ctx, &args), Ok(32));
+        let word = |i: usize| u32::from_ne_bytes([out[i * 4], out[i * 4 + 1], out[i * 4 + 2], out[i * 4 + 3]]);
+        assert_eq!([word(0), word(1), word(2), word(7)], [16384, 512, 4096, 7]);
+    }
+
+    #[test]
+    fn boot_measurements_layout_and_checks() {
+        use crate::measured_boot::{extend, BootLog, LOG_MAGIC, LOG_VERSION, MAX_LOG_LEN};
+        let mut raw = [0u8; MAX_LOG_LEN];
+        raw[..8].copy_from_slice(&LOG_MAGIC);
+        raw[8] = LOG_VERSION;
+        raw[9] = 1;
+        raw[12..44].copy_from_slice(&extend(&[0; 32], &[0x5A; 32]));
+        raw[44] = 2;
+        raw[48..80].copy_from_slice(&[0x5A; 32]);
+        let bytes = BootMeasurementsAbi::from(&BootLog::parse(&raw).unwrap()).to_ne_bytes();
+        assert_eq!(BootMeasurementsAbi::SIZE, 180);
+        assert_eq!(&bytes[..32], &raw[12..44]);
+        assert_eq!(&bytes[32..40], &[1u32.to_ne_bytes(), 2u32.to_ne_bytes()].concat()[..]);
+        assert_eq!(&bytes[40..72], &[0x5A; 32]);
+        assert_eq!(&bytes[72..], &[0; 108]);
+
+        let ctx = CurrentContext { uid: 0, task: 0, capabilities: caps::BOOT_MEASUREMENTS };
+        let short = SyscallArgs { args: [0x2000_0000, 32, 0, 0, 0, 0], nargs: 2 };
+        assert_eq!(dispatch_syscall(SyscallId::GetBootMeasurements, &ctx, &short), Err(SyscallError::Invalid));
+        let no_cap = CurrentContext { uid: 0, task: 0, capabilities: caps::FIRMWARE_INFO };
+        assert_eq!(dispatch_syscall(SyscallId::GetBootMeasurements, &no_cap, &short), Err(SyscallError::PermissionDenied));
+    }
+
+    #[test]
+    fn queue_syscalls_roundtrip() {
+        let owner = CurrentContext { uid: 0, task: 11, capabilities: caps::CREATE_QUEUE | caps::RECV_MESSAGE };
+        let sender = CurrentContext { uid: 0, task: 12, capabilities: caps::SEND_MESSAGE | caps::RECV_MESSAGE };
+        let none = SyscallArgs { args: [0; MAX_SYSCALL_ARGS], nargs: 0 };
+        let q = dispatch_syscall(SyscallId::CreateQueue, &owner, &none).unwrap();
+        assert_eq!(dispatch_syscall(SyscallId::CreateQueue, &sender, &none), Err(SyscallError::PermissionDenied));
+
+        let msg = *b"hello";
+        let send = SyscallArgs { args: [msg.as_ptr() as u64, 5, q as u64, 0, 0, 0], nargs: 3 };
+        assert_eq!(dispatch_syscall(SyscallId::SendMessage, &sender, &send), Ok(0));
+        assert_eq!(dispatch_syscall(SyscallId::SendMessage, &owner, &send), Err(SyscallError::PermissionDenied));
+
+        let mut out = [0u8; MAX_MESSAGE_LEN];
+        let recv = SyscallArgs { args: [out.as_mut_ptr() as u64, q as u64, 0, 0, 0, 0], nargs: 3 };
+        assert_eq!(dispatch_syscall(SyscallId::ReceiveMessage, &sender, &recv), Err(SyscallError::PermissionDenied), "not the owner");
+        assert_eq!(dispatch_syscall(SyscallId::ReceiveMessage, &owner, &recv), Ok(5));
+        assert_eq!(&out[..5], b"hello");
+        assert_eq!(dispatch_syscall(SyscallId::ReceiveMessage, &owner, &recv), Err(SyscallError::TimedOut));
+        ipc_queue::release_task(11);
+    }
+
+    #[test]
+    fn delegate_caps_is_privileged() {
+        let args = SyscallArgs { args: [1, caps::SYS_TIME as u64, CAPS_GRANT as u64, 0, 0, 0], nargs: 3 };
+        let user = CurrentContext { uid: 0, task: 2, capabilities: caps::SYS_TIME | caps::SEND_MESSAGE };
+        assert_eq!(dispatch_syscall(SyscallId::DelegateCaps, &user, &args), Err(SyscallError::PermissionDenied));
+
+        let sup = CurrentContext { uid: 0, task: 1, capabilities: caps::DELEGATE };
+        let bad_op = SyscallArgs { args: [2, 0, 7, 0, 0, 0], nargs: 3 };
+        assert_eq!(dispatch_syscall(SyscallId::DelegateCaps, &sup, &bad_op), Err(SyscallError::Invalid));
+    }
+
+    struct Echo;
+
+    impl SyscallHandler for Echo {
+        fn handle(&self, _ctx: &CurrentContext, args: &SyscallArgs) -> Result<u32, SyscallError> {
+            args.arg_u32(0)
+        }
+    }
+
+    static ECHO: Echo = Echo;
+
+    #[test]
+    fn registered_syscalls_dispatch_with_capability_check() {
+        const CAP_ECHO: u32 = 1 << 16;
+        let mut table = SyscallTable::new();
+        table.register(0x40, CAP_ECHO, &ECHO).unwrap();
+        assert_eq!(table.register(0x40, 0, &ECHO), Err(RegisterError::Duplicate));
+        assert_eq!(table.register(SyscallId::GetTime as u32, 0, &ECHO), Err(RegisterError::Reserved));
+        for id in 0x41..0x40 + MAX_REGISTERED_SYSCALLS as u32 {
+            table.register(id, 0, &ECHO).unwrap();
+        }
+        assert_eq!(table.register(0x100, 0, &ECHO), Err(RegisterError::Full));
+        assert_eq!(table.entries().count(), MAX_REGISTERED_SYSCALLS);
+
+        let args = SyscallArgs { args: [7, 0, 0, 0, 0, 0], nargs: 1 };
+        let ctx = CurrentContext { uid: 0, task: 0, capabilities: CAP_ECHO };
+        assert_eq!(table.dispatch(0x40, &ctx, &args), Ok(7));
+        let no_cap = CurrentContext { uid: 0, task: 0, capabilities: caps::SYS_TIME };
+        assert_eq!(table.dispatch(0x40, &no_cap, &args), Err(SyscallError::PermissionDenied));
+        assert_eq!(table.dispatch(0x41, &no_cap, &args), Ok(7));
+        assert_eq!(table.dispatch(0x200, &ctx, &args), Err(SyscallError::Invalid));
+    }
 }
@@ -0,0 +1,202 @@
+//! SecureIoTOS Kernel Task Table Module
+//! ------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author : Md Mahbubur Rahman
+//! URL    : https://m-a-h-b-u-b.github.io
+//! GitHub : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Fixed-capacity task storage without heap.
+//!
+//! `TaskTable<MAX_TASKS>` is an array of slots sized at compile time; a
+//! capacity of 0 or above `MAX_SLOTS` fails the build. Task IDs encode
+//! their slot, so lookup by ID is O(1):
+//!
+//! ```text
+//!   id = generation << 8 | slot
+//! ```
+//!
+//! The slot's generation is bumped when its task is removed, so the ID of
+//! a deleted task never finds the task that reuses the slot.
+
+// Core kernel path: must not panic (see `tools/no-panic-check`).
+#![cfg_attr(not(test), deny(
+    clippy::panic,
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::indexing_slicing,
+    clippy::unreachable,
+    clippy::todo,
+    clippy::unimplemented
+))]
+
+use crate::context::Task;
+
+/// Largest supported capacity (slot index is the low byte of the ID).
+pub const MAX_SLOTS: usize = 256;
+
+const SLOT_BITS: u32 = 8;
+const SLOT_MASK: u32 = (1 << SLOT_BITS) - 1;
+
+/// Why a task could not be added.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskTableError {
+    /// All `MAX_TASKS` slots are taken
+    Full,
+}
+
+/// Task storage with room for `MAX_TASKS` tasks.
+pub struct TaskTable<const MAX_TASKS: usize> {
+    slots: [Option<Task>; MAX_TASKS],
+    generations: [u32; MAX_TASKS],
+    len: usize,
+}
+
+impl<const MAX_TASKS: usize> TaskTable<MAX_TASKS> {
+    /// Evaluated by `new()`: an unusable capacity is a compile error.
+    const CAPACITY_OK: () = assert!(MAX_TASKS > 0 && MAX_TASKS <= MAX_SLOTS, "TaskTable capacity must be 1..=256");
+
+    pub const fn new() -> Self {
+        let () = Self::CAPACITY_OK;
+        Self { slots: [const { None }; MAX_TASKS], generations: [1; MAX_TASKS], len: 0 }
+    }
+
+    pub const fn capacity(&self) -> usize {
+        MAX_TASKS
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == MAX_TASKS
+    }
+
+    /// Store `task` in a free slot and give it its ID (overwriting
+    /// `task.id`), which is returned.
+    pub fn insert(&mut self, mut task: Task) -> Result<u32, TaskTableError> {
+        let (slot, entry) = self
+            .slots
+            .iter_mut()
+            .enumerate()
+            .find(|(_, e)| e.is_none())
+            .ok_or(TaskTableError::Full)?;
+        let generation = self.generations.get(slot).copied().unwrap_or(0);
+        let id = (generation << SLOT_BITS) | slot as u32;
+        task.id = id;
+        *entry = Some(task);
+        self.len += 1;
+        Ok(id)
+    }
+
+    /// Remove task `id`; its slot can be reused under a new ID.
+    pub fn remove(&mut self, id: u32) -> Option<Task> {
+        let slot = self.slot_of(id)?;
+        let task = self.slots.get_mut(slot)?.take()?;
+        if let Some(g) = self.generations.get_mut(slot) {
+            // Generation 0 is skipped so no ID is ever 0..=255.
+            *g = (g.wrapping_add(1) & (u32::MAX >> SLOT_BITS)).max(1);
+        }
+        self.len -= 1;
+        Some(task)
+    }
+
+    pub fn get(&self, id: u32) -> Option<&Task> {
+        self.slots.get(self.slot_of(id)?)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, id: u32) -> Option<&mut Task> {
+        let slot = self.slot_of(id)?;
+        self.slots.get_mut(slot)?.as_mut()
+    }
+
+    /// Task in slot `slot`, if any.
+    pub fn at(&self, slot: usize) -> Option<&Task> {
+        self.slots.get(slot)?.as_ref()
+    }
+
+    pub fn at_mut(&mut self, slot: usize) -> Option<&mut Task> {
+        self.slots.get_mut(slot)?.as_mut()
+    }
+
+    /// Slot of task `id`, if `id` is live.
+    pub fn slot_of(&self, id: u32) -> Option<usize> {
+        let slot = (id & SLOT_MASK) as usize;
+        let live = self.slots.get(slot)?.as_ref()?.id == id;
+        live.then_some(slot)
+    }
+
+    /// First occupied slot after `slot` in round-robin order, wrapping
+    /// around; `slot` itself is returned last.
+    pub fn next_after(&self, slot: usize) -> Option<usize> {
+        (1..=MAX_TASKS).map(|step| (slot + step) % MAX_TASKS).find(|&i| self.at(i).is_some())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Task> {
+        self.slots.iter().flatten()
+    }
+}
+
+impl<const MAX_TASKS: usize> Default for TaskTable<MAX_TASKS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task() -> Task {
+        Task { id: 0, privilege: 1, stack_pointer: core::ptr::null_mut(), capabilities: 0, stack: None }
+    }
+
+    #[test]
+    fn insert_lookup_and_capacity() {
+        let mut t = TaskTable::<3>::new();
+        assert_eq!(t.capacity(), 3);
+        let ids: [u32; 3] = core::array::from_fn(|_| t.insert(task()).unwrap());
+        assert!(t.is_full());
+        assert_eq!(t.insert(task()), Err(TaskTableError::Full));
+
+        for (slot, &id) in ids.iter().enumerate() {
+            assert_eq!(t.slot_of(id), Some(slot));
+            assert_eq!(t.get(id).map(|t| t.id), Some(id));
+        }
+        assert_eq!(t.iter().count(), 3);
+    }
+
+    #[test]
+    fn stale_ids_do_not_find_reused_slots() {
+        let mut t = TaskTable::<2>::new();
+        let a = t.insert(task()).unwrap();
+        assert!(t.remove(a).is_some());
+        assert!(t.remove(a).is_none());
+
+        let b = t.insert(task()).unwrap();
+        assert_eq!(t.slot_of(b), Some(0), "slot is reused");
+        assert_ne!(a, b);
+        assert!(t.get(a).is_none());
+        assert!(b > SLOT_MASK, "IDs are never plain slot numbers");
+    }
+
+    #[test]
+    fn round_robin_skips_empty_slots() {
+        let mut t = TaskTable::<4>::new();
+        let ids: [u32; 4] = core::array::from_fn(|_| t.insert(task()).unwrap());
+        t.remove(ids[1]);
+        t.remove(ids[2]);
+        assert_eq!(t.next_after(0), Some(3));
+        assert_eq!(t.next_after(3), Some(0));
+        t.remove(ids[3]);
+        assert_eq!(t.next_after(0), Some(0), "only task runs again");
+        t.remove(ids[0]);
+        assert_eq!(t.next_after(0), None);
+    }
+}
@@ -0,0 +1,189 @@
+//! SecureIoTOS Kernel Tickless Idle Module
+//! ---------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author : Md Mahbubur Rahman
+//! URL    : https://m-a-h-b-u-b.github.io
+//! GitHub : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Tickless idle: instead of waking `TICK_HZ` times a second with nothing
+//! to do, `idle()` stretches one SysTick period over all the ticks until
+//! the next thing that needs the CPU, sleeps, and then catches the tick
+//! counter up.
+//!
+//! The next event is the earlier of the next armed wakeup
+//! (`time::next_wakeup()`) and the next periodic job release
+//! (`scheduler::next_release()`); interrupts end the sleep early. A single
+//! stretched period is limited by the 24-bit SysTick reload, about 99 ms
+//! at 168 MHz; longer idle phases take several of them.
+//!
+//! ```text
+//!   tick grid:   |  now  |       |       |  ...  |  now + n
+//!   SysTick:     [rem ][ (n - 1) * period                 ]
+//! ```
+//!
+//! On wakeup, `settle()` works out how many whole ticks passed from the
+//! counter value and restarts SysTick for the rest of the current tick,
+//! so the tick grid does not drift. If the stretched period ran out, its
+//! SysTick exception is still pending and counts the last tick itself.
+//!
+//! `idle()` is enabled by the `tickless` feature, where `time`'s idle wait
+//! uses it; platforms call it from their idle loop. Ticks slept through
+//! are charged to no task in the scheduler statistics.
+
+// Core kernel path: must not panic (see `tools/no-panic-check`).
+#![cfg_attr(not(test), deny(
+    clippy::panic,
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::indexing_slicing,
+    clippy::unreachable,
+    clippy::todo,
+    clippy::unimplemented
+))]
+
+/// Shortest idle phase worth reprogramming SysTick for, in ticks.
+pub const MIN_IDLE_TICKS: u32 = 2;
+
+/// A stretched SysTick period.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IdlePlan {
+    /// Tick boundaries the period spans (it ends on the last one)
+    pub ticks: u32,
+    /// Length of the period in SysTick cycles
+    pub cycles: u32,
+}
+
+/// Where the tick grid stands after waking up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Settled {
+    /// Whole ticks to add to the counter
+    pub ticks: u32,
+    /// Cycles from now to the next tick boundary
+    pub next_tick_in: u32,
+}
+
+/// Plan an idle phase at tick `now` with `remaining` cycles left in the
+/// current tick, until `deadline` (`None`: no timed event). `period` is
+/// cycles per tick; `max_cycles` the longest SysTick period. `None` if
+/// the next event is too close to be worth it.
+pub fn plan(now: u32, deadline: Option<u32>, remaining: u32, period: u32, max_cycles: u32) -> Option<IdlePlan> {
+    if period == 0 || remaining > max_cycles {
+        return None;
+    }
+    let wanted = match deadline {
+        Some(d) => (d.wrapping_sub(now) as i32).max(0) as u32,
+        None => u32::MAX,
+    };
+    let fits = (max_cycles - remaining) / period + 1;
+    let ticks = wanted.min(fits);
+    if ticks < MIN_IDLE_TICKS {
+        return None;
+    }
+    Some(IdlePlan { ticks, cycles: remaining + (ticks - 1) * period })
+}
+
+/// Account for an idle phase that ended with `current` cycles left on the
+/// counter. `expired`: the stretched period ran out (its pending SysTick
+/// exception will add the last tick) and the counter has since restarted
+/// from `plan.cycles`.
+pub fn settle(plan: &IdlePlan, expired: bool, current: u32, period: u32) -> Settled {
+    let period = period.max(1);
+    if expired {
+        let since = plan.cycles.saturating_sub(current);
+        return Settled {
+            ticks: plan.ticks - 1 + since / period,
+            next_tick_in: period - since % period,
+        };
+    }
+    // The period ends on a tick boundary; count the ones still ahead.
+    let ahead = current.div_ceil(period);
+    let next = current % period;
+    Settled {
+        ticks: plan.ticks.saturating_sub(ahead.max(1)),
+        next_tick_in: if next == 0 { period } else { next },
+    }
+}
+
+/// Sleep until the next timed event or interrupt with the tick stopped,
+/// then bring `time` up to date. Falls back to a plain `wfi` when the
+/// next event is due within `MIN_IDLE_TICKS`.
+#[cfg(target_arch = "arm")]
+pub fn idle() {
+    use crate::regs::syst;
+    use crate::time;
+
+    cortex_m::interrupt::free(|_| {
+        let period = syst::reload() + 1;
+        let now = time::ticks();
+        let deadline = [time::next_wakeup(), crate::scheduler::next_release()]
+            .into_iter()
+            .flatten()
+            .min_by_key(|&d| (d.wrapping_sub(now) as i32).max(0));
+        let Some(plan) = plan(now, deadline, syst::current(), period, syst::MAX_RELOAD + 1) else {
+            cortex_m::asm::wfi();
+            return;
+        };
+
+        syst::disable();
+        syst::set_reload(plan.cycles - 1);
+        syst::clear_current();
+        syst::enable_with_interrupt();
+        // PRIMASK is set: a pending interrupt ends `wfi` but is only
+        // taken once we leave the critical section, after the catch-up.
+        cortex_m::asm::wfi();
+        let current = syst::current();
+        let expired = syst::has_wrapped();
+        syst::disable();
+
+        let settled = settle(&plan, expired, current, period);
+        // Reload for the rest of this tick, then back to whole ticks
+        syst::set_reload(settled.next_tick_in.max(2) - 1);
+        syst::clear_current();
+        syst::enable_with_interrupt();
+        syst::set_reload(period - 1);
+
+        time::advance(settled.ticks);
+        crate::scheduler::account_idle(settled.ticks);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD: u32 = 1000;
+    const MAX: u32 = 1 << 24;
+
+    #[test]
+    fn plan_stops_at_the_next_event_or_reload_limit() {
+        let p = plan(100, Some(110), 400, PERIOD, MAX).unwrap();
+        assert_eq!(p, IdlePlan { ticks: 10, cycles: 400 + 9 * PERIOD });
+
+        let far = plan(100, None, 400, PERIOD, MAX).unwrap();
+        assert!(far.cycles <= MAX);
+        assert_eq!(far.ticks, (MAX - 400) / PERIOD + 1);
+
+        assert_eq!(plan(100, Some(101), 400, PERIOD, MAX), None, "next tick");
+        assert_eq!(plan(100, Some(90), 400, PERIOD, MAX), None, "already due");
+        assert_eq!(plan(u32::MAX - 1, Some(3), 400, PERIOD, MAX).map(|p| p.ticks), Some(5), "across the wrap");
+    }
+
+    #[test]
+    fn settle_keeps_the_tick_grid() {
+        let p = plan(0, Some(10), 400, PERIOD, MAX).unwrap();
+
+        // Ran to the end; pending SysTick adds tick 10
+        let s = settle(&p, true, p.cycles, PERIOD);
+        assert_eq!(s, Settled { ticks: 9, next_tick_in: PERIOD });
+
+        // Woken 2500 cycles in: boundaries at 400, 1400, 2400 passed
+        let s = settle(&p, false, p.cycles - 2500, PERIOD);
+        assert_eq!(s, Settled { ticks: 3, next_tick_in: 900 });
+
+        // Woken before the first boundary
+        let s = settle(&p, false, p.cycles - 100, PERIOD);
+        assert_eq!(s, Settled { ticks: 0, next_tick_in: 300 });
+    }
+}
@@ -0,0 +1,299 @@
+//! SecureIoTOS Kernel Time Module
+//! ------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author : Md Mahbubur Rahman
+//! URL    : https://m-a-h-b-u-b.github.io
+//! GitHub : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Monotonic system time and task sleeping.
+//!
+//! The SysTick handler calls `on_tick()` every `1 / TICK_HZ` seconds. It
+//! advances a 64-bit tick counter and wakes every task whose sleep
+//! deadline has passed. `sleep_ms()` / `delay_until()` park the calling
+//! task through the scheduler hooks in `ipc::wait` instead of spinning;
+//! before a scheduler is installed they idle in `wfi` until the next tick.
+//!
+//! `ticks()` is the low 32 bits of the counter and wraps after ~49 days at
+//! 1 kHz; compare tick values with `reached()`, never with `<`.
+//!
+//! Reading time and arming/cancelling wakeups is ISR-safe; the sleep and
+//! delay calls are task-only (`ipc::isr`).
+//!
+//! With the `tickless` feature, idle waits go through `tickless::idle()`,
+//! which stops the tick until the next wakeup (`next_wakeup()`) and
+//! catches the counter up with `advance()` afterwards.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use ipc::wait::{parker, TaskId};
+
+/// SysTick frequency programmed by `init::kernel_init()`.
+pub const TICK_HZ: u32 = 1000;
+
+/// Maximum number of tasks sleeping at the same time.
+pub const MAX_SLEEPERS: usize = 8;
+
+/// Tick counter, low and high words. Only `on_tick()` writes them.
+static TICKS_LO: AtomicU32 = AtomicU32::new(0);
+static TICKS_HI: AtomicU32 = AtomicU32::new(0);
+
+const NO_TASK: u32 = 0;
+const RESERVED: u32 = u32::MAX;
+
+/// A task waiting for `deadline` (`tag` = task id + 1).
+struct Sleeper {
+    tag: AtomicU32,
+    deadline: AtomicU32,
+}
+
+static SLEEPERS: [Sleeper; MAX_SLEEPERS] =
+    [const { Sleeper { tag: AtomicU32::new(NO_TASK), deadline: AtomicU32::new(0) } }; MAX_SLEEPERS];
+
+/// Current tick count (low 32 bits, wrapping).
+#[inline]
+pub fn ticks() -> u32 {
+    TICKS_LO.load(Ordering::Acquire)
+}
+
+/// Ticks since boot, without wrap-around.
+pub fn uptime_ticks() -> u64 {
+    loop {
+        let hi = TICKS_HI.load(Ordering::Acquire);
+        let lo = TICKS_LO.load(Ordering::Acquire);
+        // A tick between the two reads may have carried into `hi`.
+        if TICKS_HI.load(Ordering::Acquire) == hi {
+            return ((hi as u64) << 32) | lo as u64;
+        }
+    }
+}
+
+/// Milliseconds since boot.
+pub fn uptime_ms() -> u64 {
+    uptime_ticks() * 1000 / TICK_HZ as u64
+}
+
+/// Convert milliseconds to ticks, rounding up so a sleep never ends early.
+pub const fn ms_to_ticks(ms: u32) -> u32 {
+    let t = (ms as u64 * TICK_HZ as u64).div_ceil(1000);
+    if t > u32::MAX as u64 {
+        u32::MAX
+    } else {
+        t as u32
+    }
+}
+
+/// True once tick count `now` is at or past `deadline` (wrap-safe for
+/// deadlines less than 2^31 ticks away).
+#[inline]
+pub const fn reached(now: u32, deadline: u32) -> bool {
+    now.wrapping_sub(deadline) as i32 >= 0
+}
+
+/// Advance time by one tick and wake expired sleepers. Called from the
+/// SysTick handler only.
+pub fn on_tick() {
+    advance(1);
+}
+
+/// Advance time by `n` ticks at once and wake expired sleepers. Used by
+/// tickless idle for the ticks it slept through; same context rules as
+/// `on_tick()`.
+pub fn advance(n: u32) {
+    if n == 0 {
+        return;
+    }
+    let old = TICKS_LO.load(Ordering::Relaxed);
+    let lo = old.wrapping_add(n);
+    if lo < old {
+        TICKS_HI.fetch_add(1, Ordering::AcqRel);
+    }
+    TICKS_LO.store(lo, Ordering::Release);
+
+    for s in SLEEPERS.iter() {
+        let tag = s.tag.load(Ordering::Acquire);
+        if tag == NO_TASK || tag == RESERVED || !reached(lo, s.deadline.load(Ordering::Relaxed)) {
+            continue;
+        }
+        if s.tag.compare_exchange(tag, NO_TASK, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+            if let Some(h) = parker() {
+                (h.unpark)(tag.wrapping_sub(1));
+            }
+        }
+    }
+}
+
+/// SysTick exception: advance time, charge the tick to the running task,
+/// run the watchdog and runtime monitor services, then request a context
+/// switch for round-robin time slicing.
+#[no_mangle]
+pub extern "C" fn SysTick() {
+    on_tick();
+    crate::scheduler::account_tick();
+    crate::watchdog::service();
+    crate::runtime_monitor::service();
+    crate::scheduler::schedule();
+}
+
+/// Ask `on_tick()` to unpark `task` once `deadline` is reached. Returns
+/// `false` if all `MAX_SLEEPERS` slots are taken.
+///
+/// Used by `sleep_until()` and by other blocking calls that need a
+/// timeout (e.g. the FreeRTOS shim); pair with `cancel_wakeup()`.
+pub fn arm_wakeup(task: TaskId, deadline: u32) -> bool {
+    let tag = task.wrapping_add(1);
+    SLEEPERS.iter().any(|s| {
+        if s.tag.compare_exchange(NO_TASK, RESERVED, Ordering::AcqRel, Ordering::Relaxed).is_err() {
+            return false;
+        }
+        s.deadline.store(deadline, Ordering::Relaxed);
+        s.tag.store(tag, Ordering::Release);
+        true
+    })
+}
+
+/// Drop a pending wakeup for `task` (no-op if it already fired).
+pub fn cancel_wakeup(task: TaskId) {
+    let tag = task.wrapping_add(1);
+    for s in SLEEPERS.iter() {
+        let _ = s.tag.compare_exchange(tag, NO_TASK, Ordering::AcqRel, Ordering::Relaxed);
+    }
+}
+
+/// Earliest armed wakeup deadline, if any task is waiting for one.
+/// ISR-safe.
+pub fn next_wakeup() -> Option<u32> {
+    let now = ticks();
+    SLEEPERS
+        .iter()
+        .filter(|s| !matches!(s.tag.load(Ordering::Acquire), NO_TASK | RESERVED))
+        .map(|s| s.deadline.load(Ordering::Relaxed))
+        .min_by_key(|&d| (d.wrapping_sub(now) as i32).max(0))
+}
+
+/// Wait for the next interrupt (normally the next tick).
+#[inline]
+fn idle() {
+    #[cfg(all(target_arch = "arm", feature = "tickless"))]
+    crate::tickless::idle();
+    #[cfg(all(target_arch = "arm", not(feature = "tickless")))]
+    cortex_m::asm::wfi();
+    #[cfg(not(target_arch = "arm"))]
+    core::hint::spin_loop();
+}
+
+/// Block the calling task until tick `deadline`. Task-only.
+pub fn sleep_until(deadline: u32) {
+    ipc::isr::assert_task_context("sleep_until");
+    while !reached(ticks(), deadline) {
+        let Some(h) = parker() else {
+            idle();
+            continue;
+        };
+        let me = (h.current)();
+        if !arm_wakeup(me, deadline) {
+            // Sleeper table full: fall back to idling tick by tick.
+            idle();
+            continue;
+        }
+        // Re-check after arming: a tick may have expired us meanwhile.
+        if !reached(ticks(), deadline) {
+            (h.park)();
+        }
+        cancel_wakeup(me);
+    }
+}
+
+/// Block the calling task for at least `n` ticks (0 returns at once).
+/// Task-only.
+pub fn sleep_ticks(n: u32) {
+    ipc::isr::assert_task_context("sleep_ticks");
+    if n > 0 {
+        sleep_until(ticks().wrapping_add(n));
+    }
+}
+
+/// Block the calling task for at least `ms` milliseconds. Task-only.
+pub fn sleep_ms(ms: u32) {
+    sleep_ticks(ms_to_ticks(ms));
+}
+
+/// Fixed-rate delay: block until `*last_wake + period` ticks, then advance
+/// `*last_wake` by `period`. Returns at once if that time already passed,
+/// so a late iteration does not push every later one back. Task-only.
+pub fn delay_until(last_wake: &mut u32, period: u32) {
+    let wake = last_wake.wrapping_add(period);
+    *last_wake = wake;
+    sleep_until(wake);
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use ipc::wait::{set_parker, ParkerHooks};
+
+    static WOKEN: AtomicU32 = AtomicU32::new(0);
+
+    fn fake_current() -> TaskId {
+        7
+    }
+    fn fake_park() {}
+    fn fake_unpark(id: TaskId) {
+        WOKEN.store(id + 1, Ordering::SeqCst);
+    }
+    static HOOKS: ParkerHooks = ParkerHooks { current: fake_current, park: fake_park, unpark: fake_unpark };
+
+    #[test]
+    fn conversions_and_wrap_safe_compare() {
+        assert_eq!(ms_to_ticks(0), 0);
+        assert_eq!(ms_to_ticks(10), 10 * TICK_HZ / 1000);
+        assert!(reached(5, 5));
+        assert!(!reached(4, 5));
+        assert!(reached(3, u32::MAX - 2), "deadline just before wrap");
+        assert!(!reached(u32::MAX - 2, 3));
+    }
+
+    #[test]
+    fn tick_wakes_expired_sleepers_only() {
+        set_parker(&HOOKS);
+        let start = ticks();
+        assert!(arm_wakeup(3, start.wrapping_add(2)));
+        on_tick();
+        assert_eq!(WOKEN.load(Ordering::SeqCst), 0, "not yet due");
+        on_tick();
+        assert_eq!(WOKEN.load(Ordering::SeqCst), 4, "task 3 woken");
+        assert!(uptime_ticks() >= 2);
+
+        // A multi-tick jump wakes everything it passes.
+        WOKEN.store(0, Ordering::SeqCst);
+        let start = ticks();
+        assert!(arm_wakeup(4, start.wrapping_add(30)));
+        assert!(next_wakeup().is_some_and(|d| reached(start.wrapping_add(30), d)), "no later than ours");
+        advance(29);
+        assert_eq!(WOKEN.load(Ordering::SeqCst), 0);
+        advance(5);
+        assert_eq!(WOKEN.load(Ordering::SeqCst), 5);
+
+        // Cancelled wakeups never fire.
+        WOKEN.store(0, Ordering::SeqCst);
+        assert!(arm_wakeup(5, ticks().wrapping_add(1)));
+        cancel_wakeup(5);
+        on_tick();
+        assert_eq!(WOKEN.load(Ordering::SeqCst), 0);
+
+        // With ticks supplied by another thread, a sleep completes.
+        let t = std::thread::spawn(|| {
+            for _ in 0..50 {
+                on_tick();
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            }
+        });
+        let mut last = ticks();
+        delay_until(&mut last, 3);
+        assert!(reached(ticks(), last));
+        t.join().unwrap();
+    }
+}
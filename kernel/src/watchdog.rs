@@ -0,0 +1,303 @@
+//! SecureIoTOS Kernel Watchdog Module
+//! ----------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author : Md Mahbubur Rahman
+//! URL    : https://m-a-h-b-u-b.github.io
+//! GitHub : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Watchdog service with per-task heartbeats.
+//!
+//! Tasks that must stay alive register with a timeout (`watch()`) and
+//! call `heartbeat()` at least that often. Every `CHECK_INTERVAL` ticks
+//! the SysTick handler runs `service()`, which checks every registered
+//! task and then feeds the hardware watchdog (`hal::timer::HardwareWatchdog`,
+//! e.g. the STM32 IWDG) installed with `start()`.
+//!
+//! A task that misses its heartbeat is handled per its `MissAction`:
+//! - `Log`: reported to the hook from `set_miss_hook()` (reported once,
+//!   until the task checks in again);
+//! - `Restart`: reported, killed (its kernel queues closed) and handed to
+//!   the hook from `set_restart_hook()`, which may start it again; the new
+//!   task is watched with the same settings;
+//! - `Reset`: reported, then the device is reset.
+//!
+//! The hardware watchdog covers the case the service cannot: if SysTick
+//! or the kernel itself stops, nobody feeds it and it resets the device.
+//! Its timeout must be longer than `CHECK_INTERVAL` (and, with the
+//! `tickless` feature, longer than the longest idle phase).
+
+// Core kernel path: must not panic (see `tools/no-panic-check`).
+#![cfg_attr(not(test), deny(
+    clippy::panic,
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::indexing_slicing,
+    clippy::unreachable,
+    clippy::todo,
+    clippy::unimplemented
+))]
+
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+use hal::timer::HardwareWatchdog;
+
+use crate::scheduler::MAX_TASKS;
+use crate::time::{self, reached};
+
+/// Ticks between two `service()` runs.
+pub const CHECK_INTERVAL: u32 = 100;
+
+/// What to do when a task misses its heartbeat.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissAction {
+    /// Report only
+    Log,
+    /// Kill the task and let the restart hook start it again
+    Restart,
+    /// Reset the device
+    Reset,
+}
+
+/// A missed heartbeat, as passed to the miss hook.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Missed {
+    /// Task id
+    pub task: u32,
+    /// Tick of the last heartbeat (or of registration)
+    pub last_seen: u32,
+    /// Heartbeat timeout in ticks
+    pub timeout: u32,
+    pub action: MissAction,
+}
+
+/// Why a watchdog call failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogError {
+    /// All `MAX_TASKS` entries are in use
+    Full,
+    /// Timeout is zero
+    BadTimeout,
+    /// Task is not watched
+    NotWatched,
+    /// Watchdog state is busy (re-entrant call)
+    Busy,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    task: u32,
+    timeout: u32,
+    last_seen: u32,
+    action: MissAction,
+    /// Miss already reported
+    reported: bool,
+}
+
+/// Heartbeat bookkeeping for up to `MAX_TASKS` tasks.
+pub struct Heartbeats {
+    entries: [Option<Entry>; MAX_TASKS],
+}
+
+impl Heartbeats {
+    pub const fn new() -> Self {
+        Self { entries: [None; MAX_TASKS] }
+    }
+
+    /// Watch `task` with a heartbeat `timeout` in ticks, starting at `now`.
+    /// Registering a watched task again updates its settings.
+    pub fn watch(&mut self, task: u32, timeout: u32, action: MissAction, now: u32) -> Result<(), WatchdogError> {
+        if timeout == 0 {
+            return Err(WatchdogError::BadTimeout);
+        }
+        let entry = Entry { task, timeout, last_seen: now, action, reported: false };
+        let slot = match self.entries.iter().position(|e| e.is_some_and(|e| e.task == task)) {
+            Some(i) => self.entries.get_mut(i),
+            None => self.entries.iter_mut().find(|e| e.is_none()),
+        };
+        *slot.ok_or(WatchdogError::Full)? = Some(entry);
+        Ok(())
+    }
+
+    /// Stop watching `task`.
+    pub fn unwatch(&mut self, task: u32) -> Result<(), WatchdogError> {
+        let slot = self.entries.iter_mut().find(|e| e.is_some_and(|e| e.task == task));
+        *slot.ok_or(WatchdogError::NotWatched)? = None;
+        Ok(())
+    }
+
+    /// Record a heartbeat of `task` at `now`.
+    pub fn heartbeat(&mut self, task: u32, now: u32) -> Result<(), WatchdogError> {
+        let entry = self.entries.iter_mut().flatten().find(|e| e.task == task).ok_or(WatchdogError::NotWatched)?;
+        entry.last_seen = now;
+        entry.reported = false;
+        Ok(())
+    }
+
+    /// Collect the tasks whose heartbeat is overdue at `now` and not yet
+    /// reported. Entries with `Restart` are dropped; the caller re-watches
+    /// the restarted task.
+    pub fn check(&mut self, now: u32) -> [Option<Missed>; MAX_TASKS] {
+        let mut missed = [None; MAX_TASKS];
+        for (slot, out) in self.entries.iter_mut().zip(missed.iter_mut()) {
+            let Some(e) = slot.as_mut() else { continue };
+            if e.reported || !reached(now, e.last_seen.wrapping_add(e.timeout)) {
+                continue;
+            }
+            e.reported = true;
+            *out = Some(Missed { task: e.task, last_seen: e.last_seen, timeout: e.timeout, action: e.action });
+            if e.action == MissAction::Restart {
+                *slot = None;
+            }
+        }
+        missed
+    }
+}
+
+impl Default for Heartbeats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct WatchdogState {
+    beats: Heartbeats,
+    hw: Option<&'static mut (dyn HardwareWatchdog + Send)>,
+    next_check: u32,
+    miss_hook: Option<fn(&Missed)>,
+    restart_hook: Option<fn(u32) -> Option<u32>>,
+}
+
+static STATE: Mutex<RefCell<WatchdogState>> = Mutex::new(RefCell::new(WatchdogState {
+    beats: Heartbeats::new(),
+    hw: None,
+    next_check: 0,
+    miss_hook: None,
+    restart_hook: None,
+}));
+
+fn with_state<R>(f: impl FnOnce(&mut WatchdogState) -> R) -> Result<R, WatchdogError> {
+    interrupt::free(|cs| {
+        let mut st = STATE.borrow(cs).try_borrow_mut().map_err(|_| WatchdogError::Busy)?;
+        Ok(f(&mut st))
+    })
+}
+
+/// Start `hw` with `timeout_ms` and let `service()` feed it. Returns the
+/// timeout actually programmed.
+pub fn start(hw: &'static mut (dyn HardwareWatchdog + Send), timeout_ms: u32) -> Result<u32, WatchdogError> {
+    with_state(|st| {
+        let actual = hw.start(timeout_ms);
+        st.hw = Some(hw);
+        actual
+    })
+}
+
+/// Install the hook told about every missed heartbeat (logger, telemetry
+/// counter, ...). Called from the SysTick handler: keep it short.
+pub fn set_miss_hook(hook: fn(&Missed)) -> Result<(), WatchdogError> {
+    with_state(|st| st.miss_hook = Some(hook))
+}
+
+/// Install the hook that starts a killed task again. It gets the old task
+/// id and returns the new one, or `None` if the task stays down.
+pub fn set_restart_hook(hook: fn(u32) -> Option<u32>) -> Result<(), WatchdogError> {
+    with_state(|st| st.restart_hook = Some(hook))
+}
+
+/// Watch task `task`: it must call `heartbeat()` at least every
+/// `timeout_ms`. ISR-safe.
+pub fn watch(task: u32, timeout_ms: u32, action: MissAction) -> Result<(), WatchdogError> {
+    let now = time::ticks();
+    with_state(|st| st.beats.watch(task, time::ms_to_ticks(timeout_ms), action, now))?
+}
+
+/// Stop watching task `task`. ISR-safe.
+pub fn unwatch(task: u32) -> Result<(), WatchdogError> {
+    with_state(|st| st.beats.unwatch(task))?
+}
+
+/// Check in for the calling task.
+pub fn heartbeat() -> Result<(), WatchdogError> {
+    let task = crate::scheduler::current_task_id().ok_or(WatchdogError::NotWatched)?;
+    let now = time::ticks();
+    with_state(|st| st.beats.heartbeat(task, now))?
+}
+
+/// Check heartbeats and feed the hardware watchdog, at most once per
+/// `CHECK_INTERVAL`. Called from the SysTick handler only.
+pub fn service() {
+    let now = time::ticks();
+    let Ok(Some((missed, miss_hook, restart_hook))) = with_state(|st| {
+        if !reached(now, st.next_check) {
+            return None;
+        }
+        st.next_check = now.wrapping_add(CHECK_INTERVAL);
+        let missed = st.beats.check(now);
+        if missed.iter().flatten().all(|m| m.action != MissAction::Reset) {
+            if let Some(hw) = st.hw.as_mut() {
+                hw.feed();
+            }
+        }
+        Some((missed, st.miss_hook, st.restart_hook))
+    }) else {
+        return;
+    };
+
+    for m in missed.iter().flatten() {
+        if let Some(hook) = miss_hook {
+            hook(m);
+        }
+        match m.action {
+            MissAction::Log => {}
+            MissAction::Restart => restart(m, restart_hook),
+            MissAction::Reset => cortex_m::peripheral::SCB::sys_reset(),
+        }
+    }
+}
+
+/// Kill the task behind `m` and start it again through `hook`.
+fn restart(m: &Missed, hook: Option<fn(u32) -> Option<u32>>) {
+    if crate::scheduler::kill_task(m.task).is_none() {
+        return;
+    }
+    crate::ipc_queue::release_task(m.task);
+    let Some(new_id) = hook.and_then(|h| h(m.task)) else { return };
+    let now = time::ticks();
+    let _ = with_state(|st| st.beats.watch(new_id, m.timeout, MissAction::Restart, now));
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    fn due(hb: &mut Heartbeats, now: u32) -> Vec<u32> {
+        hb.check(now).iter().flatten().map(|m| m.task).collect()
+    }
+
+    #[test]
+    fn misses_are_reported_once_until_next_heartbeat() {
+        let mut hb = Heartbeats::new();
+        hb.watch(1, 10, MissAction::Log, u32::MAX - 5).unwrap();
+        hb.watch(2, 10, MissAction::Restart, u32::MAX - 5).unwrap();
+        assert_eq!(hb.watch(3, 0, MissAction::Log, 0), Err(WatchdogError::BadTimeout));
+
+        hb.heartbeat(2, 2).unwrap();
+        assert!(due(&mut hb, 3).is_empty(), "not due across the wrap");
+        let missed = hb.check(4);
+        assert_eq!(
+            missed.iter().flatten().copied().collect::<Vec<_>>(),
+            [Missed { task: 1, last_seen: u32::MAX - 5, timeout: 10, action: MissAction::Log }]
+        );
+        assert!(due(&mut hb, 5).is_empty(), "reported once");
+
+        hb.heartbeat(1, 6).unwrap();
+        assert_eq!(due(&mut hb, 12), [2]);
+        assert_eq!(hb.heartbeat(2, 13), Err(WatchdogError::NotWatched), "restart drops the entry");
+        assert_eq!(due(&mut hb, 16), [1]);
+    }
+}
@@ -0,0 +1,460 @@
+//! SecureIoTOS Manifest Lib Module
+//! -------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Firmware image manifest: the signed header in front of an image.
+//!
+//! A short fixed header followed by type-length-value entries, in the
+//! spirit of MCUboot's TLVs and SUIT, so fields can be added without a new
+//! format version:
+//!
+//! ```text
+//! magic "SIOSMFT\0" | format u8 | reserved u8 | signed_len u16 |
+//! TLV... (tag u16 | len u16 | value)                | <- signed_len ends here
+//! TAG_SIGNATURE TLV (ECDSA P-256, DER) | 0xFF padding
+//! ```
+//!
+//! All integers little-endian. The signature covers the first `signed_len`
+//! bytes (fixed header and every TLV before the signature), so the image
+//! length, SHA-256, version and flags are all authenticated. Tags below
+//! `TAG_OPTIONAL` must be understood by the parser; unknown ones at or above
+//! it are skipped.
+//!
+//! `ManifestBuilder` writes the same layout for host tools
+//! (`tools/sign-manifest`). Its output depends only on its inputs (fixed
+//! TLV order, no timestamps), and ECDSA signatures from RFC 6979 signers
+//! are deterministic, so the same image and key always give the same
+//! signed manifest.
+
+// If we are not running tests, compile this crate without the standard library (no_std).
+#![cfg_attr(not(test), no_std)]
+
+// ECDSA P-256 over SHA-256, as `crypto::ecc`
+use p256::ecdsa::signature::Verifier;
+use p256::ecdsa::{Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+/// Magic at the start of a manifest.
+pub const MANIFEST_MAGIC: [u8; 8] = *b"SIOSMFT\0";
+/// Layout version of the fixed header.
+pub const MANIFEST_FORMAT: u8 = 1;
+/// Length of the fixed header.
+const FIXED_LEN: usize = 12;
+/// Length of a TLV's tag and length fields.
+const TLV_HEADER_LEN: usize = 4;
+
+/// Image version (u32), compared against the anti-rollback floor.
+pub const TAG_VERSION: u16 = 0x0001;
+/// Length of the image in bytes (u32).
+pub const TAG_IMAGE_LEN: u16 = 0x0002;
+/// `FLAG_*` bits (u32).
+pub const TAG_FLAGS: u16 = 0x0003;
+/// SHA-256 of the image (32 bytes).
+pub const TAG_SHA256: u16 = 0x0010;
+/// Build ID from the image header (`BUILD_ID_LEN` bytes), optional.
+pub const TAG_BUILD_ID: u16 = 0x0011;
+/// ECDSA P-256 signature (DER) over the signed part; always last.
+pub const TAG_SIGNATURE: u16 = 0x0020;
+/// Tags from here on may be ignored by parsers that do not know them.
+pub const TAG_OPTIONAL: u16 = 0x8000;
+
+/// Flag: built from a tree with uncommitted changes (as
+/// `kernel::image::FLAG_DIRTY`).
+pub const FLAG_DIRTY: u32 = 1 << 0;
+/// Flag: debug build (asserts, logging, debug port left open).
+pub const FLAG_DEBUG: u32 = 1 << 1;
+
+/// Length of a firmware build ID.
+pub const BUILD_ID_LEN: usize = 20;
+/// Longest DER encoded P-256 ECDSA signature.
+pub const MAX_SIGNATURE_LEN: usize = 72;
+
+/// Why a manifest was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestError {
+    /// No manifest (erased, or another format)
+    BadMagic,
+    /// Written for a newer format version
+    UnsupportedFormat,
+    /// Shorter than its own lengths say
+    Truncated,
+    /// A TLV has the wrong length for its tag, or appears twice
+    BadTlv,
+    /// A tag below `TAG_OPTIONAL` that this parser does not know
+    UnknownTag,
+    /// Version, image length or hash missing
+    MissingField,
+    /// Signature is not a P-256 DER signature
+    BadSignatureEncoding,
+    /// Signature does not verify under the vendor key
+    SignatureMismatch,
+    /// The image is not as long as the manifest says
+    LengthMismatch,
+    /// The image does not hash to the manifest's SHA-256
+    HashMismatch,
+    /// The builder's output buffer is too small
+    BufferTooSmall,
+}
+
+/// A parsed manifest. Nothing in it can be trusted until `verify()` has
+/// returned `Ok`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Manifest<'a> {
+    pub version: u32,
+    pub image_len: usize,
+    pub flags: u32,
+    pub sha256: [u8; 32],
+    pub build_id: Option<[u8; BUILD_ID_LEN]>,
+    /// DER encoded signature
+    pub signature: &'a [u8],
+    /// Bytes the signature covers
+    signed: &'a [u8],
+}
+
+impl<'a> Manifest<'a> {
+    /// Parse a manifest from the start of `bytes` (trailing padding is
+    /// ignored).
+    ///
+    /// # Returns
+    /// * `Ok(manifest)` if the layout is well formed and every mandatory
+    ///   field is present; the signature is not checked yet
+    /// * `Err(e)` otherwise
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, ManifestError> {
+        let fixed = bytes.get(..FIXED_LEN).ok_or(ManifestError::Truncated)?;
+        if fixed[..8] != MANIFEST_MAGIC {
+            return Err(ManifestError::BadMagic);
+        }
+        if fixed[8] != MANIFEST_FORMAT {
+            return Err(ManifestError::UnsupportedFormat);
+        }
+        let signed_len = usize::from(u16::from_le_bytes([fixed[10], fixed[11]]));
+        let signed = bytes.get(..signed_len).ok_or(ManifestError::Truncated)?;
+        if signed_len < FIXED_LEN {
+            return Err(ManifestError::Truncated);
+        }
+
+        let (mut version, mut image_len, mut flags, mut sha256, mut build_id) = (None, None, None, None, None);
+        let mut rest = &signed[FIXED_LEN..];
+        while !rest.is_empty() {
+            let (tag, value, next) = split_tlv(rest)?;
+            let seen = match tag {
+                TAG_VERSION => version.replace(le_u32(value)?).is_some(),
+                TAG_IMAGE_LEN => image_len.replace(le_u32(value)? as usize).is_some(),
+                TAG_FLAGS => flags.replace(le_u32(value)?).is_some(),
+                TAG_SHA256 => sha256.replace(<[u8; 32]>::try_from(value).map_err(|_| ManifestError::BadTlv)?).is_some(),
+                TAG_BUILD_ID => build_id
+                    .replace(<[u8; BUILD_ID_LEN]>::try_from(value).map_err(|_| ManifestError::BadTlv)?)
+                    .is_some(),
+                // The signature cannot sign itself
+                TAG_SIGNATURE => return Err(ManifestError::BadTlv),
+                t if t >= TAG_OPTIONAL => false,
+                _ => return Err(ManifestError::UnknownTag),
+            };
+            if seen {
+                return Err(ManifestError::BadTlv);
+            }
+            rest = next;
+        }
+
+        let (tag, signature, _) = split_tlv(&bytes[signed_len..])?;
+        if tag != TAG_SIGNATURE || signature.len() > MAX_SIGNATURE_LEN {
+            return Err(ManifestError::BadSignatureEncoding);
+        }
+        Ok(Self {
+            version: version.ok_or(ManifestError::MissingField)?,
+            image_len: image_len.ok_or(ManifestError::MissingField)?,
+            flags: flags.unwrap_or(0),
+            sha256: sha256.ok_or(ManifestError::MissingField)?,
+            build_id,
+            signature,
+            signed,
+        })
+    }
+
+    /// Check the manifest's signature under `vendor_key`, then `image`
+    /// against its length and hash.
+    ///
+    /// # Returns
+    /// * `Ok(())` if `image` is the image the vendor signed
+    /// * `Err(e)` naming the first check that failed
+    pub fn verify(&self, image: &[u8], vendor_key: &VerifyingKey) -> Result<(), ManifestError> {
+        let sig = Signature::from_der(self.signature).map_err(|_| ManifestError::BadSignatureEncoding)?;
+        if vendor_key.verify(self.signed, &sig).is_err() {
+            return Err(ManifestError::SignatureMismatch);
+        }
+        if image.len() != self.image_len {
+            return Err(ManifestError::LengthMismatch);
+        }
+        // Constant-time compare, as `firmware::verify_firmware()`
+        if !bool::from(Sha256::digest(image)[..].ct_eq(&self.sha256)) {
+            return Err(ManifestError::HashMismatch);
+        }
+        Ok(())
+    }
+}
+
+/// Split the first TLV off `bytes`.
+///
+/// # Returns
+/// * `(tag, value, rest)`
+fn split_tlv(bytes: &[u8]) -> Result<(u16, &[u8], &[u8]), ManifestError> {
+    let header = bytes.get(..TLV_HEADER_LEN).ok_or(ManifestError::Truncated)?;
+    let tag = u16::from_le_bytes([header[0], header[1]]);
+    let len = usize::from(u16::from_le_bytes([header[2], header[3]]));
+    let value = bytes.get(TLV_HEADER_LEN..TLV_HEADER_LEN + len).ok_or(ManifestError::Truncated)?;
+    Ok((tag, value, &bytes[TLV_HEADER_LEN + len..]))
+}
+
+fn le_u32(value: &[u8]) -> Result<u32, ManifestError> {
+    value.try_into().map(u32::from_le_bytes).map_err(|_| ManifestError::BadTlv)
+}
+
+/// Writes manifests, for signing tools and tests.
+///
+/// ```ignore
+/// let builder = ManifestBuilder::new(7, &image).flags(FLAG_DIRTY);
+/// let mut page = [0xFF; 256];
+/// let signed_len = builder.write_unsigned(&mut page)?;
+/// let der = sign_p256_sha256(&page[..signed_len]); // vendor key, offline
+/// let len = ManifestBuilder::append_signature(&mut page, signed_len, &der)?;
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ManifestBuilder {
+    version: u32,
+    image_len: u32,
+    sha256: [u8; 32],
+    flags: u32,
+    build_id: Option<[u8; BUILD_ID_LEN]>,
+}
+
+impl ManifestBuilder {
+    /// Manifest for `image` at `version`, with no flags and no build ID.
+    pub fn new(version: u32, image: &[u8]) -> Self {
+        let sha256 = Sha256::digest(image).into();
+        Self { version, image_len: image.len() as u32, sha256, flags: 0, build_id: None }
+    }
+
+    pub fn flags(mut self, flags: u32) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    pub fn build_id(mut self, build_id: [u8; BUILD_ID_LEN]) -> Self {
+        self.build_id = Some(build_id);
+        self
+    }
+
+    /// Write the fixed header and TLVs to the start of `out`.
+    ///
+    /// # Returns
+    /// * `Ok(signed_len)`: `out[..signed_len]` is what the vendor signs
+    /// * `Err(BufferTooSmall)` if `out` cannot hold them
+    pub fn write_unsigned(&self, out: &mut [u8]) -> Result<usize, ManifestError> {
+        let mut at = FIXED_LEN;
+        let fixed = out.get_mut(..FIXED_LEN).ok_or(ManifestError::BufferTooSmall)?;
+        fixed[..8].copy_from_slice(&MANIFEST_MAGIC);
+        fixed[8] = MANIFEST_FORMAT;
+        fixed[9] = 0;
+
+        at = put_tlv(out, at, TAG_VERSION, &self.version.to_le_bytes())?;
+        at = put_tlv(out, at, TAG_IMAGE_LEN, &self.image_len.to_le_bytes())?;
+        at = put_tlv(out, at, TAG_FLAGS, &self.flags.to_le_bytes())?;
+        at = put_tlv(out, at, TAG_SHA256, &self.sha256)?;
+        if let Some(id) = &self.build_id {
+            at = put_tlv(out, at, TAG_BUILD_ID, id)?;
+        }
+        let signed_len = u16::try_from(at).map_err(|_| ManifestError::BufferTooSmall)?;
+        out[10..12].copy_from_slice(&signed_len.to_le_bytes());
+        Ok(at)
+    }
+
+    /// Append the DER signature over `out[..signed_len]`.
+    ///
+    /// # Returns
+    /// * `Ok(len)`: total manifest length; the rest of `out` is untouched
+    /// * `Err(e)` if the signature is too long or does not fit
+    pub fn append_signature(out: &mut [u8], signed_len: usize, der: &[u8]) -> Result<usize, ManifestError> {
+        if der.len() > MAX_SIGNATURE_LEN {
+            return Err(ManifestError::BadSignatureEncoding);
+        }
+        put_tlv(out, signed_len, TAG_SIGNATURE, der)
+    }
+}
+
+/// Write one TLV at `out[at..]`.
+///
+/// # Returns
+/// * `Ok(end)`: offset just past the TLV
+fn put_tlv(out: &mut [u8], at: usize, tag: u16, value: &[u8]) -> Result<usize, ManifestError> {
+    let end = at + TLV_HEADER_LEN + value.len();
+    let tlv = out.get_mut(at..end).ok_or(ManifestError::BufferTooSmall)?;
+    tlv[..2].copy_from_slice(&tag.to_le_bytes());
+    tlv[2..4].copy_from_slice(&(value.len() as u16).to_le_bytes());
+    tlv[TLV_HEADER_LEN..].copy_from_slice(value);
+    Ok(end)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::signature::Signer;
+    use p256::ecdsa::SigningKey;
+
+    const IMAGE: &[u8] = b"firmware image bytes";
+
+    fn vendor_key() -> SigningKey {
+        SigningKey::from_bytes(&[0x11; 32]).unwrap()
+    }
+
+    fn sign(builder: &ManifestBuilder, key: &SigningKey, out: &mut [u8]) -> usize {
+        let signed_len = builder.write_unsigned(out).unwrap();
+        let sig: Signature = key.sign(&out[..signed_len]);
+        ManifestBuilder::append_signature(out, signed_len, sig.to_der().as_bytes()).unwrap()
+    }
+
+    /// Fixed header, `tlvs` as the signed part, then a signature TLV.
+    fn raw(tlvs: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut out = MANIFEST_MAGIC.to_vec();
+        out.extend_from_slice(&[MANIFEST_FORMAT, 0, 0, 0]);
+        for (tag, value) in tlvs {
+            out.extend_from_slice(&tag.to_le_bytes());
+            out.extend_from_slice(&(value.len() as u16).to_le_bytes());
+            out.extend_from_slice(value);
+        }
+        let signed_len = out.len() as u16;
+        out[10..12].copy_from_slice(&signed_len.to_le_bytes());
+        out.extend_from_slice(&TAG_SIGNATURE.to_le_bytes());
+        out.extend_from_slice(&8u16.to_le_bytes());
+        out.extend_from_slice(&[0x30; 8]);
+        out
+    }
+
+    fn mandatory() -> Vec<(u16, &'static [u8])> {
+        vec![(TAG_VERSION, &[7, 0, 0, 0]), (TAG_IMAGE_LEN, &[20, 0, 0, 0]), (TAG_SHA256, &[0xAB; 32])]
+    }
+
+    #[test]
+    fn round_trip() {
+        let key = vendor_key();
+        let builder = ManifestBuilder::new(7, IMAGE).flags(FLAG_DIRTY).build_id([0x42; BUILD_ID_LEN]);
+        let mut page = [0xFF; 256];
+        let len = sign(&builder, &key, &mut page);
+
+        let manifest = Manifest::parse(&page).unwrap();
+        assert_eq!(manifest.version, 7);
+        assert_eq!(manifest.image_len, IMAGE.len());
+        assert_eq!(manifest.flags, FLAG_DIRTY);
+        assert_eq!(manifest.build_id, Some([0x42; BUILD_ID_LEN]));
+        assert_eq!(Manifest::parse(&page[..len]), Ok(manifest), "padding is ignored");
+        assert_eq!(manifest.verify(IMAGE, &key.verifying_key()), Ok(()));
+
+        let mut again = [0xFF; 256];
+        sign(&builder, &key, &mut again);
+        assert_eq!(page, again, "deterministic");
+    }
+
+    #[test]
+    fn verify_rejects_other_images_and_keys() {
+        let key = vendor_key();
+        let mut page = [0xFF; 256];
+        sign(&ManifestBuilder::new(1, IMAGE), &key, &mut page);
+        let manifest = Manifest::parse(&page).unwrap();
+
+        let other = SigningKey::from_bytes(&[0x22; 32]).unwrap();
+        assert_eq!(manifest.verify(IMAGE, &other.verifying_key()), Err(ManifestError::SignatureMismatch));
+        assert_eq!(manifest.verify(&IMAGE[1..], &key.verifying_key()), Err(ManifestError::LengthMismatch));
+        let mut tampered = IMAGE.to_vec();
+        tampered[0] ^= 1;
+        assert_eq!(manifest.verify(&tampered, &key.verifying_key()), Err(ManifestError::HashMismatch));
+
+        // A signed field changed after signing
+        page[FIXED_LEN + TLV_HEADER_LEN] = 2;
+        let manifest = Manifest::parse(&page).unwrap();
+        assert_eq!(manifest.verify(IMAGE, &key.verifying_key()), Err(ManifestError::SignatureMismatch));
+    }
+
+    #[test]
+    fn every_truncation_is_refused() {
+        let mut page = [0xFF; 256];
+        let len = sign(&ManifestBuilder::new(1, IMAGE).build_id([1; BUILD_ID_LEN]), &vendor_key(), &mut page);
+        for cut in 0..len {
+            assert_eq!(Manifest::parse(&page[..cut]), Err(ManifestError::Truncated), "cut at {cut}");
+        }
+    }
+
+    #[test]
+    fn bad_tlvs_are_refused() {
+        assert!(Manifest::parse(&raw(&mandatory())).is_ok());
+
+        let mut twice = mandatory();
+        twice.push((TAG_VERSION, &[8, 0, 0, 0]));
+        assert_eq!(Manifest::parse(&raw(&twice)), Err(ManifestError::BadTlv));
+
+        let mut short = mandatory();
+        short[0] = (TAG_VERSION, &[7, 0, 0]);
+        assert_eq!(Manifest::parse(&raw(&short)), Err(ManifestError::BadTlv));
+
+        let mut hash = mandatory();
+        hash[2] = (TAG_SHA256, &[0xAB; 31]);
+        assert_eq!(Manifest::parse(&raw(&hash)), Err(ManifestError::BadTlv));
+
+        let mut signed_sig = mandatory();
+        signed_sig.push((TAG_SIGNATURE, &[0x30; 8]));
+        assert_eq!(Manifest::parse(&raw(&signed_sig)), Err(ManifestError::BadTlv));
+
+        let mut unknown = mandatory();
+        unknown.push((0x0042, &[0]));
+        assert_eq!(Manifest::parse(&raw(&unknown)), Err(ManifestError::UnknownTag));
+
+        let mut optional = mandatory();
+        optional.push((TAG_OPTIONAL | 1, &[1, 2, 3]));
+        assert!(Manifest::parse(&raw(&optional)).is_ok());
+
+        let missing = &mandatory()[..2];
+        assert_eq!(Manifest::parse(&raw(missing)), Err(ManifestError::MissingField));
+
+        // The last TLV (SHA-256) running past the signed part
+        let mut overrun = raw(&mandatory());
+        overrun[FIXED_LEN + 2 * (TLV_HEADER_LEN + 4) + 2] = 33;
+        assert_eq!(Manifest::parse(&overrun), Err(ManifestError::Truncated));
+    }
+
+    #[test]
+    fn bad_headers_are_refused() {
+        let mut magic = raw(&mandatory());
+        magic[0] = b'X';
+        assert_eq!(Manifest::parse(&magic), Err(ManifestError::BadMagic));
+        assert_eq!(Manifest::parse(&[0xFF; 64]), Err(ManifestError::BadMagic), "erased flash");
+
+        let mut format = raw(&mandatory());
+        format[8] = MANIFEST_FORMAT + 1;
+        assert_eq!(Manifest::parse(&format), Err(ManifestError::UnsupportedFormat));
+
+        let mut no_sig = raw(&mandatory());
+        let signed_len = no_sig.len() - TLV_HEADER_LEN - 8;
+        no_sig[signed_len] = 0x21;
+        assert_eq!(Manifest::parse(&no_sig), Err(ManifestError::BadSignatureEncoding));
+    }
+
+    #[test]
+    fn builder_needs_room() {
+        let builder = ManifestBuilder::new(1, IMAGE);
+        assert_eq!(builder.write_unsigned(&mut [0; 40]), Err(ManifestError::BufferTooSmall));
+        let mut page = [0xFF; 256];
+        let signed_len = builder.write_unsigned(&mut page).unwrap();
+        assert_eq!(
+            ManifestBuilder::append_signature(&mut page, signed_len, &[0x30; MAX_SIGNATURE_LEN + 1]),
+            Err(ManifestError::BadSignatureEncoding)
+        );
+        assert_eq!(
+            ManifestBuilder::append_signature(&mut page[..signed_len + 10], signed_len, &[0x30; 8]),
+            Err(ManifestError::BufferTooSmall)
+        );
+    }
+}
@@ -13,21 +13,19 @@
 
 /// Layout → describes memory allocation requests (size + alignment).
 use core::alloc::Layout;
-/// null_mut → unsafe null pointer
-use core::ptr::null_mut;
+/// vec! → heap-allocated test data in `kernel_alloc_test`
+use alloc::vec;
 
-/// LockedHeap → allocator implementation from linked_list_allocator
-/// A fixed size heap backed by a linked list of free memory blocks.
-/// It’s a simple allocator designed for no_std embedded systems.
-/// LockedHeap provides interior mutability + synchronization.
-use linked_list_allocator::LockedHeap;
+/// KernelHeap → size-class arenas in front of linked_list_allocator
+/// (a fixed size heap backed by a linked list of free memory blocks).
+use crate::size_class::{ExtendedStats, HeapStats, KernelHeap};
+#[cfg(feature = "alloc-fail-injection")]
+use crate::size_class::FailInjection;
 
-#![no_std]
-
-use core::alloc::Layout;
+#[cfg(target_os = "none")]
 use core::mem::MaybeUninit;
+#[cfg(target_os = "none")]
 use cortex_m::interrupt;
-use cortex_m::peripheral::SCB;
 
 /// A small struct to record OOM info for post-mortem analysis.
 /// Stored in RAM at a fixed address (won't use heap).
@@ -40,6 +38,7 @@ pub struct OomRecord {
 
 /// A statically reserved slot for OOM diagnostics.
 /// Use MaybeUninit to avoid running constructors and to place this in .bss.
+#[cfg(target_os = "none")]
 static mut OOM_RECORD: MaybeUninit<OomRecord> = MaybeUninit::uninit();
 
 /// Optional user provided handler. Set at init time with `set_oom_handler`.
@@ -58,8 +57,12 @@ pub fn set_oom_handler(handler: fn(Layout)) {
 /// Declares the global allocator that Rust will use for Box, Vec, String, etc.
 /// Initially empty; must be initialized later via init_heap().
 /// #[global_allocator] → tells Rust to use this as the default allocator.
-#[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+/// Small and medium requests are served from fixed-block size-class arenas;
+/// only large requests reach the linked-list heap (see `size_class`).
+/// Only registered on the target: host tests keep the std allocator and
+/// drive `KernelHeap` instances of their own.
+#[cfg_attr(all(target_os = "none", not(test)), global_allocator)]
+static ALLOCATOR: KernelHeap = KernelHeap::empty();
 
 
 /// Production-ready alloc error handler.
@@ -68,10 +71,15 @@ static ALLOCATOR: LockedHeap = LockedHeap::empty();
 /// - Attempts to log via available backends (feature-gated)
 /// - Calls user hook if set
 /// - Performs a system reset (default final action)
+///
 /// Called when allocation fails (OOM = out of memory)
 /// -> ! means diverging function (never returns)
-#[alloc_error_handler]
-fn alloc_error_handler(layout: Layout) -> ! {
+///
+/// Registered as `#[alloc_error_handler]` with the `nightly` feature; on
+/// stable the board's OOM path can call it directly.
+#[cfg(target_os = "none")]
+#[cfg_attr(feature = "nightly", alloc_error_handler)]
+pub fn alloc_error_handler(layout: Layout) -> ! {
     // 1) stop preemption and further interrupts ASAP
     interrupt::disable();
 
@@ -82,26 +90,12 @@ fn alloc_error_handler(layout: Layout) -> ! {
             align: layout.align(),
             magic: 0x4F4F4D21, // "OOM!" marker
         };
-        OOM_RECORD.as_mut_ptr().write(rec);
+        core::ptr::addr_of_mut!(OOM_RECORD).cast::<OomRecord>().write(rec);
     }
 
-    // 3) best-effort logging (feature gated to keep binary small)
-    // Use whichever backend is compiled in. None of these are required.
-    #[cfg(feature = "defmt")]
-    {
-        defmt::error!("OOM: size={} align={}", layout.size(), layout.align());
-    }
-
-    #[cfg(all(not(feature = "defmt"), feature = "rtt"))]
-    {
-        // rtt_target is common and doesn't require semihosting
-        rtt_target::rprintln!("OOM: size={} align={}", layout.size(), layout.align());
-    }
-
-    #[cfg(all(not(feature = "defmt"), not(feature = "rtt"), feature = "semihosting"))]
-    {
-        let _ = cortex_m_semihosting::hprintln!("OOM: size={} align={}", layout.size(), layout.align());
-    }
+    // 3) best-effort logging through whichever sios_log backend the
+    // image selected (defmt, or nothing)
+    sios_log::error!("OOM: size={} align={}", layout.size(), layout.align());
 
     // 4) call lightweight user hook if present (must be quick)
     unsafe {
@@ -114,13 +108,7 @@ fn alloc_error_handler(layout: Layout) -> ! {
     // 5) Final controlled action: reset the MCU.
     // Alternatives: loop forever, enter low-power halt, blink LED, or jump to bootloader.
     // We choose reset because it is often the safest way to recover automatically.
-    cortex_m::peripheral::SCB::sys_reset();
-
-    // sys_reset should not return — but Rust requires a diverging function.
-    loop {
-        // As a last resort, just spin here if reset didn't take effect.
-        cortex_m::asm::nop();
-    }
+    cortex_m::peripheral::SCB::sys_reset()
 }
 
 /// Initialize the kernel heap at the given memory address
@@ -138,7 +126,7 @@ fn alloc_error_handler(layout: Layout) -> ! {
 /// ```
 pub fn init_heap(start: usize, size: usize) {
     unsafe {
-        ALLOCATOR.lock().init(start, size);
+        ALLOCATOR.init(start, size);
     }
 }
 
@@ -148,14 +136,32 @@ pub fn kernel_alloc_test() {
     let vec = vec![1u8, 2, 3, 4, 5];
     // Normally, we wouldn’t print inside a kernel,
     // but this helps confirm heap works in early testing.
-	// Uses cortex_m_semihosting::hprintln! to print via debugger.
-    cortex_m_semihosting::hprintln!("Allocated vector: {:?}", vec).ok();
+    sios_log::info!("Allocated vector: {:?}", sios_log::Dbg(&vec));
 }
 
 /// Get heap stats (allocated vs free) 
 // Returns (total_size, used_size) of the heap.
 // Useful for debugging memory usage in the kernel.
 pub fn heap_stats() -> (usize, usize) {
-    let allocator = ALLOCATOR.lock();
-    (allocator.size(), allocator.used())
+    ALLOCATOR.usage()
+}
+
+/// Per size-class statistics (small / medium / large).
+pub fn heap_class_stats() -> HeapStats {
+    ALLOCATOR.stats()
+}
+
+/// Peak usage, allocation counts, failures and the largest free block.
+/// Probes the heap inside a critical section; see
+/// `ClassedHeap::stats_extended`. Backs the `GetHeapStats` syscall.
+pub fn stats_extended() -> ExtendedStats {
+    ALLOCATOR.stats_extended()
+}
+
+/// Make heap allocations fail per `mode` (test images only). Note that a
+/// failed infallible allocation still ends in `alloc_error_handler`; use
+/// this with fallible APIs (`try_reserve`, `Box::try_new`, ...).
+#[cfg(feature = "alloc-fail-injection")]
+pub fn simulate_alloc_failures(mode: FailInjection) {
+    ALLOCATOR.set_fail_injection(mode);
 }
@@ -12,10 +12,23 @@
 //! - By default, modules and items are **private**.  
 //! - Adding `pub` makes them **publicly accessible** from outside the crate.  
 
+// If we are not running tests, compile this crate without the standard library (no_std).
+// If we are running tests, include std so tests can use standard library features.
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(all(target_os = "none", feature = "nightly"), feature(alloc_error_handler))]
+
+extern crate alloc;
+
 // Submodules for memory management
 pub mod heap;
 pub mod mpu;
+pub mod mpu_v8;
+pub mod sau;
+pub mod ramfunc;
 pub mod stack;
+pub mod secure_ram;
+pub mod size_class;
+pub mod pool;
 
 /// Default heap start address (example: SRAM region)
 const HEAP_START: usize = 0x2000_0000;
@@ -23,18 +36,26 @@ const HEAP_START: usize = 0x2000_0000;
 /// Default heap size in bytes (16 KiB)
 const HEAP_SIZE: usize = 16 * 1024;
 
+/// What boot does when the MPU audit finds a writable + executable region.
+/// Fail closed unless the `wx-report-only` feature is enabled (bring-up).
+#[cfg(not(feature = "wx-report-only"))]
+pub const WX_POLICY: mpu::WxPolicy = mpu::WxPolicy::FailBoot;
+#[cfg(feature = "wx-report-only")]
+pub const WX_POLICY: mpu::WxPolicy = mpu::WxPolicy::Report;
+
 
 /// Initialize the memory subsystem: heap, MPU, and stacks.
 ///
 /// This function sets up:
 /// - **Heap allocator** with a default region
-/// - **MPU (Memory Protection Unit)** for memory safety
+/// - **MPU (Memory Protection Unit)** for memory safety, followed by a
+///   W^X audit of the active configuration (see `WX_POLICY`)
 /// - **Stack system** (future extension)
 ///
 /// # Examples
 ///
-/// ```rust
-/// use secure_iotos::memory::memory_init;
+/// ```no_run
+/// use memory::memory_init;
 ///
 /// fn main() {
 ///     memory_init(); // Initialize all memory subsystems
@@ -47,6 +68,11 @@ pub fn memory_init() {
     // Configure MPU
     mpu::setup_mpu();
 
+    // Refuse to run with any region that is both writable and executable
+    if let Err(v) = mpu::enforce_wx(WX_POLICY) {
+        panic!("W^X violation in MPU region {} (base {:#010x})", v.region, v.base);
+    }
+
     // TODO: Initialize stacks if needed
     // stack::init_stacks();
 }
\ No newline at end of file
@@ -9,105 +9,390 @@
 //!
 //! This module configures the ARM Cortex-M MPU for
 //! kernel, task stacks, and peripherals.
+//!
+//! Regions are described once (`RegionConfig`: number, base, power-of-two
+//! size, access, executable) and programmed through an `MpuBackend`:
+//!
+//! - `ArmV7` (default): ARMv7-M RBAR/RASR layout (Cortex-M3/M4/M7);
+//! - `mpu_v8::ArmV8` (feature `armv8m`): ARMv8-M RBAR/RLAR layout with
+//!   memory attributes from MAIR0 (Cortex-M33/M23, e.g. STM32L5, nRF5340).
+//!
+//! `Backend` names the one selected for the build. ARMv8-M regions must
+//! not overlap (an access hitting two regions faults), so region layouts
+//! that rely on ARMv7-M's "higher number wins" rule need to be split
+//! there. TrustZone SAU setup lives in `sau`.
+
+// Core kernel path: must not panic (see `tools/no-panic-check`).
+#![cfg_attr(not(test), deny(
+    clippy::panic,
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::indexing_slicing,
+    clippy::unreachable,
+    clippy::todo,
+    clippy::unimplemented
+))]
 
 // gives you access to the MPU registers (Memory Protection Unit)
+#[cfg(not(feature = "armv8m"))]
 use cortex_m::peripheral::MPU;
 
 // SCB (System Control Block), used here to enable memory fault exceptions.
 // (so invalid accesses trigger a handler instead of silent corruption)
+#[cfg(not(feature = "armv8m"))]
 use cortex_m::peripheral::SCB;
 
 /// MPU region attributes
-// ARM MPU regions need an access permission code.
+// ARM MPU regions need an access permission code (RASR.AP, bits 26:24).
 // This enum is just a nicer way to write those bit patterns.
 // #[repr(u32)] → ensures the enum values map directly to the MPU bit patterns.
 #[repr(u32)]
-enum MpuAccess {
-    PrivRW = 0b011,    // Privileged Read/Write
-    UnprivRW = 0b011,  // Unprivileged Read/Write
-    PrivRO = 0b110,    // Privileged Read-Only
-    FullAccess = 0b111,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MpuAccess {
+    NoAccess = 0b000,  // No access at any privilege level (guard regions)
+    PrivRW = 0b001,    // Privileged Read/Write, unprivileged no access
+    UnprivRW = 0b011,  // Privileged and Unprivileged Read/Write
+    PrivRO = 0b101,    // Privileged Read-Only
+    ReadOnly = 0b110,  // Read-Only for both privilege levels
 }
 
-/// Configure MPU regions for kernel, tasks, and peripherals
-pub fn setup_mpu() {
-	
-	// MPU::ptr() → gives a raw pointer to the MPU registers.
-	// unsafe block → required because we’re dereferencing raw pointers to hardware.
-    let mpu = unsafe { &*MPU::ptr() };
-    let scb = unsafe { &*SCB::ptr() };
+/// RASR bit positions used below.
+const RASR_ENABLE: u32 = 1 << 0;
+const RASR_SIZE_SHIFT: u32 = 1;
+const RASR_AP_SHIFT: u32 = 24;
+const RASR_XN: u32 = 1 << 28;
 
-    // We must disable MPU before changing its configuration, 
-	// otherwise writes may be ignored.
-    unsafe { mpu.ctrl.write(0) };
-
-	// ARM Cortex-M MPU supports multiple regions (like memory slots).
-	// Each region gets:
-	// rnr = Region Number Register (selects which slot we configure).
-	// rbar = Region Base Address Register.
-	// rasr = Region Attribute & Size Register (access perms, executable flag, etc.).
-    // ---------------------------
-    // Region 0: Kernel code (RX, privileged)
-    // ---------------------------
-    unsafe {
-        mpu.rnr.write(0); // Region number
-        mpu.rbar.write(0x0800_0000); // Flash base
-        mpu.rasr.write(
-            (0b101 << 1)      // Size = 512 KB (example, adjust)
-            | (1 << 0)        // Enable
-            | (MpuAccess::PrivRO as u32) << 24 // PrivRO → kernel code is read-only in privileged mode.
-            | (0 << 28)       // XN = 0 (execution allowed, since it's code)code must run from Flash
-        );
+/// Static description of one MPU region.
+#[derive(Debug, Clone, Copy)]
+pub struct RegionConfig {
+    pub number: u32,
+    pub base: u32,
+    /// RASR SIZE field: region size = 2^(size_field + 1) bytes
+    pub size_field: u32,
+    pub access: MpuAccess,
+    /// Request execute permission. Ignored (forced to XN) for RAM regions.
+    pub executable: bool,
+}
+
+/// True if `addr` lies in a RAM or device area of the ARMv7-M memory map
+/// (SRAM, peripherals, external RAM/devices, system). Only the Code area
+/// (0x0000_0000..0x2000_0000, i.e. flash) may ever be executable.
+#[inline]
+pub const fn is_ram_or_device(addr: u32) -> bool {
+    addr >= 0x2000_0000
+}
+
+/// Whether `cfg` gets XN under W^X:
+/// - RAM/device regions always get XN,
+/// - writable regions always get XN, wherever they are.
+pub const fn region_xn(cfg: &RegionConfig) -> bool {
+    !cfg.executable || ap_is_writable(cfg.access as u32) || is_ram_or_device(cfg.base)
+}
+
+/// ARMv7-M RASR value for an enabled region.
+pub const fn rasr(size_field: u32, access: MpuAccess, xn: bool) -> u32 {
+    ((size_field & 0x1F) << RASR_SIZE_SHIFT)
+        | RASR_ENABLE
+        | ((access as u32) << RASR_AP_SHIFT)
+        | if xn { RASR_XN } else { 0 }
+}
+
+/// Encode the RASR value for `cfg`, enforcing W^X (see `region_xn`).
+pub const fn rasr_for(cfg: &RegionConfig) -> u32 {
+    rasr(cfg.size_field, cfg.access, region_xn(cfg))
+}
+
+/// Register-level MPU operations of one architecture.
+pub trait MpuBackend {
+    /// Turn the MPU off, e.g. before reprogramming it.
+    fn disable();
+
+    /// Turn the MPU on, with the default memory map as background for
+    /// privileged code, and enable MemManage faults.
+    fn enable();
+
+    /// Program region `number` over 2^(`size_field` + 1) bytes at `base`.
+    ///
+    /// # Safety
+    /// `base` must be aligned to the region size, and the new permissions
+    /// must not take away memory the running code still needs.
+    unsafe fn set_region(number: u32, base: u32, size_field: u32, access: MpuAccess, xn: bool);
+
+    /// Disable region `number`.
+    fn clear_region(number: u32);
+
+    /// Read back every region of the active configuration and look for
+    /// regions that are simultaneously writable and executable.
+    fn audit_wx() -> WxReport;
+}
+
+/// ARMv7-M MPU (RBAR/RASR).
+pub struct ArmV7;
+
+/// Backend of this build.
+#[cfg(not(feature = "armv8m"))]
+pub type Backend = ArmV7;
+#[cfg(feature = "armv8m")]
+pub type Backend = crate::mpu_v8::ArmV8;
+
+#[cfg(not(feature = "armv8m"))]
+impl MpuBackend for ArmV7 {
+    fn disable() {
+        // SAFETY: only the MPU is touched; with it off the default
+        // memory map applies.
+        unsafe { (*MPU::PTR).ctrl.write(0) };
+    }
+
+    fn enable() {
+        // SAFETY: ENABLE | PRIVDEFENA keeps the default map for
+        // privileged code; MEMFAULTENA routes violations to MemManage.
+        unsafe {
+            (*MPU::PTR).ctrl.write(1 << 0 | 1 << 2);
+            (*SCB::PTR).shcsr.modify(|r| r | (1 << 16));
+        }
+        cortex_m::asm::dsb();
+        cortex_m::asm::isb();
     }
 
-    // ---------------------------
-    // Region 1: Kernel stack (RW, privileged)
-    // ---------------------------
-    unsafe {
-        mpu.rnr.write(1);
-        mpu.rbar.write(0x2000_0000); // SRAM base
-        mpu.rasr.write(
-            (0b101 << 1)      // Size = 512 KB (example)
-            | (1 << 0)        // Enable
-            | (MpuAccess::PrivRW as u32) << 24
-            | (1 << 28)       // XN = 1 (no execution)
-        );
+    unsafe fn set_region(number: u32, base: u32, size_field: u32, access: MpuAccess, xn: bool) {
+        let mpu = &*MPU::PTR;
+        mpu.rnr.write(number);
+        mpu.rbar.write(base);
+        mpu.rasr.write(rasr(size_field, access, xn));
     }
 
-    // ---------------------------
-    // Region 2: Task1 stack (RW, unprivileged)
-    // ---------------------------
-    unsafe {
-        mpu.rnr.write(2);
-        mpu.rbar.write(0x2001_0000); // Task1 gets its own stack region.
-        mpu.rasr.write(
-            (0b100 << 1)      // Size = 256 KB (example)
-            | (1 << 0)        // Enable
-            | (MpuAccess::UnprivRW as u32) << 24  // Accessible in unprivileged mode (so tasks can’t touch kernel memory).
-            | (1 << 28)       // XN = 1 (no execution)
-        );
+    fn clear_region(number: u32) {
+        // SAFETY: disabling a region only falls back to lower regions or
+        // the background map.
+        unsafe {
+            let mpu = &*MPU::PTR;
+            mpu.rnr.write(number);
+            mpu.rasr.write(0);
+        }
     }
 
-    // ---------------------------
+    fn audit_wx() -> WxReport {
+        let mpu = unsafe { &*MPU::PTR };
+        // MPU_TYPE.DREGION (bits 15:8) = number of supported regions
+        let count = (mpu._type.read() >> 8) & 0xFF;
+
+        audit_regions((0..count).map(|n| unsafe {
+            mpu.rnr.write(n);
+            (n, mpu.rbar.read(), mpu.rasr.read())
+        }))
+    }
+}
+
+/// Regions programmed by `setup_mpu()`.
+///
+/// ARM Cortex-M MPU supports multiple regions (like memory slots).
+/// Each region gets:
+/// rnr = Region Number Register (selects which slot we configure).
+/// rbar = Region Base Address Register.
+/// rasr = Region Attribute & Size Register (access perms, executable flag, etc.).
+pub const REGIONS: [RegionConfig; 4] = [
+    // Region 0: Kernel code (RX, privileged). Code must run from Flash.
+    RegionConfig { number: 0, base: 0x0800_0000, size_field: 0b101, access: MpuAccess::PrivRO, executable: true },
+    // Region 1: Kernel stack (RW, privileged, no execution)
+    RegionConfig { number: 1, base: 0x2000_0000, size_field: 0b101, access: MpuAccess::PrivRW, executable: false },
+    // Region 2: Task1 stack (RW, unprivileged) so tasks can't touch kernel memory
+    RegionConfig { number: 2, base: 0x2001_0000, size_field: 0b100, access: MpuAccess::UnprivRW, executable: false },
     // Region 3: Task2 stack (RW, unprivileged)
-    // ---------------------------
-    unsafe {
-        mpu.rnr.write(3);
-        mpu.rbar.write(0x2002_0000); // Example Task2 stack base (different base: 0x2002_0000)
-        mpu.rasr.write(
-            (0b100 << 1)      // Size = 256 KB (example)
-            | (1 << 0)        // Enable
-            | (MpuAccess::UnprivRW as u32) << 24
-            | (1 << 28)       // XN = 1
-        );
+    RegionConfig { number: 3, base: 0x2002_0000, size_field: 0b100, access: MpuAccess::UnprivRW, executable: false },
+];
+
+/// Configure MPU regions for kernel, tasks, and peripherals
+pub fn setup_mpu() {
+    // We must disable MPU before changing its configuration,
+	// otherwise writes may be ignored.
+    Backend::disable();
+
+    // Every region's XN goes through `region_xn()`, so no RAM region can
+    // be programmed executable by mistake.
+    for region in REGIONS.iter() {
+        // SAFETY: the MPU is off, so no access depends on the regions yet.
+        unsafe { Backend::set_region(region.number, region.base, region.size_field, region.access, region_xn(region)) };
     }
 
     // Enable MPU with default memory map for background regions disabled
 	// ENABLE (bit0) → turns MPU back on.
 	// PRIVDEFENA (bit2) → allows privileged code to access regions not explicitly defined.
 	// SCB.shcsr → enables MemManage Faults, so violations trigger a fault handler.
-    unsafe {
-        mpu.ctrl.write(1 << 0 | 1 << 2); // ENABLE | PRIVDEFENA
-        scb.shcsr.modify(|r| r | (1 << 16)); // Enable MemManage fault
+    Backend::enable();
+}
+
+/// MPU region reserved for integrity-checked RAM functions (see
+/// `ramfunc`). It is the highest region number on an 8-region MPU, so it
+/// takes priority over the kernel SRAM region it overlaps.
+pub const RAMFUNC_REGION: u32 = 7;
+
+/// Program the RAM-function region.
+///
+/// - `executable = true`: privileged read-only + execute. The region is
+///   never writable while executable, so it passes the W^X audit.
+/// - `executable = false`: privileged read/write + XN (locked).
+///
+/// # Safety
+/// `base` must be aligned to the region size 2^(size_field + 1), and the
+/// caller must have verified the region contents before enabling execute.
+pub(crate) unsafe fn program_ramfunc_region(base: u32, size_field: u32, executable: bool) {
+    let access = if executable { MpuAccess::PrivRO } else { MpuAccess::PrivRW };
+    Backend::set_region(RAMFUNC_REGION, base, size_field, access, !executable);
+    // New permissions must be in effect before the next instruction fetch.
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
+
+/// MPU region guarding the running task's stack (see
+/// `stack::GuardedStackPool`). It overlaps the kernel and task RAM
+/// regions, so its number must be higher than theirs; `RAMFUNC_REGION`
+/// never overlaps a stack.
+pub const STACK_GUARD_REGION: u32 = 6;
+
+/// RASR SIZE field of a stack guard: `stack::STACK_GUARD_SIZE` bytes
+/// (32 = 2^(4 + 1)).
+const STACK_GUARD_SIZE_FIELD: u32 = 4;
+
+/// RASR of a stack guard: no access at any privilege level, XN.
+pub const STACK_GUARD_RASR: u32 = rasr(STACK_GUARD_SIZE_FIELD, MpuAccess::NoAccess, true);
+
+/// Move the stack guard to `base`, or disable it (`None`). The scheduler
+/// calls this for every task it switches to; `base` must be aligned to
+/// `stack::STACK_GUARD_SIZE`.
+pub fn set_stack_guard(base: Option<usize>) {
+    match base {
+        // SAFETY: only the guard region is reprogrammed; it never grants
+        // access, so no running code can lose memory it is allowed to use
+        // except the guard itself.
+        Some(base) => unsafe {
+            Backend::set_region(STACK_GUARD_REGION, base as u32, STACK_GUARD_SIZE_FIELD, MpuAccess::NoAccess, true)
+        },
+        None => Backend::clear_region(STACK_GUARD_REGION),
+    }
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+}
+
+// ---------------------------
+// W^X audit
+// ---------------------------
+
+/// What to do when the audit finds a writable + executable region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WxPolicy {
+    /// Refuse to boot
+    FailBoot,
+    /// Boot anyway; the caller reports the violation (e.g. via telemetry)
+    Report,
+}
+
+/// A region that is simultaneously writable and executable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WxViolation {
+    pub region: u32,
+    pub base: u32,
+    /// Attribute register: RASR on ARMv7-M, RBAR (AP, XN) on ARMv8-M
+    pub rasr: u32,
+}
+
+/// Result of scanning the active MPU configuration.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WxReport {
+    /// Number of enabled regions checked
+    pub checked: u32,
+    /// Number of W+X regions found
+    pub violations: u32,
+    /// First violating region, if any
+    pub first: Option<WxViolation>,
+}
+
+/// True if the AP encoding grants write access at any privilege level.
+#[inline]
+pub const fn ap_is_writable(ap: u32) -> bool {
+    matches!(ap & 0b111, 0b001..=0b011)
+}
+
+/// True if an enabled region with this RASR value is both writable and
+/// executable.
+#[inline]
+pub const fn rasr_is_wx(rasr: u32) -> bool {
+    let enabled = rasr & RASR_ENABLE != 0;
+    let executable = rasr & RASR_XN == 0;
+    enabled && executable && ap_is_writable(rasr >> RASR_AP_SHIFT)
+}
+
+/// Check a list of (region number, RBAR, RASR) triples.
+pub fn audit_regions(regions: impl IntoIterator<Item = (u32, u32, u32)>) -> WxReport {
+    let mut report = WxReport::default();
+    for (region, rbar, rasr) in regions {
+        if rasr & RASR_ENABLE == 0 {
+            continue;
+        }
+        report.checked += 1;
+        if rasr_is_wx(rasr) {
+            report.violations += 1;
+            if report.first.is_none() {
+                report.first = Some(WxViolation { region, base: rbar & !0x1F, rasr });
+            }
+        }
+    }
+    report
+}
+
+/// Read back every region of the active MPU configuration and look for
+/// regions that are simultaneously writable and executable.
+///
+/// This checks what the hardware actually holds, so it also catches
+/// regions programmed outside `setup_mpu()` (drivers, loaders, tasks).
+pub fn audit_wx() -> WxReport {
+    Backend::audit_wx()
+}
+
+/// Run the W^X audit and apply `policy`.
+///
+/// With [`WxPolicy::FailBoot`] the first violation is returned as an error
+/// and boot must not continue; with [`WxPolicy::Report`] the report is
+/// returned for the caller to log.
+pub fn enforce_wx(policy: WxPolicy) -> Result<WxReport, WxViolation> {
+    let report = audit_wx();
+    match (policy, report.first) {
+        (WxPolicy::FailBoot, Some(violation)) => Err(violation),
+        _ => Ok(report),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ram_regions_are_always_xn() {
+        for region in REGIONS.iter() {
+            assert!(!rasr_is_wx(rasr_for(region)), "region {} is W+X", region.number);
+        }
+        // Asking for an executable RAM region still yields XN
+        let sneaky = RegionConfig { number: 5, base: 0x2000_8000, size_field: 9, access: MpuAccess::ReadOnly, executable: true };
+        assert_ne!(rasr_for(&sneaky) & RASR_XN, 0);
+        // Flash code stays executable
+        assert_eq!(rasr_for(&REGIONS[0]) & RASR_XN, 0);
+        // The stack guard denies everything, execution included
+        assert!(!rasr_is_wx(STACK_GUARD_RASR));
+        assert_eq!(STACK_GUARD_RASR & (0b111 << RASR_AP_SHIFT), 0);
+    }
+
+    #[test]
+    fn audit_flags_writable_executable_regions() {
+        let wx = RASR_ENABLE | (0b011 << RASR_AP_SHIFT);
+        let rx = RASR_ENABLE | (0b110 << RASR_AP_SHIFT);
+        let disabled_wx = 0b011 << RASR_AP_SHIFT;
+        let report = audit_regions([
+            (0, 0x0800_0000, rx),
+            (1, 0x2000_0000, wx),
+            (2, 0x2001_0000, disabled_wx),
+            (3, 0x2002_0000, wx | RASR_XN),
+        ]);
+        assert_eq!(report.checked, 3);
+        assert_eq!(report.violations, 1);
+        assert_eq!(report.first, Some(WxViolation { region: 1, base: 0x2000_0000, rasr: wx }));
     }
 }
@@ -0,0 +1,209 @@
+//! SecureIoTOS MPU ARMv8-M Module
+//! ----------------------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! ARMv8-M (Cortex-M33/M23) backend of `mpu`.
+//!
+//! ARMv8-M replaces RASR with a limit register: a region is the inclusive
+//! range `[RBAR.BASE, RLAR.LIMIT]` at 32-byte granularity, access and XN
+//! sit in RBAR, and RLAR selects one of eight memory attributes held in
+//! MAIR0/MAIR1 (instead of TEX/C/B). `ArmV8` keeps the `MpuBackend`
+//! interface of the ARMv7-M backend: a 2^(size_field + 1) byte region at
+//! `base` becomes `[base, base + size - 1]`.
+//!
+//! Differences callers must know about:
+//!
+//! - regions must not overlap: an access hitting two enabled regions
+//!   raises a MemManage fault instead of taking the higher region;
+//! - there is no "no access" permission: `MpuAccess::NoAccess` is mapped
+//!   to privileged read-only, which still faults on every write (a stack
+//!   push) and on any unprivileged access. Task stacks are bounded by
+//!   PSPLIM on ARMv8-M anyway.
+//!
+//! The encoders below are plain functions; the register access needs the
+//! `armv8m` feature and a `thumbv8m` target.
+
+// Core kernel path: must not panic (see `tools/no-panic-check`).
+#![cfg_attr(not(test), deny(
+    clippy::panic,
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::indexing_slicing,
+    clippy::unreachable,
+    clippy::todo,
+    clippy::unimplemented
+))]
+
+use crate::mpu::{MpuAccess, WxReport, WxViolation};
+
+/// RBAR/RLAR bit positions used below.
+const RBAR_XN: u32 = 1 << 0;
+const RBAR_AP_SHIFT: u32 = 1;
+/// AP[2]: read-only
+const RBAR_AP_RO: u32 = 1 << 2;
+const RLAR_ENABLE: u32 = 1 << 0;
+const RLAR_ATTR_SHIFT: u32 = 1;
+const ADDR_MASK: u32 = !0x1F;
+
+/// MAIR attribute index of normal memory (flash, SRAM).
+pub const ATTR_NORMAL: u32 = 0;
+/// MAIR attribute index of device memory (peripherals, system).
+pub const ATTR_DEVICE: u32 = 1;
+
+/// MAIR0 value: attribute 0 = normal, write-back read/write-allocate
+/// (0xFF); attribute 1 = Device-nGnRE (0x04).
+pub const MAIR0: u32 = 0xFF | (0x04 << 8);
+
+/// AP[2:1] for `access`. `NoAccess` has no ARMv8-M encoding and becomes
+/// privileged read-only (see the module notes).
+pub const fn ap_bits(access: MpuAccess) -> u32 {
+    match access {
+        MpuAccess::PrivRW => 0b00,
+        MpuAccess::UnprivRW => 0b01,
+        MpuAccess::PrivRO | MpuAccess::NoAccess => 0b10,
+        MpuAccess::ReadOnly => 0b11,
+    }
+}
+
+/// True if `addr` lies in the peripheral or system areas of the memory
+/// map (0x4000_0000..0x6000_0000 and 0xA000_0000 upwards).
+pub const fn is_device(addr: u32) -> bool {
+    matches!(addr, 0x4000_0000..=0x5FFF_FFFF | 0xA000_0000..=u32::MAX)
+}
+
+/// RBAR value for a region at `base`.
+pub const fn rbar(base: u32, access: MpuAccess, xn: bool) -> u32 {
+    (base & ADDR_MASK) | (ap_bits(access) << RBAR_AP_SHIFT) | if xn { RBAR_XN } else { 0 }
+}
+
+/// RLAR value (enabled) for a 2^(`size_field` + 1) byte region at
+/// `base`; the memory attribute follows from the address.
+pub const fn rlar(base: u32, size_field: u32) -> u32 {
+    let size = 1u64 << ((size_field & 0x1F) + 1);
+    let end = base as u64 + size - 1;
+    let limit = if end > u32::MAX as u64 { u32::MAX } else { end as u32 };
+    let attr = if is_device(base) { ATTR_DEVICE } else { ATTR_NORMAL };
+    (limit & ADDR_MASK) | (attr << RLAR_ATTR_SHIFT) | RLAR_ENABLE
+}
+
+/// True if an enabled region with these RBAR/RLAR values is both writable
+/// and executable.
+pub const fn region_is_wx(rbar: u32, rlar: u32) -> bool {
+    rlar & RLAR_ENABLE != 0 && rbar & RBAR_XN == 0 && rbar & RBAR_AP_RO == 0
+}
+
+/// Check a list of (region number, RBAR, RLAR) triples.
+pub fn audit_regions(regions: impl IntoIterator<Item = (u32, u32, u32)>) -> WxReport {
+    let mut report = WxReport::default();
+    for (region, rbar, rlar) in regions {
+        if rlar & RLAR_ENABLE == 0 {
+            continue;
+        }
+        report.checked += 1;
+        if region_is_wx(rbar, rlar) {
+            report.violations += 1;
+            if report.first.is_none() {
+                report.first = Some(WxViolation { region, base: rbar & ADDR_MASK, rasr: rbar });
+            }
+        }
+    }
+    report
+}
+
+/// ARMv8-M MPU (RBAR/RLAR/MAIR).
+pub struct ArmV8;
+
+#[cfg(feature = "armv8m")]
+impl crate::mpu::MpuBackend for ArmV8 {
+    fn disable() {
+        // SAFETY: only the MPU is touched; with it off the default
+        // memory map applies.
+        unsafe { (*cortex_m::peripheral::MPU::PTR).ctrl.write(0) };
+    }
+
+    fn enable() {
+        // SAFETY: MAIR0 only defines the attributes the regions refer to;
+        // ENABLE | PRIVDEFENA keeps the default map for privileged code
+        // and MEMFAULTENA routes violations to MemManage.
+        unsafe {
+            let mpu = &*cortex_m::peripheral::MPU::PTR;
+            mpu.mair[0].write(MAIR0);
+            mpu.ctrl.write(1 << 0 | 1 << 2);
+            (*cortex_m::peripheral::SCB::PTR).shcsr.modify(|r| r | (1 << 16));
+        }
+        cortex_m::asm::dsb();
+        cortex_m::asm::isb();
+    }
+
+    unsafe fn set_region(number: u32, base: u32, size_field: u32, access: MpuAccess, xn: bool) {
+        let mpu = &*cortex_m::peripheral::MPU::PTR;
+        mpu.rnr.write(number);
+        mpu.rbar.write(rbar(base, access, xn));
+        mpu.rlar.write(rlar(base, size_field));
+    }
+
+    fn clear_region(number: u32) {
+        // SAFETY: disabling a region only falls back to the background map.
+        unsafe {
+            let mpu = &*cortex_m::peripheral::MPU::PTR;
+            mpu.rnr.write(number);
+            mpu.rlar.write(0);
+        }
+    }
+
+    fn audit_wx() -> WxReport {
+        // SAFETY: register reads plus RNR selection, which nothing else
+        // relies on between the calls below.
+        let mpu = unsafe { &*cortex_m::peripheral::MPU::PTR };
+        // MPU_TYPE.DREGION (bits 15:8) = number of supported regions
+        let count = (mpu._type.read() >> 8) & 0xFF;
+
+        audit_regions((0..count).map(|n| {
+            // SAFETY: as above.
+            unsafe { mpu.rnr.write(n) };
+            (n, mpu.rbar.read(), mpu.rlar.read())
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpu::{region_xn, REGIONS};
+
+    #[test]
+    fn regions_translate_to_base_and_limit() {
+        // 64 KiB of SRAM, privileged RW
+        let (b, l) = (rbar(0x2000_0000, MpuAccess::PrivRW, true), rlar(0x2000_0000, 15));
+        assert_eq!(b, 0x2000_0000 | RBAR_XN);
+        assert_eq!(l, 0x2000_FFE0 | (ATTR_NORMAL << RLAR_ATTR_SHIFT) | RLAR_ENABLE);
+        // Peripherals use the device attribute
+        assert_eq!((rlar(0x4000_0000, 9) >> RLAR_ATTR_SHIFT) & 0b111, ATTR_DEVICE);
+        // 4 GiB does not wrap
+        assert_eq!(rlar(0, 31) & ADDR_MASK, 0xFFFF_FFE0);
+        // No-access guards stay unwritable
+        assert_ne!(rbar(0x2000_1000, MpuAccess::NoAccess, true) & RBAR_AP_RO, 0);
+    }
+
+    #[test]
+    fn wx_is_enforced_and_audited_like_armv7() {
+        for r in REGIONS.iter() {
+            let b = rbar(r.base, r.access, region_xn(r));
+            assert!(!region_is_wx(b, rlar(r.base, r.size_field)), "region {} is W+X", r.number);
+        }
+        let wx = rbar(0x2000_0000, MpuAccess::UnprivRW, false);
+        let rx = rbar(0x0800_0000, MpuAccess::ReadOnly, false);
+        let report = audit_regions([
+            (0, rx, rlar(0x0800_0000, 17)),
+            (1, wx, rlar(0x2000_0000, 9)),
+            (2, wx, 0),
+        ]);
+        assert_eq!((report.checked, report.violations), (2, 1));
+        assert_eq!(report.first.map(|v| (v.region, v.base)), Some((1, 0x2000_0000)));
+    }
+}
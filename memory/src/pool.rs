@@ -0,0 +1,279 @@
+//! SecureIoTOS Block Pool Module
+//! -----------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Statically backed pools of fixed-size, DMA-safe buffers.
+//!
+//! Drivers need buffers that a DMA engine can read or write while the CPU
+//! does something else: at a stable address, aligned, never moved and
+//! never fragmented. The kernel heap (see `size_class`) gives none of
+//! these guarantees, so a driver declares its own pool instead:
+//!
+//! ```ignore
+//! #[link_section = ".dma_ram"] // RAM the DMA engine can reach (not DTCM)
+//! static RX_POOL: BlockPool<256, 8> = BlockPool::new();
+//!
+//! let mut buf = RX_POOL.alloc().ok_or(Error::NoBuffer)?;
+//! dma.start_rx(buf.as_mut_ptr(), buf.len());
+//! ```
+//!
+//! Every block starts on a `DMA_ALIGN` (32-byte, one Cortex-M7 cache line)
+//! boundary and `BLOCK` must be a multiple of it, so cache clean/invalidate
+//! by line never touches a neighbouring block.
+//!
+//! `alloc()` and freeing (dropping the `PoolBuf`) are O(1) and lock-free:
+//! the free list is a stack of block indices whose head carries a
+//! generation tag, updated with compare-and-swap, so an ISR may allocate
+//! or free while it preempts a task doing the same (no ABA). Like
+//! `ipc::MpmcQueue` this needs CAS (ARMv7-M and later).
+//!
+//! The free-list links are stored XOR-ed with "the next block", so the
+//! all-zero initial state already links every block in address order and
+//! `new()` can be `const`.
+
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU16, AtomicU32, AtomicUsize, Ordering};
+
+/// Alignment of every pool block (one Cortex-M7 D-cache line).
+pub const DMA_ALIGN: usize = 32;
+
+const INDEX_MASK: u32 = 0xFFFF;
+const TAG_ONE: u32 = 1 << 16;
+
+#[repr(C, align(32))]
+struct Block<const BLOCK: usize>([u8; BLOCK]);
+
+/// Pool usage counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    pub block_size: usize,
+    pub blocks: usize,
+    pub in_use: usize,
+    /// Highest `in_use` seen
+    pub peak: usize,
+    /// `alloc()` calls that found the pool empty
+    pub misses: usize,
+}
+
+/// `COUNT` blocks of `BLOCK` bytes, each aligned to `DMA_ALIGN`.
+pub struct BlockPool<const BLOCK: usize, const COUNT: usize> {
+    blocks: [UnsafeCell<Block<BLOCK>>; COUNT],
+    /// Next free block of each free block, XOR (index + 1)
+    links: [AtomicU16; COUNT],
+    /// Generation tag << 16 | index of the first free block (`COUNT`: empty)
+    head: AtomicU32,
+    in_use: AtomicUsize,
+    peak: AtomicUsize,
+    misses: AtomicUsize,
+}
+
+// SAFETY: a block's bytes are only reachable through the one `PoolBuf`
+// that popped its index off the free list; the tagged CAS on `head`
+// guarantees no index is handed out twice.
+unsafe impl<const BLOCK: usize, const COUNT: usize> Sync for BlockPool<BLOCK, COUNT> {}
+
+impl<const BLOCK: usize, const COUNT: usize> BlockPool<BLOCK, COUNT> {
+    const VALID: () = {
+        assert!(BLOCK > 0 && BLOCK.is_multiple_of(DMA_ALIGN), "BLOCK must be a non-zero multiple of DMA_ALIGN");
+        assert!(COUNT > 0 && COUNT < INDEX_MASK as usize, "COUNT out of range");
+    };
+
+    /// A pool with every block free.
+    pub const fn new() -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::VALID;
+        Self {
+            blocks: [const { UnsafeCell::new(Block([0; BLOCK])) }; COUNT],
+            links: [const { AtomicU16::new(0) }; COUNT],
+            head: AtomicU32::new(0),
+            in_use: AtomicUsize::new(0),
+            peak: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+        }
+    }
+
+    fn next_of(&self, index: usize) -> Option<u32> {
+        let link = self.links.get(index)?.load(Ordering::Relaxed);
+        Some(u32::from(link ^ (index as u16).wrapping_add(1)))
+    }
+
+    fn set_next(&self, index: usize, next: u32) {
+        if let Some(link) = self.links.get(index) {
+            link.store(next as u16 ^ (index as u16).wrapping_add(1), Ordering::Relaxed);
+        }
+    }
+
+    /// Take a free block. ISR-safe.
+    pub fn alloc(&self) -> Option<PoolBuf<'_, BLOCK, COUNT>> {
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            let index = (head & INDEX_MASK) as usize;
+            let Some(next) = self.next_of(index) else {
+                self.misses.fetch_add(1, Ordering::Relaxed);
+                return None;
+            };
+            let popped = (head & !INDEX_MASK).wrapping_add(TAG_ONE) | next;
+            match self.head.compare_exchange_weak(head, popped, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => {
+                    let used = self.in_use.fetch_add(1, Ordering::Relaxed) + 1;
+                    self.peak.fetch_max(used, Ordering::Relaxed);
+                    return Some(PoolBuf { pool: self, index });
+                }
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    fn free(&self, index: usize) {
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            self.set_next(index, head & INDEX_MASK);
+            let pushed = (head & !INDEX_MASK).wrapping_add(TAG_ONE) | index as u32;
+            match self.head.compare_exchange_weak(head, pushed, Ordering::AcqRel, Ordering::Acquire) {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+        self.in_use.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn block_ptr(&self, index: usize) -> *mut u8 {
+        self.blocks.get(index).map_or(core::ptr::null_mut(), |b| b.get().cast())
+    }
+
+    /// Rebuild the buffer `ptr` came from (`PoolBuf::into_raw`), e.g. in
+    /// the DMA completion ISR. `None` if `ptr` is not a block of this pool.
+    ///
+    /// # Safety
+    /// `ptr` must come from `into_raw` on a buffer of this pool and must
+    /// not be used again afterwards.
+    pub unsafe fn from_raw(&self, ptr: NonNull<u8>) -> Option<PoolBuf<'_, BLOCK, COUNT>> {
+        let base = self.block_ptr(0) as usize;
+        let offset = (ptr.as_ptr() as usize).checked_sub(base)?;
+        let stride = core::mem::size_of::<Block<BLOCK>>();
+        if !offset.is_multiple_of(stride) || offset / stride >= COUNT {
+            return None;
+        }
+        Some(PoolBuf { pool: self, index: offset / stride })
+    }
+
+    /// Usage counters.
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            block_size: BLOCK,
+            blocks: COUNT,
+            in_use: self.in_use.load(Ordering::Relaxed),
+            peak: self.peak.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl<const BLOCK: usize, const COUNT: usize> Default for BlockPool<BLOCK, COUNT> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A block taken from a `BlockPool`; returned to it on drop.
+pub struct PoolBuf<'a, const BLOCK: usize, const COUNT: usize> {
+    pool: &'a BlockPool<BLOCK, COUNT>,
+    index: usize,
+}
+
+impl<const BLOCK: usize, const COUNT: usize> PoolBuf<'_, BLOCK, COUNT> {
+    /// Address to program into a DMA descriptor.
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.pool.block_ptr(self.index)
+    }
+
+    /// Give up the handle without freeing the block, e.g. while a DMA
+    /// transfer owns it; get it back with `BlockPool::from_raw`.
+    pub fn into_raw(self) -> NonNull<u8> {
+        let ptr = self.pool.block_ptr(self.index);
+        core::mem::forget(self);
+        // Blocks live inside the pool, never at address 0.
+        NonNull::new(ptr).unwrap_or(NonNull::dangling())
+    }
+}
+
+impl<const BLOCK: usize, const COUNT: usize> Deref for PoolBuf<'_, BLOCK, COUNT> {
+    type Target = [u8; BLOCK];
+
+    fn deref(&self) -> &[u8; BLOCK] {
+        // SAFETY: this handle owns block `index` exclusively (see `Sync`).
+        unsafe { &*self.pool.block_ptr(self.index).cast::<[u8; BLOCK]>() }
+    }
+}
+
+impl<const BLOCK: usize, const COUNT: usize> DerefMut for PoolBuf<'_, BLOCK, COUNT> {
+    fn deref_mut(&mut self) -> &mut [u8; BLOCK] {
+        // SAFETY: as for `deref`, and `&mut self` makes the access unique.
+        unsafe { &mut *self.pool.block_ptr(self.index).cast::<[u8; BLOCK]>() }
+    }
+}
+
+impl<const BLOCK: usize, const COUNT: usize> Drop for PoolBuf<'_, BLOCK, COUNT> {
+    fn drop(&mut self) {
+        self.pool.free(self.index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn blocks_are_aligned_distinct_and_reused() {
+        static POOL: BlockPool<64, 4> = BlockPool::new();
+        let mut bufs: Vec<_> = (0..4).map(|_| POOL.alloc().unwrap()).collect();
+        assert!(POOL.alloc().is_none());
+        let mut addrs: Vec<usize> = bufs.iter_mut().map(|b| b.as_mut_ptr() as usize).collect();
+        assert!(addrs.iter().all(|a| a % DMA_ALIGN == 0));
+        addrs.dedup();
+        assert_eq!(addrs.len(), 4);
+
+        bufs[1].fill(0xAB);
+        let raw = bufs.remove(1).into_raw();
+        assert_eq!(POOL.stats().in_use, 4, "still owned by the DMA transfer");
+        // SAFETY: `raw` came from `into_raw` above.
+        let back = unsafe { POOL.from_raw(raw) }.unwrap();
+        assert_eq!(back[63], 0xAB);
+        drop(back);
+        assert_eq!(POOL.alloc().map(|mut b| b.as_mut_ptr() as usize), Some(addrs[1]), "freed block reused");
+
+        drop(bufs);
+        let s = POOL.stats();
+        assert_eq!((s.in_use, s.peak, s.misses), (0, 4, 1));
+    }
+
+    #[test]
+    fn concurrent_alloc_free_never_hands_out_a_block_twice() {
+        static POOL: BlockPool<32, 8> = BlockPool::new();
+        let threads: Vec<_> = (0..4u8)
+            .map(|t| {
+                std::thread::spawn(move || {
+                    for _ in 0..2_000 {
+                        if let Some(mut b) = POOL.alloc() {
+                            b.fill(t);
+                            std::thread::yield_now();
+                            assert!(b.iter().all(|&x| x == t), "block shared between owners");
+                        }
+                    }
+                })
+            })
+            .collect();
+        threads.into_iter().for_each(|t| t.join().unwrap());
+        assert_eq!(POOL.stats().in_use, 0);
+    }
+}
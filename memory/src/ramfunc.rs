@@ -0,0 +1,194 @@
+//! SecureIoTOS RAM Function Module
+//! -------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Integrity-checked exceptions to the execute-from-flash-only policy.
+//!
+//! By default no RAM is executable (see the W^X audit in `mpu`). Some
+//! devices still need to run code from RAM, typically flash programming
+//! routines that cannot execute from the bank being erased. This module
+//! allows that for one explicitly registered region at a time:
+//!
+//! 1. `RamFunc::install()` copies the code into a dedicated RAM region and
+//!    records its SHA-256 digest.
+//! 2. `RamFunc::unlock()` re-hashes the region and, only if it still
+//!    matches, maps it privileged read-only + executable via the
+//!    `mpu::RAMFUNC_REGION` slot.
+//! 3. Dropping the returned `ExecWindow` re-locks the region (RW + XN).
+//!
+//! The region is never writable while executable, so the W^X audit keeps
+//! passing even while a RAM function runs.
+
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+use sha2::{Digest, Sha256};
+
+use crate::mpu;
+
+/// Smallest region the ARMv7-M MPU can describe.
+pub const MIN_REGION_SIZE: usize = 32;
+
+/// Base address of the region that is currently executable, if any.
+static EXEC_OPEN: Mutex<RefCell<Option<usize>>> = Mutex::new(RefCell::new(None));
+
+/// Errors returned by the RAM function API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RamFuncError {
+    /// Region size is not a power of two >= 32, or base is not aligned to it
+    BadRegion,
+    /// Code does not fit into the region
+    TooLarge,
+    /// Region contents no longer match the digest recorded at install
+    IntegrityMismatch,
+    /// Another RAM function region is already unlocked
+    Busy,
+}
+
+/// An installed, hash-pinned RAM code region.
+#[derive(Debug)]
+pub struct RamFunc {
+    base: usize,
+    size: usize,
+    code_len: usize,
+    size_field: u32,
+    digest: [u8; 32],
+}
+
+impl RamFunc {
+    /// Copy `code` into `region` and pin its SHA-256 digest.
+    ///
+    /// `region` must be a power-of-two sized buffer (>= 32 bytes) aligned
+    /// to its own size, as required by the MPU. The unused tail is zeroed
+    /// and covered by the digest as well. The region is left locked.
+    pub fn install(region: &'static mut [u8], code: &[u8]) -> Result<Self, RamFuncError> {
+        let size = region.len();
+        let base = region.as_ptr() as usize;
+        if size < MIN_REGION_SIZE || !size.is_power_of_two() || base & (size - 1) != 0 {
+            return Err(RamFuncError::BadRegion);
+        }
+        if code.len() > size {
+            return Err(RamFuncError::TooLarge);
+        }
+
+        region[..code.len()].copy_from_slice(code);
+        region[code.len()..].fill(0);
+        let digest = hash(region);
+
+        Ok(Self {
+            base,
+            size,
+            code_len: code.len(),
+            // size = 2^(SIZE + 1)
+            size_field: size.trailing_zeros() - 1,
+            digest,
+        })
+    }
+
+    /// Recompute the region digest and compare it with the pinned one.
+    pub fn verify(&self) -> Result<(), RamFuncError> {
+        // SAFETY: the region was handed over as `&'static mut` at install
+        // and is only ever read through this handle afterwards.
+        let bytes = unsafe { core::slice::from_raw_parts(self.base as *const u8, self.size) };
+        if ct_eq(&hash(bytes), &self.digest) {
+            Ok(())
+        } else {
+            Err(RamFuncError::IntegrityMismatch)
+        }
+    }
+
+    /// Verify the region and make it executable until the returned window
+    /// is dropped.
+    ///
+    /// Only one region may be executable at a time.
+    pub fn unlock(&self) -> Result<ExecWindow<'_>, RamFuncError> {
+        interrupt::free(|cs| {
+            let mut open = EXEC_OPEN.borrow(cs).borrow_mut();
+            if open.is_some() {
+                return Err(RamFuncError::Busy);
+            }
+            // Verify inside the critical section so nothing can modify the
+            // region between the check and the permission change.
+            self.verify()?;
+            unsafe { mpu::program_ramfunc_region(self.base as u32, self.size_field, true) };
+            *open = Some(self.base);
+            Ok(ExecWindow { func: self })
+        })
+    }
+
+    /// Number of code bytes installed.
+    pub fn code_len(&self) -> usize {
+        self.code_len
+    }
+
+    /// Pinned SHA-256 digest of the region.
+    pub fn digest(&self) -> &[u8; 32] {
+        &self.digest
+    }
+}
+
+/// Scope during which a `RamFunc` region is executable.
+pub struct ExecWindow<'a> {
+    func: &'a RamFunc,
+}
+
+impl ExecWindow<'_> {
+    /// Thumb entry address of the installed code (bit 0 set), suitable for
+    /// transmuting into an `extern "C" fn`.
+    pub fn entry(&self) -> usize {
+        self.func.base | 1
+    }
+}
+
+impl Drop for ExecWindow<'_> {
+    fn drop(&mut self) {
+        interrupt::free(|cs| {
+            unsafe { mpu::program_ramfunc_region(self.func.base as u32, self.func.size_field, false) };
+            *EXEC_OPEN.borrow(cs).borrow_mut() = None;
+        });
+    }
+}
+
+fn hash(bytes: &[u8]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&Sha256::digest(bytes));
+    out
+}
+
+/// Constant-time comparison of two digests.
+fn ct_eq(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a.iter().zip(b.iter()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(align(64))]
+    struct Region([u8; 64]);
+
+    #[test]
+    fn install_pins_digest_and_detects_tampering() {
+        let region: &'static mut Region = Box::leak(Box::new(Region([0xFF; 64])));
+        let func = RamFunc::install(&mut region.0, &[0x70, 0x47]).unwrap(); // bx lr
+        assert_eq!(func.code_len(), 2);
+        assert_eq!(func.size_field, 5);
+        assert!(func.verify().is_ok());
+
+        unsafe { *(func.base as *mut u8).add(10) = 0xAA };
+        assert_eq!(func.verify(), Err(RamFuncError::IntegrityMismatch));
+    }
+
+    #[test]
+    fn rejects_regions_the_mpu_cannot_describe() {
+        let odd: &'static mut [u8] = Box::leak(Box::new([0u8; 48]));
+        assert_eq!(RamFunc::install(odd, &[0]).err(), Some(RamFuncError::BadRegion));
+
+        let region: &'static mut Region = Box::leak(Box::new(Region([0; 64])));
+        assert_eq!(RamFunc::install(&mut region.0, &[0; 65]).err(), Some(RamFuncError::TooLarge));
+    }
+}
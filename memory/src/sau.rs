@@ -0,0 +1,130 @@
+//! SecureIoTOS SAU Module
+//! ----------------------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! TrustZone-M Security Attribution Unit setup, run by the Secure image
+//! before it starts the Non-secure one (feature `trustzone`).
+//!
+//! Memory is Secure unless a SAU region marks it Non-secure or
+//! Non-secure callable (the veneers of Secure entry functions). Parts
+//! with an IDAU combine both; the stricter attribution wins. The map is
+//! board specific, e.g. for an STM32L5 with the upper flash bank
+//! Non-secure:
+//!
+//! ```ignore
+//! const NS_MAP: [NsRegion; 4] = [
+//!     NsRegion { base: 0x0804_0000, limit: 0x0807_FFFF, callable: false }, // NS flash
+//!     NsRegion { base: 0x0C03_E000, limit: 0x0C03_FFFF, callable: true },  // NSC veneers
+//!     NsRegion { base: 0x2001_8000, limit: 0x2003_FFFF, callable: false }, // NS SRAM
+//!     NsRegion { base: 0x4000_0000, limit: 0x4FFF_FFFF, callable: false }, // NS peripherals
+//! ];
+//! setup_sau(&mut cp.SAU, &NS_MAP)?;
+//! ```
+//!
+//! On parts whose attribution lives entirely in the IDAU (the nRF5340
+//! SPU), pass an empty map: the SAU is left in ALLNS mode and defers to it.
+//!
+//! `check_map` validates a map without touching hardware; `setup_sau`
+//! needs a `thumbv8m.main` target.
+
+// Core kernel path: must not panic (see `tools/no-panic-check`).
+#![cfg_attr(not(test), deny(
+    clippy::panic,
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::indexing_slicing,
+    clippy::unreachable,
+    clippy::todo,
+    clippy::unimplemented
+))]
+
+/// SAU regions on current Cortex-M33 parts (STM32L5, nRF5340).
+pub const MAX_SAU_REGIONS: usize = 8;
+
+/// One Non-secure (or Non-secure callable) address range, inclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NsRegion {
+    /// First address, 32-byte aligned
+    pub base: u32,
+    /// Last address, one below a 32-byte boundary
+    pub limit: u32,
+    /// Non-secure callable (Secure gateway veneers) instead of Non-secure
+    pub callable: bool,
+}
+
+/// Why a SAU map was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SauConfigError {
+    /// More regions than the SAU implements
+    TooManyRegions,
+    /// Region `n` is not on 32-byte boundaries, or ends before it starts
+    BadRegion(usize),
+    /// Region `n` overlaps an earlier one
+    Overlap(usize),
+}
+
+/// Check `regions` against a SAU with `available` regions.
+pub fn check_map(regions: &[NsRegion], available: usize) -> Result<(), SauConfigError> {
+    if regions.len() > available.min(MAX_SAU_REGIONS) {
+        return Err(SauConfigError::TooManyRegions);
+    }
+    for (n, r) in regions.iter().enumerate() {
+        if r.base & 0x1F != 0 || r.limit & 0x1F != 0x1F || r.limit < r.base {
+            return Err(SauConfigError::BadRegion(n));
+        }
+        if regions.iter().take(n).any(|o| r.base <= o.limit && o.base <= r.limit) {
+            return Err(SauConfigError::Overlap(n));
+        }
+    }
+    Ok(())
+}
+
+/// Program `regions` into the SAU, enable it and enable SecureFault, so
+/// a Non-secure access to Secure memory faults in its own handler
+/// instead of escalating to HardFault. An empty map leaves the SAU in
+/// ALLNS mode (attribution by the IDAU only).
+#[cfg(feature = "trustzone")]
+pub fn setup_sau(sau: &mut cortex_m::peripheral::SAU, regions: &[NsRegion]) -> Result<(), SauConfigError> {
+    use cortex_m::peripheral::sau::{SauRegion, SauRegionAttribute};
+
+    if regions.is_empty() {
+        sau.disable_allns();
+        return Ok(());
+    }
+    check_map(regions, usize::from(sau.region_numbers()))?;
+    let mut hw = [SauRegion { base_address: 0, limit_address: 0x1F, attribute: SauRegionAttribute::Secure }; MAX_SAU_REGIONS];
+    for (slot, r) in hw.iter_mut().zip(regions) {
+        let attribute = if r.callable { SauRegionAttribute::NonSecureCallable } else { SauRegionAttribute::NonSecure };
+        *slot = SauRegion { base_address: r.base, limit_address: r.limit, attribute };
+    }
+    sau.init(hw.get(..regions.len()).unwrap_or_default()).map_err(|_| SauConfigError::TooManyRegions)?;
+    // SAFETY: SHCSR.SECUREFAULTENA only changes which handler reports
+    // security violations.
+    unsafe { (*cortex_m::peripheral::SCB::PTR).shcsr.modify(|r| r | (1 << 19)) };
+    cortex_m::asm::dsb();
+    cortex_m::asm::isb();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NS_FLASH: NsRegion = NsRegion { base: 0x0804_0000, limit: 0x0807_FFFF, callable: false };
+    const NSC: NsRegion = NsRegion { base: 0x0C03_E000, limit: 0x0C03_FFFF, callable: true };
+
+    #[test]
+    fn maps_are_validated() {
+        assert_eq!(check_map(&[NS_FLASH, NSC], 8), Ok(()));
+        assert_eq!(check_map(&[NS_FLASH, NSC], 1), Err(SauConfigError::TooManyRegions));
+        assert_eq!(check_map(&[NS_FLASH, NsRegion { base: 0x2000_0010, ..NSC }], 8), Err(SauConfigError::BadRegion(1)));
+        assert_eq!(check_map(&[NsRegion { limit: 0x0804_0000, ..NS_FLASH }], 8), Err(SauConfigError::BadRegion(0)));
+        let inside = NsRegion { base: 0x0807_0000, limit: 0x0808_FFFF, callable: true };
+        assert_eq!(check_map(&[NSC, NS_FLASH, inside], 8), Err(SauConfigError::Overlap(2)));
+    }
+}
@@ -0,0 +1,380 @@
+//! SecureIoTOS Secure RAM Module
+//! -----------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Encrypted RAM regions for sensitive data at rest in memory.
+//!
+//! Parts without a secure element still need somewhere to keep session keys
+//! and auth tokens. A `SecureRegion` keeps its contents encrypted with
+//! ChaCha20 under a per-boot RAM key, and only exposes plaintext inside the
+//! closure passed to `with_plaintext()`. The plaintext copy lives on the
+//! caller's stack and is wiped as soon as the closure returns.
+//!
+//! On TrustZone parts (ARMv8-M) place the regions in secure RAM instead by
+//! putting them in the `.secure_ram` linker section, e.g.
+//! `#[link_section = ".secure_ram"] static TOKEN: SecureRegion<64> = ...`.
+//!
+//! ## Notes
+//! - The RAM key itself must be installed once at boot via `init_secure_ram()`
+//!   (typically from `crypto::rng`). Until then every access fails closed.
+//! - `reseal()` re-encrypts a region under a fresh nonce. Regions handed to
+//!   `register_region()` are resealed by the kernel on every context switch
+//!   (`reseal_registered()` from `PendSV`), so their ciphertext never stays
+//!   constant for long.
+//! - The key is only ever borrowed inside a critical section; no copy of it
+//!   is left on the stack.
+
+use core::cell::RefCell;
+use critical_section::{CriticalSection, Mutex};
+
+/// Per-boot RAM encryption key (256-bit). `None` until `init_secure_ram()`.
+static RAM_KEY: Mutex<RefCell<Option<[u8; 32]>>> = Mutex::new(RefCell::new(None));
+
+/// Largest region the accessor API will decrypt onto the stack.
+pub const MAX_REGION_SIZE: usize = 256;
+
+/// Regions `reseal_registered()` can track.
+pub const MAX_REGISTERED_REGIONS: usize = 8;
+
+/// Regions resealed on every context switch.
+static REGISTRY: Mutex<RefCell<[Option<&'static dyn Reseal>; MAX_REGISTERED_REGIONS]>> =
+    Mutex::new(RefCell::new([None; MAX_REGISTERED_REGIONS]));
+
+/// Errors returned by secure RAM accessors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecureRamError {
+    /// `init_secure_ram()` has not been called yet
+    KeyUninitialized,
+    /// Data does not fit into the region
+    TooLarge,
+    /// Nonce counter exhausted; region must be cleared and re-keyed
+    NonceExhausted,
+    /// All `MAX_REGISTERED_REGIONS` registry slots are taken
+    RegistryFull,
+}
+
+/// Install the per-boot RAM key. Call once during early boot.
+///
+/// `key` is moved into the key slot and the caller's buffer is wiped. The
+/// previous key (if any) is wiped too. Regions sealed under the old key
+/// become unreadable, so only call this before any region is used.
+pub fn init_secure_ram(key: &mut [u8; 32]) {
+    critical_section::with(|cs| {
+        let mut guard = RAM_KEY.borrow(cs).borrow_mut();
+        let slot = guard.get_or_insert([0u8; 32]);
+        wipe(slot);
+        slot.copy_from_slice(key);
+    });
+    wipe(key);
+}
+
+/// Borrow the RAM key for the duration of the critical section.
+fn ram_key<'cs>(cs: CriticalSection<'cs>) -> Result<core::cell::Ref<'cs, [u8; 32]>, SecureRamError> {
+    core::cell::Ref::filter_map(RAM_KEY.borrow(cs).borrow(), Option::as_ref)
+        .map_err(|_| SecureRamError::KeyUninitialized)
+}
+
+/// A region the kernel can reseal without knowing its size.
+pub trait Reseal: Sync {
+    fn reseal(&self) -> Result<(), SecureRamError>;
+}
+
+/// Have `region` resealed on every context switch.
+pub fn register_region(region: &'static dyn Reseal) -> Result<(), SecureRamError> {
+    critical_section::with(|cs| {
+        let mut slots = REGISTRY.borrow(cs).borrow_mut();
+        let free = slots.iter_mut().find(|s| s.is_none()).ok_or(SecureRamError::RegistryFull)?;
+        *free = Some(region);
+        Ok(())
+    })
+}
+
+/// Reseal every registered region. Called from the context-switch path;
+/// returns how many regions were resealed. Regions that cannot be resealed
+/// (no key yet, nonce exhausted) keep their current ciphertext.
+pub fn reseal_registered() -> usize {
+    let slots = critical_section::with(|cs| *REGISTRY.borrow(cs).borrow());
+    slots.iter().flatten().filter(|r| r.reseal().is_ok()).count()
+}
+
+/// A fixed-size region of RAM whose contents are kept encrypted.
+///
+/// `N` is the capacity in bytes; `len` tracks how many bytes are in use.
+pub struct SecureRegion<const N: usize> {
+    inner: Mutex<RefCell<RegionState<N>>>,
+}
+
+impl<const N: usize> Default for SecureRegion<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct RegionState<const N: usize> {
+    ciphertext: [u8; N],
+    len: usize,
+    nonce: u64,
+}
+
+impl<const N: usize> SecureRegion<N> {
+    /// Create an empty region (usable in `static` context).
+    pub const fn new() -> Self {
+        Self {
+            inner: Mutex::new(RefCell::new(RegionState {
+                ciphertext: [0u8; N],
+                len: 0,
+                nonce: 0,
+            })),
+        }
+    }
+
+    /// Encrypt `plaintext` into the region under a fresh nonce.
+    pub fn store(&self, plaintext: &[u8]) -> Result<(), SecureRamError> {
+        if plaintext.len() > N || N > MAX_REGION_SIZE {
+            return Err(SecureRamError::TooLarge);
+        }
+        critical_section::with(|cs| {
+            let key = ram_key(cs)?;
+            let mut state = self.inner.borrow(cs).borrow_mut();
+
+            let nonce = state.nonce.checked_add(1).ok_or(SecureRamError::NonceExhausted)?;
+            state.ciphertext = [0u8; N];
+            state.ciphertext[..plaintext.len()].copy_from_slice(plaintext);
+            chacha20_xor(&key, nonce, &mut state.ciphertext);
+            state.nonce = nonce;
+            state.len = plaintext.len();
+            Ok(())
+        })
+    }
+
+    /// Decrypt the region onto the stack and run `f` over the plaintext.
+    ///
+    /// The temporary plaintext buffer is wiped before returning, whether or
+    /// not `f` used it. Keep `f` short: it runs inside a critical section.
+    pub fn with_plaintext<R>(&self, f: impl FnOnce(&[u8]) -> R) -> Result<R, SecureRamError> {
+        if N > MAX_REGION_SIZE {
+            return Err(SecureRamError::TooLarge);
+        }
+        critical_section::with(|cs| {
+            let key = ram_key(cs)?;
+            let state = self.inner.borrow(cs).borrow();
+
+            let mut scratch = [0u8; MAX_REGION_SIZE];
+            scratch[..N].copy_from_slice(&state.ciphertext);
+            chacha20_xor(&key, state.nonce, &mut scratch[..N]);
+
+            let result = f(&scratch[..state.len]);
+            wipe(&mut scratch);
+            Ok(result)
+        })
+    }
+
+    /// Re-encrypt the region under a new nonce without exposing plaintext
+    /// to the caller. An empty region is left alone.
+    pub fn reseal(&self) -> Result<(), SecureRamError> {
+        critical_section::with(|cs| {
+            let key = ram_key(cs)?;
+            let mut state = self.inner.borrow(cs).borrow_mut();
+            if state.len == 0 {
+                return Ok(());
+            }
+
+            let next = state.nonce.checked_add(1).ok_or(SecureRamError::NonceExhausted)?;
+            // Decrypt with the old nonce, encrypt with the new one, in place.
+            let old = state.nonce;
+            chacha20_xor(&key, old, &mut state.ciphertext);
+            chacha20_xor(&key, next, &mut state.ciphertext);
+            state.nonce = next;
+            Ok(())
+        })
+    }
+
+    /// Clear the region (ciphertext and length).
+    pub fn clear(&self) {
+        critical_section::with(|cs| {
+            let mut state = self.inner.borrow(cs).borrow_mut();
+            wipe(&mut state.ciphertext);
+            state.len = 0;
+        });
+    }
+
+    /// Number of plaintext bytes currently stored.
+    pub fn len(&self) -> usize {
+        critical_section::with(|cs| self.inner.borrow(cs).borrow().len)
+    }
+
+    /// True if nothing has been stored.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<const N: usize> Reseal for SecureRegion<N> {
+    fn reseal(&self) -> Result<(), SecureRamError> {
+        SecureRegion::reseal(self)
+    }
+}
+
+/// Overwrite a buffer with zeros using volatile writes so the compiler
+/// cannot elide the wipe.
+#[inline(never)]
+fn wipe(buf: &mut [u8]) {
+    for b in buf.iter_mut() {
+        unsafe { core::ptr::write_volatile(b, 0) };
+    }
+    core::sync::atomic::compiler_fence(core::sync::atomic::Ordering::SeqCst);
+}
+
+// ---------------------------
+// ChaCha20 (RFC 8439) keystream
+// ---------------------------
+// Small, table-free and constant-time: a good fit for a RAM cipher on
+// cores without an AES engine.
+
+#[inline(always)]
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]); s[d] ^= s[a]; s[d] = s[d].rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]); s[b] ^= s[c]; s[b] = s[b].rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]); s[d] ^= s[a]; s[d] = s[d].rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]); s[b] ^= s[c]; s[b] = s[b].rotate_left(7);
+}
+
+/// Produce one 64-byte ChaCha20 block.
+fn chacha20_block(key: &[u8; 32], counter: u32, nonce: &[u8; 12]) -> [u8; 64] {
+    let mut state = [0u32; 16];
+    // "expand 32-byte k"
+    state[0] = 0x6170_7865;
+    state[1] = 0x3320_646e;
+    state[2] = 0x7962_2d32;
+    state[3] = 0x6b20_6574;
+    for i in 0..8 {
+        state[4 + i] = u32::from_le_bytes([key[4 * i], key[4 * i + 1], key[4 * i + 2], key[4 * i + 3]]);
+    }
+    state[12] = counter;
+    for i in 0..3 {
+        state[13 + i] = u32::from_le_bytes([nonce[4 * i], nonce[4 * i + 1], nonce[4 * i + 2], nonce[4 * i + 3]]);
+    }
+
+    let mut working = state;
+    for _ in 0..10 {
+        quarter_round(&mut working, 0, 4, 8, 12);
+        quarter_round(&mut working, 1, 5, 9, 13);
+        quarter_round(&mut working, 2, 6, 10, 14);
+        quarter_round(&mut working, 3, 7, 11, 15);
+        quarter_round(&mut working, 0, 5, 10, 15);
+        quarter_round(&mut working, 1, 6, 11, 12);
+        quarter_round(&mut working, 2, 7, 8, 13);
+        quarter_round(&mut working, 3, 4, 9, 14);
+    }
+
+    let mut out = [0u8; 64];
+    for i in 0..16 {
+        let word = working[i].wrapping_add(state[i]);
+        out[4 * i..4 * i + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    out
+}
+
+/// XOR `buf` with the ChaCha20 keystream for (`key`, `nonce`).
+/// The 64-bit region nonce occupies the last 8 bytes of the 96-bit IV.
+fn chacha20_xor(key: &[u8; 32], nonce: u64, buf: &mut [u8]) {
+    let mut iv = [0u8; 12];
+    iv[4..].copy_from_slice(&nonce.to_le_bytes());
+
+    for (block_idx, chunk) in buf.chunks_mut(64).enumerate() {
+        let mut ks = chacha20_block(key, block_idx as u32, &iv);
+        for (b, k) in chunk.iter_mut().zip(ks.iter()) {
+            *b ^= *k;
+        }
+        wipe(&mut ks);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chacha20_block_matches_rfc8439() {
+        // RFC 8439, section 2.3.2 test vector
+        let mut key = [0u8; 32];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        let nonce = [0, 0, 0, 0x09, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+        let block = chacha20_block(&key, 1, &nonce);
+        assert_eq!(
+            &block[..16],
+            &[0x10, 0xf1, 0xe7, 0xe4, 0xd1, 0x3b, 0x59, 0x15, 0x50, 0x0f, 0xdd, 0x1f, 0xa3, 0x20, 0x71, 0xc4]
+        );
+    }
+
+    #[test]
+    fn keystream_roundtrip_and_nonce_separation() {
+        let key = [7u8; 32];
+        let mut a = *b"session-token-123";
+        let original = a;
+
+        chacha20_xor(&key, 1, &mut a);
+        assert_ne!(a, original);
+
+        let mut b = original;
+        chacha20_xor(&key, 2, &mut b);
+        assert_ne!(a, b, "different nonces must give different ciphertext");
+
+        chacha20_xor(&key, 1, &mut a);
+        assert_eq!(a, original);
+    }
+
+    fn ciphertext<const N: usize>(region: &SecureRegion<N>) -> [u8; N] {
+        critical_section::with(|cs| region.inner.borrow(cs).borrow().ciphertext)
+    }
+
+    #[test]
+    fn init_takes_the_key_and_wipes_the_callers_copy() {
+        let mut key = [0x5a; 32];
+        init_secure_ram(&mut key);
+        assert_eq!(key, [0u8; 32]);
+        assert!(critical_section::with(|cs| ram_key(cs).map(|k| *k == [0x5a; 32])).unwrap());
+    }
+
+    #[test]
+    fn region_roundtrip_reseal_and_clear() {
+        init_secure_ram(&mut [0x5a; 32]);
+        let region: SecureRegion<32> = SecureRegion::new();
+        assert_eq!(region.store(&[0u8; 33]), Err(SecureRamError::TooLarge));
+
+        region.store(b"auth-token").unwrap();
+        let sealed = ciphertext(&region);
+        assert_ne!(&sealed[..10], b"auth-token");
+        assert_eq!(region.with_plaintext(|p| p.to_vec()).unwrap(), b"auth-token");
+
+        region.reseal().unwrap();
+        assert_ne!(ciphertext(&region), sealed, "reseal must change the ciphertext");
+        assert_eq!(region.with_plaintext(|p| p.to_vec()).unwrap(), b"auth-token");
+
+        region.clear();
+        assert!(region.is_empty());
+        assert_eq!(ciphertext(&region), [0u8; 32]);
+        region.reseal().unwrap();
+        assert_eq!(ciphertext(&region), [0u8; 32], "an empty region is not resealed");
+    }
+
+    #[test]
+    fn registered_regions_are_resealed_on_switch() {
+        static TOKEN: SecureRegion<16> = SecureRegion::new();
+        init_secure_ram(&mut [0x5a; 32]);
+        TOKEN.store(b"session").unwrap();
+        register_region(&TOKEN).unwrap();
+
+        let before = ciphertext(&TOKEN);
+        assert!(reseal_registered() >= 1);
+        assert_ne!(ciphertext(&TOKEN), before);
+        assert_eq!(TOKEN.with_plaintext(|p| p.to_vec()).unwrap(), b"session");
+    }
+}
@@ -0,0 +1,585 @@
+//! SecureIoTOS Size-Class Heap Module
+//! ----------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Size-class segregated allocator placed in front of `linked_list_allocator`.
+//!
+//! Long-running devices that mix many small, short-lived allocations with a
+//! few large buffers fragment a single free list badly. Here the heap region
+//! is split at init time into:
+//! - a **small** arena of fixed `SMALL_BLOCK`-byte blocks,
+//! - a **medium** arena of fixed `MEDIUM_BLOCK`-byte blocks,
+//! - the remainder, handed to `linked_list_allocator` for **large** requests.
+//!
+//! Fixed-size blocks can never fragment, so small objects stop punching holes
+//! into the large-object heap. Arena sizes are const generic parameters, so
+//! they are fixed at compile time (see [`KernelHeap`] for the default split).
+//! Each class keeps its own [`ClassStats`]; [`ClassedHeap::stats_extended`]
+//! adds whole-heap figures (peak, failures, largest free block).
+//!
+//! With the `alloc-fail-injection` feature, [`ClassedHeap::set_fail_injection`]
+//! makes chosen allocations fail on purpose so OOM paths can be tested.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::RefCell;
+use core::ptr::{self, NonNull};
+use critical_section::Mutex;
+use linked_list_allocator::Heap;
+
+/// Block size of the small class (bytes).
+pub const SMALL_BLOCK: usize = 32;
+/// Block size of the medium class (bytes).
+pub const MEDIUM_BLOCK: usize = 128;
+/// Largest alignment served from the fixed-block arenas; stricter requests
+/// go to the large heap.
+pub const ARENA_ALIGN: usize = 8;
+
+/// Default arena sizes used by the kernel heap.
+pub const DEFAULT_SMALL_ARENA: usize = 4 * 1024;
+pub const DEFAULT_MEDIUM_ARENA: usize = 4 * 1024;
+
+/// Kernel heap with the default compile-time arena split.
+pub type KernelHeap = ClassedHeap<DEFAULT_SMALL_ARENA, DEFAULT_MEDIUM_ARENA>;
+
+/// Allocation class.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeClass {
+    Small,
+    Medium,
+    Large,
+}
+
+/// Per-class counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClassStats {
+    /// Block size in bytes (0 for the large class)
+    pub block_size: usize,
+    /// Arena capacity in bytes
+    pub capacity: usize,
+    /// Bytes currently handed out (whole blocks for fixed classes)
+    pub in_use: usize,
+    /// Highest `in_use` seen
+    pub peak: usize,
+    /// Successful allocations
+    pub allocs: u32,
+    /// Deallocations
+    pub frees: u32,
+    /// Requests this class could not serve (spilled to the next class or,
+    /// for the large class, failed)
+    pub misses: u32,
+}
+
+impl ClassStats {
+    fn record_alloc(&mut self, bytes: usize) {
+        self.allocs = self.allocs.wrapping_add(1);
+        self.in_use += bytes;
+        if self.in_use > self.peak {
+            self.peak = self.in_use;
+        }
+    }
+
+    fn record_free(&mut self, bytes: usize) {
+        self.frees = self.frees.wrapping_add(1);
+        self.in_use = self.in_use.saturating_sub(bytes);
+    }
+}
+
+/// Snapshot of all classes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    pub small: ClassStats,
+    pub medium: ClassStats,
+    pub large: ClassStats,
+}
+
+/// Whole-heap statistics, see [`ClassedHeap::stats_extended`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExtendedStats {
+    /// Heap bytes, all classes
+    pub total: usize,
+    /// Bytes in use, all classes
+    pub used: usize,
+    /// Highest `used` seen
+    pub peak: usize,
+    /// Successful allocations, all classes
+    pub allocs: u32,
+    /// Deallocations, all classes
+    pub frees: u32,
+    /// Allocations that returned null (injected failures included)
+    pub failures: u32,
+    /// Largest single request the heap could serve right now
+    pub largest_free: usize,
+    /// Share of the large heap's free bytes outside its largest free
+    /// block, in percent (0: not fragmented)
+    pub fragmentation_pct: u8,
+    pub classes: HeapStats,
+}
+
+/// Which allocations to fail on purpose (`alloc-fail-injection` feature).
+#[cfg(feature = "alloc-fail-injection")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailInjection {
+    Off,
+    /// Let `n` more allocations succeed, then fail all of them
+    After(u32),
+    /// Fail every `n`-th allocation
+    EveryNth(u32),
+    /// Fail requests of at least this many bytes
+    AtLeast(usize),
+}
+
+/// Fixed-block arena with an intrusive free list (each free block stores
+/// the address of the next one; 0 terminates the list).
+struct Arena {
+    base: usize,
+    end: usize,
+    block: usize,
+    free: usize,
+    stats: ClassStats,
+}
+
+impl Arena {
+    const fn empty(block: usize) -> Self {
+        Self {
+            base: 0,
+            end: 0,
+            block,
+            free: 0,
+            stats: ClassStats {
+                block_size: block,
+                capacity: 0,
+                in_use: 0,
+                peak: 0,
+                allocs: 0,
+                frees: 0,
+                misses: 0,
+            },
+        }
+    }
+
+    /// Carve `bytes` starting at `base` into blocks and thread the free list.
+    unsafe fn init(&mut self, base: usize, bytes: usize) {
+        let blocks = bytes / self.block;
+        self.base = base;
+        self.end = base + blocks * self.block;
+        self.stats.capacity = blocks * self.block;
+        self.free = 0;
+        // Push in reverse so blocks are handed out in address order.
+        for i in (0..blocks).rev() {
+            let block = base + i * self.block;
+            ptr::write(block as *mut usize, self.free);
+            self.free = block;
+        }
+    }
+
+    fn contains(&self, addr: usize) -> bool {
+        addr >= self.base && addr < self.end
+    }
+
+    fn fits(&self, layout: &Layout) -> bool {
+        layout.size() <= self.block && layout.align() <= ARENA_ALIGN
+    }
+
+    unsafe fn alloc(&mut self) -> Option<NonNull<u8>> {
+        let block = self.free;
+        if block == 0 {
+            self.stats.misses = self.stats.misses.wrapping_add(1);
+            return None;
+        }
+        self.free = ptr::read(block as *const usize);
+        self.stats.record_alloc(self.block);
+        NonNull::new(block as *mut u8)
+    }
+
+    unsafe fn dealloc(&mut self, block: usize) {
+        ptr::write(block as *mut usize, self.free);
+        self.free = block;
+        self.stats.record_free(self.block);
+    }
+}
+
+struct Inner {
+    small: Arena,
+    medium: Arena,
+    large: Heap,
+    large_stats: ClassStats,
+    /// Highest total bytes in use
+    peak: usize,
+    failures: u32,
+    #[cfg(feature = "alloc-fail-injection")]
+    inject: FailInjection,
+    /// Allocations seen since `inject` was set
+    #[cfg(feature = "alloc-fail-injection")]
+    inject_seen: u32,
+}
+
+impl Inner {
+    fn used(&self) -> usize {
+        self.small.stats.in_use + self.medium.stats.in_use + self.large_stats.in_use
+    }
+
+    fn record_success(&mut self) {
+        let used = self.used();
+        if used > self.peak {
+            self.peak = used;
+        }
+    }
+
+    #[cfg(feature = "alloc-fail-injection")]
+    fn inject_failure(&mut self, layout: &Layout) -> bool {
+        self.inject_seen = self.inject_seen.wrapping_add(1);
+        match self.inject {
+            FailInjection::Off => false,
+            FailInjection::After(n) => self.inject_seen > n,
+            FailInjection::EveryNth(n) => n != 0 && self.inject_seen.is_multiple_of(n),
+            FailInjection::AtLeast(size) => layout.size() >= size,
+        }
+    }
+
+    #[cfg(not(feature = "alloc-fail-injection"))]
+    fn inject_failure(&mut self, _layout: &Layout) -> bool {
+        false
+    }
+
+    /// Largest block the large heap can hand out, found by probing:
+    /// binary search over trial allocations that are freed again at once.
+    fn largest_large_free(&mut self) -> usize {
+        let (mut lo, mut hi) = (0, self.large.free());
+        while lo < hi {
+            let mid = lo + (hi - lo).div_ceil(2);
+            let Ok(layout) = Layout::from_size_align(mid, ARENA_ALIGN) else { break };
+            match self.large.allocate_first_fit(layout) {
+                Ok(p) => {
+                    // SAFETY: `p` was allocated with `layout` just now.
+                    unsafe { self.large.deallocate(p, layout) };
+                    lo = mid;
+                }
+                Err(()) => hi = mid - 1,
+            }
+        }
+        lo
+    }
+}
+
+/// Size-class segregated heap; use as `#[global_allocator]`.
+///
+/// `SMALL_ARENA` / `MEDIUM_ARENA` are the arena sizes in bytes, taken from
+/// the front of the region passed to [`ClassedHeap::init`].
+pub struct ClassedHeap<const SMALL_ARENA: usize, const MEDIUM_ARENA: usize> {
+    inner: Mutex<RefCell<Inner>>,
+}
+
+impl<const SMALL_ARENA: usize, const MEDIUM_ARENA: usize> ClassedHeap<SMALL_ARENA, MEDIUM_ARENA> {
+    /// Uninitialized heap; every allocation fails until `init` is called.
+    pub const fn empty() -> Self {
+        Self {
+            inner: Mutex::new(RefCell::new(Inner {
+                small: Arena::empty(SMALL_BLOCK),
+                medium: Arena::empty(MEDIUM_BLOCK),
+                large: Heap::empty(),
+                large_stats: ClassStats {
+                    block_size: 0,
+                    capacity: 0,
+                    in_use: 0,
+                    peak: 0,
+                    allocs: 0,
+                    frees: 0,
+                    misses: 0,
+                },
+                peak: 0,
+                failures: 0,
+                #[cfg(feature = "alloc-fail-injection")]
+                inject: FailInjection::Off,
+                #[cfg(feature = "alloc-fail-injection")]
+                inject_seen: 0,
+            })),
+        }
+    }
+
+    /// Hand the heap region `[start, start + size)` to the allocator.
+    ///
+    /// # Safety
+    /// - The region must be valid, unused RAM owned exclusively by the heap.
+    /// - Must be called once, before the first allocation.
+    ///
+    /// # Panics
+    /// If the region is too small to hold both arenas.
+    pub unsafe fn init(&self, start: usize, size: usize) {
+        let base = align_up(start, ARENA_ALIGN);
+        let reserved = (base - start) + SMALL_ARENA + MEDIUM_ARENA;
+        assert!(size > reserved, "heap region smaller than configured size-class arenas");
+
+        critical_section::with(|cs| {
+            let mut inner = self.inner.borrow(cs).borrow_mut();
+            inner.small.init(base, SMALL_ARENA);
+            inner.medium.init(base + SMALL_ARENA, MEDIUM_ARENA);
+            inner.large.init((start + reserved) as *mut u8, size - reserved);
+            inner.large_stats.capacity = size - reserved;
+        });
+    }
+
+    /// Class a request of this layout is served from first.
+    pub fn class_of(layout: &Layout) -> SizeClass {
+        if layout.align() > ARENA_ALIGN {
+            SizeClass::Large
+        } else if layout.size() <= SMALL_BLOCK {
+            SizeClass::Small
+        } else if layout.size() <= MEDIUM_BLOCK {
+            SizeClass::Medium
+        } else {
+            SizeClass::Large
+        }
+    }
+
+    /// Per-class statistics snapshot.
+    pub fn stats(&self) -> HeapStats {
+        critical_section::with(|cs| {
+            let inner = self.inner.borrow(cs).borrow();
+            HeapStats {
+                small: inner.small.stats,
+                medium: inner.medium.stats,
+                large: inner.large_stats,
+            }
+        })
+    }
+
+    /// Whole-heap statistics. Finding the largest free block takes a few
+    /// trial allocations inside one critical section (interrupts masked on
+    /// the target), so the figures are a consistent snapshot: for
+    /// diagnostics, not for hot paths.
+    pub fn stats_extended(&self) -> ExtendedStats {
+        critical_section::with(|cs| {
+            let mut inner = self.inner.borrow(cs).borrow_mut();
+            let large_largest = inner.largest_large_free();
+            let large_free = inner.large.free();
+            let classes = HeapStats { small: inner.small.stats, medium: inner.medium.stats, large: inner.large_stats };
+            let arena_largest = [&inner.small, &inner.medium]
+                .iter()
+                .filter(|a| a.free != 0)
+                .map(|a| a.block)
+                .max()
+                .unwrap_or(0);
+            let all = [classes.small, classes.medium, classes.large];
+            ExtendedStats {
+                total: all.iter().map(|c| c.capacity).sum(),
+                used: inner.used(),
+                peak: inner.peak,
+                allocs: all.iter().fold(0u32, |n, c| n.wrapping_add(c.allocs)),
+                frees: all.iter().fold(0u32, |n, c| n.wrapping_add(c.frees)),
+                failures: inner.failures,
+                largest_free: large_largest.max(arena_largest),
+                fragmentation_pct: match large_free {
+                    0 => 0,
+                    free => ((free - large_largest) * 100 / free) as u8,
+                },
+                classes,
+            }
+        })
+    }
+
+    /// Fail allocations per `mode` from now on, to exercise OOM paths.
+    /// `FailInjection::Off` restores normal behaviour.
+    #[cfg(feature = "alloc-fail-injection")]
+    pub fn set_fail_injection(&self, mode: FailInjection) {
+        critical_section::with(|cs| {
+            let mut inner = self.inner.borrow(cs).borrow_mut();
+            inner.inject = mode;
+            inner.inject_seen = 0;
+        });
+    }
+
+    /// Total heap bytes (all classes) and bytes in use.
+    pub fn usage(&self) -> (usize, usize) {
+        let s = self.stats();
+        (
+            s.small.capacity + s.medium.capacity + s.large.capacity,
+            s.small.in_use + s.medium.in_use + s.large.in_use,
+        )
+    }
+}
+
+unsafe impl<const SMALL_ARENA: usize, const MEDIUM_ARENA: usize> GlobalAlloc
+    for ClassedHeap<SMALL_ARENA, MEDIUM_ARENA>
+{
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        critical_section::with(|cs| {
+            let mut inner = self.inner.borrow(cs).borrow_mut();
+            if inner.inject_failure(&layout) {
+                inner.failures = inner.failures.wrapping_add(1);
+                return ptr::null_mut();
+            }
+
+            // Try the tightest fitting class first, then spill upwards.
+            let mut block = None;
+            if inner.small.fits(&layout) {
+                block = inner.small.alloc();
+            }
+            if block.is_none() && inner.medium.fits(&layout) {
+                block = inner.medium.alloc();
+            }
+            if block.is_none() {
+                match inner.large.allocate_first_fit(layout) {
+                    Ok(p) => {
+                        inner.large_stats.record_alloc(layout.size());
+                        block = Some(p);
+                    }
+                    Err(()) => inner.large_stats.misses = inner.large_stats.misses.wrapping_add(1),
+                }
+            }
+            match block {
+                Some(p) => {
+                    inner.record_success();
+                    p.as_ptr()
+                }
+                None => {
+                    inner.failures = inner.failures.wrapping_add(1);
+                    ptr::null_mut()
+                }
+            }
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        critical_section::with(|cs| {
+            let mut inner = self.inner.borrow(cs).borrow_mut();
+            let addr = ptr as usize;
+            if inner.small.contains(addr) {
+                inner.small.dealloc(addr);
+            } else if inner.medium.contains(addr) {
+                inner.medium.dealloc(addr);
+            } else if let Some(p) = NonNull::new(ptr) {
+                inner.large.deallocate(p, layout);
+                inner.large_stats.record_free(layout.size());
+            }
+        })
+    }
+}
+
+#[inline]
+const fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(align(8))]
+    struct Region([u8; 4096]);
+
+    fn heap(region: &mut Region) -> ClassedHeap<256, 512> {
+        let heap = ClassedHeap::empty();
+        unsafe { heap.init(region.0.as_mut_ptr() as usize, region.0.len()) };
+        heap
+    }
+
+    #[test]
+    fn requests_land_in_their_class() {
+        let mut region = Region([0; 4096]);
+        let h = heap(&mut region);
+        unsafe {
+            let a = h.alloc(Layout::from_size_align(16, 4).unwrap());
+            let b = h.alloc(Layout::from_size_align(100, 8).unwrap());
+            let c = h.alloc(Layout::from_size_align(600, 8).unwrap());
+            assert!(!a.is_null() && !b.is_null() && !c.is_null());
+
+            let s = h.stats();
+            assert_eq!((s.small.allocs, s.medium.allocs, s.large.allocs), (1, 1, 1));
+            assert_eq!(s.small.in_use, SMALL_BLOCK);
+            assert_eq!(s.large.in_use, 600);
+
+            h.dealloc(a, Layout::from_size_align(16, 4).unwrap());
+            h.dealloc(c, Layout::from_size_align(600, 8).unwrap());
+            let s = h.stats();
+            assert_eq!(s.small.in_use, 0);
+            assert_eq!(s.small.peak, SMALL_BLOCK);
+            assert_eq!(s.large.frees, 1);
+        }
+    }
+
+    #[test]
+    fn exhausted_class_spills_upward_and_blocks_are_reused() {
+        let mut region = Region([0; 4096]);
+        let h = heap(&mut region);
+        let small = Layout::from_size_align(8, 8).unwrap();
+        unsafe {
+            let blocks = 256 / SMALL_BLOCK;
+            let mut ptrs = [ptr::null_mut(); 8];
+            for p in ptrs.iter_mut().take(blocks) {
+                *p = h.alloc(small);
+            }
+            let spilled = h.alloc(small);
+            assert!(!spilled.is_null());
+            let s = h.stats();
+            assert_eq!(s.small.misses, 1);
+            assert_eq!(s.medium.allocs, 1);
+
+            // A freed small block is handed out again
+            h.dealloc(ptrs[3], small);
+            assert_eq!(h.alloc(small), ptrs[3]);
+        }
+    }
+
+    #[test]
+    fn extended_stats_track_peak_failures_and_fragmentation() {
+        let mut region = Region([0; 4096]);
+        let h = heap(&mut region);
+        let big = Layout::from_size_align(1000, 8).unwrap();
+        unsafe {
+            let free = h.stats_extended().largest_free;
+            assert_eq!(h.stats_extended().fragmentation_pct, 0);
+
+            let a = h.alloc(big);
+            let b = h.alloc(big);
+            let c = h.alloc(big);
+            assert!(!a.is_null() && !b.is_null() && !c.is_null());
+            assert!(h.alloc(big).is_null());
+            h.dealloc(b, big);
+
+            // Free space is now split by `c`
+            let s = h.stats_extended();
+            assert_eq!((s.allocs, s.frees, s.failures), (3, 1, 1));
+            assert_eq!((s.used, s.peak), (2000, 3000));
+            assert!(s.largest_free >= 1000 && s.largest_free < free - 2000);
+            assert!(s.fragmentation_pct > 0);
+            assert_eq!(h.stats_extended(), s, "probing leaves the heap as it was");
+
+            h.dealloc(a, big);
+            h.dealloc(c, big);
+            assert_eq!(h.stats_extended().largest_free, free);
+        }
+    }
+
+    #[cfg(feature = "alloc-fail-injection")]
+    #[test]
+    fn injected_failures() {
+        let mut region = Region([0; 4096]);
+        let h = heap(&mut region);
+        let small = Layout::from_size_align(8, 8).unwrap();
+        unsafe {
+            h.set_fail_injection(FailInjection::After(2));
+            assert!(!h.alloc(small).is_null());
+            assert!(!h.alloc(small).is_null());
+            assert!(h.alloc(small).is_null());
+
+            h.set_fail_injection(FailInjection::AtLeast(600));
+            assert!(!h.alloc(small).is_null());
+            assert!(h.alloc(Layout::from_size_align(600, 8).unwrap()).is_null());
+
+            h.set_fail_injection(FailInjection::Off);
+            assert!(!h.alloc(Layout::from_size_align(600, 8).unwrap()).is_null());
+            assert_eq!(h.stats_extended().failures, 2);
+        }
+    }
+
+    #[test]
+    fn over_aligned_requests_use_large_heap() {
+        assert_eq!(KernelHeap::class_of(&Layout::from_size_align(8, 64).unwrap()), SizeClass::Large);
+        assert_eq!(KernelHeap::class_of(&Layout::from_size_align(64, 8).unwrap()), SizeClass::Medium);
+    }
+}
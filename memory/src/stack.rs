@@ -7,11 +7,17 @@
 //! URL: https://m-a-h-b-u-b.github.io
 //! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
 //!
-//! Task stack utilities: static stacks, fast fill, watermarking, and canary.
+//! Task stack utilities: static stacks, fast fill, watermarking, canary,
+//! and pools of MPU-guarded stacks.
+//!
+//! A canary only shows an overflow after the fact, once the stack below
+//! is already damaged. `GuardedStackPool` stacks sit directly above a
+//! `STACK_GUARD_SIZE` guard; the scheduler moves the MPU guard region
+//! there (`mpu::set_stack_guard`) when it switches to the task, so the
+//! first push past the bottom raises a MemManage fault instead.
 
-// If we are not running tests, compile this crate without the standard library (no_std).
-// If we are running tests, include std so tests can use standard library features.
-#![cfg_attr(not(test), no_std)]
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 /// Task stacks (1 KiB each). Access requires `unsafe`.
 // Declared static mut because stacks are global and 
@@ -55,11 +61,17 @@ fn write_canary(stack: &mut [u8]) {
     stack[..n].copy_from_slice(&STACK_CANARY);
 }
 
+/// True if the canary at the bottom of `stack` is intact (false if it
+/// was overwritten or `stack` is too short to hold it).
+#[inline]
+pub fn canary_intact(stack: &[u8]) -> bool {
+    stack.get(..STACK_CANARY.len()) == Some(&STACK_CANARY[..])
+}
+
 /// Verify that the canary is intact.  
 /// Panics if overwritten (indicating stack overflow).
 pub fn check_canary(stack: &[u8]) {
-    let n = STACK_CANARY.len();
-    if &stack[..n] != &STACK_CANARY {
+    if !canary_intact(stack) {
         panic!("Stack canary corrupted! Possible overflow detected.");
     }
 }
@@ -73,8 +85,8 @@ pub fn check_canary(stack: &[u8]) {
 /// - Caller must ensure all `TASK_STACK*` statics are valid
 ///   and properly initialized with `init_task_stack()`.
 pub unsafe fn check_canary_all() {
-    check_canary(&TASK_STACK1);
-    check_canary(&TASK_STACK2);
+    check_canary(&*core::ptr::addr_of!(TASK_STACK1));
+    check_canary(&*core::ptr::addr_of!(TASK_STACK2));
 }
 
 /// Return the stack "top" pointer (end of the buffer) aligned to 8 bytes.
@@ -86,14 +98,16 @@ pub fn stack_top_aligned(stack: &mut [u8]) -> *mut u8 {
 }
 
 /// Estimate used stack bytes since last `init_task_stack()`.
+// Stacks grow down: the pattern left untouched sits right above the
+// canary, and everything from the first overwritten byte up was used.
 #[inline]
 pub fn used_stack_bytes(stack: &[u8]) -> usize {
-    let untouched_from_top = stack
+    let above_canary = stack.get(STACK_CANARY.len()..).unwrap_or(&[]);
+    let untouched_from_bottom = above_canary
         .iter()
-        .rev()
         .take_while(|&&b| b == STACK_PATTERN)
         .count();
-    stack.len().saturating_sub(untouched_from_top)
+    above_canary.len() - untouched_from_bottom
 }
 
 /// Convenience: remaining free bytes in the stack.
@@ -102,6 +116,86 @@ pub fn free_stack_bytes(stack: &[u8]) -> usize {
     stack.len() - used_stack_bytes(stack)
 }
 
+/// Size of the no-access guard below a guarded stack: the smallest
+/// ARMv7-M MPU region, which must also be aligned to its size.
+pub const STACK_GUARD_SIZE: usize = 32;
+
+/// A stack taken from a `GuardedStackPool`.
+pub struct GuardedStack {
+    /// Pool slot, for `GuardedStackPool::free`
+    pub slot: usize,
+    /// Base of the guard directly below `stack` (`STACK_GUARD_SIZE` aligned)
+    pub guard: usize,
+    pub stack: &'static mut [u8],
+}
+
+#[repr(C, align(32))]
+struct GuardedSlot<const SIZE: usize> {
+    guard: [u8; STACK_GUARD_SIZE],
+    stack: [u8; SIZE],
+}
+
+/// `N` stacks of up to `SIZE` bytes, each with an MPU guard below it.
+pub struct GuardedStackPool<const N: usize, const SIZE: usize> {
+    used: [AtomicBool; N],
+    slots: [UnsafeCell<GuardedSlot<SIZE>>; N],
+}
+
+// SAFETY: a slot's memory is only handed out to the caller that claimed
+// it via `used`, until it is freed.
+unsafe impl<const N: usize, const SIZE: usize> Sync for GuardedStackPool<N, SIZE> {}
+
+impl<const N: usize, const SIZE: usize> GuardedStackPool<N, SIZE> {
+    const VALID: () = assert!(SIZE > 0 && SIZE.is_multiple_of(STACK_GUARD_SIZE), "SIZE must be a multiple of STACK_GUARD_SIZE");
+
+    pub const fn new() -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::VALID;
+        Self {
+            used: [const { AtomicBool::new(false) }; N],
+            slots: [const { UnsafeCell::new(GuardedSlot { guard: [0; STACK_GUARD_SIZE], stack: [0; SIZE] }) }; N],
+        }
+    }
+
+    /// Claim a slot and carve a stack of `size` bytes, rounded up to
+    /// `STACK_GUARD_SIZE`, from its top; the guard lies directly below
+    /// it. `None` if `size` is 0 or above `SIZE`, or every slot is in use.
+    pub fn alloc(&'static self, size: usize) -> Option<GuardedStack> {
+        let size = size.div_ceil(STACK_GUARD_SIZE) * STACK_GUARD_SIZE;
+        if size == 0 || size > SIZE {
+            return None;
+        }
+        let slot = self
+            .used
+            .iter()
+            .position(|u| u.compare_exchange(false, true, Ordering::AcqRel, Ordering::Relaxed).is_ok())?;
+        let base = self.slots[slot].get() as usize;
+        let bottom = base + STACK_GUARD_SIZE + SIZE - size;
+        // SAFETY: `[bottom, bottom + size)` lies inside the slot we just
+        // claimed, which nobody else touches until `free(slot)`.
+        let stack = unsafe { core::slice::from_raw_parts_mut(bottom as *mut u8, size) };
+        Some(GuardedStack { slot, guard: bottom - STACK_GUARD_SIZE, stack })
+    }
+
+    /// Return slot `slot`. Its stack must no longer be in use.
+    pub fn free(&self, slot: usize) {
+        if let Some(used) = self.used.get(slot) {
+            used.store(false, Ordering::Release);
+        }
+    }
+
+    /// Slots currently handed out.
+    pub fn in_use(&self) -> usize {
+        self.used.iter().filter(|u| u.load(Ordering::Relaxed)).count()
+    }
+}
+
+impl<const N: usize, const SIZE: usize> Default for GuardedStackPool<N, SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -109,8 +203,8 @@ mod tests {
     #[test]
     fn canary_all_ok_then_detects_overflow() {
         unsafe {
-            init_task_stack(&mut TASK_STACK1);
-            init_task_stack(&mut TASK_STACK2);
+            init_task_stack(&mut *core::ptr::addr_of_mut!(TASK_STACK1));
+            init_task_stack(&mut *core::ptr::addr_of_mut!(TASK_STACK2));
 
             // Canary intact, should not panic
             check_canary_all();
@@ -121,4 +215,34 @@ mod tests {
             assert!(result.is_err(), "Expected canary panic on TASK_STACK1");
         }
     }
+
+    #[test]
+    fn watermark_counts_down_from_the_top() {
+        let mut stack = [0u8; 64];
+        init_task_stack(&mut stack);
+        assert_eq!(used_stack_bytes(&stack), 0);
+        stack[40..].fill(0);
+        assert_eq!((used_stack_bytes(&stack), free_stack_bytes(&stack)), (24, 40));
+        stack[8] = 0;
+        assert_eq!(used_stack_bytes(&stack), 56, "everything above the canary");
+    }
+
+    #[test]
+    fn guarded_stacks_sit_on_aligned_guards() {
+        static POOL: GuardedStackPool<2, 256> = GuardedStackPool::new();
+        let full = POOL.alloc(256).unwrap();
+        let small = POOL.alloc(100).unwrap();
+        assert!(POOL.alloc(32).is_none(), "pool exhausted");
+
+        for g in [&full, &small] {
+            assert_eq!(g.guard % STACK_GUARD_SIZE, 0);
+            assert_eq!(g.guard + STACK_GUARD_SIZE, g.stack.as_ptr() as usize, "guard right below the stack");
+        }
+        assert_eq!((full.stack.len(), small.stack.len()), (256, 128));
+
+        POOL.free(small.slot);
+        assert_eq!(POOL.in_use(), 1);
+        assert!(POOL.alloc(257).is_none() && POOL.alloc(0).is_none());
+        assert_eq!(POOL.alloc(64).map(|g| g.slot), Some(small.slot));
+    }
 }
@@ -0,0 +1,267 @@
+//! SecureIoTOS net ARP Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! ARP (RFC 826) for IPv4 over Ethernet: the packet format and the
+//! neighbour cache of an Ethernet `NetInterface`.
+//!
+//! A send to a next hop that is not in the cache broadcasts a request and
+//! fails with `NetError::Unresolved`; sockets keep their data queued and
+//! try again on the next dispatch. Requests for the same address go out
+//! at most once per `REQUEST_INTERVAL_MS`, and entries are trusted for
+//! `ENTRY_TTL_MS` before being resolved afresh.
+//!
+//! The cache only learns from ARP addressed to the interface's own IPv4
+//! address: the sender of a request for us (we are about to answer it),
+//! and the sender of a reply to a request we made. Unsolicited replies and
+//! other hosts' traffic are ignored, and a resolved entry is not replaced
+//! until it expires, which keeps a neighbour on the LAN from taking over
+//! an entry with a gratuitous reply. It does not stop a host that answers
+//! faster than the real owner; that needs a static entry
+//! (`ArpCache::insert_static()`) for the gateway.
+//!
+//! Keyed by `Ipv6Addr`, the same cache holds the neighbours IPv6
+//! neighbour discovery resolves (`ipv6::NeighborCache`), under the same
+//! rules.
+
+use crate::ethernet::MacAddr;
+use crate::Ipv4Addr;
+
+/// Length of an Ethernet/IPv4 ARP packet.
+pub const PACKET_LEN: usize = 28;
+/// How long a learnt entry is used before it is resolved again.
+pub const ENTRY_TTL_MS: u64 = 60_000;
+/// Minimum time between two requests for the same address.
+pub const REQUEST_INTERVAL_MS: u64 = 1_000;
+
+/// ARP operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ArpOp {
+    Request = 1,
+    Reply = 2,
+}
+
+/// An ARP packet for IPv4 over Ethernet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ArpPacket {
+    pub op: ArpOp,
+    pub sender_mac: MacAddr,
+    pub sender_ip: Ipv4Addr,
+    pub target_mac: MacAddr,
+    pub target_ip: Ipv4Addr,
+}
+
+impl ArpPacket {
+    /// Parse an ARP packet; `None` unless it is Ethernet/IPv4.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < PACKET_LEN || data[0..6] != [0, 1, 0x08, 0x00, 6, 4] {
+            return None;
+        }
+        let op = match u16::from_be_bytes([data[6], data[7]]) {
+            1 => ArpOp::Request,
+            2 => ArpOp::Reply,
+            _ => return None,
+        };
+        let mac = |at: usize| MacAddr(data[at..at + 6].try_into().unwrap());
+        let ip = |at: usize| Ipv4Addr::new(data[at], data[at + 1], data[at + 2], data[at + 3]);
+        Some(Self { op, sender_mac: mac(8), sender_ip: ip(14), target_mac: mac(18), target_ip: ip(24) })
+    }
+
+    pub fn emit(&self, data: &mut [u8; PACKET_LEN]) {
+        // Hardware type 1 (Ethernet), protocol IPv4, address lengths 6 and 4
+        data[0..6].copy_from_slice(&[0, 1, 0x08, 0x00, 6, 4]);
+        data[6..8].copy_from_slice(&(self.op as u16).to_be_bytes());
+        data[8..14].copy_from_slice(&self.sender_mac.0);
+        data[14..18].copy_from_slice(&self.sender_ip.octets);
+        data[18..24].copy_from_slice(&self.target_mac.0);
+        data[24..28].copy_from_slice(&self.target_ip.octets);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry<A> {
+    ip: A,
+    /// `None` while a request is outstanding
+    mac: Option<MacAddr>,
+    /// When the address was learnt, or last requested while unresolved
+    stamp_ms: u64,
+    /// Configured by hand: never expires or gets replaced
+    fixed: bool,
+}
+
+/// IP (`Ipv4Addr` by default) to MAC address cache of up to `N`
+/// neighbours.
+#[derive(Debug)]
+pub struct ArpCache<const N: usize, A = Ipv4Addr> {
+    entries: [Option<Entry<A>>; N],
+}
+
+impl<const N: usize, A: Copy + PartialEq> Default for ArpCache<N, A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize, A: Copy + PartialEq> ArpCache<N, A> {
+    pub const fn new() -> Self {
+        Self { entries: [None; N] }
+    }
+
+    /// MAC address of `ip`, if known and not expired at `now_ms`.
+    pub fn lookup(&self, ip: A, now_ms: u64) -> Option<MacAddr> {
+        let entry = self.find(ip)?;
+        let fresh = entry.fixed || now_ms.saturating_sub(entry.stamp_ms) < ENTRY_TTL_MS;
+        entry.mac.filter(|_| fresh)
+    }
+
+    /// Pin `ip` to `mac`: the entry never expires and ARP cannot change
+    /// it. Fails if the cache is full of pinned entries.
+    pub fn insert_static(&mut self, ip: A, mac: MacAddr) -> bool {
+        let Some(slot) = self.slot(ip) else {
+            return false;
+        };
+        *slot = Some(Entry { ip, mac: Some(mac), stamp_ms: 0, fixed: true });
+        true
+    }
+
+    /// Forget every entry learnt from the network; pinned ones stay.
+    pub fn flush(&mut self) {
+        for slot in &mut self.entries {
+            if slot.is_some_and(|e| !e.fixed) {
+                *slot = None;
+            }
+        }
+    }
+
+    /// Known neighbours (expired ones included).
+    pub fn iter(&self) -> impl Iterator<Item = (A, MacAddr)> + '_ {
+        self.entries.iter().flatten().filter_map(|e| Some((e.ip, e.mac?)))
+    }
+
+    /// Note that `ip` is wanted. Returns `true` if a request should be
+    /// sent now, `false` if one went out less than `REQUEST_INTERVAL_MS`
+    /// ago.
+    pub(crate) fn request(&mut self, ip: A, now_ms: u64) -> bool {
+        if let Some(entry) = self.entries.iter_mut().flatten().find(|e| e.ip == ip) {
+            if entry.fixed {
+                return false;
+            }
+            let asked_recently = entry.mac.is_none() && now_ms.saturating_sub(entry.stamp_ms) < REQUEST_INTERVAL_MS;
+            if asked_recently {
+                return false;
+            }
+            // An expired address is not used while it is re-resolved
+            *entry = Entry { ip, mac: None, stamp_ms: now_ms, fixed: false };
+            return true;
+        }
+        match self.slot(ip) {
+            Some(slot) => {
+                *slot = Some(Entry { ip, mac: None, stamp_ms: now_ms, fixed: false });
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Record `ip` at `mac` if a request for it is outstanding, or, with
+    /// `create`, if it is not in the cache at all. A resolved entry is
+    /// never replaced before it expires.
+    pub(crate) fn learn(&mut self, ip: A, mac: MacAddr, now_ms: u64, create: bool) {
+        if !mac.is_unicast() {
+            return;
+        }
+        match self.entries.iter_mut().flatten().find(|e| e.ip == ip) {
+            Some(entry) if entry.mac.is_none() => *entry = Entry { ip, mac: Some(mac), stamp_ms: now_ms, fixed: false },
+            Some(_) => {}
+            None if create => {
+                if let Some(slot) = self.slot(ip) {
+                    *slot = Some(Entry { ip, mac: Some(mac), stamp_ms: now_ms, fixed: false });
+                }
+            }
+            None => {}
+        }
+    }
+
+    fn find(&self, ip: A) -> Option<&Entry<A>> {
+        self.entries.iter().flatten().find(|e| e.ip == ip)
+    }
+
+    /// Slot for `ip`: its own, a free one, or the oldest learnt entry.
+    fn slot(&mut self, ip: A) -> Option<&mut Option<Entry<A>>> {
+        let index = self
+            .entries
+            .iter()
+            .position(|e| e.is_some_and(|e| e.ip == ip))
+            .or_else(|| self.entries.iter().position(Option::is_none))
+            .or_else(|| {
+                (0..N)
+                    .filter(|&i| self.entries[i].is_some_and(|e| !e.fixed))
+                    .min_by_key(|&i| self.entries[i].map_or(0, |e| e.stamp_ms))
+            })?;
+        Some(&mut self.entries[index])
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    const GW: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 1);
+    const HOST: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 20);
+    const MAC_GW: MacAddr = MacAddr([0x02, 0, 0, 0, 0, 1]);
+    const MAC_HOST: MacAddr = MacAddr([0x02, 0, 0, 0, 0, 20]);
+
+    #[test]
+    fn packet_roundtrip() {
+        let packet =
+            ArpPacket { op: ArpOp::Reply, sender_mac: MAC_GW, sender_ip: GW, target_mac: MAC_HOST, target_ip: HOST };
+        let mut raw = [0u8; PACKET_LEN];
+        packet.emit(&mut raw);
+        assert_eq!(ArpPacket::parse(&raw), Some(packet));
+        // Not Ethernet/IPv4
+        raw[5] = 16;
+        assert_eq!(ArpPacket::parse(&raw), None);
+    }
+
+    #[test]
+    fn requests_are_rate_limited_and_entries_expire() {
+        let mut cache = ArpCache::<4>::new();
+        assert!(cache.request(GW, 0));
+        assert!(!cache.request(GW, 500));
+        assert_eq!(cache.lookup(GW, 500), None);
+        assert!(cache.request(GW, REQUEST_INTERVAL_MS));
+
+        cache.learn(GW, MAC_GW, 1_200, false);
+        assert_eq!(cache.lookup(GW, 1_200), Some(MAC_GW));
+        assert!(cache.lookup(GW, 1_200 + ENTRY_TTL_MS).is_none());
+        assert!(cache.request(GW, 1_200 + ENTRY_TTL_MS));
+    }
+
+    #[test]
+    fn only_wanted_or_pinned_addresses_are_learnt() {
+        let mut cache = ArpCache::<2>::new();
+        // Unsolicited: ignored
+        cache.learn(HOST, MAC_HOST, 0, false);
+        assert_eq!(cache.lookup(HOST, 0), None);
+        cache.learn(HOST, MacAddr::BROADCAST, 0, true);
+        assert_eq!(cache.lookup(HOST, 0), None);
+
+        // A pinned gateway cannot be taken over, and survives a full cache
+        assert!(cache.insert_static(GW, MAC_GW));
+        cache.learn(GW, MAC_HOST, 10, true);
+        assert_eq!(cache.lookup(GW, u64::MAX), Some(MAC_GW));
+        for i in 0..4 {
+            cache.learn(Ipv4Addr::new(10, 0, 0, i), MAC_HOST, i as u64, true);
+        }
+        assert_eq!(cache.lookup(GW, 0), Some(MAC_GW));
+        assert_eq!(cache.iter().count(), 2);
+        cache.flush();
+        assert_eq!(cache.iter().collect::<Vec<_>>(), [(GW, MAC_GW)]);
+    }
+}
@@ -0,0 +1,608 @@
+//! SecureIoTOS net DHCP Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! DHCPv4 client (RFC 2131) for an Ethernet `NetInterface`.
+//!
+//! `NetworkStack::enable_dhcp()` starts it; from then on the stack's
+//! `dispatch()` runs its timers and sends its messages, `poll()` feeds it
+//! the server's replies, and the interface's address, netmask and gateway
+//! follow the lease:
+//!
+//! ```text
+//!   INIT -> SELECTING --OFFER--> REQUESTING --ACK--> BOUND
+//!                                                      | T1
+//!                  lease expired or NAK            RENEWING  (unicast to the server)
+//!   INIT <------------------------------------------   | T2
+//!                                                  REBINDING (broadcast)
+//! ```
+//!
+//! Messages are retransmitted after 4, 8, 16, 32 and then every 64
+//! seconds; a REQUEST that gets no answer after four tries starts over
+//! with a DISCOVER. T1 and T2 default to half and seven eighths of the
+//! lease. The offered address is not probed for conflicts (no DHCPDECLINE).
+//!
+//! Transaction ids come from the stack's random source
+//! (`NetworkStack::set_isn_source()`); replies with another id or another
+//! client hardware address are ignored.
+
+use crate::ethernet::MacAddr;
+use crate::Ipv4Addr;
+
+pub const SERVER_PORT: u16 = 67;
+pub const CLIENT_PORT: u16 = 68;
+
+/// Fixed BOOTP fields and the magic cookie.
+const FIXED_LEN: usize = 240;
+/// Messages are padded to the BOOTP minimum (RFC 1542 §3.1.1).
+pub const MIN_MESSAGE_LEN: usize = 300;
+const MAGIC_COOKIE: [u8; 4] = [0x63, 0x82, 0x53, 0x63];
+
+const INITIAL_TIMEOUT_MS: u64 = 4_000;
+const MAX_TIMEOUT_MS: u64 = 64_000;
+/// REQUESTs sent for one offer before starting over
+const MAX_REQUESTS: u8 = 4;
+/// Shortest wait between retransmissions while renewing or rebinding
+/// (RFC 2131 §4.4.5).
+const MIN_RENEW_RETRY_MS: u64 = 60_000;
+
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_DNS: u8 = 6;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_PARAMETER_LIST: u8 = 55;
+const OPT_RENEWAL_TIME: u8 = 58;
+const OPT_REBINDING_TIME: u8 = 59;
+const OPT_CLIENT_ID: u8 = 61;
+const OPT_END: u8 = 255;
+
+const UNSPECIFIED: Ipv4Addr = Ipv4Addr::new(0, 0, 0, 0);
+const BROADCAST: Ipv4Addr = Ipv4Addr::new(255, 255, 255, 255);
+
+/// DHCP message type (option 53).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MessageType {
+    Discover = 1,
+    Offer = 2,
+    Request = 3,
+    Decline = 4,
+    Ack = 5,
+    Nak = 6,
+    Release = 7,
+    Inform = 8,
+}
+
+impl MessageType {
+    fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            1 => Self::Discover,
+            2 => Self::Offer,
+            3 => Self::Request,
+            4 => Self::Decline,
+            5 => Self::Ack,
+            6 => Self::Nak,
+            7 => Self::Release,
+            8 => Self::Inform,
+            _ => return None,
+        })
+    }
+}
+
+/// The fields of a DHCP message the client uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DhcpMessage {
+    /// 1 from a client, 2 from a server
+    pub(crate) op: u8,
+    pub(crate) xid: u32,
+    pub(crate) ciaddr: Ipv4Addr,
+    pub(crate) yiaddr: Ipv4Addr,
+    pub(crate) chaddr: MacAddr,
+    pub(crate) msg_type: MessageType,
+    pub(crate) server_id: Option<Ipv4Addr>,
+    pub(crate) requested_ip: Option<Ipv4Addr>,
+    pub(crate) lease_secs: Option<u32>,
+    pub(crate) renew_secs: Option<u32>,
+    pub(crate) rebind_secs: Option<u32>,
+    pub(crate) subnet_mask: Option<Ipv4Addr>,
+    pub(crate) router: Option<Ipv4Addr>,
+    pub(crate) dns: [Option<Ipv4Addr>; 2],
+}
+
+impl DhcpMessage {
+    fn new(op: u8, xid: u32, chaddr: MacAddr, msg_type: MessageType) -> Self {
+        Self {
+            op,
+            xid,
+            ciaddr: UNSPECIFIED,
+            yiaddr: UNSPECIFIED,
+            chaddr,
+            msg_type,
+            server_id: None,
+            requested_ip: None,
+            lease_secs: None,
+            renew_secs: None,
+            rebind_secs: None,
+            subnet_mask: None,
+            router: None,
+            dns: [None; 2],
+        }
+    }
+
+    /// Parse a message for Ethernet hardware addresses; `None` if it is
+    /// malformed or has no message type.
+    pub(crate) fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < FIXED_LEN || data[1] != 1 || data[2] != 6 || data[236..240] != MAGIC_COOKIE {
+            return None;
+        }
+        let ip = |at: usize| Ipv4Addr::new(data[at], data[at + 1], data[at + 2], data[at + 3]);
+        let mut msg = Self::new(
+            data[0],
+            u32::from_be_bytes(data[4..8].try_into().unwrap()),
+            MacAddr(data[28..34].try_into().unwrap()),
+            MessageType::Discover,
+        );
+        msg.ciaddr = ip(12);
+        msg.yiaddr = ip(16);
+
+        let mut msg_type = None;
+        let mut at = FIXED_LEN;
+        while at < data.len() {
+            let code = data[at];
+            match code {
+                OPT_PAD => {
+                    at += 1;
+                    continue;
+                }
+                OPT_END => break,
+                _ => {}
+            }
+            let len = *data.get(at + 1)? as usize;
+            let value = data.get(at + 2..at + 2 + len)?;
+            let addr = |i: usize| value.get(i * 4..i * 4 + 4).map(|v| Ipv4Addr::new(v[0], v[1], v[2], v[3]));
+            let secs = || value.try_into().ok().map(u32::from_be_bytes);
+            match code {
+                OPT_MESSAGE_TYPE if len == 1 => msg_type = MessageType::from_u8(value[0]),
+                OPT_SUBNET_MASK => msg.subnet_mask = addr(0),
+                OPT_ROUTER => msg.router = addr(0),
+                OPT_DNS => msg.dns = [addr(0), addr(1)],
+                OPT_REQUESTED_IP => msg.requested_ip = addr(0),
+                OPT_SERVER_ID => msg.server_id = addr(0),
+                OPT_LEASE_TIME => msg.lease_secs = secs(),
+                OPT_RENEWAL_TIME => msg.renew_secs = secs(),
+                OPT_REBINDING_TIME => msg.rebind_secs = secs(),
+                _ => {}
+            }
+            at += 2 + len;
+        }
+        msg.msg_type = msg_type?;
+        Some(msg)
+    }
+
+    /// Write the message into `buf` (at least `MIN_MESSAGE_LEN` bytes).
+    /// Returns its length.
+    pub(crate) fn emit(&self, buf: &mut [u8]) -> usize {
+        buf[..MIN_MESSAGE_LEN].fill(0);
+        buf[0] = self.op;
+        // Ethernet, 6-byte addresses, no hops
+        buf[1] = 1;
+        buf[2] = 6;
+        buf[4..8].copy_from_slice(&self.xid.to_be_bytes());
+        buf[12..16].copy_from_slice(&self.ciaddr.octets);
+        buf[16..20].copy_from_slice(&self.yiaddr.octets);
+        buf[28..34].copy_from_slice(&self.chaddr.0);
+        buf[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+        let mut at = FIXED_LEN;
+        let mut option = |code: u8, value: &[u8]| {
+            buf[at] = code;
+            buf[at + 1] = value.len() as u8;
+            buf[at + 2..at + 2 + value.len()].copy_from_slice(value);
+            at += 2 + value.len();
+        };
+        option(OPT_MESSAGE_TYPE, &[self.msg_type as u8]);
+        if self.op == 1 {
+            let m = self.chaddr.0;
+            option(OPT_CLIENT_ID, &[1, m[0], m[1], m[2], m[3], m[4], m[5]]);
+            option(
+                OPT_PARAMETER_LIST,
+                &[OPT_SUBNET_MASK, OPT_ROUTER, OPT_DNS, OPT_LEASE_TIME, OPT_RENEWAL_TIME, OPT_REBINDING_TIME],
+            );
+        }
+        for (code, addr) in [
+            (OPT_REQUESTED_IP, self.requested_ip),
+            (OPT_SERVER_ID, self.server_id),
+            (OPT_SUBNET_MASK, self.subnet_mask),
+            (OPT_ROUTER, self.router),
+        ] {
+            if let Some(addr) = addr {
+                option(code, &addr.octets);
+            }
+        }
+        if let [Some(a), b] = self.dns {
+            let b = b.unwrap_or(a).octets;
+            let a = a.octets;
+            option(OPT_DNS, &[a[0], a[1], a[2], a[3], b[0], b[1], b[2], b[3]]);
+        }
+        for (code, secs) in [
+            (OPT_LEASE_TIME, self.lease_secs),
+            (OPT_RENEWAL_TIME, self.renew_secs),
+            (OPT_REBINDING_TIME, self.rebind_secs),
+        ] {
+            if let Some(secs) = secs {
+                option(code, &secs.to_be_bytes());
+            }
+        }
+        buf[at] = OPT_END;
+        (at + 1).max(MIN_MESSAGE_LEN)
+    }
+}
+
+/// Where the client is in acquiring or keeping a lease.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DhcpState {
+    Init,
+    Selecting,
+    Requesting,
+    Bound,
+    Renewing,
+    Rebinding,
+}
+
+/// Configuration granted by a DHCP server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Lease {
+    pub address: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Option<Ipv4Addr>,
+    pub dns: [Option<Ipv4Addr>; 2],
+    /// Server that granted the lease
+    pub server: Ipv4Addr,
+    /// Lease length; `u32::MAX` is infinite
+    pub lease_secs: u32,
+    /// When the lease was (last) granted
+    pub acquired_ms: u64,
+    renew_at_ms: u64,
+    rebind_at_ms: u64,
+    expires_ms: u64,
+}
+
+impl Lease {
+    fn from_ack(ack: &DhcpMessage, server: Ipv4Addr, now_ms: u64) -> Self {
+        let lease_secs = ack.lease_secs.unwrap_or(u32::MAX);
+        let at = |secs: u64| if lease_secs == u32::MAX { u64::MAX } else { now_ms.saturating_add(secs * 1000) };
+        let lease = lease_secs as u64;
+        Self {
+            address: ack.yiaddr,
+            netmask: ack.subnet_mask.unwrap_or_else(|| default_netmask(ack.yiaddr)),
+            gateway: ack.router,
+            dns: ack.dns,
+            server,
+            lease_secs,
+            acquired_ms: now_ms,
+            renew_at_ms: at(ack.renew_secs.map_or(lease / 2, u64::from)),
+            rebind_at_ms: at(ack.rebind_secs.map_or(lease * 7 / 8, u64::from)),
+            expires_ms: at(lease),
+        }
+    }
+
+    /// When the lease runs out, on the stack's clock (`u64::MAX` for an
+    /// infinite lease).
+    pub fn expires_ms(&self) -> u64 {
+        self.expires_ms
+    }
+}
+
+/// Netmask of the address's class, for servers that send none.
+fn default_netmask(addr: Ipv4Addr) -> Ipv4Addr {
+    match addr.octets[0] {
+        0..=127 => Ipv4Addr::new(255, 0, 0, 0),
+        128..=191 => Ipv4Addr::new(255, 255, 0, 0),
+        _ => Ipv4Addr::new(255, 255, 255, 0),
+    }
+}
+
+/// A message for the stack to send: source and destination address and
+/// length of the message written to the caller's buffer.
+pub(crate) struct Outgoing {
+    pub(crate) src: Ipv4Addr,
+    pub(crate) dst: Ipv4Addr,
+    pub(crate) len: usize,
+}
+
+/// DHCP client state machine; reached through `NetworkStack::dhcp()`.
+pub struct DhcpClient {
+    mac: MacAddr,
+    random: fn() -> u32,
+    state: DhcpState,
+    xid: u32,
+    /// (server, offered address) while requesting
+    offer: Option<(Ipv4Addr, Ipv4Addr)>,
+    lease: Option<Lease>,
+    next_send_ms: u64,
+    timeout_ms: u64,
+    requests: u8,
+}
+
+impl DhcpClient {
+    pub(crate) fn new(mac: MacAddr, random: fn() -> u32) -> Self {
+        Self {
+            mac,
+            random,
+            state: DhcpState::Init,
+            xid: 0,
+            offer: None,
+            lease: None,
+            next_send_ms: 0,
+            timeout_ms: INITIAL_TIMEOUT_MS,
+            requests: 0,
+        }
+    }
+
+    pub fn state(&self) -> DhcpState {
+        self.state
+    }
+
+    /// The current lease, while there is one (BOUND, RENEWING, REBINDING).
+    pub fn lease(&self) -> Option<&Lease> {
+        self.lease.as_ref()
+    }
+
+    /// Drop the lease and start over with a DISCOVER, e.g. after the link
+    /// came back up on what may be another network.
+    pub fn restart(&mut self) {
+        self.lease = None;
+        self.enter(DhcpState::Init, 0);
+    }
+
+    /// Handle a reply from a server.
+    pub(crate) fn process(&mut self, now_ms: u64, msg: &DhcpMessage) {
+        if msg.op != 2 || msg.xid != self.xid || msg.chaddr != self.mac {
+            return;
+        }
+        match (self.state, msg.msg_type) {
+            (DhcpState::Selecting, MessageType::Offer) => {
+                let Some(server) = msg.server_id else {
+                    return;
+                };
+                if msg.yiaddr == UNSPECIFIED {
+                    return;
+                }
+                sios_log::debug!("DHCP: offer of {:?} from {:?}", msg.yiaddr, server);
+                self.offer = Some((server, msg.yiaddr));
+                self.requests = 0;
+                self.enter(DhcpState::Requesting, now_ms);
+            }
+            (DhcpState::Requesting, MessageType::Ack) => {
+                let Some((server, offered)) = self.offer else {
+                    return;
+                };
+                if msg.yiaddr != offered {
+                    return;
+                }
+                self.bind(Lease::from_ack(msg, server, now_ms));
+            }
+            (DhcpState::Renewing | DhcpState::Rebinding, MessageType::Ack) => {
+                let Some(current) = self.lease else {
+                    return;
+                };
+                let server = msg.server_id.unwrap_or(current.server);
+                self.bind(Lease::from_ack(msg, server, now_ms));
+            }
+            (DhcpState::Requesting | DhcpState::Renewing | DhcpState::Rebinding, MessageType::Nak) => {
+                sios_log::info!("DHCP: NAK, starting over");
+                self.restart();
+                self.next_send_ms = now_ms;
+            }
+            _ => {}
+        }
+    }
+
+    /// Run the timers and write the next message due, if any, into `buf`
+    /// (at least `MIN_MESSAGE_LEN` bytes).
+    pub(crate) fn dispatch(&mut self, now_ms: u64, buf: &mut [u8]) -> Option<Outgoing> {
+        if let Some(lease) = self.lease {
+            if now_ms >= lease.expires_ms {
+                sios_log::info!("DHCP: lease on {:?} expired", lease.address);
+                self.restart();
+                self.next_send_ms = now_ms;
+            } else if self.state == DhcpState::Bound && now_ms >= lease.renew_at_ms {
+                self.enter(DhcpState::Renewing, now_ms);
+            } else if self.state == DhcpState::Renewing && now_ms >= lease.rebind_at_ms {
+                self.enter(DhcpState::Rebinding, now_ms);
+            }
+        }
+        if self.state == DhcpState::Bound || now_ms < self.next_send_ms {
+            return None;
+        }
+
+        let (msg, src, dst) = match self.state {
+            DhcpState::Init => {
+                self.enter(DhcpState::Selecting, now_ms);
+                (self.message(MessageType::Discover), UNSPECIFIED, BROADCAST)
+            }
+            DhcpState::Selecting => (self.message(MessageType::Discover), UNSPECIFIED, BROADCAST),
+            DhcpState::Requesting => {
+                let (server, offered) = self.offer?;
+                if self.requests >= MAX_REQUESTS {
+                    self.enter(DhcpState::Selecting, now_ms);
+                    (self.message(MessageType::Discover), UNSPECIFIED, BROADCAST)
+                } else {
+                    self.requests += 1;
+                    let mut request = self.message(MessageType::Request);
+                    request.requested_ip = Some(offered);
+                    request.server_id = Some(server);
+                    (request, UNSPECIFIED, BROADCAST)
+                }
+            }
+            DhcpState::Renewing | DhcpState::Rebinding => {
+                let lease = self.lease?;
+                let mut request = self.message(MessageType::Request);
+                request.ciaddr = lease.address;
+                let (dst, until) = match self.state {
+                    DhcpState::Renewing => (lease.server, lease.rebind_at_ms),
+                    _ => (BROADCAST, lease.expires_ms),
+                };
+                // Half the time left, but not more often than once a minute
+                self.next_send_ms = now_ms + ((until - now_ms) / 2).max(MIN_RENEW_RETRY_MS);
+                (request, lease.address, dst)
+            }
+            DhcpState::Bound => return None,
+        };
+
+        if matches!(self.state, DhcpState::Selecting | DhcpState::Requesting) {
+            self.next_send_ms = now_ms + self.timeout_ms;
+            self.timeout_ms = (self.timeout_ms * 2).min(MAX_TIMEOUT_MS);
+        }
+        Some(Outgoing { src, dst, len: msg.emit(buf) })
+    }
+
+    /// Send the message that was due again at `at_ms`, e.g. because the
+    /// server's address was not resolved yet.
+    pub(crate) fn defer(&mut self, at_ms: u64) {
+        self.next_send_ms = at_ms;
+    }
+
+    fn message(&self, msg_type: MessageType) -> DhcpMessage {
+        DhcpMessage::new(1, self.xid, self.mac, msg_type)
+    }
+
+    /// Switch state; every new exchange gets a fresh transaction id and
+    /// retransmission timer, and its first message goes out at once.
+    fn enter(&mut self, state: DhcpState, now_ms: u64) {
+        self.state = state;
+        self.xid = (self.random)() ^ u32::from_be_bytes([self.mac.0[2], self.mac.0[3], self.mac.0[4], self.mac.0[5]]);
+        self.timeout_ms = INITIAL_TIMEOUT_MS;
+        self.next_send_ms = now_ms;
+    }
+
+    fn bind(&mut self, lease: Lease) {
+        sios_log::info!("DHCP: bound to {:?} for {} s", lease.address, lease.lease_secs);
+        self.lease = Some(lease);
+        self.offer = None;
+        self.state = DhcpState::Bound;
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    const MAC: MacAddr = MacAddr([0x02, 0, 0, 0, 0, 0x42]);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 1);
+    const OFFERED: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 50);
+
+    fn random() -> u32 {
+        0x1234_5678
+    }
+
+    /// Run the client and parse what it sends.
+    fn sent(client: &mut DhcpClient, now: u64) -> Option<(DhcpMessage, Ipv4Addr, Ipv4Addr)> {
+        let mut buf = [0u8; 576];
+        let out = client.dispatch(now, &mut buf)?;
+        assert_eq!(out.len, MIN_MESSAGE_LEN);
+        Some((DhcpMessage::parse(&buf[..out.len]).unwrap(), out.src, out.dst))
+    }
+
+    fn reply(to: &DhcpMessage, msg_type: MessageType) -> DhcpMessage {
+        let mut reply = DhcpMessage::new(2, to.xid, to.chaddr, msg_type);
+        reply.yiaddr = OFFERED;
+        reply.server_id = Some(SERVER);
+        reply.subnet_mask = Some(Ipv4Addr::new(255, 255, 255, 0));
+        reply.router = Some(SERVER);
+        reply.dns = [Some(Ipv4Addr::new(1, 1, 1, 1)), None];
+        reply.lease_secs = Some(3600);
+        // Through the wire format, as a server would send it
+        let mut buf = [0u8; 576];
+        let len = reply.emit(&mut buf);
+        DhcpMessage::parse(&buf[..len]).unwrap()
+    }
+
+    fn bound() -> DhcpClient {
+        let mut client = DhcpClient::new(MAC, random);
+        let (discover, src, dst) = sent(&mut client, 0).unwrap();
+        assert_eq!((discover.msg_type, src, dst), (MessageType::Discover, UNSPECIFIED, BROADCAST));
+        client.process(100, &reply(&discover, MessageType::Offer));
+
+        let (request, _, dst) = sent(&mut client, 100).unwrap();
+        assert_eq!((request.msg_type, dst), (MessageType::Request, BROADCAST));
+        assert_eq!((request.requested_ip, request.server_id), (Some(OFFERED), Some(SERVER)));
+        client.process(200, &reply(&request, MessageType::Ack));
+        client
+    }
+
+    #[test]
+    fn acquires_a_lease() {
+        let client = bound();
+        assert_eq!(client.state(), DhcpState::Bound);
+        let lease = client.lease().unwrap();
+        assert_eq!(lease.address, OFFERED);
+        assert_eq!(lease.gateway, Some(SERVER));
+        assert_eq!(lease.dns, [Some(Ipv4Addr::new(1, 1, 1, 1)), Some(Ipv4Addr::new(1, 1, 1, 1))]);
+        assert_eq!(lease.expires_ms(), 200 + 3_600_000);
+    }
+
+    #[test]
+    fn renews_then_rebinds_then_expires() {
+        let mut client = bound();
+        assert!(sent(&mut client, 200 + 1_799_999).is_none());
+
+        // T1: unicast to the server, from the leased address
+        let (renew, src, dst) = sent(&mut client, 200 + 1_800_000).unwrap();
+        assert_eq!(client.state(), DhcpState::Renewing);
+        assert_eq!((renew.ciaddr, src, dst), (OFFERED, OFFERED, SERVER));
+        assert!(sent(&mut client, 200 + 1_800_000 + 1_000).is_none());
+
+        // T2: broadcast to any server
+        let (rebind, _, dst) = sent(&mut client, 200 + 3_150_000).unwrap();
+        assert_eq!(client.state(), DhcpState::Rebinding);
+        assert_eq!(dst, BROADCAST);
+
+        // Answered: bound again with a fresh lease
+        client.process(200 + 3_150_100, &reply(&rebind, MessageType::Ack));
+        assert_eq!(client.state(), DhcpState::Bound);
+        assert_eq!(client.lease().unwrap().acquired_ms, 200 + 3_150_100);
+
+        // Never answered again: the lease runs out and discovery restarts
+        let expiry = client.lease().unwrap().expires_ms();
+        let (discover, _, _) = sent(&mut client, expiry).unwrap();
+        assert_eq!(discover.msg_type, MessageType::Discover);
+        assert!(client.lease().is_none());
+    }
+
+    #[test]
+    fn nak_and_foreign_replies() {
+        let mut client = DhcpClient::new(MAC, random);
+        let (discover, _, _) = sent(&mut client, 0).unwrap();
+        // Another transaction or another client's reply: ignored
+        let mut stray = reply(&discover, MessageType::Offer);
+        stray.xid ^= 1;
+        client.process(10, &stray);
+        stray = reply(&discover, MessageType::Offer);
+        stray.chaddr = MacAddr([0x02, 0, 0, 0, 0, 0x43]);
+        client.process(10, &stray);
+        assert_eq!(client.state(), DhcpState::Selecting);
+        // Retransmitted after the timeout, with backoff
+        assert!(sent(&mut client, 3_999).is_none());
+        assert!(sent(&mut client, 4_000).is_some());
+        assert!(sent(&mut client, 11_999).is_none());
+        assert!(sent(&mut client, 12_000).is_some());
+
+        let mut client = bound();
+        let (renew, _, _) = sent(&mut client, 200 + 1_800_000).unwrap();
+        client.process(200 + 1_800_100, &reply(&renew, MessageType::Nak));
+        assert!(client.lease().is_none());
+        let (discover, _, _) = sent(&mut client, 200 + 1_800_100).unwrap();
+        assert_eq!(discover.msg_type, MessageType::Discover);
+    }
+}
@@ -0,0 +1,208 @@
+//! SecureIoTOS net Egress Accounting Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Outgoing traffic accounting per task and destination, with quotas.
+//!
+//! On a metered (cellular) link every byte is billed, so `NetworkStack`
+//! counts the IPv4 bytes each task sends to each destination and refuses
+//! a send that would take a task past its quota. A quota covers either
+//! all destinations of a task or a single one, and applies to the current
+//! accounting period: `reset_period()` at the start of each billing cycle
+//! clears the byte counters but keeps the quotas.
+//!
+//! Checks run in the stack's egress path after the firewall hook, so a
+//! send the firewall drops costs nothing. The tables are fixed size:
+//! traffic of unlimited tasks that no longer fits is added to
+//! `overflow()`, while a task with a quota whose traffic cannot be tracked
+//! is refused (fail closed). `usage()` is what the telemetry report
+//! publishes.
+
+use crate::{Ipv4Addr, NetError, NetResult};
+
+/// Sending task, as numbered by the scheduler.
+pub type TaskId = u16;
+
+/// Task id used for traffic the stack sends on its own behalf.
+pub const SYSTEM_TASK: TaskId = TaskId::MAX;
+
+/// Traffic of one task to one destination in the current period.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EgressUsage {
+    pub task: TaskId,
+    pub dest: Ipv4Addr,
+    /// IPv4 bytes sent (headers included)
+    pub bytes: u64,
+    pub packets: u32,
+    /// Sends refused because of a quota
+    pub denied: u32,
+}
+
+/// Byte limit for a task, on all its traffic or on one destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub task: TaskId,
+    /// `None`: the limit applies to the task's total
+    pub dest: Option<Ipv4Addr>,
+    pub bytes: u64,
+}
+
+/// Usage and quota tables for up to `SLOTS` (task, destination) pairs and
+/// `QUOTAS` quotas.
+#[derive(Debug)]
+pub struct EgressAccounting<const SLOTS: usize, const QUOTAS: usize> {
+    usage: [Option<EgressUsage>; SLOTS],
+    quotas: [Option<Quota>; QUOTAS],
+    overflow: u64,
+}
+
+impl<const SLOTS: usize, const QUOTAS: usize> Default for EgressAccounting<SLOTS, QUOTAS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const SLOTS: usize, const QUOTAS: usize> EgressAccounting<SLOTS, QUOTAS> {
+    pub const fn new() -> Self {
+        Self { usage: [None; SLOTS], quotas: [None; QUOTAS], overflow: 0 }
+    }
+
+    /// Set (or replace) the quota for `task`, on `dest` or on its total.
+    pub fn set_quota(&mut self, task: TaskId, dest: Option<Ipv4Addr>, bytes: u64) -> NetResult<()> {
+        let quota = Quota { task, dest, bytes };
+        let slot = match self.quotas.iter().position(|q| matches!(q, Some(q) if q.task == task && q.dest == dest)) {
+            Some(i) => i,
+            None => self.quotas.iter().position(Option::is_none).ok_or(NetError::Unsupported)?,
+        };
+        self.quotas[slot] = Some(quota);
+        Ok(())
+    }
+
+    /// Remove the quota for `task` on `dest` (or on its total).
+    pub fn clear_quota(&mut self, task: TaskId, dest: Option<Ipv4Addr>) {
+        for q in self.quotas.iter_mut() {
+            if matches!(q, Some(x) if x.task == task && x.dest == dest) {
+                *q = None;
+            }
+        }
+    }
+
+    /// Bytes sent by `task` this period, over all destinations.
+    pub fn task_total(&self, task: TaskId) -> u64 {
+        self.usage.iter().flatten().filter(|u| u.task == task).map(|u| u.bytes).sum()
+    }
+
+    /// Check that `task` may send `len` more bytes to `dest`. A refusal is
+    /// counted in the usage entry.
+    pub fn check(&mut self, task: TaskId, dest: Ipv4Addr, len: usize) -> NetResult<()> {
+        let limited = self.quotas.iter().flatten().any(|q| q.task == task);
+        if !limited {
+            return Ok(());
+        }
+        let Some(slot) = self.slot(task, dest) else {
+            sios_log::warn!("egress table full, refusing task {} with a quota", task);
+            return Err(NetError::Denied);
+        };
+        let len = len as u64;
+        let total = self.task_total(task);
+        let to_dest = self.usage[slot].map_or(0, |u| u.bytes);
+        let over = self.quotas.iter().flatten().filter(|q| q.task == task).any(|q| match q.dest {
+            None => total + len > q.bytes,
+            Some(d) => d == dest && to_dest + len > q.bytes,
+        });
+        if over {
+            let entry = self.usage[slot].get_or_insert(EgressUsage { task, dest, bytes: 0, packets: 0, denied: 0 });
+            entry.denied = entry.denied.saturating_add(1);
+            sios_log::debug!("task {} over quota sending {} bytes to {:?}", task, len, dest);
+            return Err(NetError::QuotaExceeded);
+        }
+        Ok(())
+    }
+
+    /// Account `len` bytes sent by `task` to `dest`.
+    pub fn record(&mut self, task: TaskId, dest: Ipv4Addr, len: usize) {
+        let Some(slot) = self.slot(task, dest) else {
+            self.overflow = self.overflow.saturating_add(len as u64);
+            return;
+        };
+        let entry = self.usage[slot].get_or_insert(EgressUsage { task, dest, bytes: 0, packets: 0, denied: 0 });
+        entry.bytes = entry.bytes.saturating_add(len as u64);
+        entry.packets = entry.packets.saturating_add(1);
+    }
+
+    /// Usage entries of the current period.
+    pub fn usage(&self) -> impl Iterator<Item = &EgressUsage> {
+        self.usage.iter().flatten()
+    }
+
+    /// Bytes that could not be attributed because the table was full.
+    pub fn overflow(&self) -> u64 {
+        self.overflow
+    }
+
+    /// Start a new accounting period: usage is cleared, quotas are kept.
+    pub fn reset_period(&mut self) {
+        self.usage = [None; SLOTS];
+        self.overflow = 0;
+    }
+
+    /// Index of the entry for (task, dest), or of a free one.
+    fn slot(&self, task: TaskId, dest: Ipv4Addr) -> Option<usize> {
+        self.usage
+            .iter()
+            .position(|u| matches!(u, Some(u) if u.task == task && u.dest == dest))
+            .or_else(|| self.usage.iter().position(Option::is_none))
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    const CLOUD: Ipv4Addr = Ipv4Addr::new(52, 1, 2, 3);
+    const NTP: Ipv4Addr = Ipv4Addr::new(162, 159, 200, 1);
+
+    #[test]
+    fn quotas_limit_task_totals_and_single_destinations() {
+        let mut acct = EgressAccounting::<4, 4>::new();
+        acct.set_quota(1, None, 1000).unwrap();
+        acct.set_quota(2, Some(NTP), 100).unwrap();
+
+        for _ in 0..2 {
+            acct.check(1, CLOUD, 400).unwrap();
+            acct.record(1, CLOUD, 400);
+        }
+        assert_eq!(acct.check(1, NTP, 300), Err(NetError::QuotaExceeded));
+        acct.check(1, NTP, 200).unwrap();
+
+        // Task 2 is only limited towards NTP
+        acct.record(2, NTP, 90);
+        assert_eq!(acct.check(2, NTP, 48), Err(NetError::QuotaExceeded));
+        acct.check(2, CLOUD, 5000).unwrap();
+        // Unlimited tasks are never refused
+        acct.check(3, CLOUD, usize::MAX).unwrap();
+
+        let e = acct.usage().find(|u| u.task == 1 && u.dest == NTP).unwrap();
+        assert_eq!((e.bytes, e.denied), (0, 1));
+        assert_eq!(acct.task_total(1), 800);
+
+        acct.reset_period();
+        acct.check(2, NTP, 100).unwrap();
+        assert_eq!(acct.usage().count(), 0);
+    }
+
+    #[test]
+    fn full_table_overflows_or_fails_closed() {
+        let mut acct = EgressAccounting::<1, 1>::new();
+        acct.record(SYSTEM_TASK, CLOUD, 60);
+        acct.record(3, CLOUD, 60);
+        assert_eq!(acct.overflow(), 60);
+        acct.set_quota(4, None, 1 << 20).unwrap();
+        assert_eq!(acct.check(4, CLOUD, 1), Err(NetError::Denied));
+        assert_eq!(acct.set_quota(5, None, 1), Err(NetError::Unsupported));
+    }
+}
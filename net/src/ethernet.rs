@@ -0,0 +1,99 @@
+//! SecureIoTOS net Ethernet Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Ethernet II framing for interfaces on a LAN.
+//!
+//! A `NetInterface` with a MAC address (`NetInterface::with_ethernet()`)
+//! puts every IP packet in an Ethernet frame addressed to the next hop,
+//! whose MAC address comes from the ARP cache (`arp`) or, for IPv6, from
+//! neighbour discovery (`ipv6`). Without one the interface carries bare
+//! IP, as on PPP or TUN links. No VLAN tags; the frame check sequence is
+//! the MAC's business.
+
+use core::fmt;
+
+/// Destination, source and EtherType.
+pub const HEADER_LEN: usize = 14;
+/// Shortest frame on the wire, FCS excluded; shorter ones are padded.
+pub const MIN_FRAME_LEN: usize = 60;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+pub const ETHERTYPE_IPV6: u16 = 0x86DD;
+
+/// 48-bit Ethernet (MAC) address.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    pub const BROADCAST: Self = Self([0xFF; 6]);
+
+    /// Group (multicast or broadcast) address: the I/G bit is set.
+    pub const fn is_multicast(&self) -> bool {
+        self.0[0] & 0x01 != 0
+    }
+
+    /// Globally unique and not a group address, so usable as an
+    /// interface's own address.
+    pub const fn is_unicast(&self) -> bool {
+        !self.is_multicast() && !matches!(self.0, [0, 0, 0, 0, 0, 0])
+    }
+}
+
+impl fmt::Debug for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let m = &self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", m[0], m[1], m[2], m[3], m[4], m[5])
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for MacAddr {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        let m = &self.0;
+        defmt::write!(
+            f,
+            "{=u8:02x}:{=u8:02x}:{=u8:02x}:{=u8:02x}:{=u8:02x}:{=u8:02x}",
+            m[0],
+            m[1],
+            m[2],
+            m[3],
+            m[4],
+            m[5]
+        )
+    }
+}
+
+/// Header of an Ethernet II frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct EthernetHeader {
+    pub dst: MacAddr,
+    pub src: MacAddr,
+    pub ethertype: u16,
+}
+
+impl EthernetHeader {
+    /// Split a frame into header and payload. The payload may carry
+    /// padding past the end of the packet inside it.
+    pub fn parse(frame: &[u8]) -> Option<(Self, &[u8])> {
+        if frame.len() < HEADER_LEN {
+            return None;
+        }
+        let mac = |at: usize| MacAddr(frame[at..at + 6].try_into().unwrap());
+        let header = Self { dst: mac(0), src: mac(6), ethertype: u16::from_be_bytes([frame[12], frame[13]]) };
+        Some((header, &frame[HEADER_LEN..]))
+    }
+
+    /// Write the header into the first `HEADER_LEN` bytes of `frame`.
+    pub fn emit(&self, frame: &mut [u8]) {
+        frame[0..6].copy_from_slice(&self.dst.0);
+        frame[6..12].copy_from_slice(&self.src.0);
+        frame[12..14].copy_from_slice(&self.ethertype.to_be_bytes());
+    }
+}
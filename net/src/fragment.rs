@@ -0,0 +1,234 @@
+//! SecureIoTOS net Fragment Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! IPv4 fragmentation and reassembly (RFC 791).
+//!
+//! `NetInterface::send_ipv4()` splits a datagram that does not fit the
+//! device MTU into fragments of a multiple of eight bytes, all carrying
+//! the same identification from the interface's counter.
+//!
+//! `NetworkStack::poll()` collects fragments addressed to the interface
+//! in a `Reassembler` and hands the datagram to the sockets once it is
+//! complete. It holds one datagram at a time, of at most `limit()` bytes
+//! (`REASSEMBLY_BUFFER` at most), for `REASSEMBLY_TIMEOUT_MS`. A datagram
+//! is given up, and counted in `dropped()`, when it
+//!
+//! - would exceed the limit,
+//! - has fragments that overlap or disagree about its length (RFC 5722
+//!   takes the same line for IPv6: overlaps are how filters get evaded),
+//! - is not complete in time, or
+//! - is pushed out by the first fragment of another datagram.
+//!
+//! Reassembled datagrams only go to sockets; the `poll()` handler never
+//! sees fragments of traffic the stack takes.
+
+use crate::socket::Ipv4Header;
+use crate::{Ipv4Addr, REASSEMBLY_BUFFER};
+
+/// How long the fragments of a datagram are kept waiting for the rest.
+pub const REASSEMBLY_TIMEOUT_MS: u64 = 15_000;
+
+/// Fragment offsets count 8-byte blocks.
+const BLOCK: usize = 8;
+const BLOCKS: usize = REASSEMBLY_BUFFER / BLOCK;
+
+/// The fragments of one datagram share source, destination, protocol and
+/// identification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Key {
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    protocol: u8,
+    ident: u16,
+}
+
+/// Reassembly buffer for one datagram; reached through
+/// `NetworkStack::reassembler()`.
+pub struct Reassembler {
+    key: Option<Key>,
+    started_ms: u64,
+    buf: [u8; REASSEMBLY_BUFFER],
+    /// Blocks received so far, one bit each
+    blocks: [u32; BLOCKS / 32],
+    /// End of the furthest fragment received
+    end: usize,
+    /// Datagram length, once the last fragment is in
+    total: Option<usize>,
+    received: usize,
+    limit: usize,
+    dropped: u32,
+}
+
+impl Default for Reassembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Reassembler {
+    pub const fn new() -> Self {
+        Self {
+            key: None,
+            started_ms: 0,
+            buf: [0; REASSEMBLY_BUFFER],
+            blocks: [0; BLOCKS / 32],
+            end: 0,
+            total: None,
+            received: 0,
+            limit: REASSEMBLY_BUFFER,
+            dropped: 0,
+        }
+    }
+
+    /// Largest datagram (IPv4 payload) reassembled.
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Set the largest datagram to reassemble, up to `REASSEMBLY_BUFFER`
+    /// bytes; 0 drops every fragment.
+    pub fn set_limit(&mut self, bytes: usize) {
+        self.limit = bytes.min(REASSEMBLY_BUFFER);
+        self.reset();
+    }
+
+    /// Datagrams given up before they were complete.
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Take in a fragment. Returns the length of the datagram once this
+    /// completes it; its payload is then in `datagram()` until the next
+    /// call.
+    pub(crate) fn process(&mut self, header: &Ipv4Header, payload: &[u8], now_ms: u64) -> Option<usize> {
+        let key = Key { src: header.src, dst: header.dst, protocol: header.protocol, ident: header.ident };
+        if let Some(current) = self.key {
+            if current != key || now_ms.saturating_sub(self.started_ms) >= REASSEMBLY_TIMEOUT_MS {
+                self.give_up();
+            }
+        }
+        if self.key.is_none() {
+            self.key = Some(key);
+            self.started_ms = now_ms;
+        }
+
+        let (start, end) = (header.fragment_offset, header.fragment_offset + payload.len());
+        let misaligned = header.more_fragments && !payload.len().is_multiple_of(BLOCK);
+        let bad_end = match (self.total, header.more_fragments) {
+            (Some(total), _) => end > total || (!header.more_fragments && end != total),
+            (None, false) => self.end > end,
+            (None, true) => false,
+        };
+        if end > self.limit || payload.is_empty() || misaligned || bad_end || self.overlaps(start, end) {
+            self.give_up();
+            return None;
+        }
+
+        self.buf[start..end].copy_from_slice(payload);
+        for block in start / BLOCK..end.div_ceil(BLOCK) {
+            self.blocks[block / 32] |= 1 << (block % 32);
+        }
+        self.received += payload.len();
+        self.end = self.end.max(end);
+        if !header.more_fragments {
+            self.total = Some(end);
+        }
+        let total = self.total.filter(|total| *total == self.received)?;
+        self.reset();
+        Some(total)
+    }
+
+    /// The last datagram `process()` completed.
+    pub(crate) fn datagram(&self, len: usize) -> &[u8] {
+        &self.buf[..len]
+    }
+
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        (start / BLOCK..end.div_ceil(BLOCK)).any(|block| self.blocks[block / 32] & (1 << (block % 32)) != 0)
+    }
+
+    fn give_up(&mut self) {
+        if self.key.is_some() {
+            self.dropped = self.dropped.wrapping_add(1);
+            sios_log::debug!("IPv4 reassembly of datagram {:?} given up", self.key.map(|k| k.ident));
+        }
+        self.reset();
+    }
+
+    fn reset(&mut self) {
+        self.key = None;
+        self.blocks = [0; BLOCKS / 32];
+        self.end = 0;
+        self.total = None;
+        self.received = 0;
+    }
+}
+
+/// Split a payload of `len` bytes into fragments for a link with `mtu`:
+/// (offset, length, more fragments) of each. One piece if it fits.
+pub(crate) fn fragments(len: usize, mtu: usize) -> impl Iterator<Item = (usize, usize, bool)> {
+    let room = mtu.saturating_sub(20);
+    let step = if len <= room { len.max(1) } else { room / BLOCK * BLOCK };
+    (0..len.max(1)).step_by(step.max(1)).map(move |offset| {
+        let piece = step.min(len - offset);
+        (offset, piece, offset + piece < len)
+    })
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    fn fragment(ident: u16, offset: usize, more: bool) -> Ipv4Header {
+        Ipv4Header {
+            src: Ipv4Addr::new(10, 0, 0, 2),
+            dst: Ipv4Addr::new(10, 0, 0, 1),
+            protocol: 17,
+            ident,
+            fragment_offset: offset,
+            more_fragments: more,
+        }
+    }
+
+    #[test]
+    fn split_into_blocks() {
+        assert_eq!(fragments(100, 1500).collect::<Vec<_>>(), [(0, 100, false)]);
+        assert_eq!(fragments(0, 1500).collect::<Vec<_>>(), [(0, 0, false)]);
+        // 576 - 20 = 556, rounded down to 552
+        assert_eq!(fragments(1200, 576).collect::<Vec<_>>(), [(0, 552, true), (552, 552, true), (1104, 96, false)]);
+    }
+
+    #[test]
+    fn reassembles_out_of_order() {
+        let data: Vec<u8> = (0..100u8).collect();
+        let mut r = Reassembler::new();
+        assert_eq!(r.process(&fragment(7, 48, true), &data[48..96], 0), None);
+        assert_eq!(r.process(&fragment(7, 96, false), &data[96..], 0), None);
+        assert_eq!(r.process(&fragment(7, 0, true), &data[..48], 0), Some(100));
+        assert_eq!(r.datagram(100), &data[..]);
+        assert_eq!(r.dropped(), 0);
+    }
+
+    #[test]
+    fn gives_up_on_overlap_limit_and_timeout() {
+        let data = [0xAAu8; 64];
+        let mut r = Reassembler::new();
+        r.process(&fragment(1, 0, true), &data[..16], 0);
+        // Rewrites bytes 8..16
+        assert_eq!(r.process(&fragment(1, 8, false), &data[..16], 0), None);
+        assert_eq!(r.dropped(), 1);
+
+        r.set_limit(32);
+        assert_eq!(r.process(&fragment(2, 24, false), &data[..16], 0), None);
+        assert_eq!(r.dropped(), 2);
+
+        r.process(&fragment(3, 0, true), &data[..8], 0);
+        assert_eq!(r.process(&fragment(3, 8, false), &data[..8], REASSEMBLY_TIMEOUT_MS), None);
+        assert_eq!(r.dropped(), 3);
+    }
+}
@@ -0,0 +1,69 @@
+//! SecureIoTOS net ICMP Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! ICMP echo (RFC 792) for IPv4, so a device can be pinged.
+//!
+//! `NetworkStack::poll()` answers echo requests sent to the interface's
+//! own address; requests to broadcast or multicast addresses are not
+//! answered (no smurf amplification). Replies are accounted to
+//! `egress::SYSTEM_TASK` and pass the firewall hook and its quotas like
+//! any system traffic, which bounds what a flood of requests can make the
+//! device send. `NetworkStack::set_echo_reply(false)` turns them off.
+//!
+//! Other ICMP messages, echo replies included, go to the `poll()` handler.
+
+use crate::socket::checksum;
+
+/// IPv4 protocol number of ICMP.
+pub(crate) const PROTO_ICMP: u8 = 1;
+
+const ECHO_REPLY: u8 = 0;
+const ECHO_REQUEST: u8 = 8;
+
+/// The ICMP messages the stack handles itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Icmpv4<'a> {
+    EchoRequest { ident: u16, seq: u16, data: &'a [u8] },
+    EchoReply { ident: u16, seq: u16, data: &'a [u8] },
+}
+
+impl<'a> Icmpv4<'a> {
+    /// Parse an ICMP message, checking its checksum. Other message types
+    /// give `None`.
+    pub(crate) fn parse(body: &'a [u8]) -> Option<Self> {
+        if body.len() < 8 || body[1] != 0 || checksum(body, 0) != 0 {
+            return None;
+        }
+        let word = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+        let (ident, seq, data) = (word(4), word(6), &body[8..]);
+        match body[0] {
+            ECHO_REQUEST => Some(Self::EchoRequest { ident, seq, data }),
+            ECHO_REPLY => Some(Self::EchoReply { ident, seq, data }),
+            _ => None,
+        }
+    }
+
+    /// Write the message into `buf`, checksum included. Returns its
+    /// length.
+    pub(crate) fn emit(&self, buf: &mut [u8]) -> usize {
+        let (kind, ident, seq, data) = match *self {
+            Self::EchoRequest { ident, seq, data } => (ECHO_REQUEST, ident, seq, data),
+            Self::EchoReply { ident, seq, data } => (ECHO_REPLY, ident, seq, data),
+        };
+        let len = 8 + data.len();
+        buf[0] = kind;
+        buf[1] = 0;
+        buf[2..4].fill(0);
+        buf[4..6].copy_from_slice(&ident.to_be_bytes());
+        buf[6..8].copy_from_slice(&seq.to_be_bytes());
+        buf[8..len].copy_from_slice(data);
+        let sum = checksum(&buf[..len], 0);
+        buf[2..4].copy_from_slice(&sum.to_be_bytes());
+        len
+    }
+}
@@ -0,0 +1,403 @@
+//! SecureIoTOS net Inspect Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Remote memory and task inspection for a trusted operator tool
+//! ("GDB-lite"), behind the `inspect` feature.
+//!
+//! The protocol is a single authenticated request/response exchange and
+//! does not care about the transport: post the request frame as the
+//! payload of a CoAP `POST /diag`, or send it over raw TCP with a 2-byte
+//! big-endian length prefix in front of each frame. `Inspector::handle()`
+//! turns a request into a response; `encode_request()` /
+//! `decode_response()` are the operator side.
+//!
+//! Request (28 bytes, big-endian):
+//!
+//! ```text
+//!   version(1) | command(1) | seq(4) | addr(4) | len(2) | mac(16)
+//! ```
+//!
+//! Response:
+//!
+//! ```text
+//!   status(1) | seq(4) | payload_len(2) | payload | mac(16)
+//! ```
+//!
+//! `mac` is HMAC-SHA256 under the shared diagnostic key, truncated to 16
+//! bytes, over everything before it. `seq` must grow with every request
+//! (replays are rejected) and is echoed, so a response cannot be paired
+//! with another request.
+//!
+//! Only memory inside the regions the device whitelisted can be read;
+//! task states and trace buffers come from the `InspectTarget`. Every
+//! request, accepted or not, is reported to the `AuditSink`.
+
+use sha2::{Digest, Sha256};
+use sios_log::{warn, Secret};
+
+/// Protocol version in every request.
+pub const VERSION: u8 = 1;
+/// Length of the diagnostic key.
+pub const KEY_LEN: usize = 32;
+/// Length of the truncated MAC.
+pub const MAC_LEN: usize = 16;
+/// Length of a request frame.
+pub const REQUEST_LEN: usize = 12 + MAC_LEN;
+/// Response bytes around the payload.
+pub const RESPONSE_OVERHEAD: usize = 7 + MAC_LEN;
+
+/// What the operator asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Command {
+    /// `len` bytes at `addr`, which must lie in a whitelisted region
+    ReadMemory = 1,
+    /// Task states, as encoded by the target
+    TaskStates = 2,
+    /// Up to `len` bytes of trace records, as encoded by the target
+    ReadTrace = 3,
+}
+
+impl Command {
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(Command::ReadMemory),
+            2 => Some(Command::TaskStates),
+            3 => Some(Command::ReadTrace),
+            _ => None,
+        }
+    }
+}
+
+/// Why a request was refused (sent as the response status).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum InspectError {
+    /// Frame has the wrong length or version
+    Malformed = 1,
+    /// MAC does not verify under the diagnostic key
+    BadMac = 2,
+    /// `seq` is not above the last accepted one
+    Replay = 3,
+    /// Unknown command
+    UnknownCommand = 4,
+    /// Memory range is not (entirely) inside a whitelisted region
+    NotAllowed = 5,
+    /// Response does not fit the output buffer
+    TooLarge = 6,
+}
+
+impl InspectError {
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            1 => Some(InspectError::Malformed),
+            2 => Some(InspectError::BadMac),
+            3 => Some(InspectError::Replay),
+            4 => Some(InspectError::UnknownCommand),
+            5 => Some(InspectError::NotAllowed),
+            6 => Some(InspectError::TooLarge),
+            _ => None,
+        }
+    }
+}
+
+/// A memory range the operator may read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    start: usize,
+    len: usize,
+}
+
+impl Region {
+    /// # Safety
+    /// `[start, start + len)` must stay mapped and readable for as long as
+    /// an `Inspector` uses it, and reading it must have no side effects
+    /// (no peripheral FIFOs or read-to-clear registers).
+    pub const unsafe fn new(start: usize, len: usize) -> Self {
+        Region { start, len }
+    }
+
+    fn contains(&self, addr: usize, len: usize) -> bool {
+        addr >= self.start && addr.checked_add(len).is_some_and(|end| end <= self.start + self.len)
+    }
+}
+
+/// Device state the inspector can report besides raw memory.
+pub trait InspectTarget {
+    /// Write the task states to `out`, return the number of bytes written.
+    fn task_states(&mut self, out: &mut [u8]) -> usize;
+    /// Move trace records into `out`, return the number of bytes written.
+    fn read_trace(&mut self, out: &mut [u8]) -> usize;
+
+    /// Copy memory at `addr` into `out`. The range has already been
+    /// checked against the whitelisted regions.
+    fn read_memory(&mut self, addr: usize, out: &mut [u8]) {
+        for (i, b) in out.iter_mut().enumerate() {
+            // SAFETY: `[addr, addr + out.len())` lies in a region whose
+            // creator vouched for it being readable (`Region::new`).
+            *b = unsafe { core::ptr::read_volatile((addr + i) as *const u8) };
+        }
+    }
+}
+
+/// One inspection request, as recorded in the audit trail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuditEntry {
+    pub seq: u32,
+    /// `None` if the frame could not be parsed far enough
+    pub command: Option<Command>,
+    pub addr: u32,
+    pub len: u16,
+    /// Payload bytes returned, or why the request was refused
+    pub outcome: Result<usize, InspectError>,
+}
+
+/// Destination of the audit trail (secure storage log, telemetry, ...).
+pub trait AuditSink {
+    fn record(&mut self, entry: &AuditEntry);
+}
+
+/// Device side of the protocol.
+pub struct Inspector<'r, T: InspectTarget, A: AuditSink> {
+    key: Secret<[u8; KEY_LEN]>,
+    regions: &'r [Region],
+    /// Highest `seq` accepted so far
+    last_seq: Option<u32>,
+    target: T,
+    audit: A,
+}
+
+impl<'r, T: InspectTarget, A: AuditSink> Inspector<'r, T, A> {
+    pub fn new(key: Secret<[u8; KEY_LEN]>, regions: &'r [Region], target: T, audit: A) -> Self {
+        Self { key, regions, last_seq: None, target, audit }
+    }
+
+    /// Answer `request` into `response` and return the response length.
+    /// Refused requests get an error response (status != 0); `Err` only
+    /// means `response` cannot hold even that.
+    pub fn handle(&mut self, request: &[u8], response: &mut [u8]) -> Result<usize, InspectError> {
+        if response.len() < RESPONSE_OVERHEAD {
+            return Err(InspectError::TooLarge);
+        }
+        let (entry, outcome) = self.serve(request, response);
+        self.audit.record(&entry);
+        if let Err(e) = entry.outcome {
+            warn!("diag request {} refused: {:?}", entry.seq, e);
+        }
+        let (status, payload_len) = match outcome {
+            Ok(n) => (0, n),
+            Err(e) => (e as u8, 0),
+        };
+        Ok(finish_response(self.key.expose_secret(), response, status, entry.seq, payload_len))
+    }
+
+    /// Check and execute `request`, writing the payload in place.
+    fn serve(&mut self, request: &[u8], response: &mut [u8]) -> (AuditEntry, Result<usize, InspectError>) {
+        let mut entry = AuditEntry { seq: 0, command: None, addr: 0, len: 0, outcome: Err(InspectError::Malformed) };
+        let Some((body, mac)) = request.split_at_checked(REQUEST_LEN - MAC_LEN).filter(|(_, m)| m.len() == MAC_LEN)
+        else {
+            return (entry, entry.outcome);
+        };
+        entry.seq = u32::from_be_bytes([body[2], body[3], body[4], body[5]]);
+        entry.addr = u32::from_be_bytes([body[6], body[7], body[8], body[9]]);
+        entry.len = u16::from_be_bytes([body[10], body[11]]);
+        entry.command = Command::from_u8(body[1]);
+
+        let outcome = self.execute(body, mac, &entry, response);
+        entry.outcome = outcome;
+        (entry, outcome)
+    }
+
+    fn execute(&mut self, body: &[u8], mac: &[u8], entry: &AuditEntry, response: &mut [u8]) -> Result<usize, InspectError> {
+        if body[0] != VERSION {
+            return Err(InspectError::Malformed);
+        }
+        if !ct_eq(&mac_of(self.key.expose_secret(), &[body]), mac) {
+            return Err(InspectError::BadMac);
+        }
+        if self.last_seq.is_some_and(|last| entry.seq <= last) {
+            return Err(InspectError::Replay);
+        }
+        self.last_seq = Some(entry.seq);
+
+        let end = response.len() - MAC_LEN;
+        let payload = &mut response[7..end];
+        match entry.command.ok_or(InspectError::UnknownCommand)? {
+            Command::ReadMemory => {
+                let (addr, len) = (entry.addr as usize, entry.len as usize);
+                if !self.regions.iter().any(|r| r.contains(addr, len)) {
+                    return Err(InspectError::NotAllowed);
+                }
+                let out = payload.get_mut(..len).ok_or(InspectError::TooLarge)?;
+                self.target.read_memory(addr, out);
+                Ok(len)
+            }
+            Command::TaskStates => Ok(self.target.task_states(payload)),
+            Command::ReadTrace => {
+                let max = payload.len().min(entry.len as usize);
+                Ok(self.target.read_trace(&mut payload[..max]))
+            }
+        }
+    }
+}
+
+/// Fill in the response header and MAC around a payload already at
+/// `out[7..]`; returns the frame length.
+fn finish_response(key: &[u8; KEY_LEN], out: &mut [u8], status: u8, seq: u32, payload_len: usize) -> usize {
+    out[0] = status;
+    out[1..5].copy_from_slice(&seq.to_be_bytes());
+    out[5..7].copy_from_slice(&(payload_len as u16).to_be_bytes());
+    let end = 7 + payload_len;
+    let mac = mac_of(key, &[&out[..end]]);
+    out[end..end + MAC_LEN].copy_from_slice(&mac);
+    end + MAC_LEN
+}
+
+/// Operator side: build a request frame.
+pub fn encode_request(key: &[u8; KEY_LEN], command: Command, seq: u32, addr: u32, len: u16) -> [u8; REQUEST_LEN] {
+    let mut frame = [0u8; REQUEST_LEN];
+    frame[0] = VERSION;
+    frame[1] = command as u8;
+    frame[2..6].copy_from_slice(&seq.to_be_bytes());
+    frame[6..10].copy_from_slice(&addr.to_be_bytes());
+    frame[10..12].copy_from_slice(&len.to_be_bytes());
+    let mac = mac_of(key, &[&frame[..12]]);
+    frame[12..].copy_from_slice(&mac);
+    frame
+}
+
+/// Operator side: verify a response to request `seq` and return its
+/// payload, or the device's reason for refusing.
+pub fn decode_response<'a>(key: &[u8; KEY_LEN], seq: u32, frame: &'a [u8]) -> Result<&'a [u8], InspectError> {
+    if frame.len() < RESPONSE_OVERHEAD {
+        return Err(InspectError::Malformed);
+    }
+    let payload_len = u16::from_be_bytes([frame[5], frame[6]]) as usize;
+    let end = 7 + payload_len;
+    if frame.len() != end + MAC_LEN {
+        return Err(InspectError::Malformed);
+    }
+    if !ct_eq(&mac_of(key, &[&frame[..end]]), &frame[end..]) {
+        return Err(InspectError::BadMac);
+    }
+    if u32::from_be_bytes([frame[1], frame[2], frame[3], frame[4]]) != seq {
+        return Err(InspectError::Replay);
+    }
+    match frame[0] {
+        0 => Ok(&frame[7..end]),
+        s => Err(InspectError::from_u8(s).unwrap_or(InspectError::Malformed)),
+    }
+}
+
+/// HMAC-SHA256 over the concatenation of `parts`, truncated to `MAC_LEN`.
+fn mac_of(key: &[u8; KEY_LEN], parts: &[&[u8]]) -> [u8; MAC_LEN] {
+    const BLOCK: usize = 64;
+    let mut ipad = [0x36u8; BLOCK];
+    let mut opad = [0x5cu8; BLOCK];
+    for (i, k) in key.iter().enumerate() {
+        ipad[i] ^= k;
+        opad[i] ^= k;
+    }
+    let mut inner = Sha256::new();
+    inner.update(ipad);
+    for p in parts {
+        inner.update(p);
+    }
+    let mut outer = Sha256::new();
+    outer.update(opad);
+    outer.update(inner.finalize());
+    let mut mac = [0u8; MAC_LEN];
+    mac.copy_from_slice(&outer.finalize()[..MAC_LEN]);
+    mac
+}
+
+/// Constant-time comparison (no early exit on the first differing byte).
+fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; KEY_LEN] = [7; KEY_LEN];
+
+    /// Device memory as seen by the tests (host addresses do not fit the
+    /// protocol's 32-bit `addr`).
+    const BASE: usize = 0x2000_0000;
+    const RAM: &[u8] = b"sensor42";
+
+    struct Target;
+
+    impl InspectTarget for Target {
+        fn task_states(&mut self, out: &mut [u8]) -> usize {
+            out[..2].copy_from_slice(&[1, 0]);
+            2
+        }
+        fn read_trace(&mut self, _out: &mut [u8]) -> usize {
+            0
+        }
+        fn read_memory(&mut self, addr: usize, out: &mut [u8]) {
+            out.copy_from_slice(&RAM[addr - BASE..][..out.len()]);
+        }
+    }
+
+    impl AuditSink for &mut Vec<AuditEntry> {
+        fn record(&mut self, entry: &AuditEntry) {
+            self.push(*entry);
+        }
+    }
+
+    fn exchange(insp: &mut Inspector<Target, &mut Vec<AuditEntry>>, req: &[u8], seq: u32) -> Result<Vec<u8>, InspectError> {
+        let mut resp = [0u8; 64];
+        let n = insp.handle(req, &mut resp).unwrap();
+        decode_response(&KEY, seq, &resp[..n]).map(|p| p.to_vec())
+    }
+
+    #[test]
+    fn whitelisted_reads_and_audit_trail() {
+        // SAFETY: `Target` serves reads from `RAM`.
+        let regions = [unsafe { Region::new(BASE, RAM.len()) }];
+        let mut log = Vec::new();
+        {
+            let mut insp = Inspector::new(Secret::new(KEY), &regions, Target, &mut log);
+            let base = BASE as u32;
+
+            let req = encode_request(&KEY, Command::ReadMemory, 1, base + 2, 4);
+            assert_eq!(exchange(&mut insp, &req, 1).unwrap(), b"nsor");
+
+            let req = encode_request(&KEY, Command::ReadMemory, 2, base + 6, 4);
+            assert_eq!(exchange(&mut insp, &req, 2), Err(InspectError::NotAllowed), "runs past the region");
+
+            let req = encode_request(&KEY, Command::TaskStates, 3, 0, 0);
+            assert_eq!(exchange(&mut insp, &req, 3).unwrap(), [1, 0]);
+        }
+
+        assert_eq!(log.len(), 3);
+        assert_eq!(log[0].outcome, Ok(4));
+        assert_eq!(log[1].outcome, Err(InspectError::NotAllowed));
+        assert_eq!(log[2].command, Some(Command::TaskStates));
+    }
+
+    #[test]
+    fn forged_and_replayed_requests_are_refused() {
+        let mut log = Vec::new();
+        {
+            let mut insp = Inspector::new(Secret::new(KEY), &[], Target, &mut log);
+
+            let forged = encode_request(&[8; KEY_LEN], Command::TaskStates, 1, 0, 0);
+            assert_eq!(exchange(&mut insp, &forged, 1), Err(InspectError::BadMac));
+
+            let req = encode_request(&KEY, Command::TaskStates, 5, 0, 0);
+            assert!(exchange(&mut insp, &req, 5).is_ok());
+            assert_eq!(exchange(&mut insp, &req, 5), Err(InspectError::Replay));
+            assert_eq!(exchange(&mut insp, &req[..10], 0), Err(InspectError::Malformed));
+        }
+        assert_eq!(log.len(), 4, "refused requests are audited too");
+    }
+}
@@ -0,0 +1,270 @@
+//! SecureIoTOS net IPv6 Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! IPv6 next to IPv4 on a `NetInterface`: the header, ICMPv6 echo and
+//! the neighbour discovery (RFC 4861) an Ethernet link needs.
+//!
+//! An Ethernet interface gets a link-local address from its MAC
+//! (modified EUI-64) and may be given one more, with an on-link prefix
+//! and a default router (`NetInterface::configure_ipv6()`). The
+//! interface then
+//!
+//! - resolves next hops with neighbour solicitations, in a
+//!   `NeighborCache` that follows the ARP cache's rules (see `arp`);
+//! - answers solicitations for its addresses, duplicate address
+//!   detection probes included, and echo requests;
+//! - sends packets with `NetInterface::send_ipv6()`.
+//!
+//! Neighbour discovery messages are only accepted with hop limit 255, so
+//! they cannot come from off the link. There are no router
+//! advertisements (SLAAC), no extension headers and no fragmentation;
+//! sockets remain IPv4, and other IPv6 traffic goes to the `poll()`
+//! handler. IEEE 802.15.4 links compress the header with `sixlowpan`.
+
+use crate::arp::ArpCache;
+use crate::ethernet::MacAddr;
+use crate::socket::checksum;
+use crate::Ipv6Addr;
+
+/// Fixed IPv6 header length.
+pub const HEADER_LEN: usize = 40;
+/// Next-header value of ICMPv6.
+pub const NEXT_HEADER_ICMPV6: u8 = 58;
+/// Hop limit of the packets the interface originates.
+pub const DEFAULT_HOP_LIMIT: u8 = 64;
+/// Hop limit neighbour discovery is sent and accepted with.
+pub(crate) const NDP_HOP_LIMIT: u8 = 255;
+
+const ECHO_REQUEST: u8 = 128;
+const ECHO_REPLY: u8 = 129;
+const NEIGHBOR_SOLICIT: u8 = 135;
+const NEIGHBOR_ADVERT: u8 = 136;
+const OPT_SOURCE_LL_ADDR: u8 = 1;
+const OPT_TARGET_LL_ADDR: u8 = 2;
+
+/// IPv6 to MAC address cache.
+pub type NeighborCache<const N: usize> = ArpCache<N, Ipv6Addr>;
+
+/// The fields of an IPv6 header the stack uses. Traffic class and flow
+/// label are sent as zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Ipv6Header {
+    pub src: Ipv6Addr,
+    pub dst: Ipv6Addr,
+    pub next_header: u8,
+    pub hop_limit: u8,
+}
+
+impl Ipv6Header {
+    /// Split a packet into header and payload, dropping link padding.
+    pub fn parse(packet: &[u8]) -> Option<(Self, &[u8])> {
+        if packet.len() < HEADER_LEN || packet[0] >> 4 != 6 {
+            return None;
+        }
+        let payload_len = u16::from_be_bytes([packet[4], packet[5]]) as usize;
+        let payload = packet.get(HEADER_LEN..HEADER_LEN + payload_len)?;
+        let addr = |at: usize| Ipv6Addr { octets: packet[at..at + 16].try_into().unwrap() };
+        Some((Self { src: addr(8), dst: addr(24), next_header: packet[6], hop_limit: packet[7] }, payload))
+    }
+
+    /// Write the header for a payload of `payload_len` bytes into the
+    /// first `HEADER_LEN` bytes of `packet`.
+    pub fn emit(&self, packet: &mut [u8], payload_len: usize) {
+        packet[0..4].copy_from_slice(&[0x60, 0, 0, 0]);
+        packet[4..6].copy_from_slice(&(payload_len as u16).to_be_bytes());
+        packet[6] = self.next_header;
+        packet[7] = self.hop_limit;
+        packet[8..24].copy_from_slice(&self.src.octets);
+        packet[24..40].copy_from_slice(&self.dst.octets);
+    }
+}
+
+/// Partial checksum of the upper-layer pseudo-header (RFC 8200 §8.1).
+pub fn pseudo_header_sum(src: Ipv6Addr, dst: Ipv6Addr, next_header: u8, len: usize) -> u32 {
+    let words =
+        |octets: [u8; 16]| -> u32 { octets.chunks_exact(2).map(|w| u16::from_be_bytes([w[0], w[1]]) as u32).sum() };
+    words(src.octets) + words(dst.octets) + (len as u32 >> 16) + (len as u32 & 0xFFFF) + next_header as u32
+}
+
+/// Ethernet group address of an IPv6 multicast address, `33:33` and its
+/// last 32 bits (RFC 2464 §7).
+pub fn multicast_mac(addr: Ipv6Addr) -> MacAddr {
+    let o = &addr.octets;
+    MacAddr([0x33, 0x33, o[12], o[13], o[14], o[15]])
+}
+
+/// Modified EUI-64 interface identifier of a MAC address (RFC 4291
+/// appendix A).
+pub fn interface_id(mac: MacAddr) -> [u8; 8] {
+    let m = mac.0;
+    [m[0] ^ 0x02, m[1], m[2], 0xff, 0xfe, m[3], m[4], m[5]]
+}
+
+/// The ICMPv6 messages the interface handles itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Icmpv6<'a> {
+    EchoRequest { ident: u16, seq: u16, data: &'a [u8] },
+    EchoReply { ident: u16, seq: u16, data: &'a [u8] },
+    NeighborSolicit { target: Ipv6Addr, source_mac: Option<MacAddr> },
+    NeighborAdvert { target: Ipv6Addr, solicited: bool, target_mac: Option<MacAddr> },
+}
+
+impl<'a> Icmpv6<'a> {
+    /// Parse the ICMPv6 message carried under `header`, checking its
+    /// checksum and the neighbour discovery rules of RFC 4861 §7.1.
+    /// Other message types give `None`.
+    pub(crate) fn parse(header: &Ipv6Header, body: &'a [u8]) -> Option<Self> {
+        if body.len() < 8
+            || checksum(body, pseudo_header_sum(header.src, header.dst, NEXT_HEADER_ICMPV6, body.len())) != 0
+        {
+            return None;
+        }
+        let word = |at: usize| u16::from_be_bytes([body[at], body[at + 1]]);
+        match body[0] {
+            ECHO_REQUEST | ECHO_REPLY if body[1] == 0 => {
+                let (ident, seq, data) = (word(4), word(6), &body[8..]);
+                Some(match body[0] {
+                    ECHO_REQUEST => Self::EchoRequest { ident, seq, data },
+                    _ => Self::EchoReply { ident, seq, data },
+                })
+            }
+            NEIGHBOR_SOLICIT | NEIGHBOR_ADVERT => {
+                if header.hop_limit != NDP_HOP_LIMIT || body[1] != 0 || body.len() < 24 {
+                    return None;
+                }
+                let target = Ipv6Addr { octets: body[8..24].try_into().unwrap() };
+                if target.is_multicast() {
+                    return None;
+                }
+                let link_addr = link_addr_option(&body[24..])?;
+                if body[0] == NEIGHBOR_SOLICIT {
+                    let source_mac = link_addr.filter(|(kind, _)| *kind == OPT_SOURCE_LL_ADDR).map(|(_, mac)| mac);
+                    // A duplicate address probe carries no source address option
+                    if header.src.is_unspecified() && (source_mac.is_some() || !header.dst.is_multicast()) {
+                        return None;
+                    }
+                    Some(Self::NeighborSolicit { target, source_mac })
+                } else {
+                    let solicited = body[4] & 0x40 != 0;
+                    if solicited && header.dst.is_multicast() {
+                        return None;
+                    }
+                    let target_mac = link_addr.filter(|(kind, _)| *kind == OPT_TARGET_LL_ADDR).map(|(_, mac)| mac);
+                    Some(Self::NeighborAdvert { target, solicited, target_mac })
+                }
+            }
+            _ => None,
+        }
+    }
+
+    /// Write the message from `src` to `dst` into `buf`, checksum
+    /// included. Returns its length.
+    pub(crate) fn emit(&self, src: Ipv6Addr, dst: Ipv6Addr, buf: &mut [u8]) -> usize {
+        let len = match *self {
+            Self::EchoRequest { ident, seq, data } | Self::EchoReply { ident, seq, data } => {
+                buf[0] = if matches!(self, Self::EchoRequest { .. }) { ECHO_REQUEST } else { ECHO_REPLY };
+                buf[4..6].copy_from_slice(&ident.to_be_bytes());
+                buf[6..8].copy_from_slice(&seq.to_be_bytes());
+                buf[8..8 + data.len()].copy_from_slice(data);
+                8 + data.len()
+            }
+            Self::NeighborSolicit { target, source_mac: mac } => {
+                buf[..8].fill(0);
+                buf[0] = NEIGHBOR_SOLICIT;
+                buf[8..24].copy_from_slice(&target.octets);
+                24 + emit_link_addr(OPT_SOURCE_LL_ADDR, mac, &mut buf[24..])
+            }
+            Self::NeighborAdvert { target, solicited, target_mac: mac } => {
+                buf[..8].fill(0);
+                buf[0] = NEIGHBOR_ADVERT;
+                // Override: the address is this node's own, not a proxy's
+                buf[4] = if solicited { 0x60 } else { 0x20 };
+                buf[8..24].copy_from_slice(&target.octets);
+                24 + emit_link_addr(OPT_TARGET_LL_ADDR, mac, &mut buf[24..])
+            }
+        };
+        buf[1] = 0;
+        buf[2..4].fill(0);
+        let sum = checksum(&buf[..len], pseudo_header_sum(src, dst, NEXT_HEADER_ICMPV6, len));
+        buf[2..4].copy_from_slice(&sum.to_be_bytes());
+        len
+    }
+}
+
+/// The link-layer address option among neighbour discovery `options`, as
+/// (option type, address). `None` if the options are malformed.
+fn link_addr_option(mut options: &[u8]) -> Option<Option<(u8, MacAddr)>> {
+    let mut found = None;
+    while !options.is_empty() {
+        let len = *options.get(1)? as usize * 8;
+        if len == 0 || len > options.len() {
+            return None;
+        }
+        if matches!(options[0], OPT_SOURCE_LL_ADDR | OPT_TARGET_LL_ADDR) && len == 8 {
+            found = Some((options[0], MacAddr(options[2..8].try_into().unwrap())));
+        }
+        options = &options[len..];
+    }
+    Some(found)
+}
+
+fn emit_link_addr(kind: u8, mac: Option<MacAddr>, buf: &mut [u8]) -> usize {
+    let Some(mac) = mac else {
+        return 0;
+    };
+    buf[0] = kind;
+    buf[1] = 1;
+    buf[2..8].copy_from_slice(&mac.0);
+    8
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    const MAC: MacAddr = MacAddr([0x02, 0x00, 0x5e, 0x10, 0x00, 0x01]);
+
+    #[test]
+    fn addresses() {
+        let ll = Ipv6Addr::link_local(interface_id(MAC));
+        assert_eq!(format!("{:?}", ll), "fe80::5eff:fe10:1");
+        assert!(ll.is_link_local() && !ll.is_multicast());
+        assert_eq!(format!("{:?}", ll.solicited_node()), "ff02::1:ff10:1");
+        assert_eq!(multicast_mac(ll.solicited_node()), MacAddr([0x33, 0x33, 0xff, 0x10, 0x00, 0x01]));
+        assert_eq!(format!("{:?}", Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 1, 0, 0, 1)), "2001:db8::1:0:0:1");
+        assert_eq!(format!("{:?}", Ipv6Addr::UNSPECIFIED), "::");
+        assert_eq!(format!("{:?}", Ipv6Addr::new(1, 0, 2, 3, 4, 5, 6, 7)), "1:0:2:3:4:5:6:7");
+
+        let prefix = Ipv6Addr::new(0x2001, 0xdb8, 0, 1, 0, 0, 0, 0);
+        assert!(Ipv6Addr::new(0x2001, 0xdb8, 0, 1, 0, 0, 0, 9).same_prefix(&prefix, 64));
+        assert!(!Ipv6Addr::new(0x2001, 0xdb8, 0, 2, 0, 0, 0, 9).same_prefix(&prefix, 64));
+    }
+
+    #[test]
+    fn neighbor_discovery_is_checked() {
+        let src = Ipv6Addr::link_local(interface_id(MAC));
+        let target = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 2);
+        let solicit = Icmpv6::NeighborSolicit { target, source_mac: Some(MAC) };
+        let mut buf = [0u8; 32];
+        let len = solicit.emit(src, target.solicited_node(), &mut buf);
+        let header =
+            Ipv6Header { src, dst: target.solicited_node(), next_header: NEXT_HEADER_ICMPV6, hop_limit: NDP_HOP_LIMIT };
+        assert_eq!(Icmpv6::parse(&header, &buf[..len]), Some(solicit));
+
+        // Forwarded by a router, or corrupted: dropped
+        assert_eq!(Icmpv6::parse(&Ipv6Header { hop_limit: 254, ..header }, &buf[..len]), None);
+        buf[10] ^= 1;
+        assert_eq!(Icmpv6::parse(&header, &buf[..len]), None);
+
+        // A probe from the unspecified address must not claim a MAC address
+        let probe = Ipv6Header { src: Ipv6Addr::UNSPECIFIED, ..header };
+        let len = solicit.emit(probe.src, probe.dst, &mut buf);
+        assert_eq!(Icmpv6::parse(&probe, &buf[..len]), None);
+    }
+}
@@ -6,40 +6,75 @@
 //! URL: https://m-a-h-b-u-b.github.io
 //! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
 //! Minimal, portable networking primitives and traits for SecureIoTOS.
-
-/// #![no_std]: allows the code to run in embedded environments without the standard library.
-#![cfg_attr(not(feature = "std"), no_std)]
-
-#[cfg(all(not(feature = "std"), feature = "alloc"))]
-
-/// extern crate alloc: enables heap allocations when alloc is available.
-extern crate alloc;
-
-#[cfg(feature = "std")]
-
-/// use std as core_std: makes std available for debug/test features
-use std as core_std;
-
-use core::fmt;
-
+//!
 //! This module is intentionally small and dependency-light so it can be
 //! integrated into embedded projects. It provides:
 //! - `NetworkDevice` trait: low-level send/receive abstraction for a link
 //! - `NetworkStack` struct: a tiny coordinator that can hold a device and
-//!   perform simple operations (ARP/DHCP stubs left for integration)
-//! - Small IP/address types and error handling
-//! - Feature gates: `std` (enables std collections & tests) and `alloc`
+//!   perform simple operations (ARP, DHCP, ping, IPv6 neighbour discovery)
+//! - Small IPv4/IPv6 address types and error handling
+//! - `socket`: UDP and TCP sockets the stack demultiplexes by port
+//! - Feature gates: `std` (enables std collections & tests), `alloc`,
+//!   `error-strings` (error text; numeric codes otherwise) and
+//!   `sixlowpan` (IPv6 header compression for 802.15.4)
 //!
 //! Guidance:
 //! - For real embedded networking use `smoltcp`, `embassy-net`, or similar.
 //!   This module is a thin, testable shim that lets higher-level code be
 //!   written against an interface that can be adapted to those stacks.
 
+// no_std: allows the code to run in embedded environments without the standard library.
+#![cfg_attr(not(feature = "std"), no_std)]
 
-
-/// Use alloc::vec::Vec when `alloc` feature is enabled; fall back to slice API otherwise.
+// extern crate alloc: enables heap allocations when alloc is available.
 #[cfg(feature = "alloc")]
-use alloc::vec::Vec;
+extern crate alloc;
+
+use core::fmt;
+
+/// ARP neighbour cache for Ethernet interfaces
+pub mod arp;
+
+/// Capture and deterministic replay of received traffic
+pub mod capture;
+
+/// DHCP client that configures the interface
+pub mod dhcp;
+
+/// Egress accounting and quotas per task and destination
+pub mod egress;
+
+/// IPv4 fragmentation and reassembly
+pub mod fragment;
+
+/// ICMP echo for IPv4
+pub mod icmp;
+
+/// Ethernet II framing and MAC addresses
+pub mod ethernet;
+
+/// IPv6 header, ICMPv6 and neighbour discovery
+pub mod ipv6;
+
+/// 6LoWPAN header compression for IEEE 802.15.4 links
+#[cfg(feature = "sixlowpan")]
+pub mod sixlowpan;
+
+/// UDP and TCP sockets demultiplexed by `NetworkStack::poll`
+pub mod socket;
+
+use arp::{ArpCache, ArpOp, ArpPacket};
+use dhcp::DhcpClient;
+use egress::{EgressAccounting, TaskId};
+use fragment::Reassembler;
+use icmp::Icmpv4;
+use ethernet::{EthernetHeader, MacAddr};
+use ipv6::{Icmpv6, Ipv6Header, NeighborCache};
+use socket::{Endpoint, Events, Socket, SocketHandle, TcpSocket, UdpSocket};
+
+/// Authenticated remote memory/task inspection for operator tools
+#[cfg(feature = "inspect")]
+pub mod inspect;
 
 /// IP address type (IPv4 only for now)
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
@@ -98,8 +133,160 @@ impl fmt::Debug for Ipv4Addr {
     }
 }
 
+#[cfg(feature = "defmt")]
+impl defmt::Format for Ipv4Addr {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        let o = &self.octets;
+        defmt::write!(f, "{}.{}.{}.{}", o[0], o[1], o[2], o[3])
+    }
+}
+
+/// IPv6 address, stored in network byte order.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Ipv6Addr {
+    pub octets: [u8; 16],
+}
+
+impl Ipv6Addr {
+    /// `::`, the source of a packet sent before an address is assigned.
+    pub const UNSPECIFIED: Self = Self { octets: [0; 16] };
+    /// `ff02::1`, every node on the link.
+    pub const ALL_NODES: Self = Self::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+    /// `ff02::2`, every router on the link.
+    pub const ALL_ROUTERS: Self = Self::new(0xff02, 0, 0, 0, 0, 0, 0, 2);
+
+    /// Address from its eight 16-bit groups, e.g.
+    /// `Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1)` for `fe80::1`.
+    #[allow(clippy::too_many_arguments)]
+    pub const fn new(a: u16, b: u16, c: u16, d: u16, e: u16, f: u16, g: u16, h: u16) -> Self {
+        let mut octets = [0u8; 16];
+        let groups = [a, b, c, d, e, f, g, h];
+        let mut i = 0;
+        while i < 8 {
+            let [hi, lo] = groups[i].to_be_bytes();
+            octets[2 * i] = hi;
+            octets[2 * i + 1] = lo;
+            i += 1;
+        }
+        Self { octets }
+    }
+
+    pub const fn localhost() -> Self {
+        Self::new(0, 0, 0, 0, 0, 0, 0, 1)
+    }
+
+    /// Link-local address `fe80::/64` with interface identifier `iid`.
+    pub const fn link_local(iid: [u8; 8]) -> Self {
+        let mut octets = [0u8; 16];
+        octets[0] = 0xfe;
+        octets[1] = 0x80;
+        let mut i = 0;
+        while i < 8 {
+            octets[8 + i] = iid[i];
+            i += 1;
+        }
+        Self { octets }
+    }
+
+    /// The eight 16-bit groups.
+    pub fn segments(&self) -> [u16; 8] {
+        core::array::from_fn(|i| u16::from_be_bytes([self.octets[2 * i], self.octets[2 * i + 1]]))
+    }
+
+    pub fn is_unspecified(&self) -> bool {
+        *self == Self::UNSPECIFIED
+    }
+
+    /// `ff00::/8`
+    pub const fn is_multicast(&self) -> bool {
+        self.octets[0] == 0xff
+    }
+
+    /// `fe80::/10`
+    pub const fn is_link_local(&self) -> bool {
+        self.octets[0] == 0xfe && self.octets[1] & 0xc0 == 0x80
+    }
+
+    /// Multicast group neighbour solicitations for this address go to,
+    /// `ff02::1:ffXX:XXXX` (RFC 4291 §2.7.1).
+    pub const fn solicited_node(&self) -> Self {
+        let mut group = Self::new(0xff02, 0, 0, 0, 0, 1, 0xff00, 0);
+        group.octets[13] = self.octets[13];
+        group.octets[14] = self.octets[14];
+        group.octets[15] = self.octets[15];
+        group
+    }
+
+    /// True if the first `prefix_len` bits of both addresses match.
+    pub fn same_prefix(&self, other: &Ipv6Addr, prefix_len: u8) -> bool {
+        let a = u128::from_be_bytes(self.octets);
+        let b = u128::from_be_bytes(other.octets);
+        match prefix_len {
+            0 => true,
+            len => (a ^ b) >> (128 - len.min(128) as u32) == 0,
+        }
+    }
+}
+
+impl fmt::Debug for Ipv6Addr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let groups = self.segments();
+        // The longest run of two or more zero groups becomes "::" (RFC 5952)
+        let (mut best, mut run) = ((0, 0), (0, 0));
+        for (i, group) in groups.iter().enumerate() {
+            if *group != 0 {
+                run = (i + 1, 0);
+                continue;
+            }
+            run.1 += 1;
+            if run.1 > best.1 {
+                best = run;
+            }
+        }
+        let write_groups = |f: &mut fmt::Formatter<'_>, groups: &[u16]| -> fmt::Result {
+            for (i, group) in groups.iter().enumerate() {
+                if i > 0 {
+                    f.write_str(":")?;
+                }
+                write!(f, "{:x}", group)?;
+            }
+            Ok(())
+        };
+        if best.1 < 2 {
+            return write_groups(f, &groups);
+        }
+        write_groups(f, &groups[..best.0])?;
+        f.write_str("::")?;
+        write_groups(f, &groups[best.0 + best.1..])
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for Ipv6Addr {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        let g = self.segments();
+        defmt::write!(
+            f,
+            "{=u16:x}:{=u16:x}:{=u16:x}:{=u16:x}:{=u16:x}:{=u16:x}:{=u16:x}:{=u16:x}",
+            g[0],
+            g[1],
+            g[2],
+            g[3],
+            g[4],
+            g[5],
+            g[6],
+            g[7]
+        )
+    }
+}
+
 /// Simple network error enum used everywhere in this module.
-#[derive(Debug)]
+///
+/// Every error has a stable numeric code (`code()`), so error paths need
+/// neither `alloc` nor string tables. The human-readable text
+/// (`error_str()`) is only compiled in with the `error-strings` feature;
+/// without it `Display`/`defmt` print the code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum NetError {
     /// Device-specific IO error
     DeviceError,
@@ -109,24 +296,114 @@ pub enum NetError {
     Timeout,
     /// Operation not supported by the device/stack
     Unsupported,
-    /// Generic failure with a textual message (requires `std` or `alloc`)
-    #[cfg(any(feature = "alloc", feature = "std"))]
-    Other(String),
+    /// Send refused by the firewall hook
+    Denied,
+    /// Send would exceed the sender's egress quota
+    QuotaExceeded,
+    /// No free socket, port or buffer space
+    Exhausted,
+    /// Port already bound by another socket
+    AddrInUse,
+    /// Wrong kind of socket, or not valid in the socket's state
+    InvalidState,
+    /// The peer reset the connection
+    ConnectionReset,
+    /// The peer stopped acknowledging
+    ConnectionTimedOut,
+    /// Next hop's MAC address not known yet; an ARP request is out
+    Unresolved,
+    /// Destination off the local subnet and no gateway configured
+    NoRoute,
+    /// Failure reported by a device or application, with its own code
+    /// (`NetError::OTHER_BASE` and up)
+    Other(u16),
 }
 
-impl fmt::Display for NetError {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+impl NetError {
+    /// First code available to `Other`; lower codes are reserved for the
+    /// variants above.
+    pub const OTHER_BASE: u16 = 0x100;
+
+    /// Stable numeric code of this error.
+    pub const fn code(&self) -> u16 {
+        match self {
+            NetError::DeviceError => 1,
+            NetError::MalformedPacket => 2,
+            NetError::Timeout => 3,
+            NetError::Unsupported => 4,
+            NetError::Denied => 5,
+            NetError::QuotaExceeded => 6,
+            NetError::Exhausted => 7,
+            NetError::AddrInUse => 8,
+            NetError::InvalidState => 9,
+            NetError::ConnectionReset => 10,
+            NetError::ConnectionTimedOut => 11,
+            NetError::Unresolved => 12,
+            NetError::NoRoute => 13,
+            NetError::Other(code) => *code,
+        }
+    }
+
+    /// Error for `code`, as returned by `code()`. Unknown codes below
+    /// `OTHER_BASE` give `None`.
+    pub const fn from_code(code: u16) -> Option<Self> {
+        match code {
+            1 => Some(NetError::DeviceError),
+            2 => Some(NetError::MalformedPacket),
+            3 => Some(NetError::Timeout),
+            4 => Some(NetError::Unsupported),
+            5 => Some(NetError::Denied),
+            6 => Some(NetError::QuotaExceeded),
+            7 => Some(NetError::Exhausted),
+            8 => Some(NetError::AddrInUse),
+            9 => Some(NetError::InvalidState),
+            10 => Some(NetError::ConnectionReset),
+            11 => Some(NetError::ConnectionTimedOut),
+            12 => Some(NetError::Unresolved),
+            13 => Some(NetError::NoRoute),
+            c if c >= Self::OTHER_BASE => Some(NetError::Other(c)),
+            _ => None,
+        }
+    }
+
+    /// Short description of the error.
+    #[cfg(feature = "error-strings")]
+    pub const fn error_str(&self) -> &'static str {
         match self {
-            NetError::DeviceError => write!(f, "device error"),
-            NetError::MalformedPacket => write!(f, "malformed packet"),
-            NetError::Timeout => write!(f, "timeout"),
-            NetError::Unsupported => write!(f, "unsupported operation"),
-            #[cfg(any(feature = "alloc", feature = "std"))]
-            NetError::Other(s) => write!(f, "{}", s),
+            NetError::DeviceError => "device error",
+            NetError::MalformedPacket => "malformed packet",
+            NetError::Timeout => "timeout",
+            NetError::Unsupported => "unsupported operation",
+            NetError::Denied => "denied by firewall",
+            NetError::QuotaExceeded => "egress quota exceeded",
+            NetError::Exhausted => "resources exhausted",
+            NetError::AddrInUse => "address in use",
+            NetError::InvalidState => "invalid socket state",
+            NetError::ConnectionReset => "connection reset",
+            NetError::ConnectionTimedOut => "connection timed out",
+            NetError::Unresolved => "address not resolved",
+            NetError::NoRoute => "no route to host",
+            NetError::Other(_) => "device/application error",
         }
     }
 }
 
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        #[cfg(feature = "error-strings")]
+        write!(f, "{} ", self.error_str())?;
+        write!(f, "(net error {})", self.code())
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for NetError {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        // Codes only: the host tools can look the text up.
+        defmt::write!(f, "net error {=u16}", self.code())
+    }
+}
+
 #[cfg(feature = "std")]
 impl std::error::Error for NetError {}
 
@@ -161,12 +438,50 @@ pub trait NetworkDevice {
     }
 }
 
+/// Neighbours an Ethernet `NetInterface` caches, per IP version.
+pub const ARP_ENTRIES: usize = 8;
+
+/// What a received frame turned out to be.
+enum LinkInput<'a> {
+    /// An IPv4 packet for this interface (link header stripped)
+    Ipv4(&'a [u8]),
+    /// An IPv6 packet for this interface
+    Ipv6(&'a [u8]),
+    /// Link-layer traffic the interface handled itself (ARP)
+    Consumed,
+    /// Anything else
+    Other,
+}
+
 /// Simple structure representing a bound interface (device + IP info)
+///
+/// With a MAC address the interface speaks Ethernet: packets go to the
+/// next hop (the destination on the local subnet, the gateway otherwise)
+/// resolved through ARP (see `arp`). Without one it sends bare IPv4.
+///
+/// IPv6 runs alongside, with neighbour discovery in place of ARP (see
+/// `ipv6`).
 pub struct NetInterface<D: NetworkDevice> {
     pub device: D,
     pub ip: Option<Ipv4Addr>,
     pub netmask: Option<Ipv4Addr>,
     pub gateway: Option<Ipv4Addr>,
+    /// Ethernet address; `None` on links that carry bare IP (PPP, TUN)
+    pub mac: Option<MacAddr>,
+    /// IPv6 link-local address; derived from the MAC address on Ethernet
+    pub link_local: Option<Ipv6Addr>,
+    /// Further IPv6 address (global or unique local)
+    pub ipv6: Option<Ipv6Addr>,
+    /// Length of the on-link prefix of `ipv6`
+    pub ipv6_prefix_len: u8,
+    /// Default IPv6 router, usually a link-local address
+    pub ipv6_gateway: Option<Ipv6Addr>,
+    arp: ArpCache<ARP_ENTRIES>,
+    neighbors: NeighborCache<ARP_ENTRIES>,
+    /// Clock for ARP expiry, advanced by `set_time()`
+    now_ms: u64,
+    /// Identification of the last IPv4 datagram sent
+    ident: u16,
 }
 
 impl<D: NetworkDevice> NetInterface<D> {
@@ -177,6 +492,154 @@ impl<D: NetworkDevice> NetInterface<D> {
             ip: None,
             netmask: None,
             gateway: None,
+            mac: None,
+            link_local: None,
+            ipv6: None,
+            ipv6_prefix_len: 64,
+            ipv6_gateway: None,
+            arp: ArpCache::new(),
+            neighbors: NeighborCache::new(),
+            now_ms: 0,
+            ident: 0,
+        }
+    }
+
+    /// Create an Ethernet interface with MAC address `mac`, and the IPv6
+    /// link-local address derived from it.
+    pub fn with_ethernet(device: D, mac: MacAddr) -> Self {
+        let link_local = Ipv6Addr::link_local(ipv6::interface_id(mac));
+        Self { mac: Some(mac), link_local: Some(link_local), ..Self::new(device) }
+    }
+
+    /// Advance the interface's clock (milliseconds, monotonic).
+    /// `NetworkStack::dispatch()` does this.
+    pub fn set_time(&mut self, now_ms: u64) {
+        self.now_ms = now_ms;
+    }
+
+    /// The ARP cache, to inspect it or pin the gateway.
+    pub fn arp_cache(&mut self) -> &mut ArpCache<ARP_ENTRIES> {
+        &mut self.arp
+    }
+
+    /// Start the IPv4 identification counter at `seed`, ideally random so
+    /// that off-path hosts cannot guess it and inject fragments.
+    pub fn set_ident_seed(&mut self, seed: u16) {
+        self.ident = seed;
+    }
+
+    /// The IPv6 neighbour cache, to inspect it or pin the router.
+    pub fn neighbor_cache(&mut self) -> &mut NeighborCache<ARP_ENTRIES> {
+        &mut self.neighbors
+    }
+
+    /// Address a packet for `dest` goes to first: `dest` itself on the
+    /// local subnet, the gateway otherwise.
+    pub fn next_hop(&self, dest: Ipv4Addr) -> NetResult<Ipv4Addr> {
+        let (Some(ip), Some(mask)) = (self.ip, self.netmask) else {
+            return Err(NetError::Unsupported);
+        };
+        let (ip, mask, d) =
+            (u32::from_be_bytes(ip.octets), u32::from_be_bytes(mask.octets), u32::from_be_bytes(dest.octets));
+        if d & mask == ip & mask || d == u32::MAX {
+            return Ok(dest);
+        }
+        self.gateway.filter(|gw| *gw != Ipv4Addr::new(0, 0, 0, 0)).ok_or(NetError::NoRoute)
+    }
+
+    /// MAC address to send a packet for `dest` to, or `None` on a bare
+    /// IPv4 link. On a cache miss this broadcasts an ARP request (unless
+    /// one went out recently) and fails with `NetError::Unresolved`.
+    pub fn resolve(&mut self, dest: Ipv4Addr) -> NetResult<Option<MacAddr>> {
+        let Some(mac) = self.mac else {
+            return Ok(None);
+        };
+        // Needs no address of our own, which DHCP relies on
+        if dest == Ipv4Addr::new(255, 255, 255, 255) {
+            return Ok(Some(MacAddr::BROADCAST));
+        }
+        let hop = self.next_hop(dest)?;
+        let mask = self.netmask.map_or(0, |m| u32::from_be_bytes(m.octets));
+        let hop_bits = u32::from_be_bytes(hop.octets);
+        // Limited or subnet-directed broadcast
+        if hop_bits == u32::MAX || (mask != u32::MAX && hop_bits | mask == u32::MAX) {
+            return Ok(Some(MacAddr::BROADCAST));
+        }
+        if let Some(hop_mac) = self.arp.lookup(hop, self.now_ms) {
+            return Ok(Some(hop_mac));
+        }
+        if self.arp.request(hop, self.now_ms) {
+            sios_log::debug!("ARP: who has {:?}", hop);
+            let request = ArpPacket {
+                op: ArpOp::Request,
+                sender_mac: mac,
+                sender_ip: self.ip.ok_or(NetError::Unsupported)?,
+                target_mac: MacAddr([0; 6]),
+                target_ip: hop,
+            };
+            self.send_arp(MacAddr::BROADCAST, &request)?;
+        }
+        Err(NetError::Unresolved)
+    }
+
+    fn send_arp(&mut self, dst: MacAddr, packet: &ArpPacket) -> NetResult<()> {
+        let src = self.mac.ok_or(NetError::Unsupported)?;
+        let mut frame = [0u8; ethernet::MIN_FRAME_LEN];
+        EthernetHeader { dst, src, ethertype: ethernet::ETHERTYPE_ARP }.emit(&mut frame);
+        let body: &mut [u8; arp::PACKET_LEN] =
+            (&mut frame[ethernet::HEADER_LEN..ethernet::HEADER_LEN + arp::PACKET_LEN]).try_into().unwrap();
+        packet.emit(body);
+        self.device.send(&frame)
+    }
+
+    /// Strip the link layer from a received frame. ARP is answered and
+    /// learnt from here.
+    fn link_input<'a>(&mut self, frame: &'a [u8]) -> LinkInput<'a> {
+        let Some(mac) = self.mac else {
+            return match frame.first().map(|b| b >> 4) {
+                Some(6) => LinkInput::Ipv6(frame),
+                _ => LinkInput::Ipv4(frame),
+            };
+        };
+        let Some((header, payload)) = EthernetHeader::parse(frame) else {
+            return LinkInput::Other;
+        };
+        let joined = |group: Ipv6Addr| ipv6::multicast_mac(group) == header.dst;
+        let ipv6_group = header.dst.is_multicast()
+            && (joined(Ipv6Addr::ALL_NODES) || self.ipv6_addrs().any(|a| joined(a.solicited_node())));
+        if header.dst != mac && header.dst != MacAddr::BROADCAST && !ipv6_group {
+            return LinkInput::Other;
+        }
+        match header.ethertype {
+            ethernet::ETHERTYPE_IPV4 => LinkInput::Ipv4(payload),
+            ethernet::ETHERTYPE_IPV6 => LinkInput::Ipv6(payload),
+            ethernet::ETHERTYPE_ARP => {
+                let Some(packet) = ArpPacket::parse(payload) else {
+                    return LinkInput::Other;
+                };
+                let Some(ip) = self.ip.filter(|ip| *ip == packet.target_ip) else {
+                    // Not about us: nothing to answer or learn
+                    return LinkInput::Consumed;
+                };
+                match packet.op {
+                    ArpOp::Request => {
+                        self.arp.learn(packet.sender_ip, packet.sender_mac, self.now_ms, true);
+                        let reply = ArpPacket {
+                            op: ArpOp::Reply,
+                            sender_mac: mac,
+                            sender_ip: ip,
+                            target_mac: packet.sender_mac,
+                            target_ip: packet.sender_ip,
+                        };
+                        if self.send_arp(packet.sender_mac, &reply).is_err() {
+                            sios_log::debug!("ARP: reply to {:?} not sent", packet.sender_ip);
+                        }
+                    }
+                    ArpOp::Reply => self.arp.learn(packet.sender_ip, packet.sender_mac, self.now_ms, false),
+                }
+                LinkInput::Consumed
+            }
+            _ => LinkInput::Other,
         }
     }
 
@@ -193,43 +656,242 @@ impl<D: NetworkDevice> NetInterface<D> {
     /// used; it produces a minimal IPv4 header (no options) and doesn't set
     /// all fields properly for production. Use a real IP stack in production.
     pub fn send_ipv4_payload(&mut self, dest: Ipv4Addr, payload: &[u8]) -> NetResult<()> {
+        self.send_ipv4(dest, socket::PROTO_UDP, payload)
+    }
+
+    /// Send `payload` to `dest` as an IPv4 datagram of `protocol` (no
+    /// options), in fragments if it does not fit the device MTU (see
+    /// `fragment`), in Ethernet frames to the next hop if the interface
+    /// has a MAC address.
+    pub fn send_ipv4(&mut self, dest: Ipv4Addr, protocol: u8, payload: &[u8]) -> NetResult<()> {
         let src = self.ip.ok_or(NetError::Unsupported)?;
-        let total_len = 20 + payload.len(); // IPv4 header (20) + payload
-        if total_len > self.device.mtu() {
+        self.send_ipv4_from(src, dest, protocol, payload)
+    }
+
+    /// `send_ipv4` with source address `src`, e.g. 0.0.0.0 while DHCP
+    /// has not configured one yet.
+    pub fn send_ipv4_from(&mut self, src: Ipv4Addr, dest: Ipv4Addr, protocol: u8, payload: &[u8]) -> NetResult<()> {
+        let mtu = self.device.mtu().min(1500);
+        if 20 + payload.len() > u16::MAX as usize || mtu < 20 + 8 {
+            sios_log::debug!("IPv4 datagram of {} bytes cannot be sent with MTU {}", 20 + payload.len(), mtu);
             return Err(NetError::MalformedPacket);
         }
+        let link_dst = self.resolve(dest)?;
+        self.ident = self.ident.wrapping_add(1);
 
-        let mut frame: [u8; 1500] = [0u8; 1500];
-        // IPv4 minimal header build (big-endian)
-        // Version(4) + IHL(4)
-        frame[0] = 0x45;
-        // DSCP/ECN
-        frame[1] = 0;
-        // Total Length
-        frame[2] = ((total_len >> 8) & 0xFF) as u8;
-        frame[3] = (total_len & 0xFF) as u8;
-        // Identification
-        frame[4] = 0;
-        frame[5] = 0;
-        // Flags/Fragment offset
-        frame[6] = 0;
-        frame[7] = 0;
-        // TTL
-        frame[8] = 64;
-        // Protocol: 0x11 = UDP (we're just illustrating)
-        frame[9] = 0x11;
-        // Header checksum (0 for now; a real stack would compute)
-        frame[10] = 0;
-        frame[11] = 0;
-        // Src IP
-        frame[12..16].copy_from_slice(&src.to_be_bytes());
-        // Dst IP
-        frame[16..20].copy_from_slice(&dest.to_be_bytes());
-        // Payload
-        let start = 20;
-        frame[start..start + payload.len()].copy_from_slice(payload);
-
-        self.device.send(&frame[..total_len])
+        for (offset, len, more) in fragment::fragments(payload.len(), mtu) {
+            let mut header = [0u8; 20];
+            // Version 4, 5-word header, no DSCP/ECN
+            header[0] = 0x45;
+            header[2..4].copy_from_slice(&((20 + len) as u16).to_be_bytes());
+            header[4..6].copy_from_slice(&self.ident.to_be_bytes());
+            // More-fragments flag and the offset in 8-byte blocks
+            let flags_offset = if more { 0x2000 } else { 0 } | (offset / 8) as u16;
+            header[6..8].copy_from_slice(&flags_offset.to_be_bytes());
+            // TTL
+            header[8] = 64;
+            // Protocol: 0x06 = TCP, 0x11 = UDP
+            header[9] = protocol;
+            header[12..16].copy_from_slice(&src.to_be_bytes());
+            header[16..20].copy_from_slice(&dest.to_be_bytes());
+            // Header checksum, over the header with the field zeroed
+            let sum = socket::checksum(&header, 0);
+            header[10..12].copy_from_slice(&sum.to_be_bytes());
+            self.send_ipv4_packet(link_dst, &header, &payload[offset..offset + len])?;
+        }
+        Ok(())
+    }
+
+    fn send_ipv4_packet(&mut self, link_dst: Option<MacAddr>, header: &[u8; 20], payload: &[u8]) -> NetResult<()> {
+        let mut frame: [u8; ethernet::HEADER_LEN + 1500] = [0u8; ethernet::HEADER_LEN + 1500];
+        let link_len = match (link_dst, self.mac) {
+            (Some(dst), Some(src)) => {
+                EthernetHeader { dst, src, ethertype: ethernet::ETHERTYPE_IPV4 }.emit(&mut frame);
+                ethernet::HEADER_LEN
+            }
+            _ => 0,
+        };
+        let total_len = 20 + payload.len();
+        frame[link_len..link_len + 20].copy_from_slice(header);
+        frame[link_len + 20..link_len + total_len].copy_from_slice(payload);
+
+        // Ethernet frames are padded to the minimum length
+        let len = match link_len {
+            0 => total_len,
+            _ => (link_len + total_len).max(ethernet::MIN_FRAME_LEN),
+        };
+        self.device.send(&frame[..len])
+    }
+
+    /// Configure a static IPv6 address next to the link-local one, with
+    /// its on-link prefix and an optional default router.
+    pub fn configure_ipv6(&mut self, addr: Ipv6Addr, prefix_len: u8, gateway: Option<Ipv6Addr>) {
+        self.ipv6 = Some(addr);
+        self.ipv6_prefix_len = prefix_len.min(128);
+        self.ipv6_gateway = gateway;
+    }
+
+    /// The interface's IPv6 unicast addresses.
+    pub fn ipv6_addrs(&self) -> impl Iterator<Item = Ipv6Addr> {
+        self.link_local.into_iter().chain(self.ipv6)
+    }
+
+    fn owns_ipv6(&self, addr: Ipv6Addr) -> bool {
+        self.ipv6_addrs().any(|own| own == addr)
+    }
+
+    /// Address to send packets for `dest` from: the link-local one for
+    /// link-local and link-scope multicast destinations, the configured
+    /// one otherwise.
+    pub fn ipv6_source(&self, dest: Ipv6Addr) -> Option<Ipv6Addr> {
+        let link_scope = dest.is_link_local() || (dest.is_multicast() && dest.octets[1] & 0x0F <= 2);
+        if link_scope {
+            self.link_local.or(self.ipv6)
+        } else {
+            self.ipv6.or(self.link_local)
+        }
+    }
+
+    /// `next_hop` for IPv6: `dest` itself if link-local or on the
+    /// configured prefix, the default router otherwise.
+    pub fn next_hop_ipv6(&self, dest: Ipv6Addr) -> NetResult<Ipv6Addr> {
+        let on_link = dest.is_link_local()
+            || dest.is_multicast()
+            || self.ipv6.is_some_and(|ip| ip.same_prefix(&dest, self.ipv6_prefix_len));
+        if on_link {
+            return Ok(dest);
+        }
+        self.ipv6_gateway.ok_or(NetError::NoRoute)
+    }
+
+    /// `resolve` for IPv6. On a cache miss this sends a neighbour
+    /// solicitation (unless one went out recently) and fails with
+    /// `NetError::Unresolved`.
+    pub fn resolve_ipv6(&mut self, dest: Ipv6Addr) -> NetResult<Option<MacAddr>> {
+        let Some(mac) = self.mac else {
+            return Ok(None);
+        };
+        if dest.is_multicast() {
+            return Ok(Some(ipv6::multicast_mac(dest)));
+        }
+        let hop = self.next_hop_ipv6(dest)?;
+        if let Some(hop_mac) = self.neighbors.lookup(hop, self.now_ms) {
+            return Ok(Some(hop_mac));
+        }
+        if self.neighbors.request(hop, self.now_ms) {
+            sios_log::debug!("NDP: who has {:?}", hop);
+            let src = self.ipv6_source(hop).ok_or(NetError::Unsupported)?;
+            let solicit = Icmpv6::NeighborSolicit { target: hop, source_mac: Some(mac) };
+            self.send_icmpv6(src, hop.solicited_node(), &solicit)?;
+        }
+        Err(NetError::Unresolved)
+    }
+
+    /// Send `payload` to `dest` as a single IPv6 packet with
+    /// `next_header` (no extension headers, no fragmentation), in an
+    /// Ethernet frame to the next hop if the interface has a MAC address.
+    pub fn send_ipv6(&mut self, dest: Ipv6Addr, next_header: u8, payload: &[u8]) -> NetResult<()> {
+        let src = self.ipv6_source(dest).ok_or(NetError::Unsupported)?;
+        let link_dst = self.resolve_ipv6(dest)?;
+        let header = Ipv6Header { src, dst: dest, next_header, hop_limit: ipv6::DEFAULT_HOP_LIMIT };
+        self.send_ipv6_packet(link_dst, &header, payload)
+    }
+
+    fn send_icmpv6(&mut self, src: Ipv6Addr, dst: Ipv6Addr, message: &Icmpv6) -> NetResult<()> {
+        let link_dst = match message {
+            // Neighbour discovery goes to a group or an address just learnt
+            Icmpv6::NeighborSolicit { .. } | Icmpv6::NeighborAdvert { .. } => {
+                let link_dst = if dst.is_multicast() { Some(ipv6::multicast_mac(dst)) } else { None };
+                link_dst.or_else(|| self.neighbors.lookup(dst, self.now_ms)).filter(|_| self.mac.is_some())
+            }
+            _ => self.resolve_ipv6(dst)?,
+        };
+        let hop_limit = match message {
+            Icmpv6::NeighborSolicit { .. } | Icmpv6::NeighborAdvert { .. } => ipv6::NDP_HOP_LIMIT,
+            _ => ipv6::DEFAULT_HOP_LIMIT,
+        };
+        let mut body = [0u8; MAX_IPV6_PAYLOAD];
+        let len = message.emit(src, dst, &mut body);
+        let header = Ipv6Header { src, dst, next_header: ipv6::NEXT_HEADER_ICMPV6, hop_limit };
+        self.send_ipv6_packet(link_dst, &header, &body[..len])
+    }
+
+    fn send_ipv6_packet(&mut self, link_dst: Option<MacAddr>, header: &Ipv6Header, payload: &[u8]) -> NetResult<()> {
+        let total_len = ipv6::HEADER_LEN + payload.len();
+        if total_len > self.device.mtu() || total_len > 1500 {
+            sios_log::debug!("IPv6 packet of {} bytes exceeds MTU {}", total_len, self.device.mtu());
+            return Err(NetError::MalformedPacket);
+        }
+        let mut frame = [0u8; ethernet::HEADER_LEN + 1500];
+        let link_len = match (link_dst, self.mac) {
+            (Some(dst), Some(src)) => {
+                EthernetHeader { dst, src, ethertype: ethernet::ETHERTYPE_IPV6 }.emit(&mut frame);
+                ethernet::HEADER_LEN
+            }
+            (None, Some(_)) => return Err(NetError::Unresolved),
+            _ => 0,
+        };
+        header.emit(&mut frame[link_len..], payload.len());
+        frame[link_len + ipv6::HEADER_LEN..link_len + total_len].copy_from_slice(payload);
+        let len = match link_len {
+            0 => total_len,
+            _ => (link_len + total_len).max(ethernet::MIN_FRAME_LEN),
+        };
+        self.device.send(&frame[..len])
+    }
+
+    /// Handle neighbour discovery and echo requests for this interface.
+    /// Returns `false` for any other IPv6 packet.
+    fn ipv6_input(&mut self, packet: &[u8]) -> bool {
+        let Some((header, payload)) = Ipv6Header::parse(packet) else {
+            return false;
+        };
+        let for_us = self.owns_ipv6(header.dst)
+            || header.dst == Ipv6Addr::ALL_NODES
+            || self.ipv6_addrs().any(|own| own.solicited_node() == header.dst);
+        if !for_us || header.next_header != ipv6::NEXT_HEADER_ICMPV6 {
+            return false;
+        }
+        let Some(message) = Icmpv6::parse(&header, payload) else {
+            return false;
+        };
+        let result = match message {
+            Icmpv6::NeighborSolicit { target, source_mac } => {
+                if !self.owns_ipv6(target) {
+                    return true;
+                }
+                let dst = match source_mac {
+                    Some(mac) => {
+                        self.neighbors.learn(header.src, mac, self.now_ms, true);
+                        header.src
+                    }
+                    // Duplicate address detection: tell everyone
+                    None if header.src.is_unspecified() => Ipv6Addr::ALL_NODES,
+                    None => header.src,
+                };
+                let advert =
+                    Icmpv6::NeighborAdvert { target, solicited: !header.src.is_unspecified(), target_mac: self.mac };
+                self.send_icmpv6(target, dst, &advert)
+            }
+            Icmpv6::NeighborAdvert { target, target_mac, .. } => {
+                if let Some(mac) = target_mac {
+                    self.neighbors.learn(target, mac, self.now_ms, false);
+                }
+                Ok(())
+            }
+            Icmpv6::EchoRequest { ident, seq, data } => {
+                let src = if self.owns_ipv6(header.dst) { Some(header.dst) } else { self.ipv6_source(header.src) };
+                let Some(src) = src.filter(|_| 8 + data.len() <= MAX_IPV6_PAYLOAD) else {
+                    return true;
+                };
+                self.send_icmpv6(src, header.src, &Icmpv6::EchoReply { ident, seq, data })
+            }
+            Icmpv6::EchoReply { .. } => return false,
+        };
+        if result.is_err() {
+            sios_log::debug!("ICMPv6: reply to {:?} not sent", header.src);
+        }
+        true
     }
 
     /// Receive a raw frame (delegates to device)
@@ -238,15 +900,99 @@ impl<D: NetworkDevice> NetInterface<D> {
     }
 }
 
+/// (task, destination) pairs `NetworkStack` accounts egress for.
+pub const EGRESS_SLOTS: usize = 16;
+/// Egress quotas `NetworkStack` can hold.
+pub const EGRESS_QUOTAS: usize = 8;
+/// Sockets `NetworkStack` can hold.
+pub const SOCKETS: usize = 4;
+/// Largest IPv4 datagram `NetworkStack` can reassemble from fragments.
+pub const REASSEMBLY_BUFFER: usize = 4096;
+
+/// Largest IPv4 payload (TCP segment or UDP datagram) the stack builds.
+const MAX_IP_PAYLOAD: usize = 1500 - 20;
+/// Largest IPv6 payload the interface builds itself.
+const MAX_IPV6_PAYLOAD: usize = 1500 - ipv6::HEADER_LEN;
+
+/// Egress firewall hook: called with the sending task, the destination
+/// and the IPv4 packet length; `false` drops the packet.
+pub type FirewallHook = fn(TaskId, Ipv4Addr, usize) -> bool;
+
 /// Very small network stack wrapper which owns a single interface.
-/// For real use you would expand this to support routing, ARP, DHCP, etc.
+/// For real use you would expand this to support routing, IPv6, etc.
+///
+/// Outgoing packets pass the firewall hook, then the egress quotas
+/// (see `egress`), and are accounted once the device has sent them.
+///
+/// The stack also holds up to `SOCKETS` UDP and TCP sockets (see
+/// `socket`), fed by `poll()` and drained by `dispatch()`, and optionally
+/// a DHCP client (`enable_dhcp()`) that keeps the interface's address.
 pub struct NetworkStack<D: NetworkDevice> {
     iface: NetInterface<D>,
+    firewall: Option<FirewallHook>,
+    egress: EgressAccounting<EGRESS_SLOTS, EGRESS_QUOTAS>,
+    sockets: [Option<Socket>; SOCKETS],
+    /// Time of the last `dispatch()`, for segments `poll()` delivers
+    now_ms: u64,
+    next_port: u16,
+    isn_source: fn() -> u32,
+    isn_counter: u32,
+    dhcp: Option<DhcpClient>,
+    reassembler: Reassembler,
+    echo_reply: bool,
 }
 
 impl<D: NetworkDevice> NetworkStack<D> {
     pub fn new(iface: NetInterface<D>) -> Self {
-        Self { iface }
+        Self {
+            iface,
+            firewall: None,
+            egress: EgressAccounting::new(),
+            sockets: core::array::from_fn(|_| None),
+            now_ms: 0,
+            next_port: *socket::EPHEMERAL_PORTS.start(),
+            isn_source: || 0,
+            isn_counter: 0,
+            dhcp: None,
+            reassembler: Reassembler::new(),
+            echo_reply: true,
+        }
+    }
+
+    /// Install the source of TCP initial sequence numbers. Wire it to the
+    /// hardware RNG: without one the numbers only follow the clock, and an
+    /// off-path attacker who can guess them can inject into connections
+    /// (RFC 6528). DHCP transaction ids and the start of the IPv4
+    /// identification counter come from the same source.
+    pub fn set_isn_source(&mut self, source: fn() -> u32) {
+        self.isn_source = source;
+        self.iface.set_ident_seed(source() as u16);
+    }
+
+    /// Answer ICMP echo requests (ping) to the interface's address; on by
+    /// default (see `icmp`).
+    pub fn set_echo_reply(&mut self, enabled: bool) {
+        self.echo_reply = enabled;
+    }
+
+    /// IPv4 reassembly, to set its limit or read its drop count.
+    pub fn reassembler(&mut self) -> &mut Reassembler {
+        &mut self.reassembler
+    }
+
+    /// Install (or remove) the egress firewall hook.
+    pub fn set_firewall(&mut self, hook: Option<FirewallHook>) {
+        self.firewall = hook;
+    }
+
+    /// Egress usage and quotas.
+    pub fn egress(&self) -> &EgressAccounting<EGRESS_SLOTS, EGRESS_QUOTAS> {
+        &self.egress
+    }
+
+    /// Egress accounting, to set quotas or start a new period.
+    pub fn egress_mut(&mut self) -> &mut EgressAccounting<EGRESS_SLOTS, EGRESS_QUOTAS> {
+        &mut self.egress
     }
 
     /// Configure static IPv4 address
@@ -254,15 +1000,99 @@ impl<D: NetworkDevice> NetworkStack<D> {
         self.iface.configure_ipv4(ip, netmask, gateway);
     }
 
+    /// Configure a static IPv6 address (see `NetInterface::configure_ipv6`).
+    pub fn set_static_ipv6(&mut self, addr: Ipv6Addr, prefix_len: u8, gateway: Option<Ipv6Addr>) {
+        self.iface.configure_ipv6(addr, prefix_len, gateway);
+    }
+
+    /// Get the interface's address from DHCP instead (see `dhcp`). The
+    /// static configuration is dropped on the next `dispatch()`, which
+    /// sends the first DISCOVER; from then on the address, netmask and
+    /// gateway are those of the current lease, and none without one.
+    /// DHCP replies are taken before any socket bound to port 68.
+    ///
+    /// # Returns
+    /// - `Ok(())` once enabled (again a no-op if it already was)
+    /// - `Err(NetError::Unsupported)` if the interface is not Ethernet
+    pub fn enable_dhcp(&mut self) -> NetResult<()> {
+        let mac = self.iface.mac.ok_or(NetError::Unsupported)?;
+        if self.dhcp.is_none() {
+            self.dhcp = Some(DhcpClient::new(mac, self.isn_source));
+        }
+        Ok(())
+    }
+
+    /// The DHCP client, if enabled: its state and lease.
+    pub fn dhcp(&mut self) -> Option<&mut DhcpClient> {
+        self.dhcp.as_mut()
+    }
+
+    /// Make the interface configuration follow the DHCP lease.
+    fn apply_lease(&mut self) {
+        let Some(client) = &self.dhcp else {
+            return;
+        };
+        let lease = client.lease().copied();
+        let ip = lease.map(|l| l.address);
+        if self.iface.ip != ip {
+            // Neighbours learnt under the old address may be on another LAN
+            self.iface.arp.flush();
+        }
+        self.iface.ip = ip;
+        self.iface.netmask = lease.map(|l| l.netmask);
+        self.iface.gateway = lease.and_then(|l| l.gateway);
+    }
+
     /// Send a small UDP-like payload to `dest`. This uses the `send_ipv4_payload`
-    /// helper and sets "protocol" to UDP in the IPv4 header.
+    /// helper and sets "protocol" to UDP in the IPv4 header. The traffic is
+    /// accounted to `egress::SYSTEM_TASK`.
     pub fn send_udp_like(&mut self, dest: Ipv4Addr, payload: &[u8]) -> NetResult<()> {
-        self.iface.send_ipv4_payload(dest, payload)
+        self.send_udp_like_from(egress::SYSTEM_TASK, dest, payload)
+    }
+
+    /// `send_udp_like` on behalf of `task`, subject to the firewall hook
+    /// and the task's egress quotas.
+    pub fn send_udp_like_from(&mut self, task: TaskId, dest: Ipv4Addr, payload: &[u8]) -> NetResult<()> {
+        self.transmit(task, dest, socket::PROTO_UDP, payload)
+    }
+
+    /// Send an IPv4 packet for `task` through the firewall hook and the
+    /// egress quotas.
+    fn transmit(&mut self, task: TaskId, dest: Ipv4Addr, protocol: u8, payload: &[u8]) -> NetResult<()> {
+        let src = self.iface.ip.ok_or(NetError::Unsupported)?;
+        self.transmit_from(task, src, dest, protocol, payload)
+    }
+
+    /// `transmit` with source address `src`.
+    fn transmit_from(
+        &mut self,
+        task: TaskId,
+        src: Ipv4Addr,
+        dest: Ipv4Addr,
+        protocol: u8,
+        payload: &[u8],
+    ) -> NetResult<()> {
+        let len = 20 + payload.len();
+        if let Some(allow) = self.firewall {
+            if !allow(task, dest, len) {
+                sios_log::debug!("firewall dropped {} bytes from task {} to {:?}", len, task, dest);
+                return Err(NetError::Denied);
+            }
+        }
+        self.egress.check(task, dest, len)?;
+        self.iface.send_ipv4_from(src, dest, protocol, payload)?;
+        self.egress.record(task, dest, len);
+        Ok(())
     }
 
     /// Poll for incoming frames and call the provided handler for each
     /// successfully received frame. The handler may return `false` to stop
     /// further processing.
+    ///
+    /// UDP datagrams and TCP segments addressed to the interface go to the
+    /// socket bound to their destination port instead, DHCP replies to the
+    /// DHCP client, pings and ARP are answered; the handler only sees the
+    /// rest.
     pub fn poll<F>(&mut self, mut handler: F) -> NetResult<()>
     where
         F: FnMut(&[u8]) -> bool,
@@ -270,6 +1100,9 @@ impl<D: NetworkDevice> NetworkStack<D> {
         let mut buf: [u8; 2048] = [0u8; 2048];
         match self.iface.recv_frame(&mut buf) {
             Ok(len) => {
+                if self.demux(&buf[..len]) {
+                    return Ok(());
+                }
                 let cont = handler(&buf[..len]);
                 if cont {
                     Ok(())
@@ -281,6 +1114,307 @@ impl<D: NetworkDevice> NetworkStack<D> {
             Err(e) => Err(e),
         }
     }
+
+    /// Hand a received frame to the socket it is addressed to, to the DHCP
+    /// client, or to the interface if it is ARP. Returns `false` if none
+    /// takes it.
+    fn demux(&mut self, frame: &[u8]) -> bool {
+        let packet = match self.iface.link_input(frame) {
+            LinkInput::Ipv4(packet) => packet,
+            LinkInput::Ipv6(packet) => return self.iface.ipv6_input(packet),
+            LinkInput::Consumed => return true,
+            LinkInput::Other => return false,
+        };
+        let Some((ip, payload)) = socket::parse_ipv4(packet) else {
+            return false;
+        };
+        if ip.protocol == socket::PROTO_UDP && self.dhcp.is_some() {
+            // Before the first ACK replies come broadcast or to the offered
+            // address, which is not ours yet
+            if let Some((dhcp::SERVER_PORT, dhcp::CLIENT_PORT, data)) = socket::udp::parse(ip.src, ip.dst, payload) {
+                if let (Some(client), Some(msg)) = (&mut self.dhcp, dhcp::DhcpMessage::parse(data)) {
+                    client.process(self.now_ms, &msg);
+                    self.apply_lease();
+                }
+                return true;
+            }
+        }
+        if Some(ip.dst) != self.iface.ip {
+            return false;
+        }
+        if !ip.is_fragment() {
+            return self.deliver_ipv4(&ip, payload);
+        }
+        let Some(len) = self.reassembler.process(&ip, payload, self.now_ms) else {
+            return true;
+        };
+        let mut datagram = [0u8; REASSEMBLY_BUFFER];
+        datagram[..len].copy_from_slice(self.reassembler.datagram(len));
+        self.deliver_ipv4(&ip, &datagram[..len]);
+        true
+    }
+
+    /// Hand a whole IPv4 payload addressed to the interface to the socket
+    /// it is for, or answer it if it is a ping. Returns `false` if no
+    /// socket takes it.
+    fn deliver_ipv4(&mut self, ip: &socket::Ipv4Header, payload: &[u8]) -> bool {
+        match ip.protocol {
+            icmp::PROTO_ICMP => {
+                let Some(Icmpv4::EchoRequest { ident, seq, data }) = Icmpv4::parse(payload) else {
+                    return false;
+                };
+                if !self.echo_reply || 8 + data.len() > MAX_IP_PAYLOAD {
+                    return false;
+                }
+                let mut reply = [0u8; MAX_IP_PAYLOAD];
+                let len = Icmpv4::EchoReply { ident, seq, data }.emit(&mut reply);
+                if let Err(e) = self.transmit(egress::SYSTEM_TASK, ip.src, icmp::PROTO_ICMP, &reply[..len]) {
+                    sios_log::debug!("ICMP: echo reply to {:?} not sent: {}", ip.src, e.code());
+                }
+                true
+            }
+            socket::PROTO_UDP => {
+                let Some((src_port, dst_port, data)) = socket::udp::parse(ip.src, ip.dst, payload) else {
+                    // Ours, but corrupt
+                    return true;
+                };
+                let Some(udp) = self.sockets.iter_mut().find_map(|s| match s {
+                    Some(Socket::Udp(u)) if u.local_port() == dst_port => Some(u),
+                    _ => None,
+                }) else {
+                    return false;
+                };
+                udp.deliver(Endpoint::new(ip.src, src_port), data);
+                true
+            }
+            socket::PROTO_TCP => {
+                let Some((seg, data)) = socket::tcp::TcpRepr::parse(ip.src, ip.dst, payload) else {
+                    return true;
+                };
+                let remote = Endpoint::new(ip.src, seg.src_port);
+                let tcp_sockets = || {
+                    self.sockets.iter().map(|s| match s {
+                        Some(Socket::Tcp(t)) => Some(t),
+                        _ => None,
+                    })
+                };
+                // An established connection wins over a listener on the port
+                let Some(index) = tcp_sockets()
+                    .position(|t| t.is_some_and(|t| t.matches(seg.dst_port, remote)))
+                    .or_else(|| tcp_sockets().position(|t| t.is_some_and(|t| t.listens_on(seg.dst_port))))
+                else {
+                    return false;
+                };
+                if let Some(Socket::Tcp(tcp)) = &mut self.sockets[index] {
+                    tcp.process(self.now_ms, ip.src, &seg, data);
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Send whatever the sockets have queued and run the TCP and DHCP
+    /// timers. `now_ms` is a monotonic millisecond clock.
+    ///
+    /// # Returns
+    /// - `Ok(())` once every socket is drained
+    /// - `Err(e)` with the first send that failed (firewall, quota, device).
+    ///   That UDP datagram is dropped; TCP and DHCP resend on their timers
+    ///   like after any lost packet. The other sockets are still served.
+    pub fn dispatch(&mut self, now_ms: u64) -> NetResult<()> {
+        self.now_ms = now_ms;
+        self.iface.set_time(now_ms);
+        let mut result = self.dispatch_dhcp();
+        for index in 0..SOCKETS {
+            if let Err(e) = self.dispatch_socket(index) {
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+
+    fn dispatch_dhcp(&mut self) -> NetResult<()> {
+        let Some(client) = &mut self.dhcp else {
+            return Ok(());
+        };
+        let mut packet = [0u8; socket::udp::HEADER_LEN + dhcp::MIN_MESSAGE_LEN];
+        let sent = client.dispatch(self.now_ms, &mut packet[socket::udp::HEADER_LEN..]);
+        self.apply_lease();
+        let Some(out) = sent else {
+            return Ok(());
+        };
+        let len = socket::udp::emit_header(
+            Endpoint::new(out.src, dhcp::CLIENT_PORT),
+            Endpoint::new(out.dst, dhcp::SERVER_PORT),
+            &mut packet,
+            out.len,
+        );
+        match self.transmit_from(egress::SYSTEM_TASK, out.src, out.dst, socket::PROTO_UDP, &packet[..len]) {
+            Err(NetError::Unresolved) => {
+                // Renewing goes to the server's unicast address: ask again
+                // once ARP has had time to answer instead of after a minute
+                if let Some(client) = &mut self.dhcp {
+                    client.defer(self.now_ms + arp::REQUEST_INTERVAL_MS);
+                }
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn dispatch_socket(&mut self, index: usize) -> NetResult<()> {
+        if self.sockets[index].is_none() {
+            return Ok(());
+        }
+        let src = self.iface.ip.ok_or(NetError::Unsupported)?;
+        let mut packet = [0u8; MAX_IP_PAYLOAD];
+        let mut result = Ok(());
+        loop {
+            // Leave the data queued until the next hop is resolved
+            let next_dest = match &self.sockets[index] {
+                Some(Socket::Udp(udp)) => udp.next_dest(),
+                Some(Socket::Tcp(tcp)) => tcp.remote().filter(|_| tcp.is_active()),
+                None => None,
+            };
+            if let Some(dest) = next_dest {
+                if let Err(NetError::Unresolved) = self.iface.resolve(dest.addr) {
+                    break;
+                }
+            }
+            let (task, dest, protocol, len) = match &mut self.sockets[index] {
+                Some(Socket::Udp(udp)) => match udp.dispatch(src, &mut packet) {
+                    Some((dest, len)) => (udp.task(), dest, socket::PROTO_UDP, len),
+                    None => break,
+                },
+                Some(Socket::Tcp(tcp)) => match tcp.dispatch(self.now_ms, &mut packet) {
+                    Some((repr, dest, payload_len)) => {
+                        (tcp.task(), dest, socket::PROTO_TCP, repr.emit(src, dest.addr, &mut packet, payload_len))
+                    }
+                    None => break,
+                },
+                None => break,
+            };
+            if let Err(e) = self.transmit(task, dest.addr, protocol, &packet[..len]) {
+                result = result.and(Err(e));
+            }
+        }
+        result
+    }
+
+    /// Bind a UDP socket for `task` to `port`, or to a free ephemeral port
+    /// if `port` is 0.
+    ///
+    /// # Returns
+    /// - `Ok(handle)` of the new socket
+    /// - `Err(NetError::AddrInUse)` if another UDP socket has the port
+    /// - `Err(NetError::Exhausted)` if the socket table is full
+    pub fn udp_bind(&mut self, task: TaskId, port: u16) -> NetResult<SocketHandle> {
+        let port = self.local_port(socket::PROTO_UDP, port)?;
+        self.add(Socket::Udp(UdpSocket::new(task, port)))
+    }
+
+    /// Open a TCP connection for `task` to `remote`, from an ephemeral
+    /// port. The SYN goes out on the next `dispatch()`; the socket turns
+    /// writable once the connection is established.
+    pub fn tcp_connect(&mut self, task: TaskId, remote: Endpoint) -> NetResult<SocketHandle> {
+        let port = self.local_port(socket::PROTO_TCP, 0)?;
+        let (isn, mss) = (self.next_isn(), self.mss());
+        self.add(Socket::Tcp(TcpSocket::connect(task, port, remote, isn, mss)))
+    }
+
+    /// Wait for a TCP connection on `port`. The socket becomes that
+    /// connection; listen again for the next one.
+    pub fn tcp_listen(&mut self, task: TaskId, port: u16) -> NetResult<SocketHandle> {
+        if port == 0 {
+            return Err(NetError::InvalidState);
+        }
+        let port = self.local_port(socket::PROTO_TCP, port)?;
+        let (isn, mss) = (self.next_isn(), self.mss());
+        self.add(Socket::Tcp(TcpSocket::listen(task, port, isn, mss)))
+    }
+
+    /// The UDP socket behind `handle`.
+    pub fn udp(&mut self, handle: SocketHandle) -> NetResult<&mut UdpSocket> {
+        match self.sockets.get_mut(handle.0) {
+            Some(Some(Socket::Udp(udp))) => Ok(udp),
+            _ => Err(NetError::InvalidState),
+        }
+    }
+
+    /// The TCP socket behind `handle`.
+    pub fn tcp(&mut self, handle: SocketHandle) -> NetResult<&mut TcpSocket> {
+        match self.sockets.get_mut(handle.0) {
+            Some(Some(Socket::Tcp(tcp))) => Ok(tcp),
+            _ => Err(NetError::InvalidState),
+        }
+    }
+
+    /// Readiness of the socket behind `handle`; all clear for a released
+    /// socket.
+    pub fn events(&self, handle: SocketHandle) -> Events {
+        match self.sockets.get(handle.0) {
+            Some(Some(s)) => s.events(),
+            _ => Events::default(),
+        }
+    }
+
+    /// Free the socket behind `handle`. A TCP connection still in use is
+    /// reset first.
+    pub fn release(&mut self, handle: SocketHandle) {
+        if let Ok(tcp) = self.tcp(handle) {
+            if tcp.is_active() {
+                tcp.abort();
+                // Best effort: the peer times out if the reset is lost
+                let _ = self.dispatch_socket(handle.0);
+            }
+        }
+        if let Some(slot) = self.sockets.get_mut(handle.0) {
+            *slot = None;
+        }
+    }
+
+    fn add(&mut self, socket: Socket) -> NetResult<SocketHandle> {
+        let index = self.sockets.iter().position(Option::is_none).ok_or(NetError::Exhausted)?;
+        self.sockets[index] = Some(socket);
+        Ok(SocketHandle(index))
+    }
+
+    /// `port` if no socket of `protocol` has it, or a free ephemeral
+    /// port for 0.
+    fn local_port(&mut self, protocol: u8, port: u16) -> NetResult<u16> {
+        let in_use = |sockets: &[Option<Socket>], port: u16| {
+            sockets.iter().flatten().any(|s| match s {
+                Socket::Udp(u) => protocol == socket::PROTO_UDP && u.local_port() == port,
+                Socket::Tcp(t) => protocol == socket::PROTO_TCP && t.local_port() == port,
+            })
+        };
+        if port != 0 {
+            return if in_use(&self.sockets, port) { Err(NetError::AddrInUse) } else { Ok(port) };
+        }
+        // At most SOCKETS ports are taken, so this ends quickly
+        loop {
+            let port = self.next_port;
+            self.next_port =
+                if port == *socket::EPHEMERAL_PORTS.end() { *socket::EPHEMERAL_PORTS.start() } else { port + 1 };
+            if !in_use(&self.sockets, port) {
+                return Ok(port);
+            }
+        }
+    }
+
+    /// Initial sequence number for a new connection: the installed source,
+    /// plus a 4 µs clock as in RFC 9293 §3.4.1 so numbers still move
+    /// without one.
+    fn next_isn(&mut self) -> u32 {
+        self.isn_counter = self.isn_counter.wrapping_add(0x0001_0000);
+        (self.isn_source)().wrapping_add((self.now_ms as u32).wrapping_mul(250)).wrapping_add(self.isn_counter)
+    }
+
+    /// Segment size that fits the link and the stack's packet buffer.
+    fn mss(&self) -> usize {
+        self.iface.device.mtu().min(1500).saturating_sub(40)
+    }
 }
 
 // ----------------------
@@ -289,6 +1423,7 @@ impl<D: NetworkDevice> NetworkStack<D> {
 #[cfg(all(test, feature = "std"))]
 mod tests {
     use super::*;
+    use std::collections::VecDeque;
     use std::sync::{Arc, Mutex};
 
     /// A tiny in-memory device useful for tests.
@@ -331,6 +1466,361 @@ mod tests {
         }
     }
 
+    /// One direction of a point-to-point link: frames queue until read.
+    type Wire = Arc<Mutex<VecDeque<Vec<u8>>>>;
+
+    /// Device on one end of a pair of `Wire`s.
+    struct WireDevice {
+        tx: Wire,
+        rx: Wire,
+        mtu: usize,
+    }
+
+    impl NetworkDevice for WireDevice {
+        fn send(&mut self, frame: &[u8]) -> NetResult<()> {
+            self.tx.lock().unwrap().push_back(frame.to_vec());
+            Ok(())
+        }
+
+        fn recv(&mut self, buffer: &mut [u8]) -> NetResult<usize> {
+            let frame = self.rx.lock().unwrap().pop_front().ok_or(NetError::Timeout)?;
+            buffer[..frame.len()].copy_from_slice(&frame);
+            Ok(frame.len())
+        }
+
+        fn mtu(&self) -> usize {
+            self.mtu
+        }
+    }
+
+    const A: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const B: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+    fn linked_stacks() -> (NetworkStack<WireDevice>, NetworkStack<WireDevice>) {
+        let (ab, ba) = (Wire::default(), Wire::default());
+        let stack = |ip, tx, rx| {
+            let mut iface = NetInterface::new(WireDevice { tx, rx, mtu: 1500 });
+            iface.configure_ipv4(ip, Ipv4Addr::new(255, 255, 255, 0), Ipv4Addr::new(10, 0, 0, 254));
+            NetworkStack::new(iface)
+        };
+        (stack(A, ab.clone(), ba.clone()), stack(B, ba, ab))
+    }
+
+    /// Run both stacks until neither has anything left to send.
+    fn run(now: u64, a: &mut NetworkStack<WireDevice>, b: &mut NetworkStack<WireDevice>) {
+        for _ in 0..32 {
+            a.dispatch(now).unwrap();
+            b.dispatch(now).unwrap();
+            let mut received = false;
+            while a.poll(|_| panic!("frame not demultiplexed")).is_ok() {
+                received = true;
+            }
+            while b.poll(|_| panic!("frame not demultiplexed")).is_ok() {
+                received = true;
+            }
+            if !received {
+                return;
+            }
+        }
+        panic!("stacks never went quiet");
+    }
+
+    const MAC_A: MacAddr = MacAddr([0x02, 0, 0, 0, 0, 0x0A]);
+    const MAC_B: MacAddr = MacAddr([0x02, 0, 0, 0, 0, 0x0B]);
+
+    fn ethernet_stacks() -> (NetworkStack<WireDevice>, NetworkStack<WireDevice>) {
+        let (ab, ba) = (Wire::default(), Wire::default());
+        let stack = |ip, mac, tx, rx| {
+            let mut iface = NetInterface::with_ethernet(WireDevice { tx, rx, mtu: 1500 }, mac);
+            iface.configure_ipv4(ip, Ipv4Addr::new(255, 255, 255, 0), Ipv4Addr::new(10, 0, 0, 254));
+            NetworkStack::new(iface)
+        };
+        (stack(A, MAC_A, ab.clone(), ba.clone()), stack(B, MAC_B, ba, ab))
+    }
+
+    #[test]
+    fn test_ethernet_resolves_next_hop_before_sending() {
+        let (mut a, mut b) = ethernet_stacks();
+        let client = a.udp_bind(3, 0).unwrap();
+        let server = b.udp_bind(4, 7).unwrap();
+        a.udp(client).unwrap().send_to(b"echo", Endpoint::new(B, 7)).unwrap();
+
+        // The first dispatch only asks who B is; the datagram waits
+        a.dispatch(0).unwrap();
+        let request = a.iface.device.tx.lock().unwrap().front().cloned().unwrap();
+        assert_eq!(request.len(), ethernet::MIN_FRAME_LEN);
+        assert_eq!(&request[..6], &MacAddr::BROADCAST.0);
+        assert_eq!(u16::from_be_bytes([request[12], request[13]]), ethernet::ETHERTYPE_ARP);
+
+        run(0, &mut a, &mut b);
+        assert_eq!(a.iface.arp_cache().lookup(B, 0), Some(MAC_B));
+        // B learnt A from the request it answered
+        assert_eq!(b.iface.arp_cache().lookup(A, 0), Some(MAC_A));
+        let mut buf = [0u8; 8];
+        let (len, from) = b.udp(server).unwrap().recv_from(&mut buf).unwrap();
+        assert_eq!((&buf[..len], from.addr), (&b"echo"[..], A));
+
+        // Off the subnet the gateway is resolved, not the destination
+        assert_eq!(a.iface.next_hop(Ipv4Addr::new(8, 8, 8, 8)), Ok(Ipv4Addr::new(10, 0, 0, 254)));
+        assert_eq!(a.send_udp_like(Ipv4Addr::new(8, 8, 8, 8), b"x"), Err(NetError::Unresolved));
+        let request = a.iface.device.tx.lock().unwrap().pop_back().unwrap();
+        assert_eq!(&request[ethernet::HEADER_LEN + 24..ethernet::HEADER_LEN + 28], &[10, 0, 0, 254]);
+
+        // Frames for another MAC are not ours to demultiplex
+        let mut foreign = request.clone();
+        foreign[..6].copy_from_slice(&[0x02, 0, 0, 0, 0, 0x0C]);
+        b.iface.device.rx.lock().unwrap().push_back(foreign);
+        let mut raw = 0;
+        b.poll(|_| {
+            raw += 1;
+            true
+        })
+        .unwrap();
+        assert_eq!(raw, 1);
+    }
+
+    #[test]
+    fn test_ipv6_neighbor_discovery_and_echo() {
+        let (mut a, mut b) = ethernet_stacks();
+        let (a_ll, b_ll) = (a.iface.link_local.unwrap(), b.iface.link_local.unwrap());
+        let mut echo = [0u8; 12];
+        let len = Icmpv6::EchoRequest { ident: 7, seq: 1, data: b"ping" }.emit(a_ll, b_ll, &mut echo);
+        assert_eq!(a.iface.send_ipv6(b_ll, ipv6::NEXT_HEADER_ICMPV6, &echo[..len]), Err(NetError::Unresolved));
+
+        // The solicitation reaches B through its solicited-node group; B
+        // learns A from it and answers
+        run(0, &mut a, &mut b);
+        assert_eq!(b.iface.neighbor_cache().lookup(a_ll, 0), Some(MAC_A));
+        assert_eq!(a.iface.neighbor_cache().lookup(b_ll, 0), Some(MAC_B));
+
+        a.iface.send_ipv6(b_ll, ipv6::NEXT_HEADER_ICMPV6, &echo[..len]).unwrap();
+        b.poll(|_| panic!("echo request not answered")).unwrap();
+        let mut replies = 0;
+        a.poll(|frame| {
+            let (_, packet) = EthernetHeader::parse(frame).unwrap();
+            let (header, body) = Ipv6Header::parse(packet).unwrap();
+            assert_eq!(header.src, b_ll);
+            assert_eq!(Icmpv6::parse(&header, body), Some(Icmpv6::EchoReply { ident: 7, seq: 1, data: b"ping" }));
+            replies += 1;
+            true
+        })
+        .unwrap();
+        assert_eq!(replies, 1);
+
+        // A further address: used off the link, which needs a router
+        let ula = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+        a.set_static_ipv6(ula, 64, None);
+        assert_eq!(a.iface.ipv6_source(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2)), Some(ula));
+        assert_eq!(a.iface.ipv6_source(b_ll), Some(a_ll));
+        assert_eq!(a.iface.next_hop_ipv6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1)), Err(NetError::NoRoute));
+    }
+
+    /// Answer the DHCP message A broadcast with `msg_type`, as B.
+    fn dhcp_server(
+        b: &mut NetworkStack<WireDevice>,
+        server: SocketHandle,
+        msg_type: dhcp::MessageType,
+    ) -> dhcp::DhcpMessage {
+        let mut request = None;
+        b.poll(|frame| {
+            let (_, packet) = EthernetHeader::parse(frame).unwrap();
+            let (ip, payload) = socket::parse_ipv4(packet).unwrap();
+            assert_eq!((ip.src, ip.dst), (Ipv4Addr::new(0, 0, 0, 0), Ipv4Addr::new(255, 255, 255, 255)));
+            let (_, _, data) = socket::udp::parse(ip.src, ip.dst, payload).unwrap();
+            request = dhcp::DhcpMessage::parse(data);
+            true
+        })
+        .unwrap();
+        let request = request.unwrap();
+        let reply = dhcp::DhcpMessage {
+            op: 2,
+            msg_type,
+            yiaddr: Ipv4Addr::new(10, 0, 0, 50),
+            server_id: Some(B),
+            requested_ip: None,
+            subnet_mask: Some(Ipv4Addr::new(255, 255, 255, 0)),
+            router: Some(B),
+            lease_secs: Some(600),
+            ..request
+        };
+        let mut buf = [0u8; dhcp::MIN_MESSAGE_LEN];
+        let len = reply.emit(&mut buf);
+        let to = Endpoint::new(Ipv4Addr::new(255, 255, 255, 255), dhcp::CLIENT_PORT);
+        b.udp(server).unwrap().send_to(&buf[..len], to).unwrap();
+        b.dispatch(0).unwrap();
+        request
+    }
+
+    #[test]
+    fn test_dhcp_configures_the_interface() {
+        let (mut a, mut b) = ethernet_stacks();
+        let server = b.udp_bind(egress::SYSTEM_TASK, dhcp::SERVER_PORT).unwrap();
+        a.enable_dhcp().unwrap();
+
+        // DISCOVER and REQUEST go out from 0.0.0.0; the static address is gone
+        a.dispatch(0).unwrap();
+        assert_eq!(a.iface.ip, None);
+        let discover = dhcp_server(&mut b, server, dhcp::MessageType::Offer);
+        assert_eq!(discover.msg_type, dhcp::MessageType::Discover);
+        a.poll(|_| panic!("DHCP offer not taken")).unwrap();
+        assert_eq!(a.dhcp().unwrap().state(), dhcp::DhcpState::Requesting);
+
+        a.dispatch(0).unwrap();
+        let request = dhcp_server(&mut b, server, dhcp::MessageType::Ack);
+        assert_eq!(request.requested_ip, Some(Ipv4Addr::new(10, 0, 0, 50)));
+        a.poll(|_| panic!("DHCP ack not taken")).unwrap();
+        assert_eq!(a.dhcp().unwrap().state(), dhcp::DhcpState::Bound);
+        assert_eq!(a.iface.ip, Some(Ipv4Addr::new(10, 0, 0, 50)));
+        assert_eq!(a.iface.gateway, Some(B));
+        // Accounted like any system traffic
+        assert_eq!(a.egress().task_total(egress::SYSTEM_TASK), 2 * (20 + 8 + dhcp::MIN_MESSAGE_LEN as u64));
+
+        // Never renewed: the address goes with the lease
+        a.dispatch(600_000).unwrap();
+        assert_eq!(a.iface.ip, None);
+        assert_eq!(a.dhcp().unwrap().state(), dhcp::DhcpState::Selecting);
+    }
+
+    #[test]
+    fn test_udp_sockets_demultiplex_by_port() {
+        let (mut a, mut b) = linked_stacks();
+        let client = a.udp_bind(3, 0).unwrap();
+        let server = b.udp_bind(4, 5683).unwrap();
+        let other = b.udp_bind(4, 5684).unwrap();
+        assert_eq!(b.udp_bind(4, 5683), Err(NetError::AddrInUse));
+
+        a.udp(client).unwrap().send_to(b"GET /temp", Endpoint::new(B, 5683)).unwrap();
+        run(0, &mut a, &mut b);
+        assert!(b.events(server).readable);
+        assert!(!b.events(other).readable);
+
+        let mut buf = [0u8; 32];
+        let (len, from) = b.udp(server).unwrap().recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"GET /temp");
+        assert_eq!(from.addr, A);
+        b.udp(server).unwrap().send_to(b"21.5", from).unwrap();
+        run(0, &mut a, &mut b);
+        let (len, from) = a.udp(client).unwrap().recv_from(&mut buf).unwrap();
+        assert_eq!((&buf[..len], from), (&b"21.5"[..], Endpoint::new(B, 5683)));
+        assert_eq!(a.udp(client).unwrap().recv_from(&mut buf), Err(NetError::Timeout));
+
+        // Accounted to the owning task, headers included
+        assert_eq!(a.egress().task_total(3), 20 + 8 + 9);
+        // Datagrams to an unbound port reach the raw handler
+        a.udp(client).unwrap().send_to(b"x", Endpoint::new(B, 9)).unwrap();
+        a.dispatch(0).unwrap();
+        let mut raw = 0;
+        b.poll(|_| {
+            raw += 1;
+            true
+        })
+        .unwrap();
+        assert_eq!(raw, 1);
+    }
+
+    #[test]
+    fn test_ping_is_answered() {
+        let (mut a, mut b) = linked_stacks();
+        let mut request = [0u8; 32];
+        let len = Icmpv4::EchoRequest { ident: 0x1234, seq: 1, data: b"SecureIoT" }.emit(&mut request);
+        b.iface.send_ipv4(A, icmp::PROTO_ICMP, &request[..len]).unwrap();
+        a.poll(|_| panic!("ping not answered")).unwrap();
+        assert_eq!(a.egress().task_total(egress::SYSTEM_TASK), 20 + len as u64);
+
+        let mut replies = 0;
+        b.poll(|frame| {
+            let (ip, body) = socket::parse_ipv4(frame).unwrap();
+            assert_eq!((ip.src, ip.protocol), (A, icmp::PROTO_ICMP));
+            assert_eq!(Icmpv4::parse(body), Some(Icmpv4::EchoReply { ident: 0x1234, seq: 1, data: b"SecureIoT" }));
+            replies += 1;
+            true
+        })
+        .unwrap();
+        assert_eq!(replies, 1);
+
+        // Turned off, or corrupted: left to the handler
+        let mut raw = 0;
+        a.set_echo_reply(false);
+        b.iface.send_ipv4(A, icmp::PROTO_ICMP, &request[..len]).unwrap();
+        a.poll(|_| {
+            raw += 1;
+            true
+        })
+        .unwrap();
+        a.set_echo_reply(true);
+        request[9] ^= 0xFF;
+        b.iface.send_ipv4(A, icmp::PROTO_ICMP, &request[..len]).unwrap();
+        a.poll(|_| {
+            raw += 1;
+            true
+        })
+        .unwrap();
+        assert_eq!(raw, 2);
+        assert!(b.iface.device.rx.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_ipv4_fragments_are_reassembled() {
+        let (mut a, mut b) = linked_stacks();
+        a.iface.device.mtu = 576;
+        let client = a.udp_bind(3, 0).unwrap();
+        let server = b.udp_bind(4, 7).unwrap();
+        let data: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        a.udp(client).unwrap().send_to(&data, Endpoint::new(B, 7)).unwrap();
+        a.dispatch(0).unwrap();
+
+        // 1008 bytes of UDP: 552 (a multiple of 8) and the remaining 456
+        let frames: Vec<Vec<u8>> = a.iface.device.tx.lock().unwrap().iter().cloned().collect();
+        assert_eq!(frames.iter().map(Vec::len).collect::<Vec<_>>(), [20 + 552, 20 + 456]);
+        let (first, _) = socket::parse_ipv4(&frames[0]).unwrap();
+        let (last, _) = socket::parse_ipv4(&frames[1]).unwrap();
+        assert!(first.more_fragments && !last.more_fragments);
+        assert_eq!((first.ident, first.fragment_offset, last.fragment_offset), (last.ident, 0, 552));
+
+        // Arriving out of order
+        b.iface.device.rx.lock().unwrap().swap(0, 1);
+        run(0, &mut a, &mut b);
+        let mut buf = [0u8; 1024];
+        let (len, _) = b.udp(server).unwrap().recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..len], &data[..]);
+
+        // A fragment repeated over the first: the datagram is dropped
+        a.udp(client).unwrap().send_to(&data, Endpoint::new(B, 7)).unwrap();
+        a.dispatch(0).unwrap();
+        let again = b.iface.device.rx.lock().unwrap()[0].clone();
+        let (second, _) = socket::parse_ipv4(&again).unwrap();
+        assert_ne!(second.ident, first.ident);
+        b.iface.device.rx.lock().unwrap().insert(1, again);
+        run(0, &mut a, &mut b);
+        assert!(!b.events(server).readable);
+        assert_eq!(b.reassembler().dropped(), 1);
+    }
+
+    #[test]
+    fn test_tcp_over_stack() {
+        let (mut a, mut b) = linked_stacks();
+        let listener = b.tcp_listen(4, 8883).unwrap();
+        let conn = a.tcp_connect(3, Endpoint::new(B, 8883)).unwrap();
+        assert!(!a.events(conn).writable);
+        run(0, &mut a, &mut b);
+        assert!(a.events(conn).writable);
+        assert_eq!(b.tcp(listener).unwrap().state(), socket::TcpState::Established);
+
+        a.tcp(conn).unwrap().send(b"hello over tcp").unwrap();
+        run(1, &mut a, &mut b);
+        assert!(b.events(listener).readable);
+        let mut buf = [0u8; 32];
+        assert_eq!(b.tcp(listener).unwrap().recv(&mut buf).unwrap(), 14);
+        assert_eq!(&buf[..14], b"hello over tcp");
+
+        // Releasing an open connection resets it
+        a.release(conn);
+        run(2, &mut a, &mut b);
+        assert!(b.events(listener).hangup);
+        assert_eq!(b.tcp(listener).unwrap().recv(&mut buf), Err(NetError::ConnectionReset));
+        assert_eq!(a.tcp(conn).err(), Some(NetError::InvalidState));
+    }
+
     #[test]
     fn test_send_and_recv_ipv4_payload() {
         let dev = LoopbackDevice::new();
@@ -352,4 +1842,52 @@ mod tests {
 
         assert!(res.is_ok());
     }
+
+    #[test]
+    fn test_egress_firewall_and_quota() {
+        let mut iface = NetInterface::new(LoopbackDevice::new());
+        iface.configure_ipv4(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(255, 255, 255, 0), Ipv4Addr::new(10, 0, 0, 254));
+        let mut stack = NetworkStack::new(iface);
+        let dest = Ipv4Addr::new(10, 0, 0, 2);
+        stack.set_firewall(Some(|task, _, _| task != 7));
+        stack.egress_mut().set_quota(1, None, 50).unwrap();
+
+        assert_eq!(stack.send_udp_like_from(7, dest, b"x"), Err(NetError::Denied));
+        stack.send_udp_like_from(1, dest, &[0; 25]).unwrap();
+        assert_eq!(stack.send_udp_like_from(1, dest, &[0; 6]), Err(NetError::QuotaExceeded));
+        stack.send_udp_like(dest, b"ping").unwrap();
+
+        assert_eq!(stack.egress().task_total(1), 45);
+        assert_eq!(stack.egress().task_total(egress::SYSTEM_TASK), 24);
+        assert_eq!(stack.egress().task_total(7), 0);
+    }
+
+    #[test]
+    fn test_error_codes_roundtrip() {
+        for e in [
+            NetError::DeviceError,
+            NetError::MalformedPacket,
+            NetError::Timeout,
+            NetError::Unsupported,
+            NetError::Denied,
+            NetError::QuotaExceeded,
+            NetError::Exhausted,
+            NetError::AddrInUse,
+            NetError::InvalidState,
+            NetError::ConnectionReset,
+            NetError::ConnectionTimedOut,
+            NetError::Unresolved,
+            NetError::NoRoute,
+            NetError::Other(0x1234),
+        ] {
+            assert_eq!(NetError::from_code(e.code()), Some(e));
+        }
+        assert_eq!(NetError::from_code(0), None);
+        assert_eq!(NetError::from_code(NetError::OTHER_BASE - 1), None);
+        assert!(std::format!("{}", NetError::Timeout).contains("net error 3"));
+        #[cfg(feature = "error-strings")]
+        assert_eq!(std::format!("{}", NetError::NoRoute), "no route to host (net error 13)");
+        #[cfg(not(feature = "error-strings"))]
+        assert_eq!(std::format!("{}", NetError::NoRoute), "(net error 13)");
+    }
 }
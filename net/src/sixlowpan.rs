@@ -0,0 +1,274 @@
+//! SecureIoTOS net 6LoWPAN Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! IPHC compression of the IPv6 header (RFC 6282) for IEEE 802.15.4
+//! links (Thread and the like), where a 40-byte header would take a
+//! third of a 127-byte frame.
+//!
+//! `compress()` writes the IPHC form of an `Ipv6Header` that the radio
+//! driver puts between its 802.15.4 MAC header and the payload;
+//! `decompress()` undoes it given the frame's link-layer addresses. In
+//! the common case, link-local addresses derived from those link-layer
+//! addresses, the header shrinks to three bytes.
+//!
+//! Only stateless compression is implemented: traffic class and flow
+//! label are elided (sent as zero), and link-local and multicast
+//! addresses are compressed without shared contexts. Headers that use a
+//! context (CID, SAC or DAC set) or next header compression (NHC) are
+//! refused on decompression. Fragmentation and mesh headers are the
+//! driver's business.
+
+use crate::ipv6::Ipv6Header;
+use crate::Ipv6Addr;
+
+/// Dispatch value of an IPHC header, in its top three bits.
+const DISPATCH_IPHC: u8 = 0b0110_0000;
+/// Traffic class and flow label elided.
+const TF_ELIDED: u8 = 0b11 << 3;
+
+/// Longest header `compress()` writes: IPHC, next header, hop limit and
+/// two full addresses.
+pub const MAX_HEADER_LEN: usize = 2 + 1 + 1 + 16 + 16;
+
+/// IEEE 802.15.4 link-layer address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Ieee802154Addr {
+    /// 16-bit short address assigned by the coordinator
+    Short(u16),
+    /// 64-bit extended address (EUI-64)
+    Extended([u8; 8]),
+}
+
+impl Ieee802154Addr {
+    /// Interface identifier derived from the address (RFC 4944 §6):
+    /// `0000:00ff:fe00:XXXX` for a short address, the EUI-64 with the
+    /// universal/local bit inverted for an extended one.
+    pub fn interface_id(&self) -> [u8; 8] {
+        match *self {
+            Self::Short(short) => {
+                let [hi, lo] = short.to_be_bytes();
+                [0, 0, 0, 0xff, 0xfe, 0, hi, lo]
+            }
+            Self::Extended(eui) => {
+                let mut iid = eui;
+                iid[0] ^= 0x02;
+                iid
+            }
+        }
+    }
+}
+
+/// Write the compressed form of `header` into `out` (at least
+/// `MAX_HEADER_LEN` bytes). `ll_src` and `ll_dst` are the link-layer
+/// addresses of the frame that carries it. Returns the length written.
+pub fn compress(header: &Ipv6Header, ll_src: Ieee802154Addr, ll_dst: Ieee802154Addr, out: &mut [u8]) -> usize {
+    let hlim = match header.hop_limit {
+        1 => 0b01,
+        64 => 0b10,
+        255 => 0b11,
+        _ => 0b00,
+    };
+    let mut at = 2;
+    out[at] = header.next_header;
+    at += 1;
+    if hlim == 0 {
+        out[at] = header.hop_limit;
+        at += 1;
+    }
+
+    // Source: stateless (SAC=0) unless unspecified (SAC=1, SAM=00)
+    let src_bits = if header.src.is_unspecified() {
+        0b0100_0000
+    } else {
+        let (mode, inline) = unicast_mode(&header.src, ll_src);
+        at += put(&mut out[at..], &header.src.octets[inline..]);
+        mode << 4
+    };
+
+    let dst_bits = if header.dst.is_multicast() {
+        let o = &header.dst.octets;
+        let zeros = |range: core::ops::Range<usize>| o[range].iter().all(|b| *b == 0);
+        // ff02::00XX, ffXX::00XX:XXXX, ffXX::00XX:XXXX:XXXX or in full
+        let (mode, inline) = if o[1] == 0x02 && zeros(2..15) {
+            (0b11, 15)
+        } else if zeros(2..13) {
+            (0b10, 13)
+        } else if zeros(2..11) {
+            (0b01, 11)
+        } else {
+            (0b00, 0)
+        };
+        if mode == 0b01 || mode == 0b10 {
+            out[at] = o[1];
+            at += 1;
+        }
+        at += put(&mut out[at..], &o[inline..]);
+        0b1000 | mode
+    } else {
+        let (mode, inline) = unicast_mode(&header.dst, ll_dst);
+        at += put(&mut out[at..], &header.dst.octets[inline..]);
+        mode
+    };
+
+    out[0] = DISPATCH_IPHC | TF_ELIDED | hlim;
+    out[1] = src_bits | dst_bits;
+    at
+}
+
+/// Parse a compressed header at the start of `data`, the payload of a
+/// frame from `ll_src` to `ll_dst`. Returns the header and the number of
+/// bytes it took; the IPv6 payload follows.
+pub fn decompress(data: &[u8], ll_src: Ieee802154Addr, ll_dst: Ieee802154Addr) -> Option<(Ipv6Header, usize)> {
+    let (b0, b1) = (*data.first()?, *data.get(1)?);
+    if b0 & 0b1110_0000 != DISPATCH_IPHC {
+        return None;
+    }
+    let mut inline = Inline(&data[2..]);
+
+    // Traffic class and flow label are skipped, whichever form they take
+    inline.take([4, 3, 1, 0][((b0 >> 3) & 0b11) as usize])?;
+    let (nh, hlim) = (b0 & 0b100 != 0, b0 & 0b11);
+    let (cid, sac, sam) = (b1 & 0x80 != 0, b1 & 0x40 != 0, (b1 >> 4) & 0b11);
+    let (m, dac, dam) = (b1 & 0x08 != 0, b1 & 0x04 != 0, b1 & 0b11);
+    if nh || cid || dac || (sac && sam != 0) {
+        return None;
+    }
+    let next_header = inline.take(1)?[0];
+    let hop_limit = match hlim {
+        0b00 => inline.take(1)?[0],
+        0b01 => 1,
+        0b10 => 64,
+        _ => 255,
+    };
+
+    let src = if sac { Ipv6Addr::UNSPECIFIED } else { inline.unicast(sam, ll_src)? };
+    let dst = if m {
+        let mut addr = Ipv6Addr { octets: [0; 16] };
+        addr.octets[0] = 0xff;
+        match dam {
+            0b00 => addr.octets.copy_from_slice(inline.take(16)?),
+            0b01 | 0b10 => {
+                let from = if dam == 0b01 { 11 } else { 13 };
+                addr.octets[1] = inline.take(1)?[0];
+                addr.octets[from..].copy_from_slice(inline.take(16 - from)?);
+            }
+            _ => {
+                addr.octets[1] = 0x02;
+                addr.octets[15] = inline.take(1)?[0];
+            }
+        }
+        addr
+    } else {
+        inline.unicast(dam, ll_dst)?
+    };
+
+    let used = data.len() - inline.0.len();
+    Some((Ipv6Header { src, dst, next_header, hop_limit }, used))
+}
+
+/// The inline fields after the IPHC bytes, consumed in order.
+struct Inline<'a>(&'a [u8]);
+
+impl<'a> Inline<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        let (head, tail) = (self.0.get(..n)?, self.0.get(n..)?);
+        self.0 = tail;
+        Some(head)
+    }
+
+    /// A unicast address in SAM/DAM `mode`, its elided part derived
+    /// from the link-layer address `ll`.
+    fn unicast(&mut self, mode: u8, ll: Ieee802154Addr) -> Option<Ipv6Addr> {
+        let mut addr = Ipv6Addr::link_local(ll.interface_id());
+        match mode {
+            0b00 => addr.octets.copy_from_slice(self.take(16)?),
+            0b01 => addr.octets[8..].copy_from_slice(self.take(8)?),
+            0b10 => {
+                addr.octets[8..14].copy_from_slice(&[0, 0, 0, 0xff, 0xfe, 0]);
+                addr.octets[14..].copy_from_slice(self.take(2)?);
+            }
+            _ => {}
+        }
+        Some(addr)
+    }
+}
+
+/// SAM/DAM mode for a unicast address and the offset of its part that
+/// goes inline.
+fn unicast_mode(addr: &Ipv6Addr, ll: Ieee802154Addr) -> (u8, usize) {
+    let o = &addr.octets;
+    if o[..8] != [0xfe, 0x80, 0, 0, 0, 0, 0, 0] {
+        return (0b00, 0);
+    }
+    if o[8..] == ll.interface_id() {
+        (0b11, 16)
+    } else if o[8..14] == [0, 0, 0, 0xff, 0xfe, 0] {
+        (0b10, 14)
+    } else {
+        (0b01, 8)
+    }
+}
+
+fn put(out: &mut [u8], bytes: &[u8]) -> usize {
+    out[..bytes.len()].copy_from_slice(bytes);
+    bytes.len()
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+    use crate::ipv6::NEXT_HEADER_ICMPV6;
+
+    const NODE: Ieee802154Addr = Ieee802154Addr::Extended([0x00, 0x12, 0x4b, 0x00, 0x01, 0x02, 0x03, 0x04]);
+    const PARENT: Ieee802154Addr = Ieee802154Addr::Short(0x0400);
+
+    fn roundtrip(header: Ipv6Header, ll_src: Ieee802154Addr, ll_dst: Ieee802154Addr) -> usize {
+        let mut buf = [0u8; MAX_HEADER_LEN + 4];
+        let len = compress(&header, ll_src, ll_dst, &mut buf);
+        buf[len..len + 4].copy_from_slice(b"data");
+        assert_eq!(decompress(&buf[..len + 4], ll_src, ll_dst), Some((header, len)));
+        len
+    }
+
+    #[test]
+    fn link_local_and_multicast_shrink() {
+        let src = Ipv6Addr::link_local(NODE.interface_id());
+        let dst = Ipv6Addr::link_local(PARENT.interface_id());
+        let header = Ipv6Header { src, dst, next_header: NEXT_HEADER_ICMPV6, hop_limit: 255 };
+        // Both addresses come from the frame
+        assert_eq!(roundtrip(header, NODE, PARENT), 3);
+        // Not derived from this frame's addresses: 64 and 16 bits inline
+        assert_eq!(roundtrip(header, PARENT, NODE), 3 + 8 + 2);
+
+        let all_nodes = Ipv6Header { dst: Ipv6Addr::ALL_NODES, hop_limit: 64, ..header };
+        assert_eq!(roundtrip(all_nodes, NODE, Ieee802154Addr::Short(0xffff)), 4);
+        let realm = Ipv6Header { dst: Ipv6Addr::new(0xff03, 0, 0, 0, 0, 0, 0, 0xfc), ..all_nodes };
+        assert_eq!(roundtrip(realm, NODE, Ieee802154Addr::Short(0xffff)), 3 + 4);
+        let dad = Ipv6Header { src: Ipv6Addr::UNSPECIFIED, dst: src.solicited_node(), ..header };
+        assert_eq!(roundtrip(dad, NODE, Ieee802154Addr::Short(0xffff)), 3 + 6);
+    }
+
+    #[test]
+    fn global_addresses_go_inline() {
+        let header = Ipv6Header {
+            src: Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1),
+            dst: Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2),
+            next_header: 17,
+            hop_limit: 10,
+        };
+        assert_eq!(roundtrip(header, NODE, PARENT), MAX_HEADER_LEN);
+
+        // Context-based compression is not supported
+        let mut buf = [0u8; MAX_HEADER_LEN];
+        let len = compress(&header, NODE, PARENT, &mut buf);
+        buf[1] |= 0x04;
+        assert_eq!(decompress(&buf[..len], NODE, PARENT), None);
+        assert_eq!(decompress(&buf[..3], NODE, PARENT), None);
+    }
+}
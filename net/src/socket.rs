@@ -0,0 +1,279 @@
+//! SecureIoTOS net Socket Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! UDP and TCP sockets on top of `NetworkStack`.
+//!
+//! Sockets live in a fixed table inside the stack (`SOCKETS` entries) and
+//! are addressed by `SocketHandle`. They never touch the device: `send`
+//! and `recv` only move bytes in and out of the socket's buffers, and the
+//! stack does the I/O when it is polled:
+//!
+//! - `NetworkStack::poll()` receives a frame and hands UDP datagrams and
+//!   TCP segments addressed to the interface to the socket bound to the
+//!   destination port. Anything else still goes to the caller's handler.
+//! - `NetworkStack::dispatch(now_ms)` sends what the sockets have queued
+//!   (datagrams, TCP data, ACKs, retransmissions) through the firewall
+//!   hook and the egress quotas of the task that owns the socket, and runs
+//!   the TCP timers.
+//!
+//! Between the two, `NetworkStack::events()` says which sockets can be
+//! read or written, so a no_std main loop needs neither callbacks nor an
+//! allocator:
+//!
+//! ```text
+//!   loop {
+//!       while stack.poll(|_| true).is_ok() {}
+//!       stack.dispatch(now_ms())?;
+//!       if stack.events(coap).readable { ... stack.udp(coap)?.recv_from(&mut buf) ... }
+//!   }
+//! ```
+//!
+//! Packets go out through `NetInterface::send_ipv4()`: in Ethernet frames
+//! to the ARP-resolved next hop on a LAN interface, bare otherwise. There
+//! is no IP fragmentation.
+//!
+//! TCP is deliberately small: one connection per socket, in-order receive
+//! only (an out-of-order segment is dropped and the duplicate ACK asks for
+//! it again), go-back-N retransmission with a doubling timeout, no
+//! congestion control and no urgent data. That suits a device talking to
+//! its own backend; put `smoltcp` behind `NetworkDevice` for more.
+
+pub mod tcp;
+pub mod udp;
+
+pub use tcp::{TcpSocket, TcpState};
+pub use udp::UdpSocket;
+
+use crate::Ipv4Addr;
+
+/// Receive and transmit buffer size of each socket, in bytes.
+pub const SOCKET_BUFFER: usize = 1024;
+
+/// IPv4 protocol numbers.
+pub(crate) const PROTO_TCP: u8 = 6;
+pub(crate) const PROTO_UDP: u8 = 17;
+
+/// Ports `NetworkStack` picks from when none is asked for (RFC 6335).
+pub(crate) const EPHEMERAL_PORTS: core::ops::RangeInclusive<u16> = 49152..=65535;
+
+/// Address and port of one end of a connection or datagram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Endpoint {
+    pub addr: Ipv4Addr,
+    pub port: u16,
+}
+
+impl Endpoint {
+    pub const fn new(addr: Ipv4Addr, port: u16) -> Self {
+        Self { addr, port }
+    }
+}
+
+/// Names a socket in the stack's table. Only valid until the socket is
+/// released (`NetworkStack::release()`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SocketHandle(pub(crate) usize);
+
+/// Readiness of a socket after the last poll.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Events {
+    /// `recv` has data, or will report end of stream or an error
+    pub readable: bool,
+    /// `send` has room to queue data
+    pub writable: bool,
+    /// The connection is over: closed, reset or timed out
+    pub hangup: bool,
+}
+
+/// A socket in the stack's table.
+pub(crate) enum Socket {
+    Udp(UdpSocket),
+    Tcp(TcpSocket),
+}
+
+impl Socket {
+    pub(crate) fn events(&self) -> Events {
+        match self {
+            Socket::Udp(s) => s.events(),
+            Socket::Tcp(s) => s.events(),
+        }
+    }
+}
+
+/// Byte FIFO of `N` bytes that wraps around.
+pub(crate) struct RingBuffer<const N: usize> {
+    buf: [u8; N],
+    start: usize,
+    len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    pub(crate) const fn new() -> Self {
+        Self { buf: [0; N], start: 0, len: 0 }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn free(&self) -> usize {
+        N - self.len
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.start = 0;
+        self.len = 0;
+    }
+
+    /// Append as much of `data` as fits; returns how much did.
+    pub(crate) fn push(&mut self, data: &[u8]) -> usize {
+        let n = data.len().min(self.free());
+        for (i, &b) in data[..n].iter().enumerate() {
+            self.buf[(self.start + self.len + i) % N] = b;
+        }
+        self.len += n;
+        n
+    }
+
+    /// Copy bytes from `offset` on into `out` without consuming them.
+    pub(crate) fn peek(&self, offset: usize, out: &mut [u8]) -> usize {
+        let n = out.len().min(self.len.saturating_sub(offset));
+        for (i, b) in out[..n].iter_mut().enumerate() {
+            *b = self.buf[(self.start + offset + i) % N];
+        }
+        n
+    }
+
+    /// Drop up to `n` bytes from the front.
+    pub(crate) fn discard(&mut self, n: usize) {
+        let n = n.min(self.len);
+        self.start = (self.start + n) % N;
+        self.len -= n;
+    }
+
+    /// Move bytes from the front into `out`.
+    pub(crate) fn pop(&mut self, out: &mut [u8]) -> usize {
+        let n = self.peek(0, out);
+        self.discard(n);
+        n
+    }
+}
+
+/// Header fields of a received IPv4 packet.
+pub(crate) struct Ipv4Header {
+    pub(crate) src: Ipv4Addr,
+    pub(crate) dst: Ipv4Addr,
+    pub(crate) protocol: u8,
+    pub(crate) ident: u16,
+    /// Offset of the payload in the datagram, in bytes
+    pub(crate) fragment_offset: usize,
+    pub(crate) more_fragments: bool,
+}
+
+impl Ipv4Header {
+    /// Part of a fragmented datagram rather than a whole one.
+    pub(crate) fn is_fragment(&self) -> bool {
+        self.more_fragments || self.fragment_offset != 0
+    }
+}
+
+/// Split a bare IPv4 packet into header and payload. `None` for anything
+/// that is not a valid IPv4 packet; fragments are returned as they are
+/// (see `fragment`).
+pub(crate) fn parse_ipv4(frame: &[u8]) -> Option<(Ipv4Header, &[u8])> {
+    if frame.len() < 20 || frame[0] >> 4 != 4 {
+        return None;
+    }
+    let header_len = (frame[0] & 0x0F) as usize * 4;
+    let total_len = u16::from_be_bytes([frame[2], frame[3]]) as usize;
+    if header_len < 20 || total_len < header_len || total_len > frame.len() {
+        return None;
+    }
+    if checksum(&frame[..header_len], 0) != 0 {
+        return None;
+    }
+    let flags_offset = u16::from_be_bytes([frame[6], frame[7]]);
+    let addr = |at: usize| Ipv4Addr::new(frame[at], frame[at + 1], frame[at + 2], frame[at + 3]);
+    let header = Ipv4Header {
+        src: addr(12),
+        dst: addr(16),
+        protocol: frame[9],
+        ident: u16::from_be_bytes([frame[4], frame[5]]),
+        fragment_offset: (flags_offset & 0x1FFF) as usize * 8,
+        more_fragments: flags_offset & 0x2000 != 0,
+    };
+    Some((header, &frame[header_len..total_len]))
+}
+
+/// Internet checksum (RFC 1071) of `data`, continuing from the partial
+/// sum `initial`.
+pub(crate) fn checksum(data: &[u8], initial: u32) -> u16 {
+    let mut sum = initial;
+    let mut words = data.chunks_exact(2);
+    for w in &mut words {
+        sum += u16::from_be_bytes([w[0], w[1]]) as u32;
+    }
+    if let [last] = words.remainder() {
+        sum += (*last as u32) << 8;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Partial checksum of the TCP/UDP pseudo-header.
+pub(crate) fn pseudo_header_sum(src: Ipv4Addr, dst: Ipv4Addr, protocol: u8, len: usize) -> u32 {
+    let (s, d) = (src.octets, dst.octets);
+    [[s[0], s[1]], [s[2], s[3]], [d[0], d[1]], [d[2], d[3]], [0, protocol], (len as u16).to_be_bytes()]
+        .iter()
+        .map(|w| u16::from_be_bytes(*w) as u32)
+        .sum()
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_buffer_wraps() {
+        let mut ring = RingBuffer::<8>::new();
+        assert_eq!(ring.push(b"abcdef"), 6);
+        let mut out = [0u8; 4];
+        assert_eq!(ring.pop(&mut out), 4);
+        assert_eq!(&out, b"abcd");
+        // Wraps past the end of the storage
+        assert_eq!(ring.push(b"ghijklmn"), 6);
+        assert_eq!(ring.free(), 0);
+
+        let mut out = [0u8; 3];
+        assert_eq!(ring.peek(2, &mut out), 3);
+        assert_eq!(&out, b"ghi");
+        ring.discard(5);
+        let mut out = [0u8; 8];
+        assert_eq!(ring.pop(&mut out), 3);
+        assert_eq!(&out[..3], b"jkl");
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn checksum_matches_rfc1071_example() {
+        // RFC 1071 §3 example words; the sum folds to 0xDDF2
+        let data = [0x00, 0x01, 0xF2, 0x03, 0xF4, 0xF5, 0xF6, 0xF7];
+        assert_eq!(checksum(&data, 0), !0xDDF2);
+        // Odd length pads with a zero byte
+        assert_eq!(checksum(&[0x12], 0), !0x1200);
+    }
+}
@@ -0,0 +1,815 @@
+//! SecureIoTOS net TCP Socket Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! One TCP connection (RFC 9293), opened actively (`NetworkStack::
+//! tcp_connect()`) or passively (`NetworkStack::tcp_listen()`; the
+//! listening socket becomes the connection, so listen again for the next
+//! one).
+//!
+//! The transmit buffer holds everything from the oldest unacknowledged
+//! byte on, so retransmitting is re-reading it. Every segment that carries
+//! data or a FIN is acknowledged at once; there is no delayed ACK, no
+//! Nagle and no congestion window, only the peer's receive window.
+
+use super::{checksum, pseudo_header_sum, Endpoint, Events, RingBuffer, PROTO_TCP, SOCKET_BUFFER};
+use crate::egress::TaskId;
+use crate::{Ipv4Addr, NetError, NetResult};
+
+/// TCP header length without options.
+pub(crate) const HEADER_LEN: usize = 20;
+
+const FIN: u8 = 0x01;
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const PSH: u8 = 0x08;
+const ACK: u8 = 0x10;
+
+/// Segment size assumed when the peer announces none (RFC 9293 §3.7.1).
+const DEFAULT_MSS: usize = 536;
+const INITIAL_RTO_MS: u64 = 1_000;
+const MAX_RTO_MS: u64 = 60_000;
+/// Retransmissions of the same segment before the connection is dropped
+const MAX_RETRIES: u8 = 8;
+/// Time spent in TIME-WAIT. Far below 2*MSL: the socket table is tiny,
+/// and a device seldom reconnects from the same port.
+const TIME_WAIT_MS: u64 = 2_000;
+
+/// Connection state (RFC 9293 §3.3.2).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TcpState {
+    Closed,
+    Listen,
+    SynSent,
+    SynReceived,
+    Established,
+    FinWait1,
+    FinWait2,
+    CloseWait,
+    Closing,
+    LastAck,
+    TimeWait,
+}
+
+/// Header fields of a TCP segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct TcpRepr {
+    pub(crate) src_port: u16,
+    pub(crate) dst_port: u16,
+    pub(crate) seq: u32,
+    pub(crate) ack: u32,
+    pub(crate) flags: u8,
+    pub(crate) window: u16,
+    /// MSS option (SYN segments only)
+    pub(crate) mss: Option<u16>,
+}
+
+impl TcpRepr {
+    /// Split a received segment into header and payload, checking its
+    /// checksum. Options other than MSS are skipped.
+    pub(crate) fn parse(src: Ipv4Addr, dst: Ipv4Addr, segment: &[u8]) -> Option<(Self, &[u8])> {
+        if segment.len() < HEADER_LEN {
+            return None;
+        }
+        let header_len = (segment[12] >> 4) as usize * 4;
+        if header_len < HEADER_LEN || header_len > segment.len() {
+            return None;
+        }
+        if checksum(segment, pseudo_header_sum(src, dst, PROTO_TCP, segment.len())) != 0 {
+            return None;
+        }
+
+        let mut mss = None;
+        let mut options = &segment[HEADER_LEN..header_len];
+        while let [kind, rest @ ..] = options {
+            match kind {
+                0 => break,
+                1 => options = rest,
+                _ => {
+                    let len = rest.first().map_or(0, |&l| l as usize);
+                    if len < 2 || len > options.len() {
+                        break;
+                    }
+                    if *kind == 2 && len == 4 {
+                        mss = Some(u16::from_be_bytes([options[2], options[3]]));
+                    }
+                    options = &options[len..];
+                }
+            }
+        }
+
+        let u16_at = |at: usize| u16::from_be_bytes([segment[at], segment[at + 1]]);
+        let u32_at = |at: usize| u32::from_be_bytes([segment[at], segment[at + 1], segment[at + 2], segment[at + 3]]);
+        let repr = Self {
+            src_port: u16_at(0),
+            dst_port: u16_at(2),
+            seq: u32_at(4),
+            ack: u32_at(8),
+            flags: segment[13],
+            window: u16_at(14),
+            mss,
+        };
+        Some((repr, &segment[header_len..]))
+    }
+
+    /// Write the header in front of the `payload_len` bytes already at
+    /// `packet[HEADER_LEN..]`. Returns the segment length.
+    pub(crate) fn emit(&self, src: Ipv4Addr, dst: Ipv4Addr, packet: &mut [u8], payload_len: usize) -> usize {
+        // Options only go on a SYN, which carries no data
+        let header_len = if self.mss.is_some() { HEADER_LEN + 4 } else { HEADER_LEN };
+        debug_assert!(self.mss.is_none() || payload_len == 0);
+        let total = header_len + payload_len;
+
+        packet[0..2].copy_from_slice(&self.src_port.to_be_bytes());
+        packet[2..4].copy_from_slice(&self.dst_port.to_be_bytes());
+        packet[4..8].copy_from_slice(&self.seq.to_be_bytes());
+        packet[8..12].copy_from_slice(&self.ack.to_be_bytes());
+        packet[12] = ((header_len / 4) << 4) as u8;
+        packet[13] = self.flags;
+        packet[14..16].copy_from_slice(&self.window.to_be_bytes());
+        packet[16..20].fill(0);
+        if let Some(mss) = self.mss {
+            let [hi, lo] = mss.to_be_bytes();
+            packet[20..24].copy_from_slice(&[2, 4, hi, lo]);
+        }
+        let sum = checksum(&packet[..total], pseudo_header_sum(src, dst, PROTO_TCP, total));
+        packet[16..18].copy_from_slice(&sum.to_be_bytes());
+        total
+    }
+}
+
+/// A TCP socket, reached through `NetworkStack::tcp()`.
+pub struct TcpSocket {
+    task: TaskId,
+    state: TcpState,
+    local_port: u16,
+    remote: Option<Endpoint>,
+    /// Opened by `listen`: a failed handshake returns to LISTEN
+    listening: bool,
+    /// Oldest unacknowledged sequence number; once synchronized, the
+    /// transmit buffer starts here
+    snd_una: u32,
+    snd_nxt: u32,
+    /// Highest sequence number sent (`snd_nxt` goes back to `snd_una` to
+    /// retransmit)
+    snd_max: u32,
+    /// Peer's receive window
+    snd_wnd: u16,
+    rcv_nxt: u32,
+    /// Largest segment to send: ours until the peer's SYN lowers it
+    mss: usize,
+    /// Receive window last advertised
+    adv_wnd: usize,
+    rx: RingBuffer<SOCKET_BUFFER>,
+    tx: RingBuffer<SOCKET_BUFFER>,
+    /// `close()` was called: a FIN follows the queued data
+    fin_queued: bool,
+    /// The peer's FIN arrived: no more data will
+    fin_received: bool,
+    ack_pending: bool,
+    rst_pending: bool,
+    rto_ms: u64,
+    rto_deadline: Option<u64>,
+    retries: u8,
+    time_wait_until: u64,
+    error: Option<NetError>,
+}
+
+impl TcpSocket {
+    fn new(task: TaskId, local_port: u16, isn: u32, mss: usize) -> Self {
+        Self {
+            task,
+            state: TcpState::Closed,
+            local_port,
+            remote: None,
+            listening: false,
+            snd_una: isn,
+            snd_nxt: isn,
+            snd_max: isn,
+            snd_wnd: 0,
+            rcv_nxt: 0,
+            mss,
+            adv_wnd: 0,
+            rx: RingBuffer::new(),
+            tx: RingBuffer::new(),
+            fin_queued: false,
+            fin_received: false,
+            ack_pending: false,
+            rst_pending: false,
+            rto_ms: INITIAL_RTO_MS,
+            rto_deadline: None,
+            retries: 0,
+            time_wait_until: 0,
+            error: None,
+        }
+    }
+
+    /// Socket that opens a connection to `remote`; the SYN goes out on the
+    /// next dispatch.
+    pub(crate) fn connect(task: TaskId, local_port: u16, remote: Endpoint, isn: u32, mss: usize) -> Self {
+        Self { state: TcpState::SynSent, remote: Some(remote), ..Self::new(task, local_port, isn, mss) }
+    }
+
+    /// Socket that waits for a connection on `local_port`.
+    pub(crate) fn listen(task: TaskId, local_port: u16, isn: u32, mss: usize) -> Self {
+        Self { state: TcpState::Listen, listening: true, ..Self::new(task, local_port, isn, mss) }
+    }
+
+    pub fn state(&self) -> TcpState {
+        self.state
+    }
+
+    pub fn local_port(&self) -> u16 {
+        self.local_port
+    }
+
+    /// The peer, once known.
+    pub fn remote(&self) -> Option<Endpoint> {
+        self.remote
+    }
+
+    /// Task the socket's traffic is accounted to.
+    pub fn task(&self) -> TaskId {
+        self.task
+    }
+
+    /// Queue as much of `data` as the transmit buffer has room for. Data
+    /// queued before the handshake completes goes out after it.
+    ///
+    /// # Returns
+    /// - `Ok(n)`: the first `n` bytes were queued (0 if the buffer is full)
+    /// - `Err(NetError::InvalidState)` if the socket was closed
+    /// - `Err(NetError::ConnectionReset)` / `Err(NetError::ConnectionTimedOut)`
+    ///   if the connection failed
+    pub fn send(&mut self, data: &[u8]) -> NetResult<usize> {
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        match self.state {
+            TcpState::SynSent | TcpState::SynReceived | TcpState::Established | TcpState::CloseWait
+                if !self.fin_queued =>
+            {
+                Ok(self.tx.push(data))
+            }
+            _ => Err(NetError::InvalidState),
+        }
+    }
+
+    /// Take received data into `buf`.
+    ///
+    /// # Returns
+    /// - `Ok(n)` with `n > 0`: bytes copied into `buf`
+    /// - `Ok(0)`: end of stream, the peer closed its side
+    /// - `Err(NetError::Timeout)` if no data has arrived yet
+    /// - `Err(NetError::ConnectionReset)` / `Err(NetError::ConnectionTimedOut)`
+    ///   once the data received before the failure has been read
+    pub fn recv(&mut self, buf: &mut [u8]) -> NetResult<usize> {
+        if !self.rx.is_empty() {
+            let n = self.rx.pop(buf);
+            // Tell the peer once the window has opened usefully (RFC 9293
+            // §3.8.6.2.2), so a sender stalled on a full window resumes.
+            if !self.fin_received && self.rx.free().saturating_sub(self.adv_wnd) >= self.mss.min(SOCKET_BUFFER / 2) {
+                self.ack_pending = true;
+            }
+            return Ok(n);
+        }
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        match self.state {
+            TcpState::Listen
+            | TcpState::SynSent
+            | TcpState::SynReceived
+            | TcpState::Established
+            | TcpState::FinWait1
+            | TcpState::FinWait2 => Err(NetError::Timeout),
+            _ => Ok(0),
+        }
+    }
+
+    /// True if `recv` would return data or end of stream.
+    pub fn can_recv(&self) -> bool {
+        !self.rx.is_empty() || self.fin_received
+    }
+
+    /// Close our side once the queued data is sent. The peer may still
+    /// send until it closes too. A socket that never connected closes at
+    /// once.
+    pub fn close(&mut self) {
+        match self.state {
+            TcpState::Listen | TcpState::SynSent => self.state = TcpState::Closed,
+            // The FIN waits for the handshake to complete
+            TcpState::SynReceived => self.fin_queued = true,
+            TcpState::Established => {
+                self.fin_queued = true;
+                self.state = TcpState::FinWait1;
+            }
+            TcpState::CloseWait => {
+                self.fin_queued = true;
+                self.state = TcpState::LastAck;
+            }
+            _ => {}
+        }
+    }
+
+    /// Drop the connection: queued data is discarded and the peer gets a
+    /// reset.
+    pub fn abort(&mut self) {
+        if !matches!(self.state, TcpState::Closed | TcpState::Listen | TcpState::SynSent | TcpState::TimeWait) {
+            self.rst_pending = true;
+        }
+        self.state = TcpState::Closed;
+        self.listening = false;
+        self.fin_queued = false;
+        self.rto_deadline = None;
+        self.rx.clear();
+        self.tx.clear();
+    }
+
+    /// True while the connection is in use: neither closed nor lingering
+    /// in TIME-WAIT.
+    pub fn is_active(&self) -> bool {
+        !matches!(self.state, TcpState::Closed | TcpState::Listen | TcpState::TimeWait)
+    }
+
+    pub(crate) fn events(&self) -> Events {
+        Events {
+            readable: self.can_recv() || self.error.is_some(),
+            writable: matches!(self.state, TcpState::Established | TcpState::CloseWait)
+                && !self.fin_queued
+                && self.tx.free() > 0,
+            hangup: self.error.is_some() || matches!(self.state, TcpState::Closed | TcpState::TimeWait),
+        }
+    }
+
+    /// True if a segment from `remote` to `local_port` belongs to this
+    /// connection.
+    pub(crate) fn matches(&self, local_port: u16, remote: Endpoint) -> bool {
+        self.local_port == local_port && self.remote == Some(remote) && self.state != TcpState::Closed
+    }
+
+    /// True if this socket takes new connections on `local_port`.
+    pub(crate) fn listens_on(&self, local_port: u16) -> bool {
+        self.local_port == local_port && self.state == TcpState::Listen
+    }
+
+    /// Handle a segment from `src` addressed to this socket.
+    pub(crate) fn process(&mut self, now: u64, src: Ipv4Addr, seg: &TcpRepr, payload: &[u8]) {
+        match self.state {
+            TcpState::Closed => return,
+            TcpState::Listen => return self.process_listen(Endpoint::new(src, seg.src_port), seg),
+            TcpState::SynSent => return self.process_syn_sent(now, seg),
+            _ => {}
+        }
+
+        if seg.flags & RST != 0 {
+            // Only a reset exactly in sequence is believed (RFC 5961 §3.2)
+            if seg.seq == self.rcv_nxt {
+                if self.state == TcpState::SynReceived && self.listening {
+                    self.relisten();
+                } else {
+                    self.fail(NetError::ConnectionReset);
+                }
+            }
+            return;
+        }
+        if seg.flags & SYN != 0 {
+            // A retransmitted SYN-ACK: our ACK of it got lost
+            self.ack_pending = true;
+            return;
+        }
+        if seg.flags & ACK == 0 || !self.process_ack(now, seg) {
+            return;
+        }
+        self.process_data(now, seg, payload);
+    }
+
+    fn process_listen(&mut self, remote: Endpoint, seg: &TcpRepr) {
+        if seg.flags & (RST | ACK) != 0 || seg.flags & SYN == 0 {
+            return;
+        }
+        self.remote = Some(remote);
+        self.rcv_nxt = seg.seq.wrapping_add(1);
+        self.snd_wnd = seg.window;
+        self.mss = self.mss.min(seg.mss.map_or(DEFAULT_MSS, |m| m as usize));
+        self.state = TcpState::SynReceived;
+    }
+
+    fn process_syn_sent(&mut self, now: u64, seg: &TcpRepr) {
+        let has_ack = seg.flags & ACK != 0;
+        let acceptable = has_ack && seg.ack == self.snd_una.wrapping_add(1);
+        if has_ack && !acceptable {
+            return;
+        }
+        if seg.flags & RST != 0 {
+            if acceptable {
+                self.fail(NetError::ConnectionReset);
+            }
+            return;
+        }
+        // Simultaneous open (a bare SYN) is not supported
+        if seg.flags & SYN == 0 || !acceptable {
+            return;
+        }
+        self.rcv_nxt = seg.seq.wrapping_add(1);
+        self.snd_una = seg.ack;
+        self.snd_nxt = seg.ack;
+        self.snd_max = seg.ack;
+        self.snd_wnd = seg.window;
+        self.mss = self.mss.min(seg.mss.map_or(DEFAULT_MSS, |m| m as usize));
+        self.state = TcpState::Established;
+        self.ack_pending = true;
+        self.acked(now);
+    }
+
+    /// Apply the acknowledgment of `seg`. Returns `false` if the segment
+    /// must be dropped.
+    fn process_ack(&mut self, now: u64, seg: &TcpRepr) -> bool {
+        if seq_lt(self.snd_max, seg.ack) {
+            // Acknowledges something never sent
+            self.ack_pending = true;
+            return false;
+        }
+        if self.state == TcpState::SynReceived && seg.ack == self.snd_una {
+            return false;
+        }
+        if seq_lt(seg.ack, self.snd_una) {
+            // Old duplicate: the data may still be new
+            return true;
+        }
+        let reopened = self.snd_wnd == 0 && seg.window > 0;
+        self.snd_wnd = seg.window;
+        if seg.ack == self.snd_una {
+            if seg.window == 0 {
+                // The peer is alive, just full: the zero-window probe does
+                // not count towards giving up
+                self.retries = 0;
+            } else if reopened {
+                // Whatever went out while the window was shut (the probe)
+                // was dropped; send it again now rather than on timeout
+                self.snd_nxt = self.snd_una;
+            }
+            return true;
+        }
+
+        let mut acked = seg.ack.wrapping_sub(self.snd_una) as usize;
+        self.snd_una = seg.ack;
+        if seq_lt(self.snd_nxt, seg.ack) {
+            self.snd_nxt = seg.ack;
+        }
+        if self.state == TcpState::SynReceived {
+            // Our SYN
+            acked -= 1;
+            self.state = if self.fin_queued { TcpState::FinWait1 } else { TcpState::Established };
+        }
+        let data = acked.min(self.tx.len());
+        self.tx.discard(data);
+        if acked > data {
+            // Our FIN
+            match self.state {
+                TcpState::FinWait1 => self.state = TcpState::FinWait2,
+                TcpState::Closing => self.enter_time_wait(now),
+                TcpState::LastAck => self.state = TcpState::Closed,
+                _ => {}
+            }
+        }
+        self.acked(now);
+        true
+    }
+
+    fn process_data(&mut self, now: u64, seg: &TcpRepr, payload: &[u8]) {
+        let fin = seg.flags & FIN != 0;
+        if self.fin_received || !matches!(self.state, TcpState::Established | TcpState::FinWait1 | TcpState::FinWait2) {
+            // Nothing more can arrive; answer retransmissions (of the
+            // peer's FIN, say) so the peer stops sending them
+            if !payload.is_empty() || fin {
+                self.ack_pending = true;
+            }
+            return;
+        }
+
+        let mut payload = payload;
+        if seq_lt(seg.seq, self.rcv_nxt) {
+            let seen = self.rcv_nxt.wrapping_sub(seg.seq) as usize;
+            if seen > payload.len() {
+                self.ack_pending = true;
+                return;
+            }
+            payload = &payload[seen..];
+        } else if seg.seq != self.rcv_nxt {
+            // Out of order: the duplicate ACK asks for the gap
+            self.ack_pending = true;
+            return;
+        }
+
+        let accepted = self.rx.push(payload);
+        self.rcv_nxt = self.rcv_nxt.wrapping_add(accepted as u32);
+        if !payload.is_empty() {
+            self.ack_pending = true;
+        }
+        if fin && accepted == payload.len() {
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+            self.fin_received = true;
+            self.ack_pending = true;
+            match self.state {
+                TcpState::Established => self.state = TcpState::CloseWait,
+                TcpState::FinWait1 => self.state = TcpState::Closing,
+                TcpState::FinWait2 => self.enter_time_wait(now),
+                _ => {}
+            }
+        }
+    }
+
+    /// Produce the next segment to send, if any, with its payload written
+    /// to `packet[HEADER_LEN..]`. Returns the header, the destination and
+    /// the payload length.
+    pub(crate) fn dispatch(&mut self, now: u64, packet: &mut [u8]) -> Option<(TcpRepr, Endpoint, usize)> {
+        let remote = self.remote?;
+        if self.rst_pending {
+            self.rst_pending = false;
+            return Some((self.segment(RST, self.snd_nxt), remote, 0));
+        }
+        if self.state == TcpState::TimeWait && now >= self.time_wait_until {
+            self.state = TcpState::Closed;
+        }
+        if matches!(self.state, TcpState::Closed | TcpState::Listen) {
+            return None;
+        }
+
+        if self.rto_deadline.is_some_and(|deadline| now >= deadline) {
+            self.rto_deadline = None;
+            if self.retries >= MAX_RETRIES {
+                if self.state == TcpState::SynReceived && self.listening {
+                    self.relisten();
+                    return None;
+                }
+                // A peer that never answered the SYN gets no reset
+                let synchronized = self.state != TcpState::SynSent;
+                self.fail(NetError::ConnectionTimedOut);
+                return synchronized.then(|| (self.segment(RST, self.snd_nxt), remote, 0));
+            }
+            self.retries += 1;
+            self.rto_ms = (self.rto_ms * 2).min(MAX_RTO_MS);
+            // Go back and resend everything unacknowledged
+            self.snd_nxt = self.snd_una;
+        }
+
+        match self.state {
+            TcpState::SynSent | TcpState::SynReceived => {
+                if self.snd_nxt != self.snd_una {
+                    return None;
+                }
+                let flags = if self.state == TcpState::SynSent { SYN } else { SYN | ACK };
+                let mut syn = self.segment(flags, self.snd_una);
+                syn.mss = Some(self.mss as u16);
+                self.snd_nxt = self.snd_una.wrapping_add(1);
+                self.sent(now);
+                return Some((syn, remote, 0));
+            }
+            TcpState::Established
+            | TcpState::CloseWait
+            | TcpState::FinWait1
+            | TcpState::Closing
+            | TcpState::LastAck => {
+                let in_flight = self.snd_nxt.wrapping_sub(self.snd_una) as usize;
+                let unsent = self.tx.len().saturating_sub(in_flight);
+                let mut room = (self.snd_wnd as usize).saturating_sub(in_flight);
+                if room == 0 && in_flight == 0 {
+                    // Zero-window probe, so we learn when the window opens
+                    room = 1;
+                }
+                let n = unsent.min(room).min(self.mss).min(packet.len() - HEADER_LEN);
+                if n > 0 {
+                    let seq = self.snd_nxt;
+                    self.tx.peek(in_flight, &mut packet[HEADER_LEN..HEADER_LEN + n]);
+                    self.snd_nxt = seq.wrapping_add(n as u32);
+                    self.sent(now);
+                    return Some((self.segment(ACK | PSH, seq), remote, n));
+                }
+                let fin_unsent = in_flight == self.tx.len();
+                if self.fin_queued
+                    && fin_unsent
+                    && self.state != TcpState::Established
+                    && self.state != TcpState::CloseWait
+                {
+                    let seq = self.snd_nxt;
+                    self.snd_nxt = seq.wrapping_add(1);
+                    self.sent(now);
+                    return Some((self.segment(FIN | ACK, seq), remote, 0));
+                }
+            }
+            _ => {}
+        }
+
+        if self.ack_pending {
+            self.ack_pending = false;
+            return Some((self.segment(ACK, self.snd_nxt), remote, 0));
+        }
+        None
+    }
+
+    /// Header for an outgoing segment; also records the window advertised.
+    fn segment(&mut self, flags: u8, seq: u32) -> TcpRepr {
+        self.adv_wnd = self.rx.free().min(u16::MAX as usize);
+        TcpRepr {
+            src_port: self.local_port,
+            dst_port: self.remote.map_or(0, |r| r.port),
+            seq,
+            ack: if flags & ACK != 0 { self.rcv_nxt } else { 0 },
+            flags,
+            window: self.adv_wnd as u16,
+            mss: None,
+        }
+    }
+
+    /// Bookkeeping after sending something that takes sequence space.
+    fn sent(&mut self, now: u64) {
+        // Every segment but a RST carries the ACK
+        self.ack_pending = false;
+        if seq_lt(self.snd_max, self.snd_nxt) {
+            self.snd_max = self.snd_nxt;
+        }
+        if self.rto_deadline.is_none() {
+            self.rto_deadline = Some(now + self.rto_ms);
+        }
+    }
+
+    /// Bookkeeping after the peer acknowledged something new.
+    fn acked(&mut self, now: u64) {
+        self.retries = 0;
+        self.rto_ms = INITIAL_RTO_MS;
+        self.rto_deadline = (self.snd_una != self.snd_max).then_some(now + self.rto_ms);
+    }
+
+    fn enter_time_wait(&mut self, now: u64) {
+        self.state = TcpState::TimeWait;
+        self.rto_deadline = None;
+        self.time_wait_until = now + TIME_WAIT_MS;
+    }
+
+    fn fail(&mut self, error: NetError) {
+        self.state = TcpState::Closed;
+        self.error = Some(error);
+        self.rto_deadline = None;
+        self.tx.clear();
+    }
+
+    /// Give up on a half-open passive connection and listen again.
+    fn relisten(&mut self) {
+        let isn = self.snd_max.wrapping_add(0x0001_0000);
+        *self = Self::listen(self.task, self.local_port, isn, self.mss);
+    }
+}
+
+/// `a` comes before `b` in sequence space.
+fn seq_lt(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) < 0
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+    /// Run one socket's next segment through emit/parse into the other.
+    /// Returns the flags sent, or `None` if there was nothing to send.
+    fn transfer(now: u64, from: &mut TcpSocket, from_ip: Ipv4Addr, to: &mut TcpSocket) -> Option<u8> {
+        let mut packet = [0u8; 600];
+        let (repr, dest, len) = from.dispatch(now, &mut packet)?;
+        let to_ip = dest.addr;
+        let total = repr.emit(from_ip, to_ip, &mut packet, len);
+        let (parsed, payload) = TcpRepr::parse(from_ip, to_ip, &packet[..total]).expect("bad segment");
+        assert_eq!(parsed, repr);
+        to.process(now, from_ip, &parsed, payload);
+        Some(repr.flags)
+    }
+
+    /// Exchange segments until both sides are quiet.
+    fn settle(now: u64, client: &mut TcpSocket, server: &mut TcpSocket) {
+        for _ in 0..64 {
+            let a = transfer(now, client, CLIENT, server);
+            let b = transfer(now, server, SERVER, client);
+            if a.is_none() && b.is_none() {
+                return;
+            }
+        }
+        panic!("sockets never went quiet");
+    }
+
+    fn connected() -> (TcpSocket, TcpSocket) {
+        let mut server = TcpSocket::listen(1, 80, 5000, 512);
+        let mut client = TcpSocket::connect(2, 50000, Endpoint::new(SERVER, 80), u32::MAX - 2, 512);
+        settle(0, &mut client, &mut server);
+        (client, server)
+    }
+
+    #[test]
+    fn handshake_data_and_close() {
+        let (mut client, mut server) = connected();
+        assert_eq!(client.state(), TcpState::Established);
+        assert_eq!(server.state(), TcpState::Established);
+        assert_eq!(server.remote(), Some(Endpoint::new(CLIENT, 50000)));
+
+        // More than one segment's worth, across the sequence number wrap
+        let request: Vec<u8> = (0..900u32).map(|i| i as u8).collect();
+        assert_eq!(client.send(&request).unwrap(), 900);
+        settle(10, &mut client, &mut server);
+        let mut buf = [0u8; 1024];
+        assert_eq!(server.recv(&mut buf).unwrap(), 900);
+        assert_eq!(&buf[..900], &request[..]);
+        assert_eq!(server.recv(&mut buf), Err(NetError::Timeout));
+
+        server.send(b"reply").unwrap();
+        server.close();
+        settle(20, &mut client, &mut server);
+        assert_eq!(client.recv(&mut buf).unwrap(), 5);
+        assert_eq!(client.recv(&mut buf).unwrap(), 0);
+        assert_eq!(client.state(), TcpState::CloseWait);
+        assert_eq!(server.state(), TcpState::FinWait2);
+
+        client.close();
+        settle(30, &mut client, &mut server);
+        assert_eq!(client.state(), TcpState::Closed);
+        assert_eq!(server.state(), TcpState::TimeWait);
+        assert!(server.events().hangup);
+        assert!(server.dispatch(30 + TIME_WAIT_MS, &mut [0; 64]).is_none());
+        assert_eq!(server.state(), TcpState::Closed);
+    }
+
+    #[test]
+    fn lost_segment_is_retransmitted() {
+        let (mut client, mut server) = connected();
+        client.send(b"lost once").unwrap();
+        // The first transmission never arrives
+        assert!(client.dispatch(100, &mut [0; 600]).is_some());
+        settle(100, &mut client, &mut server);
+        assert!(!server.can_recv());
+
+        settle(100 + INITIAL_RTO_MS, &mut client, &mut server);
+        let mut buf = [0u8; 16];
+        assert_eq!(server.recv(&mut buf).unwrap(), 9);
+        assert_eq!(&buf[..9], b"lost once");
+        assert!(client.rto_deadline.is_none());
+    }
+
+    #[test]
+    fn unanswered_connect_times_out() {
+        let mut client = TcpSocket::connect(2, 50000, Endpoint::new(SERVER, 80), 1, 512);
+        let mut now = 0;
+        let mut syns = 0;
+        while client.state() == TcpState::SynSent {
+            if let Some((repr, _, _)) = client.dispatch(now, &mut [0; 64]) {
+                assert_eq!(repr.flags, SYN);
+                syns += 1;
+            }
+            now += 1_000;
+        }
+        assert_eq!(syns, 1 + MAX_RETRIES as usize);
+        assert_eq!(client.send(b"x"), Err(NetError::ConnectionTimedOut));
+        assert!(client.events().hangup);
+    }
+
+    #[test]
+    fn reset_only_in_sequence() {
+        let (mut client, mut server) = connected();
+        let mut rst = TcpRepr { src_port: 80, dst_port: 50000, seq: 0, ack: 0, flags: RST, window: 0, mss: None };
+        rst.seq = client.rcv_nxt.wrapping_add(100);
+        client.process(0, SERVER, &rst, &[]);
+        assert_eq!(client.state(), TcpState::Established);
+
+        rst.seq = client.rcv_nxt;
+        client.process(0, SERVER, &rst, &[]);
+        assert_eq!(client.recv(&mut [0; 8]), Err(NetError::ConnectionReset));
+
+        // Aborting sends the peer a reset
+        server.abort();
+        assert_eq!(transfer(0, &mut server, SERVER, &mut client), Some(RST));
+        assert_eq!(server.state(), TcpState::Closed);
+    }
+
+    #[test]
+    fn full_window_is_probed_and_reopened() {
+        let (mut client, mut server) = connected();
+        let chunk = [0x5Au8; 512];
+        for _ in 0..2 {
+            while client.send(&chunk).unwrap() > 0 {}
+            settle(0, &mut client, &mut server);
+        }
+        // Server buffer full, client still holding the rest
+        assert_eq!(server.rx.free(), 0);
+        assert!(!client.tx.is_empty());
+
+        let mut buf = [0u8; SOCKET_BUFFER];
+        assert_eq!(server.recv(&mut buf).unwrap(), SOCKET_BUFFER);
+        settle(1, &mut client, &mut server);
+        assert!(client.tx.is_empty());
+        assert_eq!(server.recv(&mut buf).unwrap(), SOCKET_BUFFER);
+    }
+}
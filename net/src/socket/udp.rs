@@ -0,0 +1,180 @@
+//! SecureIoTOS net UDP Socket Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Datagram socket bound to one local port.
+//!
+//! Both directions are queues of whole datagrams in a `SOCKET_BUFFER`
+//! ring, each stored behind an 8-byte record header (peer address, peer
+//! port, length). A datagram that does not fit is refused on send and
+//! dropped on receive (`dropped()` counts those), never cut short.
+
+use super::{checksum, pseudo_header_sum, Endpoint, Events, RingBuffer, PROTO_UDP, SOCKET_BUFFER};
+use crate::egress::TaskId;
+use crate::{Ipv4Addr, NetError, NetResult};
+
+/// UDP header length.
+pub(crate) const HEADER_LEN: usize = 8;
+/// Queue record header: peer address, peer port, payload length.
+const RECORD_LEN: usize = 8;
+
+/// Largest payload a `UdpSocket` can queue.
+pub const MAX_DATAGRAM: usize = SOCKET_BUFFER - RECORD_LEN;
+
+/// A UDP socket, reached through `NetworkStack::udp()`.
+pub struct UdpSocket {
+    task: TaskId,
+    port: u16,
+    rx: RingBuffer<SOCKET_BUFFER>,
+    tx: RingBuffer<SOCKET_BUFFER>,
+    dropped: u32,
+}
+
+impl UdpSocket {
+    pub(crate) fn new(task: TaskId, port: u16) -> Self {
+        Self { task, port, rx: RingBuffer::new(), tx: RingBuffer::new(), dropped: 0 }
+    }
+
+    /// Local port the socket is bound to.
+    pub fn local_port(&self) -> u16 {
+        self.port
+    }
+
+    /// Task the socket's traffic is accounted to.
+    pub fn task(&self) -> TaskId {
+        self.task
+    }
+
+    /// Datagrams dropped on arrival because the receive queue was full.
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// Queue `data` for `remote`; it goes out on the next
+    /// `NetworkStack::dispatch()`.
+    ///
+    /// # Returns
+    /// - `Ok(())` once queued
+    /// - `Err(NetError::MalformedPacket)` if `data` is longer than `MAX_DATAGRAM`
+    /// - `Err(NetError::Exhausted)` if the transmit queue has no room for it now
+    pub fn send_to(&mut self, data: &[u8], remote: Endpoint) -> NetResult<()> {
+        if data.len() > MAX_DATAGRAM {
+            return Err(NetError::MalformedPacket);
+        }
+        enqueue(&mut self.tx, remote, data).then_some(()).ok_or(NetError::Exhausted)
+    }
+
+    /// Take the oldest received datagram. A datagram longer than `buf` is
+    /// truncated and the rest of it discarded.
+    ///
+    /// # Returns
+    /// - `Ok((len, sender))`: `len` bytes copied into `buf`
+    /// - `Err(NetError::Timeout)` if nothing has arrived
+    pub fn recv_from(&mut self, buf: &mut [u8]) -> NetResult<(usize, Endpoint)> {
+        dequeue(&mut self.rx, buf).ok_or(NetError::Timeout)
+    }
+
+    /// True if a datagram is waiting.
+    pub fn can_recv(&self) -> bool {
+        !self.rx.is_empty()
+    }
+
+    pub(crate) fn events(&self) -> Events {
+        Events { readable: self.can_recv(), writable: self.tx.free() > RECORD_LEN, hangup: false }
+    }
+
+    /// Queue a datagram that arrived from `sender`.
+    pub(crate) fn deliver(&mut self, sender: Endpoint, payload: &[u8]) {
+        if !enqueue(&mut self.rx, sender, payload) {
+            self.dropped = self.dropped.wrapping_add(1);
+        }
+    }
+
+    /// Destination of the next queued datagram.
+    pub(crate) fn next_dest(&self) -> Option<Endpoint> {
+        let mut record = [0u8; RECORD_LEN];
+        if self.tx.peek(0, &mut record) < RECORD_LEN {
+            return None;
+        }
+        Some(peer(&record))
+    }
+
+    /// Build the next queued datagram, header included, into `packet`.
+    /// Returns the destination and the packet length.
+    pub(crate) fn dispatch(&mut self, src: Ipv4Addr, packet: &mut [u8]) -> Option<(Endpoint, usize)> {
+        let (len, dest) = dequeue(&mut self.tx, &mut packet[HEADER_LEN..])?;
+        let total = emit_header(Endpoint::new(src, self.port), dest, packet, len);
+        Some((dest, total))
+    }
+}
+
+/// Fill in the header of a datagram from `src` to `dst` whose
+/// `payload_len` bytes of payload already follow it in `packet`.
+/// Returns the datagram length.
+pub(crate) fn emit_header(src: Endpoint, dst: Endpoint, packet: &mut [u8], payload_len: usize) -> usize {
+    let total = HEADER_LEN + payload_len;
+    packet[0..2].copy_from_slice(&src.port.to_be_bytes());
+    packet[2..4].copy_from_slice(&dst.port.to_be_bytes());
+    packet[4..6].copy_from_slice(&(total as u16).to_be_bytes());
+    packet[6..8].fill(0);
+    let sum = match checksum(&packet[..total], pseudo_header_sum(src.addr, dst.addr, PROTO_UDP, total)) {
+        // Zero means "no checksum" in UDP
+        0 => 0xFFFF,
+        sum => sum,
+    };
+    packet[6..8].copy_from_slice(&sum.to_be_bytes());
+    total
+}
+
+/// Split a received UDP datagram into (source port, destination port,
+/// payload), checking its length and checksum.
+pub(crate) fn parse(src: Ipv4Addr, dst: Ipv4Addr, datagram: &[u8]) -> Option<(u16, u16, &[u8])> {
+    if datagram.len() < HEADER_LEN {
+        return None;
+    }
+    let len = u16::from_be_bytes([datagram[4], datagram[5]]) as usize;
+    if len < HEADER_LEN || len > datagram.len() {
+        return None;
+    }
+    let datagram = &datagram[..len];
+    let has_checksum = datagram[6] != 0 || datagram[7] != 0;
+    if has_checksum && checksum(datagram, pseudo_header_sum(src, dst, PROTO_UDP, len)) != 0 {
+        return None;
+    }
+    let port = |at: usize| u16::from_be_bytes([datagram[at], datagram[at + 1]]);
+    Some((port(0), port(2), &datagram[HEADER_LEN..]))
+}
+
+fn enqueue(queue: &mut RingBuffer<SOCKET_BUFFER>, peer: Endpoint, data: &[u8]) -> bool {
+    if queue.free() < RECORD_LEN + data.len() {
+        return false;
+    }
+    let mut record = [0u8; RECORD_LEN];
+    record[0..4].copy_from_slice(&peer.addr.octets);
+    record[4..6].copy_from_slice(&peer.port.to_be_bytes());
+    record[6..8].copy_from_slice(&(data.len() as u16).to_be_bytes());
+    queue.push(&record);
+    queue.push(data);
+    true
+}
+
+fn dequeue(queue: &mut RingBuffer<SOCKET_BUFFER>, buf: &mut [u8]) -> Option<(usize, Endpoint)> {
+    let mut record = [0u8; RECORD_LEN];
+    if queue.pop(&mut record) < RECORD_LEN {
+        return None;
+    }
+    let peer = peer(&record);
+    let len = u16::from_be_bytes([record[6], record[7]]) as usize;
+    let copied = len.min(buf.len());
+    queue.pop(&mut buf[..copied]);
+    queue.discard(len - copied);
+    Some((copied, peer))
+}
+
+fn peer(record: &[u8; RECORD_LEN]) -> Endpoint {
+    Endpoint::new(Ipv4Addr::new(record[0], record[1], record[2], record[3]), u16::from_be_bytes([record[4], record[5]]))
+}
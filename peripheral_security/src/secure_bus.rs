@@ -20,19 +20,25 @@
 //! - Nonces are 96-bit (or 24-byte for XChaCha) and MUST be unique per key.
 //! - All sensitive key material is zeroized after use.
 //! - Decryption failures return an error (fail-closed).
+//! - Peripherals must be enrolled in the downstream device registry
+//!   (`secure_storage::enrollment`): `authenticate_peripheral` checks a
+//!   signed challenge at attach, `decrypt_packet_from` drops traffic from
+//!   unknown or revoked devices.
 
 //!
 //! For `no_std` embedded targets, choose `aead` crates and RNG suited to your
 //! platform and swap the RNG / storage backends accordingly.
 
-use crate::hal::bus::{I2c, Spi};
+use hal::bus::{I2c, Spi};
 use chacha20poly1305::aead::{Aead, KeyInit, OsRng};
 use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
 use lazy_static::lazy_static;
 use rand::RngCore;
+use secure_storage::enrollment::{DeviceRegistry, RegistryStore};
+use sios_log::Secret;
 use std::sync::Mutex;
 use thiserror::Error;
-use zeroize::Zeroize;
+use zeroize::{Zeroize, ZeroizeOnDrop};
 
 /// Errors returned by this module
 #[derive(Debug, Error)]
@@ -43,14 +49,13 @@ pub enum BusSecurityError {
     EncryptionFailed,
     #[error("decryption failed or authentication failed")]
     DecryptionFailed,
-    #[error("bus write failed")]
-    BusWriteFailed,
+    #[error("peripheral not enrolled, revoked or failed authentication")]
+    UnauthorizedDevice,
 }
 
 /// Internal session key wrapper which zeroizes on drop
-#[derive(Zeroize)]
-#[zeroize(drop)]
-struct SessionKey([u8; 32]);
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+struct SessionKey(Secret<[u8; 32]>);
 
 lazy_static! {
     /// Global session key storage (Option). Use init/rotate APIs to set.
@@ -68,15 +73,15 @@ const NONCE_LEN: usize = 12; // ChaCha20-Poly1305 uses 12-byte nonces
 /// ECDH handshake (X25519 + HKDF) rather than purely random keys. This helper
 /// is useful for bootstrapping and tests.
 pub fn init_bus_security() {
-    let mut key_bytes = [0u8; 32];
+    let mut key_bytes = Secret::new([0u8; 32]);
     // Use platform RNG; replace with hardware RNG for embedded targets
-    OsRng.fill_bytes(&mut key_bytes);
+    OsRng.fill_bytes(key_bytes.expose_secret_mut());
 
     let mut guard = SESSION_KEY.lock().unwrap();
     *guard = Some(SessionKey(key_bytes));
 
     // Avoid logging secrets; log only state changes
-    log::info!("[SecureIoTOS] Bus security initialized (session key set)");
+    sios_log::info!("Bus security initialized (session key set)");
 }
 
 /// Rotate (replace) the session key with a new random key.
@@ -89,20 +94,16 @@ pub fn rotate_session_key() {
 pub fn clear_session_key() {
     let mut guard = SESSION_KEY.lock().unwrap();
     *guard = None; // previous SessionKey will be zeroized on drop
-    log::info!("[SecureIoTOS] Session key cleared");
+    sios_log::info!("Session key cleared");
 }
 
-/// Retrieve a clone of the session key bytes if initialized.
-/// The clone is returned as a Vec<u8> and MUST be zeroized by the caller
-/// when no longer needed. (We return an owned Vec so callers on different
-/// tasks/threads don't hold the global lock while using the key.)
-fn get_session_key_clone() -> Result<[u8; 32], BusSecurityError> {
+/// Retrieve a clone of the session key if initialized. The copy zeroizes
+/// itself when dropped, on every return path. (We return an owned copy so
+/// callers on different tasks/threads don't hold the global lock while
+/// using the key.)
+fn get_session_key_clone() -> Result<SessionKey, BusSecurityError> {
     let guard = SESSION_KEY.lock().unwrap();
-    if let Some(sk) = guard.as_ref() {
-        Ok(sk.0)
-    } else {
-        Err(BusSecurityError::SessionKeyUninitialized)
-    }
+    guard.clone().ok_or(BusSecurityError::SessionKeyUninitialized)
 }
 
 /// Encrypt and send a single byte over SPI using AEAD.
@@ -110,7 +111,7 @@ fn get_session_key_clone() -> Result<[u8; 32], BusSecurityError> {
 /// Packet format: nonce (12) || ciphertext (len=plaintext_len + tag)
 pub fn encrypt_and_send_spi<T: Spi>(spi: &mut T, plaintext: &[u8]) -> Result<(), BusSecurityError> {
     let key_bytes = get_session_key_clone()?;
-    let key = Key::from_slice(&key_bytes);
+    let key = Key::from_slice(key_bytes.0.expose_secret());
     let aead = ChaCha20Poly1305::new(key);
 
     // generate unique nonce. In many embedded systems prefer an incrementing
@@ -124,17 +125,11 @@ pub fn encrypt_and_send_spi<T: Spi>(spi: &mut T, plaintext: &[u8]) -> Result<(),
         .map_err(|_| BusSecurityError::EncryptionFailed)?;
 
     // Compose packet: nonce || ciphertext
-    // Note: adapt to your HAL write API. Here we assume `write_frame(&[u8])`.
     let mut packet = Vec::with_capacity(NONCE_LEN + ciphertext.len());
     packet.extend_from_slice(&nonce_bytes);
     packet.extend_from_slice(&ciphertext);
 
-    // Send packet; translate bus errors into BusSecurityError::BusWriteFailed
-    spi.write_frame(&packet).map_err(|_| BusSecurityError::BusWriteFailed)?;
-
-    // Zeroize local sensitive copy
-    let mut k = key_bytes;
-    k.zeroize();
+    spi.write(&packet);
 
     Ok(())
 }
@@ -148,7 +143,7 @@ pub fn encrypt_and_send_i2c<T: I2c>(i2c: &mut T, addr: u8, plaintext: &[u8]) ->
 	let key_bytes = get_session_key_clone()?;
 	
 	// wraps the raw bytes into the AEAD key type.
-    let key = Key::from_slice(&key_bytes);
+    let key = Key::from_slice(key_bytes.0.expose_secret());
 	
 	// Uses the ChaCha20-Poly1305 -- uthenticated encryption algorithm.
 	// Provides both confidentiality (encryption) and integrity (authentication tag).
@@ -168,10 +163,7 @@ pub fn encrypt_and_send_i2c<T: I2c>(i2c: &mut T, addr: u8, plaintext: &[u8]) ->
     packet.extend_from_slice(&nonce_bytes);
     packet.extend_from_slice(&ciphertext);
 
-    i2c.write_frame(addr, &packet).map_err(|_| BusSecurityError::BusWriteFailed)?;
-
-    let mut k = key_bytes;
-    k.zeroize();
+    i2c.write(addr, &packet);
 
     Ok(())
 }
@@ -186,7 +178,7 @@ pub fn decrypt_packet(packet: &[u8]) -> Result<Vec<u8>, BusSecurityError> {
     let (nonce_bytes, ciphertext) = packet.split_at(NONCE_LEN);
 
     let key_bytes = get_session_key_clone()?;
-    let key = Key::from_slice(&key_bytes);
+    let key = Key::from_slice(key_bytes.0.expose_secret());
     let aead = ChaCha20Poly1305::new(key);
 
     let nonce = Nonce::from_slice(nonce_bytes);
@@ -194,28 +186,42 @@ pub fn decrypt_packet(packet: &[u8]) -> Result<Vec<u8>, BusSecurityError> {
         .decrypt(nonce, ciphertext)
         .map_err(|_| BusSecurityError::DecryptionFailed)?;
 
-    let mut k = key_bytes;
-    k.zeroize();
-
     Ok(plaintext)
 }
 
-// --- Example helper traits in `crate::hal::bus` (for reference) ---
-// The real HAL in your project will provide concrete implementations.
-//
-// pub trait Spi {
-//     fn write_frame(&mut self, frame: &[u8]) -> Result<(), SpiError>;
-// }
-//
-// pub trait I2c {
-//     fn write_frame(&mut self, addr: u8, frame: &[u8]) -> Result<(), I2cError>;
-// }
-// -----------------------------------------------------------------
+/// Authenticate a peripheral when it attaches to the bus: it must be
+/// enrolled and active in `registry`, and `signature` must be its
+/// signature over our fresh random `challenge`.
+pub fn authenticate_peripheral<S: RegistryStore>(
+    registry: &DeviceRegistry<S>,
+    device_id: &str,
+    challenge: &[u8],
+    signature: &[u8],
+) -> Result<(), BusSecurityError> {
+    registry.verify(device_id, challenge, signature).map_err(|e| {
+        sios_log::warn!("Peripheral {} rejected: {:?}", device_id, sios_log::Dbg(&e));
+        BusSecurityError::UnauthorizedDevice
+    })
+}
+
+/// `decrypt_packet` for a packet from `device_id`, which must still be
+/// enrolled and active (checked before any decryption work).
+pub fn decrypt_packet_from<S: RegistryStore>(
+    registry: &DeviceRegistry<S>,
+    device_id: &str,
+    packet: &[u8],
+) -> Result<Vec<u8>, BusSecurityError> {
+    registry.authorize(device_id).map_err(|_| BusSecurityError::UnauthorizedDevice)?;
+    decrypt_packet(packet)
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// The session key is global: tests that set or clear it take turns
+    static SESSION_KEY_TESTS: Mutex<()> = Mutex::new(());
+
     struct MockSpi {
         last: Vec<u8>,
     }
@@ -226,12 +232,13 @@ mod tests {
         }
     }
 
-    impl crate::hal::bus::Spi for MockSpi {
-        fn write_frame(&mut self, frame: &[u8]) -> Result<(), crate::hal::bus::SpiError> {
+    impl Spi for MockSpi {
+        fn write(&mut self, data: &[u8]) {
             self.last.clear();
-            self.last.extend_from_slice(frame);
-            Ok(())
+            self.last.extend_from_slice(data);
         }
+
+        fn transfer(&mut self, _data: &mut [u8]) {}
     }
 
     struct MockI2c {
@@ -245,17 +252,19 @@ mod tests {
         }
     }
 
-    impl crate::hal::bus::I2c for MockI2c {
-        fn write_frame(&mut self, addr: u8, frame: &[u8]) -> Result<(), crate::hal::bus::I2cError> {
+    impl I2c for MockI2c {
+        fn write(&mut self, addr: u8, data: &[u8]) {
             self.last_addr = Some(addr);
             self.last_frame.clear();
-            self.last_frame.extend_from_slice(frame);
-            Ok(())
+            self.last_frame.extend_from_slice(data);
         }
+
+        fn read(&mut self, _addr: u8, _buffer: &mut [u8]) {}
     }
 
     #[test]
     fn roundtrip_spi_encrypt_decrypt() {
+        let _turn = SESSION_KEY_TESTS.lock().unwrap();
         // initialize
         init_bus_security();
         let mut spi = MockSpi::new();
@@ -272,6 +281,7 @@ mod tests {
 
     #[test]
     fn roundtrip_i2c_encrypt_decrypt() {
+        let _turn = SESSION_KEY_TESTS.lock().unwrap();
         init_bus_security();
         let mut i2c = MockI2c::new();
         let payload = b"iot-data";
@@ -283,4 +293,43 @@ mod tests {
 
         clear_session_key();
     }
+
+    #[test]
+    fn session_key_is_wiped_and_required() {
+        let mut key = SessionKey(Secret::new([0x5a; 32]));
+        key.zeroize();
+        assert_eq!(key.0.expose_secret(), &[0u8; 32]);
+
+        let _turn = SESSION_KEY_TESTS.lock().unwrap();
+        clear_session_key();
+        assert!(matches!(encrypt_and_send_spi(&mut MockSpi::new(), b"x"), Err(BusSecurityError::SessionKeyUninitialized)));
+    }
+
+    #[derive(Default)]
+    struct RamStore(Option<Vec<u8>>);
+
+    impl RegistryStore for RamStore {
+        fn load(&mut self) -> Result<Option<Vec<u8>>, &'static str> {
+            Ok(self.0.clone())
+        }
+        fn save(&mut self, data: &[u8]) -> Result<(), &'static str> {
+            self.0 = Some(data.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn rejects_unenrolled_and_revoked_peripherals() {
+        let mut registry = DeviceRegistry::open(RamStore::default(), 4).unwrap();
+        registry.enroll("imu-0", &[0x02; 33]).unwrap();
+        let packet = [0u8; NONCE_LEN + 17];
+
+        assert!(matches!(decrypt_packet_from(&registry, "imu-9", &packet), Err(BusSecurityError::UnauthorizedDevice)));
+        assert!(matches!(
+            authenticate_peripheral(&registry, "imu-0", b"challenge", &[0u8; 64]),
+            Err(BusSecurityError::UnauthorizedDevice)
+        ));
+        registry.revoke("imu-0").unwrap();
+        assert!(matches!(decrypt_packet_from(&registry, "imu-0", &packet), Err(BusSecurityError::UnauthorizedDevice)));
+    }
 }
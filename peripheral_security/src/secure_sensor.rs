@@ -11,8 +11,8 @@
 //! interrupt-free critical sections. It prevents race conditions
 //! and unauthorized tampering with sensor data.
 
-use crate::hal::gpio::GPIO;
-use cortex_m::interrupt::Mutex;
+use hal::gpio::GPIO;
+use critical_section::Mutex;
 use core::cell::RefCell;
 
 /// SecureSensor structure wraps a GPIO pin
@@ -47,7 +47,7 @@ impl SecureSensor {
     /// - `true` if sensor is active
     /// - `false` if sensor is inactive
     pub fn read_sensor(&self) -> bool {
-        cortex_m::interrupt::free(|cs| {
+        critical_section::with(|cs| {
             *SENSOR_STATE.borrow(cs).borrow()
         })
     }
@@ -60,7 +60,7 @@ impl SecureSensor {
     /// # Parameters
     /// - `value`: Boolean state to set (`true` = active, `false` = inactive).
     pub fn write_sensor(&self, value: bool) {
-        cortex_m::interrupt::free(|cs| {
+        critical_section::with(|cs| {
             *SENSOR_STATE.borrow(cs).borrow_mut() = value;
         });
     }
@@ -72,18 +72,19 @@ impl SecureSensor {
 /// - Sets secure access policy
 /// - Initializes default sensor state to inactive (`false`)
 pub fn init_sensor() {
-    cortex_m::interrupt::free(|cs| {
+    critical_section::with(|cs| {
         // Reset global state to "inactive"
         *SENSOR_STATE.borrow(cs).borrow_mut() = false;
     });
 
-    // Example: attach a default GPIO pin for the first secure sensor
-    let default_sensor = SecureSensor::new(GPIO::new(0));
+    // Example: attach a default GPIO pin for the first secure sensor (the
+    // board support package supplies the real port address)
+    let default_sensor = SecureSensor::new(GPIO { port: 0, pin: 0 });
 
     // Simulate initial secure configuration (placeholder)
-    println!(
-        "[SecureIoTOS] Secure sensor initialized on GPIO pin {} with state = {}",
-        default_sensor.pin.id,
+    sios_log::info!(
+        "Secure sensor initialized on GPIO pin {} with state = {}",
+        default_sensor.pin.pin,
         default_sensor.read_sensor()
     );
 }
@@ -9,12 +9,29 @@
 //!
 //! Provides a single-producer, single-consumer (SPSC) message queue
 //! using `heapless::spsc::Queue`. Designed for no_std and embedded environments.
+//!
+//! # Notes
+//! - Capacity: 16 messages of type `u32`.
+//! - `static mut` is used for global queue; access must be safe (interrupt-free or critical section).
+//! - Optional: wrap send/receive in `Result` for overflow/empty detection in production.
+
+// Core kernel path: must not panic (see `tools/no-panic-check`).
+#![cfg_attr(not(test), deny(
+    clippy::panic,
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::indexing_slicing,
+    clippy::unreachable,
+    clippy::todo,
+    clippy::unimplemented
+))]
 
 // Queue → lock-free SPSC queue with fixed capacity.
 use heapless::spsc::Queue;
-// interrupt::free → disables interrupts temporarily for 
-// safe access (since static mut is not thread-safe).
-use cortex_m::interrupt;
+// critical_section::with → disables interrupts temporarily for
+// safe access (since static mut is not thread-safe); host tests run it
+// under a std mutex instead.
+use critical_section as cs;
 
 // Global SPSC message queue (u32, capacity 16)
 // static mut → global mutable storage (dangerous in Rust, requires unsafe).
@@ -26,20 +43,14 @@ static mut MSG_QUEUE: Queue<u32, 16> = Queue::new();
 ///
 /// Resets the queue to empty. Should be called at system startup
 /// before any send/receive operations.
-
-// Writing |_| means: 
-// "This closure takes one argument, but I don’t care about its name or value."
-// Using |_| because I don’t need the CriticalSection handle itself — 
-// I only need the guarantee that the closure runs with interrupts disabled.
 pub fn init_queue() {
-    interrupt::free(|_| unsafe { MSG_QUEUE.reset() });
+    // Writing |_| means: 
+    // "This closure takes one argument, but I don’t care about its name or value."
+    // Using |_| because I don’t need the CriticalSection handle itself — 
+    // I only need the guarantee that the closure runs with interrupts disabled.
+    cs::with(|_| unsafe { *core::ptr::addr_of_mut!(MSG_QUEUE) = Queue::new() });
 }
 
-//!
-//! # Notes
-//! - Capacity: 16 messages of type `u32`.
-//! - `static mut` is used for global queue; access must be safe (interrupt-free or critical section).
-//! - Optional: wrap send/receive in `Result` for overflow/empty detection in production.
 
 /// Send a message to the queue.
 ///
@@ -47,9 +58,10 @@ pub fn init_queue() {
 /// * `msg` - u32 message to enqueue
 ///
 /// Returns `Ok(())` if enqueued successfully, or `Err(())` if the queue is full.
+#[allow(clippy::result_unit_err)] // a full queue is the only failure
 pub fn send_message(msg: u32) -> Result<(), ()> {
-    interrupt::free(|_| unsafe {
-        MSG_QUEUE.enqueue(msg).map_err(|_| ())
+    cs::with(|_| unsafe {
+        (*core::ptr::addr_of_mut!(MSG_QUEUE)).enqueue(msg).map_err(|_| ())
     })
 }
 
@@ -57,7 +69,7 @@ pub fn send_message(msg: u32) -> Result<(), ()> {
 ///
 /// Returns `Some(u32)` if a message was available, or `None` if the queue is empty.
 pub fn receive_message() -> Option<u32> {
-    interrupt::free(|_| unsafe { MSG_QUEUE.dequeue() })
+    cs::with(|_| unsafe { (*core::ptr::addr_of_mut!(MSG_QUEUE)).dequeue() })
 }
 
 #[cfg(test)]
@@ -9,7 +9,11 @@
 /// Core modules of SecureIoTOS
 pub mod scheduler; // Task scheduler (e.g., round-robin)
 pub mod ipc;       // Inter-process communication (message queue)
-pub mod tasks;     // Task management (task structures and context switching)
+pub mod tasks;     // Task management (creation/teardown, context switching)
+pub mod trace;       // Wake-latency tracing of ISR-boosted tasks
+
+#[cfg(test)]
+mod model;         // Host model checking of scheduler invariants
 
 /// Initialize the SecureIoTOS system.
 ///
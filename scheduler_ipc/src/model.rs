@@ -0,0 +1,328 @@
+//! SecureIoTOS Scheduler Model Tests
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Model-based tests of the scheduler invariants.
+//!
+//! A small state-space explorer drives the real `Scheduler` with every
+//! sequence of events (scheduling step, current task blocks, some task is
+//! woken, suspended or resumed) up to a bounded depth, next to a reference model of what the
+//! scheduler *should* do. States are deduplicated, so for small task
+//! counts the reachable state space is covered exhaustively.
+//!
+//! Invariants checked after every event:
+//!
+//! - **no task lost**: every task is runnable, blocked or suspended,
+//!   exactly as the reference model says, and no wakeup is dropped;
+//! - **no double-run**: at most the current task is `Running`, and after
+//!   a scheduling step it is runnable, unless no task is (idle);
+//! - **round-robin budget**: a runnable task waits at most `n - 1`
+//!   scheduling steps before it runs.
+//!
+//! Bugs found here get a named regression test at the bottom.
+
+use std::collections::{HashSet, VecDeque};
+
+use crate::scheduler::Scheduler;
+use crate::tasks::{Task, TaskState};
+
+/// Largest task count explored exhaustively.
+const MAX_TASKS: usize = 4;
+
+/// First task ID; IDs are deliberately not equal to table indices.
+const ID_BASE: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Event {
+    /// PendSV / SysTick: pick the next task
+    Schedule,
+    /// The running task parks on a wait list
+    Block,
+    /// Some context wakes task `idx`
+    Wake(usize),
+    /// Some context suspends task `idx`
+    Suspend(usize),
+    /// Some context resumes task `idx`
+    Resume(usize),
+}
+
+/// Reference model: what the scheduler state should be.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Model {
+    n: usize,
+    current: usize,
+    blocked: Vec<bool>,
+    suspended: Vec<bool>,
+    /// Wakeup delivered before the task parked
+    pending: Vec<bool>,
+    /// Scheduling steps each runnable task has waited since it last ran
+    waited: Vec<usize>,
+}
+
+impl Model {
+    fn new(n: usize) -> Self {
+        Self {
+            n,
+            current: 0,
+            blocked: vec![false; n],
+            suspended: vec![false; n],
+            pending: vec![false; n],
+            waited: vec![0; n],
+        }
+    }
+
+    fn runnable(&self, i: usize) -> bool {
+        !self.blocked[i] && !self.suspended[i]
+    }
+
+    fn none_runnable(&self) -> bool {
+        !(0..self.n).any(|i| self.runnable(i))
+    }
+
+    /// Events that can actually happen in this state.
+    fn enabled(&self) -> Vec<Event> {
+        let mut ev = vec![Event::Schedule];
+        // Only a task that is on the CPU and runnable can park itself.
+        if self.runnable(self.current) {
+            ev.push(Event::Block);
+        }
+        ev.extend((0..self.n).map(Event::Wake));
+        ev.extend((0..self.n).map(Event::Suspend));
+        ev.extend((0..self.n).map(Event::Resume));
+        ev
+    }
+
+    /// Apply `ev`; returns the expected result of `block_current()`.
+    fn apply(&mut self, ev: Event) -> Option<bool> {
+        match ev {
+            Event::Schedule => {
+                let next = (1..=self.n)
+                    .map(|step| (self.current + step) % self.n)
+                    .find(|&i| self.runnable(i));
+                if let Some(next) = next {
+                    self.current = next;
+                }
+                for i in 0..self.n {
+                    if i == self.current || !self.runnable(i) {
+                        self.waited[i] = 0;
+                    } else {
+                        self.waited[i] += 1;
+                    }
+                }
+                None
+            }
+            Event::Block => {
+                let cur = self.current;
+                if std::mem::take(&mut self.pending[cur]) {
+                    Some(false)
+                } else {
+                    self.blocked[cur] = true;
+                    self.waited[cur] = 0;
+                    Some(true)
+                }
+            }
+            Event::Wake(i) => {
+                if self.suspended[i] {
+                    // ignored
+                } else if self.blocked[i] {
+                    self.blocked[i] = false;
+                } else {
+                    self.pending[i] = true;
+                }
+                None
+            }
+            Event::Suspend(i) => {
+                self.suspended[i] = true;
+                self.blocked[i] = false;
+                self.pending[i] = false;
+                self.waited[i] = 0;
+                None
+            }
+            Event::Resume(i) => {
+                self.suspended[i] = false;
+                None
+            }
+        }
+    }
+}
+
+fn scheduler(n: usize) -> Scheduler {
+    let tasks = (0..n as u32)
+        .map(|i| Task { id: ID_BASE + i, privilege: 1, priority: 0, stack_pointer: core::ptr::null_mut(), stack_slot: None, state: TaskState::Ready })
+        .collect();
+    Scheduler::new(tasks)
+}
+
+fn id(idx: usize) -> u32 {
+    ID_BASE + idx as u32
+}
+
+/// Apply one event to both the scheduler and the model and check every
+/// invariant. Panics with the event trace on the first violation.
+fn step(s: &mut Scheduler, m: &mut Model, ev: Event, trace: &[Event]) {
+    let expect_block = m.apply(ev);
+    let m: &Model = m;
+    let fail = |what: &str| -> ! { panic!("{what} after {trace:?} + {ev:?}\nmodel: {m:?}") };
+
+    match ev {
+        Event::Schedule => {
+            let prev = s.current_id();
+            let moved = s.advance();
+            match moved {
+                Some((p, n)) if id(p) != prev || p == n => fail("advance() reported a bogus switch"),
+                None if s.current_id() != prev => fail("current changed without a switch"),
+                _ => {}
+            }
+        }
+        Event::Block => {
+            if Some(s.block_current()) != expect_block {
+                fail("block_current() disagrees with the model (lost or spurious wakeup)");
+            }
+        }
+        Event::Wake(i) => s.wake(id(i)),
+        Event::Suspend(i) => {
+            if !s.suspend(id(i)) {
+                fail("suspend() rejected a live task");
+            }
+        }
+        Event::Resume(i) => {
+            if !s.resume(id(i)) {
+                fail("resume() rejected a live task");
+            }
+        }
+    }
+
+    // No task lost: blocked/suspended/runnable state matches for every task.
+    for i in 0..m.n {
+        if s.is_blocked(id(i)) != m.blocked[i] {
+            fail("task blocked state diverged from the model");
+        }
+        if (s.state(id(i)) == Some(TaskState::Suspended)) != m.suspended[i] {
+            fail("task suspended state diverged from the model");
+        }
+    }
+    if s.current_id() != id(m.current) {
+        fail("running task diverged from the model");
+    }
+    // No double-run: only the current task may be `Running` ...
+    if (0..m.n).any(|i| i != m.current && s.state(id(i)) == Some(TaskState::Running)) {
+        fail("a task other than the current one is Running");
+    }
+    // ... and a non-runnable task only keeps the CPU while idle.
+    if ev == Event::Schedule {
+        if !m.runnable(m.current) && !m.none_runnable() {
+            fail("blocked or suspended task still running although another is runnable");
+        }
+        if m.runnable(m.current) && s.state(id(m.current)) != Some(TaskState::Running) {
+            fail("task on the CPU is not marked Running");
+        }
+    }
+    // Round-robin budget.
+    if let Some(i) = (0..m.n).find(|&i| m.waited[i] > m.n.saturating_sub(1)) {
+        fail(&format!("task {i} starved for {} steps", m.waited[i]));
+    }
+}
+
+/// Breadth-first exploration of every reachable state of an `n`-task
+/// scheduler, up to `depth` events. Returns the number of distinct states.
+fn explore(n: usize, depth: usize) -> usize {
+    let mut seen = HashSet::new();
+    let mut queue = VecDeque::new();
+    let m = Model::new(n);
+    seen.insert(m.clone());
+    queue.push_back((scheduler(n), m, Vec::new()));
+
+    while let Some((s, m, trace)) = queue.pop_front() {
+        if trace.len() == depth {
+            continue;
+        }
+        for ev in m.enabled() {
+            let (mut s2, mut m2) = (s.clone(), m.clone());
+            step(&mut s2, &mut m2, ev, &trace);
+            if seen.insert(m2.clone()) {
+                let mut t2 = trace.clone();
+                t2.push(ev);
+                queue.push_back((s2, m2, t2));
+            }
+        }
+    }
+    seen.len()
+}
+
+/// Run a fixed event sequence through the checker.
+fn replay(n: usize, events: &[Event]) -> (Scheduler, Model) {
+    let (mut s, mut m) = (scheduler(n), Model::new(n));
+    for (k, &ev) in events.iter().enumerate() {
+        step(&mut s, &mut m, ev, &events[..k]);
+    }
+    (s, m)
+}
+
+#[test]
+fn invariants_hold_for_all_reachable_states() {
+    for n in 1..=MAX_TASKS {
+        let states = explore(n, 12);
+        assert!(states > n, "explorer made no progress for {n} tasks");
+    }
+}
+
+#[test]
+fn explorer_detects_a_lost_wakeup() {
+    // Sanity check of the checker itself: a scheduler that forgets the
+    // wakeup token must be caught.
+    let result = std::panic::catch_unwind(|| {
+        let (mut s, mut m) = (scheduler(2), Model::new(2));
+        step(&mut s, &mut m, Event::Wake(0), &[]);
+        s.block_current(); // consume the token behind the model's back
+        step(&mut s, &mut m, Event::Block, &[Event::Wake(0)]);
+    });
+    assert!(result.is_err());
+}
+
+// ---------------------------
+// Regression cases
+// ---------------------------
+
+use Event::*;
+
+#[test]
+fn regression_wake_before_park_is_not_lost() {
+    // Woken between "queue full" and parking: the park must return at once.
+    let (s, _) = replay(2, &[Wake(0), Block, Schedule]);
+    assert_eq!(s.current_id(), id(1));
+    assert!(!s.is_blocked(id(0)));
+}
+
+#[test]
+fn regression_all_blocked_idles_then_resumes_in_order() {
+    let (s, m) = replay(3, &[Block, Schedule, Block, Schedule, Block, Schedule, Wake(0), Schedule]);
+    assert!(!m.none_runnable());
+    assert_eq!(s.current_id(), id(0));
+}
+
+#[test]
+fn regression_empty_task_table_does_not_divide_by_zero() {
+    let mut s = scheduler(0);
+    assert_eq!(s.advance(), None);
+    s.wake(id(0));
+    assert!(!s.block_current());
+}
+
+#[test]
+fn regression_single_task_never_switches_to_itself() {
+    let (mut s, _) = replay(1, &[Schedule, Schedule]);
+    assert_eq!(s.advance(), None);
+}
+
+#[test]
+fn regression_suspend_while_blocked_drops_the_wait() {
+    // Suspended on a wait list, woken, then resumed: the wakeup is not
+    // remembered and the task runs (and re-checks its condition) once.
+    let (s, m) = replay(2, &[Block, Schedule, Suspend(0), Wake(0), Resume(0), Schedule]);
+    assert_eq!(s.current_id(), id(0));
+    assert!(!m.pending[0]);
+}
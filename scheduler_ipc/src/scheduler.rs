@@ -6,8 +6,23 @@
 //! URL: https://m-a-h-b-u-b.github.io
 //! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
 
-use crate::tasks::Task;
-use crate::tasks::context_switch;
+// Core kernel path: must not panic (see `tools/no-panic-check`).
+#![cfg_attr(not(test), deny(
+    clippy::panic,
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::indexing_slicing,
+    clippy::unreachable,
+    clippy::todo,
+    clippy::unimplemented
+))]
+
+use crate::tasks::{Task, TaskState};
+use crate::tasks::{context_switch, free_stack};
+use crate::trace;
+use core::cell::RefCell;
+use cortex_m::interrupt::{self, Mutex};
+use ipc::wait::{self, ParkerHooks, TaskId};
 
 /// A simple round-robin task scheduler.
 ///
@@ -15,11 +30,24 @@ use crate::tasks::context_switch;
 /// task a chance to run. After reaching the last task, it wraps around
 /// to the first one again. This approach ensures fairness but does not
 /// consider task priority or deadlines.
+///
+/// The one exception is the ISR wake fast path: an interrupt that wakes a
+/// task with a higher `priority` than the running one makes that task
+/// run next (`wake_from_isr()`), so a sensor event is handled at the next
+/// exception return instead of after a full round-robin cycle.
+#[cfg_attr(test, derive(Clone))]
 pub struct Scheduler {
     /// List of all tasks managed by the scheduler.
     tasks: Vec<Task>,
     /// Index of the currently running task.
     current: usize,
+    /// Wakeups delivered before the task parked (same index as `tasks`).
+    wake_pending: Vec<bool>,
+    /// Tasks that called `task_exit()` and wait for their stack to be
+    /// reclaimed (same index as `tasks`).
+    exited: Vec<bool>,
+    /// Task an ISR woke to run next, with the trace clock at the wakeup
+    boost: Option<(TaskId, Option<u32>)>,
 }
 
 impl Scheduler {
@@ -27,8 +55,70 @@ impl Scheduler {
     ///
     /// The scheduler starts with `current` set to 0, meaning the first
     /// task in the list will run initially.
-    pub fn new(tasks: Vec<Task>) -> Self {
-        Scheduler { tasks, current: 0 }
+    pub fn new(mut tasks: Vec<Task>) -> Self {
+        let n = tasks.len();
+        if let Some(first) = tasks.first_mut() {
+            first.state = TaskState::Running;
+        }
+        Scheduler { tasks, current: 0, wake_pending: vec![false; n], exited: vec![false; n], boost: None }
+    }
+
+    /// Add a task at the end of the round-robin order, in the state it
+    /// carries (normally `Ready`).
+    pub fn add_task(&mut self, task: Task) {
+        self.tasks.push(task);
+        self.wake_pending.push(false);
+        self.exited.push(false);
+    }
+
+    /// Remove task `id` and return it. The running task cannot be removed
+    /// (it must `exit_current()` instead).
+    pub fn remove_task(&mut self, id: TaskId) -> Option<Task> {
+        let i = self.index_of(id)?;
+        if i == self.current {
+            return None;
+        }
+        self.remove_at(i)
+    }
+
+    fn remove_at(&mut self, i: usize) -> Option<Task> {
+        if i >= self.tasks.len() {
+            return None;
+        }
+        self.wake_pending.remove(i);
+        self.exited.remove(i);
+        if i < self.current {
+            self.current -= 1;
+        }
+        Some(self.tasks.remove(i))
+    }
+
+    /// Take the current task off the run queue for good. It keeps running
+    /// until the next switch; `reap()` then frees it.
+    pub fn exit_current(&mut self) {
+        self.exit_at(self.current);
+    }
+
+    fn exit_at(&mut self, i: usize) {
+        if let (Some(t), Some(e)) = (self.tasks.get_mut(i), self.exited.get_mut(i)) {
+            t.state = TaskState::Blocked;
+            *e = true;
+        }
+    }
+
+    /// Drop exited tasks the CPU has switched away from and return their
+    /// stacks to the pool.
+    pub fn reap(&mut self) {
+        let mut i = 0;
+        while i < self.tasks.len() {
+            if i != self.current && self.exited.get(i) == Some(&true) {
+                if let Some(slot) = self.remove_at(i).and_then(|t| t.stack_slot) {
+                    free_stack(slot);
+                }
+            } else {
+                i += 1;
+            }
+        }
     }
 
     /// Perform a scheduling step.
@@ -36,9 +126,355 @@ impl Scheduler {
     /// - Determines the next task index in round-robin order.
     /// - Performs a context switch from the current task to the next task.
     /// - Updates the `current` index to point to the task that is now running.
+    /// - Only `Ready` tasks are switched to; if no other task is ready the
+    ///   current one keeps the CPU (it is expected to idle until an
+    ///   interrupt if it is not runnable itself).
+    /// - Stacks of tasks that exited earlier are reclaimed first.
+    /// - A task boosted by `wake_from_isr()` goes first; its wake latency
+    ///   is recorded in `trace`.
     pub fn schedule(&mut self) {
-        let next = (self.current + 1) % self.tasks.len();
-        context_switch(&self.tasks[self.current], &self.tasks[next]);
-        self.current = next;
+        self.reap();
+        let boost = self.boost;
+        if let Some((prev, next)) = self.advance() {
+            let nxt = self.tasks.get(next).cloned();
+            if let (Some(cur), Some(nxt)) = (self.tasks.get_mut(prev), nxt) {
+                if let Some((id, Some(woken_at))) = boost.filter(|&(id, _)| id == nxt.id) {
+                    trace::record_switch(id, woken_at);
+                }
+                context_switch(cur, &nxt);
+            }
+        }
+    }
+
+    /// Scheduling decision of `schedule()` without the context switch:
+    /// moves `current` to the next runnable task and returns
+    /// `(previous, next)` indices, or `None` if the current task keeps
+    /// the CPU. Split out so the host model tests can drive it.
+    pub(crate) fn advance(&mut self) -> Option<(usize, usize)> {
+        let next = self.take_boost().unwrap_or_else(|| self.next_ready());
+        if next >= self.tasks.len() {
+            return None;
+        }
+        if next == self.current {
+            // Still on the CPU, e.g. woken or resumed before a switch away
+            self.set_state(next, TaskState::Ready, TaskState::Running);
+            return None;
+        }
+        let prev = core::mem::replace(&mut self.current, next);
+        self.set_state(prev, TaskState::Running, TaskState::Ready);
+        self.set_state(next, TaskState::Ready, TaskState::Running);
+        Some((prev, next))
+    }
+
+    /// Move task `i` from state `from` to `to`; no effect in any other state.
+    fn set_state(&mut self, i: usize, from: TaskState, to: TaskState) {
+        if let Some(t) = self.tasks.get_mut(i).filter(|t| t.state == from) {
+            t.state = to;
+        }
+    }
+
+    /// Index of the next runnable task in round-robin order.
+    fn next_ready(&self) -> usize {
+        let n = self.tasks.len();
+        (1..=n)
+            .map(|step| (self.current + step) % n)
+            .find(|&i| self.tasks.get(i).is_some_and(|t| matches!(t.state, TaskState::Ready | TaskState::Running)))
+            .unwrap_or(self.current)
+    }
+
+    /// Index of the boosted task if it is still ready; the boost is used
+    /// up either way.
+    fn take_boost(&mut self) -> Option<usize> {
+        let (id, _) = self.boost.take()?;
+        self.index_of(id).filter(|&i| self.tasks.get(i).is_some_and(|t| t.state == TaskState::Ready))
+    }
+
+    fn index_of(&self, id: TaskId) -> Option<usize> {
+        self.tasks.iter().position(|t| t.id == id)
+    }
+
+    /// ID of the currently running task.
+    pub fn current_id(&self) -> TaskId {
+        self.tasks.get(self.current).map_or(0, |t| t.id)
+    }
+
+    /// Mark the current task blocked. Returns `false` (and stays runnable)
+    /// if a wakeup for it arrived in the meantime.
+    pub fn block_current(&mut self) -> bool {
+        let i = self.current;
+        if let Some(pending) = self.wake_pending.get_mut(i) {
+            if core::mem::take(pending) {
+                return false;
+            }
+        }
+        match self.tasks.get_mut(i) {
+            Some(t) => {
+                // A suspended task parks too, but stays suspended
+                if t.state != TaskState::Suspended {
+                    t.state = TaskState::Blocked;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Make task `id` runnable again. If it has not parked yet the wakeup
+    /// is remembered so its next `block_current()` returns at once.
+    /// Suspended and exited tasks ignore wakeups.
+    pub fn wake(&mut self, id: TaskId) {
+        let Some(i) = self.index_of(id) else {
+            return;
+        };
+        if self.exited.get(i) == Some(&true) {
+            return;
+        }
+        match self.tasks.get_mut(i).map(|t| &mut t.state) {
+            Some(state @ TaskState::Blocked) => *state = TaskState::Ready,
+            Some(TaskState::Ready | TaskState::Running) => {
+                if let Some(pending) = self.wake_pending.get_mut(i) {
+                    *pending = true;
+                }
+            }
+            Some(TaskState::Suspended) | None => {}
+        }
+    }
+
+    /// `wake()` for a delivery from interrupt context. If the task was
+    /// blocked and has a higher priority than the running task (and any
+    /// task boosted before), it is boosted to run at the next scheduling
+    /// step; `woken_at` is the trace clock for the latency sample.
+    /// Returns `true` if the caller should pend PendSV now.
+    pub fn wake_from_isr(&mut self, id: TaskId, woken_at: Option<u32>) -> bool {
+        let was_blocked = self.is_blocked(id);
+        self.wake(id);
+        let Some(prio) = self.index_of(id).and_then(|i| self.tasks.get(i)).filter(|_| was_blocked).map(|t| t.priority)
+        else {
+            return false;
+        };
+        let running = self.tasks.get(self.current).filter(|t| t.state == TaskState::Running).map(|t| t.priority);
+        let boosted = self.boost.and_then(|(b, _)| self.index_of(b)).and_then(|i| self.tasks.get(i)).map(|t| t.priority);
+        if running.is_some_and(|p| prio <= p) || boosted.is_some_and(|p| prio <= p) {
+            return false;
+        }
+        self.boost = Some((id, woken_at));
+        true
+    }
+
+    /// Suspend task `id`, whatever it is doing, and drop any wakeup
+    /// pending for it. Returns `false` if there is no such (live) task.
+    /// Suspending the current task takes effect at the next `schedule()`.
+    pub fn suspend(&mut self, id: TaskId) -> bool {
+        let Some(i) = self.index_of(id) else {
+            return false;
+        };
+        if self.exited.get(i) == Some(&true) {
+            return false;
+        }
+        if let (Some(t), Some(pending)) = (self.tasks.get_mut(i), self.wake_pending.get_mut(i)) {
+            t.state = TaskState::Suspended;
+            *pending = false;
+        }
+        true
+    }
+
+    /// Make suspended task `id` ready. Returns `false` if there is no such
+    /// task; other states are left alone.
+    pub fn resume(&mut self, id: TaskId) -> bool {
+        let Some(i) = self.index_of(id) else {
+            return false;
+        };
+        self.set_state(i, TaskState::Suspended, TaskState::Ready);
+        true
+    }
+
+    /// Scheduling state of task `id`.
+    pub fn state(&self, id: TaskId) -> Option<TaskState> {
+        self.index_of(id).and_then(|i| self.tasks.get(i)).map(|t| t.state)
+    }
+
+    /// True if task `id` is parked on a wait list.
+    pub fn is_blocked(&self, id: TaskId) -> bool {
+        self.state(id) == Some(TaskState::Blocked)
+    }
+}
+
+// ---------------------------
+// Wait-list integration
+// ---------------------------
+
+// SAFETY: the raw stack pointers inside `Task` are only dereferenced by the
+// context-switch code, which runs with interrupts disabled on a single core.
+unsafe impl Send for Scheduler {}
+
+/// The scheduler instance IPC wait lists park tasks on.
+static SCHEDULER: Mutex<RefCell<Option<Scheduler>>> = Mutex::new(RefCell::new(None));
+
+static PARKER: ParkerHooks = ParkerHooks {
+    current: park_current_id,
+    park: park_current,
+    unpark: unpark_task,
+};
+
+/// Install `scheduler` as the system scheduler and hook it into the ipc
+/// wait lists, so blocking IPC calls park tasks instead of spinning.
+/// Task-only (called once at startup).
+pub fn install_scheduler(scheduler: Scheduler) {
+    ipc::isr::assert_task_context("install_scheduler");
+    interrupt::free(|cs| {
+        if let Ok(mut slot) = SCHEDULER.borrow(cs).try_borrow_mut() {
+            *slot = Some(scheduler);
+        }
+    });
+    wait::set_parker(&PARKER);
+}
+
+/// Run `f` on the installed scheduler. `None` if there is none or it is
+/// already borrowed.
+pub(crate) fn with_scheduler<R>(f: impl FnOnce(&mut Scheduler) -> R) -> Option<R> {
+    interrupt::free(|cs| SCHEDULER.borrow(cs).try_borrow_mut().ok().and_then(|mut s| s.as_mut().map(f)))
+}
+
+fn park_current_id() -> TaskId {
+    interrupt::free(|cs| {
+        SCHEDULER.borrow(cs).try_borrow().ok().and_then(|s| s.as_ref().map(|s| s.current_id())).unwrap_or(0)
+    })
+}
+
+fn park_current() {
+    let blocked = interrupt::free(|cs| {
+        SCHEDULER
+            .borrow(cs)
+            .try_borrow_mut()
+            .ok()
+            .is_some_and(|mut s| s.as_mut().is_some_and(|s| s.block_current()))
+    });
+    if blocked {
+        // Switch away at the next exception return; we resume here once
+        // another context has woken us and the scheduler picks us again.
+        cortex_m::peripheral::SCB::set_pendsv();
+        cortex_m::asm::dsb();
+        cortex_m::asm::isb();
+    }
+}
+
+/// Wake `id`. From an ISR, a wakeup that boosts the task pends PendSV
+/// right away so the switch happens on exception return.
+fn unpark_task(id: TaskId) {
+    let from_isr = ipc::isr::in_isr();
+    let woken_at = if from_isr { trace::now() } else { None };
+    let switch = interrupt::free(|cs| {
+        let Ok(mut s) = SCHEDULER.borrow(cs).try_borrow_mut() else {
+            return false;
+        };
+        let Some(s) = s.as_mut() else {
+            return false;
+        };
+        if from_isr {
+            s.wake_from_isr(id, woken_at)
+        } else {
+            s.wake(id);
+            false
+        }
+    });
+    if switch {
+        cortex_m::peripheral::SCB::set_pendsv();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sched() -> Scheduler {
+        let task = |id| Task { id, privilege: 1, priority: 0, stack_pointer: core::ptr::null_mut(), stack_slot: None, state: TaskState::Ready };
+        Scheduler::new(vec![task(10), task(11), task(12)])
+    }
+
+    #[test]
+    fn blocked_tasks_are_skipped() {
+        let mut s = sched();
+        assert_eq!(s.next_ready(), 1);
+        s.current = 1;
+        assert!(s.block_current());
+        assert!(s.is_blocked(11));
+        s.current = 0;
+        assert_eq!(s.next_ready(), 2);
+
+        s.wake(11);
+        assert!(!s.is_blocked(11));
+        assert_eq!(s.next_ready(), 1);
+    }
+
+    #[test]
+    fn suspended_tasks_get_no_cpu_until_resumed() {
+        let mut s = sched();
+        assert_eq!(s.state(10), Some(TaskState::Running));
+        assert!(s.suspend(11));
+        assert_eq!(s.advance(), Some((0, 2)));
+        assert_eq!(s.state(10), Some(TaskState::Ready));
+        assert_eq!(s.state(12), Some(TaskState::Running));
+
+        s.wake(11);
+        assert_eq!(s.state(11), Some(TaskState::Suspended), "wakeups do not resume");
+        assert!(s.resume(11));
+        assert_eq!(s.advance(), Some((2, 0)));
+        assert_eq!(s.advance(), Some((0, 1)));
+        assert!(!s.suspend(99) && !s.resume(99));
+    }
+
+    #[test]
+    fn isr_wake_boosts_higher_priority_task_once() {
+        let mut s = sched();
+        if let Some(t) = s.tasks.get_mut(2) {
+            t.priority = 5;
+        }
+        s.current = 2;
+        assert!(s.block_current());
+        s.current = 0;
+
+        assert!(s.wake_from_isr(12, Some(100)));
+        assert_eq!(s.advance(), Some((0, 2)), "boosted task runs next");
+        assert_eq!(s.boost, None);
+        assert_eq!(s.advance(), Some((2, 0)), "then round-robin resumes");
+
+        // Equal priority gets no boost
+        s.current = 1;
+        assert!(s.block_current());
+        s.current = 0;
+        assert!(!s.wake_from_isr(11, None));
+        assert!(!s.is_blocked(11), "still woken");
+        assert!(!s.wake_from_isr(12, Some(1)), "task 12 is not blocked");
+    }
+
+    #[test]
+    fn early_wakeup_is_not_lost() {
+        let mut s = sched();
+        // Woken between "queue full" and parking
+        s.wake(10);
+        assert!(!s.block_current(), "pending wakeup makes park return at once");
+        assert!(s.block_current());
+    }
+
+    #[test]
+    fn add_remove_and_reap_keep_current_task() {
+        let mut s = sched();
+        s.current = 2;
+        assert!(s.remove_task(12).is_none(), "running task cannot be removed");
+        assert_eq!(s.remove_task(10).map(|t| t.id), Some(10));
+        assert_eq!(s.current_id(), 12, "index follows the running task");
+        assert!(s.remove_task(10).is_none());
+
+        s.add_task(Task { id: 13, privilege: 0, priority: 1, stack_pointer: core::ptr::null_mut(), stack_slot: None, state: TaskState::Ready });
+        s.exit_current();
+        assert!(s.is_blocked(12));
+        s.reap();
+        assert_eq!(s.current_id(), 12, "exited task lives until switched away");
+
+        assert_eq!(s.advance(), Some((1, 2)));
+        assert_eq!(s.current_id(), 13);
+        s.reap();
+        assert_eq!(s.tasks.iter().map(|t| t.id).collect::<Vec<_>>(), [11, 13]);
+        assert_eq!(s.current_id(), 13);
     }
 }
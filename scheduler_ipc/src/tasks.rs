@@ -5,66 +5,272 @@
 //! Author: Md Mahbubur Rahman
 //! URL: https://m-a-h-b-u-b.github.io
 //! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Task structures, task creation/teardown and context switching.
+//!
+//! `task_create()` takes a stack from a fixed pool of `MAX_TASK_STACKS`
+//! MPU-guarded slots (`memory::stack::GuardedStackPool`), lays out the initial exception frame so the first switch to the
+//! task "returns" into its entry function, and registers the task with the
+//! installed scheduler. A task ends by calling `task_exit()` (or returning
+//! from its entry function) or by another task calling `task_delete()`.
+//! The stack of an exiting task is still in use until the scheduler has
+//! switched away from it, so it is reclaimed at the next scheduling step.
+//!
+//! Task states:
+//!
+//! ```text
+//!            schedule              block (IPC, sleep)
+//!   Ready  ------------>  Running  ------------------->  Blocked
+//!     ^    <------------                                    |
+//!     |      preempted                                      |
+//!     +------------------------ wake -----------------------+
+//!
+//!   any task  --task_suspend--> Suspended --task_resume--> Ready
+//! ```
+//!
+//! Only `Ready` and `Running` tasks get the CPU. A task suspended while
+//! blocked leaves its wait; once resumed it re-checks the condition it
+//! was waiting for (see `ipc::wait::block_on`).
+
+use core::sync::atomic::{AtomicU32, Ordering};
 
 use cortex_m::register::psp;
+use ipc::wait::TaskId;
+use memory::stack::{init_task_stack, GuardedStackPool, STACK_CANARY};
 
+use crate::scheduler::with_scheduler;
 
 /// Representation of a task in the system.
 ///
 /// Fields:
 /// - `id`: Unique task identifier.
 /// - `privilege`: Privilege level of the task (e.g., 0 = unprivileged, 1 = privileged).
+/// - `priority`: Scheduling priority (higher runs first once the scheduler uses it).
 /// - `stack_pointer`: Pointer to the task's stack frame in memory.
+/// - `stack_slot`: Pool slot owning the stack, `None` for static stacks.
+/// - `state`: Scheduling state, maintained by the scheduler.
 #[derive(Clone)]
 pub struct Task {
     pub id: u32,
     pub privilege: u8,
+    pub priority: u8,
     pub stack_pointer: *mut u32,
+    pub stack_slot: Option<usize>,
+    pub state: TaskState,
+}
+
+/// Scheduling state of a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    /// Runnable, waiting for the CPU
+    Ready,
+    /// On the CPU
+    Running,
+    /// Parked on an IPC wait list or a timer until woken
+    Blocked,
+    /// Taken off the run queue by `task_suspend()` until `task_resume()`
+    Suspended,
 }
 
 /// Initialize example tasks for demonstration purposes.
 ///
 /// Returns a vector containing two tasks with placeholder stack pointers.
-/// In a real system, stack pointers would be set to valid memory regions
-/// allocated for each task.
+/// Real tasks are created with `task_create()`.
 pub fn init_tasks() -> Vec<Task> {
     vec![
-        Task { id: 0, privilege: 0, stack_pointer: 0 as *mut u32 },
-        Task { id: 1, privilege: 1, stack_pointer: 0 as *mut u32 },
+        Task { id: alloc_id(), privilege: 0, priority: 0, stack_pointer: core::ptr::null_mut(), stack_slot: None, state: TaskState::Ready },
+        Task { id: alloc_id(), privilege: 1, priority: 0, stack_pointer: core::ptr::null_mut(), stack_slot: None, state: TaskState::Ready },
     ]
 }
 
+// ---------------------------
+// Task creation and teardown
+// ---------------------------
+
+/// Number of task stacks in the pool.
+pub const MAX_TASK_STACKS: usize = 8;
+
+/// Size of one pool stack in bytes (the largest stack `task_create` accepts).
+pub const TASK_STACK_SIZE: usize = 2048;
+
+/// Words in the initial frame: R4-R11 (software-saved) + R0-R3, R12, LR,
+/// PC, xPSR (hardware-stacked on exception return).
+const FRAME_WORDS: usize = 16;
+
+/// Smallest stack `task_create` accepts: canary, initial frame and room
+/// for the task's first calls.
+pub const MIN_TASK_STACK: usize = STACK_CANARY.len() + FRAME_WORDS * 4 + 128;
+
+/// xPSR with only the Thumb bit set.
+const INITIAL_XPSR: u32 = 0x0100_0000;
+
+/// Entry point of a task. Returning from it ends the task.
+pub type TaskEntry = extern "C" fn();
+
+/// Why a task could not be created or deleted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskError {
+    /// Requested stack is below `MIN_TASK_STACK` or above `TASK_STACK_SIZE`
+    BadStackSize,
+    /// Every pool stack is in use
+    NoStack,
+    /// No scheduler is installed, or its state is borrowed (re-entrant call)
+    NoScheduler,
+    /// No task with that id
+    NotFound,
+}
+
+static STACKS: GuardedStackPool<MAX_TASK_STACKS, TASK_STACK_SIZE> = GuardedStackPool::new();
+
+static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+
+fn alloc_id() -> TaskId {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Return a pool stack. The task that ran on it must never run again.
+pub(crate) fn free_stack(slot: usize) {
+    STACKS.free(slot);
+}
+
+/// Lay out the initial frame at the (8-byte aligned) top of `stack` and
+/// return the saved stack pointer for the task, or `None` if it does not
+/// fit. The first switch pops R4-R11, then exception return pops the
+/// hardware frame and starts `entry` with `LR = exit`.
+fn init_frame(stack: &mut [u8], entry: usize, exit: usize) -> Option<*mut u32> {
+    let base = stack.as_mut_ptr() as usize;
+    let top = (base + stack.len()) & !0x7;
+    let sp = top.checked_sub(FRAME_WORDS * 4)?;
+    let off = sp.checked_sub(base)?;
+    if off < STACK_CANARY.len() {
+        return None;
+    }
+    let mut frame = [0u32; FRAME_WORDS];
+    // [0..8] = R4-R11, [8..12] = R0-R3, [12] = R12
+    frame[13] = exit as u32; // LR
+    frame[14] = entry as u32 & !1; // PC (Thumb bit lives in xPSR)
+    frame[15] = INITIAL_XPSR;
+    let dst = stack.get_mut(off..off + FRAME_WORDS * 4)?;
+    for (d, w) in dst.chunks_exact_mut(4).zip(frame) {
+        d.copy_from_slice(&w.to_ne_bytes());
+    }
+    Some(sp as *mut u32)
+}
+
+/// Landing pad for tasks that return from their entry function.
+extern "C" fn task_return() {
+    task_exit()
+}
+
+/// Create a task running `entry` on a pool stack of `stack_size` bytes and
+/// register it with the scheduler. Returns the new task's id.
+///
+/// The stack is painted and guarded with a canary (`memory::stack`), so
+/// its watermark and overflow status can be checked later.
+/// `stack_size` is rounded up to the pool's guard size. Task-only.
+pub fn task_create(entry: TaskEntry, stack_size: usize, priority: u8, privilege: u8) -> Result<TaskId, TaskError> {
+    ipc::isr::assert_task_context("task_create");
+    if !(MIN_TASK_STACK..=TASK_STACK_SIZE).contains(&stack_size) {
+        return Err(TaskError::BadStackSize);
+    }
+    let guarded = STACKS.alloc(stack_size).ok_or(TaskError::NoStack)?;
+    let (slot, stack) = (guarded.slot, guarded.stack);
+    init_task_stack(stack);
+    let Some(sp) = init_frame(stack, entry as usize, task_return as *const () as usize) else {
+        free_stack(slot);
+        return Err(TaskError::BadStackSize);
+    };
+
+    let id = alloc_id();
+    let task = Task { id, privilege, priority, stack_pointer: sp, stack_slot: Some(slot), state: TaskState::Ready };
+    if with_scheduler(|s| s.add_task(task)).is_none() {
+        free_stack(slot);
+        return Err(TaskError::NoScheduler);
+    }
+    Ok(id)
+}
+
+/// End the calling task. Never returns: the task is taken off the run
+/// queue at once and its stack is reclaimed after the next switch away.
+/// Task-only.
+pub fn task_exit() -> ! {
+    ipc::isr::assert_task_context("task_exit");
+    let _ = with_scheduler(|s| s.exit_current());
+    loop {
+        cortex_m::peripheral::SCB::set_pendsv();
+        cortex_m::asm::dsb();
+        cortex_m::asm::isb();
+        cortex_m::asm::wfi();
+    }
+}
+
+/// Delete task `id`. Deleting the calling task is the same as
+/// `task_exit()`; any other task is removed and its stack freed at once.
+///
+/// The task must not be parked on an IPC wait list, whose slot would
+/// otherwise keep its stale id until the next wakeup. Task-only.
+pub fn task_delete(id: TaskId) -> Result<(), TaskError> {
+    ipc::isr::assert_task_context("task_delete");
+    let removed = with_scheduler(|s| {
+        if s.current_id() == id {
+            return Ok(None);
+        }
+        s.remove_task(id).map(Some).ok_or(TaskError::NotFound)
+    })
+    .ok_or(TaskError::NoScheduler)??;
+    match removed {
+        None => task_exit(),
+        Some(task) => {
+            if let Some(slot) = task.stack_slot {
+                free_stack(slot);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Suspend task `id`: it gets no CPU time, and wakeups are ignored, until
+/// `task_resume()`. Suspending the calling task switches away at once.
+/// ISR-safe (from an ISR, "the calling task" is the one interrupted).
+pub fn task_suspend(id: TaskId) -> Result<(), TaskError> {
+    let is_current = with_scheduler(|s| s.suspend(id).then(|| s.current_id() == id))
+        .ok_or(TaskError::NoScheduler)?
+        .ok_or(TaskError::NotFound)?;
+    if is_current {
+        cortex_m::peripheral::SCB::set_pendsv();
+        cortex_m::asm::dsb();
+        cortex_m::asm::isb();
+    }
+    Ok(())
+}
+
+/// Make a suspended task `id` ready again. Resuming a task that is not
+/// suspended has no effect. ISR-safe.
+pub fn task_resume(id: TaskId) -> Result<(), TaskError> {
+    with_scheduler(|s| s.resume(id))
+        .ok_or(TaskError::NoScheduler)?
+        .then_some(())
+        .ok_or(TaskError::NotFound)
+}
+
 /// Perform a context switch between two tasks.
 ///
 /// This function is responsible for saving the CPU state of the currently
 /// running task and restoring the state of the next task to be executed.
 /// In this example, the functions are placeholders and do not yet manipulate
 /// registers or memory.
-pub fn context_switch(current: &Task, next: &Task) {
+pub fn context_switch(current: &mut Task, next: &Task) {
     save_cpu_state(current);
     restore_cpu_state(next);
 }
 
 /// Save the CPU state of a task by updating its saved stack pointer.
 ///
-/// In a real scheduler, this function would:
-/// - Push registers onto the task's stack.
-/// - Save the Process Stack Pointer (PSP) value.
-/// 
-/// Here, we simulate this by reading PSP and storing it in the Task struct.
+/// The callee-saved registers (R4-R11) are pushed onto the task's stack by
+/// the PendSV handler before it gets here; what is left is recording the
+/// resulting Process Stack Pointer (PSP) in the Task struct.
 fn save_cpu_state(task: &mut Task) {
-    unsafe {
-        // Read Process Stack Pointer (PSP)
-        let current_sp = psp::read();
-        task.stack_pointer = current_sp as *mut u32;
-
-		// push registers onto the stack (R4–R11 at minimum for cooperative multitasking)
-        asm!(
-             "mrs {0}, psp", out(reg) current_sp,
-             "stmdb {0}!, {{r4-r11}}", // push callee-saved regs
-             inout(reg) current_sp => _,
-         );
-    }
+    task.stack_pointer = psp::read() as *mut u32;
 }
 
 /// Restore the CPU state of a task.
@@ -72,3 +278,44 @@ fn save_cpu_state(task: &mut Task) {
 /// In a complete implementation, this would pop registers and status
 /// information from the task’s stack and update the CPU to resume execution.
 fn restore_cpu_state(_task: &Task) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    extern "C" fn entry() {}
+
+    #[test]
+    fn initial_frame_layout() {
+        let mut stack = [0u8; 256];
+        init_task_stack(&mut stack);
+        let base = stack.as_ptr() as usize;
+        let sp = init_frame(&mut stack, 0x0800_1235, 0x0800_2001).unwrap() as usize;
+        assert_eq!(sp % 8, 0, "AAPCS stack alignment");
+        assert!(base + stack.len() - sp <= FRAME_WORDS * 4 + 7);
+
+        let word = |i: usize| {
+            let o = sp - base + i * 4;
+            u32::from_ne_bytes(stack[o..o + 4].try_into().unwrap())
+        };
+        assert_eq!(word(0), 0, "R4");
+        assert_eq!(word(13), 0x0800_2001, "LR = exit trampoline");
+        assert_eq!(word(14), 0x0800_1234, "PC without Thumb bit");
+        assert_eq!(word(15), INITIAL_XPSR);
+        assert_eq!(&stack[..STACK_CANARY.len()], &STACK_CANARY, "canary intact");
+
+        assert!(init_frame(&mut [0u8; FRAME_WORDS * 4], 1, 1).is_none(), "no room for canary");
+    }
+
+    #[test]
+    fn bad_sizes_are_rejected_without_leaking_stacks() {
+        assert_eq!(task_create(entry, MIN_TASK_STACK - 1, 0, 0), Err(TaskError::BadStackSize));
+        assert_eq!(task_create(entry, TASK_STACK_SIZE + 1, 0, 0), Err(TaskError::BadStackSize));
+        assert_eq!(STACKS.in_use(), 0);
+
+        let slot = STACKS.alloc(MIN_TASK_STACK).unwrap().slot;
+        free_stack(slot);
+        assert_eq!(STACKS.alloc(MIN_TASK_STACK).map(|g| g.slot), Some(slot), "freed stack is reused");
+        free_stack(slot);
+    }
+}
@@ -0,0 +1,167 @@
+//! SecureIoTOS Scheduler Trace Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Wake-latency tracing for interrupt-originated IPC deliveries.
+//!
+//! When an ISR wakes a task that outranks the running one, the scheduler
+//! boosts it to run next (see `Scheduler::wake_from_isr()`). The time
+//! from that wakeup to the task being switched in is recorded here, in
+//! ticks of the clock installed with `set_trace_clock()` (typically the
+//! DWT cycle counter). Nothing is measured until a clock is set.
+//!
+//! `wake_latency()` gives min/max/mean over all samples since the last
+//! `reset_wake_latency()`; `drain_wake_samples()` hands out the most
+//! recent individual samples, like `hal::trace::drain_trace()`.
+
+// Core kernel path: must not panic (see `tools/no-panic-check`).
+#![cfg_attr(not(test), deny(
+    clippy::panic,
+    clippy::unwrap_used,
+    clippy::expect_used,
+    clippy::indexing_slicing,
+    clippy::unreachable,
+    clippy::todo,
+    clippy::unimplemented
+))]
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use cortex_m::interrupt::{self, Mutex};
+use ipc::wait::TaskId;
+
+/// Number of individual samples kept (oldest are overwritten).
+pub const WAKE_TRACE_DEPTH: usize = 16;
+
+/// One boosted wakeup: ISR wake to switch-in of `task`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WakeSample {
+    pub task: TaskId,
+    /// Clock ticks between the wakeup and the switch
+    pub latency: u32,
+}
+
+/// Summary of the wake latencies recorded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WakeLatencyStats {
+    pub samples: u32,
+    pub min: u32,
+    pub max: u32,
+    pub mean: u32,
+    pub last: u32,
+}
+
+/// Sample ring and running statistics.
+pub(crate) struct WakeTrace {
+    ring: [Option<WakeSample>; WAKE_TRACE_DEPTH],
+    head: usize,
+    stats: WakeLatencyStats,
+    /// Sum of all latencies, for the mean
+    total: u64,
+}
+
+impl WakeTrace {
+    pub(crate) const fn new() -> Self {
+        Self { ring: [None; WAKE_TRACE_DEPTH], head: 0, stats: WakeLatencyStats { samples: 0, min: 0, max: 0, mean: 0, last: 0 }, total: 0 }
+    }
+
+    pub(crate) fn record(&mut self, sample: WakeSample) {
+        if let Some(slot) = self.ring.get_mut(self.head) {
+            *slot = Some(sample);
+        }
+        self.head = (self.head + 1) % WAKE_TRACE_DEPTH;
+
+        let s = &mut self.stats;
+        let latency = sample.latency;
+        s.min = if s.samples == 0 { latency } else { s.min.min(latency) };
+        s.max = s.max.max(latency);
+        s.last = latency;
+        s.samples = s.samples.saturating_add(1);
+        self.total = self.total.saturating_add(u64::from(latency));
+        s.mean = (self.total / u64::from(s.samples)) as u32;
+    }
+
+    pub(crate) fn drain(&mut self, mut f: impl FnMut(&WakeSample)) {
+        let head = self.head;
+        for i in 0..WAKE_TRACE_DEPTH {
+            if let Some(sample) = self.ring.get_mut((head + i) % WAKE_TRACE_DEPTH).and_then(Option::take) {
+                f(&sample);
+            }
+        }
+        self.head = 0;
+    }
+}
+
+static TRACE: Mutex<RefCell<WakeTrace>> = Mutex::new(RefCell::new(WakeTrace::new()));
+
+/// Installed clock (`fn() -> u32` as usize), 0 if none.
+static CLOCK: AtomicUsize = AtomicUsize::new(0);
+
+/// Install the timestamp source for wake latencies (e.g. DWT CYCCNT).
+pub fn set_trace_clock(clock: fn() -> u32) {
+    CLOCK.store(clock as usize, Ordering::Release);
+}
+
+/// Current trace clock value, `None` without a clock. ISR-safe.
+pub(crate) fn now() -> Option<u32> {
+    let raw = CLOCK.load(Ordering::Acquire);
+    if raw == 0 {
+        return None;
+    }
+    // SAFETY: only ever stored from a `fn() -> u32` in `set_trace_clock()`.
+    let clock: fn() -> u32 = unsafe { core::mem::transmute::<usize, fn() -> u32>(raw) };
+    Some(clock())
+}
+
+/// Record the switch-in of `task`, boosted by a wakeup at `woken_at`.
+pub(crate) fn record_switch(task: TaskId, woken_at: u32) {
+    let Some(now) = now() else {
+        return;
+    };
+    let sample = WakeSample { task, latency: now.wrapping_sub(woken_at) };
+    interrupt::free(|cs| TRACE.borrow(cs).borrow_mut().record(sample));
+}
+
+/// Wake-latency statistics since the last reset.
+pub fn wake_latency() -> WakeLatencyStats {
+    interrupt::free(|cs| TRACE.borrow(cs).borrow().stats)
+}
+
+/// Clear statistics and samples.
+pub fn reset_wake_latency() {
+    interrupt::free(|cs| *TRACE.borrow(cs).borrow_mut() = WakeTrace::new());
+}
+
+/// Hand every buffered sample to `f`, oldest first, and empty the buffer.
+/// `f` runs inside a critical section.
+pub fn drain_wake_samples(f: impl FnMut(&WakeSample)) {
+    interrupt::free(|cs| TRACE.borrow(cs).borrow_mut().drain(f));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_and_ring_follow_samples() {
+        let mut t = WakeTrace::new();
+        for (i, latency) in [120, 80, 400].into_iter().enumerate() {
+            t.record(WakeSample { task: i as u32, latency });
+        }
+        assert_eq!(t.stats, WakeLatencyStats { samples: 3, min: 80, max: 400, mean: 200, last: 400 });
+
+        for n in 0..WAKE_TRACE_DEPTH as u32 {
+            t.record(WakeSample { task: 100 + n, latency: 1 });
+        }
+        let mut drained = Vec::new();
+        t.drain(|s| drained.push(s.task));
+        assert_eq!(drained.len(), WAKE_TRACE_DEPTH);
+        assert_eq!(drained.first(), Some(&100), "oldest surviving sample first");
+        t.drain(|_| panic!("ring is empty"));
+        assert_eq!(t.stats.min, 1);
+    }
+}
@@ -0,0 +1,457 @@
+//! SecureIoTOS CoAP Block-wise Module
+//! ----------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source / commercial use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/SecureIoTOS>
+//!
+//! Block-wise transfers (RFC 7959), so CoAP bodies larger than one
+//! datagram (firmware images, bulk telemetry) are moved block by block.
+//!
+//! - Block1 carries a request body, Block2 a response body, in blocks of
+//!   16 to 1024 bytes (a power of two).
+//! - Client side, [`transfer`] sends the request body in Block1 blocks,
+//!   each acknowledged with 2.31 Continue, then fetches the rest of the
+//!   response with Block2 requests. It switches to smaller blocks when
+//!   the server asks for them.
+//! - Server side, [`BlockServer`] reassembles uploads per peer and path
+//!   and serves large responses block by block from a cache.
+//!
+//! Bodies are capped at [`MAX_BODY_SIZE`] on both sides; the server also
+//! keeps at most [`MAX_TRANSFERS`] transfers of each kind, each for at
+//! most [`TRANSFER_TIMEOUT`] after its last block.
+
+use anyhow::{bail, ensure, Context, Result};
+use coap_lite::{CoapOption, MessageClass, Packet, ResponseType};
+use sios_log::{debug, warn, Disp};
+
+use crate::coap::path_of;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Block size used when none is negotiated.
+pub const DEFAULT_BLOCK_SIZE: usize = 512;
+
+/// Largest body reassembled from blocks, in either direction.
+pub const MAX_BODY_SIZE: usize = 4 * 1024 * 1024;
+
+/// Transfers of each kind a [`BlockServer`] keeps at once.
+pub const MAX_TRANSFERS: usize = 16;
+
+/// How long a [`BlockServer`] waits for the next block of a transfer.
+pub const TRANSFER_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Block numbers are 20 bits wide.
+const MAX_BLOCK_NUM: u32 = (1 << 20) - 1;
+
+/// Value of a Block1 or Block2 option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockValue {
+    /// Block number, counted in blocks of this size
+    pub num: u32,
+    /// More blocks follow
+    pub more: bool,
+    /// Size exponent: blocks are `2^(szx + 4)` bytes
+    pub szx: u8,
+}
+
+impl BlockValue {
+    /// Block `num` of `size` bytes, a power of two from 16 to 1024.
+    pub fn new(num: u32, more: bool, size: usize) -> Result<Self> {
+        ensure!(
+            size.is_power_of_two() && (16..=1024).contains(&size),
+            "Invalid CoAP block size {}",
+            size
+        );
+        ensure!(num <= MAX_BLOCK_NUM, "CoAP block number {} out of range", num);
+        Ok(Self { num, more, szx: (size.trailing_zeros() - 4) as u8 })
+    }
+
+    /// Block size in bytes.
+    pub fn size(&self) -> usize {
+        1 << (self.szx + 4)
+    }
+
+    /// Offset of the block in the body.
+    pub fn offset(&self) -> usize {
+        self.num as usize * self.size()
+    }
+
+    /// Encode as an option value (0 to 3 bytes).
+    pub fn encode(&self) -> Vec<u8> {
+        encode_uint(self.num << 4 | (self.more as u32) << 3 | self.szx as u32)
+    }
+
+    /// Decode an option value; `None` if it is malformed or uses the
+    /// reserved size exponent 7.
+    pub fn decode(value: &[u8]) -> Option<Self> {
+        if value.len() > 3 {
+            return None;
+        }
+        let raw = value.iter().fold(0u32, |acc, b| acc << 8 | *b as u32);
+        let szx = (raw & 0x7) as u8;
+        (szx < 7).then_some(Self { num: raw >> 4, more: raw & 0x8 != 0, szx })
+    }
+
+    /// The Block1 or Block2 option of `packet`, if it has a valid one.
+    pub fn of(packet: &Packet, option: CoapOption) -> Option<Self> {
+        packet.get_option(option)?.front().and_then(|value| Self::decode(value))
+    }
+}
+
+/// Encode an unsigned option value in as few bytes as possible.
+fn encode_uint(value: u32) -> Vec<u8> {
+    let bytes = value.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    bytes[skip..].to_vec()
+}
+
+fn decode_uint(packet: &Packet, option: CoapOption) -> Option<usize> {
+    let value = packet.get_option(option)?.front()?;
+    (value.len() <= 4).then(|| value.iter().fold(0usize, |acc, b| acc << 8 | *b as usize))
+}
+
+// ------------------ CLIENT ------------------
+
+/// Run a request whose body may span several blocks.
+///
+/// `template` carries the method, path and options of the request, but
+/// not its body; `exchange` sends one request and waits for its response.
+/// The body goes out in blocks of `block_size` bytes when it does not fit
+/// one, and a response split in Block2 blocks is fetched whole.
+///
+/// # Returns
+/// - The final response, its payload the whole reassembled body
+/// - The server's response as-is if it refuses an upload block (e.g. 4.13)
+/// - An error if a block goes missing, comes out of order or the body
+///   exceeds [`MAX_BODY_SIZE`]
+pub async fn transfer<F, Fut>(
+    template: &Packet,
+    body: &[u8],
+    block_size: usize,
+    mut exchange: F,
+) -> Result<Packet>
+where
+    F: FnMut(Packet) -> Fut,
+    Fut: Future<Output = Result<Packet>>,
+{
+    let mut size = BlockValue::new(0, false, block_size)?.size();
+    let mut response = if body.len() <= size {
+        let mut request = template.clone();
+        request.payload = body.to_vec();
+        exchange(request).await?
+    } else {
+        ensure!(
+            body.len() / size <= MAX_BLOCK_NUM as usize,
+            "Body of {} bytes needs too many {}-byte blocks",
+            body.len(),
+            size
+        );
+        let mut offset = 0;
+        loop {
+            let end = (offset + size).min(body.len());
+            let block = BlockValue::new((offset / size) as u32, end < body.len(), size)?;
+            let mut request = template.clone();
+            request.payload = body[offset..end].to_vec();
+            request.add_option(CoapOption::Block1, block.encode());
+            if offset == 0 {
+                request.add_option(CoapOption::Size1, encode_uint(body.len() as u32));
+            }
+            let response = exchange(request).await?;
+            if !block.more || response.header.code != MessageClass::Response(ResponseType::Continue) {
+                break response;
+            }
+            // The server may ask for smaller blocks
+            let ack = BlockValue::of(&response, CoapOption::Block1)
+                .context("2.31 Continue without a Block1 option")?;
+            size = size.min(ack.size());
+            offset = end;
+        }
+    };
+
+    let Some(mut block) = BlockValue::of(&response, CoapOption::Block2) else {
+        return Ok(response);
+    };
+    ensure!(block.num == 0, "Response starts at block {}", block.num);
+    if let Some(total) = decode_uint(&response, CoapOption::Size2) {
+        ensure!(total <= MAX_BODY_SIZE, "Response body of {} bytes is too large", total);
+    }
+    let mut whole = std::mem::take(&mut response.payload);
+    while block.more {
+        ensure!(whole.len() == block.offset() + block.size(), "Block2 block {} is short", block.num);
+        let mut request = template.clone();
+        let next = BlockValue::new(block.num + 1, false, block.size())?;
+        request.add_option(CoapOption::Block2, next.encode());
+        response = exchange(request).await?;
+        block = BlockValue::of(&response, CoapOption::Block2).context("Response block without a Block2 option")?;
+        if block.offset() != whole.len() {
+            bail!("Block2 block {} out of order, expected offset {}", block.num, whole.len());
+        }
+        ensure!(whole.len() + response.payload.len() <= MAX_BODY_SIZE, "Response body is too large");
+        whole.append(&mut response.payload);
+    }
+    debug!("Received {} bytes in Block2 blocks", whole.len());
+    response.payload = whole;
+    response.clear_option(CoapOption::Block2);
+    response.clear_option(CoapOption::Size2);
+    Ok(response)
+}
+
+// ------------------ SERVER ------------------
+
+/// An upload being assembled.
+struct Partial {
+    body: Vec<u8>,
+    touched: Instant,
+}
+
+/// A response being served block by block.
+struct Cached {
+    response: Packet,
+    touched: Instant,
+}
+
+/// Server-side state of block-wise transfers.
+///
+/// Feed every request to [`incoming`](Self::incoming) before handling
+/// it, and every response to [`outgoing`](Self::outgoing) before sending
+/// it.
+pub struct BlockServer {
+    block_size: usize,
+    uploads: HashMap<(SocketAddr, String), Partial>,
+    downloads: HashMap<(SocketAddr, String), Cached>,
+}
+
+impl Default for BlockServer {
+    fn default() -> Self {
+        Self::new(DEFAULT_BLOCK_SIZE)
+    }
+}
+
+impl BlockServer {
+    /// Serve responses in blocks of at most `block_size` bytes (16 to
+    /// 1024, rounded down to a power of two).
+    pub fn new(block_size: usize) -> Self {
+        let block_size = 1 << block_size.clamp(16, 1024).ilog2();
+        Self { block_size, uploads: HashMap::new(), downloads: HashMap::new() }
+    }
+
+    /// Transfers in progress: (uploads, downloads).
+    pub fn in_progress(&self) -> (usize, usize) {
+        (self.uploads.len(), self.downloads.len())
+    }
+
+    /// Take in a request. Returns the response to send if the request is
+    /// a block the server answers itself: an upload block other than the
+    /// last, a later block of a cached response, or a block it refuses.
+    /// Otherwise the request is left to the handler; after the last upload
+    /// block its payload is the whole body.
+    pub fn incoming(&mut self, peer: SocketAddr, request: &mut Packet) -> Option<Packet> {
+        self.expire();
+        let key = (peer, path_of(request));
+
+        if BlockValue::of(request, CoapOption::Block2).is_some_and(|b| b.num > 0) {
+            if let Some(cached) = self.downloads.get_mut(&key) {
+                cached.touched = Instant::now();
+                let mut response = cached.response.clone();
+                response.header.message_id = request.header.message_id;
+                response.set_token(request.get_token().clone());
+                self.outgoing(peer, request, &mut response);
+                return Some(response);
+            }
+        }
+
+        let block = BlockValue::of(request, CoapOption::Block1)?;
+        if block.num == 0 {
+            if decode_uint(request, CoapOption::Size1).is_some_and(|total| total > MAX_BODY_SIZE) {
+                return Some(too_large(request));
+            }
+            if !self.uploads.contains_key(&key) && self.uploads.len() >= MAX_TRANSFERS {
+                warn!("Refusing upload from {}: too many transfers", Disp(peer));
+                return Some(reply_to(request, ResponseType::ServiceUnavailable));
+            }
+            self.uploads.insert(key.clone(), Partial { body: Vec::new(), touched: Instant::now() });
+        }
+
+        let Some(upload) = self.uploads.get_mut(&key).filter(|u| u.body.len() == block.offset()) else {
+            self.uploads.remove(&key);
+            return Some(reply_to(request, ResponseType::RequestEntityIncomplete));
+        };
+        if block.more && request.payload.len() != block.size() {
+            self.uploads.remove(&key);
+            return Some(reply_to(request, ResponseType::BadRequest));
+        }
+        if upload.body.len() + request.payload.len() > MAX_BODY_SIZE {
+            self.uploads.remove(&key);
+            return Some(too_large(request));
+        }
+        upload.body.extend_from_slice(&request.payload);
+        upload.touched = Instant::now();
+
+        if block.more {
+            let mut response = reply_to(request, ResponseType::Continue);
+            // Ask for our block size if the client's is larger
+            let size = self.block_size.min(block.size());
+            let ack = BlockValue { szx: BlockValue::new(0, true, size).ok()?.szx, ..block };
+            response.add_option(CoapOption::Block1, ack.encode());
+            return Some(response);
+        }
+        let upload = self.uploads.remove(&key)?;
+        debug!("Received {} bytes in Block1 blocks from {}", upload.body.len(), Disp(peer));
+        request.payload = upload.body;
+        request.clear_option(CoapOption::Size1);
+        None
+    }
+
+    /// Prepare a response before it is sent: acknowledge the last upload
+    /// block, and cut a large body into blocks, keeping the rest for the
+    /// client's next Block2 requests.
+    pub fn outgoing(&mut self, peer: SocketAddr, request: &Packet, response: &mut Packet) {
+        self.split(peer, request, response);
+        if let Some(block) = BlockValue::of(request, CoapOption::Block1) {
+            response.add_option(CoapOption::Block1, block.encode());
+        }
+    }
+
+    /// Cut the block the client asked for (the first by default) out of a
+    /// body that does not fit one.
+    fn split(&mut self, peer: SocketAddr, request: &Packet, response: &mut Packet) {
+        let asked = BlockValue::of(request, CoapOption::Block2);
+        let size = asked.map_or(self.block_size, |b| b.size().min(self.block_size));
+        let num = asked.map_or(0, |b| b.num);
+        if num == 0 && response.payload.len() <= size {
+            return;
+        }
+        let key = (peer, path_of(request));
+        let Ok(block) = BlockValue::new(num, false, size) else {
+            return;
+        };
+        if block.offset() >= response.payload.len() {
+            self.downloads.remove(&key);
+            *response = reply_to(request, ResponseType::BadOption);
+            return;
+        }
+
+        let end = (block.offset() + size).min(response.payload.len());
+        let block = BlockValue { more: end < response.payload.len(), ..block };
+        if num == 0 {
+            if self.downloads.contains_key(&key) || self.downloads.len() < MAX_TRANSFERS {
+                let cached = Cached { response: response.clone(), touched: Instant::now() };
+                self.downloads.insert(key.clone(), cached);
+            }
+            response.add_option(CoapOption::Size2, encode_uint(response.payload.len() as u32));
+        }
+        if !block.more {
+            self.downloads.remove(&key);
+        }
+        response.payload = response.payload[block.offset()..end].to_vec();
+        response.add_option(CoapOption::Block2, block.encode());
+    }
+
+    fn expire(&mut self) {
+        self.uploads.retain(|_, p| p.touched.elapsed() < TRANSFER_TIMEOUT);
+        self.downloads.retain(|_, p| p.touched.elapsed() < TRANSFER_TIMEOUT);
+    }
+}
+
+/// An empty response to `request` with `code`.
+pub(crate) fn reply_to(request: &Packet, code: ResponseType) -> Packet {
+    let mut response = Packet::new();
+    response.header.message_id = request.header.message_id;
+    response.set_token(request.get_token().clone());
+    response.header.code = MessageClass::Response(code);
+    response
+}
+
+/// 4.13 with the largest body accepted in Size1.
+fn too_large(request: &Packet) -> Packet {
+    let mut response = reply_to(request, ResponseType::RequestEntityTooLarge);
+    response.add_option(CoapOption::Size1, encode_uint(MAX_BODY_SIZE as u32));
+    response
+}
+
+// ------------------ TESTS ------------------
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coap::{new_request, set_path};
+    use coap_lite::RequestType as Method;
+
+    #[test]
+    fn test_block_value_roundtrip() {
+        let block = BlockValue::new(5, true, 256).unwrap();
+        assert_eq!((block.szx, block.offset()), (4, 1280));
+        assert_eq!(block.encode(), vec![0x5c]);
+        assert_eq!(BlockValue::decode(&block.encode()), Some(block));
+        assert_eq!(BlockValue::decode(&[]), Some(BlockValue { num: 0, more: false, szx: 0 }));
+        assert_eq!(BlockValue::decode(&[0x0f]), None);
+        assert!(BlockValue::new(0, false, 100).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_upload_and_download_in_blocks() {
+        let peer: SocketAddr = "127.0.0.1:5683".parse().unwrap();
+        let mut server = BlockServer::new(64);
+        let template = new_request(Method::Post, "/fw");
+        let image: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+
+        let mut uploaded = Vec::new();
+        let mut exchanges = 0;
+        let response = transfer(&template, &image, 128, |mut request| {
+            exchanges += 1;
+            let reply = server.incoming(peer, &mut request).unwrap_or_else(|| {
+                // Echo the body back, as the demo server does
+                uploaded = request.payload.clone();
+                let mut response = reply_to(&request, ResponseType::Changed);
+                response.payload = request.payload.clone();
+                server.outgoing(peer, &request, &mut response);
+                response
+            });
+            async move { Ok(reply) }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(uploaded, image);
+        assert_eq!(response.payload, image);
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Changed));
+        // 128 bytes, then 64 once the server asks: 1 + 14 up, 15 more down
+        assert_eq!(exchanges, 1 + 14 + 15);
+        assert_eq!(server.in_progress(), (0, 0));
+    }
+
+    #[test]
+    fn test_out_of_order_and_oversized_uploads_are_refused() {
+        let peer: SocketAddr = "127.0.0.1:5683".parse().unwrap();
+        let mut server = BlockServer::default();
+        let block = |num: u32, size1: Option<u32>| {
+            let mut request = Packet::new();
+            set_path(&mut request, "/fw");
+            request.payload = vec![0; 16];
+            request.add_option(CoapOption::Block1, BlockValue::new(num, true, 16).unwrap().encode());
+            if let Some(total) = size1 {
+                request.add_option(CoapOption::Size1, encode_uint(total));
+            }
+            request
+        };
+        let code = |response: Option<Packet>| response.map(|r| r.header.code);
+
+        assert_eq!(code(server.incoming(peer, &mut block(0, None))), Some(MessageClass::Response(ResponseType::Continue)));
+        assert_eq!(server.in_progress(), (1, 0));
+        assert_eq!(
+            code(server.incoming(peer, &mut block(2, None))),
+            Some(MessageClass::Response(ResponseType::RequestEntityIncomplete))
+        );
+        assert_eq!(server.in_progress(), (0, 0));
+
+        let mut oversized = block(0, Some(MAX_BODY_SIZE as u32 + 1));
+        let response = server.incoming(peer, &mut oversized).unwrap();
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::RequestEntityTooLarge));
+        assert_eq!(decode_uint(&response, CoapOption::Size1), Some(MAX_BODY_SIZE));
+    }
+}
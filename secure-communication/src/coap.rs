@@ -8,54 +8,103 @@
 //! GitHub  : <https://github.com/m-a-h-b-u-b/SecureIoTOS>
 //!
 //! Provides both a minimal async CoAP client and server for IoT devices.
-//! Built on UDP + `coap-lite`.
+//! Built on UDP + `coap-lite`. Bodies larger than one block go block-wise
+//! (RFC 7959, see [`crate::blockwise`]) in both directions, and both ends
+//! can protect messages end to end with OSCORE (see [`crate::oscore`]).
 
 use anyhow::{Context, Result};
-use coap_lite::{Packet, RequestType as Method, ResponseType};
-use log::{info, warn, debug};
+use coap_lite::{CoapOption, MessageClass, Packet, RequestType as Method, ResponseType};
+use sios_log::{debug, info, warn, Dbg, Disp};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
 
-/// ------------------ CLIENT ------------------
+use crate::blockwise::{self, BlockServer, DEFAULT_BLOCK_SIZE};
+use crate::oscore::{self, SecurityContext};
+use crate::router::{CoapResponse, CoapRouter};
+use crate::request_manager::{MessageIdAllocator, RequestError, RequestManager};
+use crate::retry::{Backoff, RetryPolicy};
+
+// ------------------ CLIENT ------------------
 
 /// Generic CoAP request (used by GET/POST/PUT/DELETE).
+///
+/// A payload larger than [`DEFAULT_BLOCK_SIZE`] is sent in Block1 blocks,
+/// and a response split in Block2 blocks is fetched whole.
 pub async fn coap_request(
     addr: &str,
     method: Method,
     path: &str,
     payload: Option<&[u8]>,
+) -> Result<Packet> {
+    request_via(addr, method, path, payload, None).await
+}
+
+/// Like [`coap_request`], but every request (or block of one) is protected
+/// with OSCORE under `context`, and so must be every response. The sender
+/// sequence number is reserved in the context's [`oscore::OscoreStore`]
+/// before use; if that fails, nothing is sent.
+pub async fn coap_request_oscore(
+    addr: &str,
+    method: Method,
+    path: &str,
+    payload: Option<&[u8]>,
+    context: &SecurityContext,
+) -> Result<Packet> {
+    request_via(addr, method, path, payload, Some(context)).await
+}
+
+async fn request_via(
+    addr: &str,
+    method: Method,
+    path: &str,
+    payload: Option<&[u8]>,
+    context: Option<&SecurityContext>,
 ) -> Result<Packet> {
     let socket = UdpSocket::bind("0.0.0.0:0")
         .await
         .context("Failed to bind UDP socket")?;
+    let ids = MessageIdAllocator::new(std::process::id() as u16);
 
-    let mut request = Packet::new();
-    request.set_method(method);
-    request.set_path(path);
-    if let Some(data) = payload {
-        request.payload = data.to_vec();
-    }
-
-    let req_bytes = request
-        .to_bytes()
-        .context("Failed to serialize CoAP request")?;
-
-    debug!("Sending {:?} request to {}{}", method, addr, path);
+    debug!("Sending {:?} request to {}{}", Dbg(&method), addr, path);
 
-    socket
-        .send_to(&req_bytes, addr)
-        .await
-        .with_context(|| format!("Failed to send CoAP request to {}", addr))?;
-
-    let mut buf = [0u8; 1500]; // typical UDP MTU
-    let (size, _) = socket
-        .recv_from(&mut buf)
-        .await
-        .context("Failed to receive CoAP response")?;
+    let template = new_request(method, path);
+    let body = payload.unwrap_or_default();
+    blockwise::transfer(&template, body, DEFAULT_BLOCK_SIZE, |mut request| {
+        let socket = &socket;
+        request.header.message_id = ids.next_id();
+        let protected = context.map(|c| c.protect_request(&request)).transpose();
+        async move {
+            let (request, binding) = match protected? {
+                Some((protected, binding)) => (protected, Some(binding)),
+                None => (request, None),
+            };
+            let req_bytes = request
+                .to_bytes()
+                .context("Failed to serialize CoAP request")?;
+            socket
+                .send_to(&req_bytes, addr)
+                .await
+                .with_context(|| format!("Failed to send CoAP request to {}", addr))?;
 
-    let response = Packet::from_bytes(&buf[..size])
-        .context("Failed to parse CoAP response")?;
+            let mut buf = [0u8; 1500]; // typical UDP MTU, above the largest block
+            let (size, _) = socket
+                .recv_from(&mut buf)
+                .await
+                .context("Failed to receive CoAP response")?;
 
-    Ok(response)
+            let response = Packet::from_bytes(&buf[..size]).context("Failed to parse CoAP response")?;
+            match (context, binding) {
+                (Some(context), Some(binding)) => context
+                    .unprotect_response(&response, &binding)
+                    .with_context(|| format!("OSCORE response from {} ({:?})", addr, response.header.code)),
+                _ => Ok(response),
+            }
+        }
+    })
+    .await
 }
 
 /// Shorthand: CoAP GET request.
@@ -78,7 +127,216 @@ pub async fn coap_delete(addr: &str, path: &str) -> Result<Packet> {
     coap_request(addr, Method::Delete, path, None).await
 }
 
-/// ------------------ SERVER ------------------
+// ------------------ CORRELATED CLIENT ------------------
+
+/// Long-lived CoAP client session with request/response correlation.
+///
+/// One background task owns the socket's receive side and routes every
+/// response to the waiting request by token, so several requests can be in
+/// flight at once and each one has its own timeout budget.
+pub struct CoapSession {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    requests: RequestManager<Vec<u8>, Packet>,
+    ids: MessageIdAllocator,
+    receiver: JoinHandle<()>,
+}
+
+impl CoapSession {
+    /// Bind an ephemeral socket and start routing responses from `addr`.
+    pub async fn connect(addr: &str) -> Result<Self> {
+        let socket = Arc::new(
+            UdpSocket::bind("0.0.0.0:0")
+                .await
+                .context("Failed to bind UDP socket")?,
+        );
+        let peer = tokio::net::lookup_host(addr)
+            .await
+            .with_context(|| format!("Failed to resolve {}", addr))?
+            .next()
+            .with_context(|| format!("No address for {}", addr))?;
+
+        let requests = RequestManager::new();
+        let receiver = {
+            let socket = socket.clone();
+            let requests = requests.clone();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 1500];
+                while let Ok((size, from)) = socket.recv_from(&mut buf).await {
+                    if from != peer {
+                        continue;
+                    }
+                    if let Ok(packet) = Packet::from_bytes(&buf[..size]) {
+                        let token = packet.get_token().clone();
+                        requests.complete(&token, packet);
+                    }
+                }
+                requests.cancel_all();
+            })
+        };
+
+        Ok(Self {
+            socket,
+            peer,
+            requests,
+            ids: MessageIdAllocator::new(std::process::id() as u16),
+            receiver,
+        })
+    }
+
+    /// Send a request and wait at most `budget` for the matching response.
+    pub async fn request(
+        &self,
+        method: Method,
+        path: &str,
+        payload: Option<&[u8]>,
+        budget: Duration,
+    ) -> Result<Packet> {
+        let mut request = new_request(method, path);
+        request.payload = payload.unwrap_or_default().to_vec();
+        self.send_once(self.ids.next_id(), request, budget).await
+    }
+
+    /// Send a request, retransmitting on timeout according to `policy`
+    /// (see [`RetryPolicy::coap`]). Retransmissions reuse the message ID and
+    /// token so the server can deduplicate them.
+    pub async fn request_with_retry(
+        &self,
+        method: Method,
+        path: &str,
+        payload: Option<&[u8]>,
+        policy: &RetryPolicy,
+    ) -> Result<Packet> {
+        let mut request = new_request(method, path);
+        request.payload = payload.unwrap_or_default().to_vec();
+        self.exchange(request, policy).await
+    }
+
+    /// Like [`request_with_retry`](Self::request_with_retry), but the
+    /// payload goes in Block1 blocks and the response is fetched in Block2
+    /// blocks when they do not fit one; each block is retransmitted on its
+    /// own. For firmware images and other large bodies.
+    pub async fn request_blockwise(
+        &self,
+        method: Method,
+        path: &str,
+        payload: Option<&[u8]>,
+        policy: &RetryPolicy,
+    ) -> Result<Packet> {
+        let template = new_request(method, path);
+        let body = payload.unwrap_or_default();
+        blockwise::transfer(&template, body, DEFAULT_BLOCK_SIZE, |request| self.exchange(request, policy)).await
+    }
+
+    /// Send `request` under a fresh message ID, retransmitting it on
+    /// timeout according to `policy`.
+    async fn exchange(&self, request: Packet, policy: &RetryPolicy) -> Result<Packet> {
+        let message_id = self.ids.next_id();
+        let mut backoff = Backoff::new(policy.clone());
+        let mut sent = 0;
+        loop {
+            // The first wait is the ACK timeout; each retransmission grows it.
+            let timeout = backoff.jittered(sent);
+            sent += 1;
+            match self.send_once(message_id, request.clone(), timeout).await {
+                Ok(packet) => return Ok(packet),
+                Err(e) if e.downcast_ref::<RequestError>() == Some(&RequestError::Timeout) => {
+                    if !policy.allows(sent) {
+                        return Err(e.context(format!(
+                            "CoAP {:?} {}: no response after {} transmission(s)",
+                            Dbg(method_of(&request)),
+                            path_of(&request),
+                            sent
+                        )));
+                    }
+                    debug!("Retransmitting mid {} to {}", message_id, Disp(self.peer));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn send_once(&self, message_id: u16, mut request: Packet, budget: Duration) -> Result<Packet> {
+        let token = message_id.to_be_bytes().to_vec();
+        request.header.message_id = message_id;
+        request.set_token(token.clone());
+        let req_bytes = request
+            .to_bytes()
+            .context("Failed to serialize CoAP request")?;
+
+        // Register before sending so a fast response cannot be missed.
+        let pending = self.requests.register(token, budget)?;
+        debug!(
+            "Sending {:?} request to {}{} (mid {})",
+            Dbg(method_of(&request)),
+            Disp(self.peer),
+            path_of(&request).as_str(),
+            message_id
+        );
+        self.socket
+            .send_to(&req_bytes, self.peer)
+            .await
+            .with_context(|| format!("Failed to send CoAP request to {}", self.peer))?;
+
+        Ok(pending.await?)
+    }
+
+    /// Number of requests currently awaiting a response.
+    pub fn in_flight(&self) -> usize {
+        self.requests.in_flight()
+    }
+}
+
+impl Drop for CoapSession {
+    fn drop(&mut self) {
+        self.receiver.abort();
+        self.requests.cancel_all();
+    }
+}
+
+// ------------------ PACKETS ------------------
+// coap-lite keeps the method in the header code and the path in Uri-Path
+// options of a `Packet`; these read and write them.
+
+/// A request for `path` without payload.
+pub fn new_request(method: Method, path: &str) -> Packet {
+    let mut request = Packet::new();
+    request.header.code = MessageClass::Request(method);
+    set_path(&mut request, path);
+    request
+}
+
+/// Method of a request; `None` for a response or an empty message.
+pub fn method_of(packet: &Packet) -> Option<Method> {
+    match packet.header.code {
+        MessageClass::Request(method) => Some(method),
+        _ => None,
+    }
+}
+
+/// Path of a request from its Uri-Path segments, e.g. `/sensor/temp`;
+/// `/` without any (RFC 7252 §6.5).
+pub fn path_of(packet: &Packet) -> String {
+    let mut path = String::new();
+    for segment in packet.get_option(CoapOption::UriPath).into_iter().flatten() {
+        path.push('/');
+        path.push_str(&String::from_utf8_lossy(segment));
+    }
+    if path.is_empty() {
+        path.push('/');
+    }
+    path
+}
+
+/// Replace the Uri-Path options of `packet` with the segments of `path`.
+pub fn set_path(packet: &mut Packet, path: &str) {
+    packet.clear_option(CoapOption::UriPath);
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        packet.add_option(CoapOption::UriPath, segment.as_bytes().to_vec());
+    }
+}
+
+// ------------------ SERVER ------------------
 
 /// Minimal async CoAP server.
 ///
@@ -90,7 +348,37 @@ pub async fn coap_delete(addr: &str, path: &str) -> Result<Packet> {
 /// - POST `/sensor/data` → echoes payload
 /// - PUT `/resource` → echoes payload
 /// - DELETE `/resource` → responds with Deleted
+///
+/// Use [`coap_serve`] with a [`CoapRouter`] to serve real resources.
 pub async fn coap_server(bind_addr: &str) -> Result<()> {
+    let mut router = CoapRouter::new();
+    router
+        .get("/sensor/temp", |_| CoapResponse::new(ResponseType::Content, "23.5°C"))
+        .describe("/sensor/temp", "rt=\"temperature\"")
+        .post("/sensor/data", |req| CoapResponse::new(ResponseType::Created, req.payload)) // echo back
+        .put("/resource", |req| CoapResponse::new(ResponseType::Changed, req.payload))
+        .delete("/resource", |_| CoapResponse::empty(ResponseType::Deleted));
+    coap_serve(bind_addr, router).await
+}
+
+/// Serve the resources registered on `router` forever on `bind_addr`.
+///
+/// Uploads and responses larger than [`DEFAULT_BLOCK_SIZE`] go block-wise.
+pub async fn coap_serve(bind_addr: &str, router: CoapRouter) -> Result<()> {
+    serve(bind_addr, router, &[]).await
+}
+
+/// Like [`coap_serve`], but only OSCORE requests from a peer holding one
+/// of `contexts` are served, and their responses are protected. Anything
+/// else gets an unprotected 4.01 (or 4.00/4.02 for a broken OSCORE
+/// message) with the reason as payload. A request is only served once
+/// its context's replay window is saved to its [`oscore::OscoreStore`],
+/// 5.00 otherwise.
+pub async fn coap_serve_oscore(bind_addr: &str, router: CoapRouter, contexts: Vec<SecurityContext>) -> Result<()> {
+    serve(bind_addr, router, &contexts).await
+}
+
+async fn serve(bind_addr: &str, router: CoapRouter, contexts: &[SecurityContext]) -> Result<()> {
     let socket = UdpSocket::bind(bind_addr)
         .await
         .with_context(|| format!("Failed to bind CoAP server on {}", bind_addr))?;
@@ -98,6 +386,7 @@ pub async fn coap_server(bind_addr: &str) -> Result<()> {
     info!("CoAP server listening on {}", bind_addr);
 
     let mut buf = [0u8; 1500];
+    let mut blocks = BlockServer::new(DEFAULT_BLOCK_SIZE);
 
     loop {
         let (size, peer) = socket
@@ -105,50 +394,63 @@ pub async fn coap_server(bind_addr: &str) -> Result<()> {
             .await
             .context("Failed to receive CoAP request")?;
 
-        if let Ok(request) = Packet::from_bytes(&buf[..size]) {
-            debug!("Received request from {}: {:?}", peer, request);
-
-            let mut response = Packet::new();
-            response.header.message_id = request.header.message_id;
-            response.set_token(request.get_token().clone());
+        if let Ok(mut request) = Packet::from_bytes(&buf[..size]) {
+            debug!("Received request from {}: {:?}", Disp(peer), Dbg(&request));
 
-            match request.get_method() {
-                Some(Method::Get) if request.get_path() == ("/sensor/temp") => {
-                    
-					response.header.code = ResponseType::Content.into();
-                    response.payload = b"23.5°C".to_vec();
-                }
-                Some(Method::Post) => {
-                    response.header.code = ResponseType::Created.into();
-                    response.payload = request.payload.clone(); // echo back
-                }
-                Some(Method::Put) => {
-                    response.header.code = ResponseType::Changed.into();
-                    response.payload = request.payload.clone();
+            let response = match unprotect(contexts, &mut request) {
+                Ok(binding) => {
+                    let response = match blocks.incoming(peer, &mut request) {
+                        Some(response) => response,
+                        None => {
+                            let mut response = router.handle(peer, &request);
+                            blocks.outgoing(peer, &request, &mut response);
+                            response
+                        }
+                    };
+                    match binding {
+                        Some((context, binding)) => context.protect_response(&response, &binding),
+                        None => Ok(response),
+                    }
                 }
-                Some(Method::Delete) => {
-                    response.header.code = ResponseType::Deleted.into();
+                Err(e) => {
+                    debug!("Refusing request from {}: {}", Disp(peer), Disp(&e));
+                    let mut response = blockwise::reply_to(&request, e.response_code());
+                    response.payload = e.to_string().into_bytes();
+                    Ok(response)
                 }
-                Some(_) => {
-                    response.header.code = ResponseType::NotFound.into();
-                }
-                None => {
-                    warn!("Request had no method, ignoring.");
-                    response.header.code = ResponseType::MethodNotAllowed.into();
-                }
-            }
+            };
 
-            if let Ok(res_bytes) = response.to_bytes() {
-                socket
-                    .send_to(&res_bytes, peer)
-                    .await
-                    .with_context(|| format!("Failed to send response to {}", peer))?;
+            match response.map(|response| response.to_bytes()) {
+                Ok(Ok(res_bytes)) => {
+                    socket
+                        .send_to(&res_bytes, peer)
+                        .await
+                        .with_context(|| format!("Failed to send response to {}", peer))?;
+                }
+                Ok(Err(_)) => {}
+                Err(e) => warn!("Failed to protect response to {}: {}", Disp(peer), Disp(&e)),
             }
         }
     }
 }
 
-/// ------------------ TESTS ------------------
+/// Replace an OSCORE request with the request it protects. Returns the
+/// context to protect the response with; `None` if no contexts are
+/// configured and the request is served as it is.
+fn unprotect<'a>(
+    contexts: &'a [SecurityContext],
+    request: &mut Packet,
+) -> Result<Option<(&'a SecurityContext, oscore::RequestBinding)>, oscore::OscoreError> {
+    if contexts.is_empty() {
+        return Ok(None);
+    }
+    let context = oscore::find_context(contexts, request)?;
+    let (inner, binding) = context.unprotect_request(request)?;
+    *request = inner;
+    Ok(Some((context, binding)))
+}
+
+// ------------------ TESTS ------------------
 
 #[cfg(test)]
 mod tests {
@@ -175,4 +477,89 @@ mod tests {
             .unwrap();
         assert_eq!(res.payload, b"42");
     }
+
+    #[tokio::test]
+    async fn test_coap_session_correlates_concurrent_requests() {
+        task::spawn(async {
+            coap_server("127.0.0.1:5684").await.unwrap();
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+        let session = CoapSession::connect("127.0.0.1:5684").await.unwrap();
+        let budget = Duration::from_secs(2);
+        let (a, b) = tokio::join!(
+            session.request(Method::Post, "/sensor/data", Some(b"a"), budget),
+            session.request(Method::Put, "/resource", Some(b"b"), budget),
+        );
+        assert_eq!(a.unwrap().payload, b"a");
+        assert_eq!(b.unwrap().payload, b"b");
+        assert_eq!(session.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_coap_blockwise_echo() {
+        task::spawn(async {
+            coap_server("127.0.0.1:5685").await.unwrap();
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+        // Several blocks each way, past the 1500-byte receive buffer
+        let image: Vec<u8> = (0..5000u32).map(|i| (i % 251) as u8).collect();
+        let res = coap_post("127.0.0.1:5685", "/sensor/data", &image).await.unwrap();
+        assert_eq!(res.payload, image);
+
+        let session = CoapSession::connect("127.0.0.1:5685").await.unwrap();
+        let res = session
+            .request_blockwise(Method::Put, "/resource", Some(&image), &RetryPolicy::coap())
+            .await
+            .unwrap();
+        assert_eq!(res.payload, image);
+    }
+
+    #[derive(Default)]
+    struct RamStore(Option<Vec<u8>>);
+
+    impl oscore::OscoreStore for RamStore {
+        fn load(&mut self) -> Result<Option<Vec<u8>>, &'static str> {
+            Ok(self.0.clone())
+        }
+        fn save(&mut self, data: &[u8]) -> Result<(), &'static str> {
+            self.0 = Some(data.to_vec());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_coap_oscore_end_to_end() {
+        let secret = [7u8; 16];
+        let server_context = SecurityContext::new(&secret, b"salt", b"S", b"C", None, RamStore::default()).unwrap();
+        let mut router = CoapRouter::new();
+        router.put("/actuator/led", |req| CoapResponse::new(ResponseType::Changed, req.payload));
+        task::spawn(async move {
+            coap_serve_oscore("127.0.0.1:5686", router, vec![server_context]).await.unwrap();
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+        let client = SecurityContext::new(&secret, b"salt", b"C", b"S", None, RamStore::default()).unwrap();
+        let res = coap_request_oscore("127.0.0.1:5686", Method::Put, "/actuator/led", Some(b"on"), &client)
+            .await
+            .unwrap();
+        assert_eq!(res.header.code, MessageClass::Response(ResponseType::Changed));
+        assert_eq!(res.payload, b"on");
+
+        // Block-wise inside OSCORE: every block protected on its own
+        let image: Vec<u8> = (0..3000u32).map(|i| (i % 253) as u8).collect();
+        let res = coap_request_oscore("127.0.0.1:5686", Method::Put, "/actuator/led", Some(&image), &client)
+            .await
+            .unwrap();
+        assert_eq!(res.payload, image);
+
+        // Unprotected requests and strangers are refused
+        let res = coap_put("127.0.0.1:5686", "/actuator/led", b"on").await.unwrap();
+        assert_eq!(res.header.code, MessageClass::Response(ResponseType::Unauthorized));
+        let stranger = SecurityContext::new(&[8u8; 16], b"salt", b"C", b"S", None, RamStore::default()).unwrap();
+        assert!(coap_request_oscore("127.0.0.1:5686", Method::Put, "/actuator/led", Some(b"on"), &stranger)
+            .await
+            .is_err());
+    }
 }
@@ -0,0 +1,496 @@
+//! SecureIoTOS Connection Manager Module
+//! -------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source / commercial use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/SecureIoTOS>
+//!
+//! Network connection manager with link prioritization and failover.
+//!
+//! The manager owns every uplink (Ethernet, Wi-Fi, cellular, ...), probes
+//! their health periodically (ICMP ping, MQTT keepalive, ...), and keeps the
+//! best healthy link selected according to a per-link policy:
+//!
+//! 1. Lower `priority` wins.
+//! 2. On equal priority, lower `cost` wins (e.g. prefer Wi-Fi over metered
+//!    cellular).
+//!
+//! Health uses hysteresis: a link goes down after `failure_threshold`
+//! consecutive failed probes and only comes back after `recovery_threshold`
+//! consecutive good ones, so a flapping link does not cause failover storms.
+//!
+//! Every state change is published on a broadcast event bus; subscribers
+//! (MQTT client, gateway, telemetry) react to `ConnectionEvent`s instead of
+//! polling the links themselves.
+//!
+//! Links of different types share one manager (`Link` is object safe).
+//! [`TcpLink`] probes with a TCP handshake to a server (e.g. the broker),
+//! [`CoapPingLink`] with a CoAP ping; both can be bound to an address of
+//! their interface so the probe takes that uplink. A probe that has not
+//! answered within the policy's `max_rtt` is abandoned and counts as failed,
+//! so a hung link cannot stall the probing of the others.
+
+use anyhow::{anyhow, bail, Context, Result};
+use sios_log::{info, warn, Dbg, Disp};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpSocket, UdpSocket};
+use tokio::sync::broadcast;
+use tokio::time::timeout;
+
+/// Physical type of an uplink.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkKind {
+    Ethernet,
+    WiFi,
+    Cellular,
+}
+
+/// Health of an uplink as seen by the manager.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkHealth {
+    Up,
+    Down,
+}
+
+/// Future returned by [`Link::probe`].
+pub type ProbeFuture<'a> = Pin<Box<dyn Future<Output = Result<Duration>> + Send + 'a>>;
+
+/// An uplink the manager can probe.
+pub trait Link: Send {
+    fn name(&self) -> &str;
+    fn kind(&self) -> LinkKind;
+    /// Probe the link (ping, MQTT keepalive, ...) and return the round-trip time.
+    fn probe(&mut self) -> ProbeFuture<'_>;
+}
+
+/// Socket address to bind a probe to: `local` if set, else any.
+fn source(local: Option<IpAddr>, target: &SocketAddr) -> SocketAddr {
+    match (local, target) {
+        (Some(ip), _) => SocketAddr::new(ip, 0),
+        (None, SocketAddr::V4(_)) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        (None, SocketAddr::V6(_)) => SocketAddr::from(([0u16; 8], 0)),
+    }
+}
+
+/// Link probed by a TCP handshake with `target`, e.g. the MQTT broker.
+pub struct TcpLink {
+    name: String,
+    kind: LinkKind,
+    target: SocketAddr,
+    local: Option<IpAddr>,
+}
+
+impl TcpLink {
+    pub fn new(name: &str, kind: LinkKind, target: SocketAddr) -> Self {
+        Self { name: name.to_string(), kind, target, local: None }
+    }
+
+    /// Probe from `local`, an address of this link's interface.
+    pub fn bind(mut self, local: IpAddr) -> Self {
+        self.local = Some(local);
+        self
+    }
+}
+
+impl Link for TcpLink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn kind(&self) -> LinkKind {
+        self.kind
+    }
+
+    fn probe(&mut self) -> ProbeFuture<'_> {
+        Box::pin(async move {
+            let socket = if self.target.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+            socket.bind(source(self.local, &self.target)).context("Failed to bind probe socket")?;
+            let start = Instant::now();
+            socket.connect(self.target).await.with_context(|| format!("No TCP handshake with {}", self.target))?;
+            Ok(start.elapsed())
+        })
+    }
+}
+
+/// Link probed by a CoAP ping (an empty confirmable message, RFC 7252
+/// §4.3) to `server`. Any reply with the ping's message ID counts.
+pub struct CoapPingLink {
+    name: String,
+    kind: LinkKind,
+    server: SocketAddr,
+    local: Option<IpAddr>,
+    message_id: u16,
+}
+
+impl CoapPingLink {
+    pub fn new(name: &str, kind: LinkKind, server: SocketAddr) -> Self {
+        Self { name: name.to_string(), kind, server, local: None, message_id: 0 }
+    }
+
+    /// Probe from `local`, an address of this link's interface.
+    pub fn bind(mut self, local: IpAddr) -> Self {
+        self.local = Some(local);
+        self
+    }
+}
+
+impl Link for CoapPingLink {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn kind(&self) -> LinkKind {
+        self.kind
+    }
+
+    fn probe(&mut self) -> ProbeFuture<'_> {
+        self.message_id = self.message_id.wrapping_add(1);
+        let mid = self.message_id.to_be_bytes();
+        Box::pin(async move {
+            let socket = UdpSocket::bind(source(self.local, &self.server)).await.context("Failed to bind probe socket")?;
+            socket.connect(self.server).await?;
+            let start = Instant::now();
+            // Version 1, CON, no token; code 0.00
+            socket.send(&[0x40, 0x00, mid[0], mid[1]]).await?;
+            let mut buf = [0u8; 64];
+            loop {
+                let len = socket.recv(&mut buf).await.with_context(|| format!("No CoAP reply from {}", self.server))?;
+                if len >= 4 && buf[2..4] == mid {
+                    return Ok(start.elapsed());
+                }
+                if len == 0 {
+                    bail!("Empty datagram from {}", self.server);
+                }
+            }
+        })
+    }
+}
+
+/// Selection and health policy for one link.
+#[derive(Debug, Clone)]
+pub struct LinkPolicy {
+    /// Lower is preferred
+    pub priority: u8,
+    /// Relative cost (e.g. per MB); tie-breaker on equal priority
+    pub cost: u32,
+    /// Probes slower than this count as failures, and are abandoned
+    pub max_rtt: Duration,
+    /// Consecutive failures before the link is marked down
+    pub failure_threshold: u32,
+    /// Consecutive successes before a down link is marked up again
+    pub recovery_threshold: u32,
+}
+
+impl Default for LinkPolicy {
+    fn default() -> Self {
+        Self {
+            priority: 10,
+            cost: 0,
+            max_rtt: Duration::from_secs(2),
+            failure_threshold: 3,
+            recovery_threshold: 2,
+        }
+    }
+}
+
+/// Events published on the connection event bus.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    LinkUp(String),
+    LinkDown(String),
+    /// The active link changed (`from` is `None` on first selection)
+    Failover { from: Option<String>, to: String },
+    /// No healthy link is left
+    Disconnected,
+}
+
+struct ManagedLink {
+    link: Box<dyn Link>,
+    policy: LinkPolicy,
+    health: LinkHealth,
+    successes: u32,
+    failures: u32,
+    last_rtt: Option<Duration>,
+}
+
+/// Owns all uplinks and keeps the preferred healthy one active.
+pub struct ConnectionManager {
+    links: Vec<ManagedLink>,
+    active: Option<usize>,
+    events: broadcast::Sender<ConnectionEvent>,
+}
+
+impl ConnectionManager {
+    pub fn new() -> Self {
+        let (events, _) = broadcast::channel(32);
+        Self { links: Vec::new(), active: None, events }
+    }
+
+    /// Register an uplink. New links start `Down` until they pass
+    /// `recovery_threshold` probes.
+    pub fn add_link(&mut self, link: impl Link + 'static, policy: LinkPolicy) {
+        info!("Connection manager: added {:?} link `{}`", Dbg(link.kind()), link.name());
+        self.links.push(ManagedLink {
+            link: Box::new(link),
+            policy,
+            health: LinkHealth::Down,
+            successes: 0,
+            failures: 0,
+            last_rtt: None,
+        });
+    }
+
+    /// Subscribe to connection events.
+    pub fn subscribe(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.events.subscribe()
+    }
+
+    /// Currently selected link.
+    pub fn active(&self) -> Option<&dyn Link> {
+        self.active.map(|i| self.links[i].link.as_ref())
+    }
+
+    /// Mutable access to the active link.
+    pub fn active_mut(&mut self) -> Result<&mut dyn Link> {
+        let idx = self.active.ok_or_else(|| anyhow!("no uplink available"))?;
+        Ok(self.links[idx].link.as_mut())
+    }
+
+    /// Health and last RTT of a link by name.
+    pub fn link_status(&self, name: &str) -> Option<(LinkHealth, Option<Duration>)> {
+        self.links
+            .iter()
+            .find(|m| m.link.name() == name)
+            .map(|m| (m.health, m.last_rtt))
+    }
+
+    /// Probe every link once, update health, and re-select the active link.
+    pub async fn tick(&mut self) {
+        let events = &self.events;
+        for m in self.links.iter_mut() {
+            let ok = match timeout(m.policy.max_rtt, m.link.probe()).await {
+                Ok(Ok(rtt)) => {
+                    m.last_rtt = Some(rtt);
+                    rtt <= m.policy.max_rtt
+                }
+                Ok(Err(e)) => {
+                    warn!("Probe on `{}` failed: {}", m.link.name(), Disp(&e));
+                    false
+                }
+                Err(_) => {
+                    warn!("Probe on `{}` timed out after {:?}", m.link.name(), Dbg(m.policy.max_rtt));
+                    false
+                }
+            };
+
+            if ok {
+                m.failures = 0;
+                m.successes = m.successes.saturating_add(1);
+                if m.health == LinkHealth::Down && m.successes >= m.policy.recovery_threshold {
+                    m.health = LinkHealth::Up;
+                    let _ = events.send(ConnectionEvent::LinkUp(m.link.name().to_string()));
+                }
+            } else {
+                m.successes = 0;
+                m.failures = m.failures.saturating_add(1);
+                if m.health == LinkHealth::Up && m.failures >= m.policy.failure_threshold {
+                    m.health = LinkHealth::Down;
+                    let _ = events.send(ConnectionEvent::LinkDown(m.link.name().to_string()));
+                }
+            }
+        }
+        self.select();
+    }
+
+    /// Probe forever every `interval`.
+    pub async fn run(&mut self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            self.tick().await;
+        }
+    }
+
+    /// Pick the best healthy link by (priority, cost) and announce changes.
+    fn select(&mut self) {
+        let best = self
+            .links
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| m.health == LinkHealth::Up)
+            .min_by_key(|(_, m)| (m.policy.priority, m.policy.cost))
+            .map(|(i, _)| i);
+
+        if best == self.active {
+            return;
+        }
+
+        let from = self.active.map(|i| self.links[i].link.name().to_string());
+        self.active = best;
+        match best {
+            Some(i) => {
+                let to = self.links[i].link.name().to_string();
+                info!("Connection manager: switching uplink {:?} -> {}", Dbg(&from), to.as_str());
+                self.publish(ConnectionEvent::Failover { from, to });
+            }
+            None => {
+                warn!("Connection manager: no healthy uplink");
+                self.publish(ConnectionEvent::Disconnected);
+            }
+        }
+    }
+
+    fn publish(&self, event: ConnectionEvent) {
+        // Having no subscribers is fine; events are advisory.
+        let _ = self.events.send(event);
+    }
+}
+
+impl Default for ConnectionManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+
+    struct MockLink {
+        name: &'static str,
+        kind: LinkKind,
+        up: Arc<AtomicBool>,
+        /// Never answers instead of failing when down
+        hangs: bool,
+    }
+
+    impl Link for MockLink {
+        fn name(&self) -> &str {
+            self.name
+        }
+        fn kind(&self) -> LinkKind {
+            self.kind
+        }
+        fn probe(&mut self) -> ProbeFuture<'_> {
+            Box::pin(async move {
+                match (self.up.load(Ordering::Relaxed), self.hangs) {
+                    (true, _) => Ok(Duration::from_millis(20)),
+                    (false, true) => std::future::pending().await,
+                    (false, false) => Err(anyhow!("timeout")),
+                }
+            })
+        }
+    }
+
+    fn policy(priority: u8, cost: u32) -> LinkPolicy {
+        LinkPolicy {
+            priority,
+            cost,
+            max_rtt: Duration::from_millis(100),
+            failure_threshold: 2,
+            recovery_threshold: 1,
+        }
+    }
+
+    /// Manager with cellular and Wi-Fi links, and the switches of both.
+    fn manager(hangs: bool) -> (ConnectionManager, Arc<AtomicBool>, Arc<AtomicBool>) {
+        let (cell, wlan) = (Arc::new(AtomicBool::new(true)), Arc::new(AtomicBool::new(true)));
+        let mut cm = ConnectionManager::new();
+        cm.add_link(MockLink { name: "cell0", kind: LinkKind::Cellular, up: cell.clone(), hangs }, policy(1, 100));
+        cm.add_link(MockLink { name: "wlan0", kind: LinkKind::WiFi, up: wlan.clone(), hangs }, policy(1, 1));
+        (cm, cell, wlan)
+    }
+
+    #[tokio::test]
+    async fn prefers_cheaper_link_on_equal_priority() {
+        let (mut cm, _, _) = manager(false);
+        cm.tick().await;
+        assert_eq!(cm.active().map(|l| l.name()), Some("wlan0"));
+    }
+
+    #[tokio::test]
+    async fn fails_over_after_threshold_and_notifies() {
+        let (mut cm, _, wlan) = manager(false);
+        let mut rx = cm.subscribe();
+        cm.tick().await;
+
+        wlan.store(false, Ordering::Relaxed);
+        cm.tick().await;
+        assert_eq!(cm.active().map(|l| l.name()), Some("wlan0"), "one failure is below threshold");
+        cm.tick().await;
+        assert_eq!(cm.active().map(|l| l.name()), Some("cell0"));
+
+        let mut events = Vec::new();
+        while let Ok(e) = rx.try_recv() {
+            events.push(e);
+        }
+        assert!(events.contains(&ConnectionEvent::LinkDown("wlan0".into())));
+        assert_eq!(
+            events.last(),
+            Some(&ConnectionEvent::Failover { from: Some("wlan0".into()), to: "cell0".into() })
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_disconnected_when_all_links_fail() {
+        let (mut cm, cell, wlan) = manager(false);
+        cm.tick().await;
+        cell.store(false, Ordering::Relaxed);
+        wlan.store(false, Ordering::Relaxed);
+        cm.tick().await;
+        cm.tick().await;
+        assert!(cm.active().is_none());
+        assert!(cm.active_mut().is_err());
+    }
+
+    #[tokio::test]
+    async fn a_hung_probe_times_out() {
+        let (mut cm, _, wlan) = manager(true);
+        cm.tick().await;
+        wlan.store(false, Ordering::Relaxed);
+        cm.tick().await;
+        cm.tick().await;
+        assert_eq!(cm.active().map(|l| l.name()), Some("cell0"));
+        assert_eq!(cm.link_status("cell0").map(|(h, _)| h), Some(LinkHealth::Up));
+    }
+
+    #[tokio::test]
+    async fn tcp_and_coap_links_probe_real_servers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let broker = listener.local_addr().unwrap();
+        tokio::spawn(async move { while listener.accept().await.is_ok() {} });
+
+        // Answers a CoAP ping with a reset
+        let coap = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = coap.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            while let Ok((len, peer)) = coap.recv_from(&mut buf).await {
+                if len == 4 && buf[0] == 0x40 {
+                    let _ = coap.send_to(&[0x70, 0x00, buf[2], buf[3]], peer).await;
+                }
+            }
+        });
+
+        let loopback = IpAddr::from([127, 0, 0, 1]);
+        let mut cm = ConnectionManager::new();
+        cm.add_link(TcpLink::new("eth0", LinkKind::Ethernet, broker).bind(loopback), policy(1, 0));
+        cm.add_link(CoapPingLink::new("wwan0", LinkKind::Cellular, server).bind(loopback), policy(2, 0));
+        cm.tick().await;
+        assert!(matches!(cm.link_status("eth0"), Some((LinkHealth::Up, Some(_)))));
+        assert!(matches!(cm.link_status("wwan0"), Some((LinkHealth::Up, Some(_)))));
+        assert_eq!(cm.active().map(|l| l.kind()), Some(LinkKind::Ethernet));
+
+        // Nobody listens on a closed port
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+        assert!(TcpLink::new("eth1", LinkKind::Ethernet, closed).probe().await.is_err());
+    }
+}
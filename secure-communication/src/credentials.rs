@@ -0,0 +1,331 @@
+//! SecureIoTOS Broker Credential Rotation Module
+//! ---------------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source / commercial use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/SecureIoTOS>
+//!
+//! Hot rotation of broker credentials (client certificate, PSK, token)
+//! without a reboot.
+//!
+//! New credentials arrive through the config/provisioning channel (any
+//! [`CredentialSource`]). [`CredentialRotator::rotate`] then:
+//!
+//! 1. drains the session: waits, up to `drain_timeout`, for in-flight
+//!    QoS 1/2 publishes to be acknowledged, so none are lost or
+//!    re-sent under the new identity;
+//! 2. disconnects cleanly and reconnects with the new credentials;
+//! 3. if the broker refuses them, reconnects with the previous ones and
+//!    reports [`RotationOutcome::RolledBack`]. The old credentials are only
+//!    forgotten after the new ones have been accepted once.
+//!
+//! Credential sets carry a version; anything not newer than the current
+//! set is ignored, so a replayed or duplicated config message cannot roll
+//! the device back to an older (possibly revoked) credential.
+
+use anyhow::{anyhow, Context, Result};
+use rumqttc::{AsyncClient, Event, EventLoop, Incoming, Key, MqttOptions, TlsConfiguration, Transport};
+use sios_log::{info, warn, Disp, Secret};
+use std::time::Duration;
+use tokio::time::{timeout, Instant};
+
+/// One way of authenticating to the broker.
+#[derive(Debug, Clone)]
+pub enum Credential {
+    /// TLS client certificate and its private key (PEM)
+    ClientCert { cert_pem: Vec<u8>, key_pem: Secret<Vec<u8>> },
+    /// TLS pre-shared key
+    Psk { identity: String, key: Secret<Vec<u8>> },
+    /// MQTT username and access token
+    Token { username: String, token: Secret<String> },
+}
+
+/// A versioned set of credentials.
+#[derive(Debug, Clone)]
+pub struct CredentialSet {
+    /// Monotonic version assigned by the backend
+    pub version: u32,
+    pub credential: Credential,
+}
+
+/// Where new credentials come from (the config/provisioning channel).
+#[allow(async_fn_in_trait)]
+pub trait CredentialSource {
+    /// The latest credential set, if the channel has one.
+    async fn fetch(&mut self) -> Result<Option<CredentialSet>>;
+}
+
+/// The broker connection being re-keyed.
+#[allow(async_fn_in_trait)]
+pub trait BrokerSession {
+    /// Wait until no publish is awaiting acknowledgement, or `limit` passes.
+    /// Returns the number still in flight.
+    async fn drain(&mut self, limit: Duration) -> Result<usize>;
+    /// Close the connection cleanly.
+    async fn disconnect(&mut self) -> Result<()>;
+    /// Connect with `creds`; `Ok` once the broker has accepted them.
+    async fn connect(&mut self, creds: &CredentialSet) -> Result<()>;
+}
+
+/// Result of a rotation attempt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RotationOutcome {
+    /// Connected with the new credentials
+    Rotated { version: u32, undrained: usize },
+    /// Set was not newer than the current one; nothing done
+    Ignored { version: u32 },
+    /// New credentials were refused; back on the previous ones
+    RolledBack { version: u32, reason: String },
+}
+
+/// Swaps the credentials of a live [`BrokerSession`].
+pub struct CredentialRotator<S: BrokerSession> {
+    session: S,
+    current: CredentialSet,
+    /// How long to wait for in-flight publishes before reconnecting anyway
+    pub drain_timeout: Duration,
+}
+
+impl<S: BrokerSession> CredentialRotator<S> {
+    /// `current` must be the set `session` is connected with.
+    pub fn new(session: S, current: CredentialSet) -> Self {
+        Self { session, current, drain_timeout: Duration::from_secs(5) }
+    }
+
+    /// Version of the credentials in use.
+    pub fn current_version(&self) -> u32 {
+        self.current.version
+    }
+
+    pub fn session(&self) -> &S {
+        &self.session
+    }
+
+    /// Fetch from `source` and rotate if it offers a newer set.
+    pub async fn poll<C: CredentialSource>(&mut self, source: &mut C) -> Result<Option<RotationOutcome>> {
+        match source.fetch().await.context("Failed to fetch broker credentials")? {
+            Some(set) => self.rotate(set).await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Drain, reconnect with `next`, and fall back to the current set if
+    /// the broker refuses it. Errors only if neither set connects.
+    pub async fn rotate(&mut self, next: CredentialSet) -> Result<RotationOutcome> {
+        if next.version <= self.current.version {
+            return Ok(RotationOutcome::Ignored { version: next.version });
+        }
+
+        let undrained = self.session.drain(self.drain_timeout).await?;
+        if undrained > 0 {
+            warn!("Credential rotation: {} publish(es) still in flight after drain", undrained);
+        }
+        self.session.disconnect().await?;
+
+        match self.session.connect(&next).await {
+            Ok(()) => {
+                info!("Broker credentials rotated to version {}", next.version);
+                let version = next.version;
+                self.current = next;
+                Ok(RotationOutcome::Rotated { version, undrained })
+            }
+            Err(e) => {
+                warn!("Credentials version {} refused: {}; restoring version {}", next.version, Disp(&e), self.current.version);
+                self.session
+                    .connect(&self.current)
+                    .await
+                    .context("Fallback to previous broker credentials failed")?;
+                Ok(RotationOutcome::RolledBack { version: next.version, reason: e.to_string() })
+            }
+        }
+    }
+}
+
+/// Broker endpoint settings that stay fixed across rotations.
+#[derive(Debug, Clone)]
+pub struct BrokerEndpoint {
+    pub client_id: String,
+    pub host: String,
+    pub port: u16,
+    /// CA certificate (PEM) the broker certificate must chain to
+    pub ca_pem: Vec<u8>,
+    /// How long to wait for CONNACK
+    pub connect_timeout: Duration,
+}
+
+/// [`BrokerSession`] over `rumqttc`. Owns the event loop; the application
+/// drives it through [`MqttSession::poll`] and publishes via
+/// [`MqttSession::client`].
+pub struct MqttSession {
+    endpoint: BrokerEndpoint,
+    client: Option<AsyncClient>,
+    eventloop: Option<EventLoop>,
+}
+
+impl MqttSession {
+    pub fn new(endpoint: BrokerEndpoint) -> Self {
+        Self { endpoint, client: None, eventloop: None }
+    }
+
+    /// Client for publishing/subscribing, while connected.
+    pub fn client(&self) -> Option<&AsyncClient> {
+        self.client.as_ref()
+    }
+
+    /// Process the next connection event.
+    pub async fn poll(&mut self) -> Result<Event> {
+        let eventloop = self.eventloop.as_mut().ok_or_else(|| anyhow!("not connected"))?;
+        Ok(eventloop.poll().await?)
+    }
+
+    fn options(&self, creds: &CredentialSet) -> Result<MqttOptions> {
+        let ep = &self.endpoint;
+        let mut options = MqttOptions::new(&ep.client_id, &ep.host, ep.port);
+        options.set_keep_alive(Duration::from_secs(10));
+        let client_auth = match &creds.credential {
+            Credential::ClientCert { cert_pem, key_pem } => {
+                Some((cert_pem.clone(), Key::ECC(key_pem.expose_secret().clone())))
+            }
+            Credential::Token { username, token } => {
+                options.set_credentials(username, token.expose_secret());
+                None
+            }
+            Credential::Psk { .. } => return Err(anyhow!("PSK credentials are not supported by the rumqttc transport")),
+        };
+        options.set_transport(Transport::Tls(TlsConfiguration::Simple {
+            ca: ep.ca_pem.clone(),
+            alpn: None,
+            client_auth,
+        }));
+        Ok(options)
+    }
+}
+
+impl BrokerSession for MqttSession {
+    async fn drain(&mut self, limit: Duration) -> Result<usize> {
+        let Some(eventloop) = self.eventloop.as_mut() else {
+            return Ok(0);
+        };
+        let deadline = Instant::now() + limit;
+        while eventloop.state.inflight() > 0 {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match timeout(left, eventloop.poll()).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(e)) => return Err(anyhow!("connection lost while draining: {}", e)),
+                Err(_) => break,
+            }
+        }
+        Ok(usize::from(eventloop.state.inflight()))
+    }
+
+    async fn disconnect(&mut self) -> Result<()> {
+        if let Some(client) = self.client.take() {
+            // The broker may already have dropped us; the session is gone either way.
+            let _ = client.disconnect().await;
+        }
+        self.eventloop = None;
+        Ok(())
+    }
+
+    async fn connect(&mut self, creds: &CredentialSet) -> Result<()> {
+        let (client, mut eventloop) = AsyncClient::new(self.options(creds)?, 10);
+        let wait_connack = async {
+            loop {
+                match eventloop.poll().await? {
+                    Event::Incoming(Incoming::ConnAck(ack)) => return Ok::<_, anyhow::Error>(ack),
+                    _ => continue,
+                }
+            }
+        };
+        timeout(self.endpoint.connect_timeout, wait_connack)
+            .await
+            .map_err(|_| anyhow!("no CONNACK within {:?}", self.endpoint.connect_timeout))??;
+        self.client = Some(client);
+        self.eventloop = Some(eventloop);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Session that accepts only the listed credential versions.
+    struct MockSession {
+        accepts: Vec<u32>,
+        connected: Option<u32>,
+        log: Vec<String>,
+    }
+
+    impl BrokerSession for MockSession {
+        async fn drain(&mut self, _limit: Duration) -> Result<usize> {
+            self.log.push("drain".into());
+            Ok(0)
+        }
+        async fn disconnect(&mut self) -> Result<()> {
+            self.log.push("disconnect".into());
+            self.connected = None;
+            Ok(())
+        }
+        async fn connect(&mut self, creds: &CredentialSet) -> Result<()> {
+            self.log.push(format!("connect v{}", creds.version));
+            if !self.accepts.contains(&creds.version) {
+                return Err(anyhow!("not authorized"));
+            }
+            self.connected = Some(creds.version);
+            Ok(())
+        }
+    }
+
+    struct OneShot(Option<CredentialSet>);
+
+    impl CredentialSource for OneShot {
+        async fn fetch(&mut self) -> Result<Option<CredentialSet>> {
+            Ok(self.0.take())
+        }
+    }
+
+    fn token(version: u32) -> CredentialSet {
+        CredentialSet {
+            version,
+            credential: Credential::Token { username: "dev-1".into(), token: Secret::new(format!("t{version}")) },
+        }
+    }
+
+    fn rotator(accepts: Vec<u32>) -> CredentialRotator<MockSession> {
+        let session = MockSession { accepts, connected: Some(1), log: Vec::new() };
+        CredentialRotator::new(session, token(1))
+    }
+
+    #[tokio::test]
+    async fn drains_then_reconnects_with_new_credentials() {
+        let mut r = rotator(vec![1, 2]);
+        let mut source = OneShot(Some(token(2)));
+        assert_eq!(r.poll(&mut source).await.unwrap(), Some(RotationOutcome::Rotated { version: 2, undrained: 0 }));
+        assert_eq!(r.session().log, ["drain", "disconnect", "connect v2"]);
+        assert_eq!(r.session().connected, Some(2));
+        assert_eq!(r.current_version(), 2);
+        assert_eq!(r.poll(&mut source).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn falls_back_when_new_credentials_are_refused() {
+        let mut r = rotator(vec![1]);
+        let outcome = r.rotate(token(2)).await.unwrap();
+        assert!(matches!(outcome, RotationOutcome::RolledBack { version: 2, .. }));
+        assert_eq!(r.session().connected, Some(1));
+        assert_eq!(r.current_version(), 1);
+
+        let mut dead = rotator(vec![]);
+        assert!(dead.rotate(token(2)).await.is_err(), "neither set connects");
+    }
+
+    #[tokio::test]
+    async fn ignores_stale_versions() {
+        let mut r = rotator(vec![0, 1]);
+        assert_eq!(r.rotate(token(1)).await.unwrap(), RotationOutcome::Ignored { version: 1 });
+        assert!(r.session().log.is_empty());
+    }
+}
@@ -0,0 +1,511 @@
+//! SecureIoTOS Gateway Module
+//! --------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source / commercial use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/SecureIoTOS>
+//!
+//! Gateway (border-router) mode: bridges local low-power sensors to an
+//! MQTT/TLS uplink.
+//!
+//! Flow:
+//! 1. Sensors POST/PUT to `/dev/<device-id>/<resource>` over CoAP (or any
+//!    other local link that calls [`Gateway::ingest`] directly).
+//! 2. Each message is checked (size, and device ID and resource usable as
+//!    single MQTT topic levels), then has to pass the gateway's
+//!    [`MessageValidator`]. There is no default: with
+//!    [`AllowlistValidator`] only devices enrolled in the
+//!    `secure_storage::enrollment` registry are forwarded.
+//! 3. Valid messages are re-enveloped as JSON with gateway metadata.
+//! 4. Envelopes go upstream through a [`StoreAndForward`], the same
+//!    persisted queue the gateway's own telemetry can use: if the uplink
+//!    is down they are kept and flushed in order later, and one the broker
+//!    refuses is dropped rather than holding up the rest.
+//!
+//! [`MqttUplink`] only reports an envelope as delivered once the broker
+//! has acknowledged it (PUBACK), so nothing leaves the queue before then.
+
+use anyhow::{anyhow, Context, Result};
+use coap_lite::{MessageClass, Packet, RequestType as Method, ResponseType};
+use sios_log::{debug, info, warn, Disp};
+use rumqttc::{MqttOptions, QoS};
+use secure_storage::enrollment::{DeviceRegistry, RegistryStore};
+use secure_storage::outbox::OutboxStore;
+use serde::Serialize;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+use crate::coap::{method_of, path_of};
+
+use crate::managed_mqtt::{MqttMessage, MqttTransport, RumqttTransport, TransportEvent};
+use crate::store_forward::{Channel, Refused, StoreAndForward, TelemetryLink};
+
+/// Default time to wait for the broker's PUBACK.
+pub const DEFAULT_ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Message received from a downstream sensor.
+#[derive(Debug, Clone)]
+pub struct SensorMessage {
+    pub device_id: String,
+    pub resource: String,
+    pub payload: Vec<u8>,
+}
+
+/// Upstream envelope produced by the gateway.
+#[derive(Debug, Clone, Serialize)]
+pub struct Envelope {
+    pub gateway_id: String,
+    pub device_id: String,
+    pub resource: String,
+    /// Unix time (seconds) at which the gateway accepted the message
+    pub received_at: u64,
+    /// Original payload, hex-encoded so binary sensor data survives JSON
+    pub payload_hex: String,
+}
+
+/// Gateway configuration.
+#[derive(Debug, Clone)]
+pub struct GatewayConfig {
+    /// Identifier of this gateway (included in every envelope)
+    pub gateway_id: String,
+    /// Topic prefix; envelopes go to `<prefix>/<device-id>/<resource>`
+    pub topic_prefix: String,
+    /// Largest accepted sensor payload in bytes
+    pub max_payload: usize,
+}
+
+impl Default for GatewayConfig {
+    fn default() -> Self {
+        Self {
+            gateway_id: "gw-0".into(),
+            topic_prefix: "secureiotos/gw".into(),
+            max_payload: 512,
+        }
+    }
+}
+
+/// Decides whether a downstream message may be forwarded. Runs after the
+/// gateway's own size and topic checks.
+pub trait MessageValidator: Send + Sync {
+    fn validate(&self, msg: &SensorMessage) -> Result<()>;
+}
+
+/// Validator for production gateways: the device must be enrolled and not
+/// revoked. The registry is shared so enrollment/revocation take effect on
+/// the next message.
+pub struct AllowlistValidator<S: RegistryStore + Send + Sync> {
+    pub registry: Arc<RwLock<DeviceRegistry<S>>>,
+}
+
+impl<S: RegistryStore + Send + Sync> MessageValidator for AllowlistValidator<S> {
+    fn validate(&self, msg: &SensorMessage) -> Result<()> {
+        let registry = self.registry.read().map_err(|_| anyhow!("device registry lock poisoned"))?;
+        registry
+            .authorize(&msg.device_id)
+            .map_err(|e| anyhow!("device {} not allowed: {:?}", msg.device_id, e))?;
+        Ok(())
+    }
+}
+
+/// MQTT uplink: publishes at QoS 1 and waits for the broker's PUBACK, so
+/// a message only counts as delivered once the broker has it.
+///
+/// Connects on first use. A connection error or a missing PUBACK drops the
+/// connection (and whatever rumqttc still held for it); the message stays
+/// queued and is sent again on a new connection, so the broker may see it
+/// twice.
+pub struct MqttUplink {
+    transport: RumqttTransport,
+    connected: bool,
+    next_id: u64,
+    /// How long to wait for the PUBACK
+    pub ack_timeout: Duration,
+}
+
+impl MqttUplink {
+    /// Uplink to the broker in `options` (see [`crate::mqtt::mqtt_options`]).
+    pub fn new(options: MqttOptions) -> Self {
+        Self { transport: RumqttTransport::new(options), connected: false, next_id: 0, ack_timeout: DEFAULT_ACK_TIMEOUT }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    async fn drop_connection(&mut self) {
+        self.connected = false;
+        let _ = self.transport.disconnect().await;
+    }
+}
+
+impl TelemetryLink for MqttUplink {
+    async fn send(&mut self, channel: Channel, destination: &str, payload: &[u8]) -> Result<()> {
+        if channel != Channel::Mqtt {
+            return Err(Refused(format!("{} is not for the MQTT uplink", destination)).into());
+        }
+        if !valid_topic_name(destination) {
+            return Err(Refused(format!("invalid topic name {:?}", destination)).into());
+        }
+        if !self.connected {
+            self.transport.connect().await.context("MQTT uplink connect failed")?;
+            self.connected = true;
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        let message = MqttMessage { topic: destination.to_string(), qos: QoS::AtLeastOnce, retain: false, payload: payload.to_vec() };
+        let transport = &mut self.transport;
+        let acked = async {
+            transport.publish(id, &message).await?;
+            loop {
+                // Messages on subscribed topics are not ours to handle.
+                if let TransportEvent::Acked(acked) = transport.poll().await? {
+                    if acked == id {
+                        return Ok::<_, anyhow::Error>(());
+                    }
+                }
+            }
+        };
+        match timeout(self.ack_timeout, acked).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => {
+                self.drop_connection().await;
+                Err(e).with_context(|| format!("Failed to publish to topic {}", destination))
+            }
+            Err(_) => {
+                self.drop_connection().await;
+                Err(anyhow!("no PUBACK for {} within {:?}", destination, self.ack_timeout))
+            }
+        }
+    }
+}
+
+/// The gateway itself: validation + re-enveloping + store-and-forward.
+pub struct Gateway<S: OutboxStore, L: TelemetryLink> {
+    config: GatewayConfig,
+    validator: Box<dyn MessageValidator>,
+    forward: StoreAndForward<S, L>,
+}
+
+impl<S: OutboxStore, L: TelemetryLink> Gateway<S, L> {
+    /// Create a gateway that forwards what `validator` accepts through
+    /// `forward` (e.g. a [`StoreAndForward`] over an [`MqttUplink`]).
+    pub fn new(config: GatewayConfig, validator: Box<dyn MessageValidator>, forward: StoreAndForward<S, L>) -> Self {
+        Self { config, validator, forward }
+    }
+
+    /// Number of envelopes waiting for the uplink.
+    pub fn backlog_len(&self) -> usize {
+        self.forward.queued()
+    }
+
+    /// Number of envelopes dropped because the backlog was full.
+    pub fn dropped(&self) -> u64 {
+        self.forward.dropped()
+    }
+
+    /// The queue envelopes go out through, for the gateway's own telemetry.
+    pub fn store_and_forward(&mut self) -> &mut StoreAndForward<S, L> {
+        &mut self.forward
+    }
+
+    /// Validate, re-envelope and forward one downstream message.
+    ///
+    /// Returns an error if the message is refused (checks, validator, or
+    /// the uplink refusing it outright) or could not be queued; a down
+    /// uplink is absorbed by the store-and-forward queue.
+    pub async fn ingest(&mut self, msg: SensorMessage) -> Result<()> {
+        self.check(&msg)?;
+        self.forward_message(msg).await
+    }
+
+    /// Size and topic checks, then the validator.
+    fn check(&self, msg: &SensorMessage) -> Result<()> {
+        for (what, level) in [("device id", &msg.device_id), ("resource", &msg.resource)] {
+            if !valid_topic_level(level) {
+                return Err(anyhow!("{} {:?} is not a single topic level", what, level));
+            }
+        }
+        if msg.payload.len() > self.config.max_payload {
+            return Err(anyhow!(
+                "payload from {} too large ({} > {})",
+                msg.device_id,
+                msg.payload.len(),
+                self.config.max_payload
+            ));
+        }
+        self.validator.validate(msg)
+    }
+
+    async fn forward_message(&mut self, msg: SensorMessage) -> Result<()> {
+        let topic = format!("{}/{}/{}", self.config.topic_prefix, msg.device_id, msg.resource);
+        let envelope = Envelope {
+            gateway_id: self.config.gateway_id.clone(),
+            device_id: msg.device_id,
+            resource: msg.resource,
+            received_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            payload_hex: hex::encode(&msg.payload),
+        };
+        let body = serde_json::to_vec(&envelope).context("Failed to serialize envelope")?;
+        self.forward.send(Channel::Mqtt, &topic, &body).await
+    }
+
+    /// Deliver the backlog, oldest first, until it is empty or the uplink
+    /// fails; envelopes the uplink refuses are dropped on the way. Returns
+    /// how many were delivered.
+    pub async fn flush(&mut self) -> Result<usize> {
+        self.forward.flush().await
+    }
+
+    /// Run the CoAP ingress loop forever on `bind_addr`.
+    ///
+    /// Accepts POST/PUT on `/dev/<device-id>/<resource>`; replies 2.04 on
+    /// success, 4.00 when the message is refused, 5.00 when it could not be
+    /// queued and 4.04 for any other path.
+    pub async fn run_coap_ingress(&mut self, bind_addr: &str) -> Result<()> {
+        let socket = UdpSocket::bind(bind_addr)
+            .await
+            .with_context(|| format!("Failed to bind gateway CoAP ingress on {}", bind_addr))?;
+        info!("Gateway CoAP ingress listening on {}", bind_addr);
+
+        let mut buf = [0u8; 1500];
+        loop {
+            let (size, peer) = socket
+                .recv_from(&mut buf)
+                .await
+                .context("Failed to receive CoAP request")?;
+
+            let request = match Packet::from_bytes(&buf[..size]) {
+                Ok(p) => p,
+                Err(_) => continue,
+            };
+
+            let mut response = Packet::new();
+            response.header.message_id = request.header.message_id;
+            response.set_token(request.get_token().clone());
+
+            let accepted_method = matches!(method_of(&request), Some(Method::Post) | Some(Method::Put));
+            let code = match (accepted_method, parse_device_path(&path_of(&request))) {
+                (true, Some((device_id, resource))) => {
+                    let msg = SensorMessage { device_id, resource, payload: request.payload.clone() };
+                    match self.check(&msg) {
+                        Err(e) => {
+                            debug!("Rejected message from {}: {}", Disp(peer), Disp(&e));
+                            ResponseType::BadRequest
+                        }
+                        Ok(()) => match self.forward_message(msg).await {
+                            Ok(()) => ResponseType::Changed,
+                            Err(e) if crate::store_forward::is_refused(&e) => {
+                                debug!("Uplink refused message from {}: {}", Disp(peer), Disp(&e));
+                                ResponseType::BadRequest
+                            }
+                            Err(e) => {
+                                warn!("Failed to forward message from {}: {}", Disp(peer), Disp(&e));
+                                ResponseType::InternalServerError
+                            }
+                        },
+                    }
+                }
+                _ => ResponseType::NotFound,
+            };
+            response.header.code = MessageClass::Response(code);
+
+            if let Ok(res_bytes) = response.to_bytes() {
+                socket
+                    .send_to(&res_bytes, peer)
+                    .await
+                    .with_context(|| format!("Failed to send response to {}", peer))?;
+            }
+        }
+    }
+}
+
+/// Whether `level` can stand as one level of an MQTT topic name: not
+/// empty, no level separator, no wildcard, no NUL.
+fn valid_topic_level(level: &str) -> bool {
+    !level.is_empty() && !level.contains(['/', '+', '#', '\0'])
+}
+
+/// Whether `topic` is a valid MQTT topic name to publish to (MQTT 3.1.1
+/// section 4.7): not empty, no wildcards, no NUL, at most 65535 bytes.
+fn valid_topic_name(topic: &str) -> bool {
+    !topic.is_empty() && topic.len() <= usize::from(u16::MAX) && !topic.contains(['+', '#', '\0'])
+}
+
+/// Split `dev/<device-id>/<resource...>` into its parts.
+fn parse_device_path(path: &str) -> Option<(String, String)> {
+    let mut parts = path.trim_matches('/').splitn(3, '/');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some("dev"), Some(id), Some(res)) if !id.is_empty() && !res.is_empty() => {
+            Some((id.to_string(), res.to_string()))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{BackendSimulator, FaultAction, FaultScript, SimulatorConfig};
+    use secure_storage::outbox::Outbox;
+
+    /// Link that can be switched on/off and records what it delivered.
+    /// Topics of the device `poison` are refused.
+    struct MockLink {
+        online: bool,
+        sent: Vec<String>,
+    }
+
+    impl TelemetryLink for MockLink {
+        async fn send(&mut self, _channel: Channel, destination: &str, _payload: &[u8]) -> Result<()> {
+            if !self.online {
+                return Err(anyhow!("offline"));
+            }
+            if destination.contains("/poison/") {
+                return Err(Refused("not authorized".into()).into());
+            }
+            self.sent.push(destination.to_string());
+            Ok(())
+        }
+    }
+
+    /// Backs both the device registry and the outbox.
+    #[derive(Default)]
+    struct RamStore(Option<Vec<u8>>);
+
+    impl RegistryStore for RamStore {
+        fn load(&mut self) -> std::result::Result<Option<Vec<u8>>, &'static str> {
+            Ok(self.0.clone())
+        }
+        fn save(&mut self, data: &[u8]) -> std::result::Result<(), &'static str> {
+            self.0 = Some(data.to_vec());
+            Ok(())
+        }
+    }
+
+    impl OutboxStore for RamStore {
+        fn load(&mut self) -> std::result::Result<Option<Vec<u8>>, &'static str> {
+            Ok(self.0.clone())
+        }
+        fn save(&mut self, data: &[u8]) -> std::result::Result<(), &'static str> {
+            self.0 = Some(data.to_vec());
+            Ok(())
+        }
+    }
+
+    struct AllowAll;
+
+    impl MessageValidator for AllowAll {
+        fn validate(&self, _msg: &SensorMessage) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn forward<L: TelemetryLink>(link: L) -> StoreAndForward<RamStore, L> {
+        StoreAndForward::new(Outbox::open(RamStore::default(), 16, 4096).unwrap(), link)
+    }
+
+    fn gateway(config: GatewayConfig, online: bool) -> Gateway<RamStore, MockLink> {
+        Gateway::new(config, Box::new(AllowAll), forward(MockLink { online, sent: Vec::new() }))
+    }
+
+    fn msg(id: &str, n: u8) -> SensorMessage {
+        SensorMessage { device_id: id.into(), resource: "temp".into(), payload: vec![n] }
+    }
+
+    #[test]
+    fn parses_device_paths() {
+        assert_eq!(parse_device_path("dev/node-1/temp"), Some(("node-1".into(), "temp".into())));
+        assert_eq!(parse_device_path("/dev/a/b/c"), Some(("a".into(), "b/c".into())));
+        assert_eq!(parse_device_path("sensor/temp"), None);
+    }
+
+    #[tokio::test]
+    async fn buffers_while_offline_and_flushes_in_order() {
+        let mut gw = gateway(GatewayConfig::default(), false);
+
+        gw.ingest(msg("a", 1)).await.unwrap();
+        gw.ingest(msg("b", 2)).await.unwrap();
+        assert_eq!(gw.backlog_len(), 2);
+
+        gw.store_and_forward().link().online = true;
+        assert_eq!(gw.flush().await.unwrap(), 2);
+        assert_eq!(gw.backlog_len(), 0);
+        assert_eq!(gw.store_and_forward().link().sent, vec!["secureiotos/gw/a/temp", "secureiotos/gw/b/temp"]);
+    }
+
+    #[tokio::test]
+    async fn refused_head_message_does_not_block_the_backlog() {
+        let mut gw = gateway(GatewayConfig::default(), false);
+
+        gw.ingest(msg("poison", 1)).await.unwrap();
+        gw.ingest(msg("a", 2)).await.unwrap();
+
+        gw.store_and_forward().link().online = true;
+        assert_eq!(gw.flush().await.unwrap(), 1);
+        assert_eq!(gw.backlog_len(), 0);
+        assert_eq!(gw.store_and_forward().link().sent, vec!["secureiotos/gw/a/temp"]);
+    }
+
+    #[tokio::test]
+    async fn forwards_only_enrolled_devices() {
+        let registry = Arc::new(RwLock::new(DeviceRegistry::open(RamStore::default(), 8).unwrap()));
+        registry.write().unwrap().enroll("a", &[0x02; 33]).unwrap();
+        let validator = AllowlistValidator { registry: registry.clone() };
+        let link = MockLink { online: true, sent: Vec::new() };
+        let mut gw = Gateway::new(GatewayConfig::default(), Box::new(validator), forward(link));
+
+        gw.ingest(msg("a", 1)).await.unwrap();
+        assert!(gw.ingest(msg("intruder", 2)).await.is_err());
+        registry.write().unwrap().revoke("a").unwrap();
+        assert!(gw.ingest(msg("a", 3)).await.is_err());
+        assert_eq!(gw.store_and_forward().link().sent, vec!["secureiotos/gw/a/temp"]);
+    }
+
+    #[tokio::test]
+    async fn rejects_oversize_payloads() {
+        let config = GatewayConfig { max_payload: 2, ..GatewayConfig::default() };
+        let mut gw = gateway(config, true);
+        let big = SensorMessage { device_id: "a".into(), resource: "r".into(), payload: vec![0; 3] };
+        assert!(gw.ingest(big).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_ids_that_are_not_one_topic_level() {
+        let mut gw = gateway(GatewayConfig::default(), true);
+        for (device_id, resource) in [("+", "temp"), ("a", "#"), ("a/b", "temp"), ("a", "x/y"), ("", "temp"), ("a\0", "temp")] {
+            let m = SensorMessage { device_id: device_id.into(), resource: resource.into(), payload: vec![1] };
+            assert!(gw.ingest(m).await.is_err(), "{:?}/{:?} accepted", device_id, resource);
+        }
+        assert_eq!(gw.backlog_len(), 0);
+        assert!(gw.store_and_forward().link().sent.is_empty());
+    }
+
+    #[tokio::test]
+    async fn mqtt_uplink_waits_for_puback() {
+        let faults = FaultScript::new([FaultAction::Normal, FaultAction::Drop]);
+        let sim = BackendSimulator::start(SimulatorConfig { mqtt_faults: faults, ..Default::default() }).await.unwrap();
+        let options = crate::mqtt::mqtt_options("gw-test", "127.0.0.1", sim.mqtt_addr.port(), false);
+        let mut uplink = MqttUplink::new(options);
+        uplink.ack_timeout = Duration::from_secs(2);
+
+        // The broker swallows the PUBLISH: not delivered, and the
+        // connection is dropped.
+        assert!(uplink.send(Channel::Mqtt, "gw/a/temp", b"1").await.is_err());
+        assert!(!uplink.is_connected());
+
+        uplink.send(Channel::Mqtt, "gw/a/temp", b"2").await.unwrap();
+        assert!(uplink.is_connected());
+        assert_eq!(sim.published(), vec![("gw/a/temp".to_string(), b"2".to_vec())]);
+
+        let refused = uplink.send(Channel::Mqtt, "gw/+/temp", b"3").await.unwrap_err();
+        assert!(crate::store_forward::is_refused(&refused));
+    }
+}
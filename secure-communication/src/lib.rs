@@ -10,9 +10,26 @@
 //! This module provides secure communication primitives for IoT systems,
 //! including TLS, MQTT, and CoAP protocols.
 
+use sios_log::info;
+
 pub mod tls;
+pub mod tls_psk;
 pub mod mqtt;
+pub mod managed_mqtt;
+pub mod mqtt_tls;
+pub mod mqtt5;
 pub mod coap;
+pub mod blockwise;
+pub mod router;
+pub mod oscore;
+pub mod gateway;
+pub mod connection;
+pub mod credentials;
+pub mod request_manager;
+pub mod retry;
+pub mod store_forward;
+#[cfg(any(test, feature = "simulator"))]
+pub mod simulator;
 
 /// Runs a demo showcasing all available secure communication modules.
 ///
@@ -28,23 +45,25 @@ pub mod coap;
 /// }
 /// ```
 pub async fn run_demo() -> Result<(), Box<dyn std::error::Error>> {
-    println!("Starting SecureIoTOS Communication Demo...");
+    info!("Starting SecureIoTOS Communication Demo...");
 
     // TLS demo
-    println!("Connecting via TLS...");
-    tls::connect_tls("example.com:443").await?;
-    println!("TLS connection successful.");
+    info!("Connecting via TLS...");
+    tls::connect_tls("example.com:443", "example.com").await?;
+    info!("TLS connection successful.");
 
-    // MQTT demo
-    println!("Publishing MQTT demo message...");
-    mqtt::publish_demo().await?;
-    println!("MQTT demo message published.");
+    // MQTT demo: the event loop sends the queued publish and its PUBACK
+    info!("Publishing MQTT demo message...");
+    let (client, mut eventloop) = mqtt::mqtt_connect("secureiotos-demo", "broker.hivemq.com", 1883, false);
+    mqtt::mqtt_publish(&client, "secureiotos/demo", "hello").await?;
+    while !matches!(eventloop.poll().await?, rumqttc::Event::Incoming(rumqttc::Packet::PubAck(_))) {}
+    info!("MQTT demo message published.");
 
     // CoAP demo
-    println!("Sending CoAP demo request...");
-    coap::send_demo().await?;
-    println!("CoAP demo request completed.");
+    info!("Sending CoAP demo request...");
+    coap::coap_get("coap.me:5683", "/test").await?;
+    info!("CoAP demo request completed.");
 
-    println!("SecureIoTOS Communication Demo finished successfully.");
+    info!("SecureIoTOS Communication Demo finished successfully.");
     Ok(())
 }
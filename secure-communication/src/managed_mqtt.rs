@@ -0,0 +1,697 @@
+//! SecureIoTOS Managed MQTT Client Module
+//! --------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source / commercial use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/SecureIoTOS>
+//!
+//! MQTT client that survives broker restarts and network drops.
+//!
+//! [`ManagedMqttClient`] owns the connection and, whenever it is lost:
+//!
+//! 1. reconnects with exponential backoff and jitter ([`RetryPolicy`],
+//!    [`RetryPolicy::mqtt_reconnect`] by default);
+//! 2. re-subscribes to every topic subscribed through it;
+//! 3. resumes the QoS 1/2 messages the broker has not acknowledged yet,
+//!    oldest first: one sent before is sent again with its original packet
+//!    id and DUP set, one the broker already has (QoS 2, PUBREC received)
+//!    gets its PUBREL again, and one published while offline goes out now;
+//! 4. reports each transition to the state callback
+//!    ([`ManagedMqttClient::on_state_change`]).
+//!
+//! [`managed_mqtt_client`] connects without a clean session, so the broker
+//! also keeps our subscriptions and queues QoS 1/2 messages for us while we
+//! are away, and recognises a resumed QoS 2 message by its packet id: it is
+//! delivered once. QoS 1 messages may still be delivered twice.
+//!
+//! The client is single-owner: the application publishes between calls to
+//! [`ManagedMqttClient::next_message`], which drives the connection.
+
+use anyhow::{anyhow, Context, Result};
+use rumqttc::{AsyncClient, Event, EventLoop, Incoming, MqttOptions, Outgoing, PubRel, Publish, QoS, Request};
+use sios_log::{debug, info, warn, Dbg};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::time::Duration;
+use tokio::time::{sleep, timeout};
+
+use crate::mqtt::mqtt_options;
+use crate::retry::{Backoff, RetryPolicy};
+
+/// Default bound on QoS 1/2 messages held for re-sending.
+pub const DEFAULT_MAX_UNACKED: usize = 100;
+
+/// An application message, sent or received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MqttMessage {
+    pub topic: String,
+    pub qos: QoS,
+    pub retain: bool,
+    pub payload: Vec<u8>,
+}
+
+/// Connection state reported to the state callback.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MqttState {
+    /// Broker accepted the connection; subscriptions are restored and
+    /// `replayed` unacknowledged messages re-sent
+    Connected { session_present: bool, replayed: usize },
+    /// An established connection was lost
+    Disconnected { reason: String },
+    /// Next connection attempt after `delay`; `attempt` counts failures
+    /// since the last successful connection
+    Reconnecting { attempt: u32, delay: Duration },
+}
+
+/// Callback invoked on every [`MqttState`] transition.
+pub type StateCallback = Box<dyn FnMut(&MqttState) + Send>;
+
+/// What [`MqttTransport::poll`] reports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransportEvent {
+    /// A message arrived on a subscribed topic
+    Message(MqttMessage),
+    /// The broker has the QoS 2 publish sent with this id (PUBREC); it
+    /// still has to be released and completes with `Acked`
+    Received(u64),
+    /// The broker acknowledged the QoS 1/2 publish sent with this id
+    Acked(u64),
+}
+
+/// The broker connection driven by a [`ManagedMqttClient`].
+#[allow(async_fn_in_trait)]
+pub trait MqttTransport {
+    /// Open a new connection; `Ok(session_present)` once the broker has
+    /// accepted it.
+    async fn connect(&mut self) -> Result<bool>;
+    async fn subscribe(&mut self, topic: &str, qos: QoS) -> Result<()>;
+    async fn unsubscribe(&mut self, topic: &str) -> Result<()>;
+    /// Send `message`. For QoS 1/2, `id` comes back in
+    /// [`TransportEvent::Acked`] once the broker has acknowledged it.
+    async fn publish(&mut self, id: u64, message: &MqttMessage) -> Result<()>;
+    /// Send the QoS 1/2 message `id` again on a new connection: with the
+    /// packet id it had and DUP set, or as a new publish if it never got
+    /// one.
+    async fn republish(&mut self, id: u64, message: &MqttMessage) -> Result<()>;
+    /// Send PUBREL again on a new connection for the QoS 2 message `id`,
+    /// which the broker received on an earlier one.
+    async fn release(&mut self, id: u64) -> Result<()>;
+    /// Wait for the next event. An error means the connection is gone.
+    async fn poll(&mut self) -> Result<TransportEvent>;
+    /// Drop the connection, cleanly if it is still up.
+    async fn disconnect(&mut self) -> Result<()>;
+}
+
+/// How far a QoS 1/2 message got.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Delivery {
+    /// Not handed to a connection yet
+    Queued,
+    /// Sent; no acknowledgement yet
+    Sent,
+    /// QoS 2: the broker has it (PUBREC), waiting for PUBCOMP
+    Received,
+}
+
+/// A QoS 1/2 message awaiting acknowledgement.
+#[derive(Debug)]
+struct Unacked {
+    message: MqttMessage,
+    delivery: Delivery,
+}
+
+/// MQTT client that reconnects, re-subscribes and re-sends by itself.
+pub struct ManagedMqttClient<T: MqttTransport> {
+    transport: T,
+    backoff: Backoff,
+    connected: bool,
+    subscriptions: Vec<(String, QoS)>,
+    /// QoS 1/2 messages awaiting acknowledgement, by id (= send order)
+    unacked: BTreeMap<u64, Unacked>,
+    next_id: u64,
+    on_state: Option<StateCallback>,
+    /// Most QoS 1/2 messages held for re-sending; publishing more fails
+    pub max_unacked: usize,
+}
+
+impl<T: MqttTransport> ManagedMqttClient<T> {
+    /// Not connected yet: the first [`Self::next_message`] connects.
+    pub fn new(transport: T, policy: RetryPolicy) -> Self {
+        Self {
+            transport,
+            backoff: Backoff::new(policy),
+            connected: false,
+            subscriptions: Vec::new(),
+            unacked: BTreeMap::new(),
+            next_id: 0,
+            on_state: None,
+            max_unacked: DEFAULT_MAX_UNACKED,
+        }
+    }
+
+    /// Call `callback` on every connection state transition.
+    pub fn on_state_change(&mut self, callback: impl FnMut(&MqttState) + Send + 'static) {
+        self.on_state = Some(Box::new(callback));
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Number of QoS 1/2 messages not yet acknowledged by the broker.
+    pub fn unacked(&self) -> usize {
+        self.unacked.len()
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    /// Subscribe to `topic` now and after every reconnect.
+    pub async fn subscribe(&mut self, topic: &str, qos: QoS) -> Result<()> {
+        match self.subscriptions.iter_mut().find(|(t, _)| t == topic) {
+            Some(entry) => entry.1 = qos,
+            None => self.subscriptions.push((topic.to_string(), qos)),
+        }
+        if self.connected {
+            if let Err(e) = self.transport.subscribe(topic, qos).await {
+                // Restored on reconnect like the others.
+                self.connection_lost(e.to_string()).await;
+            }
+        }
+        info!("Subscribed to `{}`", topic);
+        Ok(())
+    }
+
+    /// Stop receiving `topic`, now and after reconnects.
+    pub async fn unsubscribe(&mut self, topic: &str) -> Result<()> {
+        self.subscriptions.retain(|(t, _)| t != topic);
+        if self.connected {
+            if let Err(e) = self.transport.unsubscribe(topic).await {
+                // A resumed session may still hold it: let the caller retry.
+                self.connection_lost(e.to_string()).await;
+                return Err(e).with_context(|| format!("Failed to unsubscribe from topic {}", topic));
+            }
+        }
+        Ok(())
+    }
+
+    /// Publish a message. QoS 1/2 messages are kept until acknowledged and
+    /// re-sent after a reconnect, so they are accepted while offline too;
+    /// QoS 0 messages need a live connection.
+    pub async fn publish(&mut self, topic: &str, qos: QoS, retain: bool, payload: impl Into<Vec<u8>>) -> Result<()> {
+        let message = MqttMessage { topic: topic.to_string(), qos, retain, payload: payload.into() };
+
+        if qos == QoS::AtMostOnce {
+            if !self.connected {
+                return Err(anyhow!("not connected; QoS 0 message to {} dropped", topic));
+            }
+            if let Err(e) = self.transport.publish(self.next_id, &message).await {
+                self.connection_lost(e.to_string()).await;
+                return Err(e).with_context(|| format!("Failed to publish to topic {}", topic));
+            }
+            self.next_id += 1;
+            return Ok(());
+        }
+
+        if self.unacked.len() >= self.max_unacked {
+            return Err(anyhow!("{} messages awaiting acknowledgement; not publishing to {}", self.unacked.len(), topic));
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let mut delivery = Delivery::Queued;
+        if self.connected {
+            match self.transport.publish(id, &message).await {
+                Ok(()) => delivery = Delivery::Sent,
+                // Kept below, sent on reconnect.
+                Err(e) => self.connection_lost(e.to_string()).await,
+            }
+        }
+        self.unacked.insert(id, Unacked { message, delivery });
+        Ok(())
+    }
+
+    /// Drive the connection until a message arrives, reconnecting as
+    /// needed. Errors only once the retry policy gives up.
+    pub async fn next_message(&mut self) -> Result<MqttMessage> {
+        loop {
+            self.ensure_connected().await?;
+            match self.transport.poll().await {
+                Ok(TransportEvent::Message(message)) => return Ok(message),
+                Ok(TransportEvent::Received(id)) => {
+                    if let Some(unacked) = self.unacked.get_mut(&id) {
+                        unacked.delivery = Delivery::Received;
+                    }
+                }
+                Ok(TransportEvent::Acked(id)) => {
+                    self.unacked.remove(&id);
+                }
+                Err(e) => {
+                    let reason = e.to_string();
+                    self.connection_lost(reason.clone()).await;
+                    self.back_off(&reason).await?;
+                }
+            }
+        }
+    }
+
+    /// Close the connection. Unacknowledged messages stay queued; the next
+    /// [`Self::next_message`] reconnects.
+    pub async fn disconnect(&mut self) -> Result<()> {
+        self.connected = false;
+        self.transport.disconnect().await
+    }
+
+    async fn ensure_connected(&mut self) -> Result<()> {
+        while !self.connected {
+            match self.establish().await {
+                Ok(session_present) => {
+                    self.backoff.reset();
+                    self.connected = true;
+                    let replayed = self.unacked.len();
+                    info!("MQTT connected (session present: {}, re-sent {})", session_present, replayed);
+                    self.notify(MqttState::Connected { session_present, replayed });
+                }
+                Err(e) => {
+                    let _ = self.transport.disconnect().await;
+                    self.back_off(&e.to_string()).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Connect, restore subscriptions and resume unacknowledged messages.
+    async fn establish(&mut self) -> Result<bool> {
+        let session_present = self.transport.connect().await?;
+        for (topic, qos) in &self.subscriptions {
+            self.transport.subscribe(topic, *qos).await?;
+        }
+        for (id, unacked) in self.unacked.iter_mut() {
+            match unacked.delivery {
+                Delivery::Queued => {
+                    self.transport.publish(*id, &unacked.message).await?;
+                    unacked.delivery = Delivery::Sent;
+                }
+                Delivery::Sent => self.transport.republish(*id, &unacked.message).await?,
+                Delivery::Received => self.transport.release(*id).await?,
+            }
+        }
+        Ok(session_present)
+    }
+
+    async fn connection_lost(&mut self, reason: String) {
+        if self.connected {
+            self.connected = false;
+            warn!("MQTT connection lost: {}", reason.as_str());
+            self.notify(MqttState::Disconnected { reason });
+        }
+        let _ = self.transport.disconnect().await;
+    }
+
+    async fn back_off(&mut self, reason: &str) -> Result<()> {
+        let delay = self
+            .backoff
+            .next_delay()
+            .ok_or_else(|| anyhow!("MQTT reconnect attempts exhausted: {}", reason))?;
+        warn!("MQTT error: {} (reconnecting in {:?})", reason, Dbg(delay));
+        self.notify(MqttState::Reconnecting { attempt: self.backoff.attempts(), delay });
+        sleep(delay).await;
+        Ok(())
+    }
+
+    fn notify(&mut self, state: MqttState) {
+        if let Some(callback) = self.on_state.as_mut() {
+            callback(&state);
+        }
+    }
+}
+
+/// [`MqttTransport`] over `rumqttc`. Every connection gets a fresh event
+/// loop, so re-sending is left to the [`ManagedMqttClient`]; packet ids are
+/// remembered across connections so that it can resume messages.
+///
+/// Resumed publishes and releases go out before anything else on the new
+/// connection. New QoS 1/2 publishes are held back until the broker has
+/// acknowledged all of them, as the fresh event loop would otherwise hand
+/// out their packet ids again.
+pub struct RumqttTransport {
+    options: MqttOptions,
+    /// How long to wait for CONNACK
+    pub connect_timeout: Duration,
+    client: Option<AsyncClient>,
+    eventloop: Option<EventLoop>,
+    /// Ids of publishes handed to rumqttc, until it reports their packet
+    /// id (`None` for QoS 0)
+    unnumbered: VecDeque<Option<u64>>,
+    /// Resumed publishes queued on this connection whose `Outgoing` event
+    /// has not been seen yet
+    replaying: usize,
+    /// Packet ids resumed on this connection, until acknowledged
+    resumed: HashSet<u16>,
+    /// New QoS 1/2 publishes waiting for `resumed` to drain
+    held: VecDeque<(u64, MqttMessage)>,
+    /// Packet id -> message id, until acknowledged (kept across connections)
+    inflight: HashMap<u16, u64>,
+}
+
+impl RumqttTransport {
+    pub fn new(options: MqttOptions) -> Self {
+        Self {
+            options,
+            connect_timeout: Duration::from_secs(10),
+            client: None,
+            eventloop: None,
+            unnumbered: VecDeque::new(),
+            replaying: 0,
+            resumed: HashSet::new(),
+            held: VecDeque::new(),
+            inflight: HashMap::new(),
+        }
+    }
+
+    fn client(&self) -> Result<&AsyncClient> {
+        self.client.as_ref().ok_or_else(|| anyhow!("not connected"))
+    }
+
+    fn packet_id(&self, id: u64) -> Option<u16> {
+        self.inflight.iter().find(|(_, &v)| v == id).map(|(&pkid, _)| pkid)
+    }
+
+    /// Queue `request` ahead of everything handed over through the client.
+    fn push_pending(&mut self, request: Request) -> Result<()> {
+        let eventloop = self.eventloop.as_mut().ok_or_else(|| anyhow!("not connected"))?;
+        let mut pending: Vec<Request> = eventloop.pending.by_ref().collect();
+        pending.push(request);
+        eventloop.pending = pending.into_iter();
+        Ok(())
+    }
+
+    /// Hand the held publishes to rumqttc once nothing resumed is left.
+    fn release_held(&mut self) -> Result<()> {
+        if !self.resumed.is_empty() {
+            return Ok(());
+        }
+        while let Some((id, message)) = self.held.front() {
+            let client = self.client.as_ref().ok_or_else(|| anyhow!("not connected"))?;
+            match client.try_publish(message.topic.as_str(), message.qos, message.retain, message.payload.clone()) {
+                Ok(()) => {
+                    self.unnumbered.push_back(Some(*id));
+                    self.held.pop_front();
+                }
+                // Full: the rest goes on a later poll.
+                Err(_) => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MqttTransport for RumqttTransport {
+    async fn connect(&mut self) -> Result<bool> {
+        let (client, mut eventloop) = AsyncClient::new(self.options.clone(), 10);
+        let wait_connack = async {
+            loop {
+                if let Event::Incoming(Incoming::ConnAck(ack)) = eventloop.poll().await? {
+                    return Ok::<_, anyhow::Error>(ack);
+                }
+            }
+        };
+        let ack = timeout(self.connect_timeout, wait_connack)
+            .await
+            .map_err(|_| anyhow!("no CONNACK within {:?}", self.connect_timeout))??;
+        self.client = Some(client);
+        self.eventloop = Some(eventloop);
+        self.unnumbered.clear();
+        self.replaying = 0;
+        self.resumed.clear();
+        self.held.clear();
+        Ok(ack.session_present)
+    }
+
+    async fn subscribe(&mut self, topic: &str, qos: QoS) -> Result<()> {
+        self.client()?
+            .subscribe(topic, qos)
+            .await
+            .with_context(|| format!("Failed to subscribe to topic {}", topic))
+    }
+
+    async fn unsubscribe(&mut self, topic: &str) -> Result<()> {
+        self.client()?
+            .unsubscribe(topic)
+            .await
+            .with_context(|| format!("Failed to unsubscribe from topic {}", topic))
+    }
+
+    async fn publish(&mut self, id: u64, message: &MqttMessage) -> Result<()> {
+        if message.qos != QoS::AtMostOnce && !(self.resumed.is_empty() && self.held.is_empty()) {
+            self.client()?;
+            self.held.push_back((id, message.clone()));
+            return Ok(());
+        }
+        self.client()?
+            .publish(message.topic.as_str(), message.qos, message.retain, message.payload.clone())
+            .await
+            .with_context(|| format!("Failed to publish to topic {}", message.topic))?;
+        self.unnumbered.push_back((message.qos != QoS::AtMostOnce).then_some(id));
+        Ok(())
+    }
+
+    async fn republish(&mut self, id: u64, message: &MqttMessage) -> Result<()> {
+        let Some(pkid) = self.packet_id(id) else {
+            return self.publish(id, message).await;
+        };
+        let mut publish = Publish::new(message.topic.as_str(), message.qos, message.payload.clone());
+        publish.retain = message.retain;
+        publish.pkid = pkid;
+        publish.dup = true;
+        self.push_pending(Request::Publish(publish))?;
+        self.replaying += 1;
+        self.resumed.insert(pkid);
+        Ok(())
+    }
+
+    async fn release(&mut self, id: u64) -> Result<()> {
+        let pkid = self.packet_id(id).ok_or_else(|| anyhow!("no packet id for QoS 2 message {}", id))?;
+        self.push_pending(Request::PubRel(PubRel::new(pkid)))?;
+        self.resumed.insert(pkid);
+        Ok(())
+    }
+
+    async fn poll(&mut self) -> Result<TransportEvent> {
+        loop {
+            self.release_held()?;
+            let eventloop = self.eventloop.as_mut().ok_or_else(|| anyhow!("not connected"))?;
+            match eventloop.poll().await? {
+                Event::Incoming(Incoming::Publish(p)) => {
+                    return Ok(TransportEvent::Message(MqttMessage {
+                        topic: p.topic.clone(),
+                        qos: p.qos,
+                        retain: p.retain,
+                        payload: p.payload.to_vec(),
+                    }));
+                }
+                // rumqttc sends the pending (resumed) requests first, then
+                // publishes in the order they were handed over.
+                Event::Outgoing(Outgoing::Publish(pkid)) => {
+                    if self.replaying > 0 {
+                        self.replaying -= 1;
+                    } else if let Some(Some(id)) = self.unnumbered.pop_front() {
+                        self.inflight.insert(pkid, id);
+                    }
+                }
+                Event::Incoming(Incoming::PubAck(ack)) => {
+                    self.resumed.remove(&ack.pkid);
+                    if let Some(id) = self.inflight.remove(&ack.pkid) {
+                        return Ok(TransportEvent::Acked(id));
+                    }
+                }
+                Event::Incoming(Incoming::PubRec(rec)) => {
+                    if let Some(&id) = self.inflight.get(&rec.pkid) {
+                        return Ok(TransportEvent::Received(id));
+                    }
+                }
+                Event::Incoming(Incoming::PubComp(comp)) => {
+                    self.resumed.remove(&comp.pkid);
+                    if let Some(id) = self.inflight.remove(&comp.pkid) {
+                        return Ok(TransportEvent::Acked(id));
+                    }
+                }
+                other => debug!("MQTT event: {:?}", Dbg(&other)),
+            }
+        }
+    }
+
+    async fn disconnect(&mut self) -> Result<()> {
+        if let Some(client) = self.client.take() {
+            // The broker may already have dropped us; the session is gone either way.
+            let _ = client.disconnect().await;
+        }
+        self.eventloop = None;
+        Ok(())
+    }
+}
+
+/// A managed client for `broker` with [`RetryPolicy::mqtt_reconnect`]
+/// backoff and a persistent broker session.
+///
+/// # Arguments
+/// * `client_id` – Unique MQTT client ID; the broker keys the session by it
+/// * `broker`    – MQTT broker hostname
+/// * `port`      – Broker port (1883 for TCP, 8883 for TLS)
+/// * `use_tls`   – If true, connect securely with TLS
+pub fn managed_mqtt_client(client_id: &str, broker: &str, port: u16, use_tls: bool) -> ManagedMqttClient<RumqttTransport> {
+    let mut options = mqtt_options(client_id, broker, port, use_tls);
+    options.set_clean_session(false);
+    info!("Managed MQTT client {} for {}:{}", client_id, broker, port);
+    ManagedMqttClient::new(RumqttTransport::new(options), RetryPolicy::mqtt_reconnect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Scripted transport that records what it is asked to do.
+    #[derive(Default)]
+    struct MockTransport {
+        /// Outcome of each connect: `Some(session_present)`, or `None` to refuse
+        connects: VecDeque<Option<bool>>,
+        /// What each poll returns; `None` drops the connection
+        events: VecDeque<Option<TransportEvent>>,
+        log: Vec<String>,
+    }
+
+    impl MqttTransport for MockTransport {
+        async fn connect(&mut self) -> Result<bool> {
+            self.log.push("connect".into());
+            self.connects.pop_front().flatten().ok_or_else(|| anyhow!("connection refused"))
+        }
+        async fn subscribe(&mut self, topic: &str, _qos: QoS) -> Result<()> {
+            self.log.push(format!("sub {topic}"));
+            Ok(())
+        }
+        async fn unsubscribe(&mut self, topic: &str) -> Result<()> {
+            self.log.push(format!("unsub {topic}"));
+            Ok(())
+        }
+        async fn publish(&mut self, id: u64, message: &MqttMessage) -> Result<()> {
+            self.log.push(format!("pub {} {}", id, message.topic));
+            Ok(())
+        }
+        async fn republish(&mut self, id: u64, message: &MqttMessage) -> Result<()> {
+            self.log.push(format!("dup {} {}", id, message.topic));
+            Ok(())
+        }
+        async fn release(&mut self, id: u64) -> Result<()> {
+            self.log.push(format!("rel {}", id));
+            Ok(())
+        }
+        async fn poll(&mut self) -> Result<TransportEvent> {
+            self.events.pop_front().flatten().ok_or_else(|| anyhow!("connection reset"))
+        }
+        async fn disconnect(&mut self) -> Result<()> {
+            self.log.push("disconnect".into());
+            Ok(())
+        }
+    }
+
+    fn fast_policy(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy { max_attempts, initial_backoff_ms: 1, max_backoff_ms: 1, multiplier: 1.0, jitter: 0.0 }
+    }
+
+    fn message(topic: &str) -> MqttMessage {
+        MqttMessage { topic: topic.into(), qos: QoS::AtLeastOnce, retain: false, payload: b"on".to_vec() }
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_resubscribes_and_resends() {
+        let transport = MockTransport {
+            connects: [None, Some(false), Some(true)].into(),
+            events: [
+                Some(TransportEvent::Message(message("cmd/led"))),
+                None,
+                Some(TransportEvent::Acked(0)),
+                Some(TransportEvent::Message(message("cmd/fan"))),
+            ]
+            .into(),
+            log: Vec::new(),
+        };
+        let mut client = ManagedMqttClient::new(transport, fast_policy(0));
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let seen = states.clone();
+        client.on_state_change(move |s| seen.lock().unwrap().push(s.clone()));
+
+        // Offline: the subscription is remembered, QoS 1 queued, QoS 0 refused.
+        client.subscribe("cmd/#", QoS::AtLeastOnce).await.unwrap();
+        client.publish("up/temp", QoS::AtLeastOnce, false, "21.5").await.unwrap();
+        assert!(client.publish("up/debug", QoS::AtMostOnce, false, "x").await.is_err());
+        assert_eq!(client.unacked(), 1);
+
+        assert_eq!(client.next_message().await.unwrap().topic, "cmd/led");
+        assert_eq!(client.next_message().await.unwrap().topic, "cmd/fan");
+        assert_eq!(client.unacked(), 0);
+
+        assert_eq!(
+            client.transport().log,
+            [
+                "connect", "disconnect", // refused
+                "connect", "sub cmd/#", "pub 0 up/temp", //
+                "disconnect", // reset
+                "connect", "sub cmd/#", "dup 0 up/temp",
+            ]
+        );
+        let delay = Duration::from_millis(1);
+        assert_eq!(
+            *states.lock().unwrap(),
+            [
+                MqttState::Reconnecting { attempt: 1, delay },
+                MqttState::Connected { session_present: false, replayed: 1 },
+                MqttState::Disconnected { reason: "connection reset".into() },
+                MqttState::Reconnecting { attempt: 1, delay },
+                MqttState::Connected { session_present: true, replayed: 1 },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_and_bounds_queue() {
+        let mut client = ManagedMqttClient::new(MockTransport::default(), fast_policy(3));
+        client.max_unacked = 1;
+        client.publish("up/a", QoS::ExactlyOnce, false, "1").await.unwrap();
+        assert!(client.publish("up/b", QoS::AtLeastOnce, false, "2").await.is_err());
+        assert_eq!(client.unacked(), 1);
+
+        assert!(client.next_message().await.is_err());
+        assert_eq!(client.transport().log.iter().filter(|l| *l == "connect").count(), 3);
+        assert!(!client.is_connected());
+    }
+
+    #[tokio::test]
+    async fn test_resumes_qos2_where_it_left_off() {
+        let transport = MockTransport {
+            connects: [Some(false), Some(true)].into(),
+            events: [Some(TransportEvent::Received(0)), None, Some(TransportEvent::Acked(0)), Some(TransportEvent::Acked(1)), None]
+                .into(),
+            log: Vec::new(),
+        };
+        let mut client = ManagedMqttClient::new(transport, fast_policy(2));
+
+        client.publish("up/a", QoS::ExactlyOnce, false, "1").await.unwrap();
+        client.publish("up/b", QoS::ExactlyOnce, false, "2").await.unwrap();
+        assert!(client.next_message().await.is_err());
+        assert_eq!(client.unacked(), 0);
+
+        assert_eq!(
+            client.transport().log,
+            [
+                "connect", "pub 0 up/a", "pub 1 up/b", //
+                "disconnect", // reset after PUBREC for 0
+                "connect", "rel 0", "dup 1 up/b", //
+                "disconnect", // reset
+                "connect", "disconnect", // refused
+            ]
+        );
+    }
+}
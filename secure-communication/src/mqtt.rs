@@ -9,20 +9,44 @@
 //!
 //! Provides async MQTT client (TCP + TLS) for IoT devices
 //! using the `rumqttc` crate.
+//! MQTT 3.1.1 only; MQTT 5.0 is in [`crate::mqtt5`].
 
 use rumqttc::{AsyncClient, Event, EventLoop, Incoming, MqttOptions, QoS, Transport};
 use std::time::Duration;
 use anyhow::{Context, Result};
+use sios_log::{debug, info, warn, Dbg, Disp};
 use tokio::time::sleep;
 
+use crate::mqtt_tls::MqttTlsOptions;
+use crate::retry::{Backoff, RetryPolicy};
+
 /// Create a new MQTT client (async) with TCP or TLS transport.
 ///
 /// # Arguments
 /// * `client_id` – Unique MQTT client ID
 /// * `broker`    – MQTT broker hostname (e.g., "broker.hivemq.com")
 /// * `port`      – Broker port (1883 for TCP, 8883 for TLS)
-/// * `use_tls`   – If true, connect securely with TLS
+/// * `use_tls`   – If true, connect securely with TLS (Mozilla roots only;
+///   see [`mqtt_connect_tls`] for more)
 pub fn mqtt_connect(client_id: &str, broker: &str, port: u16, use_tls: bool) -> (AsyncClient, EventLoop) {
+    AsyncClient::new(mqtt_options(client_id, broker, port, use_tls), 10)
+}
+
+/// Like [`mqtt_connect`], with TLS configured by `tls` (custom CA, client
+/// certificate, server name, ALPN).
+pub fn mqtt_connect_tls(
+    client_id: &str,
+    broker: &str,
+    port: u16,
+    tls: &MqttTlsOptions,
+) -> Result<(AsyncClient, EventLoop)> {
+    let mut mqttoptions = mqtt_options(client_id, broker, port, false);
+    tls.apply(&mut mqttoptions).context("Invalid MQTT TLS configuration")?;
+    Ok(AsyncClient::new(mqttoptions, 10))
+}
+
+/// Connection options used by [`mqtt_connect`]; same arguments.
+pub fn mqtt_options(client_id: &str, broker: &str, port: u16, use_tls: bool) -> MqttOptions {
     let mut mqttoptions = MqttOptions::new(client_id, broker, port);
     mqttoptions.set_keep_alive(Duration::from_secs(10));
 
@@ -30,7 +54,7 @@ pub fn mqtt_connect(client_id: &str, broker: &str, port: u16, use_tls: bool) ->
         mqttoptions.set_transport(Transport::Tls(rumqttc::TlsConfiguration::default()));
     }
 
-    AsyncClient::new(mqttoptions, 10)
+    mqttoptions
 }
 
 /// Publish a message to an MQTT topic.
@@ -40,7 +64,7 @@ pub async fn mqtt_publish(client: &AsyncClient, topic: &str, payload: &str) -> R
         .await
         .with_context(|| format!("Failed to publish to topic {}", topic))?;
 
-    println!(" Published to `{}`: {}", topic, payload);
+    info!("Published to `{}`: {}", topic, payload);
     Ok(())
 }
 
@@ -51,27 +75,45 @@ pub async fn mqtt_subscribe(client: &AsyncClient, topic: &str) -> Result<()> {
         .await
         .with_context(|| format!("Failed to subscribe to topic {}", topic))?;
 
-    println!("📡 Subscribed to `{}`", topic);
+    info!("Subscribed to `{}`", topic);
     Ok(())
 }
 
 /// Run the MQTT event loop to process incoming messages and connection events.
-pub async fn mqtt_event_loop(mut eventloop: EventLoop) -> Result<()> {
+///
+/// Connection errors are retried with [`RetryPolicy::mqtt_reconnect`]
+/// backoff; polling again after an error makes `rumqttc` reconnect.
+/// For re-subscription and re-sending across reconnects use
+/// [`crate::managed_mqtt::ManagedMqttClient`].
+pub async fn mqtt_event_loop(eventloop: EventLoop) -> Result<()> {
+    mqtt_event_loop_with_policy(eventloop, RetryPolicy::mqtt_reconnect()).await
+}
+
+/// Same as [`mqtt_event_loop`] with an explicit reconnect policy. Returns an
+/// error once the policy's attempt budget is exhausted.
+pub async fn mqtt_event_loop_with_policy(mut eventloop: EventLoop, policy: RetryPolicy) -> Result<()> {
+    let mut backoff = Backoff::new(policy);
     loop {
         match eventloop.poll().await {
+            Ok(Event::Incoming(Incoming::ConnAck(ack))) => {
+                info!("Connected: {:?}", Dbg(&ack));
+                backoff.reset();
+            }
             Ok(Event::Incoming(Incoming::Publish(p))) => {
-                println!("Received on `{}`: {:?}", p.topic, String::from_utf8_lossy(&p.payload));
+                info!("Received on `{}`: {:?}", p.topic.as_str(), Dbg(String::from_utf8_lossy(&p.payload)));
             }
             Ok(Event::Incoming(other)) => {
-                println!("Incoming: {:?}", other);
+                debug!("Incoming: {:?}", Dbg(&other));
             }
             Ok(Event::Outgoing(out)) => {
-                println!("Outgoing: {:?}", out);
+                debug!("Outgoing: {:?}", Dbg(&out));
             }
             Err(e) => {
-                eprintln!("MQTT error: {}", e);
-                sleep(Duration::from_secs(3)).await;
-                // TODO: add reconnect logic here if needed
+                let delay = backoff
+                    .next_delay()
+                    .with_context(|| format!("MQTT reconnect attempts exhausted: {}", e))?;
+                warn!("MQTT error: {} (reconnecting in {:?})", Disp(&e), Dbg(delay));
+                sleep(delay).await;
             }
         }
     }
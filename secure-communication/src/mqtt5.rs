@@ -0,0 +1,1041 @@
+//! SecureIoTOS MQTT 5.0 Module
+//! ---------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source / commercial use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/SecureIoTOS>
+//!
+//! MQTT 5.0 client, with a fallback to 3.1.1 for older brokers.
+//!
+//! `rumqttc` 0.17 speaks MQTT 3.1.1 only, so [`Mqtt5Client`] implements the
+//! parts of MQTT 5.0 devices use on the wire itself:
+//! - user properties, message expiry and content type on publish
+//!   ([`PublishProperties`]), also reported on received messages;
+//! - shared subscriptions (`$share/<group>/<filter>`, see
+//!   [`shared_subscription`]): the broker hands each message to one member
+//!   of the group, for load-balanced consumers;
+//! - topic aliases in both directions: repeated publishes to a topic carry
+//!   a 2-byte alias instead of the name, up to the broker's Topic Alias
+//!   Maximum; aliases the broker uses (up to
+//!   [`Mqtt5Options::topic_alias_maximum`]) are resolved before delivery.
+//!
+//! QoS 0 and 1 only. The client is single-owner: [`Mqtt5Client::poll`]
+//! reads incoming packets, acknowledges them and keeps the connection
+//! alive.
+//!
+//! Compatibility mode: [`mqtt_connect_compat`] tries 5.0 first and, if the
+//! broker rejects the protocol version in its CONNACK, connects with 3.1.1
+//! through `rumqttc`. Anything else, including a connection closed before
+//! CONNACK, is an error for the caller to retry: it does not show that the
+//! broker lacks 5.0, and falling back on it would pin a 5.0 broker to
+//! 3.1.1. [`CompatClient`] offers the same calls on both; on 3.1.1
+//! publish properties are dropped and no topic aliases are used, while
+//! shared subscription filters are passed through (many 3.1.1 brokers
+//! accept them).
+
+use anyhow::{anyhow, bail, Context, Result};
+use rumqttc::{AsyncClient, Event, EventLoop, Incoming, QoS};
+use sios_log::{debug, info, warn, Dbg, Secret};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{timeout, Instant};
+use tokio_rustls::rustls::ServerName;
+use tokio_rustls::TlsConnector;
+
+use crate::mqtt::mqtt_options;
+use crate::mqtt_tls::MqttTlsOptions;
+use crate::retry::Permanent;
+
+/// Largest packet accepted from the broker (advertised in CONNECT).
+pub const MAX_PACKET_SIZE: usize = 256 * 1024;
+/// How long to wait for CONNACK.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Packet types
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+// Property identifiers
+const PROP_PAYLOAD_FORMAT: u8 = 0x01;
+const PROP_MESSAGE_EXPIRY: u8 = 0x02;
+const PROP_CONTENT_TYPE: u8 = 0x03;
+const PROP_RESPONSE_TOPIC: u8 = 0x08;
+const PROP_CORRELATION_DATA: u8 = 0x09;
+const PROP_SUBSCRIPTION_ID: u8 = 0x0B;
+const PROP_SESSION_EXPIRY: u8 = 0x11;
+const PROP_ASSIGNED_CLIENT_ID: u8 = 0x12;
+const PROP_SERVER_KEEP_ALIVE: u8 = 0x13;
+const PROP_AUTH_METHOD: u8 = 0x15;
+const PROP_AUTH_DATA: u8 = 0x16;
+const PROP_REQUEST_PROBLEM_INFO: u8 = 0x17;
+const PROP_WILL_DELAY: u8 = 0x18;
+const PROP_REQUEST_RESPONSE_INFO: u8 = 0x19;
+const PROP_RESPONSE_INFO: u8 = 0x1A;
+const PROP_SERVER_REFERENCE: u8 = 0x1C;
+const PROP_REASON_STRING: u8 = 0x1F;
+const PROP_RECEIVE_MAXIMUM: u8 = 0x21;
+const PROP_TOPIC_ALIAS_MAXIMUM: u8 = 0x22;
+const PROP_TOPIC_ALIAS: u8 = 0x23;
+const PROP_MAXIMUM_QOS: u8 = 0x24;
+const PROP_RETAIN_AVAILABLE: u8 = 0x25;
+const PROP_USER_PROPERTY: u8 = 0x26;
+const PROP_MAXIMUM_PACKET_SIZE: u8 = 0x27;
+const PROP_WILDCARD_SUB_AVAILABLE: u8 = 0x28;
+const PROP_SUB_ID_AVAILABLE: u8 = 0x29;
+const PROP_SHARED_SUB_AVAILABLE: u8 = 0x2A;
+
+/// Error marker: the broker does not speak MQTT 5.0. It answered CONNECT
+/// with "unsupported protocol version", in the 3.1.1 (return code 0x01) or
+/// the 5.0 (reason code 0x84) encoding.
+#[derive(Debug)]
+pub struct UnsupportedProtocol;
+
+impl fmt::Display for UnsupportedProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "broker does not support MQTT 5.0")
+    }
+}
+
+impl std::error::Error for UnsupportedProtocol {}
+
+/// Connection settings.
+#[derive(Clone)]
+pub struct Mqtt5Options {
+    pub client_id: String,
+    pub host: String,
+    pub port: u16,
+    pub keep_alive: Duration,
+    /// Start a new session instead of resuming the broker's one
+    pub clean_start: bool,
+    /// Seconds the broker keeps the session after a disconnect (0 = none)
+    pub session_expiry: u32,
+    /// Most topic aliases the broker may use towards us (0 = none)
+    pub topic_alias_maximum: u16,
+    /// Username and password
+    pub credentials: Option<(String, Secret<String>)>,
+    /// TLS settings; plain TCP when `None`
+    pub tls: Option<MqttTlsOptions>,
+}
+
+impl Mqtt5Options {
+    pub fn new(client_id: &str, host: &str, port: u16) -> Self {
+        Self {
+            client_id: client_id.to_string(),
+            host: host.to_string(),
+            port,
+            keep_alive: Duration::from_secs(10),
+            clean_start: true,
+            session_expiry: 0,
+            topic_alias_maximum: 16,
+            credentials: None,
+            tls: None,
+        }
+    }
+}
+
+/// MQTT 5.0 properties of a message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PublishProperties {
+    /// Seconds the broker keeps the message for subscribers that are away
+    pub message_expiry: Option<u32>,
+    /// MIME type of the payload
+    pub content_type: Option<String>,
+    /// Application-defined name/value pairs, in order
+    pub user_properties: Vec<(String, String)>,
+}
+
+impl PublishProperties {
+    pub fn is_empty(&self) -> bool {
+        self.message_expiry.is_none() && self.content_type.is_none() && self.user_properties.is_empty()
+    }
+}
+
+/// A received message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mqtt5Message {
+    /// Topic name, with any topic alias resolved
+    pub topic: String,
+    pub qos: QoS,
+    pub retain: bool,
+    pub payload: Vec<u8>,
+    pub properties: PublishProperties,
+}
+
+/// What the broker announced in CONNACK.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrokerCapabilities {
+    pub session_present: bool,
+    /// Highest QoS the broker accepts
+    pub max_qos: QoS,
+    pub retain_available: bool,
+    pub shared_subscriptions: bool,
+    /// Most topic aliases we may use towards the broker
+    pub topic_alias_maximum: u16,
+    /// Client identifier the broker assigned, if ours was empty
+    pub assigned_client_id: Option<String>,
+}
+
+/// What [`Mqtt5Client::poll`] reports.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Mqtt5Event {
+    Message(Mqtt5Message),
+    /// QoS 1 publish `pkid` acknowledged; a reason of 0x80 or more means
+    /// the broker refused it
+    PubAck { pkid: u16, reason: u8 },
+    /// One reason code per filter: the granted QoS, or 0x80 or more if
+    /// refused
+    SubAck { pkid: u16, reasons: Vec<u8> },
+}
+
+/// `$share/<group>/<filter>`: a subscription shared by every client
+/// subscribing with the same `group`.
+pub fn shared_subscription(group: &str, filter: &str) -> Result<String> {
+    if group.is_empty() || group.contains(['/', '+', '#']) {
+        bail!("invalid share name {:?}", group);
+    }
+    if filter.is_empty() {
+        bail!("empty topic filter");
+    }
+    Ok(format!("$share/{}/{}", group, filter))
+}
+
+/// Byte stream to the broker (TCP or TLS).
+pub trait BrokerStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> BrokerStream for T {}
+
+/// MQTT 5.0 client over one broker connection.
+pub struct Mqtt5Client {
+    stream: Box<dyn BrokerStream>,
+    /// Received bytes not yet forming a whole packet
+    buf: Vec<u8>,
+    capabilities: BrokerCapabilities,
+    keep_alive: Duration,
+    last_sent: Instant,
+    ping_outstanding: bool,
+    next_pkid: u16,
+    /// Topic -> alias we assigned
+    aliases_out: HashMap<String, u16>,
+    /// Alias -> topic the broker assigned
+    aliases_in: HashMap<u16, String>,
+    alias_in_max: u16,
+}
+
+impl Mqtt5Client {
+    /// Connect to the broker in `options` with MQTT 5.0. Fails with
+    /// [`UnsupportedProtocol`] if the broker only speaks an older version.
+    pub async fn connect(options: &Mqtt5Options) -> Result<Self> {
+        let stream = open_stream(options).await?;
+        Self::handshake(stream, options).await
+    }
+
+    /// Run the CONNECT/CONNACK exchange over an open `stream`.
+    pub async fn handshake(stream: Box<dyn BrokerStream>, options: &Mqtt5Options) -> Result<Self> {
+        let mut client = Self {
+            stream,
+            buf: Vec::new(),
+            capabilities: BrokerCapabilities {
+                session_present: false,
+                max_qos: QoS::AtLeastOnce,
+                retain_available: true,
+                shared_subscriptions: true,
+                topic_alias_maximum: 0,
+                assigned_client_id: None,
+            },
+            keep_alive: options.keep_alive,
+            last_sent: Instant::now(),
+            ping_outstanding: false,
+            next_pkid: 0,
+            aliases_out: HashMap::new(),
+            aliases_in: HashMap::new(),
+            alias_in_max: options.topic_alias_maximum,
+        };
+        client.send(&encode_connect(options)?).await?;
+
+        let frame = timeout(CONNECT_TIMEOUT, read_frame(&mut client.stream, &mut client.buf))
+            .await
+            .map_err(|_| anyhow!("no CONNACK within {:?}", CONNECT_TIMEOUT))??;
+        // Could be a 3.1.1 broker, or just a network or broker hiccup.
+        let Some((header, body)) = frame else {
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "connection closed before CONNACK"))
+                .context("MQTT 5.0 handshake failed");
+        };
+        if header >> 4 != CONNACK {
+            bail!("expected CONNACK, got packet type {}", header >> 4);
+        }
+        // 3.1.1 CONNACK, return code 1: unacceptable protocol version
+        if body.len() == 2 && body[1] == 0x01 {
+            return Err(UnsupportedProtocol.into());
+        }
+        let mut r = Reader::new(&body);
+        let session_present = r.u8()? & 0x01 == 1;
+        let reason = r.u8()?;
+        let props = if r.is_empty() { Properties::default() } else { read_properties(&mut r)? };
+        let refusal = || match &props.reason_string {
+            Some(text) => format!("broker refused connection: 0x{:02x} ({})", reason, text),
+            None => format!("broker refused connection: 0x{:02x}", reason),
+        };
+        match reason {
+            0x00 => {}
+            0x84 => return Err(UnsupportedProtocol.into()),
+            // Client identifier not valid, bad credentials, not authorized,
+            // banned, bad authentication method
+            0x85 | 0x86 | 0x87 | 0x8A | 0x8C => return Err(Permanent(refusal()).into()),
+            _ => bail!(refusal()),
+        }
+
+        client.capabilities = BrokerCapabilities {
+            session_present,
+            max_qos: match props.maximum_qos {
+                Some(0) => QoS::AtMostOnce,
+                Some(1) => QoS::AtLeastOnce,
+                _ => QoS::ExactlyOnce,
+            },
+            retain_available: props.retain_available.unwrap_or(true),
+            shared_subscriptions: props.shared_available.unwrap_or(true),
+            topic_alias_maximum: props.topic_alias_maximum.unwrap_or(0),
+            assigned_client_id: props.assigned_client_id,
+        };
+        if let Some(secs) = props.server_keep_alive {
+            client.keep_alive = Duration::from_secs(secs.into());
+        }
+        info!("MQTT 5.0 connected to {}:{} (session present: {})", options.host.as_str(), options.port, session_present);
+        Ok(client)
+    }
+
+    pub fn capabilities(&self) -> &BrokerCapabilities {
+        &self.capabilities
+    }
+
+    /// Publish a message. Returns its packet identifier (0 for QoS 0).
+    pub async fn publish(
+        &mut self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: &[u8],
+        properties: &PublishProperties,
+    ) -> Result<u16> {
+        if qos == QoS::ExactlyOnce {
+            bail!("QoS 2 is not supported by the MQTT 5.0 client");
+        }
+        if qos as u8 > self.capabilities.max_qos as u8 {
+            bail!("broker accepts at most {:?}", self.capabilities.max_qos);
+        }
+        if retain && !self.capabilities.retain_available {
+            bail!("broker does not support retained messages");
+        }
+        let pkid = if qos == QoS::AtMostOnce { 0 } else { self.next_pkid() };
+        let (wire_topic, alias) = self.alias_for(topic);
+        let packet = encode_publish(wire_topic, qos, retain, pkid, alias, properties, payload)?;
+        self.send(&packet).await.with_context(|| format!("Failed to publish to topic {}", topic))?;
+        Ok(pkid)
+    }
+
+    /// Subscribe to `filter` (which may be a [`shared_subscription`]).
+    /// Returns the packet identifier the SUBACK will carry.
+    pub async fn subscribe(&mut self, filter: &str, qos: QoS) -> Result<u16> {
+        if qos == QoS::ExactlyOnce {
+            bail!("QoS 2 is not supported by the MQTT 5.0 client");
+        }
+        if filter.starts_with("$share/") && !self.capabilities.shared_subscriptions {
+            bail!("broker does not support shared subscriptions");
+        }
+        let pkid = self.next_pkid();
+        self.send(&encode_subscribe(pkid, filter, qos)?)
+            .await
+            .with_context(|| format!("Failed to subscribe to topic {}", filter))?;
+        Ok(pkid)
+    }
+
+    /// Wait for the next event, answering pings and acknowledging QoS 1
+    /// messages on the way. An error means the connection is gone.
+    pub async fn poll(&mut self) -> Result<Mqtt5Event> {
+        loop {
+            let frame = if self.keep_alive.is_zero() {
+                read_frame(&mut self.stream, &mut self.buf).await?
+            } else {
+                let idle = self.keep_alive.saturating_sub(self.last_sent.elapsed());
+                match timeout(idle, read_frame(&mut self.stream, &mut self.buf)).await {
+                    Ok(frame) => frame?,
+                    Err(_) if self.ping_outstanding => bail!("no PINGRESP within {:?}", self.keep_alive),
+                    Err(_) => {
+                        self.send(&[PINGREQ << 4, 0]).await?;
+                        self.ping_outstanding = true;
+                        continue;
+                    }
+                }
+            };
+            let (header, body) = frame.ok_or_else(|| anyhow!("connection closed by broker"))?;
+            if let Some(event) = self.handle(header, &body).await? {
+                return Ok(event);
+            }
+        }
+    }
+
+    /// Close the connection cleanly.
+    pub async fn disconnect(&mut self) -> Result<()> {
+        self.send(&[DISCONNECT << 4, 0]).await?;
+        self.stream.shutdown().await.context("MQTT connection shutdown failed")
+    }
+
+    async fn handle(&mut self, header: u8, body: &[u8]) -> Result<Option<Mqtt5Event>> {
+        let mut r = Reader::new(body);
+        match header >> 4 {
+            PUBLISH => {
+                let publish = decode_publish(header, body)?;
+                if publish.qos == QoS::ExactlyOnce {
+                    bail!("QoS 2 delivery is not supported");
+                }
+                let topic = self.resolve_alias(publish.topic, publish.properties.topic_alias)?;
+                if publish.qos == QoS::AtLeastOnce {
+                    let [hi, lo] = publish.pkid.to_be_bytes();
+                    self.send(&[PUBACK << 4, 2, hi, lo]).await?;
+                }
+                Ok(Some(Mqtt5Event::Message(Mqtt5Message {
+                    topic,
+                    qos: publish.qos,
+                    retain: publish.retain,
+                    payload: publish.payload,
+                    properties: publish.properties.into(),
+                })))
+            }
+            PUBACK => {
+                let pkid = r.u16()?;
+                let reason = if r.is_empty() { 0 } else { r.u8()? };
+                Ok(Some(Mqtt5Event::PubAck { pkid, reason }))
+            }
+            SUBACK => {
+                let pkid = r.u16()?;
+                read_properties(&mut r)?;
+                Ok(Some(Mqtt5Event::SubAck { pkid, reasons: r.rest().to_vec() }))
+            }
+            PINGRESP => {
+                self.ping_outstanding = false;
+                Ok(None)
+            }
+            DISCONNECT => {
+                let reason = if r.is_empty() { 0 } else { r.u8()? };
+                let text = if r.is_empty() { None } else { read_properties(&mut r)?.reason_string };
+                bail!("broker disconnected: 0x{:02x} {}", reason, text.unwrap_or_default())
+            }
+            other => bail!("unexpected packet type {}", other),
+        }
+    }
+
+    /// Topic to send and alias to attach for a publish to `topic`.
+    fn alias_for<'t>(&mut self, topic: &'t str) -> (&'t str, Option<u16>) {
+        if let Some(&alias) = self.aliases_out.get(topic) {
+            return ("", Some(alias));
+        }
+        let next = self.aliases_out.len() + 1;
+        if next > usize::from(self.capabilities.topic_alias_maximum) {
+            return (topic, None);
+        }
+        self.aliases_out.insert(topic.to_string(), next as u16);
+        (topic, Some(next as u16))
+    }
+
+    fn resolve_alias(&mut self, topic: String, alias: Option<u16>) -> Result<String> {
+        match alias {
+            None => Ok(topic),
+            Some(alias) if alias == 0 || alias > self.alias_in_max => bail!("topic alias {} out of range", alias),
+            Some(alias) if topic.is_empty() => {
+                self.aliases_in.get(&alias).cloned().ok_or_else(|| anyhow!("unknown topic alias {}", alias))
+            }
+            Some(alias) => {
+                self.aliases_in.insert(alias, topic.clone());
+                Ok(topic)
+            }
+        }
+    }
+
+    fn next_pkid(&mut self) -> u16 {
+        self.next_pkid = self.next_pkid.checked_add(1).unwrap_or(1);
+        self.next_pkid
+    }
+
+    async fn send(&mut self, packet: &[u8]) -> Result<()> {
+        self.stream.write_all(packet).await.context("MQTT connection write failed")?;
+        self.stream.flush().await.context("MQTT connection write failed")?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+}
+
+/// TCP connection to the broker, wrapped in TLS if `options.tls` is set.
+/// SNI is the TLS server name override if there is one, else the host.
+async fn open_stream(options: &Mqtt5Options) -> Result<Box<dyn BrokerStream>> {
+    let tcp = TcpStream::connect((options.host.as_str(), options.port))
+        .await
+        .with_context(|| format!("Failed to connect TCP to {}:{}", options.host, options.port))?;
+    let Some(tls) = &options.tls else {
+        return Ok(Box::new(tcp));
+    };
+    let name = tls.server_name_override().unwrap_or(&options.host);
+    let server_name = ServerName::try_from(name).map_err(|_| anyhow!("Invalid TLS server name {}", name))?;
+    let connector = TlsConnector::from(Arc::new(tls.client_config()?));
+    let stream = connector
+        .connect(server_name, tcp)
+        .await
+        .with_context(|| format!("TLS handshake failed with {}", name))?;
+    Ok(Box::new(stream))
+}
+
+/// A broker connection on whichever protocol version the broker accepted.
+pub enum CompatClient {
+    V5(Mqtt5Client),
+    V311 { client: AsyncClient, eventloop: Box<EventLoop> },
+}
+
+/// Connect with MQTT 5.0, or with 3.1.1 if the broker rejects 5.0.
+pub async fn mqtt_connect_compat(options: &Mqtt5Options) -> Result<CompatClient> {
+    match Mqtt5Client::connect(options).await {
+        Ok(client) => Ok(CompatClient::V5(client)),
+        Err(e) if e.is::<UnsupportedProtocol>() => {
+            warn!("Broker {} does not support MQTT 5.0; falling back to 3.1.1", options.host.as_str());
+            connect_v311(options).await
+        }
+        Err(e) => Err(e),
+    }
+}
+
+async fn connect_v311(options: &Mqtt5Options) -> Result<CompatClient> {
+    let mut mqttoptions = mqtt_options(&options.client_id, &options.host, options.port, false);
+    mqttoptions.set_keep_alive(options.keep_alive);
+    mqttoptions.set_clean_session(options.clean_start);
+    if let Some((username, password)) = &options.credentials {
+        mqttoptions.set_credentials(username, password.expose_secret());
+    }
+    if let Some(tls) = &options.tls {
+        tls.apply(&mut mqttoptions).context("Invalid MQTT TLS configuration")?;
+    }
+    let (client, mut eventloop) = AsyncClient::new(mqttoptions, 10);
+    let wait_connack = async {
+        loop {
+            if let Event::Incoming(Incoming::ConnAck(ack)) = eventloop.poll().await? {
+                return Ok::<_, anyhow::Error>(ack);
+            }
+        }
+    };
+    timeout(CONNECT_TIMEOUT, wait_connack)
+        .await
+        .map_err(|_| anyhow!("no CONNACK within {:?}", CONNECT_TIMEOUT))??;
+    info!("MQTT 3.1.1 connected to {}:{}", options.host.as_str(), options.port);
+    Ok(CompatClient::V311 { client, eventloop: Box::new(eventloop) })
+}
+
+impl CompatClient {
+    pub fn is_v5(&self) -> bool {
+        matches!(self, CompatClient::V5(_))
+    }
+
+    /// Publish a message; on 3.1.1 `properties` are dropped.
+    pub async fn publish(
+        &mut self,
+        topic: &str,
+        qos: QoS,
+        retain: bool,
+        payload: &[u8],
+        properties: &PublishProperties,
+    ) -> Result<()> {
+        match self {
+            CompatClient::V5(client) => client.publish(topic, qos, retain, payload, properties).await.map(|_| ()),
+            CompatClient::V311 { client, .. } => {
+                if !properties.is_empty() {
+                    debug!("MQTT 3.1.1: properties of publish to `{}` dropped", topic);
+                }
+                client
+                    .publish(topic, qos, retain, payload.to_vec())
+                    .await
+                    .with_context(|| format!("Failed to publish to topic {}", topic))
+            }
+        }
+    }
+
+    pub async fn subscribe(&mut self, filter: &str, qos: QoS) -> Result<()> {
+        match self {
+            CompatClient::V5(client) => client.subscribe(filter, qos).await.map(|_| ()),
+            CompatClient::V311 { client, .. } => client
+                .subscribe(filter, qos)
+                .await
+                .with_context(|| format!("Failed to subscribe to topic {}", filter)),
+        }
+    }
+
+    /// Drive the connection until a message arrives.
+    pub async fn next_message(&mut self) -> Result<Mqtt5Message> {
+        match self {
+            CompatClient::V5(client) => loop {
+                match client.poll().await? {
+                    Mqtt5Event::Message(message) => return Ok(message),
+                    Mqtt5Event::PubAck { pkid, reason } if reason >= 0x80 => {
+                        warn!("Publish {} refused by broker: {:#x}", pkid, reason);
+                    }
+                    Mqtt5Event::SubAck { pkid, reasons } if reasons.iter().any(|&r| r >= 0x80) => {
+                        warn!("Subscription {} refused by broker: {:?}", pkid, Dbg(&reasons));
+                    }
+                    _ => {}
+                }
+            },
+            CompatClient::V311 { eventloop, .. } => loop {
+                if let Event::Incoming(Incoming::Publish(p)) = eventloop.poll().await? {
+                    return Ok(Mqtt5Message {
+                        topic: p.topic.clone(),
+                        qos: p.qos,
+                        retain: p.retain,
+                        payload: p.payload.to_vec(),
+                        properties: PublishProperties::default(),
+                    });
+                }
+            },
+        }
+    }
+}
+
+// ------------------ Codec ------------------
+
+/// Properties of any packet; only the ones this client uses are kept.
+#[derive(Debug, Default)]
+struct Properties {
+    message_expiry: Option<u32>,
+    content_type: Option<String>,
+    topic_alias: Option<u16>,
+    user_properties: Vec<(String, String)>,
+    topic_alias_maximum: Option<u16>,
+    maximum_qos: Option<u8>,
+    retain_available: Option<bool>,
+    shared_available: Option<bool>,
+    assigned_client_id: Option<String>,
+    server_keep_alive: Option<u16>,
+    reason_string: Option<String>,
+}
+
+impl From<Properties> for PublishProperties {
+    fn from(p: Properties) -> Self {
+        Self { message_expiry: p.message_expiry, content_type: p.content_type, user_properties: p.user_properties }
+    }
+}
+
+struct RawPublish {
+    topic: String,
+    qos: QoS,
+    retain: bool,
+    pkid: u16,
+    properties: Properties,
+    payload: Vec<u8>,
+}
+
+/// Bounds-checked reader over a packet body.
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < n {
+            bail!("truncated MQTT packet");
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.buf)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into()?))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn varint(&mut self) -> Result<usize> {
+        let mut value = 0usize;
+        for shift in [0, 7, 14, 21] {
+            let b = self.u8()?;
+            value |= usize::from(b & 0x7F) << shift;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        bail!("malformed variable byte integer")
+    }
+
+    fn binary(&mut self) -> Result<&'a [u8]> {
+        let len = self.u16()?;
+        self.take(len.into())
+    }
+
+    fn string(&mut self) -> Result<String> {
+        Ok(std::str::from_utf8(self.binary()?).context("invalid UTF-8 in MQTT packet")?.to_string())
+    }
+}
+
+fn read_properties(r: &mut Reader) -> Result<Properties> {
+    let len = r.varint()?;
+    let mut p = Reader::new(r.take(len)?);
+    let mut props = Properties::default();
+    while !p.is_empty() {
+        match p.u8()? {
+            PROP_MESSAGE_EXPIRY => props.message_expiry = Some(p.u32()?),
+            PROP_CONTENT_TYPE => props.content_type = Some(p.string()?),
+            PROP_TOPIC_ALIAS => props.topic_alias = Some(p.u16()?),
+            PROP_USER_PROPERTY => props.user_properties.push((p.string()?, p.string()?)),
+            PROP_TOPIC_ALIAS_MAXIMUM => props.topic_alias_maximum = Some(p.u16()?),
+            PROP_MAXIMUM_QOS => props.maximum_qos = Some(p.u8()?),
+            PROP_RETAIN_AVAILABLE => props.retain_available = Some(p.u8()? == 1),
+            PROP_SHARED_SUB_AVAILABLE => props.shared_available = Some(p.u8()? == 1),
+            PROP_ASSIGNED_CLIENT_ID => props.assigned_client_id = Some(p.string()?),
+            PROP_SERVER_KEEP_ALIVE => props.server_keep_alive = Some(p.u16()?),
+            PROP_REASON_STRING => props.reason_string = Some(p.string()?),
+            // Valid but unused here: skip by type
+            PROP_PAYLOAD_FORMAT
+            | PROP_REQUEST_PROBLEM_INFO
+            | PROP_REQUEST_RESPONSE_INFO
+            | PROP_WILDCARD_SUB_AVAILABLE
+            | PROP_SUB_ID_AVAILABLE => {
+                p.u8()?;
+            }
+            PROP_RECEIVE_MAXIMUM => {
+                p.u16()?;
+            }
+            PROP_SESSION_EXPIRY | PROP_WILL_DELAY | PROP_MAXIMUM_PACKET_SIZE => {
+                p.u32()?;
+            }
+            PROP_SUBSCRIPTION_ID => {
+                p.varint()?;
+            }
+            PROP_RESPONSE_TOPIC | PROP_CORRELATION_DATA | PROP_AUTH_METHOD | PROP_AUTH_DATA | PROP_RESPONSE_INFO
+            | PROP_SERVER_REFERENCE => {
+                p.binary()?;
+            }
+            id => bail!("unknown MQTT property 0x{:02x}", id),
+        }
+    }
+    Ok(props)
+}
+
+fn decode_publish(header: u8, body: &[u8]) -> Result<RawPublish> {
+    let qos = match (header >> 1) & 0x03 {
+        0 => QoS::AtMostOnce,
+        1 => QoS::AtLeastOnce,
+        2 => QoS::ExactlyOnce,
+        _ => bail!("invalid QoS in PUBLISH"),
+    };
+    let mut r = Reader::new(body);
+    let topic = r.string()?;
+    let pkid = if qos == QoS::AtMostOnce { 0 } else { r.u16()? };
+    let properties = read_properties(&mut r)?;
+    Ok(RawPublish { topic, qos, retain: header & 0x01 == 1, pkid, properties, payload: r.rest().to_vec() })
+}
+
+/// Split the first complete packet off `buf`: its first byte and body.
+fn take_frame(buf: &mut Vec<u8>) -> Result<Option<(u8, Vec<u8>)>> {
+    let mut len = 0usize;
+    let mut at = 1;
+    for shift in [0, 7, 14, 21] {
+        let Some(&b) = buf.get(at) else {
+            return Ok(None);
+        };
+        at += 1;
+        len |= usize::from(b & 0x7F) << shift;
+        if b & 0x80 == 0 {
+            break;
+        }
+        if shift == 21 {
+            bail!("malformed MQTT remaining length");
+        }
+    }
+    if len > MAX_PACKET_SIZE {
+        bail!("MQTT packet of {} bytes exceeds the {} byte limit", len, MAX_PACKET_SIZE);
+    }
+    if buf.len() < at + len {
+        return Ok(None);
+    }
+    let header = buf[0];
+    let body = buf[at..at + len].to_vec();
+    buf.drain(..at + len);
+    Ok(Some((header, body)))
+}
+
+/// Next packet from `stream`, or `None` at end of stream. Cancel-safe:
+/// partial packets stay in `buf`.
+async fn read_frame<S: AsyncRead + Unpin + ?Sized>(stream: &mut S, buf: &mut Vec<u8>) -> Result<Option<(u8, Vec<u8>)>> {
+    let mut chunk = [0u8; 1024];
+    loop {
+        if let Some(frame) = take_frame(buf)? {
+            return Ok(Some(frame));
+        }
+        let n = stream.read(&mut chunk).await.context("MQTT connection read failed")?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+fn put_u16(out: &mut Vec<u8>, v: u16) {
+    out.extend_from_slice(&v.to_be_bytes());
+}
+
+fn put_u32(out: &mut Vec<u8>, v: u32) {
+    out.extend_from_slice(&v.to_be_bytes());
+}
+
+fn put_varint(out: &mut Vec<u8>, mut v: usize) {
+    loop {
+        let b = (v & 0x7F) as u8;
+        v >>= 7;
+        if v == 0 {
+            out.push(b);
+            return;
+        }
+        out.push(b | 0x80);
+    }
+}
+
+fn put_binary(out: &mut Vec<u8>, data: &[u8]) -> Result<()> {
+    let len = u16::try_from(data.len()).map_err(|_| anyhow!("MQTT string of {} bytes too long", data.len()))?;
+    put_u16(out, len);
+    out.extend_from_slice(data);
+    Ok(())
+}
+
+fn put_string(out: &mut Vec<u8>, s: &str) -> Result<()> {
+    put_binary(out, s.as_bytes())
+}
+
+/// Fixed header + `body`.
+fn frame(header: u8, body: Vec<u8>) -> Result<Vec<u8>> {
+    if body.len() >= 1 << 28 {
+        bail!("MQTT packet of {} bytes too large", body.len());
+    }
+    let mut out = vec![header];
+    put_varint(&mut out, body.len());
+    out.extend(body);
+    Ok(out)
+}
+
+fn encode_connect(options: &Mqtt5Options) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    put_string(&mut body, "MQTT")?;
+    body.push(5);
+    let mut flags = 0u8;
+    if options.credentials.is_some() {
+        flags |= 0x80 | 0x40;
+    }
+    if options.clean_start {
+        flags |= 0x02;
+    }
+    body.push(flags);
+    put_u16(&mut body, options.keep_alive.as_secs().min(u16::MAX.into()) as u16);
+
+    let mut props = Vec::new();
+    if options.session_expiry > 0 {
+        props.push(PROP_SESSION_EXPIRY);
+        put_u32(&mut props, options.session_expiry);
+    }
+    props.push(PROP_MAXIMUM_PACKET_SIZE);
+    put_u32(&mut props, MAX_PACKET_SIZE as u32);
+    if options.topic_alias_maximum > 0 {
+        props.push(PROP_TOPIC_ALIAS_MAXIMUM);
+        put_u16(&mut props, options.topic_alias_maximum);
+    }
+    put_varint(&mut body, props.len());
+    body.extend(props);
+
+    put_string(&mut body, &options.client_id)?;
+    if let Some((username, password)) = &options.credentials {
+        put_string(&mut body, username)?;
+        put_binary(&mut body, password.expose_secret().as_bytes())?;
+    }
+    frame(CONNECT << 4, body)
+}
+
+fn encode_publish(
+    topic: &str,
+    qos: QoS,
+    retain: bool,
+    pkid: u16,
+    alias: Option<u16>,
+    properties: &PublishProperties,
+    payload: &[u8],
+) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    put_string(&mut body, topic)?;
+    if qos != QoS::AtMostOnce {
+        put_u16(&mut body, pkid);
+    }
+    let mut props = Vec::new();
+    if let Some(secs) = properties.message_expiry {
+        props.push(PROP_MESSAGE_EXPIRY);
+        put_u32(&mut props, secs);
+    }
+    if let Some(content_type) = &properties.content_type {
+        props.push(PROP_CONTENT_TYPE);
+        put_string(&mut props, content_type)?;
+    }
+    if let Some(alias) = alias {
+        props.push(PROP_TOPIC_ALIAS);
+        put_u16(&mut props, alias);
+    }
+    for (name, value) in &properties.user_properties {
+        props.push(PROP_USER_PROPERTY);
+        put_string(&mut props, name)?;
+        put_string(&mut props, value)?;
+    }
+    put_varint(&mut body, props.len());
+    body.extend(props);
+    body.extend_from_slice(payload);
+    frame(PUBLISH << 4 | (qos as u8) << 1 | u8::from(retain), body)
+}
+
+fn encode_subscribe(pkid: u16, filter: &str, qos: QoS) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    put_u16(&mut body, pkid);
+    put_varint(&mut body, 0);
+    put_string(&mut body, filter)?;
+    body.push(qos as u8);
+    frame(SUBSCRIBE << 4 | 0x02, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+
+    fn options() -> Mqtt5Options {
+        Mqtt5Options::new("dev-1", "broker.local", 1883)
+    }
+
+    /// Read the next packet on the broker side.
+    async fn next(broker: &mut DuplexStream, buf: &mut Vec<u8>) -> (u8, Vec<u8>) {
+        read_frame(broker, buf).await.unwrap().expect("client closed")
+    }
+
+    /// Broker that answers CONNECT with `connack` and hands back its end.
+    async fn connect_with(connack: &[u8]) -> (Result<Mqtt5Client>, DuplexStream, Vec<u8>) {
+        let (client_io, mut broker) = duplex(4096);
+        let connack = connack.to_vec();
+        let broker = tokio::spawn(async move {
+            let mut buf = Vec::new();
+            let (header, body) = next(&mut broker, &mut buf).await;
+            assert_eq!(header >> 4, CONNECT);
+            assert_eq!(&body[..7], b"\x00\x04MQTT\x05");
+            broker.write_all(&connack).await.unwrap();
+            (broker, buf)
+        });
+        let client = Mqtt5Client::handshake(Box::new(client_io), &options()).await;
+        let (broker, buf) = broker.await.unwrap();
+        (client, broker, buf)
+    }
+
+    #[tokio::test]
+    async fn test_properties_and_topic_aliases() {
+        // CONNACK: topic alias maximum 4, shared subscriptions available
+        let (client, mut broker, mut buf) = connect_with(&[0x20, 8, 0, 0, 5, 0x22, 0, 4, 0x2A, 1]).await;
+        let mut client = client.unwrap();
+        assert_eq!(client.capabilities().topic_alias_maximum, 4);
+
+        let props = PublishProperties {
+            message_expiry: Some(60),
+            user_properties: vec![("unit".into(), "C".into())],
+            ..Default::default()
+        };
+        assert_eq!(client.publish("dev/1/temp", QoS::AtLeastOnce, false, b"21.5", &props).await.unwrap(), 1);
+        assert_eq!(client.publish("dev/1/temp", QoS::AtMostOnce, false, b"21.6", &props).await.unwrap(), 0);
+        client.subscribe(&shared_subscription("workers", "jobs/#").unwrap(), QoS::AtLeastOnce).await.unwrap();
+
+        let (header, body) = next(&mut broker, &mut buf).await;
+        let first = decode_publish(header, &body).unwrap();
+        assert_eq!((first.topic.as_str(), first.properties.topic_alias, first.pkid), ("dev/1/temp", Some(1), 1));
+        assert_eq!(PublishProperties::from(first.properties), props);
+        let (header, body) = next(&mut broker, &mut buf).await;
+        let second = decode_publish(header, &body).unwrap();
+        assert_eq!((second.topic.as_str(), second.properties.topic_alias), ("", Some(1)));
+        assert_eq!(second.payload, b"21.6");
+        let (header, body) = next(&mut broker, &mut buf).await;
+        assert_eq!(header, 0x82);
+        assert!(body.windows(20).any(|w| w == b"$share/workers/jobs/"));
+
+        // Broker side: acknowledge, then deliver twice through its own alias.
+        let cloud = PublishProperties { user_properties: vec![("src".into(), "cloud".into())], ..Default::default() };
+        let mut out = vec![PUBACK << 4, 2, 0, 1];
+        out.extend(encode_publish("cmd/led", QoS::AtLeastOnce, false, 7, Some(2), &cloud, b"on").unwrap());
+        out.extend(encode_publish("", QoS::AtMostOnce, false, 0, Some(2), &Default::default(), b"off").unwrap());
+        broker.write_all(&out).await.unwrap();
+
+        assert_eq!(client.poll().await.unwrap(), Mqtt5Event::PubAck { pkid: 1, reason: 0 });
+        let Mqtt5Event::Message(on) = client.poll().await.unwrap() else { panic!("expected a message") };
+        assert_eq!((on.topic.as_str(), on.payload.as_slice()), ("cmd/led", &b"on"[..]));
+        assert_eq!(on.properties, cloud);
+        let Mqtt5Event::Message(off) = client.poll().await.unwrap() else { panic!("expected a message") };
+        assert_eq!((off.topic.as_str(), off.payload.as_slice()), ("cmd/led", &b"off"[..]));
+        assert_eq!(next(&mut broker, &mut buf).await, (PUBACK << 4, vec![0, 7]));
+    }
+
+    #[tokio::test]
+    async fn test_rejections_and_fallback_detection() {
+        // 3.1.1 broker: "unacceptable protocol version"
+        let (client, _, _) = connect_with(&[0x20, 2, 0, 1]).await;
+        assert!(client.err().unwrap().is::<UnsupportedProtocol>());
+        // 5.0 reason code "unsupported protocol version"
+        let (client, _, _) = connect_with(&[0x20, 3, 0, 0x84, 0]).await;
+        assert!(client.err().unwrap().is::<UnsupportedProtocol>());
+        // Not authorized: retrying cannot help
+        let (client, _, _) = connect_with(&[0x20, 3, 0, 0x87, 0]).await;
+        assert!(client.err().unwrap().is::<Permanent>());
+        // Other refusals are neither
+        let (client, _, _) = connect_with(&[0x20, 3, 0, 0x88, 0]).await;
+        let e = client.err().unwrap();
+        assert!(!e.is::<UnsupportedProtocol>() && !e.is::<Permanent>());
+
+        // Closed before CONNACK: an I/O error to retry, not a reason to fall back
+        let (client_io, mut broker) = duplex(4096);
+        let broker = tokio::spawn(async move {
+            next(&mut broker, &mut Vec::new()).await;
+        });
+        let e = Mqtt5Client::handshake(Box::new(client_io), &options()).await.err().unwrap();
+        broker.await.unwrap();
+        assert!(!e.is::<UnsupportedProtocol>());
+        assert_eq!(e.downcast_ref::<std::io::Error>().unwrap().kind(), std::io::ErrorKind::UnexpectedEof);
+
+        // Broker without shared subscriptions
+        let (client, _broker, _) = connect_with(&[0x20, 5, 0, 0, 2, 0x2A, 0]).await;
+        let mut client = client.unwrap();
+        assert!(client.subscribe("$share/g/jobs", QoS::AtLeastOnce).await.is_err());
+
+        assert_eq!(shared_subscription("g1", "dev/+/temp").unwrap(), "$share/g1/dev/+/temp");
+        assert!(shared_subscription("a/b", "dev/#").is_err());
+        assert!(shared_subscription("", "dev/#").is_err());
+    }
+}
@@ -0,0 +1,380 @@
+//! SecureIoTOS MQTT TLS Configuration Module
+//! -----------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source / commercial use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/SecureIoTOS>
+//!
+//! TLS settings for broker connections, as needed by managed IoT clouds.
+//!
+//! [`MqttTlsOptions`] builds the `rumqttc` TLS configuration from:
+//! - trusted roots: the broker's CA certificates (PEM), or the Mozilla
+//!   roots when none are given;
+//! - a client certificate for mutual TLS. The device identity key
+//!   provisioned by `auth_identity` never leaves the key store, so the
+//!   handshake signature is produced through a [`DeviceSigner`]
+//!   ([`KeyStoreSigner`] signs with that key in place); the certificate is
+//!   the one `DeviceIdentity::certificate` returns. A PEM key can be used
+//!   instead where the key is a file;
+//! - a server name override: the name the broker certificate is checked
+//!   against, for brokers reached by IP address or an alias. `rumqttc`
+//!   still sends the broker address as SNI; [`crate::mqtt5`] sends this
+//!   name;
+//! - ALPN protocols, e.g. `x-amzn-mqtt-ca` for AWS IoT Core on port 443.
+//!
+//! ```ignore
+//! let tls = MqttTlsOptions::new()
+//!     .ca_pem(AMAZON_ROOT_CA_PEM)
+//!     .client_identity(identity.certificate().unwrap(), KeyStoreSigner::new(keys, NET_TASK))
+//!     .alpn("x-amzn-mqtt-ca");
+//! let (client, eventloop) = mqtt_connect_tls("dev-1", "xxx.iot.eu-west-1.amazonaws.com", 443, &tls)?;
+//! ```
+
+use anyhow::{anyhow, bail, Context, Result};
+use auth_identity::provisioning::IDENTITY_KEY;
+use rumqttc::{MqttOptions, TlsConfiguration, Transport};
+use secure_storage::key_store::{Caller, KeyBlobStore, KeyStore};
+use sios_log::Secret;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use tokio_rustls::rustls::client::{
+    ResolvesClientCert, ServerCertVerified, ServerCertVerifier, WantsTransparencyPolicyOrClientCert, WebPkiVerifier,
+};
+use tokio_rustls::rustls::sign::{CertifiedKey, Signer, SigningKey};
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, ConfigBuilder, Error as TlsError, OwnedTrustAnchor, PrivateKey, RootCertStore,
+    ServerName, SignatureAlgorithm, SignatureScheme,
+};
+use webpki_roots::TLS_SERVER_ROOTS;
+
+/// ALPN protocol AWS IoT Core expects for MQTT with client certificates
+/// on port 443.
+pub const ALPN_AWS_IOT_MQTT: &str = "x-amzn-mqtt-ca";
+
+/// Signs with the device identity key (ECDSA P-256), wherever it lives.
+pub trait DeviceSigner: Send + Sync {
+    /// ECDSA P-256 / SHA-256 signature over `message`, DER-encoded.
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// [`DeviceSigner`] over the identity key `auth_identity` provisioned into
+/// the key store (slot `IDENTITY_KEY`). The key store does the signing; the
+/// key is never unwrapped outside it.
+pub struct KeyStoreSigner<K: KeyBlobStore> {
+    keys: Arc<Mutex<KeyStore<K>>>,
+    caller: Caller,
+}
+
+impl<K: KeyBlobStore> KeyStoreSigner<K> {
+    /// Sign as task `caller`, which the identity key's policy must allow.
+    pub fn new(keys: Arc<Mutex<KeyStore<K>>>, caller: Caller) -> Self {
+        Self { keys, caller }
+    }
+}
+
+impl<K: KeyBlobStore + Send> DeviceSigner for KeyStoreSigner<K> {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>> {
+        let mut keys = self.keys.lock().map_err(|_| anyhow!("key store lock poisoned"))?;
+        let signature = keys
+            .sign(IDENTITY_KEY, self.caller, message)
+            .map_err(|e| anyhow!("identity key cannot sign: {:?}", e))?;
+        Ok(signature.to_der().as_bytes().to_vec())
+    }
+}
+
+#[derive(Clone)]
+pub(crate) enum ClientIdentity {
+    /// Certificate (DER) whose key signs through a [`DeviceSigner`]
+    Device { cert_der: Vec<u8>, signer: Arc<dyn DeviceSigner> },
+    /// Certificate chain and private key, both PEM
+    Pem { cert_pem: Vec<u8>, key_pem: Secret<Vec<u8>> },
+}
+
+/// Builder for the TLS side of a broker connection.
+#[derive(Clone, Default)]
+pub struct MqttTlsOptions {
+    ca_pem: Vec<Vec<u8>>,
+    client: Option<ClientIdentity>,
+    server_name: Option<String>,
+    alpn: Vec<Vec<u8>>,
+}
+
+impl MqttTlsOptions {
+    /// Mozilla roots, no client certificate, no ALPN.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trust the CA certificates in `pem` instead of the Mozilla roots.
+    /// May be called several times.
+    pub fn ca_pem(mut self, pem: &[u8]) -> Self {
+        self.ca_pem.push(pem.to_vec());
+        self
+    }
+
+    /// Authenticate with the device certificate `cert_der`, signing with
+    /// `signer`.
+    pub fn client_identity(mut self, cert_der: &[u8], signer: impl DeviceSigner + 'static) -> Self {
+        self.client = Some(ClientIdentity::Device { cert_der: cert_der.to_vec(), signer: Arc::new(signer) });
+        self
+    }
+
+    /// Authenticate with a certificate chain and private key (PKCS#8,
+    /// SEC1 or PKCS#1), both PEM.
+    pub fn client_cert_pem(mut self, cert_pem: &[u8], key_pem: &[u8]) -> Self {
+        self.client = Some(ClientIdentity::Pem { cert_pem: cert_pem.to_vec(), key_pem: Secret::new(key_pem.to_vec()) });
+        self
+    }
+
+    /// Check the broker certificate against `name` instead of the broker
+    /// address.
+    pub fn server_name(mut self, name: &str) -> Self {
+        self.server_name = Some(name.to_string());
+        self
+    }
+
+    /// Offer `protocol` in ALPN; protocols are offered in call order.
+    pub fn alpn(mut self, protocol: &str) -> Self {
+        self.alpn.push(protocol.as_bytes().to_vec());
+        self
+    }
+
+    /// Name set by [`Self::server_name`], if any.
+    pub(crate) fn server_name_override(&self) -> Option<&str> {
+        self.server_name.as_deref()
+    }
+
+    /// The `rustls` client configuration.
+    pub fn client_config(&self) -> Result<ClientConfig> {
+        let roots = roots(&self.ca_pem)?;
+        let builder = ClientConfig::builder().with_safe_defaults().with_root_certificates(roots.clone());
+        let mut config = with_client_auth(builder, self.client.as_ref())?;
+        if let Some(name) = &self.server_name {
+            let name = ServerName::try_from(name.as_str()).map_err(|_| anyhow!("Invalid TLS server name {}", name))?;
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(NamedVerifier { inner: WebPkiVerifier::new(roots, None), name }));
+        }
+        config.alpn_protocols = self.alpn.clone();
+        Ok(config)
+    }
+
+    /// The configuration in the form `rumqttc` takes.
+    pub fn build(&self) -> Result<TlsConfiguration> {
+        Ok(TlsConfiguration::Rustls(Arc::new(self.client_config()?)))
+    }
+
+    /// Switch `options` to TLS with this configuration.
+    pub fn apply(&self, options: &mut MqttOptions) -> Result<()> {
+        options.set_transport(Transport::Tls(self.build()?));
+        Ok(())
+    }
+}
+
+/// The CA certificates in `ca_pem`, or the Mozilla roots if there are none.
+pub(crate) fn roots(ca_pem: &[Vec<u8>]) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    if ca_pem.is_empty() {
+        roots.add_server_trust_anchors(TLS_SERVER_ROOTS.0.iter().map(|ta| {
+            OwnedTrustAnchor::from_subject_spki_name_constraints(ta.subject, ta.spki, ta.name_constraints)
+        }));
+        return Ok(roots);
+    }
+    for pem in ca_pem {
+        for cert in read_certs(pem).context("Invalid CA certificate")? {
+            roots.add(&cert).context("Unusable CA certificate")?;
+        }
+    }
+    Ok(roots)
+}
+
+/// Finish `builder` with `client` as the client certificate, if any.
+pub(crate) fn with_client_auth(
+    builder: ConfigBuilder<ClientConfig, WantsTransparencyPolicyOrClientCert>,
+    client: Option<&ClientIdentity>,
+) -> Result<ClientConfig> {
+    Ok(match client {
+        None => builder.with_no_client_auth(),
+        Some(ClientIdentity::Device { cert_der, signer }) => {
+            let key = CertifiedKey::new(vec![Certificate(cert_der.clone())], Arc::new(DeviceKey(signer.clone())));
+            builder.with_client_cert_resolver(Arc::new(DeviceCert(Arc::new(key))))
+        }
+        Some(ClientIdentity::Pem { cert_pem, key_pem }) => {
+            let chain = read_certs(cert_pem).context("Invalid client certificate")?;
+            let key = read_key(key_pem.expose_secret())?;
+            builder.with_single_cert(chain, key).context("Client certificate does not match its key")?
+        }
+    })
+}
+
+/// All certificates in `pem`; at least one.
+pub(crate) fn read_certs(pem: &[u8]) -> Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut &pem[..]).context("malformed PEM")?;
+    if certs.is_empty() {
+        bail!("no certificate in PEM");
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// The first private key in `pem`.
+fn read_key(pem: &[u8]) -> Result<PrivateKey> {
+    use rustls_pemfile::Item;
+
+    for item in rustls_pemfile::read_all(&mut &pem[..]).context("Invalid client key: malformed PEM")? {
+        if let Item::PKCS8Key(der) | Item::ECKey(der) | Item::RSAKey(der) = item {
+            return Ok(PrivateKey(der));
+        }
+    }
+    bail!("Invalid client key: no private key in PEM")
+}
+
+/// Offers the device certificate whenever the broker accepts P-256.
+struct DeviceCert(Arc<CertifiedKey>);
+
+impl ResolvesClientCert for DeviceCert {
+    fn resolve(&self, _acceptable_issuers: &[&[u8]], sigschemes: &[SignatureScheme]) -> Option<Arc<CertifiedKey>> {
+        sigschemes.contains(&SignatureScheme::ECDSA_NISTP256_SHA256).then(|| self.0.clone())
+    }
+
+    fn has_certs(&self) -> bool {
+        true
+    }
+}
+
+/// The identity key as `rustls` sees it.
+struct DeviceKey(Arc<dyn DeviceSigner>);
+
+impl SigningKey for DeviceKey {
+    fn choose_scheme(&self, offered: &[SignatureScheme]) -> Option<Box<dyn Signer>> {
+        offered
+            .contains(&SignatureScheme::ECDSA_NISTP256_SHA256)
+            .then(|| Box::new(DeviceKey(self.0.clone())) as Box<dyn Signer>)
+    }
+
+    fn algorithm(&self) -> SignatureAlgorithm {
+        SignatureAlgorithm::ECDSA
+    }
+}
+
+impl Signer for DeviceKey {
+    fn sign(&self, message: &[u8]) -> Result<Vec<u8>, TlsError> {
+        self.0.sign(message).map_err(|e| TlsError::General(format!("device key: {}", e)))
+    }
+
+    fn scheme(&self) -> SignatureScheme {
+        SignatureScheme::ECDSA_NISTP256_SHA256
+    }
+}
+
+/// WebPKI verification against a fixed server name.
+struct NamedVerifier {
+    inner: WebPkiVerifier,
+    name: ServerName,
+}
+
+impl ServerCertVerifier for NamedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        _server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        self.inner.verify_server_cert(end_entity, intermediates, &self.name, scts, ocsp_response, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const CA_PEM: &[u8] = b"-----BEGIN CERTIFICATE-----
+MIIBkjCCATmgAwIBAgIUVFiBoAKBGoS3BJ7aXIhhxANDAMwwCgYIKoZIzj0EAwIw
+HjEcMBoGA1UEAwwTU2VjdXJlSW9UT1MgVGVzdCBDQTAgFw0yNjEwMTYxNTQ1MTda
+GA8yMTI2MDkyMjE1NDUxN1owHjEcMBoGA1UEAwwTU2VjdXJlSW9UT1MgVGVzdCBD
+QTBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABLfxb6OxLTlPDe/NGO2jAu7gUZ6C
+e0dL/wPnkVn1muhOJ5DB0vXOI6HMiSJ15Oe8X9//qJkOIQH6jqhe5JQPslmjUzBR
+MB0GA1UdDgQWBBSa01FiDDEPv3lGJoQSuxQXnhtU6zAfBgNVHSMEGDAWgBSa01Fi
+DDEPv3lGJoQSuxQXnhtU6zAPBgNVHRMBAf8EBTADAQH/MAoGCCqGSM49BAMCA0cA
+MEQCIGcQomAhrB+FDgZBEdOXuvdrSXw0LamietYekLVKciclAiAzEFVoqB03l8bB
+NMsUy6aXxomqgWLGJUB8oC98oQtMWw==
+-----END CERTIFICATE-----
+";
+
+    /// Counts signatures instead of making real ones.
+    struct CountingSigner(Arc<AtomicUsize>);
+
+    impl DeviceSigner for CountingSigner {
+        fn sign(&self, _message: &[u8]) -> Result<Vec<u8>> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(vec![0x30, 0x00])
+        }
+    }
+
+    #[test]
+    fn test_custom_ca_alpn_and_server_name() {
+        let options = MqttTlsOptions::new().ca_pem(CA_PEM).alpn(ALPN_AWS_IOT_MQTT).server_name("broker.local");
+        let config = options.client_config().unwrap();
+        assert_eq!(config.alpn_protocols, [ALPN_AWS_IOT_MQTT.as_bytes()]);
+        assert!(!config.client_auth_cert_resolver.has_certs());
+        assert!(matches!(options.build().unwrap(), TlsConfiguration::Rustls(_)));
+
+        assert!(MqttTlsOptions::new().ca_pem(b"not a certificate").build().is_err());
+        assert!(MqttTlsOptions::new().server_name("bad name!").build().is_err());
+        assert!(MqttTlsOptions::new().client_cert_pem(CA_PEM, b"no key here").build().is_err());
+    }
+
+    #[test]
+    fn test_device_identity_signs_through_signer() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let cert_der = read_certs(CA_PEM).unwrap().remove(0).0;
+        let config = MqttTlsOptions::new()
+            .ca_pem(CA_PEM)
+            .client_identity(&cert_der, CountingSigner(count.clone()))
+            .client_config()
+            .unwrap();
+
+        let resolver = &config.client_auth_cert_resolver;
+        assert!(resolver.resolve(&[], &[SignatureScheme::RSA_PSS_SHA256]).is_none());
+        let key = resolver.resolve(&[], &[SignatureScheme::ECDSA_NISTP256_SHA256]).unwrap();
+        assert_eq!(key.cert[0].0, cert_der);
+        let signer = key.key.choose_scheme(&[SignatureScheme::ECDSA_NISTP256_SHA256]).unwrap();
+        signer.sign(b"transcript").unwrap();
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[derive(Default)]
+    struct RamBlobs(Option<Vec<u8>>);
+
+    impl KeyBlobStore for RamBlobs {
+        fn load(&mut self) -> std::result::Result<Option<Vec<u8>>, &'static str> {
+            Ok(self.0.clone())
+        }
+        fn save(&mut self, data: &[u8]) -> std::result::Result<(), &'static str> {
+            self.0 = Some(data.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_key_store_signer_uses_the_identity_key() {
+        use p256::ecdsa::signature::Verifier;
+        use p256::ecdsa::Signature;
+        use secure_storage::key_store::{Kek, KeyKind, KeyPolicy, Usage};
+
+        let mut keys = KeyStore::open(RamBlobs::default(), Kek::new(&[0x4B; 32]), 4).unwrap();
+        keys.generate(IDENTITY_KEY, KeyKind::P256, KeyPolicy::new(Usage::SIGN).for_tasks(1 << 3)).unwrap();
+        let public = keys.public_key(IDENTITY_KEY).unwrap();
+        let keys = Arc::new(Mutex::new(keys));
+
+        let der = KeyStoreSigner::new(keys.clone(), 3).sign(b"transcript").unwrap();
+        public.verify(b"transcript", &Signature::from_der(&der).unwrap()).unwrap();
+        // Another task is not allowed to use the key.
+        assert!(KeyStoreSigner::new(keys, 4).sign(b"transcript").is_err());
+    }
+}
@@ -0,0 +1,705 @@
+//! SecureIoTOS OSCORE Module
+//! -------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source / commercial use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/SecureIoTOS>
+//!
+//! Object Security for CoAP (OSCORE, RFC 8613): end-to-end protection of
+//! CoAP messages that survives proxies, unlike DTLS which ends at the
+//! first hop.
+//!
+//! - A [`SecurityContext`] is derived with HKDF-SHA256 from a pre-shared
+//!   master secret and salt, the sender and recipient IDs and an optional
+//!   ID context. Both ends hold mirrored contexts: one's sender ID is the
+//!   other's recipient ID.
+//! - Protection moves the code, the options a proxy has no business
+//!   reading (class E: Uri-Path, Content-Format, Block1/2, ...) and the
+//!   payload into a COSE_Encrypt0 object sealed with AES-CCM-16-64-128.
+//!   The outer message keeps only the routing options, the OSCORE option
+//!   and an innocuous code (POST, or 2.04 Changed for responses).
+//! - Requests carry a Partial IV from the sender sequence number; the
+//!   recipient refuses any it has accepted before, or that falls behind a
+//!   [`REPLAY_WINDOW`] of the highest accepted. Responses reuse the
+//!   request's nonce and are bound to it through the AAD.
+//!
+//! The sender sequence number must never repeat under one context, and a
+//! request accepted once must not be accepted again after a reboot, so
+//! every context persists both through its [`OscoreStore`]: the replay
+//! window on every accepted request, the sender sequence number
+//! [`SEQUENCE_RESERVE`] numbers ahead of use (RFC 8613 Appendix B.1.1).
+//! A restored context continues after the reserved numbers, skipping the
+//! ones that may have been used before the reboot. Block-wise transfers
+//! are protected block by block (inner block-wise, RFC 8613 §4.1.3.4.1).
+
+use aes::Aes128;
+use ccm::aead::{AeadInPlace, KeyInit};
+use ccm::consts::{U13, U8};
+use codec::cbor::{CborError, Encoder};
+use coap_lite::{CoapOption, MessageClass, Packet, RequestType as Method, ResponseType};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::fmt;
+use std::sync::Mutex;
+
+/// COSE algorithm AES-CCM-16-64-128, the one OSCORE mandates.
+pub const AES_CCM_16_64_128: u8 = 10;
+
+/// Received Partial IVs tracked behind the highest one.
+pub const REPLAY_WINDOW: u64 = 32;
+
+/// Sender sequence numbers reserved per store write; at most this many are
+/// skipped after a reboot.
+pub const SEQUENCE_RESERVE: u64 = 64;
+
+const KEY_LEN: usize = 16;
+const NONCE_LEN: usize = 13;
+const TAG_LEN: usize = 8;
+/// Sender and recipient IDs are at most the nonce length minus 6.
+const MAX_ID_LEN: usize = NONCE_LEN - 6;
+/// Partial IVs are at most 5 bytes.
+const MAX_SEQUENCE: u64 = (1 << 40) - 1;
+/// Encoding version of the persisted state.
+const STATE_VERSION: u8 = 1;
+/// version(1) | sender limit(8) | has highest(1) | highest(8) | seen(8)
+const STATE_LEN: usize = 26;
+
+/// Options encrypted into the COSE object (class E).
+const INNER_OPTIONS: [CoapOption; 14] = [
+    CoapOption::IfMatch,
+    CoapOption::ETag,
+    CoapOption::IfNoneMatch,
+    CoapOption::LocationPath,
+    CoapOption::UriPath,
+    CoapOption::ContentFormat,
+    CoapOption::MaxAge,
+    CoapOption::UriQuery,
+    CoapOption::Accept,
+    CoapOption::LocationQuery,
+    CoapOption::Block2,
+    CoapOption::Block1,
+    CoapOption::Size2,
+    CoapOption::Size1,
+];
+
+/// Errors of OSCORE protection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OscoreError {
+    /// The message has no OSCORE option
+    NotProtected,
+    /// The OSCORE option or the decrypted message is malformed
+    BadOption,
+    /// No security context for the request's kid and kid context
+    UnknownContext,
+    /// The request's Partial IV was seen before or is too old
+    Replay,
+    /// Authentication of the COSE object failed
+    DecryptFailed,
+    /// The sender sequence number is used up; derive a new context
+    SequenceExhausted,
+    /// Invalid context parameters or a message too large to protect
+    InvalidInput,
+    /// The context state could not be loaded or saved
+    Storage(&'static str),
+}
+
+impl OscoreError {
+    /// Response code a server answers this error with (RFC 8613 §8.2).
+    pub fn response_code(&self) -> ResponseType {
+        match self {
+            OscoreError::BadOption => ResponseType::BadOption,
+            OscoreError::DecryptFailed | OscoreError::InvalidInput => ResponseType::BadRequest,
+            OscoreError::SequenceExhausted | OscoreError::Storage(_) => ResponseType::InternalServerError,
+            OscoreError::NotProtected | OscoreError::UnknownContext | OscoreError::Replay => {
+                ResponseType::Unauthorized
+            }
+        }
+    }
+}
+
+impl fmt::Display for OscoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OscoreError::NotProtected => write!(f, "message not protected with OSCORE"),
+            OscoreError::BadOption => write!(f, "malformed OSCORE message"),
+            OscoreError::UnknownContext => write!(f, "security context not found"),
+            OscoreError::Replay => write!(f, "replay detected"),
+            OscoreError::DecryptFailed => write!(f, "decryption failed"),
+            OscoreError::SequenceExhausted => write!(f, "sender sequence number exhausted"),
+            OscoreError::InvalidInput => write!(f, "invalid OSCORE input"),
+            OscoreError::Storage(e) => write!(f, "OSCORE state storage failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for OscoreError {}
+
+/// Only encoding into a buffer that is too small fails, which sizing rules out.
+impl From<CborError> for OscoreError {
+    fn from(_: CborError) -> Self {
+        OscoreError::InvalidInput
+    }
+}
+
+/// Ties a response to its request: the request's kid and Partial IV give
+/// the response's nonce and AAD.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestBinding {
+    kid: Vec<u8>,
+    piv: Vec<u8>,
+}
+
+/// Where a [`SecurityContext`] keeps its sender sequence number and replay
+/// window across reboots, typically a small flash record. One per context.
+pub trait OscoreStore: Send {
+    /// Load the last saved state; `Ok(None)` if nothing was ever saved.
+    fn load(&mut self) -> Result<Option<Vec<u8>>, &'static str>;
+    /// Atomically replace the saved state.
+    fn save(&mut self, data: &[u8]) -> Result<(), &'static str>;
+}
+
+/// Partial IVs accepted so far: the highest and a bitmap of the ones below.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct ReplayWindow {
+    highest: Option<u64>,
+    /// Bit `i` set: `highest - 1 - i` was accepted
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn check(&self, seq: u64) -> Result<(), OscoreError> {
+        match self.highest {
+            Some(highest) if seq == highest => Err(OscoreError::Replay),
+            Some(highest) if seq < highest => {
+                let age = highest - seq;
+                if age > REPLAY_WINDOW || self.seen & (1 << (age - 1)) != 0 {
+                    Err(OscoreError::Replay)
+                } else {
+                    Ok(())
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn accept(&mut self, seq: u64) {
+        match self.highest {
+            Some(highest) if seq < highest => self.seen |= 1 << (highest - seq - 1),
+            Some(highest) => {
+                let shift = seq - highest;
+                self.seen = if shift > REPLAY_WINDOW { 0 } else { (self.seen << shift) | (1 << (shift - 1)) };
+                self.highest = Some(seq);
+            }
+            None => self.highest = Some(seq),
+        }
+        self.seen &= (1 << REPLAY_WINDOW) - 1;
+    }
+}
+
+/// The mutable part of a context and where it is persisted.
+struct State {
+    sender_sequence: u64,
+    /// Sender sequence numbers below this are reserved in the store
+    sender_limit: u64,
+    replay: ReplayWindow,
+    store: Box<dyn OscoreStore>,
+}
+
+impl State {
+    /// Restore from `store`, continuing after the reserved sequence numbers.
+    fn restore(mut store: Box<dyn OscoreStore>) -> Result<Self, OscoreError> {
+        let (sender_sequence, replay) = match store.load().map_err(OscoreError::Storage)? {
+            Some(data) => decode_state(&data).ok_or(OscoreError::Storage("corrupt OSCORE state"))?,
+            None => (0, ReplayWindow::default()),
+        };
+        Ok(Self { sender_sequence, sender_limit: sender_sequence, replay, store })
+    }
+
+    /// Save `sender_limit` and `replay`; only then do they take effect.
+    fn persist(&mut self, sender_limit: u64, replay: ReplayWindow) -> Result<(), OscoreError> {
+        let mut data = Vec::with_capacity(STATE_LEN);
+        data.push(STATE_VERSION);
+        data.extend_from_slice(&sender_limit.to_be_bytes());
+        data.push(replay.highest.is_some() as u8);
+        data.extend_from_slice(&replay.highest.unwrap_or(0).to_be_bytes());
+        data.extend_from_slice(&replay.seen.to_be_bytes());
+        self.store.save(&data).map_err(OscoreError::Storage)?;
+        self.sender_limit = sender_limit;
+        self.replay = replay;
+        Ok(())
+    }
+}
+
+fn decode_state(data: &[u8]) -> Option<(u64, ReplayWindow)> {
+    if data.len() != STATE_LEN || data[0] != STATE_VERSION {
+        return None;
+    }
+    let u64_at = |at: usize| u64::from_be_bytes(data[at..at + 8].try_into().unwrap());
+    let highest = match data[9] {
+        0 => None,
+        1 => Some(u64_at(10)),
+        _ => return None,
+    };
+    Some((u64_at(1), ReplayWindow { highest, seen: u64_at(18) }))
+}
+
+/// One end of an OSCORE association.
+pub struct SecurityContext {
+    sender_id: Vec<u8>,
+    recipient_id: Vec<u8>,
+    id_context: Option<Vec<u8>>,
+    sender_key: [u8; KEY_LEN],
+    recipient_key: [u8; KEY_LEN],
+    common_iv: [u8; NONCE_LEN],
+    state: Mutex<State>,
+}
+
+impl SecurityContext {
+    /// Derive a context (RFC 8613 §3.2) and restore its state from `store`.
+    /// `master_salt` may be empty; sender and recipient IDs are at most 7
+    /// bytes and must differ.
+    pub fn new(
+        master_secret: &[u8],
+        master_salt: &[u8],
+        sender_id: &[u8],
+        recipient_id: &[u8],
+        id_context: Option<&[u8]>,
+        store: impl OscoreStore + 'static,
+    ) -> Result<Self, OscoreError> {
+        if master_secret.is_empty()
+            || sender_id.len() > MAX_ID_LEN
+            || recipient_id.len() > MAX_ID_LEN
+            || sender_id == recipient_id
+        {
+            return Err(OscoreError::InvalidInput);
+        }
+        let hkdf = Hkdf::<Sha256>::new(Some(master_salt), master_secret);
+        let derive = |id: &[u8], kind: &str, out: &mut [u8]| {
+            let mut buf = vec![0u8; 32 + id.len() + id_context.map_or(0, <[u8]>::len)];
+            let mut info = Encoder::new(&mut buf);
+            info.array(5)?.bytes(id)?;
+            match id_context {
+                Some(context) => info.bytes(context)?,
+                None => info.null()?,
+            };
+            info.u64(AES_CCM_16_64_128 as u64)?.str(kind)?.u64(out.len() as u64)?;
+            hkdf.expand(info.as_slice(), out).map_err(|_| OscoreError::InvalidInput)
+        };
+
+        let mut context = Self {
+            sender_id: sender_id.to_vec(),
+            recipient_id: recipient_id.to_vec(),
+            id_context: id_context.map(<[u8]>::to_vec),
+            sender_key: [0; KEY_LEN],
+            recipient_key: [0; KEY_LEN],
+            common_iv: [0; NONCE_LEN],
+            state: Mutex::new(State::restore(Box::new(store))?),
+        };
+        derive(sender_id, "Key", &mut context.sender_key)?;
+        derive(recipient_id, "Key", &mut context.recipient_key)?;
+        derive(&[], "IV", &mut context.common_iv)?;
+        Ok(context)
+    }
+
+    pub fn sender_id(&self) -> &[u8] {
+        &self.sender_id
+    }
+
+    pub fn recipient_id(&self) -> &[u8] {
+        &self.recipient_id
+    }
+
+    /// Next sender sequence number.
+    pub fn sender_sequence(&self) -> u64 {
+        self.state.lock().unwrap().sender_sequence
+    }
+
+    /// Protect a request. Returns the OSCORE message and the binding its
+    /// response is checked against.
+    pub fn protect_request(&self, request: &Packet) -> Result<(Packet, RequestBinding), OscoreError> {
+        let sequence = {
+            let mut state = self.state.lock().unwrap();
+            let sequence = state.sender_sequence;
+            if sequence > MAX_SEQUENCE {
+                return Err(OscoreError::SequenceExhausted);
+            }
+            if sequence >= state.sender_limit {
+                let replay = state.replay;
+                state.persist(sequence + SEQUENCE_RESERVE, replay)?;
+            }
+            state.sender_sequence += 1;
+            sequence
+        };
+        let piv = encode_piv(sequence);
+        let nonce = self.nonce(&self.sender_id, &piv);
+        let mut outer = seal(&self.sender_key, &nonce, &aad(&self.sender_id, &piv)?, request)?;
+        outer.header.code = MessageClass::Request(Method::Post);
+        outer.add_option(CoapOption::Oscore, option_value(&piv, Some(&self.sender_id), self.id_context.as_deref()));
+        Ok((outer, RequestBinding { kid: self.sender_id.clone(), piv }))
+    }
+
+    /// Check and decrypt a request meant for this context.
+    pub fn unprotect_request(&self, request: &Packet) -> Result<(Packet, RequestBinding), OscoreError> {
+        let option = OscoreOption::of(request)?;
+        if !self.is_for(&option) {
+            return Err(OscoreError::UnknownContext);
+        }
+        let piv = option.piv.ok_or(OscoreError::BadOption)?;
+        let sequence = piv.iter().fold(0u64, |acc, b| acc << 8 | *b as u64);
+        self.state.lock().unwrap().replay.check(sequence)?;
+
+        let nonce = self.nonce(&self.recipient_id, &piv);
+        let inner = open(&self.recipient_key, &nonce, &aad(&self.recipient_id, &piv)?, request)?;
+        // Only authentic requests move the window, and only once it is saved
+        let mut state = self.state.lock().unwrap();
+        state.replay.check(sequence)?;
+        let mut replay = state.replay;
+        replay.accept(sequence);
+        let sender_limit = state.sender_limit;
+        state.persist(sender_limit, replay)?;
+        Ok((inner, RequestBinding { kid: self.recipient_id.clone(), piv }))
+    }
+
+    /// Protect the response to the request `binding` came from.
+    pub fn protect_response(&self, response: &Packet, binding: &RequestBinding) -> Result<Packet, OscoreError> {
+        let nonce = self.nonce(&binding.kid, &binding.piv);
+        let mut outer = seal(&self.sender_key, &nonce, &aad(&binding.kid, &binding.piv)?, response)?;
+        outer.header.code = MessageClass::Response(ResponseType::Changed);
+        outer.add_option(CoapOption::Oscore, option_value(&[], None, None));
+        Ok(outer)
+    }
+
+    /// Check and decrypt the response to the request `binding` came from.
+    pub fn unprotect_response(&self, response: &Packet, binding: &RequestBinding) -> Result<Packet, OscoreError> {
+        let option = OscoreOption::of(response)?;
+        let nonce = match &option.piv {
+            Some(piv) => self.nonce(&self.recipient_id, piv),
+            None => self.nonce(&binding.kid, &binding.piv),
+        };
+        open(&self.recipient_key, &nonce, &aad(&binding.kid, &binding.piv)?, response)
+    }
+
+    /// Whether a request's OSCORE option names this context.
+    fn is_for(&self, option: &OscoreOption) -> bool {
+        option.kid.as_deref() == Some(&self.recipient_id[..]) && option.kid_context == self.id_context
+    }
+
+    /// AEAD nonce from the ID and Partial IV (RFC 8613 §5.2).
+    fn nonce(&self, id: &[u8], piv: &[u8]) -> [u8; NONCE_LEN] {
+        let mut nonce = [0u8; NONCE_LEN];
+        nonce[0] = id.len() as u8;
+        nonce[1 + MAX_ID_LEN - id.len()..1 + MAX_ID_LEN].copy_from_slice(id);
+        nonce[NONCE_LEN - piv.len()..].copy_from_slice(piv);
+        for (n, iv) in nonce.iter_mut().zip(self.common_iv) {
+            *n ^= iv;
+        }
+        nonce
+    }
+}
+
+/// The context of `contexts` a protected request is for, by its kid and
+/// kid context.
+pub fn find_context<'a>(contexts: &'a [SecurityContext], request: &Packet) -> Result<&'a SecurityContext, OscoreError> {
+    let option = OscoreOption::of(request)?;
+    contexts.iter().find(|c| c.is_for(&option)).ok_or(OscoreError::UnknownContext)
+}
+
+/// Decoded value of the OSCORE option (RFC 8613 §6.1).
+#[derive(Debug, Default)]
+struct OscoreOption {
+    piv: Option<Vec<u8>>,
+    kid: Option<Vec<u8>>,
+    kid_context: Option<Vec<u8>>,
+}
+
+impl OscoreOption {
+    fn of(packet: &Packet) -> Result<Self, OscoreError> {
+        let value = packet.get_option(CoapOption::Oscore).and_then(|v| v.front()).ok_or(OscoreError::NotProtected)?;
+        let Some((&flags, mut rest)) = value.split_first() else {
+            return Ok(Self::default());
+        };
+        let piv_len = (flags & 0x07) as usize;
+        if flags & 0xe0 != 0 || piv_len > 5 {
+            return Err(OscoreError::BadOption);
+        }
+        let mut take = |n: usize| {
+            let (head, tail) = (rest.get(..n).ok_or(OscoreError::BadOption)?, &rest[n..]);
+            rest = tail;
+            Ok::<_, OscoreError>(head.to_vec())
+        };
+        let piv = if piv_len > 0 { Some(take(piv_len)?) } else { None };
+        let kid_context = if flags & 0x10 != 0 {
+            let len = take(1)?[0] as usize;
+            Some(take(len)?)
+        } else {
+            None
+        };
+        let kid = (flags & 0x08 != 0).then(|| rest.to_vec());
+        Ok(Self { piv, kid, kid_context })
+    }
+}
+
+fn option_value(piv: &[u8], kid: Option<&[u8]>, kid_context: Option<&[u8]>) -> Vec<u8> {
+    let mut flags = piv.len() as u8;
+    if kid.is_some() {
+        flags |= 0x08;
+    }
+    if kid_context.is_some() {
+        flags |= 0x10;
+    }
+    if flags == 0 {
+        return Vec::new();
+    }
+    let mut value = vec![flags];
+    value.extend_from_slice(piv);
+    if let Some(context) = kid_context {
+        value.push(context.len() as u8);
+        value.extend_from_slice(context);
+    }
+    value.extend_from_slice(kid.unwrap_or_default());
+    value
+}
+
+/// Sequence number as a Partial IV: big-endian, no leading zeros, 0 as
+/// one zero byte.
+fn encode_piv(sequence: u64) -> Vec<u8> {
+    let bytes = sequence.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count().min(7);
+    bytes[skip..].to_vec()
+}
+
+/// COSE Enc_structure with the OSCORE external AAD (RFC 8613 §5.4); no
+/// class I options are used.
+fn aad(request_kid: &[u8], request_piv: &[u8]) -> Result<Vec<u8>, OscoreError> {
+    let mut external_buf = vec![0u8; 32 + request_kid.len() + request_piv.len()];
+    let mut external = Encoder::new(&mut external_buf);
+    external.array(5)?.u64(1)?.array(1)?.u64(AES_CCM_16_64_128 as u64)?;
+    external.bytes(request_kid)?.bytes(request_piv)?.bytes(&[])?;
+
+    let mut aad = vec![0u8; 32 + external.len()];
+    let mut encoder = Encoder::new(&mut aad);
+    encoder.array(3)?.str("Encrypt0")?.bytes(&[])?.bytes(external.as_slice())?;
+    let len = encoder.len();
+    aad.truncate(len);
+    Ok(aad)
+}
+
+/// Encrypt the code, class E options and payload of `message`. Returns
+/// the outer message carrying the ciphertext; the caller sets its code
+/// and OSCORE option.
+fn seal(key: &[u8; KEY_LEN], nonce: &[u8; NONCE_LEN], aad: &[u8], message: &Packet) -> Result<Packet, OscoreError> {
+    let mut inner = Packet::new();
+    inner.header.code = message.header.code;
+    let mut outer = message.clone();
+    for option in INNER_OPTIONS {
+        if let Some(values) = message.get_option(option) {
+            inner.set_option(option, values.clone());
+            outer.clear_option(option);
+        }
+    }
+    inner.payload = message.payload.clone();
+
+    // The plaintext is the inner message without its header and token,
+    // but with its code
+    let bytes = inner.to_bytes().map_err(|_| OscoreError::InvalidInput)?;
+    let mut plaintext = Vec::with_capacity(bytes.len() - 3 + TAG_LEN);
+    plaintext.push(bytes[1]);
+    plaintext.extend_from_slice(&bytes[4..]);
+    let tag = ccm_seal(key, nonce, aad, &mut plaintext)?;
+    plaintext.extend_from_slice(&tag);
+    outer.payload = plaintext;
+    Ok(outer)
+}
+
+/// Decrypt an OSCORE message into the message it protects.
+fn open(key: &[u8; KEY_LEN], nonce: &[u8; NONCE_LEN], aad: &[u8], message: &Packet) -> Result<Packet, OscoreError> {
+    let split = message.payload.len().checked_sub(TAG_LEN).filter(|n| *n > 0).ok_or(OscoreError::DecryptFailed)?;
+    let mut plaintext = message.payload[..split].to_vec();
+    ccm_open(key, nonce, aad, &mut plaintext, &message.payload[split..])?;
+
+    let mut bytes = vec![0x40, plaintext[0], 0, 0];
+    bytes.extend_from_slice(&plaintext[1..]);
+    let inner = Packet::from_bytes(&bytes).map_err(|_| OscoreError::BadOption)?;
+
+    let mut restored = message.clone();
+    restored.clear_option(CoapOption::Oscore);
+    for option in INNER_OPTIONS {
+        restored.clear_option(option);
+        if let Some(values) = inner.get_option(option) {
+            restored.set_option(option, values.clone());
+        }
+    }
+    restored.header.code = inner.header.code;
+    restored.payload = inner.payload;
+    Ok(restored)
+}
+
+/// AES-CCM-16-64-128 (COSE algorithm 10): 13-byte nonce, 8-byte tag.
+type Aead = ccm::Ccm<Aes128, U8, U13>;
+
+/// Encrypt `data` in place and return the tag.
+fn ccm_seal(
+    key: &[u8; KEY_LEN],
+    nonce: &[u8; NONCE_LEN],
+    aad: &[u8],
+    data: &mut [u8],
+) -> Result<[u8; TAG_LEN], OscoreError> {
+    let tag = Aead::new(key.into())
+        .encrypt_in_place_detached(nonce.into(), aad, data)
+        .map_err(|_| OscoreError::InvalidInput)?;
+    Ok(tag.into())
+}
+
+/// Check `tag` and decrypt `data` in place.
+fn ccm_open(
+    key: &[u8; KEY_LEN],
+    nonce: &[u8; NONCE_LEN],
+    aad: &[u8],
+    data: &mut [u8],
+    tag: &[u8],
+) -> Result<(), OscoreError> {
+    Aead::new(key.into())
+        .decrypt_in_place_detached(nonce.into(), aad, data, tag.into())
+        .map_err(|_| OscoreError::DecryptFailed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coap::{method_of, new_request, path_of};
+    use std::sync::Arc;
+
+    const MASTER_SECRET: [u8; 16] = [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16];
+    const MASTER_SALT: [u8; 8] = [0x9e, 0x7c, 0xa9, 0x22, 0x23, 0x78, 0x63, 0x40];
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    /// Survives the context, like flash survives a reboot.
+    #[derive(Clone, Default)]
+    struct RamStore {
+        data: Arc<Mutex<Option<Vec<u8>>>>,
+        saves: Arc<Mutex<usize>>,
+    }
+
+    impl OscoreStore for RamStore {
+        fn load(&mut self) -> Result<Option<Vec<u8>>, &'static str> {
+            Ok(self.data.lock().unwrap().clone())
+        }
+        fn save(&mut self, data: &[u8]) -> Result<(), &'static str> {
+            *self.saves.lock().unwrap() += 1;
+            *self.data.lock().unwrap() = Some(data.to_vec());
+            Ok(())
+        }
+    }
+
+    fn client(store: RamStore) -> SecurityContext {
+        SecurityContext::new(&MASTER_SECRET, &MASTER_SALT, &[], &[0x01], None, store).unwrap()
+    }
+
+    fn server(store: RamStore) -> SecurityContext {
+        SecurityContext::new(&MASTER_SECRET, &MASTER_SALT, &[0x01], &[], None, store).unwrap()
+    }
+
+    fn pair() -> (SecurityContext, SecurityContext) {
+        (client(RamStore::default()), server(RamStore::default()))
+    }
+
+    fn led_request() -> Packet {
+        let mut request = new_request(Method::Put, "/actuator/led");
+        request.payload = b"on".to_vec();
+        request
+    }
+
+    #[test]
+    fn test_rfc8613_vectors() {
+        // Appendix C.1.1 and C.4
+        let (client, _) = pair();
+        assert_eq!(client.sender_key.to_vec(), hex("f0910ed7295e6ad4b54fc793154302ff"));
+        assert_eq!(client.recipient_key.to_vec(), hex("ffb14e093c94c9cac9471648b4f98710"));
+        assert_eq!(client.common_iv.to_vec(), hex("4622d4dd6d944168eefb54987c"));
+
+        client.state.lock().unwrap().sender_sequence = 20;
+        let request = new_request(Method::Get, "/tv1");
+        let (protected, _) = client.protect_request(&request).unwrap();
+        assert_eq!(protected.payload, hex("612f1092f1776f1c1668b3825e"));
+        assert_eq!(protected.get_option(CoapOption::Oscore).unwrap().front().unwrap(), &hex("0914"));
+        assert_eq!(method_of(&protected), Some(Method::Post));
+        assert_eq!(path_of(&protected), "/");
+    }
+
+    #[test]
+    fn test_roundtrip_and_replay() {
+        let (client, server) = pair();
+        let request = led_request();
+
+        let (protected, binding) = client.protect_request(&request).unwrap();
+        assert!(std::ptr::eq(find_context(std::slice::from_ref(&server), &protected).unwrap(), &server));
+        let (inner, server_binding) = server.unprotect_request(&protected).unwrap();
+        assert_eq!((method_of(&inner), path_of(&inner)), (Some(Method::Put), "/actuator/led".to_string()));
+        assert_eq!(inner.payload, b"on");
+        assert_eq!(server.unprotect_request(&protected).unwrap_err(), OscoreError::Replay);
+
+        let mut response = Packet::new();
+        response.header.code = MessageClass::Response(ResponseType::Changed);
+        response.payload = b"done".to_vec();
+        let sealed = server.protect_response(&response, &server_binding).unwrap();
+        assert_eq!(client.unprotect_response(&sealed, &binding).unwrap().payload, b"done");
+
+        // Tampering is detected, and does not burn the Partial IV
+        let (mut protected, _) = client.protect_request(&request).unwrap();
+        protected.payload[0] ^= 1;
+        assert_eq!(server.unprotect_request(&protected).unwrap_err(), OscoreError::DecryptFailed);
+        protected.payload[0] ^= 1;
+        assert!(server.unprotect_request(&protected).is_ok());
+    }
+
+    #[test]
+    fn test_state_survives_a_reboot() {
+        let (client_store, server_store) = (RamStore::default(), RamStore::default());
+        let (sender, receiver) = (client(client_store.clone()), server(server_store.clone()));
+        let (first, _) = sender.protect_request(&led_request()).unwrap();
+        let (second, _) = sender.protect_request(&led_request()).unwrap();
+        receiver.unprotect_request(&first).unwrap();
+        // One write reserves a run of sequence numbers
+        assert_eq!(*client_store.saves.lock().unwrap(), 1);
+        drop((sender, receiver));
+
+        // The restored sender skips past everything it may have used
+        let sender = client(client_store.clone());
+        assert_eq!(sender.sender_sequence(), SEQUENCE_RESERVE);
+        let (third, _) = sender.protect_request(&led_request()).unwrap();
+        assert_eq!(third.get_option(CoapOption::Oscore).unwrap().front().unwrap()[1..2], [SEQUENCE_RESERVE as u8]);
+
+        // The restored receiver still knows what it accepted
+        let receiver = server(server_store.clone());
+        assert_eq!(receiver.unprotect_request(&first).unwrap_err(), OscoreError::Replay);
+        receiver.unprotect_request(&second).unwrap();
+        receiver.unprotect_request(&third).unwrap();
+
+        // A corrupt record is an error, not a fresh start
+        *server_store.data.lock().unwrap() = Some(vec![9; 3]);
+        assert!(matches!(
+            SecurityContext::new(&MASTER_SECRET, &MASTER_SALT, &[0x01], &[], None, server_store).err(),
+            Some(OscoreError::Storage(_))
+        ));
+    }
+
+    #[test]
+    fn test_replay_window() {
+        let mut window = ReplayWindow::default();
+        for seq in [5, 3, 40] {
+            window.check(seq).unwrap();
+            window.accept(seq);
+        }
+        assert_eq!(window.check(40), Err(OscoreError::Replay));
+        assert_eq!(window.check(5), Err(OscoreError::Replay));
+        // 3 fell out of the window when 40 arrived
+        assert_eq!(window.check(3), Err(OscoreError::Replay));
+        assert_eq!(window.check(8), Ok(()));
+        assert_eq!(window.check(7), Err(OscoreError::Replay));
+    }
+}
@@ -0,0 +1,251 @@
+//! SecureIoTOS Request Manager Module
+//! ----------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source / commercial use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/SecureIoTOS>
+//!
+//! Request/response correlation with per-request timeout budgets.
+//!
+//! A single receive loop owns the socket (or MQTT event loop) and hands each
+//! incoming response to [`RequestManager::complete`] under its correlation
+//! key: the CoAP token, an MQTT packet ID, or a correlation-data string for
+//! MQTT request/response topics. Callers get a typed [`PendingRequest`]
+//! future that resolves to the matching response, fails with
+//! [`RequestError::Timeout`] once its budget is spent, and deregisters
+//! itself when dropped, so abandoning a request is cancellation.
+
+use sios_log::{debug, Dbg};
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::hash::Hash;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::{sleep, Instant, Sleep};
+
+/// Why a pending request did not produce a response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequestError {
+    /// The timeout budget ran out
+    Timeout,
+    /// The request was cancelled via [`RequestManager::cancel`] or the
+    /// manager was shut down
+    Cancelled,
+    /// Another request with the same key is still in flight
+    DuplicateKey,
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RequestError::Timeout => write!(f, "request timed out"),
+            RequestError::Cancelled => write!(f, "request cancelled"),
+            RequestError::DuplicateKey => write!(f, "duplicate request key in flight"),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {}
+
+type PendingMap<K, R> = Arc<Mutex<HashMap<K, oneshot::Sender<R>>>>;
+
+/// Correlates outstanding requests with their responses.
+///
+/// Cheap to clone; clones share the same table, so the receive loop and the
+/// callers can each hold one.
+pub struct RequestManager<K, R> {
+    pending: PendingMap<K, R>,
+}
+
+impl<K, R> Clone for RequestManager<K, R> {
+    fn clone(&self) -> Self {
+        Self { pending: self.pending.clone() }
+    }
+}
+
+impl<K: Hash + Eq + Clone + fmt::Debug, R> Default for RequestManager<K, R> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Hash + Eq + Clone + fmt::Debug, R> RequestManager<K, R> {
+    pub fn new() -> Self {
+        Self { pending: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Register a request under `key` with a timeout `budget`.
+    ///
+    /// Register *before* sending so a fast response cannot race the
+    /// registration.
+    pub fn register(&self, key: K, budget: Duration) -> Result<PendingRequest<K, R>, RequestError> {
+        let (tx, rx) = oneshot::channel();
+        let mut pending = self.pending.lock().unwrap();
+        if pending.contains_key(&key) {
+            return Err(RequestError::DuplicateKey);
+        }
+        pending.insert(key.clone(), tx);
+        Ok(PendingRequest {
+            key: Some(key),
+            rx,
+            deadline: Box::pin(sleep(budget)),
+            pending: self.pending.clone(),
+        })
+    }
+
+    /// Deliver a response. Returns `false` if nobody is waiting for `key`
+    /// (late, duplicate or unsolicited response), which callers may log.
+    pub fn complete(&self, key: &K, response: R) -> bool {
+        let waiter = self.pending.lock().unwrap().remove(key);
+        match waiter {
+            Some(tx) => tx.send(response).is_ok(),
+            None => {
+                debug!("No pending request for {:?}; dropping response", Dbg(key));
+                false
+            }
+        }
+    }
+
+    /// Cancel the request registered under `key`; its future resolves to
+    /// [`RequestError::Cancelled`].
+    pub fn cancel(&self, key: &K) -> bool {
+        self.pending.lock().unwrap().remove(key).is_some()
+    }
+
+    /// Cancel every outstanding request (e.g. when the transport goes down).
+    pub fn cancel_all(&self) {
+        self.pending.lock().unwrap().clear();
+    }
+
+    /// Number of requests currently in flight.
+    pub fn in_flight(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+}
+
+/// Future resolving to the response for one registered request.
+pub struct PendingRequest<K: Hash + Eq, R> {
+    key: Option<K>,
+    rx: oneshot::Receiver<R>,
+    deadline: Pin<Box<Sleep>>,
+    pending: PendingMap<K, R>,
+}
+
+impl<K: Hash + Eq, R> PendingRequest<K, R> {
+    /// Time left before this request times out.
+    pub fn remaining(&self) -> Duration {
+        self.deadline.deadline().saturating_duration_since(Instant::now())
+    }
+
+    fn deregister(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.pending.lock().unwrap().remove(&key);
+        }
+    }
+}
+
+// The key is never pin-projected and the timer is boxed.
+impl<K: Hash + Eq, R> Unpin for PendingRequest<K, R> {}
+
+impl<K: Hash + Eq, R> Future for PendingRequest<K, R> {
+    type Output = Result<R, RequestError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+
+        if let Poll::Ready(res) = Pin::new(&mut this.rx).poll(cx) {
+            this.key = None;
+            return Poll::Ready(res.map_err(|_| RequestError::Cancelled));
+        }
+        if this.deadline.as_mut().poll(cx).is_ready() {
+            this.deregister();
+            return Poll::Ready(Err(RequestError::Timeout));
+        }
+        Poll::Pending
+    }
+}
+
+impl<K: Hash + Eq, R> Drop for PendingRequest<K, R> {
+    fn drop(&mut self) {
+        // Dropping an unfinished request cancels it.
+        self.deregister();
+    }
+}
+
+/// Allocator for 16-bit message/packet IDs (CoAP message IDs, MQTT packet
+/// IDs). Never hands out 0, which MQTT reserves.
+#[derive(Debug)]
+pub struct MessageIdAllocator {
+    next: AtomicU16,
+}
+
+impl MessageIdAllocator {
+    /// Start from `seed`; use a random seed so IDs do not repeat across reboots.
+    pub fn new(seed: u16) -> Self {
+        Self { next: AtomicU16::new(seed.max(1)) }
+    }
+
+    pub fn next_id(&self) -> u16 {
+        loop {
+            let id = self.next.fetch_add(1, Ordering::Relaxed);
+            if id != 0 {
+                return id;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn correlates_out_of_order_responses() {
+        let mgr: RequestManager<u16, &'static str> = RequestManager::new();
+        let a = mgr.register(1, Duration::from_secs(1)).unwrap();
+        let b = mgr.register(2, Duration::from_secs(1)).unwrap();
+
+        assert!(mgr.complete(&2, "second"));
+        assert!(mgr.complete(&1, "first"));
+        assert!(!mgr.complete(&1, "duplicate"));
+
+        assert_eq!(a.await, Ok("first"));
+        assert_eq!(b.await, Ok("second"));
+        assert_eq!(mgr.in_flight(), 0);
+    }
+
+    #[tokio::test]
+    async fn times_out_and_deregisters() {
+        let mgr: RequestManager<Vec<u8>, ()> = RequestManager::new();
+        let req = mgr.register(vec![0xAB], Duration::from_millis(50)).unwrap();
+        assert_eq!(req.await, Err(RequestError::Timeout));
+        assert_eq!(mgr.in_flight(), 0);
+        assert!(!mgr.complete(&vec![0xAB], ()));
+    }
+
+    #[tokio::test]
+    async fn drop_and_cancel() {
+        let mgr: RequestManager<u16, u8> = RequestManager::new();
+        drop(mgr.register(7, Duration::from_secs(1)).unwrap());
+        assert_eq!(mgr.in_flight(), 0, "dropping the future cancels the request");
+
+        let req = mgr.register(8, Duration::from_secs(1)).unwrap();
+        assert_eq!(mgr.register(8, Duration::from_secs(1)).err(), Some(RequestError::DuplicateKey));
+        assert!(mgr.cancel(&8));
+        assert_eq!(req.await, Err(RequestError::Cancelled));
+    }
+
+    #[test]
+    fn message_ids_skip_zero() {
+        let ids = MessageIdAllocator::new(u16::MAX);
+        assert_eq!(ids.next_id(), u16::MAX);
+        assert_eq!(ids.next_id(), 1);
+    }
+}
@@ -0,0 +1,284 @@
+//! SecureIoTOS Retry Module
+//! ------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source / commercial use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/SecureIoTOS>
+//!
+//! Retry/backoff policy shared by the network modules.
+//!
+//! One [`RetryPolicy`] type drives MQTT reconnects and CoAP
+//! retransmissions, so backoff behaviour is consistent and tunable in one
+//! place. Policies are `serde`-deserializable, so they can be
+//! loaded from the device configuration file; missing fields fall back to
+//! the defaults.
+//!
+//! Operations signal special cases through the error they return:
+//! - [`RetryAfter`]: the server asked us to wait (MQTT server busy, HTTP
+//!   `Retry-After`, CoAP `Max-Age` on 5.03); the hint replaces the computed
+//!   delay, capped at `max_backoff_ms`.
+//! - [`Permanent`]: retrying cannot help (bad credentials, 4.xx); give up
+//!   immediately.
+
+use anyhow::Result;
+use sios_log::{debug, warn, Dbg, Disp};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::future::Future;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Exponential backoff with jitter.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// Total attempts including the first one; 0 means retry forever
+    pub max_attempts: u32,
+    /// Delay before the first retry
+    pub initial_backoff_ms: u64,
+    /// Upper bound for any single delay
+    pub max_backoff_ms: u64,
+    /// Growth factor between consecutive delays
+    pub multiplier: f64,
+    /// Random spread as a fraction of the delay (0.0 = none, 0.5 = ±50%)
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff_ms: 500,
+            max_backoff_ms: 30_000,
+            multiplier: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// MQTT reconnect: never give up, back off to one minute.
+    pub fn mqtt_reconnect() -> Self {
+        Self { max_attempts: 0, initial_backoff_ms: 1_000, max_backoff_ms: 60_000, ..Self::default() }
+    }
+
+    /// CoAP confirmable retransmission (RFC 7252 §4.8 defaults:
+    /// ACK_TIMEOUT 2 s, ACK_RANDOM_FACTOR 1.5, MAX_RETRANSMIT 4).
+    ///
+    /// The first timeout is drawn from [ACK_TIMEOUT, ACK_TIMEOUT × 1.5),
+    /// never below ACK_TIMEOUT: 2.5 s ± 20 %, doubling per retransmission.
+    pub fn coap() -> Self {
+        Self { max_attempts: 5, initial_backoff_ms: 2_500, max_backoff_ms: 32_000, multiplier: 2.0, jitter: 0.2 }
+    }
+
+    /// Delay before retry number `retry` (0-based), given a uniform random
+    /// sample `rand01` in [0, 1).
+    pub fn delay_for(&self, retry: u32, rand01: f64) -> Duration {
+        let base = self.initial_backoff_ms as f64 * self.multiplier.max(1.0).powi(retry.min(63) as i32);
+        let capped = base.min(self.max_backoff_ms as f64);
+        let spread = self.jitter.clamp(0.0, 1.0);
+        // Map rand01 to [1 - spread, 1 + spread)
+        let factor = 1.0 - spread + 2.0 * spread * rand01.clamp(0.0, 1.0);
+        let ms = (capped * factor).min(self.max_backoff_ms as f64).max(0.0);
+        Duration::from_millis(ms as u64)
+    }
+
+    /// True if another attempt is allowed after `attempts` have been made.
+    pub fn allows(&self, attempts: u32) -> bool {
+        self.max_attempts == 0 || attempts < self.max_attempts
+    }
+}
+
+/// Error marker: wait at least this long before the next attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryAfter(pub Duration);
+
+impl fmt::Display for RetryAfter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "server requested retry after {:?}", self.0)
+    }
+}
+
+impl std::error::Error for RetryAfter {}
+
+/// Error marker: the failure is not transient; do not retry.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Permanent(pub String);
+
+impl fmt::Display for Permanent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "permanent failure: {}", self.0)
+    }
+}
+
+impl std::error::Error for Permanent {}
+
+/// Stateful backoff iterator for loops that manage their own attempts
+/// (e.g. an event loop that reconnects on error).
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    policy: RetryPolicy,
+    attempts: u32,
+    rng: u64,
+}
+
+impl Backoff {
+    pub fn new(policy: RetryPolicy) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0x9E37_79B9_7F4A_7C15);
+        Self { policy, attempts: 0, rng: seed | 1 }
+    }
+
+    /// Record a failure and return how long to wait, or `None` once the
+    /// attempt budget is exhausted.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        self.attempts = self.attempts.saturating_add(1);
+        if !self.policy.allows(self.attempts) {
+            return None;
+        }
+        let r = self.next_rand();
+        Some(self.policy.delay_for(self.attempts - 1, r))
+    }
+
+    /// Like `next_delay`, but honour a server-provided hint.
+    pub fn next_delay_with_hint(&mut self, hint: Option<Duration>) -> Option<Duration> {
+        let computed = self.next_delay()?;
+        Some(match hint {
+            Some(h) => h.min(Duration::from_millis(self.policy.max_backoff_ms)),
+            None => computed,
+        })
+    }
+
+    /// Jittered delay for retry number `retry` without recording a failure
+    /// (for callers that count attempts themselves).
+    pub fn jittered(&mut self, retry: u32) -> Duration {
+        let r = self.next_rand();
+        self.policy.delay_for(retry, r)
+    }
+
+    /// Call after a success so the next failure starts from the initial delay.
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+
+    /// Failures recorded since the last reset.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// xorshift64*: jitter only, not for cryptographic use.
+    fn next_rand(&mut self) -> f64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let x = self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D);
+        (x >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Run `op` until it succeeds, fails permanently, or the policy gives up.
+///
+/// `op` receives the 0-based attempt number. The last error is returned
+/// when the budget is exhausted.
+pub async fn retry<T, F, Fut>(policy: &RetryPolicy, what: &str, mut op: F) -> Result<T>
+where
+    F: FnMut(u32) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut backoff = Backoff::new(policy.clone());
+    let mut attempt = 0;
+    loop {
+        match op(attempt).await {
+            Ok(v) => return Ok(v),
+            Err(e) if e.downcast_ref::<Permanent>().is_some() => return Err(e),
+            Err(e) => {
+                let hint = e.downcast_ref::<RetryAfter>().map(|r| r.0);
+                match backoff.next_delay_with_hint(hint) {
+                    Some(delay) => {
+                        warn!("{} failed (attempt {}): {}; retrying in {:?}", what, attempt + 1, Disp(&e), Dbg(delay));
+                        tokio::time::sleep(delay).await;
+                        attempt += 1;
+                    }
+                    None => {
+                        debug!("{} giving up after {} attempt(s)", what, attempt + 1);
+                        return Err(e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+
+    #[test]
+    fn delays_grow_and_cap() {
+        let p = RetryPolicy { jitter: 0.0, ..RetryPolicy::default() };
+        assert_eq!(p.delay_for(0, 0.5), Duration::from_millis(500));
+        assert_eq!(p.delay_for(1, 0.5), Duration::from_millis(1_000));
+        assert_eq!(p.delay_for(3, 0.5), Duration::from_millis(4_000));
+        assert_eq!(p.delay_for(20, 0.5), Duration::from_millis(30_000));
+    }
+
+    #[test]
+    fn jitter_stays_within_bounds() {
+        let p = RetryPolicy { jitter: 0.5, ..RetryPolicy::default() };
+        assert_eq!(p.delay_for(0, 0.0), Duration::from_millis(250));
+        assert!(p.delay_for(0, 0.999) < Duration::from_millis(750));
+    }
+
+    #[test]
+    fn coap_timeouts_follow_rfc7252() {
+        let p = RetryPolicy::coap();
+        for retry in 0..4 {
+            let (low, high) = (2_000u64 << retry, 3_000u64 << retry);
+            assert_eq!(p.delay_for(retry, 0.0), Duration::from_millis(low));
+            let longest = p.delay_for(retry, 0.999_999);
+            assert!(longest >= Duration::from_millis(high - 1) && longest < Duration::from_millis(high), "{:?}", longest);
+        }
+        assert!(p.allows(4) && !p.allows(5));
+    }
+
+    #[test]
+    fn backoff_respects_attempt_budget_and_hint() {
+        let mut b = Backoff::new(RetryPolicy { max_attempts: 3, ..RetryPolicy::default() });
+        assert_eq!(b.next_delay_with_hint(Some(Duration::from_secs(120))), Some(Duration::from_secs(30)));
+        assert!(b.next_delay().is_some());
+        assert!(b.next_delay().is_none());
+        b.reset();
+        assert!(b.next_delay().is_some());
+    }
+
+    #[test]
+    fn policy_loads_from_partial_config() {
+        let p: RetryPolicy = serde_json::from_str(r#"{ "max_attempts": 9 }"#).unwrap();
+        assert_eq!(p.max_attempts, 9);
+        assert_eq!(p.initial_backoff_ms, RetryPolicy::default().initial_backoff_ms);
+    }
+
+    #[tokio::test]
+    async fn retry_stops_on_permanent_and_succeeds_after_transient() {
+        let fast = RetryPolicy { initial_backoff_ms: 1, max_backoff_ms: 2, ..RetryPolicy::default() };
+
+        let res: Result<u32> = retry(&fast, "op", |n| async move {
+            if n < 2 { Err(anyhow!("transient")) } else { Ok(n) }
+        })
+        .await;
+        assert_eq!(res.unwrap(), 2);
+
+        let mut calls = 0;
+        let res: Result<()> = retry(&fast, "op", |_| {
+            calls += 1;
+            async { Err(Permanent("bad credentials".into()).into()) }
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(calls, 1);
+    }
+}
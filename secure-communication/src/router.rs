@@ -0,0 +1,275 @@
+//! SecureIoTOS CoAP Router Module
+//! ------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source / commercial use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/SecureIoTOS>
+//!
+//! Resource routing for the CoAP server.
+//!
+//! Applications register a handler per method and path pattern on a
+//! [`CoapRouter`] and hand it to [`crate::coap::coap_serve`]. In a pattern
+//! `*` matches one path segment and a trailing `**` the rest of the path;
+//! the matched segments reach the handler in [`RouteRequest::params`].
+//!
+//! The router answers on its own:
+//! - 4.04 Not Found when no pattern matches the path,
+//! - 4.05 Method Not Allowed when one does but not for that method,
+//! - GET `/.well-known/core` with the registered resources in CoRE Link
+//!   Format (RFC 6690), for discovery. Patterns with wildcards are not
+//!   listed, as the format cannot express them.
+
+use coap_lite::{ContentFormat, MessageClass, Packet, RequestType as Method, ResponseType};
+use sios_log::{debug, Dbg, Disp};
+use std::net::SocketAddr;
+
+use crate::coap::{method_of, path_of};
+
+/// Path of the discovery resource.
+pub const WELL_KNOWN_CORE: &str = "/.well-known/core";
+
+/// A request matched to a route.
+#[derive(Debug)]
+pub struct RouteRequest<'a> {
+    /// Sender of the request
+    pub peer: SocketAddr,
+    pub method: Method,
+    /// Full request path, e.g. `/dev/7/temp`
+    pub path: &'a str,
+    /// Segments matched by the wildcards of the pattern, in order; a
+    /// trailing `**` gives the rest of the path as one entry
+    pub params: Vec<&'a str>,
+    pub payload: &'a [u8],
+}
+
+/// What a handler answers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoapResponse {
+    pub code: ResponseType,
+    pub payload: Vec<u8>,
+}
+
+impl CoapResponse {
+    pub fn new(code: ResponseType, payload: impl Into<Vec<u8>>) -> Self {
+        Self { code, payload: payload.into() }
+    }
+
+    /// A response without payload.
+    pub fn empty(code: ResponseType) -> Self {
+        Self::new(code, Vec::new())
+    }
+}
+
+/// Request handler registered on a [`CoapRouter`].
+pub type Handler = Box<dyn Fn(&RouteRequest) -> CoapResponse + Send + Sync>;
+
+struct Route {
+    pattern: String,
+    /// Link-format attributes listed in `/.well-known/core`
+    attributes: String,
+    handlers: Vec<(Method, Handler)>,
+}
+
+/// Routes CoAP requests to handlers by method and path pattern.
+#[derive(Default)]
+pub struct CoapRouter {
+    routes: Vec<Route>,
+}
+
+impl CoapRouter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `handler` for `method` on `pattern`, replacing any earlier
+    /// one. Patterns are matched in registration order.
+    pub fn route<F>(&mut self, method: Method, pattern: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&RouteRequest) -> CoapResponse + Send + Sync + 'static,
+    {
+        let route = self.route_mut(pattern);
+        route.handlers.retain(|(m, _)| *m != method);
+        route.handlers.push((method, Box::new(handler)));
+        self
+    }
+
+    /// Shorthand: register a GET handler.
+    pub fn get<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&RouteRequest) -> CoapResponse + Send + Sync + 'static,
+    {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    /// Shorthand: register a POST handler.
+    pub fn post<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&RouteRequest) -> CoapResponse + Send + Sync + 'static,
+    {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    /// Shorthand: register a PUT handler.
+    pub fn put<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&RouteRequest) -> CoapResponse + Send + Sync + 'static,
+    {
+        self.route(Method::Put, pattern, handler)
+    }
+
+    /// Shorthand: register a DELETE handler.
+    pub fn delete<F>(&mut self, pattern: &str, handler: F) -> &mut Self
+    where
+        F: Fn(&RouteRequest) -> CoapResponse + Send + Sync + 'static,
+    {
+        self.route(Method::Delete, pattern, handler)
+    }
+
+    /// Set the link-format attributes `/.well-known/core` lists for
+    /// `pattern`, e.g. `rt="temperature";if="sensor"`.
+    pub fn describe(&mut self, pattern: &str, attributes: &str) -> &mut Self {
+        self.route_mut(pattern).attributes = attributes.to_string();
+        self
+    }
+
+    /// The `/.well-known/core` document: one link per resource without
+    /// wildcards, with its attributes.
+    pub fn well_known_core(&self) -> String {
+        self.routes
+            .iter()
+            .filter(|r| !r.pattern.split('/').any(|s| s == "*" || s == "**"))
+            .map(|r| match r.attributes.as_str() {
+                "" => format!("<{}>", r.pattern),
+                attributes => format!("<{}>;{}", r.pattern, attributes),
+            })
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    /// Answer `request` from `peer`: the response carries its message ID
+    /// and token.
+    pub fn handle(&self, peer: SocketAddr, request: &Packet) -> Packet {
+        let (method, path) = (method_of(request), path_of(request));
+        let answer = self.dispatch(peer, method, &path, &request.payload);
+        debug!("{:?} {} from {} -> {:?}", Dbg(method), path.as_str(), Disp(peer), Dbg(answer.code));
+
+        let mut response = Packet::new();
+        response.header.message_id = request.header.message_id;
+        response.set_token(request.get_token().clone());
+        response.header.code = MessageClass::Response(answer.code);
+        response.payload = answer.payload;
+        if path == WELL_KNOWN_CORE && answer.code == ResponseType::Content {
+            response.set_content_format(ContentFormat::ApplicationLinkFormat);
+        }
+        response
+    }
+
+    fn dispatch(&self, peer: SocketAddr, method: Option<Method>, path: &str, payload: &[u8]) -> CoapResponse {
+        let Some(method) = method else {
+            return CoapResponse::empty(ResponseType::MethodNotAllowed);
+        };
+        let mut path_known = false;
+        for route in &self.routes {
+            let Some(params) = match_path(&route.pattern, path) else {
+                continue;
+            };
+            path_known = true;
+            if let Some((_, handler)) = route.handlers.iter().find(|(m, _)| *m == method) {
+                return handler(&RouteRequest { peer, method, path, params, payload });
+            }
+        }
+        if path_known {
+            return CoapResponse::empty(ResponseType::MethodNotAllowed);
+        }
+        match (path == WELL_KNOWN_CORE, method) {
+            (true, Method::Get) => CoapResponse::new(ResponseType::Content, self.well_known_core()),
+            (true, _) => CoapResponse::empty(ResponseType::MethodNotAllowed),
+            (false, _) => CoapResponse::empty(ResponseType::NotFound),
+        }
+    }
+
+    fn route_mut(&mut self, pattern: &str) -> &mut Route {
+        let pattern = format!("/{}", pattern.trim_matches('/'));
+        let at = match self.routes.iter().position(|r| r.pattern == pattern) {
+            Some(at) => at,
+            None => {
+                self.routes.push(Route { pattern, attributes: String::new(), handlers: Vec::new() });
+                self.routes.len() - 1
+            }
+        };
+        &mut self.routes[at]
+    }
+}
+
+/// Match `path` against `pattern`. Returns the segments the wildcards
+/// matched.
+fn match_path<'a>(pattern: &str, path: &'a str) -> Option<Vec<&'a str>> {
+    let mut params = Vec::new();
+    let mut rest = path.trim_matches('/');
+    for expected in pattern.trim_matches('/').split('/').filter(|s| !s.is_empty()) {
+        if expected == "**" {
+            params.push(rest);
+            return Some(params);
+        }
+        if rest.is_empty() {
+            return None;
+        }
+        let (segment, tail) = rest.split_once('/').unwrap_or((rest, ""));
+        match expected {
+            "*" => params.push(segment),
+            literal if literal == segment => {}
+            _ => return None,
+        }
+        rest = tail;
+    }
+    rest.is_empty().then_some(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coap::new_request as request;
+
+    fn peer() -> SocketAddr {
+        "127.0.0.1:5683".parse().unwrap()
+    }
+
+    #[test]
+    fn test_wildcards_and_automatic_errors() {
+        let mut router = CoapRouter::new();
+        router
+            .put("/dev/*/*", |req| CoapResponse::new(ResponseType::Changed, req.params.join(":")))
+            .get("/fw/**", |req| CoapResponse::new(ResponseType::Content, req.params[0]));
+
+        let response = router.handle(peer(), &request(Method::Put, "/dev/7/temp"));
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Changed));
+        assert_eq!(response.payload, b"7:temp");
+        let response = router.handle(peer(), &request(Method::Get, "/fw/v2/image.bin"));
+        assert_eq!(response.payload, b"v2/image.bin");
+
+        let code = |method, path| router.handle(peer(), &request(method, path)).header.code;
+        assert_eq!(code(Method::Put, "/dev/7"), MessageClass::Response(ResponseType::NotFound));
+        assert_eq!(code(Method::Put, "/dev/7/temp/raw"), MessageClass::Response(ResponseType::NotFound));
+        assert_eq!(code(Method::Get, "/dev/7/temp"), MessageClass::Response(ResponseType::MethodNotAllowed));
+        assert_eq!(code(Method::Post, WELL_KNOWN_CORE), MessageClass::Response(ResponseType::MethodNotAllowed));
+    }
+
+    #[test]
+    fn test_well_known_core_lists_resources() {
+        let mut router = CoapRouter::new();
+        router
+            .get("/sensor/temp", |_| CoapResponse::new(ResponseType::Content, "23.5"))
+            .describe("/sensor/temp", "rt=\"temperature\";if=\"sensor\"")
+            .put("actuator/led", |_| CoapResponse::empty(ResponseType::Changed))
+            .put("/dev/*/*", |_| CoapResponse::empty(ResponseType::Changed));
+
+        let response = router.handle(peer(), &request(Method::Get, WELL_KNOWN_CORE));
+        assert_eq!(response.header.code, MessageClass::Response(ResponseType::Content));
+        assert_eq!(
+            String::from_utf8(response.payload).unwrap(),
+            "</sensor/temp>;rt=\"temperature\";if=\"sensor\",</actuator/led>"
+        );
+    }
+}
@@ -0,0 +1,408 @@
+//! SecureIoTOS Backend Simulator Module
+//! ------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source / commercial use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/SecureIoTOS>
+//!
+//! Captive backend simulator for integration tests (std only).
+//!
+//! Stands up three local services on ephemeral ports:
+//! - an MQTT 3.1.1 broker stub (CONNECT, PUBLISH QoS 0/1, SUBSCRIBE, PING),
+//!   which records everything published to it;
+//! - a CoAP server answering GET on scripted resources;
+//! - an HTTP/1.1 OTA file server serving `/firmware.bin` and `/firmware.sig`.
+//!
+//! Each service follows a [`FaultScript`]: a queue of per-request actions
+//! (drop, delay, corrupt) consumed in order, so a test can say "drop the first
+//! two publishes, then answer slowly" and exercise retry/failover paths of
+//! the full device pipeline without a real backend.
+//!
+//! Enabled in unit tests and with the `simulator` feature.
+
+use anyhow::{Context, Result};
+use coap_lite::{MessageClass, Packet, ResponseType};
+use sios_log::{debug, Disp};
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::task::JoinHandle;
+
+use crate::coap::path_of;
+
+/// What a simulated service does with one request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultAction {
+    /// Answer normally
+    Normal,
+    /// Swallow the request without answering (MQTT/HTTP also close the connection)
+    Drop,
+    /// Answer normally after a delay
+    Delay(Duration),
+    /// Answer with corrupted content (flipped signature byte for OTA,
+    /// error response code for CoAP, rejected CONNACK for MQTT)
+    Corrupt,
+}
+
+/// Ordered list of actions; once exhausted every request is `Normal`.
+#[derive(Debug, Clone, Default)]
+pub struct FaultScript {
+    actions: Arc<Mutex<VecDeque<FaultAction>>>,
+}
+
+impl FaultScript {
+    pub fn new(actions: impl IntoIterator<Item = FaultAction>) -> Self {
+        Self { actions: Arc::new(Mutex::new(actions.into_iter().collect())) }
+    }
+
+    /// Append further actions while the simulator is running.
+    pub fn push(&self, action: FaultAction) {
+        self.actions.lock().unwrap().push_back(action);
+    }
+
+    fn next(&self) -> FaultAction {
+        self.actions.lock().unwrap().pop_front().unwrap_or(FaultAction::Normal)
+    }
+}
+
+/// Fault scripts and content for each simulated service.
+#[derive(Debug, Clone, Default)]
+pub struct SimulatorConfig {
+    pub mqtt_faults: FaultScript,
+    pub coap_faults: FaultScript,
+    pub ota_faults: FaultScript,
+    /// CoAP path (without leading '/') → payload
+    pub coap_resources: HashMap<String, Vec<u8>>,
+    pub firmware: Vec<u8>,
+    pub firmware_signature: Vec<u8>,
+}
+
+/// (topic, payload) pairs received by the MQTT stub.
+type PublishLog = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+/// A running simulator. Services stop when this is dropped.
+pub struct BackendSimulator {
+    pub mqtt_addr: SocketAddr,
+    pub coap_addr: SocketAddr,
+    pub ota_addr: SocketAddr,
+    published: PublishLog,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl BackendSimulator {
+    /// Start all services on 127.0.0.1 with ephemeral ports.
+    pub async fn start(config: SimulatorConfig) -> Result<Self> {
+        let published = Arc::new(Mutex::new(Vec::new()));
+
+        let mqtt = TcpListener::bind("127.0.0.1:0").await.context("Failed to bind MQTT stub")?;
+        let coap = UdpSocket::bind("127.0.0.1:0").await.context("Failed to bind CoAP stub")?;
+        let ota = TcpListener::bind("127.0.0.1:0").await.context("Failed to bind OTA stub")?;
+
+        let sim_addrs = (mqtt.local_addr()?, coap.local_addr()?, ota.local_addr()?);
+
+        let mut tasks = Vec::new();
+        {
+            let faults = config.mqtt_faults.clone();
+            let published = published.clone();
+            tasks.push(tokio::spawn(async move {
+                while let Ok((stream, _)) = mqtt.accept().await {
+                    tokio::spawn(serve_mqtt(stream, faults.clone(), published.clone()));
+                }
+            }));
+        }
+        {
+            let faults = config.coap_faults.clone();
+            let resources = config.coap_resources.clone();
+            tasks.push(tokio::spawn(async move {
+                let _ = serve_coap(coap, faults, resources).await;
+            }));
+        }
+        {
+            let faults = config.ota_faults.clone();
+            let files = Arc::new((config.firmware.clone(), config.firmware_signature.clone()));
+            tasks.push(tokio::spawn(async move {
+                while let Ok((stream, _)) = ota.accept().await {
+                    tokio::spawn(serve_ota(stream, faults.clone(), files.clone()));
+                }
+            }));
+        }
+
+        Ok(Self {
+            mqtt_addr: sim_addrs.0,
+            coap_addr: sim_addrs.1,
+            ota_addr: sim_addrs.2,
+            published,
+            tasks,
+        })
+    }
+
+    /// Everything published to the MQTT stub so far, in arrival order.
+    pub fn published(&self) -> Vec<(String, Vec<u8>)> {
+        self.published.lock().unwrap().clone()
+    }
+}
+
+impl Drop for BackendSimulator {
+    fn drop(&mut self) {
+        for t in &self.tasks {
+            t.abort();
+        }
+    }
+}
+
+/// Apply a fault action; returns false if the request must be dropped.
+async fn apply(action: FaultAction) -> bool {
+    match action {
+        FaultAction::Drop => false,
+        FaultAction::Delay(d) => {
+            tokio::time::sleep(d).await;
+            true
+        }
+        FaultAction::Normal | FaultAction::Corrupt => true,
+    }
+}
+
+// ---------------------------
+// MQTT 3.1.1 broker stub
+// ---------------------------
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+/// Read one MQTT control packet: (first header byte, body).
+async fn read_packet(stream: &mut TcpStream) -> Result<(u8, Vec<u8>)> {
+    let header = stream.read_u8().await?;
+    // Remaining length: up to 4 bytes, 7 bits each
+    let mut len = 0usize;
+    for shift in (0..28).step_by(7) {
+        let b = stream.read_u8().await?;
+        len |= ((b & 0x7F) as usize) << shift;
+        if b & 0x80 == 0 {
+            break;
+        }
+    }
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+    Ok((header, body))
+}
+
+async fn serve_mqtt(mut stream: TcpStream, faults: FaultScript, published: PublishLog) {
+    while let Ok((header, body)) = read_packet(&mut stream).await {
+        let kind = header >> 4;
+        if kind == DISCONNECT {
+            return;
+        }
+        if kind == PINGREQ {
+            let _ = stream.write_all(&[PINGRESP << 4, 0]).await;
+            continue;
+        }
+
+        let action = faults.next();
+        if !apply(action).await {
+            debug!("simulator: dropping MQTT packet type {}", kind);
+            return;
+        }
+
+        let reply: Option<Vec<u8>> = match kind {
+            // Return code 5 = not authorized
+            CONNECT if action == FaultAction::Corrupt => Some(vec![CONNACK << 4, 2, 0, 5]),
+            CONNECT => Some(vec![CONNACK << 4, 2, 0, 0]),
+            PUBLISH => {
+                let qos = (header >> 1) & 0x03;
+                if body.len() < 2 {
+                    return;
+                }
+                let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                let Some(topic) = body.get(2..2 + topic_len) else { return };
+                let mut rest = 2 + topic_len;
+                let packet_id = if qos > 0 && body.len() >= rest + 2 {
+                    rest += 2;
+                    Some([body[rest - 2], body[rest - 1]])
+                } else {
+                    None
+                };
+                published
+                    .lock()
+                    .unwrap()
+                    .push((String::from_utf8_lossy(topic).into_owned(), body[rest..].to_vec()));
+                packet_id.map(|id| vec![PUBACK << 4, 2, id[0], id[1]])
+            }
+            SUBSCRIBE if body.len() >= 2 => {
+                // Grant QoS 1 for a single topic filter
+                Some(vec![SUBACK << 4, 3, body[0], body[1], 1])
+            }
+            _ => None,
+        };
+
+        if let Some(r) = reply {
+            if stream.write_all(&r).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// ---------------------------
+// CoAP server stub
+// ---------------------------
+
+async fn serve_coap(socket: UdpSocket, faults: FaultScript, resources: HashMap<String, Vec<u8>>) -> Result<()> {
+    let mut buf = [0u8; 1500];
+    loop {
+        let (size, peer) = socket.recv_from(&mut buf).await?;
+        let request = match Packet::from_bytes(&buf[..size]) {
+            Ok(p) => p,
+            Err(_) => continue,
+        };
+
+        let action = faults.next();
+        if !apply(action).await {
+            debug!("simulator: dropping CoAP request from {}", Disp(peer));
+            continue;
+        }
+
+        let mut response = Packet::new();
+        response.header.message_id = request.header.message_id;
+        response.set_token(request.get_token().clone());
+
+        match resources.get(path_of(&request).trim_start_matches('/')) {
+            _ if action == FaultAction::Corrupt => {
+                response.header.code = MessageClass::Response(ResponseType::InternalServerError);
+            }
+            Some(payload) => {
+                response.header.code = MessageClass::Response(ResponseType::Content);
+                response.payload = payload.clone();
+            }
+            None => response.header.code = MessageClass::Response(ResponseType::NotFound),
+        }
+
+        if let Ok(bytes) = response.to_bytes() {
+            socket.send_to(&bytes, peer).await?;
+        }
+    }
+}
+
+// ---------------------------
+// OTA HTTP file server stub
+// ---------------------------
+
+async fn serve_ota(mut stream: TcpStream, faults: FaultScript, files: Arc<(Vec<u8>, Vec<u8>)>) {
+    // Read the request head (GET requests have no body)
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") && head.len() < 4096 {
+        match stream.read(&mut byte).await {
+            Ok(1) => head.push(byte[0]),
+            _ => return,
+        }
+    }
+
+    let action = faults.next();
+    if !apply(action).await {
+        debug!("simulator: dropping OTA request");
+        return;
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    let path = head.split_whitespace().nth(1).unwrap_or("");
+    let body = match path {
+        "/firmware.bin" => Some(files.0.clone()),
+        "/firmware.sig" => {
+            let mut sig = files.1.clone();
+            if action == FaultAction::Corrupt {
+                if let Some(b) = sig.first_mut() {
+                    *b ^= 0xFF;
+                }
+            }
+            Some(sig)
+        }
+        _ => None,
+    };
+
+    let response = match body {
+        Some(body) => {
+            let mut r = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            )
+            .into_bytes();
+            r.extend_from_slice(&body);
+            r
+        }
+        None => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec(),
+    };
+    let _ = stream.write_all(&response).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn http_get(addr: SocketAddr, path: &str) -> Vec<u8> {
+        let mut s = TcpStream::connect(addr).await.unwrap();
+        s.write_all(format!("GET {} HTTP/1.1\r\nHost: sim\r\n\r\n", path).as_bytes()).await.unwrap();
+        let mut out = Vec::new();
+        s.read_to_end(&mut out).await.unwrap();
+        let split = out.windows(4).position(|w| w == b"\r\n\r\n").map(|i| i + 4).unwrap_or(0);
+        out[split..].to_vec()
+    }
+
+    #[tokio::test]
+    async fn ota_serves_corrupted_signature_when_scripted() {
+        let config = SimulatorConfig {
+            ota_faults: FaultScript::new([FaultAction::Corrupt]),
+            firmware: vec![1, 2, 3],
+            firmware_signature: vec![0xAA, 0xBB],
+            ..SimulatorConfig::default()
+        };
+        let sim = BackendSimulator::start(config).await.unwrap();
+
+        assert_eq!(http_get(sim.ota_addr, "/firmware.sig").await, vec![0x55, 0xBB]);
+        assert_eq!(http_get(sim.ota_addr, "/firmware.sig").await, vec![0xAA, 0xBB]);
+        assert_eq!(http_get(sim.ota_addr, "/firmware.bin").await, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn mqtt_stub_records_publishes_and_drops_when_scripted() {
+        let config = SimulatorConfig {
+            mqtt_faults: FaultScript::new([FaultAction::Normal, FaultAction::Drop]),
+            ..SimulatorConfig::default()
+        };
+        let sim = BackendSimulator::start(config).await.unwrap();
+        let mut s = TcpStream::connect(sim.mqtt_addr).await.unwrap();
+
+        // CONNECT (contents are not inspected by the stub)
+        s.write_all(&[CONNECT << 4, 2, 0, 0]).await.unwrap();
+        let mut connack = [0u8; 4];
+        s.read_exact(&mut connack).await.unwrap();
+        assert_eq!(connack, [CONNACK << 4, 2, 0, 0]);
+
+        // PUBLISH QoS 0 to "t" with payload "hi": second scripted action drops it
+        s.write_all(&[PUBLISH << 4, 5, 0, 1, b't', b'h', b'i']).await.unwrap();
+        let mut rest = Vec::new();
+        let _ = s.read_to_end(&mut rest).await;
+        assert!(sim.published().is_empty(), "dropped publish must not be recorded");
+
+        // Script exhausted: next connection is served normally
+        let mut s = TcpStream::connect(sim.mqtt_addr).await.unwrap();
+        s.write_all(&[CONNECT << 4, 2, 0, 0]).await.unwrap();
+        s.read_exact(&mut connack).await.unwrap();
+        s.write_all(&[PUBLISH << 4 | 0x02, 7, 0, 1, b't', 0, 9, b'h', b'i']).await.unwrap();
+        let mut puback = [0u8; 4];
+        s.read_exact(&mut puback).await.unwrap();
+        assert_eq!(puback, [PUBACK << 4, 2, 0, 9]);
+        assert_eq!(sim.published(), vec![("t".to_string(), b"hi".to_vec())]);
+    }
+}
@@ -0,0 +1,398 @@
+//! SecureIoTOS Store-and-Forward Module
+//! ------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source / commercial use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/SecureIoTOS>
+//!
+//! Store-and-forward for outgoing telemetry, so readings taken while the
+//! link is down are not lost.
+//!
+//! Telemetry goes out through a [`TelemetryLink`] (MQTT topic or CoAP path).
+//! While nothing is queued, a message is sent directly. If sending fails, or
+//! older messages are still waiting, it is queued in a
+//! `secure_storage::outbox` instead, which is persisted and so survives a
+//! reboot. It is bounded, and drops the oldest messages when full.
+//!
+//! [`StoreAndForward::flush`] delivers the queue oldest first and stops at
+//! the first failure, so ordering is preserved. Call it when the link comes
+//! back, e.g. on [`MqttState::Connected`](crate::managed_mqtt::MqttState).
+//! A message delivered but not yet removed when power is lost is sent again,
+//! so delivery is at-least-once.
+//!
+//! A message the far end refuses outright ([`Refused`], e.g. a CoAP 4.04)
+//! would be refused again on every retry, and would hold up everything
+//! queued behind it. It is dropped instead, counted in
+//! [`StoreAndForward::refused`], and the flush goes on.
+//!
+//! Built with [`StoreAndForward::stamped`], every message is prefixed with
+//! the 25-byte `auth_identity::timestamp` stamp (kind `Telemetry`) when it
+//! is handed over, so a reading keeps its place in the device's event order
+//! however long it waits in the queue.
+
+use anyhow::{anyhow, Context, Result};
+use auth_identity::timestamp::{self, ArtifactKind};
+use coap_lite::{MessageClass, ResponseType};
+use rumqttc::QoS;
+use secure_storage::outbox::{Outbox, OutboxStore};
+use sios_log::{debug, info, warn, Disp};
+use std::fmt;
+use std::time::Duration;
+
+use crate::coap::coap_post;
+use crate::managed_mqtt::{ManagedMqttClient, MqttTransport};
+
+/// Default time to wait for a CoAP response before the link counts as down.
+pub const DEFAULT_COAP_TIMEOUT: Duration = Duration::from_secs(5);
+
+// ------------------ LINK ------------------
+
+/// Transport a message is meant for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    /// Destination is an MQTT topic
+    Mqtt,
+    /// Destination is a CoAP path
+    Coap,
+}
+
+impl Channel {
+    /// Tag stored with each queued message.
+    fn kind(self) -> u8 {
+        match self {
+            Channel::Mqtt => 0,
+            Channel::Coap => 1,
+        }
+    }
+
+    fn from_kind(kind: u8) -> Option<Self> {
+        match kind {
+            0 => Some(Channel::Mqtt),
+            1 => Some(Channel::Coap),
+            _ => None,
+        }
+    }
+}
+
+/// Error for a message the far end will never accept as it is. Return it
+/// (`Err(Refused(..).into())`) from [`TelemetryLink::send`] so the message
+/// is dropped instead of retried.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Refused(pub String);
+
+impl fmt::Display for Refused {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "refused: {}", self.0)
+    }
+}
+
+impl std::error::Error for Refused {}
+
+/// Whether `e` is (or wraps) a [`Refused`].
+pub fn is_refused(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<Refused>().is_some()
+}
+
+/// Upstream link that delivers telemetry.
+#[allow(async_fn_in_trait)]
+pub trait TelemetryLink {
+    /// Deliver one message; an error means it was not delivered. A
+    /// [`Refused`] error means it never will be; anything else is retried.
+    async fn send(&mut self, channel: Channel, destination: &str, payload: &[u8]) -> Result<()>;
+}
+
+/// Link over a [`ManagedMqttClient`] and a CoAP server.
+///
+/// MQTT messages are published at QoS 1 only while the client is connected;
+/// once accepted, the client itself re-sends them across reconnects. CoAP
+/// messages are POSTed to `coap_server` and count as delivered on a 2.xx
+/// response. A 4.xx response is [`Refused`], except 4.29 (Too Many
+/// Requests), which like 5.xx only means "not now".
+pub struct TelemetryUplink<T: MqttTransport> {
+    pub mqtt: ManagedMqttClient<T>,
+    pub coap_server: String,
+    pub coap_timeout: Duration,
+}
+
+impl<T: MqttTransport> TelemetryUplink<T> {
+    pub fn new(mqtt: ManagedMqttClient<T>, coap_server: &str) -> Self {
+        Self { mqtt, coap_server: coap_server.to_string(), coap_timeout: DEFAULT_COAP_TIMEOUT }
+    }
+}
+
+impl<T: MqttTransport> TelemetryLink for TelemetryUplink<T> {
+    async fn send(&mut self, channel: Channel, destination: &str, payload: &[u8]) -> Result<()> {
+        match channel {
+            Channel::Mqtt => {
+                if !self.mqtt.is_connected() {
+                    return Err(anyhow!("MQTT not connected"));
+                }
+                self.mqtt.publish(destination, QoS::AtLeastOnce, false, payload).await
+            }
+            Channel::Coap => {
+                let post = coap_post(&self.coap_server, destination, payload);
+                let response = tokio::time::timeout(self.coap_timeout, post).await.map_err(|_| {
+                    anyhow!("no CoAP response from {} within {:?}", self.coap_server, self.coap_timeout)
+                })??;
+                match response.header.code {
+                    MessageClass::Response(
+                        ResponseType::Created
+                        | ResponseType::Deleted
+                        | ResponseType::Valid
+                        | ResponseType::Changed
+                        | ResponseType::Content,
+                    ) => Ok(()),
+                    MessageClass::Response(ResponseType::TooManyRequests) => {
+                        Err(anyhow!("CoAP POST {} throttled by {}", destination, self.coap_server))
+                    }
+                    code if u8::from(code) >> 5 == 4 => {
+                        Err(Refused(format!("CoAP POST {}: {:?}", destination, code)).into())
+                    }
+                    code => Err(anyhow!("CoAP POST {} failed: {:?}", destination, code)),
+                }
+            }
+        }
+    }
+}
+
+// ------------------ QUEUE ------------------
+
+/// Telemetry sender with a persisted backlog.
+pub struct StoreAndForward<S: OutboxStore, L: TelemetryLink> {
+    outbox: Outbox<S>,
+    link: L,
+    refused: u64,
+    stamped: bool,
+}
+
+impl<S: OutboxStore, L: TelemetryLink> StoreAndForward<S, L> {
+    /// Send through `link`, queueing in `outbox`. A queue restored from a
+    /// previous run is delivered on the next [`send`](Self::send) or
+    /// [`flush`](Self::flush).
+    pub fn new(outbox: Outbox<S>, link: L) -> Self {
+        Self { outbox, link, refused: 0, stamped: false }
+    }
+
+    /// Prefix every message with a telemetry stamp. The time-stamping
+    /// service must be running (`timestamp::init_timestamping()`).
+    pub fn stamped(mut self) -> Self {
+        self.stamped = true;
+        self
+    }
+
+    /// Messages waiting for the link.
+    pub fn queued(&self) -> usize {
+        self.outbox.len()
+    }
+
+    /// Messages dropped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.outbox.dropped()
+    }
+
+    /// Queued messages dropped because the far end refused them, since
+    /// this was created.
+    pub fn refused(&self) -> u64 {
+        self.refused
+    }
+
+    pub fn link(&mut self) -> &mut L {
+        &mut self.link
+    }
+
+    /// Send one message, or queue it if it cannot be sent now.
+    ///
+    /// Returns an error if the message could not be queued (invalid
+    /// destination, oversize, storage failure) or was [`Refused`] when sent
+    /// directly; link failures are absorbed by the queue.
+    pub async fn send(&mut self, channel: Channel, destination: &str, payload: &[u8]) -> Result<()> {
+        let stamped;
+        let payload = if self.stamped {
+            let ts = timestamp::stamp(ArtifactKind::Telemetry).map_err(|e| anyhow!("Failed to stamp telemetry: {:?}", e))?;
+            stamped = [&ts.to_bytes()[..], payload].concat();
+            &stamped[..]
+        } else {
+            payload
+        };
+        let link_up = if self.outbox.is_empty() {
+            match self.link.send(channel, destination, payload).await {
+                Ok(()) => return Ok(()),
+                Err(e) if is_refused(&e) => return Err(e),
+                Err(e) => {
+                    debug!("Telemetry to {} not sent: {}", destination, Disp(&e));
+                    false
+                }
+            }
+        } else {
+            true
+        };
+
+        self.outbox
+            .push(channel.kind(), destination, payload)
+            .map_err(|e| anyhow!("Failed to queue telemetry for {}: {:?}", destination, e))?;
+        if link_up {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Deliver queued messages, oldest first, until the queue is empty or
+    /// the link fails. [`Refused`] messages are dropped on the way. Returns
+    /// how many were delivered.
+    pub async fn flush(&mut self) -> Result<usize> {
+        let mut delivered = 0;
+        while let Some(entry) = self.outbox.front() {
+            let seq = entry.seq;
+            match Channel::from_kind(entry.kind) {
+                Some(channel) => match self.link.send(channel, &entry.destination, &entry.payload).await {
+                    Ok(()) => delivered += 1,
+                    Err(e) if is_refused(&e) => {
+                        warn!("Dropping queued message {} to {}: {}", seq, entry.destination.as_str(), Disp(&e));
+                        self.refused += 1;
+                    }
+                    Err(e) => {
+                        warn!("Link unavailable, {} message(s) queued: {}", self.outbox.len(), Disp(&e));
+                        break;
+                    }
+                },
+                None => warn!("Dropping queued message {} with unknown channel {}", seq, entry.kind),
+            }
+            self.outbox
+                .ack(seq)
+                .map_err(|e| anyhow!("{:?}", e))
+                .context("Failed to remove delivered message from the outbox")?;
+        }
+        if delivered > 0 {
+            info!("Flushed {} queued message(s), {} left", delivered, self.outbox.len());
+        }
+        Ok(delivered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct RamStore {
+        data: Option<Vec<u8>>,
+        saves: usize,
+    }
+
+    impl OutboxStore for &mut RamStore {
+        fn load(&mut self) -> Result<Option<Vec<u8>>, &'static str> {
+            Ok(self.data.clone())
+        }
+        fn save(&mut self, data: &[u8]) -> Result<(), &'static str> {
+            self.saves += 1;
+            self.data = Some(data.to_vec());
+            Ok(())
+        }
+    }
+
+    /// Link that can be switched on/off and records what it delivered.
+    /// Destinations starting with `/bad` are refused.
+    #[derive(Default)]
+    struct MockLink {
+        online: bool,
+        sent: Vec<(Channel, String, Vec<u8>)>,
+    }
+
+    impl TelemetryLink for MockLink {
+        async fn send(&mut self, channel: Channel, destination: &str, payload: &[u8]) -> Result<()> {
+            if !self.online {
+                return Err(anyhow!("link down"));
+            }
+            if destination.starts_with("/bad") {
+                return Err(Refused("4.04".into()).into());
+            }
+            self.sent.push((channel, destination.to_string(), payload.to_vec()));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn queues_while_offline_and_flushes_in_order() {
+        let mut store = RamStore::default();
+        let mut sf = StoreAndForward::new(Outbox::open(&mut store, 2, 1024).unwrap(), MockLink::default());
+
+        sf.send(Channel::Mqtt, "tele/temp", b"1").await.unwrap();
+        sf.send(Channel::Coap, "/hum", b"2").await.unwrap();
+        sf.send(Channel::Mqtt, "tele/temp", b"3").await.unwrap();
+        assert_eq!((sf.queued(), sf.dropped()), (2, 1));
+
+        sf.link().online = true;
+        assert_eq!(sf.flush().await.unwrap(), 2);
+        sf.send(Channel::Mqtt, "tele/temp", b"4").await.unwrap();
+        let sent: Vec<_> = sf.link().sent.iter().map(|(c, d, p)| (*c, d.as_str(), p[0])).collect();
+        assert_eq!(
+            sent,
+            [(Channel::Coap, "/hum", b'2'), (Channel::Mqtt, "tele/temp", b'3'), (Channel::Mqtt, "tele/temp", b'4')]
+        );
+        assert_eq!(sf.queued(), 0);
+        drop(sf);
+        assert_eq!(store.saves, 5, "3 queued + 2 removed; the direct send is not written");
+    }
+
+    #[tokio::test]
+    async fn backlog_survives_restart() {
+        let mut store = RamStore::default();
+        {
+            let mut sf = StoreAndForward::new(Outbox::open(&mut store, 8, 1024).unwrap(), MockLink::default());
+            sf.send(Channel::Mqtt, "tele/a", b"x").await.unwrap();
+            sf.send(Channel::Coap, "/b", b"y").await.unwrap();
+        }
+
+        let link = MockLink { online: true, ..Default::default() };
+        let mut sf = StoreAndForward::new(Outbox::open(&mut store, 8, 1024).unwrap(), link);
+        // A new message goes out behind the restored backlog.
+        sf.send(Channel::Mqtt, "tele/c", b"z").await.unwrap();
+        let order: Vec<_> = sf.link().sent.iter().map(|(_, d, _)| d.clone()).collect();
+        assert_eq!(order, ["tele/a", "/b", "tele/c"]);
+        assert_eq!(sf.queued(), 0);
+    }
+
+    #[tokio::test]
+    async fn stamps_when_handed_over_not_when_delivered() {
+        fn uptime() -> u64 {
+            0
+        }
+        timestamp::init_timestamping(2, 0xb007, uptime);
+        let mut store = RamStore::default();
+        let mut sf = StoreAndForward::new(Outbox::open(&mut store, 8, 1024).unwrap(), MockLink::default()).stamped();
+        sf.send(Channel::Mqtt, "tele/a", b"1").await.unwrap();
+        sf.link().online = true;
+        sf.send(Channel::Mqtt, "tele/b", b"2").await.unwrap();
+
+        let sent = &sf.link().sent;
+        let (first, second) = (&sent[0].2, &sent[1].2);
+        assert_eq!(first.len(), timestamp::TIMESTAMP_LEN + 1);
+        assert_eq!(first[0], ArtifactKind::Telemetry as u8);
+        assert_eq!(first[1..5], 2u32.to_be_bytes());
+        // The queued reading was stamped first
+        assert!(first[9..17] < second[9..17]);
+        assert_eq!((first[25], second[25]), (b'1', b'2'));
+    }
+
+    #[tokio::test]
+    async fn refused_messages_are_dropped_and_do_not_block_the_queue() {
+        let mut store = RamStore::default();
+        let mut sf = StoreAndForward::new(Outbox::open(&mut store, 8, 1024).unwrap(), MockLink::default());
+        sf.send(Channel::Mqtt, "tele/a", b"1").await.unwrap();
+        sf.send(Channel::Coap, "/bad", b"2").await.unwrap();
+        sf.send(Channel::Mqtt, "tele/c", b"3").await.unwrap();
+
+        sf.link().online = true;
+        assert_eq!(sf.flush().await.unwrap(), 2);
+        assert_eq!((sf.queued(), sf.refused()), (0, 1));
+        let order: Vec<_> = sf.link().sent.iter().map(|(_, d, _)| d.clone()).collect();
+        assert_eq!(order, ["tele/a", "tele/c"]);
+
+        // Sent directly, the refusal goes back to the caller and nothing is queued.
+        let e = sf.send(Channel::Coap, "/bad", b"4").await.unwrap_err();
+        assert!(is_refused(&e));
+        assert_eq!(sf.queued(), 0);
+    }
+}
@@ -3,21 +3,46 @@
 //! License : Dual License
 //!           - Apache 2.0 for open-source / personal use
 //!           - Commercial license required for closed-source use
-//! Author	: Md Mahbubur Rahman
-//! URL		: https://m-a-h-b-u-b.github.io
-//! GitHub	: https://github.com/m-a-h-b-u-b/SecureIoTOS
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
 //! 
 //! This module provides a minimal async TLS client connector
 //! built on top of tokio-rustls with system root certificates.
+//!
+//! [`connect_tls_with`] adds what device deployments need on top, set
+//! through [`TlsOptions`]:
+//! - mutual TLS with the device certificate, its key signing through a
+//!   [`DeviceSigner`] such as [`KeyStoreSigner`](crate::mqtt_tls::KeyStoreSigner)
+//!   (the `auth_identity` key never leaves the key store),
+//!   or with a PEM certificate and key;
+//! - pre-shared keys instead of certificates, for constrained deployments
+//!   without a PKI (TLS 1.3 PSK, see [`crate::tls_psk`]);
+//! - a restricted list of cipher suites;
+//! - certificate pinning by the SHA-256 of the SubjectPublicKeyInfo
+//!   ([`spki_sha256`]), checked on top of normal chain validation.
 
+use tokio_rustls::rustls::client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier};
 use tokio_rustls::rustls::{
-    ClientConfig, OwnedTrustAnchor, RootCertStore, ServerName,
+    Certificate, ClientConfig, Error as TlsError, OwnedTrustAnchor, RootCertStore, ServerName, SupportedCipherSuite,
+    ALL_CIPHER_SUITES,
 };
 use tokio_rustls::{TlsConnector, client::TlsStream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::TcpStream;
+use sha2::{Digest, Sha256};
+use sios_log::{debug, Dbg, Secret};
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::SystemTime;
 use webpki_roots::TLS_SERVER_ROOTS;
-use anyhow::{Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+
+use crate::mqtt_tls::{roots, with_client_auth, ClientIdentity, DeviceSigner};
+use crate::tls_psk::{self, Psk, PskCipherSuite, PskStream, DEFAULT_PSK_SUITES};
 
 /// Establish a secure TLS connection to the given address and domain.
 ///
@@ -82,3 +107,306 @@ pub async fn connect_tls(addr: &str, domain: &str) -> Result<TlsStream<TcpStream
 
     Ok(tls_stream)
 }
+
+// ------------------ OPTIONS ------------------
+
+/// How a TLS client connection authenticates, and what it accepts.
+#[derive(Clone, Default)]
+pub struct TlsOptions {
+    ca_pem: Vec<Vec<u8>>,
+    client: Option<ClientIdentity>,
+    psk: Option<Psk>,
+    allow_psk_ke: bool,
+    cipher_suites: Vec<String>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl TlsOptions {
+    /// Server authentication against the Mozilla roots, as [`connect_tls`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Trust the CA certificates in `pem` instead of the Mozilla roots.
+    /// May be called several times.
+    pub fn ca_pem(mut self, pem: &[u8]) -> Self {
+        self.ca_pem.push(pem.to_vec());
+        self
+    }
+
+    /// Authenticate with the device certificate `cert_der`, e.g. from
+    /// `DeviceIdentity::certificate`, signing with `signer`.
+    pub fn client_identity(mut self, cert_der: &[u8], signer: impl DeviceSigner + 'static) -> Self {
+        self.client = Some(ClientIdentity::Device { cert_der: cert_der.to_vec(), signer: Arc::new(signer) });
+        self
+    }
+
+    /// Authenticate with a certificate chain and private key, both PEM.
+    pub fn client_cert_pem(mut self, cert_pem: &[u8], key_pem: &[u8]) -> Self {
+        self.client = Some(ClientIdentity::Pem { cert_pem: cert_pem.to_vec(), key_pem: Secret::new(key_pem.to_vec()) });
+        self
+    }
+
+    /// Authenticate both sides with a pre-shared key instead of
+    /// certificates. Cannot be combined with the certificate options.
+    pub fn psk(mut self, identity: &[u8], key: &[u8]) -> Self {
+        self.psk = Some(Psk::new(identity, key));
+        self
+    }
+
+    /// With a PSK, also accept `psk_ke`: no key exchange, so no forward
+    /// secrecy. Only for servers that cannot do `psk_dhe_ke`.
+    pub fn allow_psk_ke(mut self) -> Self {
+        self.allow_psk_ke = true;
+        self
+    }
+
+    /// Offer only these cipher suites, in this order, by their `rustls`
+    /// names (e.g. `TLS13_AES_128_GCM_SHA256`,
+    /// `TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256`). With a PSK only
+    /// `TLS13_AES_128_GCM_SHA256` and `TLS13_CHACHA20_POLY1305_SHA256`
+    /// are available.
+    pub fn cipher_suites(mut self, names: &[&str]) -> Self {
+        self.cipher_suites = names.iter().map(|n| n.to_string()).collect();
+        self
+    }
+
+    /// Accept the server only if a certificate in its chain has this
+    /// SubjectPublicKeyInfo hash. May be called several times (e.g. the
+    /// current and the next key); any one pin matching is enough.
+    pub fn pin_spki_sha256(mut self, hash: [u8; 32]) -> Self {
+        self.pins.push(hash);
+        self
+    }
+
+    /// The `rustls` client configuration for certificate-based connections.
+    pub fn client_config(&self) -> Result<ClientConfig> {
+        if self.psk.is_some() {
+            bail!("a PSK connection has no rustls configuration");
+        }
+        let roots = roots(&self.ca_pem)?;
+        let builder = if self.cipher_suites.is_empty() {
+            ClientConfig::builder().with_safe_defaults()
+        } else {
+            let suites = self.cipher_suites.iter().map(|n| rustls_suite(n)).collect::<Result<Vec<_>>>()?;
+            ClientConfig::builder()
+                .with_cipher_suites(&suites)
+                .with_safe_default_kx_groups()
+                .with_safe_default_protocol_versions()
+                .context("Unusable cipher suite selection")?
+        };
+        let mut config = with_client_auth(builder.with_root_certificates(roots.clone()), self.client.as_ref())?;
+        if !self.pins.is_empty() {
+            let verifier = PinnedVerifier { inner: WebPkiVerifier::new(roots, None), pins: self.pins.clone() };
+            config.dangerous().set_certificate_verifier(Arc::new(verifier));
+        }
+        Ok(config)
+    }
+
+    fn psk_suites(&self) -> Result<Vec<PskCipherSuite>> {
+        if self.cipher_suites.is_empty() {
+            return Ok(DEFAULT_PSK_SUITES.to_vec());
+        }
+        self.cipher_suites
+            .iter()
+            .map(|n| {
+                PskCipherSuite::from_name(n).ok_or_else(|| anyhow!("Cipher suite {} is not available with a PSK", n))
+            })
+            .collect()
+    }
+}
+
+fn rustls_suite(name: &str) -> Result<SupportedCipherSuite> {
+    ALL_CIPHER_SUITES
+        .iter()
+        .copied()
+        .find(|s| s.suite().as_str() == Some(name))
+        .ok_or_else(|| anyhow!("Unknown cipher suite {}", name))
+}
+
+/// Connect to `addr` and authenticate as set in `options`. `domain` is the
+/// name the server certificate must be valid for, and is sent as SNI.
+pub async fn connect_tls_with(addr: &str, domain: &str, options: &TlsOptions) -> Result<TlsClientStream> {
+    if let Some(psk) = &options.psk {
+        if options.client.is_some() || !options.ca_pem.is_empty() || !options.pins.is_empty() {
+            bail!("PSK cannot be combined with certificate options");
+        }
+        let suites = options.psk_suites()?;
+        let tcp = TcpStream::connect(addr)
+            .await
+            .with_context(|| format!("Failed to connect TCP to {}", addr))?;
+        // SNI carries host names only
+        let sni = domain.parse::<IpAddr>().is_err().then_some(domain);
+        let psk = Psk { allow_psk_ke: options.allow_psk_ke, ..psk.clone() };
+        let stream = tls_psk::handshake(tcp, &psk, &suites, sni)
+            .await
+            .with_context(|| format!("TLS-PSK handshake failed with {}", domain))?;
+        return Ok(TlsClientStream::Psk(Box::new(stream)));
+    }
+
+    let connector = TlsConnector::from(Arc::new(options.client_config()?));
+    let server_name = ServerName::try_from(domain).map_err(|_| anyhow!("Invalid TLS server name {}", domain))?;
+    let tcp = TcpStream::connect(addr)
+        .await
+        .with_context(|| format!("Failed to connect TCP to {}", addr))?;
+    let stream = connector
+        .connect(server_name, tcp)
+        .await
+        .with_context(|| format!("TLS handshake failed with {}", domain))?;
+    debug!("TLS connected to {}: {:?}", domain, Dbg(&stream.get_ref().1.negotiated_cipher_suite()));
+    Ok(TlsClientStream::Certificate(Box::new(stream)))
+}
+
+// ------------------ STREAM ------------------
+
+/// A connection from [`connect_tls_with`], either kind.
+pub enum TlsClientStream {
+    /// Certificate-authenticated (`rustls`)
+    Certificate(Box<TlsStream<TcpStream>>),
+    /// PSK-authenticated
+    Psk(Box<PskStream<TcpStream>>),
+}
+
+impl AsyncRead for TlsClientStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TlsClientStream::Certificate(s) => Pin::new(s.as_mut()).poll_read(cx, buf),
+            TlsClientStream::Psk(s) => Pin::new(s.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for TlsClientStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            TlsClientStream::Certificate(s) => Pin::new(s.as_mut()).poll_write(cx, buf),
+            TlsClientStream::Psk(s) => Pin::new(s.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TlsClientStream::Certificate(s) => Pin::new(s.as_mut()).poll_flush(cx),
+            TlsClientStream::Psk(s) => Pin::new(s.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TlsClientStream::Certificate(s) => Pin::new(s.as_mut()).poll_shutdown(cx),
+            TlsClientStream::Psk(s) => Pin::new(s.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+// ------------------ PINNING ------------------
+
+/// SHA-256 of the SubjectPublicKeyInfo of a DER certificate, the value
+/// [`TlsOptions::pin_spki_sha256`] takes. The same as
+/// `openssl x509 -pubkey -noout | openssl pkey -pubin -outform DER | openssl dgst -sha256`.
+pub fn spki_sha256(cert_der: &[u8]) -> Option<[u8; 32]> {
+    let (_, certificate) = der_tlv(cert_der)?;
+    let (_, mut tbs) = der_tlv(certificate)?;
+    // [0] version if present, serialNumber, signature, issuer, validity, subject
+    let skip = if tbs.first() == Some(&0xA0) { 6 } else { 5 };
+    for _ in 0..skip {
+        let (field, _) = der_tlv(tbs)?;
+        tbs = &tbs[field.len()..];
+    }
+    let (spki, _) = der_tlv(tbs)?;
+    (spki[0] == 0x30).then(|| Sha256::digest(spki).into())
+}
+
+/// The DER TLV at the start of `data`: the whole TLV and its contents.
+fn der_tlv(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let first = *data.get(1)?;
+    let (len, header) = if first < 0x80 {
+        (first as usize, 2)
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 {
+            return None;
+        }
+        let len = data.get(2..2 + n)?.iter().fold(0usize, |len, &b| len << 8 | b as usize);
+        (len, 2 + n)
+    };
+    let tlv = data.get(..header.checked_add(len)?)?;
+    Some((tlv, &tlv[header..]))
+}
+
+/// WebPKI verification, then a pinned key somewhere in the chain.
+struct PinnedVerifier {
+    inner: WebPkiVerifier,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        let verified = self.inner.verify_server_cert(end_entity, intermediates, server_name, scts, ocsp_response, now)?;
+        let pinned = std::iter::once(end_entity)
+            .chain(intermediates)
+            .filter_map(|cert| spki_sha256(&cert.0))
+            .any(|hash| self.pins.contains(&hash));
+        if !pinned {
+            return Err(TlsError::General("no certificate in the chain matches a pinned key".into()));
+        }
+        Ok(verified)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mqtt_tls::read_certs;
+
+    const CA_PEM: &[u8] = b"-----BEGIN CERTIFICATE-----
+MIIBkjCCATmgAwIBAgIUVFiBoAKBGoS3BJ7aXIhhxANDAMwwCgYIKoZIzj0EAwIw
+HjEcMBoGA1UEAwwTU2VjdXJlSW9UT1MgVGVzdCBDQTAgFw0yNjEwMTYxNTQ1MTda
+GA8yMTI2MDkyMjE1NDUxN1owHjEcMBoGA1UEAwwTU2VjdXJlSW9UT1MgVGVzdCBD
+QTBZMBMGByqGSM49AgEGCCqGSM49AwEHA0IABLfxb6OxLTlPDe/NGO2jAu7gUZ6C
+e0dL/wPnkVn1muhOJ5DB0vXOI6HMiSJ15Oe8X9//qJkOIQH6jqhe5JQPslmjUzBR
+MB0GA1UdDgQWBBSa01FiDDEPv3lGJoQSuxQXnhtU6zAfBgNVHSMEGDAWgBSa01Fi
+DDEPv3lGJoQSuxQXnhtU6zAPBgNVHRMBAf8EBTADAQH/MAoGCCqGSM49BAMCA0cA
+MEQCIGcQomAhrB+FDgZBEdOXuvdrSXw0LamietYekLVKciclAiAzEFVoqB03l8bB
+NMsUy6aXxomqgWLGJUB8oC98oQtMWw==
+-----END CERTIFICATE-----
+";
+
+    #[test]
+    fn test_spki_pin_and_cipher_suites() {
+        let cert = read_certs(CA_PEM).unwrap().remove(0);
+        // openssl x509 -pubkey -noout | openssl pkey -pubin -outform DER | openssl dgst -sha256
+        let pin = hex::decode("8062be2286c0f9c91a62f6b5398557ac7fd454a5a2592ff61cef195f5646a443").unwrap();
+        assert_eq!(spki_sha256(&cert.0).map(|h| h.to_vec()), Some(pin.clone()));
+        assert_eq!(spki_sha256(&cert.0[..40]), None);
+
+        let options = TlsOptions::new()
+            .ca_pem(CA_PEM)
+            .pin_spki_sha256(pin.try_into().unwrap())
+            .cipher_suites(&["TLS13_CHACHA20_POLY1305_SHA256", "TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256"]);
+        assert!(options.client_config().is_ok());
+
+        assert!(TlsOptions::new().cipher_suites(&["TLS_RSA_WITH_RC4_128_MD5"]).client_config().is_err());
+        assert!(TlsOptions::new().psk(b"dev", &[1; 16]).client_config().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_psk_options_are_checked_before_connecting() {
+        let psk = TlsOptions::new().psk(b"dev", &[1; 16]);
+        let err = connect_tls_with("127.0.0.1:9", "localhost", &psk.clone().ca_pem(CA_PEM)).await.err().unwrap();
+        assert!(err.to_string().contains("PSK cannot be combined"), "{}", err);
+        let err = connect_tls_with("127.0.0.1:9", "localhost", &psk.cipher_suites(&["TLS13_AES_256_GCM_SHA384"]))
+            .await
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("not available with a PSK"), "{}", err);
+    }
+}
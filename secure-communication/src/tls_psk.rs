@@ -0,0 +1,939 @@
+//! SecureIoTOS TLS-PSK Module
+//! --------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source / commercial use
+//! Author  : Md Mahbubur Rahman
+//! URL     : <https://m-a-h-b-u-b.github.io>
+//! GitHub  : <https://github.com/m-a-h-b-u-b/SecureIoTOS>
+//!
+//! TLS 1.3 client authenticated with an external pre-shared key (RFC 8446),
+//! for constrained deployments without a PKI.
+//!
+//! `rustls` does not support external PSKs, so [`handshake`] implements the
+//! PSK-only handshake itself:
+//! - the client offers one identity, bound to SHA-256, with `psk_dhe_ke`
+//!   (X25519, forward secret). `psk_ke` (no key exchange: whoever gets the
+//!   PSK can decrypt every recorded session) is offered as well only if
+//!   [`Psk::allow_psk_ke`] is set; otherwise a server choosing it is
+//!   refused;
+//! - cipher suites `TLS_AES_128_GCM_SHA256` and
+//!   `TLS_CHACHA20_POLY1305_SHA256` ([`PskCipherSuite`]);
+//! - no certificates: a server that asks for or sends one is refused, as is
+//!   a HelloRetryRequest;
+//! - after the handshake, session tickets are ignored and key updates are
+//!   followed.
+//!
+//! The result is a [`PskStream`], an `AsyncRead + AsyncWrite` like the
+//! `tokio-rustls` stream, so it can carry MQTT or any other protocol.
+
+use anyhow::{anyhow, bail, Context, Result};
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::agreement::{self, EphemeralPrivateKey, UnparsedPublicKey, X25519};
+use ring::digest::{self, SHA256};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+use sios_log::{debug, Dbg, Secret};
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context as TaskContext, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+// Record content types
+const CHANGE_CIPHER_SPEC: u8 = 20;
+const ALERT: u8 = 21;
+const HANDSHAKE: u8 = 22;
+const APPLICATION_DATA: u8 = 23;
+
+// Handshake message types
+const CLIENT_HELLO: u8 = 1;
+const SERVER_HELLO: u8 = 2;
+const NEW_SESSION_TICKET: u8 = 4;
+const ENCRYPTED_EXTENSIONS: u8 = 8;
+const CERTIFICATE: u8 = 11;
+const CERTIFICATE_REQUEST: u8 = 13;
+const FINISHED: u8 = 20;
+const KEY_UPDATE: u8 = 24;
+
+// Extensions
+const EXT_SERVER_NAME: u16 = 0;
+const EXT_SUPPORTED_GROUPS: u16 = 10;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 13;
+const EXT_PRE_SHARED_KEY: u16 = 41;
+const EXT_SUPPORTED_VERSIONS: u16 = 43;
+const EXT_PSK_KEY_EXCHANGE_MODES: u16 = 45;
+const EXT_KEY_SHARE: u16 = 51;
+
+const TLS13: u16 = 0x0304;
+const GROUP_X25519: u16 = 0x001d;
+const PSK_KE: u8 = 0;
+const PSK_DHE_KE: u8 = 1;
+/// ServerHello.random of a HelloRetryRequest (SHA-256 of "HelloRetryRequest").
+const HRR_RANDOM: [u8; 32] = [
+    0xcf, 0x21, 0xad, 0x74, 0xe5, 0x9a, 0x61, 0x11, 0xbe, 0x1d, 0x8c, 0x02, 0x1e, 0x65, 0xb8, 0x91, 0xc2, 0xa2, 0x11,
+    0x16, 0x7a, 0xbb, 0x8c, 0x5e, 0x07, 0x9e, 0x09, 0xe2, 0xc8, 0xa8, 0x33, 0x9c,
+];
+
+const HASH_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+/// Largest plaintext in one record.
+const MAX_FRAGMENT: usize = 16384;
+/// Largest record body accepted (plaintext, content type, padding and tag).
+const MAX_RECORD: usize = MAX_FRAGMENT + 256;
+
+/// TLS 1.3 cipher suites usable with a SHA-256 PSK.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PskCipherSuite {
+    Aes128GcmSha256,
+    Chacha20Poly1305Sha256,
+}
+
+/// Suites offered by default, in preference order.
+pub const DEFAULT_PSK_SUITES: [PskCipherSuite; 2] =
+    [PskCipherSuite::Aes128GcmSha256, PskCipherSuite::Chacha20Poly1305Sha256];
+
+impl PskCipherSuite {
+    /// Suite by its `rustls` name, e.g. `TLS13_AES_128_GCM_SHA256`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "TLS13_AES_128_GCM_SHA256" => Some(Self::Aes128GcmSha256),
+            "TLS13_CHACHA20_POLY1305_SHA256" => Some(Self::Chacha20Poly1305Sha256),
+            _ => None,
+        }
+    }
+
+    fn id(self) -> u16 {
+        match self {
+            Self::Aes128GcmSha256 => 0x1301,
+            Self::Chacha20Poly1305Sha256 => 0x1303,
+        }
+    }
+
+    fn aead(self) -> &'static aead::Algorithm {
+        match self {
+            Self::Aes128GcmSha256 => &aead::AES_128_GCM,
+            Self::Chacha20Poly1305Sha256 => &aead::CHACHA20_POLY1305,
+        }
+    }
+}
+
+/// An external pre-shared key and the identity the server knows it by.
+#[derive(Debug, Clone)]
+pub struct Psk {
+    pub identity: Vec<u8>,
+    pub key: Secret<Vec<u8>>,
+    /// Also offer `psk_ke`, the mode without forward secrecy, for servers
+    /// that cannot do X25519. Off by default.
+    pub allow_psk_ke: bool,
+}
+
+impl Psk {
+    pub fn new(identity: &[u8], key: &[u8]) -> Self {
+        Self { identity: identity.to_vec(), key: Secret::new(key.to_vec()), allow_psk_ke: false }
+    }
+}
+
+// ------------------ HANDSHAKE ------------------
+
+/// Run a PSK handshake over `stream`, offering `suites` in order.
+/// `server_name` is sent as SNI, if given.
+pub async fn handshake<S: AsyncRead + AsyncWrite + Unpin>(
+    mut stream: S,
+    psk: &Psk,
+    suites: &[PskCipherSuite],
+    server_name: Option<&str>,
+) -> Result<PskStream<S>> {
+    if suites.is_empty() {
+        bail!("no PSK cipher suite configured");
+    }
+    if psk.identity.is_empty() || psk.key.expose_secret().is_empty() {
+        bail!("PSK identity and key must not be empty");
+    }
+    let rng = SystemRandom::new();
+    let mut random = [0u8; 32];
+    rng.fill(&mut random).map_err(|_| anyhow!("no randomness for ClientHello"))?;
+    let ephemeral = EphemeralPrivateKey::generate(&X25519, &rng).map_err(|_| anyhow!("X25519 key generation failed"))?;
+    let share = ephemeral.compute_public_key().map_err(|_| anyhow!("X25519 key generation failed"))?;
+
+    let early = hkdf_extract(&[0; HASH_LEN], psk.key.expose_secret());
+    let mut hello = client_hello(&random, suites, server_name, share.as_ref(), &psk.identity, psk.allow_psk_ke);
+    let binder_key = derive_secret(&early, "ext binder", &empty_hash());
+    let binder_len = hello.len() - HASH_LEN;
+    let binder = hmac_sha256(&expand_label(&binder_key, "finished", &[], HASH_LEN), &sha256(&hello[..binder_len - 3]));
+    hello[binder_len..].copy_from_slice(&binder);
+
+    let mut transcript = digest::Context::new(&SHA256);
+    transcript.update(&hello);
+    stream.write_all(&record(HANDSHAKE, 0x0301, &hello)).await.context("Failed to send ClientHello")?;
+
+    // ServerHello
+    let mut messages = HandshakeReader::default();
+    let server_hello = loop {
+        if let Some(message) = messages.next()? {
+            break message;
+        }
+        let (kind, body) = read_record(&mut stream).await?;
+        match kind {
+            HANDSHAKE => messages.push(&body),
+            ALERT => bail!("server refused the handshake: {}", alert_text(&body)),
+            _ => bail!("unexpected record type {} before ServerHello", kind),
+        }
+    };
+    if server_hello[0] != SERVER_HELLO {
+        bail!("expected ServerHello, got handshake message {}", server_hello[0]);
+    }
+    let hello = parse_server_hello(&server_hello[4..])?;
+    let suite = suites
+        .iter()
+        .copied()
+        .find(|s| s.id() == hello.suite)
+        .ok_or_else(|| anyhow!("server chose cipher suite {:#x}, which was not offered", hello.suite))?;
+    if hello.key_share.is_none() && !psk.allow_psk_ke {
+        bail!("server chose psk_ke, which was not offered (no forward secrecy)");
+    }
+    transcript.update(&server_hello);
+
+    let shared = match hello.key_share {
+        Some(key) => agreement::agree_ephemeral(
+            ephemeral,
+            &UnparsedPublicKey::new(&X25519, key),
+            anyhow!("invalid server key share"),
+            |secret| Ok(secret.to_vec()),
+        )?,
+        None => vec![0; HASH_LEN],
+    };
+    let handshake_secret = hkdf_extract(&derive_secret(&early, "derived", &empty_hash()), &shared);
+    let hello_hash = finish(&transcript);
+    let client_hs = derive_secret(&handshake_secret, "c hs traffic", &hello_hash);
+    let server_hs = derive_secret(&handshake_secret, "s hs traffic", &hello_hash);
+    let mut read = TrafficKeys::new(suite, &server_hs)?;
+
+    // EncryptedExtensions .. Finished
+    loop {
+        let message = match messages.next()? {
+            Some(message) => message,
+            None => {
+                let (kind, mut body) = read_record(&mut stream).await?;
+                match kind {
+                    CHANGE_CIPHER_SPEC => continue,
+                    APPLICATION_DATA => {}
+                    ALERT => bail!("server aborted the handshake: {}", alert_text(&body)),
+                    _ => bail!("unexpected plaintext record type {} in handshake", kind),
+                }
+                match read.open(&mut body)? {
+                    (HANDSHAKE, data) => messages.push(data),
+                    (ALERT, data) => bail!("server aborted the handshake: {}", alert_text(data)),
+                    (kind, _) => bail!("unexpected record type {} in handshake", kind),
+                }
+                continue;
+            }
+        };
+        match message[0] {
+            ENCRYPTED_EXTENSIONS => transcript.update(&message),
+            CERTIFICATE | CERTIFICATE_REQUEST => bail!("server wants certificate authentication; PSK only"),
+            FINISHED => {
+                let key = hmac::Key::new(hmac::HMAC_SHA256, &expand_label(&server_hs, "finished", &[], HASH_LEN));
+                hmac::verify(&key, &finish(&transcript), &message[4..])
+                    .map_err(|_| anyhow!("server Finished does not verify; wrong PSK?"))?;
+                transcript.update(&message);
+                break;
+            }
+            kind => bail!("unexpected handshake message {}", kind),
+        }
+    }
+    if !messages.is_empty() {
+        bail!("handshake data after server Finished");
+    }
+
+    let server_finished_hash = finish(&transcript);
+    let master = hkdf_extract(&derive_secret(&handshake_secret, "derived", &empty_hash()), &[0; HASH_LEN]);
+    let client_ap = derive_secret(&master, "c ap traffic", &server_finished_hash);
+    let server_ap = derive_secret(&master, "s ap traffic", &server_finished_hash);
+
+    let verify_data = hmac_sha256(&expand_label(&client_hs, "finished", &[], HASH_LEN), &server_finished_hash);
+    let finished = handshake_message(FINISHED, &verify_data);
+    let mut write = TrafficKeys::new(suite, &client_hs)?;
+    stream.write_all(&write.seal(HANDSHAKE, &finished)?).await.context("Failed to send Finished")?;
+    stream.flush().await.context("Failed to send Finished")?;
+
+    let mode = if hello.key_share.is_some() { "psk_dhe_ke" } else { "psk_ke" };
+    debug!("TLS-PSK handshake done: {:?}, {}", Dbg(&suite), mode);
+    PskStream::new(stream, suite, server_ap, client_ap)
+}
+
+/// ClientHello with a zeroed binder in its last `HASH_LEN` bytes.
+fn client_hello(
+    random: &[u8; 32],
+    suites: &[PskCipherSuite],
+    server_name: Option<&str>,
+    share: &[u8],
+    identity: &[u8],
+    allow_psk_ke: bool,
+) -> Vec<u8> {
+    let mut body = Vec::with_capacity(256);
+    body.extend_from_slice(&0x0303u16.to_be_bytes());
+    body.extend_from_slice(random);
+    body.push(0); // legacy_session_id
+    put_u16(&mut body, (suites.len() * 2) as u16);
+    for suite in suites {
+        put_u16(&mut body, suite.id());
+    }
+    body.extend_from_slice(&[1, 0]); // null compression
+
+    let mut ext = Vec::with_capacity(160);
+    if let Some(name) = server_name {
+        let name = name.as_bytes();
+        let mut list = vec![0];
+        put_vec16(&mut list, name);
+        let mut value = Vec::new();
+        put_vec16(&mut value, &list);
+        put_extension(&mut ext, EXT_SERVER_NAME, &value);
+    }
+    put_extension(&mut ext, EXT_SUPPORTED_VERSIONS, &[2, 0x03, 0x04]);
+    put_extension(&mut ext, EXT_SUPPORTED_GROUPS, &[0, 2, 0x00, 0x1d]);
+    // ecdsa_secp256r1_sha256, rsa_pss_rsae_sha256, rsa_pkcs1_sha256
+    put_extension(&mut ext, EXT_SIGNATURE_ALGORITHMS, &[0, 6, 0x04, 0x03, 0x08, 0x04, 0x04, 0x01]);
+    let mut entry = Vec::new();
+    put_u16(&mut entry, GROUP_X25519);
+    put_vec16(&mut entry, share);
+    let mut value = Vec::new();
+    put_vec16(&mut value, &entry);
+    put_extension(&mut ext, EXT_KEY_SHARE, &value);
+    if allow_psk_ke {
+        put_extension(&mut ext, EXT_PSK_KEY_EXCHANGE_MODES, &[2, PSK_DHE_KE, PSK_KE]);
+    } else {
+        put_extension(&mut ext, EXT_PSK_KEY_EXCHANGE_MODES, &[1, PSK_DHE_KE]);
+    }
+    // pre_shared_key must come last
+    let mut identities = Vec::new();
+    put_vec16(&mut identities, identity);
+    identities.extend_from_slice(&0u32.to_be_bytes()); // obfuscated_ticket_age
+    let mut value = Vec::new();
+    put_vec16(&mut value, &identities);
+    put_u16(&mut value, 1 + HASH_LEN as u16);
+    value.push(HASH_LEN as u8);
+    value.extend_from_slice(&[0; HASH_LEN]);
+    put_extension(&mut ext, EXT_PRE_SHARED_KEY, &value);
+
+    put_vec16(&mut body, &ext);
+    handshake_message(CLIENT_HELLO, &body)
+}
+
+struct ServerHello<'a> {
+    suite: u16,
+    key_share: Option<&'a [u8]>,
+}
+
+fn parse_server_hello(body: &[u8]) -> Result<ServerHello<'_>> {
+    let mut r = Reader(body);
+    r.take(2)?; // legacy_version
+    if r.take(32)? == HRR_RANDOM {
+        bail!("server sent HelloRetryRequest; only X25519 is supported");
+    }
+    let session_len = r.u8()? as usize;
+    r.take(session_len)?;
+    let suite = r.u16()?;
+    r.take(1)?; // compression
+    let mut ext = Reader(r.vec16()?);
+    let (mut version, mut key_share, mut psk) = (None, None, None);
+    while !ext.0.is_empty() {
+        let kind = ext.u16()?;
+        let mut value = Reader(ext.vec16()?);
+        match kind {
+            EXT_SUPPORTED_VERSIONS => version = Some(value.u16()?),
+            EXT_KEY_SHARE => {
+                if value.u16()? != GROUP_X25519 {
+                    bail!("server key share is not X25519");
+                }
+                key_share = Some(value.vec16()?);
+            }
+            EXT_PRE_SHARED_KEY => psk = Some(value.u16()?),
+            _ => {}
+        }
+    }
+    if version != Some(TLS13) {
+        bail!("server does not speak TLS 1.3");
+    }
+    if psk != Some(0) {
+        bail!("server did not accept the PSK identity");
+    }
+    Ok(ServerHello { suite, key_share })
+}
+
+/// Reassembles handshake messages (header included) from record payloads.
+#[derive(Default)]
+struct HandshakeReader {
+    buf: Vec<u8>,
+}
+
+impl HandshakeReader {
+    fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn next(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.buf.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([0, self.buf[1], self.buf[2], self.buf[3]]) as usize;
+        if len > MAX_RECORD * 4 {
+            bail!("handshake message of {} bytes", len);
+        }
+        if self.buf.len() < 4 + len {
+            return Ok(None);
+        }
+        Ok(Some(self.buf.drain(..4 + len).collect()))
+    }
+}
+
+async fn read_record<S: AsyncRead + Unpin>(stream: &mut S) -> Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; 5];
+    stream.read_exact(&mut header).await.context("Connection closed during TLS handshake")?;
+    let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+    if len > MAX_RECORD {
+        bail!("TLS record of {} bytes", len);
+    }
+    let mut body = vec![0; len];
+    stream.read_exact(&mut body).await.context("Connection closed during TLS handshake")?;
+    Ok((header[0], body))
+}
+
+// ------------------ RECORD LAYER ------------------
+
+/// AEAD key, IV and sequence number of one direction.
+struct TrafficKeys {
+    key: LessSafeKey,
+    iv: [u8; NONCE_LEN],
+    seq: u64,
+}
+
+impl TrafficKeys {
+    fn new(suite: PskCipherSuite, secret: &[u8; HASH_LEN]) -> Result<Self> {
+        let algorithm = suite.aead();
+        let key = expand_label(secret, "key", &[], algorithm.key_len());
+        let key = UnboundKey::new(algorithm, &key).map_err(|_| anyhow!("invalid traffic key"))?;
+        let mut iv = [0u8; NONCE_LEN];
+        iv.copy_from_slice(&expand_label(secret, "iv", &[], NONCE_LEN));
+        Ok(Self { key: LessSafeKey::new(key), iv, seq: 0 })
+    }
+
+    fn nonce(&mut self) -> Result<Nonce> {
+        let mut nonce = self.iv;
+        for (n, s) in nonce[4..].iter_mut().zip(self.seq.to_be_bytes()) {
+            *n ^= s;
+        }
+        self.seq = self.seq.checked_add(1).ok_or_else(|| anyhow!("TLS record sequence exhausted"))?;
+        Ok(Nonce::assume_unique_for_key(nonce))
+    }
+
+    /// Protected record carrying `data` of content type `kind`.
+    fn seal(&mut self, kind: u8, data: &[u8]) -> Result<Vec<u8>> {
+        let mut header = [APPLICATION_DATA, 0x03, 0x03, 0, 0];
+        header[3..].copy_from_slice(&((data.len() + 1 + TAG_LEN) as u16).to_be_bytes());
+        let mut out = Vec::with_capacity(header.len() + data.len() + 1 + TAG_LEN);
+        out.extend_from_slice(&header);
+        out.extend_from_slice(data);
+        out.push(kind);
+        let mut inner = out.split_off(header.len());
+        let nonce = self.nonce()?;
+        self.key
+            .seal_in_place_append_tag(nonce, Aad::from(header), &mut inner)
+            .map_err(|_| anyhow!("TLS record encryption failed"))?;
+        out.extend_from_slice(&inner);
+        Ok(out)
+    }
+
+    /// Decrypt a record body in place; returns the inner content type and
+    /// plaintext.
+    fn open<'a>(&mut self, body: &'a mut [u8]) -> Result<(u8, &'a [u8])> {
+        let mut header = [APPLICATION_DATA, 0x03, 0x03, 0, 0];
+        header[3..].copy_from_slice(&(body.len() as u16).to_be_bytes());
+        let nonce = self.nonce()?;
+        let plain = self
+            .key
+            .open_in_place(nonce, Aad::from(header), body)
+            .map_err(|_| anyhow!("TLS record does not decrypt"))?;
+        let end = plain.iter().rposition(|&b| b != 0).ok_or_else(|| anyhow!("TLS record without content type"))?;
+        Ok((plain[end], &plain[..end]))
+    }
+}
+
+/// Application data stream after a successful [`handshake`].
+pub struct PskStream<S> {
+    inner: S,
+    suite: PskCipherSuite,
+    read: TrafficKeys,
+    read_secret: [u8; HASH_LEN],
+    write: TrafficKeys,
+    write_secret: [u8; HASH_LEN],
+    /// Raw bytes read, not yet a whole record
+    incoming: Vec<u8>,
+    /// Decrypted application data not yet returned
+    plain: Vec<u8>,
+    plain_pos: usize,
+    /// Protected records not yet written
+    outgoing: Vec<u8>,
+    eof: bool,
+    closing: bool,
+}
+
+impl<S> PskStream<S> {
+    fn new(inner: S, suite: PskCipherSuite, read_secret: [u8; HASH_LEN], write_secret: [u8; HASH_LEN]) -> Result<Self> {
+        Ok(Self {
+            inner,
+            suite,
+            read: TrafficKeys::new(suite, &read_secret)?,
+            read_secret,
+            write: TrafficKeys::new(suite, &write_secret)?,
+            write_secret,
+            incoming: Vec::new(),
+            plain: Vec::new(),
+            plain_pos: 0,
+            outgoing: Vec::new(),
+            eof: false,
+            closing: false,
+        })
+    }
+
+    /// Negotiated cipher suite.
+    pub fn cipher_suite(&self) -> PskCipherSuite {
+        self.suite
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Process the next whole record in `incoming`, if there is one.
+    fn process_record(&mut self) -> Result<bool> {
+        if self.incoming.len() < 5 {
+            return Ok(false);
+        }
+        let len = u16::from_be_bytes([self.incoming[3], self.incoming[4]]) as usize;
+        if len > MAX_RECORD {
+            bail!("TLS record of {} bytes", len);
+        }
+        if self.incoming.len() < 5 + len {
+            return Ok(false);
+        }
+        let mut record: Vec<u8> = self.incoming.drain(..5 + len).collect();
+        match record[0] {
+            CHANGE_CIPHER_SPEC => return Ok(true),
+            APPLICATION_DATA => {}
+            kind => bail!("unexpected plaintext record type {}", kind),
+        }
+        let (kind, data) = self.read.open(&mut record[5..])?;
+        match kind {
+            APPLICATION_DATA => {
+                self.plain = data.to_vec();
+                self.plain_pos = 0;
+            }
+            HANDSHAKE => {
+                let data = data.to_vec();
+                self.post_handshake(&data)?;
+            }
+            ALERT if data.get(1) == Some(&0) => self.eof = true, // close_notify
+            ALERT => bail!("TLS alert from server: {}", alert_text(data)),
+            kind => bail!("unexpected record type {}", kind),
+        }
+        Ok(true)
+    }
+
+    /// Session tickets are ignored; key updates are followed and, when
+    /// asked, answered.
+    fn post_handshake(&mut self, mut data: &[u8]) -> Result<()> {
+        while !data.is_empty() {
+            if data.len() < 4 {
+                bail!("fragmented post-handshake message");
+            }
+            let len = u32::from_be_bytes([0, data[1], data[2], data[3]]) as usize;
+            let body = data.get(4..4 + len).ok_or_else(|| anyhow!("fragmented post-handshake message"))?;
+            match data[0] {
+                NEW_SESSION_TICKET => {}
+                KEY_UPDATE => {
+                    self.read_secret = next_secret(&self.read_secret);
+                    self.read = TrafficKeys::new(self.suite, &self.read_secret)?;
+                    if body.first() == Some(&1) {
+                        let update = handshake_message(KEY_UPDATE, &[0]);
+                        let record = self.write.seal(HANDSHAKE, &update)?;
+                        self.outgoing.extend_from_slice(&record);
+                        self.write_secret = next_secret(&self.write_secret);
+                        self.write = TrafficKeys::new(self.suite, &self.write_secret)?;
+                    }
+                }
+                kind => bail!("unexpected post-handshake message {}", kind),
+            }
+            data = &data[4 + len..];
+        }
+        Ok(())
+    }
+}
+
+impl<S: AsyncWrite + Unpin> PskStream<S> {
+    fn poll_drain(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        while !self.outgoing.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.outgoing))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.outgoing.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for PskStream<S> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.plain_pos < this.plain.len() {
+                let n = buf.remaining().min(this.plain.len() - this.plain_pos);
+                buf.put_slice(&this.plain[this.plain_pos..this.plain_pos + n]);
+                this.plain_pos += n;
+                return Poll::Ready(Ok(()));
+            }
+            if this.eof {
+                return Poll::Ready(Ok(()));
+            }
+            if this.process_record().map_err(invalid_data)? {
+                continue;
+            }
+            let mut chunk = [0u8; 4096];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                let e = io::Error::new(io::ErrorKind::UnexpectedEof, "TLS connection closed without close_notify");
+                return Poll::Ready(Err(e));
+            }
+            this.incoming.extend_from_slice(chunk.filled());
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for PskStream<S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut TaskContext<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        let n = buf.len().min(MAX_FRAGMENT);
+        let record = this.write.seal(APPLICATION_DATA, &buf[..n]).map_err(invalid_data)?;
+        this.outgoing = record;
+        // The data is accepted; whatever is not written yet goes out on the
+        // next write or flush.
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if !this.closing {
+            ready!(this.poll_drain(cx))?;
+            this.outgoing = this.write.seal(ALERT, &[1, 0]).map_err(invalid_data)?; // close_notify
+            this.closing = true;
+        }
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+fn invalid_data(e: anyhow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{:#}", e))
+}
+
+// ------------------ KEY SCHEDULE ------------------
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; HASH_LEN] {
+    let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data);
+    tag.as_ref().try_into().unwrap()
+}
+
+fn hkdf_extract(salt: &[u8], ikm: &[u8]) -> [u8; HASH_LEN] {
+    hmac_sha256(salt, ikm)
+}
+
+/// HKDF-Expand-Label for up to one hash of output.
+fn expand_label(secret: &[u8; HASH_LEN], label: &str, context: &[u8], len: usize) -> Vec<u8> {
+    let mut info = Vec::with_capacity(10 + label.len() + context.len());
+    put_u16(&mut info, len as u16);
+    info.push(6 + label.len() as u8);
+    info.extend_from_slice(b"tls13 ");
+    info.extend_from_slice(label.as_bytes());
+    info.push(context.len() as u8);
+    info.extend_from_slice(context);
+    info.push(1);
+    hmac_sha256(secret, &info)[..len].to_vec()
+}
+
+fn derive_secret(secret: &[u8; HASH_LEN], label: &str, transcript_hash: &[u8]) -> [u8; HASH_LEN] {
+    expand_label(secret, label, transcript_hash, HASH_LEN).try_into().unwrap()
+}
+
+fn next_secret(secret: &[u8; HASH_LEN]) -> [u8; HASH_LEN] {
+    expand_label(secret, "traffic upd", &[], HASH_LEN).try_into().unwrap()
+}
+
+fn sha256(data: &[u8]) -> [u8; HASH_LEN] {
+    digest::digest(&SHA256, data).as_ref().try_into().unwrap()
+}
+
+fn empty_hash() -> [u8; HASH_LEN] {
+    sha256(&[])
+}
+
+fn finish(transcript: &digest::Context) -> [u8; HASH_LEN] {
+    transcript.clone().finish().as_ref().try_into().unwrap()
+}
+
+// ------------------ ENCODING ------------------
+
+fn record(kind: u8, version: u16, body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(5 + body.len());
+    out.push(kind);
+    put_u16(&mut out, version);
+    put_vec16(&mut out, body);
+    out
+}
+
+fn handshake_message(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(4 + body.len());
+    out.push(kind);
+    out.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    out.extend_from_slice(body);
+    out
+}
+
+fn put_u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_vec16(out: &mut Vec<u8>, data: &[u8]) {
+    put_u16(out, data.len() as u16);
+    out.extend_from_slice(data);
+}
+
+fn put_extension(out: &mut Vec<u8>, kind: u16, value: &[u8]) {
+    put_u16(out, kind);
+    put_vec16(out, value);
+}
+
+fn alert_text(alert: &[u8]) -> String {
+    match alert.get(1) {
+        Some(40) => "handshake_failure".into(),
+        Some(47) => "illegal_parameter".into(),
+        Some(51) => "decrypt_error".into(),
+        Some(115) => "unknown_psk_identity".into(),
+        Some(code) => format!("alert {}", code),
+        None => "malformed alert".into(),
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            bail!("truncated ServerHello");
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        let b = self.take(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn vec16(&mut self) -> Result<&'a [u8]> {
+        let n = self.u16()? as usize;
+        self.take(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap()).collect()
+    }
+
+    /// Client and server ends of an established connection.
+    fn pair(suite: PskCipherSuite) -> (PskStream<DuplexStream>, PskStream<DuplexStream>) {
+        let (a, b) = duplex(64 * 1024);
+        let (client, server) = ([1u8; HASH_LEN], [2u8; HASH_LEN]);
+        (PskStream::new(a, suite, server, client).unwrap(), PskStream::new(b, suite, client, server).unwrap())
+    }
+
+    #[test]
+    fn test_rfc8448_key_schedule() {
+        // RFC 8448 section 3: early secret without PSK, then "derived"
+        let early = hkdf_extract(&[0; HASH_LEN], &[0; HASH_LEN]);
+        assert_eq!(early.to_vec(), hex("33ad0a1c607ec03b09e6cd9893680ce210adf300aa1f2660e1b22e10f170f92a"));
+        let derived = derive_secret(&early, "derived", &empty_hash());
+        assert_eq!(derived.to_vec(), hex("6f2615a108c702c5678f54fc9dbab69716c076189c48250cebeac3576c3611ba"));
+    }
+
+    #[tokio::test]
+    async fn test_records_and_key_update() {
+        for suite in DEFAULT_PSK_SUITES {
+            let (mut client, mut server) = pair(suite);
+            let data = vec![0x5a; MAX_FRAGMENT + 100];
+            client.write_all(&data).await.unwrap();
+            client.flush().await.unwrap();
+            let mut received = vec![0; data.len()];
+            server.read_exact(&mut received).await.unwrap();
+            assert_eq!(received, data);
+
+            // Server updates its keys and asks the client to do the same
+            let update = server.write.seal(HANDSHAKE, &handshake_message(KEY_UPDATE, &[1])).unwrap();
+            server.outgoing = update;
+            server.write_secret = next_secret(&server.write_secret);
+            server.write = TrafficKeys::new(suite, &server.write_secret).unwrap();
+            server.write_all(b"after").await.unwrap();
+            let mut buf = [0u8; 5];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"after");
+
+            client.write_all(b"reply").await.unwrap();
+            client.flush().await.unwrap();
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"reply");
+
+            client.shutdown().await.unwrap();
+            assert_eq!(server.read(&mut buf).await.unwrap(), 0, "close_notify ends the stream");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unknown_identity_is_reported() {
+        let (client, mut server) = duplex(4096);
+        let peer = tokio::spawn(async move {
+            let (kind, body) = read_record(&mut server).await.unwrap();
+            server.write_all(&record(ALERT, 0x0303, &[2, 115])).await.unwrap();
+            (kind, body)
+        });
+        let psk = Psk::new(b"sensor-17", &[0x42; 16]);
+        let err = handshake(client, &psk, &DEFAULT_PSK_SUITES, Some("gw.local")).await.err().unwrap();
+        assert!(err.to_string().contains("unknown_psk_identity"), "{}", err);
+
+        let (kind, hello) = peer.await.unwrap();
+        assert_eq!((kind, hello[0]), (HANDSHAKE, CLIENT_HELLO));
+        assert!(hello.windows(9).any(|w| w == b"sensor-17") && hello.windows(8).any(|w| w == b"gw.local"));
+    }
+
+    /// ServerHello accepting the PSK for `TLS_AES_128_GCM_SHA256`, with
+    /// `key_share` if given.
+    fn server_hello(key_share: Option<&[u8]>) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[7; 32]);
+        body.push(0);
+        put_u16(&mut body, 0x1301);
+        body.push(0);
+        let mut ext = Vec::new();
+        put_extension(&mut ext, EXT_SUPPORTED_VERSIONS, &TLS13.to_be_bytes());
+        put_extension(&mut ext, EXT_PRE_SHARED_KEY, &[0, 0]);
+        if let Some(key) = key_share {
+            let mut value = Vec::new();
+            put_u16(&mut value, GROUP_X25519);
+            put_vec16(&mut value, key);
+            put_extension(&mut ext, EXT_KEY_SHARE, &value);
+        }
+        put_vec16(&mut body, &ext);
+        handshake_message(SERVER_HELLO, &body)
+    }
+
+    #[tokio::test]
+    async fn test_psk_ke_needs_opt_in() {
+        for allow in [false, true] {
+            let (client, mut server) = duplex(4096);
+            let peer = tokio::spawn(async move {
+                let (_, hello) = read_record(&mut server).await.unwrap();
+                server.write_all(&record(HANDSHAKE, 0x0303, &server_hello(None))).await.unwrap();
+                // Then hang up: only the ServerHello check matters here.
+                hello
+            });
+            let psk = Psk { allow_psk_ke: allow, ..Psk::new(b"sensor-17", &[0x42; 16]) };
+            let err = handshake(client, &psk, &DEFAULT_PSK_SUITES, None).await.err().unwrap();
+            assert_eq!(err.to_string().contains("psk_ke"), !allow, "{}", err);
+
+            let hello = peer.await.unwrap();
+            let modes: &[u8] = if allow { &[0, 45, 0, 3, 2, PSK_DHE_KE, PSK_KE] } else { &[0, 45, 0, 2, 1, PSK_DHE_KE] };
+            assert!(hello.windows(modes.len()).any(|w| w == modes));
+        }
+    }
+
+    /// Handshake and echo against `openssl s_server`, if `openssl` is on
+    /// the PATH.
+    #[tokio::test]
+    async fn test_openssl_interop() {
+        use std::process::{Command, Stdio};
+        use std::time::Duration;
+
+        let key = [0x5a; 32];
+        for (suite, name) in [
+            (PskCipherSuite::Aes128GcmSha256, "TLS_AES_128_GCM_SHA256"),
+            (PskCipherSuite::Chacha20Poly1305Sha256, "TLS_CHACHA20_POLY1305_SHA256"),
+        ] {
+            let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+            let server = Command::new("openssl")
+                .args(["s_server", "-quiet", "-rev", "-naccept", "1", "-nocert", "-tls1_3"])
+                .args(["-accept", &format!("127.0.0.1:{}", port), "-ciphersuites", name])
+                .args(["-psk_identity", "sensor-17", "-psk", &hex::encode(key)])
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn();
+            let Ok(mut server) = server else {
+                eprintln!("openssl not found; skipping interop test");
+                return;
+            };
+
+            let mut tcp = None;
+            for _ in 0..50 {
+                match tokio::net::TcpStream::connect(("127.0.0.1", port)).await {
+                    Ok(stream) => {
+                        tcp = Some(stream);
+                        break;
+                    }
+                    Err(_) => tokio::time::sleep(Duration::from_millis(100)).await,
+                }
+            }
+            let tcp = tcp.expect("openssl s_server did not come up");
+            let psk = Psk::new(b"sensor-17", &key);
+            let mut stream = handshake(tcp, &psk, &[suite], Some("localhost")).await.unwrap();
+
+            // -rev answers each line reversed
+            stream.write_all(b"hello psk\n").await.unwrap();
+            stream.flush().await.unwrap();
+            let mut reply = Vec::new();
+            while !reply.ends_with(b"\n") {
+                let mut buf = [0u8; 64];
+                let n = stream.read(&mut buf).await.unwrap();
+                assert!(n > 0, "connection closed early");
+                reply.extend_from_slice(&buf[..n]);
+            }
+            assert_eq!(reply, b"ksp olleh\n");
+            let _ = stream.shutdown().await;
+            let _ = server.kill();
+            let _ = server.wait();
+        }
+    }
+}
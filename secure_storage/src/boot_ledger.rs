@@ -0,0 +1,434 @@
+//! SecureIoTOS Boot Event Ledger Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+
+//! Hash-chained ledger of boot events, for forensic timelines.
+//!
+//! Every boot, shutdown (with its reason), OTA install and safe-mode entry
+//! is appended as a `LedgerEntry` carrying a sequence number, the boot it
+//! belongs to and a timestamp. Each entry stores the SHA-256 of its
+//! predecessor and its own hash covers that link, so editing, reordering
+//! or deleting an entry in the middle breaks the chain at that point.
+//!
+//! Cutting entries off the end, or restoring an old copy of the ledger,
+//! keeps a valid chain. Against that, mirror `head()` after each append
+//! into something the attacker cannot roll back (a monotonic counter,
+//! replay-protected storage) and check it with `verify_anchor()`.
+//!
+//! Like the replay window, the ledger is persisted before `record()`
+//! returns, and a ledger that fails its checks on `open()` is reported,
+//! not silently reset. Once `capacity` entries are held the oldest are
+//! dropped; the hash preceding the first kept entry stays in the ledger,
+//! so what is left still verifies.
+//!
+//! `export()` is the persisted encoding; `verify_export()` checks it on
+//! the analysis host.
+
+use alloc::vec::Vec;
+
+use crypto::ct;
+use sha2::{Digest, Sha256};
+use sios_log::{error, info};
+
+use crate::replay::checksum;
+
+/// Magic prefix of the persisted ledger ("BLOG").
+const MAGIC: [u8; 4] = *b"BLOG";
+/// Encoding version of the persisted ledger.
+const FORMAT_VERSION: u8 = 1;
+/// Bytes of an entry covered by its hash.
+const BODY_LEN: usize = 4 + 4 + 8 + 1 + 1 + 4 + 32;
+/// Encoded size of one entry (body + hash).
+pub const ENTRY_LEN: usize = BODY_LEN + 32;
+/// Header: magic, version, base hash, entry count.
+const HEADER_LEN: usize = 4 + 1 + 32 + 4;
+
+/// Why the machine went down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ShutdownReason {
+    PowerOff = 0,
+    Reboot = 1,
+    /// Restart into a new firmware image
+    Update = 2,
+    Watchdog = 3,
+    Fault = 4,
+    LowBattery = 5,
+}
+
+impl ShutdownReason {
+    fn from_u8(v: u8) -> Option<Self> {
+        Some(match v {
+            0 => Self::PowerOff,
+            1 => Self::Reboot,
+            2 => Self::Update,
+            3 => Self::Watchdog,
+            4 => Self::Fault,
+            5 => Self::LowBattery,
+            _ => return None,
+        })
+    }
+}
+
+/// A recorded event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BootEvent {
+    /// Device started; `reset_cause` as read from the reset controller
+    Boot { reset_cause: u8 },
+    /// Orderly or detected shutdown
+    Shutdown(ShutdownReason),
+    /// Firmware `version` was installed
+    OtaInstalled { version: u32 },
+    /// Device entered safe mode for `reason` (application defined)
+    SafeMode { reason: u8 },
+}
+
+impl BootEvent {
+    /// (kind, code, detail) as stored.
+    fn encode(self) -> (u8, u8, u32) {
+        match self {
+            Self::Boot { reset_cause } => (0, reset_cause, 0),
+            Self::Shutdown(reason) => (1, reason as u8, 0),
+            Self::OtaInstalled { version } => (2, 0, version),
+            Self::SafeMode { reason } => (3, reason, 0),
+        }
+    }
+
+    fn decode(kind: u8, code: u8, detail: u32) -> Option<Self> {
+        Some(match kind {
+            0 => Self::Boot { reset_cause: code },
+            1 => Self::Shutdown(ShutdownReason::from_u8(code)?),
+            2 => Self::OtaInstalled { version: detail },
+            3 => Self::SafeMode { reason: code },
+            _ => return None,
+        })
+    }
+}
+
+/// One ledger entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LedgerEntry {
+    /// Position in the ledger since it was created (never reused)
+    pub seq: u32,
+    /// Number of `Boot` events up to and including this entry
+    pub boot: u32,
+    /// Caller-supplied time (e.g. RTC seconds; 0 if unknown)
+    pub timestamp: u64,
+    pub event: BootEvent,
+    /// Hash of the previous entry (zero before the first one)
+    pub prev: [u8; 32],
+    /// SHA-256 over all fields above
+    pub hash: [u8; 32],
+}
+
+impl LedgerEntry {
+    fn body(&self) -> [u8; BODY_LEN] {
+        let (kind, code, detail) = self.event.encode();
+        let mut b = [0u8; BODY_LEN];
+        b[0..4].copy_from_slice(&self.seq.to_le_bytes());
+        b[4..8].copy_from_slice(&self.boot.to_le_bytes());
+        b[8..16].copy_from_slice(&self.timestamp.to_le_bytes());
+        b[16] = kind;
+        b[17] = code;
+        b[18..22].copy_from_slice(&detail.to_le_bytes());
+        b[22..].copy_from_slice(&self.prev);
+        b
+    }
+
+    fn compute_hash(&self) -> [u8; 32] {
+        Sha256::digest(self.body()).into()
+    }
+
+    fn decode(b: &[u8]) -> Option<Self> {
+        if b.len() != ENTRY_LEN {
+            return None;
+        }
+        let u32_at = |o: usize| u32::from_le_bytes([b[o], b[o + 1], b[o + 2], b[o + 3]]);
+        let mut ts = [0u8; 8];
+        ts.copy_from_slice(&b[8..16]);
+        let mut prev = [0u8; 32];
+        prev.copy_from_slice(&b[22..BODY_LEN]);
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&b[BODY_LEN..]);
+        Some(Self {
+            seq: u32_at(0),
+            boot: u32_at(4),
+            timestamp: u64::from_le_bytes(ts),
+            event: BootEvent::decode(b[16], b[17], u32_at(18))?,
+            prev,
+            hash,
+        })
+    }
+}
+
+/// Latest entry of a ledger, to be kept where it cannot be rolled back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LedgerHead {
+    pub seq: u32,
+    pub hash: [u8; 32],
+}
+
+/// Errors reported by the ledger.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LedgerError {
+    /// Persisted ledger has the wrong format or fails its checksum
+    Corrupt,
+    /// The chain does not verify at the entry with this sequence number
+    Broken(u32),
+    /// The ledger is older than the anchored head, or differs from it
+    RolledBack,
+    /// Backing storage failed
+    Storage(&'static str),
+}
+
+/// Backing store for the ledger, typically a dedicated sector in secure
+/// storage.
+pub trait LedgerStore {
+    /// Load the last saved ledger; `Ok(None)` if nothing was ever saved.
+    fn load(&mut self) -> Result<Option<Vec<u8>>, &'static str>;
+    /// Atomically replace the saved ledger.
+    fn save(&mut self, data: &[u8]) -> Result<(), &'static str>;
+}
+
+/// Entries of a ledger plus the hash preceding the first of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Chain {
+    base: [u8; 32],
+    entries: Vec<LedgerEntry>,
+}
+
+impl Chain {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + self.entries.len() * ENTRY_LEN + 4);
+        out.extend_from_slice(&MAGIC);
+        out.push(FORMAT_VERSION);
+        out.extend_from_slice(&self.base);
+        out.extend_from_slice(&(self.entries.len() as u32).to_le_bytes());
+        for e in &self.entries {
+            out.extend_from_slice(&e.body());
+            out.extend_from_slice(&e.hash);
+        }
+        let sum = checksum(&out);
+        out.extend_from_slice(&sum.to_le_bytes());
+        out
+    }
+
+    /// Decode and verify every link and hash.
+    fn from_bytes(data: &[u8]) -> Result<Self, LedgerError> {
+        if data.len() < HEADER_LEN + 4 || data[..4] != MAGIC || data[4] != FORMAT_VERSION {
+            return Err(LedgerError::Corrupt);
+        }
+        let (body, tail) = data.split_at(data.len() - 4);
+        if checksum(body).to_le_bytes() != tail {
+            return Err(LedgerError::Corrupt);
+        }
+        let mut base = [0u8; 32];
+        base.copy_from_slice(&body[5..37]);
+        let count = u32::from_le_bytes([body[37], body[38], body[39], body[40]]) as usize;
+        let raw = &body[HEADER_LEN..];
+        if raw.len() != count * ENTRY_LEN {
+            return Err(LedgerError::Corrupt);
+        }
+
+        let mut entries: Vec<LedgerEntry> = Vec::with_capacity(count);
+        for chunk in raw.chunks_exact(ENTRY_LEN) {
+            let e = LedgerEntry::decode(chunk).ok_or(LedgerError::Corrupt)?;
+            let (prev_hash, prev_seq) = entries.last().map_or((base, None), |p| (p.hash, Some(p.seq)));
+            let in_order = prev_seq.is_none_or(|s| e.seq == s.wrapping_add(1));
+            if !ct::eq(&e.prev, &prev_hash) || !in_order || !ct::eq(&e.compute_hash(), &e.hash) {
+                return Err(LedgerError::Broken(e.seq));
+            }
+            entries.push(e);
+        }
+        Ok(Self { base, entries })
+    }
+}
+
+/// Check an exported ledger (`BootLedger::export`) and return its entries.
+pub fn verify_export(data: &[u8]) -> Result<Vec<LedgerEntry>, LedgerError> {
+    Chain::from_bytes(data).map(|c| c.entries)
+}
+
+/// The boot-event ledger bound to its persistent store.
+pub struct BootLedger<S: LedgerStore> {
+    chain: Chain,
+    capacity: usize,
+    store: S,
+}
+
+impl<S: LedgerStore> BootLedger<S> {
+    /// Open the ledger kept in `store`, verifying the whole chain, or
+    /// start an empty one. At most `capacity` entries (at least 1) are
+    /// kept.
+    pub fn open(mut store: S, capacity: usize) -> Result<Self, LedgerError> {
+        let chain = match store.load().map_err(LedgerError::Storage)? {
+            Some(bytes) => Chain::from_bytes(&bytes).inspect_err(|e| {
+                error!("boot ledger not restored: {:?}", e);
+            })?,
+            None => Chain { base: [0; 32], entries: Vec::new() },
+        };
+        Ok(Self { chain, capacity: capacity.max(1), store })
+    }
+
+    /// Append `event` at `timestamp`. The ledger is persisted before this
+    /// returns; if that fails, nothing is recorded.
+    pub fn record(&mut self, event: BootEvent, timestamp: u64) -> Result<LedgerEntry, LedgerError> {
+        let last = self.chain.entries.last();
+        let seq = last.map_or(0, |e| e.seq.wrapping_add(1));
+        let boot = last.map_or(0, |e| e.boot) + matches!(event, BootEvent::Boot { .. }) as u32;
+        let prev = last.map_or(self.chain.base, |e| e.hash);
+        let mut entry = LedgerEntry { seq, boot, timestamp, event, prev, hash: [0; 32] };
+        entry.hash = entry.compute_hash();
+
+        let previous = self.chain.clone();
+        if self.chain.entries.len() >= self.capacity {
+            let dropped = self.chain.entries.remove(0);
+            self.chain.base = dropped.hash;
+        }
+        self.chain.entries.push(entry);
+        if let Err(e) = self.store.save(&self.chain.to_bytes()) {
+            error!("boot event not persisted: {}", e);
+            self.chain = previous;
+            return Err(LedgerError::Storage(e));
+        }
+        info!("boot ledger #{}: {:?}", seq, event);
+        Ok(entry)
+    }
+
+    /// Entries held, oldest first.
+    pub fn entries(&self) -> &[LedgerEntry] {
+        &self.chain.entries
+    }
+
+    /// Latest entry, to anchor against rollback; `None` while empty.
+    pub fn head(&self) -> Option<LedgerHead> {
+        self.chain.entries.last().map(|e| LedgerHead { seq: e.seq, hash: e.hash })
+    }
+
+    /// Number of boots recorded.
+    pub fn boot_count(&self) -> u32 {
+        self.chain.entries.last().map_or(0, |e| e.boot)
+    }
+
+    /// Check that the ledger still contains `anchor` (the head mirrored
+    /// after an earlier append), i.e. it was not truncated or replaced by
+    /// an older copy. An anchor older than the kept entries cannot be
+    /// checked and passes.
+    pub fn verify_anchor(&self, anchor: &LedgerHead) -> Result<(), LedgerError> {
+        let entries = &self.chain.entries;
+        let Some(first) = entries.first() else {
+            return Err(LedgerError::RolledBack);
+        };
+        let Some(offset) = anchor.seq.checked_sub(first.seq) else {
+            return Ok(());
+        };
+        match entries.get(offset as usize) {
+            Some(e) if ct::eq(&e.hash, &anchor.hash) => Ok(()),
+            _ => Err(LedgerError::RolledBack),
+        }
+    }
+
+    /// The ledger for offline analysis (see `verify_export`).
+    pub fn export(&self) -> Vec<u8> {
+        self.chain.to_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct RamStore {
+        data: Option<Vec<u8>>,
+        fail: bool,
+    }
+
+    impl LedgerStore for &mut RamStore {
+        fn load(&mut self) -> Result<Option<Vec<u8>>, &'static str> {
+            Ok(self.data.clone())
+        }
+        fn save(&mut self, data: &[u8]) -> Result<(), &'static str> {
+            if self.fail {
+                return Err("flash write failed");
+            }
+            self.data = Some(data.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn chain_survives_reboot_and_verifies_offline() {
+        let mut store = RamStore::default();
+        let anchor = {
+            let mut ledger = BootLedger::open(&mut store, 16).unwrap();
+            ledger.record(BootEvent::Boot { reset_cause: 1 }, 100).unwrap();
+            ledger.record(BootEvent::OtaInstalled { version: 0x0102_0003 }, 150).unwrap();
+            ledger.record(BootEvent::Shutdown(ShutdownReason::Update), 151).unwrap();
+            ledger.head().unwrap()
+        };
+        let mut ledger = BootLedger::open(&mut store, 16).unwrap();
+        let e = ledger.record(BootEvent::Boot { reset_cause: 4 }, 160).unwrap();
+        assert_eq!((e.seq, e.boot), (3, 2));
+        ledger.record(BootEvent::SafeMode { reason: 7 }, 161).unwrap();
+        assert_eq!(ledger.verify_anchor(&anchor), Ok(()));
+
+        let entries = verify_export(&ledger.export()).unwrap();
+        assert_eq!(entries.len(), 5);
+        assert_eq!(entries[1].event, BootEvent::OtaInstalled { version: 0x0102_0003 });
+        assert_eq!(entries[4].boot, 2);
+    }
+
+    #[test]
+    fn tampering_and_rollback_are_detected() {
+        let mut store = RamStore::default();
+        let mut ledger = BootLedger::open(&mut store, 16).unwrap();
+        for t in 0..3 {
+            ledger.record(BootEvent::Boot { reset_cause: 0 }, t).unwrap();
+        }
+        let old = ledger.export();
+        ledger.record(BootEvent::Shutdown(ShutdownReason::Fault), 9).unwrap();
+        let anchor = ledger.head().unwrap();
+
+        // Edit the timestamp of entry 1 and fix up the outer checksum
+        let mut edited = ledger.export();
+        edited[HEADER_LEN + ENTRY_LEN + 8] ^= 1;
+        let n = edited.len() - 4;
+        let sum = checksum(&edited[..n]);
+        edited[n..].copy_from_slice(&sum.to_le_bytes());
+        assert_eq!(verify_export(&edited), Err(LedgerError::Broken(1)));
+
+        // An older copy still verifies on its own, but not against the anchor
+        let mut stale = RamStore { data: Some(old), fail: false };
+        let restored = BootLedger::open(&mut stale, 16).unwrap();
+        assert_eq!(restored.verify_anchor(&anchor), Err(LedgerError::RolledBack));
+    }
+
+    #[test]
+    fn pruning_keeps_a_verifiable_tail_and_failed_writes_roll_back() {
+        let mut store = RamStore::default();
+        let mut ledger = BootLedger::open(&mut store, 2).unwrap();
+        let first = ledger.record(BootEvent::Boot { reset_cause: 0 }, 0).unwrap();
+        for t in 1..4 {
+            ledger.record(BootEvent::SafeMode { reason: t as u8 }, t).unwrap();
+        }
+        let seqs: Vec<u32> = ledger.entries().iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, [2, 3]);
+        assert_eq!(ledger.boot_count(), 1);
+        assert_eq!(ledger.verify_anchor(&LedgerHead { seq: first.seq, hash: first.hash }), Ok(()), "pruned");
+        assert_eq!(verify_export(&ledger.export()).map(|e| e.len()), Ok(2));
+
+        drop(ledger);
+        store.fail = true;
+        let mut ledger = BootLedger::open(&mut store, 2).unwrap();
+        let before = ledger.export();
+        assert!(matches!(ledger.record(BootEvent::Boot { reset_cause: 0 }, 9), Err(LedgerError::Storage(_))));
+        assert_eq!(ledger.export(), before);
+    }
+}
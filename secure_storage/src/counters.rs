@@ -0,0 +1,453 @@
+//! SecureIoTOS Monotonic Counter Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+
+//! Monotonic counters that survive reboot: nonce counters, anti-replay
+//! floors, token serial numbers.
+//!
+//! Counters only ever go up (`increment`, `advance_to`), and a new value
+//! is in flash before it is returned. Each update appends one 32-byte
+//! record to a log over the sectors of a `FlashDriver`, so a sector is
+//! erased once per few dozen updates rather than every time. When a
+//! sector fills up the log moves to the next one (circularly), starting
+//! it with a snapshot of every counter; the oldest sector is the only one
+//! ever erased, and it holds nothing the others do not.
+//!
+//! A record is `id | mark | reserved u16 | seq u32 | value | mac`. The
+//! value is encrypted with AES-256-CTR under the record's unique `seq`,
+//! and the first 16 bytes are authenticated with a truncated HMAC-SHA256.
+//! On `open()` a record that fails its MAC, a reused `seq`, or a counter
+//! whose value drops as `seq` rises is reported as `Tampered`. The one
+//! exception is a torn record at the very end of the log (a power cut
+//! mid-write): it is overwritten with zeros, which NOR flash allows
+//! without an erase, and all-zero records are skipped from then on.
+//!
+//! Flash alone cannot tell the current log from an older copy of it, or
+//! from a wiped area. Where that matters, anchor `sequence()` in a
+//! hardware monotonic counter (OTP, secure element) and compare on boot,
+//! as with `key_store::KeyStore::generation`.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crypto::aes::{self, CryptoBackend, SoftwareAes};
+use crypto::kdf::{self, KdfError, Purpose};
+use sios_log::warn;
+use zeroize::Zeroizing;
+
+use crate::flash_driver::{FlashDriver, FlashError};
+
+/// Number of counters (ids `0..MAX_COUNTERS`).
+pub const MAX_COUNTERS: usize = 32;
+/// Bytes per log record.
+pub const RECORD_LEN: usize = 32;
+
+/// Second byte of every record, so a record never looks erased.
+const RECORD_MARK: u8 = 0xC7;
+/// id | mark | reserved | seq | value: the authenticated part.
+const BODY_LEN: usize = 16;
+const MAC_LEN: usize = 16;
+
+/// Errors of the counter store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CounterError {
+    /// No counter with this id
+    InvalidId,
+    /// The counter (or the log sequence) would wrap
+    Overflow,
+    /// `advance_to` with a value below the current one
+    Backwards,
+    /// The log was modified: a bad MAC, a replayed record or a counter
+    /// that went down
+    Tampered,
+    /// The flash region cannot hold the log (too few or too small
+    /// sectors, unsupported program unit)
+    Geometry,
+    /// No device root key to derive the counter key from
+    NoRootKey,
+    /// The flash driver failed
+    Storage(FlashError),
+}
+
+impl From<FlashError> for CounterError {
+    fn from(e: FlashError) -> Self {
+        CounterError::Storage(e)
+    }
+}
+
+impl From<KdfError> for CounterError {
+    fn from(_: KdfError) -> Self {
+        CounterError::NoRootKey
+    }
+}
+
+/// Encryption and MAC keys of the counter log.
+pub struct CounterKey {
+    enc: Zeroizing<[u8; aes::KEY_LEN]>,
+    mac: Zeroizing<[u8; kdf::KEY_LEN]>,
+}
+
+impl CounterKey {
+    /// Keys derived from a 32-byte secret.
+    pub fn new(key: &[u8; 32]) -> Self {
+        let prk = Zeroizing::new(kdf::hkdf_extract(b"SecureIoTOS counters v1", key));
+        let mut enc = Zeroizing::new([0u8; aes::KEY_LEN]);
+        let mut mac = Zeroizing::new([0u8; kdf::KEY_LEN]);
+        // 32 bytes is always within the HKDF output limit.
+        let _ = kdf::hkdf_expand(&prk, b"enc", &mut enc[..]);
+        let _ = kdf::hkdf_expand(&prk, b"mac", &mut mac[..]);
+        Self { enc, mac }
+    }
+
+    /// Keys derived from the installed device root key
+    /// (`crypto::kdf::install_root_key`).
+    pub fn from_root_key() -> Result<Self, CounterError> {
+        let key = kdf::derive_key(Purpose::StorageEncryption, b"counters")?;
+        Ok(Self::new(&key))
+    }
+
+    fn seal(&self, id: u8, seq: u32, value: u64) -> [u8; RECORD_LEN] {
+        let mut record = [0u8; RECORD_LEN];
+        record[0] = id;
+        record[1] = RECORD_MARK;
+        record[4..8].copy_from_slice(&seq.to_be_bytes());
+        record[8..16].copy_from_slice(&value.to_be_bytes());
+        self.apply_keystream(&mut record);
+        let mac = kdf::hmac_sha256(&self.mac[..], &record[..BODY_LEN]);
+        record[BODY_LEN..].copy_from_slice(&mac[..MAC_LEN]);
+        record
+    }
+
+    /// `(id, seq, value)` of an authentic record.
+    fn open(&self, record: &[u8; RECORD_LEN]) -> Option<(u8, u32, u64)> {
+        let mac = kdf::hmac_sha256(&self.mac[..], &record[..BODY_LEN]);
+        if record[1] != RECORD_MARK || !crypto::ct::eq(&mac[..MAC_LEN], &record[BODY_LEN..]) {
+            return None;
+        }
+        let mut body = *record;
+        self.apply_keystream(&mut body);
+        let seq = u32::from_be_bytes(body[4..8].try_into().unwrap());
+        Some((body[0], seq, u64::from_be_bytes(body[8..16].try_into().unwrap())))
+    }
+
+    /// En- or decrypt the value field; the IV is the record header, which
+    /// is unique through `seq`.
+    fn apply_keystream(&self, record: &mut [u8; RECORD_LEN]) {
+        let mut iv = [0u8; 16];
+        iv[..8].copy_from_slice(&record[..8]);
+        // CTR over 8 bytes cannot fail.
+        let _ = SoftwareAes.ctr_apply(&self.enc, &iv, &mut record[8..16]);
+    }
+}
+
+/// Where a record sits in the log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Slot {
+    sector: usize,
+    index: usize,
+}
+
+/// The counters, bound to their flash region.
+pub struct Counters<D: FlashDriver> {
+    driver: D,
+    key: CounterKey,
+    values: [u64; MAX_COUNTERS],
+    /// `seq` of the last record written
+    seq: u32,
+    /// Next free slot of the log
+    next: Slot,
+}
+
+impl<D: FlashDriver> Counters<D> {
+    /// Load the counters from the log in `driver`'s region; a blank
+    /// region holds all counters at zero. The region needs at least two
+    /// sectors of `MAX_COUNTERS + 2` records each.
+    pub fn open(driver: D, key: CounterKey) -> Result<Self, CounterError> {
+        let geometry = driver.geometry();
+        if geometry.sector_count < 2
+            || geometry.sector_size / RECORD_LEN < MAX_COUNTERS + 2
+            || !RECORD_LEN.is_multiple_of(geometry.program_unit)
+        {
+            return Err(CounterError::Geometry);
+        }
+
+        let mut counters = Self { driver, key, values: [0; MAX_COUNTERS], seq: 0, next: Slot { sector: 0, index: 0 } };
+        let head = counters.replay_log()?;
+        counters.complete_snapshot(head)?;
+        Ok(counters)
+    }
+
+    /// Current value of counter `id`; zero until first incremented.
+    pub fn get(&self, id: u8) -> Result<u64, CounterError> {
+        self.values.get(usize::from(id)).copied().ok_or(CounterError::InvalidId)
+    }
+
+    /// Add one to counter `id` and persist it.
+    ///
+    /// # Returns
+    /// * `Ok(value)`: the new value, already in flash
+    pub fn increment(&mut self, id: u8) -> Result<u64, CounterError> {
+        let value = self.get(id)?.checked_add(1).ok_or(CounterError::Overflow)?;
+        self.store(id, value)?;
+        Ok(value)
+    }
+
+    /// Raise counter `id` to `value` (e.g. the highest counter seen from a
+    /// peer). Equal is a no-op; lower fails with `Backwards`.
+    pub fn advance_to(&mut self, id: u8, value: u64) -> Result<u64, CounterError> {
+        let current = self.get(id)?;
+        if value < current {
+            return Err(CounterError::Backwards);
+        }
+        if value > current {
+            self.store(id, value)?;
+        }
+        Ok(value)
+    }
+
+    /// Sequence number of the last record written; grows with every
+    /// update. Anchor it outside flash to detect a rolled-back log.
+    pub fn sequence(&self) -> u32 {
+        self.seq
+    }
+
+    /// Hand the flash driver back.
+    pub fn into_driver(self) -> D {
+        self.driver
+    }
+
+    fn records_per_sector(&self) -> usize {
+        self.driver.geometry().sector_size / RECORD_LEN
+    }
+
+    fn read_slot(&mut self, slot: Slot) -> Result<[u8; RECORD_LEN], CounterError> {
+        let mut record = [0u8; RECORD_LEN];
+        let offset = self.driver.geometry().sector_offset(slot.sector)? + (slot.index * RECORD_LEN) as u32;
+        self.driver.read(offset, &mut record)?;
+        Ok(record)
+    }
+
+    /// Scan the whole log, check it, and load the latest value of every
+    /// counter.
+    ///
+    /// # Returns
+    /// * `Ok(Some(slot))`: the record with the highest `seq`
+    /// * `Ok(None)` for a blank log
+    fn replay_log(&mut self) -> Result<Option<Slot>, CounterError> {
+        let mut valid: Vec<(u32, u8, u64, Slot)> = Vec::new();
+        let mut torn: Vec<Slot> = Vec::new();
+        // Slots in use per sector, up to the last non-erased one
+        let mut used = vec![0usize; self.driver.geometry().sector_count];
+        for (sector, used) in used.iter_mut().enumerate() {
+            for index in 0..self.records_per_sector() {
+                let slot = Slot { sector, index };
+                let record = self.read_slot(slot)?;
+                if record.iter().all(|&b| b == 0xFF) {
+                    continue;
+                }
+                *used = index + 1;
+                if record.iter().all(|&b| b == 0) {
+                    continue;
+                }
+                match self.key.open(&record) {
+                    Some((id, seq, value)) if usize::from(id) < MAX_COUNTERS => valid.push((seq, id, value, slot)),
+                    Some(_) => return Err(CounterError::Tampered),
+                    None => torn.push(slot),
+                }
+            }
+        }
+
+        valid.sort_unstable_by_key(|&(seq, ..)| seq);
+        for pair in valid.windows(2) {
+            if pair[0].0 == pair[1].0 {
+                return Err(CounterError::Tampered);
+            }
+        }
+        for &(_, id, value, _) in &valid {
+            let current = &mut self.values[usize::from(id)];
+            if value < *current {
+                return Err(CounterError::Tampered);
+            }
+            *current = value;
+        }
+
+        let head = valid.last().map(|&(seq, _, _, slot)| {
+            self.seq = seq;
+            slot
+        });
+        let head_sector = head.map_or(0, |slot| slot.sector);
+        self.next = Slot { sector: head_sector, index: used[head_sector] };
+        // A torn record is only possible right behind the head
+        for slot in torn {
+            if slot.sector != head_sector || head.is_some_and(|head| slot.index < head.index) {
+                return Err(CounterError::Tampered);
+            }
+            warn!("counters: voiding torn record in sector {}", slot.sector);
+            let offset = self.driver.geometry().sector_offset(slot.sector)? + (slot.index * RECORD_LEN) as u32;
+            self.driver.program(offset, &[0u8; RECORD_LEN])?;
+        }
+        Ok(head)
+    }
+
+    /// If a power cut interrupted a snapshot, copy the counters it missed
+    /// into the head sector before anything can erase their last record.
+    fn complete_snapshot(&mut self, head: Option<Slot>) -> Result<(), CounterError> {
+        let Some(head) = head else {
+            return Ok(());
+        };
+        let mut present = [false; MAX_COUNTERS];
+        for index in 0..self.next.index {
+            let record = self.read_slot(Slot { sector: head.sector, index })?;
+            if let Some((id, ..)) = self.key.open(&record) {
+                present[usize::from(id)] = true;
+            }
+        }
+        for (id, present) in present.into_iter().enumerate() {
+            if self.values[id] != 0 && !present {
+                if self.next.index == self.records_per_sector() {
+                    return self.roll();
+                }
+                self.append(id as u8, self.values[id])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Persist `value` for `id`, moving to the next sector first if the
+    /// current one is full, then update the cached value.
+    fn store(&mut self, id: u8, value: u64) -> Result<(), CounterError> {
+        if self.next.index == self.records_per_sector() {
+            self.roll()?;
+        }
+        self.append(id, value)?;
+        self.values[usize::from(id)] = value;
+        Ok(())
+    }
+
+    /// Erase the next sector and start it with a snapshot of all counters.
+    fn roll(&mut self) -> Result<(), CounterError> {
+        let sector = (self.next.sector + 1) % self.driver.geometry().sector_count;
+        self.driver.erase_sector(sector)?;
+        self.next = Slot { sector, index: 0 };
+        for id in 0..MAX_COUNTERS {
+            if self.values[id] != 0 {
+                self.append(id as u8, self.values[id])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, id: u8, value: u64) -> Result<(), CounterError> {
+        let seq = self.seq.checked_add(1).ok_or(CounterError::Overflow)?;
+        let record = self.key.seal(id, seq, value);
+        let offset = self.driver.geometry().sector_offset(self.next.sector)? + (self.next.index * RECORD_LEN) as u32;
+        self.driver.program(offset, &record)?;
+        self.seq = seq;
+        self.next.index += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash_driver::RamFlash;
+
+    type Flash = RamFlash<2048, 3>;
+
+    fn key() -> CounterKey {
+        CounterKey::new(&[7u8; 32])
+    }
+
+    fn open(flash: Flash) -> Result<Counters<Flash>, CounterError> {
+        Counters::open(flash, key())
+    }
+
+    #[test]
+    fn counters_only_go_up_and_survive_reopen() {
+        let mut c = open(Flash::with_program_unit(8)).unwrap();
+        assert_eq!(c.increment(0).unwrap(), 1);
+        assert_eq!(c.increment(0).unwrap(), 2);
+        assert_eq!(c.advance_to(5, 1000).unwrap(), 1000);
+        assert_eq!(c.advance_to(5, 999), Err(CounterError::Backwards));
+        assert_eq!(c.increment(32), Err(CounterError::InvalidId));
+
+        let c = open(c.into_driver()).unwrap();
+        assert_eq!(c.get(0).unwrap(), 2);
+        assert_eq!(c.get(5).unwrap(), 1000);
+        assert_eq!(c.get(1).unwrap(), 0);
+        assert_eq!(c.sequence(), 3);
+    }
+
+    #[test]
+    fn log_wraps_around_all_sectors() {
+        let mut c = open(Flash::new()).unwrap();
+        c.advance_to(9, 50).unwrap();
+        for i in 1..=500u64 {
+            assert_eq!(c.increment(1).unwrap(), i);
+        }
+        let c = open(c.into_driver()).unwrap();
+        assert_eq!(c.get(1).unwrap(), 500);
+        assert_eq!(c.get(9).unwrap(), 50);
+    }
+
+    #[test]
+    fn modified_or_replayed_records_are_tampering() {
+        let k = key();
+        let log = |records: &[[u8; RECORD_LEN]]| {
+            let mut flash = Flash::new();
+            for (i, record) in records.iter().enumerate() {
+                flash.program((i * RECORD_LEN) as u32, record).unwrap();
+            }
+            open(flash).err()
+        };
+        let mut flipped = k.seal(2, 1, 1);
+        flipped[10] ^= 0x01;
+
+        assert_eq!(log(&[k.seal(2, 1, 1), k.seal(2, 2, 2)]), None);
+        // A modified record in front of the head is not a torn write
+        assert_eq!(log(&[flipped, k.seal(2, 2, 2)]), Some(CounterError::Tampered));
+        // An old record replayed after the head
+        assert_eq!(log(&[k.seal(2, 1, 1), k.seal(2, 2, 2), k.seal(2, 1, 1)]), Some(CounterError::Tampered));
+        // A counter that goes down
+        assert_eq!(log(&[k.seal(2, 1, 5), k.seal(2, 2, 4)]), Some(CounterError::Tampered));
+        // A record from another device (key)
+        let foreign = CounterKey::new(&[8u8; 32]).seal(2, 1, 9);
+        assert_eq!(log(&[foreign, k.seal(2, 2, 2)]), Some(CounterError::Tampered));
+    }
+
+    #[test]
+    fn torn_tail_is_skipped_and_snapshot_completed() {
+        let mut flash = Flash::new();
+        let k = key();
+        flash.program(0, &k.seal(3, 1, 10)).unwrap();
+        flash.program(RECORD_LEN as u32, &k.seal(4, 2, 20)).unwrap();
+        // Sector 1: an interrupted snapshot holding only counter 3, then
+        // a record cut short by the power failure
+        flash.program(2048, &k.seal(3, 3, 11)).unwrap();
+        let mut torn = k.seal(4, 4, 20);
+        torn[20..].fill(0xFF);
+        flash.program(2048 + RECORD_LEN as u32, &torn).unwrap();
+
+        let mut c = open(flash).unwrap();
+        assert_eq!((c.get(3).unwrap(), c.get(4).unwrap()), (11, 20));
+        assert_eq!(c.increment(3).unwrap(), 12);
+        // Counter 4 was copied into sector 1, so erasing sector 0 loses
+        // nothing, and the voided record no longer counts as tampering
+        let mut flash = c.into_driver();
+        flash.erase_sector(0).unwrap();
+        let c = open(flash).unwrap();
+        assert_eq!((c.get(3).unwrap(), c.get(4).unwrap()), (12, 20));
+    }
+
+    #[test]
+    fn region_must_hold_a_full_snapshot() {
+        assert_eq!(Counters::open(RamFlash::<1024, 2>::new(), key()).err(), Some(CounterError::Geometry));
+        assert_eq!(Counters::open(RamFlash::<2048, 1>::new(), key()).err(), Some(CounterError::Geometry));
+    }
+}
@@ -0,0 +1,334 @@
+//! SecureIoTOS Downstream Device Enrollment Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+
+//! Allowlist of the downstream peripherals and sensors this device talks to.
+//!
+//! Each enrolled device has an ID and a P-256 public key. `secure_bus` and
+//! the gateway forwarding path ask the registry before accepting anything
+//! from a device, and check its signatures against the enrolled key:
+//! unknown and revoked devices are rejected.
+//!
+//! Revocation keeps a tombstone so a revoked device cannot be quietly
+//! enrolled again (a stolen sensor re-presenting itself); only an explicit
+//! `purge` clears it.
+//!
+//! Like the replay window, the registry is persisted to its store before a
+//! change is reported as done; a failed save rolls the change back.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use sios_log::{info, warn};
+
+use crate::replay::checksum;
+
+/// Magic prefix of the persisted registry ("DREG").
+const MAGIC: [u8; 4] = *b"DREG";
+/// Encoding version of the persisted registry.
+const FORMAT_VERSION: u8 = 1;
+
+/// Longest device ID in bytes.
+pub const MAX_ID_LEN: usize = 64;
+/// Length of an enrolled public key (SEC1 compressed P-256 point).
+pub const PUBLIC_KEY_LEN: usize = 33;
+
+/// Whether an enrolled device may talk to us.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DeviceStatus {
+    Active = 0,
+    Revoked = 1,
+}
+
+/// Errors reported by the registry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum EnrollmentError {
+    /// Device is not enrolled
+    Unknown,
+    /// Device was revoked
+    Revoked,
+    /// Device is already enrolled
+    AlreadyEnrolled,
+    /// Registry is at capacity
+    Full,
+    /// Empty or oversize ID
+    InvalidId,
+    /// Not a valid SEC1 compressed P-256 key
+    InvalidKey,
+    /// Signature does not verify against the enrolled key
+    BadSignature,
+    /// Persisted registry failed its integrity check or has the wrong format
+    Corrupt,
+    /// Backing storage failed
+    Storage(&'static str),
+}
+
+/// One allowlisted device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnrolledDevice {
+    pub id: String,
+    pub public_key: [u8; PUBLIC_KEY_LEN],
+    pub status: DeviceStatus,
+}
+
+/// Backing store for the registry, typically a dedicated encrypted sector.
+pub trait RegistryStore {
+    /// Load the last saved registry; `Ok(None)` if nothing was ever saved.
+    fn load(&mut self) -> Result<Option<Vec<u8>>, &'static str>;
+    /// Atomically replace the saved registry.
+    fn save(&mut self, data: &[u8]) -> Result<(), &'static str>;
+}
+
+/// The allowlist, bound to its persistent store.
+pub struct DeviceRegistry<S: RegistryStore> {
+    capacity: usize,
+    devices: Vec<EnrolledDevice>,
+    store: S,
+}
+
+impl<S: RegistryStore> DeviceRegistry<S> {
+    /// Open the registry, restoring it from `store` if one was saved.
+    ///
+    /// A corrupt registry is an error, not an empty allowlist, so a
+    /// damaged sector cannot be used to wipe revocations.
+    pub fn open(mut store: S, capacity: usize) -> Result<Self, EnrollmentError> {
+        let devices = match store.load().map_err(EnrollmentError::Storage)? {
+            Some(bytes) => decode(&bytes)?,
+            None => Vec::new(),
+        };
+        if devices.len() > capacity {
+            return Err(EnrollmentError::Full);
+        }
+        Ok(Self { capacity, devices, store })
+    }
+
+    /// Every entry, revoked ones included.
+    pub fn devices(&self) -> &[EnrolledDevice] {
+        &self.devices
+    }
+
+    /// Add `id` with its public key.
+    pub fn enroll(&mut self, id: &str, public_key: &[u8]) -> Result<(), EnrollmentError> {
+        if id.is_empty() || id.len() > MAX_ID_LEN {
+            return Err(EnrollmentError::InvalidId);
+        }
+        let public_key: [u8; PUBLIC_KEY_LEN] = public_key.try_into().map_err(|_| EnrollmentError::InvalidKey)?;
+        if !matches!(public_key[0], 0x02 | 0x03) {
+            return Err(EnrollmentError::InvalidKey);
+        }
+        match self.find(id) {
+            Some(d) if d.status == DeviceStatus::Revoked => return Err(EnrollmentError::Revoked),
+            Some(_) => return Err(EnrollmentError::AlreadyEnrolled),
+            None => {}
+        }
+        if self.devices.len() >= self.capacity {
+            return Err(EnrollmentError::Full);
+        }
+
+        self.devices.push(EnrolledDevice { id: id.into(), public_key, status: DeviceStatus::Active });
+        if let Err(e) = self.persist() {
+            self.devices.pop();
+            return Err(e);
+        }
+        info!("downstream device {} enrolled", id);
+        Ok(())
+    }
+
+    /// Revoke `id`: it is rejected from now on and cannot be re-enrolled
+    /// until purged.
+    pub fn revoke(&mut self, id: &str) -> Result<(), EnrollmentError> {
+        let idx = self.devices.iter().position(|d| d.id == id).ok_or(EnrollmentError::Unknown)?;
+        let previous = self.devices[idx].status;
+        self.devices[idx].status = DeviceStatus::Revoked;
+        if let Err(e) = self.persist() {
+            self.devices[idx].status = previous;
+            return Err(e);
+        }
+        warn!("downstream device {} revoked", id);
+        Ok(())
+    }
+
+    /// Forget `id` entirely, tombstone included.
+    pub fn purge(&mut self, id: &str) -> Result<(), EnrollmentError> {
+        let idx = self.devices.iter().position(|d| d.id == id).ok_or(EnrollmentError::Unknown)?;
+        let removed = self.devices.remove(idx);
+        if let Err(e) = self.persist() {
+            self.devices.insert(idx, removed);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// The entry of `id` if it may talk to us.
+    pub fn authorize(&self, id: &str) -> Result<&EnrolledDevice, EnrollmentError> {
+        match self.find(id) {
+            Some(d) if d.status == DeviceStatus::Active => Ok(d),
+            Some(_) => Err(EnrollmentError::Revoked),
+            None => Err(EnrollmentError::Unknown),
+        }
+    }
+
+    /// Check that `signature` over `message` was made by the enrolled,
+    /// active device `id`.
+    pub fn verify(&self, id: &str, message: &[u8], signature: &[u8]) -> Result<(), EnrollmentError> {
+        let device = self.authorize(id)?;
+        if crypto::ecc::verify_message(&device.public_key, message, signature) {
+            Ok(())
+        } else {
+            Err(EnrollmentError::BadSignature)
+        }
+    }
+
+    fn find(&self, id: &str) -> Option<&EnrolledDevice> {
+        self.devices.iter().find(|d| d.id == id)
+    }
+
+    fn persist(&mut self) -> Result<(), EnrollmentError> {
+        self.store.save(&encode(&self.devices)).map_err(EnrollmentError::Storage)
+    }
+}
+
+/// `magic(4) | version(1) | count(4) |
+///  (id_len(1) | id | status(1) | public_key(33)) * count | checksum(4)`.
+fn encode(devices: &[EnrolledDevice]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(13 + devices.len() * (2 + MAX_ID_LEN + PUBLIC_KEY_LEN));
+    out.extend_from_slice(&MAGIC);
+    out.push(FORMAT_VERSION);
+    out.extend_from_slice(&(devices.len() as u32).to_le_bytes());
+    for d in devices {
+        out.push(d.id.len() as u8);
+        out.extend_from_slice(d.id.as_bytes());
+        out.push(d.status as u8);
+        out.extend_from_slice(&d.public_key);
+    }
+    let sum = checksum(&out);
+    out.extend_from_slice(&sum.to_le_bytes());
+    out
+}
+
+fn decode(data: &[u8]) -> Result<Vec<EnrolledDevice>, EnrollmentError> {
+    if data.len() < 13 || data[..4] != MAGIC || data[4] != FORMAT_VERSION {
+        return Err(EnrollmentError::Corrupt);
+    }
+    let (body, tail) = data.split_at(data.len() - 4);
+    if checksum(body) != u32::from_le_bytes([tail[0], tail[1], tail[2], tail[3]]) {
+        return Err(EnrollmentError::Corrupt);
+    }
+    let count = u32::from_le_bytes([body[5], body[6], body[7], body[8]]) as usize;
+    let mut rest = &body[9..];
+    let mut devices = Vec::with_capacity(count.min(rest.len()));
+    for _ in 0..count {
+        let (&id_len, after) = rest.split_first().ok_or(EnrollmentError::Corrupt)?;
+        let id_len = id_len as usize;
+        if after.len() < id_len + 1 + PUBLIC_KEY_LEN {
+            return Err(EnrollmentError::Corrupt);
+        }
+        let id = core::str::from_utf8(&after[..id_len]).map_err(|_| EnrollmentError::Corrupt)?;
+        let status = match after[id_len] {
+            0 => DeviceStatus::Active,
+            1 => DeviceStatus::Revoked,
+            _ => return Err(EnrollmentError::Corrupt),
+        };
+        let mut public_key = [0u8; PUBLIC_KEY_LEN];
+        public_key.copy_from_slice(&after[id_len + 1..id_len + 1 + PUBLIC_KEY_LEN]);
+        devices.push(EnrolledDevice { id: id.into(), public_key, status });
+        rest = &after[id_len + 1 + PUBLIC_KEY_LEN..];
+    }
+    if !rest.is_empty() {
+        return Err(EnrollmentError::Corrupt);
+    }
+    Ok(devices)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::{signature::Signer, Signature, SigningKey};
+
+    #[derive(Default)]
+    struct RamStore {
+        data: Option<Vec<u8>>,
+        fail: bool,
+    }
+
+    impl RegistryStore for &mut RamStore {
+        fn load(&mut self) -> Result<Option<Vec<u8>>, &'static str> {
+            Ok(self.data.clone())
+        }
+        fn save(&mut self, data: &[u8]) -> Result<(), &'static str> {
+            if self.fail {
+                return Err("flash write failed");
+            }
+            self.data = Some(data.to_vec());
+            Ok(())
+        }
+    }
+
+    fn keypair(seed: u8) -> (SigningKey, Vec<u8>) {
+        let sk = SigningKey::from_bytes(&[seed; 32]).unwrap();
+        let pk = sk.verifying_key().to_encoded_point(true).as_bytes().to_vec();
+        (sk, pk)
+    }
+
+    #[test]
+    fn enrolled_devices_verify_and_others_are_rejected() {
+        let mut store = RamStore::default();
+        let mut reg = DeviceRegistry::open(&mut store, 4).unwrap();
+        let (sk, pk) = keypair(1);
+        let (other, _) = keypair(2);
+        reg.enroll("sensor-1", &pk).unwrap();
+        assert_eq!(reg.enroll("sensor-1", &pk), Err(EnrollmentError::AlreadyEnrolled));
+        assert_eq!(reg.enroll("sensor-2", &pk[..32]), Err(EnrollmentError::InvalidKey));
+
+        let sig: Signature = sk.sign(b"challenge");
+        reg.verify("sensor-1", b"challenge", sig.as_ref()).unwrap();
+        let forged: Signature = other.sign(b"challenge");
+        assert_eq!(reg.verify("sensor-1", b"challenge", forged.as_ref()), Err(EnrollmentError::BadSignature));
+        assert_eq!(reg.verify("sensor-9", b"challenge", sig.as_ref()), Err(EnrollmentError::Unknown));
+    }
+
+    #[test]
+    fn revocation_survives_reboot_and_blocks_reenrollment() {
+        let mut store = RamStore::default();
+        let (_, pk) = keypair(3);
+        {
+            let mut reg = DeviceRegistry::open(&mut store, 4).unwrap();
+            reg.enroll("valve-7", &pk).unwrap();
+            reg.revoke("valve-7").unwrap();
+        }
+        let mut reg = DeviceRegistry::open(&mut store, 4).unwrap();
+        assert_eq!(reg.authorize("valve-7"), Err(EnrollmentError::Revoked));
+        assert_eq!(reg.enroll("valve-7", &pk), Err(EnrollmentError::Revoked));
+        reg.purge("valve-7").unwrap();
+        reg.enroll("valve-7", &pk).unwrap();
+        assert!(reg.authorize("valve-7").is_ok());
+    }
+
+    #[test]
+    fn storage_failure_rolls_back_and_corruption_is_reported() {
+        let mut store = RamStore::default();
+        let (_, pk) = keypair(4);
+        DeviceRegistry::open(&mut store, 4).unwrap().enroll("a", &pk).unwrap();
+
+        store.fail = true;
+        {
+            let mut reg = DeviceRegistry::open(&mut store, 4).unwrap();
+            assert!(matches!(reg.enroll("b", &pk), Err(EnrollmentError::Storage(_))));
+            assert!(matches!(reg.revoke("a"), Err(EnrollmentError::Storage(_))));
+            assert_eq!(reg.devices().len(), 1);
+            assert!(reg.authorize("a").is_ok());
+        }
+
+        store.fail = false;
+        if let Some(bytes) = store.data.as_mut() {
+            bytes[10] ^= 0xFF;
+        }
+        assert_eq!(DeviceRegistry::open(&mut store, 4).err(), Some(EnrollmentError::Corrupt));
+    }
+}
@@ -0,0 +1,91 @@
+//! SecureIoTOS Secure Erase Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+
+//! Destruction of stored credentials, for decommissioning or after a
+//! compromise.
+//!
+//! Overwriting is not enough on flash: `erase_secure()` performs a real
+//! erase of the sector through its flash driver and reads it back, so a
+//! stuck bit or a write-protected range is reported instead of assumed
+//! away. Sectors share the storage key (`key_mgmt`), so a single sector
+//! can only be erased; erasing everything also wipes the key first, which
+//! makes any copy the erase could not reach (a flash dump, a block the
+//! device remapped) undecryptable as well. A key derived from the root
+//! key (`key_mgmt::init_keys_from_root`) comes back with the same epoch,
+//! so move to a new epoch before storing anything again.
+//!
+//! Keys in the persistent key store are destroyed with
+//! `key_store::KeyStore::delete`.
+
+use sios_log::{info, warn};
+
+use crate::{key_mgmt, wear_level};
+
+/// What `erase_secure()` destroys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum EraseTarget {
+    /// One storage sector
+    Sector(usize),
+    /// The storage key, then every sector
+    All,
+    /// Only the storage key: everything sealed under it becomes unreadable
+    /// while the sectors stay as they are
+    StorageKey,
+}
+
+/// Why secure erase did not complete.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum EraseError {
+    /// No such sector
+    InvalidSector,
+    /// The flash driver failed
+    Flash(&'static str),
+    /// The sector still holds data after the erase
+    NotErased(usize),
+}
+
+/// Destroy `target` and check that it is gone.
+///
+/// # Returns
+/// * `Ok(())` once every sector of the target reads back erased
+/// * `Err(e)` otherwise; with `All`, the storage key is destroyed even
+///   if erasing a sector then fails
+pub fn erase_secure(target: EraseTarget) -> Result<(), EraseError> {
+    match target {
+        EraseTarget::Sector(idx) => erase_verified(idx),
+        EraseTarget::StorageKey => {
+            key_mgmt::destroy_encryption_key();
+            info!("storage key destroyed");
+            Ok(())
+        }
+        EraseTarget::All => {
+            key_mgmt::destroy_encryption_key();
+            info!("storage key destroyed");
+            for idx in 0..wear_level::get_sector_count() {
+                erase_verified(idx)?;
+            }
+            wear_level::init_wear_level();
+            Ok(())
+        }
+    }
+}
+
+fn erase_verified(idx: usize) -> Result<(), EraseError> {
+    if idx >= wear_level::get_sector_count() {
+        return Err(EraseError::InvalidSector);
+    }
+    wear_level::erase_sector(idx).map_err(EraseError::Flash)?;
+    if !wear_level::is_sector_erased(idx).map_err(EraseError::Flash)? {
+        warn!("sector {} still holds data after erase", idx);
+        return Err(EraseError::NotErased(idx));
+    }
+    info!("sector {} erased", idx);
+    Ok(())
+}
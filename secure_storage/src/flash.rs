@@ -7,7 +7,15 @@
 //! URL     : <https://m-a-h-b-u-b.github.io>
 //! GitHub  : <https://github.com/m-a-h-b-u-b/SecureIoTOS>
 //!
-//! Provides sector-level flash encryption and secure wear-leveling integration.
+//! Provides sector-level flash encryption and secure wear-leveling integration,
+//! and `RegionWriter` for streaming raw images (firmware updates) into flash.
+//!
+//! Each sector holds one record: a header, the data encrypted with
+//! AES-256-GCM and the tag. Reads fail closed; a sector that was modified,
+//! moved to another index or written under another key is an error, not
+//! garbage plaintext.
+
+use alloc::vec::Vec;
 
 // Bring in the project's key management module (handles encryption keys)
 use crate::key_mgmt;
@@ -15,78 +23,378 @@ use crate::key_mgmt;
 // Bring in the wear-leveling module (manages flash memory sectors fairly)
 use crate::wear_level;
 
-// Bring in `anyhow` for ergonomic error handling:
-// - `Result` is a flexible error-aware return type
-// - `Context` lets you add human-readable context to errors
-use anyhow::{Context, Result};
+// AES-256-GCM sealing of sector records, and the legacy CBC decryption
+use crypto::aes;
+use zeroize::Zeroizing;
+
+#[cfg(feature = "legacy-sectors")]
+use sios_log::warn;
+
+use hal::bsp::{BspError, BspFlash};
+
+/// Magic at the start of every authenticated sector record.
+pub const SECTOR_MAGIC: [u8; 8] = *b"SIOSSEC2";
+/// `magic | counter u64 | len u32 | salt u32`, all big-endian.
+pub const SECTOR_HEADER_LEN: usize = 24;
+
+/// HKDF salt turning the 16-byte storage key into the sector GCM key.
+const SECTOR_KEY_SALT: &[u8] = b"SecureIoTOS sector v2";
+
+/// Why a sector could not be stored or read back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SectorError {
+    /// The sector has never been written
+    Erased,
+    /// Unauthenticated AES-CBC sector from before the record format
+    /// (accepted only with the `legacy-sectors` feature)
+    Legacy,
+    /// Header or length field is inconsistent
+    Corrupt,
+    /// GCM tag mismatch: the sector was modified, moved or written with
+    /// another key
+    AuthFailed,
+    /// Data does not fit a sector
+    TooLarge,
+    /// The storage key was never set up or has been destroyed
+    KeyUnavailable(key_mgmt::KeyStatus),
+    /// Every write counter has been used
+    CounterExhausted,
+    /// No salt for the nonce
+    Rng(crypto::rng::RngError),
+    /// The cipher refused the record
+    Crypto(aes::AesError),
+    /// Wear leveling could not read or write the sector
+    Flash(&'static str),
+}
+
+impl core::fmt::Display for SectorError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Debug::fmt(self, f)
+    }
+}
+
+impl core::error::Error for SectorError {}
+
+/// Plaintext of a sector and the write counter it was stored under.
+#[derive(Debug)]
+pub struct SectorRecord {
+    pub counter: u64,
+    pub data: Vec<u8>,
+}
+
+/// Seal `data` into a sector record for sector `sector_idx`.
+///
+/// The record is AES-256-GCM under a key derived from `key`. The nonce is
+/// the random `salt` followed by `counter`, which the caller keeps above
+/// every counter already in flash; the salt keeps nonces apart even if
+/// the sectors are wiped and the counter starts over. The header and the
+/// sector index are authenticated, so a record copied into another sector
+/// fails to open.
+pub fn seal_sector(key: &[u8; 16], sector_idx: usize, counter: u64, salt: u32, data: &[u8]) -> Result<Vec<u8>, SectorError> {
+    let len = u32::try_from(data.len()).map_err(|_| SectorError::TooLarge)?;
+    let mut header = [0u8; SECTOR_HEADER_LEN];
+    header[..8].copy_from_slice(&SECTOR_MAGIC);
+    header[8..16].copy_from_slice(&counter.to_be_bytes());
+    header[16..20].copy_from_slice(&len.to_be_bytes());
+    header[20..24].copy_from_slice(&salt.to_be_bytes());
+
+    let sealed = aes::gcm_seal(
+        &mut aes::SoftwareAes,
+        &sector_key(key),
+        &sector_nonce(&header),
+        &sector_aad(&header, sector_idx),
+        data,
+    )
+    .map_err(SectorError::Crypto)?;
+
+    let mut record = Vec::with_capacity(SECTOR_HEADER_LEN + sealed.len());
+    record.extend_from_slice(&header);
+    record.extend_from_slice(&sealed);
+    Ok(record)
+}
+
+/// Check and decrypt the record read from sector `sector_idx`. `raw` may
+/// run on past the record (the rest of the sector).
+///
+/// # Errors
+/// A `SectorError`: no data is returned unless the tag verifies.
+pub fn open_sector(key: &[u8; 16], sector_idx: usize, raw: &[u8]) -> Result<SectorRecord, SectorError> {
+    let counter = sector_counter(raw)?;
+    let header = &raw[..SECTOR_HEADER_LEN];
+    let len = u32::from_be_bytes(header[16..20].try_into().unwrap()) as usize;
+    let end = len.checked_add(SECTOR_HEADER_LEN + aes::TAG_LEN).ok_or(SectorError::Corrupt)?;
+    let sealed = raw.get(SECTOR_HEADER_LEN..end).ok_or(SectorError::Corrupt)?;
+
+    let data = aes::gcm_open(
+        &mut aes::SoftwareAes,
+        &sector_key(key),
+        &sector_nonce(header),
+        &sector_aad(header, sector_idx),
+        sealed,
+    )
+    .map_err(|_| SectorError::AuthFailed)?;
+    Ok(SectorRecord { counter, data })
+}
+
+/// Write counter in the (unauthenticated) header of a sector record.
+fn sector_counter(raw: &[u8]) -> Result<u64, SectorError> {
+    let header = raw.get(..SECTOR_HEADER_LEN).ok_or(SectorError::Corrupt)?;
+    if header[..8] != SECTOR_MAGIC {
+        return Err(if header.iter().all(|&b| b == 0xFF) { SectorError::Erased } else { SectorError::Legacy });
+    }
+    Ok(u64::from_be_bytes(header[8..16].try_into().unwrap()))
+}
+
+fn sector_key(key: &[u8; 16]) -> Zeroizing<[u8; aes::KEY_LEN]> {
+    Zeroizing::new(crypto::kdf::hkdf_extract(SECTOR_KEY_SALT, key))
+}
+
+fn sector_nonce(header: &[u8]) -> [u8; aes::NONCE_LEN] {
+    let mut nonce = [0u8; aes::NONCE_LEN];
+    nonce[..4].copy_from_slice(&header[20..24]);
+    nonce[4..].copy_from_slice(&header[8..16]);
+    nonce
+}
+
+fn sector_aad(header: &[u8], sector_idx: usize) -> [u8; SECTOR_HEADER_LEN + 4] {
+    let mut aad = [0u8; SECTOR_HEADER_LEN + 4];
+    aad[..SECTOR_HEADER_LEN].copy_from_slice(header);
+    aad[SECTOR_HEADER_LEN..].copy_from_slice(&(sector_idx as u32).to_be_bytes());
+    aad
+}
+
+/// The storage key, unless it was never set up or has been destroyed
+/// (`erase::erase_secure`).
+fn storage_key() -> Result<sios_log::Secret<[u8; 16]>, SectorError> {
+    match key_mgmt::get_key_status() {
+        key_mgmt::KeyStatus::Initialized => Ok(key_mgmt::get_encryption_key()),
+        status => Err(SectorError::KeyUnavailable(status)),
+    }
+}
+
+/// Counter for the next record: one above the highest in any sector.
+fn next_write_counter() -> Result<u64, SectorError> {
+    let mut highest = 0u64;
+    for idx in 0..wear_level::get_sector_count() {
+        let raw = wear_level::read_sector(idx).map_err(SectorError::Flash)?;
+        if let Ok(counter) = sector_counter(&raw) {
+            highest = highest.max(counter);
+        }
+    }
+    highest.checked_add(1).ok_or(SectorError::CounterExhausted)
+}
 
 /// Encrypts and securely stores a slice of data into flash.
 ///
 /// # Process
 /// 1. Fetches encryption key from [`key_mgmt`] (hardware key if available).
-/// 2. Derives per-sector IV from wear-leveling index.
-/// 3. Encrypts data via AES helper in [`crate::crypto::aes`].
-/// 4. Writes ciphertext to flash sector using wear-leveling.
+/// 2. Picks the next sector and a write counter above every stored one.
+/// 3. Seals data with AES-256-GCM via [`seal_sector`].
+/// 4. Writes the record to flash sector using wear-leveling.
 ///
 /// # Errors
-/// Returns error if sector write fails or key retrieval fails.
+/// A `SectorError` if the key is unavailable, the data does not fit or
+/// the sector cannot be written.
 ///
 /// # Example
 /// ```ignore
 /// let data = b"SecureIoTOS config block";
 /// encrypt_and_store(data).expect("Flash write failed");
 /// ```
-pub fn encrypt_and_store(data: &[u8]) -> Result<()> {
+pub fn encrypt_and_store(data: &[u8]) -> Result<(), SectorError> {
     // Fetch encryption key
-    let key = key_mgmt::get_encryption_key()
-        .context("Failed to obtain encryption key")?;
+    let key = storage_key()?;
 
-    // Derive sector index + IV
+    // Next sector, and a fresh nonce for it
     let sector_idx = wear_level::get_next_sector_index();
-    let iv = wear_level::derive_iv_for_sector(sector_idx);
+    let counter = next_write_counter()?;
+    let mut salt = [0u8; 4];
+    crypto::rng::fill_random(&mut salt).map_err(SectorError::Rng)?;
 
-    // Encrypt data
-    let ciphertext = crate::crypto::aes::encrypt_aes(data, &key, &iv)
-        .context("AES encryption failed")?;
+    // Encrypt and authenticate data
+    let record = seal_sector(key.expose_secret(), sector_idx, counter, u32::from_be_bytes(salt), data)?;
 
     // Write to flash (atomic swap via wear leveling)
-    wear_level::write_sector(sector_idx, &ciphertext)
-        .with_context(|| format!("Failed to write sector {}", sector_idx))?;
+    wear_level::write_sector(sector_idx, &record).map_err(SectorError::Flash)?;
 
     Ok(())
 }
 
-/// Reads the most recent sector, decrypts, and returns the plaintext.
+/// Reads the most recent sector, verifies and decrypts it, and returns
+/// the plaintext.
 ///
 /// # Process
 /// 1. Fetches active sector index from wear-leveling.
-/// 2. Retrieves encryption key and IV.
-/// 3. Reads ciphertext from flash and decrypts.
+/// 2. Retrieves encryption key.
+/// 3. Reads the record from flash and opens it with [`open_sector`].
 ///
 /// # Errors
-/// Returns error if read/decrypt fails.
+/// Fails closed: a sector that does not authenticate is an error
+/// (`SectorError::AuthFailed`), never garbage plaintext. With the
+/// `legacy-sectors` feature an old AES-CBC sector is decrypted once and
+/// stored again as an authenticated record; without it, it is refused
+/// with `SectorError::Legacy`.
 ///
 /// # Example
 /// ```ignore
 /// let plaintext = read_and_decrypt().expect("Failed to read sector");
 /// println!("Recovered data: {:?}", plaintext);
 /// ```
-pub fn read_and_decrypt() -> Result<Vec<u8>> {
+pub fn read_and_decrypt() -> Result<Vec<u8>, SectorError> {
     // Fetch encryption key
-    let key = key_mgmt::get_encryption_key()
-        .context("Failed to obtain encryption key")?;
+    let key = storage_key()?;
 
-    // Derive sector index + IV
+    // Read the record
     let sector_idx = wear_level::get_active_sector_index();
+    let raw = wear_level::read_sector(sector_idx).map_err(SectorError::Flash)?;
+
+    match open_sector(key.expose_secret(), sector_idx, &raw) {
+        #[cfg(feature = "legacy-sectors")]
+        Err(SectorError::Legacy) => {
+            migrate_legacy_sector(key.expose_secret(), sector_idx, &raw)
+        }
+        result => result.map(|record| record.data),
+    }
+}
+
+/// Decrypt a sector written before authenticated records (AES-128-CBC
+/// with the sector-index IV) and store it again in the new format, so it
+/// is only ever trusted once.
+#[cfg(feature = "legacy-sectors")]
+fn migrate_legacy_sector(key: &[u8; 16], sector_idx: usize, raw: &[u8]) -> Result<Vec<u8>, SectorError> {
     let iv = wear_level::derive_iv_for_sector(sector_idx);
+    // The old format stored no length; the sector holds the ciphertext
+    // followed by whatever was there before. PKCS7 finds the end.
+    let plaintext = (1..=raw.len() / 16)
+        .rev()
+        .find_map(|blocks| aes::decrypt_aes(&raw[..blocks * 16], key, &iv).ok())
+        .ok_or(SectorError::Corrupt)?;
+    warn!("migrating legacy sector {} to authenticated format", sector_idx);
+    encrypt_and_store(&plaintext)?;
+    Ok(plaintext)
+}
 
-    // Read ciphertext
-    let ciphertext = wear_level::read_sector(sector_idx)
-        .with_context(|| format!("Failed to read sector {}", sector_idx))?;
+/// Sequential writer over a flash region, for images that arrive in
+/// pieces of any size (an OTA download).
+///
+/// Data is buffered up to the flash's `WRITE_SIZE` and each page is erased
+/// when the write reaches its start, so the region never needs erasing up
+/// front. If `base` is not page aligned, the page holding it must already
+/// be erased.
+pub struct RegionWriter<'a, F: BspFlash> {
+    flash: &'a mut F,
+    base: u32,
+    len: usize,
+    /// Bytes accepted by `push()`
+    accepted: usize,
+    /// Bytes programmed so far (a multiple of `WRITE_SIZE`)
+    programmed: usize,
+    pending: Vec<u8>,
+}
 
-    // Decrypt
-    let plaintext = crate::crypto::aes::decrypt_aes(&ciphertext, &key, &iv)
-        .context("AES decryption failed")?;
+impl<'a, F: BspFlash> RegionWriter<'a, F> {
+    /// Writer for the `len` bytes at `base` (`WRITE_SIZE` aligned).
+    pub fn new(flash: &'a mut F, base: u32, len: usize) -> Result<Self, BspError> {
+        if !(base as usize).is_multiple_of(F::WRITE_SIZE) || base.checked_add(len as u32).is_none() {
+            return Err(BspError::OutOfRange);
+        }
+        Ok(Self { flash, base, len, accepted: 0, programmed: 0, pending: Vec::with_capacity(F::PAGE_SIZE) })
+    }
 
-    Ok(plaintext)
+    /// Bytes accepted so far.
+    pub fn written(&self) -> usize {
+        self.accepted
+    }
+
+    /// Append `data`; fails with `OutOfRange` past the end of the region.
+    pub fn push(&mut self, data: &[u8]) -> Result<(), BspError> {
+        if data.len() > self.len - self.accepted {
+            return Err(BspError::OutOfRange);
+        }
+        self.accepted += data.len();
+        self.pending.extend_from_slice(data);
+        self.program(false)
+    }
+
+    /// Program what is still buffered (padded with 0xFF to `WRITE_SIZE`)
+    /// and hand the flash back.
+    pub fn finish(mut self) -> Result<&'a mut F, BspError> {
+        self.program(true)?;
+        Ok(self.flash)
+    }
+
+    /// Program whole write units from `pending`, never across a page
+    /// boundary, erasing each page as it is entered.
+    fn program(&mut self, flush: bool) -> Result<(), BspError> {
+        if flush && !self.pending.is_empty() {
+            let padded = self.pending.len().next_multiple_of(F::WRITE_SIZE);
+            self.pending.resize(padded, 0xFF);
+        }
+        while self.pending.len() >= F::WRITE_SIZE {
+            let addr = self.base + self.programmed as u32;
+            let page_left = F::PAGE_SIZE - addr as usize % F::PAGE_SIZE;
+            if page_left == F::PAGE_SIZE {
+                self.flash.erase_page(addr)?;
+            }
+            let n = page_left.min(self.pending.len() / F::WRITE_SIZE * F::WRITE_SIZE);
+            self.flash.write(addr, &self.pending[..n])?;
+            self.pending.drain(..n);
+            self.programmed += n;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: [u8; 16] = [0x42; 16];
+
+    fn error_of(result: Result<SectorRecord, SectorError>) -> SectorError {
+        result.unwrap_err()
+    }
+
+    #[test]
+    fn sealed_sector_opens_with_trailing_flash() {
+        let mut raw = seal_sector(&KEY, 2, 7, 0xDEAD_BEEF, b"config block").unwrap();
+        raw.resize(4096, 0xFF);
+
+        let record = open_sector(&KEY, 2, &raw).unwrap();
+        assert_eq!(record.counter, 7);
+        assert_eq!(record.data, b"config block");
+    }
+
+    #[test]
+    fn tampered_moved_or_foreign_sectors_fail_closed() {
+        let raw = seal_sector(&KEY, 1, 3, 1, b"config block").unwrap();
+
+        for at in [8, 20, SECTOR_HEADER_LEN, raw.len() - 1] {
+            let mut tampered = raw.clone();
+            tampered[at] ^= 0x01;
+            assert_eq!(error_of(open_sector(&KEY, 1, &tampered)), SectorError::AuthFailed, "byte {}", at);
+        }
+        assert_eq!(error_of(open_sector(&KEY, 0, &raw)), SectorError::AuthFailed);
+        assert_eq!(error_of(open_sector(&[0x43; 16], 1, &raw)), SectorError::AuthFailed);
+
+        let mut truncated = raw.clone();
+        truncated[16..20].copy_from_slice(&1000u32.to_be_bytes());
+        assert_eq!(error_of(open_sector(&KEY, 1, &truncated)), SectorError::Corrupt);
+    }
+
+    #[test]
+    fn erased_and_legacy_sectors_are_told_apart() {
+        assert_eq!(error_of(open_sector(&KEY, 0, &[0xFF; 64])), SectorError::Erased);
+        assert_eq!(error_of(open_sector(&KEY, 0, &[0x5A; 64])), SectorError::Legacy);
+    }
+
+    #[test]
+    fn nonce_changes_with_counter_and_salt() {
+        let a = seal_sector(&KEY, 0, 1, 9, b"same data").unwrap();
+        let b = seal_sector(&KEY, 0, 2, 9, b"same data").unwrap();
+        let c = seal_sector(&KEY, 0, 1, 10, b"same data").unwrap();
+        assert_ne!(a[SECTOR_HEADER_LEN..], b[SECTOR_HEADER_LEN..]);
+        assert_ne!(a[SECTOR_HEADER_LEN..], c[SECTOR_HEADER_LEN..]);
+    }
 }
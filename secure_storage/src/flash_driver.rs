@@ -0,0 +1,224 @@
+//! SecureIoTOS Flash Driver Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+
+//! Raw flash access for the storage layers (`wear_level`).
+//!
+//! A `FlashDriver` covers one region of uniform erase sectors. Offsets are
+//! relative to the start of that region; erased flash reads 0xFF, and
+//! programming can only clear bits, so a range must be erased before it
+//! is written again. Writes must start and end on the device's program
+//! unit.
+//!
+//! | Backend                 | Device                               | Sector | Program unit |
+//! |-------------------------|--------------------------------------|--------|--------------|
+//! | `stm32::Stm32l4Flash`   | STM32L4 internal flash               | 2 KB   | 8 bytes      |
+//! | `w25q::W25q`            | Winbond W25Q serial NOR (SPI)        | 4 KB   | 1 byte       |
+//! | `RamFlash`              | RAM simulation, for hosts and tests  | any    | configurable |
+
+pub mod stm32;
+pub mod w25q;
+
+/// Largest program unit `program_padded()` supports.
+pub const MAX_PROGRAM_UNIT: usize = 32;
+
+/// Why a flash operation failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum FlashError {
+    /// Offset, length or sector index outside the region
+    OutOfRange,
+    /// Write not aligned to the program unit
+    Misaligned,
+    /// The device stayed busy past the operation's worst-case time
+    Timeout,
+    /// The range is write-protected
+    Protected,
+    /// The device is not the one the driver expects
+    Unsupported,
+    /// The device flagged an error; raw status register value
+    Device(u32),
+}
+
+/// Shape of a flash region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Geometry {
+    /// Bytes per erase sector (or page)
+    pub sector_size: usize,
+    /// Sectors in the region
+    pub sector_count: usize,
+    /// Writes must start and end on multiples of this many bytes
+    pub program_unit: usize,
+}
+
+impl Geometry {
+    /// Size of the region in bytes.
+    pub fn capacity(&self) -> usize {
+        self.sector_size * self.sector_count
+    }
+
+    /// Offset of the first byte of sector `index`.
+    pub fn sector_offset(&self, index: usize) -> Result<u32, FlashError> {
+        if index >= self.sector_count {
+            return Err(FlashError::OutOfRange);
+        }
+        Ok((index * self.sector_size) as u32)
+    }
+
+    /// Check that `len` bytes at `offset` lie inside the region.
+    pub fn check_range(&self, offset: u32, len: usize) -> Result<(), FlashError> {
+        match (offset as usize).checked_add(len) {
+            Some(end) if end <= self.capacity() => Ok(()),
+            _ => Err(FlashError::OutOfRange),
+        }
+    }
+
+    /// As `check_range()`, and that the range can be programmed as is.
+    pub fn check_program(&self, offset: u32, len: usize) -> Result<(), FlashError> {
+        self.check_range(offset, len)?;
+        if !(offset as usize).is_multiple_of(self.program_unit) || !len.is_multiple_of(self.program_unit) {
+            return Err(FlashError::Misaligned);
+        }
+        Ok(())
+    }
+}
+
+/// Erase/program/read access to one flash region.
+pub trait FlashDriver {
+    /// Sector layout and write constraints of the region.
+    fn geometry(&self) -> Geometry;
+
+    /// Read `buf.len()` bytes at `offset`.
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), FlashError>;
+
+    /// Erase sector `index` to 0xFF. Blocks until the device is done.
+    fn erase_sector(&mut self, index: usize) -> Result<(), FlashError>;
+
+    /// Program `data` at `offset`, which must be erased. Both must be
+    /// aligned to the program unit (`Geometry::check_program()`).
+    fn program(&mut self, offset: u32, data: &[u8]) -> Result<(), FlashError>;
+
+    /// As `program()`, but `data` may end mid-unit: the last unit is
+    /// padded with 0xFF, which leaves the flash behind it erased.
+    fn program_padded(&mut self, offset: u32, data: &[u8]) -> Result<(), FlashError> {
+        let unit = self.geometry().program_unit;
+        if unit > MAX_PROGRAM_UNIT {
+            return Err(FlashError::Unsupported);
+        }
+        let full = data.len() - data.len() % unit;
+        if full > 0 {
+            self.program(offset, &data[..full])?;
+        }
+        let tail = &data[full..];
+        if !tail.is_empty() {
+            let mut last = [0xFFu8; MAX_PROGRAM_UNIT];
+            last[..tail.len()].copy_from_slice(tail);
+            self.program(offset + full as u32, &last[..unit])?;
+        }
+        Ok(())
+    }
+}
+
+/// Flash simulated in RAM: `N` sectors of `SECTOR` bytes, with NOR
+/// semantics (programming ANDs into what is there). Used where there is
+/// no real flash behind the storage, and by the tests.
+pub struct RamFlash<const SECTOR: usize, const N: usize> {
+    data: [[u8; SECTOR]; N],
+    program_unit: usize,
+}
+
+impl<const SECTOR: usize, const N: usize> RamFlash<SECTOR, N> {
+    /// Erased flash, programmable a byte at a time.
+    pub const fn new() -> Self {
+        Self::with_program_unit(1)
+    }
+
+    /// Erased flash that must be programmed in units of `program_unit`
+    /// bytes, like the device it stands in for.
+    pub const fn with_program_unit(program_unit: usize) -> Self {
+        Self { data: [[0xFF; SECTOR]; N], program_unit }
+    }
+}
+
+impl<const SECTOR: usize, const N: usize> Default for RamFlash<SECTOR, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const SECTOR: usize, const N: usize> FlashDriver for RamFlash<SECTOR, N> {
+    fn geometry(&self) -> Geometry {
+        Geometry { sector_size: SECTOR, sector_count: N, program_unit: self.program_unit }
+    }
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+        self.geometry().check_range(offset, buf.len())?;
+        for (i, b) in buf.iter_mut().enumerate() {
+            let at = offset as usize + i;
+            *b = self.data[at / SECTOR][at % SECTOR];
+        }
+        Ok(())
+    }
+
+    fn erase_sector(&mut self, index: usize) -> Result<(), FlashError> {
+        self.data.get_mut(index).ok_or(FlashError::OutOfRange)?.fill(0xFF);
+        Ok(())
+    }
+
+    fn program(&mut self, offset: u32, data: &[u8]) -> Result<(), FlashError> {
+        self.geometry().check_program(offset, data.len())?;
+        for (i, &b) in data.iter().enumerate() {
+            let at = offset as usize + i;
+            self.data[at / SECTOR][at % SECTOR] &= b;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn program_only_clears_bits_until_erased() {
+        let mut flash = RamFlash::<64, 2>::new();
+        flash.program(70, &[0x0F, 0xAA]).unwrap();
+        flash.program(70, &[0xF1]).unwrap();
+
+        let mut buf = [0u8; 3];
+        flash.read(69, &mut buf).unwrap();
+        assert_eq!(buf, [0xFF, 0x01, 0xAA]);
+
+        flash.erase_sector(1).unwrap();
+        flash.read(69, &mut buf).unwrap();
+        assert_eq!(buf, [0xFF; 3]);
+    }
+
+    #[test]
+    fn writes_are_checked_against_the_geometry() {
+        let mut flash = RamFlash::<64, 2>::with_program_unit(8);
+        assert_eq!(flash.program(4, &[0; 8]), Err(FlashError::Misaligned));
+        assert_eq!(flash.program(8, &[0; 4]), Err(FlashError::Misaligned));
+        assert_eq!(flash.program(120, &[0; 16]), Err(FlashError::OutOfRange));
+        assert_eq!(flash.read(u32::MAX, &mut [0; 2]), Err(FlashError::OutOfRange));
+        assert_eq!(flash.erase_sector(2), Err(FlashError::OutOfRange));
+    }
+
+    #[test]
+    fn padded_program_leaves_the_tail_erased() {
+        let mut flash = RamFlash::<64, 1>::with_program_unit(8);
+        flash.program_padded(8, &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]).unwrap();
+
+        let mut buf = [0u8; 18];
+        flash.read(8, &mut buf).unwrap();
+        assert_eq!(buf[..10], [1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        assert!(buf[10..].iter().all(|&b| b == 0xFF));
+        // The padding did not disturb the next unit
+        flash.program(24, &[0; 8]).unwrap();
+    }
+}
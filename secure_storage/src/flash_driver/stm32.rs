@@ -0,0 +1,176 @@
+//! SecureIoTOS STM32 Internal Flash Driver Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+
+//! STM32L4 internal flash: 2 KB pages, programmed a 64-bit double-word
+//! at a time (RM0351 §3.3). Pages are numbered per bank, 256 to a bank,
+//! so the same arithmetic serves single-bank parts and the 1 MB
+//! dual-bank ones.
+//!
+//! The controller is unlocked for each erase or program and locked again
+//! afterwards, so a stray write elsewhere cannot reach the flash. The CPU
+//! stalls on fetches from a bank while it is busy; erasing the bank the
+//! code runs from is fine, just slow.
+
+use hal::trace::{modify32, read32, write32};
+
+use super::{FlashDriver, FlashError, Geometry};
+
+/// Start of the flash in the memory map.
+const FLASH_BASE: u32 = 0x0800_0000;
+const PAGE_SIZE: usize = 2048;
+const PAGES_PER_BANK: usize = 256;
+
+/// Flash controller registers.
+const FLASH_REGS: usize = 0x4002_2000;
+const KEYR: usize = FLASH_REGS + 0x08;
+const SR: usize = FLASH_REGS + 0x10;
+const CR: usize = FLASH_REGS + 0x14;
+
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+
+const SR_BSY: u32 = 1 << 16;
+/// OPERR, PROGERR, WRPERR, PGAERR, SIZERR, PGSERR, MISERR, FASTERR
+const SR_ERRORS: u32 = 0x3FA;
+const SR_WRPERR: u32 = 1 << 4;
+
+const CR_PG: u32 = 1 << 0;
+const CR_PER: u32 = 1 << 1;
+const CR_PNB_SHIFT: u32 = 3;
+const CR_PNB_MASK: u32 = 0xFF << CR_PNB_SHIFT;
+const CR_BKER: u32 = 1 << 11;
+const CR_STRT: u32 = 1 << 16;
+const CR_LOCK: u32 = 1 << 31;
+
+/// Busy polls before an operation is given up: well past the 25 ms
+/// worst-case page erase at 80 MHz.
+const BUSY_POLLS: u32 = 4_000_000;
+
+/// A page-aligned region of the STM32L4's internal flash.
+pub struct Stm32l4Flash {
+    /// Address of the region's first page
+    start: u32,
+    pages: usize,
+}
+
+impl Stm32l4Flash {
+    /// The `pages` pages starting at address `start`.
+    ///
+    /// # Safety
+    /// The region must be real flash on this part that nothing else (the
+    /// running code, the vector table, another driver) relies on, and
+    /// only one `Stm32l4Flash` may drive the controller at a time.
+    pub unsafe fn new(start: u32, pages: usize) -> Result<Self, FlashError> {
+        if start < FLASH_BASE || !((start - FLASH_BASE) as usize).is_multiple_of(PAGE_SIZE) {
+            return Err(FlashError::Misaligned);
+        }
+        Ok(Self { start, pages })
+    }
+
+    /// Bank-relative page number and bank of sector `index`.
+    fn page(&self, index: usize) -> (u32, bool) {
+        let page = (self.start - FLASH_BASE) as usize / PAGE_SIZE + index;
+        ((page % PAGES_PER_BANK) as u32, page >= PAGES_PER_BANK)
+    }
+
+    /// Unlock the controller, clear stale error flags and run `op`, then
+    /// lock it again.
+    fn unlocked(&mut self, op: impl FnOnce() -> Result<(), FlashError>) -> Result<(), FlashError> {
+        // SAFETY: FLASH_REGS is the STM32L4 flash controller; the unlock
+        // sequence and flag clearing follow RM0351 §3.3.5.
+        unsafe {
+            write32(SR, SR_ERRORS);
+            wait_idle()?;
+            if read32(CR) & CR_LOCK != 0 {
+                write32(KEYR, KEY1);
+                write32(KEYR, KEY2);
+            }
+        }
+        let result = op();
+        // SAFETY: as above; LOCK stays set until the next unlock sequence.
+        unsafe { modify32(CR, |cr| (cr & !(CR_PG | CR_PER | CR_BKER | CR_PNB_MASK)) | CR_LOCK) };
+        result
+    }
+}
+
+impl FlashDriver for Stm32l4Flash {
+    fn geometry(&self) -> Geometry {
+        Geometry { sector_size: PAGE_SIZE, sector_count: self.pages, program_unit: 8 }
+    }
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+        self.geometry().check_range(offset, buf.len())?;
+        // SAFETY: the range lies inside the region `new()` was given,
+        // which is memory-mapped flash.
+        let src = unsafe { core::slice::from_raw_parts((self.start + offset) as *const u8, buf.len()) };
+        buf.copy_from_slice(src);
+        Ok(())
+    }
+
+    fn erase_sector(&mut self, index: usize) -> Result<(), FlashError> {
+        if index >= self.pages {
+            return Err(FlashError::OutOfRange);
+        }
+        let (page, bank2) = self.page(index);
+        self.unlocked(|| {
+            // SAFETY: controller unlocked and idle; a single page erase.
+            unsafe {
+                modify32(CR, |cr| {
+                    let cr = (cr & !(CR_PNB_MASK | CR_BKER)) | CR_PER | (page << CR_PNB_SHIFT);
+                    if bank2 {
+                        cr | CR_BKER
+                    } else {
+                        cr
+                    }
+                });
+                modify32(CR, |cr| cr | CR_STRT);
+                wait_idle()
+            }
+        })
+    }
+
+    fn program(&mut self, offset: u32, data: &[u8]) -> Result<(), FlashError> {
+        self.geometry().check_program(offset, data.len())?;
+        let start = self.start + offset;
+        self.unlocked(|| {
+            // SAFETY: controller unlocked and idle; each double-word is
+            // written as two aligned words inside the region, the first
+            // then the second, as RM0351 §3.3.7 requires.
+            unsafe {
+                modify32(CR, |cr| cr | CR_PG);
+                for (i, dword) in data.chunks_exact(8).enumerate() {
+                    let addr = start as usize + i * 8;
+                    let (lo, hi) = dword.split_at(4);
+                    core::ptr::write_volatile(addr as *mut u32, u32::from_le_bytes(lo.try_into().unwrap()));
+                    core::ptr::write_volatile((addr + 4) as *mut u32, u32::from_le_bytes(hi.try_into().unwrap()));
+                    wait_idle()?;
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Wait for the controller to finish, and report any error it flagged.
+///
+/// # Safety
+/// Touches the flash controller registers.
+unsafe fn wait_idle() -> Result<(), FlashError> {
+    for _ in 0..BUSY_POLLS {
+        let sr = read32(SR);
+        if sr & SR_BSY != 0 {
+            continue;
+        }
+        return match sr & SR_ERRORS {
+            0 => Ok(()),
+            e if e & SR_WRPERR != 0 => Err(FlashError::Protected),
+            e => Err(FlashError::Device(e)),
+        };
+    }
+    Err(FlashError::Timeout)
+}
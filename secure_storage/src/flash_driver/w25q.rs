@@ -0,0 +1,361 @@
+//! SecureIoTOS W25Q Serial NOR Flash Driver Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+
+//! Winbond W25Q serial NOR flash (W25Q16 .. W25Q128) in single-line SPI
+//! mode, which every QSPI controller can also run. 4 KB sectors, 256-byte
+//! program pages, 3-byte addresses. The size comes from the JEDEC ID, so
+//! one driver serves the whole family.
+//!
+//! The chip must have its write enable latch set before each erase or
+//! program; if it refuses (status register protection, `/WP` held low)
+//! the operation fails with `Protected` rather than silently doing
+//! nothing.
+
+use hal::bus::Spi;
+use hal::gpio::GpioExt;
+use hal::timer::Delay;
+
+use super::{FlashDriver, FlashError, Geometry};
+
+const CMD_READ: u8 = 0x03;
+const CMD_PAGE_PROGRAM: u8 = 0x02;
+const CMD_SECTOR_ERASE: u8 = 0x20;
+const CMD_WRITE_ENABLE: u8 = 0x06;
+const CMD_READ_STATUS1: u8 = 0x05;
+const CMD_JEDEC_ID: u8 = 0x9F;
+const CMD_RELEASE_POWER_DOWN: u8 = 0xAB;
+
+const STATUS_BUSY: u8 = 1 << 0;
+const STATUS_WEL: u8 = 1 << 1;
+
+/// Winbond's JEDEC manufacturer ID.
+const MANUFACTURER_WINBOND: u8 = 0xEF;
+const SECTOR_SIZE: usize = 4096;
+const PAGE_SIZE: usize = 256;
+
+/// Worst-case times from the datasheet, and the status poll interval.
+const ERASE_TIMEOUT_US: u32 = 400_000;
+const PROGRAM_TIMEOUT_US: u32 = 3_000;
+const POLL_US: u32 = 50;
+
+/// A W25Q chip on an SPI bus, selected by `cs` (active low).
+pub struct W25q<S: Spi, CS: GpioExt, D: Delay> {
+    spi: S,
+    cs: CS,
+    delay: D,
+    sectors: usize,
+}
+
+impl<S: Spi, CS: GpioExt, D: Delay> W25q<S, CS, D> {
+    /// Wake the chip and size it from its JEDEC ID.
+    ///
+    /// # Returns
+    /// * `Ok(driver)` for the whole chip
+    /// * `Err(FlashError::Unsupported)` if it is not a W25Q that 3-byte
+    ///   addressing can cover
+    pub fn probe(spi: S, mut cs: CS, delay: D) -> Result<Self, FlashError> {
+        cs.set_high();
+        let mut flash = Self { spi, cs, delay, sectors: 0 };
+        flash.command(&[CMD_RELEASE_POWER_DOWN]);
+        // tRES1: 3 us before the chip accepts further commands
+        flash.delay.delay_us(3);
+
+        let [manufacturer, _, capacity] = flash.jedec_id();
+        if manufacturer != MANUFACTURER_WINBOND || !(16..=24).contains(&capacity) {
+            return Err(FlashError::Unsupported);
+        }
+        flash.sectors = (1usize << capacity) / SECTOR_SIZE;
+        Ok(flash)
+    }
+
+    /// Manufacturer, memory type and capacity (log2 of the size in bytes).
+    pub fn jedec_id(&mut self) -> [u8; 3] {
+        let mut id = [0u8; 3];
+        self.select();
+        self.spi.write(&[CMD_JEDEC_ID]);
+        self.spi.transfer(&mut id);
+        self.deselect();
+        id
+    }
+
+    /// Give back the bus and chip select.
+    pub fn release(self) -> (S, CS, D) {
+        (self.spi, self.cs, self.delay)
+    }
+
+    fn select(&mut self) {
+        self.cs.set_low();
+    }
+
+    fn deselect(&mut self) {
+        self.cs.set_high();
+    }
+
+    /// Send a command with no response.
+    fn command(&mut self, bytes: &[u8]) {
+        self.select();
+        self.spi.write(bytes);
+        self.deselect();
+    }
+
+    /// Send a command that takes an address, then `data`.
+    fn command_at(&mut self, cmd: u8, addr: u32, data: &[u8]) {
+        let [_, a2, a1, a0] = addr.to_be_bytes();
+        self.select();
+        self.spi.write(&[cmd, a2, a1, a0]);
+        if !data.is_empty() {
+            self.spi.write(data);
+        }
+        self.deselect();
+    }
+
+    fn status(&mut self) -> u8 {
+        let mut status = [0u8];
+        self.select();
+        self.spi.write(&[CMD_READ_STATUS1]);
+        self.spi.transfer(&mut status);
+        self.deselect();
+        status[0]
+    }
+
+    /// Set the write enable latch, and check that it took.
+    fn write_enable(&mut self) -> Result<(), FlashError> {
+        self.command(&[CMD_WRITE_ENABLE]);
+        if self.status() & STATUS_WEL == 0 {
+            return Err(FlashError::Protected);
+        }
+        Ok(())
+    }
+
+    fn wait_ready(&mut self, timeout_us: u32) -> Result<(), FlashError> {
+        for _ in 0..=timeout_us / POLL_US {
+            if self.status() & STATUS_BUSY == 0 {
+                return Ok(());
+            }
+            self.delay.delay_us(POLL_US);
+        }
+        Err(FlashError::Timeout)
+    }
+}
+
+impl<S: Spi, CS: GpioExt, D: Delay> FlashDriver for W25q<S, CS, D> {
+    fn geometry(&self) -> Geometry {
+        Geometry { sector_size: SECTOR_SIZE, sector_count: self.sectors, program_unit: 1 }
+    }
+
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+        self.geometry().check_range(offset, buf.len())?;
+        let [_, a2, a1, a0] = offset.to_be_bytes();
+        buf.fill(0);
+        self.select();
+        self.spi.write(&[CMD_READ, a2, a1, a0]);
+        self.spi.transfer(buf);
+        self.deselect();
+        Ok(())
+    }
+
+    fn erase_sector(&mut self, index: usize) -> Result<(), FlashError> {
+        let offset = self.geometry().sector_offset(index)?;
+        self.wait_ready(ERASE_TIMEOUT_US)?;
+        self.write_enable()?;
+        self.command_at(CMD_SECTOR_ERASE, offset, &[]);
+        self.wait_ready(ERASE_TIMEOUT_US)
+    }
+
+    fn program(&mut self, offset: u32, data: &[u8]) -> Result<(), FlashError> {
+        self.geometry().check_program(offset, data.len())?;
+        self.wait_ready(ERASE_TIMEOUT_US)?;
+        // A page program wraps around inside its 256-byte page, so split
+        // the data at page boundaries.
+        let mut at = offset;
+        let mut rest = data;
+        while !rest.is_empty() {
+            let take = rest.len().min(PAGE_SIZE - at as usize % PAGE_SIZE);
+            self.write_enable()?;
+            self.command_at(CMD_PAGE_PROGRAM, at, &rest[..take]);
+            self.wait_ready(PROGRAM_TIMEOUT_US)?;
+            at += take as u32;
+            rest = &rest[take..];
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// A W25Q16-sized chip behind the mock bus: decodes commands byte by
+    /// byte like the real part, wrapping page programs inside their page.
+    struct Chip {
+        mem: Vec<u8>,
+        selected: bool,
+        frame: Vec<u8>,
+        wel: bool,
+        /// Status polls left before an operation completes
+        busy: u32,
+        /// Ignore write enable, as with `/WP` asserted
+        protected: bool,
+        /// Never finish an operation
+        stuck: bool,
+    }
+
+    impl Chip {
+        fn new() -> Rc<RefCell<Chip>> {
+            Rc::new(RefCell::new(Chip {
+                mem: vec![0xFF; 2 << 20],
+                selected: false,
+                frame: Vec::new(),
+                wel: false,
+                busy: 0,
+                protected: false,
+                stuck: false,
+            }))
+        }
+
+        /// Address bytes of a command frame.
+        fn addr(frame: &[u8]) -> usize {
+            usize::from(frame[1]) << 16 | usize::from(frame[2]) << 8 | usize::from(frame[3])
+        }
+
+        /// Clock one byte in; returns the byte clocked out.
+        fn clock(&mut self, mosi: u8) -> u8 {
+            assert!(self.selected, "bus traffic without chip select");
+            self.frame.push(mosi);
+            let n = self.frame.len();
+            match self.frame[0] {
+                CMD_JEDEC_ID if n > 1 => [0xEF, 0x40, 0x15][n - 2],
+                CMD_READ_STATUS1 if n > 1 => {
+                    if self.busy > 0 && !self.stuck {
+                        self.busy -= 1;
+                    }
+                    (u8::from(self.busy > 0) * STATUS_BUSY) | (u8::from(self.wel) * STATUS_WEL)
+                }
+                CMD_READ if n > 4 => self.mem[Self::addr(&self.frame) + n - 5],
+                CMD_PAGE_PROGRAM if n > 4 && self.wel && self.busy == 0 => {
+                    let addr = Self::addr(&self.frame);
+                    let at = (addr & !(PAGE_SIZE - 1)) + (addr + n - 5) % PAGE_SIZE;
+                    self.mem[at] &= mosi;
+                    0
+                }
+                _ => 0,
+            }
+        }
+
+        /// Chip select released: finish the command.
+        fn end(&mut self) {
+            let frame = core::mem::take(&mut self.frame);
+            match frame.first() {
+                Some(&CMD_WRITE_ENABLE) if !self.protected => self.wel = true,
+                Some(&CMD_PAGE_PROGRAM) if self.wel => {
+                    self.wel = false;
+                    self.busy = 2;
+                }
+                Some(&CMD_SECTOR_ERASE) if self.wel => {
+                    let start = Self::addr(&frame) & !(SECTOR_SIZE - 1);
+                    self.mem[start..start + SECTOR_SIZE].fill(0xFF);
+                    self.wel = false;
+                    self.busy = 5;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    struct MockSpi(Rc<RefCell<Chip>>);
+
+    impl Spi for MockSpi {
+        fn write(&mut self, data: &[u8]) {
+            let mut chip = self.0.borrow_mut();
+            for &b in data {
+                chip.clock(b);
+            }
+        }
+
+        fn transfer(&mut self, data: &mut [u8]) {
+            let mut chip = self.0.borrow_mut();
+            for b in data.iter_mut() {
+                *b = chip.clock(*b);
+            }
+        }
+    }
+
+    struct MockCs(Rc<RefCell<Chip>>);
+
+    impl GpioExt for MockCs {
+        fn set_high(&mut self) {
+            let mut chip = self.0.borrow_mut();
+            if chip.selected {
+                chip.end();
+            }
+            chip.selected = false;
+        }
+
+        fn set_low(&mut self) {
+            self.0.borrow_mut().selected = true;
+        }
+
+        fn toggle(&mut self) {
+            unimplemented!()
+        }
+    }
+
+    struct NoDelay;
+
+    impl Delay for NoDelay {
+        fn delay_us(&mut self, _us: u32) {}
+    }
+
+    fn flash(chip: &Rc<RefCell<Chip>>) -> W25q<MockSpi, MockCs, NoDelay> {
+        W25q::probe(MockSpi(chip.clone()), MockCs(chip.clone()), NoDelay).unwrap()
+    }
+
+    #[test]
+    fn probe_sizes_the_chip_from_its_jedec_id() {
+        let chip = Chip::new();
+        let geometry = flash(&chip).geometry();
+        assert_eq!(geometry.capacity(), 2 << 20);
+        assert_eq!(geometry.sector_count, 512);
+    }
+
+    #[test]
+    fn program_is_split_at_page_boundaries() {
+        let chip = Chip::new();
+        let mut flash = flash(&chip);
+        let data: Vec<u8> = (0..600u32).map(|i| i as u8).collect();
+        flash.program(4096 + 200, &data).unwrap();
+
+        let mut back = vec![0u8; 600];
+        flash.read(4096 + 200, &mut back).unwrap();
+        assert_eq!(back, data);
+        // Nothing wrapped back to the start of the first page
+        assert!(chip.borrow().mem[4096..4096 + 200].iter().all(|&b| b == 0xFF));
+
+        flash.erase_sector(1).unwrap();
+        flash.read(4096 + 200, &mut back).unwrap();
+        assert!(back.iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn refused_write_enable_and_a_stuck_chip_are_reported() {
+        let chip = Chip::new();
+        let mut flash = flash(&chip);
+        chip.borrow_mut().protected = true;
+        assert_eq!(flash.program(0, &[0]), Err(FlashError::Protected));
+        assert_eq!(chip.borrow().mem[0], 0xFF);
+
+        let mut chip_ref = chip.borrow_mut();
+        chip_ref.protected = false;
+        chip_ref.stuck = true;
+        chip_ref.busy = 1;
+        drop(chip_ref);
+        assert_eq!(flash.erase_sector(0), Err(FlashError::Timeout));
+    }
+}
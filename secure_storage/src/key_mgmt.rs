@@ -9,34 +9,65 @@
 //! This module manages encryption keys for flash and other secure data.
 //! Keys should be hardware-backed in production (secure element, OTP fuses).
 //! Here we use an in-RAM protected store (via interrupt mutex) for demo/testing.
+//! These are the device's own keys; a gateway keeps the keys of its
+//! downstream devices in separate namespaces (see `namespace`).
 
 use core::cell::RefCell;
 use cortex_m::interrupt::Mutex;
+use sios_log::Secret;
 use zeroize::Zeroize;
-use crate::crypto::rng;
+use crypto::rng;
 
 /// Key status for monitoring initialization and rotation
-#[derive(Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum KeyStatus {
     Uninitialized,
     Initialized,
+    /// Wiped by `destroy_encryption_key()`; nothing sealed under the old
+    /// key can be read any more
+    Destroyed,
 }
 
 /// Atomic, interrupt-protected storage for the encryption key
-static ENCRYPTION_KEY: Mutex<RefCell<[u8; 16]>> = Mutex::new(RefCell::new([0u8; 16]));
+static ENCRYPTION_KEY: Mutex<RefCell<Secret<[u8; 16]>>> = Mutex::new(RefCell::new(Secret::new([0u8; 16])));
 static KEY_STATUS: Mutex<RefCell<KeyStatus>> = Mutex::new(RefCell::new(KeyStatus::Uninitialized));
 
 /// Initialize key material (call during boot once)
 pub fn init_keys() {
-    let key = rng::generate_random_key();
+    let key = Secret::new(rng::generate_random_key());
     store_encryption_key(key);
     cortex_m::interrupt::free(|cs| {
-        *KEY_STATUS.borrow(cs).borrow_mut() = KeyStatus::Initialized;
+        let cell = KEY_STATUS.borrow(cs);
+        hal::watch::expected_write(cell.as_ptr() as usize, || *cell.borrow_mut() = KeyStatus::Initialized);
     });
 }
 
+/// Initialize the storage key from the device root key instead of the
+/// RNG (call during boot once, after `crypto::kdf::install_root_key`).
+/// The key is the same on every boot, so nothing has to be persisted;
+/// `epoch` selects a new key after a rotation.
+pub fn init_keys_from_root(epoch: u32) -> Result<(), crypto::kdf::KdfError> {
+    let derived = crypto::kdf::derive_key(crypto::kdf::Purpose::StorageEncryption, &epoch.to_be_bytes())?;
+    let mut key = [0u8; 16];
+    key.copy_from_slice(&derived[..16]);
+    store_encryption_key(Secret::new(key));
+    key.zeroize();
+    cortex_m::interrupt::free(|cs| {
+        let cell = KEY_STATUS.borrow(cs);
+        hal::watch::expected_write(cell.as_ptr() as usize, || *cell.borrow_mut() = KeyStatus::Initialized);
+    });
+    Ok(())
+}
+
+/// Report writes to the key status from anywhere but this module (see
+/// `hal::watch`). Returns `false` if no watchpoint could be armed.
+pub fn watch_key_status() -> bool {
+    cortex_m::interrupt::free(|cs| hal::watch::watch_var("key_status", KEY_STATUS.borrow(cs).as_ptr()).is_some())
+}
+
 /// Store the encryption key (atomic and zeroizes previous key)
-pub fn store_encryption_key(key: [u8; 16]) {
+pub fn store_encryption_key(key: Secret<[u8; 16]>) {
     cortex_m::interrupt::free(|cs| {
         let mut guard = ENCRYPTION_KEY.borrow(cs).borrow_mut();
         guard.zeroize();           // Clear old key securely
@@ -45,9 +76,12 @@ pub fn store_encryption_key(key: [u8; 16]) {
 }
 
 /// Retrieve a copy of the encryption key
-pub fn get_encryption_key() -> [u8; 16] {
+///
+/// The copy stays wrapped in `Secret` so it cannot end up in a log line;
+/// use `expose_secret()` at the point the raw bytes are needed.
+pub fn get_encryption_key() -> Secret<[u8; 16]> {
     cortex_m::interrupt::free(|cs| {
-        *ENCRYPTION_KEY.borrow(cs).borrow()
+        ENCRYPTION_KEY.borrow(cs).borrow().clone()
     })
 }
 
@@ -58,10 +92,21 @@ pub fn get_key_status() -> KeyStatus {
     })
 }
 
+/// Wipe the encryption key and mark it destroyed, so whatever was stored
+/// under it is unreadable (crypto-erase). Storage refuses to work until a
+/// new key is initialized.
+pub fn destroy_encryption_key() {
+    cortex_m::interrupt::free(|cs| {
+        ENCRYPTION_KEY.borrow(cs).borrow_mut().zeroize();
+        let cell = KEY_STATUS.borrow(cs);
+        hal::watch::expected_write(cell.as_ptr() as usize, || *cell.borrow_mut() = KeyStatus::Destroyed);
+    });
+}
+
 /// Rotate the key safely
 /// In production: re-encrypt stored flash sectors with new key (atomic or sector-by-sector)
 pub fn rotate_key() {
-    let new_key = rng::generate_random_key();
+    let new_key = Secret::new(rng::generate_random_key());
     
     // TODO: Re-encrypt existing flash/data sectors here
     // Example: re_encrypt_sector(old_key, new_key);
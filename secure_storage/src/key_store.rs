@@ -0,0 +1,695 @@
+//! SecureIoTOS Persistent Key Store Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+
+//! Named, policy-checked keys kept in flash, wrapped under a device KEK.
+//!
+//! Each key lives in a named slot together with its kind (AES-128,
+//! AES-256, P-256) and a `KeyPolicy`: which operations it may be used for
+//! (sign-only, decrypt-only, ...) and which tasks may use it. Key bytes are
+//! only ever stored wrapped with AES-256-GCM under the key encryption key;
+//! the slot's name, kind and policy are the GCM associated data, so a
+//! policy edited in flash makes the key fail to unwrap instead of
+//! loosening its use.
+//!
+//! The whole store is one image, `magic | version | generation | records |
+//! HMAC-SHA256`, replaced atomically through `KeyBlobStore::save`. Every
+//! change bumps the generation and is persisted before it is reported as
+//! done; a failed save rolls the change back, as in `enrollment`. The MAC
+//! is checked on `open`, so a torn or tampered image is an error rather
+//! than an empty store. It cannot tell an old image from the current one:
+//! anchor `generation()` in a monotonic counter (or the boot ledger) where
+//! rollback matters.
+//!
+//! Unwrapped keys exist only for the duration of one call and are wiped
+//! afterwards; only a key whose policy allows `Operation::Export` is ever
+//! handed out as bytes.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crypto::aes::{self, AesError, CryptoBackend, SoftwareAes};
+use crypto::ecc::KeyPair;
+use crypto::kdf::{self, KdfError, Purpose};
+use p256::ecdsa::{Signature, VerifyingKey};
+use sios_log::{info, warn};
+use zeroize::Zeroizing;
+
+/// Magic prefix of the persisted store ("KSTR").
+const MAGIC: [u8; 4] = *b"KSTR";
+/// Encoding version of the persisted store.
+const FORMAT_VERSION: u8 = 1;
+/// magic(4) | version(1) | generation(8) | count(2)
+const HEADER_LEN: usize = 15;
+const MAC_LEN: usize = kdf::KEY_LEN;
+
+/// Longest slot name in bytes.
+pub const MAX_NAME_LEN: usize = 32;
+
+/// Task index the kernel assigns (0..32); policies grant access per task.
+pub type Caller = u8;
+
+/// Key material held in a slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum KeyKind {
+    Aes128 = 0,
+    Aes256 = 1,
+    /// P-256 private scalar (big-endian)
+    P256 = 2,
+}
+
+impl KeyKind {
+    pub const fn key_len(&self) -> usize {
+        match self {
+            KeyKind::Aes128 => 16,
+            KeyKind::Aes256 | KeyKind::P256 => 32,
+        }
+    }
+
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(KeyKind::Aes128),
+            1 => Some(KeyKind::Aes256),
+            2 => Some(KeyKind::P256),
+            _ => None,
+        }
+    }
+
+    /// Whether a key of this kind can perform `op` at all.
+    fn supports(&self, op: Operation) -> bool {
+        match op {
+            Operation::Sign => *self == KeyKind::P256,
+            Operation::Encrypt | Operation::Decrypt => *self != KeyKind::P256,
+            Operation::Export => true,
+        }
+    }
+}
+
+/// What a caller wants to do with a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Operation {
+    Sign,
+    Encrypt,
+    Decrypt,
+    /// Read the raw key bytes
+    Export,
+}
+
+/// Set of operations a key may be used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Usage(u8);
+
+impl Usage {
+    pub const SIGN: Usage = Usage(1 << 0);
+    pub const ENCRYPT: Usage = Usage(1 << 1);
+    pub const DECRYPT: Usage = Usage(1 << 2);
+    pub const EXPORT: Usage = Usage(1 << 3);
+    const ALL: u8 = 0x0F;
+
+    pub const fn bits(&self) -> u8 {
+        self.0
+    }
+
+    pub fn allows(&self, op: Operation) -> bool {
+        let bit = match op {
+            Operation::Sign => Usage::SIGN,
+            Operation::Encrypt => Usage::ENCRYPT,
+            Operation::Decrypt => Usage::DECRYPT,
+            Operation::Export => Usage::EXPORT,
+        };
+        self.0 & bit.0 != 0
+    }
+}
+
+impl core::ops::BitOr for Usage {
+    type Output = Usage;
+
+    fn bitor(self, rhs: Usage) -> Usage {
+        Usage(self.0 | rhs.0)
+    }
+}
+
+/// Access control of one key: allowed operations and allowed tasks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct KeyPolicy {
+    pub usage: Usage,
+    /// Bit `n` set: task `n` may use the key
+    pub tasks: u32,
+}
+
+impl KeyPolicy {
+    /// `usage` for every task.
+    pub const fn new(usage: Usage) -> Self {
+        Self { usage, tasks: u32::MAX }
+    }
+
+    /// Restrict the key to the tasks in `mask`.
+    pub const fn for_tasks(self, mask: u32) -> Self {
+        Self { tasks: mask, ..self }
+    }
+
+    pub fn permits(&self, caller: Caller, op: Operation) -> bool {
+        caller < 32 && self.tasks & (1 << caller) != 0 && self.usage.allows(op)
+    }
+}
+
+/// Errors reported by the key store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum KeyStoreError {
+    /// No slot with this name
+    Unknown,
+    /// A slot with this name exists
+    Exists,
+    /// Store is at capacity
+    Full,
+    /// Empty or oversize name
+    InvalidName,
+    /// Key bytes have the wrong length or are not a valid key of the kind
+    InvalidKey,
+    /// The key's policy does not allow this caller or operation
+    Denied,
+    /// The key's kind cannot perform this operation
+    WrongKind,
+    /// Persisted store failed its MAC, has the wrong format or a key did
+    /// not unwrap
+    Corrupt,
+    /// No randomness for a key or wrapping nonce
+    Rng,
+    /// Encryption with the key failed (bad input, authentication failure)
+    Crypto(AesError),
+    /// No device root key to derive the KEK from
+    NoRootKey,
+    /// Backing storage failed
+    Storage(&'static str),
+}
+
+impl From<KdfError> for KeyStoreError {
+    fn from(_: KdfError) -> Self {
+        KeyStoreError::NoRootKey
+    }
+}
+
+/// Backing store for the key image, typically a dedicated flash sector.
+pub trait KeyBlobStore {
+    /// Load the last saved image; `Ok(None)` if nothing was ever saved.
+    fn load(&mut self) -> Result<Option<Vec<u8>>, &'static str>;
+    /// Atomically replace the saved image.
+    fn save(&mut self, data: &[u8]) -> Result<(), &'static str>;
+}
+
+/// Key encryption key: wraps the stored keys and authenticates the image.
+pub struct Kek {
+    wrap: Zeroizing<[u8; aes::KEY_LEN]>,
+    mac: Zeroizing<[u8; kdf::KEY_LEN]>,
+}
+
+impl Kek {
+    /// Wrapping and MAC keys derived from a 32-byte KEK (e.g. read from a
+    /// secure element or OTP).
+    pub fn new(kek: &[u8; 32]) -> Self {
+        let prk = Zeroizing::new(kdf::hkdf_extract(b"SecureIoTOS key store v1", kek));
+        let mut wrap = Zeroizing::new([0u8; aes::KEY_LEN]);
+        let mut mac = Zeroizing::new([0u8; kdf::KEY_LEN]);
+        // 32 bytes is always within the HKDF output limit.
+        let _ = kdf::hkdf_expand(&prk, b"wrap", &mut wrap[..]);
+        let _ = kdf::hkdf_expand(&prk, b"mac", &mut mac[..]);
+        Self { wrap, mac }
+    }
+
+    /// KEK derived from the installed device root key
+    /// (`crypto::kdf::install_root_key`).
+    pub fn from_root_key() -> Result<Self, KeyStoreError> {
+        let kek = kdf::derive_key(Purpose::KeyWrapping, b"")?;
+        Ok(Self::new(&kek))
+    }
+}
+
+/// One slot as stored: everything but the key is in the clear.
+#[derive(Clone)]
+struct Record {
+    name: String,
+    kind: KeyKind,
+    policy: KeyPolicy,
+    nonce: [u8; aes::NONCE_LEN],
+    /// Key ciphertext followed by the GCM tag
+    wrapped: Vec<u8>,
+}
+
+impl Record {
+    /// `name_len | name | kind | usage | tasks`: the wrapping AAD and the
+    /// start of the encoded record.
+    fn header(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(7 + self.name.len());
+        out.push(self.name.len() as u8);
+        out.extend_from_slice(self.name.as_bytes());
+        out.push(self.kind as u8);
+        out.push(self.policy.usage.bits());
+        out.extend_from_slice(&self.policy.tasks.to_le_bytes());
+        out
+    }
+}
+
+/// The key store, bound to its persistent image.
+pub struct KeyStore<S: KeyBlobStore, B: CryptoBackend = SoftwareAes> {
+    capacity: usize,
+    generation: u64,
+    records: Vec<Record>,
+    kek: Kek,
+    backend: B,
+    store: S,
+}
+
+impl<S: KeyBlobStore> KeyStore<S> {
+    /// Open the store with the software AES implementation.
+    pub fn open(store: S, kek: Kek, capacity: usize) -> Result<Self, KeyStoreError> {
+        Self::open_with_backend(store, kek, capacity, SoftwareAes)
+    }
+}
+
+impl<S: KeyBlobStore, B: CryptoBackend> KeyStore<S, B> {
+    /// Open the store, restoring it from `store` if one was saved, and
+    /// wrap/unwrap keys on `backend` (e.g. the MCU's AES engine).
+    ///
+    /// A corrupt image is an error, not an empty store: silently starting
+    /// over would replace the device's keys.
+    pub fn open_with_backend(mut store: S, kek: Kek, capacity: usize, backend: B) -> Result<Self, KeyStoreError> {
+        let (generation, records) = match store.load().map_err(KeyStoreError::Storage)? {
+            Some(bytes) => decode(&bytes, &kek)?,
+            None => (0, Vec::new()),
+        };
+        if records.len() > capacity {
+            return Err(KeyStoreError::Full);
+        }
+        Ok(Self { capacity, generation, records, kek, backend, store })
+    }
+
+    /// Number of committed updates; grows by one per change.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.records.iter().map(|r| r.name.as_str())
+    }
+
+    pub fn kind(&self, name: &str) -> Result<KeyKind, KeyStoreError> {
+        Ok(self.find(name)?.kind)
+    }
+
+    pub fn policy(&self, name: &str) -> Result<KeyPolicy, KeyStoreError> {
+        Ok(self.find(name)?.policy)
+    }
+
+    /// Create a fresh random key in a new slot.
+    pub fn generate(&mut self, name: &str, kind: KeyKind, policy: KeyPolicy) -> Result<(), KeyStoreError> {
+        let key = random_key(kind)?;
+        self.import(name, kind, policy, &key)
+    }
+
+    /// Store existing key bytes (provisioning) in a new slot.
+    pub fn import(&mut self, name: &str, kind: KeyKind, policy: KeyPolicy, key: &[u8]) -> Result<(), KeyStoreError> {
+        if name.is_empty() || name.len() > MAX_NAME_LEN {
+            return Err(KeyStoreError::InvalidName);
+        }
+        check_key(kind, key)?;
+        if self.find(name).is_ok() {
+            return Err(KeyStoreError::Exists);
+        }
+        if self.records.len() >= self.capacity {
+            return Err(KeyStoreError::Full);
+        }
+        let record = self.wrap(name, kind, policy, key)?;
+        self.commit(|records| records.push(record))?;
+        info!("key '{}' stored", name);
+        Ok(())
+    }
+
+    /// Replace the key in `name` with a fresh random one, keeping its kind
+    /// and policy. Whatever was protected by the old key must be moved over
+    /// by the caller first.
+    pub fn rotate(&mut self, name: &str) -> Result<(), KeyStoreError> {
+        let idx = self.index(name)?;
+        let (kind, policy) = (self.records[idx].kind, self.records[idx].policy);
+        let key = random_key(kind)?;
+        let record = self.wrap(name, kind, policy, &key)?;
+        self.commit(|records| records[idx] = record)?;
+        info!("key '{}' rotated", name);
+        Ok(())
+    }
+
+    /// Remove a slot. The wrapped key is gone with the next image.
+    pub fn delete(&mut self, name: &str) -> Result<(), KeyStoreError> {
+        let idx = self.index(name)?;
+        self.commit(|records| {
+            records.remove(idx);
+        })?;
+        info!("key '{}' deleted", name);
+        Ok(())
+    }
+
+    /// Lend the unwrapped key to `f` for `op`, if the policy allows it.
+    /// The bytes are wiped when `f` returns.
+    pub fn with_key<R>(
+        &mut self,
+        name: &str,
+        caller: Caller,
+        op: Operation,
+        f: impl FnOnce(&[u8]) -> R,
+    ) -> Result<R, KeyStoreError> {
+        let record = self.find(name)?;
+        if !record.kind.supports(op) {
+            return Err(KeyStoreError::WrongKind);
+        }
+        if !record.policy.permits(caller, op) {
+            warn!("task {} denied {:?} with key '{}'", caller, op, name);
+            return Err(KeyStoreError::Denied);
+        }
+        let key = self.unwrap(name)?;
+        Ok(f(&key))
+    }
+
+    /// ECDSA P-256 / SHA-256 signature over `message` with a P-256 key.
+    pub fn sign(&mut self, name: &str, caller: Caller, message: &[u8]) -> Result<Signature, KeyStoreError> {
+        self.with_key(name, caller, Operation::Sign, |key| {
+            KeyPair::from_private_bytes(key).map(|pair| pair.sign(message))
+        })?
+        .map_err(|_| KeyStoreError::Corrupt)
+    }
+
+    /// Public half of a P-256 key. Public keys need no permission.
+    pub fn public_key(&mut self, name: &str) -> Result<VerifyingKey, KeyStoreError> {
+        if self.kind(name)? != KeyKind::P256 {
+            return Err(KeyStoreError::WrongKind);
+        }
+        let key = self.unwrap(name)?;
+        KeyPair::from_private_bytes(&key).map(|pair| pair.public_key()).map_err(|_| KeyStoreError::Corrupt)
+    }
+
+    /// AES-256-GCM encryption with an AES-256 key (see `aes::gcm_seal`).
+    pub fn seal(
+        &mut self,
+        name: &str,
+        caller: Caller,
+        nonce: &[u8; aes::NONCE_LEN],
+        aad: &[u8],
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, KeyStoreError> {
+        let key = self.aes256(name, caller, Operation::Encrypt)?;
+        aes::gcm_seal(&mut self.backend, &key, nonce, aad, plaintext).map_err(KeyStoreError::Crypto)
+    }
+
+    /// AES-256-GCM decryption with an AES-256 key (see `aes::gcm_open`).
+    pub fn open_sealed(
+        &mut self,
+        name: &str,
+        caller: Caller,
+        nonce: &[u8; aes::NONCE_LEN],
+        aad: &[u8],
+        sealed: &[u8],
+    ) -> Result<Vec<u8>, KeyStoreError> {
+        let key = self.aes256(name, caller, Operation::Decrypt)?;
+        aes::gcm_open(&mut self.backend, &key, nonce, aad, sealed).map_err(KeyStoreError::Crypto)
+    }
+
+    fn aes256(&mut self, name: &str, caller: Caller, op: Operation) -> Result<Zeroizing<[u8; 32]>, KeyStoreError> {
+        if self.kind(name)? != KeyKind::Aes256 {
+            return Err(KeyStoreError::WrongKind);
+        }
+        self.with_key(name, caller, op, |key| {
+            let mut out = Zeroizing::new([0u8; 32]);
+            out.copy_from_slice(key);
+            out
+        })
+    }
+
+    /// Key bytes of `name`; no policy check, callers do that.
+    fn unwrap(&mut self, name: &str) -> Result<Zeroizing<Vec<u8>>, KeyStoreError> {
+        let idx = self.index(name)?;
+        let record = &self.records[idx];
+        aes::gcm_open(&mut self.backend, &self.kek.wrap, &record.nonce, &record.header(), &record.wrapped)
+            .map(Zeroizing::new)
+            .map_err(|_| KeyStoreError::Corrupt)
+    }
+
+    fn wrap(&mut self, name: &str, kind: KeyKind, policy: KeyPolicy, key: &[u8]) -> Result<Record, KeyStoreError> {
+        let mut record = Record { name: name.into(), kind, policy, nonce: [0; aes::NONCE_LEN], wrapped: Vec::new() };
+        // Random nonces: the store rewraps at most a few thousand keys over
+        // the device's life, far below the GCM collision bound.
+        crypto::rng::fill_random(&mut record.nonce).map_err(|_| KeyStoreError::Rng)?;
+        let aad = record.header();
+        record.wrapped = aes::gcm_seal(&mut self.backend, &self.kek.wrap, &record.nonce, &aad, key)
+            .map_err(KeyStoreError::Crypto)?;
+        Ok(record)
+    }
+
+    /// Apply `change` to a copy of the records and persist it as the next
+    /// generation; only then does it become the store's state.
+    fn commit(&mut self, change: impl FnOnce(&mut Vec<Record>)) -> Result<(), KeyStoreError> {
+        let mut records = self.records.clone();
+        change(&mut records);
+        let generation = self.generation + 1;
+        self.store.save(&encode(generation, &records, &self.kek)).map_err(KeyStoreError::Storage)?;
+        self.records = records;
+        self.generation = generation;
+        Ok(())
+    }
+
+    fn find(&self, name: &str) -> Result<&Record, KeyStoreError> {
+        self.records.iter().find(|r| r.name == name).ok_or(KeyStoreError::Unknown)
+    }
+
+    fn index(&self, name: &str) -> Result<usize, KeyStoreError> {
+        self.records.iter().position(|r| r.name == name).ok_or(KeyStoreError::Unknown)
+    }
+}
+
+fn random_key(kind: KeyKind) -> Result<Zeroizing<Vec<u8>>, KeyStoreError> {
+    match kind {
+        KeyKind::P256 => {
+            let pair = KeyPair::generate().map_err(|_| KeyStoreError::Rng)?;
+            Ok(Zeroizing::new(pair.private_bytes().to_vec()))
+        }
+        KeyKind::Aes128 | KeyKind::Aes256 => {
+            let mut key = Zeroizing::new(vec![0u8; kind.key_len()]);
+            crypto::rng::fill_random(&mut key).map_err(|_| KeyStoreError::Rng)?;
+            Ok(key)
+        }
+    }
+}
+
+fn check_key(kind: KeyKind, key: &[u8]) -> Result<(), KeyStoreError> {
+    if key.len() != kind.key_len() {
+        return Err(KeyStoreError::InvalidKey);
+    }
+    if kind == KeyKind::P256 && KeyPair::from_private_bytes(key).is_err() {
+        return Err(KeyStoreError::InvalidKey);
+    }
+    Ok(())
+}
+
+/// `magic(4) | version(1) | generation(8) | count(2) |
+///  (header | nonce(12) | wrapped_len(2) | wrapped) * count | mac(32)`.
+fn encode(generation: u64, records: &[Record], kek: &Kek) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_LEN + MAC_LEN + records.len() * 128);
+    out.extend_from_slice(&MAGIC);
+    out.push(FORMAT_VERSION);
+    out.extend_from_slice(&generation.to_le_bytes());
+    out.extend_from_slice(&(records.len() as u16).to_le_bytes());
+    for r in records {
+        out.extend_from_slice(&r.header());
+        out.extend_from_slice(&r.nonce);
+        out.extend_from_slice(&(r.wrapped.len() as u16).to_le_bytes());
+        out.extend_from_slice(&r.wrapped);
+    }
+    let mac = kdf::hmac_sha256(&kek.mac[..], &out);
+    out.extend_from_slice(&mac);
+    out
+}
+
+fn decode(data: &[u8], kek: &Kek) -> Result<(u64, Vec<Record>), KeyStoreError> {
+    if data.len() < HEADER_LEN + MAC_LEN || data[..4] != MAGIC || data[4] != FORMAT_VERSION {
+        return Err(KeyStoreError::Corrupt);
+    }
+    let (body, mac) = data.split_at(data.len() - MAC_LEN);
+    if !kdf::hmac_sha256_verify(&kek.mac[..], body, mac) {
+        warn!("key store image failed its MAC");
+        return Err(KeyStoreError::Corrupt);
+    }
+    let mut generation = [0u8; 8];
+    generation.copy_from_slice(&body[5..13]);
+    let count = u16::from_le_bytes([body[13], body[14]]) as usize;
+
+    let mut rest = &body[HEADER_LEN..];
+    let mut records = Vec::with_capacity(count.min(rest.len()));
+    for _ in 0..count {
+        let (&name_len, after) = rest.split_first().ok_or(KeyStoreError::Corrupt)?;
+        let name_len = name_len as usize;
+        let fixed = name_len + 6 + aes::NONCE_LEN + 2;
+        if name_len == 0 || name_len > MAX_NAME_LEN || after.len() < fixed {
+            return Err(KeyStoreError::Corrupt);
+        }
+        let name = core::str::from_utf8(&after[..name_len]).map_err(|_| KeyStoreError::Corrupt)?;
+        let kind = KeyKind::from_u8(after[name_len]).ok_or(KeyStoreError::Corrupt)?;
+        let usage = after[name_len + 1];
+        if usage & !Usage::ALL != 0 {
+            return Err(KeyStoreError::Corrupt);
+        }
+        let t = &after[name_len + 2..name_len + 6];
+        let tasks = u32::from_le_bytes([t[0], t[1], t[2], t[3]]);
+        let mut nonce = [0u8; aes::NONCE_LEN];
+        nonce.copy_from_slice(&after[name_len + 6..name_len + 6 + aes::NONCE_LEN]);
+        let w = &after[fixed - 2..fixed];
+        let wrapped_len = u16::from_le_bytes([w[0], w[1]]) as usize;
+        if after.len() < fixed + wrapped_len {
+            return Err(KeyStoreError::Corrupt);
+        }
+        records.push(Record {
+            name: name.into(),
+            kind,
+            policy: KeyPolicy { usage: Usage(usage), tasks },
+            nonce,
+            wrapped: after[fixed..fixed + wrapped_len].to_vec(),
+        });
+        rest = &after[fixed + wrapped_len..];
+    }
+    if !rest.is_empty() {
+        return Err(KeyStoreError::Corrupt);
+    }
+    Ok((u64::from_le_bytes(generation), records))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use p256::ecdsa::signature::Verifier;
+
+    #[derive(Default)]
+    struct RamStore {
+        data: Option<Vec<u8>>,
+        fail: bool,
+    }
+
+    impl KeyBlobStore for &mut RamStore {
+        fn load(&mut self) -> Result<Option<Vec<u8>>, &'static str> {
+            Ok(self.data.clone())
+        }
+        fn save(&mut self, data: &[u8]) -> Result<(), &'static str> {
+            if self.fail {
+                return Err("flash write failed");
+            }
+            self.data = Some(data.to_vec());
+            Ok(())
+        }
+    }
+
+    fn kek() -> Kek {
+        Kek::new(&[0x4B; 32])
+    }
+
+    #[test]
+    fn keys_survive_reboot_and_work_after_unwrapping() {
+        let mut store = RamStore::default();
+        let public = {
+            let mut ks = KeyStore::open(&mut store, kek(), 4).unwrap();
+            ks.generate("device-id", KeyKind::P256, KeyPolicy::new(Usage::SIGN)).unwrap();
+            ks.import("telemetry", KeyKind::Aes256, KeyPolicy::new(Usage::ENCRYPT | Usage::DECRYPT), &[7; 32]).unwrap();
+            assert_eq!(ks.generation(), 2);
+            ks.public_key("device-id").unwrap()
+        };
+        let raw = store.data.clone().unwrap();
+        assert!(!raw.windows(32).any(|w| w == [7; 32]), "key bytes stored in the clear");
+
+        let mut ks = KeyStore::open(&mut store, kek(), 4).unwrap();
+        assert_eq!(ks.names().collect::<Vec<_>>(), ["device-id", "telemetry"]);
+        let sig = ks.sign("device-id", 0, b"hello").unwrap();
+        public.verify(b"hello", &sig).unwrap();
+
+        let nonce = [1u8; aes::NONCE_LEN];
+        let sealed = ks.seal("telemetry", 3, &nonce, b"hdr", b"reading").unwrap();
+        assert_eq!(ks.open_sealed("telemetry", 3, &nonce, b"hdr", &sealed).unwrap(), b"reading");
+        assert_eq!(ks.sign("telemetry", 0, b"x").err(), Some(KeyStoreError::WrongKind));
+    }
+
+    #[test]
+    fn policies_limit_operations_and_tasks() {
+        let mut store = RamStore::default();
+        let mut ks = KeyStore::open(&mut store, kek(), 4).unwrap();
+        ks.generate("boot-sign", KeyKind::P256, KeyPolicy::new(Usage::SIGN).for_tasks(1 << 2)).unwrap();
+        ks.generate("fw-decrypt", KeyKind::Aes256, KeyPolicy::new(Usage::DECRYPT)).unwrap();
+
+        assert!(ks.sign("boot-sign", 2, b"m").is_ok());
+        assert_eq!(ks.sign("boot-sign", 3, b"m").err(), Some(KeyStoreError::Denied));
+        assert_eq!(ks.with_key("boot-sign", 2, Operation::Export, |_| ()).err(), Some(KeyStoreError::Denied));
+
+        let nonce = [0u8; aes::NONCE_LEN];
+        assert_eq!(ks.seal("fw-decrypt", 0, &nonce, b"", b"x").err(), Some(KeyStoreError::Denied));
+        assert_eq!(
+            ks.open_sealed("fw-decrypt", 0, &nonce, b"", &[0; 20]).err(),
+            Some(KeyStoreError::Crypto(AesError::AuthFailed))
+        );
+        assert_eq!(
+            ks.generate("fw-decrypt", KeyKind::Aes256, KeyPolicy::new(Usage::DECRYPT)),
+            Err(KeyStoreError::Exists)
+        );
+        assert_eq!(
+            ks.import("bad", KeyKind::Aes128, KeyPolicy::new(Usage::EXPORT), &[0; 15]),
+            Err(KeyStoreError::InvalidKey)
+        );
+    }
+
+    #[test]
+    fn tampering_and_a_wrong_kek_are_detected() {
+        let mut store = RamStore::default();
+        KeyStore::open(&mut store, kek(), 4)
+            .unwrap()
+            .generate("k", KeyKind::Aes128, KeyPolicy::new(Usage::DECRYPT))
+            .unwrap();
+        assert_eq!(KeyStore::open(&mut store, Kek::new(&[0; 32]), 4).err(), Some(KeyStoreError::Corrupt));
+
+        // Widening the policy byte in flash breaks the image MAC.
+        let original = store.data.clone().unwrap();
+        let usage_at = HEADER_LEN + 1 + 1 + 1;
+        store.data.as_mut().unwrap()[usage_at] = Usage::EXPORT.bits();
+        assert_eq!(KeyStore::open(&mut store, kek(), 4).err(), Some(KeyStoreError::Corrupt));
+        store.data = Some(original);
+        assert!(KeyStore::open(&mut store, kek(), 4).is_ok());
+    }
+
+    #[test]
+    fn failed_saves_leave_the_store_unchanged() {
+        let mut store = RamStore::default();
+        KeyStore::open(&mut store, kek(), 2)
+            .unwrap()
+            .import("a", KeyKind::Aes128, KeyPolicy::new(Usage::EXPORT), &[1; 16])
+            .unwrap();
+
+        store.fail = true;
+        let mut ks = KeyStore::open(&mut store, kek(), 2).unwrap();
+        assert!(matches!(ks.rotate("a"), Err(KeyStoreError::Storage(_))));
+        assert!(matches!(ks.delete("a"), Err(KeyStoreError::Storage(_))));
+        assert!(matches!(
+            ks.generate("b", KeyKind::Aes256, KeyPolicy::new(Usage::DECRYPT)),
+            Err(KeyStoreError::Storage(_))
+        ));
+        assert_eq!(ks.generation(), 1);
+        assert_eq!(ks.with_key("a", 0, Operation::Export, |k| k.to_vec()).unwrap(), [1; 16]);
+    }
+}
@@ -6,9 +6,28 @@
 //! URL: https://m-a-h-b-u-b.github.io
 //! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
 
+// If we are not running tests, compile this crate without the standard library (no_std).
+#![cfg_attr(not(test), no_std)]
+
+// Records, manifests and the outbox are variable length
+extern crate alloc;
+
 pub mod flash;
 pub mod wear_level;
+pub mod flash_driver;
+pub mod erase;
+pub mod counters;
 pub mod key_mgmt;
+pub mod key_store;
+pub mod replay;
+pub mod namespace;
+pub mod enrollment;
+pub mod boot_ledger;
+pub mod update;
+pub mod vendor_keys;
+pub mod outbox;
+
+pub use erase::{erase_secure, EraseError, EraseTarget};
 
 /// Initialize secure storage subsystem
 /// - init crypto (if needed)
@@ -18,3 +37,11 @@ pub fn init_secure_storage() {
     // Initialize wear-leveling metadata
     wear_level::init_wear_level();
 }
+
+/// Arm DWT watchpoints over the active sector index and the key status,
+/// so stray writes to them show up in `hal::watch::drain_hits()`. Does
+/// nothing without the `watchpoints` feature.
+pub fn watch_critical_state() {
+    wear_level::watch_active_sector();
+    key_mgmt::watch_key_status();
+}
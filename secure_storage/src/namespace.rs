@@ -0,0 +1,456 @@
+//! SecureIoTOS Key Namespace Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+
+//! Per-downstream-device key sets for gateways.
+//!
+//! A gateway aggregating several downstream devices keeps, for each of
+//! them, its own namespace: a set of keys (one per `KeyPurpose`), a key
+//! epoch and the session state of the link to that device. The device's
+//! own keys stay in `key_mgmt`.
+//!
+//! Isolation:
+//! - Keys are generated independently per namespace, never derived from
+//!   one another, so leaking one device's keys reveals nothing about the
+//!   others.
+//! - Every operation takes a `NamespaceHandle`, not a bare device ID. A
+//!   handle names one namespace and one incarnation of it: once the
+//!   namespace is removed, old handles stop working even if the device ID
+//!   is enrolled again.
+//! - Key bytes are only lent to a closure (`with_key`), never returned.
+//!
+//! Rotation is per namespace or in bulk (`rotate_all`, `rotate_matching`,
+//! e.g. after a gateway compromise or on a schedule). Rotating bumps the
+//! epoch and drops the session, since it was keyed with the old material;
+//! the caller then re-provisions each returned device.
+//!
+//! `to_bytes()` contains key material: persist it only through encrypted
+//! storage (`flash::encrypt_and_store`).
+
+use alloc::vec::Vec;
+
+use sios_log::{info, Secret};
+use zeroize::Zeroize;
+
+use crate::replay::checksum;
+
+/// Magic prefix of the persisted namespaces ("KNSP").
+const MAGIC: [u8; 4] = *b"KNSP";
+/// Encoding version of the persisted namespaces.
+const FORMAT_VERSION: u8 = 1;
+/// Encoded size of one namespace.
+const RECORD_LEN: usize = 4 + 4 + KEY_SLOTS * 16 + 8 + 8 + 1;
+
+/// Identifier of a downstream device (and of its namespace).
+pub type DeviceId = u32;
+
+/// Number of keys in each namespace.
+pub const KEY_SLOTS: usize = 3;
+
+/// What a namespace key is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum KeyPurpose {
+    /// Encrypts data the gateway stores on the device's behalf
+    Storage = 0,
+    /// Protects the link to the device
+    Session = 1,
+    /// Authenticates commands sent to the device
+    Command = 2,
+}
+
+/// Errors reported by the namespace manager.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum NamespaceError {
+    /// No namespace for this device, or the handle is stale
+    Unknown,
+    /// The device already has a namespace
+    Exists,
+    /// Capacity reached
+    Full,
+    /// No session is open
+    NoSession,
+    /// Received counter is not above the last accepted one
+    Replayed,
+    /// Transmit counter exhausted; rotate the namespace
+    CounterExhausted,
+    /// Persisted namespaces failed their integrity check or have the wrong format
+    Corrupt,
+}
+
+/// Access to one namespace. Obtained from `create` or `handle`; stale once
+/// the namespace is removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamespaceHandle {
+    device: DeviceId,
+    generation: u32,
+}
+
+impl NamespaceHandle {
+    pub fn device(&self) -> DeviceId {
+        self.device
+    }
+}
+
+/// State of the secured link to a downstream device.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SessionState {
+    pub open: bool,
+    /// Last counter used for a frame sent to the device
+    pub tx_counter: u64,
+    /// Last counter accepted from the device
+    pub rx_counter: u64,
+}
+
+struct Namespace {
+    device: DeviceId,
+    generation: u32,
+    epoch: u32,
+    keys: [Secret<[u8; 16]>; KEY_SLOTS],
+    session: SessionState,
+}
+
+impl Drop for Namespace {
+    fn drop(&mut self) {
+        self.keys.iter_mut().for_each(Zeroize::zeroize);
+    }
+}
+
+/// Key sets and session state of the downstream devices of a gateway.
+pub struct KeyNamespaces {
+    capacity: usize,
+    spaces: Vec<Namespace>,
+    next_generation: u32,
+    keygen: fn() -> [u8; 16],
+}
+
+impl KeyNamespaces {
+    /// Manager for up to `capacity` downstream devices, with keys from the
+    /// system RNG.
+    pub fn new(capacity: usize) -> Self {
+        Self::with_keygen(capacity, crypto::rng::generate_random_key)
+    }
+
+    /// Manager with a custom key source (e.g. a secure element).
+    pub fn with_keygen(capacity: usize, keygen: fn() -> [u8; 16]) -> Self {
+        Self { capacity, spaces: Vec::with_capacity(capacity), next_generation: 1, keygen }
+    }
+
+    pub fn len(&self) -> usize {
+        self.spaces.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spaces.is_empty()
+    }
+
+    /// Create the namespace of `device` with fresh keys (epoch 1).
+    pub fn create(&mut self, device: DeviceId) -> Result<NamespaceHandle, NamespaceError> {
+        if self.spaces.iter().any(|ns| ns.device == device) {
+            return Err(NamespaceError::Exists);
+        }
+        if self.spaces.len() >= self.capacity {
+            return Err(NamespaceError::Full);
+        }
+        let generation = self.issue_generation();
+        let keys = self.fresh_keys();
+        self.spaces.push(Namespace { device, generation, epoch: 1, keys, session: SessionState::default() });
+        info!("key namespace created for device {}", device);
+        Ok(NamespaceHandle { device, generation })
+    }
+
+    /// Handle to the existing namespace of `device`.
+    pub fn handle(&self, device: DeviceId) -> Option<NamespaceHandle> {
+        self.spaces
+            .iter()
+            .find(|ns| ns.device == device)
+            .map(|ns| NamespaceHandle { device, generation: ns.generation })
+    }
+
+    /// Remove a namespace, wiping its keys. Outstanding handles go stale.
+    pub fn remove(&mut self, handle: &NamespaceHandle) -> Result<(), NamespaceError> {
+        let idx = self.index(handle)?;
+        self.spaces.remove(idx);
+        info!("key namespace removed for device {}", handle.device);
+        Ok(())
+    }
+
+    /// Current key epoch of a namespace (1 after creation, +1 per rotation).
+    pub fn epoch(&self, handle: &NamespaceHandle) -> Result<u32, NamespaceError> {
+        Ok(self.get(handle)?.epoch)
+    }
+
+    /// Lend the key for `purpose` to `f`.
+    pub fn with_key<R>(
+        &self,
+        handle: &NamespaceHandle,
+        purpose: KeyPurpose,
+        f: impl FnOnce(&[u8; 16]) -> R,
+    ) -> Result<R, NamespaceError> {
+        let ns = self.get(handle)?;
+        Ok(f(ns.keys[purpose as usize].expose_secret()))
+    }
+
+    /// Replace every key of one namespace.
+    pub fn rotate(&mut self, handle: &NamespaceHandle) -> Result<u32, NamespaceError> {
+        let idx = self.index(handle)?;
+        Ok(self.rotate_at(idx))
+    }
+
+    /// Rotate every namespace; returns the devices to re-provision.
+    pub fn rotate_all(&mut self) -> Vec<DeviceId> {
+        self.rotate_matching(|_| true)
+    }
+
+    /// Rotate the namespaces of the devices `select` picks; returns them.
+    pub fn rotate_matching(&mut self, mut select: impl FnMut(DeviceId) -> bool) -> Vec<DeviceId> {
+        let mut rotated = Vec::new();
+        for idx in 0..self.spaces.len() {
+            let device = self.spaces[idx].device;
+            if select(device) {
+                self.rotate_at(idx);
+                rotated.push(device);
+            }
+        }
+        info!("bulk key rotation: {} namespaces", rotated.len());
+        rotated
+    }
+
+    /// Session state of the link to the device.
+    pub fn session(&self, handle: &NamespaceHandle) -> Result<SessionState, NamespaceError> {
+        Ok(self.get(handle)?.session)
+    }
+
+    /// Start a session with the device (after a handshake with the session
+    /// key). Counters restart from 0.
+    pub fn open_session(&mut self, handle: &NamespaceHandle) -> Result<(), NamespaceError> {
+        self.get_mut(handle)?.session = SessionState { open: true, tx_counter: 0, rx_counter: 0 };
+        Ok(())
+    }
+
+    pub fn close_session(&mut self, handle: &NamespaceHandle) -> Result<(), NamespaceError> {
+        self.get_mut(handle)?.session = SessionState::default();
+        Ok(())
+    }
+
+    /// Counter for the next frame sent to the device.
+    pub fn next_tx_counter(&mut self, handle: &NamespaceHandle) -> Result<u64, NamespaceError> {
+        let session = &mut self.get_mut(handle)?.session;
+        if !session.open {
+            return Err(NamespaceError::NoSession);
+        }
+        session.tx_counter = session.tx_counter.checked_add(1).ok_or(NamespaceError::CounterExhausted)?;
+        Ok(session.tx_counter)
+    }
+
+    /// Accept a frame counter received from the device; it must be above
+    /// every counter accepted before in this session.
+    pub fn accept_rx_counter(&mut self, handle: &NamespaceHandle, counter: u64) -> Result<(), NamespaceError> {
+        let session = &mut self.get_mut(handle)?.session;
+        if !session.open {
+            return Err(NamespaceError::NoSession);
+        }
+        if counter <= session.rx_counter {
+            return Err(NamespaceError::Replayed);
+        }
+        session.rx_counter = counter;
+        Ok(())
+    }
+
+    /// Serialize every namespace, keys included:
+    /// `magic(4) | version(1) | count(4) |
+    ///  (device(4) | epoch(4) | keys(16 * KEY_SLOTS) | tx(8) | rx(8) | open(1)) * count |
+    ///  checksum(4)`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(13 + self.spaces.len() * RECORD_LEN);
+        out.extend_from_slice(&MAGIC);
+        out.push(FORMAT_VERSION);
+        out.extend_from_slice(&(self.spaces.len() as u32).to_le_bytes());
+        for ns in &self.spaces {
+            out.extend_from_slice(&ns.device.to_le_bytes());
+            out.extend_from_slice(&ns.epoch.to_le_bytes());
+            for key in &ns.keys {
+                out.extend_from_slice(key.expose_secret());
+            }
+            out.extend_from_slice(&ns.session.tx_counter.to_le_bytes());
+            out.extend_from_slice(&ns.session.rx_counter.to_le_bytes());
+            out.push(ns.session.open as u8);
+        }
+        let sum = checksum(&out);
+        out.extend_from_slice(&sum.to_le_bytes());
+        out
+    }
+
+    /// Restore namespaces saved with `to_bytes()`. Handles from before are
+    /// not valid; get new ones with `handle()`.
+    pub fn from_bytes(data: &[u8], capacity: usize, keygen: fn() -> [u8; 16]) -> Result<Self, NamespaceError> {
+        if data.len() < 13 || data[..4] != MAGIC || data[4] != FORMAT_VERSION {
+            return Err(NamespaceError::Corrupt);
+        }
+        let (body, tail) = data.split_at(data.len() - 4);
+        if checksum(body) != u32::from_le_bytes([tail[0], tail[1], tail[2], tail[3]]) {
+            return Err(NamespaceError::Corrupt);
+        }
+        let count = u32::from_le_bytes([body[5], body[6], body[7], body[8]]) as usize;
+        let records = &body[9..];
+        if records.len() != count * RECORD_LEN {
+            return Err(NamespaceError::Corrupt);
+        }
+        if count > capacity {
+            return Err(NamespaceError::Full);
+        }
+
+        let mut this = Self::with_keygen(capacity, keygen);
+        for r in records.chunks_exact(RECORD_LEN) {
+            let word = |at: usize| u32::from_le_bytes([r[at], r[at + 1], r[at + 2], r[at + 3]]);
+            let mut long = [0u8; 8];
+            let device = word(0);
+            if this.spaces.iter().any(|ns| ns.device == device) {
+                return Err(NamespaceError::Corrupt);
+            }
+            let keys = core::array::from_fn(|slot| {
+                let mut key = [0u8; 16];
+                key.copy_from_slice(&r[8 + slot * 16..8 + (slot + 1) * 16]);
+                Secret::new(key)
+            });
+            let at = 8 + KEY_SLOTS * 16;
+            long.copy_from_slice(&r[at..at + 8]);
+            let tx_counter = u64::from_le_bytes(long);
+            long.copy_from_slice(&r[at + 8..at + 16]);
+            let rx_counter = u64::from_le_bytes(long);
+            let session = SessionState { open: r[at + 16] != 0, tx_counter, rx_counter };
+            let generation = this.issue_generation();
+            this.spaces.push(Namespace { device, generation, epoch: word(4), keys, session });
+        }
+        Ok(this)
+    }
+
+    fn issue_generation(&mut self) -> u32 {
+        let generation = self.next_generation;
+        self.next_generation = self.next_generation.wrapping_add(1).max(1);
+        generation
+    }
+
+    fn fresh_keys(&self) -> [Secret<[u8; 16]>; KEY_SLOTS] {
+        core::array::from_fn(|_| Secret::new((self.keygen)()))
+    }
+
+    fn rotate_at(&mut self, idx: usize) -> u32 {
+        let keys = self.fresh_keys();
+        let ns = &mut self.spaces[idx];
+        ns.keys.iter_mut().for_each(Zeroize::zeroize);
+        ns.keys = keys;
+        ns.epoch = ns.epoch.wrapping_add(1);
+        ns.session = SessionState::default();
+        ns.epoch
+    }
+
+    fn index(&self, handle: &NamespaceHandle) -> Result<usize, NamespaceError> {
+        self.spaces
+            .iter()
+            .position(|ns| ns.device == handle.device && ns.generation == handle.generation)
+            .ok_or(NamespaceError::Unknown)
+    }
+
+    fn get(&self, handle: &NamespaceHandle) -> Result<&Namespace, NamespaceError> {
+        self.index(handle).map(|idx| &self.spaces[idx])
+    }
+
+    fn get_mut(&mut self, handle: &NamespaceHandle) -> Result<&mut Namespace, NamespaceError> {
+        let idx = self.index(handle)?;
+        Ok(&mut self.spaces[idx])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicU8, Ordering};
+
+    /// Distinct, predictable keys.
+    fn counting_keygen() -> [u8; 16] {
+        static NEXT: AtomicU8 = AtomicU8::new(1);
+        [NEXT.fetch_add(1, Ordering::Relaxed); 16]
+    }
+
+    fn key(ns: &KeyNamespaces, h: &NamespaceHandle, purpose: KeyPurpose) -> [u8; 16] {
+        ns.with_key(h, purpose, |k| *k).unwrap()
+    }
+
+    #[test]
+    fn namespaces_are_isolated_and_handles_go_stale() {
+        let mut ns = KeyNamespaces::with_keygen(2, counting_keygen);
+        let a = ns.create(7).unwrap();
+        let b = ns.create(8).unwrap();
+        assert_eq!(ns.create(7), Err(NamespaceError::Exists));
+        assert_eq!(ns.create(9), Err(NamespaceError::Full));
+
+        assert_ne!(key(&ns, &a, KeyPurpose::Session), key(&ns, &b, KeyPurpose::Session));
+        assert_ne!(key(&ns, &a, KeyPurpose::Storage), key(&ns, &a, KeyPurpose::Command));
+
+        ns.remove(&a).unwrap();
+        let a2 = ns.create(7).unwrap();
+        assert_eq!(ns.with_key(&a, KeyPurpose::Session, |_| ()), Err(NamespaceError::Unknown), "stale handle");
+        assert_eq!(ns.handle(7), Some(a2));
+    }
+
+    #[test]
+    fn bulk_rotation_rekeys_and_drops_sessions() {
+        let mut ns = KeyNamespaces::with_keygen(4, counting_keygen);
+        let handles: Vec<_> = (1..=3).map(|d| ns.create(d).unwrap()).collect();
+        for h in &handles {
+            ns.open_session(h).unwrap();
+            ns.next_tx_counter(h).unwrap();
+        }
+        let before = key(&ns, &handles[1], KeyPurpose::Session);
+
+        assert_eq!(ns.rotate_matching(|d| d != 1), vec![2, 3]);
+        assert_eq!(ns.epoch(&handles[0]), Ok(1));
+        assert_eq!(ns.epoch(&handles[1]), Ok(2));
+        assert_ne!(key(&ns, &handles[1], KeyPurpose::Session), before);
+        assert!(ns.session(&handles[0]).unwrap().open);
+        assert_eq!(ns.next_tx_counter(&handles[2]), Err(NamespaceError::NoSession));
+
+        assert_eq!(ns.rotate_all(), vec![1, 2, 3]);
+        assert_eq!(ns.rotate(&handles[2]), Ok(4));
+    }
+
+    #[test]
+    fn session_counters_reject_replays() {
+        let mut ns = KeyNamespaces::with_keygen(1, counting_keygen);
+        let h = ns.create(5).unwrap();
+        assert_eq!(ns.accept_rx_counter(&h, 1), Err(NamespaceError::NoSession));
+        ns.open_session(&h).unwrap();
+        assert_eq!(ns.next_tx_counter(&h), Ok(1));
+        assert_eq!(ns.next_tx_counter(&h), Ok(2));
+        ns.accept_rx_counter(&h, 10).unwrap();
+        assert_eq!(ns.accept_rx_counter(&h, 10), Err(NamespaceError::Replayed));
+        ns.accept_rx_counter(&h, 11).unwrap();
+    }
+
+    #[test]
+    fn persists_and_detects_corruption() {
+        let mut ns = KeyNamespaces::with_keygen(4, counting_keygen);
+        let h = ns.create(42).unwrap();
+        ns.rotate(&h).unwrap();
+        ns.open_session(&h).unwrap();
+        ns.accept_rx_counter(&h, 3).unwrap();
+        let mut bytes = ns.to_bytes();
+
+        let restored = KeyNamespaces::from_bytes(&bytes, 4, counting_keygen).unwrap();
+        let h2 = restored.handle(42).unwrap();
+        assert_eq!(restored.epoch(&h2), Ok(2));
+        assert_eq!(key(&restored, &h2, KeyPurpose::Command), key(&ns, &h, KeyPurpose::Command));
+        assert_eq!(restored.session(&h2), ns.session(&h));
+        assert!(KeyNamespaces::from_bytes(&bytes, 0, counting_keygen).is_err());
+
+        bytes[20] ^= 0xFF;
+        assert_eq!(KeyNamespaces::from_bytes(&bytes, 4, counting_keygen).err(), Some(NamespaceError::Corrupt));
+    }
+}
@@ -0,0 +1,338 @@
+//! SecureIoTOS Outbox Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+
+//! Persistent store-and-forward queue for outgoing messages.
+//!
+//! Telemetry produced while the link is down is queued here instead of
+//! being lost, and survives a reboot. Each message has a destination (MQTT
+//! topic, CoAP path, ...), a caller-defined `kind` saying which transport
+//! it is for, and a sequence number; they are handed back oldest first and
+//! removed with `ack()` once delivered.
+//!
+//! The queue is bounded by message count and by total payload bytes. When
+//! a new message does not fit, the oldest ones are dropped to make room
+//! (fresh telemetry is usually worth more than stale), and counted in
+//! `dropped()`.
+//!
+//! Like the replay window, the queue is persisted to its store before a
+//! change is reported as done; a failed save rolls the change back. Every
+//! change rewrites the whole queue, so keep the bounds in line with the
+//! sector size and put the store on wear-levelled storage.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use sios_log::{info, warn};
+
+use crate::replay::checksum;
+
+/// Magic prefix of the persisted queue ("OBOX").
+const MAGIC: [u8; 4] = *b"OBOX";
+/// Encoding version of the persisted queue.
+const FORMAT_VERSION: u8 = 1;
+/// Header: magic, version, next sequence number, dropped count, count.
+const HEADER_LEN: usize = 4 + 1 + 8 + 8 + 4;
+
+/// Longest destination in bytes.
+pub const MAX_DESTINATION_LEN: usize = 255;
+
+/// Errors reported by the outbox.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum OutboxError {
+    /// Empty or oversize destination
+    InvalidDestination,
+    /// The message alone exceeds the byte bound
+    TooLarge,
+    /// No message with that sequence number at the head of the queue
+    NotQueued,
+    /// Persisted queue failed its integrity check or has the wrong format
+    Corrupt,
+    /// Backing storage failed
+    Storage(&'static str),
+}
+
+/// One queued message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutboxEntry {
+    /// Position in the queue, increasing across reboots
+    pub seq: u64,
+    /// Caller-defined transport tag
+    pub kind: u8,
+    pub destination: String,
+    pub payload: Vec<u8>,
+}
+
+/// Backing store for the queue, typically a dedicated encrypted sector.
+pub trait OutboxStore {
+    /// Load the last saved queue; `Ok(None)` if nothing was ever saved.
+    fn load(&mut self) -> Result<Option<Vec<u8>>, &'static str>;
+    /// Atomically replace the saved queue.
+    fn save(&mut self, data: &[u8]) -> Result<(), &'static str>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Queue {
+    next_seq: u64,
+    dropped: u64,
+    entries: Vec<OutboxEntry>,
+}
+
+/// The queue, bound to its persistent store.
+pub struct Outbox<S: OutboxStore> {
+    queue: Queue,
+    capacity: usize,
+    max_bytes: usize,
+    store: S,
+}
+
+impl<S: OutboxStore> Outbox<S> {
+    /// Open the queue kept in `store`, or start an empty one. At most
+    /// `capacity` messages (at least 1) and `max_bytes` of payload are
+    /// held; a restored queue over the bounds is trimmed oldest first.
+    ///
+    /// A corrupt queue is an error, so the caller decides whether to
+    /// discard it.
+    pub fn open(mut store: S, capacity: usize, max_bytes: usize) -> Result<Self, OutboxError> {
+        let queue = match store.load().map_err(OutboxError::Storage)? {
+            Some(bytes) => decode(&bytes)?,
+            None => Queue { next_seq: 0, dropped: 0, entries: Vec::new() },
+        };
+        let mut outbox = Self { queue, capacity: capacity.max(1), max_bytes, store };
+        if outbox.make_room(0, 0) > 0 {
+            outbox.persist()?;
+        }
+        if !outbox.is_empty() {
+            info!("outbox: {} message(s) restored", outbox.len());
+        }
+        Ok(outbox)
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.entries.is_empty()
+    }
+
+    /// Payload bytes held.
+    pub fn bytes(&self) -> usize {
+        self.queue.entries.iter().map(|e| e.payload.len()).sum()
+    }
+
+    /// Messages dropped to make room, since the queue was created.
+    pub fn dropped(&self) -> u64 {
+        self.queue.dropped
+    }
+
+    /// Oldest queued message.
+    pub fn front(&self) -> Option<&OutboxEntry> {
+        self.queue.entries.first()
+    }
+
+    /// Queued messages, oldest first.
+    pub fn entries(&self) -> &[OutboxEntry] {
+        &self.queue.entries
+    }
+
+    /// Queue a message, dropping the oldest ones if it does not fit.
+    /// Returns its sequence number once persisted.
+    pub fn push(&mut self, kind: u8, destination: &str, payload: &[u8]) -> Result<u64, OutboxError> {
+        if destination.is_empty() || destination.len() > MAX_DESTINATION_LEN {
+            return Err(OutboxError::InvalidDestination);
+        }
+        if payload.len() > self.max_bytes {
+            return Err(OutboxError::TooLarge);
+        }
+        let previous = self.queue.clone();
+        let dropped = self.make_room(1, payload.len());
+        let seq = self.queue.next_seq;
+        self.queue.next_seq += 1;
+        self.queue.entries.push(OutboxEntry { seq, kind, destination: destination.into(), payload: payload.to_vec() });
+        if let Err(e) = self.persist() {
+            self.queue = previous;
+            return Err(e);
+        }
+        if dropped > 0 {
+            warn!("outbox full: dropped {} oldest message(s)", dropped);
+        }
+        Ok(seq)
+    }
+
+    /// Remove the oldest message, which must be `seq`, once it has been
+    /// delivered.
+    pub fn ack(&mut self, seq: u64) -> Result<(), OutboxError> {
+        if self.front().map(|e| e.seq) != Some(seq) {
+            return Err(OutboxError::NotQueued);
+        }
+        let previous = self.queue.clone();
+        self.queue.entries.remove(0);
+        if let Err(e) = self.persist() {
+            self.queue = previous;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Drop the oldest messages until `count` more messages of `incoming`
+    /// bytes in total fit. Returns how many were dropped.
+    fn make_room(&mut self, count: usize, incoming: usize) -> usize {
+        let slots = self.capacity - count;
+        let mut bytes = self.bytes() + incoming;
+        let mut n = 0;
+        while n < self.queue.entries.len() && (self.queue.entries.len() - n > slots || bytes > self.max_bytes) {
+            bytes -= self.queue.entries[n].payload.len();
+            n += 1;
+        }
+        self.queue.entries.drain(..n);
+        self.queue.dropped += n as u64;
+        n
+    }
+
+    fn persist(&mut self) -> Result<(), OutboxError> {
+        self.store.save(&encode(&self.queue)).map_err(OutboxError::Storage)
+    }
+}
+
+/// `magic(4) | version(1) | next_seq(8) | dropped(8) | count(4) |
+///  (seq(8) | kind(1) | dest_len(1) | dest | payload_len(4) | payload) * count |
+///  checksum(4)`.
+fn encode(queue: &Queue) -> Vec<u8> {
+    let size: usize = queue.entries.iter().map(|e| 14 + e.destination.len() + e.payload.len()).sum();
+    let mut out = Vec::with_capacity(HEADER_LEN + size + 4);
+    out.extend_from_slice(&MAGIC);
+    out.push(FORMAT_VERSION);
+    out.extend_from_slice(&queue.next_seq.to_le_bytes());
+    out.extend_from_slice(&queue.dropped.to_le_bytes());
+    out.extend_from_slice(&(queue.entries.len() as u32).to_le_bytes());
+    for e in &queue.entries {
+        out.extend_from_slice(&e.seq.to_le_bytes());
+        out.push(e.kind);
+        out.push(e.destination.len() as u8);
+        out.extend_from_slice(e.destination.as_bytes());
+        out.extend_from_slice(&(e.payload.len() as u32).to_le_bytes());
+        out.extend_from_slice(&e.payload);
+    }
+    let sum = checksum(&out);
+    out.extend_from_slice(&sum.to_le_bytes());
+    out
+}
+
+fn decode(data: &[u8]) -> Result<Queue, OutboxError> {
+    if data.len() < HEADER_LEN + 4 || data[..4] != MAGIC || data[4] != FORMAT_VERSION {
+        return Err(OutboxError::Corrupt);
+    }
+    let (body, tail) = data.split_at(data.len() - 4);
+    if checksum(body).to_le_bytes() != tail {
+        return Err(OutboxError::Corrupt);
+    }
+    let u64_at = |at: usize| u64::from_le_bytes(body[at..at + 8].try_into().unwrap());
+    let next_seq = u64_at(5);
+    let dropped = u64_at(13);
+    let count = u32::from_le_bytes([body[21], body[22], body[23], body[24]]) as usize;
+
+    let mut rest = &body[HEADER_LEN..];
+    let mut entries = Vec::with_capacity(count.min(rest.len() / 14));
+    for _ in 0..count {
+        if rest.len() < 10 {
+            return Err(OutboxError::Corrupt);
+        }
+        let seq = u64::from_le_bytes(rest[..8].try_into().unwrap());
+        let kind = rest[8];
+        let dest_len = rest[9] as usize;
+        rest = &rest[10..];
+        if rest.len() < dest_len + 4 {
+            return Err(OutboxError::Corrupt);
+        }
+        let destination = core::str::from_utf8(&rest[..dest_len]).map_err(|_| OutboxError::Corrupt)?;
+        let payload_len = u32::from_le_bytes(rest[dest_len..dest_len + 4].try_into().unwrap()) as usize;
+        rest = &rest[dest_len + 4..];
+        if rest.len() < payload_len || entries.last().is_some_and(|p: &OutboxEntry| p.seq >= seq) || seq >= next_seq {
+            return Err(OutboxError::Corrupt);
+        }
+        entries.push(OutboxEntry { seq, kind, destination: destination.into(), payload: rest[..payload_len].to_vec() });
+        rest = &rest[payload_len..];
+    }
+    if !rest.is_empty() {
+        return Err(OutboxError::Corrupt);
+    }
+    Ok(Queue { next_seq, dropped, entries })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct RamStore {
+        data: Option<Vec<u8>>,
+        fail: bool,
+    }
+
+    impl OutboxStore for &mut RamStore {
+        fn load(&mut self) -> Result<Option<Vec<u8>>, &'static str> {
+            Ok(self.data.clone())
+        }
+        fn save(&mut self, data: &[u8]) -> Result<(), &'static str> {
+            if self.fail {
+                return Err("flash write failed");
+            }
+            self.data = Some(data.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn survives_reopen_in_order() {
+        let mut store = RamStore::default();
+        {
+            let mut outbox = Outbox::open(&mut store, 8, 1024).unwrap();
+            assert_eq!(outbox.push(0, "tele/temp", b"21.5").unwrap(), 0);
+            assert_eq!(outbox.push(1, "/sensors/hum", b"40").unwrap(), 1);
+            outbox.push(0, "tele/temp", b"21.6").unwrap();
+            assert_eq!(outbox.ack(1), Err(OutboxError::NotQueued));
+            outbox.ack(0).unwrap();
+        }
+        let mut outbox = Outbox::open(&mut store, 8, 1024).unwrap();
+        let queued: Vec<_> = outbox.entries().iter().map(|e| (e.seq, e.kind, e.destination.as_str())).collect();
+        assert_eq!(queued, [(1, 1, "/sensors/hum"), (2, 0, "tele/temp")]);
+        assert_eq!(outbox.push(0, "tele/temp", b"21.7").unwrap(), 3, "sequence continues after reboot");
+    }
+
+    #[test]
+    fn drops_oldest_when_full() {
+        let mut store = RamStore::default();
+        let mut outbox = Outbox::open(&mut store, 3, 10).unwrap();
+        for n in 0..4u8 {
+            outbox.push(0, "t", &[n; 2]).unwrap();
+        }
+        assert_eq!(outbox.entries().iter().map(|e| e.seq).collect::<Vec<_>>(), [1, 2, 3]);
+        // Byte bound: 6 held + 9 new > 10
+        outbox.push(0, "t", &[9; 9]).unwrap();
+        assert_eq!(outbox.entries().iter().map(|e| e.seq).collect::<Vec<_>>(), [4]);
+        assert_eq!(outbox.dropped(), 4);
+        assert_eq!(outbox.push(0, "t", &[0; 11]), Err(OutboxError::TooLarge));
+        assert_eq!(outbox.push(0, "", b"x"), Err(OutboxError::InvalidDestination));
+    }
+
+    #[test]
+    fn failed_save_rolls_back_and_corruption_is_reported() {
+        let mut store = RamStore::default();
+        let mut outbox = Outbox::open(&mut store, 4, 64).unwrap();
+        outbox.push(0, "t", b"a").unwrap();
+        outbox.store.fail = true;
+        assert_eq!(outbox.push(0, "t", b"b"), Err(OutboxError::Storage("flash write failed")));
+        assert_eq!(outbox.ack(0), Err(OutboxError::Storage("flash write failed")));
+        assert_eq!(outbox.len(), 1);
+        drop(outbox);
+
+        store.data.as_mut().unwrap()[30] ^= 1;
+        assert_eq!(Outbox::open(&mut store, 4, 64).err(), Some(OutboxError::Corrupt));
+    }
+}
@@ -0,0 +1,343 @@
+//! SecureIoTOS Replay-Protected Command Inbox Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+
+//! Replay protection for the actuator command channel.
+//!
+//! The inbox remembers a window of recently accepted command nonces/IDs and
+//! persists it to secure storage *before* a command is handed to the
+//! application, so a command captured on the wire cannot be replayed, not
+//! even after a reboot or a power cut right after execution.
+//!
+//! Two eviction policies are supported:
+//! - `Eviction::Oldest`: for random nonces. When full, the oldest entry is
+//!   forgotten.
+//! - `Eviction::LowestId`: for monotonically increasing command IDs. When
+//!   full, the smallest ID is evicted and becomes the new floor; anything at
+//!   or below the floor is rejected as too old.
+
+use alloc::vec::Vec;
+
+use sios_log::{error, warn};
+
+/// Magic prefix of the persisted window ("RPLY").
+const MAGIC: [u8; 4] = *b"RPLY";
+/// Encoding version of the persisted window.
+const FORMAT_VERSION: u8 = 1;
+
+/// How entries are evicted once the window is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Eviction {
+    Oldest = 0,
+    LowestId = 1,
+}
+
+/// Errors reported by the inbox.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ReplayError {
+    /// Nonce is already in the window
+    Replayed,
+    /// ID is at or below the eviction floor (`Eviction::LowestId` only)
+    TooOld,
+    /// Persisted window failed its integrity check or has the wrong format
+    Corrupt,
+    /// Persisted window was saved under a different eviction policy
+    PolicyMismatch,
+    /// Backing storage failed
+    Storage(&'static str),
+}
+
+/// Backing store for the persisted window, typically a dedicated encrypted
+/// sector in secure storage.
+pub trait ReplayStore {
+    /// Load the last saved window; `Ok(None)` if nothing was ever saved.
+    fn load(&mut self) -> Result<Option<Vec<u8>>, &'static str>;
+    /// Atomically replace the saved window.
+    fn save(&mut self, data: &[u8]) -> Result<(), &'static str>;
+}
+
+/// In-memory window of seen nonces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayWindow {
+    capacity: usize,
+    policy: Eviction,
+    /// Accepted nonces in insertion order
+    seen: Vec<u64>,
+    /// Highest evicted ID (`Eviction::LowestId`); nothing at or below is accepted
+    floor: Option<u64>,
+}
+
+impl ReplayWindow {
+    /// Create an empty window holding up to `capacity` nonces (at least 1).
+    pub fn new(capacity: usize, policy: Eviction) -> Self {
+        let capacity = capacity.max(1);
+        Self { capacity, policy, seen: Vec::with_capacity(capacity), floor: None }
+    }
+
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+
+    /// Check `nonce` without recording it.
+    pub fn check(&self, nonce: u64) -> Result<(), ReplayError> {
+        if let Some(floor) = self.floor {
+            if nonce <= floor {
+                return Err(ReplayError::TooOld);
+            }
+        }
+        if self.seen.contains(&nonce) {
+            return Err(ReplayError::Replayed);
+        }
+        Ok(())
+    }
+
+    /// Check and record `nonce`, evicting according to the policy if full.
+    pub fn accept(&mut self, nonce: u64) -> Result<(), ReplayError> {
+        self.check(nonce)?;
+        if self.seen.len() >= self.capacity {
+            match self.policy {
+                Eviction::Oldest => {
+                    self.seen.remove(0);
+                }
+                Eviction::LowestId => {
+                    // Window is non-empty here, so a minimum exists.
+                    let (idx, &lowest) = self
+                        .seen
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, &n)| n)
+                        .ok_or(ReplayError::Corrupt)?;
+                    self.seen.remove(idx);
+                    if lowest >= nonce {
+                        // The new ID would itself be the one evicted: reject.
+                        self.seen.insert(idx, lowest);
+                        return Err(ReplayError::TooOld);
+                    }
+                    self.floor = Some(self.floor.map_or(lowest, |f| f.max(lowest)));
+                }
+            }
+        }
+        self.seen.push(nonce);
+        Ok(())
+    }
+
+    /// Serialize for persistence:
+    /// `magic(4) | version(1) | policy(1) | has_floor(1) | floor(8) |
+    ///  count(4) | nonces(8 * count) | checksum(4)`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(23 + self.seen.len() * 8);
+        out.extend_from_slice(&MAGIC);
+        out.push(FORMAT_VERSION);
+        out.push(self.policy as u8);
+        out.push(self.floor.is_some() as u8);
+        out.extend_from_slice(&self.floor.unwrap_or(0).to_le_bytes());
+        out.extend_from_slice(&(self.seen.len() as u32).to_le_bytes());
+        for n in &self.seen {
+            out.extend_from_slice(&n.to_le_bytes());
+        }
+        let sum = checksum(&out);
+        out.extend_from_slice(&sum.to_le_bytes());
+        out
+    }
+
+    /// Restore a persisted window. `capacity` may differ from the one it was
+    /// saved with; when shrinking, entries are evicted per the policy.
+    /// `policy` must match the stored one: a window of random nonces has no
+    /// meaningful floor, and an ID window evicted oldest-first loses it.
+    pub fn from_bytes(data: &[u8], capacity: usize, policy: Eviction) -> Result<Self, ReplayError> {
+        if data.len() < 23 || data[..4] != MAGIC || data[4] != FORMAT_VERSION {
+            return Err(ReplayError::Corrupt);
+        }
+        let (body, tail) = data.split_at(data.len() - 4);
+        let stored = u32::from_le_bytes([tail[0], tail[1], tail[2], tail[3]]);
+        if checksum(body) != stored {
+            return Err(ReplayError::Corrupt);
+        }
+
+        let stored_policy = match body[5] {
+            0 => Eviction::Oldest,
+            1 => Eviction::LowestId,
+            _ => return Err(ReplayError::Corrupt),
+        };
+        if stored_policy != policy {
+            return Err(ReplayError::PolicyMismatch);
+        }
+        let mut floor_bytes = [0u8; 8];
+        floor_bytes.copy_from_slice(&body[7..15]);
+        let floor = (body[6] != 0).then(|| u64::from_le_bytes(floor_bytes));
+        let count = u32::from_le_bytes([body[15], body[16], body[17], body[18]]) as usize;
+        let nonces = &body[19..];
+        if Some(nonces.len()) != count.checked_mul(8) {
+            return Err(ReplayError::Corrupt);
+        }
+
+        let mut window = Self::new(capacity, policy);
+        window.floor = floor;
+        for chunk in nonces.chunks_exact(8) {
+            let mut b = [0u8; 8];
+            b.copy_from_slice(chunk);
+            // Replaying our own saved entries re-applies eviction if capacity shrank.
+            match window.accept(u64::from_le_bytes(b)) {
+                Ok(()) | Err(ReplayError::TooOld) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(window)
+    }
+}
+
+/// Command inbox: a replay window bound to its persistent store.
+pub struct CommandInbox<S: ReplayStore> {
+    window: ReplayWindow,
+    store: S,
+}
+
+impl<S: ReplayStore> CommandInbox<S> {
+    /// Open the inbox, restoring the window from `store` if one was saved.
+    ///
+    /// A corrupt window, or one saved under another policy, is reported
+    /// rather than silently reset: starting with an empty window would
+    /// re-open every previously seen command.
+    pub fn open(mut store: S, capacity: usize, policy: Eviction) -> Result<Self, ReplayError> {
+        let window = match store.load().map_err(ReplayError::Storage)? {
+            Some(bytes) => ReplayWindow::from_bytes(&bytes, capacity, policy).inspect_err(|e| {
+                error!("replay window not restored: {:?}", e);
+            })?,
+            None => ReplayWindow::new(capacity, policy),
+        };
+        Ok(Self { window, store })
+    }
+
+    /// Explicitly reset to an empty window (e.g. after re-keying the command
+    /// channel, which invalidates every old command anyway).
+    pub fn reset(&mut self) -> Result<(), ReplayError> {
+        self.window = ReplayWindow::new(self.window.capacity, self.window.policy);
+        self.store.save(&self.window.to_bytes()).map_err(ReplayError::Storage)
+    }
+
+    /// Accept a command with the given nonce/ID.
+    ///
+    /// The updated window is persisted before returning `Ok`, so the caller
+    /// must only execute the command after this succeeds. If persisting
+    /// fails the command is rejected and the in-memory window rolled back.
+    pub fn accept(&mut self, nonce: u64) -> Result<(), ReplayError> {
+        let previous = self.window.clone();
+        if let Err(e) = self.window.accept(nonce) {
+            warn!("command {} rejected: {:?}", nonce, e);
+            return Err(e);
+        }
+        if let Err(e) = self.store.save(&self.window.to_bytes()) {
+            error!("replay window not persisted: {}", e);
+            self.window = previous;
+            return Err(ReplayError::Storage(e));
+        }
+        Ok(())
+    }
+
+    pub fn window(&self) -> &ReplayWindow {
+        &self.window
+    }
+}
+
+/// FNV-1a 32-bit; detects torn writes and bit rot. Authenticity comes from
+/// the encrypted storage the window is written to.
+pub(crate) fn checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0x811c_9dc5u32, |h, &b| (h ^ b as u32).wrapping_mul(0x0100_0193))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct RamStore {
+        data: Option<Vec<u8>>,
+        fail: bool,
+    }
+
+    impl ReplayStore for &mut RamStore {
+        fn load(&mut self) -> Result<Option<Vec<u8>>, &'static str> {
+            Ok(self.data.clone())
+        }
+        fn save(&mut self, data: &[u8]) -> Result<(), &'static str> {
+            if self.fail {
+                return Err("flash write failed");
+            }
+            self.data = Some(data.to_vec());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn rejects_replay_across_reboot() {
+        let mut store = RamStore::default();
+        {
+            let mut inbox = CommandInbox::open(&mut store, 4, Eviction::Oldest).unwrap();
+            inbox.accept(0xAAAA).unwrap();
+            assert_eq!(inbox.accept(0xAAAA), Err(ReplayError::Replayed));
+        }
+        // "Reboot": reopen from the persisted bytes.
+        let mut inbox = CommandInbox::open(&mut store, 4, Eviction::Oldest).unwrap();
+        assert_eq!(inbox.accept(0xAAAA), Err(ReplayError::Replayed));
+        inbox.accept(0xBBBB).unwrap();
+    }
+
+    #[test]
+    fn lowest_id_eviction_raises_floor() {
+        let mut w = ReplayWindow::new(2, Eviction::LowestId);
+        w.accept(10).unwrap();
+        w.accept(12).unwrap();
+        w.accept(11).unwrap(); // evicts 10
+        assert_eq!(w.accept(10), Err(ReplayError::TooOld));
+        assert_eq!(w.accept(9), Err(ReplayError::TooOld));
+        assert_eq!(w.accept(12), Err(ReplayError::Replayed));
+    }
+
+    #[test]
+    fn storage_failure_rolls_back() {
+        let mut store = RamStore { fail: true, ..Default::default() };
+        let mut inbox = CommandInbox::open(&mut store, 4, Eviction::Oldest).unwrap();
+        assert!(matches!(inbox.accept(1), Err(ReplayError::Storage(_))));
+        assert!(inbox.window().is_empty());
+    }
+
+    #[test]
+    fn detects_corruption_and_shrinks_capacity() {
+        let mut w = ReplayWindow::new(4, Eviction::Oldest);
+        for n in 1..=4 {
+            w.accept(n).unwrap();
+        }
+        let mut bytes = w.to_bytes();
+
+        let shrunk = ReplayWindow::from_bytes(&bytes, 2, Eviction::Oldest).unwrap();
+        assert_eq!(shrunk.len(), 2);
+        assert_eq!(shrunk.check(4), Err(ReplayError::Replayed));
+
+        bytes[20] ^= 0xFF;
+        assert_eq!(ReplayWindow::from_bytes(&bytes, 4, Eviction::Oldest), Err(ReplayError::Corrupt));
+    }
+
+    #[test]
+    fn refuses_a_window_saved_under_another_policy() {
+        let mut store = RamStore::default();
+        CommandInbox::open(&mut store, 4, Eviction::Oldest).unwrap().accept(7).unwrap();
+        assert_eq!(CommandInbox::open(&mut store, 4, Eviction::LowestId).err(), Some(ReplayError::PolicyMismatch));
+
+        // A count whose byte length overflows is corrupt, not a panic
+        let mut bytes = ReplayWindow::new(4, Eviction::Oldest).to_bytes();
+        bytes[15..19].copy_from_slice(&u32::MAX.to_le_bytes());
+        let sum = checksum(&bytes[..19]);
+        bytes[19..].copy_from_slice(&sum.to_le_bytes());
+        assert_eq!(ReplayWindow::from_bytes(&bytes, 4, Eviction::Oldest), Err(ReplayError::Corrupt));
+    }
+}
@@ -0,0 +1,960 @@
+//! SecureIoTOS Firmware Update Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+
+//! A/B (dual-slot) firmware updates.
+//!
+//! The application area holds two slots. Each starts with a manifest page
+//! (`ImageManifest`: version, length, SHA-256 of the image, and the
+//! vendor's ECDSA P-256 signature over those) followed by the image. The
+//! manifest names which of the bootloader's vendor keys signed it and may
+//! revoke others (`vendor_keys`); revocations are burnt when the image is
+//! confirmed.
+//! Images run in place, so each build is linked for the slot it goes to;
+//! `Updater::target()` tells the update client which build to fetch.
+//!
+//! Which slot boots is decided by the `BootControl` record, kept in two
+//! flash pages written alternately so a reset mid-write leaves the
+//! previous copy in charge:
+//!
+//! 1. `Updater` writes a new image into the inactive slot through
+//!    `flash::RegionWriter` (decompressing it on the way if the server
+//!    sent it compressed), checks the signature before erasing anything
+//!    and the hash after the last byte, and only then writes the manifest
+//!    and marks the slot `Pending` with `BOOT_ATTEMPTS` tries.
+//! 2. The bootloader (`select_boot_slot()`) boots a pending slot while
+//!    tries are left, using one up per boot, and verifies every slot it
+//!    picks; a slot that fails verification is marked `Invalid`.
+//! 3. The new image calls `confirm_boot()` once it is up and healthy. It
+//!    becomes the active slot and its version the minimum any slot must
+//!    carry from then on (anti-rollback). If it resets before that more
+//!    than `BOOT_ATTEMPTS` times, the bootloader rolls back to the
+//!    previous slot.
+//!
+//! When neither slot boots, the bootloader's recovery mode takes an image
+//! over a local link and writes it with `Updater::recover()`, which only
+//! relaxes the "newer than the running image" rule.
+
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+
+use codec::compress::DefaultDecompressor;
+use crypto::hash::{self, Algorithm};
+use hal::bsp::{BspError, BspFlash};
+use sios_log::{error, info, warn};
+
+use crate::flash::RegionWriter;
+use crate::replay::checksum;
+use crate::vendor_keys::{self, VendorKeys};
+
+/// Bytes reserved for the manifest at the start of each slot; the image
+/// follows.
+pub const MANIFEST_LEN: usize = 256;
+/// Magic at the start of a slot manifest.
+pub const MANIFEST_MAGIC: [u8; 8] = *b"SIOSUPD\0";
+/// Longest DER encoded P-256 ECDSA signature.
+pub const MAX_SIGNATURE_LEN: usize = 72;
+/// Boots a new image gets to call `confirm_boot()` before it is rolled
+/// back.
+pub const BOOT_ATTEMPTS: u8 = 3;
+
+/// Manifest bytes covered by the signature: magic, version, length, hash,
+/// key ID, revocations.
+const SIGNED_LEN: usize = 8 + 4 + 4 + 32 + 4 + 4;
+/// Magic prefix of the boot control record ("BCTL").
+const CONTROL_MAGIC: [u8; 4] = *b"BCTL";
+/// Encoding version of the boot control record.
+const FORMAT_VERSION: u8 = 1;
+/// Encoded size of the boot control record.
+const CONTROL_LEN: usize = 4 + 4 + 4 + 4 + 2 * 8 + 4;
+/// Read buffer for hashing a slot.
+const HASH_CHUNK: usize = 256;
+
+/// One of the two application slots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Slot {
+    A = 0,
+    B = 1,
+}
+
+impl Slot {
+    pub fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Flash region of one slot: manifest, then image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlotRegion {
+    /// Start of the slot (page aligned)
+    pub base: u32,
+    /// Size of the slot including the manifest
+    pub len: usize,
+}
+
+impl SlotRegion {
+    /// Address of the image (its vector table).
+    pub fn image_base(&self) -> u32 {
+        self.base + MANIFEST_LEN as u32
+    }
+
+    /// Largest image the slot holds.
+    pub fn max_image_len(&self) -> usize {
+        self.len.saturating_sub(MANIFEST_LEN)
+    }
+}
+
+/// Where the slots and the boot control pages are. Pages are at least
+/// `MANIFEST_LEN` bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layout {
+    pub slots: [SlotRegion; 2],
+    /// The two pages the boot control record alternates between
+    pub control: [u32; 2],
+    /// Vendor key revocation area (`vendor_keys`), in a page never erased
+    pub revocations: u32,
+}
+
+impl Layout {
+    pub fn slot(&self, slot: Slot) -> &SlotRegion {
+        &self.slots[slot.index()]
+    }
+}
+
+/// Errors of the update subsystem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UpdateError {
+    /// Flash read, erase or write failed
+    Flash,
+    /// Manifest missing, erased or malformed
+    BadManifest,
+    /// The manifest is not signed by a known, unrevoked vendor key
+    BadSignature,
+    /// Version not newer than the running image, or below the minimum
+    Downgrade,
+    /// Image larger than the slot, or more data than announced
+    TooLarge,
+    /// `finish()` before the whole image was written
+    Incomplete,
+    /// The image in flash does not match the manifest hash
+    HashMismatch,
+    /// The running image is itself on trial; confirm it first
+    TrialInProgress,
+    /// Neither slot holds a bootable image
+    NoBootableSlot,
+    /// A compressed image does not decompress
+    BadCompression,
+}
+
+impl From<BspError> for UpdateError {
+    fn from(e: BspError) -> Self {
+        match e {
+            BspError::OutOfRange => UpdateError::TooLarge,
+            _ => UpdateError::Flash,
+        }
+    }
+}
+
+/// Signed description of a slot's image:
+///
+/// ```text
+/// magic "SIOSUPD\0" | version u32 | image_len u32 | image_hash [32] |
+/// key_id u8 | reserved [3] | revoke u32 | sig_len u16 | reserved u16 |
+/// signature (DER)
+/// ```
+///
+/// Integers little-endian; the signature is ECDSA P-256 / SHA-256 over
+/// everything up to and including `revoke`, by vendor key `key_id`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageManifest {
+    pub version: u32,
+    pub image_len: usize,
+    /// SHA-256 of the image
+    pub image_hash: [u8; 32],
+    /// Index of the vendor key that signed the manifest
+    pub key_id: u8,
+    /// Vendor keys to revoke once this image is confirmed
+    pub revoke: u32,
+    pub signature: Vec<u8>,
+}
+
+impl ImageManifest {
+    /// The bytes the signature covers.
+    pub fn signed_bytes(&self) -> [u8; SIGNED_LEN] {
+        let mut out = [0u8; SIGNED_LEN];
+        out[..8].copy_from_slice(&MANIFEST_MAGIC);
+        out[8..12].copy_from_slice(&self.version.to_le_bytes());
+        out[12..16].copy_from_slice(&(self.image_len as u32).to_le_bytes());
+        out[16..48].copy_from_slice(&self.image_hash);
+        out[48] = self.key_id;
+        out[52..56].copy_from_slice(&self.revoke.to_le_bytes());
+        out
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = self.signed_bytes().to_vec();
+        out.extend_from_slice(&(self.signature.len() as u16).to_le_bytes());
+        out.extend_from_slice(&[0, 0]);
+        out.extend_from_slice(&self.signature);
+        out
+    }
+
+    /// Parse a manifest; `None` if it is erased or malformed.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        if bytes.get(..8)? != MANIFEST_MAGIC {
+            return None;
+        }
+        let version = u32::from_le_bytes(bytes.get(8..12)?.try_into().ok()?);
+        let image_len = u32::from_le_bytes(bytes.get(12..16)?.try_into().ok()?) as usize;
+        let image_hash = bytes.get(16..48)?.try_into().ok()?;
+        let key_id = *bytes.get(48)?;
+        let revoke = u32::from_le_bytes(bytes.get(52..56)?.try_into().ok()?);
+        let sig_len = u16::from_le_bytes(bytes.get(56..58)?.try_into().ok()?) as usize;
+        if image_len == 0 || sig_len > MAX_SIGNATURE_LEN {
+            return None;
+        }
+        let signature = bytes.get(60..60 + sig_len)?.to_vec();
+        Some(Self { version, image_len, image_hash, key_id, revoke, signature })
+    }
+
+    /// Whether the unrevoked vendor key `key_id` signed this manifest. A
+    /// manifest revoking its own key is refused.
+    pub fn verify(&self, keys: &VendorKeys) -> bool {
+        let Some(key) = keys.key(self.key_id) else {
+            return false;
+        };
+        if u32::checked_shl(1, u32::from(self.key_id)).is_some_and(|bit| self.revoke & bit != 0) {
+            return false;
+        }
+        crypto::ecc::parse_signature(&self.signature, crypto::ecc::SignatureFormat::Der)
+            .and_then(|sig| crypto::ecc::verify(&key, &self.signed_bytes(), &sig))
+            .is_ok()
+    }
+}
+
+/// State of a slot in the boot control record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SlotStatus {
+    /// Nothing bootable (erased, being written, or superseded)
+    Empty = 0,
+    /// New image waiting for its trial boots
+    Pending = 1,
+    /// Booted and confirmed
+    Confirmed = 2,
+    /// Failed verification or was rolled back
+    Invalid = 3,
+}
+
+impl SlotStatus {
+    fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Self::Empty),
+            1 => Some(Self::Pending),
+            2 => Some(Self::Confirmed),
+            3 => Some(Self::Invalid),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SlotInfo {
+    pub status: SlotStatus,
+    pub version: u32,
+}
+
+/// Which slot to boot, shared by the bootloader and the application:
+///
+/// ```text
+/// magic "BCTL" | version u8 | active u8 | tries_left u8 | reserved u8 |
+/// seq u32 | min_version u32 | (status u8, reserved [3], version u32) x 2 |
+/// checksum u32
+/// ```
+///
+/// A store goes to control page `seq % 2`, so it never overwrites the
+/// copy it replaces; `load()` takes the valid copy with the highest `seq`.
+/// Without any valid copy (a device flashed by a programmer) slot A is
+/// taken to be confirmed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BootControl {
+    seq: u32,
+    active: Slot,
+    tries_left: u8,
+    min_version: u32,
+    slots: [SlotInfo; 2],
+}
+
+impl Default for BootControl {
+    fn default() -> Self {
+        Self {
+            seq: 0,
+            active: Slot::A,
+            tries_left: 0,
+            min_version: 0,
+            slots: [
+                SlotInfo { status: SlotStatus::Confirmed, version: 0 },
+                SlotInfo { status: SlotStatus::Empty, version: 0 },
+            ],
+        }
+    }
+}
+
+impl BootControl {
+    /// The confirmed slot, booted when no trial is due.
+    pub fn active(&self) -> Slot {
+        self.active
+    }
+
+    pub fn slot(&self, slot: Slot) -> SlotInfo {
+        self.slots[slot.index()]
+    }
+
+    /// Lowest image version any slot may boot.
+    pub fn min_version(&self) -> u32 {
+        self.min_version
+    }
+
+    /// Trial boots left for the pending slot.
+    pub fn tries_left(&self) -> u8 {
+        self.tries_left
+    }
+
+    /// The slot holding a new, unconfirmed image.
+    pub fn pending(&self) -> Option<Slot> {
+        [Slot::A, Slot::B].into_iter().find(|&s| self.slot(s).status == SlotStatus::Pending)
+    }
+
+    /// Whether the pending slot has been booted at least once, i.e. is
+    /// what is running now.
+    pub fn in_trial(&self) -> bool {
+        self.pending().is_some() && self.tries_left < BOOT_ATTEMPTS
+    }
+
+    /// Bootloader decision: the pending slot while it has tries left
+    /// (using one up), otherwise the active slot if it is confirmed. A
+    /// pending slot out of tries is rolled back. The caller stores the
+    /// record before starting the slot.
+    pub fn next_boot(&mut self) -> Option<Slot> {
+        if let Some(pending) = self.pending() {
+            if self.tries_left > 0 {
+                self.tries_left -= 1;
+                return Some(pending);
+            }
+            warn!("update in slot {:?} never confirmed, rolling back", pending);
+            self.slots[pending.index()].status = SlotStatus::Invalid;
+        }
+        (self.slot(self.active).status == SlotStatus::Confirmed).then_some(self.active)
+    }
+
+    /// Mark `slot` unbootable (it failed verification); if it was the
+    /// active slot and the other one is confirmed, that one takes over.
+    pub fn reject(&mut self, slot: Slot) {
+        if self.slot(slot).status == SlotStatus::Pending {
+            self.tries_left = 0;
+        }
+        self.slots[slot.index()].status = SlotStatus::Invalid;
+        if slot == self.active && self.slot(slot.other()).status == SlotStatus::Confirmed {
+            self.active = slot.other();
+        }
+    }
+
+    /// Confirm the image on trial: it becomes the active slot, its version
+    /// the new minimum, and the previous slot is no longer bootable.
+    /// Returns `false` if no trial is in progress.
+    pub fn confirm(&mut self) -> bool {
+        let Some(pending) = self.pending().filter(|_| self.in_trial()) else {
+            return false;
+        };
+        let previous = self.active;
+        self.slots[pending.index()].status = SlotStatus::Confirmed;
+        self.slots[previous.index()].status = SlotStatus::Empty;
+        self.active = pending;
+        self.min_version = self.slot(pending).version;
+        self.tries_left = 0;
+        true
+    }
+
+    pub fn to_bytes(&self) -> [u8; CONTROL_LEN] {
+        let mut out = [0u8; CONTROL_LEN];
+        out[..4].copy_from_slice(&CONTROL_MAGIC);
+        out[4] = FORMAT_VERSION;
+        out[5] = self.active as u8;
+        out[6] = self.tries_left;
+        out[8..12].copy_from_slice(&self.seq.to_le_bytes());
+        out[12..16].copy_from_slice(&self.min_version.to_le_bytes());
+        for (i, info) in self.slots.iter().enumerate() {
+            let at = 16 + i * 8;
+            out[at] = info.status as u8;
+            out[at + 4..at + 8].copy_from_slice(&info.version.to_le_bytes());
+        }
+        let sum = checksum(&out[..CONTROL_LEN - 4]);
+        out[CONTROL_LEN - 4..].copy_from_slice(&sum.to_le_bytes());
+        out
+    }
+
+    /// Decode a stored record; `None` if it is erased, torn or malformed.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        let data = data.get(..CONTROL_LEN)?;
+        let sum = u32::from_le_bytes(data[CONTROL_LEN - 4..].try_into().ok()?);
+        if data[..4] != CONTROL_MAGIC || data[4] != FORMAT_VERSION || checksum(&data[..CONTROL_LEN - 4]) != sum {
+            return None;
+        }
+        let active = match data[5] {
+            0 => Slot::A,
+            1 => Slot::B,
+            _ => return None,
+        };
+        let mut slots = [SlotInfo { status: SlotStatus::Empty, version: 0 }; 2];
+        for (i, info) in slots.iter_mut().enumerate() {
+            let at = 16 + i * 8;
+            info.status = SlotStatus::from_u8(data[at])?;
+            info.version = u32::from_le_bytes(data[at + 4..at + 8].try_into().ok()?);
+        }
+        Some(Self {
+            seq: u32::from_le_bytes(data[8..12].try_into().ok()?),
+            active,
+            tries_left: data[6],
+            min_version: u32::from_le_bytes(data[12..16].try_into().ok()?),
+            slots,
+        })
+    }
+
+    /// Read the newest valid copy from the control pages.
+    pub fn load<F: BspFlash>(flash: &mut F, layout: &Layout) -> Result<Self, UpdateError> {
+        let mut newest: Option<Self> = None;
+        for &page in &layout.control {
+            let mut buf = [0u8; CONTROL_LEN];
+            flash.read(page, &mut buf)?;
+            if let Some(copy) = Self::from_bytes(&buf) {
+                if newest.as_ref().is_none_or(|n| copy.seq > n.seq) {
+                    newest = Some(copy);
+                }
+            }
+        }
+        Ok(newest.unwrap_or_default())
+    }
+
+    /// Write the record as the next copy, to the page not holding the
+    /// current one.
+    pub fn store<F: BspFlash>(&mut self, flash: &mut F, layout: &Layout) -> Result<(), UpdateError> {
+        let mut next = self.clone();
+        next.seq = self.seq.wrapping_add(1);
+        let page = layout.control[(next.seq % 2) as usize];
+        let mut bytes = next.to_bytes().to_vec();
+        bytes.resize(CONTROL_LEN.next_multiple_of(F::WRITE_SIZE), 0xFF);
+        flash.erase_page(page)?;
+        flash.write(page, &bytes)?;
+        *self = next;
+        Ok(())
+    }
+}
+
+/// Check the image in `slot`: manifest signed by one of `keys`, version at
+/// least `min_version`, image matching the manifest hash.
+pub fn verify_slot<F: BspFlash>(
+    flash: &mut F,
+    layout: &Layout,
+    slot: Slot,
+    keys: &VendorKeys,
+    min_version: u32,
+) -> Result<ImageManifest, UpdateError> {
+    let region = layout.slot(slot);
+    let mut page = [0u8; MANIFEST_LEN];
+    flash.read(region.base, &mut page)?;
+    let manifest = ImageManifest::parse(&page).ok_or(UpdateError::BadManifest)?;
+    if manifest.image_len > region.max_image_len() {
+        return Err(UpdateError::BadManifest);
+    }
+    if !manifest.verify(keys) {
+        return Err(UpdateError::BadSignature);
+    }
+    if manifest.version < min_version {
+        return Err(UpdateError::Downgrade);
+    }
+    check_image_hash(flash, region, &manifest)?;
+    Ok(manifest)
+}
+
+fn check_image_hash<F: BspFlash>(
+    flash: &mut F,
+    region: &SlotRegion,
+    manifest: &ImageManifest,
+) -> Result<(), UpdateError> {
+    let mut chunk = [0u8; HASH_CHUNK];
+    let base = region.image_base();
+    let digest = hash::digest_reader(Algorithm::Sha256, manifest.image_len, &mut chunk, |offset, buf| {
+        flash.read(base + offset as u32, buf)
+    })?;
+    if !digest.matches(&manifest.image_hash) {
+        return Err(UpdateError::HashMismatch);
+    }
+    Ok(())
+}
+
+/// Bootloader side: pick the slot to start, persisting the boot counter
+/// and any rollback before returning, and verify it. Slots failing
+/// verification are rejected and the choice is made again.
+pub fn select_boot_slot<F: BspFlash>(
+    flash: &mut F,
+    layout: &Layout,
+    keys: &VendorKeys,
+) -> Result<(Slot, ImageManifest), UpdateError> {
+    let mut control = BootControl::load(flash, layout)?;
+    loop {
+        let before = control.clone();
+        let choice = control.next_boot();
+        if control != before {
+            control.store(flash, layout)?;
+        }
+        let slot = choice.ok_or(UpdateError::NoBootableSlot)?;
+        match verify_slot(flash, layout, slot, keys, control.min_version()) {
+            Ok(manifest) => {
+                // Again on every boot, in case the reset came between
+                // `confirm_boot()` storing the record and burning.
+                if control.slot(slot).status == SlotStatus::Confirmed {
+                    burn_revocations(flash, layout, &manifest)?;
+                }
+                return Ok((slot, manifest));
+            }
+            Err(UpdateError::Flash) => return Err(UpdateError::Flash),
+            Err(e) => {
+                error!("slot {:?} refused: {:?}", slot, e);
+                control.reject(slot);
+                control.store(flash, layout)?;
+            }
+        }
+    }
+}
+
+/// Application side: confirm the running image after a trial boot.
+/// Returns `false` (and writes nothing) if no trial is in progress.
+pub fn confirm_boot<F: BspFlash>(flash: &mut F, layout: &Layout) -> Result<bool, UpdateError> {
+    let mut control = BootControl::load(flash, layout)?;
+    if !control.confirm() {
+        return Ok(false);
+    }
+    control.store(flash, layout)?;
+    info!("image version {} confirmed", control.min_version());
+    let mut page = [0u8; MANIFEST_LEN];
+    flash.read(layout.slot(control.active()).base, &mut page)?;
+    if let Some(manifest) = ImageManifest::parse(&page) {
+        burn_revocations(flash, layout, &manifest)?;
+    }
+    Ok(true)
+}
+
+/// Burn the key revocations a confirmed image carries. Only confirmed
+/// images do, so a rollback can still reach the previous image.
+fn burn_revocations<F: BspFlash>(flash: &mut F, layout: &Layout, manifest: &ImageManifest) -> Result<(), UpdateError> {
+    if manifest.revoke != 0 {
+        vendor_keys::revoke(flash, layout.revocations, manifest.revoke)?;
+    }
+    Ok(())
+}
+
+/// Writes a new image into the inactive slot.
+pub struct Updater<'a, F: BspFlash> {
+    writer: RegionWriter<'a, F>,
+    layout: Layout,
+    control: BootControl,
+    target: Slot,
+    manifest: ImageManifest,
+    /// State of a compressed transfer, from its first piece
+    inflate: Option<Box<DefaultDecompressor>>,
+}
+
+impl<'a, F: BspFlash> Updater<'a, F> {
+    /// Start an update with the manifest received from the server. The
+    /// signature, version and size are checked before the target slot is
+    /// touched; the slot is then marked `Empty` and its first page erased.
+    pub fn begin(flash: &'a mut F, layout: &Layout, manifest: &[u8], keys: &VendorKeys) -> Result<Self, UpdateError> {
+        Self::start(flash, layout, manifest, keys, false)
+    }
+
+    /// As `begin()`, for the bootloader's recovery mode once no slot
+    /// boots: a trial in progress does not block it, and the image only
+    /// has to be at least the minimum version, so the release that was
+    /// running can be written back.
+    pub fn recover(flash: &'a mut F, layout: &Layout, manifest: &[u8], keys: &VendorKeys) -> Result<Self, UpdateError> {
+        Self::start(flash, layout, manifest, keys, true)
+    }
+
+    fn start(
+        flash: &'a mut F,
+        layout: &Layout,
+        manifest: &[u8],
+        keys: &VendorKeys,
+        recovery: bool,
+    ) -> Result<Self, UpdateError> {
+        let mut control = BootControl::load(flash, layout)?;
+        if control.in_trial() && !recovery {
+            return Err(UpdateError::TrialInProgress);
+        }
+        let manifest = ImageManifest::parse(manifest).ok_or(UpdateError::BadManifest)?;
+        let target = control.active().other();
+        let region = *layout.slot(target);
+        if manifest.image_len > region.max_image_len() {
+            return Err(UpdateError::TooLarge);
+        }
+        if !manifest.verify(keys) {
+            return Err(UpdateError::BadSignature);
+        }
+        let running = control.slot(control.active()).version;
+        if (manifest.version <= running && !recovery) || manifest.version < control.min_version() {
+            return Err(UpdateError::Downgrade);
+        }
+
+        // A slot being written is never bootable, even after a reset.
+        control.slots[target.index()] = SlotInfo { status: SlotStatus::Empty, version: 0 };
+        control.tries_left = 0;
+        control.store(flash, layout)?;
+        flash.erase_page(region.base)?;
+        let writer = RegionWriter::new(flash, region.image_base(), manifest.image_len)?;
+        info!("update to version {} into slot {:?}", manifest.version, target);
+        Ok(Self { writer, layout: *layout, control, target, manifest, inflate: None })
+    }
+
+    /// The slot being written (pick the build linked for it).
+    pub fn target(&self) -> Slot {
+        self.target
+    }
+
+    /// Bytes of the image written so far.
+    pub fn written(&self) -> usize {
+        self.writer.written()
+    }
+
+    /// Bytes of the image still to come; transfers padded past the end
+    /// (XMODEM) are cut here.
+    pub fn remaining(&self) -> usize {
+        self.manifest.image_len - self.writer.written()
+    }
+
+    /// Append the next piece of the image.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), UpdateError> {
+        Ok(self.writer.push(data)?)
+    }
+
+    /// Append the next piece of an image sent compressed with
+    /// `codec::compress` (default window); the manifest describes the
+    /// image after decompression. Do not mix with `write()` in one update.
+    pub fn write_compressed(&mut self, data: &[u8]) -> Result<(), UpdateError> {
+        let remaining = self.remaining();
+        let inflate = self.inflate.get_or_insert_with(Default::default);
+        let mut image = Vec::with_capacity(data.len() * 2);
+        let decoded = inflate.feed(data, |b| {
+            if image.len() < remaining {
+                image.push(b);
+            }
+        });
+        // Whatever follows the image is transfer padding (XMODEM)
+        if decoded.is_err() && image.len() < remaining {
+            return Err(UpdateError::BadCompression);
+        }
+        self.write(&image)
+    }
+
+    /// Check the written image against the manifest hash, write the
+    /// manifest and mark the slot pending. The new image runs from the
+    /// next reset.
+    pub fn finish(self) -> Result<Slot, UpdateError> {
+        let Self { writer, layout, mut control, target, manifest, .. } = self;
+        if writer.written() != manifest.image_len {
+            return Err(UpdateError::Incomplete);
+        }
+        let flash = writer.finish()?;
+        let region = layout.slot(target);
+        check_image_hash(flash, region, &manifest)?;
+
+        // The manifest goes last: without it the slot never verifies.
+        let mut bytes = manifest.to_bytes();
+        bytes.resize(bytes.len().next_multiple_of(F::WRITE_SIZE), 0xFF);
+        flash.write(region.base, &bytes)?;
+        control.slots[target.index()] = SlotInfo { status: SlotStatus::Pending, version: manifest.version };
+        control.tries_left = BOOT_ATTEMPTS;
+        control.store(flash, &layout)?;
+        info!("version {} staged in slot {:?}", manifest.version, target);
+        Ok(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crypto::ecc::{export_public_key, KeyPair, PublicKeyFormat};
+
+    const PAGE: usize = 1024;
+
+    /// NOR flash: erase sets 0xFF, programming only clears bits.
+    struct RamFlash(Vec<u8>);
+
+    impl BspFlash for RamFlash {
+        const PAGE_SIZE: usize = PAGE;
+        const WRITE_SIZE: usize = 4;
+
+        fn read(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), BspError> {
+            let at = addr as usize;
+            buf.copy_from_slice(self.0.get(at..at + buf.len()).ok_or(BspError::OutOfRange)?);
+            Ok(())
+        }
+        fn erase_page(&mut self, addr: u32) -> Result<(), BspError> {
+            assert_eq!(addr as usize % PAGE, 0);
+            self.0[addr as usize..addr as usize + PAGE].fill(0xFF);
+            Ok(())
+        }
+        fn write(&mut self, addr: u32, data: &[u8]) -> Result<(), BspError> {
+            assert_eq!((addr as usize % 4, data.len() % 4), (0, 0));
+            for (cell, &b) in self.0[addr as usize..].iter_mut().zip(data) {
+                assert_eq!(*cell, 0xFF, "programming unerased flash");
+                *cell = b;
+            }
+            Ok(())
+        }
+    }
+
+    const LAYOUT: Layout = Layout {
+        slots: [SlotRegion { base: 0x0800, len: 0x1C00 }, SlotRegion { base: 0x2400, len: 0x1C00 }],
+        control: [0x0000, 0x0400],
+        revocations: 0x4000,
+    };
+
+    fn vendor() -> KeyPair {
+        KeyPair::from_private_bytes(&[7; 32]).unwrap()
+    }
+
+    /// The second key of the ring.
+    fn backup() -> KeyPair {
+        KeyPair::from_private_bytes(&[9; 32]).unwrap()
+    }
+
+    fn ring() -> &'static [[u8; 65]] {
+        static RING: std::sync::OnceLock<[[u8; 65]; 2]> = std::sync::OnceLock::new();
+        RING.get_or_init(|| {
+            [vendor(), backup()]
+                .map(|k| export_public_key(&k.public_key(), PublicKeyFormat::Sec1Uncompressed).try_into().unwrap())
+        })
+    }
+
+    fn keys(flash: &mut RamFlash) -> VendorKeys<'static> {
+        VendorKeys::load(flash, LAYOUT.revocations, ring()).unwrap()
+    }
+
+    fn image(version: u32, len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i as u32 * 31 + version) as u8).collect()
+    }
+
+    fn manifest(signer: &KeyPair, version: u32, image: &[u8]) -> Vec<u8> {
+        signed_manifest(signer, 0, 0, version, image)
+    }
+
+    fn signed_manifest(signer: &KeyPair, key_id: u8, revoke: u32, version: u32, image: &[u8]) -> Vec<u8> {
+        let mut m = ImageManifest {
+            version,
+            image_len: image.len(),
+            image_hash: *hash::digest(Algorithm::Sha256, image).as_bytes().first_chunk().unwrap(),
+            key_id,
+            revoke,
+            signature: Vec::new(),
+        };
+        m.signature = signer.sign(&m.signed_bytes()).to_der().as_bytes().to_vec();
+        m.to_bytes()
+    }
+
+    /// A device as it leaves the programmer: version 1 in slot A, no
+    /// control record.
+    fn fresh_device() -> RamFlash {
+        let mut flash = RamFlash(vec![0xFF; 0x4400]);
+        let img = image(1, 3000);
+        let m = manifest(&vendor(), 1, &img);
+        flash.0[0x0800..0x0800 + m.len()].copy_from_slice(&m);
+        flash.0[0x0900..0x0900 + img.len()].copy_from_slice(&img);
+        flash
+    }
+
+    fn install(flash: &mut RamFlash, version: u32, img: &[u8]) -> Result<Slot, UpdateError> {
+        install_signed(flash, &manifest(&vendor(), version, img), img)
+    }
+
+    fn install_signed(flash: &mut RamFlash, manifest: &[u8], img: &[u8]) -> Result<Slot, UpdateError> {
+        let keys = keys(flash);
+        let mut updater = Updater::begin(flash, &LAYOUT, manifest, &keys)?;
+        for piece in img.chunks(333) {
+            updater.write(piece)?;
+        }
+        updater.finish()
+    }
+
+    fn boot(flash: &mut RamFlash) -> Result<(Slot, u32), UpdateError> {
+        let keys = keys(flash);
+        select_boot_slot(flash, &LAYOUT, &keys).map(|(slot, m)| (slot, m.version))
+    }
+
+    #[test]
+    fn update_is_tried_then_confirmed() {
+        let mut flash = fresh_device();
+        assert_eq!(boot(&mut flash), Ok((Slot::A, 1)));
+
+        assert_eq!(install(&mut flash, 2, &image(2, 5000)), Ok(Slot::B));
+        assert_eq!(boot(&mut flash), Ok((Slot::B, 2)));
+        let control = BootControl::load(&mut flash, &LAYOUT).unwrap();
+        assert_eq!((control.pending(), control.tries_left()), (Some(Slot::B), BOOT_ATTEMPTS - 1));
+        // No new update on top of an unconfirmed one.
+        assert_eq!(install(&mut flash, 3, &image(3, 100)), Err(UpdateError::TrialInProgress));
+
+        assert_eq!(confirm_boot(&mut flash, &LAYOUT), Ok(true));
+        assert_eq!(confirm_boot(&mut flash, &LAYOUT), Ok(false));
+        for _ in 0..5 {
+            assert_eq!(boot(&mut flash), Ok((Slot::B, 2)));
+        }
+        let control = BootControl::load(&mut flash, &LAYOUT).unwrap();
+        assert_eq!((control.active(), control.min_version()), (Slot::B, 2));
+
+        // Anti-rollback: an older signed image is refused.
+        assert_eq!(install(&mut flash, 1, &image(1, 3000)).err(), Some(UpdateError::Downgrade));
+        assert_eq!(install(&mut flash, 3, &image(3, 100)), Ok(Slot::A));
+    }
+
+    #[test]
+    fn unconfirmed_or_corrupt_update_rolls_back() {
+        let mut flash = fresh_device();
+        install(&mut flash, 2, &image(2, 5000)).unwrap();
+        for _ in 0..BOOT_ATTEMPTS {
+            assert_eq!(boot(&mut flash), Ok((Slot::B, 2)));
+        }
+        assert_eq!(boot(&mut flash), Ok((Slot::A, 1)));
+        assert_eq!(BootControl::load(&mut flash, &LAYOUT).unwrap().slot(Slot::B).status, SlotStatus::Invalid);
+
+        // A staged image damaged in flash is rejected on its first boot.
+        install(&mut flash, 3, &image(3, 5000)).unwrap();
+        flash.0[0x2400 + MANIFEST_LEN + 4000] ^= 0x01;
+        assert_eq!(boot(&mut flash), Ok((Slot::A, 1)));
+        assert_eq!(BootControl::load(&mut flash, &LAYOUT).unwrap().pending(), None);
+
+        // A torn control write falls back to the previous copy.
+        let before = BootControl::load(&mut flash, &LAYOUT).unwrap();
+        let mut next = before.clone();
+        next.store(&mut flash, &LAYOUT).unwrap();
+        let page = LAYOUT.control[(next.seq % 2) as usize] as usize;
+        flash.0[page + 12] ^= 0xFF;
+        assert_eq!(BootControl::load(&mut flash, &LAYOUT).unwrap(), before);
+    }
+
+    #[test]
+    fn bad_images_are_refused() {
+        let mut flash = fresh_device();
+        let img = image(2, 2000);
+        let key = keys(&mut flash);
+
+        let forged = manifest(&KeyPair::from_private_bytes(&[8; 32]).unwrap(), 2, &img);
+        assert_eq!(Updater::begin(&mut flash, &LAYOUT, &forged, &key).err(), Some(UpdateError::BadSignature));
+        let huge = manifest(&vendor(), 2, &image(2, 0x1C00));
+        assert_eq!(Updater::begin(&mut flash, &LAYOUT, &huge, &key).err(), Some(UpdateError::TooLarge));
+
+        let mut updater = Updater::begin(&mut flash, &LAYOUT, &manifest(&vendor(), 2, &img), &key).unwrap();
+        updater.write(&img[..1000]).unwrap();
+        assert_eq!(updater.finish(), Err(UpdateError::Incomplete));
+
+        let mut updater = Updater::begin(&mut flash, &LAYOUT, &manifest(&vendor(), 2, &img), &key).unwrap();
+        let mut tampered = img.clone();
+        tampered[10] ^= 0x80;
+        updater.write(&tampered).unwrap();
+        assert_eq!(updater.write(&[0]), Err(UpdateError::TooLarge));
+        assert_eq!(updater.finish(), Err(UpdateError::HashMismatch));
+
+        // None of it was ever bootable.
+        assert_eq!(boot(&mut flash), Ok((Slot::A, 1)));
+        assert_eq!(BootControl::load(&mut flash, &LAYOUT).unwrap().slot(Slot::B).status, SlotStatus::Empty);
+    }
+
+    #[test]
+    fn compressed_images_are_decompressed_into_the_slot() {
+        use codec::compress::DefaultCompressor;
+
+        let mut flash = fresh_device();
+        let img: Vec<u8> = (0..5000u32).map(|i| (i / 40) as u8).collect();
+        let mut packed = Vec::new();
+        let mut c = DefaultCompressor::new();
+        c.feed(&img, |b| packed.push(b));
+        c.finish(|b| packed.push(b));
+        assert!(packed.len() < img.len() / 2);
+        packed.extend_from_slice(&[0x1A; 64]); // XMODEM padding
+
+        let key = keys(&mut flash);
+        let mut updater = Updater::begin(&mut flash, &LAYOUT, &manifest(&vendor(), 2, &img), &key).unwrap();
+        for piece in packed.chunks(128) {
+            updater.write_compressed(piece).unwrap();
+        }
+        assert_eq!(updater.remaining(), 0);
+        assert_eq!(updater.finish(), Ok(Slot::B));
+        assert_eq!(boot(&mut flash), Ok((Slot::B, 2)));
+
+        // A back-reference before the start of the image
+        let mut flash = fresh_device();
+        let key = keys(&mut flash);
+        let mut updater = Updater::begin(&mut flash, &LAYOUT, &manifest(&vendor(), 2, &img), &key).unwrap();
+        assert_eq!(updater.write_compressed(&[0, 0, 0]), Err(UpdateError::BadCompression));
+    }
+
+    #[test]
+    fn recovery_rewrites_a_bricked_device() {
+        let mut flash = fresh_device();
+        let img = image(2, 5000);
+        install(&mut flash, 2, &img).unwrap();
+        boot(&mut flash).unwrap();
+        confirm_boot(&mut flash, &LAYOUT).unwrap();
+        // Version 2 goes bad in flash; version 1 is below the minimum.
+        flash.0[0x2400 + MANIFEST_LEN + 100] ^= 0x01;
+        assert_eq!(boot(&mut flash), Err(UpdateError::NoBootableSlot));
+
+        // The same release goes back in, through the usual checks.
+        let key = keys(&mut flash);
+        assert_eq!(install(&mut flash, 2, &img), Err(UpdateError::Downgrade));
+        let forged = manifest(&KeyPair::from_private_bytes(&[8; 32]).unwrap(), 2, &img);
+        assert_eq!(Updater::recover(&mut flash, &LAYOUT, &forged, &key).err(), Some(UpdateError::BadSignature));
+        let old = manifest(&vendor(), 1, &image(1, 3000));
+        assert_eq!(Updater::recover(&mut flash, &LAYOUT, &old, &key).err(), Some(UpdateError::Downgrade));
+        let mut updater = Updater::recover(&mut flash, &LAYOUT, &manifest(&vendor(), 2, &img), &key).unwrap();
+        updater.write(&img).unwrap();
+        assert_eq!(updater.finish(), Ok(Slot::A));
+        assert_eq!(boot(&mut flash), Ok((Slot::A, 2)));
+        assert_eq!(confirm_boot(&mut flash, &LAYOUT), Ok(true));
+    }
+
+    #[test]
+    fn leaked_key_is_revoked_by_a_confirmed_update() {
+        let mut flash = fresh_device();
+        let img = image(2, 2000);
+        // No manifest may revoke the key that signed it.
+        let suicidal = signed_manifest(&backup(), 1, 0b10, 2, &img);
+        assert_eq!(install_signed(&mut flash, &suicidal, &img), Err(UpdateError::BadSignature));
+        // Key 0 leaked: version 2, signed with key 1, revokes it.
+        assert_eq!(install_signed(&mut flash, &signed_manifest(&backup(), 1, 0b01, 2, &img), &img), Ok(Slot::B));
+        assert_eq!(boot(&mut flash), Ok((Slot::B, 2)));
+        // Not burnt before the image is confirmed, so a rollback still works.
+        assert_eq!(keys(&mut flash).revoked(), 0);
+        assert_eq!(confirm_boot(&mut flash, &LAYOUT), Ok(true));
+        assert_eq!(keys(&mut flash).revoked(), 0b01);
+        assert_eq!(boot(&mut flash), Ok((Slot::B, 2)));
+
+        assert_eq!(install(&mut flash, 3, &image(3, 100)), Err(UpdateError::BadSignature));
+        let img = image(3, 100);
+        assert_eq!(install_signed(&mut flash, &signed_manifest(&backup(), 1, 0, 3, &img), &img), Ok(Slot::A));
+        // The area is only ever programmed, and burning again is harmless.
+        assert_eq!(vendor_keys::revoke(&mut flash, LAYOUT.revocations, 0b01), Ok(0b01));
+    }
+}
@@ -0,0 +1,105 @@
+//! SecureIoTOS Vendor Key Revocation Module
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author: Md Mahbubur Rahman
+//! URL: https://m-a-h-b-u-b.github.io
+//! GitHub: https://github.com/m-a-h-b-u-b/SecureIoTOS
+
+//! Vendor signing keys embedded in the bootloader, and their revocation.
+//!
+//! The bootloader carries a small ring of vendor public keys; a slot
+//! manifest names the key that signed it by index (`key_id`). Should one
+//! signing key leak, an image signed with another key revokes it by
+//! setting its bit in the manifest's `revoke` mask. Once that image is
+//! confirmed the bits are burnt into the revocation area, and images
+//! signed with the revoked key no longer verify, without the bootloader
+//! being re-flashed.
+//!
+//! The revocation area emulates OTP in ordinary flash: one `WRITE_SIZE`
+//! marker per key, erased (0xFF) while the key is valid and programmed to
+//! zero to revoke it. It sits in a page nothing ever erases, so a bit
+//! once burnt stays burnt; on flash with ECC every marker is still written
+//! only once. A manifest may not revoke the key that signed it, so at
+//! least one key always remains.
+
+use hal::bsp::{BspError, BspFlash};
+use p256::ecdsa::VerifyingKey;
+use sios_log::warn;
+
+/// Most keys a ring can hold (bits of the revocation mask).
+pub const MAX_VENDOR_KEYS: usize = 32;
+/// Length of an uncompressed SEC1 P-256 public key.
+pub const VENDOR_KEY_LEN: usize = 65;
+/// Largest flash write unit the revocation area supports.
+const MAX_MARKER_LEN: usize = 32;
+
+/// The bootloader's vendor keys, with the revocations read from flash.
+#[derive(Debug, Clone, Copy)]
+pub struct VendorKeys<'a> {
+    keys: &'a [[u8; VENDOR_KEY_LEN]],
+    revoked: u32,
+}
+
+impl<'a> VendorKeys<'a> {
+    /// Key ring `keys` (uncompressed SEC1, index = `key_id`) with the keys
+    /// in `revoked` unusable. Keys past `MAX_VENDOR_KEYS` are ignored.
+    pub fn new(keys: &'a [[u8; VENDOR_KEY_LEN]], revoked: u32) -> Self {
+        Self { keys: &keys[..keys.len().min(MAX_VENDOR_KEYS)], revoked }
+    }
+
+    /// As `new()`, with the revocations burnt into the area at `area`.
+    pub fn load<F: BspFlash>(flash: &mut F, area: u32, keys: &'a [[u8; VENDOR_KEY_LEN]]) -> Result<Self, BspError> {
+        Ok(Self::new(keys, read_revocations(flash, area)?))
+    }
+
+    /// The key with index `key_id`; `None` if there is none, it is
+    /// revoked, or it is not a valid P-256 point (a placeholder).
+    pub fn key(&self, key_id: u8) -> Option<VerifyingKey> {
+        if self.is_revoked(key_id) {
+            return None;
+        }
+        crypto::ecc::import_public_key(self.keys.get(usize::from(key_id))?).ok()
+    }
+
+    pub fn is_revoked(&self, key_id: u8) -> bool {
+        u32::checked_shl(1, u32::from(key_id)).is_none_or(|bit| self.revoked & bit != 0)
+    }
+
+    /// Mask of the revoked keys.
+    pub fn revoked(&self) -> u32 {
+        self.revoked
+    }
+}
+
+/// Read the revocation mask from the area at `area`: bit `i` is set if
+/// key `i`'s marker has been programmed.
+pub fn read_revocations<F: BspFlash>(flash: &mut F, area: u32) -> Result<u32, BspError> {
+    let mut revoked = 0u32;
+    let mut marker = [0u8; MAX_MARKER_LEN];
+    let marker = marker.get_mut(..F::WRITE_SIZE).ok_or(BspError::OutOfRange)?;
+    for i in 0..MAX_VENDOR_KEYS {
+        flash.read(area + (i * F::WRITE_SIZE) as u32, marker)?;
+        if marker.iter().any(|&b| b != 0xFF) {
+            revoked |= 1 << i;
+        }
+    }
+    Ok(revoked)
+}
+
+/// Burn the keys in `mask` into the revocation area at `area`. Keys
+/// already revoked are left alone, so this can be repeated. The area
+/// takes `MAX_VENDOR_KEYS * WRITE_SIZE` bytes.
+///
+/// # Returns
+/// * `Ok(revoked)`: the full revocation mask afterwards
+pub fn revoke<F: BspFlash>(flash: &mut F, area: u32, mask: u32) -> Result<u32, BspError> {
+    let revoked = read_revocations(flash, area)?;
+    let zeros = [0u8; MAX_MARKER_LEN];
+    let zeros = zeros.get(..F::WRITE_SIZE).ok_or(BspError::OutOfRange)?;
+    for i in (0..MAX_VENDOR_KEYS).filter(|&i| mask & !revoked & (1 << i) != 0) {
+        warn!("revoking vendor key {}", i);
+        flash.write(area + (i * F::WRITE_SIZE) as u32, zeros)?;
+    }
+    Ok(revoked | mask)
+}
@@ -8,83 +8,232 @@
 
 //! Simple circular wear-leveling manager implemented in RAM metadata.
 //! Production systems should persist metadata and handle power-fail atomicity.
+//!
+//! `WearLevel` runs over any `FlashDriver` (`crate::flash_driver`): the
+//! STM32 internal flash, a W25Q NOR chip, or flash simulated in RAM. The
+//! free functions below drive the default instance, which uses the RAM
+//! simulation.
+
+use alloc::vec;
+use alloc::vec::Vec;
 
 use core::cell::RefCell;
 use cortex_m::interrupt::Mutex;
 
+use crate::flash_driver::{FlashDriver, RamFlash};
+
 // Number of physical sectors used for the logical storage
 const NUM_SECTORS: usize = 4;
 const SECTOR_SIZE: usize = 4096; // example sector size (bytes)
 
-static mut PHYSICAL_FLASH: [[u8; SECTOR_SIZE]; NUM_SECTORS] = [[0u8; SECTOR_SIZE]; NUM_SECTORS];
+/// Backend of the default instance.
+pub type SimulatedFlash = RamFlash<SECTOR_SIZE, NUM_SECTORS>;
 
-// runtime metadata (would normally live in reserved flash area)
-static ACTIVE_SECTOR: Mutex<RefCell<usize>> = Mutex::new(RefCell::new(0));
+static WEAR_LEVEL: Mutex<RefCell<WearLevel<SimulatedFlash>>> =
+    Mutex::new(RefCell::new(WearLevel::new(SimulatedFlash::new())));
 
-/// Initialize the wear-leveling metadata
-pub fn init_wear_level() {
-    cortex_m::interrupt::free(|cs| {
-        *ACTIVE_SECTOR.borrow(cs).borrow_mut() = 0;
-    });
+/// Rotates writes over the sectors of a flash driver.
+pub struct WearLevel<D: FlashDriver> {
+    driver: D,
+    // runtime metadata (would normally live in reserved flash area)
+    active: usize,
 }
 
-/// Get the next physical sector index to write (circular)
-pub fn get_next_sector_index() -> usize {
-    cortex_m::interrupt::free(|cs| {
-        let mut idx = ACTIVE_SECTOR.borrow(cs).borrow().clone();
-        idx = (idx + 1) % NUM_SECTORS;
-        idx
-    })
-}
+impl<D: FlashDriver> WearLevel<D> {
+    pub const fn new(driver: D) -> Self {
+        Self { driver, active: 0 }
+    }
 
-/// Write a ciphertext into the specified sector (simulated)
-pub fn write_sector(sector_idx: usize, data: &[u8]) -> Result<(), &'static str> {
-    if sector_idx >= NUM_SECTORS || data.len() > SECTOR_SIZE {
-        return Err("invalid sector or oversize data");
+    /// Forget the write history: sector 0 is active again.
+    pub fn reset(&mut self) {
+        self.set_active(0);
+    }
+
+    /// Update the active sector index. The only legitimate writer, so the
+    /// write is exempt from the watchpoint (`watch_active()`).
+    fn set_active(&mut self, idx: usize) {
+        let addr = core::ptr::addr_of!(self.active) as usize;
+        hal::watch::expected_write(addr, || self.active = idx);
+    }
+
+    /// Report writes to the active sector index other than through this
+    /// type (see `hal::watch`). Returns `false` if no watchpoint could be
+    /// armed.
+    pub fn watch_active(&self) -> bool {
+        hal::watch::watch_var("active_sector", &self.active).is_some()
+    }
+
+    /// Sector the next write goes to (circular)
+    pub fn next_sector_index(&self) -> usize {
+        (self.active + 1) % self.driver.geometry().sector_count
+    }
+
+    /// Active sector index (last written)
+    pub fn active_sector_index(&self) -> usize {
+        self.active
     }
 
-    // Simulate flash erase+program (in real hardware: erase then program)
-    unsafe {
-        let sector = &mut PHYSICAL_FLASH[sector_idx];
-        for i in 0..data.len() {
-            sector[i] = data[i];
+    /// Erase sector `sector_idx`, program `data` into it and make it the
+    /// active sector. Data shorter than the sector leaves the rest erased.
+    pub fn write_sector(&mut self, sector_idx: usize, data: &[u8]) -> Result<(), &'static str> {
+        let geometry = self.driver.geometry();
+        if sector_idx >= geometry.sector_count || data.len() > geometry.sector_size {
+            return Err("invalid sector or oversize data");
         }
+
+        let offset = geometry.sector_offset(sector_idx).map_err(|_| "invalid sector")?;
+        self.driver.erase_sector(sector_idx).map_err(|_| "flash erase failed")?;
+        self.driver.program_padded(offset, data).map_err(|_| "flash program failed")?;
+
+        self.set_active(sector_idx);
+        Ok(())
+    }
+
+    /// Erase sector `sector_idx` without changing the active sector.
+    pub fn erase_sector(&mut self, sector_idx: usize) -> Result<(), &'static str> {
+        if sector_idx >= self.driver.geometry().sector_count {
+            return Err("invalid sector");
+        }
+        self.driver.erase_sector(sector_idx).map_err(|_| "flash erase failed")
     }
 
-    // Mark sector as active (atomic in this example via interrupt-free)
-    cortex_m::interrupt::free(|cs| {
-        *ACTIVE_SECTOR.borrow(cs).borrow_mut() = sector_idx;
-    });
+    /// Read sector `sector_idx` back and check that every byte is erased.
+    pub fn is_erased(&mut self, sector_idx: usize) -> Result<bool, &'static str> {
+        let geometry = self.driver.geometry();
+        let offset = geometry.sector_offset(sector_idx).map_err(|_| "invalid sector")?;
+        let mut chunk = [0u8; 256];
+        for at in (0..geometry.sector_size).step_by(chunk.len()) {
+            let n = chunk.len().min(geometry.sector_size - at);
+            self.driver.read(offset + at as u32, &mut chunk[..n]).map_err(|_| "flash read failed")?;
+            if chunk[..n].iter().any(|&b| b != 0xFF) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Read the start of sector `sector_idx` into `buf`, which may be at
+    /// most a sector long.
+    pub fn read_sector(&mut self, sector_idx: usize, buf: &mut [u8]) -> Result<(), &'static str> {
+        let geometry = self.driver.geometry();
+        if buf.len() > geometry.sector_size {
+            return Err("oversize read");
+        }
+        let offset = geometry.sector_offset(sector_idx).map_err(|_| "invalid sector")?;
+        self.driver.read(offset, buf).map_err(|_| "flash read failed")
+    }
+
+    /// The flash underneath.
+    pub fn driver(&mut self) -> &mut D {
+        &mut self.driver
+    }
+}
+
+/// Initialize the wear-leveling metadata
+pub fn init_wear_level() {
+    cortex_m::interrupt::free(|cs| WEAR_LEVEL.borrow(cs).borrow_mut().reset());
+}
 
-    Ok(())
+/// Report writes to the active sector index other than by the wear-leveling
+/// code (see `hal::watch`). Returns `false` if no watchpoint could be armed.
+pub fn watch_active_sector() -> bool {
+    cortex_m::interrupt::free(|cs| WEAR_LEVEL.borrow(cs).borrow().watch_active())
+}
+
+/// Number of sectors the wear-leveling rotates over
+pub fn get_sector_count() -> usize {
+    NUM_SECTORS
+}
+
+/// Get the next physical sector index to write (circular)
+pub fn get_next_sector_index() -> usize {
+    cortex_m::interrupt::free(|cs| WEAR_LEVEL.borrow(cs).borrow().next_sector_index())
+}
+
+/// Write a ciphertext into the specified sector
+pub fn write_sector(sector_idx: usize, data: &[u8]) -> Result<(), &'static str> {
+    cortex_m::interrupt::free(|cs| WEAR_LEVEL.borrow(cs).borrow_mut().write_sector(sector_idx, data))
 }
 
 /// Read the specified sector (returns a Vec of the sector content)
 pub fn read_sector(sector_idx: usize) -> Result<Vec<u8>, &'static str> {
-    if sector_idx >= NUM_SECTORS { return Err("invalid sector"); }
-
-    let mut buf = Vec::with_capacity(SECTOR_SIZE);
-    unsafe {
-        let sector = &PHYSICAL_FLASH[sector_idx];
-        for &b in sector.iter() {
-            buf.push(b);
-        }
-    }
+    let mut buf = vec![0u8; SECTOR_SIZE];
+    cortex_m::interrupt::free(|cs| WEAR_LEVEL.borrow(cs).borrow_mut().read_sector(sector_idx, &mut buf))?;
     Ok(buf)
 }
 
+/// Erase the specified sector
+pub fn erase_sector(sector_idx: usize) -> Result<(), &'static str> {
+    cortex_m::interrupt::free(|cs| WEAR_LEVEL.borrow(cs).borrow_mut().erase_sector(sector_idx))
+}
+
+/// Check that the specified sector reads back fully erased
+pub fn is_sector_erased(sector_idx: usize) -> Result<bool, &'static str> {
+    cortex_m::interrupt::free(|cs| WEAR_LEVEL.borrow(cs).borrow_mut().is_erased(sector_idx))
+}
+
 /// Return the active sector index (last written)
 pub fn get_active_sector_index() -> usize {
-    cortex_m::interrupt::free(|cs| {
-        *ACTIVE_SECTOR.borrow(cs).borrow()
-    })
+    cortex_m::interrupt::free(|cs| WEAR_LEVEL.borrow(cs).borrow().active_sector_index())
 }
 
 /// Derive a per-sector IV from the sector index (simple deterministic method)
-/// In production, prefer a random IV stored alongside the ciphertext or derived via secure KDF.
+/// Only sectors from before authenticated records use it; new records carry
+/// their own nonce (see `flash::seal_sector`).
 pub fn derive_iv_for_sector(sector_idx: usize) -> [u8; 16] {
     let mut iv = [0u8; 16];
     iv[0] = sector_idx as u8;
     // Fill rest with fixed or better: RNG-derived nonce saved with sector
     iv
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash_driver::{FlashError, Geometry};
+
+    /// Flash whose erase silently leaves one byte programmed.
+    struct StuckBit(RamFlash<512, 2>);
+
+    impl FlashDriver for StuckBit {
+        fn geometry(&self) -> Geometry {
+            self.0.geometry()
+        }
+
+        fn read(&mut self, offset: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+            self.0.read(offset, buf)
+        }
+
+        fn erase_sector(&mut self, index: usize) -> Result<(), FlashError> {
+            self.0.erase_sector(index)?;
+            self.0.program(index as u32 * 512 + 300, &[0x7F])
+        }
+
+        fn program(&mut self, offset: u32, data: &[u8]) -> Result<(), FlashError> {
+            self.0.program(offset, data)
+        }
+    }
+
+    #[test]
+    fn writes_rotate_and_erase_is_verified() {
+        let mut wl = WearLevel::new(RamFlash::<512, 2>::with_program_unit(8));
+        assert_eq!(wl.next_sector_index(), 1);
+        wl.write_sector(1, b"secret").unwrap();
+        assert_eq!(wl.active_sector_index(), 1);
+        assert_eq!(wl.next_sector_index(), 0);
+        assert!(!wl.is_erased(1).unwrap());
+
+        wl.erase_sector(1).unwrap();
+        assert!(wl.is_erased(1).unwrap());
+        assert_eq!(wl.erase_sector(2), Err("invalid sector"));
+    }
+
+    #[test]
+    fn erase_that_leaves_data_behind_is_detected() {
+        let mut wl = WearLevel::new(StuckBit(RamFlash::new()));
+        wl.write_sector(0, &[0u8; 512]).unwrap();
+        wl.erase_sector(0).unwrap();
+        assert!(!wl.is_erased(0).unwrap());
+        assert!(wl.is_erased(1).unwrap());
+    }
+}
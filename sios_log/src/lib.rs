@@ -0,0 +1,153 @@
+//! SecureIoTOS Logging Module
+//! --------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! One logging front end for every SecureIoTOS crate.
+//!
+//! `error!` / `warn!` / `info!` / `debug!` / `trace!` forward to the
+//! backend selected by feature:
+//!
+//! - `defmt`: format strings are interned into the ELF and only a small
+//!   ID plus the binary-encoded arguments leave the device, so a log call
+//!   costs a few bytes of flash and no formatting code.
+//! - `log`: the `log` facade (host tools and std crates).
+//! - neither: calls compile to nothing.
+//!
+//! `defmt` wins if both are enabled. A crate exposes the choice as its own
+//! `defmt` feature, e.g. `defmt = ["dep:defmt", "sios_log/defmt"]`, which
+//! also enables `defmt` on every dependency that logs, so the types those
+//! dependencies log derive `defmt::Format` too. The log
+//! macros go through sios_log's re-export, so they also work in crates
+//! without a `defmt` dependency that get the backend through feature
+//! unification; `defmt` itself is only needed to derive `defmt::Format`.
+//!
+//! Module prefix: messages do not repeat their origin. Both backends
+//! record the call site's module path (`net::capture`, `ipc::wait`, ...),
+//! which the host side prints in front of the message:
+//! `defmt-print --log-format "{t} {L} {m}: {s}"` for defmt, the record
+//! target for `log`.
+//!
+//! Format strings must stick to the subset both backends accept: `{}` and
+//! `{:?}` without width/precision. Arguments must implement
+//! `defmt::Format` when `defmt` is enabled; wrap foreign types in
+//! [`Disp`] / [`Dbg`] to fall back to their `core::fmt` implementation.
+//!
+//! Keys, tokens and credentials are held in [`Secret`], which every
+//! backend prints as `[REDACTED]`.
+
+#![no_std]
+
+use core::fmt;
+
+pub mod secret;
+pub use secret::Secret;
+
+#[cfg(feature = "defmt")]
+#[doc(hidden)]
+pub use defmt;
+#[cfg(feature = "log")]
+#[doc(hidden)]
+pub use log;
+
+#[cfg(feature = "defmt")]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log {
+    ($level:ident, $($arg:tt)+) => {{
+        // defmt's macros expand to `defmt::...` paths; resolve them to our
+        // re-export so callers need no `defmt` dependency of their own.
+        use $crate::defmt;
+        defmt::$level!($($arg)+)
+    }};
+}
+
+#[cfg(all(feature = "log", not(feature = "defmt")))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log {
+    ($level:ident, $($arg:tt)+) => {
+        $crate::log::$level!($($arg)+)
+    };
+}
+
+#[cfg(not(any(feature = "defmt", feature = "log")))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log {
+    // Type-check the arguments, emit nothing.
+    ($level:ident, $($arg:tt)+) => {
+        if false {
+            let _ = ::core::format_args!($($arg)+);
+        }
+    };
+}
+
+/// Log at error level.
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)+) => { $crate::__log!(error, $($arg)+) };
+}
+
+/// Log at warning level.
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)+) => { $crate::__log!(warn, $($arg)+) };
+}
+
+/// Log at info level.
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)+) => { $crate::__log!(info, $($arg)+) };
+}
+
+/// Log at debug level.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)+) => { $crate::__log!(debug, $($arg)+) };
+}
+
+/// Log at trace level.
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)+) => { $crate::__log!(trace, $($arg)+) };
+}
+
+/// Log a value through its `Display` implementation (`{}`).
+///
+/// Under defmt this formats on the device, so keep it for host-side
+/// crates and for types that cannot implement `defmt::Format`.
+pub struct Disp<T>(pub T);
+
+impl<T: fmt::Display> fmt::Display for Disp<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<T: fmt::Display> defmt::Format for Disp<T> {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "{}", defmt::Display2Format(&self.0))
+    }
+}
+
+/// Log a value through its `Debug` implementation (`{:?}`); see [`Disp`].
+pub struct Dbg<T>(pub T);
+
+impl<T: fmt::Debug> fmt::Debug for Dbg<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<T: fmt::Debug> defmt::Format for Dbg<T> {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "{}", defmt::Debug2Format(&self.0))
+    }
+}
@@ -0,0 +1,103 @@
+//! SecureIoTOS Logging Secret Module
+//! ---------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! `Secret<T>` marks keys, tokens and credentials. Its `Debug`, `Display`
+//! and `defmt::Format` output is always `[REDACTED]`, so a secret that
+//! ends up in a log call, a `{:?}` of a containing struct or a panic
+//! message prints nothing of its value.
+//!
+//! The value is only reachable through `expose_secret()` /
+//! `expose_secret_mut()`, which keeps every use greppable in review.
+//! `Secret` is deliberately not `Copy`; copies are explicit `clone()`s.
+//! With the `zeroize` feature it implements `Zeroize` for wiping in place.
+
+use core::fmt;
+
+const REDACTED: &str = "[REDACTED]";
+
+/// A value that must never be logged.
+#[derive(Clone, Default)]
+pub struct Secret<T>(T);
+
+impl<T> Secret<T> {
+    pub const fn new(value: T) -> Self {
+        Self(value)
+    }
+
+    /// Borrow the secret value.
+    pub fn expose_secret(&self) -> &T {
+        &self.0
+    }
+
+    /// Mutably borrow the secret value.
+    pub fn expose_secret_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T> From<T> for Secret<T> {
+    fn from(value: T) -> Self {
+        Self(value)
+    }
+}
+
+impl<T> fmt::Debug for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+impl<T> fmt::Display for Secret<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<T> defmt::Format for Secret<T> {
+    fn format(&self, f: defmt::Formatter<'_>) {
+        defmt::write!(f, "[REDACTED]")
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl<T: zeroize::Zeroize> zeroize::Zeroize for Secret<T> {
+    fn zeroize(&mut self) {
+        self.0.zeroize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::*;
+    use std::format;
+
+    #[derive(Debug)]
+    #[allow(dead_code)]
+    struct Credentials {
+        user: &'static str,
+        token: Secret<[u8; 4]>,
+    }
+
+    #[test]
+    fn every_formatter_redacts() {
+        let s = Secret::new(*b"key!");
+        assert_eq!(format!("{}", s), REDACTED);
+        assert_eq!(format!("{:?}", s), REDACTED);
+        assert_eq!(format!("{:#?}", s), REDACTED);
+
+        let c = Credentials { user: "dev", token: s.clone() };
+        let dbg = format!("{:?}", c);
+        assert!(dbg.contains("dev") && dbg.contains(REDACTED));
+        assert!(!dbg.contains("107"), "no byte of the key leaks: {dbg}");
+        assert_eq!(s.expose_secret(), b"key!");
+    }
+}
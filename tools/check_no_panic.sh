@@ -0,0 +1,41 @@
+#!/usr/bin/env bash
+#
+# SecureIoTOS – No-Panic Link Check
+#
+# Builds tools/no-panic-check with LTO. The build fails at link time if any
+# panic path is reachable from the core kernel paths it exercises
+# (scheduler, IPC queues, syscall dispatch, MPU audit).
+#
+# License : Dual License
+#           - Apache 2.0 for open-source / personal use
+#           - Commercial license required for closed-source use
+# Author  : Md Mahbubur Rahman
+# Project : https://m-a-h-b-u-b.github.io
+# GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+#
+
+set -euo pipefail
+
+SCRIPT_DIR="$(cd "$(dirname "${BASH_SOURCE[0]}")" && pwd)"
+TARGET="${TARGET:-thumbv7em-none-eabihf}"
+
+log() { echo -e "[no-panic] $*" >&2; }
+
+cd "${SCRIPT_DIR}/no-panic-check"
+
+log "Building no-panic check for ${TARGET} (release, LTO)..."
+if cargo build --release --target "${TARGET}" 2>build.log; then
+  rm -f build.log
+  log "OK: no reachable panic in core kernel paths."
+  exit 0
+fi
+
+if grep -q "__secureiotos_panic_path_reachable" build.log; then
+  log "FAIL: a panic path is reachable from a core kernel path."
+  log "Inspect callers of rust_begin_unwind (e.g. cargo bloat / map file)."
+else
+  log "FAIL: build error (see below)."
+fi
+cat build.log >&2
+rm -f build.log
+exit 1
@@ -0,0 +1,226 @@
+//! SecureIoTOS Crash Symbolication Tool
+//! ------------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Host side of `kernel::crash`: decode an uploaded crash dump and resolve
+//! its addresses against the firmware ELF.
+//!
+//! ```text
+//! crash-symbolicate <firmware.elf> <dump>
+//! ```
+//!
+//! `<dump>` is the dump as received from the telemetry backend, either raw
+//! bytes or hex text. The ELF must be the one the device was running (with
+//! debug info for file:line); the build ID in its `.image_header` (see
+//! `kernel::image`) is checked against the dump's and a mismatch is
+//! reported, since symbols from another build are misleading.
+//!
+//! Output: the fault reason and task, decoded CFSR/HFSR bits, PC and LR
+//! with function and source line, and every stack word that points into
+//! code (most likely return addresses, which give a rough backtrace).
+
+use std::process::ExitCode;
+
+use addr2line::Loader;
+use kernel::crash::{CrashDump, BUILD_ID_LEN};
+use kernel::image::{ImageHeader, HEADER_MAGIC};
+use object::{Object, ObjectSection};
+
+/// Names of the CFSR bits (MMFSR, BFSR, UFSR), bit position first.
+const CFSR_BITS: &[(u32, &str)] = &[
+    (0, "IACCVIOL: instruction access violation"),
+    (1, "DACCVIOL: data access violation"),
+    (3, "MUNSTKERR: MemManage fault on exception return"),
+    (4, "MSTKERR: MemManage fault on exception entry"),
+    (5, "MLSPERR: MemManage fault during FP lazy state preservation"),
+    (7, "MMARVALID: MMFAR holds the faulting address"),
+    (8, "IBUSERR: instruction bus error"),
+    (9, "PRECISERR: precise data bus error"),
+    (10, "IMPRECISERR: imprecise data bus error"),
+    (11, "UNSTKERR: bus fault on exception return"),
+    (12, "STKERR: bus fault on exception entry"),
+    (13, "LSPERR: bus fault during FP lazy state preservation"),
+    (15, "BFARVALID: BFAR holds the faulting address"),
+    (16, "UNDEFINSTR: undefined instruction"),
+    (17, "INVSTATE: invalid EPSR state (Thumb bit clear?)"),
+    (18, "INVPC: invalid EXC_RETURN"),
+    (19, "NOCP: coprocessor access (FPU disabled?)"),
+    (20, "STKOF: stack limit (PSPLIM/MSPLIM) violation"),
+    (24, "UNALIGNED: unaligned access"),
+    (25, "DIVBYZERO: division by zero"),
+];
+
+const HFSR_BITS: &[(u32, &str)] = &[
+    (1, "VECTTBL: vector table read fault"),
+    (30, "FORCED: escalated configurable fault"),
+    (31, "DEBUGEVT: debug event"),
+];
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    let [_, elf, dump] = args.as_slice() else {
+        eprintln!("usage: crash-symbolicate <firmware.elf> <dump>");
+        return ExitCode::from(2);
+    };
+    match run(elf, dump) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("crash-symbolicate: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(elf_path: &str, dump_path: &str) -> Result<(), String> {
+    let raw = std::fs::read(dump_path).map_err(|e| format!("{dump_path}: {e}"))?;
+    let bytes = parse_hex(&raw).unwrap_or(raw);
+    let dump = CrashDump::decode(&bytes).map_err(|e| format!("{dump_path}: not a valid crash dump ({e:?})"))?;
+
+    let elf = std::fs::read(elf_path).map_err(|e| format!("{elf_path}: {e}"))?;
+    let file = object::File::parse(&*elf).map_err(|e| format!("{elf_path}: {e}"))?;
+    let loader = Loader::new(elf_path).map_err(|e| format!("{elf_path}: {e}"))?;
+
+    println!("reason   : {:?}", dump.reason);
+    match dump.task {
+        Some(id) => println!("task     : {id}"),
+        None => println!("task     : none (handler or boot code)"),
+    }
+    println!("build id : {}", hex(&dump.build_id));
+    match image_build_id(&file) {
+        Some(id) if id == dump.build_id => {}
+        Some(id) => println!("WARNING  : ELF build id is {}; symbols below may be wrong", hex(&id)),
+        None => println!("WARNING  : ELF has no image header; cannot check it matches the dump"),
+    }
+
+    println!();
+    let f = &dump.frame;
+    println!("pc   {:#010x}  {}", f.pc, describe(&loader, &file, f.pc));
+    println!("lr   {:#010x}  {}", f.lr, describe(&loader, &file, f.lr));
+    println!("sp   {:#010x}", dump.sp);
+    println!("r0   {:#010x}  r1 {:#010x}  r2 {:#010x}  r3 {:#010x}", f.r0, f.r1, f.r2, f.r3);
+    println!("r12  {:#010x}  xpsr {:#010x}", f.r12, f.xpsr);
+
+    let s = &dump.status;
+    println!();
+    println!("cfsr {:#010x}", s.cfsr);
+    for name in bits(s.cfsr, CFSR_BITS) {
+        println!("       {name}");
+    }
+    if s.cfsr & (1 << 7) != 0 {
+        println!("mmfar {:#010x}", s.mmfar);
+    }
+    if s.cfsr & (1 << 15) != 0 {
+        println!("bfar {:#010x}", s.bfar);
+    }
+    println!("hfsr {:#010x}", s.hfsr);
+    for name in bits(s.hfsr, HFSR_BITS) {
+        println!("       {name}");
+    }
+
+    println!();
+    println!("stack ({} words from sp):", dump.stack().len());
+    for (i, &w) in dump.stack().iter().enumerate() {
+        if in_code(&file, w) {
+            println!("  sp+{:#04x}  {:#010x}  {}", i * 4, w, describe(&loader, &file, w));
+        } else {
+            println!("  sp+{:#04x}  {:#010x}", i * 4, w);
+        }
+    }
+    Ok(())
+}
+
+/// Build ID from the ELF's `.image_header` section.
+fn image_build_id(file: &object::File) -> Option<[u8; BUILD_ID_LEN]> {
+    let data = file.section_by_name(".image_header")?.data().ok()?;
+    header_build_id(data)
+}
+
+/// Build ID of a serialized `ImageHeader`.
+fn header_build_id(header: &[u8]) -> Option<[u8; BUILD_ID_LEN]> {
+    if header.len() < ImageHeader::SIZE || !header.starts_with(&HEADER_MAGIC) {
+        return None;
+    }
+    let at = core::mem::offset_of!(ImageHeader, build_id);
+    header.get(at..at + BUILD_ID_LEN)?.try_into().ok()
+}
+
+/// `function (file:line)` for a code address, `?` if it is not in the ELF.
+fn describe(loader: &Loader, file: &object::File, addr: u32) -> String {
+    if addr & 0xFFFF_FF00 == 0xFFFF_FF00 {
+        return "EXC_RETURN".to_string();
+    }
+    if !in_code(file, addr) {
+        return "?".to_string();
+    }
+    // Clear the Thumb bit of return addresses.
+    let probe = u64::from(addr & !1);
+    let func = loader.find_symbol(probe).map(demangle).unwrap_or_else(|| "?".to_string());
+    match loader.find_location(probe).ok().flatten() {
+        Some(loc) => format!("{func} ({}:{})", loc.file.unwrap_or("?"), loc.line.unwrap_or(0)),
+        None => func,
+    }
+}
+
+/// Whether `addr` lies in an executable section of the ELF.
+fn in_code(file: &object::File, addr: u32) -> bool {
+    let addr = u64::from(addr & !1);
+    file.sections().any(|s| {
+        s.kind() == object::SectionKind::Text && (s.address()..s.address() + s.size()).contains(&addr)
+    })
+}
+
+fn demangle(name: &str) -> String {
+    addr2line::demangle_auto(name.into(), None).into_owned()
+}
+
+fn bits(value: u32, names: &'static [(u32, &'static str)]) -> impl Iterator<Item = &'static str> {
+    names.iter().filter(move |(bit, _)| value & (1 << bit) != 0).map(|(_, name)| *name)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Decode hex text (whitespace ignored); `None` if `raw` is not hex.
+fn parse_hex(raw: &[u8]) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = raw.iter().copied().filter(|b| !b.is_ascii_whitespace()).collect();
+    if digits.is_empty() || !digits.len().is_multiple_of(2) {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_hex_dumps_and_names_fault_bits() {
+        assert_eq!(parse_hex(b"53 43\n4"), None, "odd digit count");
+        assert_eq!(parse_hex(b"5343 4401\n"), Some(vec![0x53, 0x43, 0x44, 0x01]));
+        assert_eq!(parse_hex(b"SCD\x01"), None, "raw dump");
+
+        let names: Vec<_> = bits(0x0000_0082, CFSR_BITS).collect();
+        assert_eq!(names.len(), 2);
+        assert!(names[0].starts_with("DACCVIOL"));
+        assert!(names[1].starts_with("MMARVALID"));
+    }
+
+    #[test]
+    fn reads_build_id_from_image_header() {
+        let header = &kernel::image::IMAGE_HEADER;
+        // SAFETY: `ImageHeader` is `repr(C)` without padding.
+        let bytes = unsafe { std::slice::from_raw_parts((header as *const ImageHeader).cast::<u8>(), ImageHeader::SIZE) };
+        assert_eq!(header_build_id(bytes), Some(kernel::image::BUILD_ID));
+        assert_eq!(header_build_id(&bytes[..20]), None, "truncated");
+        assert_eq!(header_build_id(&[0; ImageHeader::SIZE]), None, "no magic");
+    }
+}
@@ -0,0 +1,85 @@
+//! SecureIoTOS No-Panic Link Check
+//! -------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Link-time proof that the core kernel paths cannot panic.
+//!
+//! The `#[panic_handler]` below calls a symbol that is defined nowhere.
+//! After LTO, the linker only needs that symbol if some panic path is
+//! still reachable from `_start`, so:
+//!
+//! - the image links → no panic is reachable from the exercised paths;
+//! - link fails with `undefined symbol: __secureiotos_panic_path_reachable`
+//!   → one of them can still panic. Build with `debug = true` and look at
+//!   the caller of `rust_begin_unwind` in the map file to find it.
+//!
+//! The clippy gates in each core module catch most panics at review time;
+//! this check also catches the ones hidden behind arithmetic overflow,
+//! slice bounds in dependencies and `RefCell` borrows.
+//!
+//! Run via `tools/check_no_panic.sh`. The binary is never flashed.
+
+#![no_std]
+#![no_main]
+
+use core::hint::black_box;
+use core::panic::PanicInfo;
+
+use ipc::{IpcMessage, MessageQueue, MpmcQueue};
+
+extern "C" {
+    /// Intentionally undefined; see the module docs.
+    fn __secureiotos_panic_path_reachable() -> !;
+}
+
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    // SAFETY: never actually called; only its presence at link time matters.
+    unsafe { __secureiotos_panic_path_reachable() }
+}
+
+static SPSC: MessageQueue<8, 32> = MessageQueue::new();
+static MPMC: MpmcQueue<8, 32> = MpmcQueue::new();
+
+/// Entry point that pulls every checked path into the link.
+/// Inputs go through `black_box` so nothing is const-folded away.
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    loop {
+        // Syscall dispatch with arbitrary register contents
+        let r = kernel::syscall::syscall_entry(
+            black_box(0),
+            black_box(0),
+            black_box(0),
+            black_box(0),
+            black_box(0),
+            black_box(0),
+            black_box(0),
+        );
+        black_box(r);
+
+        // IPC queues
+        let msg = black_box(IpcMessage::<32>::new());
+        let _ = SPSC.enqueue(msg);
+        black_box(SPSC.dequeue());
+        let _ = MPMC.enqueue(msg);
+        let _ = MPMC.enqueue_from_isr(msg);
+        black_box(MPMC.dequeue());
+        black_box(MPMC.dequeue_from_isr());
+
+        // Scheduler
+        // SAFETY: not executed on hardware; linked for analysis only.
+        black_box(unsafe { kernel::scheduler::do_context_switch(black_box(core::ptr::null_mut())) }).ok();
+
+        // MPU W^X audit
+        let regions = black_box(memory::mpu::REGIONS);
+        black_box(memory::mpu::audit_regions(
+            regions.iter().map(|r| (r.number, r.base, memory::mpu::rasr_for(r))),
+        ));
+    }
+}
@@ -0,0 +1,248 @@
+//! SecureIoTOS Manifest Signing Tool
+//! ---------------------------------
+//! License : Dual License
+//!           - Apache 2.0 for open-source / personal use
+//!           - Commercial license required for closed-source use
+//! Author  : Md Mahbubur Rahman
+//! URL     : https://m-a-h-b-u-b.github.io
+//! GitHub  : https://github.com/m-a-h-b-u-b/SecureIoTOS
+//!
+//! Host side of the `manifest` crate: the bootloader parses exactly what
+//! this writes, since both use the same code.
+//!
+//! ```text
+//! sign-manifest tbs <version> <app.bin> <manifest.tbs>
+//! sign-manifest assemble <page_len> <manifest.tbs> <manifest.sig> <app.bin> <app.signed.bin>
+//! ```
+//!
+//! `tbs` writes the signed part of the manifest for `app.bin` at
+//! `version`; flags and build ID come from the image header (see
+//! `kernel::image`), if the image has one. The vendor key never touches
+//! this tool: sign `manifest.tbs` with it (ECDSA P-256, SHA-256, DER), e.g.
+//! `openssl dgst -sha256 -sign vendor.pem`, or with an HSM.
+//!
+//! `assemble` appends that signature, pads the manifest with 0xFF to
+//! `page_len` bytes and writes it followed by the image. The result is
+//! parsed back and checked against the image before it is written.
+//!
+//! ```text
+//! sign-manifest slot-tbs <version> <key_id> <revoke> <app.bin> <manifest.tbs>
+//! sign-manifest slot-assemble <manifest.tbs> <manifest.sig> <app.bin> <slot.bin>
+//! ```
+//!
+//! The same two steps for an A/B update slot (`secure_storage::update`):
+//! `slot-tbs` writes the signed part of the slot manifest, naming the
+//! vendor key `key_id` that will sign it and the keys `revoke` (a bit
+//! mask) retires; `slot-assemble` makes the slot image as it sits in
+//! flash and as recovery mode takes it: the manifest, padded with 0xFF to
+//! `update::MANIFEST_LEN`, then the image.
+
+use std::process::ExitCode;
+
+use crypto::hash::{digest, Algorithm};
+use kernel::image::{ImageHeader, HEADER_MAGIC};
+use manifest::{Manifest, ManifestBuilder, BUILD_ID_LEN};
+use secure_storage::update::{ImageManifest, MANIFEST_LEN};
+
+/// The image header is searched for within this many bytes of the image
+/// start, on word boundaries (as `bootloader/src/firmware.rs`).
+const IMAGE_HEADER_SEARCH: usize = 4 * 1024;
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    let res = match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        [_, "tbs", version, image, out] => tbs(version, image, out),
+        [_, "assemble", page_len, tbs, sig, image, out] => assemble(page_len, tbs, sig, image, out),
+        [_, "slot-tbs", version, key_id, revoke, image, out] => slot_tbs(version, key_id, revoke, image, out),
+        [_, "slot-assemble", tbs, sig, image, out] => slot_assemble(tbs, sig, image, out),
+        _ => {
+            eprintln!("usage: sign-manifest tbs <version> <app.bin> <manifest.tbs>");
+            eprintln!("       sign-manifest assemble <page_len> <manifest.tbs> <manifest.sig> <app.bin> <out>");
+            eprintln!("       sign-manifest slot-tbs <version> <key_id> <revoke> <app.bin> <manifest.tbs>");
+            eprintln!("       sign-manifest slot-assemble <manifest.tbs> <manifest.sig> <app.bin> <slot.bin>");
+            return ExitCode::from(2);
+        }
+    };
+    match res {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("sign-manifest: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn read(path: &str) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|e| format!("{path}: {e}"))
+}
+
+fn write(path: &str, data: &[u8]) -> Result<(), String> {
+    std::fs::write(path, data).map_err(|e| format!("{path}: {e}"))
+}
+
+fn tbs(version: &str, image_path: &str, out_path: &str) -> Result<(), String> {
+    let version = version.parse::<u32>().map_err(|e| format!("version {version}: {e}"))?;
+    let image = read(image_path)?;
+    if u32::try_from(image.len()).is_err() {
+        return Err(format!("{image_path}: too large"));
+    }
+    let mut builder = ManifestBuilder::new(version, &image);
+    match image_header(&image) {
+        Some((flags, build_id)) => builder = builder.flags(flags).build_id(build_id),
+        None => eprintln!("sign-manifest: {image_path}: no image header, signing without flags or build ID"),
+    }
+    let mut page = [0xFF; u16::MAX as usize];
+    let signed_len = builder.write_unsigned(&mut page).map_err(|e| format!("{e:?}"))?;
+    write(out_path, &page[..signed_len])
+}
+
+fn assemble(page_len: &str, tbs_path: &str, sig_path: &str, image_path: &str, out_path: &str) -> Result<(), String> {
+    let page_len = page_len.parse::<usize>().map_err(|e| format!("page length {page_len}: {e}"))?;
+    let tbs = read(tbs_path)?;
+    let sig = read(sig_path)?;
+    let image = read(image_path)?;
+
+    let mut page = vec![0xFF; page_len];
+    page.get_mut(..tbs.len())
+        .ok_or_else(|| format!("{tbs_path}: does not fit a {page_len}-byte manifest"))?
+        .copy_from_slice(&tbs);
+    ManifestBuilder::append_signature(&mut page, tbs.len(), &sig).map_err(|e| format!("{sig_path}: {e:?}"))?;
+
+    let manifest = Manifest::parse(&page).map_err(|e| format!("{tbs_path}: not a manifest ({e:?})"))?;
+    // Rebuilt from the image, the signed part must come out the same
+    let mut check = vec![0xFF; tbs.len()];
+    let mut expected = ManifestBuilder::new(manifest.version, &image).flags(manifest.flags);
+    if let Some(id) = manifest.build_id {
+        expected = expected.build_id(id);
+    }
+    if expected.write_unsigned(&mut check).ok() != Some(tbs.len()) || check != tbs {
+        return Err(format!("{image_path}: not the image {tbs_path} was made for"));
+    }
+
+    page.extend_from_slice(&image);
+    write(out_path, &page)?;
+    println!("signed {} bytes -> {out_path}", image.len());
+    Ok(())
+}
+
+/// The unsigned slot manifest for `image`.
+fn slot_manifest(version: u32, key_id: u8, revoke: u32, image: &[u8]) -> ImageManifest {
+    let mut image_hash = [0u8; 32];
+    image_hash.copy_from_slice(digest(Algorithm::Sha256, image).as_bytes());
+    ImageManifest { version, image_len: image.len(), image_hash, key_id, revoke, signature: Vec::new() }
+}
+
+fn slot_tbs(version: &str, key_id: &str, revoke: &str, image_path: &str, out_path: &str) -> Result<(), String> {
+    let version = version.parse::<u32>().map_err(|e| format!("version {version}: {e}"))?;
+    let key_id = key_id.parse::<u8>().map_err(|e| format!("key ID {key_id}: {e}"))?;
+    let revoke = parse_mask(revoke)?;
+    let image = read(image_path)?;
+    if image.is_empty() || u32::try_from(image.len()).is_err() {
+        return Err(format!("{image_path}: empty or too large"));
+    }
+    write(out_path, &slot_manifest(version, key_id, revoke, &image).signed_bytes())
+}
+
+fn slot_assemble(tbs_path: &str, sig_path: &str, image_path: &str, out_path: &str) -> Result<(), String> {
+    let tbs = read(tbs_path)?;
+    let sig = read(sig_path)?;
+    let image = read(image_path)?;
+
+    let mut page = tbs.clone();
+    page.extend_from_slice(&u16::try_from(sig.len()).map_err(|_| format!("{sig_path}: too long"))?.to_le_bytes());
+    page.extend_from_slice(&[0, 0]);
+    page.extend_from_slice(&sig);
+    let manifest = ImageManifest::parse(&page).ok_or_else(|| format!("{tbs_path}: not a slot manifest, or {sig_path} too long"))?;
+    // Rebuilt from the image, the signed part must come out the same
+    let expected = slot_manifest(manifest.version, manifest.key_id, manifest.revoke, &image);
+    if expected.signed_bytes()[..] != tbs[..] {
+        return Err(format!("{image_path}: not the image {tbs_path} was made for"));
+    }
+    if page.len() > MANIFEST_LEN {
+        return Err(format!("{sig_path}: does not fit a {MANIFEST_LEN}-byte manifest"));
+    }
+
+    page.resize(MANIFEST_LEN, 0xFF);
+    page.extend_from_slice(&image);
+    write(out_path, &page)?;
+    println!("signed slot image of {} bytes with key {} -> {out_path}", image.len(), manifest.key_id);
+    Ok(())
+}
+
+/// A key mask, decimal or `0x` hex.
+fn parse_mask(text: &str) -> Result<u32, String> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => text.parse::<u32>(),
+    }
+    .map_err(|e| format!("key mask {text}: {e}"))
+}
+
+/// Flags and build ID from the image header, if `image` has one.
+fn image_header(image: &[u8]) -> Option<(u32, [u8; BUILD_ID_LEN])> {
+    let search = &image[..image.len().min(IMAGE_HEADER_SEARCH)];
+    let start = (0..search.len()).step_by(4).find(|&i| search[i..].starts_with(&HEADER_MAGIC))?;
+    let header = image.get(start..start + ImageHeader::SIZE)?;
+    let flags = core::mem::offset_of!(ImageHeader, flags);
+    let build_id = core::mem::offset_of!(ImageHeader, build_id);
+    Some((
+        u32::from_le_bytes(header[flags..flags + 4].try_into().ok()?),
+        header[build_id..build_id + BUILD_ID_LEN].try_into().ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kernel::image::IMAGE_HEADER;
+
+    #[test]
+    fn image_header_is_found_on_a_word_boundary() {
+        // SAFETY: `ImageHeader` is `repr(C)` without padding.
+        let header = unsafe {
+            std::slice::from_raw_parts((&IMAGE_HEADER as *const ImageHeader).cast::<u8>(), ImageHeader::SIZE)
+        };
+        let mut image = vec![0u8; 64];
+        image.extend_from_slice(header);
+        assert_eq!(image_header(&image), Some((IMAGE_HEADER.flags, IMAGE_HEADER.build_id)));
+
+        let mut unaligned = vec![0u8; 2];
+        unaligned.extend_from_slice(header);
+        assert_eq!(image_header(&unaligned), None);
+        assert_eq!(image_header(&image[..64 + ImageHeader::SIZE - 1]), None, "cut short");
+    }
+
+    #[test]
+    fn slot_image_verifies_against_the_signing_key() {
+        use crypto::ecc::{export_public_key, KeyPair, PublicKeyFormat};
+        use secure_storage::vendor_keys::VendorKeys;
+
+        let dir = std::env::temp_dir().join(format!("sign-manifest-slot-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_owned();
+        let image: Vec<u8> = (0..3000u32).map(|i| (i * 7) as u8).collect();
+        write(&path("app.bin"), &image).unwrap();
+
+        slot_tbs("4", "1", "0x1", &path("app.bin"), &path("tbs")).unwrap();
+        let signer = KeyPair::from_private_bytes(&[5; 32]).unwrap();
+        let sig = signer.sign(&read(&path("tbs")).unwrap()).to_der().as_bytes().to_vec();
+        write(&path("sig"), &sig).unwrap();
+        slot_assemble(&path("tbs"), &path("sig"), &path("app.bin"), &path("slot.bin")).unwrap();
+        write(&path("other.bin"), &image[1..]).unwrap();
+        assert!(slot_assemble(&path("tbs"), &path("sig"), &path("other.bin"), &path("x")).is_err());
+
+        let slot = read(&path("slot.bin")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(&slot[MANIFEST_LEN..], &image[..]);
+        let manifest = ImageManifest::parse(&slot[..MANIFEST_LEN]).unwrap();
+        assert_eq!((manifest.version, manifest.key_id, manifest.revoke), (4, 1, 1));
+
+        let spare = KeyPair::from_private_bytes(&[6; 32]).unwrap();
+        let ring: Vec<[u8; 65]> = [&spare, &signer]
+            .iter()
+            .map(|k| export_public_key(&k.public_key(), PublicKeyFormat::Sec1Uncompressed).try_into().unwrap())
+            .collect();
+        assert!(manifest.verify(&VendorKeys::new(&ring, 0)));
+        assert!(!manifest.verify(&VendorKeys::new(&ring, 1 << 1)), "signing key revoked");
+    }
+}